@@ -0,0 +1,231 @@
+//! Detection of NaN and infinite values.
+//!
+//! When anomaly detection is enabled, the output of each op is checked for non-finite values
+//! and an error naming the op and the shapes of its inputs is returned as soon as one is found.
+//! This makes it easy to find the op where a diverging training run first produces NaNs, at the
+//! cost of an additional reduction per op. On cpu the check is a scan of the output values, on
+//! other devices a reduction is run on the device and only its scalar result is copied back.
+//!
+//! ```rust
+//! use candle_core::{anomaly, Device, Tensor};
+//! let xs = Tensor::new(&[1f32, 0., -1.], &Device::Cpu)?;
+//! anomaly::enable();
+//! let err = xs.log().unwrap_err();
+//! anomaly::disable();
+//! assert!(err.to_string().starts_with("log produced nan values"), "{err}");
+//! # Ok::<(), candle_core::Error>(())
+//! ```
+//!
+//! Only the outputs of ops are checked, tensors created directly from some data are not, so a
+//! mask containing `-inf` values can still be created. Such masks typically result in infinite
+//! values being propagated by subsequent ops, [`Options::allow_inf`] can be used to only report
+//! NaN values in this case.
+//!
+//! NaN gradients often appear during the backward pass while the forward pass only produced
+//! finite values, e.g. for the square root of zero. [`set_detect_anomaly`] records where each op
+//! of the graph is called during the forward pass, and the gradients computed by the backward
+//! pass of each op are checked so that the error points at the forward code responsible for the
+//! non-finite gradients. Capturing a backtrace for each op is slow so this should only be used
+//! for debugging.
+//!
+//! ```rust
+//! use candle_core::{anomaly, Device, Var};
+//! anomaly::set_detect_anomaly(true);
+//! let xs = Var::new(&[4f32, 0.], &Device::Cpu)?;
+//! let ys = xs.sqrt()?.sum_all()?;
+//! let err = ys.backward().unwrap_err();
+//! anomaly::set_detect_anomaly(false);
+//! assert!(err.to_string().contains("the backward pass of sqrt produced inf gradients"), "{err}");
+//! # Ok::<(), candle_core::Error>(())
+//! ```
+use crate::backprop::GradStore;
+use crate::op::Op;
+use crate::{CpuStorageRef, DType, DeviceLocation, Error, Layout, Result, Shape, Tensor};
+use std::cell::{Cell, RefCell};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static DETECT_BACKWARD: AtomicBool = AtomicBool::new(false);
+static OPTIONS: Mutex<Options> = Mutex::new(Options {
+    allow_inf: false,
+    capture_backtrace: false,
+    devices: Vec::new(),
+});
+
+thread_local! {
+    static CURRENT_OP: RefCell<Option<(&'static str, Vec<Shape>)>> = const { RefCell::new(None) };
+    static IN_CHECK: Cell<bool> = const { Cell::new(false) };
+}
+
+/// The anomaly detection settings.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Options {
+    /// Only report NaN values, infinite values are allowed.
+    pub allow_inf: bool,
+    /// Always attach a backtrace of the op call to the error, by default a backtrace is only
+    /// captured when enabled via `RUST_BACKTRACE`.
+    pub capture_backtrace: bool,
+    /// Restrict the checks to the ops running on these devices, all the devices are checked
+    /// when empty.
+    pub devices: Vec<DeviceLocation>,
+}
+
+/// Enables anomaly detection on all devices with the default options.
+pub fn enable() {
+    enable_with(Options::default())
+}
+
+/// Enables anomaly detection.
+pub fn enable_with(options: Options) {
+    *OPTIONS.lock().unwrap() = options;
+    ENABLED.store(true, Ordering::Release)
+}
+
+/// Disables anomaly detection.
+pub fn disable() {
+    ENABLED.store(false, Ordering::Release)
+}
+
+/// Whether anomaly detection is currently enabled.
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// Enables or disables anomaly detection for the backward pass, this is independent of the checks
+/// on the outputs of ops controlled by [`enable`] and [`disable`].
+///
+/// Only the ops called while this is enabled are recorded, so this should be enabled before
+/// running the forward pass.
+pub fn set_detect_anomaly(enabled: bool) {
+    DETECT_BACKWARD.store(enabled, Ordering::Release)
+}
+
+/// Whether anomaly detection is currently enabled for the backward pass.
+pub fn is_detect_anomaly_enabled() -> bool {
+    DETECT_BACKWARD.load(Ordering::Relaxed)
+}
+
+/// Checks the gradients of the arguments of `op` after its backward pass has been run.
+pub(crate) fn check_grads(node: &Tensor, op: &Op, grads: &GradStore) -> Result<()> {
+    let args = op.args();
+    for arg in args.iter() {
+        let grad = match grads.get(arg) {
+            Some(grad) if grad.dtype().is_float() => grad,
+            _ => continue,
+        };
+        IN_CHECK.with(|c| c.set(true));
+        let kind = non_finite(grad, false);
+        IN_CHECK.with(|c| c.set(false));
+        if let Some(kind) = kind? {
+            let err = Error::NonFiniteGrad {
+                op: op.name(),
+                kind,
+                shapes: args.iter().map(|a| a.shape().clone()).collect(),
+                created_at: node.created_at().cloned(),
+            };
+            Err(err.bt())?
+        }
+    }
+    Ok(())
+}
+
+/// Records the op that is about to be executed on the current thread, so that it can be named
+/// when checking its output. This is called once the arguments of the op have been validated so
+/// that an op returning an error does not get blamed for the output of the next one.
+pub(crate) fn record_op(name: &'static str, layouts: &[&Layout]) {
+    if !is_enabled() || IN_CHECK.with(|c| c.get()) {
+        return;
+    }
+    let shapes = layouts.iter().map(|l| l.shape().clone()).collect();
+    CURRENT_OP.with(|op| *op.borrow_mut() = Some((name, shapes)))
+}
+
+/// Forgets the last recorded op, this is used by the ops that do not produce a new tensor and so
+/// are not checked.
+pub(crate) fn clear_op() {
+    CURRENT_OP.with(|op| *op.borrow_mut() = None)
+}
+
+/// Checks the output of the last recorded op, this is a no-op if anomaly detection is not
+/// enabled or if the tensor has not been created by an op.
+pub(crate) fn check(t: Tensor) -> Result<Tensor> {
+    if !is_enabled() || IN_CHECK.with(|c| c.get()) {
+        return Ok(t);
+    }
+    let (op, shapes) = match CURRENT_OP.with(|op| op.borrow_mut().take()) {
+        None => return Ok(t),
+        Some(op) => op,
+    };
+    if !t.dtype().is_float() {
+        return Ok(t);
+    }
+    let options = OPTIONS.lock().unwrap().clone();
+    if !options.devices.is_empty() && !options.devices.contains(&t.device().location()) {
+        return Ok(t);
+    }
+    IN_CHECK.with(|c| c.set(true));
+    let kind = non_finite(&t, options.allow_inf);
+    IN_CHECK.with(|c| c.set(false));
+    match kind? {
+        None => Ok(t),
+        Some(kind) => {
+            let err = Error::NonFiniteValue { op, kind, shapes };
+            if options.capture_backtrace {
+                Err(Error::WithBacktrace {
+                    inner: Box::new(err),
+                    backtrace: Box::new(std::backtrace::Backtrace::force_capture()),
+                })
+            } else {
+                Err(err.bt())
+            }
+        }
+    }
+}
+
+fn anomaly_kind(has_nan: bool, has_inf: bool, allow_inf: bool) -> Option<&'static str> {
+    if has_nan {
+        Some("nan")
+    } else if has_inf && !allow_inf {
+        Some("inf")
+    } else {
+        None
+    }
+}
+
+fn scan<T: Copy>(vs: &[T], f: impl Fn(T) -> f64, allow_inf: bool) -> Option<&'static str> {
+    let has_nan = vs.iter().any(|&v| f(v).is_nan());
+    let has_inf = vs.iter().any(|&v| f(v).is_infinite());
+    anomaly_kind(has_nan, has_inf, allow_inf)
+}
+
+fn non_finite(t: &Tensor, allow_inf: bool) -> Result<Option<&'static str>> {
+    if t.device().is_cpu() {
+        let storage = t.storage();
+        let kind = match &*storage {
+            crate::Storage::Cpu(storage) => match storage.as_storage_ref() {
+                CpuStorageRef::BF16(vs) => scan(vs, |v| v.to_f64(), allow_inf),
+                CpuStorageRef::F16(vs) => scan(vs, |v| v.to_f64(), allow_inf),
+                CpuStorageRef::F32(vs) => scan(vs, |v| v as f64, allow_inf),
+                CpuStorageRef::F64(vs) => scan(vs, |v| v, allow_inf),
+                _ => None,
+            },
+            _ => None,
+        };
+        return Ok(kind);
+    }
+    let t = t.flatten_all()?;
+    // NaN is the only value that is not equal to itself.
+    let has_nan = t
+        .ne(&t)?
+        .to_dtype(DType::U32)?
+        .sum_all()?
+        .to_scalar::<u32>()?
+        > 0;
+    // Multiplying by zero results in NaN for infinite values.
+    let has_inf = !has_nan && !allow_inf && {
+        let sum = t.affine(0., 0.)?.sum_all()?.to_dtype(DType::F32)?;
+        sum.to_scalar::<f32>()?.is_nan()
+    };
+    Ok(anomaly_kind(has_nan, has_inf, allow_inf))
+}
@@ -147,6 +147,14 @@ pub trait BackendDevice: Sized + std::fmt::Debug + Clone {
 
     fn set_seed(&self, _: u64) -> Result<()>;
 
+    fn rng_state(&self) -> Result<crate::rng::RngState>;
+
+    fn set_rng_state(&self, _: crate::rng::RngState) -> Result<()>;
+
+    fn memory_stats(&self) -> Result<crate::MemoryStats>;
+
+    fn reset_peak_memory_stats(&self) -> Result<()>;
+
     /// Synchronize should block until all the operations on the device are completed.
     fn synchronize(&self) -> Result<()>;
 }
@@ -707,6 +707,9 @@ impl Tensor {
                         *sum_grad = sum_grad.add(&arg_grad)?
                     }
                 };
+                if crate::anomaly::is_detect_anomaly_enabled() {
+                    crate::anomaly::check_grads(node, op, &grads)?
+                }
             }
         }
         Ok(grads)
@@ -714,12 +717,12 @@ impl Tensor {
 }
 
 /// A store for gradients, associating a tensor id to the corresponding gradient tensor, used for back propagation.
-#[derive(Debug)]
+#[derive(Debug, Default)]
 pub struct GradStore(HashMap<TensorId, Tensor>);
 
 impl GradStore {
     /// Create a new gradient store
-    fn new() -> Self {
+    pub fn new() -> Self {
         GradStore(HashMap::new())
     }
 
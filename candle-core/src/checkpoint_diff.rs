@@ -0,0 +1,208 @@
+//! Deterministic tensor hashing and comparison of checkpoints.
+//!
+//! This is mostly useful in tests and model conversion or merging tools, e.g. to check that a
+//! converted checkpoint matches the original one or that two training runs are reproducible.
+//!
+//! ```rust
+//! use candle_core::{checkpoint_diff, Device, Tensor};
+//! use std::collections::HashMap;
+//! let dev = Device::Cpu;
+//! let lhs = HashMap::from([
+//!     ("w".to_string(), Tensor::new(&[1f32, 2., 3.], &dev)?),
+//!     ("b".to_string(), Tensor::new(&[0f32], &dev)?),
+//! ]);
+//! let rhs = HashMap::from([("w".to_string(), Tensor::new(&[1f32, 2.5, 3.], &dev)?)]);
+//! let diff = checkpoint_diff::diff(&lhs, &rhs)?;
+//! assert_eq!(diff.only_in_lhs, ["b"]);
+//! assert_eq!(diff.changed_keys(0.), ["b", "w"]);
+//! assert_eq!(diff.tensors["w"].max_abs_diff, 0.5);
+//! # Ok::<(), candle_core::Error>(())
+//! ```
+use crate::{DType, Device, Result, Shape, Tensor};
+use std::collections::{BTreeMap, HashMap};
+use std::path::Path;
+
+const FNV_OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+const FNV_PRIME: u64 = 0x0000_0100_0000_01b3;
+
+/// A 64 bits FNV-1a hasher, unlike the standard library hashers its output is guaranteed to be
+/// stable across platforms, processes and compiler versions.
+pub(crate) struct Fnv1a(u64);
+
+impl Fnv1a {
+    pub(crate) fn new() -> Self {
+        Self(FNV_OFFSET_BASIS)
+    }
+
+    pub(crate) fn finish(&self) -> u64 {
+        self.0
+    }
+
+    pub(crate) fn write(&mut self, bytes: &[u8]) {
+        for &b in bytes {
+            self.0 ^= b as u64;
+            self.0 = self.0.wrapping_mul(FNV_PRIME);
+        }
+    }
+}
+
+impl Tensor {
+    /// A hash of the dtype, shape and values of the tensor.
+    ///
+    /// The hash only depends on the logical content of the tensor: it does not depend on the
+    /// device, on the memory layout, or on the process. Two tensors with the same values but
+    /// different dtypes have different hashes. Values are hashed using their little endian
+    /// byte representation so, for floats, `0.0` and `-0.0` hash differently.
+    ///
+    /// ```rust
+    /// use candle_core::{Tensor, Device};
+    /// let a = Tensor::new(&[[1f32, 2.], [3., 4.]], &Device::Cpu)?;
+    /// let b = Tensor::new(&[[1f32, 3.], [2., 4.]], &Device::Cpu)?.t()?;
+    /// assert_eq!(a.content_hash()?, b.content_hash()?);
+    /// assert_ne!(a.content_hash()?, a.to_dtype(candle_core::DType::F64)?.content_hash()?);
+    /// # Ok::<(), candle_core::Error>(())
+    /// ```
+    pub fn content_hash(&self) -> Result<u64> {
+        let mut hasher = Fnv1a::new();
+        hasher.write(self.dtype().as_str().as_bytes());
+        hasher.write(&(self.rank() as u64).to_le_bytes());
+        for &d in self.dims() {
+            hasher.write(&(d as u64).to_le_bytes());
+        }
+        let bytes = crate::safetensors::convert_back(self)?;
+        if cfg!(target_endian = "big") {
+            let size_in_bytes = self.dtype().size_in_bytes();
+            for chunk in bytes.chunks_exact(size_in_bytes) {
+                let mut chunk = chunk.to_vec();
+                chunk.reverse();
+                hasher.write(&chunk)
+            }
+        } else {
+            hasher.write(&bytes)
+        }
+        Ok(hasher.finish())
+    }
+}
+
+/// The differences between two tensors with the same shape.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TensorDiff {
+    pub lhs_dtype: DType,
+    pub rhs_dtype: DType,
+    /// The maximum of the absolute element-wise differences, the values are compared in `f64`.
+    pub max_abs_diff: f64,
+    /// The mean of the absolute element-wise differences.
+    pub mean_abs_diff: f64,
+}
+
+impl TensorDiff {
+    /// Whether both tensors have the same dtype and the same values.
+    pub fn is_identical(&self) -> bool {
+        self.lhs_dtype == self.rhs_dtype && self.max_abs_diff == 0.
+    }
+}
+
+/// Compares the values of two tensors, the dtypes can differ but the shapes must match.
+pub fn tensor_diff(lhs: &Tensor, rhs: &Tensor) -> Result<TensorDiff> {
+    let lhs_dtype = lhs.dtype();
+    let rhs_dtype = rhs.dtype();
+    lhs.same_shape_binary_op(rhs, "tensor-diff")?;
+    let (max_abs_diff, mean_abs_diff) = if lhs.elem_count() == 0 {
+        (0., 0.)
+    } else {
+        // The comparison is done on the cpu as f64 is not supported on all devices.
+        let lhs = lhs.to_device(&Device::Cpu)?.to_dtype(DType::F64)?;
+        let rhs = rhs.to_device(&Device::Cpu)?.to_dtype(DType::F64)?;
+        let abs_diff = lhs.sub(&rhs)?.abs()?.flatten_all()?;
+        let max = abs_diff.max(0)?.to_scalar::<f64>()?;
+        let mean = abs_diff.mean(0)?.to_scalar::<f64>()?;
+        (max, mean)
+    };
+    Ok(TensorDiff {
+        lhs_dtype,
+        rhs_dtype,
+        max_abs_diff,
+        mean_abs_diff,
+    })
+}
+
+/// The differences between two checkpoints, the names are sorted.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct CheckpointDiff {
+    /// The tensors that are only present in the left hand side checkpoint.
+    pub only_in_lhs: Vec<String>,
+    /// The tensors that are only present in the right hand side checkpoint.
+    pub only_in_rhs: Vec<String>,
+    /// The tensors that are present on both sides but with different shapes.
+    pub shape_mismatches: BTreeMap<String, (Shape, Shape)>,
+    /// The comparison of the tensors that are present on both sides with the same shape.
+    pub tensors: BTreeMap<String, TensorDiff>,
+}
+
+impl CheckpointDiff {
+    /// The names of the tensors that differ between both checkpoints: tensors that are missing
+    /// on one side, that have different shapes or dtypes, or for which some values differ by
+    /// more than `tolerance`. With a `tolerance` of `0.` any difference in value is reported.
+    pub fn changed_keys(&self, tolerance: f64) -> Vec<&str> {
+        let mut keys: Vec<&str> = self
+            .only_in_lhs
+            .iter()
+            .chain(self.only_in_rhs.iter())
+            .chain(self.shape_mismatches.keys())
+            .map(|k| k.as_str())
+            .collect();
+        for (name, diff) in self.tensors.iter() {
+            let max_abs_diff = diff.max_abs_diff;
+            if diff.lhs_dtype != diff.rhs_dtype || max_abs_diff.is_nan() || max_abs_diff > tolerance
+            {
+                keys.push(name.as_str())
+            }
+        }
+        keys.sort();
+        keys
+    }
+
+    /// Whether both checkpoints contain the same tensors with identical dtypes and values.
+    pub fn is_identical(&self) -> bool {
+        self.changed_keys(0.).is_empty()
+    }
+}
+
+/// Compares two checkpoints tensor by tensor.
+pub fn diff(
+    lhs: &HashMap<String, Tensor>,
+    rhs: &HashMap<String, Tensor>,
+) -> Result<CheckpointDiff> {
+    let mut diff = CheckpointDiff::default();
+    for (name, lhs_t) in lhs.iter() {
+        match rhs.get(name) {
+            None => diff.only_in_lhs.push(name.clone()),
+            Some(rhs_t) if lhs_t.shape() != rhs_t.shape() => {
+                let shapes = (lhs_t.shape().clone(), rhs_t.shape().clone());
+                diff.shape_mismatches.insert(name.clone(), shapes);
+            }
+            Some(rhs_t) => {
+                diff.tensors
+                    .insert(name.clone(), tensor_diff(lhs_t, rhs_t)?);
+            }
+        }
+    }
+    for name in rhs.keys() {
+        if !lhs.contains_key(name) {
+            diff.only_in_rhs.push(name.clone())
+        }
+    }
+    diff.only_in_lhs.sort();
+    diff.only_in_rhs.sort();
+    Ok(diff)
+}
+
+/// Compares two safetensors files, the tensors are loaded on the cpu.
+pub fn diff_safetensors<P1: AsRef<Path>, P2: AsRef<Path>>(
+    lhs: P1,
+    rhs: P2,
+) -> Result<CheckpointDiff> {
+    let lhs = crate::safetensors::load(lhs, &Device::Cpu)?;
+    let rhs = crate::safetensors::load(rhs, &Device::Cpu)?;
+    diff(&lhs, &rhs)
+}
@@ -140,7 +140,7 @@ impl Tensor {
             dilation: params.dilation,
         });
         let out_dims = params.out_dims();
-        Ok(crate::tensor::from_storage(storage, out_dims, op, false))
+        crate::anomaly::check(crate::tensor::from_storage(storage, out_dims, op, false))
     }
 
     /// Applies a 1D convolution over the input tensor.
@@ -209,7 +209,7 @@ impl Tensor {
             dilation: params.dilation,
         });
         let out_dims = params.out_dims();
-        Ok(crate::tensor::from_storage(storage, out_dims, op, false))
+        crate::anomaly::check(crate::tensor::from_storage(storage, out_dims, op, false))
     }
 
     /// Applies a 1D transposed convolution over the input tensor.
@@ -267,7 +267,7 @@ impl Tensor {
             dilation: params.dilation,
         });
         let out_dims = params.out_dims();
-        Ok(crate::tensor::from_storage(storage, out_dims, op, false))
+        crate::anomaly::check(crate::tensor::from_storage(storage, out_dims, op, false))
     }
 
     /// Applies a 2D convolution over the input tensor.
@@ -355,6 +355,6 @@ impl Tensor {
             dilation: params.dilation,
         });
         let out_dims = params.out_dims();
-        Ok(crate::tensor::from_storage(storage, out_dims, op, false))
+        crate::anomaly::check(crate::tensor::from_storage(storage, out_dims, op, false))
     }
 }
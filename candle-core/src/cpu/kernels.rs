@@ -77,7 +77,7 @@ impl VecOps for f32 {
 
     #[inline(always)]
     unsafe fn vec_reduce_sum(xs: *const Self, res: *mut Self, len: usize) {
-        super::vec_sum(xs, res, len)
+        *res = super::pairwise_sum_f32(xs, len)
     }
 }
 
@@ -98,6 +98,11 @@ impl VecOps for half::f16 {
         super::vec_dot_f16(lhs, rhs, &mut res_f32, len);
         *res = half::f16::from_f32(res_f32);
     }
+
+    #[inline(always)]
+    unsafe fn vec_reduce_sum(xs: *const Self, res: *mut Self, len: usize) {
+        *res = Self::from_f32(super::pairwise_sum(xs, len, 0f32, Self::to_f32))
+    }
 }
 
 impl VecOps for f64 {
@@ -110,6 +115,11 @@ impl VecOps for f64 {
     fn max(self, other: Self) -> Self {
         Self::max(self, other)
     }
+
+    #[inline(always)]
+    unsafe fn vec_reduce_sum(xs: *const Self, res: *mut Self, len: usize) {
+        *res = super::pairwise_sum(xs, len, 0f64, |v| v)
+    }
 }
 impl VecOps for half::bf16 {
     #[inline(always)]
@@ -121,6 +131,11 @@ impl VecOps for half::bf16 {
     fn max(self, other: Self) -> Self {
         Self::max(self, other)
     }
+
+    #[inline(always)]
+    unsafe fn vec_reduce_sum(xs: *const Self, res: *mut Self, len: usize) {
+        *res = Self::from_f32(super::pairwise_sum(xs, len, 0f32, Self::to_f32))
+    }
 }
 impl VecOps for u8 {
     #[inline(always)]
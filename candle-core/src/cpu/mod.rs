@@ -144,6 +144,122 @@ pub(crate) unsafe fn vec_sum(row: *const f32, b: *mut f32, k: usize) {
     }
 }
 
+/// The accumulation strategy used by the sum reductions on the cpu.
+///
+/// Pairwise summation is always used when summing contiguous values of a floating point dtype,
+/// i.e. when reducing over the last dimensions of a contiguous tensor, this setting selects what
+/// happens on top of it. Summing values naively in order has an error that grows linearly with
+/// the number of values, which is visible when comparing large f32 sums with NumPy (that also
+/// uses pairwise summation).
+///
+/// The other backends accumulate in a different order and are not affected by this setting: the
+/// cuda and metal kernels have each thread sum a strided subset of the values before combining
+/// the per-thread partial sums with a tree reduction, the accumulation is done in the dtype of
+/// the tensor.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SumAccumulation {
+    /// Pairwise summation of the contiguous values using blocks of [`PAIRWISE_BLOCK_SIZE`]
+    /// values, the error grows with the logarithm of the number of values. f16 and bf16 values
+    /// are accumulated in f32. Reductions over non-contiguous values or over leading dimensions
+    /// accumulate sequentially in the dtype of the tensor.
+    #[default]
+    Pairwise,
+    /// Kahan-Babuska (Neumaier) compensated summation with an f64 accumulator for all the
+    /// floating point sum reductions, the error does not depend on the number of values. This
+    /// is a few times slower than pairwise summation.
+    Kahan,
+}
+
+/// The number of values below which pairwise summation sums the values with simd accumulators
+/// rather than splitting them in two halves.
+pub const PAIRWISE_BLOCK_SIZE: usize = 1024;
+
+static SUM_ACCUMULATION: std::sync::atomic::AtomicU8 = std::sync::atomic::AtomicU8::new(0);
+
+/// Sets the accumulation strategy for the cpu sum reductions, this applies to all the threads.
+pub fn set_sum_accumulation(acc: SumAccumulation) {
+    let v = match acc {
+        SumAccumulation::Pairwise => 0,
+        SumAccumulation::Kahan => 1,
+    };
+    SUM_ACCUMULATION.store(v, std::sync::atomic::Ordering::Relaxed)
+}
+
+/// The accumulation strategy for the cpu sum reductions.
+pub fn sum_accumulation() -> SumAccumulation {
+    match SUM_ACCUMULATION.load(std::sync::atomic::Ordering::Relaxed) {
+        0 => SumAccumulation::Pairwise,
+        _ => SumAccumulation::Kahan,
+    }
+}
+
+/// Pairwise sum of `k` f32 values.
+pub(crate) unsafe fn pairwise_sum_f32(row: *const f32, k: usize) -> f32 {
+    if k <= PAIRWISE_BLOCK_SIZE {
+        let mut sum = 0f32;
+        vec_sum(row, &mut sum, k);
+        sum
+    } else {
+        // Keep the split aligned on the simd step so that only the last block has leftovers.
+        let half = (k / 2 + 31) & !31;
+        pairwise_sum_f32(row, half) + pairwise_sum_f32(row.add(half), k - half)
+    }
+}
+
+/// Pairwise sum of `k` values, the values are converted to the accumulator type `A` using `f`.
+pub(crate) unsafe fn pairwise_sum<T: Copy, A: Copy + std::ops::Add<Output = A>>(
+    row: *const T,
+    k: usize,
+    zero: A,
+    f: impl Fn(T) -> A + Copy,
+) -> A {
+    if k <= PAIRWISE_BLOCK_SIZE {
+        // Use multiple accumulators so that the additions can be pipelined.
+        let mut acc = [zero; 8];
+        let np = k & !7;
+        for i in (0..np).step_by(8) {
+            for (j, acc) in acc.iter_mut().enumerate() {
+                *acc = *acc + f(*row.add(i + j))
+            }
+        }
+        let mut sum =
+            ((acc[0] + acc[1]) + (acc[2] + acc[3])) + ((acc[4] + acc[5]) + (acc[6] + acc[7]));
+        for i in np..k {
+            sum = sum + f(*row.add(i))
+        }
+        sum
+    } else {
+        let half = (k / 2 + 7) & !7;
+        pairwise_sum(row, half, zero, f) + pairwise_sum(row.add(half), k - half, zero, f)
+    }
+}
+
+/// Kahan-Babuska (Neumaier) compensated summation, the compensation also accounts for the case
+/// where the added value is larger than the running sum.
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct KahanSum {
+    sum: f64,
+    compensation: f64,
+}
+
+impl KahanSum {
+    #[inline(always)]
+    pub(crate) fn add(&mut self, v: f64) {
+        let t = self.sum + v;
+        if self.sum.abs() >= v.abs() {
+            self.compensation += (self.sum - t) + v
+        } else {
+            self.compensation += (v - t) + self.sum
+        }
+        self.sum = t
+    }
+
+    #[inline(always)]
+    pub(crate) fn value(&self) -> f64 {
+        self.sum + self.compensation
+    }
+}
+
 #[cfg(target_feature = "avx")]
 #[inline(always)]
 pub(crate) unsafe fn vec_dot_f16(a_row: *const f16, b_row: *const f16, c: *mut f32, k: usize) {
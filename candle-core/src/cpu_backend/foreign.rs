@@ -0,0 +1,107 @@
+//! Cpu buffers owned by another framework, e.g. the tensors imported with DLPack.
+use super::{CpuStorage, CpuStorageRef};
+use crate::{DType, Result, WithDType};
+use std::sync::Arc;
+
+/// A read-only buffer that candle borrows without copying it. The buffer is released by dropping
+/// `owner` once the last storage using it is dropped.
+#[derive(Clone)]
+pub struct ForeignStorage {
+    ptr: *const u8,
+    len: usize,
+    dtype: DType,
+    _owner: Arc<dyn std::any::Any + Send + Sync>,
+}
+
+// Safety: the buffer is never written through this type and `owner` is thread safe.
+unsafe impl Send for ForeignStorage {}
+unsafe impl Sync for ForeignStorage {}
+
+impl std::fmt::Debug for ForeignStorage {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "ForeignStorage[{:?}; {}]", self.dtype, self.len)
+    }
+}
+
+impl ForeignStorage {
+    /// Borrows the `len` values of type `dtype` starting at `ptr`.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` has to point to `len` initialized values of type `dtype`. These have to stay valid
+    /// and must not be modified while `owner` is alive.
+    pub unsafe fn new(
+        ptr: *const u8,
+        len: usize,
+        dtype: DType,
+        owner: Arc<dyn std::any::Any + Send + Sync>,
+    ) -> Result<Self> {
+        if len > 0 && (ptr.is_null() || ptr.align_offset(dtype.size_in_bytes()) != 0) {
+            crate::bail!("foreign buffer {ptr:?} is not aligned for {dtype:?}")
+        }
+        Ok(Self {
+            ptr,
+            len,
+            dtype,
+            _owner: owner,
+        })
+    }
+
+    pub fn dtype(&self) -> DType {
+        self.dtype
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn as_slice<T: WithDType>(&self) -> Result<&[T]> {
+        if T::DTYPE != self.dtype {
+            Err(crate::Error::UnexpectedDType {
+                expected: T::DTYPE,
+                got: self.dtype,
+                msg: "unexpected dtype",
+            }
+            .bt())?
+        }
+        if self.len == 0 {
+            return Ok(&[]);
+        }
+        // Safety: the bounds and alignment are guaranteed by the caller of `new` and the buffer
+        // is kept alive by the owner.
+        Ok(unsafe { std::slice::from_raw_parts(self.ptr as *const T, self.len) })
+    }
+
+    pub fn as_storage_ref(&self) -> CpuStorageRef<'_> {
+        fn slice<T: WithDType>(s: &ForeignStorage) -> &[T] {
+            // The dtype is checked by the caller.
+            s.as_slice().unwrap_or(&[])
+        }
+        match self.dtype {
+            DType::U8 => CpuStorageRef::U8(slice(self)),
+            DType::U32 => CpuStorageRef::U32(slice(self)),
+            DType::I64 => CpuStorageRef::I64(slice(self)),
+            DType::BF16 => CpuStorageRef::BF16(slice(self)),
+            DType::F16 => CpuStorageRef::F16(slice(self)),
+            DType::F32 => CpuStorageRef::F32(slice(self)),
+            DType::F64 => CpuStorageRef::F64(slice(self)),
+        }
+    }
+
+    /// Copies the buffer to an owned storage.
+    pub fn to_owned_storage(&self) -> CpuStorage {
+        match self.as_storage_ref() {
+            CpuStorageRef::U8(s) => CpuStorage::U8(s.to_vec()),
+            CpuStorageRef::U32(s) => CpuStorage::U32(s.to_vec()),
+            CpuStorageRef::I64(s) => CpuStorage::I64(s.to_vec()),
+            CpuStorageRef::BF16(s) => CpuStorage::BF16(s.to_vec()),
+            CpuStorageRef::F16(s) => CpuStorage::F16(s.to_vec()),
+            CpuStorageRef::F32(s) => CpuStorage::F32(s.to_vec()),
+            CpuStorageRef::F64(s) => CpuStorage::F64(s.to_vec()),
+        }
+    }
+}
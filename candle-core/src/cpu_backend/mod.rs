@@ -5,7 +5,9 @@ use crate::{DType, Error, IntDType, Layout, Result, Shape, WithDType};
 use half::{bf16, f16};
 use rayon::prelude::*;
 
+mod foreign;
 mod utils;
+pub use foreign::ForeignStorage;
 pub use utils::{
     binary_map, binary_map_vec, unary_map, unary_map_vec, Map1, Map1Any, Map2, Map2U8,
 };
@@ -25,6 +27,8 @@ pub enum CpuStorage {
     F16(Vec<f16>),
     F32(Vec<f32>),
     F64(Vec<f64>),
+    /// A read-only buffer owned by another framework, it is copied before being modified.
+    Foreign(ForeignStorage),
 }
 
 #[derive(Debug, Clone)]
@@ -222,6 +226,11 @@ impl ReduceSum<'_> {
     where
         T: WithDType,
     {
+        if T::DTYPE.is_float()
+            && crate::cpu::sum_accumulation() == crate::cpu::SumAccumulation::Kahan
+        {
+            return Ok(self.kahan_fold_impl(src, src_l));
+        }
         let mut dst = vec![start_elt; self.dst_shape.elem_count()];
         match src_l.contiguous_offsets() {
             Some((o1, o2)) => {
@@ -281,6 +290,36 @@ impl ReduceSum<'_> {
     }
 }
 
+impl ReduceSum<'_> {
+    fn dst_index(&self, unstr_index: usize) -> usize {
+        let mut dst_index = unstr_index;
+        // Set the reduce_dims indexes to 0.
+        for &(dim, stride) in self.reduce_dims_and_stride.iter() {
+            let (pre, post) = (dst_index / stride, dst_index % stride);
+            dst_index = (pre / dim) * stride + post;
+        }
+        dst_index
+    }
+
+    // Compensated summation in f64, used for all the layouts.
+    fn kahan_fold_impl<T: WithDType>(&self, src: &[T], src_l: &Layout) -> Vec<T> {
+        let mut dst = vec![crate::cpu::KahanSum::default(); self.dst_shape.elem_count()];
+        match src_l.contiguous_offsets() {
+            Some((o1, o2)) => {
+                for (unstr_index, &src) in src[o1..o2].iter().enumerate() {
+                    dst[self.dst_index(unstr_index)].add(src.to_f64())
+                }
+            }
+            None => {
+                for (unstr_index, src_index) in src_l.strided_index().enumerate() {
+                    dst[self.dst_index(unstr_index)].add(src[src_index].to_f64())
+                }
+            }
+        }
+        dst.iter().map(|v| T::from_f64(v.value())).collect()
+    }
+}
+
 impl Map1 for ReduceSum<'_> {
     #[inline(always)]
     fn f<T: WithDType>(&self, src: &[T], src_l: &Layout) -> Result<Vec<T>> {
@@ -1543,7 +1582,43 @@ impl CpuStorage {
         D::cpu_storage_as_slice(self)
     }
 
+    /// The data of the storage, this borrows the buffer of foreign storages.
+    pub fn as_storage_ref(&self) -> CpuStorageRef<'_> {
+        match self {
+            Self::U8(s) => CpuStorageRef::U8(s),
+            Self::U32(s) => CpuStorageRef::U32(s),
+            Self::I64(s) => CpuStorageRef::I64(s),
+            Self::BF16(s) => CpuStorageRef::BF16(s),
+            Self::F16(s) => CpuStorageRef::F16(s),
+            Self::F32(s) => CpuStorageRef::F32(s),
+            Self::F64(s) => CpuStorageRef::F64(s),
+            Self::Foreign(s) => s.as_storage_ref(),
+        }
+    }
+
+    /// The storage with its data in a `Vec`, the data of foreign storages is copied.
+    pub fn owned_data(&self) -> std::borrow::Cow<'_, CpuStorage> {
+        match self {
+            Self::Foreign(s) => std::borrow::Cow::Owned(s.to_owned_storage()),
+            s => std::borrow::Cow::Borrowed(s),
+        }
+    }
+
+    /// Replaces the data of a foreign storage with an owned copy so that it can be modified.
+    pub(crate) fn make_owned(&mut self) {
+        if let Self::Foreign(s) = self {
+            *self = s.to_owned_storage()
+        }
+    }
+
     pub fn concat(storages: &[CpuStorage]) -> Result<CpuStorage> {
+        if storages.iter().any(|s| matches!(s, Self::Foreign(_))) {
+            let storages: Vec<_> = storages
+                .iter()
+                .map(|s| s.owned_data().into_owned())
+                .collect();
+            return Self::concat(&storages);
+        }
         let storage0 = &storages[0];
         let s = match storage0 {
             Self::U8(_) => {
@@ -1623,6 +1698,7 @@ impl CpuStorage {
                     .concat();
                 Self::F64(storages)
             }
+            Self::Foreign(_) => unreachable!(),
         };
         Ok(s)
     }
@@ -1640,205 +1716,214 @@ impl BackendStorage for CpuStorage {
             Self::F16(_) => DType::F16,
             Self::F32(_) => DType::F32,
             Self::F64(_) => DType::F64,
+            Self::Foreign(s) => s.dtype(),
         }
     }
 
     fn to_dtype(&self, layout: &Layout, dtype: DType) -> Result<Self> {
         // TODO: find a way around the quadratic number of cases below.
-        match (self, dtype) {
-            (Self::U8(storage), DType::BF16) => {
+        match (self.as_storage_ref(), dtype) {
+            (CpuStorageRef::U8(storage), DType::BF16) => {
                 let data = unary_map(storage, layout, |v| bf16::from_f32(v as f32));
                 Ok(Self::BF16(data))
             }
-            (Self::U32(storage), DType::BF16) => {
+            (CpuStorageRef::U32(storage), DType::BF16) => {
                 let data = unary_map(storage, layout, |v| bf16::from_f32(v as f32));
                 Ok(Self::BF16(data))
             }
-            (Self::I64(storage), DType::BF16) => {
+            (CpuStorageRef::I64(storage), DType::BF16) => {
                 let data = unary_map(storage, layout, |v| bf16::from_f32(v as f32));
                 Ok(Self::BF16(data))
             }
-            (Self::BF16(storage), DType::BF16) => {
+            (CpuStorageRef::BF16(storage), DType::BF16) => {
                 let data = unary_map(storage, layout, |v| v);
                 Ok(Self::BF16(data))
             }
-            (Self::F16(storage), DType::BF16) => {
+            (CpuStorageRef::F16(storage), DType::BF16) => {
                 let data = unary_map(storage, layout, |v| bf16::from_f32(v.to_f32()));
                 Ok(Self::BF16(data))
             }
-            (Self::F32(storage), DType::BF16) => {
+            (CpuStorageRef::F32(storage), DType::BF16) => {
                 let data = unary_map(storage, layout, bf16::from_f32);
                 Ok(Self::BF16(data))
             }
-            (Self::F64(storage), DType::BF16) => {
+            (CpuStorageRef::F64(storage), DType::BF16) => {
                 let data = unary_map(storage, layout, bf16::from_f64);
                 Ok(Self::BF16(data))
             }
-            (Self::U8(storage), DType::F16) => {
+            (CpuStorageRef::U8(storage), DType::F16) => {
                 let data = unary_map(storage, layout, |v| f16::from_f32(v as f32));
                 Ok(Self::F16(data))
             }
-            (Self::U32(storage), DType::F16) => {
+            (CpuStorageRef::U32(storage), DType::F16) => {
                 let data = unary_map(storage, layout, |v| f16::from_f32(v as f32));
                 Ok(Self::F16(data))
             }
-            (Self::I64(storage), DType::F16) => {
+            (CpuStorageRef::I64(storage), DType::F16) => {
                 let data = unary_map(storage, layout, |v| f16::from_f32(v as f32));
                 Ok(Self::F16(data))
             }
-            (Self::BF16(storage), DType::F16) => {
-                let data = unary_map(storage, layout, |v| f16::from_f32(v.to_f32()));
+            (CpuStorageRef::BF16(storage), DType::F16) => {
+                // The finite values too large for f16 saturate rather than becoming infinities.
+                let data = unary_map(storage, layout, |v| {
+                    let v = v.to_f32();
+                    if v.is_finite() {
+                        f16::from_f32(v.clamp(-f16::MAX.to_f32(), f16::MAX.to_f32()))
+                    } else {
+                        f16::from_f32(v)
+                    }
+                });
                 Ok(Self::F16(data))
             }
-            (Self::F16(storage), DType::F16) => {
+            (CpuStorageRef::F16(storage), DType::F16) => {
                 let data = unary_map(storage, layout, |v| v);
                 Ok(Self::F16(data))
             }
-            (Self::F32(storage), DType::F16) => {
+            (CpuStorageRef::F32(storage), DType::F16) => {
                 let data = unary_map(storage, layout, f16::from_f32);
                 Ok(Self::F16(data))
             }
-            (Self::F64(storage), DType::F16) => {
+            (CpuStorageRef::F64(storage), DType::F16) => {
                 let data = unary_map(storage, layout, f16::from_f64);
                 Ok(Self::F16(data))
             }
-            (Self::U8(storage), DType::F32) => {
+            (CpuStorageRef::U8(storage), DType::F32) => {
                 let data = unary_map(storage, layout, |v| v as f32);
                 Ok(Self::F32(data))
             }
-            (Self::U32(storage), DType::F32) => {
+            (CpuStorageRef::U32(storage), DType::F32) => {
                 let data = unary_map(storage, layout, |v| v as f32);
                 Ok(Self::F32(data))
             }
-            (Self::I64(storage), DType::F32) => {
+            (CpuStorageRef::I64(storage), DType::F32) => {
                 let data = unary_map(storage, layout, |v| v as f32);
                 Ok(Self::F32(data))
             }
-            (Self::BF16(storage), DType::F32) => {
+            (CpuStorageRef::BF16(storage), DType::F32) => {
                 let data = unary_map(storage, layout, |v| v.to_f32());
                 Ok(Self::F32(data))
             }
-            (Self::F16(storage), DType::F32) => {
+            (CpuStorageRef::F16(storage), DType::F32) => {
                 let data = unary_map(storage, layout, |v| v.to_f32());
                 Ok(Self::F32(data))
             }
-            (Self::F32(storage), DType::F32) => {
+            (CpuStorageRef::F32(storage), DType::F32) => {
                 let data = unary_map(storage, layout, |v| v);
                 Ok(Self::F32(data))
             }
-            (Self::F64(storage), DType::F32) => {
+            (CpuStorageRef::F64(storage), DType::F32) => {
                 let data = unary_map(storage, layout, |v| v as f32);
                 Ok(Self::F32(data))
             }
-            (Self::U8(storage), DType::U8) => {
+            (CpuStorageRef::U8(storage), DType::U8) => {
                 let data = unary_map(storage, layout, |v| v);
                 Ok(Self::U8(data))
             }
-            (Self::BF16(storage), DType::U8) => {
+            (CpuStorageRef::BF16(storage), DType::U8) => {
                 let data = unary_map(storage, layout, |v| v.to_f32() as u8);
                 Ok(Self::U8(data))
             }
-            (Self::F16(storage), DType::U8) => {
+            (CpuStorageRef::F16(storage), DType::U8) => {
                 let data = unary_map(storage, layout, |v| v.to_f32() as u8);
                 Ok(Self::U8(data))
             }
-            (Self::F32(storage), DType::U8) => {
+            (CpuStorageRef::F32(storage), DType::U8) => {
                 let data = unary_map(storage, layout, |v| v as u8);
                 Ok(Self::U8(data))
             }
-            (Self::F64(storage), DType::U8) => {
+            (CpuStorageRef::F64(storage), DType::U8) => {
                 let data = unary_map(storage, layout, |v| v as u8);
                 Ok(Self::U8(data))
             }
-            (Self::U32(storage), DType::U8) => {
+            (CpuStorageRef::U32(storage), DType::U8) => {
                 let data = unary_map(storage, layout, |v| v as u8);
                 Ok(Self::U8(data))
             }
-            (Self::I64(storage), DType::U8) => {
+            (CpuStorageRef::I64(storage), DType::U8) => {
                 let data = unary_map(storage, layout, |v| v as u8);
                 Ok(Self::U8(data))
             }
-            (Self::U8(storage), DType::U32) => {
+            (CpuStorageRef::U8(storage), DType::U32) => {
                 let data = unary_map(storage, layout, |v| v as u32);
                 Ok(Self::U32(data))
             }
-            (Self::U32(storage), DType::U32) => {
+            (CpuStorageRef::U32(storage), DType::U32) => {
                 let data = unary_map(storage, layout, |v| v);
                 Ok(Self::U32(data))
             }
-            (Self::I64(storage), DType::U32) => {
+            (CpuStorageRef::I64(storage), DType::U32) => {
                 let data = unary_map(storage, layout, |v| v as u32);
                 Ok(Self::U32(data))
             }
-            (Self::BF16(storage), DType::U32) => {
+            (CpuStorageRef::BF16(storage), DType::U32) => {
                 let data = unary_map(storage, layout, |v| v.to_f32() as u32);
                 Ok(Self::U32(data))
             }
-            (Self::F16(storage), DType::U32) => {
+            (CpuStorageRef::F16(storage), DType::U32) => {
                 let data = unary_map(storage, layout, |v| v.to_f32() as u32);
                 Ok(Self::U32(data))
             }
-            (Self::F32(storage), DType::U32) => {
+            (CpuStorageRef::F32(storage), DType::U32) => {
                 let data = unary_map(storage, layout, |v| v as u32);
                 Ok(Self::U32(data))
             }
-            (Self::F64(storage), DType::U32) => {
+            (CpuStorageRef::F64(storage), DType::U32) => {
                 let data = unary_map(storage, layout, |v| v as u32);
                 Ok(Self::U32(data))
             }
-            (Self::U8(storage), DType::I64) => {
+            (CpuStorageRef::U8(storage), DType::I64) => {
                 let data = unary_map(storage, layout, |v| v as i64);
                 Ok(Self::I64(data))
             }
-            (Self::U32(storage), DType::I64) => {
+            (CpuStorageRef::U32(storage), DType::I64) => {
                 let data = unary_map(storage, layout, |v| v as i64);
                 Ok(Self::I64(data))
             }
-            (Self::I64(storage), DType::I64) => {
+            (CpuStorageRef::I64(storage), DType::I64) => {
                 let data = unary_map(storage, layout, |v| v);
                 Ok(Self::I64(data))
             }
-            (Self::BF16(storage), DType::I64) => {
+            (CpuStorageRef::BF16(storage), DType::I64) => {
                 let data = unary_map(storage, layout, |v| v.to_f32() as i64);
                 Ok(Self::I64(data))
             }
-            (Self::F16(storage), DType::I64) => {
+            (CpuStorageRef::F16(storage), DType::I64) => {
                 let data = unary_map(storage, layout, |v| v.to_f32() as i64);
                 Ok(Self::I64(data))
             }
-            (Self::F32(storage), DType::I64) => {
+            (CpuStorageRef::F32(storage), DType::I64) => {
                 let data = unary_map(storage, layout, |v| v as i64);
                 Ok(Self::I64(data))
             }
-            (Self::F64(storage), DType::I64) => {
+            (CpuStorageRef::F64(storage), DType::I64) => {
                 let data = unary_map(storage, layout, |v| v as i64);
                 Ok(Self::I64(data))
             }
-            (Self::U8(storage), DType::F64) => {
+            (CpuStorageRef::U8(storage), DType::F64) => {
                 let data = unary_map(storage, layout, |v| v as f64);
                 Ok(Self::F64(data))
             }
-            (Self::U32(storage), DType::F64) => {
+            (CpuStorageRef::U32(storage), DType::F64) => {
                 let data = unary_map(storage, layout, |v| v as f64);
                 Ok(Self::F64(data))
             }
-            (Self::I64(storage), DType::F64) => {
+            (CpuStorageRef::I64(storage), DType::F64) => {
                 let data = unary_map(storage, layout, |v| v as f64);
                 Ok(Self::F64(data))
             }
-            (Self::BF16(storage), DType::F64) => {
+            (CpuStorageRef::BF16(storage), DType::F64) => {
                 let data = unary_map(storage, layout, |v| v.to_f64());
                 Ok(Self::F64(data))
             }
-            (Self::F16(storage), DType::F64) => {
+            (CpuStorageRef::F16(storage), DType::F64) => {
                 let data = unary_map(storage, layout, |v| v.to_f64());
                 Ok(Self::F64(data))
             }
-            (Self::F32(storage), DType::F64) => {
+            (CpuStorageRef::F32(storage), DType::F64) => {
                 let data = unary_map(storage, layout, |v| v as f64);
                 Ok(Self::F64(data))
             }
-            (Self::F64(storage), DType::F64) => {
+            (CpuStorageRef::F64(storage), DType::F64) => {
                 let data = unary_map(storage, layout, |v| v);
                 Ok(Self::F64(data))
             }
@@ -1938,57 +2023,57 @@ impl BackendStorage for CpuStorage {
     fn powf(&self, layout: &Layout, e: f64) -> Result<Self> {
         use num_traits::Float;
         // TODO: Have some generic map for functions that apply on num_traits::Float elements.
-        match self {
-            Self::BF16(storage) => {
+        match self.as_storage_ref() {
+            CpuStorageRef::BF16(storage) => {
                 let data = unary_map(storage, layout, |v| v.powf(bf16::from_f64(e)));
                 Ok(Self::BF16(data))
             }
-            Self::F16(storage) => {
+            CpuStorageRef::F16(storage) => {
                 let data = unary_map(storage, layout, |v| v.powf(f16::from_f64(e)));
                 Ok(Self::F16(data))
             }
-            Self::F32(storage) => {
+            CpuStorageRef::F32(storage) => {
                 let data = unary_map(storage, layout, |v| v.powf(e as f32));
                 Ok(Self::F32(data))
             }
-            Self::F64(storage) => {
+            CpuStorageRef::F64(storage) => {
                 let data = unary_map(storage, layout, |v| v.powf(e));
                 Ok(Self::F64(data))
             }
-            Self::U8(_) => Err(Error::UnsupportedDTypeForOp(DType::U8, "elu").bt()),
-            Self::U32(_) => Err(Error::UnsupportedDTypeForOp(DType::U32, "elu").bt()),
-            Self::I64(_) => Err(Error::UnsupportedDTypeForOp(DType::I64, "elu").bt()),
+            CpuStorageRef::U8(_) => Err(Error::UnsupportedDTypeForOp(DType::U8, "elu").bt()),
+            CpuStorageRef::U32(_) => Err(Error::UnsupportedDTypeForOp(DType::U32, "elu").bt()),
+            CpuStorageRef::I64(_) => Err(Error::UnsupportedDTypeForOp(DType::I64, "elu").bt()),
         }
     }
 
     fn elu(&self, layout: &Layout, alpha: f64) -> Result<Self> {
         // TODO: Have some generic map for functions that apply on num_traits::Float elements.
-        match self {
-            Self::BF16(storage) => {
+        match self.as_storage_ref() {
+            CpuStorageRef::BF16(storage) => {
                 let data = unary_map(storage, layout, |v| elu(v, bf16::from_f64(alpha)));
                 Ok(Self::BF16(data))
             }
-            Self::F16(storage) => {
+            CpuStorageRef::F16(storage) => {
                 let data = unary_map(storage, layout, |v| elu(v, f16::from_f64(alpha)));
                 Ok(Self::F16(data))
             }
-            Self::F32(storage) => {
+            CpuStorageRef::F32(storage) => {
                 let data = unary_map(storage, layout, |v| elu(v, f32::from_f64(alpha)));
                 Ok(Self::F32(data))
             }
-            Self::F64(storage) => {
+            CpuStorageRef::F64(storage) => {
                 let data = unary_map(storage, layout, |v| elu(v, alpha));
                 Ok(Self::F64(data))
             }
-            Self::U8(_) => Err(Error::UnsupportedDTypeForOp(DType::U8, "elu").bt()),
-            Self::U32(_) => Err(Error::UnsupportedDTypeForOp(DType::U32, "elu").bt()),
-            Self::I64(_) => Err(Error::UnsupportedDTypeForOp(DType::I64, "elu").bt()),
+            CpuStorageRef::U8(_) => Err(Error::UnsupportedDTypeForOp(DType::U8, "elu").bt()),
+            CpuStorageRef::U32(_) => Err(Error::UnsupportedDTypeForOp(DType::U32, "elu").bt()),
+            CpuStorageRef::I64(_) => Err(Error::UnsupportedDTypeForOp(DType::I64, "elu").bt()),
         }
     }
 
     fn unary_impl<B: UnaryOpT>(&self, layout: &Layout) -> Result<Self> {
-        match self {
-            Self::BF16(storage) => {
+        match self.as_storage_ref() {
+            CpuStorageRef::BF16(storage) => {
                 if B::BF16_VEC {
                     let data = unary_map_vec(storage, layout, B::bf16, B::bf16_vec);
                     Ok(Self::BF16(data))
@@ -1997,7 +2082,7 @@ impl BackendStorage for CpuStorage {
                     Ok(Self::BF16(data))
                 }
             }
-            Self::F16(storage) => {
+            CpuStorageRef::F16(storage) => {
                 if B::F16_VEC {
                     let data = unary_map_vec(storage, layout, B::f16, B::f16_vec);
                     Ok(Self::F16(data))
@@ -2006,7 +2091,7 @@ impl BackendStorage for CpuStorage {
                     Ok(Self::F16(data))
                 }
             }
-            Self::F32(storage) => {
+            CpuStorageRef::F32(storage) => {
                 if B::F32_VEC {
                     let data = unary_map_vec(storage, layout, B::f32, B::f32_vec);
                     Ok(Self::F32(data))
@@ -2015,7 +2100,7 @@ impl BackendStorage for CpuStorage {
                     Ok(Self::F32(data))
                 }
             }
-            Self::F64(storage) => {
+            CpuStorageRef::F64(storage) => {
                 if B::F64_VEC {
                     let data = unary_map_vec(storage, layout, B::f64, B::f64_vec);
                     Ok(Self::F64(data))
@@ -2024,15 +2109,15 @@ impl BackendStorage for CpuStorage {
                     Ok(Self::F64(data))
                 }
             }
-            Self::U8(storage) => {
+            CpuStorageRef::U8(storage) => {
                 let data = unary_map(storage, layout, B::u8);
                 Ok(Self::U8(data))
             }
-            Self::U32(storage) => {
+            CpuStorageRef::U32(storage) => {
                 let data = unary_map(storage, layout, B::u32);
                 Ok(Self::U32(data))
             }
-            Self::I64(storage) => {
+            CpuStorageRef::I64(storage) => {
                 let data = unary_map(storage, layout, B::i64);
                 Ok(Self::I64(data))
             }
@@ -2045,8 +2130,8 @@ impl BackendStorage for CpuStorage {
         lhs_l: &Layout,
         rhs_l: &Layout,
     ) -> Result<Self> {
-        match (self, rhs) {
-            (Self::BF16(lhs), Self::BF16(rhs)) => {
+        match (self.as_storage_ref(), rhs.as_storage_ref()) {
+            (CpuStorageRef::BF16(lhs), CpuStorageRef::BF16(rhs)) => {
                 let data = if B::BF16_VEC {
                     binary_map_vec(lhs_l, rhs_l, lhs, rhs, B::bf16, B::bf16_vec)
                 } else {
@@ -2054,7 +2139,7 @@ impl BackendStorage for CpuStorage {
                 };
                 Ok(Self::BF16(data))
             }
-            (Self::F16(lhs), Self::F16(rhs)) => {
+            (CpuStorageRef::F16(lhs), CpuStorageRef::F16(rhs)) => {
                 let data = if B::F16_VEC {
                     binary_map_vec(lhs_l, rhs_l, lhs, rhs, B::f16, B::f16_vec)
                 } else {
@@ -2062,7 +2147,7 @@ impl BackendStorage for CpuStorage {
                 };
                 Ok(Self::F16(data))
             }
-            (Self::F32(lhs), Self::F32(rhs)) => {
+            (CpuStorageRef::F32(lhs), CpuStorageRef::F32(rhs)) => {
                 let data = if B::F32_VEC {
                     binary_map_vec(lhs_l, rhs_l, lhs, rhs, B::f32, B::f32_vec)
                 } else {
@@ -2070,7 +2155,7 @@ impl BackendStorage for CpuStorage {
                 };
                 Ok(Self::F32(data))
             }
-            (Self::F64(lhs), Self::F64(rhs)) => {
+            (CpuStorageRef::F64(lhs), CpuStorageRef::F64(rhs)) => {
                 let data = if B::F64_VEC {
                     binary_map_vec(lhs_l, rhs_l, lhs, rhs, B::f64, B::f64_vec)
                 } else {
@@ -2078,7 +2163,7 @@ impl BackendStorage for CpuStorage {
                 };
                 Ok(Self::F64(data))
             }
-            (Self::U32(lhs), Self::U32(rhs)) => {
+            (CpuStorageRef::U32(lhs), CpuStorageRef::U32(rhs)) => {
                 let data = if B::U32_VEC {
                     binary_map_vec(lhs_l, rhs_l, lhs, rhs, B::u32, B::u32_vec)
                 } else {
@@ -2086,7 +2171,7 @@ impl BackendStorage for CpuStorage {
                 };
                 Ok(Self::U32(data))
             }
-            (Self::I64(lhs), Self::I64(rhs)) => {
+            (CpuStorageRef::I64(lhs), CpuStorageRef::I64(rhs)) => {
                 let data = if B::I64_VEC {
                     binary_map_vec(lhs_l, rhs_l, lhs, rhs, B::i64, B::i64_vec)
                 } else {
@@ -2094,7 +2179,7 @@ impl BackendStorage for CpuStorage {
                 };
                 Ok(Self::I64(data))
             }
-            (Self::U8(lhs), Self::U8(rhs)) => {
+            (CpuStorageRef::U8(lhs), CpuStorageRef::U8(rhs)) => {
                 let data = if B::U8_VEC {
                     binary_map_vec(lhs_l, rhs_l, lhs, rhs, B::u8, B::u8_vec)
                 } else {
@@ -2124,24 +2209,27 @@ impl BackendStorage for CpuStorage {
         src_o: usize,
         dst_o: usize,
     ) -> Result<()> {
-        match (self, dst) {
-            (Self::U8(src), Self::U8(dst)) => copy2d_(src, dst, d1, d2, src_s, dst_s, src_o, dst_o),
-            (Self::U32(src), Self::U32(dst)) => {
+        dst.make_owned();
+        match (self.as_storage_ref(), dst) {
+            (CpuStorageRef::U8(src), Self::U8(dst)) => {
+                copy2d_(src, dst, d1, d2, src_s, dst_s, src_o, dst_o)
+            }
+            (CpuStorageRef::U32(src), Self::U32(dst)) => {
                 copy2d_(src, dst, d1, d2, src_s, dst_s, src_o, dst_o)
             }
-            (Self::I64(src), Self::I64(dst)) => {
+            (CpuStorageRef::I64(src), Self::I64(dst)) => {
                 copy2d_(src, dst, d1, d2, src_s, dst_s, src_o, dst_o)
             }
-            (Self::BF16(src), Self::BF16(dst)) => {
+            (CpuStorageRef::BF16(src), Self::BF16(dst)) => {
                 copy2d_(src, dst, d1, d2, src_s, dst_s, src_o, dst_o)
             }
-            (Self::F16(src), Self::F16(dst)) => {
+            (CpuStorageRef::F16(src), Self::F16(dst)) => {
                 copy2d_(src, dst, d1, d2, src_s, dst_s, src_o, dst_o)
             }
-            (Self::F32(src), Self::F32(dst)) => {
+            (CpuStorageRef::F32(src), Self::F32(dst)) => {
                 copy2d_(src, dst, d1, d2, src_s, dst_s, src_o, dst_o)
             }
-            (Self::F64(src), Self::F64(dst)) => {
+            (CpuStorageRef::F64(src), Self::F64(dst)) => {
                 copy2d_(src, dst, d1, d2, src_s, dst_s, src_o, dst_o)
             }
             (_, dst) => {
@@ -2157,14 +2245,29 @@ impl BackendStorage for CpuStorage {
     }
 
     fn copy_strided_src(&self, dst: &mut Self, dst_offset: usize, src_l: &Layout) -> Result<()> {
-        match (self, dst) {
-            (Self::U8(src), Self::U8(dst)) => copy_strided_src_(src, dst, dst_offset, src_l),
-            (Self::U32(src), Self::U32(dst)) => copy_strided_src_(src, dst, dst_offset, src_l),
-            (Self::I64(src), Self::I64(dst)) => copy_strided_src_(src, dst, dst_offset, src_l),
-            (Self::BF16(src), Self::BF16(dst)) => copy_strided_src_(src, dst, dst_offset, src_l),
-            (Self::F16(src), Self::F16(dst)) => copy_strided_src_(src, dst, dst_offset, src_l),
-            (Self::F32(src), Self::F32(dst)) => copy_strided_src_(src, dst, dst_offset, src_l),
-            (Self::F64(src), Self::F64(dst)) => copy_strided_src_(src, dst, dst_offset, src_l),
+        dst.make_owned();
+        match (self.as_storage_ref(), dst) {
+            (CpuStorageRef::U8(src), Self::U8(dst)) => {
+                copy_strided_src_(src, dst, dst_offset, src_l)
+            }
+            (CpuStorageRef::U32(src), Self::U32(dst)) => {
+                copy_strided_src_(src, dst, dst_offset, src_l)
+            }
+            (CpuStorageRef::I64(src), Self::I64(dst)) => {
+                copy_strided_src_(src, dst, dst_offset, src_l)
+            }
+            (CpuStorageRef::BF16(src), Self::BF16(dst)) => {
+                copy_strided_src_(src, dst, dst_offset, src_l)
+            }
+            (CpuStorageRef::F16(src), Self::F16(dst)) => {
+                copy_strided_src_(src, dst, dst_offset, src_l)
+            }
+            (CpuStorageRef::F32(src), Self::F32(dst)) => {
+                copy_strided_src_(src, dst, dst_offset, src_l)
+            }
+            (CpuStorageRef::F64(src), Self::F64(dst)) => {
+                copy_strided_src_(src, dst, dst_offset, src_l)
+            }
             (_, dst) => {
                 // This should be covered by the dtype check above.
                 return Err(Error::DTypeMismatchBinaryOp {
@@ -2186,10 +2289,10 @@ impl BackendStorage for CpuStorage {
         f: &Self,
         f_l: &Layout,
     ) -> Result<Self> {
-        match self {
-            Self::U8(pred) => WCond(pred, layout).map(t, t_l, f, f_l),
-            Self::U32(pred) => WCond(pred, layout).map(t, t_l, f, f_l),
-            Self::I64(pred) => WCond(pred, layout).map(t, t_l, f, f_l),
+        match self.as_storage_ref() {
+            CpuStorageRef::U8(pred) => WCond(pred, layout).map(t, t_l, f, f_l),
+            CpuStorageRef::U32(pred) => WCond(pred, layout).map(t, t_l, f, f_l),
+            CpuStorageRef::I64(pred) => WCond(pred, layout).map(t, t_l, f, f_l),
             _ => Err(Error::UnsupportedDTypeForOp(self.dtype(), "where-cond")),
         }
     }
@@ -2355,19 +2458,19 @@ impl BackendStorage for CpuStorage {
     }
 
     fn index_select(&self, ids: &Self, l: &Layout, ids_l: &Layout, dim: usize) -> Result<Self> {
-        match ids {
-            Self::U8(ids) => IndexSelect { ids, ids_l, dim }.map(self, l),
-            Self::U32(ids) => IndexSelect { ids, ids_l, dim }.map(self, l),
-            Self::I64(ids) => IndexSelect { ids, ids_l, dim }.map(self, l),
+        match ids.as_storage_ref() {
+            CpuStorageRef::U8(ids) => IndexSelect { ids, ids_l, dim }.map(self, l),
+            CpuStorageRef::U32(ids) => IndexSelect { ids, ids_l, dim }.map(self, l),
+            CpuStorageRef::I64(ids) => IndexSelect { ids, ids_l, dim }.map(self, l),
             _ => Err(Error::UnsupportedDTypeForOp(self.dtype(), "index-select").bt()),
         }
     }
 
     fn gather(&self, l: &Layout, ids: &Self, ids_l: &Layout, dim: usize) -> Result<Self> {
-        match ids {
-            Self::U8(ids) => Gather { ids, ids_l, dim }.map(self, l),
-            Self::U32(ids) => Gather { ids, ids_l, dim }.map(self, l),
-            Self::I64(ids) => Gather { ids, ids_l, dim }.map(self, l),
+        match ids.as_storage_ref() {
+            CpuStorageRef::U8(ids) => Gather { ids, ids_l, dim }.map(self, l),
+            CpuStorageRef::U32(ids) => Gather { ids, ids_l, dim }.map(self, l),
+            CpuStorageRef::I64(ids) => Gather { ids, ids_l, dim }.map(self, l),
             _ => Err(Error::UnsupportedDTypeForOp(self.dtype(), "gather").bt()),
         }
     }
@@ -2381,10 +2484,10 @@ impl BackendStorage for CpuStorage {
         src_l: &Layout,
         dim: usize,
     ) -> Result<Self> {
-        match ids {
-            Self::U8(ids) => ScatterAdd { ids, ids_l, dim }.map(self, l, src, src_l),
-            Self::U32(ids) => ScatterAdd { ids, ids_l, dim }.map(self, l, src, src_l),
-            Self::I64(ids) => ScatterAdd { ids, ids_l, dim }.map(self, l, src, src_l),
+        match ids.as_storage_ref() {
+            CpuStorageRef::U8(ids) => ScatterAdd { ids, ids_l, dim }.map(self, l, src, src_l),
+            CpuStorageRef::U32(ids) => ScatterAdd { ids, ids_l, dim }.map(self, l, src, src_l),
+            CpuStorageRef::I64(ids) => ScatterAdd { ids, ids_l, dim }.map(self, l, src, src_l),
             _ => Err(Error::UnsupportedDTypeForOp(self.dtype(), "scatter-add").bt()),
         }
     }
@@ -2398,22 +2501,22 @@ impl BackendStorage for CpuStorage {
         src_l: &Layout,
         dim: usize,
     ) -> Result<Self> {
-        match ids {
-            Self::U8(ids) => {
+        match ids.as_storage_ref() {
+            CpuStorageRef::U8(ids) => {
                 let ids = match ids_l.contiguous_offsets() {
                     Some((a, b)) => &ids[a..b],
                     None => Err(Error::RequiresContiguous { op: "index-add" }.bt())?,
                 };
                 IndexAdd { ids, dim }.map(self, l, src, src_l)
             }
-            Self::U32(ids) => {
+            CpuStorageRef::U32(ids) => {
                 let ids = match ids_l.contiguous_offsets() {
                     Some((a, b)) => &ids[a..b],
                     None => Err(Error::RequiresContiguous { op: "index-add" }.bt())?,
                 };
                 IndexAdd { ids, dim }.map(self, l, src, src_l)
             }
-            Self::I64(ids) => {
+            CpuStorageRef::I64(ids) => {
                 let ids = match ids_l.contiguous_offsets() {
                     Some((a, b)) => &ids[a..b],
                     None => Err(Error::RequiresContiguous { op: "index-add" }.bt())?,
@@ -2443,10 +2546,32 @@ impl BackendStorage for CpuStorage {
     }
 
     fn to_cpu_storage(&self) -> Result<CpuStorage> {
-        Ok(self.clone())
+        Ok(self.owned_data().into_owned())
     }
 }
 
+const UNSEEDED_RNG: &str = "the random number generator has not been seeded";
+
+// The resident set size of the process and its peak value, in bytes.
+#[cfg(target_os = "linux")]
+fn resident_set_size() -> Result<(usize, usize)> {
+    let status = std::fs::read_to_string("/proc/self/status")?;
+    let field = |name: &str| -> Result<usize> {
+        let value = status
+            .lines()
+            .find_map(|line| line.strip_prefix(name))
+            .ok_or_else(|| Error::Msg(format!("no {name} in /proc/self/status")))?;
+        let kb: usize = value.trim().trim_end_matches("kB").trim().parse()?;
+        Ok(kb * 1024)
+    };
+    Ok((field("VmRSS:")?, field("VmHWM:")?))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn resident_set_size() -> Result<(usize, usize)> {
+    crate::bail!("memory stats are only supported on linux for the cpu device")
+}
+
 impl BackendDevice for CpuDevice {
     type Storage = CpuStorage;
 
@@ -2474,101 +2599,55 @@ impl BackendDevice for CpuDevice {
         Ok(Self)
     }
 
-    fn set_seed(&self, _seed: u64) -> Result<()> {
-        crate::bail!("cannot seed the CPU rng with set_seed")
+    fn set_seed(&self, seed: u64) -> Result<()> {
+        self.set_rng_state(crate::rng::RngState::new(seed))
     }
 
-    fn rand_uniform(&self, shape: &Shape, dtype: DType, min: f64, max: f64) -> Result<CpuStorage> {
-        use rand::prelude::*;
+    fn rng_state(&self) -> Result<crate::rng::RngState> {
+        Ok(crate::rng::with_cpu_rng(|state, _| *state))
+    }
 
-        let elem_count = shape.elem_count();
-        let mut rng = rand::thread_rng();
-        match dtype {
-            DType::U8 | DType::U32 | DType::I64 => {
-                Err(Error::UnsupportedDTypeForOp(dtype, "rand_uniform").bt())
-            }
-            DType::BF16 => {
-                let mut data = Vec::with_capacity(elem_count);
-                let uniform =
-                    rand::distributions::Uniform::new(bf16::from_f64(min), bf16::from_f64(max));
-                for _i in 0..elem_count {
-                    data.push(rng.sample::<bf16, _>(uniform))
-                }
-                Ok(CpuStorage::BF16(data))
-            }
-            DType::F16 => {
-                let mut data = Vec::with_capacity(elem_count);
-                let uniform =
-                    rand::distributions::Uniform::new(f16::from_f64(min), f16::from_f64(max));
-                for _i in 0..elem_count {
-                    data.push(rng.sample::<f16, _>(uniform))
-                }
-                Ok(CpuStorage::F16(data))
-            }
-            DType::F32 => {
-                let mut data = Vec::with_capacity(elem_count);
-                let uniform = rand::distributions::Uniform::new(min as f32, max as f32);
-                for _i in 0..elem_count {
-                    data.push(rng.sample::<f32, _>(uniform))
-                }
-                Ok(CpuStorage::F32(data))
-            }
-            DType::F64 => {
-                let mut data = Vec::with_capacity(elem_count);
-                let uniform = rand::distributions::Uniform::new(min, max);
-                for _i in 0..elem_count {
-                    data.push(rng.sample::<f64, _>(uniform))
-                }
-                Ok(CpuStorage::F64(data))
-            }
+    fn memory_stats(&self) -> Result<crate::MemoryStats> {
+        let (rss, peak_rss) = resident_set_size()?;
+        Ok(crate::MemoryStats {
+            allocated: rss,
+            reserved: rss,
+            peak_allocated: peak_rss,
+            peak_reserved: peak_rss,
+        })
+    }
+
+    fn reset_peak_memory_stats(&self) -> Result<()> {
+        if cfg!(target_os = "linux") {
+            // Writing 5 to clear_refs resets the peak resident set size of the process.
+            std::fs::write("/proc/self/clear_refs", "5")?;
+            Ok(())
+        } else {
+            crate::bail!("memory stats are only supported on linux for the cpu device")
         }
     }
 
-    fn rand_normal(&self, shape: &Shape, dtype: DType, mean: f64, std: f64) -> Result<CpuStorage> {
-        use rand::prelude::*;
+    fn set_rng_state(&self, state: crate::rng::RngState) -> Result<()> {
+        crate::rng::set_cpu_rng_state(state);
+        Ok(())
+    }
 
-        let elem_count = shape.elem_count();
-        let mut rng = rand::thread_rng();
-        match dtype {
-            DType::U8 | DType::U32 | DType::I64 => {
-                Err(Error::UnsupportedDTypeForOp(dtype, "rand_normal").bt())
-            }
-            DType::BF16 => {
-                let mut data = Vec::with_capacity(elem_count);
-                let normal = rand_distr::Normal::new(bf16::from_f64(mean), bf16::from_f64(std))
-                    .map_err(Error::wrap)?;
-                for _i in 0..elem_count {
-                    data.push(normal.sample(&mut rng))
-                }
-                Ok(CpuStorage::BF16(data))
-            }
-            DType::F16 => {
-                let mut data = Vec::with_capacity(elem_count);
-                let normal = rand_distr::Normal::new(f16::from_f64(mean), f16::from_f64(std))
-                    .map_err(Error::wrap)?;
-                for _i in 0..elem_count {
-                    data.push(normal.sample(&mut rng))
-                }
-                Ok(CpuStorage::F16(data))
-            }
-            DType::F32 => {
-                let mut data = Vec::with_capacity(elem_count);
-                let normal =
-                    rand_distr::Normal::new(mean as f32, std as f32).map_err(Error::wrap)?;
-                for _i in 0..elem_count {
-                    data.push(normal.sample(&mut rng))
-                }
-                Ok(CpuStorage::F32(data))
+    fn rand_uniform(&self, shape: &Shape, dtype: DType, min: f64, max: f64) -> Result<CpuStorage> {
+        crate::rng::with_cpu_rng(|state, seeded| {
+            if !seeded {
+                crate::determinism::ensure("rand_uniform", self.location(), UNSEEDED_RNG)?;
             }
-            DType::F64 => {
-                let mut data = Vec::with_capacity(elem_count);
-                let normal = rand_distr::Normal::new(mean, std).map_err(Error::wrap)?;
-                for _i in 0..elem_count {
-                    data.push(normal.sample(&mut rng))
-                }
-                Ok(CpuStorage::F64(data))
+            crate::rng::rand_uniform(state, shape, dtype, min, max)
+        })
+    }
+
+    fn rand_normal(&self, shape: &Shape, dtype: DType, mean: f64, std: f64) -> Result<CpuStorage> {
+        crate::rng::with_cpu_rng(|state, seeded| {
+            if !seeded {
+                crate::determinism::ensure("rand_normal", self.location(), UNSEEDED_RNG)?;
             }
-        }
+            crate::rng::rand_normal(state, shape, dtype, mean, std)
+        })
     }
 
     #[allow(clippy::uninit_vec)]
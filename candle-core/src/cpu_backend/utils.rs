@@ -3,18 +3,19 @@ use crate::backend::BackendStorage;
 use crate::{Error, Layout, Result, WithDType};
 
 type C = super::CpuStorage;
+type R<'a> = super::CpuStorageRef<'a>;
 pub trait Map1 {
     fn f<T: WithDType>(&self, vs: &[T], layout: &Layout) -> Result<Vec<T>>;
 
     fn map(&self, vs: &C, layout: &Layout) -> Result<C> {
-        match vs {
-            C::U8(vs) => Ok(C::U8(self.f(vs, layout)?)),
-            C::U32(vs) => Ok(C::U32(self.f(vs, layout)?)),
-            C::I64(vs) => Ok(C::I64(self.f(vs, layout)?)),
-            C::BF16(vs) => Ok(C::BF16(self.f(vs, layout)?)),
-            C::F16(vs) => Ok(C::F16(self.f(vs, layout)?)),
-            C::F32(vs) => Ok(C::F32(self.f(vs, layout)?)),
-            C::F64(vs) => Ok(C::F64(self.f(vs, layout)?)),
+        match vs.as_storage_ref() {
+            R::U8(vs) => Ok(C::U8(self.f(vs, layout)?)),
+            R::U32(vs) => Ok(C::U32(self.f(vs, layout)?)),
+            R::I64(vs) => Ok(C::I64(self.f(vs, layout)?)),
+            R::BF16(vs) => Ok(C::BF16(self.f(vs, layout)?)),
+            R::F16(vs) => Ok(C::F16(self.f(vs, layout)?)),
+            R::F32(vs) => Ok(C::F32(self.f(vs, layout)?)),
+            R::F64(vs) => Ok(C::F64(self.f(vs, layout)?)),
         }
     }
 }
@@ -23,14 +24,14 @@ pub trait Map1Any {
     fn f<T: WithDType, W: Fn(Vec<T>) -> C>(&self, vs: &[T], layout: &Layout, wrap: W) -> Result<C>;
 
     fn map(&self, vs: &C, layout: &Layout) -> Result<C> {
-        match vs {
-            C::U8(vs) => Ok(self.f(vs, layout, C::U8)?),
-            C::U32(vs) => Ok(self.f(vs, layout, C::U32)?),
-            C::I64(vs) => Ok(self.f(vs, layout, C::I64)?),
-            C::BF16(vs) => Ok(self.f(vs, layout, C::BF16)?),
-            C::F16(vs) => Ok(self.f(vs, layout, C::F16)?),
-            C::F32(vs) => Ok(self.f(vs, layout, C::F32)?),
-            C::F64(vs) => Ok(self.f(vs, layout, C::F64)?),
+        match vs.as_storage_ref() {
+            R::U8(vs) => Ok(self.f(vs, layout, C::U8)?),
+            R::U32(vs) => Ok(self.f(vs, layout, C::U32)?),
+            R::I64(vs) => Ok(self.f(vs, layout, C::I64)?),
+            R::BF16(vs) => Ok(self.f(vs, layout, C::BF16)?),
+            R::F16(vs) => Ok(self.f(vs, layout, C::F16)?),
+            R::F32(vs) => Ok(self.f(vs, layout, C::F32)?),
+            R::F64(vs) => Ok(self.f(vs, layout, C::F64)?),
         }
     }
 }
@@ -40,14 +41,14 @@ pub trait Map2 {
     fn f<T: WithDType>(&self, v1: &[T], l1: &Layout, v2: &[T], l2: &Layout) -> Result<Vec<T>>;
 
     fn map(&self, v1: &C, l1: &Layout, v2: &C, l2: &Layout) -> Result<C> {
-        match (v1, v2) {
-            (C::U8(v1), C::U8(v2)) => Ok(C::U8(self.f(v1, l1, v2, l2)?)),
-            (C::U32(v1), C::U32(v2)) => Ok(C::U32(self.f(v1, l1, v2, l2)?)),
-            (C::I64(v1), C::I64(v2)) => Ok(C::I64(self.f(v1, l1, v2, l2)?)),
-            (C::BF16(v1), C::BF16(v2)) => Ok(C::BF16(self.f(v1, l1, v2, l2)?)),
-            (C::F16(v1), C::F16(v2)) => Ok(C::F16(self.f(v1, l1, v2, l2)?)),
-            (C::F32(v1), C::F32(v2)) => Ok(C::F32(self.f(v1, l1, v2, l2)?)),
-            (C::F64(v1), C::F64(v2)) => Ok(C::F64(self.f(v1, l1, v2, l2)?)),
+        match (v1.as_storage_ref(), v2.as_storage_ref()) {
+            (R::U8(v1), R::U8(v2)) => Ok(C::U8(self.f(v1, l1, v2, l2)?)),
+            (R::U32(v1), R::U32(v2)) => Ok(C::U32(self.f(v1, l1, v2, l2)?)),
+            (R::I64(v1), R::I64(v2)) => Ok(C::I64(self.f(v1, l1, v2, l2)?)),
+            (R::BF16(v1), R::BF16(v2)) => Ok(C::BF16(self.f(v1, l1, v2, l2)?)),
+            (R::F16(v1), R::F16(v2)) => Ok(C::F16(self.f(v1, l1, v2, l2)?)),
+            (R::F32(v1), R::F32(v2)) => Ok(C::F32(self.f(v1, l1, v2, l2)?)),
+            (R::F64(v1), R::F64(v2)) => Ok(C::F64(self.f(v1, l1, v2, l2)?)),
             _ => Err(Error::DTypeMismatchBinaryOp {
                 lhs: v1.dtype(),
                 rhs: v2.dtype(),
@@ -63,14 +64,14 @@ pub trait Map2U8 {
     fn f<T: WithDType>(&self, v1: &[T], l1: &Layout, v2: &[T], l2: &Layout) -> Result<Vec<u8>>;
 
     fn map(&self, v1: &C, l1: &Layout, v2: &C, l2: &Layout) -> Result<C> {
-        match (v1, v2) {
-            (C::U8(v1), C::U8(v2)) => Ok(C::U8(self.f(v1, l1, v2, l2)?)),
-            (C::U32(v1), C::U32(v2)) => Ok(C::U8(self.f(v1, l1, v2, l2)?)),
-            (C::I64(v1), C::I64(v2)) => Ok(C::U8(self.f(v1, l1, v2, l2)?)),
-            (C::BF16(v1), C::BF16(v2)) => Ok(C::U8(self.f(v1, l1, v2, l2)?)),
-            (C::F16(v1), C::F16(v2)) => Ok(C::U8(self.f(v1, l1, v2, l2)?)),
-            (C::F32(v1), C::F32(v2)) => Ok(C::U8(self.f(v1, l1, v2, l2)?)),
-            (C::F64(v1), C::F64(v2)) => Ok(C::U8(self.f(v1, l1, v2, l2)?)),
+        match (v1.as_storage_ref(), v2.as_storage_ref()) {
+            (R::U8(v1), R::U8(v2)) => Ok(C::U8(self.f(v1, l1, v2, l2)?)),
+            (R::U32(v1), R::U32(v2)) => Ok(C::U8(self.f(v1, l1, v2, l2)?)),
+            (R::I64(v1), R::I64(v2)) => Ok(C::U8(self.f(v1, l1, v2, l2)?)),
+            (R::BF16(v1), R::BF16(v2)) => Ok(C::U8(self.f(v1, l1, v2, l2)?)),
+            (R::F16(v1), R::F16(v2)) => Ok(C::U8(self.f(v1, l1, v2, l2)?)),
+            (R::F32(v1), R::F32(v2)) => Ok(C::U8(self.f(v1, l1, v2, l2)?)),
+            (R::F64(v1), R::F64(v2)) => Ok(C::U8(self.f(v1, l1, v2, l2)?)),
             _ => Err(Error::DTypeMismatchBinaryOp {
                 lhs: v1.dtype(),
                 rhs: v2.dtype(),
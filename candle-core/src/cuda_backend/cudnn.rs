@@ -95,6 +95,10 @@ pub(crate) fn launch_conv2d<
         y: &y,
     };
     let alg = match params.cudnn_fwd_algo {
+        // Use a fixed algorithm in deterministic mode rather than relying on the heuristics.
+        None if crate::determinism::is_deterministic() => {
+            A::CUDNN_CONVOLUTION_FWD_ALGO_IMPLICIT_GEMM
+        }
         None => conv2d.pick_algorithm()?,
         Some(CandleAlgo::ImplicitGemm) => A::CUDNN_CONVOLUTION_FWD_ALGO_IMPLICIT_GEMM,
         Some(CandleAlgo::ImplicitPrecompGemm) => {
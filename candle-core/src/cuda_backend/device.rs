@@ -1,5 +1,6 @@
 use crate::backend::BackendDevice;
-use crate::{CpuStorage, CpuStorageRef, DType, Layout, Result, Shape};
+use crate::rng::RngState;
+use crate::{CpuStorage, CpuStorageRef, DType, Result, Shape};
 pub use candle_kernels as kernels;
 pub use cudarc;
 use cudarc::driver::{CudaFunction, LaunchAsync, LaunchConfig};
@@ -21,15 +22,13 @@ impl DeviceId {
     }
 }
 
-struct CudaRng(cudarc::curand::CudaRng);
-unsafe impl Send for CudaRng {}
-
 #[derive(Clone)]
 pub struct CudaDevice {
     id: DeviceId,
     device: Arc<cudarc::driver::CudaDevice>,
     pub(crate) blas: Arc<cudarc::cublas::CudaBlas>,
-    curand: Arc<Mutex<CudaRng>>,
+    rng: Arc<Mutex<RngState>>,
+    pub(super) transfers: Arc<Mutex<super::pinned::Transfers>>,
 }
 
 impl std::fmt::Debug for CudaDevice {
@@ -169,14 +168,114 @@ impl CudaDevice {
     pub fn new_with_stream(ordinal: usize) -> Result<Self> {
         let device = cudarc::driver::CudaDevice::new_with_stream(ordinal).w()?;
         let blas = cudarc::cublas::CudaBlas::new(device.clone()).w()?;
-        let curand = cudarc::curand::CudaRng::new(299792458, device.clone()).w()?;
         Ok(Self {
             id: DeviceId::new(),
             device,
             blas: Arc::new(blas),
-            curand: Arc::new(Mutex::new(CudaRng(curand))),
+            rng: Arc::new(Mutex::new(RngState::new(crate::rng::DEFAULT_SEED))),
+            transfers: Arc::new(Mutex::new(Default::default())),
         })
     }
+
+    // Runs one of the philox kernels, `a` and `b` are either the bounds of the uniform
+    // distribution or the mean and standard deviation of the normal one.
+    fn rand_impl(
+        &self,
+        op: &'static str,
+        shape: &Shape,
+        dtype: DType,
+        a: f64,
+        b: f64,
+    ) -> Result<CudaStorage> {
+        let elem_count = shape.elem_count();
+        let mut rng = self.rng.lock().unwrap();
+        let offset = rng.advance(elem_count);
+        let cfg = LaunchConfig::for_num_elems(elem_count.div_ceil(4).max(1) as u32);
+        let slice = match dtype {
+            // TODO: Add some f16 and bf16 kernels, these dtypes are generated as f32 and then
+            // converted in `Device::rand_uniform_f64`.
+            DType::U8 | DType::U32 | DType::I64 | DType::F16 | DType::BF16 => {
+                Err(CudaError::UnsupportedDtype { dtype, op }).w()?
+            }
+            DType::F32 => {
+                let func = self.get_or_load_func(&format!("{op}_f32"), kernels::RANDOM)?;
+                // SAFETY: Set later by running the kernel.
+                let data = unsafe { self.alloc::<f32>(elem_count) }.w()?;
+                let params = (elem_count, rng.seed, offset, a as f32, b as f32, &data);
+                // SAFETY: ffi.
+                unsafe { func.launch(cfg, params) }.w()?;
+                CudaStorageSlice::F32(data)
+            }
+            DType::F64 => {
+                let func = self.get_or_load_func(&format!("{op}_f64"), kernels::RANDOM)?;
+                // SAFETY: Set later by running the kernel.
+                let data = unsafe { self.alloc::<f64>(elem_count) }.w()?;
+                let params = (elem_count, rng.seed, offset, a, b, &data);
+                // SAFETY: ffi.
+                unsafe { func.launch(cfg, params) }.w()?;
+                CudaStorageSlice::F64(data)
+            }
+        };
+        Ok(CudaStorage {
+            slice,
+            device: self.clone(),
+        })
+    }
+}
+
+mod mem_pool {
+    use super::WrapErr;
+    use crate::Result;
+    use cudarc::driver::sys;
+    pub(super) use sys::CUmemPool_attribute as Attr;
+
+    pub(super) fn attribute(pool: sys::CUmemoryPool, attr: Attr) -> Result<usize> {
+        let mut value = 0u64;
+        let ptr = &mut value as *mut u64 as *mut std::ffi::c_void;
+        unsafe { sys::lib().cuMemPoolGetAttribute(pool, attr, ptr) }
+            .result()
+            .w()?;
+        Ok(value as usize)
+    }
+
+    // Setting the high watermark attributes to zero resets them to the current value.
+    pub(super) fn reset_attribute(pool: sys::CUmemoryPool, attr: Attr) -> Result<()> {
+        let mut value = 0u64;
+        let ptr = &mut value as *mut u64 as *mut std::ffi::c_void;
+        unsafe { sys::lib().cuMemPoolSetAttribute(pool, attr, ptr) }
+            .result()
+            .w()
+    }
+}
+
+impl CudaDevice {
+    // The pool used by the stream ordered allocator, `None` if the device does not support
+    // memory pools in which case the regular allocator is used.
+    fn default_mem_pool(&self) -> Result<Option<cudarc::driver::sys::CUmemoryPool>> {
+        use cudarc::driver::{result, sys};
+        self.device.bind_to_thread().w()?;
+        let cu_device = result::device::get(self.device.ordinal() as i32).w()?;
+        let attr = sys::CUdevice_attribute::CU_DEVICE_ATTRIBUTE_MEMORY_POOLS_SUPPORTED;
+        let supported = unsafe { result::device::get_attribute(cu_device, attr) }.w()?;
+        if supported == 0 {
+            return Ok(None);
+        }
+        let mut pool = std::ptr::null_mut();
+        unsafe { sys::lib().cuDeviceGetDefaultMemPool(&mut pool, cu_device) }
+            .result()
+            .w()?;
+        Ok(Some(pool))
+    }
+
+    /// The major and minor compute capability of the device.
+    pub(crate) fn compute_capability(&self) -> Result<(i32, i32)> {
+        use cudarc::driver::{result, sys};
+        let cu_device = result::device::get(self.device.ordinal() as i32).w()?;
+        let attr = |attr| unsafe { result::device::get_attribute(cu_device, attr) }.w();
+        let major = attr(sys::CUdevice_attribute::CU_DEVICE_ATTRIBUTE_COMPUTE_CAPABILITY_MAJOR)?;
+        let minor = attr(sys::CUdevice_attribute::CU_DEVICE_ATTRIBUTE_COMPUTE_CAPABILITY_MINOR)?;
+        Ok((major, minor))
+    }
 }
 
 impl BackendDevice for CudaDevice {
@@ -185,20 +284,58 @@ impl BackendDevice for CudaDevice {
     fn new(ordinal: usize) -> Result<Self> {
         let device = cudarc::driver::CudaDevice::new(ordinal).w()?;
         let blas = cudarc::cublas::CudaBlas::new(device.clone()).w()?;
-        let curand = cudarc::curand::CudaRng::new(299792458, device.clone()).w()?;
         Ok(Self {
             id: DeviceId::new(),
             device,
             blas: Arc::new(blas),
-            curand: Arc::new(Mutex::new(CudaRng(curand))),
+            rng: Arc::new(Mutex::new(RngState::new(crate::rng::DEFAULT_SEED))),
+            transfers: Arc::new(Mutex::new(Default::default())),
         })
     }
 
     fn set_seed(&self, seed: u64) -> Result<()> {
-        // We do not call set_seed but instead create a new curand object. This ensures that the
-        // state will be identical and the same random numbers will be generated.
-        let mut curand = self.curand.lock().unwrap();
-        curand.0 = cudarc::curand::CudaRng::new(seed, self.device.clone()).w()?;
+        self.set_rng_state(RngState::new(seed))
+    }
+
+    fn rng_state(&self) -> Result<RngState> {
+        Ok(*self.rng.lock().unwrap())
+    }
+
+    fn set_rng_state(&self, state: RngState) -> Result<()> {
+        *self.rng.lock().unwrap() = state;
+        Ok(())
+    }
+
+    fn memory_stats(&self) -> Result<crate::MemoryStats> {
+        use mem_pool::{attribute, Attr};
+        match self.default_mem_pool()? {
+            Some(pool) => Ok(crate::MemoryStats {
+                allocated: attribute(pool, Attr::CU_MEMPOOL_ATTR_USED_MEM_CURRENT)?,
+                reserved: attribute(pool, Attr::CU_MEMPOOL_ATTR_RESERVED_MEM_CURRENT)?,
+                peak_allocated: attribute(pool, Attr::CU_MEMPOOL_ATTR_USED_MEM_HIGH)?,
+                peak_reserved: attribute(pool, Attr::CU_MEMPOOL_ATTR_RESERVED_MEM_HIGH)?,
+            }),
+            None => {
+                // Without memory pools, the allocations go straight to the driver and only the
+                // current usage of the device is available.
+                let (free, total) = cudarc::driver::result::mem_get_info().w()?;
+                let used = total - free;
+                Ok(crate::MemoryStats {
+                    allocated: used,
+                    reserved: used,
+                    peak_allocated: used,
+                    peak_reserved: used,
+                })
+            }
+        }
+    }
+
+    fn reset_peak_memory_stats(&self) -> Result<()> {
+        use mem_pool::{reset_attribute, Attr};
+        if let Some(pool) = self.default_mem_pool()? {
+            reset_attribute(pool, Attr::CU_MEMPOOL_ATTR_USED_MEM_HIGH)?;
+            reset_attribute(pool, Attr::CU_MEMPOOL_ATTR_RESERVED_MEM_HIGH)?;
+        }
         Ok(())
     }
 
@@ -251,80 +388,11 @@ impl BackendDevice for CudaDevice {
     }
 
     fn rand_uniform(&self, shape: &Shape, dtype: DType, lo: f64, up: f64) -> Result<CudaStorage> {
-        let elem_count = shape.elem_count();
-        let curand = self.curand.lock().unwrap();
-        let slice = match dtype {
-            // TODO: Add support for F16 and BF16 though this is likely to require some upstream
-            // cudarc changes.
-            DType::U8 | DType::U32 | DType::I64 | DType::F16 | DType::BF16 => {
-                Err(CudaError::UnsupportedDtype {
-                    dtype,
-                    op: "rand_uniform",
-                })
-                .w()?
-            }
-            DType::F32 => {
-                let mut data = unsafe { self.alloc::<f32>(elem_count) }.w()?;
-                curand.0.fill_with_uniform(&mut data).w()?;
-                CudaStorageSlice::F32(data)
-            }
-            DType::F64 => {
-                let mut data = unsafe { self.alloc::<f64>(elem_count) }.w()?;
-                curand.0.fill_with_uniform(&mut data).w()?;
-                CudaStorageSlice::F64(data)
-            }
-        };
-        let slice = if lo == 0. && up == 1.0 {
-            slice
-        } else {
-            use super::utils::Map1;
-            let layout = Layout::contiguous(shape);
-            super::Affine(up - lo, lo).map(&slice, self, &layout)?
-        };
-        Ok(CudaStorage {
-            slice,
-            device: self.clone(),
-        })
+        self.rand_impl("rand_uniform", shape, dtype, lo, up)
     }
 
     fn rand_normal(&self, shape: &Shape, dtype: DType, mean: f64, std: f64) -> Result<CudaStorage> {
-        // TODO: Add support for F16 and BF16 though this is likely to require some upstream
-        // cudarc changes.
-        let elem_count = shape.elem_count();
-        let curand = self.curand.lock().unwrap();
-        // curand can only generate an odd number of values.
-        // https://github.com/huggingface/candle/issues/734
-        let elem_count_round = if elem_count % 2 == 1 {
-            elem_count + 1
-        } else {
-            elem_count
-        };
-        let slice = match dtype {
-            DType::U8 | DType::U32 | DType::I64 | DType::F16 | DType::BF16 => {
-                Err(CudaError::UnsupportedDtype {
-                    dtype,
-                    op: "rand_normal",
-                })
-                .w()?
-            }
-            DType::F32 => {
-                let mut data = unsafe { self.alloc::<f32>(elem_count_round) }.w()?;
-                curand
-                    .0
-                    .fill_with_normal(&mut data, mean as f32, std as f32)
-                    .w()?;
-                CudaStorageSlice::F32(data)
-            }
-            DType::F64 => {
-                let mut data = unsafe { self.alloc::<f64>(elem_count_round) }.w()?;
-                curand.0.fill_with_normal(&mut data, mean, std).w()?;
-                CudaStorageSlice::F64(data)
-            }
-        };
-        Ok(CudaStorage {
-            slice,
-            device: self.clone(),
-        })
+        self.rand_impl("rand_normal", shape, dtype, mean, std)
     }
 
     fn ones_impl(&self, shape: &Shape, dtype: DType) -> Result<CudaStorage> {
@@ -407,32 +475,32 @@ impl BackendDevice for CudaDevice {
     }
 
     fn storage_from_cpu_storage(&self, storage: &CpuStorage) -> Result<CudaStorage> {
-        let slice = match storage {
-            CpuStorage::U8(storage) => {
+        let slice = match storage.as_storage_ref() {
+            CpuStorageRef::U8(storage) => {
                 let data = self.htod_sync_copy(storage).w()?;
                 CudaStorageSlice::U8(data)
             }
-            CpuStorage::U32(storage) => {
+            CpuStorageRef::U32(storage) => {
                 let data = self.htod_sync_copy(storage).w()?;
                 CudaStorageSlice::U32(data)
             }
-            CpuStorage::I64(storage) => {
+            CpuStorageRef::I64(storage) => {
                 let data = self.htod_sync_copy(storage).w()?;
                 CudaStorageSlice::I64(data)
             }
-            CpuStorage::BF16(storage) => {
+            CpuStorageRef::BF16(storage) => {
                 let data = self.htod_sync_copy(storage).w()?;
                 CudaStorageSlice::BF16(data)
             }
-            CpuStorage::F16(storage) => {
+            CpuStorageRef::F16(storage) => {
                 let data = self.htod_sync_copy(storage).w()?;
                 CudaStorageSlice::F16(data)
             }
-            CpuStorage::F32(storage) => {
+            CpuStorageRef::F32(storage) => {
                 let data = self.htod_sync_copy(storage).w()?;
                 CudaStorageSlice::F32(data)
             }
-            CpuStorage::F64(storage) => {
+            CpuStorageRef::F64(storage) => {
                 let data = self.htod_sync_copy(storage).w()?;
                 CudaStorageSlice::F64(data)
             }
@@ -445,6 +513,7 @@ impl BackendDevice for CudaDevice {
 
     fn storage_from_cpu_storage_owned(&self, storage: CpuStorage) -> Result<CudaStorage> {
         let slice = match storage {
+            CpuStorage::Foreign(_) => return self.storage_from_cpu_storage(&storage),
             CpuStorage::U8(storage) => {
                 let data = self.htod_copy(storage).w()?;
                 CudaStorageSlice::U8(data)
@@ -482,6 +551,8 @@ impl BackendDevice for CudaDevice {
 
     fn synchronize(&self) -> Result<()> {
         self.device.synchronize().map_err(crate::Error::wrap)?;
+        let completed = self.transfers.lock().unwrap().take_all();
+        drop(completed);
         Ok(())
     }
 }
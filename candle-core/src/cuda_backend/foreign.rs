@@ -0,0 +1,130 @@
+//! Cuda buffers owned by another framework, e.g. the tensors imported with DLPack.
+//!
+//! A borrowed buffer is wrapped in a regular `CudaSlice` so that all the kernels can use it, the
+//! slice is registered here and leaked when its storage is dropped rather than being freed by
+//! cudarc. The owner of the buffer is dropped at that point.
+use super::{CudaDevice, CudaStorage, CudaStorageSlice, WrapErr};
+use crate::{DType, Result};
+use cudarc::driver::DevicePtr;
+use std::any::Any;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+
+struct Borrowed {
+    owner: Arc<dyn Any + Send + Sync>,
+    // An empty allocation that replaces the borrowed slice in the storage being dropped.
+    placeholder: CudaStorageSlice,
+}
+
+// The number of borrowed buffers, this avoids locking the registry when dropping storages if no
+// buffer has been borrowed.
+static BORROWED_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+// The borrowed buffers indexed by device pointer, the same buffer can be imported several times.
+fn borrowed() -> &'static Mutex<HashMap<u64, Vec<Borrowed>>> {
+    static BORROWED: OnceLock<Mutex<HashMap<u64, Vec<Borrowed>>>> = OnceLock::new();
+    BORROWED.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+fn slice_ptr(slice: &CudaStorageSlice) -> u64 {
+    match slice {
+        CudaStorageSlice::U8(s) => *s.device_ptr(),
+        CudaStorageSlice::U32(s) => *s.device_ptr(),
+        CudaStorageSlice::I64(s) => *s.device_ptr(),
+        CudaStorageSlice::BF16(s) => *s.device_ptr(),
+        CudaStorageSlice::F16(s) => *s.device_ptr(),
+        CudaStorageSlice::F32(s) => *s.device_ptr(),
+        CudaStorageSlice::F64(s) => *s.device_ptr(),
+    }
+}
+
+fn leak(slice: CudaStorageSlice) {
+    match slice {
+        CudaStorageSlice::U8(s) => s.leak(),
+        CudaStorageSlice::U32(s) => s.leak(),
+        CudaStorageSlice::I64(s) => s.leak(),
+        CudaStorageSlice::BF16(s) => s.leak(),
+        CudaStorageSlice::F16(s) => s.leak(),
+        CudaStorageSlice::F32(s) => s.leak(),
+        CudaStorageSlice::F64(s) => s.leak(),
+    };
+}
+
+impl CudaDevice {
+    /// Creates a storage using the `len` values of type `dtype` starting at `ptr` without copying
+    /// them. `owner` is dropped once the storage has been dropped and the kernels queued on the
+    /// device stream have completed.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` has to point to `len` values of type `dtype` allocated on this device, these have to
+    /// stay valid while `owner` is alive.
+    pub unsafe fn storage_from_foreign_ptr(
+        &self,
+        ptr: u64,
+        len: usize,
+        dtype: DType,
+        owner: Arc<dyn Any + Send + Sync>,
+    ) -> Result<CudaStorage> {
+        if len == 0 {
+            let slice = match dtype {
+                DType::U8 => CudaStorageSlice::U8(self.null().w()?),
+                DType::U32 => CudaStorageSlice::U32(self.null().w()?),
+                DType::I64 => CudaStorageSlice::I64(self.null().w()?),
+                DType::BF16 => CudaStorageSlice::BF16(self.null().w()?),
+                DType::F16 => CudaStorageSlice::F16(self.null().w()?),
+                DType::F32 => CudaStorageSlice::F32(self.null().w()?),
+                DType::F64 => CudaStorageSlice::F64(self.null().w()?),
+            };
+            return Ok(CudaStorage {
+                slice,
+                device: self.clone(),
+            });
+        }
+        // Allocated first so that no error can happen once the slice has been created.
+        let placeholder = CudaStorageSlice::U8(self.null().w()?);
+        let slice = match dtype {
+            DType::U8 => CudaStorageSlice::U8(self.upgrade_device_ptr(ptr, len)),
+            DType::U32 => CudaStorageSlice::U32(self.upgrade_device_ptr(ptr, len)),
+            DType::I64 => CudaStorageSlice::I64(self.upgrade_device_ptr(ptr, len)),
+            DType::BF16 => CudaStorageSlice::BF16(self.upgrade_device_ptr(ptr, len)),
+            DType::F16 => CudaStorageSlice::F16(self.upgrade_device_ptr(ptr, len)),
+            DType::F32 => CudaStorageSlice::F32(self.upgrade_device_ptr(ptr, len)),
+            DType::F64 => CudaStorageSlice::F64(self.upgrade_device_ptr(ptr, len)),
+        };
+        let mut borrowed = borrowed().lock().unwrap();
+        borrowed
+            .entry(ptr)
+            .or_default()
+            .push(Borrowed { owner, placeholder });
+        BORROWED_COUNT.fetch_add(1, Ordering::AcqRel);
+        Ok(CudaStorage {
+            slice,
+            device: self.clone(),
+        })
+    }
+}
+
+/// Leaks the slice of `storage` if it is a borrowed buffer and drops its owner.
+pub(super) fn release(storage: &mut CudaStorage) {
+    if BORROWED_COUNT.load(Ordering::Acquire) == 0 {
+        return;
+    }
+    let ptr = slice_ptr(&storage.slice);
+    let entry = {
+        let mut borrowed = borrowed().lock().unwrap();
+        let entry = borrowed.get_mut(&ptr).and_then(|v| v.pop());
+        if borrowed.get(&ptr).is_some_and(|v| v.is_empty()) {
+            borrowed.remove(&ptr);
+        }
+        entry
+    };
+    if let Some(Borrowed { owner, placeholder }) = entry {
+        BORROWED_COUNT.fetch_sub(1, Ordering::AcqRel);
+        leak(std::mem::replace(&mut storage.slice, placeholder));
+        // The buffer may still be read by queued kernels, the owner is not stream ordered.
+        let _ = storage.device.synchronize();
+        drop(owner)
+    }
+}
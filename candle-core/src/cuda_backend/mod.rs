@@ -15,6 +15,8 @@ use half::{bf16, f16};
 pub mod cudnn;
 mod device;
 mod error;
+mod foreign;
+mod pinned;
 mod utils;
 pub use device::{CudaDevice, DeviceId};
 pub use error::{CudaError, WrapErr};
@@ -999,6 +1001,12 @@ pub struct CudaStorage {
     pub device: CudaDevice,
 }
 
+impl Drop for CudaStorage {
+    fn drop(&mut self) {
+        foreign::release(self)
+    }
+}
+
 pub trait CudaDType: Sized {
     fn as_cuda_slice(s: &CudaStorage) -> Result<&CudaSlice<Self>>;
     fn wrap_cuda_slice(s: CudaSlice<Self>, dev: CudaDevice) -> CudaStorage;
@@ -0,0 +1,293 @@
+//! Page-locked host memory and asynchronous copies between the host and the devices.
+use super::{CudaDevice, CudaStorage, CudaStorageSlice, WrapErr};
+use crate::cpu_backend::ForeignStorage;
+use crate::{CpuStorage, CpuStorageRef, DType, Result};
+use cudarc::driver::{result, sys, CudaSlice, CudaStream, DevicePtr, DeviceRepr, DeviceSlice};
+use std::any::Any;
+use std::ffi::c_void;
+use std::sync::{Arc, Mutex};
+
+/// Host memory registered as page-locked with the cuda driver, it gets unregistered on drop.
+struct PinnedHostMemory {
+    ptr: usize,
+}
+
+impl Drop for PinnedHostMemory {
+    fn drop(&mut self) {
+        // The memory may already have been unregistered if the cuda context is gone, the error
+        // can be safely ignored in that case.
+        let _ = unsafe { sys::lib().cuMemHostUnregister(self.ptr as *mut c_void) };
+    }
+}
+
+/// Host memory allocated as page-locked by the cuda driver.
+struct PinnedBuffer {
+    ptr: usize,
+    bytes: usize,
+}
+
+impl Drop for PinnedBuffer {
+    fn drop(&mut self) {
+        // As for the registered memory, the error can be ignored if the context is gone.
+        let _ = unsafe { sys::lib().cuMemFreeHost(self.ptr as *mut c_void) };
+    }
+}
+
+/// The page-locked buffers that are not used, these are reused by the later copies to the host
+/// rather than being freed as allocating page-locked memory is expensive.
+#[derive(Default)]
+struct HostPool {
+    free: Vec<PinnedBuffer>,
+}
+
+impl HostPool {
+    // The smallest free buffer that holds `bytes` bytes without wasting more than half of it.
+    fn take(&mut self, bytes: usize) -> Option<PinnedBuffer> {
+        let position = self
+            .free
+            .iter()
+            .enumerate()
+            .filter(|(_, b)| b.bytes >= bytes && b.bytes / 2 <= bytes)
+            .min_by_key(|(_, b)| b.bytes)
+            .map(|(i, _)| i)?;
+        Some(self.free.swap_remove(position))
+    }
+}
+
+/// A buffer of the pool, it goes back to the pool when dropped.
+struct PooledBuffer {
+    buffer: Option<PinnedBuffer>,
+    pool: Arc<Mutex<HostPool>>,
+}
+
+impl Drop for PooledBuffer {
+    fn drop(&mut self) {
+        if let Some(buffer) = self.buffer.take() {
+            self.pool.lock().unwrap().free.push(buffer)
+        }
+    }
+}
+
+struct Event(sys::CUevent);
+
+// SAFETY: cuda events can be used from any thread.
+unsafe impl Send for Event {}
+
+impl Drop for Event {
+    fn drop(&mut self) {
+        let _ = unsafe { result::event::destroy(self.0) };
+    }
+}
+
+type Pending = (Event, Box<dyn Any + Send + Sync>);
+
+/// The state of the asynchronous copies of a device: the stream they run on, the data that has
+/// to be kept alive until they complete and the page-locked buffers used for the copies to the
+/// host.
+#[derive(Default)]
+pub(super) struct Transfers {
+    stream: Option<CudaStream>,
+    pending: Vec<Pending>,
+    pool: Arc<Mutex<HostPool>>,
+}
+
+// SAFETY: the stream is only used while holding the lock on the transfers.
+unsafe impl Send for Transfers {}
+
+impl Transfers {
+    /// Removes the copies that have completed, the data they kept alive has to be dropped after
+    /// releasing the lock as dropping a device storage can synchronize the device.
+    fn take_completed(&mut self) -> Vec<Pending> {
+        let (completed, pending) =
+            std::mem::take(&mut self.pending)
+                .into_iter()
+                .partition(|(event, _)| {
+                    let status = unsafe { sys::lib().cuEventQuery(event.0) };
+                    status != sys::CUresult::CUDA_ERROR_NOT_READY
+                });
+        self.pending = pending;
+        completed
+    }
+
+    /// Removes all the copies and returns the data they kept alive, see `take_completed`.
+    pub(super) fn take_all(&mut self) -> Vec<Box<dyn Any + Send + Sync>> {
+        let pending = std::mem::take(&mut self.pending);
+        pending
+            .into_iter()
+            .map(|(_, keep_alive)| keep_alive)
+            .collect()
+    }
+
+    // The stream used for the copies, created on first use.
+    fn stream(&mut self, device: &CudaDevice) -> Result<&CudaStream> {
+        if self.stream.is_none() {
+            self.stream = Some(device.fork_default_stream().w()?);
+        }
+        Ok(self.stream.as_ref().unwrap())
+    }
+
+    fn record(&mut self, keep_alive: Box<dyn Any + Send + Sync>) -> Result<()> {
+        let stream = self.stream.as_ref().expect("no transfer stream");
+        let event = result::event::create(sys::CUevent_flags::CU_EVENT_DISABLE_TIMING).w()?;
+        let event = Event(event);
+        unsafe { result::event::record(event.0, stream.stream) }.w()?;
+        self.pending.push((event, keep_alive));
+        Ok(())
+    }
+}
+
+fn slice_ptr(slice: &CudaStorageSlice) -> (u64, usize, DType) {
+    fn ptr<T>(s: &CudaSlice<T>, dtype: DType) -> (u64, usize, DType) {
+        (*s.device_ptr(), s.len(), dtype)
+    }
+    match slice {
+        CudaStorageSlice::U8(s) => ptr(s, DType::U8),
+        CudaStorageSlice::U32(s) => ptr(s, DType::U32),
+        CudaStorageSlice::I64(s) => ptr(s, DType::I64),
+        CudaStorageSlice::BF16(s) => ptr(s, DType::BF16),
+        CudaStorageSlice::F16(s) => ptr(s, DType::F16),
+        CudaStorageSlice::F32(s) => ptr(s, DType::F32),
+        CudaStorageSlice::F64(s) => ptr(s, DType::F64),
+    }
+}
+
+fn cpu_storage_ptr(storage: &CpuStorage) -> (*const c_void, usize) {
+    fn ptr<T>(data: &[T]) -> (*const c_void, usize) {
+        (data.as_ptr() as *const c_void, std::mem::size_of_val(data))
+    }
+    match storage.as_storage_ref() {
+        CpuStorageRef::U8(data) => ptr(data),
+        CpuStorageRef::U32(data) => ptr(data),
+        CpuStorageRef::I64(data) => ptr(data),
+        CpuStorageRef::BF16(data) => ptr(data),
+        CpuStorageRef::F16(data) => ptr(data),
+        CpuStorageRef::F32(data) => ptr(data),
+        CpuStorageRef::F64(data) => ptr(data),
+    }
+}
+
+fn copy_async<T: DeviceRepr>(
+    device: &CudaDevice,
+    src: &[T],
+    stream: &CudaStream,
+) -> Result<CudaSlice<T>> {
+    // SAFETY: Set later by the copy.
+    let dst = unsafe { device.alloc::<T>(src.len()) }.w()?;
+    unsafe { result::memcpy_htod_async(*dst.device_ptr(), src, stream.stream) }.w()?;
+    Ok(dst)
+}
+
+impl CudaDevice {
+    /// Registers the memory of `storage` as page-locked, the returned guard unregisters it when
+    /// dropped. The memory is registered as portable so that it can be used with all the
+    /// devices.
+    pub fn pin_host_memory(&self, storage: &CpuStorage) -> Result<Box<dyn Any + Send + Sync>> {
+        let (ptr, bytes) = cpu_storage_ptr(storage);
+        if bytes == 0 {
+            return Ok(Box::new(()));
+        }
+        self.bind_to_thread().w()?;
+        let flags = sys::CU_MEMHOSTREGISTER_PORTABLE;
+        unsafe { sys::lib().cuMemHostRegister_v2(ptr as *mut c_void, bytes, flags) }
+            .result()
+            .w()?;
+        Ok(Box::new(PinnedHostMemory { ptr: ptr as usize }))
+    }
+
+    /// Copies page-locked host memory to the device without blocking the host.
+    ///
+    /// The copy runs on a dedicated stream so that it overlaps with the kernels already queued
+    /// on the device stream, the kernels queued after this call wait for the copy to complete.
+    /// `keep_alive` holds the host memory, it is dropped once the copy has completed.
+    pub fn storage_from_pinned(
+        &self,
+        storage: &CpuStorage,
+        keep_alive: Box<dyn Any + Send + Sync>,
+    ) -> Result<CudaStorage> {
+        let mut transfers = self.transfers.lock().unwrap();
+        let completed = transfers.take_completed();
+        let stream = transfers.stream(self)?;
+        // The destination is allocated on the device stream.
+        stream.wait_for_default().w()?;
+        let slice = match storage.as_storage_ref() {
+            CpuStorageRef::U8(data) => CudaStorageSlice::U8(copy_async(self, data, stream)?),
+            CpuStorageRef::U32(data) => CudaStorageSlice::U32(copy_async(self, data, stream)?),
+            CpuStorageRef::I64(data) => CudaStorageSlice::I64(copy_async(self, data, stream)?),
+            CpuStorageRef::BF16(data) => CudaStorageSlice::BF16(copy_async(self, data, stream)?),
+            CpuStorageRef::F16(data) => CudaStorageSlice::F16(copy_async(self, data, stream)?),
+            CpuStorageRef::F32(data) => CudaStorageSlice::F32(copy_async(self, data, stream)?),
+            CpuStorageRef::F64(data) => CudaStorageSlice::F64(copy_async(self, data, stream)?),
+        };
+        self.wait_for(stream).w()?;
+        transfers.record(keep_alive)?;
+        drop(transfers);
+        drop(completed);
+        Ok(CudaStorage {
+            slice,
+            device: self.clone(),
+        })
+    }
+
+    /// Copies `storage` to page-locked host memory without blocking the host.
+    ///
+    /// The copy runs on the stream of [`CudaDevice::storage_from_pinned`] once the kernels
+    /// already queued on the device stream have completed, these kernels are not delayed. The
+    /// returned storage must not be read before the copy has completed, e.g. by calling
+    /// `Device::synchronize`, it can be copied back with [`CudaDevice::storage_from_pinned`]
+    /// which is ordered after the copy. `keep_alive` has to hold the device memory of `storage`,
+    /// it is dropped once the copy has completed.
+    ///
+    /// The host buffers come from a pool of page-locked buffers of the device, a buffer goes back
+    /// to the pool when the returned storage is dropped.
+    pub fn storage_to_pinned(
+        &self,
+        storage: &CudaStorage,
+        keep_alive: Box<dyn Any + Send + Sync>,
+    ) -> Result<CpuStorage> {
+        let (src, len, dtype) = slice_ptr(&storage.slice);
+        let bytes = len * dtype.size_in_bytes();
+        let mut transfers = self.transfers.lock().unwrap();
+        let completed = transfers.take_completed();
+        let pool = transfers.pool.clone();
+        let buffer = match pool.lock().unwrap().take(bytes) {
+            Some(buffer) => buffer,
+            None => {
+                self.bind_to_thread().w()?;
+                let mut ptr = std::ptr::null_mut();
+                let flags = sys::CU_MEMHOSTALLOC_PORTABLE;
+                // Zero sized allocations are not supported.
+                unsafe { sys::lib().cuMemHostAlloc(&mut ptr, bytes.max(1), flags) }
+                    .result()
+                    .w()?;
+                PinnedBuffer {
+                    ptr: ptr as usize,
+                    bytes: bytes.max(1),
+                }
+            }
+        };
+        let ptr = buffer.ptr;
+        let owner = PooledBuffer {
+            buffer: Some(buffer),
+            pool,
+        };
+        let stream = transfers.stream(self)?;
+        if bytes > 0 {
+            // The data is written by the kernels queued on the device stream.
+            stream.wait_for_default().w()?;
+            unsafe {
+                sys::lib()
+                    .cuMemcpyDtoHAsync_v2(ptr as *mut c_void, src, bytes, stream.stream)
+                    .result()
+                    .w()?
+            };
+        }
+        transfers.record(keep_alive)?;
+        drop(transfers);
+        drop(completed);
+        // SAFETY: the buffer holds `len` values of type `dtype` once the copy has completed, it is
+        // only written by the copy and stays valid while the owner is alive.
+        let storage =
+            unsafe { ForeignStorage::new(ptr as *const u8, len, dtype, Arc::new(owner))? };
+        Ok(CpuStorage::Foreign(storage))
+    }
+}
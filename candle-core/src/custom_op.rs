@@ -155,7 +155,7 @@ impl Tensor {
     /// Applies a unary custom op without backward support
     pub fn apply_op1_no_bwd<C: CustomOp1>(&self, c: &C) -> Result<Self> {
         let (storage, shape) = self.storage().apply_op1(self.layout(), c)?;
-        Ok(from_storage(storage, shape, BackpropOp::none(), false))
+        crate::anomaly::check(from_storage(storage, shape, BackpropOp::none(), false))
     }
 
     /// Applies a binary custom op without backward support
@@ -163,7 +163,7 @@ impl Tensor {
         let (storage, shape) =
             self.storage()
                 .apply_op2(self.layout(), &rhs.storage(), rhs.layout(), c)?;
-        Ok(from_storage(storage, shape, BackpropOp::none(), false))
+        crate::anomaly::check(from_storage(storage, shape, BackpropOp::none(), false))
     }
 
     /// Applies a ternary custom op without backward support
@@ -176,7 +176,7 @@ impl Tensor {
             t3.layout(),
             c,
         )?;
-        Ok(from_storage(storage, shape, BackpropOp::none(), false))
+        crate::anomaly::check(from_storage(storage, shape, BackpropOp::none(), false))
     }
 
     /// Applies a unary custom op.
@@ -185,7 +185,7 @@ impl Tensor {
             .storage()
             .apply_op1(self.layout(), c.as_ref().as_ref())?;
         let op = BackpropOp::new1(self, |s| Op::CustomOp1(s, c.clone()));
-        Ok(from_storage(storage, shape, op, false))
+        crate::anomaly::check(from_storage(storage, shape, op, false))
     }
 
     pub fn apply_op1<C: 'static + CustomOp1 + Send + Sync>(&self, c: C) -> Result<Self> {
@@ -205,7 +205,7 @@ impl Tensor {
             c.as_ref().as_ref(),
         )?;
         let op = BackpropOp::new2(self, rhs, |t1, t2| Op::CustomOp2(t1, t2, c.clone()));
-        Ok(from_storage(storage, shape, op, false))
+        crate::anomaly::check(from_storage(storage, shape, op, false))
     }
 
     pub fn apply_op2<C: 'static + CustomOp2 + Send + Sync>(&self, r: &Self, c: C) -> Result<Self> {
@@ -230,7 +230,7 @@ impl Tensor {
         let op = BackpropOp::new3(self, t2, t3, |t1, t2, t3| {
             Op::CustomOp3(t1, t2, t3, c.clone())
         });
-        Ok(from_storage(storage, shape, op, false))
+        crate::anomaly::check(from_storage(storage, shape, op, false))
     }
 
     pub fn apply_op3<C: 'static + CustomOp3 + Send + Sync>(
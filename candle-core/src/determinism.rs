@@ -0,0 +1,45 @@
+//! Deterministic mode, ensuring that running the same computation twice gives bitwise
+//! identical results.
+//!
+//! Most kernels are already deterministic: reductions use an accumulation order that only
+//! depends on the shapes involved, and the `scatter_add` and `index_add` kernels accumulate the
+//! updates of each output element sequentially, in index order, rather than with atomics. When
+//! deterministic mode is enabled:
+//! - The cudnn convolution algorithm is fixed to implicit gemm when not explicitly specified,
+//!   rather than being picked by the cudnn heuristics.
+//! - Ops that do not have a deterministic implementation return an error instead of silently
+//!   producing results that cannot be reproduced. This is currently the case for random number
+//!   generation on the cpu before the generator has been seeded, as it then starts from a random
+//!   seed, and for the custom ops that accumulate with atomics on gpu such as the backward pass
+//!   of the gaussian splatting rasterizer of candle-nn, which use [`ensure`].
+//!
+//! ```rust
+//! use candle_core::{Device, Tensor};
+//! candle_core::set_deterministic(true);
+//! assert!(Tensor::rand(0f32, 1f32, 4, &Device::Cpu).is_err());
+//! candle_core::set_deterministic(false);
+//! ```
+use crate::{DeviceLocation, Error, Result};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+static DETERMINISTIC: AtomicBool = AtomicBool::new(false);
+
+/// Enables or disables deterministic mode, this applies to all the devices and threads.
+pub fn set_deterministic(b: bool) {
+    DETERMINISTIC.store(b, Ordering::Relaxed)
+}
+
+/// Whether deterministic mode is enabled.
+pub fn is_deterministic() -> bool {
+    DETERMINISTIC.load(Ordering::Relaxed)
+}
+
+/// Returns an error if deterministic mode is enabled, to be used by ops that have no
+/// deterministic implementation on `device`, including the custom ops of other crates.
+pub fn ensure(op: &'static str, device: DeviceLocation, reason: &'static str) -> Result<()> {
+    if is_deterministic() {
+        Err(Error::NonDeterministic { op, device, reason }.bt())
+    } else {
+        Ok(())
+    }
+}
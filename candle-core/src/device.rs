@@ -1,5 +1,6 @@
 use crate::backend::BackendDevice;
 use crate::cpu_backend::CpuDevice;
+use crate::rng::RngState;
 use crate::{CpuStorage, DType, Result, Shape, Storage, WithDType};
 
 /// A `DeviceLocation` represents a physical device whereas multiple `Device`
@@ -19,6 +20,25 @@ pub enum Device {
     Metal(crate::MetalDevice),
 }
 
+/// Memory usage of a device, in bytes.
+///
+/// On cuda devices these are the statistics of the stream ordered allocator: `allocated` is the
+/// memory used by live buffers and `reserved` the memory held by the allocator pool. When the
+/// driver does not support memory pools, both are the memory used on the device as reported by
+/// the driver. On metal devices `allocated` is the size of the buffers in use and `reserved`
+/// includes the buffers kept around for reuse. On cpu both are the resident set size of the
+/// process.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MemoryStats {
+    pub allocated: usize,
+    pub reserved: usize,
+    /// The maximum of `allocated` since the device was created or the last call to
+    /// [`Device::reset_peak_memory_stats`].
+    pub peak_allocated: usize,
+    /// The maximum of `reserved` over the same period.
+    pub peak_reserved: usize,
+}
+
 pub trait NdArray {
     fn shape(&self) -> Result<Shape>;
 
@@ -163,6 +183,43 @@ impl Device {
         }
     }
 
+    /// The state of the random number generator of this device, see [`crate::rng`].
+    pub fn rng_state(&self) -> Result<RngState> {
+        match self {
+            Self::Cpu => CpuDevice.rng_state(),
+            Self::Cuda(c) => c.rng_state(),
+            Self::Metal(m) => m.rng_state(),
+        }
+    }
+
+    /// Restores the state of the random number generator, so that the same random values get
+    /// generated again.
+    pub fn set_rng_state(&self, state: RngState) -> Result<()> {
+        match self {
+            Self::Cpu => CpuDevice.set_rng_state(state),
+            Self::Cuda(c) => c.set_rng_state(state),
+            Self::Metal(m) => m.set_rng_state(state),
+        }
+    }
+
+    /// The current and peak memory usage of the device.
+    pub fn memory_stats(&self) -> Result<MemoryStats> {
+        match self {
+            Self::Cpu => CpuDevice.memory_stats(),
+            Self::Cuda(c) => c.memory_stats(),
+            Self::Metal(m) => m.memory_stats(),
+        }
+    }
+
+    /// Resets the peak memory usage reported by [`Self::memory_stats`] to the current usage.
+    pub fn reset_peak_memory_stats(&self) -> Result<()> {
+        match self {
+            Self::Cpu => CpuDevice.reset_peak_memory_stats(),
+            Self::Cuda(c) => c.reset_peak_memory_stats(),
+            Self::Metal(m) => m.reset_peak_memory_stats(),
+        }
+    }
+
     pub fn same_device(&self, rhs: &Self) -> bool {
         match (self, rhs) {
             (Self::Cpu, Self::Cpu) => true,
@@ -0,0 +1,345 @@
+//! Exchanging tensors with other frameworks through [DLPack](https://dmlc.github.io/dlpack/latest/).
+//!
+//! [`Tensor::to_dlpack`] exports a tensor without copying its data, the resulting
+//! `DLManagedTensor` shares the storage of the tensor and keeps it alive until its deleter is
+//! called by the consumer. [`Tensor::from_dlpack`] imports a tensor produced by another framework
+//! without copying it either, the memory of the producer is borrowed by a foreign storage that
+//! calls the DLPack deleter once dropped.
+//!
+//! This uses the ABI of DLPack v0.8, cpu and cuda tensors are supported.
+use crate::op::BackpropOp;
+use crate::storage::Storage;
+use crate::tensor::from_storage_and_layout;
+use crate::{
+    bail, CpuStorage, CpuStorageRef, DType, Device, DeviceLocation, ForeignStorage, Layout, Result,
+    Tensor,
+};
+use std::ffi::c_void;
+use std::sync::Arc;
+
+/// The cpu device type.
+pub const DL_CPU: i32 = 1;
+/// The cuda device type.
+pub const DL_CUDA: i32 = 2;
+/// The device type of page-locked host memory allocated with cuda.
+pub const DL_CUDA_HOST: i32 = 3;
+/// The metal device type.
+pub const DL_METAL: i32 = 8;
+
+pub const DL_INT: u8 = 0;
+pub const DL_UINT: u8 = 1;
+pub const DL_FLOAT: u8 = 2;
+pub const DL_BFLOAT: u8 = 4;
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DLDevice {
+    pub device_type: i32,
+    pub device_id: i32,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DLDataType {
+    pub code: u8,
+    pub bits: u8,
+    pub lanes: u16,
+}
+
+#[repr(C)]
+#[derive(Debug)]
+pub struct DLTensor {
+    pub data: *mut c_void,
+    pub device: DLDevice,
+    pub ndim: i32,
+    pub dtype: DLDataType,
+    pub shape: *mut i64,
+    /// The strides in number of elements, a null pointer means that the tensor is contiguous.
+    pub strides: *mut i64,
+    pub byte_offset: u64,
+}
+
+#[repr(C)]
+#[derive(Debug)]
+pub struct DLManagedTensor {
+    pub dl_tensor: DLTensor,
+    pub manager_ctx: *mut c_void,
+    pub deleter: Option<unsafe extern "C" fn(*mut DLManagedTensor)>,
+}
+
+impl DLDataType {
+    pub fn from_dtype(dtype: DType) -> Self {
+        let code = match dtype {
+            DType::U8 | DType::U32 => DL_UINT,
+            DType::I64 => DL_INT,
+            DType::BF16 => DL_BFLOAT,
+            DType::F16 | DType::F32 | DType::F64 => DL_FLOAT,
+        };
+        Self {
+            code,
+            bits: (dtype.size_in_bytes() * 8) as u8,
+            lanes: 1,
+        }
+    }
+
+    pub fn to_dtype(&self) -> Result<DType> {
+        let dtype = match (self.code, self.bits, self.lanes) {
+            (DL_UINT, 8, 1) => DType::U8,
+            (DL_UINT, 32, 1) => DType::U32,
+            (DL_INT, 64, 1) => DType::I64,
+            (DL_BFLOAT, 16, 1) => DType::BF16,
+            (DL_FLOAT, 16, 1) => DType::F16,
+            (DL_FLOAT, 32, 1) => DType::F32,
+            (DL_FLOAT, 64, 1) => DType::F64,
+            _ => bail!("unsupported dlpack dtype {self:?}"),
+        };
+        Ok(dtype)
+    }
+}
+
+impl DLDevice {
+    pub fn from_location(location: DeviceLocation) -> Self {
+        match location {
+            DeviceLocation::Cpu => Self {
+                device_type: DL_CPU,
+                device_id: 0,
+            },
+            DeviceLocation::Cuda { gpu_id } => Self {
+                device_type: DL_CUDA,
+                device_id: gpu_id as i32,
+            },
+            DeviceLocation::Metal { gpu_id } => Self {
+                device_type: DL_METAL,
+                device_id: gpu_id as i32,
+            },
+        }
+    }
+}
+
+// The data owned by an exported tensor.
+struct ManagerContext {
+    _tensor: Tensor,
+    _shape: Vec<i64>,
+    _strides: Vec<i64>,
+}
+
+unsafe extern "C" fn deleter(managed: *mut DLManagedTensor) {
+    if managed.is_null() {
+        return;
+    }
+    let managed = Box::from_raw(managed);
+    drop(Box::from_raw(managed.manager_ctx as *mut ManagerContext));
+}
+
+fn data_ptr(storage: &Storage) -> Result<*mut c_void> {
+    fn ptr<T>(data: &[T]) -> *mut c_void {
+        data.as_ptr() as *mut c_void
+    }
+    let ptr = match storage {
+        Storage::Cpu(storage) => match storage.as_storage_ref() {
+            CpuStorageRef::U8(data) => ptr(data),
+            CpuStorageRef::U32(data) => ptr(data),
+            CpuStorageRef::I64(data) => ptr(data),
+            CpuStorageRef::BF16(data) => ptr(data),
+            CpuStorageRef::F16(data) => ptr(data),
+            CpuStorageRef::F32(data) => ptr(data),
+            CpuStorageRef::F64(data) => ptr(data),
+        },
+        #[cfg(feature = "cuda")]
+        Storage::Cuda(storage) => cuda::data_ptr(storage),
+        storage => bail!(
+            "dlpack is not supported on {:?}",
+            storage.device().location()
+        ),
+    };
+    Ok(ptr)
+}
+
+impl Tensor {
+    /// Exports the tensor as a DLPack tensor sharing the same data.
+    ///
+    /// The caller takes ownership of the result and has to call its deleter once done, this is
+    /// usually left to the framework the tensor is passed to. For cuda tensors, the consumer
+    /// should synchronize with the device stream before using the data.
+    pub fn to_dlpack(&self) -> Result<*mut DLManagedTensor> {
+        let (storage, layout) = self.storage_and_layout();
+        let data = data_ptr(&storage)?;
+        let mut shape: Vec<i64> = self.dims().iter().map(|&d| d as i64).collect();
+        let mut strides: Vec<i64> = layout.stride().iter().map(|&s| s as i64).collect();
+        let dl_tensor = DLTensor {
+            data,
+            device: DLDevice::from_location(self.device().location()),
+            ndim: shape.len() as i32,
+            dtype: DLDataType::from_dtype(self.dtype()),
+            shape: shape.as_mut_ptr(),
+            strides: strides.as_mut_ptr(),
+            byte_offset: (layout.start_offset() * self.dtype().size_in_bytes()) as u64,
+        };
+        // Moving the vectors into the context does not move their buffers.
+        let ctx = Box::new(ManagerContext {
+            _tensor: self.clone(),
+            _shape: shape,
+            _strides: strides,
+        });
+        let managed = Box::new(DLManagedTensor {
+            dl_tensor,
+            manager_ctx: Box::into_raw(ctx) as *mut c_void,
+            deleter: Some(deleter),
+        });
+        Ok(Box::into_raw(managed))
+    }
+
+    /// Imports a DLPack tensor on `device` without copying its data, the DLPack tensor has to be
+    /// on the same device. The resulting tensor borrows the memory of the producer, the deleter
+    /// of the DLPack tensor is called once this memory is not used by any tensor anymore, or
+    /// right away when an error is returned.
+    ///
+    /// Cpu buffers are read-only, in place operations copy them first. Cuda buffers are shared
+    /// with the producer, in place operations modify the data of both frameworks.
+    ///
+    /// # Safety
+    ///
+    /// `managed` should point to a valid DLPack tensor that is not used elsewhere, its data must
+    /// not be modified by the producer until the deleter is called. For cuda tensors, the
+    /// producer should have synchronized with the device stream.
+    pub unsafe fn from_dlpack(managed: *mut DLManagedTensor, device: &Device) -> Result<Tensor> {
+        if managed.is_null() {
+            bail!("null dlpack tensor")
+        }
+        let owner = Arc::new(DlpackOwner(managed));
+        from_dl_tensor(&(*managed).dl_tensor, device, owner)
+    }
+}
+
+// Calls the deleter of an imported tensor when dropped.
+struct DlpackOwner(*mut DLManagedTensor);
+
+// Safety: the DLPack tensor is only used to call its deleter, which DLPack allows from any thread.
+unsafe impl Send for DlpackOwner {}
+unsafe impl Sync for DlpackOwner {}
+
+impl Drop for DlpackOwner {
+    fn drop(&mut self) {
+        unsafe {
+            if let Some(deleter) = (*self.0).deleter {
+                deleter(self.0)
+            }
+        }
+    }
+}
+
+unsafe fn from_dl_tensor(t: &DLTensor, device: &Device, owner: Arc<DlpackOwner>) -> Result<Tensor> {
+    let dtype = t.dtype.to_dtype()?;
+    let expected = match t.device.device_type {
+        DL_CPU | DL_CUDA_HOST => DeviceLocation::Cpu,
+        DL_CUDA => DeviceLocation::Cuda {
+            gpu_id: t.device.device_id as usize,
+        },
+        device_type => bail!("unsupported dlpack device type {device_type}"),
+    };
+    if expected != device.location() {
+        bail!(
+            "the dlpack tensor is on {expected:?} but the target device is {:?}",
+            device.location()
+        )
+    }
+    let ndim = t.ndim as usize;
+    let dims: Vec<usize> = if ndim == 0 {
+        vec![]
+    } else {
+        std::slice::from_raw_parts(t.shape, ndim)
+            .iter()
+            .map(|&d| d as usize)
+            .collect()
+    };
+    let layout = if t.strides.is_null() || ndim == 0 {
+        Layout::contiguous(dims.as_slice())
+    } else {
+        let strides = std::slice::from_raw_parts(t.strides, ndim);
+        if let Some(s) = strides.iter().find(|&&s| s < 0) {
+            bail!("negative dlpack strides are not supported ({s})")
+        }
+        let strides = strides.iter().map(|&s| s as usize).collect();
+        Layout::new(dims.as_slice().into(), strides, 0)
+    };
+    // The number of elements between the first and the last element of the tensor.
+    let span = if layout.shape().elem_count() == 0 {
+        0
+    } else {
+        let last: usize = dims
+            .iter()
+            .zip(layout.stride())
+            .map(|(d, s)| (d - 1) * s)
+            .sum();
+        last + 1
+    };
+    let data = (t.data as *const u8).add(t.byte_offset as usize);
+    let storage = match device {
+        Device::Cpu => Storage::Cpu(CpuStorage::Foreign(ForeignStorage::new(
+            data, span, dtype, owner,
+        )?)),
+        #[cfg(feature = "cuda")]
+        Device::Cuda(cuda) => {
+            Storage::Cuda(cuda.storage_from_foreign_ptr(data as u64, span, dtype, owner)?)
+        }
+        device => bail!("dlpack is not supported on {:?}", device.location()),
+    };
+    Ok(from_storage_and_layout(
+        storage,
+        layout,
+        BackpropOp::none(),
+        false,
+    ))
+}
+
+/// Makes the stream of a DLPack consumer wait for the kernels queued so far on `device`, before
+/// exporting a tensor. `stream` follows the `__dlpack__` convention of the Python array API:
+/// `None` and 1 are the legacy default stream, 2 is the per-thread default stream, -1 skips the
+/// synchronization and other values are `cudaStream_t` handles. This does nothing for the cpu.
+///
+/// # Safety
+///
+/// A stream handle has to be a valid stream of the primary context of the cuda device.
+pub unsafe fn wait_for_device(device: &Device, stream: Option<i64>) -> Result<()> {
+    match (device, stream) {
+        (_, Some(0)) => bail!("stream 0 is ambiguous for dlpack, use 1 or 2"),
+        (_, Some(-1)) | (Device::Cpu, _) => Ok(()),
+        #[cfg(feature = "cuda")]
+        (Device::Cuda(cuda), stream) => cuda::wait_for_device(cuda, stream.unwrap_or(1)),
+        (device, _) => bail!("dlpack is not supported on {:?}", device.location()),
+    }
+}
+
+#[cfg(feature = "cuda")]
+mod cuda {
+    use crate::cuda_backend::{CudaDevice, CudaStorage, CudaStorageSlice, WrapErr};
+    use crate::Result;
+    use cudarc::driver::{CudaSlice, DevicePtr};
+    use std::ffi::c_void;
+
+    pub(super) fn data_ptr(storage: &CudaStorage) -> *mut c_void {
+        fn ptr<T>(slice: &CudaSlice<T>) -> *mut c_void {
+            *slice.device_ptr() as *mut c_void
+        }
+        match &storage.slice {
+            CudaStorageSlice::U8(s) => ptr(s),
+            CudaStorageSlice::U32(s) => ptr(s),
+            CudaStorageSlice::I64(s) => ptr(s),
+            CudaStorageSlice::BF16(s) => ptr(s),
+            CudaStorageSlice::F16(s) => ptr(s),
+            CudaStorageSlice::F32(s) => ptr(s),
+            CudaStorageSlice::F64(s) => ptr(s),
+        }
+    }
+
+    pub(super) unsafe fn wait_for_device(device: &CudaDevice, stream: i64) -> Result<()> {
+        use cudarc::driver::{result, sys};
+        let stream = stream as usize as sys::CUstream;
+        let event = result::event::create(sys::CUevent_flags::CU_EVENT_DISABLE_TIMING).w()?;
+        let res = result::event::record(event, *device.cu_stream())
+            .and_then(|()| sys::lib().cuStreamWaitEvent(stream, event, 0).result());
+        // The event can be destroyed right away, the wait still happens once it is recorded.
+        let _ = result::event::destroy(event);
+        res.w()
+    }
+}
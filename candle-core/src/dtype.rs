@@ -142,6 +142,7 @@ macro_rules! with_dtype {
             fn cpu_storage_data(s: CpuStorage) -> Result<Vec<Self>> {
                 match s {
                     CpuStorage::$dtype(data) => Ok(data),
+                    CpuStorage::Foreign(data) => Ok(data.as_slice::<Self>()?.to_vec()),
                     _ => Err(Error::UnexpectedDType {
                         expected: DType::$dtype,
                         got: s.dtype(),
@@ -154,6 +155,7 @@ macro_rules! with_dtype {
             fn cpu_storage_as_slice(s: &CpuStorage) -> Result<&[Self]> {
                 match s {
                     CpuStorage::$dtype(data) => Ok(data),
+                    CpuStorage::Foreign(data) => data.as_slice(),
                     _ => Err(Error::UnexpectedDType {
                         expected: DType::$dtype,
                         got: s.dtype(),
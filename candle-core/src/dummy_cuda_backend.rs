@@ -20,6 +20,26 @@ impl CudaDevice {
     pub fn new_with_stream(_: usize) -> Result<Self> {
         Err(Error::NotCompiledWithCudaSupport)
     }
+
+    pub fn pin_host_memory(&self, _: &CpuStorage) -> Result<Box<dyn std::any::Any + Send + Sync>> {
+        Err(Error::NotCompiledWithCudaSupport)
+    }
+
+    pub fn storage_from_pinned(
+        &self,
+        _: &CpuStorage,
+        _: Box<dyn std::any::Any + Send + Sync>,
+    ) -> Result<CudaStorage> {
+        Err(Error::NotCompiledWithCudaSupport)
+    }
+
+    pub fn storage_to_pinned(
+        &self,
+        _: &CudaStorage,
+        _: Box<dyn std::any::Any + Send + Sync>,
+    ) -> Result<CpuStorage> {
+        Err(Error::NotCompiledWithCudaSupport)
+    }
 }
 
 impl crate::backend::BackendStorage for CudaStorage {
@@ -202,6 +222,22 @@ impl crate::backend::BackendDevice for CudaDevice {
         Err(Error::NotCompiledWithCudaSupport)
     }
 
+    fn rng_state(&self) -> Result<crate::rng::RngState> {
+        Err(Error::NotCompiledWithCudaSupport)
+    }
+
+    fn set_rng_state(&self, _: crate::rng::RngState) -> Result<()> {
+        Err(Error::NotCompiledWithCudaSupport)
+    }
+
+    fn memory_stats(&self) -> Result<crate::MemoryStats> {
+        Err(Error::NotCompiledWithCudaSupport)
+    }
+
+    fn reset_peak_memory_stats(&self) -> Result<()> {
+        Err(Error::NotCompiledWithCudaSupport)
+    }
+
     fn location(&self) -> crate::DeviceLocation {
         fail!()
     }
@@ -206,6 +206,22 @@ impl crate::backend::BackendDevice for MetalDevice {
         Err(Error::NotCompiledWithMetalSupport)
     }
 
+    fn rng_state(&self) -> Result<crate::rng::RngState> {
+        Err(Error::NotCompiledWithMetalSupport)
+    }
+
+    fn set_rng_state(&self, _: crate::rng::RngState) -> Result<()> {
+        Err(Error::NotCompiledWithMetalSupport)
+    }
+
+    fn memory_stats(&self) -> Result<crate::MemoryStats> {
+        Err(Error::NotCompiledWithMetalSupport)
+    }
+
+    fn reset_peak_memory_stats(&self) -> Result<()> {
+        Err(Error::NotCompiledWithMetalSupport)
+    }
+
     fn location(&self) -> crate::DeviceLocation {
         fail!()
     }
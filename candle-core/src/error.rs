@@ -45,6 +45,20 @@ pub enum Error {
         op: &'static str,
     },
 
+    #[error("{op}: no dimension named {name:?} in {names:?}")]
+    UnknownDimName {
+        name: String,
+        names: Vec<Option<String>>,
+        op: &'static str,
+    },
+
+    #[error("dimension names mismatch in {op}, lhs: {lhs:?}, rhs: {rhs:?}")]
+    DimNamesMismatch {
+        lhs: Vec<Option<String>>,
+        rhs: Vec<Option<String>>,
+        op: &'static str,
+    },
+
     // === Shape Errors ===
     #[error("unexpected rank, expected: {expected}, got: {got} ({shape:?})")]
     UnexpectedNumberOfDims {
@@ -133,6 +147,35 @@ pub enum Error {
     #[error("cannot set variable {msg}")]
     CannotSetVar { msg: &'static str },
 
+    #[error("{op} cannot be applied in place, {msg}")]
+    CannotApplyInplace { op: &'static str, msg: &'static str },
+
+    /// A NaN or infinite value was produced by an op while anomaly detection was enabled.
+    #[error("{op} produced {kind} values, input shapes: {shapes:?}")]
+    NonFiniteValue {
+        op: &'static str,
+        kind: &'static str,
+        shapes: Vec<Shape>,
+    },
+
+    /// The backward pass of an op produced NaN or infinite gradients while anomaly detection was
+    /// enabled for the backward pass.
+    #[error("the backward pass of {op} produced {kind} gradients, input shapes: {shapes:?}{}", .created_at.as_ref().map_or(String::new(), |bt| format!("\nthe {op} op was called at:\n{bt}")))]
+    NonFiniteGrad {
+        op: String,
+        kind: &'static str,
+        shapes: Vec<Shape>,
+        created_at: Option<std::sync::Arc<std::backtrace::Backtrace>>,
+    },
+
+    /// An op without a deterministic implementation was used in deterministic mode.
+    #[error("{op} has no deterministic implementation on {device:?}: {reason}")]
+    NonDeterministic {
+        op: &'static str,
+        device: DeviceLocation,
+        reason: &'static str,
+    },
+
     // Box indirection to avoid large variant.
     #[error("{0:?}")]
     MatMulUnexpectedStriding(Box<MatMulUnexpectedStriding>),
@@ -0,0 +1,383 @@
+//! Fused elementwise kernels generated and compiled at runtime for cuda devices.
+use super::{BinaryOp, Instr, LazyTensor, Program, UnaryOp};
+use crate::checkpoint_diff::Fnv1a;
+use crate::cuda_backend::{CudaDevice, CudaError, CudaStorage, CudaStorageSlice, WrapErr};
+use crate::op::BackpropOp;
+use crate::{bail, DType, Result, Storage, Tensor};
+use cudarc::driver::{CudaFunction, DeviceRepr, LaunchAsync, LaunchConfig};
+use cudarc::nvrtc::Ptx;
+use std::fmt::Write;
+use std::path::{Path, PathBuf};
+
+const FUNC_NAME: &str = "fused";
+
+fn c_type(dtype: DType) -> Result<&'static str> {
+    let c_type = match dtype {
+        DType::BF16 => "__nv_bfloat16",
+        DType::F16 => "__half",
+        DType::F32 => "float",
+        DType::F64 => "double",
+        dtype => bail!("lazy: no fused cuda kernel for {dtype:?}"),
+    };
+    Ok(c_type)
+}
+
+/// Converts `v` from `dtype` to the compute type `c`.
+fn load(v: &str, dtype: DType, c: &str) -> String {
+    match dtype {
+        DType::BF16 => format!("(({c})__bfloat162float({v}))"),
+        DType::F16 => format!("(({c})__half2float({v}))"),
+        _ => format!("(({c}){v})"),
+    }
+}
+
+/// Converts `v` from the compute type to `dtype`.
+fn store(v: &str, dtype: DType) -> String {
+    match dtype {
+        DType::BF16 => format!("__float2bfloat16((float){v})"),
+        DType::F16 => format!("__float2half((float){v})"),
+        DType::F32 => format!("((float){v})"),
+        _ => format!("((double){v})"),
+    }
+}
+
+/// The constants are written using their bit representation so that the kernel uses the exact
+/// same values as the cpu, this also handles infinities and nans.
+fn constant(v: f64, c: &str) -> String {
+    format!("(({c})__longlong_as_double((long long){}ull))", v.to_bits())
+}
+
+fn unary(op: UnaryOp, x: &str, c: &str) -> String {
+    let one = format!("(({c})1)");
+    match op {
+        UnaryOp::Exp => format!("exp({x})"),
+        UnaryOp::Log => format!("log({x})"),
+        UnaryOp::Sin => format!("sin({x})"),
+        UnaryOp::Cos => format!("cos({x})"),
+        UnaryOp::Tanh => format!("tanh({x})"),
+        UnaryOp::Neg => format!("(-{x})"),
+        UnaryOp::Recip => format!("({one} / {x})"),
+        UnaryOp::Sqr => format!("({x} * {x})"),
+        UnaryOp::Sqrt => format!("sqrt({x})"),
+        UnaryOp::Abs => format!("fabs({x})"),
+        UnaryOp::Gelu => format!(
+            "(({c})0.5 * {x} * ({one} + tanh(({c})0.7978845608028654 * {x} * ({one} + ({c})0.044715 * {x} * {x}))))"
+        ),
+        UnaryOp::GeluErf => {
+            format!("(({c})0.5 * {x} * ({one} + erf({x} * ({c})0.7071067811865476)))")
+        }
+        UnaryOp::Erf => format!("erf({x})"),
+        UnaryOp::Relu => format!("fmax({x}, ({c})0)"),
+        UnaryOp::Silu => format!("({x} / ({one} + exp(-{x})))"),
+    }
+}
+
+fn binary(op: BinaryOp, x: &str, y: &str) -> String {
+    match op {
+        BinaryOp::Add => format!("({x} + {y})"),
+        BinaryOp::Sub => format!("({x} - {y})"),
+        BinaryOp::Mul => format!("({x} * {y})"),
+        BinaryOp::Div => format!("({x} / {y})"),
+        BinaryOp::Maximum => format!("({x} < {y} ? {y} : {x})"),
+        BinaryOp::Minimum => format!("({x} > {y} ? {y} : {x})"),
+    }
+}
+
+/// Generates the source of the kernel. The kernel arguments are the number of elements, the
+/// number of dimensions, the layouts of the inputs, then the inputs and the outputs.
+///
+/// The layout of each input takes `1 + 2 * num_dims` values: its start offset, its dims and
+/// its strides. Contiguous inputs only use the start offset.
+fn gen_source(
+    program: &Program,
+    outputs: &[&LazyTensor],
+    compute: DType,
+    contiguous: &[bool],
+) -> Result<String> {
+    let c = c_type(compute)?;
+    let mut src = String::new();
+    let uses_half = program
+        .inputs
+        .iter()
+        .map(|t| t.dtype())
+        .chain(outputs.iter().map(|o| o.dtype()))
+        .chain(program.instrs.iter().filter_map(|i| match i {
+            Instr::Cast(_, dtype) => Some(*dtype),
+            _ => None,
+        }))
+        .any(|d| d == DType::F16 || d == DType::BF16);
+    if uses_half {
+        src.push_str("#include <cuda_fp16.h>\n#include <cuda_bf16.h>\n");
+    }
+    src.push_str(
+        r#"
+__device__ __forceinline__ size_t strided_offset(size_t i, const size_t num_dims, const size_t *info) {
+    size_t offset = info[0];
+    for (int d = num_dims - 1; d >= 0; d--) {
+        const size_t dim = info[1 + d];
+        offset += (i % dim) * info[1 + num_dims + d];
+        i /= dim;
+    }
+    return offset;
+}
+"#,
+    );
+    let mut params = vec![];
+    for (i, t) in program.inputs.iter().enumerate() {
+        params.push(format!("const {} *in{i}", c_type(t.dtype())?))
+    }
+    for (i, o) in outputs.iter().enumerate() {
+        params.push(format!("{} *out{i}", c_type(o.dtype())?))
+    }
+    let _ = writeln!(
+        src,
+        "extern \"C\" __global__ void {FUNC_NAME}(const size_t numel, const size_t num_dims, const size_t *info, {}) {{",
+        params.join(", ")
+    );
+    src.push_str(
+        "  for (size_t i = blockIdx.x * blockDim.x + threadIdx.x; i < numel; i += blockDim.x * gridDim.x) {\n",
+    );
+    for (reg, instr) in program.instrs.iter().enumerate() {
+        let r = |reg: &usize| format!("r{reg}");
+        let value = match instr {
+            Instr::Load(j) => {
+                let info = format!("info + {j} * (1 + 2 * num_dims)");
+                let index = if contiguous[*j] {
+                    format!("({info})[0] + i")
+                } else {
+                    format!("strided_offset(i, num_dims, {info})")
+                };
+                load(&format!("in{j}[{index}]"), program.inputs[*j].dtype(), c)
+            }
+            Instr::Const(v, _, _) => constant(*v, c),
+            Instr::Unary(op, arg) => unary(*op, &r(arg), c),
+            Instr::Binary(op, lhs, rhs) => binary(*op, &r(lhs), &r(rhs)),
+            Instr::Affine(arg, mul, add) => {
+                format!(
+                    "({} * {} + {})",
+                    r(arg),
+                    constant(*mul, c),
+                    constant(*add, c)
+                )
+            }
+            Instr::Cast(arg, dtype) => load(&store(&r(arg), *dtype), *dtype, c),
+        };
+        let _ = writeln!(src, "    const {c} r{reg} = {value};");
+    }
+    for (i, (reg, o)) in program.outputs.iter().zip(outputs.iter()).enumerate() {
+        let _ = writeln!(
+            src,
+            "    out{i}[i] = {};",
+            store(&format!("r{reg}"), o.dtype())
+        );
+    }
+    src.push_str("  }\n}\n");
+    Ok(src)
+}
+
+// The on disk cache, it is per user as the kernels found there get loaded without further
+// checks. `None` when neither the cache environment variables nor `HOME` are set.
+fn cache_dir() -> Option<PathBuf> {
+    if let Some(dir) = std::env::var_os("CANDLE_FUSED_KERNEL_CACHE") {
+        return Some(dir.into());
+    }
+    let cache = std::env::var_os("XDG_CACHE_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".cache")))?;
+    Some(cache.join("candle").join("fused-kernels"))
+}
+
+fn create_cache_dir(dir: &Path) -> std::io::Result<()> {
+    let mut builder = std::fs::DirBuilder::new();
+    builder.recursive(true);
+    #[cfg(unix)]
+    std::os::unix::fs::DirBuilderExt::mode(&mut builder, 0o700);
+    builder.create(dir)
+}
+
+// The cache is not used when other users can write to it.
+fn is_private(dir: &Path) -> bool {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        match std::fs::metadata(dir) {
+            Ok(metadata) => metadata.permissions().mode() & 0o022 == 0,
+            Err(_) => false,
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        dir.is_dir()
+    }
+}
+
+fn source_hash(src: &str) -> String {
+    let mut hasher = Fnv1a::new();
+    hasher.write(src.as_bytes());
+    format!("// candle fused kernel {:016x}\n", hasher.finish())
+}
+
+// The cached PTX starts with a hash of its source, the file is only used when it matches `src`.
+fn read_cached(path: &Path, src: &str) -> Option<Ptx> {
+    let ptx = std::fs::read_to_string(path).ok()?;
+    let ptx = ptx.strip_prefix(&source_hash(src))?;
+    Some(Ptx::from_src(ptx))
+}
+
+fn compile(module_name: &str, src: &str) -> Result<Ptx> {
+    let dir = cache_dir();
+    let path = dir
+        .as_ref()
+        .map(|dir| dir.join(format!("{module_name}.ptx")));
+    if let (Some(dir), Some(path)) = (&dir, &path) {
+        if is_private(dir) {
+            if let Some(ptx) = read_cached(path, src) {
+                return Ok(ptx);
+            }
+        }
+    }
+    let cuda_root = std::env::var("CUDA_ROOT")
+        .or_else(|_| std::env::var("CUDA_PATH"))
+        .unwrap_or_else(|_| "/usr/local/cuda".to_string());
+    let opts = cudarc::nvrtc::CompileOptions {
+        include_paths: vec![format!("{cuda_root}/include")],
+        ..Default::default()
+    };
+    let ptx = cudarc::nvrtc::safe::compile_ptx_with_opts(src, opts).w()?;
+    // Failing to write the on disk cache only results in the kernel being compiled again by
+    // the next process so errors are ignored. The file is renamed once written so that other
+    // processes never read a partial file.
+    if let (Some(dir), Some(path)) = (dir, path) {
+        let tmp = path.with_extension(format!("{}.tmp", std::process::id()));
+        let written = create_cache_dir(&dir)
+            .and_then(|()| std::fs::write(&tmp, source_hash(src) + &ptx.to_src()))
+            .and_then(|()| std::fs::rename(&tmp, &path));
+        if written.is_err() {
+            let _ = std::fs::remove_file(&tmp);
+        }
+    }
+    Ok(ptx)
+}
+
+fn nvrtc_version() -> (i32, i32) {
+    let (mut major, mut minor) = (0, 0);
+    // A failure leaves the version unknown, the compilation then reports the actual error.
+    let _ = unsafe { cudarc::nvrtc::sys::lib().nvrtcVersion(&mut major, &mut minor) };
+    (major, minor)
+}
+
+fn get_or_compile_func(dev: &CudaDevice, src: &str) -> Result<CudaFunction> {
+    // The PTX depends on the compiler and on the architecture it targets, which defaults to the
+    // one of the device.
+    let (nvrtc_major, nvrtc_minor) = nvrtc_version();
+    let (cc_major, cc_minor) = dev.compute_capability()?;
+    let mut hasher = Fnv1a::new();
+    hasher.write(env!("CARGO_PKG_VERSION").as_bytes());
+    hasher.write(format!("nvrtc {nvrtc_major}.{nvrtc_minor} sm {cc_major}.{cc_minor}").as_bytes());
+    hasher.write(src.as_bytes());
+    let module_name = format!("fused_{:016x}", hasher.finish());
+    if !dev.has_func(&module_name, FUNC_NAME) {
+        let ptx = compile(&module_name, src)?;
+        dev.load_ptx(ptx, &module_name, &[FUNC_NAME])
+            .map_err(|cuda| CudaError::Load {
+                cuda,
+                module_name: module_name.to_string(),
+            })
+            .w()?;
+    }
+    dev.get_func(&module_name, FUNC_NAME)
+        .ok_or(CudaError::MissingKernel { module_name })
+        .w()
+}
+
+pub(super) fn run(
+    program: &Program,
+    outputs: &[&LazyTensor],
+    compute: DType,
+    dev: &CudaDevice,
+) -> Result<Vec<Tensor>> {
+    let shape = outputs[0].shape();
+    let el = shape.elem_count();
+    if el == 0 {
+        return program.run_eager(outputs);
+    }
+    let num_dims = shape.rank();
+    let contiguous: Vec<bool> = program
+        .inputs
+        .iter()
+        .map(|t| t.layout().is_contiguous())
+        .collect();
+    let src = gen_source(program, outputs, compute, &contiguous)?;
+    let func = get_or_compile_func(dev, &src)?;
+
+    let mut info = Vec::with_capacity(program.inputs.len() * (1 + 2 * num_dims));
+    for t in program.inputs.iter() {
+        let layout = t.layout();
+        if layout.dims().len() != num_dims {
+            bail!("lazy: unexpected input shape {:?}", layout.shape())
+        }
+        info.push(layout.start_offset());
+        info.extend_from_slice(layout.dims());
+        info.extend_from_slice(layout.stride());
+    }
+    // Avoid an empty allocation when the graph only contains constants.
+    info.push(0);
+    let info = dev.htod_copy(info).w()?;
+
+    let guards: Vec<_> = program.inputs.iter().map(|t| t.storage()).collect();
+    let mut ys = Vec::with_capacity(outputs.len());
+    for o in outputs.iter() {
+        // SAFETY: Set later by running the kernel.
+        let slice = match o.dtype() {
+            DType::BF16 => CudaStorageSlice::BF16(unsafe { dev.alloc::<half::bf16>(el) }.w()?),
+            DType::F16 => CudaStorageSlice::F16(unsafe { dev.alloc::<half::f16>(el) }.w()?),
+            DType::F32 => CudaStorageSlice::F32(unsafe { dev.alloc::<f32>(el) }.w()?),
+            DType::F64 => CudaStorageSlice::F64(unsafe { dev.alloc::<f64>(el) }.w()?),
+            dtype => bail!("lazy: no fused cuda kernel for {dtype:?}"),
+        };
+        ys.push(slice)
+    }
+
+    let mut params = vec![el.as_kernel_param(), num_dims.as_kernel_param()];
+    params.push((&info).as_kernel_param());
+    for guard in guards.iter() {
+        let param = match &**guard {
+            Storage::Cuda(storage) => match &storage.slice {
+                CudaStorageSlice::BF16(s) => s.as_kernel_param(),
+                CudaStorageSlice::F16(s) => s.as_kernel_param(),
+                CudaStorageSlice::F32(s) => s.as_kernel_param(),
+                CudaStorageSlice::F64(s) => s.as_kernel_param(),
+                _ => bail!("lazy: unexpected dtype for a fused cuda kernel"),
+            },
+            _ => bail!("lazy: unexpected device for a fused cuda kernel"),
+        };
+        params.push(param)
+    }
+    for y in ys.iter() {
+        let param = match y {
+            CudaStorageSlice::BF16(s) => s.as_kernel_param(),
+            CudaStorageSlice::F16(s) => s.as_kernel_param(),
+            CudaStorageSlice::F32(s) => s.as_kernel_param(),
+            CudaStorageSlice::F64(s) => s.as_kernel_param(),
+            _ => bail!("lazy: unexpected dtype for a fused cuda kernel"),
+        };
+        params.push(param)
+    }
+    let cfg = LaunchConfig::for_num_elems(el as u32);
+    // SAFETY: ffi, the parameters match the generated kernel signature and outlive the launch.
+    unsafe { func.launch(cfg, &mut params[..]) }.w()?;
+    drop(guards);
+
+    let ys = ys
+        .into_iter()
+        .map(|slice| {
+            let storage = CudaStorage {
+                slice,
+                device: dev.clone(),
+            };
+            crate::tensor::from_storage(Storage::Cuda(storage), shape, BackpropOp::none(), false)
+        })
+        .collect();
+    Ok(ys)
+}
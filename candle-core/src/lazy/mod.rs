@@ -0,0 +1,852 @@
+//! Opt-in lazy execution of elementwise operations.
+//!
+//! Operations on a [`LazyTensor`] do not run any computation, instead they build a small graph
+//! that is only executed when calling [`LazyTensor::realize`]. Before execution the graph is
+//! optimized:
+//! - Constant folding, operations on constants are evaluated when the graph is built and chains
+//!   of scalar additions/multiplications are merged in a single affine operation.
+//! - Dead-code elimination, only the nodes that the realized outputs depend on are evaluated.
+//! - Elementwise fusion, on the cpu the whole graph is evaluated in a single pass over the data,
+//!   processing small chunks so that intermediate values stay in cache rather than being
+//!   written back to memory. On cuda, a kernel is generated for the graph and compiled at
+//!   runtime with NVRTC, see below.
+//!
+//! On metal, for integer dtypes, or when some inputs are tracked for backpropagation, the graph
+//! is evaluated operation by operation.
+//!
+//! The cuda kernels are cached in memory for each device and the compiled PTX is also stored
+//! on disk so that it can be reused by later processes. The cache directory can be set using
+//! the `CANDLE_FUSED_KERNEL_CACHE` environment variable, it defaults to `candle/fused-kernels`
+//! in the per-user cache directory, `$XDG_CACHE_HOME` or `~/.cache`, and is created with 0700
+//! permissions. The cache key covers the NVRTC version and the compute capability of the device,
+//! and cached PTX is only loaded when its recorded source hash matches the kernel.
+//!
+//! ```rust
+//! use candle_core::{Device, Tensor};
+//! let x = Tensor::new(&[[-1f32, 0., 1.], [2., 3., 4.]], &Device::Cpu)?;
+//! let bias = Tensor::new(&[0.5f32, 0., -0.5], &Device::Cpu)?;
+//! let ys = (x.lazy() * 2.)?.broadcast_add(&bias.lazy())?.gelu()?.realize()?;
+//! let expected = x.affine(2., 0.)?.broadcast_add(&bias)?.gelu()?;
+//! assert_eq!(ys.to_vec2::<f32>()?, expected.to_vec2::<f32>()?);
+//! # Ok::<(), candle_core::Error>(())
+//! ```
+use crate::op::{self, BinaryOpT, UnaryOpT};
+use crate::{bail, DType, Device, Error, Layout, Result, Shape, Storage, Tensor};
+use rayon::prelude::*;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+/// The number of elements processed at once by the fused cpu kernel.
+const CHUNK_SIZE: usize = 4096;
+
+#[cfg(feature = "cuda")]
+mod cuda;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum UnaryOp {
+    Exp,
+    Log,
+    Sin,
+    Cos,
+    Tanh,
+    Neg,
+    Recip,
+    Sqr,
+    Sqrt,
+    Abs,
+    Gelu,
+    GeluErf,
+    Erf,
+    Relu,
+    Silu,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum BinaryOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Maximum,
+    Minimum,
+}
+
+#[derive(Debug)]
+enum LazyOp {
+    Input(Tensor),
+    Const(f64),
+    Unary(UnaryOp, LazyTensor),
+    Binary(BinaryOp, LazyTensor, LazyTensor),
+    Affine { arg: LazyTensor, mul: f64, add: f64 },
+    ToDType(LazyTensor),
+}
+
+#[derive(Debug)]
+struct Node {
+    op: LazyOp,
+    shape: Shape,
+    dtype: DType,
+    device: Device,
+}
+
+/// A tensor whose value is computed on demand, see the [module level documentation](self).
+#[derive(Debug, Clone)]
+pub struct LazyTensor(Arc<Node>);
+
+macro_rules! unary_op {
+    ($fn_name:ident, $op_name:ident) => {
+        pub fn $fn_name(&self) -> Result<Self> {
+            self.unary_op(UnaryOp::$op_name)
+        }
+    };
+}
+
+macro_rules! binary_op {
+    ($fn_name:ident, $broadcast_fn_name:ident, $op_name:ident) => {
+        pub fn $fn_name(&self, rhs: &Self) -> Result<Self> {
+            self.binary_op(rhs, BinaryOp::$op_name)
+        }
+
+        pub fn $broadcast_fn_name(&self, rhs: &Self) -> Result<Self> {
+            let shape = self
+                .shape()
+                .broadcast_shape_binary_op(rhs.shape(), stringify!($broadcast_fn_name))?;
+            self.broadcast_as(&shape)?
+                .$fn_name(&rhs.broadcast_as(&shape)?)
+        }
+    };
+}
+
+impl Tensor {
+    /// Starts a lazy computation from this tensor, see [`LazyTensor`].
+    pub fn lazy(&self) -> LazyTensor {
+        LazyTensor::new(LazyOp::Input(self.clone()), self)
+    }
+}
+
+impl LazyTensor {
+    fn new(op: LazyOp, like: &Tensor) -> Self {
+        Self::from_parts(
+            op,
+            like.shape().clone(),
+            like.dtype(),
+            like.device().clone(),
+        )
+    }
+
+    fn from_parts(op: LazyOp, shape: Shape, dtype: DType, device: Device) -> Self {
+        Self(Arc::new(Node {
+            op,
+            shape,
+            dtype,
+            device,
+        }))
+    }
+
+    fn with_op(&self, op: LazyOp) -> Self {
+        Self::from_parts(
+            op,
+            self.shape().clone(),
+            self.dtype(),
+            self.device().clone(),
+        )
+    }
+
+    /// A constant tensor where all the elements are equal to `value`.
+    pub fn full<S: Into<Shape>>(value: f64, shape: S, dtype: DType, device: &Device) -> Self {
+        Self::from_parts(LazyOp::Const(value), shape.into(), dtype, device.clone())
+    }
+
+    pub fn shape(&self) -> &Shape {
+        &self.0.shape
+    }
+
+    pub fn dims(&self) -> &[usize] {
+        self.shape().dims()
+    }
+
+    pub fn dtype(&self) -> DType {
+        self.0.dtype
+    }
+
+    pub fn device(&self) -> &Device {
+        &self.0.device
+    }
+
+    pub fn elem_count(&self) -> usize {
+        self.shape().elem_count()
+    }
+
+    fn as_const(&self) -> Option<f64> {
+        match self.0.op {
+            LazyOp::Const(v) => Some(v),
+            _ => None,
+        }
+    }
+
+    fn check_float(&self, op: &'static str) -> Result<()> {
+        if self.dtype().is_float() {
+            Ok(())
+        } else {
+            Err(Error::UnsupportedDTypeForOp(self.dtype(), op).bt())
+        }
+    }
+
+    fn unary_op(&self, op: UnaryOp) -> Result<Self> {
+        self.check_float("lazy-unary")?;
+        let lazy = match self.as_const() {
+            Some(v) => self.with_op(LazyOp::Const(op.f64(v))),
+            None => self.with_op(LazyOp::Unary(op, self.clone())),
+        };
+        Ok(lazy)
+    }
+
+    fn binary_op(&self, rhs: &Self, op: BinaryOp) -> Result<Self> {
+        let name = op.name();
+        if self.shape() != rhs.shape() {
+            Err(Error::ShapeMismatchBinaryOp {
+                lhs: self.shape().clone(),
+                rhs: rhs.shape().clone(),
+                op: name,
+            }
+            .bt())?
+        }
+        if self.dtype() != rhs.dtype() {
+            Err(Error::DTypeMismatchBinaryOp {
+                lhs: self.dtype(),
+                rhs: rhs.dtype(),
+                op: name,
+            }
+            .bt())?
+        }
+        if !self.device().same_device(rhs.device()) {
+            Err(Error::DeviceMismatchBinaryOp {
+                lhs: self.device().location(),
+                rhs: rhs.device().location(),
+                op: name,
+            }
+            .bt())?
+        }
+        if self.dtype().is_float() {
+            match (self.as_const(), rhs.as_const(), op) {
+                (Some(l), Some(r), _) => return Ok(self.with_op(LazyOp::Const(op.f64(l, r)))),
+                (None, Some(r), BinaryOp::Add) => return self.affine(1., r),
+                (None, Some(r), BinaryOp::Sub) => return self.affine(1., -r),
+                (None, Some(r), BinaryOp::Mul) => return self.affine(r, 0.),
+                (None, Some(r), BinaryOp::Div) => return self.affine(1. / r, 0.),
+                (Some(l), None, BinaryOp::Add) => return rhs.affine(1., l),
+                (Some(l), None, BinaryOp::Sub) => return rhs.affine(-1., l),
+                (Some(l), None, BinaryOp::Mul) => return rhs.affine(l, 0.),
+                _ => {}
+            }
+        }
+        Ok(self.with_op(LazyOp::Binary(op, self.clone(), rhs.clone())))
+    }
+
+    /// Computes `self * mul + add`, consecutive affine operations are merged together.
+    pub fn affine(&self, mul: f64, add: f64) -> Result<Self> {
+        self.check_float("lazy-affine")?;
+        if mul == 1. && add == 0. {
+            return Ok(self.clone());
+        }
+        let lazy = match &self.0.op {
+            LazyOp::Const(v) => self.with_op(LazyOp::Const(v * mul + add)),
+            LazyOp::Affine {
+                arg,
+                mul: mul1,
+                add: add1,
+            } => arg.affine(mul1 * mul, add1 * mul + add)?,
+            _ => self.with_op(LazyOp::Affine {
+                arg: self.clone(),
+                mul,
+                add,
+            }),
+        };
+        Ok(lazy)
+    }
+
+    /// Converts the values to `dtype`, only float dtypes are supported. The fused kernels
+    /// apply the rounding of the target dtype without materializing the converted values.
+    pub fn to_dtype(&self, dtype: DType) -> Result<Self> {
+        self.check_float("lazy-to-dtype")?;
+        if !dtype.is_float() {
+            Err(Error::UnsupportedDTypeForOp(dtype, "lazy-to-dtype").bt())?
+        }
+        if dtype == self.dtype() {
+            return Ok(self.clone());
+        }
+        let op = match self.as_const() {
+            Some(v) => LazyOp::Const(f64::cast(v, dtype)),
+            None => LazyOp::ToDType(self.clone()),
+        };
+        let device = self.device().clone();
+        Ok(Self::from_parts(op, self.shape().clone(), dtype, device))
+    }
+
+    unary_op!(exp, Exp);
+    unary_op!(log, Log);
+    unary_op!(sin, Sin);
+    unary_op!(cos, Cos);
+    unary_op!(tanh, Tanh);
+    unary_op!(neg, Neg);
+    unary_op!(recip, Recip);
+    unary_op!(sqr, Sqr);
+    unary_op!(sqrt, Sqrt);
+    unary_op!(abs, Abs);
+    unary_op!(gelu, Gelu);
+    unary_op!(gelu_erf, GeluErf);
+    unary_op!(erf, Erf);
+    unary_op!(relu, Relu);
+    unary_op!(silu, Silu);
+
+    binary_op!(add, broadcast_add, Add);
+    binary_op!(sub, broadcast_sub, Sub);
+    binary_op!(mul, broadcast_mul, Mul);
+    binary_op!(div, broadcast_div, Div);
+    binary_op!(maximum, broadcast_maximum, Maximum);
+    binary_op!(minimum, broadcast_minimum, Minimum);
+
+    /// Broadcasts the lazy tensor to `shape`. This is free for inputs and constants, other
+    /// nodes are realized first, which splits the fused computation in two parts.
+    pub fn broadcast_as<S: Into<Shape>>(&self, shape: S) -> Result<Self> {
+        let shape = shape.into();
+        if &shape == self.shape() {
+            return Ok(self.clone());
+        }
+        match &self.0.op {
+            LazyOp::Input(t) => Ok(t.broadcast_as(shape)?.lazy()),
+            LazyOp::Const(v) => {
+                // Check that the broadcast is valid.
+                self.shape()
+                    .broadcast_shape_binary_op(&shape, "broadcast_as")?;
+                Ok(Self::full(*v, shape, self.dtype(), self.device()))
+            }
+            _ => Ok(self.realize()?.broadcast_as(shape)?.lazy()),
+        }
+    }
+
+    /// Runs the computation and returns the resulting tensor.
+    pub fn realize(&self) -> Result<Tensor> {
+        let mut ys = Self::realize_all(&[self])?;
+        Ok(ys.remove(0))
+    }
+
+    /// Runs the computation of multiple lazy tensors at once, the nodes shared between these
+    /// tensors are only evaluated once.
+    pub fn realize_all(xs: &[&Self]) -> Result<Vec<Tensor>> {
+        let mut fused = vec![];
+        let mut ys: Vec<Option<Tensor>> = vec![None; xs.len()];
+        for (i, x) in xs.iter().enumerate() {
+            match &x.0.op {
+                LazyOp::Input(t) => ys[i] = Some(t.clone()),
+                LazyOp::Const(v) => {
+                    ys[i] = Some(Tensor::zeros(x.shape(), x.dtype(), x.device())?.affine(1., *v)?)
+                }
+                _ => fused.push(i),
+            }
+        }
+        if !fused.is_empty() {
+            let outputs: Vec<&Self> = fused.iter().map(|&i| xs[i]).collect();
+            let program = Program::compile(&outputs);
+            let fused_ys = match (program.compute_dtype(), outputs[0].device()) {
+                (Some(dtype), Device::Cpu) if program.can_fuse(&outputs) => {
+                    program.run_cpu(&outputs, dtype)?
+                }
+                #[cfg(feature = "cuda")]
+                (Some(dtype), Device::Cuda(dev)) if program.can_fuse(&outputs) => {
+                    cuda::run(&program, &outputs, dtype, dev)?
+                }
+                _ => program.run_eager(&outputs)?,
+            };
+            for (i, y) in fused.into_iter().zip(fused_ys) {
+                ys[i] = Some(y)
+            }
+        }
+        Ok(ys.into_iter().flatten().collect())
+    }
+}
+
+impl UnaryOp {
+    fn f32(&self, v: f32) -> f32 {
+        match self {
+            Self::Exp => op::Exp::f32(v),
+            Self::Log => op::Log::f32(v),
+            Self::Sin => op::Sin::f32(v),
+            Self::Cos => op::Cos::f32(v),
+            Self::Tanh => op::Tanh::f32(v),
+            Self::Neg => op::Neg::f32(v),
+            Self::Recip => op::Recip::f32(v),
+            Self::Sqr => op::Sqr::f32(v),
+            Self::Sqrt => op::Sqrt::f32(v),
+            Self::Abs => op::Abs::f32(v),
+            Self::Gelu => op::Gelu::f32(v),
+            Self::GeluErf => op::GeluErf::f32(v),
+            Self::Erf => op::Erf::f32(v),
+            Self::Relu => op::Relu::f32(v),
+            Self::Silu => op::Silu::f32(v),
+        }
+    }
+
+    fn f64(&self, v: f64) -> f64 {
+        match self {
+            Self::Exp => op::Exp::f64(v),
+            Self::Log => op::Log::f64(v),
+            Self::Sin => op::Sin::f64(v),
+            Self::Cos => op::Cos::f64(v),
+            Self::Tanh => op::Tanh::f64(v),
+            Self::Neg => op::Neg::f64(v),
+            Self::Recip => op::Recip::f64(v),
+            Self::Sqr => op::Sqr::f64(v),
+            Self::Sqrt => op::Sqrt::f64(v),
+            Self::Abs => op::Abs::f64(v),
+            Self::Gelu => op::Gelu::f64(v),
+            Self::GeluErf => op::GeluErf::f64(v),
+            Self::Erf => op::Erf::f64(v),
+            Self::Relu => op::Relu::f64(v),
+            Self::Silu => op::Silu::f64(v),
+        }
+    }
+
+    fn eager(&self, t: &Tensor) -> Result<Tensor> {
+        match self {
+            Self::Exp => t.exp(),
+            Self::Log => t.log(),
+            Self::Sin => t.sin(),
+            Self::Cos => t.cos(),
+            Self::Tanh => t.tanh(),
+            Self::Neg => t.neg(),
+            Self::Recip => t.recip(),
+            Self::Sqr => t.sqr(),
+            Self::Sqrt => t.sqrt(),
+            Self::Abs => t.abs(),
+            Self::Gelu => t.gelu(),
+            Self::GeluErf => t.gelu_erf(),
+            Self::Erf => t.erf(),
+            Self::Relu => t.relu(),
+            Self::Silu => t.silu(),
+        }
+    }
+}
+
+impl BinaryOp {
+    fn name(&self) -> &'static str {
+        match self {
+            Self::Add => op::Add::NAME,
+            Self::Sub => op::Sub::NAME,
+            Self::Mul => op::Mul::NAME,
+            Self::Div => op::Div::NAME,
+            Self::Maximum => op::Maximum::NAME,
+            Self::Minimum => op::Minimum::NAME,
+        }
+    }
+
+    fn f32(&self, v1: f32, v2: f32) -> f32 {
+        match self {
+            Self::Add => op::Add::f32(v1, v2),
+            Self::Sub => op::Sub::f32(v1, v2),
+            Self::Mul => op::Mul::f32(v1, v2),
+            Self::Div => op::Div::f32(v1, v2),
+            Self::Maximum => op::Maximum::f32(v1, v2),
+            Self::Minimum => op::Minimum::f32(v1, v2),
+        }
+    }
+
+    fn f64(&self, v1: f64, v2: f64) -> f64 {
+        match self {
+            Self::Add => op::Add::f64(v1, v2),
+            Self::Sub => op::Sub::f64(v1, v2),
+            Self::Mul => op::Mul::f64(v1, v2),
+            Self::Div => op::Div::f64(v1, v2),
+            Self::Maximum => op::Maximum::f64(v1, v2),
+            Self::Minimum => op::Minimum::f64(v1, v2),
+        }
+    }
+
+    fn eager(&self, lhs: &Tensor, rhs: &Tensor) -> Result<Tensor> {
+        match self {
+            Self::Add => lhs.add(rhs),
+            Self::Sub => lhs.sub(rhs),
+            Self::Mul => lhs.mul(rhs),
+            Self::Div => lhs.div(rhs),
+            Self::Maximum => lhs.maximum(rhs),
+            Self::Minimum => lhs.minimum(rhs),
+        }
+    }
+}
+
+/// A linearized version of the graph, each instruction writes to its own register.
+#[derive(Debug)]
+enum Instr {
+    Load(usize),
+    Const(f64, Shape, DType),
+    Unary(UnaryOp, usize),
+    Binary(BinaryOp, usize, usize),
+    Affine(usize, f64, f64),
+    Cast(usize, DType),
+}
+
+#[derive(Debug)]
+struct Program {
+    instrs: Vec<Instr>,
+    inputs: Vec<Tensor>,
+    outputs: Vec<usize>,
+    all_float: bool,
+    uses_f64: bool,
+}
+
+impl Program {
+    fn compile(outputs: &[&LazyTensor]) -> Self {
+        let mut program = Self {
+            instrs: vec![],
+            inputs: vec![],
+            outputs: vec![],
+            all_float: true,
+            uses_f64: false,
+        };
+        let mut registers = HashMap::new();
+        for output in outputs.iter() {
+            let reg = program.visit(output, &mut registers);
+            program.outputs.push(reg)
+        }
+        program
+    }
+
+    fn visit(&mut self, node: &LazyTensor, registers: &mut HashMap<*const Node, usize>) -> usize {
+        let key = Arc::as_ptr(&node.0);
+        if let Some(&reg) = registers.get(&key) {
+            return reg;
+        }
+        self.all_float &= node.dtype().is_float();
+        self.uses_f64 |= node.dtype() == DType::F64;
+        let instr = match &node.0.op {
+            LazyOp::Input(t) => {
+                self.inputs.push(t.clone());
+                Instr::Load(self.inputs.len() - 1)
+            }
+            LazyOp::Const(v) => Instr::Const(*v, node.shape().clone(), node.dtype()),
+            LazyOp::Unary(op, arg) => Instr::Unary(*op, self.visit(arg, registers)),
+            LazyOp::Binary(op, lhs, rhs) => {
+                let lhs = self.visit(lhs, registers);
+                let rhs = self.visit(rhs, registers);
+                Instr::Binary(*op, lhs, rhs)
+            }
+            LazyOp::Affine { arg, mul, add } => {
+                Instr::Affine(self.visit(arg, registers), *mul, *add)
+            }
+            LazyOp::ToDType(arg) => Instr::Cast(self.visit(arg, registers), node.dtype()),
+        };
+        self.instrs.push(instr);
+        let reg = self.instrs.len() - 1;
+        registers.insert(key, reg);
+        reg
+    }
+
+    /// The dtype used by the fused kernel, half precision values are computed using f32.
+    fn compute_dtype(&self) -> Option<DType> {
+        match (self.all_float, self.uses_f64) {
+            (false, _) => None,
+            (true, true) => Some(DType::F64),
+            (true, false) => Some(DType::F32),
+        }
+    }
+
+    /// Fused kernels require all the outputs to have the same shape. They do not record the
+    /// operations for backpropagation so tracked inputs are evaluated eagerly.
+    fn can_fuse(&self, outputs: &[&LazyTensor]) -> bool {
+        let shape = outputs[0].shape();
+        outputs.iter().all(|o| o.shape() == shape) && !self.inputs.iter().any(|t| t.track_op())
+    }
+
+    fn run_eager(&self, outputs: &[&LazyTensor]) -> Result<Vec<Tensor>> {
+        let mut values: Vec<Tensor> = Vec::with_capacity(self.instrs.len());
+        let device = outputs[0].device();
+        for instr in self.instrs.iter() {
+            let value = match instr {
+                Instr::Load(i) => self.inputs[*i].clone(),
+                Instr::Const(v, shape, dtype) => {
+                    Tensor::zeros(shape, *dtype, device)?.affine(1., *v)?
+                }
+                Instr::Unary(op, arg) => op.eager(&values[*arg])?,
+                Instr::Binary(op, lhs, rhs) => op.eager(&values[*lhs], &values[*rhs])?,
+                Instr::Affine(arg, mul, add) => values[*arg].affine(*mul, *add)?,
+                Instr::Cast(arg, dtype) => values[*arg].to_dtype(*dtype)?,
+            };
+            values.push(value)
+        }
+        self.outputs
+            .iter()
+            .zip(outputs.iter())
+            .map(|(&reg, o)| {
+                let t = &values[reg];
+                if t.shape() != o.shape() {
+                    bail!("lazy: unexpected shape {:?} for {:?}", t.shape(), o.shape())
+                }
+                Ok(t.clone())
+            })
+            .collect()
+    }
+
+    fn run_cpu(&self, outputs: &[&LazyTensor], dtype: DType) -> Result<Vec<Tensor>> {
+        let shape = outputs[0].shape();
+        let ys = match dtype {
+            DType::F64 => {
+                let ys = self.run_cpu_::<f64>(shape.elem_count())?;
+                ys.into_iter()
+                    .map(|y| Tensor::from_vec(y, shape, &Device::Cpu))
+                    .collect::<Result<Vec<_>>>()?
+            }
+            _ => {
+                let ys = self.run_cpu_::<f32>(shape.elem_count())?;
+                ys.into_iter()
+                    .map(|y| Tensor::from_vec(y, shape, &Device::Cpu))
+                    .collect::<Result<Vec<_>>>()?
+            }
+        };
+        ys.into_iter()
+            .zip(outputs.iter())
+            .map(|(y, o)| y.to_dtype(o.dtype()))
+            .collect()
+    }
+
+    fn run_cpu_<T: Compute>(&self, elem_count: usize) -> Result<Vec<Vec<T>>> {
+        let guards: Vec<_> = self.inputs.iter().map(|t| t.storage()).collect();
+        let inputs = guards
+            .iter()
+            .zip(self.inputs.iter())
+            .map(|(storage, t)| Input::new(storage, t.layout()))
+            .collect::<Result<Vec<_>>>()?;
+        let mut ys = vec![vec![T::default(); elem_count]; self.outputs.len()];
+        let mut chunks: Vec<Vec<&mut [T]>> = vec![];
+        for y in ys.iter_mut() {
+            for (chunk_idx, chunk) in y.chunks_mut(CHUNK_SIZE).enumerate() {
+                if chunk_idx >= chunks.len() {
+                    chunks.push(vec![])
+                }
+                chunks[chunk_idx].push(chunk)
+            }
+        }
+        chunks
+            .into_par_iter()
+            .enumerate()
+            .for_each(|(chunk_idx, mut out_chunks)| {
+                let start = chunk_idx * CHUNK_SIZE;
+                let len = out_chunks[0].len();
+                let mut regs = vec![vec![T::default(); len]; self.instrs.len()];
+                for (i, instr) in self.instrs.iter().enumerate() {
+                    let (prev, cur) = regs.split_at_mut(i);
+                    let dst = &mut cur[0];
+                    match instr {
+                        Instr::Load(input) => inputs[*input].load(start, dst),
+                        Instr::Const(v, _, _) => dst.fill(T::from_f64(*v)),
+                        Instr::Unary(op, arg) => {
+                            for (d, &v) in dst.iter_mut().zip(prev[*arg].iter()) {
+                                *d = T::unary(*op, v)
+                            }
+                        }
+                        Instr::Binary(op, lhs, rhs) => {
+                            let lhs = prev[*lhs].iter();
+                            for ((d, &l), &r) in dst.iter_mut().zip(lhs).zip(prev[*rhs].iter()) {
+                                *d = T::binary(*op, l, r)
+                            }
+                        }
+                        Instr::Affine(arg, mul, add) => {
+                            let (mul, add) = (T::from_f64(*mul), T::from_f64(*add));
+                            for (d, &v) in dst.iter_mut().zip(prev[*arg].iter()) {
+                                *d = v * mul + add
+                            }
+                        }
+                        Instr::Cast(arg, dtype) => {
+                            for (d, &v) in dst.iter_mut().zip(prev[*arg].iter()) {
+                                *d = T::cast(v, *dtype)
+                            }
+                        }
+                    }
+                }
+                for (out, &reg) in out_chunks.iter_mut().zip(self.outputs.iter()) {
+                    out.copy_from_slice(&regs[reg])
+                }
+            });
+        Ok(ys)
+    }
+}
+
+enum InputData<'a> {
+    BF16(&'a [half::bf16]),
+    F16(&'a [half::f16]),
+    F32(&'a [f32]),
+    F64(&'a [f64]),
+}
+
+struct Input<'a> {
+    data: InputData<'a>,
+    layout: &'a Layout,
+}
+
+impl<'a> Input<'a> {
+    fn new(storage: &'a Storage, layout: &'a Layout) -> Result<Self> {
+        use crate::CpuStorageRef;
+        let data = match storage {
+            Storage::Cpu(storage) => match storage.as_storage_ref() {
+                CpuStorageRef::BF16(vs) => InputData::BF16(vs),
+                CpuStorageRef::F16(vs) => InputData::F16(vs),
+                CpuStorageRef::F32(vs) => InputData::F32(vs),
+                CpuStorageRef::F64(vs) => InputData::F64(vs),
+                _ => bail!("lazy: unexpected storage for a fused cpu kernel"),
+            },
+            _ => bail!("lazy: unexpected storage for a fused cpu kernel"),
+        };
+        Ok(Self { data, layout })
+    }
+
+    /// The offset in the storage for the element at index `index` in the flattened tensor.
+    fn offset(&self, mut index: usize) -> usize {
+        let mut offset = self.layout.start_offset();
+        for (&dim, &stride) in self.layout.dims().iter().zip(self.layout.stride()).rev() {
+            offset += (index % dim) * stride;
+            index /= dim;
+        }
+        offset
+    }
+
+    fn load<T: Compute>(&self, start: usize, dst: &mut [T]) {
+        fn load_<S: Copy, T>(input: &Input, vs: &[S], start: usize, dst: &mut [T], f: fn(S) -> T) {
+            match input.layout.contiguous_offsets() {
+                Some((o1, _)) => {
+                    let vs = &vs[o1 + start..o1 + start + dst.len()];
+                    for (d, &v) in dst.iter_mut().zip(vs.iter()) {
+                        *d = f(v)
+                    }
+                }
+                None => {
+                    for (i, d) in dst.iter_mut().enumerate() {
+                        *d = f(vs[input.offset(start + i)])
+                    }
+                }
+            }
+        }
+        match self.data {
+            InputData::BF16(vs) => load_(self, vs, start, dst, |v| T::from_f64(v.to_f64())),
+            InputData::F16(vs) => load_(self, vs, start, dst, |v| T::from_f64(v.to_f64())),
+            InputData::F32(vs) => load_(self, vs, start, dst, T::from_f32),
+            InputData::F64(vs) => load_(self, vs, start, dst, T::from_f64),
+        }
+    }
+}
+
+trait Compute:
+    Copy
+    + Default
+    + Send
+    + Sync
+    + std::ops::Mul<Output = Self>
+    + std::ops::Add<Output = Self>
+    + crate::WithDType
+{
+    fn from_f32(v: f32) -> Self;
+    /// Rounds the value to the precision of `dtype`.
+    fn cast(v: Self, dtype: DType) -> Self;
+    fn unary(op: UnaryOp, v: Self) -> Self;
+    fn binary(op: BinaryOp, v1: Self, v2: Self) -> Self;
+}
+
+impl Compute for f32 {
+    fn from_f32(v: f32) -> Self {
+        v
+    }
+
+    fn cast(v: Self, dtype: DType) -> Self {
+        match dtype {
+            DType::F16 => half::f16::from_f32(v).to_f32(),
+            DType::BF16 => half::bf16::from_f32(v).to_f32(),
+            _ => v,
+        }
+    }
+
+    fn unary(op: UnaryOp, v: Self) -> Self {
+        op.f32(v)
+    }
+
+    fn binary(op: BinaryOp, v1: Self, v2: Self) -> Self {
+        op.f32(v1, v2)
+    }
+}
+
+impl Compute for f64 {
+    fn from_f32(v: f32) -> Self {
+        v as f64
+    }
+
+    fn cast(v: Self, dtype: DType) -> Self {
+        match dtype {
+            DType::F16 => half::f16::from_f64(v).to_f64(),
+            DType::BF16 => half::bf16::from_f64(v).to_f64(),
+            DType::F32 => v as f32 as f64,
+            _ => v,
+        }
+    }
+
+    fn unary(op: UnaryOp, v: Self) -> Self {
+        op.f64(v)
+    }
+
+    fn binary(op: BinaryOp, v1: Self, v2: Self) -> Self {
+        op.f64(v1, v2)
+    }
+}
+
+macro_rules! bin_trait {
+    ($trait:ident, $fn1:ident, $mul:expr, $add:expr) => {
+        impl std::ops::$trait<LazyTensor> for LazyTensor {
+            type Output = Result<LazyTensor>;
+
+            fn $fn1(self, rhs: LazyTensor) -> Self::Output {
+                LazyTensor::$fn1(&self, &rhs)
+            }
+        }
+
+        impl std::ops::$trait<&LazyTensor> for LazyTensor {
+            type Output = Result<LazyTensor>;
+
+            fn $fn1(self, rhs: &LazyTensor) -> Self::Output {
+                LazyTensor::$fn1(&self, rhs)
+            }
+        }
+
+        impl std::ops::$trait<LazyTensor> for &LazyTensor {
+            type Output = Result<LazyTensor>;
+
+            fn $fn1(self, rhs: LazyTensor) -> Self::Output {
+                LazyTensor::$fn1(self, &rhs)
+            }
+        }
+
+        impl std::ops::$trait<&LazyTensor> for &LazyTensor {
+            type Output = Result<LazyTensor>;
+
+            fn $fn1(self, rhs: &LazyTensor) -> Self::Output {
+                LazyTensor::$fn1(self, rhs)
+            }
+        }
+
+        impl std::ops::$trait<f64> for LazyTensor {
+            type Output = Result<LazyTensor>;
+
+            fn $fn1(self, rhs: f64) -> Self::Output {
+                self.affine($mul(rhs), $add(rhs))
+            }
+        }
+
+        impl std::ops::$trait<f64> for &LazyTensor {
+            type Output = Result<LazyTensor>;
+
+            fn $fn1(self, rhs: f64) -> Self::Output {
+                self.affine($mul(rhs), $add(rhs))
+            }
+        }
+    };
+}
+
+bin_trait!(Add, add, |_| 1., |v| v);
+bin_trait!(Sub, sub, |_| 1., |v: f64| -v);
+bin_trait!(Mul, mul, |v| v, |_| 0.);
+bin_trait!(Div, div, |v| 1. / v, |_| 0.);
@@ -49,8 +49,10 @@
 
 #[cfg(feature = "accelerate")]
 mod accelerate;
+pub mod anomaly;
 pub mod backend;
 pub mod backprop;
+pub mod checkpoint_diff;
 pub mod conv;
 mod convert;
 pub mod cpu;
@@ -58,22 +60,28 @@ pub mod cpu_backend;
 #[cfg(feature = "cuda")]
 pub mod cuda_backend;
 mod custom_op;
+pub mod determinism;
 mod device;
 pub mod display;
+pub mod dlpack;
 mod dtype;
 pub mod dummy_cuda_backend;
 mod dummy_metal_backend;
 pub mod error;
 mod indexer;
 pub mod layout;
+pub mod lazy;
 #[cfg(feature = "metal")]
 pub mod metal_backend;
 #[cfg(feature = "mkl")]
 mod mkl;
 pub mod npy;
+pub mod offload;
 pub mod op;
 pub mod pickle;
+pub mod profiler;
 pub mod quantized;
+pub mod rng;
 pub mod safetensors;
 pub mod scalar;
 pub mod shape;
@@ -83,6 +91,10 @@ pub mod streaming;
 mod strided_index;
 mod tensor;
 mod tensor_cat;
+mod tensor_inplace;
+mod tensor_names;
+#[cfg(feature = "serde")]
+pub mod tensor_serde;
 pub mod test_utils;
 pub mod utils;
 mod variable;
@@ -90,13 +102,15 @@ mod variable;
 #[cfg(feature = "cudnn")]
 pub use cuda_backend::cudnn;
 
-pub use cpu_backend::{CpuStorage, CpuStorageRef};
+pub use cpu_backend::{CpuStorage, CpuStorageRef, ForeignStorage};
 pub use custom_op::{CustomOp1, CustomOp2, CustomOp3, InplaceOp1, InplaceOp2, InplaceOp3, UgIOp1};
-pub use device::{Device, DeviceLocation, NdArray};
+pub use determinism::{is_deterministic, set_deterministic};
+pub use device::{Device, DeviceLocation, MemoryStats, NdArray};
 pub use dtype::{DType, DTypeParseError, FloatDType, IntDType, WithDType};
 pub use error::{Error, Result};
 pub use indexer::{IndexOp, TensorIndexer};
 pub use layout::Layout;
+pub use lazy::LazyTensor;
 pub use shape::{Shape, D};
 pub use storage::Storage;
 pub use streaming::{StreamTensor, StreamingBinOp, StreamingModule};
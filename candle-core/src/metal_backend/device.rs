@@ -119,8 +119,10 @@ pub struct MetalDevice {
     /// Simple keeper struct to keep track of the already compiled kernels so we can reuse them.
     /// Heavily used by [`candle_metal_kernels`]
     pub(crate) kernels: Arc<Kernels>,
-    /// Seed for random number generation.
-    pub(crate) seed: Arc<Mutex<Buffer>>,
+    /// State of the random number generator.
+    pub(crate) rng: Arc<Mutex<crate::rng::RngState>>,
+    /// The peak memory usage, sampled when new buffers get allocated and before each sweep.
+    pub(crate) peak_memory: Arc<Mutex<crate::MemoryStats>>,
     /// Whether to use the MLX matmul kernels instead of the MFA ones.
     pub(crate) use_mlx_mm: bool,
 }
@@ -176,6 +178,7 @@ impl MetalDevice {
 
     fn drop_unused_buffers(&self) -> Result<()> {
         let mut buffers = self.buffers.write().map_err(MetalError::from)?;
+        self.update_peak_memory(&buffers);
         for subbuffers in buffers.values_mut() {
             let newbuffers = subbuffers
                 .iter()
@@ -187,6 +190,45 @@ impl MetalDevice {
         Ok(())
     }
 
+    // The memory used by the buffers in use and by all the buffers of the pool.
+    fn memory_usage(buffers: &BufferMap) -> (usize, usize) {
+        let (mut allocated, mut reserved) = (0, 0);
+        for buffer in buffers.values().flatten() {
+            let size = buffer.length() as usize;
+            reserved += size;
+            if Arc::strong_count(buffer) > 1 {
+                allocated += size
+            }
+        }
+        (allocated, reserved)
+    }
+
+    fn update_peak_memory(&self, buffers: &BufferMap) -> crate::MemoryStats {
+        let (allocated, reserved) = Self::memory_usage(buffers);
+        let mut peak = self.peak_memory.lock().unwrap();
+        peak.peak_allocated = peak.peak_allocated.max(allocated);
+        peak.peak_reserved = peak.peak_reserved.max(reserved);
+        crate::MemoryStats {
+            allocated,
+            reserved,
+            ..*peak
+        }
+    }
+
+    pub fn memory_stats(&self) -> Result<crate::MemoryStats> {
+        let buffers = self.buffers.read().map_err(MetalError::from)?;
+        Ok(self.update_peak_memory(&buffers))
+    }
+
+    pub fn reset_peak_memory_stats(&self) -> Result<()> {
+        let buffers = self.buffers.read().map_err(MetalError::from)?;
+        let (allocated, reserved) = Self::memory_usage(&buffers);
+        let mut peak = self.peak_memory.lock().unwrap();
+        peak.peak_allocated = allocated;
+        peak.peak_reserved = reserved;
+        Ok(())
+    }
+
     pub fn command_buffer(&self) -> Result<CommandBuffer> {
         let mut commands = self.commands.write().map_err(MetalError::from)?;
         let (flushed, command_buffer) = commands.command_buffer()?;
@@ -253,6 +295,7 @@ impl MetalDevice {
 
         let new_buffer = Arc::new(new_buffer);
         subbuffers.push(new_buffer.clone());
+        self.update_peak_memory(&buffers);
         Ok(new_buffer)
     }
 
@@ -296,6 +339,7 @@ impl MetalDevice {
         let new_buffer = self.device.new_buffer(size as NSUInteger, option);
         let new_buffer = Arc::new(new_buffer);
         subbuffers.push(new_buffer.clone());
+        self.update_peak_memory(&buffers);
 
         Ok(new_buffer)
     }
@@ -5,9 +5,8 @@ use crate::conv::{ParamsConv1D, ParamsConv2D, ParamsConvTranspose1D, ParamsConvT
 use crate::op::{BinaryOpT, CmpOp, ReduceOp, UnaryOpT};
 use crate::{CpuStorage, CpuStorageRef, DType, Layout, Result, Shape};
 use candle_metal_kernels::{BufferOffset, CallConvTranspose2dCfg, Kernels};
-use metal::{Buffer, MTLResourceOptions, NSUInteger};
+use metal::{Buffer, NSUInteger};
 use std::collections::HashMap;
-use std::ffi::c_void;
 use std::sync::{Arc, Mutex, PoisonError, RwLock, TryLockError};
 
 mod device;
@@ -1882,10 +1881,8 @@ impl BackendDevice for MetalDevice {
             Ok("false") | Ok("False") | Ok("FALSE") | Ok("0") | Err(_) => true,
             Ok(_) => false,
         };
-        let seed = Arc::new(Mutex::new(device.new_buffer_with_data(
-            [299792458].as_ptr() as *const c_void,
-            4,
-            MTLResourceOptions::StorageModeManaged,
+        let rng = Arc::new(Mutex::new(crate::rng::RngState::new(
+            crate::rng::DEFAULT_SEED,
         )));
         let commands = device::Commands::new(command_queue)?;
         Ok(Self {
@@ -1894,7 +1891,8 @@ impl BackendDevice for MetalDevice {
             commands: Arc::new(RwLock::new(commands)),
             buffers: Arc::new(RwLock::new(HashMap::new())),
             kernels,
-            seed,
+            rng,
+            peak_memory: Arc::new(Mutex::new(Default::default())),
             use_mlx_mm,
         })
     }
@@ -1978,14 +1976,14 @@ impl BackendDevice for MetalDevice {
     }
 
     fn storage_from_cpu_storage(&self, storage: &CpuStorage) -> Result<Self::Storage> {
-        let (count, buffer) = match storage {
-            CpuStorage::U8(storage) => (storage.len(), self.new_buffer_with_data(storage)),
-            CpuStorage::U32(storage) => (storage.len(), self.new_buffer_with_data(storage)),
-            CpuStorage::I64(storage) => (storage.len(), self.new_buffer_with_data(storage)),
-            CpuStorage::BF16(storage) => (storage.len(), self.new_buffer_with_data(storage)),
-            CpuStorage::F16(storage) => (storage.len(), self.new_buffer_with_data(storage)),
-            CpuStorage::F32(storage) => (storage.len(), self.new_buffer_with_data(storage)),
-            CpuStorage::F64(storage) => (storage.len(), self.new_buffer_with_data(storage)),
+        let (count, buffer) = match storage.as_storage_ref() {
+            CpuStorageRef::U8(storage) => (storage.len(), self.new_buffer_with_data(storage)),
+            CpuStorageRef::U32(storage) => (storage.len(), self.new_buffer_with_data(storage)),
+            CpuStorageRef::I64(storage) => (storage.len(), self.new_buffer_with_data(storage)),
+            CpuStorageRef::BF16(storage) => (storage.len(), self.new_buffer_with_data(storage)),
+            CpuStorageRef::F16(storage) => (storage.len(), self.new_buffer_with_data(storage)),
+            CpuStorageRef::F32(storage) => (storage.len(), self.new_buffer_with_data(storage)),
+            CpuStorageRef::F64(storage) => (storage.len(), self.new_buffer_with_data(storage)),
         };
         Ok(Self::Storage::new(
             buffer?,
@@ -2013,6 +2011,8 @@ impl BackendDevice for MetalDevice {
             dtype => crate::bail!("rand_uniform not implemented for {dtype:?}"),
         };
         let buffer = self.new_buffer(shape.elem_count(), dtype, "rand_uniform")?;
+        let mut rng = self.rng.lock().unwrap();
+        let offset = rng.advance(shape.elem_count());
         let command_buffer = self.command_buffer()?;
         candle_metal_kernels::call_random_uniform(
             &self.device,
@@ -2022,7 +2022,8 @@ impl BackendDevice for MetalDevice {
             min as f32,
             max as f32,
             shape.elem_count(),
-            &self.seed.lock().unwrap(),
+            rng.seed,
+            offset,
             &buffer,
         )
         .map_err(MetalError::from)?;
@@ -2049,6 +2050,8 @@ impl BackendDevice for MetalDevice {
             dtype => crate::bail!("rand_uniform not implemented for {dtype:?}"),
         };
         let buffer = self.new_buffer(shape.elem_count(), dtype, "rand_normal")?;
+        let mut rng = self.rng.lock().unwrap();
+        let offset = rng.advance(shape.elem_count());
         let command_buffer = self.command_buffer()?;
         candle_metal_kernels::call_random_normal(
             &self.device,
@@ -2058,7 +2061,8 @@ impl BackendDevice for MetalDevice {
             mean as f32,
             stddev as f32,
             shape.elem_count(),
-            &self.seed.lock().unwrap(),
+            rng.seed,
+            offset,
             &buffer,
         )
         .map_err(MetalError::from)?;
@@ -2072,20 +2076,26 @@ impl BackendDevice for MetalDevice {
     }
 
     fn set_seed(&self, seed: u64) -> Result<()> {
-        let seed: u32 = seed.try_into().map_err(|_| {
-            MetalError::Message("Metal seed must be less than or equal to u32::MAX".to_string())
-        })?;
-
-        let seed_buffer = self.seed.try_lock().map_err(MetalError::from)?;
-        let contents = seed_buffer.contents();
-        unsafe {
-            std::ptr::copy([seed].as_ptr(), contents as *mut u32, 1);
-        }
-        seed_buffer.did_modify_range(metal::NSRange::new(0, 4));
+        self.set_rng_state(crate::rng::RngState::new(seed))
+    }
+
+    fn rng_state(&self) -> Result<crate::rng::RngState> {
+        Ok(*self.rng.lock().unwrap())
+    }
 
+    fn set_rng_state(&self, state: crate::rng::RngState) -> Result<()> {
+        *self.rng.lock().unwrap() = state;
         Ok(())
     }
 
+    fn memory_stats(&self) -> Result<crate::MemoryStats> {
+        MetalDevice::memory_stats(self)
+    }
+
+    fn reset_peak_memory_stats(&self) -> Result<()> {
+        MetalDevice::reset_peak_memory_stats(self)
+    }
+
     fn synchronize(&self) -> Result<()> {
         self.wait_until_completed()
     }
@@ -4,7 +4,17 @@
 //! [npy-format](https://docs.scipy.org/doc/numpy-1.14.2/neps/npy-format.html).
 //! The functions from this module can be used to read tensors from npy/npz files
 //! or write tensors to these files. A npy file contains a single tensor (unnamed)
-//! whereas a npz file can contain multiple named tensors. npz files are also compressed.
+//! whereas a npz file is a zip archive of npy files with multiple named tensors.
+//!
+//! All the numpy boolean, integer and floating point types can be read, in either byte order
+//! and in row-major or column-major (fortran) order. The types that do not exist in candle are
+//! converted: booleans are loaded as `u8`, `u16` as `u32`, and the other integers as `i64`.
+//! numpy does not have a bf16 type, bf16 tensors are written with the `V2` void type used by
+//! `ml_dtypes.bfloat16` and `V2` arrays are loaded as bf16. Tensors are always written in
+//! little-endian row-major order.
+//!
+//! Large arrays can be written chunk by chunk with [`NpyWriter`] and [`NpzWriter`], and read
+//! partially through a memory mapping with [`MmapedNpy`] and [`MmapedNpz`].
 //!
 //! These two formats are easy to use in Python using the numpy library.
 //!
@@ -35,6 +45,9 @@ use std::path::Path;
 
 const NPY_MAGIC_STRING: &[u8] = b"\x93NUMPY";
 const NPY_SUFFIX: &str = ".npy";
+// The data is aligned on this number of bytes, as done by numpy, so that memory mapped arrays
+// can be used without copies.
+const NPY_ALIGNMENT: usize = 64;
 
 fn read_header<R: Read>(reader: &mut R) -> Result<String> {
     let mut magic_string = vec![0u8; NPY_MAGIC_STRING.len()];
@@ -46,7 +59,7 @@ fn read_header<R: Read>(reader: &mut R) -> Result<String> {
     reader.read_exact(&mut version)?;
     let header_len_len = match version[0] {
         1 => 2,
-        2 => 4,
+        2 | 3 => 4,
         otherwise => return Err(Error::Npy(format!("unsupported version {otherwise}"))),
     };
     let mut header_len = vec![0u8; header_len_len];
@@ -60,18 +73,132 @@ fn read_header<R: Read>(reader: &mut R) -> Result<String> {
     Ok(String::from_utf8_lossy(&header).to_string())
 }
 
+/// The element type of an array as stored in a npy file, e.g. `<i4` is a little-endian signed
+/// integer with 4 bytes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Descr {
+    kind: char,
+    size: usize,
+    big_endian: bool,
+}
+
+impl Descr {
+    // numpy does not have a bf16 type, ml_dtypes saves its bfloat16 arrays with the `V2` void
+    // type of the same size.
+    fn of(dtype: DType) -> Self {
+        let (kind, size) = match dtype {
+            DType::U8 => ('u', 1),
+            DType::U32 => ('u', 4),
+            DType::I64 => ('i', 8),
+            DType::BF16 => ('V', 2),
+            DType::F16 => ('f', 2),
+            DType::F32 => ('f', 4),
+            DType::F64 => ('f', 8),
+        };
+        Self {
+            kind,
+            size,
+            big_endian: false,
+        }
+    }
+
+    fn parse(descr: &str) -> Result<Self> {
+        let (big_endian, code) = match descr.chars().next() {
+            Some('>') => (true, &descr[1..]),
+            Some('<' | '=' | '|') => (false, &descr[1..]),
+            _ => (false, descr),
+        };
+        let (kind, size) = match code {
+            "?" => ('b', 1),
+            "b" => ('i', 1),
+            "B" => ('u', 1),
+            "h" => ('i', 2),
+            "H" => ('u', 2),
+            "i" => ('i', 4),
+            "I" => ('u', 4),
+            "l" | "q" => ('i', 8),
+            "L" | "Q" => ('u', 8),
+            "e" => ('f', 2),
+            "f" => ('f', 4),
+            "d" => ('f', 8),
+            code => {
+                let mut chars = code.chars();
+                match (chars.next(), chars.as_str().parse::<usize>()) {
+                    (Some(kind), Ok(size)) => (kind, size),
+                    _ => return Err(Error::Npy(format!("unrecognized descr {descr}"))),
+                }
+            }
+        };
+        let descr = Self {
+            kind,
+            size,
+            big_endian,
+        };
+        // Checks that the type is supported.
+        descr.dtype()?;
+        Ok(descr)
+    }
+
+    /// The dtype of the tensors for this element type, the integer types that do not exist in
+    /// candle are converted to a larger type and booleans are loaded as `u8`.
+    fn dtype(&self) -> Result<DType> {
+        let dtype = match (self.kind, self.size) {
+            ('b', 1) | ('u', 1) => DType::U8,
+            ('u', 2) | ('u', 4) => DType::U32,
+            ('i', 1) | ('i', 2) | ('i', 4) | ('i', 8) | ('u', 8) => DType::I64,
+            ('V', 2) => DType::BF16,
+            ('f', 2) => DType::F16,
+            ('f', 4) => DType::F32,
+            ('f', 8) => DType::F64,
+            (kind, size) => return Err(Error::Npy(format!("unsupported descr {kind}{size}"))),
+        };
+        Ok(dtype)
+    }
+}
+
+impl std::fmt::Display for Descr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let byte_order = match (self.size, self.big_endian) {
+            (1, _) => '|',
+            (_, true) => '>',
+            (_, false) => '<',
+        };
+        write!(f, "{byte_order}{}{}", self.kind, self.size)
+    }
+}
+
 #[derive(Debug, PartialEq)]
 struct Header {
     descr: DType,
+    stored: Descr,
     fortran_order: bool,
     shape: Vec<usize>,
 }
 
 impl Header {
+    fn new(dtype: DType, shape: &[usize]) -> Self {
+        Self {
+            descr: dtype,
+            stored: Descr::of(dtype),
+            fortran_order: false,
+            shape: shape.to_vec(),
+        }
+    }
+
     fn shape(&self) -> Shape {
         Shape::from(self.shape.as_slice())
     }
 
+    /// The size of the data in bytes.
+    fn data_len(&self) -> usize {
+        self.shape.iter().product::<usize>() * self.stored.size
+    }
+
+    // Whether the data can be read as is in a tensor.
+    fn is_native(&self) -> bool {
+        !self.fortran_order && self.stored == Descr::of(self.descr)
+    }
+
     fn to_string(&self) -> Result<String> {
         let fortran_order = if self.fortran_order { "True" } else { "False" };
         let mut shape = self
@@ -80,20 +207,12 @@ impl Header {
             .map(|x| x.to_string())
             .collect::<Vec<_>>()
             .join(",");
-        let descr = match self.descr {
-            DType::BF16 => Err(Error::Npy("bf16 is not supported".into()))?,
-            DType::F16 => "f2",
-            DType::F32 => "f4",
-            DType::F64 => "f8",
-            DType::I64 => "i8",
-            DType::U32 => "u4",
-            DType::U8 => "u1",
-        };
+        let descr = self.stored.to_string();
         if !shape.is_empty() {
             shape.push(',')
         }
         Ok(format!(
-            "{{'descr': '<{descr}', 'fortran_order': {fortran_order}, 'shape': ({shape}), }}"
+            "{{'descr': '{descr}', 'fortran_order': {fortran_order}, 'shape': ({shape}), }}"
         ))
     }
 
@@ -142,35 +261,13 @@ impl Header {
                 _ => return Err(Error::Npy(format!("unknown fortran_order {fortran_order}"))),
             },
         };
-        let descr = match part_map.get("descr") {
+        let stored = match part_map.get("descr") {
             None => return Err(Error::Npy("no descr in header".to_string())),
             Some(descr) => {
                 if descr.is_empty() {
                     return Err(Error::Npy("empty descr".to_string()));
                 }
-                if descr.starts_with('>') {
-                    return Err(Error::Npy(format!("little-endian descr {descr}")));
-                }
-                // the only supported types in tensor are:
-                //     float64, float32, float16,
-                //     complex64, complex128,
-                //     int64, int32, int16, int8,
-                //     uint8, and bool.
-                match descr.trim_matches(|c: char| c == '=' || c == '<' || c == '|') {
-                    "e" | "f2" => DType::F16,
-                    "f" | "f4" => DType::F32,
-                    "d" | "f8" => DType::F64,
-                    // "i" | "i4" => DType::S32,
-                    "q" | "i8" => DType::I64,
-                    // "h" | "i2" => DType::S16,
-                    // "b" | "i1" => DType::S8,
-                    "B" | "u1" => DType::U8,
-                    "I" | "u4" => DType::U32,
-                    "?" | "b1" => DType::U8,
-                    // "F" | "F4" => DType::C64,
-                    // "D" | "F8" => DType::C128,
-                    descr => return Err(Error::Npy(format!("unrecognized descr {descr}"))),
-                }
+                Descr::parse(descr)?
             }
         };
         let shape = match part_map.get("shape") {
@@ -188,13 +285,115 @@ impl Header {
             }
         };
         Ok(Header {
-            descr,
+            descr: stored.dtype()?,
+            stored,
             fortran_order,
             shape,
         })
     }
 }
 
+fn write_header<T: Write>(f: &mut T, dtype: DType, shape: &[usize]) -> Result<()> {
+    let mut header = Header::new(dtype, shape).to_string()?;
+    // The version 2 has a 4 bytes header length, it is only used for large headers.
+    let header_len_len = if header.len() + 1 + NPY_ALIGNMENT > u16::MAX as usize {
+        4
+    } else {
+        2
+    };
+    let prefix_len = NPY_MAGIC_STRING.len() + 2 + header_len_len;
+    let pad = NPY_ALIGNMENT - (prefix_len + header.len() + 1) % NPY_ALIGNMENT;
+    for _ in 0..pad % NPY_ALIGNMENT {
+        header.push(' ')
+    }
+    header.push('\n');
+    f.write_all(NPY_MAGIC_STRING)?;
+    if header_len_len == 2 {
+        f.write_all(&[1u8, 0u8])?;
+        f.write_all(&(header.len() as u16).to_le_bytes())?;
+    } else {
+        f.write_all(&[2u8, 0u8])?;
+        f.write_all(&(header.len() as u32).to_le_bytes())?;
+    }
+    f.write_all(header.as_bytes())?;
+    Ok(())
+}
+
+// Converts elements of `N` bytes to a supported type.
+fn widen<const N: usize, T: crate::WithDType>(
+    data: &[u8],
+    shape: &[usize],
+    conv: impl Fn([u8; N]) -> Result<T>,
+) -> Result<Tensor> {
+    let data = data
+        .chunks_exact(N)
+        .map(|c| conv(c.try_into().expect("chunks of N bytes")))
+        .collect::<Result<Vec<_>>>()?;
+    Tensor::from_vec(data, shape, &Device::Cpu)
+}
+
+// Creates a tensor from the data of an array with the given header, `data` is the data of
+// the array in little-endian.
+fn decode_le(header: &Header, data: &[u8], shape: &[usize], device: &Device) -> Result<Tensor> {
+    let tensor = match (header.stored.kind, header.stored.size) {
+        ('b', 1) => widen(data, shape, |[b]| Ok((b != 0) as u8))?,
+        ('u', 2) => widen(data, shape, |b| Ok(u16::from_le_bytes(b) as u32))?,
+        ('i', 1) => widen(data, shape, |b| Ok(i8::from_le_bytes(b) as i64))?,
+        ('i', 2) => widen(data, shape, |b| Ok(i16::from_le_bytes(b) as i64))?,
+        ('i', 4) => widen(data, shape, |b| Ok(i32::from_le_bytes(b) as i64))?,
+        ('u', 8) => widen(data, shape, |b| {
+            let v = u64::from_le_bytes(b);
+            i64::try_from(v).map_err(|_| Error::Npy(format!("u64 value {v} overflows i64")))
+        })?,
+        _ => return Tensor::from_raw_buffer(data, header.descr, shape, device),
+    };
+    tensor.to_device(device)
+}
+
+/// Creates a tensor from the data of an array with the given header, `data` has to contain at
+/// least [`Header::data_len`] bytes.
+fn decode(header: &Header, data: &[u8], device: &Device) -> Result<Tensor> {
+    let data_len = header.data_len();
+    if data.len() < data_len {
+        Err(Error::Npy(format!(
+            "only {} bytes of data for an array of {data_len} bytes",
+            data.len()
+        )))?
+    }
+    let data = &data[..data_len];
+    let size = header.stored.size;
+    let swapped;
+    let data = if header.stored.big_endian && size > 1 {
+        let mut bytes = data.to_vec();
+        bytes.chunks_exact_mut(size).for_each(|c| c.reverse());
+        swapped = bytes;
+        swapped.as_slice()
+    } else {
+        data
+    };
+    if header.fortran_order {
+        // The data in column-major order is the transpose of the data in row-major order.
+        let shape = header.shape.iter().rev().copied().collect::<Vec<_>>();
+        let tensor = decode_le(header, data, &shape, device)?;
+        let dims = (0..shape.len()).rev().collect::<Vec<_>>();
+        tensor.permute(dims)?.contiguous()
+    } else {
+        decode_le(header, data, &header.shape, device)
+    }
+}
+
+// Reads the header and the data of an array.
+fn read_array<R: Read>(reader: &mut R) -> Result<Tensor> {
+    let header = read_header(reader)?;
+    let header = Header::parse(&header)?;
+    if header.is_native() {
+        return Tensor::from_reader(header.shape(), header.descr, reader);
+    }
+    let mut data = vec![0u8; header.data_len()];
+    reader.read_exact(&mut data)?;
+    decode(&header, &data, &Device::Cpu)
+}
+
 impl Tensor {
     // TODO: Add the possibility to read directly to a device?
     pub(crate) fn from_reader<R: std::io::Read>(
@@ -244,13 +443,8 @@ impl Tensor {
 
     /// Reads a npy file and return the stored multi-dimensional array as a tensor.
     pub fn read_npy<T: AsRef<Path>>(path: T) -> Result<Self> {
-        let mut reader = File::open(path.as_ref())?;
-        let header = read_header(&mut reader)?;
-        let header = Header::parse(&header)?;
-        if header.fortran_order {
-            return Err(Error::Npy("fortran order not supported".to_string()));
-        }
-        Self::from_reader(header.shape(), header.descr, &mut reader)
+        let mut reader = BufReader::new(File::open(path.as_ref())?);
+        read_array(&mut reader)
     }
 
     /// Reads a npz file and returns the stored multi-dimensional arrays together with their names.
@@ -264,12 +458,7 @@ impl Tensor {
                 let name = reader.name();
                 name.strip_suffix(NPY_SUFFIX).unwrap_or(name).to_owned()
             };
-            let header = read_header(&mut reader)?;
-            let header = Header::parse(&header)?;
-            if header.fortran_order {
-                return Err(Error::Npy("fortran order not supported".to_string()));
-            }
-            let s = Self::from_reader(header.shape(), header.descr, &mut reader)?;
+            let s = read_array(&mut reader)?;
             result.push((name, s))
         }
         Ok(result)
@@ -288,33 +477,14 @@ impl Tensor {
                     path.as_ref()
                 )))?,
             };
-            let header = read_header(&mut reader)?;
-            let header = Header::parse(&header)?;
-            if header.fortran_order {
-                return Err(Error::Npy("fortran order not supported".to_string()));
-            }
-            let s = Self::from_reader(header.shape(), header.descr, &mut reader)?;
+            let s = read_array(&mut reader)?;
             result.push(s)
         }
         Ok(result)
     }
 
     fn write<T: Write>(&self, f: &mut T) -> Result<()> {
-        f.write_all(NPY_MAGIC_STRING)?;
-        f.write_all(&[1u8, 0u8])?;
-        let header = Header {
-            descr: self.dtype(),
-            fortran_order: false,
-            shape: self.dims().to_vec(),
-        };
-        let mut header = header.to_string()?;
-        let pad = 16 - (NPY_MAGIC_STRING.len() + 5 + header.len()) % 16;
-        for _ in 0..pad % 16 {
-            header.push(' ')
-        }
-        header.push('\n');
-        f.write_all(&[(header.len() % 256) as u8, (header.len() / 256) as u8])?;
-        f.write_all(header.as_bytes())?;
+        write_header(f, self.dtype(), self.dims())?;
         self.write_bytes(f)
     }
 
@@ -341,6 +511,154 @@ impl Tensor {
     }
 }
 
+/// Writes multiple multi-dimensional arrays using the npz format, see [`Tensor::write_npz`].
+pub fn write_npz<S: AsRef<str>, T: AsRef<Tensor>, P: AsRef<Path>>(
+    path: P,
+    ts: &[(S, T)],
+) -> Result<()> {
+    Tensor::write_npz(ts, path)
+}
+
+/// Writes an array in the npy format chunk by chunk, so that arrays that do not fit in memory
+/// can be written.
+///
+/// The shape of the full array is written in the header upfront, the chunks contain the
+/// elements of the array in row-major order and can have any shape.
+///
+/// ```no_run
+/// # fn main() -> candle_core::Result<()> {
+/// use candle_core::{npy::NpyWriter, DType, Device, Tensor};
+/// let mut writer = NpyWriter::create("activations.npy", DType::F32, (1024, 4096))?;
+/// for _ in 0..16 {
+///     writer.write(&Tensor::zeros((64, 4096), DType::F32, &Device::Cpu)?)?;
+/// }
+/// writer.finish()?;
+/// # Ok(())
+/// # }
+/// ```
+pub struct NpyWriter<W: Write> {
+    writer: std::io::BufWriter<W>,
+    dtype: DType,
+    shape: Shape,
+    written: usize,
+}
+
+impl NpyWriter<File> {
+    pub fn create<P: AsRef<Path>, S: Into<Shape>>(path: P, dtype: DType, shape: S) -> Result<Self> {
+        Self::new(File::create(path.as_ref())?, dtype, shape)
+    }
+}
+
+impl<W: Write> NpyWriter<W> {
+    pub fn new<S: Into<Shape>>(writer: W, dtype: DType, shape: S) -> Result<Self> {
+        let shape = shape.into();
+        let mut writer = std::io::BufWriter::new(writer);
+        write_header(&mut writer, dtype, shape.dims())?;
+        Ok(Self {
+            writer,
+            dtype,
+            shape,
+            written: 0,
+        })
+    }
+
+    /// The number of elements written so far.
+    pub fn written(&self) -> usize {
+        self.written
+    }
+
+    /// Appends the elements of `chunk` to the array.
+    pub fn write(&mut self, chunk: &Tensor) -> Result<()> {
+        if chunk.dtype() != self.dtype {
+            Err(Error::DTypeMismatchBinaryOp {
+                lhs: self.dtype,
+                rhs: chunk.dtype(),
+                op: "npy-write",
+            }
+            .bt())?
+        }
+        let elem_count = self.shape.elem_count();
+        if self.written + chunk.elem_count() > elem_count {
+            Err(Error::Npy(format!(
+                "too many elements written, {} + {} > {elem_count}",
+                self.written,
+                chunk.elem_count()
+            )))?
+        }
+        chunk.write_bytes(&mut self.writer)?;
+        self.written += chunk.elem_count();
+        Ok(())
+    }
+
+    /// Checks that all the elements have been written and flushes the underlying writer.
+    pub fn finish(self) -> Result<W> {
+        let elem_count = self.shape.elem_count();
+        if self.written != elem_count {
+            Err(Error::Npy(format!(
+                "only {} elements written out of {elem_count}",
+                self.written
+            )))?
+        }
+        self.writer
+            .into_inner()
+            .map_err(|e| Error::Io(e.into_error()))
+    }
+}
+
+/// Writes multiple named arrays in a npz file, the arrays can be written at once or chunk by
+/// chunk with [`NpzWriter::start_array`].
+pub struct NpzWriter<W: Write + std::io::Seek> {
+    zip: zip::ZipWriter<W>,
+}
+
+impl NpzWriter<File> {
+    pub fn create<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Ok(Self::new(File::create(path.as_ref())?))
+    }
+}
+
+impl<W: Write + std::io::Seek> NpzWriter<W> {
+    pub fn new(writer: W) -> Self {
+        Self {
+            zip: zip::ZipWriter::new(writer),
+        }
+    }
+
+    fn start_file(&mut self, name: &str) -> Result<()> {
+        let options: zip::write::FileOptions<()> = zip::write::FileOptions::default()
+            .compression_method(zip::CompressionMethod::Stored)
+            .large_file(true);
+        self.zip
+            .start_file(format!("{name}{NPY_SUFFIX}"), options)?;
+        Ok(())
+    }
+
+    pub fn write(&mut self, name: &str, tensor: &Tensor) -> Result<()> {
+        self.start_file(name)?;
+        let mut writer = std::io::BufWriter::new(&mut self.zip);
+        tensor.write(&mut writer)?;
+        writer.flush()?;
+        Ok(())
+    }
+
+    /// Starts a new array that gets written chunk by chunk, the returned writer has to be
+    /// finished before writing the next array.
+    pub fn start_array<S: Into<Shape>>(
+        &mut self,
+        name: &str,
+        dtype: DType,
+        shape: S,
+    ) -> Result<NpyWriter<&mut zip::ZipWriter<W>>> {
+        self.start_file(name)?;
+        NpyWriter::new(&mut self.zip, dtype, shape)
+    }
+
+    /// Writes the index of the archive.
+    pub fn finish(mut self) -> Result<W> {
+        Ok(self.zip.finish()?)
+    }
+}
+
 /// Lazy tensor loader.
 pub struct NpzTensors {
     index_per_name: HashMap<String, usize>,
@@ -397,19 +715,175 @@ impl NpzTensors {
         let zip_reader = BufReader::new(File::open(&self.path)?);
         let mut zip = zip::ZipArchive::new(zip_reader)?;
         let mut reader = zip.by_index(index)?;
+        Ok(Some(read_array(&mut reader)?))
+    }
+}
+
+// An array in a memory mapped file, `offset` is the position of its data.
+#[derive(Debug)]
+struct MmapedArray {
+    header: Header,
+    offset: usize,
+}
+
+impl MmapedArray {
+    // Parses the header of the array at the start of `data`, `offset` is the position of
+    // `data` in the file.
+    fn parse(data: &[u8], offset: usize) -> Result<Self> {
+        let mut reader = data;
         let header = read_header(&mut reader)?;
         let header = Header::parse(&header)?;
+        let offset = offset + data.len() - reader.len();
+        Ok(Self { header, offset })
+    }
+
+    fn load(&self, mmap: &[u8], device: &Device) -> Result<Tensor> {
+        decode(&self.header, &mmap[self.offset..], device)
+    }
+
+    fn load_rows(&self, mmap: &[u8], start: usize, len: usize, device: &Device) -> Result<Tensor> {
+        let header = &self.header;
+        let rows = match header.shape.first() {
+            None => crate::bail!("cannot load rows from a scalar array"),
+            Some(&rows) => rows,
+        };
+        if start + len > rows {
+            crate::bail!(
+                "cannot load rows {start}..{} from an array with {rows} rows",
+                start + len
+            )
+        }
         if header.fortran_order {
-            return Err(Error::Npy("fortran order not supported".to_string()));
+            // The rows are not contiguous in this case.
+            return self.load(mmap, device)?.narrow(0, start, len);
         }
-        let tensor = Tensor::from_reader(header.shape(), header.descr, &mut reader)?;
-        Ok(Some(tensor))
+        let row_len = header.shape[1..].iter().product::<usize>() * header.stored.size;
+        let mut shape = header.shape.clone();
+        shape[0] = len;
+        let header = Header { shape, ..*header };
+        decode(&header, &mmap[self.offset + start * row_len..], device)
+    }
+}
+
+/// A memory mapped npy file, the data is only read when loading the array or some of its rows.
+pub struct MmapedNpy {
+    mmap: memmap2::Mmap,
+    array: MmapedArray,
+}
+
+impl MmapedNpy {
+    /// Maps the file and parses the npy header.
+    ///
+    /// # Safety
+    ///
+    /// The unsafe is inherited from [`memmap2::MmapOptions`].
+    pub unsafe fn new<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let file = File::open(path).map_err(|e| Error::from(e).with_path(path))?;
+        let mmap = memmap2::MmapOptions::new()
+            .map(&file)
+            .map_err(|e| Error::from(e).with_path(path))?;
+        let array = MmapedArray::parse(&mmap, 0).map_err(|e| e.with_path(path))?;
+        Ok(Self { mmap, array })
+    }
+
+    pub fn shape(&self) -> Shape {
+        self.array.header.shape()
+    }
+
+    pub fn dtype(&self) -> DType {
+        self.array.header.descr
+    }
+
+    pub fn load(&self, device: &Device) -> Result<Tensor> {
+        self.array.load(&self.mmap, device)
+    }
+
+    /// Loads `len` entries along the first dimension starting from `start`, only this part of
+    /// the file is read for arrays in row-major order.
+    pub fn load_rows(&self, start: usize, len: usize, device: &Device) -> Result<Tensor> {
+        self.array.load_rows(&self.mmap, start, len, device)
+    }
+}
+
+/// A memory mapped npz file. The arrays have to be stored without compression, as done by
+/// `np.savez` and [`NpzWriter`].
+pub struct MmapedNpz {
+    mmap: memmap2::Mmap,
+    arrays: HashMap<String, MmapedArray>,
+}
+
+impl MmapedNpz {
+    /// Maps the file and parses the headers of all the arrays.
+    ///
+    /// # Safety
+    ///
+    /// The unsafe is inherited from [`memmap2::MmapOptions`].
+    pub unsafe fn new<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let file = File::open(path).map_err(|e| Error::from(e).with_path(path))?;
+        let mmap = memmap2::MmapOptions::new()
+            .map(&file)
+            .map_err(|e| Error::from(e).with_path(path))?;
+        let mut zip = zip::ZipArchive::new(std::io::Cursor::new(&mmap[..]))?;
+        let mut arrays = HashMap::new();
+        for i in 0..zip.len() {
+            let file = zip.by_index_raw(i)?;
+            let name = {
+                let name = file.name();
+                name.strip_suffix(NPY_SUFFIX).unwrap_or(name).to_owned()
+            };
+            if file.compression() != zip::CompressionMethod::Stored {
+                Err(
+                    Error::Npy(format!("{name} is compressed and cannot be memory mapped"))
+                        .with_path(path),
+                )?
+            }
+            let start = file.data_start() as usize;
+            let end = start + file.size() as usize;
+            let array = MmapedArray::parse(&mmap[start..end], start)?;
+            arrays.insert(name, array);
+        }
+        Ok(Self { mmap, arrays })
+    }
+
+    pub fn names(&self) -> Vec<&String> {
+        self.arrays.keys().collect()
+    }
+
+    fn get(&self, name: &str) -> Result<&MmapedArray> {
+        match self.arrays.get(name) {
+            None => crate::bail!("cannot find tensor {name}"),
+            Some(array) => Ok(array),
+        }
+    }
+
+    pub fn get_shape_and_dtype(&self, name: &str) -> Result<(Shape, DType)> {
+        let header = &self.get(name)?.header;
+        Ok((header.shape(), header.descr))
+    }
+
+    pub fn load(&self, name: &str, device: &Device) -> Result<Tensor> {
+        self.get(name)?.load(&self.mmap, device)
+    }
+
+    /// Loads `len` entries along the first dimension of an array starting from `start`, see
+    /// [`MmapedNpy::load_rows`].
+    pub fn load_rows(
+        &self,
+        name: &str,
+        start: usize,
+        len: usize,
+        device: &Device,
+    ) -> Result<Tensor> {
+        self.get(name)?.load_rows(&self.mmap, start, len, device)
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::Header;
+    use super::{Descr, Header};
+    use crate::DType;
 
     #[test]
     fn parse() {
@@ -417,7 +891,8 @@ mod tests {
         assert_eq!(
             Header::parse(h).unwrap(),
             Header {
-                descr: crate::DType::F64,
+                descr: DType::F64,
+                stored: Descr::of(DType::F64),
                 fortran_order: false,
                 shape: vec![128]
             }
@@ -427,7 +902,8 @@ mod tests {
         assert_eq!(
             h,
             Header {
-                descr: crate::DType::F32,
+                descr: DType::F32,
+                stored: Descr::of(DType::F32),
                 fortran_order: true,
                 shape: vec![256, 1, 128]
             }
@@ -437,14 +913,25 @@ mod tests {
             "{'descr': '<f4', 'fortran_order': True, 'shape': (256,1,128,), }"
         );
 
-        let h = Header {
-            descr: crate::DType::U32,
-            fortran_order: false,
-            shape: vec![],
-        };
+        let h = Header::new(DType::U32, &[]);
         assert_eq!(
             h.to_string().unwrap(),
             "{'descr': '<u4', 'fortran_order': False, 'shape': (), }"
         );
     }
+
+    #[test]
+    fn descr() {
+        let d = Descr::parse(">i2").unwrap();
+        assert_eq!((d.kind, d.size, d.big_endian), ('i', 2, true));
+        assert_eq!(d.dtype().unwrap(), DType::I64);
+        assert_eq!(Descr::parse("|b1").unwrap().dtype().unwrap(), DType::U8);
+        assert_eq!(Descr::parse("?").unwrap(), Descr::parse("|b1").unwrap());
+        assert_eq!(Descr::parse("H").unwrap().dtype().unwrap(), DType::U32);
+        assert_eq!(Descr::parse("<V2").unwrap().dtype().unwrap(), DType::BF16);
+        assert_eq!(Descr::of(DType::BF16).to_string(), "<V2");
+        assert_eq!(Descr::of(DType::U8).to_string(), "|u1");
+        assert!(Descr::parse("<c8").is_err());
+        assert!(Descr::parse("<U10").is_err());
+    }
 }
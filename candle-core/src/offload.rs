@@ -0,0 +1,178 @@
+//! Moving the activations saved for the backward pass to host memory.
+//!
+//! The ops of the graph keep their inputs alive until the backward pass, so the memory used by a
+//! training step grows with the depth of the model and the sequence length. [`offload`] moves the
+//! data of such a saved tensor to page-locked host memory in place: the tensor and all the
+//! tensors sharing its storage stay in the graph but their device memory is released. The data
+//! has to be copied back with [`Offloaded::reload`] before the backward pass uses it. Both copies
+//! are asynchronous so that they overlap with the computations of the other ops, they run on the
+//! transfer stream of the device and use a pool of page-locked buffers.
+//!
+//! [`saved_tensors`] lists the tensors that a part of the graph keeps alive, this is typically
+//! used on the output of a transformer block with the block input as the only input.
+//!
+//! Only cuda tensors can be offloaded, the other tensors are left untouched.
+use crate::{CpuStorage, Device, Result, Storage, Tensor};
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex, RwLock};
+
+/// The tensors kept alive by the ops of a part of the graph, see [`saved_tensors`].
+#[derive(Debug, Clone)]
+pub struct SavedTensors {
+    /// The tensors computed between the inputs and the output, one per storage. These tensors
+    /// do not share their storage with the inputs, the output, the variables or the frontier.
+    pub tensors: Vec<Tensor>,
+    /// The tensors used by this part of the graph that were computed before the inputs, e.g. the
+    /// activations of a previous block reused by a later one.
+    pub frontier: Vec<Tensor>,
+}
+
+/// The tensors saved by the ops between `inputs` and `output`.
+///
+/// The walk starts from `output` and stops at the inputs, the variables, the tensors that are
+/// not the result of an op such as masks, and the tensors created before the inputs which end
+/// up in [`SavedTensors::frontier`].
+pub fn saved_tensors(output: &Tensor, inputs: &[&Tensor]) -> SavedTensors {
+    let max_input_id = inputs.iter().map(|t| t.id()).max();
+    let input_ids: HashSet<_> = inputs.iter().map(|t| t.id()).collect();
+    let storage_ptr = |t: &Tensor| Arc::as_ptr(t.storage_arc()) as usize;
+    let mut excluded: HashSet<usize> = inputs.iter().map(|t| storage_ptr(t)).collect();
+    excluded.insert(storage_ptr(output));
+    let mut seen = HashSet::new();
+    let mut candidates = vec![];
+    let mut frontier = vec![];
+    let mut stack: Vec<Tensor> = match output.op() {
+        None => vec![],
+        Some(op) => op.args().into_iter().cloned().collect(),
+    };
+    while let Some(t) = stack.pop() {
+        if !seen.insert(t.id()) || input_ids.contains(&t.id()) {
+            continue;
+        }
+        let op = match t.op() {
+            Some(op) if !t.is_variable() => op,
+            _ => {
+                excluded.insert(storage_ptr(&t));
+                continue;
+            }
+        };
+        if max_input_id.is_some_and(|id| t.id() < id) {
+            excluded.insert(storage_ptr(&t));
+            frontier.push(t);
+            continue;
+        }
+        stack.extend(op.args().into_iter().cloned());
+        candidates.push(t)
+    }
+    let mut tensors = vec![];
+    for t in candidates {
+        // Views share the storage of the tensor they come from, only one of these is returned.
+        if excluded.insert(storage_ptr(&t)) {
+            tensors.push(t)
+        }
+    }
+    SavedTensors { tensors, frontier }
+}
+
+fn cpu_storage_bytes(storage: &CpuStorage) -> usize {
+    match storage {
+        CpuStorage::U8(data) => std::mem::size_of_val(data.as_slice()),
+        CpuStorage::U32(data) => std::mem::size_of_val(data.as_slice()),
+        CpuStorage::I64(data) => std::mem::size_of_val(data.as_slice()),
+        CpuStorage::BF16(data) => std::mem::size_of_val(data.as_slice()),
+        CpuStorage::F16(data) => std::mem::size_of_val(data.as_slice()),
+        CpuStorage::F32(data) => std::mem::size_of_val(data.as_slice()),
+        CpuStorage::F64(data) => std::mem::size_of_val(data.as_slice()),
+        CpuStorage::Foreign(s) => s.len() * s.dtype().size_in_bytes(),
+    }
+}
+
+/// The storage of a tensor moved to host memory by [`offload`].
+pub struct Offloaded {
+    storage: Arc<RwLock<Storage>>,
+    device: Device,
+    bytes: usize,
+}
+
+impl std::fmt::Debug for Offloaded {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Offloaded")
+            .field("device", &self.device)
+            .field("bytes", &self.bytes)
+            .finish()
+    }
+}
+
+/// Moves the storage of `tensor` to page-locked host memory, the tensors using this storage
+/// cannot be used until [`Offloaded::reload`] is called. Returns `None` if the storage is not
+/// on a cuda device, e.g. if it has already been offloaded.
+///
+/// The copy to the host does not block the host, it starts once the kernels already queued on
+/// the device have completed and the device memory is released when it completes, see
+/// [`crate::CudaDevice::storage_to_pinned`].
+pub fn offload(tensor: &Tensor) -> Result<Option<Offloaded>> {
+    let cuda = match tensor.device() {
+        Device::Cuda(cuda) => cuda,
+        _ => return Ok(None),
+    };
+    let storage = tensor.storage_arc().clone();
+    let mut guard = storage.write().unwrap();
+    // The device storage is only moved in the slot once replaced, it is dropped when the copy
+    // completes.
+    let slot: Arc<Mutex<Option<Storage>>> = Arc::new(Mutex::new(None));
+    let cpu = match &*guard {
+        Storage::Cuda(s) => cuda.storage_to_pinned(s, Box::new(slot.clone()))?,
+        _ => return Ok(None),
+    };
+    let bytes = cpu_storage_bytes(&cpu);
+    let device_storage = std::mem::replace(&mut *guard, Storage::Cpu(cpu));
+    *slot.lock().unwrap() = Some(device_storage);
+    drop(guard);
+    Ok(Some(Offloaded {
+        storage,
+        device: tensor.device().clone(),
+        bytes,
+    }))
+}
+
+impl Offloaded {
+    /// The size of the offloaded storage.
+    pub fn bytes(&self) -> usize {
+        self.bytes
+    }
+
+    /// Whether some tensors still use the storage, the memory is released when this returns
+    /// false and the [`Offloaded`] is dropped.
+    pub fn is_used(&self) -> bool {
+        Arc::strong_count(&self.storage) > 1
+    }
+
+    /// Whether `tensor` uses the offloaded storage.
+    pub fn shares_storage(&self, tensor: &Tensor) -> bool {
+        Arc::ptr_eq(&self.storage, tensor.storage_arc())
+    }
+
+    /// Copies the storage back to its device. The copy does not block the host, the kernels
+    /// queued afterwards wait for it to complete, see [`Tensor::to_device_nonblocking`].
+    pub fn reload(self) -> Result<()> {
+        let Self {
+            storage, device, ..
+        } = self;
+        let cuda = match &device {
+            Device::Cuda(cuda) => cuda,
+            _ => return Ok(()),
+        };
+        let mut storage = storage.write().unwrap();
+        let cpu = match &*storage {
+            Storage::Cpu(cpu) => cpu,
+            _ => return Ok(()),
+        };
+        // The host storage is only moved in the slot once replaced, its page-locked buffer goes
+        // back to the pool when the copy completes.
+        let slot: Arc<Mutex<Option<Storage>>> = Arc::new(Mutex::new(None));
+        let reloaded = cuda.storage_from_pinned(cpu, Box::new(slot.clone()))?;
+        let host = std::mem::replace(&mut *storage, Storage::Cuda(reloaded));
+        *slot.lock().unwrap() = Some(host);
+        Ok(())
+    }
+}
@@ -4,6 +4,8 @@
 use crate::Tensor;
 use half::{bf16, f16};
 use num_traits::float::Float;
+use std::backtrace::Backtrace;
+use std::sync::Arc;
 
 #[derive(Clone, Copy, PartialEq, Eq)]
 pub enum CmpOp {
@@ -181,6 +183,89 @@ pub enum Op {
     ),
 }
 
+impl Op {
+    /// A short name for the op, used when reporting errors.
+    pub(crate) fn name(&self) -> String {
+        let name = match self {
+            Self::Binary(_, _, op) => return format!("{op:?}").to_lowercase(),
+            Self::Unary(_, op) => return format!("{op:?}").to_lowercase(),
+            Self::Reduce(_, op, _) => return op.name().to_string(),
+            Self::CustomOp1(_, c) => return c.name().to_string(),
+            Self::CustomOp2(_, _, c) => return c.name().to_string(),
+            Self::CustomOp3(_, _, _, c) => return c.name().to_string(),
+            Self::Cmp(_, _) => "cmp",
+            Self::Matmul(_, _) => "matmul",
+            Self::Gather(_, _, _) => "gather",
+            Self::ScatterAdd(_, _, _, _) => "scatter-add",
+            Self::IndexSelect(_, _, _) => "index-select",
+            Self::IndexAdd(_, _, _, _) => "index-add",
+            Self::WhereCond(_, _, _) => "where-cond",
+            Self::Conv1D { .. } => "conv1d",
+            Self::ConvTranspose1D { .. } => "conv-transpose1d",
+            Self::Conv2D { .. } => "conv2d",
+            Self::ConvTranspose2D { .. } => "conv-transpose2d",
+            Self::AvgPool2D { .. } => "avg-pool2d",
+            Self::MaxPool2D { .. } => "max-pool2d",
+            Self::UpsampleNearest1D { .. } => "upsample-nearest1d",
+            Self::UpsampleNearest2D { .. } => "upsample-nearest2d",
+            Self::Cat(_, _) => "cat",
+            Self::Affine { .. } => "affine",
+            Self::ToDType(_) => "to-dtype",
+            Self::Copy(_) => "copy",
+            Self::Broadcast(_) => "broadcast",
+            Self::Narrow(_, _, _, _) => "narrow",
+            Self::SliceScatter0(_, _, _) => "slice-scatter",
+            Self::Reshape(_) => "reshape",
+            Self::ToDevice(_) => "to-device",
+            Self::Transpose(_, _, _) => "transpose",
+            Self::Permute(_, _) => "permute",
+            Self::Elu(_, _) => "elu",
+            Self::Powf(_, _) => "powf",
+        };
+        name.to_string()
+    }
+
+    /// The tensors this op has been applied to.
+    pub(crate) fn args(&self) -> Vec<&Tensor> {
+        match self {
+            Self::IndexAdd(t1, t2, t3, _)
+            | Self::ScatterAdd(t1, t2, t3, _)
+            | Self::CustomOp3(t1, t2, t3, _)
+            | Self::WhereCond(t1, t2, t3) => vec![t1, t2, t3],
+            Self::Conv1D { arg, kernel, .. }
+            | Self::ConvTranspose1D { arg, kernel, .. }
+            | Self::Conv2D { arg, kernel, .. }
+            | Self::ConvTranspose2D { arg, kernel, .. } => vec![arg, kernel],
+            Self::CustomOp2(t1, t2, _)
+            | Self::Binary(t1, t2, _)
+            | Self::Gather(t1, t2, _)
+            | Self::IndexSelect(t1, t2, _)
+            | Self::Matmul(t1, t2)
+            | Self::SliceScatter0(t1, t2, _) => vec![t1, t2],
+            Self::Cat(args, _) => args.iter().collect(),
+            Self::Affine { arg, .. }
+            | Self::UpsampleNearest1D { arg, .. }
+            | Self::UpsampleNearest2D { arg, .. }
+            | Self::AvgPool2D { arg, .. }
+            | Self::MaxPool2D { arg, .. } => vec![arg],
+            Self::Unary(arg, _)
+            | Self::Cmp(arg, _)
+            | Self::Reduce(arg, _, _)
+            | Self::ToDType(arg)
+            | Self::Copy(arg)
+            | Self::Broadcast(arg)
+            | Self::Narrow(arg, _, _, _)
+            | Self::Reshape(arg)
+            | Self::ToDevice(arg)
+            | Self::Transpose(arg, _, _)
+            | Self::Permute(arg, _)
+            | Self::Elu(arg, _)
+            | Self::Powf(arg, _)
+            | Self::CustomOp1(arg, _) => vec![arg],
+        }
+    }
+}
+
 pub trait UnaryOpT {
     const NAME: &'static str;
     const KERNEL: &'static str;
@@ -869,11 +954,26 @@ impl UnaryOpT for Relu {
 /// `BackpropOp` is a wrapper around `Option<Op>`. The main goal is to ensure that dependencies are
 /// properly checked when creating a new value
 #[derive(Clone)]
-pub struct BackpropOp(Option<Op>);
+pub struct BackpropOp {
+    op: Option<Op>,
+    // Where the op was called, only captured when anomaly detection is enabled for the backward
+    // pass so that ops producing non-finite gradients can be reported.
+    created_at: Option<Arc<Backtrace>>,
+}
 
 impl BackpropOp {
+    fn from_op(op: Option<Op>) -> Self {
+        let created_at = match op {
+            Some(_) if crate::anomaly::is_detect_anomaly_enabled() => {
+                Some(Arc::new(Backtrace::force_capture()))
+            }
+            _ => None,
+        };
+        Self { op, created_at }
+    }
+
     pub(crate) fn none() -> Self {
-        BackpropOp(None)
+        Self::from_op(None)
     }
 
     pub(crate) fn new1(arg: &Tensor, f: impl Fn(Tensor) -> Op) -> Self {
@@ -882,7 +982,7 @@ impl BackpropOp {
         } else {
             None
         };
-        Self(op)
+        Self::from_op(op)
     }
 
     pub(crate) fn new2(arg1: &Tensor, arg2: &Tensor, f: impl Fn(Tensor, Tensor) -> Op) -> Self {
@@ -891,7 +991,7 @@ impl BackpropOp {
         } else {
             None
         };
-        Self(op)
+        Self::from_op(op)
     }
 
     pub(crate) fn new3(
@@ -905,7 +1005,7 @@ impl BackpropOp {
         } else {
             None
         };
-        Self(op)
+        Self::from_op(op)
     }
 
     pub(crate) fn new<A: AsRef<Tensor>>(args: &[A], f: impl Fn(Vec<Tensor>) -> Op) -> Self {
@@ -915,18 +1015,22 @@ impl BackpropOp {
         } else {
             None
         };
-        Self(op)
+        Self::from_op(op)
     }
 
     pub(crate) fn is_none(&self) -> bool {
-        self.0.is_none()
+        self.op.is_none()
+    }
+
+    pub(crate) fn created_at(&self) -> Option<&Arc<Backtrace>> {
+        self.created_at.as_ref()
     }
 }
 
 impl std::ops::Deref for BackpropOp {
     type Target = Option<Op>;
     fn deref(&self) -> &Self::Target {
-        &self.0
+        &self.op
     }
 }
 
@@ -0,0 +1,363 @@
+//! A per-op profiler.
+//!
+//! When enabled, each operation executed on a storage is recorded with its name, the shapes of
+//! its inputs, its dtype and device, as well as the time it took. The resulting [`Profile`] can
+//! be printed as a summary table or exported in the chrome trace format so that it can be
+//! visualized in `chrome://tracing` or [perfetto](https://ui.perfetto.dev).
+//!
+//! ```rust
+//! use candle_core::{profiler, Device, Tensor};
+//! let a = Tensor::randn(0f32, 1., (64, 64), &Device::Cpu)?;
+//! profiler::start();
+//! let b = {
+//!     let _scope = profiler::scope("block");
+//!     a.matmul(&a)?.relu()?
+//! };
+//! let profile = profiler::stop();
+//! assert_eq!(profile.events.iter().filter(|e| e.name == "matmul").count(), 1);
+//! println!("{}", profile.summary_table());
+//! let _json = profile.chrome_trace();
+//! # Ok::<(), candle_core::Error>(())
+//! ```
+//!
+//! On devices that execute asynchronously such as cuda or metal, the wall time of an op only
+//! measures the time to enqueue its kernels. By default the device is synchronized before and
+//! after each op so that the device time of the op can also be measured, this makes
+//! the profiled code slower but gives an accurate picture of where the time is spent.
+use crate::storage::Storage;
+use crate::{DType, DeviceLocation, Layout, Result, Shape};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static RECORDER: Mutex<Option<Recorder>> = Mutex::new(None);
+static NEXT_THREAD_ID: AtomicU64 = AtomicU64::new(0);
+
+thread_local! {
+    static THREAD_ID: u64 = NEXT_THREAD_ID.fetch_add(1, Ordering::Relaxed);
+}
+
+struct Recorder {
+    start: Instant,
+    options: Options,
+    events: Vec<Event>,
+}
+
+/// The profiler settings.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Options {
+    /// Synchronize asynchronous devices around each op to measure the device time.
+    pub synchronize: bool,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self { synchronize: true }
+    }
+}
+
+/// The kind of recorded event.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EventKind {
+    /// An operation executed by a backend.
+    Op,
+    /// A user defined scope created with [`scope`].
+    Scope,
+}
+
+/// A single recorded op or scope.
+#[derive(Debug, Clone)]
+pub struct Event {
+    pub name: String,
+    pub kind: EventKind,
+    /// The shapes of the inputs of the op, the shape of the output is not included.
+    pub shapes: Vec<Shape>,
+    pub dtype: Option<DType>,
+    pub device: Option<DeviceLocation>,
+    pub thread_id: u64,
+    /// The start time, relative to the call to [`start`].
+    pub start: Duration,
+    /// The time spent on the host.
+    pub wall_time: Duration,
+    /// The time until the device has completed the op, only measured on asynchronous devices
+    /// when [`Options::synchronize`] is set.
+    pub device_time: Option<Duration>,
+}
+
+impl Event {
+    /// The device time when available, and the wall time otherwise.
+    pub fn duration(&self) -> Duration {
+        self.device_time.unwrap_or(self.wall_time)
+    }
+}
+
+/// Starts recording ops with the default options, any previously recorded event is discarded.
+pub fn start() {
+    start_with(Options::default())
+}
+
+/// Starts recording ops, any previously recorded event is discarded.
+pub fn start_with(options: Options) {
+    let mut recorder = RECORDER.lock().unwrap();
+    *recorder = Some(Recorder {
+        start: Instant::now(),
+        options,
+        events: vec![],
+    });
+    ENABLED.store(true, Ordering::Release);
+}
+
+/// Stops recording and returns the events recorded since the last call to [`start`].
+pub fn stop() -> Profile {
+    ENABLED.store(false, Ordering::Release);
+    let recorder = RECORDER.lock().unwrap().take();
+    let mut events = recorder.map_or_else(Vec::new, |r| r.events);
+    events.sort_by_key(|e| e.start);
+    Profile { events }
+}
+
+/// Whether the profiler is currently recording.
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+fn push(event: Event) {
+    if let Some(recorder) = RECORDER.lock().unwrap().as_mut() {
+        recorder.events.push(event)
+    }
+}
+
+fn start_and_options() -> Option<(Instant, Options)> {
+    let recorder = RECORDER.lock().unwrap();
+    recorder.as_ref().map(|r| (r.start, r.options))
+}
+
+/// A guard recording the time between its creation and the moment it gets dropped.
+pub struct Scope {
+    name: String,
+    kind: EventKind,
+    shapes: Vec<Shape>,
+    dtype: Option<DType>,
+    device: Option<DeviceLocation>,
+    sync_device: Option<crate::Device>,
+    profile_start: Instant,
+    start: Instant,
+}
+
+/// Records a user defined scope, the scope ends when the returned guard is dropped.
+///
+/// The ops executed within the scope are still recorded individually, scopes are displayed as
+/// enclosing spans in the chrome trace.
+pub fn scope(name: impl Into<String>) -> Option<Scope> {
+    if !is_enabled() {
+        return None;
+    }
+    let (profile_start, _) = start_and_options()?;
+    Some(Scope {
+        name: name.into(),
+        kind: EventKind::Scope,
+        shapes: vec![],
+        dtype: None,
+        device: None,
+        sync_device: None,
+        profile_start,
+        start: Instant::now(),
+    })
+}
+
+/// Records an op, this is a no-op returning `None` when the profiler is not enabled.
+pub(crate) fn op(name: &'static str, storage: &Storage, layouts: &[&Layout]) -> Option<Scope> {
+    if !is_enabled() {
+        return None;
+    }
+    let (profile_start, options) = start_and_options()?;
+    let device = storage.device();
+    let location = device.location();
+    let sync_device = if options.synchronize && !device.is_cpu() {
+        // Wait for the previously enqueued ops so that they are not attributed to this one.
+        device.synchronize().ok()?;
+        Some(device)
+    } else {
+        None
+    };
+    let shapes = layouts.iter().map(|l| l.shape().clone()).collect();
+    Some(Scope {
+        name: name.to_string(),
+        kind: EventKind::Op,
+        shapes,
+        dtype: Some(storage.dtype()),
+        device: Some(location),
+        sync_device,
+        profile_start,
+        start: Instant::now(),
+    })
+}
+
+impl Drop for Scope {
+    fn drop(&mut self) {
+        let wall_time = self.start.elapsed();
+        let device_time = match &self.sync_device {
+            Some(device) => device.synchronize().ok().map(|()| self.start.elapsed()),
+            None => None,
+        };
+        let thread_id = THREAD_ID.with(|id| *id);
+        push(Event {
+            name: std::mem::take(&mut self.name),
+            kind: self.kind,
+            shapes: std::mem::take(&mut self.shapes),
+            dtype: self.dtype,
+            device: self.device,
+            thread_id,
+            start: self.start.duration_since(self.profile_start),
+            wall_time,
+            device_time,
+        })
+    }
+}
+
+/// Aggregated statistics for all the events sharing the same name.
+#[derive(Debug, Clone, PartialEq)]
+pub struct OpSummary {
+    pub name: String,
+    pub calls: usize,
+    pub total: Duration,
+    pub max: Duration,
+}
+
+impl OpSummary {
+    pub fn mean(&self) -> Duration {
+        self.total / self.calls.max(1) as u32
+    }
+}
+
+/// The events recorded between calls to [`start`] and [`stop`], sorted by start time.
+#[derive(Debug, Clone, Default)]
+pub struct Profile {
+    pub events: Vec<Event>,
+}
+
+impl Profile {
+    /// Aggregates the op events by name, sorted by decreasing total time. Scopes are not
+    /// included as their time already accounts for the ops that they contain.
+    pub fn summary(&self) -> Vec<OpSummary> {
+        let mut summaries: HashMap<&str, OpSummary> = HashMap::new();
+        for event in self.events.iter().filter(|e| e.kind == EventKind::Op) {
+            let duration = event.duration();
+            let summary = summaries.entry(&event.name).or_insert_with(|| OpSummary {
+                name: event.name.clone(),
+                calls: 0,
+                total: Duration::ZERO,
+                max: Duration::ZERO,
+            });
+            summary.calls += 1;
+            summary.total += duration;
+            summary.max = summary.max.max(duration);
+        }
+        let mut summaries: Vec<_> = summaries.into_values().collect();
+        summaries.sort_by(|a, b| b.total.cmp(&a.total).then_with(|| a.name.cmp(&b.name)));
+        summaries
+    }
+
+    /// A human readable table with the time spent in each op.
+    pub fn summary_table(&self) -> String {
+        let summaries = self.summary();
+        let total: Duration = summaries.iter().map(|s| s.total).sum();
+        let width = summaries
+            .iter()
+            .map(|s| s.name.len())
+            .max()
+            .unwrap_or(0)
+            .max(2);
+        let mut table = format!(
+            "{:<width$} {:>8} {:>12} {:>12} {:>12} {:>7}\n",
+            "op", "calls", "total(ms)", "mean(us)", "max(us)", "%"
+        );
+        for s in summaries.iter() {
+            let percent = if total.is_zero() {
+                0.
+            } else {
+                100. * s.total.as_secs_f64() / total.as_secs_f64()
+            };
+            table.push_str(&format!(
+                "{:<width$} {:>8} {:>12.3} {:>12.1} {:>12.1} {:>7.2}\n",
+                s.name,
+                s.calls,
+                s.total.as_secs_f64() * 1e3,
+                s.mean().as_secs_f64() * 1e6,
+                s.max.as_secs_f64() * 1e6,
+                percent
+            ))
+        }
+        table
+    }
+
+    /// The events in the chrome trace json format.
+    pub fn chrome_trace(&self) -> String {
+        let mut json = String::from("{\"traceEvents\":[");
+        for (i, event) in self.events.iter().enumerate() {
+            if i > 0 {
+                json.push(',');
+            }
+            let cat = match event.kind {
+                EventKind::Op => "op",
+                EventKind::Scope => "scope",
+            };
+            let shapes = event
+                .shapes
+                .iter()
+                .map(|s| format!("{:?}", s.dims()))
+                .collect::<Vec<_>>()
+                .join(" ");
+            let mut args = vec![format!("\"shapes\":\"{shapes}\"")];
+            if let Some(dtype) = event.dtype {
+                args.push(format!("\"dtype\":\"{}\"", dtype.as_str()))
+            }
+            if let Some(device) = event.device {
+                args.push(format!("\"device\":\"{}\"", device_str(device)))
+            }
+            args.push(format!(
+                "\"wall_us\":{:.3}",
+                event.wall_time.as_secs_f64() * 1e6
+            ));
+            json.push_str(&format!(
+                "{{\"name\":\"{}\",\"cat\":\"{cat}\",\"ph\":\"X\",\"ts\":{:.3},\"dur\":{:.3},\"pid\":0,\"tid\":{},\"args\":{{{}}}}}",
+                escape(&event.name),
+                event.start.as_secs_f64() * 1e6,
+                event.duration().as_secs_f64() * 1e6,
+                event.thread_id,
+                args.join(","),
+            ))
+        }
+        json.push_str("]}");
+        json
+    }
+
+    /// Writes the events in the chrome trace json format.
+    pub fn write_chrome_trace<P: AsRef<std::path::Path>>(&self, path: P) -> Result<()> {
+        std::fs::write(path, self.chrome_trace())?;
+        Ok(())
+    }
+}
+
+fn device_str(device: DeviceLocation) -> String {
+    match device {
+        DeviceLocation::Cpu => "cpu".to_string(),
+        DeviceLocation::Cuda { gpu_id } => format!("cuda:{gpu_id}"),
+        DeviceLocation::Metal { gpu_id } => format!("metal:{gpu_id}"),
+    }
+}
+
+fn escape(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            c if (c as u32) < 0x20 => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
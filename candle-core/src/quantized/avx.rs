@@ -1,5 +1,6 @@
 use super::k_quants::{
-    BlockQ2K, BlockQ3K, BlockQ4K, BlockQ4_0, BlockQ5K, BlockQ6K, BlockQ8K, BlockQ8_0, QK8_0, QK_K,
+    BlockIQ2xxs, BlockIQ4nl, BlockQ2K, BlockQ3K, BlockQ4K, BlockQ4_0, BlockQ5K, BlockQ6K, BlockQ8K,
+    BlockQ8_0, IQ2XXS_GRID, KSIGNS_IQ2XS, KVALUES_IQ4NL, QK4_NL, QK8_0, QK_K,
 };
 use crate::Result;
 use byteorder::{ByteOrder, LittleEndian};
@@ -68,6 +69,79 @@ pub(crate) fn vec_dot_q4_0_q8_0(n: usize, xs: &[BlockQ4_0], ys: &[BlockQ8_0]) ->
     }
 }
 
+#[inline(always)]
+pub(crate) fn vec_dot_iq4_nl_q8_0(n: usize, xs: &[BlockIQ4nl], ys: &[BlockQ8_0]) -> Result<f32> {
+    if n % QK4_NL != 0 {
+        crate::bail!("vec_dot_iq4_nl_q8_0: {n} is not divisible by {QK4_NL}")
+    }
+    unsafe {
+        // The shuffle is done per 128-bit lane so the grid values are repeated in both lanes.
+        let values = _mm_loadu_si128(KVALUES_IQ4NL.as_ptr() as *const __m128i);
+        let values = _mm256_insertf128_si256::<1>(_mm256_castsi128_si256(values), values);
+        let mut acc = _mm256_setzero_ps();
+        for (x, y) in xs.iter().zip(ys.iter()) {
+            let d = _mm256_set1_ps(f16::to_f32(x.d) * f16::to_f32(y.d));
+            let bx = _mm256_shuffle_epi8(values, bytes_from_nibbles_32(x.qs.as_ptr()));
+            let by = _mm256_loadu_si256(y.qs.as_ptr() as *const __m256i);
+            let q = mul_sum_i8_pairs_float(bx, by);
+            acc = _mm256_fmadd_ps(d, q, acc);
+        }
+        Ok(hsum_float_8(acc))
+    }
+}
+
+// The sign bytes of `KSIGNS_IQ2XS`, 1 or -1 for each of the 8 values, to be applied with
+// `_mm256_sign_epi8`.
+const KEVEN_SIGNS_IQ2XS: [u64; 128] = {
+    let mut signs = [0u64; 128];
+    let mut i = 0;
+    while i < 128 {
+        let mut j = 0;
+        while j < 8 {
+            let byte = if KSIGNS_IQ2XS[i] & (1 << j) != 0 {
+                0xff
+            } else {
+                0x01
+            };
+            signs[i] |= byte << (8 * j);
+            j += 1;
+        }
+        i += 1;
+    }
+    signs
+};
+
+#[inline(always)]
+pub(crate) fn vec_dot_iq2_xxs_q8k(n: usize, xs: &[BlockIQ2xxs], ys: &[BlockQ8K]) -> Result<f32> {
+    if n % QK_K != 0 {
+        crate::bail!("vec_dot_iq2_xxs_q8k: {n} is not divisible by {QK_K}")
+    }
+    unsafe {
+        let mut acc = _mm256_setzero_ps();
+        for (x, y) in xs.iter().zip(ys.iter()) {
+            let mut sumi = _mm256_setzero_si256();
+            for (q2, q8) in x.qs.chunks_exact(4).zip(y.qs.chunks_exact(32)) {
+                let aux0 = q2[0] as u32 | (q2[1] as u32) << 16;
+                let aux1 = q2[2] as u32 | (q2[3] as u32) << 16;
+                let grid =
+                    |l: u32| i64::from_le_bytes(IQ2XXS_GRID[(aux0 >> (8 * l)) as usize & 0xFF]);
+                let signs = |l: u32| KEVEN_SIGNS_IQ2XS[(aux1 >> (7 * l)) as usize & 127] as i64;
+                let q2 = _mm256_set_epi64x(grid(3), grid(2), grid(1), grid(0));
+                let s2 = _mm256_set_epi64x(signs(3), signs(2), signs(1), signs(0));
+                let q8 = _mm256_loadu_si256(q8.as_ptr() as *const __m256i);
+                // The signs are applied to the grid values as the q8 values can be -128.
+                let q2 = _mm256_sign_epi8(_mm256_sign_epi8(q2, s2), q8);
+                let dot = _mm256_maddubs_epi16(_mm256_sign_epi8(q8, q8), q2);
+                let ls = _mm256_set1_epi16(2 * (aux1 >> 28) as i16 + 1);
+                sumi = _mm256_add_epi32(sumi, _mm256_madd_epi16(dot, ls));
+            }
+            let d = _mm256_set1_ps(x.d.to_f32() * y.d);
+            acc = _mm256_fmadd_ps(d, _mm256_cvtepi32_ps(sumi), acc);
+        }
+        Ok(0.125 * hsum_float_8(acc))
+    }
+}
+
 #[inline(always)]
 pub(crate) fn vec_dot_q8_0_q8_0(n: usize, xs: &[BlockQ8_0], ys: &[BlockQ8_0]) -> Result<f32> {
     let qk = QK8_0;
@@ -0,0 +1,96 @@
+//! Loading of the NF4 weights of bitsandbytes checkpoints, e.g. as saved by QLoRA fine-tuning.
+//!
+//! bitsandbytes stores a NF4 weight as the flattened 4-bit indexes packed in a `u8` tensor, two
+//! values per byte, and the absmax of each block of 64 values. With double quantization the
+//! absmax values are themselves quantized to 8 bits: `absmax = nested_quant_map[absmax_q] *
+//! nested_absmax[i / nested_blocksize] + nested_offset`, these are the `absmax`,
+//! `nested_absmax`, `nested_quant_map` entries of the checkpoint and the `nested_offset` and
+//! `nested_blocksize` fields of the quant state.
+//!
+//! [`super::GgmlDType::Nf4`] uses the same indexes and packing so they are kept as is, the absmax values
+//! are dequantized and stored as f16.
+use super::k_quants::{BlockNf4, NF4_BLOCK_SIZE, QK_NF4};
+use super::{QStorage, QTensor};
+use crate::{DType, Device, Result, Shape, Tensor};
+
+/// The parameters of the double quantization of the absmax values.
+#[derive(Debug, Clone)]
+pub struct NestedAbsmax {
+    /// The 8-bit quantized absmax values, one per block of 64 values.
+    pub absmax: Tensor,
+    /// One scale per group of `blocksize` absmax values.
+    pub nested_absmax: Tensor,
+    /// The 256 values of the 8-bit code.
+    pub nested_quant_map: Tensor,
+    pub nested_offset: f32,
+    pub blocksize: usize,
+}
+
+impl NestedAbsmax {
+    /// The f32 absmax values.
+    pub fn dequantize(&self) -> Result<Tensor> {
+        let absmax = self.absmax.flatten_all()?;
+        let n = absmax.elem_count();
+        let groups = n.div_ceil(self.blocksize);
+        let nested_absmax = self.nested_absmax.flatten_all()?.to_dtype(DType::F32)?;
+        if nested_absmax.elem_count() != groups {
+            crate::bail!(
+                "unexpected nested absmax size {}, expected {groups}",
+                nested_absmax.elem_count()
+            )
+        }
+        let values = self
+            .nested_quant_map
+            .flatten_all()?
+            .to_dtype(DType::F32)?
+            .index_select(&absmax.to_dtype(DType::U32)?, 0)?;
+        let scales = nested_absmax
+            .unsqueeze(1)?
+            .repeat((1, self.blocksize))?
+            .flatten_all()?
+            .narrow(0, 0, n)?;
+        (values * scales)? + self.nested_offset as f64
+    }
+}
+
+/// Builds a [`super::GgmlDType::Nf4`] tensor of the given shape from the packed indexes of a
+/// bitsandbytes weight and the f32 absmax of its blocks of 64 values. The last dimension has to
+/// be a multiple of 256.
+pub fn nf4_from_bnb<S: Into<Shape>>(
+    packed: &Tensor,
+    absmax: &Tensor,
+    shape: S,
+    device: &Device,
+) -> Result<QTensor> {
+    let shape: Shape = shape.into();
+    let elem_count = shape.elem_count();
+    match shape.dims().last() {
+        Some(d) if d % QK_NF4 == 0 => {}
+        _ => crate::bail!("the last dim of {shape:?} is not a multiple of {QK_NF4}"),
+    }
+    let packed = packed.flatten_all()?.to_vec1::<u8>()?;
+    let absmax = absmax
+        .flatten_all()?
+        .to_dtype(DType::F32)?
+        .to_vec1::<f32>()?;
+    if packed.len() != elem_count / 2 {
+        crate::bail!("unexpected packed size {} for {shape:?}", packed.len())
+    }
+    if absmax.len() != elem_count / NF4_BLOCK_SIZE {
+        crate::bail!(
+            "unexpected absmax size {} for {shape:?}, only a blocksize of {NF4_BLOCK_SIZE} is supported",
+            absmax.len()
+        )
+    }
+    let blocks = packed
+        .chunks_exact(QK_NF4 / 2)
+        .zip(absmax.chunks_exact(QK_NF4 / NF4_BLOCK_SIZE))
+        .map(|(qs, absmax)| BlockNf4::from_codes(qs, absmax))
+        .collect::<Result<Vec<_>>>()?;
+    let storage = match device {
+        Device::Cpu => QStorage::Cpu(Box::new(blocks)),
+        Device::Metal(metal) => super::metal::load_quantized(metal, &blocks)?,
+        Device::Cuda(cuda) => super::cuda::load_quantized(cuda, &blocks)?,
+    };
+    QTensor::new(storage, shape)
+}
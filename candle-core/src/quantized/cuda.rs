@@ -91,12 +91,15 @@ fn dequantize_f32(
             ceil_div(elem_count, 2 * CUDA_DEQUANTIZE_BLOCK_SIZE),
         ),
         GgmlDType::Q8_0 => ("dequantize_block_q8_0_f32", false, 32, nb),
+        GgmlDType::Iq4Nl => ("dequantize_block_iq4_nl_f32", false, 32, nb),
+        GgmlDType::Nf4 => ("dequantize_block_nf4_f32", true, 64, nb),
         GgmlDType::Q2K => ("dequantize_block_q2_K_f32", true, 64, nb),
         GgmlDType::Q3K => ("dequantize_block_q3_K_f32", true, 64, nb),
         GgmlDType::Q4K => ("dequantize_block_q4_K_f32", true, 32, nb),
         GgmlDType::Q5K => ("dequantize_block_q5_K_f32", true, 64, nb),
         GgmlDType::Q6K => ("dequantize_block_q6_K_f32", true, 64, nb),
         GgmlDType::Q8K => ("dequantize_block_q8_K_f32", true, 32, nb),
+        GgmlDType::Iq2Xxs => ("dequantize_block_iq2_xxs_f32", true, 32, nb),
         _ => crate::bail!("unsupported dtype for dequantize {dtype:?}"),
     };
     let func = dev.get_or_load_func(kernel_name, candle_kernels::QUANTIZED)?;
@@ -148,12 +151,15 @@ fn dequantize_f16(
             ceil_div(elem_count, 2 * CUDA_DEQUANTIZE_BLOCK_SIZE),
         ),
         GgmlDType::Q8_0 => ("dequantize_block_q8_0_f16", false, 32, nb),
+        GgmlDType::Iq4Nl => ("dequantize_block_iq4_nl_f16", false, 32, nb),
+        GgmlDType::Nf4 => ("dequantize_block_nf4_f16", true, 64, nb),
         GgmlDType::Q2K => ("dequantize_block_q2_K_f16", true, 64, nb),
         GgmlDType::Q3K => ("dequantize_block_q3_K_f16", true, 64, nb),
         GgmlDType::Q4K => ("dequantize_block_q4_K_f16", true, 32, nb),
         GgmlDType::Q5K => ("dequantize_block_q5_K_f16", true, 64, nb),
         GgmlDType::Q6K => ("dequantize_block_q6_K_f16", true, 64, nb),
         GgmlDType::Q8K => ("dequantize_block_q8_K_f16", true, 32, nb),
+        GgmlDType::Iq2Xxs => ("dequantize_block_iq2_xxs_f16", true, 32, nb),
         _ => crate::bail!("unsupported dtype for dequantize {dtype:?}"),
     };
     let func = dev.get_or_load_func(kernel_name, candle_kernels::QUANTIZED)?;
@@ -203,6 +209,9 @@ fn dequantize_mul_mat_vec(
         GgmlDType::Q5_0 => "dequantize_mul_mat_vec_q5_0_cuda",
         GgmlDType::Q5_1 => "dequantize_mul_mat_vec_q5_1_cuda",
         GgmlDType::Q8_0 => "dequantize_mul_mat_vec_q8_0_cuda",
+        GgmlDType::Iq4Nl => "dequantize_mul_mat_vec_iq4_nl_cuda",
+        GgmlDType::Nf4 => "dequantize_mul_mat_vec_nf4_cuda",
+        GgmlDType::Iq2Xxs => "dequantize_mul_mat_vec_iq2_xxs_cuda",
         GgmlDType::Q2K => "dequantize_mul_mat_vec_q2_k",
         GgmlDType::Q3K => "dequantize_mul_mat_vec_q3_k",
         GgmlDType::Q4K => "dequantize_mul_mat_vec_q4_k",
@@ -258,6 +267,8 @@ fn mul_mat_vec_via_q8_1(
         GgmlDType::Q5_0 => "mul_mat_vec_q5_0_q8_1_cuda",
         GgmlDType::Q5_1 => "mul_mat_vec_q5_1_q8_1_cuda",
         GgmlDType::Q8_0 => "mul_mat_vec_q8_0_q8_1_cuda",
+        GgmlDType::Iq4Nl => "mul_mat_vec_iq4_nl_q8_1_cuda",
+        GgmlDType::Iq2Xxs => "mul_mat_vec_iq2_xxs_q8_1_cuda",
         GgmlDType::Q2K => "mul_mat_vec_q2_K_q8_1_cuda",
         GgmlDType::Q3K => "mul_mat_vec_q3_K_q8_1_cuda",
         GgmlDType::Q4K => "mul_mat_vec_q4_K_q8_1_cuda",
@@ -408,6 +419,9 @@ impl QCudaStorage {
                 | GgmlDType::Q5K
                 | GgmlDType::Q6K
                 | GgmlDType::Q8K
+                | GgmlDType::Iq2Xxs
+                | GgmlDType::Iq4Nl
+                | GgmlDType::Nf4
         );
         if fast_kernel {
             return dequantize_f32(&self.data, self.dtype, elem_count, self.device());
@@ -435,6 +449,11 @@ impl QCudaStorage {
             GgmlDType::Q5K => deq::<crate::quantized::BlockQ5K>(&buffer, block_len, &mut out)?,
             GgmlDType::Q6K => deq::<crate::quantized::BlockQ6K>(&buffer, block_len, &mut out)?,
             GgmlDType::Q8K => deq::<crate::quantized::BlockQ8K>(&buffer, block_len, &mut out)?,
+            GgmlDType::Iq2Xxs => {
+                deq::<crate::quantized::BlockIQ2xxs>(&buffer, block_len, &mut out)?
+            }
+            GgmlDType::Iq4Nl => deq::<crate::quantized::BlockIQ4nl>(&buffer, block_len, &mut out)?,
+            GgmlDType::Nf4 => deq::<crate::quantized::BlockNf4>(&buffer, block_len, &mut out)?,
         }
 
         self.device
@@ -481,11 +500,7 @@ impl QCudaStorage {
         storage: &CudaStorage,
         layout: &crate::Layout,
     ) -> Result<(CudaStorage, crate::Shape)> {
-        let max_bm = if FORCE_DMMV.load(std::sync::atomic::Ordering::Relaxed) {
-            1
-        } else {
-            8
-        };
+        let max_bm = if self.use_dmmv() { 1 } else { 8 };
         let use_vec_kernel = match layout.shape().dims() {
             [b, m, _k] => b * m <= max_bm,
             [b, _k] => *b <= max_bm,
@@ -500,6 +515,11 @@ impl QCudaStorage {
 }
 
 impl QCudaStorage {
+    // There are no mmvq kernels for NF4 as its values are not integers.
+    fn use_dmmv(&self) -> bool {
+        FORCE_DMMV.load(std::sync::atomic::Ordering::Relaxed) || self.dtype == GgmlDType::Nf4
+    }
+
     fn dequantize_matmul_vec(
         &self,
         self_shape: &crate::Shape,
@@ -521,7 +541,7 @@ impl QCudaStorage {
             crate::bail!("mismatch on matmul dim {self_shape:?} {:?}", rhs_l.shape())
         }
 
-        let out = if FORCE_DMMV.load(std::sync::atomic::Ordering::Relaxed) {
+        let out = if self.use_dmmv() {
             dequantize_mul_mat_vec(&self.data, &rhs, self.dtype, ncols, nrows, self.device())?
         } else {
             mul_mat_vec_via_q8_1(
@@ -557,7 +577,12 @@ impl QCudaStorage {
             crate::bail!("mismatch on matmul dim {self_shape:?} {:?}", layout.shape())
         }
 
-        let out = if FORCE_DMMV.load(std::sync::atomic::Ordering::Relaxed) {
+        // There are no mmq kernels for the IQ types and NF4 so the weights are dequantized.
+        let dequantize = matches!(
+            self.dtype,
+            GgmlDType::Iq2Xxs | GgmlDType::Iq4Nl | GgmlDType::Nf4
+        );
+        let out = if dequantize || FORCE_DMMV.load(std::sync::atomic::Ordering::Relaxed) {
             let data_f32 = self.dequantize(n * k)?;
             let rhs_l = crate::Layout::new((k, n).into(), vec![1, k], 0).broadcast_as((b, k, n))?;
             storage.matmul(&data_f32, (b, m, n, k), layout, &rhs_l)?
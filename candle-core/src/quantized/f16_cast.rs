@@ -0,0 +1,155 @@
+//! Overflow-safe conversion of full precision weights to f16.
+//!
+//! The range of bf16 is the same as f32 while f16 saturates at 65504, a plain cast turns the
+//! larger values into infinities which then propagate through the whole model. The cast here
+//! saturates these values to the largest f16 instead, or rescales the whole tensor by a power of
+//! two when [`CastOptions::rescale`] is set, and reports how many values were affected so that
+//! the problematic tensors can be spotted before shipping a converted model.
+use super::{gguf_file, GgmlDType, QTensor};
+use crate::{DType, Device, Result, Tensor};
+use half::f16;
+
+/// The prefix of the gguf metadata keys holding the scale of the rescaled tensors, the weights
+/// of the tensor `name` have to be multiplied by the `candle.f16_scale.{name}` value. This is
+/// done by [`gguf_file::Content::tensor`] which returns these tensors in f32.
+pub const SCALE_KEY_PREFIX: &str = "candle.f16_scale.";
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CastOptions {
+    /// Divide the tensors that overflow by a power of two so that all their values fit in f16,
+    /// rather than saturating these values.
+    pub rescale: bool,
+}
+
+/// What happened to the values of a tensor when casting it to f16.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CastStats {
+    pub elem_count: usize,
+    /// The number of finite values too large for f16.
+    pub overflow: usize,
+    /// The number of values saturated to the largest f16, this is zero when the tensor has been
+    /// rescaled.
+    pub saturated: usize,
+    /// The number of non-zero values that became zero.
+    pub underflow: usize,
+    /// The number of non-zero values that became f16 subnormals, these lose some precision.
+    pub subnormal: usize,
+    /// The number of infinite or nan values in the source tensor, these are kept as is.
+    pub non_finite: usize,
+    /// The largest finite absolute value of the source tensor.
+    pub max_abs: f32,
+    /// The factor the stored f16 values have to be multiplied by, 1 unless rescaled.
+    pub scale: f32,
+}
+
+impl CastStats {
+    /// Whether some values were saturated or lost.
+    pub fn is_lossy(&self) -> bool {
+        self.saturated > 0 || self.underflow > 0
+    }
+}
+
+/// Casts `tensor` to f16 with saturation, the result is on the cpu.
+pub fn cast_to_f16(tensor: &Tensor, options: &CastOptions) -> Result<(Tensor, CastStats)> {
+    let values = tensor
+        .to_device(&Device::Cpu)?
+        .to_dtype(DType::F32)?
+        .flatten_all()?
+        .to_vec1::<f32>()?;
+    let mut stats = CastStats {
+        elem_count: values.len(),
+        overflow: 0,
+        saturated: 0,
+        underflow: 0,
+        subnormal: 0,
+        non_finite: 0,
+        max_abs: 0.,
+        scale: 1.,
+    };
+    for &v in values.iter() {
+        if !v.is_finite() {
+            stats.non_finite += 1;
+            continue;
+        }
+        stats.max_abs = stats.max_abs.max(v.abs());
+        if f16::from_f32(v).is_infinite() {
+            stats.overflow += 1
+        }
+    }
+    if options.rescale && stats.overflow > 0 {
+        // A power of two scale only changes the exponents, it is doubled when the largest value
+        // still rounds to an infinity.
+        let ratio = stats.max_abs / f16::MAX.to_f32();
+        stats.scale = 2f32.powi(ratio.log2().ceil() as i32);
+        if f16::from_f32(stats.max_abs / stats.scale).is_infinite() {
+            stats.scale *= 2.
+        }
+    }
+    let inv_scale = 1. / stats.scale;
+    let f16_max = f16::MAX.to_f32();
+    let data = values
+        .iter()
+        .map(|&v| {
+            if !v.is_finite() {
+                return f16::from_f32(v);
+            }
+            let v = v * inv_scale;
+            let h = f16::from_f32(v);
+            if h.is_infinite() {
+                stats.saturated += 1;
+                f16::from_f32(f16_max.copysign(v))
+            } else {
+                if v != 0. {
+                    if h.to_f32() == 0. {
+                        stats.underflow += 1
+                    } else if h.to_f32().abs() < f16::MIN_POSITIVE.to_f32() {
+                        stats.subnormal += 1
+                    }
+                }
+                h
+            }
+        })
+        .collect::<Vec<_>>();
+    let tensor = Tensor::from_vec(data, tensor.shape(), &Device::Cpu)?;
+    Ok((tensor, stats))
+}
+
+/// Writes `tensors` in the gguf format, the float tensors with at least two dimensions are stored
+/// as f16 using [`cast_to_f16`] and the other ones as f32, as done by llama.cpp for the norm
+/// weights and the biases. The scales of the rescaled tensors are added to the metadata, see
+/// [`SCALE_KEY_PREFIX`]. Returns the statistics of the tensors cast to f16.
+pub fn write_gguf_f16<W: std::io::Seek + std::io::Write>(
+    w: &mut W,
+    metadata: &[(&str, &gguf_file::Value)],
+    tensors: &[(&str, &Tensor)],
+    options: &CastOptions,
+) -> Result<Vec<(String, CastStats)>> {
+    let mut report = vec![];
+    let mut qtensors = Vec::with_capacity(tensors.len());
+    for (name, tensor) in tensors.iter() {
+        let qtensor = if tensor.dtype().is_float() && tensor.rank() >= 2 {
+            let (tensor, stats) = cast_to_f16(tensor, options)?;
+            report.push((name.to_string(), stats));
+            QTensor::quantize(&tensor, GgmlDType::F16)?
+        } else {
+            QTensor::quantize(tensor, GgmlDType::F32)?
+        };
+        qtensors.push((*name, qtensor))
+    }
+    let scales = report
+        .iter()
+        .filter(|(_, stats)| stats.scale != 1.)
+        .map(|(name, stats)| {
+            let key = format!("{SCALE_KEY_PREFIX}{name}");
+            (key, gguf_file::Value::F32(stats.scale))
+        })
+        .collect::<Vec<_>>();
+    let mut all_metadata = metadata.to_vec();
+    all_metadata.extend(scales.iter().map(|(k, v)| (k.as_str(), v)));
+    let qtensors = qtensors
+        .iter()
+        .map(|(name, t)| (*name, t))
+        .collect::<Vec<_>>();
+    gguf_file::write(w, &all_metadata, &qtensors)?;
+    Ok(report)
+}
@@ -183,6 +183,12 @@ pub fn qtensor_from_ggml(
         GgmlDType::Q6K => {
             from_raw_data::<k_quants::BlockQ6K>(raw_data, size_in_bytes, dims, device)
         }
+        GgmlDType::Iq2Xxs => {
+            from_raw_data::<k_quants::BlockIQ2xxs>(raw_data, size_in_bytes, dims, device)
+        }
+        GgmlDType::Iq4Nl => {
+            from_raw_data::<k_quants::BlockIQ4nl>(raw_data, size_in_bytes, dims, device)
+        }
         _ => crate::bail!("quantized type {ggml_dtype:?} is not supported yet"),
     }
 }
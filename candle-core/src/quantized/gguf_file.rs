@@ -476,7 +476,42 @@ impl Content {
             Some(tensor_info) => tensor_info,
             None => crate::bail!("cannot find tensor info for {name}"),
         };
-        tensor_info.read(reader, self.tensor_data_offset, device)
+        let tensor = tensor_info.read(reader, self.tensor_data_offset, device)?;
+        self.apply_f16_scale(tensor, name, device)
+    }
+
+    /// Creates a tensor from a shared mapping of the gguf file, on cpu the tensor data is used
+    /// in place rather than copied.
+    pub fn tensor_from_mapping(
+        &self,
+        mapping: &super::shared::SharedMapping,
+        name: &str,
+        device: &Device,
+    ) -> Result<QTensor> {
+        let tensor_info = match self.tensor_infos.get(name) {
+            Some(tensor_info) => tensor_info,
+            None => crate::bail!("cannot find tensor info for {name}"),
+        };
+        let offset = (self.tensor_data_offset + tensor_info.offset) as usize;
+        let tensor = mapping.qtensor(
+            offset,
+            tensor_info.ggml_dtype,
+            tensor_info.shape.dims().to_vec(),
+            device,
+        )?;
+        self.apply_f16_scale(tensor, name, device)
+    }
+
+    /// Multiplies the tensors rescaled by [`super::f16_cast::write_gguf_f16`] by their scale. The
+    /// result is stored in f32 as the rescaled values do not fit in f16.
+    fn apply_f16_scale(&self, tensor: QTensor, name: &str, device: &Device) -> Result<QTensor> {
+        let key = format!("{}{name}", super::f16_cast::SCALE_KEY_PREFIX);
+        let scale = match self.metadata.get(&key) {
+            None => return Ok(tensor),
+            Some(scale) => scale.to_f32()?,
+        };
+        let tensor = (tensor.dequantize(device)? * scale as f64)?;
+        QTensor::quantize(&tensor, GgmlDType::F32)
     }
 }
 
@@ -487,11 +522,18 @@ fn write_string<W: std::io::Write>(w: &mut W, str: &str) -> Result<()> {
     Ok(())
 }
 
+/// Writes a gguf file with the given metadata and tensors, in this order. Tensors can be
+/// created from full precision ones with [`QTensor::quantize`], and the resulting file can be
+/// read back with [`Content::read`].
 pub fn write<W: std::io::Seek + std::io::Write>(
     w: &mut W,
     metadata: &[(&str, &Value)],
     tensors: &[(&str, &QTensor)],
 ) -> Result<()> {
+    // Check the dtypes before writing anything.
+    for (_, tensor) in tensors.iter() {
+        tensor.dtype().to_u32()?;
+    }
     w.write_u32::<LittleEndian>(0x46554747)?;
     w.write_u32::<LittleEndian>(2)?; // version 2.
     w.write_u64::<LittleEndian>(tensors.len() as u64)?;
@@ -510,7 +552,7 @@ pub fn write<W: std::io::Seek + std::io::Write>(
         for &dim in dims.iter().rev() {
             w.write_u64::<LittleEndian>(dim as u64)?;
         }
-        w.write_u32::<LittleEndian>(tensor.dtype().to_u32())?;
+        w.write_u32::<LittleEndian>(tensor.dtype().to_u32()?)?;
         w.write_u64::<LittleEndian>(offset as u64)?;
         offsets.push(offset);
         let size_in_bytes = tensor.storage_size_in_bytes();
@@ -18,6 +18,92 @@ pub const QK5_0: usize = 32;
 pub const QK5_1: usize = 32;
 pub const QK8_0: usize = 32;
 pub const QK8_1: usize = 32;
+pub const QK4_NL: usize = 32;
+
+// The non-linear grid used by IQ4_NL, the values are more densely packed around zero where most
+// of the weights are.
+pub(crate) const KVALUES_IQ4NL: [i8; 16] = [
+    -127, -104, -83, -65, -49, -35, -22, -10, 1, 13, 25, 38, 53, 69, 89, 113,
+];
+
+// IQ2_XXS and IQ2_XS quantize groups of 8 values to points of a lattice, the 8 coordinates of a
+// point are indexes in `IQ2_VALUES` packed on 2 bits each. These are the 256 points used by
+// IQ2_XXS, the grid is the same as `iq2xxs_grid` in ggml.
+const KGRID_IQ2XXS: [u16; 256] = [
+    0, 2, 5, 8, 10, 17, 20, 32, 34, 40, 42, 65, 68, 80, 88, 97, 100, 128, 130, 138, 162, 257, 260,
+    272, 277, 320, 388, 408, 512, 514, 546, 642, 1025, 1028, 1040, 1057, 1060, 1088, 1090, 1096,
+    1120, 1153, 1156, 1168, 1188, 1280, 1282, 1288, 1312, 1350, 1385, 1408, 1425, 1545, 1552, 1600,
+    1668, 1700, 2048, 2053, 2056, 2068, 2088, 2113, 2116, 2128, 2130, 2184, 2308, 2368, 2562, 2580,
+    4097, 4100, 4112, 4129, 4160, 4192, 4228, 4240, 4245, 4352, 4360, 4384, 4432, 4442, 4480, 4644,
+    4677, 5120, 5128, 5152, 5157, 5193, 5248, 5400, 5474, 5632, 5654, 6145, 6148, 6160, 6208, 6273,
+    6400, 6405, 6560, 6737, 8192, 8194, 8202, 8260, 8289, 8320, 8322, 8489, 8520, 8704, 8706, 9217,
+    9220, 9232, 9280, 9302, 9472, 9537, 9572, 9872, 10248, 10272, 10388, 10820, 16385, 16388,
+    16400, 16408, 16417, 16420, 16448, 16456, 16470, 16480, 16513, 16516, 16528, 16640, 16672,
+    16737, 16768, 16773, 16897, 16912, 16968, 16982, 17000, 17408, 17416, 17440, 17536, 17561,
+    17682, 17700, 17920, 18433, 18436, 18448, 18496, 18501, 18688, 18776, 18785, 18818, 19013,
+    19088, 20480, 20488, 20497, 20505, 20512, 20608, 20616, 20740, 20802, 20900, 21137, 21648,
+    21650, 21770, 22017, 22100, 22528, 22545, 22553, 22628, 22848, 23048, 24580, 24592, 24640,
+    24680, 24832, 24917, 25112, 25184, 25600, 25605, 25872, 25874, 25988, 26690, 32768, 32770,
+    32778, 32833, 32898, 33028, 33048, 33088, 33297, 33793, 33796, 33808, 33813, 33856, 33888,
+    34048, 34118, 34196, 34313, 34368, 34400, 34818, 35076, 35345, 36868, 36880, 36900, 36928,
+    37025, 37142, 37248, 37445, 37888, 37922, 37956, 38225, 39041, 39200, 40962, 41040, 41093,
+    41225, 41472, 42008, 43088, 43268,
+];
+const IQ2_VALUES: [u8; 3] = [0x08, 0x19, 0x2b];
+pub(crate) const IQ2XXS_GRID: [[u8; 8]; 256] = iq2_grid(&KGRID_IQ2XXS);
+
+const fn iq2_grid<const N: usize>(kgrid: &[u16; N]) -> [[u8; 8]; N] {
+    let mut grid = [[0u8; 8]; N];
+    let mut k = 0;
+    while k < N {
+        let mut i = 0;
+        while i < 8 {
+            grid[k][i] = IQ2_VALUES[((kgrid[k] >> (2 * i)) & 3) as usize];
+            i += 1;
+        }
+        k += 1;
+    }
+    grid
+}
+
+// The signs of 8 values are stored on 7 bits, the number of negative values being made even by
+// the quantization. `KSIGNS_IQ2XS[s]` has the 7 bits of `s` and their parity as the 8th bit.
+pub(crate) const KSIGNS_IQ2XS: [u8; 128] = {
+    let mut signs = [0u8; 128];
+    let mut i = 0;
+    while i < 128 {
+        signs[i] = i as u8 | (((i as u32).count_ones() as u8 & 1) << 7);
+        i += 1;
+    }
+    signs
+};
+
+// NF4 uses blocks of 64 values sharing an absmax, as bitsandbytes does for QLoRA. The absmax
+// values are stored as f16 so that a block keeps its precision whatever the range of its
+// neighbours, 4 of these blocks are grouped in a super-block.
+pub const QK_NF4: usize = QK_K;
+pub const NF4_BLOCK_SIZE: usize = 64;
+
+// The quantiles of a standard normal distribution normalized to [-1, 1], this is the NF4 table
+// from bitsandbytes.
+pub(crate) const KVALUES_NF4: [f32; 16] = [
+    -1.0,
+    -0.696_192_8,
+    -0.525_073_05,
+    -0.394_917_5,
+    -0.284_441_38,
+    -0.184_773_43,
+    -0.091_050_036,
+    0.0,
+    0.079_580_3,
+    0.160_930_2,
+    0.246_112_3,
+    0.337_915_24,
+    0.440_709_83,
+    0.562_617,
+    0.722_956_84,
+    1.0,
+];
 
 pub trait GgmlType: Sized + Clone + Send + Sync {
     const DTYPE: GgmlDType;
@@ -154,6 +240,34 @@ pub struct BlockQ8K {
 }
 const _: () = assert!(4 + QK_K + QK_K / 16 * 2 == std::mem::size_of::<BlockQ8K>());
 
+#[derive(Debug, Clone, PartialEq)]
+#[repr(C)]
+pub struct BlockIQ4nl {
+    pub(crate) d: f16,
+    pub(crate) qs: [u8; QK4_NL / 2],
+}
+const _: () = assert!(std::mem::size_of::<BlockIQ4nl>() == 18);
+
+/// 8 groups of 32 values, each group is stored on 64 bits: the indexes of 4 lattice points on the
+/// first 32 bits, then 4 times 7 sign bits and the 4 bits of the group scale.
+#[derive(Debug, Clone, PartialEq)]
+#[repr(C)]
+pub struct BlockIQ2xxs {
+    pub(crate) d: f16,
+    pub(crate) qs: [u16; QK_K / 8],
+}
+const _: () = assert!(std::mem::size_of::<BlockIQ2xxs>() == 66);
+
+/// 4 blocks of 64 NF4 values, the absmax of block `j` is `absmax[j]`. The values are packed like
+/// bitsandbytes does, the first value of each pair being in the high nibble.
+#[derive(Debug, Clone, PartialEq)]
+#[repr(C)]
+pub struct BlockNf4 {
+    pub(crate) absmax: [f16; QK_NF4 / NF4_BLOCK_SIZE],
+    pub(crate) qs: [u8; QK_NF4 / 2],
+}
+const _: () = assert!(std::mem::size_of::<BlockNf4>() == 136);
+
 impl GgmlType for BlockQ4_0 {
     const DTYPE: GgmlDType = GgmlDType::Q4_0;
     const BLCK_SIZE: usize = QK4_0;
@@ -1838,6 +1952,460 @@ impl GgmlType for BlockQ8K {
     }
 }
 
+// The index of the grid value closest to x.
+fn best_index_iq4nl(x: f32) -> u8 {
+    let values = &KVALUES_IQ4NL;
+    if x <= values[0] as f32 {
+        return 0;
+    }
+    if x >= values[15] as f32 {
+        return 15;
+    }
+    let (mut lo, mut hi) = (0, 15);
+    while hi - lo > 1 {
+        let mid = (lo + hi) / 2;
+        if x < values[mid] as f32 {
+            hi = mid
+        } else {
+            lo = mid
+        }
+    }
+    if x - values[lo] as f32 <= values[hi] as f32 - x {
+        lo as u8
+    } else {
+        hi as u8
+    }
+}
+
+impl GgmlType for BlockIQ4nl {
+    const DTYPE: GgmlDType = GgmlDType::Iq4Nl;
+    const BLCK_SIZE: usize = QK4_NL;
+    type VecDotType = BlockQ8_0;
+
+    fn to_float(xs: &[Self], ys: &mut [f32]) -> Result<()> {
+        let k = ys.len();
+        if k % QK4_NL != 0 {
+            crate::bail!("dequantize_row_iq4_nl: {k} is not divisible by {QK4_NL}")
+        }
+        for (x, ys) in xs.iter().zip(ys.chunks_exact_mut(QK4_NL)) {
+            let d = x.d.to_f32();
+            for (j, &q) in x.qs.iter().enumerate() {
+                ys[j] = d * KVALUES_IQ4NL[(q & 0x0F) as usize] as f32;
+                ys[j + QK4_NL / 2] = d * KVALUES_IQ4NL[(q >> 4) as usize] as f32;
+            }
+        }
+        Ok(())
+    }
+
+    // quantize_row_iq4_nl_impl, without an importance matrix the weights are the squared values.
+    fn from_float(xs: &[f32], ys: &mut [Self]) -> Result<()> {
+        let k = xs.len();
+        if k % QK4_NL != 0 {
+            crate::bail!("{k} is not divisible by {QK4_NL}");
+        };
+        let nb = k / QK4_NL;
+        if ys.len() != nb {
+            crate::bail!("size mismatch {} {} {}", xs.len(), ys.len(), QK4_NL)
+        }
+        const NTRY: i32 = 7;
+        for (ys, xs) in ys.iter_mut().zip(xs.chunks_exact(QK4_NL)) {
+            let mut amax = 0f32;
+            let mut max = 0f32;
+            for &x in xs.iter() {
+                if amax < x.abs() {
+                    amax = x.abs();
+                    max = x;
+                }
+            }
+            if amax < 1e-30 {
+                ys.d = f16::ZERO;
+                ys.qs = [0; QK4_NL / 2];
+                continue;
+            }
+            // Returns the least squares scale for the grid indexes obtained with `id`.
+            let fit = |id: f32, l: &mut [u8; QK4_NL]| {
+                let (mut sumqx, mut sumq2) = (0f32, 0f32);
+                for (l, &x) in l.iter_mut().zip(xs.iter()) {
+                    *l = best_index_iq4nl(id * x);
+                    let q = KVALUES_IQ4NL[*l as usize] as f32;
+                    let w = x * x;
+                    sumqx += w * q * x;
+                    sumq2 += w * q * q;
+                }
+                (sumqx, sumq2)
+            };
+            let mut l = [0u8; QK4_NL];
+            let mut best_l = [0u8; QK4_NL];
+            let mut d = max / KVALUES_IQ4NL[0] as f32;
+            let mut best = 0f32;
+            let (sumqx, sumq2) = fit(1. / d, &mut best_l);
+            if sumq2 > 0. {
+                d = sumqx / sumq2;
+                best = d * sumqx;
+            }
+            for itry in -NTRY..=NTRY {
+                let id = (itry as f32 + KVALUES_IQ4NL[0] as f32) / max;
+                let (sumqx, sumq2) = fit(id, &mut l);
+                if sumq2 > 0. && sumqx * sumqx > best * sumq2 {
+                    d = sumqx / sumq2;
+                    best = d * sumqx;
+                    best_l = l;
+                }
+            }
+            ys.d = f16::from_f32(d);
+            for (j, q) in ys.qs.iter_mut().enumerate() {
+                *q = best_l[j] | (best_l[j + QK4_NL / 2] << 4)
+            }
+        }
+        Ok(())
+    }
+
+    #[allow(unreachable_code)]
+    fn vec_dot(n: usize, xs: &[Self], ys: &[Self::VecDotType]) -> Result<f32> {
+        #[cfg(target_feature = "avx")]
+        return super::avx::vec_dot_iq4_nl_q8_0(n, xs, ys);
+
+        #[cfg(target_feature = "neon")]
+        return super::neon::vec_dot_iq4_nl_q8_0(n, xs, ys);
+
+        Self::vec_dot_unopt(n, xs, ys)
+    }
+
+    fn vec_dot_unopt(n: usize, xs: &[Self], ys: &[Self::VecDotType]) -> Result<f32> {
+        if n % QK4_NL != 0 {
+            crate::bail!("vec_dot_iq4_nl_q8_0: {n} is not divisible by {QK4_NL}")
+        }
+        let mut sumf = 0f32;
+        for (xs, ys) in xs.iter().zip(ys.iter()) {
+            let mut sum_i = 0;
+            for j in 0..QK4_NL / 2 {
+                let v0 = KVALUES_IQ4NL[(xs.qs[j] & 0x0F) as usize] as i32;
+                let v1 = KVALUES_IQ4NL[(xs.qs[j] >> 4) as usize] as i32;
+                sum_i += v0 * ys.qs[j] as i32 + v1 * ys.qs[j + QK4_NL / 2] as i32
+            }
+            sumf += sum_i as f32 * f16::to_f32(xs.d) * f16::to_f32(ys.d)
+        }
+        Ok(sumf)
+    }
+}
+
+// The lattice point closest to `xs / scale` for the weighted squared error.
+fn best_iq2xxs_point(xs: &[f32], weights: &[f32], scale: f32) -> usize {
+    let mut best = (f32::MAX, 0);
+    for (index, point) in IQ2XXS_GRID.iter().enumerate() {
+        let mut err = 0f32;
+        for ((&x, &w), &q) in xs.iter().zip(weights.iter()).zip(point.iter()) {
+            let diff = scale * q as f32 / 8. - x;
+            err += w * diff * diff
+        }
+        if err < best.0 {
+            best = (err, index)
+        }
+    }
+    best.1
+}
+
+impl GgmlType for BlockIQ2xxs {
+    const DTYPE: GgmlDType = GgmlDType::Iq2Xxs;
+    const BLCK_SIZE: usize = QK_K;
+    type VecDotType = BlockQ8K;
+
+    fn to_float(xs: &[Self], ys: &mut [f32]) -> Result<()> {
+        let k = ys.len();
+        if k % QK_K != 0 {
+            crate::bail!("dequantize_row_iq2_xxs: {k} is not divisible by {QK_K}")
+        }
+        for (x, ys) in xs.iter().zip(ys.chunks_exact_mut(QK_K)) {
+            let d = x.d.to_f32();
+            for (q2, ys) in x.qs.chunks_exact(4).zip(ys.chunks_exact_mut(32)) {
+                let aux0 = q2[0] as u32 | (q2[1] as u32) << 16;
+                let aux1 = q2[2] as u32 | (q2[3] as u32) << 16;
+                let db = d * (0.5 + (aux1 >> 28) as f32) * 0.25;
+                for (l, ys) in ys.chunks_exact_mut(8).enumerate() {
+                    let grid = &IQ2XXS_GRID[(aux0 >> (8 * l)) as usize & 0xFF];
+                    let signs = KSIGNS_IQ2XS[(aux1 >> (7 * l)) as usize & 127];
+                    for (j, y) in ys.iter_mut().enumerate() {
+                        let sign = if signs & (1 << j) != 0 { -1. } else { 1. };
+                        *y = db * grid[j] as f32 * sign
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // A simplified quantize_row_iq2_xxs_impl, without an importance matrix the weights only
+    // depend on the values and the points that are not on the lattice are replaced by the closest
+    // lattice point.
+    fn from_float(xs: &[f32], ys: &mut [Self]) -> Result<()> {
+        let k = xs.len();
+        if k % QK_K != 0 {
+            crate::bail!("{k} is not divisible by {QK_K}");
+        };
+        let nb = k / QK_K;
+        if ys.len() != nb {
+            crate::bail!("size mismatch {} {} {}", xs.len(), ys.len(), QK_K)
+        }
+        const NTRY: i32 = 6;
+        for (ys, xs) in ys.iter_mut().zip(xs.chunks_exact(QK_K)) {
+            let sigma2 = xs.iter().map(|x| x * x).sum::<f32>() / QK_K as f32;
+            let mut scales = [0f32; QK_K / 32];
+            // The point indexes and the signs of each group of 32 values.
+            let mut aux = [[0u32; 2]; QK_K / 32];
+            for ((xb, scale), aux) in xs
+                .chunks_exact(32)
+                .zip(scales.iter_mut())
+                .zip(aux.iter_mut())
+            {
+                let mut weights = [0f32; 32];
+                let mut xval = [0f32; 32];
+                for (i, &x) in xb.iter().enumerate() {
+                    weights[i] = (sigma2 + x * x).sqrt();
+                    xval[i] = x.abs();
+                }
+                for (l, xb) in xb.chunks_exact(8).enumerate() {
+                    let mut signs = 0u32;
+                    for (i, &x) in xb.iter().enumerate() {
+                        if x < 0. {
+                            signs |= 1 << i
+                        }
+                    }
+                    // Only an even number of negative values can be stored, so the sign of the
+                    // value with the smallest weighted magnitude gets flipped.
+                    if signs.count_ones() % 2 == 1 {
+                        let mut imin = 0;
+                        for i in 1..8 {
+                            let e = |i: usize| weights[8 * l + i] * xb[i] * xb[i];
+                            if e(i) < e(imin) {
+                                imin = i
+                            }
+                        }
+                        xval[8 * l + imin] = -xval[8 * l + imin];
+                        signs ^= 1 << imin;
+                    }
+                    aux[1] |= (signs & 127) << (7 * l)
+                }
+                let max = xval.iter().fold(0f32, |m, &x| m.max(x));
+                if max < 1e-15 {
+                    continue;
+                }
+                let mut best = 0f32;
+                let mut best_points = [0usize; 4];
+                for itry in -NTRY..=NTRY {
+                    let id = (5. + itry as f32 * 0.1) / max;
+                    let mut points = [0usize; 4];
+                    let (mut sumqx, mut sumq2) = (0f32, 0f32);
+                    for (l, point) in points.iter_mut().enumerate() {
+                        let xs = &xval[8 * l..8 * l + 8];
+                        let ws = &weights[8 * l..8 * l + 8];
+                        let mut u = 0u16;
+                        for (i, &x) in xs.iter().enumerate() {
+                            let l = nearest_int(0.5 * (id * x - 1.)).clamp(0, 2);
+                            u |= (l as u16) << (2 * i)
+                        }
+                        *point = match KGRID_IQ2XXS.binary_search(&u) {
+                            Ok(point) => point,
+                            Err(_) => best_iq2xxs_point(xs, ws, 1. / id),
+                        };
+                        for ((&x, &w), &q) in
+                            xs.iter().zip(ws.iter()).zip(IQ2XXS_GRID[*point].iter())
+                        {
+                            let q = q as f32 / 8.;
+                            sumqx += w * q * x;
+                            sumq2 += w * q * q;
+                        }
+                    }
+                    if sumq2 > 0. && sumqx * sumqx > best * sumq2 {
+                        *scale = sumqx / sumq2;
+                        best = *scale * sumqx;
+                        best_points = points;
+                    }
+                }
+                for (l, &point) in best_points.iter().enumerate() {
+                    aux[0] |= (point as u32) << (8 * l)
+                }
+            }
+            let max_scale = scales.iter().fold(0f32, |m, &s| m.max(s));
+            if max_scale < 1e-15 {
+                ys.d = f16::ZERO;
+                ys.qs = [0; QK_K / 8];
+                continue;
+            }
+            let d = max_scale / 31.;
+            let id = 1. / d;
+            for ((q2, aux), &scale) in ys.qs.chunks_exact_mut(4).zip(aux.iter()).zip(scales.iter())
+            {
+                let l = nearest_int(0.5 * (id * scale - 1.)).clamp(0, 15) as u32;
+                let aux1 = aux[1] | (l << 28);
+                q2[0] = aux[0] as u16;
+                q2[1] = (aux[0] >> 16) as u16;
+                q2[2] = aux1 as u16;
+                q2[3] = (aux1 >> 16) as u16;
+            }
+            ys.d = f16::from_f32(d);
+        }
+        Ok(())
+    }
+
+    #[allow(unreachable_code)]
+    fn vec_dot(n: usize, xs: &[Self], ys: &[Self::VecDotType]) -> Result<f32> {
+        #[cfg(target_feature = "avx")]
+        return super::avx::vec_dot_iq2_xxs_q8k(n, xs, ys);
+
+        Self::vec_dot_unopt(n, xs, ys)
+    }
+
+    fn vec_dot_unopt(n: usize, xs: &[Self], ys: &[Self::VecDotType]) -> Result<f32> {
+        if n % QK_K != 0 {
+            crate::bail!("vec_dot_iq2_xxs_q8k: {n} is not divisible by {QK_K}")
+        }
+        let mut sumf = 0f32;
+        for (x, y) in xs.iter().zip(ys.iter()) {
+            let mut bsum = 0i32;
+            for (q2, q8) in x.qs.chunks_exact(4).zip(y.qs.chunks_exact(32)) {
+                let aux0 = q2[0] as u32 | (q2[1] as u32) << 16;
+                let aux1 = q2[2] as u32 | (q2[3] as u32) << 16;
+                let mut sumi = 0i32;
+                for (l, q8) in q8.chunks_exact(8).enumerate() {
+                    let grid = &IQ2XXS_GRID[(aux0 >> (8 * l)) as usize & 0xFF];
+                    let signs = KSIGNS_IQ2XS[(aux1 >> (7 * l)) as usize & 127];
+                    for (j, &q8) in q8.iter().enumerate() {
+                        let v = grid[j] as i32 * q8 as i32;
+                        sumi += if signs & (1 << j) != 0 { -v } else { v }
+                    }
+                }
+                bsum += sumi * (2 * (aux1 >> 28) as i32 + 1)
+            }
+            sumf += x.d.to_f32() * y.d * bsum as f32
+        }
+        Ok(0.125 * sumf)
+    }
+}
+
+// The index of the NF4 value closest to x, x being in [-1, 1].
+fn best_index_nf4(x: f32) -> u8 {
+    let values = &KVALUES_NF4;
+    let (mut lo, mut hi) = (0, 15);
+    while hi - lo > 1 {
+        let mid = (lo + hi) / 2;
+        if x < values[mid] {
+            hi = mid
+        } else {
+            lo = mid
+        }
+    }
+    if x - values[lo] <= values[hi] - x {
+        lo as u8
+    } else {
+        hi as u8
+    }
+}
+
+impl BlockNf4 {
+    /// Builds a block from 256 NF4 indexes packed as per bitsandbytes and the absmax of the 4
+    /// blocks of 64 values, the absmax values are stored as f16.
+    pub fn from_codes(qs: &[u8], absmax: &[f32]) -> Result<Self> {
+        if qs.len() != QK_NF4 / 2 || absmax.len() != QK_NF4 / NF4_BLOCK_SIZE {
+            crate::bail!("unexpected nf4 block sizes {} {}", qs.len(), absmax.len())
+        }
+        let mut block = Self {
+            absmax: [f16::ZERO; QK_NF4 / NF4_BLOCK_SIZE],
+            qs: [0; QK_NF4 / 2],
+        };
+        for (d, &a) in block.absmax.iter_mut().zip(absmax.iter()) {
+            *d = f16::from_f32(a.abs())
+        }
+        block.qs.copy_from_slice(qs);
+        Ok(block)
+    }
+
+    /// The absmax of the 4 blocks of 64 values.
+    pub fn absmax(&self) -> [f32; QK_NF4 / NF4_BLOCK_SIZE] {
+        self.absmax.map(|d| d.to_f32())
+    }
+}
+
+impl GgmlType for BlockNf4 {
+    const DTYPE: GgmlDType = GgmlDType::Nf4;
+    const BLCK_SIZE: usize = QK_NF4;
+    type VecDotType = BlockQ8K;
+
+    fn to_float(xs: &[Self], ys: &mut [f32]) -> Result<()> {
+        let k = ys.len();
+        if k % QK_NF4 != 0 {
+            crate::bail!("dequantize_row_nf4: {k} is not divisible by {QK_NF4}")
+        }
+        for (x, ys) in xs.iter().zip(ys.chunks_exact_mut(QK_NF4)) {
+            let absmax = x.absmax();
+            for (j, ys) in ys.chunks_exact_mut(NF4_BLOCK_SIZE).enumerate() {
+                let qs = &x.qs[j * NF4_BLOCK_SIZE / 2..(j + 1) * NF4_BLOCK_SIZE / 2];
+                for (ys, &q) in ys.chunks_exact_mut(2).zip(qs.iter()) {
+                    ys[0] = absmax[j] * KVALUES_NF4[(q >> 4) as usize];
+                    ys[1] = absmax[j] * KVALUES_NF4[(q & 0x0F) as usize];
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn from_float(xs: &[f32], ys: &mut [Self]) -> Result<()> {
+        let k = xs.len();
+        if k % QK_NF4 != 0 {
+            crate::bail!("{k} is not divisible by {QK_NF4}");
+        };
+        let nb = k / QK_NF4;
+        if ys.len() != nb {
+            crate::bail!("size mismatch {} {} {}", xs.len(), ys.len(), QK_NF4)
+        }
+        for (ys, xs) in ys.iter_mut().zip(xs.chunks_exact(QK_NF4)) {
+            let mut absmax = [0f32; QK_NF4 / NF4_BLOCK_SIZE];
+            for (a, xs) in absmax.iter_mut().zip(xs.chunks_exact(NF4_BLOCK_SIZE)) {
+                *a = xs.iter().fold(0f32, |m, &x| m.max(x.abs()))
+            }
+            *ys = Self::from_codes(&[0; QK_NF4 / 2], &absmax)?;
+            // Quantize the values with the quantized absmax rather than the original ones.
+            let absmax = ys.absmax();
+            for (j, xs) in xs.chunks_exact(NF4_BLOCK_SIZE).enumerate() {
+                let id = if absmax[j] > 0. { 1. / absmax[j] } else { 0. };
+                let qs = &mut ys.qs[j * NF4_BLOCK_SIZE / 2..(j + 1) * NF4_BLOCK_SIZE / 2];
+                for (q, xs) in qs.iter_mut().zip(xs.chunks_exact(2)) {
+                    let q0 = best_index_nf4((xs[0] * id).clamp(-1., 1.));
+                    let q1 = best_index_nf4((xs[1] * id).clamp(-1., 1.));
+                    *q = (q0 << 4) | q1
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn vec_dot(n: usize, xs: &[Self], ys: &[Self::VecDotType]) -> Result<f32> {
+        Self::vec_dot_unopt(n, xs, ys)
+    }
+
+    fn vec_dot_unopt(n: usize, xs: &[Self], ys: &[Self::VecDotType]) -> Result<f32> {
+        if n % QK_NF4 != 0 {
+            crate::bail!("vec_dot_nf4_q8k: {n} is not divisible by {QK_NF4}")
+        }
+        let mut sumf = 0f32;
+        for (xs, ys) in xs.iter().zip(ys.iter()) {
+            let absmax = xs.absmax();
+            let mut sum_b = 0f32;
+            for (j, &absmax) in absmax.iter().enumerate() {
+                let qs = &xs.qs[j * NF4_BLOCK_SIZE / 2..(j + 1) * NF4_BLOCK_SIZE / 2];
+                let q8 = &ys.qs[j * NF4_BLOCK_SIZE..(j + 1) * NF4_BLOCK_SIZE];
+                let mut sum_j = 0f32;
+                for (&q, q8) in qs.iter().zip(q8.chunks_exact(2)) {
+                    sum_j += KVALUES_NF4[(q >> 4) as usize] * q8[0] as f32
+                        + KVALUES_NF4[(q & 0x0F) as usize] * q8[1] as f32
+                }
+                sum_b += absmax * sum_j
+            }
+            sumf += sum_b * ys.d
+        }
+        Ok(sumf)
+    }
+}
+
 // https://github.com/ggerganov/llama.cpp/blob/b5ffb2849d23afe73647f68eec7b68187af09be6/ggml.c#L10605
 pub fn matmul<T: GgmlType>(
     mkn: (usize, usize, usize),
@@ -103,6 +103,18 @@ impl QMetalStorage {
                 let vec: Vec<crate::quantized::BlockQ8K> = read_to_vec(&buffer, block_len);
                 crate::quantized::BlockQ8K::to_float(&vec, &mut out)?;
             }
+            GgmlDType::Iq2Xxs => {
+                let vec: Vec<crate::quantized::BlockIQ2xxs> = read_to_vec(&buffer, block_len);
+                crate::quantized::BlockIQ2xxs::to_float(&vec, &mut out)?;
+            }
+            GgmlDType::Iq4Nl => {
+                let vec: Vec<crate::quantized::BlockIQ4nl> = read_to_vec(&buffer, block_len);
+                crate::quantized::BlockIQ4nl::to_float(&vec, &mut out)?;
+            }
+            GgmlDType::Nf4 => {
+                let vec: Vec<crate::quantized::BlockNf4> = read_to_vec(&buffer, block_len);
+                crate::quantized::BlockNf4::to_float(&vec, &mut out)?;
+            }
         }
 
         let buffer = self.device.new_buffer_with_data(&out)?;
@@ -164,6 +176,7 @@ impl QMetalStorage {
         dst_shape.push(n);
         let dst_shape = Shape::from(dst_shape);
         let device = storage.device().clone();
+        let dtype = self.dtype.try_into()?;
         let dst = device.new_buffer(dst_shape.elem_count(), DType::F32, "qmatmul")?;
         let command_buffer = device.command_buffer()?;
         // In some cases it would be better to use the mm variant, though it has its drawbacks
@@ -173,7 +186,7 @@ impl QMetalStorage {
                 device.device(),
                 &command_buffer,
                 device.kernels(),
-                self.dtype.into(),
+                dtype,
                 (1, 1, n, k),
                 storage.buffer(),
                 (layout.start_offset() + batch_id * k) * storage.dtype().size_in_bytes(),
@@ -208,9 +221,11 @@ fn read_to_vec<T: Clone>(buffer: &Buffer, n: usize) -> Vec<T> {
     slice.to_vec()
 }
 
-impl From<GgmlDType> for candle_metal_kernels::GgmlDType {
-    fn from(value: GgmlDType) -> Self {
-        match value {
+impl TryFrom<GgmlDType> for candle_metal_kernels::GgmlDType {
+    type Error = crate::Error;
+
+    fn try_from(value: GgmlDType) -> Result<Self> {
+        let dtype = match value {
             GgmlDType::Q4_0 => candle_metal_kernels::GgmlDType::Q4_0,
             GgmlDType::Q4_1 => candle_metal_kernels::GgmlDType::Q4_1,
             GgmlDType::Q5_0 => candle_metal_kernels::GgmlDType::Q5_0,
@@ -225,6 +240,10 @@ impl From<GgmlDType> for candle_metal_kernels::GgmlDType {
             GgmlDType::Q8K => candle_metal_kernels::GgmlDType::Q8K,
             GgmlDType::F16 => candle_metal_kernels::GgmlDType::F16,
             GgmlDType::F32 => candle_metal_kernels::GgmlDType::F32,
-        }
+            GgmlDType::Iq2Xxs | GgmlDType::Iq4Nl | GgmlDType::Nf4 => {
+                crate::bail!("no metal matmul kernel for {value:?}")
+            }
+        };
+        Ok(dtype)
     }
 }
@@ -5,8 +5,10 @@ use std::borrow::Cow;
 
 #[cfg(target_feature = "avx")]
 pub mod avx;
+pub mod bnb;
 mod dummy_cuda;
 mod dummy_metal;
+pub mod f16_cast;
 pub mod ggml_file;
 pub mod gguf_file;
 pub mod k_quants;
@@ -25,6 +27,7 @@ mod cuda {
 
 #[cfg(target_feature = "neon")]
 pub mod neon;
+pub mod shared;
 #[cfg(target_feature = "simd128")]
 pub mod simd128;
 pub mod utils;
@@ -146,6 +149,11 @@ pub enum GgmlDType {
     Q5K,
     Q6K,
     Q8K,
+    Iq2Xxs,
+    Iq4Nl,
+    /// 4-bit NormalFloat with double quantized scales as used by QLoRA, this has no GGML
+    /// equivalent so it cannot be stored in GGUF files.
+    Nf4,
 }
 
 impl GgmlDType {
@@ -165,13 +173,19 @@ impl GgmlDType {
             13 => Self::Q5K,
             14 => Self::Q6K,
             15 => Self::Q8K,
+            16 => Self::Iq2Xxs,
+            17 | 18 => {
+                let name = ["IQ2_XS", "IQ3_XXS"][(u - 17) as usize];
+                crate::bail!("quantized type {name} is not supported yet")
+            }
+            20 => Self::Iq4Nl,
             _ => crate::bail!("unknown dtype for tensor {u}"),
         };
         Ok(dtype)
     }
 
-    pub(crate) fn to_u32(self) -> u32 {
-        match self {
+    pub(crate) fn to_u32(self) -> Result<u32> {
+        let u = match self {
             Self::F32 => 0,
             Self::F16 => 1,
             Self::Q4_0 => 2,
@@ -186,7 +200,11 @@ impl GgmlDType {
             Self::Q5K => 13,
             Self::Q6K => 14,
             Self::Q8K => 15,
-        }
+            Self::Iq2Xxs => 16,
+            Self::Iq4Nl => 20,
+            Self::Nf4 => crate::bail!("{self:?} has no ggml equivalent"),
+        };
+        Ok(u)
     }
 
     /// The block dtype
@@ -206,6 +224,15 @@ impl GgmlDType {
             Self::Q5K => Box::new(vec![BlockQ5K::zeros(); elem_count / BlockQ5K::BLCK_SIZE]),
             Self::Q6K => Box::new(vec![BlockQ6K::zeros(); elem_count / BlockQ6K::BLCK_SIZE]),
             Self::Q8K => Box::new(vec![BlockQ8K::zeros(); elem_count / BlockQ8K::BLCK_SIZE]),
+            Self::Iq2Xxs => Box::new(vec![
+                BlockIQ2xxs::zeros();
+                elem_count / BlockIQ2xxs::BLCK_SIZE
+            ]),
+            Self::Iq4Nl => Box::new(vec![
+                BlockIQ4nl::zeros();
+                elem_count / BlockIQ4nl::BLCK_SIZE
+            ]),
+            Self::Nf4 => Box::new(vec![BlockNf4::zeros(); elem_count / BlockNf4::BLCK_SIZE]),
         }
     }
     /// The type size for blocks in bytes.
@@ -227,6 +254,9 @@ impl GgmlDType {
             Self::Q5K => std::mem::size_of::<BlockQ5K>(),
             Self::Q6K => std::mem::size_of::<BlockQ6K>(),
             Self::Q8K => std::mem::size_of::<BlockQ8K>(),
+            Self::Iq2Xxs => std::mem::size_of::<BlockIQ2xxs>(),
+            Self::Iq4Nl => std::mem::size_of::<BlockIQ4nl>(),
+            Self::Nf4 => std::mem::size_of::<BlockNf4>(),
         }
     }
 
@@ -241,7 +271,15 @@ impl GgmlDType {
             Self::Q5_1 => k_quants::QK5_1,
             Self::Q8_0 => k_quants::QK8_0,
             Self::Q8_1 => k_quants::QK8_1,
-            Self::Q2K | Self::Q3K | Self::Q4K | Self::Q5K | Self::Q6K | Self::Q8K => k_quants::QK_K,
+            Self::Iq4Nl => k_quants::QK4_NL,
+            Self::Nf4 => k_quants::QK_NF4,
+            Self::Q2K
+            | Self::Q3K
+            | Self::Q4K
+            | Self::Q5K
+            | Self::Q6K
+            | Self::Q8K
+            | Self::Iq2Xxs => k_quants::QK_K,
         }
     }
 }
@@ -522,12 +560,59 @@ impl crate::CustomOp1 for QTensor {
         };
         self_storage.fwd(&self.shape, storage, layout)
     }
+
+    /// The gradient is only computed for the input, the quantized weight is frozen. The weight is
+    /// dequantized for the duration of the backward pass.
+    fn bwd(&self, _arg: &Tensor, _res: &Tensor, grad_res: &Tensor) -> Result<Option<Tensor>> {
+        let w = self.dequantize(grad_res.device())?;
+        let grad_arg = grad_res.broadcast_matmul(&w.to_dtype(grad_res.dtype())?)?;
+        Ok(Some(grad_arg))
+    }
+}
+
+// Shares the quantized weight of a `QMatMul` with the graph of its output.
+struct QMatMulOp(std::sync::Arc<QTensor>);
+
+impl crate::CustomOp1 for QMatMulOp {
+    fn name(&self) -> &'static str {
+        self.0.name()
+    }
+
+    fn cpu_fwd(
+        &self,
+        storage: &crate::CpuStorage,
+        layout: &crate::Layout,
+    ) -> Result<(crate::CpuStorage, Shape)> {
+        self.0.cpu_fwd(storage, layout)
+    }
+
+    fn metal_fwd(
+        &self,
+        storage: &crate::MetalStorage,
+        layout: &crate::Layout,
+    ) -> Result<(crate::MetalStorage, Shape)> {
+        self.0.metal_fwd(storage, layout)
+    }
+
+    fn cuda_fwd(
+        &self,
+        storage: &crate::CudaStorage,
+        layout: &crate::Layout,
+    ) -> Result<(crate::CudaStorage, Shape)> {
+        self.0.cuda_fwd(storage, layout)
+    }
+
+    fn bwd(&self, arg: &Tensor, res: &Tensor, grad_res: &Tensor) -> Result<Option<Tensor>> {
+        self.0.bwd(arg, res, grad_res)
+    }
 }
 
 impl crate::Module for QMatMul {
     fn forward(&self, xs: &Tensor) -> Result<Tensor> {
         match self {
-            Self::QTensor(t) => xs.apply_op1_no_bwd(t.as_ref()),
+            // The backward pass only computes the gradient of the input, e.g. for the adapters of
+            // a frozen quantized model.
+            Self::QTensor(t) => xs.apply_op1(QMatMulOp(t.clone())),
             Self::Tensor(w) => {
                 let w = match *xs.dims() {
                     [b1, b2, _, _] => w.broadcast_left((b1, b2))?.t()?,
@@ -0,0 +1,252 @@
+//! Quantized weights backed by a shared memory object.
+//!
+//! When serving a model with multiple processes on the same host, loading the weights in each
+//! process results in one copy of the model per process. Instead the weight file can be copied
+//! once to a shared memory filesystem (`/dev/shm` on Linux) and then mapped read-only by every
+//! process: all the mappings point at the same physical pages and, on cpu, the quantized tensors
+//! are used directly from the mapping without any copy.
+use super::{k_quants, GgmlDType, QStorage, QTensor, QuantizedType};
+use crate::{CpuStorage, Device, Error, Result};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// The directory used by [`SharedMapping::open_or_create`].
+pub const DEFAULT_SHM_DIR: &str = "/dev/shm";
+
+/// A read-only memory mapping of a file living in a shared memory directory.
+#[derive(Clone)]
+pub struct SharedMapping {
+    mmap: Arc<memmap2::Mmap>,
+    path: PathBuf,
+}
+
+impl std::fmt::Debug for SharedMapping {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "SharedMapping[{:?}; {}]", self.path, self.mmap.len())
+    }
+}
+
+impl SharedMapping {
+    /// Maps the shared memory object `name` in [`DEFAULT_SHM_DIR`], creating it by copying `src`
+    /// if it does not exist yet.
+    ///
+    /// # Safety
+    ///
+    /// The unsafe is inherited from [`memmap2::MmapOptions`], the shared object must not be
+    /// modified while it is mapped.
+    pub unsafe fn open_or_create<P: AsRef<Path>>(name: &str, src: P) -> Result<Self> {
+        Self::open_or_create_in(DEFAULT_SHM_DIR, name, src)
+    }
+
+    /// Same as [`SharedMapping::open_or_create`] but using `dir` rather than the default shared
+    /// memory directory.
+    ///
+    /// The copy is made to a temporary file that is then linked to its final name, so concurrent
+    /// processes never observe a partially written object: the first process to complete the
+    /// copy wins and the others reuse its object.
+    ///
+    /// # Safety
+    ///
+    /// The unsafe is inherited from [`memmap2::MmapOptions`], the shared object must not be
+    /// modified while it is mapped.
+    pub unsafe fn open_or_create_in<D: AsRef<Path>, P: AsRef<Path>>(
+        dir: D,
+        name: &str,
+        src: P,
+    ) -> Result<Self> {
+        let src = src.as_ref();
+        let path = dir.as_ref().join(name);
+        if !path.exists() {
+            let tmp = dir
+                .as_ref()
+                .join(format!(".{name}.{}.tmp", std::process::id()));
+            std::fs::copy(src, &tmp).map_err(|e| Error::from(e).with_path(src))?;
+            let linked = std::fs::hard_link(&tmp, &path);
+            std::fs::remove_file(&tmp)?;
+            match linked {
+                Ok(()) => {}
+                Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => {}
+                Err(e) => Err(Error::from(e).with_path(&path))?,
+            }
+        }
+        let mapping = Self::open_in(dir, name)?;
+        let src_len = std::fs::metadata(src)
+            .map_err(|e| Error::from(e).with_path(src))?
+            .len();
+        if mapping.len() as u64 != src_len {
+            crate::bail!(
+                "shared object {:?} has {} bytes but {src:?} has {src_len}, remove the stale object",
+                mapping.path,
+                mapping.len()
+            )
+        }
+        Ok(mapping)
+    }
+
+    /// Maps an existing shared memory object from [`DEFAULT_SHM_DIR`].
+    ///
+    /// # Safety
+    ///
+    /// The unsafe is inherited from [`memmap2::MmapOptions`].
+    pub unsafe fn open(name: &str) -> Result<Self> {
+        Self::open_in(DEFAULT_SHM_DIR, name)
+    }
+
+    /// Maps an existing shared memory object from `dir`.
+    ///
+    /// # Safety
+    ///
+    /// The unsafe is inherited from [`memmap2::MmapOptions`].
+    pub unsafe fn open_in<D: AsRef<Path>>(dir: D, name: &str) -> Result<Self> {
+        let path = dir.as_ref().join(name);
+        let file = std::fs::File::open(&path).map_err(|e| Error::from(e).with_path(&path))?;
+        let mmap = memmap2::MmapOptions::new()
+            .map(&file)
+            .map_err(|e| Error::from(e).with_path(&path))?;
+        Ok(Self {
+            mmap: Arc::new(mmap),
+            path,
+        })
+    }
+
+    /// Removes the shared memory object. Existing mappings stay valid, the memory is released
+    /// once the last of them is dropped.
+    pub fn remove(&self) -> Result<()> {
+        std::fs::remove_file(&self.path).map_err(|e| Error::from(e).with_path(&self.path))
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.mmap
+    }
+
+    pub fn len(&self) -> usize {
+        self.mmap.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.mmap.is_empty()
+    }
+
+    /// Creates a quantized tensor from the bytes starting at `offset`. On cpu the tensor data
+    /// is read directly from the mapping, on other devices the data is uploaded.
+    pub fn qtensor(
+        &self,
+        offset: usize,
+        ggml_dtype: GgmlDType,
+        dims: Vec<usize>,
+        device: &Device,
+    ) -> Result<QTensor> {
+        let tensor_elems = dims.iter().product::<usize>();
+        let block_size = ggml_dtype.block_size();
+        if tensor_elems % block_size != 0 {
+            crate::bail!(
+                "the number of elements {tensor_elems} is not divisible by the block size {block_size}"
+            )
+        }
+        let size_in_bytes = tensor_elems / block_size * ggml_dtype.type_size();
+        if offset + size_in_bytes > self.len() {
+            crate::bail!(
+                "tensor data {offset}..{} is out of the shared object bounds {}",
+                offset + size_in_bytes,
+                self.len()
+            )
+        }
+        if !matches!(device, Device::Cpu) {
+            let raw_data = &self.as_bytes()[offset..offset + size_in_bytes];
+            return super::ggml_file::qtensor_from_ggml(ggml_dtype, raw_data, dims, device);
+        }
+        let storage: Box<dyn QuantizedType> = match ggml_dtype {
+            GgmlDType::F32 => self.blocks::<f32>(offset, size_in_bytes)?,
+            GgmlDType::F16 => self.blocks::<half::f16>(offset, size_in_bytes)?,
+            GgmlDType::Q4_0 => self.blocks::<k_quants::BlockQ4_0>(offset, size_in_bytes)?,
+            GgmlDType::Q4_1 => self.blocks::<k_quants::BlockQ4_1>(offset, size_in_bytes)?,
+            GgmlDType::Q5_0 => self.blocks::<k_quants::BlockQ5_0>(offset, size_in_bytes)?,
+            GgmlDType::Q5_1 => self.blocks::<k_quants::BlockQ5_1>(offset, size_in_bytes)?,
+            GgmlDType::Q8_0 => self.blocks::<k_quants::BlockQ8_0>(offset, size_in_bytes)?,
+            GgmlDType::Q2K => self.blocks::<k_quants::BlockQ2K>(offset, size_in_bytes)?,
+            GgmlDType::Q3K => self.blocks::<k_quants::BlockQ3K>(offset, size_in_bytes)?,
+            GgmlDType::Q4K => self.blocks::<k_quants::BlockQ4K>(offset, size_in_bytes)?,
+            GgmlDType::Q5K => self.blocks::<k_quants::BlockQ5K>(offset, size_in_bytes)?,
+            GgmlDType::Q6K => self.blocks::<k_quants::BlockQ6K>(offset, size_in_bytes)?,
+            _ => crate::bail!("quantized type {ggml_dtype:?} is not supported yet"),
+        };
+        QTensor::new(QStorage::Cpu(storage), dims)
+    }
+
+    fn blocks<T: k_quants::GgmlType + Send + Sync + 'static>(
+        &self,
+        offset: usize,
+        size_in_bytes: usize,
+    ) -> Result<Box<dyn QuantizedType>> {
+        let ptr = self.as_bytes()[offset..].as_ptr();
+        if ptr as usize % std::mem::align_of::<T>() != 0 {
+            crate::bail!(
+                "tensor data at offset {offset} is not aligned for {:?}",
+                T::DTYPE
+            )
+        }
+        Ok(Box::new(MmapedBlocks::<T> {
+            mmap: self.mmap.clone(),
+            offset,
+            len: size_in_bytes / std::mem::size_of::<T>(),
+            phantom: std::marker::PhantomData,
+        }))
+    }
+}
+
+/// Quantized blocks read in place from a shared mapping.
+struct MmapedBlocks<T> {
+    mmap: Arc<memmap2::Mmap>,
+    offset: usize,
+    len: usize,
+    phantom: std::marker::PhantomData<T>,
+}
+
+impl<T: k_quants::GgmlType> MmapedBlocks<T> {
+    fn as_slice(&self) -> &[T] {
+        // Safety: the bounds and alignment are checked in `SharedMapping::blocks` and the
+        // mapping is kept alive by the `Arc`.
+        let ptr = self.mmap[self.offset..].as_ptr() as *const T;
+        unsafe { std::slice::from_raw_parts(ptr, self.len) }
+    }
+}
+
+impl<T: k_quants::GgmlType + Send + Sync> QuantizedType for MmapedBlocks<T> {
+    fn matmul_t(&self, mkn: (usize, usize, usize), lhs: &[f32], dst: &mut [f32]) -> Result<()> {
+        k_quants::matmul(mkn, lhs, self.as_slice(), dst)
+    }
+
+    fn size(&self) -> usize {
+        self.len * core::mem::size_of::<T>()
+    }
+
+    fn from_float(&mut self, _xs: &[f32]) -> Result<()> {
+        crate::bail!("cannot quantize into read-only shared weights")
+    }
+
+    fn dtype(&self) -> GgmlDType {
+        T::DTYPE
+    }
+
+    fn block_size(&self) -> usize {
+        T::BLCK_SIZE
+    }
+
+    fn dequantize(&self, elem_count: usize) -> Result<CpuStorage> {
+        let mut ys = vec![0.0f32; elem_count];
+        T::to_float(self.as_slice(), &mut ys)?;
+        Ok(CpuStorage::F32(ys))
+    }
+
+    fn storage_size_in_bytes(&self) -> usize {
+        self.len * std::mem::size_of::<T>()
+    }
+
+    fn as_ptr(&self) -> *const u8 {
+        self.as_slice().as_ptr() as *const u8
+    }
+}
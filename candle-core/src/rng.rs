@@ -0,0 +1,211 @@
+//! The random number generator shared by all the devices.
+//!
+//! Random values are generated with the counter-based Philox4x32-10 generator. Each block of
+//! four values is produced from the seed and a counter, so the values do not depend on how the
+//! generation is split between threads and the cpu, cuda and metal backends produce the same
+//! stream for a given seed. The state of a device generator is its seed together with an
+//! offset, the number of counters consumed so far, and can be saved and restored with
+//! [`crate::Device::rng_state`] and [`crate::Device::set_rng_state`].
+//!
+//! Uniform values are bitwise identical across devices. Normal values use the Box-Muller
+//! transform on the same uniform values, they can differ by a few ulps as the accuracy of the
+//! `log`, `sqrt`, `sin`, and `cos` functions varies between devices.
+use crate::{DType, Error, Result, Shape};
+use half::{bf16, f16};
+use std::sync::Mutex;
+
+const M0: u32 = 0xD2511F53;
+const M1: u32 = 0xCD9E8D57;
+const W0: u32 = 0x9E3779B9;
+const W1: u32 = 0xBB67AE85;
+
+/// The default seed used by the cuda and metal generators.
+pub const DEFAULT_SEED: u64 = 299792458;
+
+/// The state of a random number generator.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct RngState {
+    pub seed: u64,
+    /// The number of blocks of four values generated since seeding.
+    pub offset: u64,
+}
+
+impl RngState {
+    pub fn new(seed: u64) -> Self {
+        Self { seed, offset: 0 }
+    }
+
+    /// Reserves the counters for `elem_count` values and returns the offset of the first one.
+    pub fn advance(&mut self, elem_count: usize) -> u64 {
+        let offset = self.offset;
+        self.offset += elem_count.div_ceil(4) as u64;
+        offset
+    }
+}
+
+/// The Philox4x32-10 block function.
+pub fn philox4x32_10(counter: [u32; 4], key: [u32; 2]) -> [u32; 4] {
+    let [mut c0, mut c1, mut c2, mut c3] = counter;
+    let [mut k0, mut k1] = key;
+    for round in 0..10 {
+        if round > 0 {
+            k0 = k0.wrapping_add(W0);
+            k1 = k1.wrapping_add(W1);
+        }
+        let p0 = M0 as u64 * c0 as u64;
+        let p1 = M1 as u64 * c2 as u64;
+        (c0, c1, c2, c3) = (
+            (p1 >> 32) as u32 ^ c1 ^ k0,
+            p1 as u32,
+            (p0 >> 32) as u32 ^ c3 ^ k1,
+            p0 as u32,
+        );
+    }
+    [c0, c1, c2, c3]
+}
+
+/// The block of four random values at `counter` for `seed`.
+pub fn philox_block(seed: u64, counter: u64) -> [u32; 4] {
+    let counter = [counter as u32, (counter >> 32) as u32, 0, 0];
+    philox4x32_10(counter, [seed as u32, (seed >> 32) as u32])
+}
+
+/// A uniform value in `[0, 1)` with 24 bits of randomness.
+pub fn uniform_f32(x: u32) -> f32 {
+    (x >> 8) as f32 * (1.0 / 16777216.0)
+}
+
+/// A uniform value in `(0, 1]` with 24 bits of randomness.
+fn uniform_f32_nonzero(x: u32) -> f32 {
+    ((x >> 8) + 1) as f32 * (1.0 / 16777216.0)
+}
+
+/// A uniform value in `[0, 1)` with 32 bits of randomness.
+pub fn uniform_f64(x: u32) -> f64 {
+    x as f64 * (1.0 / 4294967296.0)
+}
+
+fn uniform_f64_nonzero(x: u32) -> f64 {
+    (x as f64 + 1.0) * (1.0 / 4294967296.0)
+}
+
+fn generate<T>(
+    state: &mut RngState,
+    elem_count: usize,
+    f: impl Fn(&[u32; 4], usize) -> T,
+) -> Vec<T> {
+    let offset = state.advance(elem_count);
+    let mut data = Vec::with_capacity(elem_count);
+    for block_idx in 0..elem_count.div_ceil(4) {
+        let block = philox_block(state.seed, offset + block_idx as u64);
+        for j in 0..4.min(elem_count - 4 * block_idx) {
+            data.push(f(&block, j))
+        }
+    }
+    data
+}
+
+fn normal_f32(block: &[u32; 4], j: usize, mean: f32, std: f32) -> f32 {
+    let pair = j / 2 * 2;
+    let radius = (-2.0 * uniform_f32_nonzero(block[pair]).ln()).sqrt();
+    let theta = 2.0 * std::f32::consts::PI * uniform_f32(block[pair + 1]);
+    let z = if j % 2 == 0 {
+        radius * theta.cos()
+    } else {
+        radius * theta.sin()
+    };
+    z.mul_add(std, mean)
+}
+
+fn normal_f64(block: &[u32; 4], j: usize, mean: f64, std: f64) -> f64 {
+    let pair = j / 2 * 2;
+    let radius = (-2.0 * uniform_f64_nonzero(block[pair]).ln()).sqrt();
+    let theta = 2.0 * std::f64::consts::PI * uniform_f64(block[pair + 1]);
+    let z = if j % 2 == 0 {
+        radius * theta.cos()
+    } else {
+        radius * theta.sin()
+    };
+    z.mul_add(std, mean)
+}
+
+/// Generates uniform values in `[min, max)`, half precision values are rounded from `f32`.
+pub(crate) fn rand_uniform(
+    state: &mut RngState,
+    shape: &Shape,
+    dtype: DType,
+    min: f64,
+    max: f64,
+) -> Result<crate::CpuStorage> {
+    use crate::CpuStorage as S;
+    let elem_count = shape.elem_count();
+    let (min32, max32) = (min as f32, max as f32);
+    let f32 = |b: &[u32; 4], j: usize| uniform_f32(b[j]).mul_add(max32 - min32, min32);
+    let storage = match dtype {
+        DType::U8 | DType::U32 | DType::I64 => {
+            return Err(Error::UnsupportedDTypeForOp(dtype, "rand_uniform").bt())
+        }
+        DType::BF16 => S::BF16(generate(state, elem_count, |b, j| {
+            bf16::from_f32(f32(b, j))
+        })),
+        DType::F16 => S::F16(generate(state, elem_count, |b, j| f16::from_f32(f32(b, j)))),
+        DType::F32 => S::F32(generate(state, elem_count, f32)),
+        DType::F64 => S::F64(generate(state, elem_count, |b, j| {
+            uniform_f64(b[j]).mul_add(max - min, min)
+        })),
+    };
+    Ok(storage)
+}
+
+/// Generates normally distributed values, half precision values are rounded from `f32`.
+pub(crate) fn rand_normal(
+    state: &mut RngState,
+    shape: &Shape,
+    dtype: DType,
+    mean: f64,
+    std: f64,
+) -> Result<crate::CpuStorage> {
+    use crate::CpuStorage as S;
+    let elem_count = shape.elem_count();
+    let (mean32, std32) = (mean as f32, std as f32);
+    let f32 = |b: &[u32; 4], j: usize| normal_f32(b, j, mean32, std32);
+    let storage = match dtype {
+        DType::U8 | DType::U32 | DType::I64 => {
+            return Err(Error::UnsupportedDTypeForOp(dtype, "rand_normal").bt())
+        }
+        DType::BF16 => S::BF16(generate(state, elem_count, |b, j| {
+            bf16::from_f32(f32(b, j))
+        })),
+        DType::F16 => S::F16(generate(state, elem_count, |b, j| f16::from_f32(f32(b, j)))),
+        DType::F32 => S::F32(generate(state, elem_count, f32)),
+        DType::F64 => S::F64(generate(state, elem_count, |b, j| {
+            normal_f64(b, j, mean, std)
+        })),
+    };
+    Ok(storage)
+}
+
+struct CpuRng {
+    state: RngState,
+    seeded: bool,
+}
+
+// The cpu device has no state of its own so its generator is global. Until it gets seeded, it
+// starts from a random seed.
+static CPU_RNG: Mutex<Option<CpuRng>> = Mutex::new(None);
+
+pub(crate) fn with_cpu_rng<T>(f: impl FnOnce(&mut RngState, bool) -> T) -> T {
+    let mut rng = CPU_RNG.lock().unwrap();
+    let rng = rng.get_or_insert_with(|| CpuRng {
+        state: RngState::new(rand::random()),
+        seeded: false,
+    });
+    f(&mut rng.state, rng.seeded)
+}
+
+pub(crate) fn set_cpu_rng_state(state: RngState) {
+    *CPU_RNG.lock().unwrap() = Some(CpuRng {
+        state,
+        seeded: true,
+    })
+}
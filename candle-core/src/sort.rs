@@ -62,14 +62,14 @@ impl crate::CustomOp1 for ArgSort {
         storage: &crate::CpuStorage,
         layout: &crate::Layout,
     ) -> Result<(crate::CpuStorage, crate::Shape)> {
-        let sort_indexes = match storage {
-            crate::CpuStorage::U8(vs) => self.asort(vs, layout),
-            crate::CpuStorage::U32(vs) => self.asort(vs, layout),
-            crate::CpuStorage::I64(vs) => self.asort(vs, layout),
-            crate::CpuStorage::BF16(vs) => self.asort(vs, layout),
-            crate::CpuStorage::F16(vs) => self.asort(vs, layout),
-            crate::CpuStorage::F32(vs) => self.asort(vs, layout),
-            crate::CpuStorage::F64(vs) => self.asort(vs, layout),
+        let sort_indexes = match storage.as_storage_ref() {
+            crate::CpuStorageRef::U8(vs) => self.asort(vs, layout),
+            crate::CpuStorageRef::U32(vs) => self.asort(vs, layout),
+            crate::CpuStorageRef::I64(vs) => self.asort(vs, layout),
+            crate::CpuStorageRef::BF16(vs) => self.asort(vs, layout),
+            crate::CpuStorageRef::F16(vs) => self.asort(vs, layout),
+            crate::CpuStorageRef::F32(vs) => self.asort(vs, layout),
+            crate::CpuStorageRef::F64(vs) => self.asort(vs, layout),
         };
         let sort_indexes = crate::CpuStorage::U32(sort_indexes);
         Ok((sort_indexes, layout.shape().into()))
@@ -74,6 +74,8 @@ impl Storage {
     }
 
     pub(crate) fn affine(&self, layout: &Layout, mul: f64, add: f64) -> Result<Self> {
+        let _prof = crate::profiler::op("affine", self, &[layout]);
+        crate::anomaly::record_op("affine", &[layout]);
         match self {
             Storage::Cpu(storage) => {
                 let storage = storage.affine(layout, mul, add)?;
@@ -91,6 +93,8 @@ impl Storage {
     }
 
     pub(crate) fn powf(&self, layout: &Layout, alpha: f64) -> Result<Self> {
+        let _prof = crate::profiler::op("powf", self, &[layout]);
+        crate::anomaly::record_op("powf", &[layout]);
         match self {
             Storage::Cpu(storage) => {
                 let storage = storage.powf(layout, alpha)?;
@@ -108,6 +112,8 @@ impl Storage {
     }
 
     pub(crate) fn elu(&self, layout: &Layout, alpha: f64) -> Result<Self> {
+        let _prof = crate::profiler::op("elu", self, &[layout]);
+        crate::anomaly::record_op("elu", &[layout]);
         match self {
             Storage::Cpu(storage) => {
                 let storage = storage.elu(layout, alpha)?;
@@ -131,8 +137,10 @@ impl Storage {
         lhs_layout: &Layout,
         rhs_layout: &Layout,
     ) -> Result<Self> {
+        let _prof = crate::profiler::op("cmp", self, &[lhs_layout, rhs_layout]);
         self.same_device(rhs, "cmp")?;
         self.same_dtype(rhs, "cmp")?;
+        crate::anomaly::record_op("cmp", &[lhs_layout, rhs_layout]);
         match (self, rhs) {
             (Storage::Cpu(lhs), Storage::Cpu(rhs)) => {
                 let storage = lhs.cmp(op, rhs, lhs_layout, rhs_layout)?;
@@ -160,6 +168,8 @@ impl Storage {
     }
 
     pub(crate) fn reduce_op(&self, op: ReduceOp, layout: &Layout, s: &[usize]) -> Result<Self> {
+        let _prof = crate::profiler::op(op.name(), self, &[layout]);
+        crate::anomaly::record_op(op.name(), &[layout]);
         match self {
             Storage::Cpu(storage) => {
                 let storage = storage.reduce_op(op, layout, s)?;
@@ -177,6 +187,8 @@ impl Storage {
     }
 
     pub(crate) fn to_dtype(&self, layout: &Layout, dtype: DType) -> Result<Self> {
+        let _prof = crate::profiler::op("to-dtype", self, &[layout]);
+        crate::anomaly::record_op("to-dtype", &[layout]);
         match self {
             Storage::Cpu(storage) => {
                 let storage = storage.to_dtype(layout, dtype)?;
@@ -194,9 +206,11 @@ impl Storage {
     }
 
     pub(crate) fn apply_op1(&self, l: &Layout, c: &dyn CustomOp1) -> Result<(Self, Shape)> {
+        let _prof = crate::profiler::op(c.name(), self, &[l]);
+        crate::anomaly::record_op(c.name(), &[l]);
         match self {
             Self::Cpu(storage) => {
-                let (storage, shape) = c.cpu_fwd(storage, l)?;
+                let (storage, shape) = c.cpu_fwd(&storage.owned_data(), l)?;
                 Ok((Self::Cpu(storage), shape))
             }
             Self::Cuda(storage) => {
@@ -217,10 +231,12 @@ impl Storage {
         l2: &Layout,
         c: &dyn CustomOp2,
     ) -> Result<(Self, Shape)> {
+        let _prof = crate::profiler::op(c.name(), self, &[l1, l2]);
         self.same_device(t2, c.name())?;
+        crate::anomaly::record_op(c.name(), &[l1, l2]);
         match (self, t2) {
             (Self::Cpu(s1), Self::Cpu(s2)) => {
-                let (s, shape) = c.cpu_fwd(s1, l1, s2, l2)?;
+                let (s, shape) = c.cpu_fwd(&s1.owned_data(), l1, &s2.owned_data(), l2)?;
                 Ok((Self::Cpu(s), shape))
             }
             (Self::Cuda(s1), Self::Cuda(s2)) => {
@@ -244,11 +260,14 @@ impl Storage {
         l3: &Layout,
         c: &dyn CustomOp3,
     ) -> Result<(Self, Shape)> {
+        let _prof = crate::profiler::op(c.name(), self, &[l1, l2, l3]);
         self.same_device(t2, c.name())?;
         self.same_device(t3, c.name())?;
+        crate::anomaly::record_op(c.name(), &[l1, l2, l3]);
         match (self, t2, t3) {
             (Self::Cpu(s1), Self::Cpu(s2), Self::Cpu(s3)) => {
-                let (s, shape) = c.cpu_fwd(s1, l1, s2, l2, s3, l3)?;
+                let (s1, s2, s3) = (s1.owned_data(), s2.owned_data(), s3.owned_data());
+                let (s, shape) = c.cpu_fwd(&s1, l1, &s2, l2, &s3, l3)?;
                 Ok((Self::Cpu(s), shape))
             }
             (Self::Cuda(s1), Self::Cuda(s2), Self::Cuda(s3)) => {
@@ -264,11 +283,18 @@ impl Storage {
     }
 
     pub(crate) fn inplace_op1(&mut self, l: &Layout, c: &dyn InplaceOp1) -> Result<()> {
-        match self {
-            Self::Cpu(storage) => c.cpu_fwd(storage, l),
+        let _prof = crate::profiler::op(c.name(), self, &[l]);
+        crate::anomaly::record_op(c.name(), &[l]);
+        let res = match self {
+            Self::Cpu(storage) => {
+                storage.make_owned();
+                c.cpu_fwd(storage, l)
+            }
             Self::Cuda(storage) => c.cuda_fwd(storage, l),
             Self::Metal(storage) => c.metal_fwd(storage, l),
-        }
+        };
+        crate::anomaly::clear_op();
+        res
     }
 
     pub(crate) fn inplace_op2(
@@ -278,13 +304,20 @@ impl Storage {
         l2: &Layout,
         c: &dyn InplaceOp2,
     ) -> Result<()> {
+        let _prof = crate::profiler::op(c.name(), self, &[l1, l2]);
         self.same_device(t2, c.name())?;
-        match (self, t2) {
-            (Self::Cpu(s1), Self::Cpu(s2)) => c.cpu_fwd(s1, l1, s2, l2),
+        crate::anomaly::record_op(c.name(), &[l1, l2]);
+        let res = match (self, t2) {
+            (Self::Cpu(s1), Self::Cpu(s2)) => {
+                s1.make_owned();
+                c.cpu_fwd(s1, l1, &s2.owned_data(), l2)
+            }
             (Self::Cuda(s1), Self::Cuda(s2)) => c.cuda_fwd(s1, l1, s2, l2),
             (Self::Metal(s1), Self::Metal(s2)) => c.metal_fwd(s1, l1, s2, l2),
             _ => unreachable!(),
-        }
+        };
+        crate::anomaly::clear_op();
+        res
     }
 
     pub(crate) fn inplace_op3(
@@ -296,19 +329,28 @@ impl Storage {
         l3: &Layout,
         c: &dyn InplaceOp3,
     ) -> Result<()> {
+        let _prof = crate::profiler::op(c.name(), self, &[l1, l2, l3]);
         self.same_device(t2, c.name())?;
         self.same_device(t3, c.name())?;
-        match (self, t2, t3) {
-            (Self::Cpu(s1), Self::Cpu(s2), Self::Cpu(s3)) => c.cpu_fwd(s1, l1, s2, l2, s3, l3),
+        crate::anomaly::record_op(c.name(), &[l1, l2, l3]);
+        let res = match (self, t2, t3) {
+            (Self::Cpu(s1), Self::Cpu(s2), Self::Cpu(s3)) => {
+                s1.make_owned();
+                c.cpu_fwd(s1, l1, &s2.owned_data(), l2, &s3.owned_data(), l3)
+            }
             (Self::Cuda(s1), Self::Cuda(s2), Self::Cuda(s3)) => c.cuda_fwd(s1, l1, s2, l2, s3, l3),
             (Self::Metal(s1), Self::Metal(s2), Self::Metal(s3)) => {
                 c.metal_fwd(s1, l1, s2, l2, s3, l3)
             }
             _ => unreachable!(),
-        }
+        };
+        crate::anomaly::clear_op();
+        res
     }
 
     pub(crate) fn unary_impl<B: op::UnaryOpT>(&self, layout: &Layout) -> Result<Self> {
+        let _prof = crate::profiler::op(B::NAME, self, &[layout]);
+        crate::anomaly::record_op(B::NAME, &[layout]);
         match self {
             Storage::Cpu(storage) => {
                 let storage = storage.unary_impl::<B>(layout)?;
@@ -331,8 +373,10 @@ impl Storage {
         lhs_layout: &Layout,
         rhs_layout: &Layout,
     ) -> Result<Self> {
+        let _prof = crate::profiler::op(B::NAME, self, &[lhs_layout, rhs_layout]);
         self.same_device(rhs, B::NAME)?;
         self.same_dtype(rhs, B::NAME)?;
+        crate::anomaly::record_op(B::NAME, &[lhs_layout, rhs_layout]);
         match (self, rhs) {
             (Storage::Cpu(lhs), Storage::Cpu(rhs)) => {
                 let storage = lhs.binary_impl::<B>(rhs, lhs_layout, rhs_layout)?;
@@ -366,8 +410,10 @@ impl Storage {
         kernel_l: &Layout,
         params: &crate::conv::ParamsConv1D,
     ) -> Result<Self> {
+        let _prof = crate::profiler::op("conv1d", self, &[l, kernel_l]);
         self.same_device(kernel, "conv1d")?;
         self.same_dtype(kernel, "conv1d")?;
+        crate::anomaly::record_op("conv1d", &[l, kernel_l]);
         match (self, &kernel) {
             (Storage::Cpu(inp), Storage::Cpu(kernel)) => {
                 let s = inp.conv1d(l, kernel, kernel_l, params)?;
@@ -397,8 +443,10 @@ impl Storage {
         kernel_l: &Layout,
         params: &crate::conv::ParamsConvTranspose1D,
     ) -> Result<Self> {
+        let _prof = crate::profiler::op("conv-transpose1d", self, &[l, kernel_l]);
         self.same_device(kernel, "conv-transpose1d")?;
         self.same_dtype(kernel, "conv-transpose1d")?;
+        crate::anomaly::record_op("conv-transpose1d", &[l, kernel_l]);
         match (self, &kernel) {
             (Storage::Cpu(inp), Storage::Cpu(kernel)) => {
                 let s = inp.conv_transpose1d(l, kernel, kernel_l, params)?;
@@ -428,8 +476,10 @@ impl Storage {
         kernel_l: &Layout,
         params: &crate::conv::ParamsConv2D,
     ) -> Result<Self> {
+        let _prof = crate::profiler::op("conv2d", self, &[l, kernel_l]);
         self.same_device(kernel, "conv2d")?;
         self.same_dtype(kernel, "conv2d")?;
+        crate::anomaly::record_op("conv2d", &[l, kernel_l]);
         match (self, &kernel) {
             (Storage::Cpu(inp), Storage::Cpu(kernel)) => {
                 let s = inp.conv2d(l, kernel, kernel_l, params)?;
@@ -459,8 +509,10 @@ impl Storage {
         kernel_l: &Layout,
         params: &crate::conv::ParamsConvTranspose2D,
     ) -> Result<Self> {
+        let _prof = crate::profiler::op("conv-transpose2d", self, &[l, kernel_l]);
         self.same_device(kernel, "conv_transpose2d")?;
         self.same_dtype(kernel, "conv_transpose2d")?;
+        crate::anomaly::record_op("conv-transpose2d", &[l, kernel_l]);
         match (self, &kernel) {
             (Storage::Cpu(inp), Storage::Cpu(kernel)) => {
                 let s = inp.conv_transpose2d(l, kernel, kernel_l, params)?;
@@ -489,6 +541,8 @@ impl Storage {
         kernel_size: (usize, usize),
         stride: (usize, usize),
     ) -> Result<Self> {
+        let _prof = crate::profiler::op("avg-pool2d", self, &[layout]);
+        crate::anomaly::record_op("avg-pool2d", &[layout]);
         match self {
             Storage::Cpu(storage) => {
                 let storage = storage.avg_pool2d(layout, kernel_size, stride)?;
@@ -511,6 +565,8 @@ impl Storage {
         kernel_size: (usize, usize),
         stride: (usize, usize),
     ) -> Result<Self> {
+        let _prof = crate::profiler::op("max-pool2d", self, &[layout]);
+        crate::anomaly::record_op("max-pool2d", &[layout]);
         match self {
             Storage::Cpu(storage) => {
                 let storage = storage.max_pool2d(layout, kernel_size, stride)?;
@@ -528,6 +584,8 @@ impl Storage {
     }
 
     pub(crate) fn upsample_nearest1d(&self, layout: &Layout, sz: usize) -> Result<Self> {
+        let _prof = crate::profiler::op("upsample-nearest1d", self, &[layout]);
+        crate::anomaly::record_op("upsample-nearest1d", &[layout]);
         match self {
             Storage::Cpu(storage) => {
                 let storage = storage.upsample_nearest1d(layout, sz)?;
@@ -545,6 +603,8 @@ impl Storage {
     }
 
     pub(crate) fn upsample_nearest2d(&self, layout: &Layout, h: usize, w: usize) -> Result<Self> {
+        let _prof = crate::profiler::op("upsample-nearest2d", self, &[layout]);
+        crate::anomaly::record_op("upsample-nearest2d", &[layout]);
         match self {
             Storage::Cpu(storage) => {
                 let storage = storage.upsample_nearest2d(layout, h, w)?;
@@ -569,9 +629,11 @@ impl Storage {
         f: &Self,
         layout_f: &Layout,
     ) -> Result<Self> {
+        let _prof = crate::profiler::op("where", self, &[layout, layout_t, layout_f]);
         self.same_device(t, "where")?;
         self.same_device(f, "where")?;
         t.same_dtype(f, "where")?;
+        crate::anomaly::record_op("where", &[layout, layout_t, layout_f]);
         match (self, t, f) {
             (Storage::Cpu(cond), Storage::Cpu(t), Storage::Cpu(f)) => {
                 let storage = cond.where_cond(layout, t, layout_t, f, layout_f)?;
@@ -601,7 +663,9 @@ impl Storage {
         indexes_l: &Layout,
         d: usize,
     ) -> Result<Self> {
+        let _prof = crate::profiler::op("gather", self, &[l, indexes_l]);
         self.same_device(indexes, "index-add")?;
+        crate::anomaly::record_op("gather", &[l, indexes_l]);
         match (self, indexes) {
             (Self::Cpu(s), Self::Cpu(indexes)) => {
                 let storage = s.gather(l, indexes, indexes_l, d)?;
@@ -628,8 +692,10 @@ impl Storage {
         source_l: &Layout,
         d: usize,
     ) -> Result<Self> {
+        let _prof = crate::profiler::op("scatter-add", self, &[l, indexes_l, source_l]);
         self.same_device(indexes, "scatter-add")?;
         self.same_device(source, "scatter-add")?;
+        crate::anomaly::record_op("scatter-add", &[l, indexes_l, source_l]);
         match (self, indexes, source) {
             (Self::Cpu(s), Self::Cpu(indexes), Self::Cpu(source)) => {
                 let storage = s.scatter_add(l, indexes, indexes_l, source, source_l, d)?;
@@ -656,8 +722,10 @@ impl Storage {
         source_l: &Layout,
         d: usize,
     ) -> Result<Self> {
+        let _prof = crate::profiler::op("index-add", self, &[l, indexes_l, source_l]);
         self.same_device(indexes, "index-add")?;
         self.same_device(source, "index-add")?;
+        crate::anomaly::record_op("index-add", &[l, indexes_l, source_l]);
         match (self, indexes, source) {
             (Self::Cpu(s), Self::Cpu(indexes), Self::Cpu(source)) => {
                 let storage = s.index_add(l, indexes, indexes_l, source, source_l, d)?;
@@ -682,7 +750,9 @@ impl Storage {
         rhs_l: &Layout,
         d: usize,
     ) -> Result<Self> {
+        let _prof = crate::profiler::op("index-select", self, &[lhs_l, rhs_l]);
         self.same_device(rhs, "index-select")?;
+        crate::anomaly::record_op("index-select", &[lhs_l, rhs_l]);
         match (self, rhs) {
             (Self::Cpu(lhs), Self::Cpu(rhs)) => {
                 let storage = lhs.index_select(rhs, lhs_l, rhs_l, d)?;
@@ -712,8 +782,10 @@ impl Storage {
         lhs_layout: &Layout,
         rhs_layout: &Layout,
     ) -> Result<Self> {
+        let _prof = crate::profiler::op("matmul", self, &[lhs_layout, rhs_layout]);
         self.same_device(rhs, "matmul")?;
         self.same_dtype(rhs, "matmul")?;
+        crate::anomaly::record_op("matmul", &[lhs_layout, rhs_layout]);
         match (self, rhs) {
             (Self::Cpu(lhs), Self::Cpu(rhs)) => {
                 let storage = lhs.matmul(rhs, bmnk, lhs_layout, rhs_layout)?;
@@ -743,6 +815,8 @@ impl Storage {
         dst_offset: usize,
         src_l: &Layout,
     ) -> Result<()> {
+        let _prof = crate::profiler::op("copy", self, &[src_l]);
+        crate::anomaly::record_op("copy", &[src_l]);
         match (self, dst) {
             (Self::Cpu(src), Self::Cpu(dst)) => src.copy_strided_src(dst, dst_offset, src_l),
             (Self::Cuda(src), Self::Cuda(dst)) => Ok(src.copy_strided_src(dst, dst_offset, src_l)?),
@@ -769,6 +843,8 @@ impl Storage {
         src_o: usize,
         dst_o: usize,
     ) -> Result<()> {
+        let _prof = crate::profiler::op("copy2d", self, &[]);
+        crate::anomaly::record_op("copy2d", &[]);
         match (self, dst) {
             (Self::Cpu(src), Self::Cpu(dst)) => src.copy2d(dst, d1, d2, src_s, dst_s, src_o, dst_o),
             (Self::Cuda(src), Self::Cuda(dst)) => {
@@ -4,11 +4,12 @@ use crate::backend::{BackendDevice, BackendStorage};
 use crate::op::{BackpropOp, BinaryOp, CmpOp, Op, ReduceOp, UnaryOp};
 use crate::scalar::TensorOrScalar;
 use crate::shape::{Dim, Dims};
+use crate::tensor_names::{self, DimNames};
 use crate::{bail, storage::Storage, DType, Device, Error, Layout, Result, Shape};
 use std::sync::{Arc, RwLock};
 
 /// Unique identifier for tensors.
-#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct TensorId(usize);
 
 impl TensorId {
@@ -22,6 +23,9 @@ impl TensorId {
 
 pub struct Tensor_ {
     id: TensorId,
+    // The guard keeping the storage page-locked, it is shared by all the tensors using the same
+    // storage and declared before it so that the memory gets unregistered before being freed.
+    pinned: Option<PinGuard>,
     // As we provide inner mutability on the tensor content, the alternatives are:
     // - Using a mutex, this would have the highest cost when retrieving the storage but would
     //   prevent errors when concurrent access takes place. Mutex would also be subject to
@@ -40,8 +44,13 @@ pub struct Tensor_ {
     is_variable: bool,
     dtype: DType,
     device: Device,
+    // Optional names for each dimension, these are only used for checking and name based
+    // indexing and never impact the actual computations.
+    dim_names: DimNames,
 }
 
+type PinGuard = Arc<dyn std::any::Any + Send + Sync>;
+
 impl AsRef<Tensor> for Tensor {
     fn as_ref(&self) -> &Tensor {
         self
@@ -86,7 +95,9 @@ macro_rules! unary_op {
                 .storage()
                 .unary_impl::<crate::op::$op_name>(self.layout())?;
             let op = BackpropOp::new1(self, |s| Op::Unary(s, UnaryOp::$op_name));
-            Ok(from_storage(storage, shape.clone(), op, false))
+            crate::anomaly::check(
+                from_storage(storage, shape.clone(), op, false).with_names(self.dim_names.clone()),
+            )
         }
     };
 }
@@ -95,6 +106,8 @@ macro_rules! binary_op {
     ($fn_name:ident, $op_name:ident) => {
         pub fn $fn_name(&self, rhs: &Self) -> Result<Self> {
             let shape = self.same_shape_binary_op(rhs, stringify!($fn_name))?;
+            let dim_names =
+                tensor_names::merge(&self.dim_names, &rhs.dim_names, stringify!($fn_name))?;
             if shape.elem_count() == 0 {
                 return Ok(self.clone());
             }
@@ -104,7 +117,9 @@ macro_rules! binary_op {
                 rhs.layout(),
             )?;
             let op = BackpropOp::new2(self, rhs, |t1, t2| Op::Binary(t1, t2, BinaryOp::$op_name));
-            Ok(from_storage(storage, shape.clone(), op, false))
+            crate::anomaly::check(
+                from_storage(storage, shape.clone(), op, false).with_names(dim_names),
+            )
         }
     };
 }
@@ -120,6 +135,8 @@ macro_rules! binary_op_scalar {
                     .broadcast_as(self.shape())?,
             };
             let shape = self.same_shape_binary_op(&rhs, stringify!($fn_name))?;
+            let dim_names =
+                tensor_names::merge(&self.dim_names, &rhs.dim_names, stringify!($fn_name))?;
             if self.elem_count() == 0 {
                 return Ok(self.clone());
             }
@@ -129,7 +146,9 @@ macro_rules! binary_op_scalar {
                 rhs.layout(),
             )?;
             let op = BackpropOp::new2(self, &rhs, |t1, t2| Op::Binary(t1, t2, BinaryOp::$op_name));
-            Ok(from_storage(storage, shape.clone(), op, false))
+            crate::anomaly::check(
+                from_storage(storage, shape.clone(), op, false).with_names(dim_names),
+            )
         }
     };
 }
@@ -161,17 +180,28 @@ pub(crate) fn from_storage<S: Into<Shape>>(
     shape: S,
     op: BackpropOp,
     is_variable: bool,
+) -> Tensor {
+    from_storage_and_layout(storage, Layout::contiguous(shape), op, is_variable)
+}
+
+pub(crate) fn from_storage_and_layout(
+    storage: Storage,
+    layout: Layout,
+    op: BackpropOp,
+    is_variable: bool,
 ) -> Tensor {
     let dtype = storage.dtype();
     let device = storage.device();
     let tensor_ = Tensor_ {
         id: TensorId::new(),
+        pinned: None,
         storage: Arc::new(RwLock::new(storage)),
-        layout: Layout::contiguous(shape),
+        layout,
         op,
         is_variable,
         dtype,
         device,
+        dim_names: None,
     };
     Tensor(Arc::new(tensor_))
 }
@@ -719,7 +749,9 @@ impl Tensor {
         }
         let storage = self.storage().affine(self.layout(), mul, add)?;
         let op = BackpropOp::new1(self, |arg| Op::Affine { arg, mul, add });
-        Ok(from_storage(storage, self.shape(), op, false))
+        crate::anomaly::check(
+            from_storage(storage, self.shape(), op, false).with_names(self.dim_names.clone()),
+        )
     }
 
     /// Applies the Exponential Linear Unit (ELU) function on each element of the input tensor.
@@ -729,7 +761,9 @@ impl Tensor {
         }
         let storage = self.storage().elu(self.layout(), alpha)?;
         let op = BackpropOp::new1(self, |t| Op::Elu(t, alpha));
-        Ok(from_storage(storage, self.shape(), op, false))
+        crate::anomaly::check(
+            from_storage(storage, self.shape(), op, false).with_names(self.dim_names.clone()),
+        )
     }
 
     /// Raise the tensor to some float exponent `e`.
@@ -739,7 +773,9 @@ impl Tensor {
         }
         let storage = self.storage().powf(self.layout(), e)?;
         let op = BackpropOp::new1(self, |t| Op::Powf(t, e));
-        Ok(from_storage(storage, self.shape(), op, false))
+        crate::anomaly::check(
+            from_storage(storage, self.shape(), op, false).with_names(self.dim_names.clone()),
+        )
     }
 
     pub(crate) fn check_dim(&self, dim: usize, op: &'static str) -> Result<()> {
@@ -835,12 +871,14 @@ impl Tensor {
             let layout = self.layout().narrow(dim, start, len)?;
             let tensor_ = Tensor_ {
                 id: TensorId::new(),
+                pinned: self.pinned.clone(),
                 storage: self.storage.clone(),
                 layout,
                 op,
                 is_variable: false,
                 dtype: self.dtype,
                 device: self.device.clone(),
+                dim_names: self.dim_names.clone(),
             };
             Ok(Tensor(Arc::new(tensor_)))
         }
@@ -850,20 +888,21 @@ impl Tensor {
         match dims {
             [] => Ok(self),
             [i] => self.squeeze(*i),
-            dims => {
+            dims_to_remove => {
                 let dims = self
                     .dims()
                     .iter()
                     .enumerate()
                     .filter_map(|(dim_idx, &v)| {
-                        if dims.contains(&dim_idx) {
+                        if dims_to_remove.contains(&dim_idx) {
                             None
                         } else {
                             Some(v)
                         }
                     })
                     .collect::<Vec<_>>();
-                self.reshape(dims)
+                let dim_names = tensor_names::remove(&self.dim_names, dims_to_remove);
+                Ok(self.reshape(dims)?.with_names(dim_names))
             }
         }
     }
@@ -879,7 +918,8 @@ impl Tensor {
             }
             ReduceOp::ArgMin | ReduceOp::ArgMax => BackpropOp::none(),
         };
-        let res = from_storage(storage, dims, op, false);
+        let res = from_storage(storage, dims, op, false).with_names(self.dim_names.clone());
+        let res = crate::anomaly::check(res)?;
         if keepdim {
             Ok(res)
         } else {
@@ -897,7 +937,8 @@ impl Tensor {
             dims[sum_dim] = 1
         }
         let op = BackpropOp::new1(self, |a| Op::Reduce(a, ReduceOp::Sum, dims.to_vec()));
-        let sum = from_storage(storage, dims, op, false);
+        let sum = from_storage(storage, dims, op, false).with_names(self.dim_names.clone());
+        let sum = crate::anomaly::check(sum)?;
         if keepdim {
             Ok(sum)
         } else {
@@ -1067,7 +1108,9 @@ impl Tensor {
             .storage()
             .cmp(op, &rhs.storage(), self.layout(), rhs.layout())?;
         let op = BackpropOp::new1(self, |a| Op::Cmp(a, op));
-        Ok(from_storage(storage, shape.dims(), op, false))
+        crate::anomaly::check(
+            from_storage(storage, shape.dims(), op, false).with_names(self.dim_names.clone()),
+        )
     }
 
     /// Element-wise equality.
@@ -1119,7 +1162,7 @@ impl Tensor {
         let storage = self
             .storage()
             .upsample_nearest1d(self.layout(), target_size)?;
-        Ok(from_storage(storage, (n, c, target_size), op, false))
+        crate::anomaly::check(from_storage(storage, (n, c, target_size), op, false))
     }
 
     /// Alias for `interpolate1d`.
@@ -1142,7 +1185,7 @@ impl Tensor {
         let storage = self
             .storage()
             .upsample_nearest2d(self.layout(), target_h, target_w)?;
-        Ok(from_storage(storage, (n, c, target_h, target_w), op, false))
+        crate::anomaly::check(from_storage(storage, (n, c, target_h, target_w), op, false))
     }
 
     /// Alias for `interpolate2d`.
@@ -1185,7 +1228,7 @@ impl Tensor {
         let storage = self
             .storage()
             .avg_pool2d(self.layout(), kernel_size, stride)?;
-        Ok(from_storage(storage, (n, c, h_out, w_out), op, false))
+        crate::anomaly::check(from_storage(storage, (n, c, h_out, w_out), op, false))
     }
 
     /// 2D max pooling over an input tensor with multiple channels.
@@ -1223,7 +1266,7 @@ impl Tensor {
         let storage = self
             .storage()
             .max_pool2d(self.layout(), kernel_size, stride)?;
-        Ok(from_storage(storage, (n, c, h_out, w_out), op, false))
+        crate::anomaly::check(from_storage(storage, (n, c, h_out, w_out), op, false))
     }
 
     /// Returns the matrix-multiplication of the input tensor with the other provided tensor.
@@ -1255,8 +1298,9 @@ impl Tensor {
         let n = b_dims[dim - 1];
 
         let c_shape = Shape::from(&a_dims[..dim - 2]).extend(&[m, n]);
+        let dim_names = tensor_names::matmul(&self.dim_names, &rhs.dim_names)?;
         if c_shape.elem_count() == 0 || k == 0 {
-            return Tensor::zeros(c_shape, self.dtype(), self.device());
+            return Ok(Tensor::zeros(c_shape, self.dtype(), self.device())?.with_names(dim_names));
         }
         let batching: usize = a_dims[..dim - 2].iter().product();
         let batching_b: usize = b_dims[..dim - 2].iter().product();
@@ -1276,7 +1320,7 @@ impl Tensor {
             rhs.layout(),
         )?;
         let op = BackpropOp::new2(self, rhs, Op::Matmul);
-        Ok(from_storage(storage, c_shape, op, false))
+        crate::anomaly::check(from_storage(storage, c_shape, op, false).with_names(dim_names))
     }
 
     /// Matrix-multiplication with broadcasting support.
@@ -1315,7 +1359,9 @@ impl Tensor {
             on_false.layout(),
         )?;
         let op = BackpropOp::new3(self, on_true, on_false, Op::WhereCond);
-        Ok(from_storage(storage, shape, op, false))
+        crate::anomaly::check(
+            from_storage(storage, shape, op, false).with_names(self.dim_names.clone()),
+        )
     }
 
     /// Returns a tensor with the values from the `self` tensor at the index corresponding to the
@@ -1392,7 +1438,7 @@ impl Tensor {
         let op = BackpropOp::new3(self, indexes, source, |t1, t2, t3| {
             Op::ScatterAdd(t1, t2, t3, dim)
         });
-        Ok(from_storage(storage, self.shape(), op, false))
+        crate::anomaly::check(from_storage(storage, self.shape(), op, false))
     }
 
     /// Embeds the values of the `src` tensor into the `self` tensor on the specified dimension.
@@ -1461,7 +1507,7 @@ impl Tensor {
         src.storage()
             .copy_strided_src(&mut storage, offset, src.layout())?;
         let op = BackpropOp::new2(self, src, |t1, t2| Op::SliceScatter0(t1, t2, start));
-        Ok(from_storage(storage, self.shape(), op, false))
+        crate::anomaly::check(from_storage(storage, self.shape(), op, false))
     }
 
     /// Accumulate element from `source` at indexes `indexes` and add them to `self`.
@@ -1512,7 +1558,7 @@ impl Tensor {
         let op = BackpropOp::new3(self, indexes, source, |t1, t2, t3| {
             Op::IndexAdd(t1, t2, t3, dim)
         });
-        Ok(from_storage(storage, self.shape(), op, false))
+        crate::anomaly::check(from_storage(storage, self.shape(), op, false))
     }
 
     /// Gather values across the target dimension.
@@ -1555,7 +1601,7 @@ impl Tensor {
             self.storage()
                 .gather(self.layout(), &indexes.storage(), indexes.layout(), dim)?;
         let op = BackpropOp::new2(self, indexes, |t1, t2| Op::Gather(t1, t2, dim));
-        Ok(from_storage(storage, indexes.shape(), op, false))
+        crate::anomaly::check(from_storage(storage, indexes.shape(), op, false))
     }
 
     /// Select values for the input tensor at the target indexes across the specified dimension.
@@ -1585,7 +1631,7 @@ impl Tensor {
         let mut dims = self.dims().to_vec();
         dims[dim] = indexes_len;
         let op = BackpropOp::new2(self, indexes, |t1, t2| Op::IndexSelect(t1, t2, dim));
-        Ok(from_storage(storage, dims, op, false))
+        crate::anomaly::check(from_storage(storage, dims, op, false))
     }
 
     /// Returns an iterator over position of the elements in the storage when ranging over the
@@ -1760,6 +1806,38 @@ impl Tensor {
         &self.op
     }
 
+    /// Where the op that created this tensor was called, this is only recorded when anomaly
+    /// detection is enabled for the backward pass, see [`crate::anomaly::set_detect_anomaly`].
+    pub(crate) fn created_at(&self) -> Option<&Arc<std::backtrace::Backtrace>> {
+        self.op.created_at()
+    }
+
+    /// The names attached to each dimension of this tensor if any, see
+    /// [`Tensor::with_dim_names`]. Unnamed dimensions are returned as `None`.
+    pub fn dim_names(&self) -> Option<&[Option<String>]> {
+        self.dim_names.as_deref()
+    }
+
+    /// Returns the same tensor with the specified dimension names, the storage and the
+    /// computation graph node are shared with `self`.
+    pub(crate) fn with_names(self, dim_names: DimNames) -> Self {
+        if dim_names.is_none() && self.dim_names.is_none() {
+            return self;
+        }
+        let tensor_ = Tensor_ {
+            id: self.id,
+            pinned: self.pinned.clone(),
+            storage: self.storage.clone(),
+            layout: self.layout.clone(),
+            op: self.op.clone(),
+            is_variable: self.is_variable,
+            dtype: self.dtype,
+            device: self.device.clone(),
+            dim_names,
+        };
+        Tensor(Arc::new(tensor_))
+    }
+
     /// Computes the max of all the elements in this tensor and returns a tensor holding this
     /// scalar with zero dimensions.
     ///
@@ -1947,12 +2025,14 @@ impl Tensor {
         let op = BackpropOp::new1(self, |t| Op::Transpose(t, dim1, dim2));
         let tensor_ = Tensor_ {
             id: TensorId::new(),
+            pinned: self.pinned.clone(),
             storage: self.storage.clone(),
             layout: self.layout.transpose(dim1, dim2)?,
             op,
             is_variable: false,
             dtype: self.dtype,
             device: self.device.clone(),
+            dim_names: tensor_names::transpose(&self.dim_names, dim1, dim2),
         };
         Ok(Tensor(Arc::new(tensor_)))
     }
@@ -1983,12 +2063,14 @@ impl Tensor {
         let op = BackpropOp::new1(self, |t| Op::Permute(t, dims.clone()));
         let tensor_ = Tensor_ {
             id: TensorId::new(),
+            pinned: self.pinned.clone(),
             storage: self.storage.clone(),
             layout: self.layout.permute(&dims)?,
             op,
             is_variable: false,
             dtype: self.dtype,
             device: self.device.clone(),
+            dim_names: tensor_names::permute(&self.dim_names, &dims),
         };
         Ok(Tensor(Arc::new(tensor_)))
     }
@@ -2009,16 +2091,59 @@ impl Tensor {
         let op = BackpropOp::new1(self, Op::Copy);
         let tensor_ = Tensor_ {
             id: TensorId::new(),
+            pinned: None,
             storage: Arc::new(RwLock::new(self.storage().try_clone(self.layout())?)),
             layout: self.layout.clone(),
             op,
             is_variable: false,
             dtype: self.dtype,
             device: self.device.clone(),
+            dim_names: self.dim_names.clone(),
+        };
+        Ok(Tensor(Arc::new(tensor_)))
+    }
+
+    /// Returns a copy of this cpu tensor in page-locked host memory, `device` is the cuda device
+    /// used to register the memory which can then be used with all the cuda devices. Copies
+    /// from page-locked memory are faster and can be made asynchronous with
+    /// [`Tensor::to_device_nonblocking`].
+    ///
+    /// If the tensor is already pinned, it is returned as is.
+    pub fn pin_memory(&self, device: &Device) -> Result<Tensor> {
+        let cuda = match device {
+            Device::Cuda(cuda) => cuda,
+            device => bail!("pinned memory requires a cuda device, got {device:?}"),
+        };
+        if self.is_pinned() {
+            return Ok(self.clone());
+        }
+        let storage = match &*self.storage() {
+            // The buffers borrowed from other frameworks are copied to be registered.
+            Storage::Cpu(storage) => storage.owned_data().into_owned(),
+            _ => bail!("only cpu tensors can be pinned, got {:?}", self.device()),
+        };
+        // Moving the storage afterwards does not move the registered data.
+        let pinned = cuda.pin_host_memory(&storage)?;
+        let op = BackpropOp::new1(self, Op::Copy);
+        let tensor_ = Tensor_ {
+            id: TensorId::new(),
+            pinned: Some(Arc::from(pinned)),
+            storage: Arc::new(RwLock::new(Storage::Cpu(storage))),
+            layout: self.layout.clone(),
+            op,
+            is_variable: false,
+            dtype: self.dtype,
+            device: self.device.clone(),
+            dim_names: self.dim_names.clone(),
         };
         Ok(Tensor(Arc::new(tensor_)))
     }
 
+    /// Whether the tensor data is in page-locked host memory, see [`Tensor::pin_memory`].
+    pub fn is_pinned(&self) -> bool {
+        self.pinned.is_some()
+    }
+
     /// Returns a new tensor detached from the current graph, gradient are not propagated through
     /// this new node. The storage of this tensor is shared with the initial tensor.
     ///
@@ -2029,12 +2154,14 @@ impl Tensor {
         } else {
             let tensor_ = Tensor_ {
                 id: TensorId::new(),
+                pinned: self.pinned.clone(),
                 storage: self.storage.clone(),
                 layout: self.layout.clone(),
                 op: BackpropOp::none(),
                 is_variable: false,
                 dtype: self.dtype,
                 device: self.device.clone(),
+                dim_names: self.dim_names.clone(),
             };
             Tensor(Arc::new(tensor_))
         }
@@ -2042,12 +2169,32 @@ impl Tensor {
 
     /// If the target device is the same as the tensor device, only a shallow copy is performed.
     pub fn to_device(&self, device: &Device) -> Result<Tensor> {
+        self.to_device_impl(device, false)
+    }
+
+    /// Same as [`Tensor::to_device`] but copies from page-locked memory, see
+    /// [`Tensor::pin_memory`], to a cuda device are asynchronous: this returns immediately and the
+    /// copy overlaps with the kernels already queued on the device. The kernels using the
+    /// resulting tensor wait for the copy to complete. The data of the source tensor should not
+    /// be modified before the copy completes, e.g. by calling `Device::synchronize`.
+    ///
+    /// Other copies are done synchronously as with [`Tensor::to_device`].
+    pub fn to_device_nonblocking(&self, device: &Device) -> Result<Tensor> {
+        self.to_device_impl(device, true)
+    }
+
+    fn to_device_impl(&self, device: &Device, nonblocking: bool) -> Result<Tensor> {
         if self.device().same_device(device) {
             Ok(self.clone())
         } else {
             let storage = match (&*self.storage(), device) {
                 (Storage::Cpu(storage), Device::Cuda(cuda)) => {
-                    Storage::Cuda(cuda.storage_from_cpu_storage(storage)?)
+                    if nonblocking && self.is_pinned() {
+                        let keep_alive = Box::new(self.clone());
+                        Storage::Cuda(cuda.storage_from_pinned(storage, keep_alive)?)
+                    } else {
+                        Storage::Cuda(cuda.storage_from_cpu_storage(storage)?)
+                    }
                 }
                 (Storage::Cpu(storage), Device::Metal(metal)) => {
                     Storage::Metal(metal.storage_from_cpu_storage(storage)?)
@@ -2072,12 +2219,14 @@ impl Tensor {
             let op = BackpropOp::new1(self, Op::ToDevice);
             let tensor_ = Tensor_ {
                 id: TensorId::new(),
+                pinned: None,
                 storage: Arc::new(RwLock::new(storage)),
                 layout: self.layout.clone(),
                 op,
                 is_variable: false,
                 dtype: self.dtype,
                 device: device.clone(),
+                dim_names: self.dim_names.clone(),
             };
             Ok(Tensor(Arc::new(tensor_)))
         }
@@ -2100,14 +2249,18 @@ impl Tensor {
     /// any value, the dimension `t_a` must be equal to `i_a` if `i_a` is different from 1. If
     /// `i_a` is equal to 1, any value can be used.
     pub fn broadcast_as<S: Into<Shape>>(&self, shape: S) -> Result<Self> {
+        let layout = self.layout.broadcast_as(shape)?;
+        let dim_names = tensor_names::broadcast_left(&self.dim_names, layout.dims().len());
         let tensor_ = Tensor_ {
             id: TensorId::new(),
+            pinned: self.pinned.clone(),
             storage: self.storage.clone(),
-            layout: self.layout.broadcast_as(shape)?,
+            layout,
             op: BackpropOp::new1(self, Op::Broadcast),
             is_variable: false,
             dtype: self.dtype,
             device: self.device.clone(),
+            dim_names,
         };
         Ok(Tensor(Arc::new(tensor_)))
     }
@@ -2134,7 +2287,9 @@ impl Tensor {
             let shape = self.shape();
             let storage = self.storage().to_dtype(self.layout(), dtype)?;
             let op = BackpropOp::new1(self, Op::ToDType);
-            Ok(from_storage(storage, shape.clone(), op, false))
+            crate::anomaly::check(
+                from_storage(storage, shape.clone(), op, false).with_names(self.dim_names.clone()),
+            )
         }
     }
 
@@ -2149,7 +2304,9 @@ impl Tensor {
             self.storage()
                 .copy_strided_src(&mut storage, 0, self.layout())?;
             let op = BackpropOp::new1(self, Op::Copy);
-            Ok(from_storage(storage, shape.clone(), op, false))
+            crate::anomaly::check(
+                from_storage(storage, shape.clone(), op, false).with_names(self.dim_names.clone()),
+            )
         }
     }
 
@@ -2160,7 +2317,9 @@ impl Tensor {
         self.storage()
             .copy_strided_src(&mut storage, 0, self.layout())?;
         let op = BackpropOp::new1(self, Op::Copy);
-        Ok(from_storage(storage, shape.clone(), op, false))
+        crate::anomaly::check(
+            from_storage(storage, shape.clone(), op, false).with_names(self.dim_names.clone()),
+        )
     }
 
     /// Create a variable based on the values currently stored in a tensor. The storage is always
@@ -2170,7 +2329,7 @@ impl Tensor {
         let mut storage = unsafe { self.device().alloc_uninit(&shape, self.dtype())? };
         self.storage()
             .copy_strided_src(&mut storage, 0, self.layout())?;
-        Ok(from_storage(storage, shape, BackpropOp::none(), true))
+        crate::anomaly::check(from_storage(storage, shape, BackpropOp::none(), true))
     }
 
     /// Reshape returns a tensor with the target shape provided that the number of elements of the
@@ -2211,19 +2370,21 @@ impl Tensor {
         if self.is_contiguous() {
             let tensor_ = Tensor_ {
                 id: TensorId::new(),
+                pinned: self.pinned.clone(),
                 storage: self.storage.clone(),
                 layout: Layout::contiguous_with_offset(shape, self.layout.start_offset()),
                 op,
                 is_variable: false,
                 dtype: self.dtype,
                 device: self.device.clone(),
+                dim_names: None,
             };
             Ok(Tensor(Arc::new(tensor_)))
         } else {
             let mut storage = unsafe { self.device().alloc_uninit(&shape, self.dtype())? };
             self.storage()
                 .copy_strided_src(&mut storage, 0, self.layout())?;
-            Ok(from_storage(storage, shape, op, false))
+            crate::anomaly::check(from_storage(storage, shape, op, false))
         }
     }
 
@@ -2252,12 +2413,14 @@ impl Tensor {
             strides.remove(dim);
             let tensor_ = Tensor_ {
                 id: TensorId::new(),
+                pinned: self.pinned.clone(),
                 storage: self.storage.clone(),
                 layout: Layout::new(dims.into(), strides, self.layout.start_offset()),
                 op: BackpropOp::new1(self, Op::Reshape),
                 is_variable: false,
                 dtype: self.dtype,
                 device: self.device.clone(),
+                dim_names: tensor_names::remove(&self.dim_names, &[dim]),
             };
             Ok(Tensor(Arc::new(tensor_)))
         } else {
@@ -2290,12 +2453,14 @@ impl Tensor {
         strides.insert(dim, stride);
         let tensor_ = Tensor_ {
             id: TensorId::new(),
+            pinned: self.pinned.clone(),
             storage: self.storage.clone(),
             layout: Layout::new(dims.into(), strides, self.layout.start_offset()),
             op: BackpropOp::new1(self, Op::Reshape),
             is_variable: false,
             dtype: self.dtype,
             device: self.device.clone(),
+            dim_names: tensor_names::insert(&self.dim_names, dim),
         };
         Ok(Tensor(Arc::new(tensor_)))
     }
@@ -2429,6 +2594,16 @@ impl Tensor {
         (storage, &self.layout)
     }
 
+    /// Returns true if no other tensor shares either this tensor node or its storage, in which
+    /// case the storage can be modified in place without other tensors observing the change.
+    pub(crate) fn has_exclusive_storage(&self) -> bool {
+        Arc::strong_count(&self.0) == 1 && Arc::strong_count(&self.storage) == 1
+    }
+
+    pub(crate) fn storage_arc(&self) -> &Arc<RwLock<Storage>> {
+        &self.storage
+    }
+
     pub(crate) fn same_storage(&self, rhs: &Self) -> bool {
         let lhs: &RwLock<Storage> = self.storage.as_ref();
         let rhs: &RwLock<Storage> = rhs.storage.as_ref();
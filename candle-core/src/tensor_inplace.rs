@@ -0,0 +1,289 @@
+//! In place variants of some elementwise operations.
+//!
+//! These operations mutate the storage of the tensor rather than allocating a new one, this
+//! avoids doubling the peak memory usage for simple elementwise chains during inference. In
+//! order to be safe, they can only be used on tensors that do not share their storage with any
+//! other tensor and that are not tracked by the computation graph.
+//!
+//! Only the cpu has in place kernels for now, these operations return an error on the cuda and
+//! metal devices rather than silently allocating the result of an out of place operation.
+use crate::backend::BackendStorage;
+use crate::op::{self, BinaryOpT};
+use crate::{CpuStorage, Error, InplaceOp1, InplaceOp2, Layout, Result, Tensor};
+
+fn contiguous_mut<'a, T>(
+    data: &'a mut [T],
+    layout: &Layout,
+    op: &'static str,
+) -> Result<&'a mut [T]> {
+    match layout.contiguous_offsets() {
+        Some((o1, o2)) => Ok(&mut data[o1..o2]),
+        None => Err(Error::RequiresContiguous { op }.bt()),
+    }
+}
+
+fn map_inplace<T: Copy>(
+    data: &mut [T],
+    layout: &Layout,
+    op: &'static str,
+    f: impl Fn(T) -> T,
+) -> Result<()> {
+    for v in contiguous_mut(data, layout, op)?.iter_mut() {
+        *v = f(*v)
+    }
+    Ok(())
+}
+
+fn zip_inplace<T: Copy>(
+    lhs: &mut [T],
+    lhs_l: &Layout,
+    rhs: &[T],
+    rhs_l: &Layout,
+    op: &'static str,
+    f: impl Fn(T, T) -> T,
+) -> Result<()> {
+    let lhs = contiguous_mut(lhs, lhs_l, op)?;
+    match rhs_l.contiguous_offsets() {
+        Some((o1, o2)) => {
+            for (l, r) in lhs.iter_mut().zip(rhs[o1..o2].iter()) {
+                *l = f(*l, *r)
+            }
+        }
+        None => {
+            for (l, r) in lhs.iter_mut().zip(rhs_l.strided_index()) {
+                *l = f(*l, rhs[r])
+            }
+        }
+    }
+    Ok(())
+}
+
+macro_rules! map_storage {
+    ($storage:expr, $layout:expr, $op:expr, |$v:ident: $t:ident| $body:expr) => {
+        match $storage {
+            CpuStorage::U8(data) => {
+                type $t = u8;
+                map_inplace(data, $layout, $op, |$v: $t| $body)
+            }
+            CpuStorage::U32(data) => {
+                type $t = u32;
+                map_inplace(data, $layout, $op, |$v: $t| $body)
+            }
+            CpuStorage::I64(data) => {
+                type $t = i64;
+                map_inplace(data, $layout, $op, |$v: $t| $body)
+            }
+            CpuStorage::BF16(data) => {
+                type $t = half::bf16;
+                map_inplace(data, $layout, $op, |$v: $t| $body)
+            }
+            CpuStorage::F16(data) => {
+                type $t = half::f16;
+                map_inplace(data, $layout, $op, |$v: $t| $body)
+            }
+            CpuStorage::F32(data) => {
+                type $t = f32;
+                map_inplace(data, $layout, $op, |$v: $t| $body)
+            }
+            CpuStorage::F64(data) => {
+                type $t = f64;
+                map_inplace(data, $layout, $op, |$v: $t| $body)
+            }
+            // The storages are made owned before running inplace ops.
+            CpuStorage::Foreign(_) => crate::bail!("{} on a foreign storage", $op),
+        }
+    };
+}
+
+struct Affine {
+    mul: f64,
+    add: f64,
+}
+
+impl InplaceOp1 for Affine {
+    fn name(&self) -> &'static str {
+        "affine_"
+    }
+
+    fn cpu_fwd(&self, storage: &mut CpuStorage, layout: &Layout) -> Result<()> {
+        use crate::WithDType;
+        map_storage!(storage, layout, self.name(), |v: T| {
+            v * T::from_f64(self.mul) + T::from_f64(self.add)
+        })
+    }
+}
+
+struct Clamp {
+    min: f64,
+    max: f64,
+}
+
+impl InplaceOp1 for Clamp {
+    fn name(&self) -> &'static str {
+        "clamp_"
+    }
+
+    fn cpu_fwd(&self, storage: &mut CpuStorage, layout: &Layout) -> Result<()> {
+        use crate::WithDType;
+        map_storage!(storage, layout, self.name(), |v: T| {
+            let (min, max) = (T::from_f64(self.min), T::from_f64(self.max));
+            if v < min {
+                min
+            } else if v > max {
+                max
+            } else {
+                v
+            }
+        })
+    }
+}
+
+struct Binary<B: BinaryOpT>(std::marker::PhantomData<B>);
+
+impl<B: BinaryOpT> InplaceOp2 for Binary<B> {
+    fn name(&self) -> &'static str {
+        B::NAME
+    }
+
+    fn cpu_fwd(
+        &self,
+        s1: &mut CpuStorage,
+        l1: &Layout,
+        s2: &CpuStorage,
+        l2: &Layout,
+    ) -> Result<()> {
+        let op = self.name();
+        match (s1, s2) {
+            (CpuStorage::U8(lhs), CpuStorage::U8(rhs)) => zip_inplace(lhs, l1, rhs, l2, op, B::u8),
+            (CpuStorage::U32(lhs), CpuStorage::U32(rhs)) => {
+                zip_inplace(lhs, l1, rhs, l2, op, B::u32)
+            }
+            (CpuStorage::I64(lhs), CpuStorage::I64(rhs)) => {
+                zip_inplace(lhs, l1, rhs, l2, op, B::i64)
+            }
+            (CpuStorage::BF16(lhs), CpuStorage::BF16(rhs)) => {
+                zip_inplace(lhs, l1, rhs, l2, op, B::bf16)
+            }
+            (CpuStorage::F16(lhs), CpuStorage::F16(rhs)) => {
+                zip_inplace(lhs, l1, rhs, l2, op, B::f16)
+            }
+            (CpuStorage::F32(lhs), CpuStorage::F32(rhs)) => {
+                zip_inplace(lhs, l1, rhs, l2, op, B::f32)
+            }
+            (CpuStorage::F64(lhs), CpuStorage::F64(rhs)) => {
+                zip_inplace(lhs, l1, rhs, l2, op, B::f64)
+            }
+            (lhs, rhs) => Err(Error::DTypeMismatchBinaryOp {
+                lhs: lhs.dtype(),
+                rhs: rhs.dtype(),
+                op,
+            }
+            .bt()),
+        }
+    }
+}
+
+impl Tensor {
+    fn check_inplace(&self, op: &'static str) -> Result<()> {
+        let msg = if self.track_op() {
+            "the tensor is tracked by the computation graph"
+        } else if !self.has_exclusive_storage() {
+            "the tensor storage is shared with other tensors"
+        } else if !self.is_contiguous() {
+            "the tensor is not contiguous"
+        } else if !self.device().is_cpu() {
+            "in place operations are only implemented on the cpu"
+        } else {
+            return Ok(());
+        };
+        Err(Error::CannotApplyInplace { op, msg }.bt())
+    }
+
+    fn binary_inplace<B: BinaryOpT>(&self, rhs: &Self) -> Result<()> {
+        self.check_inplace(B::NAME)?;
+        if self.same_storage(rhs) {
+            Err(Error::CannotApplyInplace {
+                op: B::NAME,
+                msg: "the rhs tensor uses the same storage",
+            }
+            .bt())?
+        }
+        self.same_shape_binary_op(rhs, B::NAME)?;
+        if self.dtype() != rhs.dtype() {
+            Err(Error::DTypeMismatchBinaryOp {
+                lhs: self.dtype(),
+                rhs: rhs.dtype(),
+                op: B::NAME,
+            }
+            .bt())?
+        }
+        if self.elem_count() == 0 {
+            return Ok(());
+        }
+        self.inplace_op2(rhs, &Binary::<B>(std::marker::PhantomData))
+    }
+
+    /// Adds `rhs` to `self` in place.
+    ///
+    /// This returns an error if `self` is tracked by the computation graph, if its storage is
+    /// shared with another tensor, if it is not contiguous, or if it is not on the cpu.
+    ///
+    /// ```rust
+    /// use candle_core::{Tensor, Device};
+    /// let a = Tensor::new(&[1f32, 2., 3.], &Device::Cpu)?;
+    /// let b = Tensor::new(&[4f32, 5., 6.], &Device::Cpu)?;
+    /// a.add_(&b)?;
+    /// assert_eq!(a.to_vec1::<f32>()?, &[5., 7., 9.]);
+    ///
+    /// // The storage of `c` is shared with `a` so it cannot be modified in place.
+    /// let c = a.clone();
+    /// assert!(a.add_(&b).is_err());
+    /// # drop(c);
+    /// # Ok::<(), candle_core::Error>(())
+    /// ```
+    pub fn add_(&self, rhs: &Self) -> Result<()> {
+        self.binary_inplace::<op::Add>(rhs)
+    }
+
+    /// Subtracts `rhs` from `self` in place, see [`Tensor::add_`] for the requirements on `self`.
+    pub fn sub_(&self, rhs: &Self) -> Result<()> {
+        self.binary_inplace::<op::Sub>(rhs)
+    }
+
+    /// Multiplies `self` by `rhs` in place, see [`Tensor::add_`] for the requirements on `self`.
+    pub fn mul_(&self, rhs: &Self) -> Result<()> {
+        self.binary_inplace::<op::Mul>(rhs)
+    }
+
+    /// Divides `self` by `rhs` in place, see [`Tensor::add_`] for the requirements on `self`.
+    pub fn div_(&self, rhs: &Self) -> Result<()> {
+        self.binary_inplace::<op::Div>(rhs)
+    }
+
+    /// In place version of [`Tensor::affine`], see [`Tensor::add_`] for the requirements on
+    /// `self`.
+    pub fn affine_(&self, mul: f64, add: f64) -> Result<()> {
+        let op = Affine { mul, add };
+        self.check_inplace(op.name())?;
+        if self.elem_count() == 0 {
+            return Ok(());
+        }
+        self.inplace_op1(&op)
+    }
+
+    /// Multiplies all the elements of `self` by `mul` in place.
+    pub fn scale_(&self, mul: f64) -> Result<()> {
+        self.affine_(mul, 0.)
+    }
+
+    /// Clamps the values of `self` to be between `min` and `max` in place, see [`Tensor::add_`]
+    /// for the requirements on `self`.
+    pub fn clamp_(&self, min: f64, max: f64) -> Result<()> {
+        let op = Clamp { min, max };
+        self.check_inplace(op.name())?;
+        if self.elem_count() == 0 {
+            return Ok(());
+        }
+        self.inplace_op1(&op)
+    }
+}
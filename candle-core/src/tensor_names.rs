@@ -0,0 +1,239 @@
+//! Optional names for the dimensions of a tensor.
+//!
+//! Names are propagated through elementwise operations, reductions, transpositions, broadcasting
+//! and matrix multiplications. Operations that combine two named tensors check that the names
+//! are compatible, which helps catching silent shape errors, e.g. summing over the wrong
+//! dimension when two dimensions happen to have the same size.
+use crate::{bail, Error, Result, Tensor};
+use std::sync::Arc;
+
+pub(crate) type DimNames = Option<Arc<[Option<String>]>>;
+
+fn compatible(lhs: &Option<String>, rhs: &Option<String>) -> bool {
+    match (lhs, rhs) {
+        (Some(lhs), Some(rhs)) => lhs == rhs,
+        _ => true,
+    }
+}
+
+fn mismatch(lhs: &[Option<String>], rhs: &[Option<String>], op: &'static str) -> Error {
+    Error::DimNamesMismatch {
+        lhs: lhs.to_vec(),
+        rhs: rhs.to_vec(),
+        op,
+    }
+    .bt()
+}
+
+/// Combines the names of two tensors with the same shape, returns an error if a dimension has a
+/// different name on both sides.
+pub(crate) fn merge(lhs: &DimNames, rhs: &DimNames, op: &'static str) -> Result<DimNames> {
+    match (lhs, rhs) {
+        (None, None) => Ok(None),
+        (Some(names), None) | (None, Some(names)) => Ok(Some(names.clone())),
+        (Some(l), Some(r)) => {
+            if Arc::ptr_eq(l, r) {
+                return Ok(lhs.clone());
+            }
+            if l.len() != r.len() {
+                Err(mismatch(l, r, op))?
+            }
+            let mut names = Vec::with_capacity(l.len());
+            for (l_name, r_name) in l.iter().zip(r.iter()) {
+                if !compatible(l_name, r_name) {
+                    Err(mismatch(l, r, op))?
+                }
+                names.push(l_name.clone().or_else(|| r_name.clone()))
+            }
+            Ok(Some(names.into()))
+        }
+    }
+}
+
+/// The names resulting from a matrix multiplication, the contracted dimensions must have
+/// compatible names.
+pub(crate) fn matmul(lhs: &DimNames, rhs: &DimNames) -> Result<DimNames> {
+    let rank = match (lhs, rhs) {
+        (None, None) => return Ok(None),
+        (Some(names), _) | (None, Some(names)) => names.len(),
+    };
+    let unnamed = || vec![None; rank].into();
+    let l: Arc<[Option<String>]> = lhs.clone().unwrap_or_else(unnamed);
+    let r: Arc<[Option<String>]> = rhs.clone().unwrap_or_else(unnamed);
+    if l.len() != rank || r.len() != rank || !compatible(&l[rank - 1], &r[rank - 2]) {
+        Err(mismatch(&l, &r, "matmul"))?
+    }
+    let mut names = Vec::with_capacity(rank);
+    for (l_name, r_name) in l[..rank - 2].iter().zip(r[..rank - 2].iter()) {
+        if !compatible(l_name, r_name) {
+            Err(mismatch(&l, &r, "matmul"))?
+        }
+        names.push(l_name.clone().or_else(|| r_name.clone()))
+    }
+    names.push(l[rank - 2].clone());
+    names.push(r[rank - 1].clone());
+    Ok(Some(names.into()))
+}
+
+pub(crate) fn remove(names: &DimNames, dims: &[usize]) -> DimNames {
+    names.as_ref().map(|names| {
+        names
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| !dims.contains(i))
+            .map(|(_, n)| n.clone())
+            .collect()
+    })
+}
+
+pub(crate) fn insert(names: &DimNames, dim: usize) -> DimNames {
+    names.as_ref().map(|names| {
+        let mut names = names.to_vec();
+        names.insert(dim, None);
+        names.into()
+    })
+}
+
+pub(crate) fn transpose(names: &DimNames, dim1: usize, dim2: usize) -> DimNames {
+    names.as_ref().map(|names| {
+        let mut names = names.to_vec();
+        names.swap(dim1, dim2);
+        names.into()
+    })
+}
+
+pub(crate) fn permute(names: &DimNames, dims: &[usize]) -> DimNames {
+    names
+        .as_ref()
+        .map(|names| dims.iter().map(|&d| names[d].clone()).collect())
+}
+
+/// Broadcasting adds new unnamed dimensions on the left.
+pub(crate) fn broadcast_left(names: &DimNames, rank: usize) -> DimNames {
+    names.as_ref().map(|names| {
+        let mut new_names = vec![None; rank.saturating_sub(names.len())];
+        new_names.extend(names.iter().cloned());
+        new_names.into()
+    })
+}
+
+impl Tensor {
+    /// Returns a tensor sharing the storage of `self` with names attached to each of its
+    /// dimensions. An empty string leaves the corresponding dimension unnamed.
+    ///
+    /// ```rust
+    /// use candle_core::{Tensor, DType, Device};
+    /// let t = Tensor::ones((2, 3, 4), DType::F32, &Device::Cpu)?;
+    /// let t = t.with_dim_names(["batch", "seq", "hidden"])?;
+    /// let s = t.sum_dim("seq")?;
+    /// assert_eq!(s.dims(), &[2, 4]);
+    /// assert_eq!(s.dim_index("hidden")?, 1);
+    /// # Ok::<(), candle_core::Error>(())
+    /// ```
+    pub fn with_dim_names<I: IntoIterator<Item = S>, S: AsRef<str>>(
+        &self,
+        names: I,
+    ) -> Result<Self> {
+        let names: Vec<Option<String>> = names
+            .into_iter()
+            .map(|n| {
+                let n = n.as_ref();
+                (!n.is_empty()).then(|| n.to_string())
+            })
+            .collect();
+        if names.len() != self.rank() {
+            bail!(
+                "with_dim_names: got {} names for a tensor of shape {:?}",
+                names.len(),
+                self.shape()
+            )
+        }
+        for (i, name) in names.iter().enumerate() {
+            if name.is_some() && names[..i].contains(name) {
+                bail!("with_dim_names: duplicate dimension name {name:?} in {names:?}")
+            }
+        }
+        Ok(self.clone().with_names(Some(names.into())))
+    }
+
+    /// Returns a tensor sharing the storage of `self` with all the dimension names removed.
+    pub fn without_dim_names(&self) -> Self {
+        self.clone().with_names(None)
+    }
+
+    /// The index of the dimension with the given name.
+    pub fn dim_index(&self, name: &str) -> Result<usize> {
+        let names = self.dim_names().unwrap_or_default();
+        let mut indexes = names
+            .iter()
+            .enumerate()
+            .filter(|(_, n)| n.as_deref() == Some(name))
+            .map(|(i, _)| i);
+        match (indexes.next(), indexes.next()) {
+            (Some(index), None) => Ok(index),
+            (Some(_), Some(_)) => bail!("dimension name {name:?} is ambiguous in {names:?}"),
+            (None, _) => Err(Error::UnknownDimName {
+                name: name.to_string(),
+                names: names.to_vec(),
+                op: "dim-index",
+            }
+            .bt()),
+        }
+    }
+
+    /// Sums the values over the dimension with the given name, this dimension is squeezed.
+    pub fn sum_dim(&self, name: &str) -> Result<Self> {
+        self.sum(self.dim_index(name)?)
+    }
+
+    /// Sums the values over the dimension with the given name, this dimension is kept with a
+    /// size of one.
+    pub fn sum_dim_keepdim(&self, name: &str) -> Result<Self> {
+        self.sum_keepdim(self.dim_index(name)?)
+    }
+
+    /// Averages the values over the dimension with the given name, this dimension is squeezed.
+    pub fn mean_dim(&self, name: &str) -> Result<Self> {
+        self.mean(self.dim_index(name)?)
+    }
+
+    /// Averages the values over the dimension with the given name, this dimension is kept with a
+    /// size of one.
+    pub fn mean_dim_keepdim(&self, name: &str) -> Result<Self> {
+        self.mean_keepdim(self.dim_index(name)?)
+    }
+
+    /// The maximum value over the dimension with the given name, this dimension is squeezed.
+    pub fn max_dim(&self, name: &str) -> Result<Self> {
+        self.max(self.dim_index(name)?)
+    }
+
+    /// The minimum value over the dimension with the given name, this dimension is squeezed.
+    pub fn min_dim(&self, name: &str) -> Result<Self> {
+        self.min(self.dim_index(name)?)
+    }
+
+    /// Swaps the two dimensions with the given names.
+    pub fn transpose_dims(&self, name1: &str, name2: &str) -> Result<Self> {
+        self.transpose(self.dim_index(name1)?, self.dim_index(name2)?)
+    }
+
+    /// Permutes the dimensions so that they appear in the order given by `names`, all the
+    /// dimensions of the tensor have to be listed.
+    ///
+    /// ```rust
+    /// use candle_core::{Tensor, DType, Device};
+    /// let t = Tensor::zeros((2, 3, 4), DType::F32, &Device::Cpu)?;
+    /// let t = t.with_dim_names(["batch", "seq", "hidden"])?;
+    /// let t = t.align_to(&["seq", "batch", "hidden"])?;
+    /// assert_eq!(t.dims(), &[3, 2, 4]);
+    /// # Ok::<(), candle_core::Error>(())
+    /// ```
+    pub fn align_to(&self, names: &[&str]) -> Result<Self> {
+        let dims = names
+            .iter()
+            .map(|name| self.dim_index(name))
+            .collect::<Result<Vec<_>>>()?;
+        self.permute(dims)
+    }
+}
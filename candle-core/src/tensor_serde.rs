@@ -0,0 +1,545 @@
+//! Serde support for tensors and dtypes, behind the `serde` feature.
+//!
+//! A tensor is serialized as a struct with its dtype, its shape and its data. By default the data
+//! is encoded as the base64 string of its little-endian bytes in human readable formats such as
+//! JSON, and as raw bytes in binary formats such as MessagePack or bincode. The data can also be
+//! encoded as nested arrays of numbers, which is easier to read and write by hand, either with
+//! the [`array`] field attribute or with [`Tensor::serialize_as`].
+//!
+//! ```ignore
+//! #[derive(serde::Serialize, serde::Deserialize)]
+//! struct Config {
+//!     // {"dtype": "f32", "shape": [2], "data": "AACAPwAAAEA="}
+//!     bias: Tensor,
+//!     // {"dtype": "f32", "shape": [2, 2], "data": [[1.0, 0.0], [0.0, 1.0]]}
+//!     #[serde(with = "candle::tensor_serde::array")]
+//!     rotation: Tensor,
+//! }
+//! ```
+//!
+//! Deserializing accepts all the encodings in self-describing formats, the shape can be omitted
+//! when the data is a nested array. The tensors are always deserialized on the cpu, and the
+//! half precision floats are written as `f32` numbers in arrays.
+use crate::{DType, Device, Tensor};
+use ::base64::Engine;
+use serde::de::{self, DeserializeSeed, Deserializer, IgnoredAny, MapAccess, SeqAccess, Visitor};
+use serde::ser::{self, SerializeSeq, SerializeStruct, Serializer};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+const FIELDS: &[&str] = &["dtype", "shape", "data"];
+const BASE64: ::base64::engine::GeneralPurpose = ::base64::engine::general_purpose::STANDARD;
+
+/// How the data of a tensor is encoded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    /// Nested arrays of numbers following the shape of the tensor.
+    Array,
+    /// The base64 string of the little-endian bytes.
+    Base64,
+    /// The little-endian bytes, for binary formats.
+    Bytes,
+}
+
+impl Serialize for DType {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
+impl<'de> Deserialize<'de> for DType {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let dtype = String::deserialize(deserializer)?;
+        dtype.parse().map_err(de::Error::custom)
+    }
+}
+
+/// A tensor serialized with a given [`Encoding`], see [`Tensor::serialize_as`].
+#[derive(Debug, Clone, Copy)]
+pub struct EncodedTensor<'a> {
+    tensor: &'a Tensor,
+    encoding: Encoding,
+}
+
+impl Tensor {
+    /// Serializes the tensor with its data encoded as `encoding`.
+    pub fn serialize_as(&self, encoding: Encoding) -> EncodedTensor<'_> {
+        EncodedTensor {
+            tensor: self,
+            encoding,
+        }
+    }
+}
+
+// The elements of a tensor as nested sequences, `data` has the elements of all the dimensions.
+struct Nested<'a, T> {
+    data: &'a [T],
+    dims: &'a [usize],
+}
+
+impl<T: Serialize> Serialize for Nested<'_, T> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self.dims.split_first() {
+            None => self.data[0].serialize(serializer),
+            Some((&dim, dims)) => {
+                let size = dims.iter().product::<usize>();
+                let mut seq = serializer.serialize_seq(Some(dim))?;
+                for i in 0..dim {
+                    let data = &self.data[i * size..(i + 1) * size];
+                    seq.serialize_element(&Nested { data, dims })?;
+                }
+                seq.end()
+            }
+        }
+    }
+}
+
+struct Bytes<'a>(&'a [u8]);
+
+impl Serialize for Bytes<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_bytes(self.0)
+    }
+}
+
+fn nested<S: SerializeStruct, T: crate::WithDType + Serialize>(
+    st: &mut S,
+    tensor: &Tensor,
+) -> Result<(), S::Error> {
+    let data = tensor
+        .flatten_all()
+        .and_then(|t| t.to_vec1::<T>())
+        .map_err(ser::Error::custom)?;
+    let dims = tensor.dims();
+    st.serialize_field("data", &Nested { data: &data, dims })
+}
+
+impl Serialize for EncodedTensor<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let tensor = self.tensor;
+        let mut st = serializer.serialize_struct("Tensor", 3)?;
+        st.serialize_field("dtype", &tensor.dtype())?;
+        st.serialize_field("shape", tensor.dims())?;
+        match self.encoding {
+            Encoding::Array => match tensor.dtype() {
+                DType::U8 => nested::<_, u8>(&mut st, tensor)?,
+                DType::U32 => nested::<_, u32>(&mut st, tensor)?,
+                DType::I64 => nested::<_, i64>(&mut st, tensor)?,
+                DType::BF16 | DType::F16 | DType::F32 => {
+                    let tensor = tensor.to_dtype(DType::F32).map_err(ser::Error::custom)?;
+                    nested::<_, f32>(&mut st, &tensor)?
+                }
+                DType::F64 => nested::<_, f64>(&mut st, tensor)?,
+            },
+            Encoding::Base64 => {
+                let bytes = crate::safetensors::convert_back(tensor).map_err(ser::Error::custom)?;
+                st.serialize_field("data", &BASE64.encode(bytes))?
+            }
+            Encoding::Bytes => {
+                let bytes = crate::safetensors::convert_back(tensor).map_err(ser::Error::custom)?;
+                st.serialize_field("data", &Bytes(&bytes))?
+            }
+        }
+        st.end()
+    }
+}
+
+impl Serialize for Tensor {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let encoding = if serializer.is_human_readable() {
+            Encoding::Base64
+        } else {
+            Encoding::Bytes
+        };
+        self.serialize_as(encoding).serialize(serializer)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Number {
+    I64(i64),
+    U64(u64),
+    F64(f64),
+}
+
+// The data as read, before knowing the dtype or the shape.
+enum Data {
+    Bytes(Vec<u8>),
+    Numbers {
+        values: Vec<Number>,
+        dims: Vec<usize>,
+    },
+}
+
+fn ragged<E: de::Error>() -> E {
+    E::custom("the nested arrays of the tensor data are ragged")
+}
+
+// Reads nested arrays of numbers, the dims are inferred from the nesting.
+struct NestedSeed<'a> {
+    // The formats that are not self-describing need the numbers to be read with the type used
+    // to serialize arrays of this dtype, with the nesting given by the rank.
+    dtype: Option<DType>,
+    rank: Option<usize>,
+    values: &'a mut Vec<Number>,
+    dims: &'a mut Vec<Option<usize>>,
+    leaf_depth: &'a mut Option<usize>,
+    depth: usize,
+}
+
+impl NestedSeed<'_> {
+    fn leaf<E: de::Error>(&mut self, value: Number) -> Result<(), E> {
+        match *self.leaf_depth {
+            None => *self.leaf_depth = Some(self.depth),
+            Some(depth) if depth != self.depth => return Err(ragged()),
+            Some(_) => {}
+        }
+        self.values.push(value);
+        Ok(())
+    }
+}
+
+impl<'de> DeserializeSeed<'de> for NestedSeed<'_> {
+    type Value = ();
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<(), D::Error> {
+        if deserializer.is_human_readable() {
+            return deserializer.deserialize_any(self);
+        }
+        let is_leaf = self.rank == Some(self.depth);
+        match self.dtype {
+            Some(DType::U8) if is_leaf => deserializer.deserialize_u8(self),
+            Some(DType::U32) if is_leaf => deserializer.deserialize_u32(self),
+            Some(DType::I64) if is_leaf => deserializer.deserialize_i64(self),
+            Some(DType::BF16 | DType::F16 | DType::F32) if is_leaf => {
+                deserializer.deserialize_f32(self)
+            }
+            Some(DType::F64) if is_leaf => deserializer.deserialize_f64(self),
+            _ => deserializer.deserialize_seq(self),
+        }
+    }
+}
+
+impl<'de> Visitor<'de> for NestedSeed<'_> {
+    type Value = ();
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a number or an array")
+    }
+
+    fn visit_i64<E: de::Error>(mut self, v: i64) -> Result<(), E> {
+        self.leaf(Number::I64(v))
+    }
+
+    fn visit_u64<E: de::Error>(mut self, v: u64) -> Result<(), E> {
+        self.leaf(Number::U64(v))
+    }
+
+    fn visit_f64<E: de::Error>(mut self, v: f64) -> Result<(), E> {
+        self.leaf(Number::F64(v))
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<(), A::Error> {
+        if matches!(*self.leaf_depth, Some(depth) if depth <= self.depth) {
+            return Err(ragged());
+        }
+        let mut len = 0;
+        loop {
+            let seed = NestedSeed {
+                dtype: self.dtype,
+                rank: self.rank,
+                values: &mut *self.values,
+                dims: &mut *self.dims,
+                leaf_depth: &mut *self.leaf_depth,
+                depth: self.depth + 1,
+            };
+            if seq.next_element_seed(seed)?.is_none() {
+                break;
+            }
+            len += 1;
+        }
+        // The inner arrays are done first.
+        if self.dims.len() <= self.depth {
+            self.dims.resize(self.depth + 1, None)
+        }
+        match self.dims[self.depth] {
+            None => self.dims[self.depth] = Some(len),
+            Some(dim) if dim != len => return Err(ragged()),
+            Some(_) => {}
+        }
+        Ok(())
+    }
+}
+
+struct DataSeed {
+    dtype: Option<DType>,
+    rank: Option<usize>,
+    encoding: Option<Encoding>,
+}
+
+impl DataSeed {
+    fn numbers<'de, D: Deserializer<'de>>(self, deserializer: D) -> Result<Data, D::Error> {
+        let mut values = vec![];
+        let mut dims = vec![];
+        let mut leaf_depth = None;
+        let seed = NestedSeed {
+            dtype: self.dtype,
+            rank: self.rank,
+            values: &mut values,
+            dims: &mut dims,
+            leaf_depth: &mut leaf_depth,
+            depth: 0,
+        };
+        seed.deserialize(deserializer)?;
+        let dims = dims.into_iter().flatten().collect();
+        Ok(Data::Numbers { values, dims })
+    }
+
+    fn scalar(value: Number) -> Data {
+        Data::Numbers {
+            values: vec![value],
+            dims: vec![],
+        }
+    }
+}
+
+impl<'de> DeserializeSeed<'de> for DataSeed {
+    type Value = Data;
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<Data, D::Error> {
+        if deserializer.is_human_readable() {
+            return deserializer.deserialize_any(self);
+        }
+        match self.encoding {
+            Some(Encoding::Array) => self.numbers(deserializer),
+            Some(Encoding::Base64) => deserializer.deserialize_str(self),
+            Some(Encoding::Bytes) | None => deserializer.deserialize_byte_buf(self),
+        }
+    }
+}
+
+impl<'de> Visitor<'de> for DataSeed {
+    type Value = Data;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a base64 string, some bytes or nested arrays of numbers")
+    }
+
+    fn visit_str<E: de::Error>(self, v: &str) -> Result<Data, E> {
+        let bytes = BASE64.decode(v).map_err(E::custom)?;
+        Ok(Data::Bytes(bytes))
+    }
+
+    fn visit_i64<E: de::Error>(self, v: i64) -> Result<Data, E> {
+        Ok(Self::scalar(Number::I64(v)))
+    }
+
+    fn visit_u64<E: de::Error>(self, v: u64) -> Result<Data, E> {
+        Ok(Self::scalar(Number::U64(v)))
+    }
+
+    fn visit_f64<E: de::Error>(self, v: f64) -> Result<Data, E> {
+        Ok(Self::scalar(Number::F64(v)))
+    }
+
+    fn visit_bytes<E: de::Error>(self, v: &[u8]) -> Result<Data, E> {
+        Ok(Data::Bytes(v.to_vec()))
+    }
+
+    fn visit_byte_buf<E: de::Error>(self, v: Vec<u8>) -> Result<Data, E> {
+        Ok(Data::Bytes(v))
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, seq: A) -> Result<Data, A::Error> {
+        let mut values = vec![];
+        let mut dims = vec![];
+        let mut leaf_depth = None;
+        let seed = NestedSeed {
+            dtype: self.dtype,
+            rank: self.rank,
+            values: &mut values,
+            dims: &mut dims,
+            leaf_depth: &mut leaf_depth,
+            depth: 0,
+        };
+        seed.visit_seq(seq)?;
+        let dims = dims.into_iter().flatten().collect();
+        Ok(Data::Numbers { values, dims })
+    }
+}
+
+fn integers<T: TryFrom<i64> + crate::WithDType>(
+    values: &[Number],
+    shape: &[usize],
+) -> crate::Result<Tensor> {
+    let out_of_range = |v: &dyn fmt::Display| {
+        crate::Error::Msg(format!("{v} is not a valid {:?} value", T::DTYPE))
+    };
+    let values = values
+        .iter()
+        .map(|&v| {
+            let v = match v {
+                Number::I64(v) => v,
+                Number::U64(v) => i64::try_from(v).map_err(|_| out_of_range(&v))?,
+                Number::F64(v) if v.fract() == 0. && v.abs() < 9.2e18 => v as i64,
+                Number::F64(v) => return Err(out_of_range(&v)),
+            };
+            T::try_from(v).map_err(|_| out_of_range(&v))
+        })
+        .collect::<crate::Result<Vec<_>>>()?;
+    Tensor::from_vec(values, shape, &Device::Cpu)
+}
+
+fn tensor(dtype: DType, shape: Option<Vec<usize>>, data: Data) -> crate::Result<Tensor> {
+    match data {
+        Data::Bytes(bytes) => {
+            let size = dtype.size_in_bytes();
+            let shape = shape.unwrap_or_else(|| vec![bytes.len() / size]);
+            let len = shape.iter().product::<usize>() * size;
+            if bytes.len() != len {
+                crate::bail!(
+                    "{} bytes of data for a {dtype:?} tensor of shape {shape:?}",
+                    bytes.len()
+                )
+            }
+            Tensor::from_raw_buffer(&bytes, dtype, &shape, &Device::Cpu)
+        }
+        Data::Numbers { values, dims } => {
+            // The data can be a flat array with the shape given separately.
+            let shape = shape.unwrap_or(dims);
+            if shape.iter().product::<usize>() != values.len() {
+                crate::bail!("{} values for a tensor of shape {shape:?}", values.len())
+            }
+            match dtype {
+                DType::U8 => integers::<u8>(&values, &shape),
+                DType::U32 => integers::<u32>(&values, &shape),
+                DType::I64 => integers::<i64>(&values, &shape),
+                DType::BF16 | DType::F16 | DType::F32 | DType::F64 => {
+                    let values = values
+                        .iter()
+                        .map(|&v| match v {
+                            Number::I64(v) => v as f64,
+                            Number::U64(v) => v as f64,
+                            Number::F64(v) => v,
+                        })
+                        .collect::<Vec<_>>();
+                    Tensor::from_vec(values, shape, &Device::Cpu)?.to_dtype(dtype)
+                }
+            }
+        }
+    }
+}
+
+struct TensorVisitor {
+    encoding: Option<Encoding>,
+}
+
+impl<'de> Visitor<'de> for TensorVisitor {
+    type Value = Tensor;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a tensor with a dtype, a shape and some data")
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Tensor, A::Error> {
+        let dtype: DType = match seq.next_element()? {
+            Some(dtype) => dtype,
+            None => return Err(de::Error::invalid_length(0, &self)),
+        };
+        let shape: Vec<usize> = match seq.next_element()? {
+            Some(shape) => shape,
+            None => return Err(de::Error::invalid_length(1, &self)),
+        };
+        let seed = DataSeed {
+            dtype: Some(dtype),
+            rank: Some(shape.len()),
+            encoding: self.encoding,
+        };
+        let data = match seq.next_element_seed(seed)? {
+            Some(data) => data,
+            None => return Err(de::Error::invalid_length(2, &self)),
+        };
+        tensor(dtype, Some(shape), data).map_err(de::Error::custom)
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Tensor, A::Error> {
+        let mut dtype: Option<DType> = None;
+        let mut shape: Option<Vec<usize>> = None;
+        let mut data = None;
+        while let Some(key) = map.next_key::<String>()? {
+            match key.as_str() {
+                "dtype" if dtype.is_none() => dtype = Some(map.next_value()?),
+                "shape" if shape.is_none() => shape = Some(map.next_value()?),
+                "data" if data.is_none() => {
+                    let seed = DataSeed {
+                        dtype,
+                        rank: shape.as_ref().map(|s| s.len()),
+                        encoding: self.encoding,
+                    };
+                    data = Some(map.next_value_seed(seed)?)
+                }
+                "dtype" | "shape" | "data" => {
+                    return Err(de::Error::custom(format!("duplicate field {key}")))
+                }
+                _ => {
+                    map.next_value::<IgnoredAny>()?;
+                }
+            }
+        }
+        let dtype = dtype.ok_or_else(|| de::Error::missing_field("dtype"))?;
+        let data = data.ok_or_else(|| de::Error::missing_field("data"))?;
+        tensor(dtype, shape, data).map_err(de::Error::custom)
+    }
+}
+
+impl<'de> Deserialize<'de> for Tensor {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let visitor = TensorVisitor { encoding: None };
+        deserializer.deserialize_struct("Tensor", FIELDS, visitor)
+    }
+}
+
+macro_rules! encoding_module {
+    ($name:ident, $encoding:ident, $doc:literal) => {
+        #[doc = $doc]
+        pub mod $name {
+            use super::{Encoding, TensorVisitor, FIELDS};
+            use crate::Tensor;
+            use serde::{Deserializer, Serialize, Serializer};
+
+            pub fn serialize<S: Serializer>(
+                tensor: &Tensor,
+                serializer: S,
+            ) -> Result<S::Ok, S::Error> {
+                tensor
+                    .serialize_as(Encoding::$encoding)
+                    .serialize(serializer)
+            }
+
+            pub fn deserialize<'de, D: Deserializer<'de>>(
+                deserializer: D,
+            ) -> Result<Tensor, D::Error> {
+                let visitor = TensorVisitor {
+                    encoding: Some(Encoding::$encoding),
+                };
+                deserializer.deserialize_struct("Tensor", FIELDS, visitor)
+            }
+        }
+    };
+}
+
+encoding_module!(
+    array,
+    Array,
+    "Encodes the data as nested arrays, to be used with `#[serde(with = \"candle::tensor_serde::array\")]`."
+);
+encoding_module!(
+    base64,
+    Base64,
+    "Encodes the data as a base64 string, to be used with `#[serde(with = \"candle::tensor_serde::base64\")]`."
+);
+encoding_module!(
+    bytes,
+    Bytes,
+    "Encodes the data as bytes, to be used with `#[serde(with = \"candle::tensor_serde::bytes\")]`."
+);
@@ -1,4 +1,4 @@
-use crate::{Result, Tensor};
+use crate::{DType, Result, Tensor};
 
 #[macro_export]
 macro_rules! test_device {
@@ -60,3 +60,12 @@ pub fn to_vec3_round(t: &Tensor, digits: i32) -> Result<Vec<Vec<Vec<f32>>>> {
         .collect();
     Ok(t)
 }
+
+/// The maximum absolute difference between the elements of two tensors.
+pub fn max_diff(a: &Tensor, b: &Tensor) -> Result<f32> {
+    (a - b)?
+        .abs()?
+        .max_all()?
+        .to_dtype(DType::F32)?
+        .to_scalar::<f32>()
+}
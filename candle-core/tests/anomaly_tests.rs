@@ -0,0 +1,84 @@
+use candle_core::{anomaly, DType, Device, DeviceLocation, Error, Result, Tensor, Var};
+
+// The anomaly detection settings are global so everything is checked in a single test.
+#[test]
+fn anomaly_detection() -> Result<()> {
+    let dev = &Device::Cpu;
+    let xs = Tensor::new(&[1f32, 0., -1.], dev)?;
+    let zeros = Tensor::zeros(3, DType::F32, dev)?;
+    assert!(xs.log().is_ok());
+
+    anomaly::enable();
+    let err = xs.log().unwrap_err();
+    assert_eq!(
+        err.to_string().lines().next().unwrap(),
+        "log produced nan values, input shapes: [[3]]"
+    );
+    // 0 / 0 gives a NaN whereas 1 / 0 gives an infinite value.
+    let err = xs.div(&zeros).unwrap_err();
+    assert!(err.to_string().starts_with("div produced nan values"));
+    let err = (xs.abs()? + 1.)?.div(&zeros).unwrap_err();
+    assert!(err.to_string().starts_with("div produced inf values"));
+    let lhs = Tensor::new(&[[f32::MAX, 1.], [1., 1.]], dev)?;
+    let err = lhs.matmul(&lhs.to_dtype(DType::F32)?).unwrap_err();
+    assert!(err
+        .to_string()
+        .starts_with("matmul produced inf values, input shapes: [[2, 2], [2, 2]]"));
+    // Integer ops and tensors created from data are not checked.
+    assert!(Tensor::new(&[f32::NAN], dev).is_ok());
+    let ids = Tensor::new(&[1u32, 2], dev)?;
+    assert!(ids.add(&ids).is_ok());
+    // In-place ops and ops returning an error are not blamed for the tensors created afterwards.
+    let ys = xs.copy()?;
+    ys.affine_(2., 0.)?;
+    assert!(Tensor::new(&[0f32, f32::NEG_INFINITY], dev).is_ok());
+    assert!(xs.add(&Tensor::new(&[1u32, 2, 3], dev)?).is_err());
+    assert!(Tensor::new(&[0f32, f32::NEG_INFINITY], dev).is_ok());
+    assert!(Tensor::from_vec(vec![f32::NAN; 3], 3, dev).is_ok());
+
+    anomaly::enable_with(anomaly::Options {
+        allow_inf: true,
+        ..Default::default()
+    });
+    let ys = (xs.abs()? + 1.)?.div(&zeros)?;
+    assert_eq!(ys.to_vec1::<f32>()?, [f32::INFINITY; 3]);
+    assert!(ys.affine(0., 0.).is_err());
+
+    anomaly::enable_with(anomaly::Options {
+        capture_backtrace: true,
+        ..Default::default()
+    });
+    let err = xs.sqrt().unwrap_err();
+    assert!(matches!(err, Error::WithBacktrace { .. }));
+
+    anomaly::enable_with(anomaly::Options {
+        devices: vec![DeviceLocation::Metal { gpu_id: 0 }],
+        ..Default::default()
+    });
+    assert!(xs.log().is_ok());
+
+    anomaly::disable();
+    assert!(!anomaly::is_enabled());
+    assert!(xs.log().is_ok());
+
+    // The gradient of the square root is infinite in 0 while the forward pass is finite.
+    let vs = Var::new(&[4f32, 0.], dev)?;
+    let grads = (vs.sqrt()? * 2.)?.sum_all()?.backward()?;
+    let grad = grads.get(&vs).unwrap().to_vec1::<f32>()?;
+    assert_eq!(grad, [0.5, f32::INFINITY]);
+
+    anomaly::set_detect_anomaly(true);
+    assert!(anomaly::is_detect_anomaly_enabled());
+    let ys = (vs.sqrt()? * 2.)?.sum_all()?;
+    let err = ys.backward().unwrap_err().to_string();
+    assert!(
+        err.starts_with("the backward pass of sqrt produced inf gradients, input shapes: [[2]]"),
+        "{err}"
+    );
+    // The error points at the forward call.
+    assert!(err.contains("anomaly_tests.rs"), "{err}");
+    let ys = (vs.exp()? * 2.)?.sum_all()?;
+    assert!(ys.backward().is_ok());
+    anomaly::set_detect_anomaly(false);
+    Ok(())
+}
@@ -0,0 +1,51 @@
+use candle_core::{Device, Result, Tensor, D};
+
+fn run(dev: &Device) -> Result<Vec<f32>> {
+    let xs = Tensor::arange(0f32, 4096., dev)?.affine(1e-3, 0.1)?.sin()?;
+    let xs = xs.reshape((64, 64))?;
+    let ids: Vec<u32> = (0..64).map(|i| i % 4).collect();
+    let ids = Tensor::new(ids, dev)?;
+    let zeros = xs.zeros_like()?;
+    let ys = xs.matmul(&xs.t()?)?;
+    let ys = zeros.index_add(&ids, &ys, 0)?;
+    let ids = ids.unsqueeze(0)?.broadcast_as((64, 64))?.contiguous()?;
+    let ys = zeros.scatter_add(&ids, &ys, 1)?;
+    let ys = ys
+        .sum_keepdim(0)?
+        .broadcast_add(&ys.max_keepdim(D::Minus1)?.sum_all()?)?;
+    ys.flatten_all()?.to_vec1::<f32>()
+}
+
+// The deterministic setting is global so everything is checked in a single test.
+#[test]
+fn deterministic_mode() -> Result<()> {
+    let dev = &Device::Cpu;
+    assert!(!candle_core::is_deterministic());
+    candle_core::set_deterministic(true);
+    assert!(candle_core::is_deterministic());
+    let res1 = run(dev)?;
+    let res2 = run(dev)?;
+    assert_eq!(res1, res2);
+
+    let err = Tensor::rand(0f32, 1f32, 4, dev).unwrap_err();
+    assert_eq!(
+        err.to_string().lines().next().unwrap(),
+        "rand_uniform has no deterministic implementation on Cpu: the random number generator has not been seeded"
+    );
+    let err = Tensor::randn(0f32, 1f32, 4, dev).unwrap_err();
+    assert!(err
+        .to_string()
+        .starts_with("rand_normal has no deterministic"));
+
+    dev.set_seed(42)?;
+    let xs = Tensor::rand(0f32, 1f32, 4, dev)?;
+    dev.set_seed(42)?;
+    assert_eq!(
+        Tensor::rand(0f32, 1f32, 4, dev)?.to_vec1::<f32>()?,
+        xs.to_vec1::<f32>()?
+    );
+
+    candle_core::set_deterministic(false);
+    assert!(Tensor::rand(0f32, 1f32, 4, dev).is_ok());
+    Ok(())
+}
@@ -0,0 +1,148 @@
+use candle_core::dlpack::{DLDataType, DLDevice, DLManagedTensor, DLTensor, DL_CPU, DL_FLOAT};
+use candle_core::{test_device, DType, Device, IndexOp, Result, Tensor};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+fn round_trip(device: &Device) -> Result<()> {
+    let t = Tensor::arange(0u32, 24, device)?.reshape((2, 3, 4))?;
+    if device.is_metal() {
+        assert!(t.to_dlpack().is_err());
+        return Ok(());
+    }
+    let managed = t.to_dlpack()?;
+    let dl = unsafe { &(*managed).dl_tensor };
+    assert_eq!(dl.ndim, 3);
+    assert_eq!(dl.dtype, DLDataType::from_dtype(DType::U32));
+    assert_eq!(dl.device, DLDevice::from_location(device.location()));
+    let t2 = unsafe { Tensor::from_dlpack(managed, device)? };
+    assert_eq!(t2.to_vec3::<u32>()?, t.to_vec3::<u32>()?);
+
+    // Strided tensors with an offset.
+    let t = Tensor::arange(0f32, 24., device)?
+        .reshape((2, 3, 4))?
+        .i((.., 1..))?
+        .transpose(0, 2)?;
+    let managed = t.to_dlpack()?;
+    let dl = unsafe { &(*managed).dl_tensor };
+    assert_eq!(dl.byte_offset, 16);
+    let t2 = unsafe { Tensor::from_dlpack(managed, device)? };
+    assert_eq!(t2.dims(), [4, 2, 2]);
+    assert_eq!(t2.to_vec3::<f32>()?, t.to_vec3::<f32>()?);
+
+    // Scalars and empty tensors.
+    let t = Tensor::new(3.5f64, device)?;
+    let t2 = unsafe { Tensor::from_dlpack(t.to_dlpack()?, device)? };
+    assert_eq!(t2.to_scalar::<f64>()?, 3.5);
+    let t = Tensor::zeros((0, 3), DType::BF16, device)?;
+    let t2 = unsafe { Tensor::from_dlpack(t.to_dlpack()?, device)? };
+    assert_eq!(t2.dims(), [0, 3]);
+
+    // The device has to match.
+    if !device.is_cpu() {
+        let managed = t.to_dlpack()?;
+        assert!(unsafe { Tensor::from_dlpack(managed, &Device::Cpu) }.is_err());
+    }
+    Ok(())
+}
+
+test_device!(round_trip, round_trip_cpu, round_trip_gpu, round_trip_metal);
+
+#[test]
+fn export_shares_data() -> Result<()> {
+    let t = Tensor::arange(0f32, 6., &Device::Cpu)?;
+    let managed = t.narrow(0, 2, 3)?.to_dlpack()?;
+    drop(t);
+    let values = unsafe {
+        let dl = &(*managed).dl_tensor;
+        let data = (dl.data as *const u8).add(dl.byte_offset as usize) as *const f32;
+        std::slice::from_raw_parts(data, 3).to_vec()
+    };
+    assert_eq!(values, [2., 3., 4.]);
+    unsafe { ((*managed).deleter.unwrap())(managed) };
+    Ok(())
+}
+
+static DELETED: AtomicUsize = AtomicUsize::new(0);
+
+struct Foreign {
+    data: Vec<f32>,
+    shape: Vec<i64>,
+    strides: Vec<i64>,
+}
+
+unsafe extern "C" fn foreign_deleter(managed: *mut DLManagedTensor) {
+    let managed = Box::from_raw(managed);
+    drop(Box::from_raw(managed.manager_ctx as *mut Foreign));
+    DELETED.fetch_add(1, Ordering::SeqCst);
+}
+
+fn foreign_tensor(data: Vec<f32>, shape: Vec<i64>, strides: Vec<i64>) -> *mut DLManagedTensor {
+    let mut foreign = Box::new(Foreign {
+        data,
+        shape,
+        strides,
+    });
+    let dl_tensor = DLTensor {
+        data: foreign.data.as_mut_ptr() as *mut _,
+        device: DLDevice {
+            device_type: DL_CPU,
+            device_id: 0,
+        },
+        ndim: foreign.shape.len() as i32,
+        dtype: DLDataType {
+            code: DL_FLOAT,
+            bits: 32,
+            lanes: 1,
+        },
+        shape: foreign.shape.as_mut_ptr(),
+        strides: foreign.strides.as_mut_ptr(),
+        byte_offset: 0,
+    };
+    Box::into_raw(Box::new(DLManagedTensor {
+        dl_tensor,
+        manager_ctx: Box::into_raw(foreign) as *mut _,
+        deleter: Some(foreign_deleter),
+    }))
+}
+
+#[test]
+fn import_foreign() -> Result<()> {
+    // A column major 2x3 matrix, as could be produced by another framework.
+    let managed = foreign_tensor(vec![1., 4., 2., 5., 3., 6.], vec![2, 3], vec![1, 2]);
+    let data = unsafe { (*managed).dl_tensor.data };
+    let t = unsafe { Tensor::from_dlpack(managed, &Device::Cpu)? };
+    // The data is borrowed and the producer only released once the tensor is dropped.
+    assert_eq!(DELETED.load(Ordering::SeqCst), 0);
+    assert_eq!(t.to_vec2::<f32>()?, [[1., 2., 3.], [4., 5., 6.]]);
+    assert_eq!(
+        t.t()?.contiguous()?.to_vec2::<f32>()?,
+        [[1., 4.], [2., 5.], [3., 6.]]
+    );
+    assert_eq!((&t + 1.)?.sum_all()?.to_scalar::<f32>()?, 27.);
+    let exported = t.to_dlpack()?;
+    assert_eq!(unsafe { (*exported).dl_tensor.data }, data);
+    drop(t);
+    assert_eq!(DELETED.load(Ordering::SeqCst), 0);
+    unsafe { ((*exported).deleter.unwrap())(exported) };
+    assert_eq!(DELETED.load(Ordering::SeqCst), 1);
+
+    // In place operations copy the borrowed data first.
+    let managed = foreign_tensor(vec![1., 2., 3.], vec![3], vec![1]);
+    let t = unsafe { Tensor::from_dlpack(managed, &Device::Cpu)? };
+    t.affine_(2., 1.)?;
+    assert_eq!(t.to_vec1::<f32>()?, [3., 5., 7.]);
+    assert_eq!(DELETED.load(Ordering::SeqCst), 2);
+    drop(t);
+
+    // The producer is released on errors.
+    let managed = foreign_tensor(vec![1., 2.], vec![2], vec![-1]);
+    assert!(unsafe { Tensor::from_dlpack(managed, &Device::Cpu) }.is_err());
+    assert_eq!(DELETED.load(Ordering::SeqCst), 3);
+
+    let t = DLDataType {
+        code: DL_FLOAT,
+        bits: 32,
+        lanes: 4,
+    };
+    assert!(t.to_dtype().is_err());
+    Ok(())
+}
@@ -0,0 +1,116 @@
+use candle_core::{
+    test_device, test_utils::to_vec2_round, DType, Device, LazyTensor, Result, Tensor,
+};
+
+fn elementwise_chain(device: &Device) -> Result<()> {
+    // Use enough elements to cover multiple chunks, including a partial one.
+    let xs = Tensor::randn(0f32, 1., (3, 5000), device)?;
+    let bias = Tensor::randn(0f32, 1., 5000, device)?;
+    let ys = (xs.lazy() * 0.5)?
+        .broadcast_add(&bias.lazy())?
+        .gelu()?
+        .realize()?;
+    let expected = xs.affine(0.5, 0.)?.broadcast_add(&bias)?.gelu()?;
+    assert_eq!(ys.dims(), &[3, 5000]);
+    assert_eq!(to_vec2_round(&ys, 4)?, to_vec2_round(&expected, 4)?);
+
+    // Non-contiguous inputs and shared nodes.
+    let xs = xs.t()?;
+    let lazy = xs.lazy().exp()?;
+    let ys = (&lazy * &lazy)?.sub(&lazy.sqrt()?)?.realize()?;
+    let expected = ((xs.exp()? * xs.exp()?)? - xs.exp()?.sqrt()?)?;
+    assert_eq!(to_vec2_round(&ys, 3)?, to_vec2_round(&expected, 3)?);
+    Ok(())
+}
+
+fn realize_all(device: &Device) -> Result<()> {
+    let xs = Tensor::new(&[[1f32, -2.], [3., -4.]], device)?;
+    let lazy = xs.lazy().relu()?;
+    let (a, b) = (lazy.silu()?, (&lazy + 1.)?.log()?);
+    let ys = LazyTensor::realize_all(&[&a, &b, &xs.lazy()])?;
+    assert_eq!(
+        to_vec2_round(&ys[0], 4)?,
+        to_vec2_round(&xs.relu()?.silu()?, 4)?
+    );
+    assert_eq!(
+        to_vec2_round(&ys[1], 4)?,
+        to_vec2_round(&(xs.relu()? + 1.)?.log()?, 4)?
+    );
+    assert_eq!(ys[2].to_vec2::<f32>()?, xs.to_vec2::<f32>()?);
+    Ok(())
+}
+
+test_device!(elementwise_chain, chain_cpu, chain_cuda, chain_metal);
+test_device!(
+    realize_all,
+    realize_all_cpu,
+    realize_all_cuda,
+    realize_all_metal
+);
+
+#[test]
+fn constant_folding() -> Result<()> {
+    let dev = &Device::Cpu;
+    let c = LazyTensor::full(0., (2, 3), DType::F32, dev).exp()?;
+    let c = (&c + &LazyTensor::full(2., (2, 3), DType::F32, dev))?;
+    assert_eq!(c.realize()?.to_vec2::<f32>()?, [[3., 3., 3.], [3., 3., 3.]]);
+
+    // Scalar operations get merged: ((x * 2 + 1) - 3) * 0.5 = x - 1
+    let xs = Tensor::new(&[1f32, 2., 3.], dev)?;
+    let ys = ((((xs.lazy() * 2.)? + 1.)? - 3.)? * 0.5)?;
+    assert_eq!(ys.realize()?.to_vec1::<f32>()?, [0., 1., 2.]);
+    let ys = (xs.lazy() * 1.)?.realize()?;
+    assert_eq!(ys.id(), xs.id());
+    let ys = xs
+        .lazy()
+        .maximum(&LazyTensor::full(1.5, 3, DType::F32, dev))?
+        .realize()?;
+    assert_eq!(ys.to_vec1::<f32>()?, [1.5, 2., 3.]);
+    Ok(())
+}
+
+#[test]
+fn dtypes() -> Result<()> {
+    let dev = &Device::Cpu;
+    let xs = Tensor::new(&[[0.5f32, -1.5], [2.0, 4.0]], dev)?;
+    for dtype in [DType::F16, DType::BF16, DType::F64] {
+        let x = xs.to_dtype(dtype)?;
+        let ys = (x.lazy().tanh()? * 3.)?.realize()?;
+        assert_eq!(ys.dtype(), dtype);
+        let expected = (x.to_dtype(DType::F64)?.tanh()? * 3.)?;
+        assert_eq!(
+            to_vec2_round(&ys.to_dtype(DType::F32)?, 1)?,
+            to_vec2_round(&expected.to_dtype(DType::F32)?, 1)?
+        );
+    }
+    // Integer dtypes are not fused but binary operations are still supported.
+    let xs = Tensor::new(&[1u32, 2, 3], dev)?;
+    let ys = xs.lazy().mul(&xs.lazy())?.add(&xs.lazy())?.realize()?;
+    assert_eq!(ys.to_vec1::<u32>()?, [2, 6, 12]);
+    assert!(xs.lazy().exp().is_err());
+    assert!(xs.lazy().add(&xs.to_dtype(DType::U8)?.lazy()).is_err());
+    Ok(())
+}
+
+#[test]
+fn casts() -> Result<()> {
+    let dev = &Device::Cpu;
+    let xs = Tensor::new(&[[1.0001f32, -2.5], [3.3333, 1e-8]], dev)?;
+    let lazy = xs.lazy().to_dtype(DType::F16)?;
+    let ys = LazyTensor::realize_all(&[&(&lazy * 3.)?, &lazy.to_dtype(DType::F32)?])?;
+    let expected = (xs.to_dtype(DType::F16)? * 3.)?;
+    assert_eq!(ys[0].dtype(), DType::F16);
+    assert_eq!(
+        ys[0].to_vec2::<half::f16>()?,
+        expected.to_vec2::<half::f16>()?
+    );
+    assert_eq!(ys[1].dtype(), DType::F32);
+    assert_eq!(
+        ys[1].to_vec2::<f32>()?,
+        xs.to_dtype(DType::F16)?
+            .to_dtype(DType::F32)?
+            .to_vec2::<f32>()?
+    );
+    assert!(xs.lazy().to_dtype(DType::U32).is_err());
+    Ok(())
+}
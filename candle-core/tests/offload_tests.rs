@@ -0,0 +1,72 @@
+use anyhow::Result;
+use candle_core::{offload, test_device, Device, Tensor, Var};
+
+fn offload_roundtrip(device: &Device) -> Result<()> {
+    let w = Var::new(&[[1f32, 2.], [3., 4.]], device)?;
+    let xs = Tensor::new(&[[0.5f32, -1.], [2., 0.]], device)?;
+    let hs = xs.matmul(w.as_tensor())?.tanh()?;
+    // The reshaped tensor shares the storage of hs.
+    let ys = (hs.reshape(4)?.exp()? * 2.)?;
+    let expected = ys.sum_all()?.backward()?;
+    let expected = expected.get(&w).unwrap().to_vec2::<f32>()?;
+
+    let saved = offload::saved_tensors(&ys, &[&xs]);
+    assert!(saved.frontier.is_empty());
+    let ids: Vec<_> = saved.tensors.iter().map(|t| t.id()).collect();
+    assert!(!ids.contains(&w.id()));
+    assert!(!ids.contains(&xs.id()));
+    assert!(!ids.contains(&ys.id()));
+    assert_eq!(saved.tensors.len(), 3);
+
+    let mut offloaded = vec![];
+    for t in saved.tensors.iter() {
+        offloaded.extend(offload::offload(t)?)
+    }
+    if device.is_cuda() {
+        assert_eq!(offloaded.len(), 3);
+        assert!(offloaded.iter().any(|o| o.shares_storage(&hs)));
+        assert_eq!(
+            offloaded.iter().map(|o| o.bytes()).sum::<usize>(),
+            3 * 4 * 4
+        );
+        // Already offloaded.
+        assert!(offload::offload(&hs)?.is_none());
+    } else {
+        assert!(offloaded.is_empty());
+    }
+    for o in offloaded {
+        assert!(o.is_used());
+        o.reload()?
+    }
+    let grads = ys.sum_all()?.backward()?;
+    assert_eq!(grads.get(&w).unwrap().to_vec2::<f32>()?, expected);
+    Ok(())
+}
+
+#[test]
+fn saved_tensors_frontier() -> Result<()> {
+    let device = &Device::Cpu;
+    let w = Var::new(&[1f32, 2., 3.], device)?;
+    let mask = Tensor::new(&[1f32, 0., 1.], device)?;
+    let first = w.as_tensor().sqr()?;
+    let xs = first.exp()?;
+    // The second part reuses an activation of the first one.
+    let ys = ((xs.sin()? + &first)? * &mask)?;
+    let saved = offload::saved_tensors(&ys, &[&xs]);
+    assert_eq!(saved.frontier.len(), 1);
+    assert_eq!(saved.frontier[0].id(), first.id());
+    assert_eq!(saved.tensors.len(), 2);
+
+    // Without inputs, the whole graph up to the variables is walked.
+    let saved = offload::saved_tensors(&ys, &[]);
+    assert!(saved.frontier.is_empty());
+    assert_eq!(saved.tensors.len(), 4);
+    Ok(())
+}
+
+test_device!(
+    offload_roundtrip,
+    offload_roundtrip_cpu,
+    offload_roundtrip_gpu,
+    offload_roundtrip_metal
+);
@@ -0,0 +1,49 @@
+use candle_core::{profiler, DType, Device, Result, Tensor};
+
+// The profiler state is global so everything is checked in a single test.
+#[test]
+fn profiler() -> Result<()> {
+    let dev = &Device::Cpu;
+    let a = Tensor::arange(0f32, 6., dev)?.reshape((2, 3))?;
+    let b = Tensor::ones((3, 4), DType::F32, dev)?;
+    let _ = a.exp()?;
+    assert!(!profiler::is_enabled());
+    assert!(profiler::scope("ignored").is_none());
+
+    profiler::start();
+    let c = {
+        let _scope = profiler::scope("block");
+        (a.matmul(&b)? + 1.)?.relu()?
+    };
+    let _ = c.sum_all()?;
+    let profile = profiler::stop();
+    assert!(!profiler::is_enabled());
+
+    let names: Vec<_> = profile.events.iter().map(|e| e.name.as_str()).collect();
+    assert_eq!(names, ["block", "matmul", "affine", "relu", "sum"]);
+    let matmul = &profile.events[1];
+    assert_eq!(matmul.kind, profiler::EventKind::Op);
+    assert_eq!(matmul.shapes, [(2, 3).into(), (3, 4).into()]);
+    assert_eq!(matmul.dtype, Some(DType::F32));
+    assert_eq!(matmul.device, Some(candle_core::DeviceLocation::Cpu));
+    assert_eq!(matmul.device_time, None);
+    let block = &profile.events[0];
+    assert_eq!(block.kind, profiler::EventKind::Scope);
+    assert!(block.start <= matmul.start && block.wall_time >= matmul.wall_time);
+
+    let summary = profile.summary();
+    assert_eq!(summary.len(), 4);
+    assert!(summary.iter().all(|s| s.calls == 1));
+    let table = profile.summary_table();
+    assert!(table.starts_with("op "), "{table}");
+    assert_eq!(table.lines().count(), 5);
+
+    let trace = profile.chrome_trace();
+    assert!(trace.starts_with("{\"traceEvents\":[{\"name\":\"block\",\"cat\":\"scope\""));
+    assert!(trace.contains("\"shapes\":\"[2, 3] [3, 4]\",\"dtype\":\"f32\",\"device\":\"cpu\""));
+
+    // Nothing gets recorded once the profiler is stopped.
+    let _ = a.exp()?;
+    assert!(profiler::stop().events.is_empty());
+    Ok(())
+}
@@ -215,7 +215,24 @@ fn qmm_batch(dev: &Device) -> Result<()> {
     Ok(())
 }
 
+// The gradient of the input goes through the dequantized weight, the weight itself is frozen.
+fn qmm_backward(dev: &Device) -> Result<()> {
+    let (lhs, rhs, _mm) = get_random_tensors(3, 256, 6, dev)?;
+    let qrhs = quantized::QTensor::quantize(&rhs, GgmlDType::Nf4)?;
+    let w = qrhs.dequantize(dev)?;
+    let qmm = quantized::QMatMul::from_qtensor(qrhs)?;
+    let lhs = candle_core::Var::from_tensor(&lhs.reshape((1, 3, 256))?)?;
+    let ys = qmm.forward(&lhs)?;
+    let grad = (&ys * &ys)?.sum_all()?.backward()?;
+    let grad = grad.get(&lhs).unwrap();
+    let expected = ((&ys * 2.)?.broadcast_matmul(&w)?).reshape((1, 3, 256))?;
+    let diff = (grad - expected)?.abs()?.max_all()?.to_vec0::<f32>()?;
+    assert!(diff < 1e-3, "{diff}");
+    Ok(())
+}
+
 test_device!(quantized_matmul, qmm_cpu, qmm_cuda, qmm_metal);
+test_device!(qmm_backward, qmm_bwd_cpu, qmm_bwd_cuda, qmm_bwd_metal);
 test_device!(quantized_matmul_neg, qmm_n_cpu, qmm_n_cuda, qmm_n_metal);
 test_device!(qmm_batch, qmm_b_cpu, qmm_b_cuda, qmm_b_metal);
 
@@ -716,6 +733,165 @@ fn quantize_q8k(device: &Device) -> Result<()> {
     Ok(())
 }
 
+fn quantize_iq2_xxs(device: &Device) -> Result<()> {
+    let dtype = GgmlDType::Iq2Xxs;
+    let src = get_test_vector2(0.5, 1024, device)?;
+    let quant = quantized::QTensor::quantize(&src, dtype)?;
+    let dst = quant.dequantize(device)?;
+    let dst_f16 = quant.dequantize_f16(device)?;
+    let diff = (dst.to_dtype(DType::F16)? - dst_f16)?
+        .to_dtype(DType::F32)?
+        .abs()?
+        .sum_all()?
+        .to_vec0::<f32>()?;
+    assert_eq!(diff, 0.);
+
+    let src = src.to_vec1::<f32>()?;
+    let dst = dst.to_vec1::<f32>()?;
+    compare_with_error(dst.as_slice(), src.as_slice(), 0.4);
+
+    // Groups of 8 values that are lattice points with an even number of negative values are
+    // represented exactly when the groups share the same scale.
+    let points: [[f32; 8]; 4] = [
+        [8., 8., 8., 8., 8., 8., 8., 8.],
+        [43., 8., 8., 8., 8., 8., 8., 8.],
+        [25., 25., 8., 8., 8., 8., 8., 8.],
+        [8., 43., 43., 8., 8., 8., 8., 8.],
+    ];
+    let src = (0..32)
+        .flat_map(|i| {
+            let sign = move |j: usize| if i % 2 == 0 && j < 2 { -1. } else { 1. };
+            let point = points[i % 4];
+            (0..8).map(move |j| sign(j) * point[j] / 64.)
+        })
+        .collect::<Vec<_>>();
+    let quant = quantized::QTensor::quantize(&Tensor::new(src.as_slice(), device)?, dtype)?;
+    let dst = quant.dequantize(device)?.to_vec1::<f32>()?;
+    compare_with_error(dst.as_slice(), src.as_slice(), 1e-3);
+
+    ggml_quantization_error_test(dtype, device, GGML_MAX_QUANTIZATION_TOTAL_ERROR_2BITS)?;
+    Ok(())
+}
+
+fn quantize_iq4_nl(device: &Device) -> Result<()> {
+    let dtype = GgmlDType::Iq4Nl;
+    let src = get_test_vector2(0.5, 1024, device)?;
+    let quant = quantized::QTensor::quantize(&src, dtype)?;
+    let dst = quant.dequantize(device)?;
+    let dst_f16 = quant.dequantize_f16(device)?;
+    let diff = (dst.to_dtype(DType::F16)? - dst_f16)?
+        .to_dtype(DType::F32)?
+        .abs()?
+        .sum_all()?
+        .to_vec0::<f32>()?;
+    assert_eq!(diff, 0.);
+
+    let src = src.to_vec1::<f32>()?;
+    let dst = dst.to_vec1::<f32>()?;
+    compare_with_error(dst.as_slice(), src.as_slice(), 0.03);
+
+    // The values are stored as indexes in the non-linear grid, so a block made of grid values
+    // is represented exactly.
+    let grid = [
+        -127f32, -104., -83., -65., -49., -35., -22., -10., 1., 13., 25., 38., 53., 69., 89., 113.,
+    ];
+    let src = grid
+        .iter()
+        .chain(grid.iter())
+        .map(|v| v / 64.)
+        .collect::<Vec<_>>();
+    let quant = quantized::QTensor::quantize(&Tensor::new(src.as_slice(), device)?, dtype)?;
+    let dst = quant.dequantize(device)?.to_vec1::<f32>()?;
+    compare_with_error(dst.as_slice(), src.as_slice(), 1e-3);
+
+    ggml_quantization_error_test(dtype, device, GGML_MAX_QUANTIZATION_TOTAL_ERROR)?;
+    Ok(())
+}
+
+fn quantize_nf4(device: &Device) -> Result<()> {
+    let dtype = GgmlDType::Nf4;
+    let src = get_test_vector2(0.5, 1024, device)?;
+    let quant = quantized::QTensor::quantize(&src, dtype)?;
+    assert_eq!(quant.storage_size_in_bytes(), 4 * 136);
+    let dst = quant.dequantize(device)?;
+    let dst_f16 = quant.dequantize_f16(device)?;
+    let diff = (dst.to_dtype(DType::F16)? - dst_f16)?
+        .to_dtype(DType::F32)?
+        .abs()?
+        .sum_all()?
+        .to_vec0::<f32>()?;
+    assert_eq!(diff, 0.);
+
+    let src = src.to_vec1::<f32>()?;
+    let dst = dst.to_vec1::<f32>()?;
+    // The largest gap in the NF4 table is between 0.723 and 1.
+    compare_with_error(dst.as_slice(), src.as_slice(), 0.07);
+
+    // The extremes of each block of 64 values are represented exactly as they are the absmax.
+    for (s, d) in src.chunks(64).zip(dst.chunks(64)) {
+        let i = (0..64)
+            .max_by(|&i, &j| s[i].abs().total_cmp(&s[j].abs()))
+            .unwrap();
+        assert!((s[i] - d[i]).abs() < 1e-3, "{} {}", s[i], d[i]);
+    }
+
+    // A block keeps its precision next to a block with a much larger range.
+    let src: Vec<f32> = (0..256)
+        .map(|i| {
+            let v = (i % 64) as f32 / 32. - 1.;
+            if (64..128).contains(&i) {
+                v * 1e-3
+            } else {
+                v
+            }
+        })
+        .collect();
+    let quant = quantized::QTensor::quantize(&Tensor::new(src.as_slice(), device)?, dtype)?;
+    let dst = quant.dequantize(device)?.to_vec1::<f32>()?;
+    for (s, d) in src[64..128].iter().zip(dst[64..128].iter()) {
+        assert!((s - d).abs() < 0.15e-3, "{s} {d}");
+    }
+
+    // The NF4 grid is tailored for normally distributed weights rather than the uniformly
+    // distributed test values.
+    ggml_quantization_error_test(dtype, device, 0.0025)?;
+    Ok(())
+}
+
+#[test]
+fn nf4_from_bnb() -> Result<()> {
+    use quantized::bnb::{nf4_from_bnb, NestedAbsmax};
+    let cpu = &Device::Cpu;
+    let codes: Vec<u8> = (0..512).map(|i| (i * 7 % 16) as u8).collect();
+    let packed: Vec<u8> = codes.chunks(2).map(|c| (c[0] << 4) | c[1]).collect();
+    // Two blocks of 256 absmax values with a linear code.
+    let quant_map: Vec<f32> = (0..256).map(|i| i as f32 / 255.).collect();
+    let nested = NestedAbsmax {
+        absmax: Tensor::new(&[255u8, 128, 64, 0, 17, 255, 1, 2], cpu)?,
+        nested_absmax: Tensor::new(&[0.5f32], cpu)?,
+        nested_quant_map: Tensor::new(quant_map.as_slice(), cpu)?,
+        nested_offset: 0.25,
+        blocksize: 256,
+    };
+    let absmax = nested.dequantize()?.to_vec1::<f32>()?;
+    assert_eq!(absmax[0], 0.75);
+    assert_eq!(absmax[3], 0.25);
+    let packed = Tensor::new(packed.as_slice(), cpu)?;
+    let qtensor = nf4_from_bnb(&packed, &nested.dequantize()?, (2, 256), cpu)?;
+    assert_eq!(qtensor.dtype(), GgmlDType::Nf4);
+    let values = qtensor.dequantize(cpu)?.flatten_all()?.to_vec1::<f32>()?;
+    let nf4 = [
+        -1.0f32, -0.6962, -0.5251, -0.3949, -0.2844, -0.1848, -0.0911, 0.0, 0.0796, 0.1609, 0.2461,
+        0.3379, 0.4407, 0.5626, 0.7230, 1.0,
+    ];
+    for (i, (&v, &c)) in values.iter().zip(codes.iter()).enumerate() {
+        let expected = nf4[c as usize] * absmax[i / 64];
+        assert!((v - expected).abs() < 2e-3, "{i} {v} {expected}");
+    }
+    assert!(nf4_from_bnb(&packed, &nested.dequantize()?, (4, 128), cpu).is_err());
+    Ok(())
+}
+
 test_device!(
     quantize_q4_0,
     quantize_q4_0_cpu,
@@ -776,6 +952,24 @@ test_device!(
     quantize_q8k_cuda,
     quantize_q8k_metal
 );
+test_device!(
+    quantize_iq2_xxs,
+    quantize_iq2_xxs_cpu,
+    quantize_iq2_xxs_cuda,
+    quantize_iq2_xxs_metal
+);
+test_device!(
+    quantize_iq4_nl,
+    quantize_iq4_nl_cpu,
+    quantize_iq4_nl_cuda,
+    quantize_iq4_nl_metal
+);
+test_device!(
+    quantize_nf4,
+    quantize_nf4_cpu,
+    quantize_nf4_cuda,
+    quantize_nf4_metal
+);
 
 /// Very simple dot product implementation
 fn vec_dot_reference(a: &[f32], b: &[f32]) -> f32 {
@@ -799,6 +993,7 @@ fn ggml_reference_matmul_error(dtype: GgmlDType) -> Result<f32> {
 
         // Not from the ggml repo.
         GgmlDType::Q8K => 0.00065,
+        GgmlDType::Iq4Nl => 0.00245,
         _ => bail!("No GGML results for quantization type {dtype:?}",),
     };
     Ok(err)
@@ -867,6 +1062,7 @@ fn quantized_mm() -> Result<()> {
     ggml_matmul_error_test::<k_quants::BlockQ5_0>()?;
     ggml_matmul_error_test::<k_quants::BlockQ5_1>()?;
     ggml_matmul_error_test::<k_quants::BlockQ8_0>()?;
+    ggml_matmul_error_test::<k_quants::BlockIQ4nl>()?;
     Ok(())
 }
 
@@ -986,6 +1182,95 @@ quantized_matmul!(
     quantized_matmul_q6k_metal,
     GgmlDType::Q6K
 );
+// Not implemented on metal
+// quantized_matmul!(
+//     quantized_matmul_iq4_nl_bis,
+//     quantized_matmul_iq4_nl_cpu,
+//     quantized_matmul_iq4_nl_cuda,
+//     quantized_matmul_iq4_nl_metal,
+//     GgmlDType::Iq4Nl
+// );
+
+#[test]
+fn quantized_matmul_nf4() -> Result<()> {
+    let cpu = &Device::Cpu;
+    test_matmul(cpu, (1, 3, 4, 256), GgmlDType::Nf4)?;
+    let (m, k, n) = (11, 512, 21);
+    let (lhs, rhs, _mm) = get_random_tensors(m, k, n, cpu)?;
+    let rhs = quantized::QTensor::quantize(&rhs, GgmlDType::Nf4)?;
+    let dequantized = lhs.matmul(&rhs.dequantize(cpu)?.t()?)?;
+    let matmul = quantized::QMatMul::from_qtensor(rhs)?;
+    let res = matmul.forward(&lhs)?;
+    let diff = (&res - &dequantized)?
+        .abs()?
+        .max_all()?
+        .to_scalar::<f32>()?;
+    assert!(diff < 0.05, "{diff}");
+    Ok(())
+}
+
+#[test]
+fn quantized_matmul_iq2_xxs() -> Result<()> {
+    let cpu = &Device::Cpu;
+    let (m, k, n) = (11, 512, 21);
+    let (lhs, rhs, mm) = get_random_tensors(m, k, n, cpu)?;
+    // The 2 bits types are not checked against the ggml errors, ggml only quantizes them with an
+    // importance matrix. The vec-dot has to match the dot product with the dequantized weights.
+    let a = rhs.narrow(0, 0, 1)?.flatten_all()?.to_vec1::<f32>()?;
+    let b = lhs.narrow(0, 0, 1)?.flatten_all()?.to_vec1::<f32>()?;
+    let mut a_quant = vec![k_quants::BlockIQ2xxs::zeros(); k / 256];
+    let mut b_quant = vec![k_quants::BlockQ8K::zeros(); k / 256];
+    k_quants::BlockIQ2xxs::from_float(&a, &mut a_quant)?;
+    k_quants::BlockQ8K::from_float(&b, &mut b_quant)?;
+    let result = k_quants::BlockIQ2xxs::vec_dot(k, &a_quant, &b_quant)?;
+    let result_unopt = k_quants::BlockIQ2xxs::vec_dot_unopt(k, &a_quant, &b_quant)?;
+    assert!(
+        (result - result_unopt).abs() < 1e-4,
+        "{result} {result_unopt}"
+    );
+    let mut a_dequant = vec![0f32; k];
+    let mut b_dequant = vec![0f32; k];
+    k_quants::BlockIQ2xxs::to_float(&a_quant, &mut a_dequant)?;
+    k_quants::BlockQ8K::to_float(&b_quant, &mut b_dequant)?;
+    let expected = vec_dot_reference(&a_dequant, &b_dequant);
+    assert!((result - expected).abs() < 1e-3, "{result} {expected}");
+
+    let rhs = quantized::QTensor::quantize(&rhs, GgmlDType::Iq2Xxs)?;
+    let dequantized = lhs.matmul(&rhs.dequantize(cpu)?.t()?)?;
+    let matmul = quantized::QMatMul::from_qtensor(rhs)?;
+    let res = matmul.forward(&lhs)?;
+    let diff = (&res - &dequantized)?
+        .abs()?
+        .max_all()?
+        .to_scalar::<f32>()?;
+    assert!(diff < 0.05, "{diff}");
+    let diff = (&res - &mm)?.abs()?.max_all()?.to_scalar::<f32>()?;
+    assert!(diff < 2., "{diff}");
+    Ok(())
+}
+
+#[test]
+fn quantized_matmul_iq4_nl() -> Result<()> {
+    let cpu = &Device::Cpu;
+    test_matmul(cpu, (1, 3, 4, 256), GgmlDType::Iq4Nl)?;
+    let (m, k, n) = (11, 512, 21);
+    let (lhs, rhs, mm) = get_random_tensors(m, k, n, cpu)?;
+    let rhs = quantized::QTensor::quantize(&rhs, GgmlDType::Iq4Nl)?;
+    // The quantized matmul should match the matmul with the dequantized weights up to the
+    // quantization of the lhs to q8_0.
+    let dequantized = lhs.matmul(&rhs.dequantize(cpu)?.t()?)?;
+    let matmul = quantized::QMatMul::from_qtensor(rhs)?;
+    let res = matmul.forward(&lhs)?;
+    let diff = (&res - &dequantized)?
+        .abs()?
+        .max_all()?
+        .to_scalar::<f32>()?;
+    assert!(diff < 0.05, "{diff}");
+    let diff = (&res - &mm)?.abs()?.max_all()?.to_scalar::<f32>()?;
+    assert!(diff < 0.5, "{diff}");
+    Ok(())
+}
+
 // Not implemented on metal
 // quantized_matmul!(
 //     quantized_matmul_q8k_bis,
@@ -1149,3 +1434,158 @@ fn quantized_matmul_q8k() -> Result<()> {
     ggml_matmul_error_test::<BlockQ8K>()?;
     Ok(())
 }
+
+#[test]
+fn shared_mapping() -> Result<()> {
+    use quantized::{gguf_file, shared::SharedMapping, QMatMul};
+
+    let dev = &Device::Cpu;
+    let xs = Tensor::randn(0f32, 1., (64, 128), dev)?;
+    let q4 = quantized::QTensor::quantize(&xs, GgmlDType::Q4_0)?;
+    let q8 = quantized::QTensor::quantize(&xs.t()?.contiguous()?, GgmlDType::Q8_0)?;
+
+    let dir = std::env::temp_dir().join(format!("candle-shm-test-{}", std::process::id()));
+    std::fs::create_dir_all(&dir)?;
+    let src = dir.join("weights.gguf");
+    let mut file = std::fs::File::create(&src)?;
+    gguf_file::write(&mut file, &[], &[("q4", &q4), ("q8", &q8)])?;
+    drop(file);
+
+    let m1 = unsafe { SharedMapping::open_or_create_in(&dir, "model", &src)? };
+    let m2 = unsafe { SharedMapping::open_or_create_in(&dir, "model", &src)? };
+    assert_eq!(m1.as_bytes(), m2.as_bytes());
+
+    let mut cursor = std::io::Cursor::new(m2.as_bytes());
+    let content = gguf_file::Content::read(&mut cursor)?;
+    let shared_q4 = content.tensor_from_mapping(&m2, "q4", dev)?;
+    let shared_q8 = content.tensor_from_mapping(&m2, "q8", dev)?;
+    assert!(content.tensor_from_mapping(&m2, "q16", dev).is_err());
+    assert_eq!(shared_q4.dtype(), GgmlDType::Q4_0);
+    assert_eq!(shared_q4.shape().dims(), &[64, 128]);
+    assert_eq!(shared_q4.data()?, q4.data()?);
+    assert_eq!(shared_q8.data()?, q8.data()?);
+
+    let lhs = Tensor::randn(0f32, 1., (3, 128), dev)?;
+    let expected = QMatMul::from_qtensor(q4)?.forward(&lhs)?;
+    // The mapping is kept alive by the tensor after being removed.
+    m1.remove()?;
+    drop((m1, m2));
+    let ys = QMatMul::from_qtensor(shared_q4)?.forward(&lhs)?;
+    assert_eq!(ys.to_vec2::<f32>()?, expected.to_vec2::<f32>()?);
+
+    std::fs::remove_dir_all(&dir)?;
+    Ok(())
+}
+
+#[test]
+fn gguf_write_roundtrip() -> Result<()> {
+    use quantized::gguf_file;
+
+    let dev = &Device::Cpu;
+    let xs = Tensor::randn(0f32, 1., (4, 256), dev)?;
+    let dtypes = [
+        GgmlDType::Q4_0,
+        GgmlDType::Q4K,
+        GgmlDType::Q5K,
+        GgmlDType::Q6K,
+        GgmlDType::Q8_0,
+        GgmlDType::Iq2Xxs,
+        GgmlDType::Iq4Nl,
+    ];
+    let names: Vec<String> = dtypes.iter().map(|d| format!("{d:?}")).collect();
+    let qtensors = dtypes
+        .iter()
+        .map(|&d| quantized::QTensor::quantize(&xs, d))
+        .collect::<Result<Vec<_>>>()?;
+    let tensors: Vec<_> = names
+        .iter()
+        .map(|n| n.as_str())
+        .zip(qtensors.iter())
+        .collect();
+    let arch = gguf_file::Value::String("test".to_string());
+    let mut buffer = std::io::Cursor::new(vec![]);
+    gguf_file::write(&mut buffer, &[("general.architecture", &arch)], &tensors)?;
+
+    buffer.set_position(0);
+    let content = gguf_file::Content::read(&mut buffer)?;
+    assert_eq!(
+        content.metadata["general.architecture"].to_string()?,
+        "test"
+    );
+    for ((name, qtensor), dtype) in tensors.iter().zip(dtypes) {
+        let read = content.tensor(&mut buffer, name, dev)?;
+        assert_eq!(read.dtype(), dtype);
+        assert_eq!(read.shape().dims(), &[4, 256]);
+        assert_eq!(read.data()?, qtensor.data()?);
+        // The quantization error stays small compared to the unit variance of the values.
+        let max_err = if dtype == GgmlDType::Iq2Xxs {
+            0.15
+        } else {
+            0.02
+        };
+        let err = (read.dequantize(dev)? - &xs)?.sqr()?.mean_all()?;
+        assert!(err.to_scalar::<f32>()? < max_err, "{dtype:?}");
+    }
+    Ok(())
+}
+
+#[test]
+fn f16_saturating_cast() -> Result<()> {
+    use quantized::f16_cast::{cast_to_f16, write_gguf_f16, CastOptions, SCALE_KEY_PREFIX};
+    use quantized::gguf_file;
+    let dev = &Device::Cpu;
+    let values = [1.5f32, -1e5, 7e4, 1e-9, 1e-6, 0., f32::INFINITY, 6e4];
+    let xs = Tensor::new(&values, dev)?.to_dtype(DType::BF16)?;
+    let (ys, stats) = cast_to_f16(&xs, &CastOptions::default())?;
+    assert_eq!(ys.dtype(), DType::F16);
+    assert_eq!(stats.elem_count, 8);
+    assert_eq!((stats.overflow, stats.saturated), (2, 2));
+    assert_eq!(
+        (stats.underflow, stats.subnormal, stats.non_finite),
+        (1, 1, 1)
+    );
+    assert_eq!(stats.scale, 1.);
+    assert!(stats.is_lossy());
+    let ys = ys.to_dtype(DType::F32)?.to_vec1::<f32>()?;
+    assert_eq!(ys[..3], [1.5, -65504., 65504.]);
+    assert_eq!(ys[3], 0.);
+    assert!(ys[6].is_infinite());
+    // A plain cast saturates the large values too.
+    let plain = xs
+        .to_dtype(DType::F16)?
+        .to_dtype(DType::F32)?
+        .to_vec1::<f32>()?;
+    assert_eq!(plain, ys);
+
+    let options = CastOptions { rescale: true };
+    let (ys, stats) = cast_to_f16(&xs, &options)?;
+    assert_eq!((stats.overflow, stats.saturated), (2, 0));
+    assert_eq!(stats.scale, 2.);
+    let ys = (ys.to_dtype(DType::F32)? * stats.scale as f64)?.to_vec1::<f32>()?;
+    let expected = xs.to_dtype(DType::F32)?.to_vec1::<f32>()?;
+    assert_eq!(ys[..3], expected[..3]);
+
+    let ids = Tensor::new(&[1u32, 2, 3], dev)?;
+    let weight = (Tensor::randn(0f32, 1., (4, 32), dev)? * 1e5)?.to_dtype(DType::BF16)?;
+    let mut buffer = std::io::Cursor::new(vec![]);
+    let tensors = [("ids", &ids), ("weight", &weight), ("xs", &xs)];
+    let report = write_gguf_f16(&mut buffer, &[], &tensors, &options)?;
+    // The 1d tensors are kept in f32.
+    assert_eq!(report.len(), 1);
+    buffer.set_position(0);
+    let content = gguf_file::Content::read(&mut buffer)?;
+    let scale = content.metadata[&format!("{SCALE_KEY_PREFIX}weight")].to_f32()?;
+    assert_eq!(scale, report[0].1.scale);
+    assert!(scale > 1.);
+    // The scale is applied when loading the tensor.
+    let read = content.tensor(&mut buffer, "weight", dev)?;
+    assert_eq!(read.dtype(), GgmlDType::F32);
+    let read = read.dequantize(dev)?;
+    let diff = (read - weight.to_dtype(DType::F32)?)?.abs()?.max_all()?;
+    assert!(diff.to_scalar::<f32>()? < 1e3);
+    assert_eq!(
+        content.tensor(&mut buffer, "ids", dev)?.dtype(),
+        GgmlDType::F32
+    );
+    Ok(())
+}
@@ -0,0 +1,84 @@
+use candle_core::rng::{philox4x32_10, philox_block, uniform_f32, RngState};
+use candle_core::{test_device, DType, Device, Result, Tensor};
+
+#[test]
+fn philox_known_answers() {
+    // Test vectors from the Random123 library.
+    assert_eq!(
+        philox4x32_10([0; 4], [0; 2]),
+        [0x6627e8d5, 0xe169c58d, 0xbc57ac4c, 0x9b00dbd8]
+    );
+    assert_eq!(
+        philox4x32_10([u32::MAX; 4], [u32::MAX; 2]),
+        [0x408f276d, 0x41c83b0e, 0xa20bc7c6, 0x6d5451fd]
+    );
+    assert_eq!(
+        philox4x32_10(
+            [0x243f6a88, 0x85a308d3, 0x13198a2e, 0x03707344],
+            [0xa4093822, 0x299f31d0]
+        ),
+        [0xd16cfe09, 0x94fdcceb, 0x5001e420, 0x24126ea1]
+    );
+}
+
+// All the checks of a device are done in a single test as the cpu generator is global.
+fn rng(device: &Device) -> Result<()> {
+    device.set_seed(1337)?;
+    assert_eq!(device.rng_state()?, RngState::new(1337));
+    let xs = Tensor::rand(-1f32, 1f32, (2, 5), device)?;
+    // 10 values use three blocks of four values.
+    assert_eq!(
+        device.rng_state()?,
+        RngState {
+            seed: 1337,
+            offset: 3
+        }
+    );
+
+    // The values only depend on the seed and offset, so they match the reference ones for all
+    // the devices.
+    let expected: Vec<f32> = (0..10)
+        .map(|i| {
+            let x = philox_block(1337, i / 4)[i as usize % 4];
+            uniform_f32(x).mul_add(2., -1.)
+        })
+        .collect();
+    assert_eq!(xs.flatten_all()?.to_vec1::<f32>()?, expected);
+
+    // Restoring the state generates the same values again.
+    let state = device.rng_state()?;
+    let ys = Tensor::randn(0f32, 1f32, 7, device)?.to_vec1::<f32>()?;
+    let zs = Tensor::rand(0f32, 1f32, 7, device)?.to_vec1::<f32>()?;
+    device.set_rng_state(state)?;
+    assert_eq!(Tensor::randn(0f32, 1f32, 7, device)?.to_vec1::<f32>()?, ys);
+    assert_eq!(Tensor::rand(0f32, 1f32, 7, device)?.to_vec1::<f32>()?, zs);
+
+    // Generating multiples of four values gives the same stream regardless of the split.
+    device.set_seed(0)?;
+    let all = Tensor::rand(0f32, 1f32, 8, device)?.to_vec1::<f32>()?;
+    device.set_seed(0)?;
+    let mut split = Tensor::rand(0f32, 1f32, 4, device)?.to_vec1::<f32>()?;
+    split.extend(Tensor::rand(0f32, 1f32, 4, device)?.to_vec1::<f32>()?);
+    assert_eq!(all, split);
+
+    // The normal distribution has the expected moments.
+    let xs = Tensor::randn(3f32, 2f32, 100_000, device)?;
+    let mean = xs.mean_all()?.to_scalar::<f32>()?;
+    let std = xs
+        .broadcast_sub(&xs.mean_all()?)?
+        .sqr()?
+        .mean_all()?
+        .sqrt()?;
+    assert!((mean - 3.).abs() < 0.02, "{mean}");
+    assert!((std.to_scalar::<f32>()? - 2.).abs() < 0.02, "{std}");
+
+    // Half precision values are rounded from the f32 ones.
+    device.set_seed(5)?;
+    let xs = Tensor::rand(0f32, 1f32, 6, device)?.to_dtype(DType::BF16)?;
+    device.set_seed(5)?;
+    let ys = Tensor::rand(half::bf16::ZERO, half::bf16::ONE, 6, device)?;
+    assert_eq!(xs.to_vec1::<half::bf16>()?, ys.to_vec1::<half::bf16>()?);
+    Ok(())
+}
+
+test_device!(rng, rng_cpu, rng_gpu, rng_metal);
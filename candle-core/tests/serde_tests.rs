@@ -0,0 +1,112 @@
+#![cfg(feature = "serde")]
+use anyhow::Result;
+use candle_core::tensor_serde::Encoding;
+use candle_core::{DType, Device, Tensor};
+
+#[derive(serde::Serialize, serde::Deserialize)]
+struct Config {
+    bias: Tensor,
+    #[serde(with = "candle_core::tensor_serde::array")]
+    rotation: Tensor,
+    dtype: DType,
+}
+
+#[test]
+fn json() -> Result<()> {
+    let dev = &Device::Cpu;
+    let config = Config {
+        bias: Tensor::new(&[1f32, 2.], dev)?,
+        rotation: Tensor::new(&[[1u32, 0], [0, 1]], dev)?,
+        dtype: DType::BF16,
+    };
+    let json = serde_json::to_string(&config)?;
+    assert_eq!(
+        json,
+        r#"{"bias":{"dtype":"f32","shape":[2],"data":"AACAPwAAAEA="},"rotation":{"dtype":"u32","shape":[2,2],"data":[[1,0],[0,1]]},"dtype":"bf16"}"#
+    );
+    let config: Config = serde_json::from_str(&json)?;
+    assert_eq!(config.bias.to_vec1::<f32>()?, [1., 2.]);
+    assert_eq!(config.rotation.to_vec2::<u32>()?, [[1, 0], [0, 1]]);
+    assert_eq!(config.dtype, DType::BF16);
+
+    // All the encodings are accepted, the shape can be inferred from nested arrays.
+    let t: Tensor = serde_json::from_str(r#"{"data": [[1, 2.5], [3, 4]], "dtype": "f16"}"#)?;
+    assert_eq!(t.dtype(), DType::F16);
+    assert_eq!(
+        t.to_dtype(DType::F32)?.to_vec2::<f32>()?,
+        [[1., 2.5], [3., 4.]]
+    );
+    let t: Tensor =
+        serde_json::from_str(r#"{"dtype": "i64", "shape": [2, 2], "data": [1, 2, 3, -4]}"#)?;
+    assert_eq!(t.to_vec2::<i64>()?, [[1, 2], [3, -4]]);
+    let t: Tensor = serde_json::from_str(r#"{"dtype": "f64", "shape": [], "data": 0.5}"#)?;
+    assert_eq!(t.to_vec0::<f64>()?, 0.5);
+    let t: Tensor = serde_json::from_str(r#"{"dtype": "u8", "data": []}"#)?;
+    assert_eq!(t.dims(), [0]);
+
+    for invalid in [
+        r#"{"dtype": "u8", "data": [1, 256]}"#,
+        r#"{"dtype": "u32", "data": [1.5]}"#,
+        r#"{"dtype": "f32", "data": [[1, 2], [3]]}"#,
+        r#"{"dtype": "f32", "data": [[1, 2], 3]}"#,
+        r#"{"dtype": "f32", "shape": [3], "data": [1, 2]}"#,
+        r#"{"dtype": "f32", "shape": [3], "data": "AACAPwAAAEA="}"#,
+        r#"{"dtype": "f8", "data": [1]}"#,
+        r#"{"data": [1]}"#,
+    ] {
+        assert!(
+            serde_json::from_str::<Tensor>(invalid).is_err(),
+            "{invalid}"
+        );
+    }
+    Ok(())
+}
+
+fn assert_same(a: &Tensor, b: &Tensor) -> Result<()> {
+    assert_eq!(a.dtype(), b.dtype());
+    assert_eq!(a.dims(), b.dims());
+    let a = a.flatten_all()?.to_dtype(DType::F64)?.to_vec1::<f64>()?;
+    let b = b.flatten_all()?.to_dtype(DType::F64)?.to_vec1::<f64>()?;
+    assert_eq!(a, b);
+    Ok(())
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+struct Binary {
+    raw: Tensor,
+    #[serde(with = "candle_core::tensor_serde::array")]
+    array: Tensor,
+    #[serde(with = "candle_core::tensor_serde::base64")]
+    base64: Tensor,
+}
+
+#[test]
+fn encodings() -> Result<()> {
+    let dev = &Device::Cpu;
+    let tensors = [
+        Tensor::arange(0u8, 6, dev)?.reshape((2, 3))?,
+        Tensor::new(&[[-1i64, 1 << 40]], dev)?,
+        Tensor::new(&[1.5f32, -2.], dev)?.to_dtype(DType::BF16)?,
+        Tensor::new(3.25f64, dev)?,
+        Tensor::zeros((2, 0, 3), DType::F32, dev)?,
+        Tensor::new(&[7u32, 8], dev)?.broadcast_as((2, 2))?,
+    ];
+    for tensor in tensors.iter() {
+        for encoding in [Encoding::Array, Encoding::Base64] {
+            let json = serde_json::to_string(&tensor.serialize_as(encoding))?;
+            assert_same(&serde_json::from_str(&json)?, tensor)?;
+        }
+        // A format that is not self-describing.
+        let binary = Binary {
+            raw: tensor.clone(),
+            array: tensor.clone(),
+            base64: tensor.clone(),
+        };
+        let bytes = bincode::serialize(&binary)?;
+        let binary: Binary = bincode::deserialize(&bytes)?;
+        assert_same(&binary.raw, tensor)?;
+        assert_same(&binary.array, tensor)?;
+        assert_same(&binary.base64, tensor)?;
+    }
+    Ok(())
+}
@@ -49,6 +49,70 @@ fn npz() -> Result<()> {
     Ok(())
 }
 
+#[test]
+fn npz_write() -> Result<()> {
+    use candle_core::Device;
+
+    let tmp_file = TmpFile::create("npz");
+    let x = Tensor::arange(0f32, 6f32, &Device::Cpu)?.reshape((2, 3))?;
+    let y = Tensor::new(&[3u32, 1, 4], &Device::Cpu)?;
+    candle_core::npy::write_npz(&tmp_file, &[("x", &x), ("y", &y)])?;
+    let npz = Tensor::read_npz(&tmp_file)?;
+    assert_eq!(npz[0].0, "x");
+    assert_eq!(npz[0].1.to_vec2::<f32>()?, [[0., 1., 2.], [3., 4., 5.]]);
+    assert_eq!(npz[1].1.to_vec1::<u32>()?, [3, 1, 4]);
+    Ok(())
+}
+
+#[test]
+fn npy_streaming() -> Result<()> {
+    use candle_core::npy::{NpyWriter, NpzWriter};
+    use candle_core::Device;
+
+    let tmp_file = TmpFile::create("npy-stream");
+    let t = Tensor::arange(0f32, 24f32, &Device::Cpu)?.reshape((4, 2, 3))?;
+    let mut writer = NpyWriter::create(&tmp_file, DType::F32, (4, 2, 3))?;
+    for i in 0..4 {
+        writer.write(&t.get(i)?)?;
+    }
+    assert_eq!(writer.written(), 24);
+    writer.finish()?;
+    let t2 = Tensor::read_npy(&tmp_file)?;
+    assert_eq!(t2.to_vec3::<f32>()?, t.to_vec3::<f32>()?);
+
+    // The number of elements and the dtype have to match the header.
+    let mut writer = NpyWriter::create(&tmp_file, DType::F32, (2, 3))?;
+    assert!(writer
+        .write(&Tensor::zeros(6, DType::F64, &Device::Cpu)?)
+        .is_err());
+    writer.write(&Tensor::zeros(4, DType::F32, &Device::Cpu)?)?;
+    assert!(writer
+        .write(&Tensor::zeros(4, DType::F32, &Device::Cpu)?)
+        .is_err());
+    assert!(writer.finish().is_err());
+
+    let tmp_file = TmpFile::create("npz-stream");
+    let mut npz = NpzWriter::create(&tmp_file)?;
+    npz.write("first", &t.get(0)?)?;
+    let mut writer = npz.start_array("streamed", DType::F32, (4, 6))?;
+    for chunk in t.chunk(2, 0)? {
+        writer.write(&chunk)?;
+    }
+    writer.finish()?;
+    npz.write("last", &Tensor::new(&[1u8, 2], &Device::Cpu)?)?;
+    npz.finish()?;
+    let npz = Tensor::read_npz(&tmp_file)?;
+    assert_eq!(npz.len(), 3);
+    assert_eq!(npz[0].1.to_vec2::<f32>()?, [[0., 1., 2.], [3., 4., 5.]]);
+    assert_eq!(npz[1].0, "streamed");
+    assert_eq!(
+        npz[1].1.to_vec2::<f32>()?,
+        t.reshape((4, 6))?.to_vec2::<f32>()?
+    );
+    assert_eq!(npz[2].1.to_vec1::<u8>()?, [1, 2]);
+    Ok(())
+}
+
 #[test]
 fn safetensors() -> Result<()> {
     use candle_core::safetensors::Load;
@@ -69,3 +133,117 @@ fn safetensors() -> Result<()> {
     assert_eq!(diff, 0f32);
     Ok(())
 }
+
+// A npy file with the given header dictionary.
+fn npy_bytes(header: &str, data: &[u8]) -> Vec<u8> {
+    let header = format!("{header}\n");
+    let mut bytes = b"\x93NUMPY\x01\x00".to_vec();
+    bytes.extend_from_slice(&(header.len() as u16).to_le_bytes());
+    bytes.extend_from_slice(header.as_bytes());
+    bytes.extend_from_slice(data);
+    bytes
+}
+
+#[test]
+fn npy_dtypes() -> Result<()> {
+    let tmp_file = TmpFile::create("npy-dtypes");
+    let read = |header: &str, data: &[u8]| {
+        std::fs::write(&tmp_file, npy_bytes(header, data))?;
+        Tensor::read_npy(&tmp_file)
+    };
+
+    let t = read(
+        "{'descr': '>i2', 'fortran_order': False, 'shape': (3,), }",
+        &[0, 1, 255, 254, 1, 0],
+    )?;
+    assert_eq!(t.dtype(), DType::I64);
+    assert_eq!(t.to_vec1::<i64>()?, [1, -2, 256]);
+
+    let t = read(
+        "{'descr': '|b1', 'fortran_order': False, 'shape': (3,), }",
+        &[1, 0, 1],
+    )?;
+    assert_eq!(t.to_vec1::<u8>()?, [1, 0, 1]);
+
+    let t = read(
+        "{'descr': '<u2', 'fortran_order': False, 'shape': (2,), }",
+        &[1, 1, 255, 255],
+    )?;
+    assert_eq!(t.to_vec1::<u32>()?, [257, 65535]);
+
+    let data = [1f32, 2., 3., 4., 5., 6.]
+        .iter()
+        .flat_map(|v| v.to_be_bytes())
+        .collect::<Vec<_>>();
+    let t = read(
+        "{'descr': '>f4', 'fortran_order': True, 'shape': (2, 3), }",
+        &data,
+    )?;
+    assert_eq!(t.to_vec2::<f32>()?, [[1., 3., 5.], [2., 4., 6.]]);
+
+    let data = u64::MAX.to_le_bytes();
+    assert!(read(
+        "{'descr': '<u8', 'fortran_order': False, 'shape': (1,), }",
+        &data
+    )
+    .is_err());
+    assert!(read(
+        "{'descr': '<c8', 'fortran_order': False, 'shape': (1,), }",
+        &data
+    )
+    .is_err());
+    // Not enough data for the shape.
+    assert!(read(
+        "{'descr': '<f8', 'fortran_order': False, 'shape': (2,), }",
+        &data
+    )
+    .is_err());
+
+    let t = Tensor::new(&[1.5f32, -2., 3.25], &candle_core::Device::Cpu)?.to_dtype(DType::BF16)?;
+    t.write_npy(&tmp_file)?;
+    let t2 = Tensor::read_npy(&tmp_file)?;
+    assert_eq!(t2.dtype(), DType::BF16);
+    assert_eq!(t2.to_dtype(DType::F32)?.to_vec1::<f32>()?, [1.5, -2., 3.25]);
+    Ok(())
+}
+
+#[test]
+fn npy_mmap() -> Result<()> {
+    use candle_core::npy::{MmapedNpy, MmapedNpz, NpzWriter};
+    use candle_core::Device;
+
+    let tmp_file = TmpFile::create("npy-mmap");
+    let t = Tensor::arange(0i64, 24, &Device::Cpu)?.reshape((4, 2, 3))?;
+    t.write_npy(&tmp_file)?;
+    // The data is aligned on 64 bytes.
+    assert_eq!(std::fs::metadata(&tmp_file)?.len(), 128 + 24 * 8);
+    let npy = unsafe { MmapedNpy::new(&tmp_file)? };
+    assert_eq!(npy.dtype(), DType::I64);
+    assert_eq!(npy.shape().dims(), [4, 2, 3]);
+    assert_eq!(
+        npy.load(&Device::Cpu)?.to_vec3::<i64>()?,
+        t.to_vec3::<i64>()?
+    );
+    assert_eq!(
+        npy.load_rows(1, 2, &Device::Cpu)?.to_vec3::<i64>()?,
+        t.narrow(0, 1, 2)?.to_vec3::<i64>()?
+    );
+    assert!(npy.load_rows(3, 2, &Device::Cpu).is_err());
+
+    let tmp_file = TmpFile::create("npz-mmap");
+    let mut npz = NpzWriter::create(&tmp_file)?;
+    npz.write("t", &t)?;
+    npz.write("u", &Tensor::new(&[3u32, 1, 4], &Device::Cpu)?)?;
+    npz.finish()?;
+    let npz = unsafe { MmapedNpz::new(&tmp_file)? };
+    assert_eq!(npz.names().len(), 2);
+    let (shape, dtype) = npz.get_shape_and_dtype("u")?;
+    assert_eq!((shape.dims(), dtype), (&[3][..], DType::U32));
+    assert_eq!(npz.load("u", &Device::Cpu)?.to_vec1::<u32>()?, [3, 1, 4]);
+    assert_eq!(
+        npz.load_rows("t", 2, 2, &Device::Cpu)?.to_vec3::<i64>()?,
+        t.narrow(0, 2, 2)?.to_vec3::<i64>()?
+    );
+    assert!(npz.load("v", &Device::Cpu).is_err());
+    Ok(())
+}
@@ -708,6 +708,51 @@ fn broadcast(device: &Device) -> Result<()> {
     Ok(())
 }
 
+fn pinned_memory(device: &Device) -> Result<()> {
+    let t = Tensor::arange(0f32, 6., &Device::Cpu)?.reshape((2, 3))?;
+    if !device.is_cuda() {
+        // Only cuda devices support pinned memory, the copies are then synchronous.
+        assert!(t.pin_memory(device).is_err());
+        let t2 = t.to_device_nonblocking(device)?;
+        assert_eq!(t2.to_vec2::<f32>()?, [[0., 1., 2.], [3., 4., 5.]]);
+        return Ok(());
+    }
+    assert!(!t.is_pinned());
+    let pinned = t.pin_memory(device)?;
+    assert!(pinned.is_pinned());
+    // Views share the pinned storage.
+    assert!(pinned.t()?.is_pinned());
+    let xs = pinned.to_device_nonblocking(device)?;
+    let ys = (xs.t()? * 2.)?;
+    assert_eq!(ys.to_vec2::<f32>()?, [[0., 6.], [2., 8.], [4., 10.]]);
+    drop(pinned);
+    device.synchronize()?;
+    assert_eq!(xs.to_vec2::<f32>()?, [[0., 1., 2.], [3., 4., 5.]]);
+    Ok(())
+}
+
+fn memory_stats(device: &Device) -> Result<()> {
+    if device.is_cpu() && !cfg!(target_os = "linux") {
+        assert!(device.memory_stats().is_err());
+        return Ok(());
+    }
+    let before = device.memory_stats()?;
+    // Other tests run concurrently so only the bounds can be checked.
+    let t = Tensor::ones(1 << 22, DType::F32, device)?;
+    device.synchronize()?;
+    let stats = device.memory_stats()?;
+    assert!(stats.allocated > 0);
+    assert!(stats.reserved >= stats.allocated);
+    assert!(stats.peak_allocated >= stats.allocated);
+    assert!(stats.peak_allocated >= before.allocated);
+    assert!(stats.peak_reserved >= stats.reserved);
+    drop(t);
+    device.reset_peak_memory_stats()?;
+    let stats = device.memory_stats()?;
+    assert!(stats.peak_allocated >= stats.allocated);
+    Ok(())
+}
+
 fn slice_set(device: &Device) -> Result<()> {
     let (b, h, max_t, d) = (2, 4, 7, 3);
     let cache = Tensor::zeros((b, h, max_t, d), DType::F32, device)?;
@@ -1479,7 +1524,28 @@ fn zero_dim(device: &Device) -> Result<()> {
     Ok(())
 }
 
+fn bf16_to_f16(device: &Device) -> Result<()> {
+    // bf16 has the range of f32, the finite values too large for f16 saturate.
+    let values = [1.5f32, -1e5, 7e4, f32::INFINITY, f32::NEG_INFINITY];
+    let xs = Tensor::new(&values, device)?.to_dtype(DType::BF16)?;
+    let ys = xs.to_dtype(DType::F16)?.to_dtype(DType::F32)?;
+    assert_eq!(
+        ys.to_vec1::<f32>()?,
+        [1.5, -65504., 65504., f32::INFINITY, f32::NEG_INFINITY]
+    );
+    let nan = Tensor::new(&[f32::NAN], device)?.to_dtype(DType::BF16)?;
+    let nan = nan.to_dtype(DType::F16)?.to_dtype(DType::F32)?;
+    assert!(nan.to_vec1::<f32>()?[0].is_nan());
+    Ok(())
+}
+
 test_device!(zeros, zeros_cpu, zeros_gpu, zeros_metal);
+test_device!(
+    bf16_to_f16,
+    bf16_to_f16_cpu,
+    bf16_to_f16_gpu,
+    bf16_to_f16_metal
+);
 test_device!(ones, ones_cpu, ones_gpu, ones_metal);
 test_device!(full, full_cpu, full_gpu, full_metal);
 test_device!(arange, arange_cpu, arange_gpu, arange_metal);
@@ -1488,6 +1554,18 @@ test_device!(tensor_2d, tensor_2d_cpu, tensor_2d_gpu, tensor_2d_metal);
 test_device!(narrow, narrow_cpu, narrow_gpu, narrow_metal);
 test_device!(broadcast, broadcast_cpu, broadcast_gpu, broadcast_metal);
 test_device!(slice_set, ss_cpu, ss_gpu, ss_metal);
+test_device!(
+    pinned_memory,
+    pinned_memory_cpu,
+    pinned_memory_gpu,
+    pinned_memory_metal
+);
+test_device!(
+    memory_stats,
+    memory_stats_cpu,
+    memory_stats_gpu,
+    memory_stats_metal
+);
 test_device!(cat, cat_cpu, cat_gpu, cat_metal);
 test_device!(sum, sum_cpu, sum_gpu, sum_metal);
 test_device!(min, min_cpu, min_gpu, min_metal);
@@ -1680,3 +1758,164 @@ fn pow() -> Result<()> {
     );
     Ok(())
 }
+
+#[test]
+fn dim_names() -> Result<()> {
+    let t = Tensor::arange(0f32, 24., &Device::Cpu)?
+        .reshape((2, 3, 4))?
+        .with_dim_names(["batch", "seq", "hidden"])?;
+    assert_eq!(t.dim_index("seq")?, 1);
+    assert!(t.dim_index("heads").is_err());
+    assert!(t.with_dim_names(["a", "a", "b"]).is_err());
+
+    let s = t.sum_dim("seq")?;
+    assert_eq!(s.dims(), &[2, 4]);
+    assert_eq!(s.dim_index("hidden")?, 1);
+    assert_eq!(s.to_vec2::<f32>()?, t.sum(1)?.to_vec2::<f32>()?);
+
+    // Names propagate through elementwise ops, transposes and broadcasting.
+    let bias = Tensor::ones(4, DType::F32, &Device::Cpu)?.with_dim_names(["hidden"])?;
+    let u = t
+        .broadcast_add(&bias)?
+        .exp()?
+        .transpose_dims("batch", "hidden")?;
+    assert_eq!(u.dims(), &[4, 3, 2]);
+    assert_eq!(u.dim_index("batch")?, 2);
+    let u = u.align_to(&["batch", "seq", "hidden"])?;
+    assert_eq!(u.dims(), &[2, 3, 4]);
+
+    // Mismatched names are reported for binary ops.
+    let other = t.zeros_like()?.with_dim_names(["batch", "hidden", "seq"])?;
+    assert!(t.add(&other).is_err());
+
+    // The contracted dimensions of a matmul have to agree.
+    let w = Tensor::zeros((2, 4, 5), DType::F32, &Device::Cpu)?;
+    let out = t.matmul(&w.with_dim_names(["batch", "hidden", "out"])?)?;
+    assert_eq!(out.dim_index("out")?, 2);
+    assert_eq!(out.dim_index("seq")?, 1);
+    assert!(t
+        .matmul(&w.with_dim_names(["batch", "seq", "out"])?)
+        .is_err());
+    assert!(t.without_dim_names().dim_names().is_none());
+    Ok(())
+}
+
+#[test]
+fn inplace_ops() -> Result<()> {
+    let t = Tensor::new(&[[1f32, -2., 3.], [4., 5., -6.]], &Device::Cpu)?;
+    let rhs = Tensor::new(&[[1f32, 1., 1.], [2., 2., 2.]], &Device::Cpu)?;
+    t.add_(&rhs)?;
+    assert_eq!(t.to_vec2::<f32>()?, [[2., -1., 4.], [6., 7., -4.]]);
+    t.mul_(&rhs.t()?.contiguous()?.t()?)?;
+    assert_eq!(t.to_vec2::<f32>()?, [[2., -1., 4.], [12., 14., -8.]]);
+    t.scale_(0.5)?;
+    t.clamp_(-1., 5.)?;
+    assert_eq!(t.to_vec2::<f32>()?, [[1., -0.5, 2.], [5., 5., -1.]]);
+    assert!(t.add_(&t).is_err());
+
+    // The results are written in the buffer of the tensor rather than in a new one.
+    let data_ptr = |t: &Tensor| match &*t.storage_and_layout().0 {
+        candle_core::Storage::Cpu(candle_core::CpuStorage::F32(data)) => data.as_ptr(),
+        _ => unreachable!(),
+    };
+    let ptr = data_ptr(&t);
+    t.sub_(&rhs)?;
+    t.div_(&rhs)?;
+    t.affine_(2., 1.)?;
+    t.clamp_(-1., 1.)?;
+    assert_eq!(data_ptr(&t), ptr);
+    assert_eq!(t.to_vec2::<f32>()?, [[1., -1., 1.], [1., 1., -1.]]);
+
+    // Shared storages cannot be modified in place.
+    let view = t.narrow(0, 0, 1)?;
+    assert!(t.scale_(2.).is_err());
+    assert!(view.scale_(2.).is_err());
+    drop(view);
+    t.scale_(2.)?;
+
+    // Nor can tensors tracked by the computation graph.
+    let var = candle_core::Var::new(&[1f32, 2.], &Device::Cpu)?;
+    assert!(var.as_tensor().affine_(2., 1.).is_err());
+    let tracked = var.as_tensor().exp()?;
+    assert!(tracked.clamp_(0., 1.).is_err());
+    Ok(())
+}
+
+#[test]
+fn content_hash_and_diff() -> Result<()> {
+    use candle_core::checkpoint_diff;
+    use std::collections::HashMap;
+
+    let dev = &Device::Cpu;
+    let t = Tensor::arange(0f32, 6., dev)?.reshape((2, 3))?;
+    let u = Tensor::new(&[[0f32, 3.], [1., 4.], [2., 5.]], dev)?.t()?;
+    assert!(!u.is_contiguous());
+    assert_eq!(t.content_hash()?, u.content_hash()?);
+    assert_eq!(t.content_hash()?, t.copy()?.content_hash()?);
+    assert_ne!(t.content_hash()?, t.reshape((3, 2))?.content_hash()?);
+    assert_ne!(t.content_hash()?, t.to_dtype(DType::F64)?.content_hash()?);
+    assert_ne!(t.content_hash()?, (&t + 1e-3)?.content_hash()?);
+    // The hash value is stable across runs and platforms.
+    let empty = Tensor::zeros(0, DType::U8, dev)?;
+    assert_eq!(empty.content_hash()?, 0x6516_9831_bae5_a219);
+
+    let lhs = HashMap::from([
+        ("a".to_string(), t.clone()),
+        ("b".to_string(), t.clone()),
+        ("c".to_string(), t.clone()),
+        ("d".to_string(), t.clone()),
+        ("e".to_string(), t.clone()),
+    ]);
+    let rhs = HashMap::from([
+        ("a".to_string(), u.clone()),
+        ("b".to_string(), (&t + 0.25)?),
+        ("c".to_string(), t.to_dtype(DType::F16)?),
+        ("d".to_string(), t.flatten_all()?),
+        ("f".to_string(), t.clone()),
+    ]);
+    let diff = checkpoint_diff::diff(&lhs, &rhs)?;
+    assert_eq!(diff.only_in_lhs, ["e"]);
+    assert_eq!(diff.only_in_rhs, ["f"]);
+    assert_eq!(diff.shape_mismatches.keys().collect::<Vec<_>>(), ["d"]);
+    assert!(diff.tensors["a"].is_identical());
+    assert_eq!(diff.tensors["b"].max_abs_diff, 0.25);
+    assert_eq!(diff.tensors["b"].mean_abs_diff, 0.25);
+    assert_eq!(diff.tensors["c"].max_abs_diff, 0.);
+    assert!(!diff.tensors["c"].is_identical());
+    assert_eq!(diff.changed_keys(0.), ["b", "c", "d", "e", "f"]);
+    assert!(!diff.is_identical());
+    assert!(checkpoint_diff::diff(&lhs, &lhs)?.is_identical());
+    Ok(())
+}
+
+#[test]
+fn sum_accumulation() -> Result<()> {
+    use candle_core::cpu::SumAccumulation;
+    let n = 1 << 24;
+    let vs: Vec<f32> = (0..n).map(|i| 0.1 + (i % 7) as f32 * 1e-3).collect();
+    let expected: f64 = vs.iter().map(|&v| v as f64).sum();
+    let t = Tensor::from_vec(vs, (n / 1024, 1024), &Device::Cpu)?;
+    let rel_err = |v: f32| ((v as f64 - expected) / expected).abs();
+
+    let sum = t.flatten_all()?.sum_all()?.to_scalar::<f32>()?;
+    assert!(rel_err(sum) < 1e-6, "{sum} {expected}");
+    let sum = t.to_dtype(DType::BF16)?.flatten_all()?.sum_all()?;
+    let sum = sum.to_dtype(DType::F32)?.to_scalar::<f32>()?;
+    assert!(rel_err(sum) < 1e-2, "{sum} {expected}");
+
+    // Leading dimensions and strided layouts are only compensated with Kahan summation.
+    candle_core::cpu::set_sum_accumulation(SumAccumulation::Kahan);
+    let sum0 = t.sum(0)?.sum_all()?.to_scalar::<f32>();
+    let sum_t = t.t()?.sum(1)?.sum_all()?.to_scalar::<f32>();
+    let sum_all = t.sum_all()?.to_scalar::<f32>();
+    candle_core::cpu::set_sum_accumulation(SumAccumulation::Pairwise);
+    for sum in [sum0?, sum_t?, sum_all?] {
+        assert!(rel_err(sum) < 1e-7, "{sum} {expected}");
+    }
+    let t = Tensor::new(&[1f64, 1e100, 1., -1e100], &Device::Cpu)?;
+    candle_core::cpu::set_sum_accumulation(SumAccumulation::Kahan);
+    let sum = t.sum_all()?.to_scalar::<f64>();
+    candle_core::cpu::set_sum_accumulation(SumAccumulation::Pairwise);
+    assert_eq!(sum?, 2.);
+    Ok(())
+}
@@ -0,0 +1,192 @@
+//! The decompression codecs shared by the HDF5 and Zarr readers.
+use candle::{Error, Result};
+use std::io::Read;
+
+pub(super) fn zlib(data: &[u8]) -> Result<Vec<u8>> {
+    let mut out = vec![];
+    flate2::read::ZlibDecoder::new(data).read_to_end(&mut out)?;
+    Ok(out)
+}
+
+pub(super) fn gzip(data: &[u8]) -> Result<Vec<u8>> {
+    let mut out = vec![];
+    flate2::read::GzDecoder::new(data).read_to_end(&mut out)?;
+    Ok(out)
+}
+
+pub(super) fn zstd(data: &[u8]) -> Result<Vec<u8>> {
+    let mut out = vec![];
+    zstd::stream::read::Decoder::new(data)?.read_to_end(&mut out)?;
+    Ok(out)
+}
+
+/// A lz4 block of `len` bytes once decompressed.
+fn lz4_block(data: &[u8], len: usize) -> Result<Vec<u8>> {
+    lz4_flex::block::decompress(data, len).map_err(Error::wrap)
+}
+
+/// The numcodecs lz4 format, the block is prefixed with its decompressed length.
+pub(super) fn lz4(data: &[u8]) -> Result<Vec<u8>> {
+    if data.len() < 4 {
+        candle::bail!("lz4 data is too short")
+    }
+    let len = u32::from_le_bytes([data[0], data[1], data[2], data[3]]) as usize;
+    lz4_block(&data[4..], len)
+}
+
+/// Undoes the byte shuffle of elements of `size` bytes: the first bytes of all the elements
+/// come first, then the second bytes, etc. The trailing bytes that do not fill an element are
+/// not shuffled.
+pub(super) fn unshuffle(data: &[u8], size: usize) -> Vec<u8> {
+    if size <= 1 {
+        return data.to_vec();
+    }
+    let n = data.len() / size;
+    let mut out = vec![0u8; data.len()];
+    for b in 0..size {
+        for i in 0..n {
+            out[i * size + b] = data[b * n + i]
+        }
+    }
+    out[n * size..].copy_from_slice(&data[n * size..]);
+    out
+}
+
+/// The LZF format used by the h5py `lzf` filter, `len` is the decompressed length.
+pub(super) fn lzf(data: &[u8], len: usize) -> Result<Vec<u8>> {
+    let mut out = Vec::with_capacity(len);
+    let mut i = 0;
+    let invalid = || Error::Msg("invalid lzf data".to_string());
+    while i < data.len() {
+        let ctrl = data[i] as usize;
+        i += 1;
+        if ctrl < 32 {
+            // A literal run of ctrl + 1 bytes.
+            let run = data.get(i..i + ctrl + 1).ok_or_else(invalid)?;
+            out.extend_from_slice(run);
+            i += ctrl + 1;
+        } else {
+            // A back reference of length (ctrl >> 5) + 2.
+            let mut run = ctrl >> 5;
+            if run == 7 {
+                run += *data.get(i).ok_or_else(invalid)? as usize;
+                i += 1
+            }
+            let run = run + 2;
+            let low = *data.get(i).ok_or_else(invalid)? as usize;
+            i += 1;
+            let back = ((ctrl & 0x1f) << 8) + low + 1;
+            if back > out.len() {
+                return Err(invalid());
+            }
+            let start = out.len() - back;
+            // The reference can overlap the bytes being written.
+            for j in 0..run {
+                out.push(out[start + j])
+            }
+        }
+    }
+    if out.len() != len {
+        candle::bail!("lzf data of {} bytes instead of {len}", out.len())
+    }
+    Ok(out)
+}
+
+const BLOSC_DOSHUFFLE: u8 = 0x1;
+const BLOSC_MEMCPYED: u8 = 0x2;
+const BLOSC_DOBITSHUFFLE: u8 = 0x4;
+
+fn read_u32(data: &[u8], offset: usize) -> Result<u32> {
+    match data.get(offset..offset + 4) {
+        Some(b) => Ok(u32::from_le_bytes([b[0], b[1], b[2], b[3]])),
+        None => candle::bail!("truncated blosc data"),
+    }
+}
+
+/// Decompresses a blosc (version 1) frame, with the lz4, zlib or zstd codecs.
+pub(super) fn blosc(data: &[u8]) -> Result<Vec<u8>> {
+    if data.len() < 16 {
+        candle::bail!("blosc data is too short")
+    }
+    let flags = data[2];
+    let typesize = data[3] as usize;
+    let nbytes = read_u32(data, 4)? as usize;
+    let blocksize = read_u32(data, 8)? as usize;
+    let cbytes = (read_u32(data, 12)? as usize).min(data.len());
+    if flags & BLOSC_MEMCPYED != 0 {
+        return match data.get(16..16 + nbytes) {
+            Some(d) => Ok(d.to_vec()),
+            None => candle::bail!("truncated blosc data"),
+        };
+    }
+    if flags & BLOSC_DOBITSHUFFLE != 0 {
+        candle::bail!("the blosc bit shuffle is not supported")
+    }
+    if blocksize == 0 {
+        candle::bail!("invalid blosc block size")
+    }
+    let decompress = |stream: &[u8], len: usize| -> Result<Vec<u8>> {
+        match flags >> 5 {
+            1 => lz4_block(stream, len),
+            3 => zlib(stream),
+            4 => zstd(stream),
+            0 => candle::bail!("the blosclz codec is not supported"),
+            codec => candle::bail!("unsupported blosc codec {codec}"),
+        }
+    };
+    let nblocks = nbytes.div_ceil(blocksize);
+    let mut out = Vec::with_capacity(nbytes);
+    for b in 0..nblocks {
+        let start = read_u32(data, 16 + 4 * b)? as usize;
+        let end = if b + 1 < nblocks {
+            read_u32(data, 16 + 4 * (b + 1))? as usize
+        } else {
+            cbytes
+        };
+        let bsize = blocksize.min(nbytes - b * blocksize);
+        // The blocks are either split in one stream per byte of the elements or compressed as a
+        // single stream, depending on the blosc version and on the block, the right layout is
+        // the one whose streams end at the start of the next block.
+        let streams = |n: usize| -> Option<Vec<(usize, usize)>> {
+            let mut pos = start;
+            let mut streams = vec![];
+            for _ in 0..n {
+                let len = read_u32(data, pos).ok()? as usize;
+                streams.push((pos + 4, len));
+                pos += 4 + len;
+            }
+            (pos == end).then_some(streams)
+        };
+        let nstreams = if typesize > 1 && bsize % typesize == 0 {
+            typesize
+        } else {
+            1
+        };
+        let (streams, nstreams) = match streams(nstreams) {
+            Some(s) => (s, nstreams),
+            None => match streams(1) {
+                Some(s) => (s, 1),
+                None => candle::bail!("invalid blosc block {b}"),
+            },
+        };
+        let neblock = bsize / nstreams;
+        let mut block = Vec::with_capacity(bsize);
+        for (pos, len) in streams {
+            let stream = &data[pos..pos + len];
+            if len == neblock {
+                // Streams that do not compress are stored as is.
+                block.extend_from_slice(stream)
+            } else {
+                block.extend_from_slice(&decompress(stream, neblock)?)
+            }
+        }
+        if block.len() != bsize {
+            candle::bail!("blosc block of {} bytes instead of {bsize}", block.len())
+        }
+        if flags & BLOSC_DOSHUFFLE != 0 && typesize > 1 {
+            block = unshuffle(&block, typesize)
+        }
+        out.extend_from_slice(&block)
+    }
+    Ok(out)
+}
@@ -0,0 +1,882 @@
+//! A reader for the datasets of HDF5 files, in pure Rust.
+//!
+//! The file is memory mapped and only the metadata needed to locate the chunks is parsed when
+//! opening a dataset. This covers the files written by h5py and the HDF5 library with their
+//! default settings:
+//!
+//! - superblocks of versions 0 to 3, object headers of versions 1 and 2,
+//! - groups with a symbol table or with compact link storage, dense link storage is not
+//!   supported,
+//! - integer, float and enum datatypes, the h5py booleans are loaded as `u8`,
+//! - compact, contiguous and chunked layouts, the chunks being indexed by a version 1 B-tree, a
+//!   single chunk, an implicit index or an unpaged fixed array,
+//! - the deflate, shuffle, fletcher32, lzf, blosc and zstd filters.
+//!
+//! The checksums of the metadata are not verified. Chunks that have not been written are filled
+//! with zeros rather than with the fill value of the dataset. Contiguous datasets are read by
+//! groups of rows of about 1MiB.
+use super::{check_chunk, codecs, edge_chunk, ChunkedArray, Element, Kind};
+use candle::{DType, Result, Tensor};
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Arc;
+
+const SIGNATURE: &[u8] = b"\x89HDF\r\n\x1a\n";
+// The size of the virtual chunks of contiguous datasets.
+const CONTIGUOUS_CHUNK_BYTES: usize = 1 << 20;
+
+const MSG_DATASPACE: u16 = 0x1;
+const MSG_LINK_INFO: u16 = 0x2;
+const MSG_DATATYPE: u16 = 0x3;
+const MSG_LINK: u16 = 0x6;
+const MSG_LAYOUT: u16 = 0x8;
+const MSG_FILTERS: u16 = 0xb;
+const MSG_CONTINUATION: u16 = 0x10;
+const MSG_SYMBOL_TABLE: u16 = 0x11;
+
+const FILTER_DEFLATE: u16 = 1;
+const FILTER_SHUFFLE: u16 = 2;
+const FILTER_FLETCHER32: u16 = 3;
+const FILTER_LZF: u16 = 32000;
+const FILTER_BLOSC: u16 = 32001;
+const FILTER_ZSTD: u16 = 32015;
+
+// The sizes of the addresses and lengths in the file, and the base address.
+#[derive(Debug, Clone, Copy)]
+struct Sizes {
+    offset: usize,
+    length: usize,
+    base: usize,
+}
+
+struct Cursor<'a> {
+    data: &'a [u8],
+    pos: usize,
+    sizes: Sizes,
+}
+
+impl<'a> Cursor<'a> {
+    fn new(data: &'a [u8], pos: usize, sizes: Sizes) -> Self {
+        Self { data, pos, sizes }
+    }
+
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8]> {
+        let end = self.pos.checked_add(len);
+        match end.and_then(|end| self.data.get(self.pos..end)) {
+            Some(bytes) => {
+                self.pos += len;
+                Ok(bytes)
+            }
+            None => candle::bail!("truncated hdf5 file, reading {len} bytes at {}", self.pos),
+        }
+    }
+
+    fn skip(&mut self, len: usize) -> Result<()> {
+        self.bytes(len).map(|_| ())
+    }
+
+    fn signature(&mut self, signature: &[u8]) -> Result<()> {
+        let pos = self.pos;
+        if self.bytes(signature.len())? != signature {
+            candle::bail!(
+                "no {:?} signature at {pos}",
+                String::from_utf8_lossy(signature)
+            )
+        }
+        Ok(())
+    }
+
+    fn uint(&mut self, len: usize) -> Result<u64> {
+        let bytes = self.bytes(len)?;
+        if len > 8 {
+            candle::bail!("unsupported hdf5 integer size {len}")
+        }
+        Ok(bytes
+            .iter()
+            .rev()
+            .fold(0u64, |acc, &b| (acc << 8) | b as u64))
+    }
+
+    fn u8(&mut self) -> Result<u8> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16> {
+        Ok(self.uint(2)? as u16)
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        Ok(self.uint(4)? as u32)
+    }
+
+    fn u64(&mut self) -> Result<u64> {
+        self.uint(8)
+    }
+
+    fn length(&mut self) -> Result<usize> {
+        Ok(self.uint(self.sizes.length)? as usize)
+    }
+
+    /// An address in the file, `None` for the undefined address.
+    fn address(&mut self) -> Result<Option<usize>> {
+        let size = self.sizes.offset;
+        let address = self.uint(size)?;
+        if address == u64::MAX >> (64 - 8 * size) {
+            Ok(None)
+        } else {
+            Ok(Some((address as usize).saturating_add(self.sizes.base)))
+        }
+    }
+
+    fn defined_address(&mut self) -> Result<usize> {
+        let pos = self.pos;
+        match self.address()? {
+            Some(address) => Ok(address),
+            None => candle::bail!("undefined hdf5 address at {pos}"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Message<'a> {
+    kind: u16,
+    data: &'a [u8],
+}
+
+// The messages of the object header at `address`, including the continuation blocks.
+fn messages(data: &[u8], sizes: Sizes, address: usize) -> Result<Vec<Message<'_>>> {
+    let mut c = Cursor::new(data, address, sizes);
+    let mut messages = vec![];
+    // The blocks of messages still to parse, with the version of the header.
+    let mut blocks = vec![];
+    let version;
+    let mut creation_order = false;
+    if address
+        .checked_add(4)
+        .and_then(|end| data.get(address..end))
+        == Some(b"OHDR")
+    {
+        c.skip(4)?;
+        version = c.u8()?;
+        let flags = c.u8()?;
+        if version != 2 {
+            candle::bail!("unsupported hdf5 object header version {version}")
+        }
+        if flags & 0x20 != 0 {
+            c.skip(16)?
+        }
+        if flags & 0x10 != 0 {
+            c.skip(4)?
+        }
+        creation_order = flags & 0x04 != 0;
+        let size = c.uint(1 << (flags & 0x3))? as usize;
+        blocks.push((c.pos, c.pos.saturating_add(size)));
+    } else {
+        version = c.u8()?;
+        if version != 1 {
+            candle::bail!("unsupported hdf5 object header version {version} at {address}")
+        }
+        c.skip(3)?;
+        c.skip(4)?;
+        let size = c.u32()? as usize;
+        // The messages are aligned on 8 bytes.
+        let start = address.saturating_add(16);
+        blocks.push((start, start.saturating_add(size)));
+    }
+    let mut block = 0;
+    while block < blocks.len() {
+        let (start, end) = blocks[block];
+        block += 1;
+        let mut c = Cursor::new(data, start, sizes);
+        let header_size = match version {
+            1 => 8,
+            _ => 4 + 2 * creation_order as usize,
+        };
+        while c.pos + header_size <= end {
+            let (kind, size) = if version == 1 {
+                let kind = c.u16()?;
+                let size = c.u16()? as usize;
+                c.skip(4)?;
+                (kind, size)
+            } else {
+                let kind = c.u8()? as u16;
+                let size = c.u16()? as usize;
+                c.skip(1 + 2 * creation_order as usize)?;
+                (kind, size)
+            };
+            let body = c.bytes(size)?;
+            if kind == MSG_CONTINUATION {
+                let mut m = Cursor::new(body, 0, sizes);
+                let address = m.defined_address()?;
+                let length = m.length()?;
+                if version == 1 {
+                    blocks.push((address, address.saturating_add(length)))
+                } else {
+                    Cursor::new(data, address, sizes).signature(b"OCHK")?;
+                    // The block ends with a checksum.
+                    blocks.push((address + 4, address.saturating_add(length) - 4))
+                }
+            } else {
+                messages.push(Message { kind, data: body })
+            }
+        }
+    }
+    Ok(messages)
+}
+
+// The elements of a datatype message, and the length of the message.
+fn datatype(data: &[u8]) -> Result<(Element, usize)> {
+    let mut c = Cursor::new(
+        data,
+        0,
+        Sizes {
+            offset: 8,
+            length: 8,
+            base: 0,
+        },
+    );
+    let class = c.u8()?;
+    let bits = c.bytes(3)?;
+    let size = c.u32()? as usize;
+    let big_endian = bits[0] & 0x1 != 0;
+    match class & 0xf {
+        0 => {
+            let kind = if bits[0] & 0x8 != 0 {
+                Kind::Int
+            } else {
+                Kind::UInt
+            };
+            let element = Element {
+                kind,
+                size,
+                big_endian,
+            };
+            Ok((element, 12))
+        }
+        1 => {
+            if bits[0] & 0x40 != 0 {
+                candle::bail!("the vax float byte order is not supported")
+            }
+            let element = Element {
+                kind: Kind::Float,
+                size,
+                big_endian,
+            };
+            Ok((element, 20))
+        }
+        8 => {
+            let (base, len) = datatype(&data[8..])?;
+            let members = u16::from_le_bytes([bits[0], bits[1]]);
+            // The h5py booleans are enums with the FALSE and TRUE members.
+            let names = &data[(8 + len).min(data.len())..];
+            if members == 2 && base.size == 1 && names.starts_with(b"FALSE\0") {
+                let element = Element {
+                    kind: Kind::Bool,
+                    ..base
+                };
+                return Ok((element, data.len()));
+            }
+            Ok((base, data.len()))
+        }
+        class => candle::bail!("unsupported hdf5 datatype class {class}"),
+    }
+}
+
+fn dataspace(data: &[u8], sizes: Sizes) -> Result<Vec<usize>> {
+    let mut c = Cursor::new(data, 0, sizes);
+    let version = c.u8()?;
+    let rank = c.u8()? as usize;
+    let _flags = c.u8()?;
+    match version {
+        1 => c.skip(5)?,
+        2 => {
+            if c.u8()? == 2 {
+                candle::bail!("null hdf5 dataspaces are not supported")
+            }
+        }
+        version => candle::bail!("unsupported hdf5 dataspace version {version}"),
+    }
+    (0..rank).map(|_| c.length()).collect()
+}
+
+// The ids of the filters of a dataset, in the order in which they were applied.
+fn filters(data: &[u8], sizes: Sizes) -> Result<Vec<u16>> {
+    let mut c = Cursor::new(data, 0, sizes);
+    let version = c.u8()?;
+    let nfilters = c.u8()? as usize;
+    match version {
+        1 => c.skip(6)?,
+        2 => {}
+        version => candle::bail!("unsupported hdf5 filter pipeline version {version}"),
+    }
+    let mut filters = Vec::with_capacity(nfilters);
+    for _ in 0..nfilters {
+        let id = c.u16()?;
+        let name_len = if version == 1 || id >= 256 {
+            c.u16()? as usize
+        } else {
+            0
+        };
+        c.skip(2)?;
+        let nvalues = c.u16()? as usize;
+        if version == 1 {
+            c.skip(name_len.div_ceil(8) * 8)?;
+            c.skip(4 * (nvalues + nvalues % 2))?;
+        } else {
+            c.skip(name_len)?;
+            c.skip(4 * nvalues)?;
+        }
+        match id {
+            FILTER_DEFLATE | FILTER_SHUFFLE | FILTER_FLETCHER32 | FILTER_LZF | FILTER_BLOSC
+            | FILTER_ZSTD => {}
+            id => candle::bail!("unsupported hdf5 filter {id}"),
+        }
+        filters.push(id)
+    }
+    Ok(filters)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Chunk {
+    address: usize,
+    size: usize,
+    filter_mask: u32,
+}
+
+#[derive(Debug, Clone)]
+enum Storage {
+    Compact(Vec<u8>),
+    Contiguous(Option<usize>),
+    Chunked(HashMap<Vec<usize>, Chunk>),
+}
+
+// The position in the grid of the chunk at index `index` in row-major order.
+fn grid_position(index: usize, grid: &[usize]) -> Vec<usize> {
+    let mut index = index;
+    let mut position = vec![0; grid.len()];
+    for (p, &g) in position.iter_mut().zip(grid.iter()).rev() {
+        *p = index % g.max(1);
+        index /= g.max(1);
+    }
+    position
+}
+
+struct Index<'a> {
+    data: &'a [u8],
+    sizes: Sizes,
+    chunk_shape: &'a [usize],
+    chunks: HashMap<Vec<usize>, Chunk>,
+}
+
+impl Index<'_> {
+    // The chunks of a version 1 B-tree of chunks.
+    fn btree(&mut self, address: usize) -> Result<()> {
+        let rank = self.chunk_shape.len();
+        let mut c = Cursor::new(self.data, address, self.sizes);
+        c.signature(b"TREE")?;
+        if c.u8()? != 1 {
+            candle::bail!("expected a B-tree of chunks at {address}")
+        }
+        let level = c.u8()?;
+        let entries = c.u16()? as usize;
+        c.address()?;
+        c.address()?;
+        for _ in 0..entries {
+            let size = c.u32()? as usize;
+            let filter_mask = c.u32()?;
+            let offsets = (0..=rank)
+                .map(|_| c.u64().map(|o| o as usize))
+                .collect::<Result<Vec<_>>>()?;
+            let child = c.defined_address()?;
+            if level == 0 {
+                let position = offsets[..rank]
+                    .iter()
+                    .zip(self.chunk_shape)
+                    .map(|(o, c)| o / c.max(&1))
+                    .collect();
+                let chunk = Chunk {
+                    address: child,
+                    size,
+                    filter_mask,
+                };
+                self.chunks.insert(position, chunk);
+            } else {
+                self.btree(child)?
+            }
+        }
+        Ok(())
+    }
+
+    // The chunks of a fixed array index, only the arrays with a single data block are supported.
+    fn fixed_array(&mut self, address: usize, grid: &[usize], chunk_bytes: usize) -> Result<()> {
+        let mut c = Cursor::new(self.data, address, self.sizes);
+        c.signature(b"FAHD")?;
+        c.skip(1)?;
+        let filtered = c.u8()? == 1;
+        let entry_size = c.u8()? as usize;
+        let page_bits = c.u8()?;
+        let nentries = c.length()?;
+        let block = c.address()?;
+        if 1usize
+            .checked_shl(page_bits as u32)
+            .is_some_and(|page| nentries > page)
+        {
+            candle::bail!("paged hdf5 fixed arrays are not supported")
+        }
+        let block = match block {
+            None => return Ok(()),
+            Some(block) => block,
+        };
+        let mut c = Cursor::new(self.data, block, self.sizes);
+        c.signature(b"FADB")?;
+        c.skip(2)?;
+        c.address()?;
+        for index in 0..nentries {
+            let address = c.address()?;
+            let (size, filter_mask) = if filtered {
+                let size = c.uint(entry_size - self.sizes.offset - 4)? as usize;
+                (size, c.u32()?)
+            } else {
+                (chunk_bytes, 0)
+            };
+            if let Some(address) = address {
+                let chunk = Chunk {
+                    address,
+                    size,
+                    filter_mask,
+                };
+                self.chunks.insert(grid_position(index, grid), chunk);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A dataset of an HDF5 file.
+#[derive(Debug, Clone)]
+pub struct Hdf5Dataset {
+    mmap: Arc<memmap2::Mmap>,
+    shape: Vec<usize>,
+    chunk_shape: Vec<usize>,
+    element: Element,
+    dtype: DType,
+    storage: Storage,
+    filters: Vec<u16>,
+}
+
+impl Hdf5Dataset {
+    fn new(mmap: Arc<memmap2::Mmap>, sizes: Sizes, address: usize) -> Result<Self> {
+        let data: &[u8] = &mmap;
+        let messages = messages(data, sizes, address)?;
+        let find = |kind: u16| messages.iter().find(|m| m.kind == kind).map(|m| m.data);
+        let shape = match find(MSG_DATASPACE) {
+            Some(m) => dataspace(m, sizes)?,
+            None => candle::bail!("the hdf5 object at {address} is not a dataset"),
+        };
+        let element = match find(MSG_DATATYPE) {
+            Some(m) => datatype(m)?.0,
+            None => candle::bail!("the hdf5 object at {address} is not a dataset"),
+        };
+        let filters = match find(MSG_FILTERS) {
+            Some(m) => filters(m, sizes)?,
+            None => vec![],
+        };
+        let layout = match find(MSG_LAYOUT) {
+            Some(m) => m,
+            None => candle::bail!("the hdf5 object at {address} is not a dataset"),
+        };
+        let mut c = Cursor::new(layout, 0, sizes);
+        let version = c.u8()?;
+        if version != 3 && version != 4 {
+            candle::bail!("unsupported hdf5 layout version {version}")
+        }
+        let row_bytes = shape.iter().skip(1).product::<usize>() * element.size;
+        let (chunk_shape, storage) = match c.u8()? {
+            0 => {
+                let size = c.u16()? as usize;
+                let data = c.bytes(size)?.to_vec();
+                (shape.clone(), Storage::Compact(data))
+            }
+            1 => {
+                let address = c.address()?;
+                let mut chunk_shape = shape.clone();
+                if let Some(rows) = chunk_shape.first_mut() {
+                    *rows = (CONTIGUOUS_CHUNK_BYTES / row_bytes.max(1)).clamp(1, (*rows).max(1));
+                }
+                (chunk_shape, Storage::Contiguous(address))
+            }
+            2 if version == 3 => {
+                let rank = c.u8()? as usize;
+                let btree = c.address()?;
+                let dims = (0..rank)
+                    .map(|_| c.u32().map(|d| d as usize))
+                    .collect::<Result<Vec<_>>>()?;
+                let chunk_shape = dims[..rank.saturating_sub(1)].to_vec();
+                let mut index = Index {
+                    data,
+                    sizes,
+                    chunk_shape: &chunk_shape,
+                    chunks: HashMap::new(),
+                };
+                if let Some(btree) = btree {
+                    index.btree(btree)?
+                }
+                let chunks = index.chunks;
+                (chunk_shape, Storage::Chunked(chunks))
+            }
+            2 => {
+                let flags = c.u8()?;
+                let rank = c.u8()? as usize;
+                let dim_size = c.u8()? as usize;
+                let dims = (0..rank)
+                    .map(|_| c.uint(dim_size).map(|d| d as usize))
+                    .collect::<Result<Vec<_>>>()?;
+                let chunk_shape = dims[..rank.saturating_sub(1)].to_vec();
+                let chunk_bytes = chunk_shape.iter().product::<usize>() * element.size;
+                let grid = shape
+                    .iter()
+                    .zip(chunk_shape.iter())
+                    .map(|(&d, &c)| d.div_ceil(c.max(1)))
+                    .collect::<Vec<_>>();
+                let mut index = Index {
+                    data,
+                    sizes,
+                    chunk_shape: &chunk_shape,
+                    chunks: HashMap::new(),
+                };
+                match c.u8()? {
+                    // A single chunk.
+                    1 => {
+                        let (size, filter_mask) = if flags & 0x2 != 0 {
+                            (c.length()?, c.u32()?)
+                        } else {
+                            (chunk_bytes, 0)
+                        };
+                        if let Some(address) = c.address()? {
+                            let chunk = Chunk {
+                                address,
+                                size,
+                                filter_mask,
+                            };
+                            index.chunks.insert(vec![0; grid.len()], chunk);
+                        }
+                    }
+                    // The chunks are stored one after the other, without filters.
+                    2 => {
+                        if let Some(address) = c.address()? {
+                            let nchunks = grid.iter().product::<usize>();
+                            for i in 0..nchunks {
+                                let chunk = Chunk {
+                                    address: address + i * chunk_bytes,
+                                    size: chunk_bytes,
+                                    filter_mask: 0,
+                                };
+                                index.chunks.insert(grid_position(i, &grid), chunk);
+                            }
+                        }
+                    }
+                    3 => {
+                        c.skip(1)?;
+                        if let Some(address) = c.address()? {
+                            index.fixed_array(address, &grid, chunk_bytes)?
+                        }
+                    }
+                    kind => candle::bail!("unsupported hdf5 chunk index type {kind}"),
+                }
+                let chunks = index.chunks;
+                (chunk_shape, Storage::Chunked(chunks))
+            }
+            class => candle::bail!("unsupported hdf5 layout class {class}"),
+        };
+        if chunk_shape.len() != shape.len() {
+            candle::bail!("invalid chunks {chunk_shape:?} for the shape {shape:?}")
+        }
+        Ok(Self {
+            mmap,
+            shape,
+            chunk_shape,
+            dtype: element.dtype()?,
+            element,
+            storage,
+            filters,
+        })
+    }
+
+    fn bytes(&self, start: usize, len: usize) -> Result<&[u8]> {
+        let end = start.checked_add(len);
+        match end.and_then(|end| self.mmap.get(start..end)) {
+            Some(bytes) => Ok(bytes),
+            None => candle::bail!("truncated hdf5 file, reading {len} bytes at {start}"),
+        }
+    }
+
+    // Undoes the filters that have been applied to a chunk.
+    fn unfilter(&self, chunk: &Chunk, chunk_bytes: usize) -> Result<Vec<u8>> {
+        let mut data = self.bytes(chunk.address, chunk.size)?.to_vec();
+        // The filters that failed when writing the chunk, e.g. optional filters that did not
+        // compress the data, are skipped.
+        for (i, &filter) in self.filters.iter().enumerate().rev() {
+            if chunk.filter_mask & (1 << i) != 0 {
+                continue;
+            }
+            data = match filter {
+                FILTER_DEFLATE => codecs::zlib(&data)?,
+                FILTER_SHUFFLE => codecs::unshuffle(&data, self.element.size),
+                FILTER_FLETCHER32 => {
+                    data.truncate(data.len().saturating_sub(4));
+                    data
+                }
+                FILTER_LZF => codecs::lzf(&data, chunk_bytes)?,
+                FILTER_BLOSC => codecs::blosc(&data)?,
+                FILTER_ZSTD => codecs::zstd(&data)?,
+                id => candle::bail!("unsupported hdf5 filter {id}"),
+            };
+        }
+        Ok(data)
+    }
+}
+
+impl ChunkedArray for Hdf5Dataset {
+    fn shape(&self) -> &[usize] {
+        &self.shape
+    }
+
+    fn chunk_shape(&self) -> &[usize] {
+        &self.chunk_shape
+    }
+
+    fn dtype(&self) -> DType {
+        self.dtype
+    }
+
+    fn read_chunk(&self, chunk: &[usize]) -> Result<Tensor> {
+        check_chunk(self, chunk)?;
+        let cpu = &candle::Device::Cpu;
+        match &self.storage {
+            Storage::Compact(data) => self.element.decode(data, &self.shape),
+            Storage::Contiguous(address) => {
+                // The chunks on the edge are not padded in the file.
+                let mut shape = self.shape.clone();
+                let rows = self.chunk_shape.first().copied().unwrap_or(1);
+                let start = chunk.first().copied().unwrap_or(0) * rows;
+                if let Some(len) = shape.first_mut() {
+                    *len = rows.min(*len - start)
+                }
+                let address = match address {
+                    Some(address) => address,
+                    None => return Tensor::zeros(shape, self.dtype, cpu),
+                };
+                let row_bytes = self.shape.iter().skip(1).product::<usize>() * self.element.size;
+                let len = shape.iter().product::<usize>() * self.element.size;
+                let data = self.bytes(address.saturating_add(start * row_bytes), len)?;
+                self.element.decode(data, &shape)
+            }
+            Storage::Chunked(chunks) => {
+                let data = match chunks.get(chunk) {
+                    None => Tensor::zeros(self.chunk_shape.as_slice(), self.dtype, cpu)?,
+                    Some(c) => {
+                        let chunk_bytes =
+                            self.chunk_shape.iter().product::<usize>() * self.element.size;
+                        let data = self.unfilter(c, chunk_bytes)?;
+                        self.element.decode(&data, &self.chunk_shape)?
+                    }
+                };
+                edge_chunk(data, chunk, &self.chunk_shape, &self.shape)
+            }
+        }
+    }
+}
+
+/// An HDF5 file, the datasets are designated by their path from the root group, e.g.
+/// `group/dataset`.
+#[derive(Debug, Clone)]
+pub struct Hdf5File {
+    mmap: Arc<memmap2::Mmap>,
+    sizes: Sizes,
+    root: usize,
+}
+
+impl Hdf5File {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let file = std::fs::File::open(path.as_ref())?;
+        let mmap = unsafe { memmap2::MmapOptions::new().map(&file)? };
+        let data: &[u8] = &mmap;
+        // The superblock can be at offset 0, 512, 1024, 2048, etc.
+        let mut start = 0;
+        while data.get(start..start + SIGNATURE.len()) != Some(SIGNATURE) {
+            start = if start == 0 { 512 } else { 2 * start };
+            if start >= data.len() {
+                candle::bail!("{:?} is not an hdf5 file", path.as_ref())
+            }
+        }
+        let version = data.get(start + 8).copied().unwrap_or(0);
+        let (sizes, root) = match version {
+            0 | 1 => {
+                let offset = data.get(start + 13).copied().unwrap_or(8) as usize;
+                let length = data.get(start + 14).copied().unwrap_or(8) as usize;
+                let header = if version == 0 { 24 } else { 28 };
+                let mut sizes = Sizes {
+                    offset,
+                    length,
+                    base: 0,
+                };
+                let mut c = Cursor::new(data, start + header, sizes);
+                sizes.base = c.defined_address()?;
+                // The free space, end of file and driver information addresses.
+                c.skip(3 * offset)?;
+                // The root group symbol table entry, after the offset of its name.
+                c.sizes = sizes;
+                c.skip(offset)?;
+                (sizes, c.defined_address()?)
+            }
+            2 | 3 => {
+                let offset = data.get(start + 9).copied().unwrap_or(8) as usize;
+                let length = data.get(start + 10).copied().unwrap_or(8) as usize;
+                let mut sizes = Sizes {
+                    offset,
+                    length,
+                    base: 0,
+                };
+                let mut c = Cursor::new(data, start + 12, sizes);
+                sizes.base = c.defined_address()?;
+                // The superblock extension and end of file addresses.
+                c.skip(2 * offset)?;
+                c.sizes = sizes;
+                (sizes, c.defined_address()?)
+            }
+            version => candle::bail!("unsupported hdf5 superblock version {version}"),
+        };
+        if ![2, 4, 8].contains(&sizes.offset) || ![2, 4, 8].contains(&sizes.length) {
+            candle::bail!("unsupported hdf5 sizes {sizes:?}")
+        }
+        Ok(Self {
+            mmap: Arc::new(mmap),
+            sizes,
+            root,
+        })
+    }
+
+    // The members of the group with its object header at `address`, with the address of their
+    // object headers.
+    fn members(&self, address: usize) -> Result<Vec<(String, usize)>> {
+        let data: &[u8] = &self.mmap;
+        let sizes = self.sizes;
+        let mut members = vec![];
+        for message in messages(data, sizes, address)? {
+            let mut c = Cursor::new(message.data, 0, sizes);
+            match message.kind {
+                MSG_SYMBOL_TABLE => {
+                    let btree = c.defined_address()?;
+                    let heap = c.defined_address()?;
+                    let mut h = Cursor::new(data, heap, sizes);
+                    h.signature(b"HEAP")?;
+                    h.skip(4)?;
+                    h.length()?;
+                    h.length()?;
+                    let heap = h.defined_address()?;
+                    self.symbol_table(btree, heap, &mut members)?
+                }
+                MSG_LINK => {
+                    c.skip(1)?;
+                    let flags = c.u8()?;
+                    let link_type = if flags & 0x8 != 0 { c.u8()? } else { 0 };
+                    if flags & 0x4 != 0 {
+                        c.skip(8)?
+                    }
+                    if flags & 0x10 != 0 {
+                        c.skip(1)?
+                    }
+                    let len = c.uint(1 << (flags & 0x3))? as usize;
+                    let name = String::from_utf8_lossy(c.bytes(len)?).to_string();
+                    // Only the hard links are followed.
+                    if link_type == 0 {
+                        members.push((name, c.defined_address()?))
+                    }
+                }
+                MSG_LINK_INFO => {
+                    c.skip(1)?;
+                    if c.u8()? & 0x1 != 0 {
+                        c.skip(8)?
+                    }
+                    if c.address()?.is_some() {
+                        candle::bail!("the hdf5 dense link storage is not supported")
+                    }
+                }
+                _ => {}
+            }
+        }
+        Ok(members)
+    }
+
+    // The entries of a group symbol table, from its B-tree and the data of its local heap.
+    fn symbol_table(
+        &self,
+        address: usize,
+        heap: usize,
+        members: &mut Vec<(String, usize)>,
+    ) -> Result<()> {
+        let data: &[u8] = &self.mmap;
+        let mut c = Cursor::new(data, address, self.sizes);
+        c.signature(b"TREE")?;
+        if c.u8()? != 0 {
+            candle::bail!("expected a B-tree of groups at {address}")
+        }
+        let level = c.u8()?;
+        let entries = c.u16()? as usize;
+        c.address()?;
+        c.address()?;
+        for _ in 0..entries {
+            c.length()?;
+            let child = c.defined_address()?;
+            if level > 0 {
+                self.symbol_table(child, heap, members)?;
+                continue;
+            }
+            let mut s = Cursor::new(data, child, self.sizes);
+            s.signature(b"SNOD")?;
+            s.skip(2)?;
+            let symbols = s.u16()?;
+            for _ in 0..symbols {
+                let name = heap + s.uint(self.sizes.offset)? as usize;
+                let header = s.defined_address()?;
+                s.skip(24)?;
+                let name = match data.get(name..) {
+                    Some(name) => name.split(|&b| b == 0).next().unwrap_or_default(),
+                    None => candle::bail!("invalid hdf5 symbol name offset"),
+                };
+                members.push((String::from_utf8_lossy(name).to_string(), header))
+            }
+        }
+        Ok(())
+    }
+
+    // The address of the object header at `path`.
+    fn object(&self, path: &str) -> Result<usize> {
+        let mut address = self.root;
+        for name in path.split('/').filter(|n| !n.is_empty()) {
+            address = match self.members(address)?.into_iter().find(|(n, _)| n == name) {
+                Some((_, address)) => address,
+                None => candle::bail!("no {name} in the hdf5 path {path}"),
+            }
+        }
+        Ok(address)
+    }
+
+    /// The names of the members of a group, the root group being `/`.
+    pub fn names(&self, group: &str) -> Result<Vec<String>> {
+        let address = self.object(group)?;
+        let mut names = self
+            .members(address)?
+            .into_iter()
+            .map(|(name, _)| name)
+            .collect::<Vec<_>>();
+        names.sort();
+        Ok(names)
+    }
+
+    /// Opens a dataset, this only reads its metadata and the index of its chunks.
+    pub fn dataset(&self, path: &str) -> Result<Hdf5Dataset> {
+        let address = self.object(path)?;
+        Hdf5Dataset::new(self.mmap.clone(), self.sizes, address)
+    }
+}
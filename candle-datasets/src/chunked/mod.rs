@@ -0,0 +1,230 @@
+//! Lazy readers for chunked arrays stored in HDF5 files or Zarr directories.
+//!
+//! Scientific datasets are often stored as large n-dimensional arrays split in chunks that are
+//! compressed independently. The readers only parse the metadata when opening an array, the
+//! chunks are read and decompressed when some elements are requested, so that arrays larger
+//! than memory can be used for training without converting them first.
+//!
+//! Both formats implement [`ChunkedArray`], which reads chunks or arbitrary hyper-rectangles as
+//! cpu tensors. The integer types that do not exist in candle are converted as for npy files:
+//! booleans are loaded as `u8`, `u16` as `u32`, and the other integers as `i64`.
+//!
+//! ```ignore
+//! use candle_datasets::chunked::{hdf5::Hdf5File, zarr::ZarrArray, ChunkedArray};
+//! let file = Hdf5File::open("era5.h5")?;
+//! let temperature = file.dataset("surface/t2m")?;
+//! for start in (0..temperature.shape()[0]).step_by(32) {
+//!     let batch = temperature.read_rows(start, 32.min(temperature.shape()[0] - start))?;
+//! }
+//! let labels = ZarrArray::open("labels.zarr")?.read_all()?;
+//! ```
+use candle::{DType, Result, Tensor};
+use std::ops::Range;
+
+mod codecs;
+pub mod hdf5;
+pub mod zarr;
+
+/// An n-dimensional array stored by chunks, the tensors are created on the cpu.
+pub trait ChunkedArray {
+    fn shape(&self) -> &[usize];
+
+    /// The shape of the chunks, the chunks on the upper edges of the array can be smaller.
+    fn chunk_shape(&self) -> &[usize];
+
+    fn dtype(&self) -> DType;
+
+    /// Reads the chunk at position `chunk` in the grid of chunks, see [`Self::grid_shape`].
+    fn read_chunk(&self, chunk: &[usize]) -> Result<Tensor>;
+
+    /// The number of chunks along each dimension.
+    fn grid_shape(&self) -> Vec<usize> {
+        self.shape()
+            .iter()
+            .zip(self.chunk_shape())
+            .map(|(&d, &c)| d.div_ceil(c.max(1)))
+            .collect()
+    }
+
+    /// Reads the elements in the given range for each dimension, only the chunks that intersect
+    /// the ranges are read.
+    fn read(&self, ranges: &[Range<usize>]) -> Result<Tensor> {
+        let shape = self.shape();
+        if ranges.len() != shape.len() {
+            candle::bail!(
+                "{} ranges for an array of rank {}",
+                ranges.len(),
+                shape.len()
+            )
+        }
+        for (range, &dim) in ranges.iter().zip(shape) {
+            if range.start > range.end || range.end > dim {
+                candle::bail!("range {range:?} out of bounds for dimension {dim}")
+            }
+        }
+        if ranges.iter().any(|r| r.is_empty()) {
+            let dims = ranges.iter().map(|r| r.len()).collect::<Vec<_>>();
+            return Tensor::zeros(dims, self.dtype(), &candle::Device::Cpu);
+        }
+        let mut chunk = vec![0; shape.len()];
+        assemble(self, ranges, 0, &mut chunk)
+    }
+
+    /// Reads `len` entries along the first dimension starting from `start`.
+    fn read_rows(&self, start: usize, len: usize) -> Result<Tensor> {
+        let mut ranges = self.shape().iter().map(|&d| 0..d).collect::<Vec<_>>();
+        match ranges.first_mut() {
+            None => candle::bail!("cannot read rows from a scalar array"),
+            Some(first) => *first = start..start + len,
+        }
+        self.read(&ranges)
+    }
+
+    fn read_all(&self) -> Result<Tensor> {
+        let ranges = self.shape().iter().map(|&d| 0..d).collect::<Vec<_>>();
+        self.read(&ranges)
+    }
+}
+
+// Concatenates the parts of the chunks intersecting `ranges` along the dimensions from `dim`,
+// the positions of the chunks for the previous dimensions are in `chunk`.
+fn assemble<A: ChunkedArray + ?Sized>(
+    array: &A,
+    ranges: &[Range<usize>],
+    dim: usize,
+    chunk: &mut [usize],
+) -> Result<Tensor> {
+    let chunk_shape = array.chunk_shape();
+    if dim == ranges.len() {
+        let mut tensor = array.read_chunk(chunk)?;
+        for (d, range) in ranges.iter().enumerate() {
+            let offset = chunk[d] * chunk_shape[d];
+            let start = range.start.max(offset) - offset;
+            let end = range.end.min(offset + chunk_shape[d]) - offset;
+            if start > 0 || end < tensor.dim(d)? {
+                tensor = tensor.narrow(d, start, end - start)?
+            }
+        }
+        return Ok(tensor);
+    }
+    let size = chunk_shape[dim].max(1);
+    let range = &ranges[dim];
+    let parts = (range.start / size..range.end.div_ceil(size))
+        .map(|c| {
+            chunk[dim] = c;
+            assemble(array, ranges, dim + 1, chunk)
+        })
+        .collect::<Result<Vec<_>>>()?;
+    if parts.len() == 1 {
+        Ok(parts.into_iter().next().expect("one part"))
+    } else {
+        Tensor::cat(&parts, dim)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    Bool,
+    Int,
+    UInt,
+    Float,
+}
+
+/// The type of the elements as stored in a file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Element {
+    kind: Kind,
+    size: usize,
+    big_endian: bool,
+}
+
+impl Element {
+    fn dtype(&self) -> Result<DType> {
+        let dtype = match (self.kind, self.size) {
+            (Kind::Bool, 1) | (Kind::UInt, 1) => DType::U8,
+            (Kind::UInt, 2) | (Kind::UInt, 4) => DType::U32,
+            (Kind::Int, 1 | 2 | 4 | 8) | (Kind::UInt, 8) => DType::I64,
+            (Kind::Float, 2) => DType::F16,
+            (Kind::Float, 4) => DType::F32,
+            (Kind::Float, 8) => DType::F64,
+            (kind, size) => candle::bail!("unsupported element type {kind:?} with {size} bytes"),
+        };
+        Ok(dtype)
+    }
+
+    // Converts elements of `N` bytes in little-endian.
+    fn convert<const N: usize, T: candle::WithDType>(
+        data: &[u8],
+        shape: &[usize],
+        conv: impl Fn([u8; N]) -> Result<T>,
+    ) -> Result<Tensor> {
+        let data = data
+            .chunks_exact(N)
+            .map(|c| conv(c.try_into().expect("chunks of N bytes")))
+            .collect::<Result<Vec<_>>>()?;
+        Tensor::from_vec(data, shape, &candle::Device::Cpu)
+    }
+
+    /// Creates a tensor from the elements in `data`, in row-major order.
+    fn decode(&self, data: &[u8], shape: &[usize]) -> Result<Tensor> {
+        let len = shape.iter().product::<usize>() * self.size;
+        if data.len() < len {
+            candle::bail!("{} bytes of data for {shape:?} elements", data.len())
+        }
+        let data = &data[..len];
+        let swapped;
+        let data = if self.big_endian && self.size > 1 {
+            let mut bytes = data.to_vec();
+            bytes.chunks_exact_mut(self.size).for_each(|c| c.reverse());
+            swapped = bytes;
+            swapped.as_slice()
+        } else {
+            data
+        };
+        match (self.kind, self.size) {
+            (Kind::Bool, 1) => Self::convert(data, shape, |[b]| Ok((b != 0) as u8)),
+            (Kind::UInt, 1) => Tensor::from_vec(data.to_vec(), shape, &candle::Device::Cpu),
+            (Kind::UInt, 2) => Self::convert(data, shape, |b| Ok(u16::from_le_bytes(b) as u32)),
+            (Kind::UInt, 4) => Self::convert(data, shape, |b| Ok(u32::from_le_bytes(b))),
+            (Kind::UInt, 8) => Self::convert(data, shape, |b| {
+                let v = u64::from_le_bytes(b);
+                i64::try_from(v).map_err(|_| candle::Error::Msg(format!("{v} overflows i64")))
+            }),
+            (Kind::Int, 1) => Self::convert(data, shape, |b| Ok(i8::from_le_bytes(b) as i64)),
+            (Kind::Int, 2) => Self::convert(data, shape, |b| Ok(i16::from_le_bytes(b) as i64)),
+            (Kind::Int, 4) => Self::convert(data, shape, |b| Ok(i32::from_le_bytes(b) as i64)),
+            (Kind::Int, 8) => Self::convert(data, shape, |b| Ok(i64::from_le_bytes(b))),
+            (Kind::Float, 2) => Self::convert(data, shape, |b| Ok(half::f16::from_le_bytes(b))),
+            (Kind::Float, 4) => Self::convert(data, shape, |b| Ok(f32::from_le_bytes(b))),
+            (Kind::Float, 8) => Self::convert(data, shape, |b| Ok(f64::from_le_bytes(b))),
+            (kind, size) => candle::bail!("unsupported element type {kind:?} with {size} bytes"),
+        }
+    }
+}
+
+// The chunk at position `chunk` with the elements in `data` for the full chunk shape, the
+// chunks on the edges of the array are truncated.
+fn edge_chunk(
+    data: Tensor,
+    chunk: &[usize],
+    chunk_shape: &[usize],
+    shape: &[usize],
+) -> Result<Tensor> {
+    let mut data = data;
+    for (d, (&c, &size)) in chunk.iter().zip(chunk_shape).enumerate() {
+        let len = shape[d].saturating_sub(c * size).min(size);
+        if len < size {
+            data = data.narrow(d, 0, len)?
+        }
+    }
+    Ok(data)
+}
+
+// Checks that `chunk` is a valid position in the grid of an array.
+fn check_chunk<A: ChunkedArray + ?Sized>(array: &A, chunk: &[usize]) -> Result<()> {
+    let grid = array.grid_shape();
+    if chunk.len() != grid.len() || chunk.iter().zip(grid.iter()).any(|(c, g)| c >= g) {
+        candle::bail!("chunk {chunk:?} out of the grid {grid:?}")
+    }
+    Ok(())
+}
@@ -0,0 +1,212 @@
+//! Zarr arrays, in the version 2 format.
+//!
+//! An array is a directory with its metadata in a `.zarray` json file and one file per chunk,
+//! named after the position of the chunk in the grid, e.g. `2.0.1`. The chunks can be
+//! compressed with the blosc (lz4, zlib or zstd), zlib, gzip, zstd or lz4 numcodecs compressors,
+//! filters are not supported. Chunks that are missing from the directory are filled with the
+//! fill value of the array.
+use super::{check_chunk, codecs, edge_chunk, ChunkedArray, Element, Kind};
+use candle::{DType, Device, Result, Tensor};
+use serde_json::Value;
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Compressor {
+    Blosc,
+    Zlib,
+    Gzip,
+    Zstd,
+    Lz4,
+}
+
+impl Compressor {
+    fn decompress(&self, data: &[u8]) -> Result<Vec<u8>> {
+        match self {
+            Self::Blosc => codecs::blosc(data),
+            Self::Zlib => codecs::zlib(data),
+            Self::Gzip => codecs::gzip(data),
+            Self::Zstd => codecs::zstd(data),
+            Self::Lz4 => codecs::lz4(data),
+        }
+    }
+}
+
+/// A Zarr array stored in a directory.
+#[derive(Debug, Clone)]
+pub struct ZarrArray {
+    dir: PathBuf,
+    shape: Vec<usize>,
+    chunks: Vec<usize>,
+    element: Element,
+    dtype: DType,
+    compressor: Option<Compressor>,
+    fill_value: Value,
+    fortran_order: bool,
+    separator: char,
+}
+
+fn parse_dtype(dtype: &str) -> Result<Element> {
+    let mut chars = dtype.chars();
+    let big_endian = match chars.next() {
+        Some('>') => true,
+        Some('<' | '|') => false,
+        _ => candle::bail!("unsupported zarr dtype {dtype}"),
+    };
+    let kind = match chars.next() {
+        Some('b') => Kind::Bool,
+        Some('i') => Kind::Int,
+        Some('u') => Kind::UInt,
+        Some('f') => Kind::Float,
+        _ => candle::bail!("unsupported zarr dtype {dtype}"),
+    };
+    let size = match chars.as_str().parse::<usize>() {
+        Ok(size) => size,
+        Err(_) => candle::bail!("unsupported zarr dtype {dtype}"),
+    };
+    Ok(Element {
+        kind,
+        size,
+        big_endian,
+    })
+}
+
+fn usizes(value: &Value, name: &str) -> Result<Vec<usize>> {
+    let values = match value.get(name).and_then(|v| v.as_array()) {
+        Some(values) => values,
+        None => candle::bail!("no {name} in the zarr metadata"),
+    };
+    values
+        .iter()
+        .map(|v| match v.as_u64() {
+            Some(v) => Ok(v as usize),
+            None => candle::bail!("invalid {name} in the zarr metadata"),
+        })
+        .collect()
+}
+
+impl ZarrArray {
+    /// Opens the array in the directory `dir`, only the metadata is read.
+    pub fn open<P: AsRef<Path>>(dir: P) -> Result<Self> {
+        let dir = dir.as_ref();
+        let metadata = std::fs::read_to_string(dir.join(".zarray"))?;
+        let metadata: Value = serde_json::from_str(&metadata).map_err(candle::Error::wrap)?;
+        match metadata.get("zarr_format").and_then(|v| v.as_u64()) {
+            Some(2) => {}
+            format => candle::bail!("unsupported zarr format {format:?} in {dir:?}"),
+        }
+        let shape = usizes(&metadata, "shape")?;
+        let chunks = usizes(&metadata, "chunks")?;
+        if chunks.len() != shape.len() || chunks.contains(&0) {
+            candle::bail!("invalid chunks {chunks:?} for the shape {shape:?}")
+        }
+        let element = match metadata.get("dtype").and_then(|v| v.as_str()) {
+            Some(dtype) => parse_dtype(dtype)?,
+            None => candle::bail!("unsupported zarr dtype {:?}", metadata.get("dtype")),
+        };
+        let compressor = match metadata.get("compressor") {
+            None | Some(Value::Null) => None,
+            Some(compressor) => match compressor.get("id").and_then(|v| v.as_str()) {
+                Some("blosc") => Some(Compressor::Blosc),
+                Some("zlib") => Some(Compressor::Zlib),
+                Some("gzip") => Some(Compressor::Gzip),
+                Some("zstd") => Some(Compressor::Zstd),
+                Some("lz4") => Some(Compressor::Lz4),
+                id => candle::bail!("unsupported zarr compressor {id:?}"),
+            },
+        };
+        match metadata.get("filters") {
+            None | Some(Value::Null) => {}
+            Some(Value::Array(filters)) if filters.is_empty() => {}
+            Some(filters) => candle::bail!("unsupported zarr filters {filters}"),
+        }
+        let fortran_order = match metadata.get("order").and_then(|v| v.as_str()) {
+            None | Some("C") => false,
+            Some("F") => true,
+            Some(order) => candle::bail!("unsupported zarr order {order}"),
+        };
+        let separator = match metadata.get("dimension_separator").and_then(|v| v.as_str()) {
+            None | Some(".") => '.',
+            Some("/") => '/',
+            Some(separator) => candle::bail!("unsupported zarr dimension separator {separator}"),
+        };
+        Ok(Self {
+            dir: dir.to_path_buf(),
+            shape,
+            chunks,
+            dtype: element.dtype()?,
+            element,
+            compressor,
+            fill_value: metadata.get("fill_value").cloned().unwrap_or(Value::Null),
+            fortran_order,
+            separator,
+        })
+    }
+
+    fn chunk_path(&self, chunk: &[usize]) -> PathBuf {
+        let key = chunk
+            .iter()
+            .map(|c| c.to_string())
+            .collect::<Vec<_>>()
+            .join(&self.separator.to_string());
+        // The chunk of a scalar array is named 0.
+        let key = if key.is_empty() { "0".to_string() } else { key };
+        self.dir.join(key)
+    }
+
+    // A chunk with the fill value, null is used for arrays without a fill value.
+    fn fill(&self) -> Result<Tensor> {
+        let shape = self.chunks.as_slice();
+        let cpu = &Device::Cpu;
+        let float = match &self.fill_value {
+            Value::Null => 0.,
+            Value::Bool(b) => *b as u8 as f64,
+            Value::Number(n) => match (self.dtype, n.as_i64(), n.as_u64()) {
+                (DType::I64, Some(v), _) => return Tensor::full(v, shape, cpu),
+                (DType::U32, _, Some(v)) => return Tensor::full(v as u32, shape, cpu),
+                (DType::U8, _, Some(v)) => return Tensor::full(v as u8, shape, cpu),
+                _ => n.as_f64().unwrap_or(0.),
+            },
+            Value::String(s) if s == "NaN" => f64::NAN,
+            Value::String(s) if s == "Infinity" => f64::INFINITY,
+            Value::String(s) if s == "-Infinity" => f64::NEG_INFINITY,
+            value => candle::bail!("unsupported zarr fill value {value}"),
+        };
+        Tensor::full(float, shape, cpu)?.to_dtype(self.dtype)
+    }
+}
+
+impl ChunkedArray for ZarrArray {
+    fn shape(&self) -> &[usize] {
+        &self.shape
+    }
+
+    fn chunk_shape(&self) -> &[usize] {
+        &self.chunks
+    }
+
+    fn dtype(&self) -> DType {
+        self.dtype
+    }
+
+    fn read_chunk(&self, chunk: &[usize]) -> Result<Tensor> {
+        check_chunk(self, chunk)?;
+        let path = self.chunk_path(chunk);
+        let data = if path.exists() {
+            let data = std::fs::read(&path)?;
+            let data = match self.compressor {
+                None => data,
+                Some(compressor) => compressor.decompress(&data)?,
+            };
+            if self.fortran_order {
+                let shape = self.chunks.iter().rev().copied().collect::<Vec<_>>();
+                let dims = (0..shape.len()).rev().collect::<Vec<_>>();
+                self.element.decode(&data, &shape)?.permute(dims)?
+            } else {
+                self.element.decode(&data, &self.chunks)?
+            }
+        } else {
+            self.fill()?
+        };
+        edge_chunk(data, chunk, &self.chunks, &self.shape)
+    }
+}
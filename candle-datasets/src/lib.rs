@@ -1,5 +1,6 @@
 //! Datasets & Dataloaders for Candle
 pub mod batcher;
+pub mod chunked;
 pub mod hub;
 pub mod nlp;
 pub mod vision;
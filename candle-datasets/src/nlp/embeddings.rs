@@ -0,0 +1,426 @@
+//! Batch inference for text embeddings.
+//!
+//! An [`EmbeddingJob`] computes the embeddings of a stream of texts and writes them to a
+//! directory, by shards of [`EmbeddingJobConfig::shard_size`] texts. The texts of a shard are
+//! tokenized in parallel, sorted by length and grouped in batches of at most
+//! [`EmbeddingJobConfig::max_batch_tokens`] tokens including the padding, so that short texts
+//! are processed in large batches. The batches are processed by a model per device, each of them
+//! running on its own thread.
+//!
+//! Each shard is written as `embeddings-00000.npy` with shape `(num_texts, dim)`, or as
+//! `embeddings-00000.parquet` with an `index` column for the position of the text in the stream
+//! and an `embedding` list column. After each shard, the number of shards and of texts done are
+//! saved in `checkpoint.txt`: running the job again on the same stream skips the texts that have
+//! already been processed, so an interrupted job resumes from its last shard.
+//!
+//! ```ignore
+//! let models = vec![BertEmbedder::load(&Device::new_cuda(0)?)?, BertEmbedder::load(&Device::new_cuda(1)?)?];
+//! let mut job = EmbeddingJob::new(models, tokenizer, "embeddings", EmbeddingJobConfig::default())?;
+//! let lines = std::io::BufReader::new(std::fs::File::open("corpus.txt")?).lines();
+//! let progress = job.run(lines.map_while(|l| l.ok()))?;
+//! println!("{} texts in {} shards", progress.num_texts, progress.num_shards);
+//! ```
+use candle::{DType, Device, Error, Result, Tensor};
+use rayon::prelude::*;
+use std::path::{Path, PathBuf};
+use std::sync::{mpsc, Mutex};
+
+/// A model computing one embedding per sequence, e.g. a BERT encoder followed by a pooling.
+pub trait EmbeddingModel: Send {
+    /// `input_ids` and `attention_mask` have shape `(batch, seq_len)` and dtype `u32`, the mask
+    /// is `1` for the tokens and `0` for the padding at the end of the sequences. Returns the
+    /// embeddings with shape `(batch, dim)`.
+    fn embed(&mut self, input_ids: &Tensor, attention_mask: &Tensor) -> Result<Tensor>;
+
+    /// The device of the weights, the inputs are created on it.
+    fn device(&self) -> &Device;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputFormat {
+    Npy,
+    Parquet,
+}
+
+impl OutputFormat {
+    fn extension(&self) -> &'static str {
+        match self {
+            Self::Npy => "npy",
+            Self::Parquet => "parquet",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EmbeddingJobConfig {
+    /// The maximum number of tokens in a batch, counting the padding.
+    pub max_batch_tokens: usize,
+    /// The texts are truncated to this number of tokens.
+    pub max_seq_len: usize,
+    /// The number of texts per output file, the progress is saved after each of them.
+    pub shard_size: usize,
+    pub pad_id: u32,
+    pub format: OutputFormat,
+}
+
+impl Default for EmbeddingJobConfig {
+    fn default() -> Self {
+        Self {
+            max_batch_tokens: 16384,
+            max_seq_len: 512,
+            shard_size: 10000,
+            pad_id: 0,
+            format: OutputFormat::Npy,
+        }
+    }
+}
+
+/// The work done by a job, as saved in its checkpoint.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Progress {
+    pub num_shards: usize,
+    pub num_texts: usize,
+}
+
+type Encoder = Box<dyn Fn(&str) -> Result<Vec<u32>> + Send + Sync>;
+
+// Everything but the models, shared with the tokenization threads.
+struct Shards {
+    encode: Encoder,
+    output_dir: PathBuf,
+    config: EmbeddingJobConfig,
+}
+
+pub struct EmbeddingJob<M: EmbeddingModel> {
+    models: Vec<M>,
+    shards: Shards,
+}
+
+// The texts of a shard processed together, `indices` are their positions in the shard.
+struct Batch {
+    indices: Vec<usize>,
+    tokens: Vec<Vec<u32>>,
+}
+
+// The embeddings of a batch, one row per index.
+type BatchOutput = Result<(Vec<usize>, Vec<Vec<f32>>)>;
+
+fn embed_batch<M: EmbeddingModel>(model: &mut M, batch: Batch, pad_id: u32) -> BatchOutput {
+    let seq_len = batch.tokens.iter().map(|t| t.len()).max().unwrap_or(0);
+    let mut input_ids = Vec::with_capacity(batch.tokens.len() * seq_len);
+    let mut mask = Vec::with_capacity(batch.tokens.len() * seq_len);
+    for tokens in batch.tokens.iter() {
+        input_ids.extend_from_slice(tokens);
+        input_ids.resize(input_ids.len() + seq_len - tokens.len(), pad_id);
+        mask.resize(mask.len() + tokens.len(), 1u32);
+        mask.resize(mask.len() + seq_len - tokens.len(), 0u32);
+    }
+    let shape = (batch.tokens.len(), seq_len);
+    let input_ids = Tensor::from_vec(input_ids, shape, model.device())?;
+    let mask = Tensor::from_vec(mask, shape, model.device())?;
+    let embeddings = model.embed(&input_ids, &mask)?;
+    let embeddings = embeddings.to_dtype(DType::F32)?.to_vec2::<f32>()?;
+    if embeddings.len() != batch.indices.len() {
+        candle::bail!(
+            "{} embeddings for a batch of {} texts",
+            embeddings.len(),
+            batch.indices.len()
+        )
+    }
+    Ok((batch.indices, embeddings))
+}
+
+const PARQUET_SCHEMA: &str = "
+message embeddings {
+    REQUIRED INT64 index;
+    REQUIRED GROUP embedding (LIST) {
+        REPEATED GROUP list {
+            REQUIRED FLOAT element;
+        }
+    }
+}
+";
+
+fn write_parquet(path: &Path, first_index: usize, embeddings: &[Vec<f32>]) -> Result<()> {
+    use parquet::data_type::{FloatType, Int64Type};
+    use parquet::file::{properties::WriterProperties, writer::SerializedFileWriter};
+    use std::sync::Arc;
+
+    let schema =
+        parquet::schema::parser::parse_message_type(PARQUET_SCHEMA).map_err(Error::wrap)?;
+    let props = Arc::new(WriterProperties::builder().build());
+    let file = std::fs::File::create(path)?;
+    let mut writer =
+        SerializedFileWriter::new(file, Arc::new(schema), props).map_err(Error::wrap)?;
+    let mut row_group = writer.next_row_group().map_err(Error::wrap)?;
+    let indices = (0..embeddings.len())
+        .map(|i| (first_index + i) as i64)
+        .collect::<Vec<_>>();
+    let values = embeddings.concat();
+    // The first value of each list starts a new row, all the values are defined.
+    let def_levels = vec![1i16; values.len()];
+    let rep_levels = embeddings
+        .iter()
+        .flat_map(|e| (0..e.len()).map(|i| (i > 0) as i16))
+        .collect::<Vec<_>>();
+    let mut column = 0;
+    while let Some(mut writer) = row_group.next_column().map_err(Error::wrap)? {
+        match column {
+            0 => writer
+                .typed::<Int64Type>()
+                .write_batch(&indices, None, None),
+            _ => writer.typed::<FloatType>().write_batch(
+                &values,
+                Some(&def_levels),
+                Some(&rep_levels),
+            ),
+        }
+        .map_err(Error::wrap)?;
+        writer.close().map_err(Error::wrap)?;
+        column += 1
+    }
+    row_group.close().map_err(Error::wrap)?;
+    writer.close().map_err(Error::wrap)?;
+    Ok(())
+}
+
+impl Shards {
+    fn shard_path(&self, shard: usize) -> PathBuf {
+        let extension = self.config.format.extension();
+        self.output_dir
+            .join(format!("embeddings-{shard:05}.{extension}"))
+    }
+
+    fn checkpoint_path(&self) -> PathBuf {
+        self.output_dir.join("checkpoint.txt")
+    }
+
+    fn progress(&self) -> Result<Progress> {
+        let path = self.checkpoint_path();
+        if !path.exists() {
+            return Ok(Progress::default());
+        }
+        let checkpoint = std::fs::read_to_string(&path)?;
+        let mut progress = Progress::default();
+        for line in checkpoint.lines() {
+            let value = |v: &str| {
+                v.trim()
+                    .parse::<usize>()
+                    .map_err(|_| Error::Msg(format!("invalid checkpoint line {line}")))
+            };
+            match line.split_once('=') {
+                Some(("num_shards", v)) => progress.num_shards = value(v)?,
+                Some(("num_texts", v)) => progress.num_texts = value(v)?,
+                _ => candle::bail!("invalid checkpoint line {line} in {path:?}"),
+            }
+        }
+        Ok(progress)
+    }
+
+    // Writes to a temporary file first so that an interrupted write does not leave a valid
+    // looking file.
+    fn write_atomic(&self, path: &Path, write: impl FnOnce(&Path) -> Result<()>) -> Result<()> {
+        let mut tmp = path.as_os_str().to_os_string();
+        tmp.push(".tmp");
+        let tmp = PathBuf::from(tmp);
+        write(&tmp)?;
+        std::fs::rename(&tmp, path)?;
+        Ok(())
+    }
+
+    fn write_shard(&self, shard: usize, first_index: usize, embeddings: &[Vec<f32>]) -> Result<()> {
+        let path = self.shard_path(shard);
+        self.write_atomic(&path, |tmp| match self.config.format {
+            OutputFormat::Npy => {
+                let dim = embeddings.first().map_or(0, |e| e.len());
+                let data = embeddings.concat();
+                Tensor::from_vec(data, (embeddings.len(), dim), &Device::Cpu)?.write_npy(tmp)
+            }
+            OutputFormat::Parquet => write_parquet(tmp, first_index, embeddings),
+        })
+    }
+
+    // The batches for the texts of a shard, the longest texts first.
+    fn batches(&self, tokens: Vec<Vec<u32>>) -> Vec<Batch> {
+        let mut order = (0..tokens.len()).collect::<Vec<_>>();
+        order.sort_by_key(|&i| std::cmp::Reverse(tokens[i].len()));
+        let mut tokens = tokens.into_iter().map(Some).collect::<Vec<_>>();
+        let mut batches: Vec<Batch> = vec![];
+        for i in order {
+            // The padded length of a batch is the one of its first text.
+            let fits = batches.last().is_some_and(|b| {
+                (b.tokens.len() + 1) * b.tokens[0].len() <= self.config.max_batch_tokens
+            });
+            let text = tokens[i].take().unwrap_or_default();
+            match batches.last_mut() {
+                Some(batch) if fits => {
+                    batch.indices.push(i);
+                    batch.tokens.push(text)
+                }
+                _ => batches.push(Batch {
+                    indices: vec![i],
+                    tokens: vec![text],
+                }),
+            }
+        }
+        batches
+    }
+
+    fn tokenize(&self, texts: &[String], first_index: usize) -> Result<Vec<Vec<u32>>> {
+        texts
+            .par_iter()
+            .enumerate()
+            .map(|(i, text)| {
+                let mut tokens = (self.encode)(text)?;
+                if tokens.is_empty() {
+                    candle::bail!("no tokens for the text {}", first_index + i)
+                }
+                tokens.truncate(self.config.max_seq_len);
+                Ok(tokens)
+            })
+            .collect()
+    }
+}
+
+impl<M: EmbeddingModel> EmbeddingJob<M> {
+    /// A job with one model per device, the results are written in `output_dir` which is
+    /// created if needed.
+    pub fn new<P: AsRef<Path>>(
+        models: Vec<M>,
+        tokenizer: tokenizers::Tokenizer,
+        output_dir: P,
+        config: EmbeddingJobConfig,
+    ) -> Result<Self> {
+        let encode = move |text: &str| match tokenizer.encode(text, true) {
+            Ok(encoding) => Ok(encoding.get_ids().to_vec()),
+            Err(err) => Err(Error::Msg(format!("Tokenizer error: {err}"))),
+        };
+        Self::from_encoder(models, encode, output_dir, config)
+    }
+
+    /// Same as [`Self::new`] with a custom tokenization.
+    pub fn from_encoder<P: AsRef<Path>>(
+        models: Vec<M>,
+        encode: impl Fn(&str) -> Result<Vec<u32>> + Send + Sync + 'static,
+        output_dir: P,
+        config: EmbeddingJobConfig,
+    ) -> Result<Self> {
+        if models.is_empty() {
+            candle::bail!("an embedding job needs at least one model")
+        }
+        if config.max_seq_len == 0 || config.max_batch_tokens < config.max_seq_len {
+            candle::bail!(
+                "the batches of {} tokens cannot hold a sequence of {} tokens",
+                config.max_batch_tokens,
+                config.max_seq_len
+            )
+        }
+        if config.shard_size == 0 {
+            candle::bail!("the shards have to hold at least one text")
+        }
+        std::fs::create_dir_all(output_dir.as_ref())?;
+        let shards = Shards {
+            encode: Box::new(encode),
+            output_dir: output_dir.as_ref().to_path_buf(),
+            config,
+        };
+        Ok(Self { models, shards })
+    }
+
+    pub fn config(&self) -> &EmbeddingJobConfig {
+        &self.shards.config
+    }
+
+    pub fn models(&self) -> &[M] {
+        &self.models
+    }
+
+    /// The path of the output file for a shard.
+    pub fn shard_path(&self, shard: usize) -> PathBuf {
+        self.shards.shard_path(shard)
+    }
+
+    /// The work saved in the checkpoint of the output directory.
+    pub fn progress(&self) -> Result<Progress> {
+        self.shards.progress()
+    }
+
+    /// Processes `texts`, skipping the ones already done according to the checkpoint, and
+    /// returns the updated progress.
+    pub fn run<I, S>(&mut self, texts: I) -> Result<Progress>
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        let mut progress = self.progress()?;
+        let mut texts = texts.into_iter().skip(progress.num_texts).map(Into::into);
+        let (batch_tx, batch_rx) = mpsc::channel::<Batch>();
+        let (output_tx, output_rx) = mpsc::channel::<BatchOutput>();
+        let batch_rx = Mutex::new(batch_rx);
+        let Self { models, shards } = self;
+        let pad_id = shards.config.pad_id;
+        std::thread::scope(|s| {
+            for model in models.iter_mut() {
+                let (batch_rx, output_tx) = (&batch_rx, output_tx.clone());
+                s.spawn(move || loop {
+                    let batch = match batch_rx.lock() {
+                        Ok(batch_rx) => batch_rx.recv(),
+                        Err(_) => break,
+                    };
+                    match batch {
+                        Ok(batch) => {
+                            if output_tx.send(embed_batch(model, batch, pad_id)).is_err() {
+                                break;
+                            }
+                        }
+                        Err(_) => break,
+                    }
+                });
+            }
+            drop(output_tx);
+            // Dropping the batch sender when returning stops the workers.
+            let batch_tx = batch_tx;
+            loop {
+                let shard = texts
+                    .by_ref()
+                    .take(shards.config.shard_size)
+                    .collect::<Vec<String>>();
+                if shard.is_empty() {
+                    return Ok(progress);
+                }
+                let tokens = shards.tokenize(&shard, progress.num_texts)?;
+                let batches = shards.batches(tokens);
+                let num_batches = batches.len();
+                for batch in batches {
+                    if batch_tx.send(batch).is_err() {
+                        candle::bail!("the embedding workers have stopped")
+                    }
+                }
+                let mut embeddings = vec![vec![]; shard.len()];
+                for _ in 0..num_batches {
+                    let (indices, rows) = match output_rx.recv() {
+                        Ok(output) => output?,
+                        Err(_) => candle::bail!("the embedding workers have stopped"),
+                    };
+                    for (i, row) in indices.into_iter().zip(rows) {
+                        embeddings[i] = row
+                    }
+                }
+                let dim = embeddings[0].len();
+                if embeddings.iter().any(|e| e.len() != dim) {
+                    candle::bail!("the models returned embeddings of different sizes")
+                }
+                shards.write_shard(progress.num_shards, progress.num_texts, &embeddings)?;
+                progress.num_shards += 1;
+                progress.num_texts += shard.len();
+                let checkpoint = format!(
+                    "num_shards={}\nnum_texts={}\n",
+                    progress.num_shards, progress.num_texts
+                );
+                shards.write_atomic(&shards.checkpoint_path(), |tmp| {
+                    Ok(std::fs::write(tmp, checkpoint)?)
+                })?;
+            }
+        })
+    }
+}
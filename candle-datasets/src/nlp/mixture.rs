@@ -0,0 +1,286 @@
+//! Weighted mixtures of token datasets.
+//!
+//! A [`DatasetMixer`] samples documents from multiple datasets, picking the dataset of each
+//! sample at random according to the mixture weights. The weights can change over the course of
+//! training with a [`Curriculum`], and can be flattened with a sampling temperature: the
+//! probability of a dataset is proportional to `weight^(1 / temperature)`, so a temperature of
+//! one keeps the weights as is and larger temperatures bring the mixture closer to uniform.
+//!
+//! Each dataset is read in a shuffled order that changes at every epoch. All the random draws
+//! come from the counter-based generator of [`candle::rng`], so the stream of samples only
+//! depends on the seed and the mixer can be resumed from a [`MixerState`] without replaying the
+//! previous samples.
+use candle::rng::{philox_block, uniform_f64};
+use candle::Result;
+
+/// A dataset of tokenized documents.
+pub trait TokenDataset {
+    fn len(&self) -> usize;
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn get(&self, index: usize) -> Result<Vec<u32>>;
+}
+
+impl TokenDataset for Vec<Vec<u32>> {
+    fn len(&self) -> usize {
+        self.as_slice().len()
+    }
+
+    fn get(&self, index: usize) -> Result<Vec<u32>> {
+        match self.as_slice().get(index) {
+            Some(tokens) => Ok(tokens.clone()),
+            None => candle::bail!("index {index} out of range ({})", self.as_slice().len()),
+        }
+    }
+}
+
+/// Normalizes `weights` into sampling probabilities, with `p_i ∝ w_i^(1 / temperature)`.
+pub fn mixture_probabilities(weights: &[f64], temperature: f64) -> Result<Vec<f64>> {
+    if temperature <= 0. || !temperature.is_finite() {
+        candle::bail!("the temperature should be positive, got {temperature}")
+    }
+    if let Some(w) = weights.iter().find(|w| **w < 0. || !w.is_finite()) {
+        candle::bail!("mixture weights should be non-negative, got {w}")
+    }
+    let scaled: Vec<f64> = weights.iter().map(|w| w.powf(1. / temperature)).collect();
+    let sum: f64 = scaled.iter().sum();
+    if sum <= 0. {
+        candle::bail!("at least one mixture weight should be positive ({weights:?})")
+    }
+    Ok(scaled.into_iter().map(|w| w / sum).collect())
+}
+
+/// Mixture weights that change at given steps.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Curriculum {
+    // Sorted by starting step, the first phase starts at step 0.
+    phases: Vec<(u64, Vec<f64>)>,
+}
+
+impl Curriculum {
+    /// Uses the same weights for the whole run.
+    pub fn constant(weights: Vec<f64>) -> Self {
+        Self {
+            phases: vec![(0, weights)],
+        }
+    }
+
+    /// Switches to `weights` from `step` onwards.
+    pub fn then(mut self, step: u64, weights: Vec<f64>) -> Result<Self> {
+        let last_step = self.phases.last().map_or(0, |(s, _)| *s);
+        if step <= last_step {
+            candle::bail!("curriculum phases should have increasing steps, {step} <= {last_step}")
+        }
+        self.phases.push((step, weights));
+        Ok(self)
+    }
+
+    /// The weights used at `step`.
+    pub fn weights(&self, step: u64) -> &[f64] {
+        let idx = self.phases.partition_point(|(s, _)| *s <= step);
+        &self.phases[idx.saturating_sub(1)].1
+    }
+}
+
+/// The position of the mixer in the stream of samples.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MixerState {
+    pub seed: u64,
+    /// The number of samples drawn so far.
+    pub step: u64,
+    /// The current epoch of each dataset.
+    pub epochs: Vec<u64>,
+    /// The number of documents read from each dataset in its current epoch.
+    pub positions: Vec<usize>,
+}
+
+/// Samples documents from a weighted mixture of datasets.
+pub struct DatasetMixer<D> {
+    datasets: Vec<D>,
+    curriculum: Curriculum,
+    temperature: f64,
+    shuffle: bool,
+    state: MixerState,
+    // The probabilities for the current phase and the weights they were computed from.
+    probabilities: Vec<f64>,
+    phase_weights: Vec<f64>,
+    // The order of the documents in the current epoch of each dataset.
+    orders: Vec<Option<Vec<usize>>>,
+}
+
+impl<D: TokenDataset> DatasetMixer<D> {
+    /// Creates a mixer where the weights of the datasets are their number of documents.
+    pub fn new(datasets: Vec<D>, seed: u64) -> Result<Self> {
+        let weights = datasets.iter().map(|d| d.len() as f64).collect();
+        Self::with_curriculum(datasets, Curriculum::constant(weights), seed)
+    }
+
+    pub fn with_weights(datasets: Vec<D>, weights: Vec<f64>, seed: u64) -> Result<Self> {
+        Self::with_curriculum(datasets, Curriculum::constant(weights), seed)
+    }
+
+    pub fn with_curriculum(datasets: Vec<D>, curriculum: Curriculum, seed: u64) -> Result<Self> {
+        if datasets.is_empty() {
+            candle::bail!("no datasets to mix")
+        }
+        for (step, weights) in curriculum.phases.iter() {
+            if weights.len() != datasets.len() {
+                candle::bail!(
+                    "the weights at step {step} have {} values but there are {} datasets",
+                    weights.len(),
+                    datasets.len()
+                )
+            }
+            mixture_probabilities(weights, 1.)?;
+            for (idx, (w, d)) in weights.iter().zip(datasets.iter()).enumerate() {
+                if *w > 0. && d.is_empty() {
+                    candle::bail!("dataset {idx} is empty but has a weight of {w} at step {step}")
+                }
+            }
+        }
+        let n = datasets.len();
+        let mut mixer = Self {
+            datasets,
+            curriculum,
+            temperature: 1.,
+            shuffle: true,
+            state: MixerState {
+                seed,
+                step: 0,
+                epochs: vec![0; n],
+                positions: vec![0; n],
+            },
+            probabilities: vec![],
+            phase_weights: vec![],
+            orders: vec![None; n],
+        };
+        mixer.update_probabilities()?;
+        Ok(mixer)
+    }
+
+    pub fn temperature(mut self, temperature: f64) -> Result<Self> {
+        self.temperature = temperature;
+        self.phase_weights.clear();
+        self.update_probabilities()?;
+        Ok(self)
+    }
+
+    /// Whether the documents of each dataset are read in a random order, this is the default.
+    pub fn shuffle(mut self, shuffle: bool) -> Self {
+        self.shuffle = shuffle;
+        self.orders.iter_mut().for_each(|o| *o = None);
+        self
+    }
+
+    pub fn datasets(&self) -> &[D] {
+        &self.datasets
+    }
+
+    /// The sampling probabilities of the datasets at the current step.
+    pub fn probabilities(&self) -> &[f64] {
+        &self.probabilities
+    }
+
+    pub fn state(&self) -> &MixerState {
+        &self.state
+    }
+
+    /// Resumes sampling from a state previously returned by [`Self::state`].
+    pub fn set_state(&mut self, state: MixerState) -> Result<()> {
+        let n = self.datasets.len();
+        if state.epochs.len() != n || state.positions.len() != n {
+            candle::bail!(
+                "the mixer state has {} epochs and {} positions but there are {n} datasets",
+                state.epochs.len(),
+                state.positions.len()
+            )
+        }
+        for (idx, (pos, d)) in state.positions.iter().zip(self.datasets.iter()).enumerate() {
+            if *pos > 0 && *pos >= d.len() {
+                candle::bail!(
+                    "position {pos} out of range for dataset {idx} ({})",
+                    d.len()
+                )
+            }
+        }
+        self.state = state;
+        self.orders.iter_mut().for_each(|o| *o = None);
+        self.update_probabilities()
+    }
+
+    fn update_probabilities(&mut self) -> Result<()> {
+        let weights = self.curriculum.weights(self.state.step);
+        if weights != self.phase_weights.as_slice() {
+            self.probabilities = mixture_probabilities(weights, self.temperature)?;
+            self.phase_weights = weights.to_vec();
+        }
+        Ok(())
+    }
+
+    fn document_index(&mut self, dataset_idx: usize) -> usize {
+        let len = self.datasets[dataset_idx].len();
+        let position = self.state.positions[dataset_idx];
+        if !self.shuffle {
+            return position;
+        }
+        let (seed, epoch) = (self.state.seed, self.state.epochs[dataset_idx]);
+        let order = self.orders[dataset_idx]
+            .get_or_insert_with(|| permutation(len, seed, dataset_idx, epoch));
+        order[position]
+    }
+
+    /// Draws the next sample, returning the index of its dataset and its tokens.
+    pub fn next_sample(&mut self) -> Result<(usize, Vec<u32>)> {
+        let u = uniform_f64(philox_block(self.state.seed, self.state.step)[0]);
+        let mut dataset_idx = 0;
+        let mut cumulative = 0.;
+        for (idx, p) in self.probabilities.iter().enumerate() {
+            if *p > 0. {
+                // Rounding errors can leave the sum slightly below one, this picks the last
+                // dataset with a non-zero probability in that case.
+                dataset_idx = idx;
+                cumulative += p;
+                if u < cumulative {
+                    break;
+                }
+            }
+        }
+        let document_idx = self.document_index(dataset_idx);
+        let tokens = self.datasets[dataset_idx].get(document_idx)?;
+        self.state.step += 1;
+        self.state.positions[dataset_idx] += 1;
+        if self.state.positions[dataset_idx] >= self.datasets[dataset_idx].len() {
+            self.state.positions[dataset_idx] = 0;
+            self.state.epochs[dataset_idx] += 1;
+            self.orders[dataset_idx] = None;
+        }
+        self.update_probabilities()?;
+        Ok((dataset_idx, tokens))
+    }
+}
+
+impl<D: TokenDataset> Iterator for DatasetMixer<D> {
+    type Item = Result<(usize, Vec<u32>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        Some(self.next_sample())
+    }
+}
+
+// A random permutation of `0..len` for an epoch of a dataset, using a Fisher-Yates shuffle.
+fn permutation(len: usize, seed: u64, dataset_idx: usize, epoch: u64) -> Vec<usize> {
+    // The shuffles use their own key so that they do not overlap with the counters used to pick
+    // the datasets.
+    let [k0, k1, _, _] = philox_block(seed, u64::MAX - dataset_idx as u64);
+    let key = (k0 as u64 | (k1 as u64) << 32) ^ epoch;
+    let mut order: Vec<usize> = (0..len).collect();
+    for i in (1..len).rev() {
+        let block = philox_block(key, (i / 4) as u64);
+        let j = ((block[i % 4] as u64 * (i as u64 + 1)) >> 32) as usize;
+        order.swap(i, j);
+    }
+    order
+}
@@ -1 +1,5 @@
+pub mod embeddings;
+pub mod mixture;
+pub mod sft;
 pub mod tinystories;
+pub mod tokenizer_training;
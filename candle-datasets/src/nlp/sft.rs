@@ -0,0 +1,180 @@
+//! Sequence packing for supervised fine-tuning.
+//!
+//! Multiple tokenized conversations are packed into fixed-length rows so that little compute is
+//! spent on padding. Each conversation gets its own segment in the row, the attention mask is
+//! block diagonal so that the tokens of a conversation cannot attend to the other ones, and the
+//! position ids restart at zero for each conversation.
+//!
+//! The labels are the next-token targets aligned with the inputs, as for the tinystories
+//! dataset. Only the tokens of the responses are trained on: the targets that are prompt tokens,
+//! that cross a conversation boundary, or that are padding have a zero weight in the loss mask.
+use candle::{Device, Result, Tensor};
+
+/// A tokenized conversation, made of prompt tokens that are not trained on and response tokens
+/// that are.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Conversation {
+    tokens: Vec<u32>,
+    trainable: Vec<bool>,
+}
+
+impl Conversation {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Appends tokens that are part of the context only, e.g. the system prompt or a user turn.
+    pub fn push_prompt(&mut self, tokens: &[u32]) -> &mut Self {
+        self.tokens.extend_from_slice(tokens);
+        self.trainable.resize(self.tokens.len(), false);
+        self
+    }
+
+    /// Appends tokens that the model has to learn to predict, e.g. an assistant turn.
+    pub fn push_response(&mut self, tokens: &[u32]) -> &mut Self {
+        self.tokens.extend_from_slice(tokens);
+        self.trainable.resize(self.tokens.len(), true);
+        self
+    }
+
+    pub fn tokens(&self) -> &[u32] {
+        &self.tokens
+    }
+
+    /// Whether each token is trained on.
+    pub fn trainable(&self) -> &[bool] {
+        &self.trainable
+    }
+
+    pub fn len(&self) -> usize {
+        self.tokens.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tokens.is_empty()
+    }
+}
+
+/// A batch of packed rows, all the tensors have shape `(batch, seq_len)` except the attention
+/// mask.
+#[derive(Debug, Clone)]
+pub struct PackedBatch {
+    /// The input tokens, as `u32`.
+    pub input_ids: Tensor,
+    /// The next-token targets, as `u32`, set to zero where the loss mask is zero.
+    pub labels: Tensor,
+    /// `1.` for the targets to train on and `0.` elsewhere, as `f32`.
+    pub loss_mask: Tensor,
+    /// The position of each token within its conversation, as `u32`.
+    pub position_ids: Tensor,
+    /// The index of the conversation in the row starting from 1, `0` for padding, as `u32`.
+    pub segment_ids: Tensor,
+    /// A block diagonal causal mask of shape `(batch, seq_len, seq_len)`, see
+    /// [`candle_nn::attention::block_diagonal_mask`].
+    pub attention_mask: Tensor,
+}
+
+/// Packs conversations into rows of `seq_len` tokens.
+#[derive(Debug, Clone)]
+pub struct PackingCollator {
+    seq_len: usize,
+    pad_token_id: u32,
+}
+
+impl PackingCollator {
+    pub fn new(seq_len: usize, pad_token_id: u32) -> Self {
+        Self {
+            seq_len,
+            pad_token_id,
+        }
+    }
+
+    pub fn seq_len(&self) -> usize {
+        self.seq_len
+    }
+
+    /// Assigns the conversations to rows using first-fit decreasing, returning the indexes of the
+    /// conversations in each row. Conversations longer than `seq_len` get a row of their own
+    /// and are truncated when collated.
+    pub fn pack(&self, conversations: &[Conversation]) -> Vec<Vec<usize>> {
+        let mut order: Vec<usize> = (0..conversations.len()).collect();
+        // The sort is stable so conversations of the same length keep their order.
+        order.sort_by_key(|&i| std::cmp::Reverse(conversations[i].len()));
+        let mut rows: Vec<(usize, Vec<usize>)> = vec![];
+        for idx in order {
+            let len = conversations[idx].len().min(self.seq_len);
+            match rows.iter_mut().find(|(used, _)| used + len <= self.seq_len) {
+                Some((used, row)) => {
+                    *used += len;
+                    row.push(idx)
+                }
+                None => rows.push((len, vec![idx])),
+            }
+        }
+        rows.into_iter().map(|(_, row)| row).collect()
+    }
+
+    /// Packs the conversations and returns a batch with as many rows as needed.
+    pub fn collate(&self, conversations: &[Conversation], device: &Device) -> Result<PackedBatch> {
+        let rows = self.pack(conversations);
+        self.collate_rows(conversations, &rows, device)
+    }
+
+    /// Builds a batch from rows of conversation indexes, e.g. as returned by [`Self::pack`].
+    pub fn collate_rows(
+        &self,
+        conversations: &[Conversation],
+        rows: &[Vec<usize>],
+        device: &Device,
+    ) -> Result<PackedBatch> {
+        let seq_len = self.seq_len;
+        let numel = rows.len() * seq_len;
+        let mut input_ids = vec![self.pad_token_id; numel];
+        let mut labels = vec![0u32; numel];
+        let mut loss_mask = vec![0f32; numel];
+        let mut position_ids = vec![0u32; numel];
+        let mut segment_ids = vec![0u32; numel];
+        for (row_idx, row) in rows.iter().enumerate() {
+            let row = row
+                .iter()
+                .map(|&idx| match conversations.get(idx) {
+                    Some(conv) => Ok(conv),
+                    None => candle::bail!(
+                        "conversation index {idx} out of range ({})",
+                        conversations.len()
+                    ),
+                })
+                .collect::<Result<Vec<_>>>()?;
+            let row_len: usize = row.iter().map(|conv| conv.len()).sum();
+            if row.len() > 1 && row_len > seq_len {
+                candle::bail!("row {row_idx} has {row_len} tokens, more than {seq_len}")
+            }
+            let mut start = row_idx * seq_len;
+            for (segment_idx, conv) in row.iter().enumerate() {
+                let len = conv.len().min(seq_len);
+                for i in 0..len {
+                    let pos = start + i;
+                    input_ids[pos] = conv.tokens[i];
+                    position_ids[pos] = i as u32;
+                    segment_ids[pos] = segment_idx as u32 + 1;
+                    if i + 1 < len && conv.trainable[i + 1] {
+                        labels[pos] = conv.tokens[i + 1];
+                        loss_mask[pos] = 1.;
+                    }
+                }
+                start += len;
+            }
+        }
+        let shape = (rows.len(), seq_len);
+        let segment_ids = Tensor::from_vec(segment_ids, shape, device)?;
+        let attention_mask = candle_nn::attention::block_diagonal_mask(&segment_ids, true)?;
+        Ok(PackedBatch {
+            input_ids: Tensor::from_vec(input_ids, shape, device)?,
+            labels: Tensor::from_vec(labels, shape, device)?,
+            loss_mask: Tensor::from_vec(loss_mask, shape, device)?,
+            position_ids: Tensor::from_vec(position_ids, shape, device)?,
+            segment_ids,
+            attention_mask,
+        })
+    }
+}
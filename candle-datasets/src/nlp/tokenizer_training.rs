@@ -0,0 +1,264 @@
+//! Training of BPE and unigram tokenizers.
+//!
+//! The corpus is split in words by the pre-tokenizer of the trained tokenizer and only the first
+//! `max_corpus_tokens` words are used, so that training on the start of a very large corpus
+//! does not require a separate pass to cut it. The pre-tokenization and the counting of the
+//! words run on all the cores. The trained tokenizer can be saved with
+//! `tokenizer.save("tokenizer.json", false)` and loaded back by the `tokenizers` crate or by
+//! the python `tokenizers` package.
+//!
+//! ```ignore
+//! let config = TrainerConfig::bpe(32_000)
+//!     .with_special_tokens(&["<|endoftext|>"])
+//!     .with_max_corpus_tokens(100_000_000);
+//! let tokenizer = train_tokenizer_from_files(&["corpus.txt"], &config)?;
+//! tokenizer.save("tokenizer.json", false).map_err(E::msg)?;
+//! ```
+use candle::{Error, Result};
+use rayon::prelude::*;
+use std::io::BufRead;
+use tokenizers::decoders::metaspace::Metaspace;
+use tokenizers::decoders::DecoderWrapper;
+use tokenizers::models::bpe::{BpeTrainerBuilder, BPE};
+use tokenizers::models::unigram::{Unigram, UnigramTrainer};
+use tokenizers::models::TrainerWrapper;
+use tokenizers::pre_tokenizers::byte_level::ByteLevel;
+use tokenizers::pre_tokenizers::metaspace::PrependScheme;
+use tokenizers::pre_tokenizers::PreTokenizerWrapper;
+use tokenizers::processors::PostProcessorWrapper;
+use tokenizers::{
+    AddedToken, OffsetReferential, OffsetType, PreTokenizedString, PreTokenizer, Tokenizer,
+};
+
+// The number of texts that are pre-tokenized in parallel at once.
+const CHUNK_SIZE: usize = 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TokenizerKind {
+    /// A byte-level BPE as used by GPT-2, any text can be encoded without unknown tokens.
+    Bpe,
+    /// A unigram language model as used by SentencePiece, the words are prefixed with `▁`.
+    Unigram,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TrainerConfig {
+    pub kind: TokenizerKind,
+    /// The size of the vocabulary including the special tokens.
+    pub vocab_size: usize,
+    /// The minimum number of occurrences of a pair to be merged, only used for BPE.
+    pub min_frequency: u64,
+    /// The special tokens get the first ids, in order.
+    pub special_tokens: Vec<String>,
+    /// The token used for the characters that were not seen during training, only used for
+    /// unigram. It is added to the special tokens if not already there.
+    pub unk_token: Option<String>,
+    /// The maximum length of a token in characters.
+    pub max_token_length: usize,
+    /// The maximum number of pre-tokenized words used for training, the whole corpus is used
+    /// when not set.
+    pub max_corpus_tokens: Option<usize>,
+}
+
+impl TrainerConfig {
+    pub fn bpe(vocab_size: usize) -> Self {
+        Self {
+            kind: TokenizerKind::Bpe,
+            vocab_size,
+            min_frequency: 2,
+            special_tokens: vec![],
+            unk_token: None,
+            max_token_length: 16,
+            max_corpus_tokens: None,
+        }
+    }
+
+    pub fn unigram(vocab_size: usize) -> Self {
+        Self {
+            kind: TokenizerKind::Unigram,
+            unk_token: Some("<unk>".to_string()),
+            ..Self::bpe(vocab_size)
+        }
+    }
+
+    pub fn with_special_tokens(mut self, special_tokens: &[&str]) -> Self {
+        self.special_tokens = special_tokens.iter().map(|s| s.to_string()).collect();
+        self
+    }
+
+    pub fn with_min_frequency(mut self, min_frequency: u64) -> Self {
+        self.min_frequency = min_frequency;
+        self
+    }
+
+    pub fn with_unk_token(mut self, unk_token: Option<&str>) -> Self {
+        self.unk_token = unk_token.map(|s| s.to_string());
+        self
+    }
+
+    pub fn with_max_token_length(mut self, max_token_length: usize) -> Self {
+        self.max_token_length = max_token_length;
+        self
+    }
+
+    pub fn with_max_corpus_tokens(mut self, max_corpus_tokens: usize) -> Self {
+        self.max_corpus_tokens = Some(max_corpus_tokens);
+        self
+    }
+}
+
+// The byte offsets of the ends of the pre-tokenized words of a text.
+fn word_ends(pre_tokenizer: &PreTokenizerWrapper, text: &str) -> Result<Vec<usize>> {
+    let mut pre_tokenized = PreTokenizedString::from(text);
+    pre_tokenizer
+        .pre_tokenize(&mut pre_tokenized)
+        .map_err(tokenizer_error)?;
+    Ok(pre_tokenized
+        .get_splits(OffsetReferential::Original, OffsetType::Byte)
+        .into_iter()
+        .map(|(_, (_, end), _)| end)
+        .collect())
+}
+
+// The texts from the start of the corpus that contain at most `max_tokens` words, the last
+// text is truncated.
+fn bounded_corpus<I, S>(
+    texts: I,
+    pre_tokenizer: &PreTokenizerWrapper,
+    max_tokens: Option<usize>,
+) -> Result<Vec<String>>
+where
+    I: IntoIterator<Item = S>,
+    S: AsRef<str>,
+{
+    let mut texts = texts.into_iter();
+    let mut corpus = vec![];
+    let mut num_tokens = 0;
+    loop {
+        let chunk = texts
+            .by_ref()
+            .take(CHUNK_SIZE)
+            .map(|s| s.as_ref().to_string())
+            .collect::<Vec<_>>();
+        if chunk.is_empty() {
+            return Ok(corpus);
+        }
+        let ends = chunk
+            .par_iter()
+            .map(|text| word_ends(pre_tokenizer, text))
+            .collect::<Result<Vec<_>>>()?;
+        for (mut text, ends) in chunk.into_iter().zip(ends) {
+            let remaining = max_tokens.map_or(usize::MAX, |max| max - num_tokens);
+            if ends.len() >= remaining {
+                if remaining > 0 {
+                    text.truncate(ends[remaining - 1]);
+                    corpus.push(text)
+                }
+                return Ok(corpus);
+            }
+            num_tokens += ends.len();
+            corpus.push(text)
+        }
+    }
+}
+
+/// Trains a tokenizer on `texts`.
+pub fn train_tokenizer<I, S>(texts: I, config: &TrainerConfig) -> Result<Tokenizer>
+where
+    I: IntoIterator<Item = S>,
+    S: AsRef<str>,
+{
+    if config.vocab_size <= config.special_tokens.len() {
+        candle::bail!(
+            "the vocabulary size {} has to be larger than the number of special tokens",
+            config.vocab_size
+        )
+    }
+    let mut special_tokens = config.special_tokens.clone();
+    let (mut tokenizer, pre_tokenizer, mut trainer) = match config.kind {
+        TokenizerKind::Bpe => {
+            let byte_level = ByteLevel::new(false, true, true);
+            let mut tokenizer = Tokenizer::new(BPE::default());
+            tokenizer
+                .with_decoder(DecoderWrapper::ByteLevel(byte_level))
+                .with_post_processor(PostProcessorWrapper::ByteLevel(byte_level));
+            let trainer = BpeTrainerBuilder::new()
+                .show_progress(false)
+                .vocab_size(config.vocab_size)
+                .min_frequency(config.min_frequency)
+                .special_tokens(added_tokens(&special_tokens))
+                .initial_alphabet(ByteLevel::alphabet())
+                .max_token_length(Some(config.max_token_length))
+                .build();
+            let pre_tokenizer = PreTokenizerWrapper::ByteLevel(byte_level);
+            (tokenizer, pre_tokenizer, TrainerWrapper::from(trainer))
+        }
+        TokenizerKind::Unigram => {
+            if let Some(unk) = config.unk_token.as_ref() {
+                if !special_tokens.contains(unk) {
+                    special_tokens.push(unk.clone())
+                }
+            }
+            let metaspace = Metaspace::new('▁', PrependScheme::Always, true);
+            let mut tokenizer = Tokenizer::new(Unigram::default());
+            tokenizer.with_decoder(DecoderWrapper::Metaspace(metaspace.clone()));
+            let trainer = UnigramTrainer::builder()
+                .show_progress(false)
+                .vocab_size(config.vocab_size as u32)
+                .special_tokens(added_tokens(&special_tokens))
+                .unk_token(config.unk_token.clone())
+                .max_piece_length(config.max_token_length)
+                .build()
+                .map_err(Error::wrap)?;
+            let pre_tokenizer = PreTokenizerWrapper::Metaspace(metaspace);
+            (tokenizer, pre_tokenizer, TrainerWrapper::from(trainer))
+        }
+    };
+    let corpus = bounded_corpus(texts, &pre_tokenizer, config.max_corpus_tokens)?;
+    if corpus.is_empty() {
+        candle::bail!("the training corpus is empty")
+    }
+    tokenizer.with_pre_tokenizer(pre_tokenizer);
+    tokenizer
+        .train(&mut trainer, corpus.iter())
+        .map_err(tokenizer_error)?;
+    Ok(tokenizer)
+}
+
+/// Trains a tokenizer on the lines of text files, the files are read lazily so that only the
+/// lines within `max_corpus_tokens` are loaded.
+pub fn train_tokenizer_from_files<P: AsRef<std::path::Path>>(
+    paths: &[P],
+    config: &TrainerConfig,
+) -> Result<Tokenizer> {
+    let mut lines = vec![];
+    for path in paths.iter() {
+        let file = std::fs::File::open(path)?;
+        lines.push(std::io::BufReader::new(file).lines())
+    }
+    // A read error ends the corpus and is returned instead of the tokenizer.
+    let mut error = None;
+    let texts = lines.into_iter().flatten().map_while(|line| match line {
+        Ok(line) => Some(line),
+        Err(err) => {
+            error = Some(err);
+            None
+        }
+    });
+    let tokenizer = train_tokenizer(texts, config);
+    match error {
+        Some(err) => Err(err.into()),
+        None => tokenizer,
+    }
+}
+
+fn tokenizer_error(err: tokenizers::Error) -> Error {
+    Error::Msg(format!("Tokenizer error: {err}"))
+}
+
+fn added_tokens(tokens: &[String]) -> Vec<AddedToken> {
+    tokens
+        .iter()
+        .map(|t| AddedToken::from(t.clone(), true))
+        .collect()
+}
@@ -0,0 +1,591 @@
+use candle::{DType, Result, Tensor};
+use candle_datasets::chunked::{hdf5::Hdf5File, zarr::ZarrArray, ChunkedArray};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+fn temp_dir(name: &str) -> Result<PathBuf> {
+    let dir = std::env::temp_dir().join(format!("candle-{name}-{}", std::process::id()));
+    std::fs::create_dir_all(&dir)?;
+    Ok(dir)
+}
+
+fn zlib(data: &[u8]) -> Vec<u8> {
+    let mut encoder = flate2::write::ZlibEncoder::new(vec![], flate2::Compression::default());
+    encoder.write_all(data).unwrap();
+    encoder.finish().unwrap()
+}
+
+// The byte shuffle used by blosc and by the hdf5 shuffle filter.
+fn shuffle(data: &[u8], size: usize) -> Vec<u8> {
+    let n = data.len() / size;
+    let mut out = vec![0u8; data.len()];
+    for i in 0..n {
+        for b in 0..size {
+            out[b * n + i] = data[i * size + b]
+        }
+    }
+    out
+}
+
+fn write_zarr(dir: &Path, metadata: &str, chunks: &[(&str, Vec<u8>)]) -> Result<ZarrArray> {
+    std::fs::create_dir_all(dir)?;
+    std::fs::write(dir.join(".zarray"), metadata)?;
+    for (key, data) in chunks {
+        let path = dir.join(key);
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?
+        }
+        std::fs::write(path, data)?
+    }
+    ZarrArray::open(dir)
+}
+
+// A [5, 4] array with [2, 3] chunks, the chunks on the edges are stored with their full size.
+fn grid_chunk(chunk: (usize, usize), value: impl Fn(usize, usize) -> f32) -> Vec<f32> {
+    let mut data = vec![];
+    for i in 0..2 {
+        for j in 0..3 {
+            let (i, j) = (chunk.0 * 2 + i, chunk.1 * 3 + j);
+            data.push(if i < 5 && j < 4 { value(i, j) } else { 0. })
+        }
+    }
+    data
+}
+
+#[test]
+fn zarr_raw() -> Result<()> {
+    let dir = temp_dir("zarr-raw")?;
+    let metadata = r#"{
+        "zarr_format": 2, "shape": [5, 4], "chunks": [2, 3], "dtype": "<f4",
+        "compressor": null, "fill_value": 7.5, "order": "C", "filters": null
+    }"#;
+    let value = |i: usize, j: usize| (i * 4 + j) as f32;
+    let mut chunks = vec![];
+    for ci in 0..3 {
+        for cj in 0..2 {
+            // The chunk 1.1 is missing and is filled with the fill value.
+            if (ci, cj) != (1, 1) {
+                let data = grid_chunk((ci, cj), value);
+                let data = data
+                    .iter()
+                    .flat_map(|v| v.to_le_bytes())
+                    .collect::<Vec<_>>();
+                chunks.push((format!("{ci}.{cj}"), data))
+            }
+        }
+    }
+    let chunks = chunks
+        .iter()
+        .map(|(k, d)| (k.as_str(), d.clone()))
+        .collect::<Vec<_>>();
+    let array = write_zarr(&dir.join("a.zarr"), metadata, &chunks)?;
+    assert_eq!(array.shape(), [5, 4]);
+    assert_eq!(array.dtype(), DType::F32);
+    assert_eq!(array.grid_shape(), [3, 2]);
+    let expected = (0..5)
+        .map(|i| {
+            (0..4)
+                .map(|j| {
+                    if (2..4).contains(&i) && j == 3 {
+                        7.5
+                    } else {
+                        value(i, j)
+                    }
+                })
+                .collect::<Vec<_>>()
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(array.read_all()?.to_vec2::<f32>()?, expected);
+    let part = array.read(&[1..4, 2..4])?;
+    assert_eq!(part.to_vec2::<f32>()?, [[6., 7.], [10., 7.5], [14., 7.5]]);
+    assert_eq!(
+        array.read_rows(4, 1)?.to_vec2::<f32>()?,
+        [[16., 17., 18., 19.]]
+    );
+    assert_eq!(array.read_chunk(&[2, 1])?.to_vec2::<f32>()?, [[19.]]);
+    assert_eq!(array.read(&[2..2, 0..4])?.dims(), [0, 4]);
+    assert!(array.read_chunk(&[3, 0]).is_err());
+    assert!(array.read(&[0..6, 0..4]).is_err());
+    std::fs::remove_dir_all(dir)?;
+    Ok(())
+}
+
+#[test]
+fn zarr_codecs() -> Result<()> {
+    let dir = temp_dir("zarr-codecs")?;
+    let values = [-3i64, 1, 4, -1, 5, 9];
+    let chunk = |c: usize| {
+        (0..4)
+            .flat_map(|i| {
+                let v = values.get(c * 4 + i).copied().unwrap_or(0) as i16;
+                v.to_be_bytes()
+            })
+            .collect::<Vec<_>>()
+    };
+    let metadata = |compressor: &str| {
+        format!(
+            r#"{{"zarr_format": 2, "shape": [6], "chunks": [4], "dtype": ">i2",
+            "compressor": {compressor}, "fill_value": 0, "order": "C", "filters": null}}"#
+        )
+    };
+    let zlib_array = write_zarr(
+        &dir.join("zlib"),
+        &metadata(r#"{"id": "zlib", "level": 1}"#),
+        &[("0", zlib(&chunk(0))), ("1", zlib(&chunk(1)))],
+    )?;
+    let lz4 = |data: &[u8]| {
+        let mut out = (data.len() as u32).to_le_bytes().to_vec();
+        out.extend(lz4_flex::block::compress(data));
+        out
+    };
+    let lz4_array = write_zarr(
+        &dir.join("lz4"),
+        &metadata(r#"{"id": "lz4", "acceleration": 1}"#),
+        &[("0", lz4(&chunk(0))), ("1", lz4(&chunk(1)))],
+    )?;
+    for array in [zlib_array, lz4_array] {
+        assert_eq!(array.dtype(), DType::I64);
+        assert_eq!(array.read_all()?.to_vec1::<i64>()?, values);
+    }
+
+    // Blosc frames, either stored as is or with the shuffle and lz4.
+    let data = (0..8).map(|i| (i / 2) as f32).collect::<Vec<_>>();
+    let bytes = data
+        .iter()
+        .flat_map(|v| v.to_le_bytes())
+        .collect::<Vec<_>>();
+    let header = |flags: u8, cbytes: usize| {
+        let mut header = vec![2, 1, flags, 4];
+        header.extend((bytes.len() as u32).to_le_bytes());
+        header.extend((bytes.len() as u32).to_le_bytes());
+        header.extend((cbytes as u32).to_le_bytes());
+        header
+    };
+    let mut memcpyed = header(0x2, 16 + bytes.len());
+    memcpyed.extend(&bytes);
+    let stream = lz4_flex::block::compress(&shuffle(&bytes, 4));
+    let mut lz4_frame = header(0x1 | (1 << 5), 24 + stream.len());
+    lz4_frame.extend(20u32.to_le_bytes());
+    lz4_frame.extend((stream.len() as u32).to_le_bytes());
+    lz4_frame.extend(&stream);
+    let metadata = r#"{"zarr_format": 2, "shape": [16], "chunks": [8], "dtype": "<f4",
+        "compressor": {"id": "blosc", "cname": "lz4", "clevel": 5, "shuffle": 1},
+        "fill_value": "NaN", "order": "C", "filters": null}"#;
+    let array = write_zarr(
+        &dir.join("blosc"),
+        metadata,
+        &[("0", memcpyed), ("1", lz4_frame)],
+    )?;
+    let all = array.read_all()?.to_vec1::<f32>()?;
+    assert_eq!(all, [data.clone(), data].concat());
+    std::fs::remove_dir_all(dir)?;
+    Ok(())
+}
+
+#[test]
+fn zarr_fortran_order() -> Result<()> {
+    let dir = temp_dir("zarr-fortran")?;
+    // A [3, 2] array of u16 with [2, 2] chunks in fortran order, and nested chunk keys.
+    let metadata = r#"{"zarr_format": 2, "shape": [3, 2], "chunks": [2, 2], "dtype": "<u2",
+        "compressor": null, "fill_value": 3, "order": "F", "filters": null,
+        "dimension_separator": "/"}"#;
+    let chunk0 = [1u16, 3, 2, 4];
+    let chunk0 = chunk0
+        .iter()
+        .flat_map(|v| v.to_le_bytes())
+        .collect::<Vec<_>>();
+    let array = write_zarr(&dir.join("f.zarr"), metadata, &[("0/0", chunk0)])?;
+    assert_eq!(array.dtype(), DType::U32);
+    assert_eq!(
+        array.read_all()?.to_vec2::<u32>()?,
+        [[1, 2], [3, 4], [3, 3]]
+    );
+    assert!(ZarrArray::open(dir.join("missing")).is_err());
+    std::fs::remove_dir_all(dir)?;
+    Ok(())
+}
+
+const UNDEFINED: u64 = u64::MAX;
+
+// Builds an hdf5 file with the objects appended at 8 bytes aligned addresses.
+struct Builder {
+    buf: Vec<u8>,
+}
+
+impl Builder {
+    fn append(&mut self, bytes: &[u8]) -> u64 {
+        while self.buf.len() % 8 != 0 {
+            self.buf.push(0)
+        }
+        let address = self.buf.len() as u64;
+        self.buf.extend_from_slice(bytes);
+        address
+    }
+}
+
+fn le(v: u64, n: usize) -> Vec<u8> {
+    v.to_le_bytes()[..n].to_vec()
+}
+
+fn header_v1(messages: &[(u16, Vec<u8>)]) -> Vec<u8> {
+    let mut body = vec![];
+    for (kind, data) in messages {
+        let size = data.len().div_ceil(8) * 8;
+        body.extend(le(*kind as u64, 2));
+        body.extend(le(size as u64, 2));
+        body.extend([0; 4]);
+        body.extend(data);
+        body.resize(body.len() + size - data.len(), 0);
+    }
+    let mut header = vec![1, 0];
+    header.extend(le(messages.len() as u64, 2));
+    header.extend(le(1, 4));
+    header.extend(le(body.len() as u64, 4));
+    header.extend([0; 4]);
+    header.extend(body);
+    header
+}
+
+fn header_v2(messages: &[(u8, Vec<u8>)]) -> Vec<u8> {
+    let mut body = vec![];
+    for (kind, data) in messages {
+        body.push(*kind);
+        body.extend(le(data.len() as u64, 2));
+        body.push(0);
+        body.extend(data);
+    }
+    // The size of the first chunk is stored on 4 bytes.
+    let mut header = b"OHDR".to_vec();
+    header.extend([2, 0x2]);
+    header.extend(le(body.len() as u64, 4));
+    header.extend(body);
+    header.extend([0; 4]);
+    header
+}
+
+fn dataspace_v1(dims: &[u64]) -> Vec<u8> {
+    let mut message = vec![1, dims.len() as u8, 0, 0, 0, 0, 0, 0];
+    dims.iter().for_each(|&d| message.extend(le(d, 8)));
+    message
+}
+
+fn dataspace_v2(dims: &[u64]) -> Vec<u8> {
+    let mut message = vec![2, dims.len() as u8, 0, !dims.is_empty() as u8];
+    dims.iter().for_each(|&d| message.extend(le(d, 8)));
+    message
+}
+
+fn int_type(size: u32, signed: bool, big_endian: bool) -> Vec<u8> {
+    let mut message = vec![0x10, big_endian as u8 | (signed as u8) << 3, 0, 0];
+    message.extend(le(size as u64, 4));
+    message.extend(le(0, 2));
+    message.extend(le(8 * size as u64, 2));
+    message
+}
+
+fn float_type(size: u32, big_endian: bool) -> Vec<u8> {
+    let (exponent, mantissa, bias) = if size == 4 {
+        (8, 23, 127)
+    } else {
+        (11, 52, 1023)
+    };
+    let mut message = vec![0x11, 0x20 | big_endian as u8, 8 * size as u8 - 1, 0];
+    message.extend(le(size as u64, 4));
+    message.extend(le(0, 2));
+    message.extend(le(8 * size as u64, 2));
+    message.extend([mantissa, exponent, 0, mantissa]);
+    message.extend(le(bias, 4));
+    message
+}
+
+fn heap_names(names: &[&str]) -> (Vec<u8>, Vec<u64>) {
+    let mut data = vec![0; 8];
+    let mut offsets = vec![];
+    for name in names {
+        offsets.push(data.len() as u64);
+        data.extend(name.as_bytes());
+        data.push(0);
+        data.resize(data.len().div_ceil(8) * 8, 0);
+    }
+    (data, offsets)
+}
+
+// A file with a contiguous dataset, a chunked and filtered dataset, and a group with a compact
+// scalar dataset and a chunked dataset indexed by a fixed array.
+fn write_hdf5(path: &Path) -> Result<()> {
+    let mut b = Builder { buf: vec![0; 96] };
+
+    // data: [5, 3] f32, contiguous.
+    let data = (0..15)
+        .flat_map(|v| (v as f32).to_le_bytes())
+        .collect::<Vec<_>>();
+    let data_address = b.append(&data);
+    let mut layout = vec![3, 1];
+    layout.extend(le(data_address, 8));
+    layout.extend(le(data.len() as u64, 8));
+    let data_header = b.append(&header_v1(&[
+        (0x1, dataspace_v1(&[5, 3])),
+        (0x3, float_type(4, false)),
+        (0x8, layout),
+    ]));
+
+    // grid: [5, 4] big-endian i16 with [2, 3] chunks, shuffled then deflated. The chunk (1, 1) is
+    // missing and the deflate filter is skipped for the chunk (0, 1).
+    let mut keys = vec![];
+    for ci in 0..3 {
+        for cj in 0..2 {
+            if (ci, cj) == (1, 1) {
+                continue;
+            }
+            let chunk = grid_chunk((ci, cj), |i, j| (i * 4 + j) as f32 - 5.);
+            let chunk = chunk
+                .iter()
+                .flat_map(|&v| (v as i16).to_be_bytes())
+                .collect::<Vec<_>>();
+            let chunk = shuffle(&chunk, 2);
+            let (chunk, mask) = if (ci, cj) == (0, 1) {
+                (chunk, 0b10)
+            } else {
+                (zlib(&chunk), 0)
+            };
+            let address = b.append(&chunk);
+            keys.push((
+                chunk.len(),
+                mask,
+                [ci as u64 * 2, cj as u64 * 3, 0],
+                address,
+            ))
+        }
+    }
+    let mut btree = b"TREE".to_vec();
+    btree.extend([1, 0]);
+    btree.extend(le(keys.len() as u64, 2));
+    btree.extend(le(UNDEFINED, 8));
+    btree.extend(le(UNDEFINED, 8));
+    for (size, mask, offsets, address) in keys {
+        btree.extend(le(size as u64, 4));
+        btree.extend(le(mask, 4));
+        offsets.iter().for_each(|&o| btree.extend(le(o, 8)));
+        btree.extend(le(address, 8));
+    }
+    btree.extend([0; 8]);
+    [6u64, 6, 0].iter().for_each(|&o| btree.extend(le(o, 8)));
+    let btree_address = b.append(&btree);
+    let mut layout = vec![3, 2, 3];
+    layout.extend(le(btree_address, 8));
+    [2u64, 3, 2].iter().for_each(|&d| layout.extend(le(d, 4)));
+    let mut filters = vec![1, 2, 0, 0, 0, 0, 0, 0];
+    for (id, value) in [(2u64, 2u64), (1, 6)] {
+        filters.extend(le(id, 2));
+        filters.extend(le(0, 2));
+        filters.extend(le(0, 2));
+        filters.extend(le(1, 2));
+        filters.extend(le(value, 4));
+        filters.extend([0; 4]);
+    }
+    let grid_header = b.append(&header_v1(&[
+        (0x1, dataspace_v1(&[5, 4])),
+        (0x3, int_type(2, true, true)),
+        (0xb, filters),
+        (0x8, layout),
+    ]));
+
+    // sub/scalar: a big-endian f64 scalar, compact.
+    let mut layout = vec![3, 0];
+    layout.extend(le(8, 2));
+    layout.extend(2.5f64.to_be_bytes());
+    let scalar_header = b.append(&header_v2(&[
+        (0x1, dataspace_v2(&[])),
+        (0x3, float_type(8, true)),
+        (0x8, layout),
+    ]));
+
+    // sub/fixed: [3, 4] u8 with [2, 2] chunks indexed by a fixed array, the last chunk is
+    // missing.
+    let mut addresses = vec![];
+    for c in 0..4usize {
+        if c == 3 {
+            addresses.push(UNDEFINED);
+            continue;
+        }
+        let (ci, cj) = (c / 2, c % 2);
+        let chunk = (0..4)
+            .map(|k| {
+                let (i, j) = (ci * 2 + k / 2, cj * 2 + k % 2);
+                if i < 3 {
+                    (i * 4 + j + 1) as u8
+                } else {
+                    0
+                }
+            })
+            .collect::<Vec<_>>();
+        addresses.push(b.append(&chunk))
+    }
+    let mut block = b"FADB".to_vec();
+    block.extend([0, 0]);
+    block.extend(le(0, 8));
+    addresses.iter().for_each(|&a| block.extend(le(a, 8)));
+    block.extend([0; 4]);
+    let block_address = b.append(&block);
+    let mut array = b"FAHD".to_vec();
+    array.extend([0, 0, 8, 10]);
+    array.extend(le(4, 8));
+    array.extend(le(block_address, 8));
+    array.extend([0; 4]);
+    let array_address = b.append(&array);
+    let mut layout = vec![4, 2, 0, 3, 4];
+    [2u64, 2, 1].iter().for_each(|&d| layout.extend(le(d, 4)));
+    layout.extend([3, 10]);
+    layout.extend(le(array_address, 8));
+    let fixed_header = b.append(&header_v2(&[
+        (0x1, dataspace_v2(&[3, 4])),
+        (0x3, int_type(1, false, false)),
+        (0x8, layout),
+    ]));
+
+    // sub: a group with link messages.
+    let link = |name: &str, address: u64| {
+        let mut message = vec![1, 0, name.len() as u8];
+        message.extend(name.as_bytes());
+        message.extend(le(address, 8));
+        message
+    };
+    let sub_header = b.append(&header_v2(&[
+        (0x6, link("scalar", scalar_header)),
+        (0x6, link("fixed", fixed_header)),
+    ]));
+
+    // The root group, with a symbol table.
+    let names = ["data", "grid", "sub"];
+    let (heap_data, offsets) = heap_names(&names);
+    let heap_data_address = b.append(&heap_data);
+    let mut heap = b"HEAP".to_vec();
+    heap.extend([0; 4]);
+    heap.extend(le(heap_data.len() as u64, 8));
+    heap.extend(le(UNDEFINED, 8));
+    heap.extend(le(heap_data_address, 8));
+    let heap_address = b.append(&heap);
+    let mut snod = b"SNOD".to_vec();
+    snod.extend([1, 0]);
+    snod.extend(le(names.len() as u64, 2));
+    for (offset, header) in offsets.iter().zip([data_header, grid_header, sub_header]) {
+        snod.extend(le(*offset, 8));
+        snod.extend(le(header, 8));
+        snod.extend([0; 24]);
+    }
+    let snod_address = b.append(&snod);
+    let mut btree = b"TREE".to_vec();
+    btree.extend([0, 0]);
+    btree.extend(le(1, 2));
+    btree.extend(le(UNDEFINED, 8));
+    btree.extend(le(UNDEFINED, 8));
+    btree.extend(le(0, 8));
+    btree.extend(le(snod_address, 8));
+    btree.extend(le(offsets[2], 8));
+    let btree_address = b.append(&btree);
+    let mut symbol_table = le(btree_address, 8);
+    symbol_table.extend(le(heap_address, 8));
+    let root_header = b.append(&header_v1(&[(0x11, symbol_table)]));
+
+    // The version 0 superblock.
+    let mut superblock = b"\x89HDF\r\n\x1a\n".to_vec();
+    superblock.extend([0, 0, 0, 0, 0, 8, 8, 0]);
+    superblock.extend(le(4, 2));
+    superblock.extend(le(16, 2));
+    superblock.extend([0; 4]);
+    superblock.extend(le(0, 8));
+    superblock.extend(le(UNDEFINED, 8));
+    superblock.extend(le(b.buf.len() as u64, 8));
+    superblock.extend(le(UNDEFINED, 8));
+    superblock.extend(le(0, 8));
+    superblock.extend(le(root_header, 8));
+    superblock.extend([0; 24]);
+    b.buf[..superblock.len()].copy_from_slice(&superblock);
+    std::fs::write(path, &b.buf)?;
+    Ok(())
+}
+
+#[test]
+fn hdf5_datasets() -> Result<()> {
+    let dir = temp_dir("hdf5")?;
+    let path = dir.join("test.h5");
+    write_hdf5(&path)?;
+    let file = Hdf5File::open(&path)?;
+    assert_eq!(file.names("/")?, ["data", "grid", "sub"]);
+    assert_eq!(file.names("sub")?, ["fixed", "scalar"]);
+
+    let data = file.dataset("data")?;
+    assert_eq!(data.shape(), [5, 3]);
+    assert_eq!(data.dtype(), DType::F32);
+    let expected = Tensor::arange(0f32, 15., &candle::Device::Cpu)?.reshape((5, 3))?;
+    assert_eq!(
+        data.read_all()?.to_vec2::<f32>()?,
+        expected.to_vec2::<f32>()?
+    );
+    assert_eq!(
+        data.read(&[1..3, 1..3])?.to_vec2::<f32>()?,
+        [[4., 5.], [7., 8.]]
+    );
+
+    let grid = file.dataset("/grid")?;
+    assert_eq!(grid.shape(), [5, 4]);
+    assert_eq!(grid.chunk_shape(), [2, 3]);
+    assert_eq!(grid.dtype(), DType::I64);
+    let expected = (0..5)
+        .map(|i| {
+            (0..4)
+                .map(|j| {
+                    if (2..4).contains(&i) && j == 3 {
+                        0
+                    } else {
+                        (i * 4 + j) as i64 - 5
+                    }
+                })
+                .collect::<Vec<_>>()
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(grid.read_all()?.to_vec2::<i64>()?, expected);
+    assert_eq!(grid.read_rows(4, 1)?.to_vec2::<i64>()?, [[11, 12, 13, 14]]);
+
+    let scalar = file.dataset("sub/scalar")?;
+    assert_eq!(scalar.shape(), [] as [usize; 0]);
+    assert_eq!(scalar.read_all()?.to_vec0::<f64>()?, 2.5);
+    assert!(scalar.read_rows(0, 1).is_err());
+
+    let fixed = file.dataset("sub/fixed")?;
+    assert_eq!(fixed.dtype(), DType::U8);
+    assert_eq!(
+        fixed.read_all()?.to_vec2::<u8>()?,
+        [[1, 2, 3, 4], [5, 6, 7, 8], [9, 10, 0, 0]]
+    );
+
+    assert!(file.dataset("sub").is_err());
+    assert!(file.dataset("missing").is_err());
+    assert!(Hdf5File::open(dir.join("missing.h5")).is_err());
+    std::fs::remove_dir_all(dir)?;
+    Ok(())
+}
+
+#[test]
+fn hdf5_invalid_address() -> Result<()> {
+    let dir = temp_dir("hdf5-invalid")?;
+    let path = dir.join("test.h5");
+    write_hdf5(&path)?;
+    // Point the contiguous layout of `data` at the end of the address space.
+    let mut bytes = std::fs::read(&path)?;
+    let mut layout = vec![3, 1];
+    layout.extend(le(96, 8));
+    layout.extend(le(60, 8));
+    let pos = bytes
+        .windows(layout.len())
+        .position(|w| w == layout)
+        .unwrap();
+    bytes[pos + 2..pos + 10].copy_from_slice(&le(UNDEFINED - 8, 8));
+    std::fs::write(&path, &bytes)?;
+    let file = Hdf5File::open(&path)?;
+    let data = file.dataset("data")?;
+    assert!(data.read_all().is_err());
+    assert!(data.read(&[1..3, 0..3]).is_err());
+    std::fs::remove_dir_all(dir)?;
+    Ok(())
+}
@@ -0,0 +1,166 @@
+use candle::{DType, Device, Result, Tensor};
+use candle_datasets::nlp::embeddings::{
+    EmbeddingJob, EmbeddingJobConfig, EmbeddingModel, OutputFormat, Progress,
+};
+use std::sync::{Arc, Mutex};
+
+type Shapes = Arc<Mutex<Vec<(usize, usize)>>>;
+
+// Embeds a sequence as the sum of its tokens and its length, and records the batch shapes.
+struct ToyModel {
+    device: Device,
+    shapes: Shapes,
+}
+
+impl EmbeddingModel for ToyModel {
+    fn embed(&mut self, input_ids: &Tensor, attention_mask: &Tensor) -> Result<Tensor> {
+        self.shapes.lock().unwrap().push(input_ids.dims2()?);
+        let input_ids = input_ids.to_dtype(DType::F32)?;
+        let mask = attention_mask.to_dtype(DType::F32)?;
+        let sum = (input_ids * &mask)?.sum_keepdim(1)?;
+        let len = mask.sum_keepdim(1)?;
+        Tensor::cat(&[sum, len], 1)
+    }
+
+    fn device(&self) -> &Device {
+        &self.device
+    }
+}
+
+fn encode(text: &str) -> Result<Vec<u32>> {
+    Ok(text.bytes().map(|b| b as u32).collect())
+}
+
+// A fresh output directory per test, as the tests run concurrently.
+fn output_dir(name: &str) -> Result<std::path::PathBuf> {
+    let dir = std::env::temp_dir().join(format!("candle-{name}-{}", std::process::id()));
+    if dir.exists() {
+        std::fs::remove_dir_all(&dir)?;
+    }
+    Ok(dir)
+}
+
+fn texts() -> Vec<String> {
+    (0..25).map(|i| "a".repeat(1 + i % 7)).collect()
+}
+
+fn expected(text: &str, max_seq_len: usize) -> Vec<f32> {
+    let len = text.len().min(max_seq_len);
+    vec![97. * len as f32, len as f32]
+}
+
+fn toy_job(
+    dir: &std::path::Path,
+    format: OutputFormat,
+) -> Result<(EmbeddingJob<ToyModel>, Shapes)> {
+    let shapes = Arc::new(Mutex::new(vec![]));
+    let models = (0..2)
+        .map(|_| ToyModel {
+            device: Device::Cpu,
+            shapes: shapes.clone(),
+        })
+        .collect();
+    let config = EmbeddingJobConfig {
+        max_batch_tokens: 16,
+        max_seq_len: 6,
+        shard_size: 10,
+        pad_id: 0,
+        format,
+    };
+    let job = EmbeddingJob::from_encoder(models, encode, dir, config)?;
+    Ok((job, shapes))
+}
+
+#[test]
+fn embeddings_npy() -> Result<()> {
+    let dir = output_dir("embeddings-npy")?;
+    let (mut job, shapes) = toy_job(&dir, OutputFormat::Npy)?;
+    let texts = texts();
+    let progress = job.run(texts.clone())?;
+    assert_eq!(
+        progress,
+        Progress {
+            num_shards: 3,
+            num_texts: 25
+        }
+    );
+    let shapes = shapes.lock().unwrap();
+    assert!(shapes.iter().all(|&(b, l)| b * l <= 16 && l <= 6));
+    assert_eq!(shapes.iter().map(|s| s.0).sum::<usize>(), 25);
+    for shard in 0..3 {
+        let embeddings = Tensor::read_npy(job.shard_path(shard))?.to_vec2::<f32>()?;
+        let expected = texts[shard * 10..(shard * 10 + 10).min(25)]
+            .iter()
+            .map(|t| expected(t, 6))
+            .collect::<Vec<_>>();
+        assert_eq!(embeddings, expected);
+    }
+    std::fs::remove_dir_all(&dir)?;
+    Ok(())
+}
+
+#[test]
+fn embeddings_resume() -> Result<()> {
+    let dir = output_dir("embeddings-resume")?;
+    let texts = texts();
+    let (mut job, shapes) = toy_job(&dir, OutputFormat::Npy)?;
+    job.run(texts[..12].to_vec())?;
+    assert_eq!(job.progress()?.num_texts, 12);
+    assert_eq!(
+        shapes.lock().unwrap().iter().map(|s| s.0).sum::<usize>(),
+        12
+    );
+
+    // A new job on the same directory only processes the remaining texts.
+    let (mut job, shapes) = toy_job(&dir, OutputFormat::Npy)?;
+    let progress = job.run(texts.clone())?;
+    assert_eq!(progress.num_shards, 4);
+    assert_eq!(progress.num_texts, 25);
+    assert_eq!(
+        shapes.lock().unwrap().iter().map(|s| s.0).sum::<usize>(),
+        13
+    );
+    let embeddings = Tensor::read_npy(job.shard_path(2))?.to_vec2::<f32>()?;
+    let expected = texts[12..22]
+        .iter()
+        .map(|t| expected(t, 6))
+        .collect::<Vec<_>>();
+    assert_eq!(embeddings, expected);
+    std::fs::remove_dir_all(&dir)?;
+    Ok(())
+}
+
+#[test]
+fn embeddings_parquet() -> Result<()> {
+    use parquet::file::reader::{FileReader, SerializedFileReader};
+    use parquet::record::{Field, RowAccessor};
+
+    let dir = output_dir("embeddings-parquet")?;
+    let (mut job, _) = toy_job(&dir, OutputFormat::Parquet)?;
+    let texts = texts();
+    job.run(texts.clone())?;
+    let file = std::fs::File::open(job.shard_path(1))?;
+    let reader = SerializedFileReader::new(file).map_err(candle::Error::wrap)?;
+    assert_eq!(reader.metadata().file_metadata().num_rows(), 10);
+    for (i, row) in reader
+        .get_row_iter(None)
+        .map_err(candle::Error::wrap)?
+        .enumerate()
+    {
+        let row = row.map_err(candle::Error::wrap)?;
+        assert_eq!(row.get_long(0).map_err(candle::Error::wrap)?, 10 + i as i64);
+        let embedding = row
+            .get_list(1)
+            .map_err(candle::Error::wrap)?
+            .elements()
+            .iter()
+            .map(|f| match f {
+                Field::Float(f) => *f,
+                _ => panic!("unexpected field {f:?}"),
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(embedding, expected(&texts[10 + i], 6));
+    }
+    std::fs::remove_dir_all(&dir)?;
+    Ok(())
+}
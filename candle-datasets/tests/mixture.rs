@@ -0,0 +1,85 @@
+use candle::Result;
+use candle_datasets::nlp::mixture::{mixture_probabilities, Curriculum, DatasetMixer};
+
+fn datasets() -> Vec<Vec<Vec<u32>>> {
+    vec![
+        (0..10).map(|i| vec![i]).collect(),
+        (100..130).map(|i| vec![i]).collect(),
+    ]
+}
+
+#[test]
+fn probabilities() -> Result<()> {
+    assert_eq!(mixture_probabilities(&[1., 3.], 1.)?, [0.25, 0.75]);
+    assert_eq!(mixture_probabilities(&[1., 4.], 2.)?, [1. / 3., 2. / 3.]);
+    assert!(mixture_probabilities(&[0., 0.], 1.).is_err());
+    assert!(mixture_probabilities(&[1., 2.], 0.).is_err());
+    Ok(())
+}
+
+#[test]
+fn mixer() -> Result<()> {
+    let mut mixer = DatasetMixer::new(datasets(), 42)?;
+    assert_eq!(mixer.probabilities(), [0.25, 0.75]);
+    let samples = (0..400)
+        .map(|_| mixer.next_sample())
+        .collect::<Result<Vec<_>>>()?;
+    let from_first = samples.iter().filter(|(d, _)| *d == 0).count();
+    assert!((70..130).contains(&from_first), "{from_first}");
+
+    // Each epoch goes over all the documents of a dataset, in a different order for each epoch.
+    let first: Vec<u32> = samples
+        .iter()
+        .filter(|(d, _)| *d == 0)
+        .map(|(_, t)| t[0])
+        .collect();
+    let mut epoch0 = first[..10].to_vec();
+    let mut epoch1 = first[10..20].to_vec();
+    assert_ne!(epoch0, epoch1);
+    epoch0.sort();
+    epoch1.sort();
+    assert_eq!(epoch0, (0..10).collect::<Vec<_>>());
+    assert_eq!(epoch1, (0..10).collect::<Vec<_>>());
+
+    // Resuming from a saved state gives the same samples as an uninterrupted run.
+    let mut mixer = DatasetMixer::new(datasets(), 42)?;
+    for _ in 0..123 {
+        mixer.next_sample()?;
+    }
+    let state = mixer.state().clone();
+    assert_eq!(state.step, 123);
+    let mut resumed = DatasetMixer::new(datasets(), 0)?;
+    resumed.set_state(state)?;
+    let resumed = (0..277)
+        .map(|_| resumed.next_sample())
+        .collect::<Result<Vec<_>>>()?;
+    assert_eq!(resumed, samples[123..]);
+
+    // A different seed gives a different stream.
+    let mut mixer = DatasetMixer::new(datasets(), 43)?;
+    let other = (0..400)
+        .map(|_| mixer.next_sample())
+        .collect::<Result<Vec<_>>>()?;
+    assert_ne!(other, samples);
+    Ok(())
+}
+
+#[test]
+fn curriculum() -> Result<()> {
+    let curriculum = Curriculum::constant(vec![1., 0.]).then(5, vec![0., 1.])?;
+    let mut mixer = DatasetMixer::with_curriculum(datasets(), curriculum, 0)?.shuffle(false);
+    let samples = (0..8)
+        .map(|_| mixer.next_sample())
+        .collect::<Result<Vec<_>>>()?;
+    let tokens: Vec<u32> = samples.iter().map(|(_, t)| t[0]).collect();
+    assert_eq!(tokens, [0, 1, 2, 3, 4, 100, 101, 102]);
+    assert_eq!(mixer.probabilities(), [0., 1.]);
+
+    let mixer = DatasetMixer::with_weights(datasets(), vec![1., 9.], 0)?.temperature(2.)?;
+    assert_eq!(mixer.probabilities(), [0.25, 0.75]);
+    assert!(Curriculum::constant(vec![1., 1.])
+        .then(0, vec![1., 1.])
+        .is_err());
+    assert!(DatasetMixer::with_weights(datasets(), vec![1.], 0).is_err());
+    Ok(())
+}
@@ -0,0 +1,71 @@
+use candle::{Device, IndexOp, Result};
+use candle_datasets::nlp::sft::{Conversation, PackingCollator};
+
+fn conversation(prompt: &[u32], response: &[u32]) -> Conversation {
+    let mut conv = Conversation::new();
+    conv.push_prompt(prompt).push_response(response);
+    conv
+}
+
+#[test]
+fn packing() -> Result<()> {
+    let conversations = [
+        conversation(&[1, 2], &[3]),
+        conversation(&[5], &[6, 7, 8, 9]),
+        conversation(&[10], &[11]),
+        conversation(&[12, 13, 14, 15, 16, 17, 18], &[19, 20, 21]),
+    ];
+    let collator = PackingCollator::new(8, 0);
+    // The longest conversation is truncated to a row of its own.
+    assert_eq!(
+        collator.pack(&conversations),
+        [vec![3], vec![1, 0], vec![2]]
+    );
+
+    let batch = collator.collate(&conversations, &Device::Cpu)?;
+    assert_eq!(batch.input_ids.dims(), [3, 8]);
+    assert_eq!(
+        batch.input_ids.i(1)?.to_vec1::<u32>()?,
+        [5, 6, 7, 8, 9, 1, 2, 3]
+    );
+    assert_eq!(
+        batch.position_ids.i(1)?.to_vec1::<u32>()?,
+        [0, 1, 2, 3, 4, 0, 1, 2]
+    );
+    assert_eq!(
+        batch.segment_ids.i(1)?.to_vec1::<u32>()?,
+        [1, 1, 1, 1, 1, 2, 2, 2]
+    );
+    // The prompt targets and the targets crossing a conversation boundary are masked.
+    assert_eq!(
+        batch.labels.i(1)?.to_vec1::<u32>()?,
+        [6, 7, 8, 9, 0, 0, 3, 0]
+    );
+    assert_eq!(
+        batch.loss_mask.i(1)?.to_vec1::<f32>()?,
+        [1., 1., 1., 1., 0., 0., 1., 0.]
+    );
+    // Padding.
+    assert_eq!(
+        batch.input_ids.i(2)?.to_vec1::<u32>()?,
+        [10, 11, 0, 0, 0, 0, 0, 0]
+    );
+    assert_eq!(
+        batch.loss_mask.i(2)?.to_vec1::<f32>()?,
+        [1., 0., 0., 0., 0., 0., 0., 0.]
+    );
+    assert_eq!(
+        batch.labels.i(0)?.to_vec1::<u32>()?,
+        [0, 0, 0, 0, 0, 0, 19, 0]
+    );
+
+    let mask = batch.attention_mask.i(1)?.to_vec2::<u8>()?;
+    assert_eq!(mask[4], [0, 0, 0, 0, 0, 1, 1, 1]);
+    assert_eq!(mask[6], [1, 1, 1, 1, 1, 0, 0, 1]);
+
+    // Rows that do not fit are rejected.
+    assert!(collator
+        .collate_rows(&conversations, &[vec![0, 1, 2]], &Device::Cpu)
+        .is_err());
+    Ok(())
+}
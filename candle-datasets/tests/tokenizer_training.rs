@@ -0,0 +1,97 @@
+use candle::{Error, Result};
+use candle_datasets::nlp::tokenizer_training::{
+    train_tokenizer, train_tokenizer_from_files, TrainerConfig,
+};
+
+// Sentences made of a few stems with various endings.
+fn corpus() -> Vec<String> {
+    let stems = ["tensor", "token", "train", "candle", "überall", "the"];
+    let endings = ["", "s", "ed", "ing", "er"];
+    (0..200)
+        .map(|i| {
+            (0..8)
+                .map(|j| {
+                    let stem = stems[(i * 3 + j * j) % stems.len()];
+                    format!("{stem}{}", endings[(i + j) % endings.len()])
+                })
+                .collect::<Vec<_>>()
+                .join(" ")
+        })
+        .collect()
+}
+
+fn roundtrip(tokenizer: &tokenizers::Tokenizer, text: &str) -> Result<(usize, String)> {
+    let encoding = tokenizer
+        .encode(text, false)
+        .map_err(|e| Error::Msg(e.to_string()))?;
+    let decoded = tokenizer
+        .decode(encoding.get_ids(), false)
+        .map_err(|e| Error::Msg(e.to_string()))?;
+    Ok((encoding.len(), decoded))
+}
+
+#[test]
+fn train_bpe() -> Result<()> {
+    let config = TrainerConfig::bpe(300).with_special_tokens(&["<s>", "</s>"]);
+    let tokenizer = train_tokenizer(corpus(), &config)?;
+    assert!(tokenizer.get_vocab_size(false) <= 300);
+    assert_eq!(tokenizer.token_to_id("<s>"), Some(0));
+    assert_eq!(tokenizer.token_to_id("</s>"), Some(1));
+    // The frequent words are single tokens, unseen text is still encoded without losing bytes.
+    let (len, decoded) = roundtrip(&tokenizer, " tensors candle")?;
+    assert_eq!((len, decoded.as_str()), (2, " tensors candle"));
+    let text = "unseen 文字 </s>";
+    assert_eq!(roundtrip(&tokenizer, text)?.1, text);
+
+    // With a budget of a few words, the later words of the corpus are not learned.
+    let config = config.with_min_frequency(1).with_max_corpus_tokens(4);
+    let tokenizer = train_tokenizer(corpus(), &config)?;
+    let first = corpus()[0].split(' ').take(4).collect::<Vec<_>>().join(" ");
+    assert_eq!(roundtrip(&tokenizer, &first)?.0, 4);
+    assert!(roundtrip(&tokenizer, &corpus()[0])?.0 > 8);
+    Ok(())
+}
+
+#[test]
+fn train_unigram() -> Result<()> {
+    let config = TrainerConfig::unigram(100);
+    let tokenizer = train_tokenizer(corpus(), &config)?;
+    assert!(tokenizer.get_vocab_size(false) <= 100);
+    assert_eq!(tokenizer.token_to_id("<unk>"), Some(0));
+    let encoding = tokenizer
+        .encode("candle tensors überall", false)
+        .map_err(|e| Error::Msg(e.to_string()))?;
+    assert_eq!(
+        encoding.get_tokens(),
+        ["▁candle", "▁tensor", "s", "▁überall"]
+    );
+    assert_eq!(roundtrip(&tokenizer, "candle tensors")?.1, "candle tensors");
+    // The characters that were not in the corpus are unknown.
+    let encoding = tokenizer
+        .encode("文", false)
+        .map_err(|e| Error::Msg(e.to_string()))?;
+    assert!(encoding.get_ids().contains(&0));
+
+    assert!(train_tokenizer(Vec::<String>::new(), &config).is_err());
+    let config = TrainerConfig::bpe(1).with_special_tokens(&["<s>"]);
+    assert!(train_tokenizer(corpus(), &config).is_err());
+    Ok(())
+}
+
+#[test]
+fn train_from_files() -> Result<()> {
+    let dir = std::env::temp_dir().join(format!("candle-tokenizer-{}", std::process::id()));
+    std::fs::create_dir_all(&dir)?;
+    let texts = corpus();
+    let (first, second) = texts.split_at(100);
+    let paths = [dir.join("a.txt"), dir.join("b.txt")];
+    std::fs::write(&paths[0], first.join("\n"))?;
+    std::fs::write(&paths[1], second.join("\n"))?;
+    let config = TrainerConfig::bpe(300);
+    let from_files = train_tokenizer_from_files(&paths, &config)?;
+    let from_texts = train_tokenizer(corpus(), &config)?;
+    assert_eq!(from_files.get_vocab(false), from_texts.get_vocab(false));
+    assert!(train_tokenizer_from_files(&[dir.join("missing.txt")], &config).is_err());
+    std::fs::remove_dir_all(&dir)?;
+    Ok(())
+}
@@ -63,6 +63,24 @@ impl Model {
     }
 }
 
+impl m::long_form::WhisperModel for Model {
+    fn config(&self) -> &Config {
+        self.config()
+    }
+
+    fn encoder_forward(&mut self, x: &Tensor, flush: bool) -> candle::Result<Tensor> {
+        self.encoder_forward(x, flush)
+    }
+
+    fn decoder_forward(&mut self, x: &Tensor, xa: &Tensor, flush: bool) -> candle::Result<Tensor> {
+        self.decoder_forward(x, xa, flush)
+    }
+
+    fn decoder_final_linear(&self, x: &Tensor) -> candle::Result<Tensor> {
+        self.decoder_final_linear(x)
+    }
+}
+
 #[allow(dead_code)]
 #[derive(Debug, Clone)]
 struct DecodingResult {
@@ -338,6 +356,51 @@ impl Decoder {
         }
         Ok(segments)
     }
+
+    /// Transcribes overlapping chunks in batches, timestamps are always used in this mode as
+    /// they are required to merge the chunks.
+    fn run_batched(&mut self, mel: &Tensor, batch_size: usize) -> Result<()> {
+        use m::long_form::{ChunkConfig, DecodeOptions};
+        let start = std::time::Instant::now();
+        let mut prompt = vec![self.sot_token];
+        if let Some(language_token) = self.language_token {
+            prompt.push(language_token);
+        }
+        match self.task {
+            None | Some(Task::Transcribe) => prompt.push(self.transcribe_token),
+            Some(Task::Translate) => prompt.push(self.translate_token),
+        }
+        let mut suppress_tokens = self.model.config().suppress_tokens.clone();
+        suppress_tokens.push(self.no_timestamps_token);
+        let opts = DecodeOptions {
+            prompt,
+            eot_token: self.eot_token,
+            no_speech_token: self.no_speech_token,
+            timestamp_begin: self.no_timestamps_token + 1,
+            suppress_tokens,
+            max_tokens: self.model.config().max_target_positions / 2,
+        };
+        let cfg = ChunkConfig {
+            batch_size,
+            ..Default::default()
+        };
+        let segments = m::long_form::transcribe(&mut self.model, mel, &cfg, &opts)?;
+        for segment in segments.iter() {
+            let text = self
+                .tokenizer
+                .decode(&segment.tokens, true)
+                .map_err(E::msg)?;
+            println!("{:.1}s -- {:.1}s: {}", segment.start, segment.end, text)
+        }
+        if self.verbose {
+            println!(
+                "transcribed {} segments in {:?}",
+                segments.len(),
+                start.elapsed()
+            );
+        }
+        Ok(())
+    }
 }
 
 pub fn token_id(tokenizer: &Tokenizer, token: &str) -> candle::Result<u32> {
@@ -471,6 +534,10 @@ struct Args {
     /// Print the full DecodingResult structure rather than just the text.
     #[arg(long)]
     verbose: bool,
+
+    /// Transcribe long inputs by decoding overlapping 30s chunks in batches of this size.
+    #[arg(long)]
+    batch_size: Option<usize>,
 }
 
 fn main() -> Result<()> {
@@ -591,6 +658,11 @@ fn main() -> Result<()> {
         args.timestamps,
         args.verbose,
     )?;
-    dc.run(&mel)?;
+    match args.batch_size {
+        Some(batch_size) => dc.run_batched(&mel, batch_size)?,
+        None => {
+            dc.run(&mel)?;
+        }
+    }
     Ok(())
 }
@@ -1,21 +1,39 @@
 use candle::Result;
+use candle_transformers::generation::{Detokenizer, SpecialTokenPolicy};
 
 /// This is a wrapper around a tokenizer to ensure that tokens can be returned to the user in a
 /// streaming way rather than having to wait for the full decoding.
 pub struct TokenOutputStream {
     tokenizer: tokenizers::Tokenizer,
+    detokenizer: Detokenizer,
     tokens: Vec<u32>,
-    prev_index: usize,
-    current_index: usize,
 }
 
 impl TokenOutputStream {
+    /// The special tokens are skipped, see [`Self::with_policy`].
     pub fn new(tokenizer: tokenizers::Tokenizer) -> Self {
+        Self::with_policy(tokenizer, SpecialTokenPolicy::Skip)
+    }
+
+    pub fn with_policy(tokenizer: tokenizers::Tokenizer, policy: SpecialTokenPolicy) -> Self {
+        let special_tokens = tokenizer
+            .get_added_tokens_decoder()
+            .into_iter()
+            .filter(|(_, token)| token.special)
+            .map(|(id, token)| (id, token.content))
+            .collect();
+        let decoder = tokenizer.clone();
+        let decode = move |tokens: &[u32]| match decoder.decode(tokens, false) {
+            Ok(str) => Ok(str),
+            Err(err) => candle::bail!("cannot decode: {err}"),
+        };
+        let detokenizer = Detokenizer::new(decode)
+            .with_special_tokens(special_tokens)
+            .with_policy(policy);
         Self {
             tokenizer,
+            detokenizer,
             tokens: Vec::new(),
-            prev_index: 0,
-            current_index: 0,
         }
     }
 
@@ -23,51 +41,21 @@ impl TokenOutputStream {
         self.tokenizer
     }
 
-    fn decode(&self, tokens: &[u32]) -> Result<String> {
-        match self.tokenizer.decode(tokens, true) {
-            Ok(str) => Ok(str),
-            Err(err) => candle::bail!("cannot decode: {err}"),
-        }
-    }
-
-    // https://github.com/huggingface/text-generation-inference/blob/5ba53d44a18983a4de32d122f4cb46f4a17d9ef6/server/text_generation_server/models/model.py#L68
     pub fn next_token(&mut self, token: u32) -> Result<Option<String>> {
-        let prev_text = if self.tokens.is_empty() {
-            String::new()
-        } else {
-            let tokens = &self.tokens[self.prev_index..self.current_index];
-            self.decode(tokens)?
-        };
         self.tokens.push(token);
-        let text = self.decode(&self.tokens[self.prev_index..])?;
-        if text.len() > prev_text.len() && text.chars().last().unwrap().is_alphanumeric() {
-            let text = text.split_at(prev_text.len());
-            self.prev_index = self.current_index;
-            self.current_index = self.tokens.len();
-            Ok(Some(text.1.to_string()))
-        } else {
-            Ok(None)
-        }
+        self.detokenizer.next_token(token)
     }
 
     pub fn decode_rest(&self) -> Result<Option<String>> {
-        let prev_text = if self.tokens.is_empty() {
-            String::new()
-        } else {
-            let tokens = &self.tokens[self.prev_index..self.current_index];
-            self.decode(tokens)?
-        };
-        let text = self.decode(&self.tokens[self.prev_index..])?;
-        if text.len() > prev_text.len() {
-            let text = text.split_at(prev_text.len());
-            Ok(Some(text.1.to_string()))
-        } else {
-            Ok(None)
-        }
+        let text = self.detokenizer.pending_text()?;
+        Ok((!text.is_empty()).then_some(text))
     }
 
     pub fn decode_all(&self) -> Result<String> {
-        self.decode(&self.tokens)
+        match self.tokenizer.decode(&self.tokens, true) {
+            Ok(str) => Ok(str),
+            Err(err) => candle::bail!("cannot decode: {err}"),
+        }
     }
 
     pub fn get_token(&self, token_s: &str) -> Option<u32> {
@@ -80,7 +68,6 @@ impl TokenOutputStream {
 
     pub fn clear(&mut self) {
         self.tokens.clear();
-        self.prev_index = 0;
-        self.current_index = 0;
+        self.detokenizer.reset();
     }
 }
@@ -19,6 +19,7 @@ extern "C" void run_mha(
     void *o_ptr,
     void *softmax_lse_ptr,
     void *alibi_slopes_ptr,
+    void *sinks_ptr,
 
     int32_t *cu_seqlens_q_ptr,
     int32_t *cu_seqlens_k_ptr,
@@ -45,6 +46,7 @@ extern "C" void run_mha(
     uint32_t d,
     uint32_t d_rounded,
     float softmax_scale,
+    float softcap,
 
     uint32_t seqlen_q,
     uint32_t seqlen_k,
@@ -69,6 +71,7 @@ extern "C" void run_mha(
 
     params.softmax_lse_ptr = softmax_lse_ptr;
     params.alibi_slopes_ptr = alibi_slopes_ptr;
+    params.sinks_ptr = sinks_ptr;
 
     // All stride are in elements, not bytes.
     params.q_batch_stride = q_batch_stride;
@@ -99,8 +102,17 @@ extern "C" void run_mha(
     params.d_rounded = d_rounded;
 
     // Set the different scale values.
-    params.scale_softmax = softmax_scale;
-    params.scale_softmax_log2 = softmax_scale * M_LOG2E;
+    // With softcapping the kernel computes `softcap * tanh(s * softmax_scale / softcap)`, the scores
+    // are multiplied by `params.softcap` before the tanh and the result by `params.scale_softmax`.
+    if (softcap > 0.0) {
+        params.softcap = softmax_scale / softcap;
+        params.scale_softmax = softcap;
+        params.scale_softmax_log2 = softcap * M_LOG2E;
+    } else {
+        params.softcap = 0.0;
+        params.scale_softmax = softmax_scale;
+        params.scale_softmax_log2 = softmax_scale * M_LOG2E;
+    }
 
     params.p_dropout = 1.; // probability to keep
     params.p_dropout_in_uint8_t = uint8_t(std::floor(params.p_dropout * 255.0));
@@ -8,6 +8,7 @@ extern "C" {
         o_ptr: *const c_void,
         softmax_lse_ptr: *const c_void,
         alibi_slopes_ptr: *const c_void,
+        sinks_ptr: *const c_void,
 
         cu_seqlens_q_ptr: *const i32,
         cu_seqlens_k_ptr: *const i32,
@@ -34,6 +35,7 @@ extern "C" {
         d: u32,
         d_rounded: u32,
         softmax_scale: f32,
+        softcap: f32,
 
         seqlen_q: u32,
         seqlen_k: u32,
@@ -6,17 +6,65 @@ use candle::cuda_backend::WrapErr;
 use candle::{CpuStorage, DType, Layout, Result, Shape, Tensor};
 use half::{bf16, f16};
 
+/// The flash-attention op, the helper functions below cover the usual cases and the fields can
+/// be combined freely, e.g. for a sliding window layer with softcapping.
 pub struct FlashAttn {
     pub softmax_scale: f32,
     pub alibi_slopes: Option<Tensor>,
     pub window_size_left: Option<usize>,
     pub window_size_right: Option<usize>,
+    /// Caps the attention logits with `softcap * tanh(logits / softcap)`, as in Gemma-2.
+    pub softcap: Option<f32>,
+    /// The logits of the attention sinks, an f32 tensor with shape `(num_heads_q)`.
+    pub sinks: Option<Tensor>,
 }
 
 fn round_multiple(x: usize, m: usize) -> usize {
     (x + m - 1) / m * m
 }
 
+fn check_softcap(softcap: Option<f32>) -> Result<()> {
+    match softcap {
+        Some(softcap) if softcap <= 0.0 => {
+            candle::bail!("softcap must be positive (got {softcap})")
+        }
+        _ => Ok(()),
+    }
+}
+
+// The device pointer of a per head f32 tensor, null when there is no tensor.
+fn per_head_ptr(
+    t: &Option<Tensor>,
+    num_heads: usize,
+    name: &str,
+) -> Result<*const core::ffi::c_void> {
+    let t = match t {
+        Some(t) => t,
+        None => return Ok(std::ptr::null()),
+    };
+    if t.dtype() != DType::F32 {
+        candle::bail!(
+            "DType mismatch {name} {:?}, expected {:?}",
+            t.dtype(),
+            DType::F32
+        );
+    }
+    let (storage, layout) = t.storage_and_layout();
+    if num_heads != layout.shape().dims1()? || !layout.is_contiguous() {
+        candle::bail!(
+            "shape mismatch {name} {:?}, expected {:?}",
+            layout.shape(),
+            (num_heads)
+        );
+    }
+    let slice = match &*storage {
+        candle::Storage::Cuda(c) => c.as_cuda_slice::<f32>()?,
+        _ => candle::bail!("{name} must be a cuda tensor"),
+    };
+    let slice = slice.slice(layout.start_offset()..);
+    Ok(*slice.device_ptr() as *const core::ffi::c_void)
+}
+
 impl FlashAttn {
     fn cuda_fwd_t<
         T: candle::cuda_backend::CudaDType + candle::cuda_backend::cudarc::driver::DeviceRepr,
@@ -117,6 +165,8 @@ impl FlashAttn {
         } else {
             std::ptr::null()
         };
+        let sinks_ptr = per_head_ptr(&self.sinks, num_heads, "sinks")?;
+        check_softcap(self.softcap)?;
 
         // if window_size_left > self.max_seqlen_k or None => -1
         let mut window_size_left = self
@@ -172,6 +222,7 @@ impl FlashAttn {
                 dst_ptr,
                 softmax_lse_ptr,
                 /* alibi_slopes_ptr */ alibi_slopes_ptr,
+                /* sinks_ptr */ sinks_ptr,
                 /* cu_seqlens_q_ptr */ std::ptr::null(),
                 /* cu_seqlens_k_ptr */ std::ptr::null(),
                 /* q_batch_stride */ q_stride[0] as u32,
@@ -193,6 +244,7 @@ impl FlashAttn {
                 /* d */ head_size as u32,
                 /* d_rounded */ head_size_rounded as u32,
                 /* softmax_scale*/ self.softmax_scale,
+                /* softcap */ self.softcap.unwrap_or(0.0),
                 /* seqlen_q */ seqlen_q as u32,
                 /* seqlen_k */ seqlen_k as u32,
                 /* seqlen_q_rounded */ seqlen_q_rounded as u32,
@@ -271,6 +323,8 @@ pub fn flash_attn(
         alibi_slopes: None,
         window_size_left,
         window_size_right,
+        softcap: None,
+        sinks: None,
     };
     q.apply_op3(k, v, op)
 }
@@ -308,6 +362,8 @@ pub fn flash_attn_windowed(
         alibi_slopes: None,
         window_size_left,
         window_size_right,
+        softcap: None,
+        sinks: None,
     };
     q.apply_op3(k, v, op)
 }
@@ -342,6 +398,8 @@ pub fn flash_attn_alibi(
         alibi_slopes: Some(alibi_slopes.clone()),
         window_size_left,
         window_size_right,
+        softcap: None,
+        sinks: None,
     };
     q.apply_op3(k, v, op)
 }
@@ -381,11 +439,89 @@ pub fn flash_attn_alibi_windowed(
         alibi_slopes: Some(alibi_slopes.clone()),
         window_size_left,
         window_size_right,
+        softcap: None,
+        sinks: None,
+    };
+    q.apply_op3(k, v, op)
+}
+
+/// Flash-attention v2 layer with logit softcapping.
+///
+/// This implements scaled dot-product attention with the logits capped as in Gemma-2,
+/// `softmax(softcap * tanh(Q @ K^T . softmax_scale / softcap)) @ V`.
+///
+/// # Arguments
+///
+/// * `q` - Query tensor with shape `(batch, seq_len_q, num_heads_q, head_size)`.
+/// * `k` - Key tensor with shape `(batch, seq_len_kv, num_heads_kv, head_size)`.
+/// * `v` - Value tensor with shape `(batch, seq_len_kv, num_heads_kv, head_size)`.
+/// * `softcap` - The logits cap, it has to be positive.
+///
+/// The resulting tensor has dimensions `(batch, seq_len_q, num_heads_q, head_size)`.
+pub fn flash_attn_softcap(
+    q: &Tensor,
+    k: &Tensor,
+    v: &Tensor,
+    softmax_scale: f32,
+    softcap: f32,
+    causal: bool,
+) -> Result<Tensor> {
+    let window_size_left = None;
+    let window_size_right = if causal { Some(0) } else { None };
+
+    let op = FlashAttn {
+        softmax_scale,
+        alibi_slopes: None,
+        window_size_left,
+        window_size_right,
+        softcap: Some(softcap),
+        sinks: None,
+    };
+    q.apply_op3(k, v, op)
+}
+
+/// Flash-attention v2 layer with attention sinks.
+///
+/// Each head has a learned sink logit that takes part in the softmax normalization as an extra
+/// key with a zero value, so that a query can attend to none of the keys as in gpt-oss.
+///
+/// # Arguments
+///
+/// * `q` - Query tensor with shape `(batch, seq_len_q, num_heads_q, head_size)`.
+/// * `k` - Key tensor with shape `(batch, seq_len_kv, num_heads_kv, head_size)`.
+/// * `v` - Value tensor with shape `(batch, seq_len_kv, num_heads_kv, head_size)`.
+/// * `sinks` - Sink logits tensor with shape `(num_heads_q)`.
+/// * `window_size_left` - Limit left attention to value tokens.
+/// * `window_size_right` - Limit right attention to value tokens.
+///
+/// # Causal mask
+///
+/// `window_size_left=None` with `window_size_right=Some(0)` applies a causal mask to the result
+/// of  `Q @ K^T`
+///
+/// The resulting tensor has dimensions `(batch, seq_len_q, num_heads_q, head_size)`.
+pub fn flash_attn_windowed_sinks(
+    q: &Tensor,
+    k: &Tensor,
+    v: &Tensor,
+    sinks: &Tensor,
+    softmax_scale: f32,
+    window_size_left: Option<usize>,
+    window_size_right: Option<usize>,
+) -> Result<Tensor> {
+    let op = FlashAttn {
+        softmax_scale,
+        alibi_slopes: None,
+        window_size_left,
+        window_size_right,
+        softcap: None,
+        sinks: Some(sinks.clone()),
     };
     q.apply_op3(k, v, op)
 }
 
-struct FlashAttnVarLen {
+/// The flash-attention op with variable-length batching, see [`FlashAttn`].
+pub struct FlashAttnVarLen {
     pub softmax_scale: f32,
     pub max_seqlen_q: usize,
     pub max_seqlen_k: usize,
@@ -394,6 +530,8 @@ struct FlashAttnVarLen {
     pub alibi_slopes: Option<Tensor>,
     pub window_size_left: Option<usize>,
     pub window_size_right: Option<usize>,
+    pub softcap: Option<f32>,
+    pub sinks: Option<Tensor>,
 }
 
 impl FlashAttnVarLen {
@@ -527,6 +665,8 @@ impl FlashAttnVarLen {
         } else {
             std::ptr::null()
         };
+        let sinks_ptr = per_head_ptr(&self.sinks, num_heads, "sinks")?;
+        check_softcap(self.softcap)?;
 
         // if window_size_left > self.max_seqlen_k or None => -1
         let mut window_size_left = self
@@ -584,6 +724,7 @@ impl FlashAttnVarLen {
                 dst_ptr,
                 softmax_lse_ptr,
                 /* alibi_slopes_ptr */ alibi_slopes_ptr,
+                /* sinks_ptr */ sinks_ptr,
                 /* cu_seqlens_q_ptr */ seqlens_q_ptr,
                 /* cu_seqlens_k_ptr */ seqlens_k_ptr,
                 /* q_batch_stride */ 0,
@@ -605,6 +746,7 @@ impl FlashAttnVarLen {
                 /* d */ head_size as u32,
                 /* d_rounded */ head_size_rounded as u32,
                 /* softmax_scale*/ self.softmax_scale,
+                /* softcap */ self.softcap.unwrap_or(0.0),
                 /* seqlen_q */ self.max_seqlen_q as u32,
                 /* seqlen_k */ self.max_seqlen_k as u32,
                 /* seqlen_q_rounded */ seqlen_q_rounded as u32,
@@ -699,6 +841,8 @@ pub fn flash_attn_varlen(
         alibi_slopes: None,
         window_size_left,
         window_size_right,
+        softcap: None,
+        sinks: None,
     };
     q.apply_op3(k, v, op)
 }
@@ -752,6 +896,8 @@ pub fn flash_attn_varlen_windowed(
         alibi_slopes: None,
         window_size_left,
         window_size_right,
+        softcap: None,
+        sinks: None,
     };
     q.apply_op3(k, v, op)
 }
@@ -802,6 +948,8 @@ pub fn flash_attn_varlen_alibi(
         alibi_slopes: Some(alibi_slopes.clone()),
         window_size_left,
         window_size_right,
+        softcap: None,
+        sinks: None,
     };
     q.apply_op3(k, v, op)
 }
@@ -857,6 +1005,115 @@ pub fn flash_attn_varlen_alibi_windowed(
         alibi_slopes: Some(alibi_slopes.clone()),
         window_size_left,
         window_size_right,
+        softcap: None,
+        sinks: None,
+    };
+    q.apply_op3(k, v, op)
+}
+
+#[allow(clippy::too_many_arguments)]
+/// Flash-attention v2 layer with variable-length batching and logit softcapping.
+///
+/// This implements scaled dot-product attention with the logits capped as in Gemma-2,
+/// `softmax(softcap * tanh(Q @ K^T . softmax_scale / softcap)) @ V`.
+///
+/// # Arguments
+///
+/// * `q` - Query tensor with shape `(total_q, num_heads_q, head_size)`.
+/// * `k` - Key tensor with shape `(total_kv, num_heads_kv, head_size)`.
+/// * `v` - Value tensor with shape `(total_kv, num_heads_kv, head_size)`.
+/// * `seqlens_q` - The cumulative lengths of the sequences in the batch, used to index in q.
+/// * `seqlens_k` - The cumulative lengths of the sequences in the batch, used to index in k and v.
+/// * `max_seqlen_q` - The maximum query sequence length for q in the batch.
+/// * `max_seqlen_k` - The maximum query sequence length for k and v in the batch.
+/// * `softcap` - The logits cap, it has to be positive.
+///
+/// `seqlens_q` and `seqlens_k` contain `batch_size + 1` elements, typically `0`, `seqlen_1`,
+/// `seqlen_1 + seqlen_2`, etc.
+///
+/// The resulting tensor has dimensions `(total_q, num_heads_q, head_size)`.
+pub fn flash_attn_varlen_softcap(
+    q: &Tensor,
+    k: &Tensor,
+    v: &Tensor,
+    seqlens_q: &Tensor,
+    seqlens_k: &Tensor,
+    max_seqlen_q: usize,
+    max_seqlen_k: usize,
+    softmax_scale: f32,
+    softcap: f32,
+    causal: bool,
+) -> Result<Tensor> {
+    let window_size_left = None;
+    let window_size_right = if causal { Some(0) } else { None };
+
+    let op = FlashAttnVarLen {
+        softmax_scale,
+        max_seqlen_q,
+        max_seqlen_k,
+        seqlens_q: seqlens_q.clone(),
+        seqlens_k: seqlens_k.clone(),
+        alibi_slopes: None,
+        window_size_left,
+        window_size_right,
+        softcap: Some(softcap),
+        sinks: None,
+    };
+    q.apply_op3(k, v, op)
+}
+
+#[allow(clippy::too_many_arguments)]
+/// Flash-attention v2 layer with variable-length batching and attention sinks.
+///
+/// Each head has a learned sink logit that takes part in the softmax normalization as an extra
+/// key with a zero value.
+///
+/// # Arguments
+///
+/// * `q` - Query tensor with shape `(total_q, num_heads_q, head_size)`.
+/// * `k` - Key tensor with shape `(total_kv, num_heads_kv, head_size)`.
+/// * `v` - Value tensor with shape `(total_kv, num_heads_kv, head_size)`.
+/// * `sinks` - Sink logits tensor with shape `(num_heads_q)`.
+/// * `seqlens_q` - The cumulative lengths of the sequences in the batch, used to index in q.
+/// * `seqlens_k` - The cumulative lengths of the sequences in the batch, used to index in k and v.
+/// * `max_seqlen_q` - The maximum query sequence length for q in the batch.
+/// * `max_seqlen_k` - The maximum query sequence length for k and v in the batch.
+/// * `window_size_left` - Limit left attention to value tokens.
+/// * `window_size_right` - Limit right attention to value tokens.
+///
+/// `seqlens_q` and `seqlens_k` contain `batch_size + 1` elements, typically `0`, `seqlen_1`,
+/// `seqlen_1 + seqlen_2`, etc.
+///
+/// The resulting tensor has dimensions `(total_q, num_heads_q, head_size)`.
+///
+/// # Causal mask
+///
+/// `window_size_left=None` with `window_size_right=Some(0)` applies a causal mask to the result
+/// of  `Q @ K^T`
+pub fn flash_attn_varlen_windowed_sinks(
+    q: &Tensor,
+    k: &Tensor,
+    v: &Tensor,
+    sinks: &Tensor,
+    seqlens_q: &Tensor,
+    seqlens_k: &Tensor,
+    max_seqlen_q: usize,
+    max_seqlen_k: usize,
+    softmax_scale: f32,
+    window_size_left: Option<usize>,
+    window_size_right: Option<usize>,
+) -> Result<Tensor> {
+    let op = FlashAttnVarLen {
+        softmax_scale,
+        max_seqlen_q,
+        max_seqlen_k,
+        seqlens_q: seqlens_q.clone(),
+        seqlens_k: seqlens_k.clone(),
+        alibi_slopes: None,
+        window_size_left,
+        window_size_right,
+        softcap: None,
+        sinks: Some(sinks.clone()),
     };
     q.apply_op3(k, v, op)
 }
@@ -27,6 +27,39 @@ fn fa_acausal(q: &Tensor, k: &Tensor, v: &Tensor, softmax_scale: f32) -> Result<
     Ok(output)
 }
 
+// The acausal attention with softcapped logits and a sink logit per head appended to the keys.
+fn fa_acausal_ext(
+    q: &Tensor,
+    k: &Tensor,
+    v: &Tensor,
+    softmax_scale: f32,
+    softcap: Option<f32>,
+    sinks: Option<&Tensor>,
+) -> Result<Tensor> {
+    let in_dtype = q.dtype();
+    let q = q.to_dtype(DType::F32)?;
+    let k = k.to_dtype(DType::F32)?;
+    let v = v.to_dtype(DType::F32)?;
+    let mut att = (q.matmul(&k.t()?)? * softmax_scale as f64)?;
+    if let Some(softcap) = softcap {
+        att = ((att / softcap as f64)?.tanh()? * softcap as f64)?;
+    }
+    let att = match sinks {
+        None => candle_nn::ops::softmax(&att, D::Minus1)?,
+        Some(sinks) => {
+            let (b, h, seq_len, _) = att.dims4()?;
+            let sinks = sinks
+                .reshape((1, h, 1, 1))?
+                .broadcast_as((b, h, seq_len, 1))?;
+            let att =
+                candle_nn::ops::softmax(&Tensor::cat(&[&att, &sinks], D::Minus1)?, D::Minus1)?;
+            att.narrow(D::Minus1, 0, att.dim(D::Minus1)? - 1)?
+        }
+    };
+    let output = att.matmul(&v.contiguous()?)?.to_dtype(in_dtype)?;
+    Ok(output)
+}
+
 #[test]
 fn flash_attn_acausal() -> Result<()> {
     let device = Device::new_cuda(0)?;
@@ -133,3 +166,45 @@ fn flash_attn_varlen() -> Result<()> {
     );
     Ok(())
 }
+
+#[test]
+fn flash_attn_softcap_sinks() -> Result<()> {
+    let device = Device::new_cuda(0)?;
+    let q = Tensor::randn(0f32, 3., (2, 4, 19, 64), &device)?.to_dtype(DType::F16)?;
+    let k = Tensor::randn(0f32, 3., (2, 4, 19, 64), &device)?.to_dtype(DType::F16)?;
+    let v = Tensor::randn(0f32, 1., (2, 4, 19, 64), &device)?.to_dtype(DType::F16)?;
+    let sinks = Tensor::new(&[0.5f32, -1., 2., 8.], &device)?;
+    let diff = |ys1: Tensor, ys2: Tensor| -> Result<f32> {
+        let ys1 = ys1.to_dtype(DType::F32)?;
+        let ys2 = ys2.transpose(1, 2)?.to_dtype(DType::F32)?;
+        Ok(ys1
+            .sub(&ys2)?
+            .abs()?
+            .flatten_all()?
+            .max(0)?
+            .to_vec0::<f32>()?)
+    };
+    let (qt, kt, vt) = (q.transpose(1, 2)?, k.transpose(1, 2)?, v.transpose(1, 2)?);
+
+    let ys1 = fa_acausal_ext(&q, &k, &v, 0.125, Some(5.), None)?;
+    let ys2 = candle_flash_attn::flash_attn_softcap(&qt, &kt, &vt, 0.125, 5., false)?;
+    assert!(diff(ys1, ys2)? < 1e-2);
+
+    let ys1 = fa_acausal_ext(&q, &k, &v, 0.125, None, Some(&sinks))?;
+    let ys2 =
+        candle_flash_attn::flash_attn_windowed_sinks(&qt, &kt, &vt, &sinks, 0.125, None, None)?;
+    assert!(diff(ys1, ys2)? < 1e-2);
+
+    let op = candle_flash_attn::FlashAttn {
+        softmax_scale: 0.125,
+        alibi_slopes: None,
+        window_size_left: None,
+        window_size_right: None,
+        softcap: Some(5.),
+        sinks: Some(sinks.clone()),
+    };
+    let ys1 = fa_acausal_ext(&q, &k, &v, 0.125, Some(5.), Some(&sinks))?;
+    let ys2 = qt.apply_op3(&kt, &vt, op)?;
+    assert!(diff(ys1, ys2)? < 1e-2);
+    Ok(())
+}
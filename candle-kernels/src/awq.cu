@@ -0,0 +1,102 @@
+// Kernels for AWQ checkpoints, weights are stored as 4-bit values packed in u32 with layout
+// (in_dim, out_dim / 8), zero points use the same packing with one row per group of input
+// features and scales have a shape (in_dim / group_size, out_dim).
+#include "cuda_utils.cuh"
+#include<stdint.h>
+
+// The nibble holding the k-th column of a pack of 8 columns.
+__device__ __constant__ int AWQ_NIBBLE[8] = {0, 4, 1, 5, 2, 6, 3, 7};
+
+template <typename T>
+__device__ void awq_dequantize(
+    const uint32_t *qweight,
+    const uint32_t *qzeros,
+    const T *scales,
+    T *dst,
+    const size_t in_dim,
+    const size_t out_dim,
+    const size_t group_size
+) {
+  const size_t packed_out = out_dim / 8;
+  const size_t idx = blockIdx.x * blockDim.x + threadIdx.x;
+  if (idx >= in_dim * packed_out) {
+    return;
+  }
+  const size_t row = idx / packed_out;
+  const size_t col = idx % packed_out;
+  const size_t group = row / group_size;
+  const uint32_t q = qweight[idx];
+  const uint32_t z = qzeros[group * packed_out + col];
+  for (int k = 0; k < 8; ++k) {
+    const int shift = AWQ_NIBBLE[k] * 4;
+    const float w = (float)((q >> shift) & 0xF) - (float)((z >> shift) & 0xF);
+    const size_t o = col * 8 + k;
+    dst[row * out_dim + o] = T(w * float(scales[group * out_dim + o]));
+  }
+}
+
+// Computes xs @ dequantize(qweight) without materializing the weights, xs has shape
+// (n_rows, in_dim). Each thread computes 8 output columns of a row.
+template <typename T>
+__device__ void awq_matmul(
+    const T *xs,
+    const uint32_t *qweight,
+    const uint32_t *qzeros,
+    const T *scales,
+    T *dst,
+    const size_t in_dim,
+    const size_t out_dim,
+    const size_t group_size
+) {
+  const size_t packed_out = out_dim / 8;
+  const size_t col = blockIdx.x * blockDim.x + threadIdx.x;
+  const size_t row = blockIdx.y;
+  if (col >= packed_out) {
+    return;
+  }
+  float acc[8] = {0., 0., 0., 0., 0., 0., 0., 0.};
+  float zeros[8];
+  float s[8];
+  for (size_t i = 0; i < in_dim; ++i) {
+    if (i % group_size == 0) {
+      const size_t group = i / group_size;
+      const uint32_t z = qzeros[group * packed_out + col];
+      for (int k = 0; k < 8; ++k) {
+        zeros[k] = (float)((z >> (AWQ_NIBBLE[k] * 4)) & 0xF);
+        s[k] = float(scales[group * out_dim + col * 8 + k]);
+      }
+    }
+    const float x = float(xs[row * in_dim + i]);
+    const uint32_t q = qweight[i * packed_out + col];
+    for (int k = 0; k < 8; ++k) {
+      const float w = (float)((q >> (AWQ_NIBBLE[k] * 4)) & 0xF) - zeros[k];
+      acc[k] += x * w * s[k];
+    }
+  }
+  for (int k = 0; k < 8; ++k) {
+    dst[row * out_dim + col * 8 + k] = T(acc[k]);
+  }
+}
+
+#define AWQ_OPS(TYPENAME, RUST_NAME) \
+  extern "C" __global__ void awq_dequantize_##RUST_NAME( \
+      const uint32_t *qweight, const uint32_t *qzeros, const TYPENAME *scales, TYPENAME *dst, \
+      const size_t in_dim, const size_t out_dim, const size_t group_size) { \
+    awq_dequantize<TYPENAME>(qweight, qzeros, scales, dst, in_dim, out_dim, group_size); \
+  } \
+  extern "C" __global__ void awq_matmul_##RUST_NAME( \
+      const TYPENAME *xs, const uint32_t *qweight, const uint32_t *qzeros, \
+      const TYPENAME *scales, TYPENAME *dst, \
+      const size_t in_dim, const size_t out_dim, const size_t group_size) { \
+    awq_matmul<TYPENAME>(xs, qweight, qzeros, scales, dst, in_dim, out_dim, group_size); \
+  } \
+
+#if __CUDA_ARCH__ >= 800
+AWQ_OPS(__nv_bfloat16, bf16)
+#endif
+
+#if __CUDA_ARCH__ >= 530
+AWQ_OPS(__half, f16)
+#endif
+
+AWQ_OPS(float, f32)
@@ -0,0 +1,460 @@
+// Kernels for the rasterization of 2D gaussians, see candle-nn/src/gaussian_splatting.rs. Each
+// gaussian is stored as (mean_x, mean_y, conic_a, conic_b, conic_c, opacity, colors...) and the
+// gaussians are sorted by depth.
+//
+// The gaussians are first binned by tile as in `tile_lists`: each gaussian emits a key
+// (tile << 32) | index per tile that its bounding box overlaps, the keys are sorted by tile with
+// a stable radix sort so that the gaussians of a tile stay sorted by depth, and the range of keys
+// of each tile is extracted. The rasterization then uses one block of GS_TILE x GS_TILE threads
+// per tile of the image and one thread per pixel, the gaussians of the tile are loaded in shared
+// memory by batches of one per thread.
+#include "cuda_utils.cuh"
+#include<stdint.h>
+
+#define GS_TILE 16
+#define GS_THREADS (GS_TILE * GS_TILE)
+#define GS_SCAN_THREADS 1024
+#define GS_SORT_THREADS 256
+#define GS_SORT_WARPS (GS_SORT_THREADS / 32)
+#define GS_RADIX_BITS 8
+#define GS_RADIX (1 << GS_RADIX_BITS)
+#define GS_MAX_CHANNELS 16
+#define GS_PARAMS 6
+#define GS_MAX_ALPHA 0.99f
+#define GS_MIN_ALPHA (1.f / 255.f)
+#define GS_MIN_TRANSMITTANCE 1e-4f
+
+// Has to match `contribution` in gaussian_splatting.rs.
+__device__ bool gs_contribution(
+    const float *p,
+    const float px,
+    const float py,
+    float *alpha,
+    float *g,
+    float *dx,
+    float *dy,
+    bool *clamped
+) {
+  const float a = p[2], b = p[3], c = p[4], opacity = p[5];
+  if (!(a > 0.f && a * c - b * b > 0.f)) {
+    return false;
+  }
+  *dx = px - p[0];
+  *dy = py - p[1];
+  const float power = -0.5f * (a * *dx * *dx + c * *dy * *dy) - b * *dx * *dy;
+  if (power > 0.f) {
+    return false;
+  }
+  *g = expf(power);
+  const float al = opacity * *g;
+  *clamped = al > GS_MAX_ALPHA;
+  *alpha = fminf(al, GS_MAX_ALPHA);
+  return *alpha >= GS_MIN_ALPHA;
+}
+
+// The tiles (tx0..tx1, ty0..ty1) overlapped by the bounding box of a gaussian, this has to match
+// `bounding_box` in gaussian_splatting.rs.
+__device__ bool gs_tile_rect(
+    const float *p,
+    const size_t width,
+    const size_t height,
+    uint32_t *tx0,
+    uint32_t *tx1,
+    uint32_t *ty0,
+    uint32_t *ty1
+) {
+  const float a = p[2], b = p[3], c = p[4], opacity = p[5];
+  const float det = a * c - b * b;
+  if (!(a > 0.f && det > 0.f) || opacity * 255.f <= 1.f) {
+    return false;
+  }
+  // The largest eigenvalue of the covariance, i.e. of the inverse of the conic.
+  const float mid = 0.5f * (a + c) / det;
+  const float lambda = mid + sqrtf(fmaxf(mid * mid - 1.f / det, 0.f));
+  const float radius = sqrtf(2.f * logf(opacity * 255.f) * lambda) + 1.f;
+  const float x0 = fmaxf(floorf(p[0] - radius), 0.f);
+  const float y0 = fmaxf(floorf(p[1] - radius), 0.f);
+  const float x1 = fminf(ceilf(p[0] + radius), (float)width);
+  const float y1 = fminf(ceilf(p[1] + radius), (float)height);
+  if (!(x0 < x1 && y0 < y1)) {
+    return false;
+  }
+  *tx0 = (uint32_t)x0 / GS_TILE;
+  *tx1 = ((uint32_t)x1 + GS_TILE - 1) / GS_TILE;
+  *ty0 = (uint32_t)y0 / GS_TILE;
+  *ty1 = ((uint32_t)y1 + GS_TILE - 1) / GS_TILE;
+  return true;
+}
+
+// The number of tiles overlapped by each gaussian.
+extern "C" __global__ void gaussians_count_tiles(
+    const float *params,
+    uint32_t *counts,
+    const size_t n,
+    const size_t stride,
+    const size_t width,
+    const size_t height
+) {
+  const size_t i = blockIdx.x * blockDim.x + threadIdx.x;
+  if (i >= n) {
+    return;
+  }
+  uint32_t tx0, tx1, ty0, ty1;
+  if (gs_tile_rect(params + i * stride, width, height, &tx0, &tx1, &ty0, &ty1)) {
+    counts[i] = (tx1 - tx0) * (ty1 - ty0);
+  } else {
+    counts[i] = 0;
+  }
+}
+
+// The exclusive prefix sum of the `n` values of `src` using a single block of GS_SCAN_THREADS
+// threads, each thread handles a contiguous chunk. `dst` gets `n + 1` values, the last one being
+// the total.
+extern "C" __global__ void gaussians_exclusive_scan(
+    const uint32_t *src,
+    uint32_t *dst,
+    const size_t n
+) {
+  __shared__ uint32_t sums[GS_SCAN_THREADS];
+  const size_t tid = threadIdx.x;
+  const size_t chunk = (n + GS_SCAN_THREADS - 1) / GS_SCAN_THREADS;
+  const size_t start = min(tid * chunk, n);
+  const size_t end = min(start + chunk, n);
+  uint32_t sum = 0;
+  for (size_t i = start; i < end; ++i) {
+    sum += src[i];
+  }
+  sums[tid] = sum;
+  __syncthreads();
+  for (size_t offset = 1; offset < GS_SCAN_THREADS; offset *= 2) {
+    const uint32_t v = tid >= offset ? sums[tid - offset] : 0;
+    __syncthreads();
+    sums[tid] += v;
+    __syncthreads();
+  }
+  uint32_t acc = sums[tid] - sum;
+  for (size_t i = start; i < end; ++i) {
+    const uint32_t v = src[i];
+    dst[i] = acc;
+    acc += v;
+  }
+  if (tid == GS_SCAN_THREADS - 1) {
+    dst[n] = sums[tid];
+  }
+}
+
+// Writes the keys of the tiles overlapped by each gaussian starting at `offsets`, the exclusive
+// prefix sum of the counts, the gaussians with a lower index come first.
+extern "C" __global__ void gaussians_emit_keys(
+    const float *params,
+    const uint32_t *offsets,
+    uint64_t *keys,
+    const size_t n,
+    const size_t stride,
+    const size_t width,
+    const size_t height
+) {
+  const size_t i = blockIdx.x * blockDim.x + threadIdx.x;
+  if (i >= n) {
+    return;
+  }
+  uint32_t tx0, tx1, ty0, ty1;
+  if (!gs_tile_rect(params + i * stride, width, height, &tx0, &tx1, &ty0, &ty1)) {
+    return;
+  }
+  const uint64_t tiles_x = (width + GS_TILE - 1) / GS_TILE;
+  uint32_t o = offsets[i];
+  for (uint32_t ty = ty0; ty < ty1; ++ty) {
+    for (uint32_t tx = tx0; tx < tx1; ++tx) {
+      keys[o++] = ((ty * tiles_x + tx) << 32) | (uint64_t)i;
+    }
+  }
+}
+
+// Returns the rank of the element of the thread among the elements of the block with the same
+// digit and a lower index, and sets `counts` to the number of elements of each digit in the
+// block. There is one element per thread, all the threads of the block have to call this.
+__device__ uint32_t gs_digit_rank(const uint32_t digit, const bool valid, uint32_t *counts) {
+  __shared__ uint32_t warp_counts[GS_SORT_WARPS][GS_RADIX];
+  const uint32_t tid = threadIdx.x;
+  const uint32_t lane = tid % 32;
+  const uint32_t warp = tid / 32;
+  for (uint32_t d = tid; d < GS_RADIX; d += GS_SORT_THREADS) {
+    for (uint32_t w = 0; w < GS_SORT_WARPS; ++w) {
+      warp_counts[w][d] = 0;
+    }
+  }
+  __syncthreads();
+  // The lanes of the warp with the same digit, one vote per bit of the digit.
+  uint32_t peers = __ballot_sync(0xffffffff, valid);
+  for (int b = 0; b < GS_RADIX_BITS; ++b) {
+    const bool bit = (digit >> b) & 1;
+    const uint32_t votes = __ballot_sync(0xffffffff, bit);
+    peers &= bit ? votes : ~votes;
+  }
+  const uint32_t rank = __popc(peers & ((1u << lane) - 1));
+  if (valid && rank == 0) {
+    warp_counts[warp][digit] = __popc(peers);
+  }
+  __syncthreads();
+  // The offset of each warp among the elements of a digit.
+  for (uint32_t d = tid; d < GS_RADIX; d += GS_SORT_THREADS) {
+    uint32_t sum = 0;
+    for (uint32_t w = 0; w < GS_SORT_WARPS; ++w) {
+      const uint32_t count = warp_counts[w][d];
+      warp_counts[w][d] = sum;
+      sum += count;
+    }
+    counts[d] = sum;
+  }
+  __syncthreads();
+  return valid ? warp_counts[warp][digit] + rank : 0;
+}
+
+// The number of keys of each digit in each block for the radix sort pass that uses the bits
+// `shift..shift + GS_RADIX_BITS`, stored digit major so that the exclusive prefix sum gives the
+// position of the first key of each (digit, block).
+extern "C" __global__ void gaussians_radix_histogram(
+    const uint64_t *keys,
+    uint32_t *hist,
+    const size_t m,
+    const uint32_t shift
+) {
+  __shared__ uint32_t counts[GS_RADIX];
+  const size_t i = blockIdx.x * GS_SORT_THREADS + threadIdx.x;
+  const bool valid = i < m;
+  const uint32_t digit = valid ? (uint32_t)(keys[i] >> shift) & (GS_RADIX - 1) : 0;
+  gs_digit_rank(digit, valid, counts);
+  for (uint32_t d = threadIdx.x; d < GS_RADIX; d += GS_SORT_THREADS) {
+    hist[d * gridDim.x + blockIdx.x] = counts[d];
+  }
+}
+
+// Moves the keys to their position for the radix sort pass, `offsets` is the exclusive prefix
+// sum of the histogram. The order of the keys with the same digit is preserved.
+extern "C" __global__ void gaussians_radix_scatter(
+    const uint64_t *keys,
+    const uint32_t *offsets,
+    uint64_t *dst,
+    const size_t m,
+    const uint32_t shift
+) {
+  __shared__ uint32_t counts[GS_RADIX];
+  const size_t i = blockIdx.x * GS_SORT_THREADS + threadIdx.x;
+  const bool valid = i < m;
+  const uint64_t key = valid ? keys[i] : 0;
+  const uint32_t digit = (uint32_t)(key >> shift) & (GS_RADIX - 1);
+  const uint32_t rank = gs_digit_rank(digit, valid, counts);
+  if (valid) {
+    dst[offsets[digit * gridDim.x + blockIdx.x] + rank] = key;
+  }
+}
+
+// The range of the sorted keys of each tile, `ranges` holds a (start, end) pair per tile and has
+// to be zeroed.
+extern "C" __global__ void gaussians_tile_ranges(
+    const uint64_t *keys,
+    uint32_t *ranges,
+    const size_t m
+) {
+  const size_t i = blockIdx.x * blockDim.x + threadIdx.x;
+  if (i >= m) {
+    return;
+  }
+  const uint32_t tile = (uint32_t)(keys[i] >> 32);
+  if (i == 0 || (uint32_t)(keys[i - 1] >> 32) != tile) {
+    ranges[2 * tile] = i;
+  }
+  if (i == m - 1 || (uint32_t)(keys[i + 1] >> 32) != tile) {
+    ranges[2 * tile + 1] = i + 1;
+  }
+}
+
+// Loads the params of the gaussians of the keys `start..end` in shared memory.
+__device__ void gs_load(
+    float *shared,
+    const float *params,
+    const uint64_t *keys,
+    const size_t start,
+    const size_t end,
+    const size_t stride
+) {
+  const size_t tid = threadIdx.y * GS_TILE + threadIdx.x;
+  const size_t j = start + tid;
+  if (j < end) {
+    const size_t i = (uint32_t)keys[j];
+    for (size_t k = 0; k < stride; ++k) {
+      shared[tid * stride + k] = params[i * stride + k];
+    }
+  }
+}
+
+extern "C" __global__ void rasterize_gaussians_fwd(
+    const float *params,
+    const uint64_t *keys,
+    const uint32_t *ranges,
+    const float *background,
+    float *dst,
+    const size_t channels,
+    const size_t width,
+    const size_t height
+) {
+  extern __shared__ float shared[];
+  const size_t stride = GS_PARAMS + channels;
+  const size_t x = blockIdx.x * GS_TILE + threadIdx.x;
+  const size_t y = blockIdx.y * GS_TILE + threadIdx.y;
+  const size_t tile = blockIdx.y * gridDim.x + blockIdx.x;
+  const size_t range_start = ranges[2 * tile];
+  const size_t range_end = ranges[2 * tile + 1];
+  const bool inside = x < width && y < height;
+  bool done = !inside;
+  float color[GS_MAX_CHANNELS];
+  for (size_t ch = 0; ch < channels; ++ch) {
+    color[ch] = 0.f;
+  }
+  float t = 1.f;
+  for (size_t start = range_start; start < range_end; start += GS_THREADS) {
+    // All the threads of the block have to reach the barriers.
+    if (__syncthreads_count(done) == GS_THREADS) {
+      break;
+    }
+    gs_load(shared, params, keys, start, range_end, stride);
+    __syncthreads();
+    const size_t count = min((size_t)GS_THREADS, range_end - start);
+    for (size_t j = 0; j < count && !done; ++j) {
+      const float *p = shared + j * stride;
+      float alpha, g, dx, dy;
+      bool clamped;
+      if (!gs_contribution(p, (float)x, (float)y, &alpha, &g, &dx, &dy, &clamped)) {
+        continue;
+      }
+      const float next_t = t * (1.f - alpha);
+      if (next_t < GS_MIN_TRANSMITTANCE) {
+        done = true;
+        break;
+      }
+      for (size_t ch = 0; ch < channels; ++ch) {
+        color[ch] += p[GS_PARAMS + ch] * alpha * t;
+      }
+      t = next_t;
+    }
+  }
+  if (inside) {
+    float *out = dst + (y * width + x) * channels;
+    for (size_t ch = 0; ch < channels; ++ch) {
+      out[ch] = color[ch] + t * background[ch];
+    }
+  }
+}
+
+// The gradient of the params, `grads` has to be zeroed. The forward pass is run again to get
+// the final transmittance and the last contributing gaussian of each pixel, then the gaussians
+// are processed back to front.
+extern "C" __global__ void rasterize_gaussians_bwd(
+    const float *params,
+    const uint64_t *keys,
+    const uint32_t *ranges,
+    const float *grad_out,
+    const float *background,
+    float *grads,
+    const size_t channels,
+    const size_t width,
+    const size_t height
+) {
+  extern __shared__ float shared[];
+  __shared__ unsigned int block_last;
+  const size_t stride = GS_PARAMS + channels;
+  const size_t x = blockIdx.x * GS_TILE + threadIdx.x;
+  const size_t y = blockIdx.y * GS_TILE + threadIdx.y;
+  const size_t tile = blockIdx.y * gridDim.x + blockIdx.x;
+  const size_t range_start = ranges[2 * tile];
+  const size_t range_end = ranges[2 * tile + 1];
+  const bool inside = x < width && y < height;
+  if (threadIdx.x == 0 && threadIdx.y == 0) {
+    block_last = 0;
+  }
+  bool done = !inside;
+  float t = 1.f;
+  // One past the key of the last contributing gaussian.
+  size_t last = 0;
+  for (size_t start = range_start; start < range_end; start += GS_THREADS) {
+    if (__syncthreads_count(done) == GS_THREADS) {
+      break;
+    }
+    gs_load(shared, params, keys, start, range_end, stride);
+    __syncthreads();
+    const size_t count = min((size_t)GS_THREADS, range_end - start);
+    for (size_t j = 0; j < count && !done; ++j) {
+      float alpha, g, dx, dy;
+      bool clamped;
+      if (!gs_contribution(shared + j * stride, (float)x, (float)y, &alpha, &g, &dx, &dy, &clamped)) {
+        continue;
+      }
+      const float next_t = t * (1.f - alpha);
+      if (next_t < GS_MIN_TRANSMITTANCE) {
+        done = true;
+        break;
+      }
+      t = next_t;
+      last = start + j + 1;
+    }
+  }
+  __syncthreads();
+  atomicMax(&block_last, (unsigned int)last);
+  __syncthreads();
+  const size_t block_end = block_last;
+
+  const float final_t = t;
+  float grad_pixel[GS_MAX_CHANNELS];
+  float suffix[GS_MAX_CHANNELS];
+  float bg_dot = 0.f;
+  for (size_t ch = 0; ch < channels; ++ch) {
+    grad_pixel[ch] = inside ? grad_out[(y * width + x) * channels + ch] : 0.f;
+    suffix[ch] = 0.f;
+    bg_dot += grad_pixel[ch] * background[ch];
+  }
+  if (block_end == 0) {
+    return;
+  }
+  const long long first = range_start;
+  for (long long start = first + ((block_end - 1 - range_start) / GS_THREADS) * GS_THREADS; start >= first; start -= GS_THREADS) {
+    __syncthreads();
+    gs_load(shared, params, keys, (size_t)start, range_end, stride);
+    __syncthreads();
+    const long long count = min((long long)GS_THREADS, (long long)range_end - start);
+    for (long long j = count - 1; j >= 0; --j) {
+      const size_t position = (size_t)(start + j);
+      if (!inside || position >= last) {
+        continue;
+      }
+      const size_t i = (uint32_t)keys[position];
+      const float *p = shared + j * stride;
+      float alpha, g, dx, dy;
+      bool clamped;
+      if (!gs_contribution(p, (float)x, (float)y, &alpha, &g, &dx, &dy, &clamped)) {
+        continue;
+      }
+      // The transmittance before this gaussian.
+      t /= 1.f - alpha;
+      float *grad = grads + i * stride;
+      float d_alpha = -final_t * bg_dot / (1.f - alpha);
+      for (size_t ch = 0; ch < channels; ++ch) {
+        const float color = p[GS_PARAMS + ch];
+        atomicAdd(grad + GS_PARAMS + ch, alpha * t * grad_pixel[ch]);
+        d_alpha += grad_pixel[ch] * (color * t - suffix[ch] / (1.f - alpha));
+        suffix[ch] += color * alpha * t;
+      }
+      if (clamped) {
+        continue;
+      }
+      const float a = p[2], b = p[3], c = p[4], opacity = p[5];
+      const float d_g = opacity * d_alpha * g;
+      atomicAdd(grad + 0, d_g * (a * dx + b * dy));
+      atomicAdd(grad + 1, d_g * (c * dy + b * dx));
+      atomicAdd(grad + 2, -0.5f * d_g * dx * dx);
+      atomicAdd(grad + 3, -d_g * dx * dy);
+      atomicAdd(grad + 4, -0.5f * d_g * dy * dy);
+      atomicAdd(grad + 5, g * d_alpha);
+    }
+  }
+}
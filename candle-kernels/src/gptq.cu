@@ -0,0 +1,102 @@
+// Kernels for GPTQ checkpoints. The weights use `bits` bits per value and are packed in u32
+// along the input dimension, qweight has shape (in_dim * bits / 32, out_dim). The zero points are
+// packed along the output dimension with shape (n_groups, out_dim * bits / 32), scales have shape
+// (n_groups, out_dim) and g_idx gives the group of each input feature.
+#include "cuda_utils.cuh"
+#include<stdint.h>
+
+template <typename T>
+__device__ __forceinline__ float gptq_weight(
+    const uint32_t *qweight,
+    const uint32_t *qzeros,
+    const T *scales,
+    const uint32_t *g_idx,
+    const size_t i,
+    const size_t o,
+    const size_t out_dim,
+    const uint32_t bits,
+    const uint32_t zero_offset
+) {
+  const uint32_t pack = 32 / bits;
+  const uint32_t mask = (1u << bits) - 1;
+  const size_t group = g_idx[i];
+  const uint32_t q = (qweight[(i / pack) * out_dim + o] >> ((i % pack) * bits)) & mask;
+  const uint32_t z = (qzeros[group * (out_dim / pack) + o / pack] >> ((o % pack) * bits)) & mask;
+  return ((float)q - (float)(z + zero_offset)) * float(scales[group * out_dim + o]);
+}
+
+template <typename T>
+__device__ void gptq_dequantize(
+    const uint32_t *qweight,
+    const uint32_t *qzeros,
+    const T *scales,
+    const uint32_t *g_idx,
+    T *dst,
+    const size_t in_dim,
+    const size_t out_dim,
+    const uint32_t bits,
+    const uint32_t zero_offset
+) {
+  const size_t idx = blockIdx.x * blockDim.x + threadIdx.x;
+  if (idx >= in_dim * out_dim) {
+    return;
+  }
+  const size_t i = idx / out_dim;
+  const size_t o = idx % out_dim;
+  dst[idx] = T(gptq_weight(qweight, qzeros, scales, g_idx, i, o, out_dim, bits, zero_offset));
+}
+
+// Computes xs @ dequantize(qweight) without materializing the weights, xs has shape
+// (n_rows, in_dim). Each thread computes one output of a row.
+template <typename T>
+__device__ void gptq_matmul(
+    const T *xs,
+    const uint32_t *qweight,
+    const uint32_t *qzeros,
+    const T *scales,
+    const uint32_t *g_idx,
+    T *dst,
+    const size_t in_dim,
+    const size_t out_dim,
+    const uint32_t bits,
+    const uint32_t zero_offset
+) {
+  const size_t o = blockIdx.x * blockDim.x + threadIdx.x;
+  const size_t row = blockIdx.y;
+  if (o >= out_dim) {
+    return;
+  }
+  float acc = 0.;
+  for (size_t i = 0; i < in_dim; ++i) {
+    const float w = gptq_weight(qweight, qzeros, scales, g_idx, i, o, out_dim, bits, zero_offset);
+    acc += float(xs[row * in_dim + i]) * w;
+  }
+  dst[row * out_dim + o] = T(acc);
+}
+
+#define GPTQ_OPS(TYPENAME, RUST_NAME) \
+  extern "C" __global__ void gptq_dequantize_##RUST_NAME( \
+      const uint32_t *qweight, const uint32_t *qzeros, const TYPENAME *scales, \
+      const uint32_t *g_idx, TYPENAME *dst, const size_t in_dim, const size_t out_dim, \
+      const uint32_t bits, const uint32_t zero_offset) { \
+    gptq_dequantize<TYPENAME>( \
+        qweight, qzeros, scales, g_idx, dst, in_dim, out_dim, bits, zero_offset); \
+  } \
+  extern "C" __global__ void gptq_matmul_##RUST_NAME( \
+      const TYPENAME *xs, const uint32_t *qweight, const uint32_t *qzeros, \
+      const TYPENAME *scales, const uint32_t *g_idx, TYPENAME *dst, \
+      const size_t in_dim, const size_t out_dim, const uint32_t bits, \
+      const uint32_t zero_offset) { \
+    gptq_matmul<TYPENAME>( \
+        xs, qweight, qzeros, scales, g_idx, dst, in_dim, out_dim, bits, zero_offset); \
+  } \
+
+#if __CUDA_ARCH__ >= 800
+GPTQ_OPS(__nv_bfloat16, bf16)
+#endif
+
+#if __CUDA_ARCH__ >= 530
+GPTQ_OPS(__half, f16)
+#endif
+
+GPTQ_OPS(float, f32)
@@ -1,11 +1,18 @@
 pub const AFFINE: &str = include_str!(concat!(env!("OUT_DIR"), "/affine.ptx"));
+pub const AWQ: &str = include_str!(concat!(env!("OUT_DIR"), "/awq.ptx"));
 pub const BINARY: &str = include_str!(concat!(env!("OUT_DIR"), "/binary.ptx"));
 pub const CAST: &str = include_str!(concat!(env!("OUT_DIR"), "/cast.ptx"));
 pub const CONV: &str = include_str!(concat!(env!("OUT_DIR"), "/conv.ptx"));
 pub const FILL: &str = include_str!(concat!(env!("OUT_DIR"), "/fill.ptx"));
+pub const GAUSSIAN_SPLATTING: &str =
+    include_str!(concat!(env!("OUT_DIR"), "/gaussian_splatting.ptx"));
+pub const GPTQ: &str = include_str!(concat!(env!("OUT_DIR"), "/gptq.ptx"));
 pub const INDEXING: &str = include_str!(concat!(env!("OUT_DIR"), "/indexing.ptx"));
+pub const OPTIM: &str = include_str!(concat!(env!("OUT_DIR"), "/optim.ptx"));
 pub const QUANTIZED: &str = include_str!(concat!(env!("OUT_DIR"), "/quantized.ptx"));
+pub const RANDOM: &str = include_str!(concat!(env!("OUT_DIR"), "/random.ptx"));
 pub const REDUCE: &str = include_str!(concat!(env!("OUT_DIR"), "/reduce.ptx"));
 pub const SORT: &str = include_str!(concat!(env!("OUT_DIR"), "/sort.ptx"));
 pub const TERNARY: &str = include_str!(concat!(env!("OUT_DIR"), "/ternary.ptx"));
 pub const UNARY: &str = include_str!(concat!(env!("OUT_DIR"), "/unary.ptx"));
+pub const W8A8: &str = include_str!(concat!(env!("OUT_DIR"), "/w8a8.ptx"));
@@ -0,0 +1,198 @@
+// Fused optimizer kernels, see candle-nn/src/optim/fused.rs and candle-nn/src/grad_clip.rs.
+#include "cuda_utils.cuh"
+#include <stdint.h>
+
+// The number of values sharing a scale in the 8-bit states, the kernels are launched with blocks
+// of QBLOCK threads.
+#define QBLOCK 256
+#define WARP_SIZE 32
+
+// The 8-bit states are laid out as the f32 scales of the blocks of m, then of v, followed by
+// the n bytes of m and the n bytes of v. m uses a signed square map, x = sign(r) r^2 scale
+// with r = (q - 128) / 127, v stores sqrt(v) with the unsigned map (q / 255)^2 scale.
+__device__ __forceinline__ float dequantize_signed(const uint8_t q, const float scale) {
+    const float r = (static_cast<float>(q) - 128.f) / 127.f;
+    return r * fabsf(r) * scale;
+}
+
+__device__ __forceinline__ uint8_t quantize_signed(const float x, const float scale) {
+    if (scale <= 0.f) {
+        return 128;
+    }
+    const float r = fminf(fmaxf(x / scale, -1.f), 1.f);
+    return static_cast<uint8_t>(128.f + copysignf(rintf(sqrtf(fabsf(r)) * 127.f), r));
+}
+
+__device__ __forceinline__ float dequantize_unsigned(const uint8_t q, const float scale) {
+    const float r = static_cast<float>(q) / 255.f;
+    return r * r * scale;
+}
+
+__device__ __forceinline__ uint8_t quantize_unsigned(const float x, const float scale) {
+    if (scale <= 0.f) {
+        return 0;
+    }
+    const float r = fminf(fmaxf(x / scale, 0.f), 1.f);
+    return static_cast<uint8_t>(rintf(sqrtf(r) * 255.f));
+}
+
+__device__ __forceinline__ float warp_reduce_max(float x) {
+#pragma unroll
+    for (int mask = 16; mask > 0; mask >>= 1) {
+        x = fmaxf(x, __shfl_xor_sync(0xffffffff, x, mask, 32));
+    }
+    return x;
+}
+
+// The max over the QBLOCK threads of the block.
+__device__ __forceinline__ float block_reduce_max(float x) {
+    __shared__ float s_max[QBLOCK / WARP_SIZE];
+    x = warp_reduce_max(x);
+    const int warp_id = threadIdx.x / WARP_SIZE;
+    const int lane_id = threadIdx.x % WARP_SIZE;
+    __syncthreads();
+    if (lane_id == 0) {
+        s_max[warp_id] = x;
+    }
+    __syncthreads();
+    x = lane_id < QBLOCK / WARP_SIZE ? s_max[lane_id] : 0.f;
+    return warp_reduce_max(x);
+}
+
+__device__ __forceinline__ float warp_reduce_sum(float x) {
+#pragma unroll
+    for (int mask = 16; mask > 0; mask >>= 1) {
+        x += __shfl_xor_sync(0xffffffff, x, mask, 32);
+    }
+    return x;
+}
+
+// The sum over the QBLOCK threads of the block.
+__device__ __forceinline__ float block_reduce_sum(float x) {
+    __shared__ float s_sum[QBLOCK / WARP_SIZE];
+    x = warp_reduce_sum(x);
+    const int warp_id = threadIdx.x / WARP_SIZE;
+    const int lane_id = threadIdx.x % WARP_SIZE;
+    __syncthreads();
+    if (lane_id == 0) {
+        s_sum[warp_id] = x;
+    }
+    __syncthreads();
+    x = lane_id < QBLOCK / WARP_SIZE ? s_sum[lane_id] : 0.f;
+    return warp_reduce_sum(x);
+}
+
+// The partial sums of the squares of multiple tensors. The row `blockIdx.y` of the grid
+// processes the tensor with the data pointer `ptrs[blockIdx.y]` and `lens[blockIdx.y]` elements,
+// the blocks have QBLOCK threads. Each block writes its sum to its own element of `out`, which
+// has `gridDim.y * gridDim.x` elements, so that the partial sums can be reduced in a fixed order
+// rather than with atomics.
+template <typename T>
+__device__ void sum_sq_multi(const uint64_t *ptrs, const uint64_t *lens, float *out) {
+    const T *x = reinterpret_cast<const T *>(ptrs[blockIdx.y]);
+    const size_t n = lens[blockIdx.y];
+    float acc = 0.f;
+    for (size_t i = blockIdx.x * blockDim.x + threadIdx.x; i < n; i += blockDim.x * gridDim.x) {
+        const float v = static_cast<float>(x[i]);
+        acc += v * v;
+    }
+    acc = block_reduce_sum(acc);
+    if (threadIdx.x == 0) {
+        out[blockIdx.y * gridDim.x + blockIdx.x] = acc;
+    }
+}
+
+// The update of the variables of `lens[y]` elements with the data pointers `thetas[y]`, the
+// gradients `grads[y]` and the moments `states[y]`, the row y = `blockIdx.y` of the grid processes
+// the variable y. The moments are stored as (m, v) with n values each, they are updated and
+// applied to the variable in the same pass.
+template <typename T>
+__device__ void adam_multi(const uint64_t *thetas, const uint64_t *grads, const uint64_t *states, const uint64_t *lens, const float beta1, const float beta2, const float lr, const float decay, const float scale_m, const float scale_v, const float eps) {
+    T *theta = reinterpret_cast<T *>(thetas[blockIdx.y]);
+    const T *grad = reinterpret_cast<const T *>(grads[blockIdx.y]);
+    float *state = reinterpret_cast<float *>(states[blockIdx.y]);
+    const size_t n = lens[blockIdx.y];
+    for (size_t i = blockIdx.x * blockDim.x + threadIdx.x; i < n; i += blockDim.x * gridDim.x) {
+        const float g = static_cast<float>(grad[i]);
+        const float m = beta1 * state[i] + (1.f - beta1) * g;
+        const float v = beta2 * state[n + i] + (1.f - beta2) * g * g;
+        state[i] = m;
+        state[n + i] = v;
+        const float t = static_cast<float>(theta[i]) * decay - lr * (m * scale_m) / (sqrtf(v * scale_v) + eps);
+        theta[i] = static_cast<T>(t);
+    }
+}
+
+// The same update with the 8-bit states, the blocks have QBLOCK threads and each block processes
+// whole quantization blocks. The update uses the quantized moments so that the result does not
+// depend on the device.
+template <typename T>
+__device__ void adam_multi_8bit(const uint64_t *thetas, const uint64_t *grads, const uint64_t *states, const uint64_t *lens, const float beta1, const float beta2, const float lr, const float decay, const float scale_m, const float scale_v, const float eps) {
+    T *theta = reinterpret_cast<T *>(thetas[blockIdx.y]);
+    const T *grad = reinterpret_cast<const T *>(grads[blockIdx.y]);
+    uint8_t *state = reinterpret_cast<uint8_t *>(states[blockIdx.y]);
+    const size_t n = lens[blockIdx.y];
+    const size_t nb = (n + QBLOCK - 1) / QBLOCK;
+    float *m_scales = reinterpret_cast<float *>(state);
+    float *v_scales = m_scales + nb;
+    uint8_t *qm = state + 8 * nb;
+    uint8_t *qv = qm + n;
+    // The loop bounds are the same for all the threads of a block, as required by the reductions.
+    for (size_t b = blockIdx.x; b < nb; b += gridDim.x) {
+        const size_t i = b * QBLOCK + threadIdx.x;
+        float m = 0.f;
+        float u = 0.f;
+        if (i < n) {
+            const float g = static_cast<float>(grad[i]);
+            m = beta1 * dequantize_signed(qm[i], m_scales[b]) + (1.f - beta1) * g;
+            const float u_old = dequantize_unsigned(qv[i], v_scales[b]);
+            u = sqrtf(beta2 * u_old * u_old + (1.f - beta2) * g * g);
+        }
+        // The reductions synchronize the threads so the old scales are read before being replaced.
+        const float m_max = block_reduce_max(fabsf(m));
+        const float u_max = block_reduce_max(u);
+        if (threadIdx.x == 0) {
+            m_scales[b] = m_max;
+            v_scales[b] = u_max;
+        }
+        if (i < n) {
+            const uint8_t q_m = quantize_signed(m, m_max);
+            const uint8_t q_v = quantize_unsigned(u, u_max);
+            qm[i] = q_m;
+            qv[i] = q_v;
+            const float m_hat = dequantize_signed(q_m, m_max) * scale_m;
+            const float u_q = dequantize_unsigned(q_v, u_max);
+            const float v_hat = u_q * u_q * scale_v;
+            const float t = static_cast<float>(theta[i]) * decay - lr * m_hat / (sqrtf(v_hat) + eps);
+            theta[i] = static_cast<T>(t);
+        }
+    }
+}
+
+#define ADAM_OPS(TYPENAME, RUST_NAME) \
+  extern "C" __global__ void adam_multi_##RUST_NAME( \
+      const uint64_t *thetas, const uint64_t *grads, const uint64_t *states, const uint64_t *lens, \
+      const float beta1, const float beta2, const float lr, const float decay, const float scale_m, \
+      const float scale_v, const float eps) { \
+    adam_multi<TYPENAME>(thetas, grads, states, lens, beta1, beta2, lr, decay, scale_m, scale_v, eps); \
+  } \
+  extern "C" __global__ void adam_multi_8bit_##RUST_NAME( \
+      const uint64_t *thetas, const uint64_t *grads, const uint64_t *states, const uint64_t *lens, \
+      const float beta1, const float beta2, const float lr, const float decay, const float scale_m, \
+      const float scale_v, const float eps) { \
+    adam_multi_8bit<TYPENAME>(thetas, grads, states, lens, beta1, beta2, lr, decay, scale_m, scale_v, eps); \
+  } \
+  extern "C" __global__ void sum_sq_multi_##RUST_NAME( \
+      const uint64_t *ptrs, const uint64_t *lens, float *out) { \
+    sum_sq_multi<TYPENAME>(ptrs, lens, out); \
+  } \
+
+#if __CUDA_ARCH__ >= 800
+ADAM_OPS(__nv_bfloat16, bf16)
+#endif
+
+#if __CUDA_ARCH__ >= 530
+ADAM_OPS(__half, f16)
+#endif
+
+ADAM_OPS(float, f32)
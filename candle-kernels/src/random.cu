@@ -0,0 +1,103 @@
+// Counter-based random number generation with Philox4x32-10, this has to match the cpu
+// implementation in candle-core/src/rng.rs so that all the devices generate the same values.
+#include "cuda_utils.cuh"
+#include<stdint.h>
+
+#define PHILOX_M0 0xD2511F53
+#define PHILOX_M1 0xCD9E8D57
+#define PHILOX_W0 0x9E3779B9
+#define PHILOX_W1 0xBB67AE85
+
+__device__ __forceinline__ uint4 philox4x32_10(uint64_t seed, uint64_t counter) {
+  uint32_t c0 = (uint32_t)counter;
+  uint32_t c1 = (uint32_t)(counter >> 32);
+  uint32_t c2 = 0;
+  uint32_t c3 = 0;
+  uint32_t k0 = (uint32_t)seed;
+  uint32_t k1 = (uint32_t)(seed >> 32);
+  for (int round = 0; round < 10; ++round) {
+    if (round > 0) {
+      k0 += PHILOX_W0;
+      k1 += PHILOX_W1;
+    }
+    const uint32_t hi0 = __umulhi(PHILOX_M0, c0);
+    const uint32_t lo0 = PHILOX_M0 * c0;
+    const uint32_t hi1 = __umulhi(PHILOX_M1, c2);
+    const uint32_t lo1 = PHILOX_M1 * c2;
+    c0 = hi1 ^ c1 ^ k0;
+    c1 = lo1;
+    c2 = hi0 ^ c3 ^ k1;
+    c3 = lo0;
+  }
+  return make_uint4(c0, c1, c2, c3);
+}
+
+__device__ __forceinline__ uint32_t lane(const uint4 block, const int j) {
+  return j == 0 ? block.x : j == 1 ? block.y : j == 2 ? block.z : block.w;
+}
+
+// Explicit fma and rounding intrinsics are used so that the compiler cannot contract or
+// reorder the operations differently from the cpu implementation.
+__device__ __forceinline__ float uniform_f32(const uint32_t x) {
+  return __fmul_rn((float)(x >> 8), 1.0f / 16777216.0f);
+}
+
+__device__ __forceinline__ float uniform_f32_nonzero(const uint32_t x) {
+  return __fmul_rn((float)((x >> 8) + 1), 1.0f / 16777216.0f);
+}
+
+__device__ __forceinline__ double uniform_f64(const uint32_t x) {
+  return __dmul_rn((double)x, 1.0 / 4294967296.0);
+}
+
+__device__ __forceinline__ double uniform_f64_nonzero(const uint32_t x) {
+  return __dmul_rn((double)x + 1.0, 1.0 / 4294967296.0);
+}
+
+__device__ __forceinline__ float sample_uniform(const uint4 block, const int j, const float lo, const float up) {
+  return fmaf(uniform_f32(lane(block, j)), __fsub_rn(up, lo), lo);
+}
+
+__device__ __forceinline__ double sample_uniform(const uint4 block, const int j, const double lo, const double up) {
+  return fma(uniform_f64(lane(block, j)), __dsub_rn(up, lo), lo);
+}
+
+__device__ __forceinline__ float sample_normal(const uint4 block, const int j, const float mean, const float std) {
+  const int pair = j / 2 * 2;
+  const float radius = sqrtf(-2.0f * logf(uniform_f32_nonzero(lane(block, pair))));
+  const float theta = 2.0f * 3.14159265358979323846f * uniform_f32(lane(block, pair + 1));
+  const float z = j % 2 == 0 ? radius * cosf(theta) : radius * sinf(theta);
+  return fmaf(z, std, mean);
+}
+
+__device__ __forceinline__ double sample_normal(const uint4 block, const int j, const double mean, const double std) {
+  const int pair = j / 2 * 2;
+  const double radius = sqrt(-2.0 * log(uniform_f64_nonzero(lane(block, pair))));
+  const double theta = 2.0 * 3.14159265358979323846 * uniform_f64(lane(block, pair + 1));
+  const double z = j % 2 == 0 ? radius * cos(theta) : radius * sin(theta);
+  return fma(z, std, mean);
+}
+
+// Each thread generates a block of four consecutive values from a single counter.
+#define RANDOM_OP(TYPENAME, FN_NAME, SAMPLE) \
+extern "C" __global__ void FN_NAME( \
+    const size_t numel, \
+    const uint64_t seed, \
+    const uint64_t offset, \
+    const TYPENAME a, \
+    const TYPENAME b, \
+    TYPENAME *out \
+) { \
+  const size_t num_blocks = (numel + 3) / 4; \
+  for (size_t i = blockIdx.x * blockDim.x + threadIdx.x; i < num_blocks; i += blockDim.x * gridDim.x) { \
+    const uint4 block = philox4x32_10(seed, offset + i); \
+    for (int j = 0; j < 4 && 4 * i + j < numel; ++j) { \
+      out[4 * i + j] = SAMPLE(block, j, a, b); \
+    } \
+  } \
+} \
+
+RANDOM_OP(float, rand_uniform_f32, sample_uniform)
+RANDOM_OP(double, rand_uniform_f64, sample_uniform)
+RANDOM_OP(float, rand_normal_f32, sample_normal)
+RANDOM_OP(double, rand_normal_f64, sample_normal)
@@ -67,6 +67,25 @@ static __device__ __forceinline__ float warp_reduce_sum(float x) {
     return x;
 }
 
+// Sums the values of all the threads of a block of up to 1024 threads along x.
+static __device__ __forceinline__ float block_reduce_sum(float x, const int block_size) {
+    x = warp_reduce_sum(x);
+    if (block_size > WARP_SIZE) {
+        __shared__ float s_sum[32];
+        int warp_id = threadIdx.x / WARP_SIZE;
+        int lane_id = threadIdx.x % WARP_SIZE;
+        // s_sum may still be read by a previous reduction.
+        __syncthreads();
+        if (lane_id == 0) {
+            s_sum[warp_id] = x;
+        }
+        __syncthreads();
+        x = s_sum[lane_id];
+        x = warp_reduce_sum(x);
+    }
+    return x;
+}
+
 // LayerNorm implementation adapted from ggml, accumulation is made using f32.
 // https://github.com/ggerganov/llama.cpp/blob/d59bd97065cd7ded6c4ecab54b1d5e0b1b11e318/ggml-cuda.cu#L477
 template <typename T>
@@ -74,30 +93,22 @@ __device__ void layernorm(const T * x, T * dst, const T * alpha, const T * beta,
     const int row = blockIdx.x*blockDim.y + threadIdx.y;
     const int tid = threadIdx.x;
 
-    float2 mean_var = make_float2(0.f, 0.f);
-
+    // The variance is computed around the mean in a second pass, using E[x^2] - E[x]^2 loses
+    // all the precision when the mean is large compared to the standard deviation.
+    float sum = 0.f;
     for (int col = tid; col < ncols; col += block_size) {
-        const float xi = x[row*ncols + col];
-        mean_var.x += xi;
-        mean_var.y += xi * xi;
+        sum += static_cast<float>(x[row*ncols + col]);
     }
+    sum = block_reduce_sum(sum, block_size);
+    const float mean = sum / ncols;
 
-    // sum up partial sums
-    mean_var = warp_reduce_sum(mean_var);
-    if (block_size > WARP_SIZE) {
-        __shared__ float2 s_sum[32];
-        int warp_id = threadIdx.x / WARP_SIZE;
-        int lane_id = threadIdx.x % WARP_SIZE;
-        if (lane_id == 0) {
-            s_sum[warp_id] = mean_var;
-        }
-        __syncthreads();
-        mean_var = s_sum[lane_id];
-        mean_var = warp_reduce_sum(mean_var);
+    float sum2 = 0.f;
+    for (int col = tid; col < ncols; col += block_size) {
+        const float xi = static_cast<float>(x[row*ncols + col]) - mean;
+        sum2 += xi * xi;
     }
-
-    const float mean = mean_var.x / ncols;
-    const float var = mean_var.y / ncols - mean * mean;
+    sum2 = block_reduce_sum(sum2, block_size);
+    const float var = sum2 / ncols;
     const float inv_std = rsqrtf(var + eps);
 
     if (alpha == nullptr && beta == nullptr) {
@@ -174,47 +185,91 @@ __device__ void rmsnorm(const T * x, T * dst, const T * alpha, const int ncols,
     }
 }
 
+// Backward of rmsnorm and layernorm, dst has 2*ncols columns per row: the gradient of x
+// followed by grad * x_normed, whose sum over the rows is the gradient of alpha.
+// With ga = grad * alpha, dx = inv_std * (ga - mean(ga) - x_normed * mean(ga * x_normed)), the
+// mean of ga is not subtracted for rmsnorm.
+template <typename T, bool CENTER>
+__device__ void norm_bwd(const T * x, const T * alpha, const T * grad, T * dst, const int ncols, const int block_size, const float eps) {
+    const int row = blockIdx.x*blockDim.y + threadIdx.y;
+    const int tid = threadIdx.x;
+
+    float mean = 0.f;
+    if (CENTER) {
+        float sum = 0.f;
+        for (int col = tid; col < ncols; col += block_size) {
+            sum += static_cast<float>(x[row*ncols + col]);
+        }
+        mean = block_reduce_sum(sum, block_size) / ncols;
+    }
+
+    float sum2 = 0.f;
+    float sum_ga = 0.f;
+    float sum_gax = 0.f;
+    for (int col = tid; col < ncols; col += block_size) {
+        const float xi = static_cast<float>(x[row*ncols + col]) - mean;
+        const float ga = static_cast<float>(grad[row*ncols + col]) * static_cast<float>(alpha[col]);
+        sum2 += xi * xi;
+        sum_ga += ga;
+        sum_gax += ga * xi;
+    }
+    sum2 = block_reduce_sum(sum2, block_size);
+    sum_gax = block_reduce_sum(sum_gax, block_size);
+    const float inv_std = rsqrtf(sum2 / ncols + eps);
+    const float mean_ga = CENTER ? block_reduce_sum(sum_ga, block_size) / ncols : 0.f;
+    const float mean_gax = sum_gax * inv_std / ncols;
+
+    for (int col = tid; col < ncols; col += block_size) {
+        const float x_normed = (static_cast<float>(x[row*ncols + col]) - mean) * inv_std;
+        const float g = static_cast<float>(grad[row*ncols + col]);
+        const float ga = g * static_cast<float>(alpha[col]);
+        dst[row*2*ncols + col] = static_cast<T>(inv_std * (ga - mean_ga - x_normed * mean_gax));
+        dst[row*2*ncols + ncols + col] = static_cast<T>(g * x_normed);
+    }
+}
+
 // Softmax implementation adapted from ggml.
 // https://github.com/ggerganov/llama.cpp/blob/d59bd97065cd7ded6c4ecab54b1d5e0b1b11e318/ggml-cuda.cu#L4159
+// The max and the sum of exponentials are computed in a single pass using the ACC type, the sum
+// is rescaled whenever the max changes so that half precision inputs with large logits cannot
+// overflow.
+template <typename ACC>
+__device__ __forceinline__ void online_softmax_merge(ACC &max_val, ACC &sum, const ACC other_max, const ACC other_sum) {
+    const ACC new_max = maxg(max_val, other_max);
+    if (new_max == -INFINITY) {
+        return;
+    }
+    sum = sum * expg(max_val - new_max) + other_sum * expg(other_max - new_max);
+    max_val = new_max;
+}
+
 template <typename T, typename ACC>
 __device__ void softmax(const T * x, T * dst, const int ncols) {
     const int row = blockDim.x*blockIdx.x + threadIdx.x;
     const int block_size = blockDim.y;
     const int tid = threadIdx.y;
 
-    T max_val = -INFINITY;
+    ACC max_val = -INFINITY;
+    ACC sum = 0.;
 
     for (int col = tid; col < ncols; col += block_size) {
         const int i = row*ncols + col;
-        max_val = maxg(max_val, x[i]);
+        online_softmax_merge<ACC>(max_val, sum, static_cast<ACC>(x[i]), 1.);
     }
 
-    // find the max value in the block
+    // merge the partial max and sums of the block
 #pragma unroll
     for (int mask = 16; mask > 0; mask >>= 1) {
-        max_val = maxg(max_val, __shfl_xor_sync(0xffffffff, max_val, mask, 32));
+        const ACC other_max = __shfl_xor_sync(0xffffffff, max_val, mask, 32);
+        const ACC other_sum = __shfl_xor_sync(0xffffffff, sum, mask, 32);
+        online_softmax_merge<ACC>(max_val, sum, other_max, other_sum);
     }
 
-    ACC tmp = 0.;
+    const ACC inv_sum = 1. / sum;
 
     for (int col = tid; col < ncols; col += block_size) {
         const int i = row*ncols + col;
-        const T val = expg(x[i] - max_val);
-        tmp += static_cast<ACC>(val);
-        dst[i] = val;
-    }
-
-    // sum up partial sums
-#pragma unroll
-    for (int mask = 16; mask > 0; mask >>= 1) {
-        tmp += __shfl_xor_sync(0xffffffff, tmp, mask, 32);
-    }
-
-    const ACC inv_tmp = 1. / tmp;
-
-    for (int col = tid; col < ncols; col += block_size) {
-        const int i = row*ncols + col;
-        dst[i] *= inv_tmp;
+        dst[i] = static_cast<T>(expg(static_cast<ACC>(x[i]) - max_val) * inv_sum);
     }
 }
 
@@ -539,6 +594,18 @@ fast_argmax(const size_t src_numel, const size_t el_to_sum_per_block,
     layernorm<TYPENAME>(src, dst, alpha, beta, n_cols, block_size, eps);       \
   }                                                                            \
 
+#define NORM_BWD_OP(TYPENAME, RMS_NAME, LN_NAME) \
+  extern "C" __global__ void RMS_NAME(                                         \
+      const TYPENAME *src, const TYPENAME *alpha, const TYPENAME *grad,        \
+      TYPENAME *dst, const int n_cols, const int block_size, const float eps) { \
+    norm_bwd<TYPENAME, false>(src, alpha, grad, dst, n_cols, block_size, eps); \
+  }                                                                            \
+  extern "C" __global__ void LN_NAME(                                          \
+      const TYPENAME *src, const TYPENAME *alpha, const TYPENAME *grad,        \
+      TYPENAME *dst, const int n_cols, const int block_size, const float eps) { \
+    norm_bwd<TYPENAME, true>(src, alpha, grad, dst, n_cols, block_size, eps);  \
+  }                                                                            \
+
 #define ROPE_OP(TYPENAME, FN_NAME, FN_NAME_I, FN_NAME_THD) \
   extern "C" __global__ void FN_NAME_I( \
       const TYPENAME *src, \
@@ -575,6 +642,7 @@ fast_argmax(const size_t src_numel, const size_t el_to_sum_per_block,
 SOFTMAX_OP(__nv_bfloat16, float, softmax_bf16)
 RMSNORM_OP(__nv_bfloat16, rmsnorm_bf16)
 LAYERNORM_OP(__nv_bfloat16, layernorm_bf16)
+NORM_BWD_OP(__nv_bfloat16, rmsnorm_bwd_bf16, layernorm_bwd_bf16)
 ROPE_OP(__nv_bfloat16, rope_bf16, rope_i_bf16, rope_thd_bf16)
 SUM_OP(__nv_bfloat16, sum_bf16)
 FAST_OP(__nv_bfloat16, fast_min_bf16, fast_max_bf16, fast_argmin_bf16, fast_argmax_bf16, fast_sum_bf16)
@@ -584,6 +652,7 @@ FAST_OP(__nv_bfloat16, fast_min_bf16, fast_max_bf16, fast_argmin_bf16, fast_argm
 SOFTMAX_OP(__half, float, softmax_f16)
 RMSNORM_OP(__half, rmsnorm_f16)
 LAYERNORM_OP(__half, layernorm_f16)
+NORM_BWD_OP(__half, rmsnorm_bwd_f16, layernorm_bwd_f16)
 ROPE_OP(__half, rope_f16, rope_i_f16, rope_thd_f16)
 SUM_OP(__half, sum_f16)
 FAST_OP(__half, fast_min_f16, fast_max_f16, fast_argmin_f16, fast_argmax_f16, fast_sum_f16)
@@ -598,6 +667,8 @@ RMSNORM_OP(float, rmsnorm_f32)
 RMSNORM_OP(double, rmsnorm_f64)
 LAYERNORM_OP(float, layernorm_f32)
 LAYERNORM_OP(double, layernorm_f64)
+NORM_BWD_OP(float, rmsnorm_bwd_f32, layernorm_bwd_f32)
+NORM_BWD_OP(double, rmsnorm_bwd_f64, layernorm_bwd_f64)
 ROPE_OP(float, rope_f32, rope_i_f32, rope_thd_f32)
 ROPE_OP(double, rope_f64, rope_i_f64, rope_thd_f64)
 
@@ -0,0 +1,102 @@
+// Kernels for W8A8 linear layers. The weights are int8 with one scale per output feature and
+// shape (out_dim, in_dim), the activations are quantized to int8 with one scale per row before
+// the int8 matmul.
+#include "cuda_utils.cuh"
+#include<stdint.h>
+
+#define W8A8_WARPS 4
+
+// Quantizes each row of xs, one block of 32 * W8A8_WARPS threads per row.
+template <typename T>
+__device__ void w8a8_quantize_rows(
+    const T *xs,
+    int8_t *xq,
+    float *x_scales,
+    const size_t in_dim
+) {
+  __shared__ float shared[W8A8_WARPS];
+  const size_t row = blockIdx.x;
+  const T *x = xs + row * in_dim;
+  float amax = 0.;
+  for (size_t i = threadIdx.x; i < in_dim; i += blockDim.x) {
+    amax = fmaxf(amax, fabsf(float(x[i])));
+  }
+  for (int offset = 16; offset > 0; offset /= 2) {
+    amax = fmaxf(amax, __shfl_xor_sync(0xffffffff, amax, offset));
+  }
+  if (threadIdx.x % 32 == 0) {
+    shared[threadIdx.x / 32] = amax;
+  }
+  __syncthreads();
+  amax = shared[0];
+  for (int w = 1; w < W8A8_WARPS; ++w) {
+    amax = fmaxf(amax, shared[w]);
+  }
+  const float scale = amax / 127.f;
+  const float inv_scale = scale == 0.f ? 0.f : 1.f / scale;
+  for (size_t i = threadIdx.x; i < in_dim; i += blockDim.x) {
+    const int q = __float2int_rn(float(x[i]) * inv_scale);
+    xq[row * in_dim + i] = (int8_t)max(-127, min(127, q));
+  }
+  if (threadIdx.x == 0) {
+    x_scales[row] = scale;
+  }
+}
+
+// Computes dequantize(xq) @ dequantize(w)^T using dp4a, each warp computes one output feature of
+// a row. in_dim has to be a multiple of 4.
+template <typename T>
+__device__ void w8a8_matmul(
+    const int8_t *xq,
+    const float *x_scales,
+    const int8_t *w,
+    const float *w_scales,
+    T *dst,
+    const size_t in_dim,
+    const size_t out_dim
+) {
+  const size_t o = blockIdx.x * W8A8_WARPS + threadIdx.y;
+  const size_t row = blockIdx.y;
+  if (o >= out_dim) {
+    return;
+  }
+  const int *x4 = (const int *)(xq + row * in_dim);
+  const int *w4 = (const int *)(w + o * in_dim);
+  int acc = 0;
+  for (size_t i = threadIdx.x; i < in_dim / 4; i += 32) {
+#if __CUDA_ARCH__ >= 610
+    acc = __dp4a(x4[i], w4[i], acc);
+#else
+    const int8_t *a = (const int8_t *)&x4[i];
+    const int8_t *b = (const int8_t *)&w4[i];
+    acc += a[0] * b[0] + a[1] * b[1] + a[2] * b[2] + a[3] * b[3];
+#endif
+  }
+  for (int offset = 16; offset > 0; offset /= 2) {
+    acc += __shfl_xor_sync(0xffffffff, acc, offset);
+  }
+  if (threadIdx.x == 0) {
+    dst[row * out_dim + o] = T((float)acc * x_scales[row] * w_scales[o]);
+  }
+}
+
+#define W8A8_OPS(TYPENAME, RUST_NAME) \
+  extern "C" __global__ void w8a8_quantize_rows_##RUST_NAME( \
+      const TYPENAME *xs, int8_t *xq, float *x_scales, const size_t in_dim) { \
+    w8a8_quantize_rows<TYPENAME>(xs, xq, x_scales, in_dim); \
+  } \
+  extern "C" __global__ void w8a8_matmul_##RUST_NAME( \
+      const int8_t *xq, const float *x_scales, const int8_t *w, const float *w_scales, \
+      TYPENAME *dst, const size_t in_dim, const size_t out_dim) { \
+    w8a8_matmul<TYPENAME>(xq, x_scales, w, w_scales, dst, in_dim, out_dim); \
+  } \
+
+#if __CUDA_ARCH__ >= 800
+W8A8_OPS(__nv_bfloat16, bf16)
+#endif
+
+#if __CUDA_ARCH__ >= 530
+W8A8_OPS(__half, f16)
+#endif
+
+W8A8_OPS(float, f32)
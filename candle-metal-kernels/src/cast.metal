@@ -72,6 +72,39 @@ kernel void FN_NAME_STRIDED( \
     output[tid] = static_cast<RIGHT_TYPENAME>(static_cast<IR_TYPENAME>(input[get_strided_index(tid, num_dims, dims, strides)])); \
 } \
 
+// The range of bf16 is the same as f32, the finite values too large for f16 saturate to the
+// largest f16 rather than becoming infinities.
+METAL_FUNC half saturate_f16(float x) {
+    return static_cast<half>(isfinite(x) ? clamp(x, -65504.0f, 65504.0f) : x);
+}
+
+#define CAST_SATURATE_F16(FN_NAME, FN_NAME_STRIDED, LEFT_TYPENAME) \
+kernel void FN_NAME( \
+    constant size_t &dim, \
+    device const LEFT_TYPENAME *input,  \
+    device half *output, \
+    uint tid [[ thread_position_in_grid ]] \
+) { \
+    if (tid >= dim) { \
+        return; \
+    } \
+    output[tid] = saturate_f16(static_cast<float>(input[tid])); \
+} \
+kernel void FN_NAME_STRIDED( \
+    constant size_t &dim, \
+    constant size_t &num_dims, \
+    constant size_t *dims, \
+    constant size_t *strides, \
+    device const LEFT_TYPENAME *input,  \
+    device half *output, \
+    uint tid [[ thread_position_in_grid ]] \
+) { \
+    if (tid >= dim) { \
+        return; \
+    } \
+    output[tid] = saturate_f16(static_cast<float>(input[get_strided_index(tid, num_dims, dims, strides)])); \
+} \
+
 // u32
 CAST(cast_u32_f32, cast_u32_f32_strided, uint32_t, float)
 CAST(cast_u32_u8, cast_u32_u8_strided, uint32_t, uint8_t)
@@ -127,5 +160,5 @@ CAST(cast_bf16_u32, cast_bf16_u32_strided, bfloat, uint32_t)
 CAST(cast_bf16_i64, cast_bf16_i64_strided, bfloat, int64_t)
 CAST(cast_bf16_f32, cast_bf16_f32_strided, bfloat, float)
 CAST_THROUGH(cast_bf16_u8, cast_bf16_u8_strided, bfloat, uint8_t, float)
-CAST_THROUGH(cast_bf16_f16, cast_bf16_f16_strided, bfloat, half, float)
+CAST_SATURATE_F16(cast_bf16_f16, cast_bf16_f16_strided, bfloat)
 #endif
\ No newline at end of file
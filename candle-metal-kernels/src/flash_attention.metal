@@ -0,0 +1,233 @@
+// Flash attention forward pass.
+//
+// Each threadgroup computes the output for `BQ` query positions of one head, the keys and values
+// are processed by blocks of `BK` positions with an online softmax so that the attention matrix
+// is never materialized. The 4 simdgroups of a threadgroup each own 8 query rows and use 8x8
+// simdgroup matrices for both q k^T and p v, the accumulation is done in f32.
+//
+// q has shape (batch, q_len, n_heads, D), k and v have shape (batch, kv_len, n_kv_heads, D), the
+// last dimension has to be contiguous. The output is contiguous with the shape of q.
+#include <metal_stdlib>
+#include <metal_simdgroup>
+
+using namespace metal;
+
+struct FlashAttnParams {
+  int q_len;
+  int kv_len;
+  int n_heads;
+  int n_kv_heads;
+  // The strides of the batch, sequence and head dimensions, in elements.
+  long q_strides[3];
+  long k_strides[3];
+  long v_strides[3];
+  float scale;
+  // With causal masking, query i attends to the keys j <= i + kv_len - q_len.
+  int causal;
+};
+
+constant constexpr int BQ = 32;
+constant constexpr int N_SIMDGROUPS = 4;
+
+typedef simdgroup_matrix<float, 8, 8> frag_t;
+
+// Loads `BK` rows of a (seq, D) matrix to threadgroup memory, the rows past `len` are zeroed.
+template <typename T, int D, int BK>
+METAL_FUNC void load_rows(
+    threadgroup float *dst,
+    const device T *src,
+    long row_stride,
+    int start,
+    int len,
+    uint tid) {
+  for (int i = tid; i < BK * D; i += N_SIMDGROUPS * 32) {
+    const int r = i / D;
+    const int c = i % D;
+    const int row = start + r;
+    dst[i] = row < len ? float(src[row * row_stride + c]) : 0.0f;
+  }
+}
+
+// Multiplies the rows of the 8x8 fragments `o` by the per-row factor held by the 4 lanes of each
+// row, through a diagonal matrix in threadgroup memory.
+template <int D>
+METAL_FUNC void scale_rows(
+    thread frag_t *o,
+    threadgroup float *diag,
+    float factor,
+    uint lane) {
+  const int row = lane / 4;
+  if (lane % 4 == 0) {
+    for (int c = 0; c < 8; c++) {
+      diag[row * 8 + c] = c == row ? factor : 0.0f;
+    }
+  }
+  simdgroup_barrier(mem_flags::mem_threadgroup);
+  frag_t d;
+  simdgroup_load(d, diag, 8);
+  for (int i = 0; i < D / 8; i++) {
+    simdgroup_multiply(o[i], d, o[i]);
+  }
+  simdgroup_barrier(mem_flags::mem_threadgroup);
+}
+
+template <typename T, int D, int BK>
+[[kernel]] void flash_attn(
+    const device T *q [[buffer(0)]],
+    const device T *k [[buffer(1)]],
+    const device T *v [[buffer(2)]],
+    device T *o [[buffer(3)]],
+    constant FlashAttnParams &p [[buffer(4)]],
+    uint3 tg_id [[threadgroup_position_in_grid]],
+    uint tid [[thread_index_in_threadgroup]],
+    uint sg_id [[simdgroup_index_in_threadgroup]],
+    uint lane [[thread_index_in_simdgroup]]) {
+  static_assert(D % 8 == 0 && BK % 8 == 0 && BQ % BK == 0, "invalid tile sizes");
+  threadgroup float kv_tg[BK * D];
+  threadgroup float s_tg[N_SIMDGROUPS * 8 * BK];
+  threadgroup float diag_tg[N_SIMDGROUPS * 64];
+
+  const int q_block = tg_id.x * BQ;
+  const int head = tg_id.y;
+  const int batch = tg_id.z;
+  const int kv_head = head / (p.n_heads / p.n_kv_heads);
+  q += batch * p.q_strides[0] + head * p.q_strides[2];
+  k += batch * p.k_strides[0] + kv_head * p.k_strides[2];
+  v += batch * p.v_strides[0] + kv_head * p.v_strides[2];
+  const int offset = p.kv_len - p.q_len;
+
+  threadgroup float *s = s_tg + sg_id * 8 * BK;
+  threadgroup float *diag = diag_tg + sg_id * 64;
+  const int sg_row = sg_id * 8;
+
+  // The query rows of the simdgroup, loaded through the key buffer by blocks of BK rows.
+  frag_t q_frag[D / 8];
+  for (int r0 = 0; r0 < BQ; r0 += BK) {
+    load_rows<T, D, BK>(kv_tg, q, p.q_strides[1], q_block + r0, p.q_len, tid);
+    threadgroup_barrier(mem_flags::mem_threadgroup);
+    if (sg_row >= r0 && sg_row < r0 + BK) {
+      for (int d = 0; d < D / 8; d++) {
+        simdgroup_load(q_frag[d], kv_tg + (sg_row - r0) * D + d * 8, D);
+      }
+    }
+    threadgroup_barrier(mem_flags::mem_threadgroup);
+  }
+
+  frag_t o_frag[D / 8];
+  for (int d = 0; d < D / 8; d++) {
+    o_frag[d] = make_filled_simdgroup_matrix<float, 8, 8>(0.0f);
+  }
+
+  // The 4 lanes of a row each handle BK / 4 of the columns of the scores.
+  const int row = lane / 4;
+  const int col0 = (lane % 4) * (BK / 4);
+  const int q_row = q_block + sg_row + row;
+  float m = -INFINITY;
+  float l = 0.0f;
+
+  int kv_end = p.kv_len;
+  if (p.causal) {
+    kv_end = min(kv_end, q_block + BQ + offset);
+  }
+  for (int kb = 0; kb < kv_end; kb += BK) {
+    load_rows<T, D, BK>(kv_tg, k, p.k_strides[1], kb, p.kv_len, tid);
+    threadgroup_barrier(mem_flags::mem_threadgroup);
+    for (int cb = 0; cb < BK / 8; cb++) {
+      frag_t acc = make_filled_simdgroup_matrix<float, 8, 8>(0.0f);
+      for (int d = 0; d < D / 8; d++) {
+        frag_t kt;
+        simdgroup_load(kt, kv_tg + cb * 8 * D + d * 8, D, ulong2(0, 0), true);
+        simdgroup_multiply_accumulate(acc, q_frag[d], kt, acc);
+      }
+      simdgroup_store(acc, s + cb * 8, BK);
+    }
+    threadgroup_barrier(mem_flags::mem_threadgroup);
+
+    float scores[BK / 4];
+    float m_new = m;
+    for (int t = 0; t < BK / 4; t++) {
+      const int j = kb + col0 + t;
+      const bool masked = j >= p.kv_len || (p.causal && j > q_row + offset);
+      scores[t] = masked ? -INFINITY : s[row * BK + col0 + t] * p.scale;
+      m_new = max(m_new, scores[t]);
+    }
+    m_new = max(m_new, simd_shuffle_xor(m_new, 1));
+    m_new = max(m_new, simd_shuffle_xor(m_new, 2));
+    // All the keys seen so far are masked for this row when m_new is -inf.
+    const bool empty = m_new == -INFINITY;
+    const float alpha = empty ? 1.0f : exp(m - m_new);
+    float row_sum = 0.0f;
+    for (int t = 0; t < BK / 4; t++) {
+      const float pr = empty ? 0.0f : exp(scores[t] - m_new);
+      s[row * BK + col0 + t] = pr;
+      row_sum += pr;
+    }
+    row_sum += simd_shuffle_xor(row_sum, 1);
+    row_sum += simd_shuffle_xor(row_sum, 2);
+    l = l * alpha + row_sum;
+    m = m_new;
+    scale_rows<D>(o_frag, diag, alpha, lane);
+
+    load_rows<T, D, BK>(kv_tg, v, p.v_strides[1], kb, p.kv_len, tid);
+    threadgroup_barrier(mem_flags::mem_threadgroup);
+    for (int d = 0; d < D / 8; d++) {
+      for (int cb = 0; cb < BK / 8; cb++) {
+        frag_t pr;
+        frag_t vm;
+        simdgroup_load(pr, s + cb * 8, BK);
+        simdgroup_load(vm, kv_tg + cb * 8 * D + d * 8, D);
+        simdgroup_multiply_accumulate(o_frag[d], pr, vm, o_frag[d]);
+      }
+    }
+    threadgroup_barrier(mem_flags::mem_threadgroup);
+  }
+
+  scale_rows<D>(o_frag, diag, l > 0.0f ? 1.0f / l : 0.0f, lane);
+
+  // The output rows are written by blocks of BK columns through the scores buffer.
+  device T *out = o + ((long)batch * p.q_len * p.n_heads + head) * D;
+  for (int c0 = 0; c0 < D; c0 += BK) {
+    const int n_cols = min(BK, D - c0);
+    for (int d = 0; d < n_cols / 8; d++) {
+      simdgroup_store(o_frag[c0 / 8 + d], s + d * 8, BK);
+    }
+    simdgroup_barrier(mem_flags::mem_threadgroup);
+    for (int i = lane; i < 8 * n_cols; i += 32) {
+      const int r = i / n_cols;
+      const int c = i % n_cols;
+      const int out_row = q_block + sg_row + r;
+      if (out_row < p.q_len) {
+        out[(long)out_row * p.n_heads * D + c0 + c] = T(s[r * BK + c]);
+      }
+    }
+    simdgroup_barrier(mem_flags::mem_threadgroup);
+  }
+}
+
+#define instantiate_flash_attn(tname, type, head_dim, bk)                      \
+  template [[host_name("flash_attn_" #tname "_" #head_dim)]]                   \
+  [[kernel]] void flash_attn<type, head_dim, bk>(                              \
+      const device type *q [[buffer(0)]],                                      \
+      const device type *k [[buffer(1)]],                                      \
+      const device type *v [[buffer(2)]],                                      \
+      device type *o [[buffer(3)]],                                            \
+      constant FlashAttnParams &p [[buffer(4)]],                               \
+      uint3 tg_id [[threadgroup_position_in_grid]],                            \
+      uint tid [[thread_index_in_threadgroup]],                                \
+      uint sg_id [[simdgroup_index_in_threadgroup]],                           \
+      uint lane [[thread_index_in_simdgroup]]);
+
+// The key blocks are smaller for the large head dims to fit in 32KB of threadgroup memory.
+#define instantiate_flash_attn_heads(tname, type) \
+  instantiate_flash_attn(tname, type, 32, 32)     \
+  instantiate_flash_attn(tname, type, 64, 32)     \
+  instantiate_flash_attn(tname, type, 80, 32)     \
+  instantiate_flash_attn(tname, type, 96, 32)     \
+  instantiate_flash_attn(tname, type, 128, 32)    \
+  instantiate_flash_attn(tname, type, 256, 16)
+
+instantiate_flash_attn_heads(f32, float)
+instantiate_flash_attn_heads(f16, half)
+#if defined(__HAVE_BFLOAT__)
+instantiate_flash_attn_heads(bf16, bfloat)
+#endif
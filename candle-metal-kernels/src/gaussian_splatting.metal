@@ -0,0 +1,491 @@
+// Kernels for the rasterization of 2D gaussians, see candle-nn/src/gaussian_splatting.rs. Each
+// gaussian is stored as (mean_x, mean_y, conic_a, conic_b, conic_c, opacity, colors...) and the
+// gaussians are sorted by depth.
+//
+// The gaussians are first binned by tile as in `tile_lists`: each gaussian emits a key
+// (tile << 32) | index per tile that its bounding box overlaps, the keys are sorted by tile with
+// a stable radix sort so that the gaussians of a tile stay sorted by depth, and the range of keys
+// of each tile is extracted. The rasterization then uses one threadgroup of GS_TILE x GS_TILE
+// threads per tile of the image and one thread per pixel, the gaussians of the tile are loaded
+// in threadgroup memory by batches of one per thread.
+#include <metal_stdlib>
+using namespace metal;
+
+#define GS_TILE 16
+#define GS_THREADS (GS_TILE * GS_TILE)
+#define GS_SCAN_THREADS 256
+#define GS_SORT_THREADS 256
+// The simdgroups of the apple gpus have 32 threads.
+#define GS_SORT_SIMDGROUPS (GS_SORT_THREADS / 32)
+#define GS_RADIX_BITS 8
+#define GS_RADIX (1 << GS_RADIX_BITS)
+#define GS_MAX_CHANNELS 16
+#define GS_PARAMS 6
+#define GS_MAX_ALPHA 0.99f
+#define GS_MIN_ALPHA (1.f / 255.f)
+#define GS_MIN_TRANSMITTANCE 1e-4f
+
+struct Contribution {
+    float alpha;
+    float g;
+    float dx;
+    float dy;
+    bool clamped;
+};
+
+// Has to match `contribution` in gaussian_splatting.rs.
+static bool gs_contribution(threadgroup const float *p, float px, float py, thread Contribution &out) {
+    const float a = p[2], b = p[3], c = p[4], opacity = p[5];
+    if (!(a > 0.f && a * c - b * b > 0.f)) {
+        return false;
+    }
+    out.dx = px - p[0];
+    out.dy = py - p[1];
+    const float power = -0.5f * (a * out.dx * out.dx + c * out.dy * out.dy) - b * out.dx * out.dy;
+    if (power > 0.f) {
+        return false;
+    }
+    out.g = exp(power);
+    const float alpha = opacity * out.g;
+    out.clamped = alpha > GS_MAX_ALPHA;
+    out.alpha = min(alpha, GS_MAX_ALPHA);
+    return out.alpha >= GS_MIN_ALPHA;
+}
+
+// The tiles (tx0..tx1, ty0..ty1) overlapped by the bounding box of a gaussian, this has to match
+// `bounding_box` in gaussian_splatting.rs.
+static bool gs_tile_rect(device const float *p, size_t width, size_t height, thread uint4 &rect) {
+    const float a = p[2], b = p[3], c = p[4], opacity = p[5];
+    const float det = a * c - b * b;
+    if (!(a > 0.f && det > 0.f) || opacity * 255.f <= 1.f) {
+        return false;
+    }
+    // The largest eigenvalue of the covariance, i.e. of the inverse of the conic.
+    const float mid = 0.5f * (a + c) / det;
+    const float lambda = mid + sqrt(max(mid * mid - 1.f / det, 0.f));
+    const float radius = sqrt(2.f * log(opacity * 255.f) * lambda) + 1.f;
+    const float x0 = max(floor(p[0] - radius), 0.f);
+    const float y0 = max(floor(p[1] - radius), 0.f);
+    const float x1 = min(ceil(p[0] + radius), (float)width);
+    const float y1 = min(ceil(p[1] + radius), (float)height);
+    if (!(x0 < x1 && y0 < y1)) {
+        return false;
+    }
+    rect = uint4(
+        (uint)x0 / GS_TILE,
+        ((uint)x1 + GS_TILE - 1) / GS_TILE,
+        (uint)y0 / GS_TILE,
+        ((uint)y1 + GS_TILE - 1) / GS_TILE
+    );
+    return true;
+}
+
+// The number of tiles overlapped by each gaussian.
+kernel void gaussians_count_tiles(
+    device const float *params,
+    device uint *counts,
+    constant size_t &n,
+    constant size_t &stride,
+    constant size_t &width,
+    constant size_t &height,
+    uint i [[thread_position_in_grid]]
+) {
+    if (i >= n) {
+        return;
+    }
+    uint4 rect;
+    if (gs_tile_rect(params + i * stride, width, height, rect)) {
+        counts[i] = (rect.y - rect.x) * (rect.w - rect.z);
+    } else {
+        counts[i] = 0;
+    }
+}
+
+// The exclusive prefix sum of the `n` values of `src` using a single threadgroup of
+// GS_SCAN_THREADS threads, each thread handles a contiguous chunk. `dst` gets `n + 1` values,
+// the last one being the total.
+kernel void gaussians_exclusive_scan(
+    device const uint *src,
+    device uint *dst,
+    constant size_t &n,
+    uint tid [[thread_position_in_threadgroup]]
+) {
+    threadgroup uint sums[GS_SCAN_THREADS];
+    const size_t chunk = (n + GS_SCAN_THREADS - 1) / GS_SCAN_THREADS;
+    const size_t start = min(tid * chunk, n);
+    const size_t end = min(start + chunk, n);
+    uint sum = 0;
+    for (size_t i = start; i < end; ++i) {
+        sum += src[i];
+    }
+    sums[tid] = sum;
+    threadgroup_barrier(mem_flags::mem_threadgroup);
+    for (uint offset = 1; offset < GS_SCAN_THREADS; offset *= 2) {
+        const uint v = tid >= offset ? sums[tid - offset] : 0;
+        threadgroup_barrier(mem_flags::mem_threadgroup);
+        sums[tid] += v;
+        threadgroup_barrier(mem_flags::mem_threadgroup);
+    }
+    uint acc = sums[tid] - sum;
+    for (size_t i = start; i < end; ++i) {
+        const uint v = src[i];
+        dst[i] = acc;
+        acc += v;
+    }
+    if (tid == GS_SCAN_THREADS - 1) {
+        dst[n] = sums[tid];
+    }
+}
+
+// Writes the keys of the tiles overlapped by each gaussian starting at `offsets`, the exclusive
+// prefix sum of the counts, the gaussians with a lower index come first.
+kernel void gaussians_emit_keys(
+    device const float *params,
+    device const uint *offsets,
+    device ulong *keys,
+    constant size_t &n,
+    constant size_t &stride,
+    constant size_t &width,
+    constant size_t &height,
+    uint i [[thread_position_in_grid]]
+) {
+    if (i >= n) {
+        return;
+    }
+    uint4 rect;
+    if (!gs_tile_rect(params + i * stride, width, height, rect)) {
+        return;
+    }
+    const ulong tiles_x = (width + GS_TILE - 1) / GS_TILE;
+    uint o = offsets[i];
+    for (uint ty = rect.z; ty < rect.w; ++ty) {
+        for (uint tx = rect.x; tx < rect.y; ++tx) {
+            keys[o++] = ((ty * tiles_x + tx) << 32) | (ulong)i;
+        }
+    }
+}
+
+// Returns the rank of the element of the thread among the elements of the threadgroup with the
+// same digit and a lower index, and sets `counts` to the number of elements of each digit in the
+// threadgroup. `simd_counts` has GS_SORT_SIMDGROUPS x GS_RADIX values. There is one element per
+// thread, all the threads of the threadgroup have to call this.
+static uint gs_digit_rank(
+    uint digit,
+    bool valid,
+    threadgroup uint *simd_counts,
+    threadgroup uint *counts,
+    uint tid,
+    uint lane,
+    uint simd
+) {
+    for (uint i = tid; i < GS_SORT_SIMDGROUPS * GS_RADIX; i += GS_SORT_THREADS) {
+        simd_counts[i] = 0;
+    }
+    threadgroup_barrier(mem_flags::mem_threadgroup);
+    // The lanes of the simdgroup with the same digit, one vote per bit of the digit.
+    ulong peers = static_cast<ulong>(simd_ballot(valid));
+    for (uint b = 0; b < GS_RADIX_BITS; ++b) {
+        const bool bit = (digit >> b) & 1;
+        const ulong votes = static_cast<ulong>(simd_ballot(bit));
+        peers &= bit ? votes : ~votes;
+    }
+    const uint rank = popcount(peers & ((1ul << lane) - 1));
+    if (valid && rank == 0) {
+        simd_counts[simd * GS_RADIX + digit] = popcount(peers);
+    }
+    threadgroup_barrier(mem_flags::mem_threadgroup);
+    // The offset of each simdgroup among the elements of a digit.
+    for (uint d = tid; d < GS_RADIX; d += GS_SORT_THREADS) {
+        uint sum = 0;
+        for (uint s = 0; s < GS_SORT_SIMDGROUPS; ++s) {
+            const uint count = simd_counts[s * GS_RADIX + d];
+            simd_counts[s * GS_RADIX + d] = sum;
+            sum += count;
+        }
+        counts[d] = sum;
+    }
+    threadgroup_barrier(mem_flags::mem_threadgroup);
+    return valid ? simd_counts[simd * GS_RADIX + digit] + rank : 0;
+}
+
+// The number of keys of each digit in each threadgroup for the radix sort pass that uses the
+// bits `shift..shift + GS_RADIX_BITS`, stored digit major so that the exclusive prefix sum gives
+// the position of the first key of each (digit, threadgroup).
+kernel void gaussians_radix_histogram(
+    device const ulong *keys,
+    device uint *hist,
+    constant size_t &m,
+    constant uint &shift,
+    uint tg_id [[threadgroup_position_in_grid]],
+    uint num_tgs [[threadgroups_per_grid]],
+    uint tid [[thread_position_in_threadgroup]],
+    uint lane [[thread_index_in_simdgroup]],
+    uint simd [[simdgroup_index_in_threadgroup]]
+) {
+    threadgroup uint simd_counts[GS_SORT_SIMDGROUPS * GS_RADIX];
+    threadgroup uint counts[GS_RADIX];
+    const size_t i = (size_t)tg_id * GS_SORT_THREADS + tid;
+    const bool valid = i < m;
+    const uint digit = valid ? (uint)(keys[i] >> shift) & (GS_RADIX - 1) : 0;
+    gs_digit_rank(digit, valid, simd_counts, counts, tid, lane, simd);
+    for (uint d = tid; d < GS_RADIX; d += GS_SORT_THREADS) {
+        hist[d * num_tgs + tg_id] = counts[d];
+    }
+}
+
+// Moves the keys to their position for the radix sort pass, `offsets` is the exclusive prefix
+// sum of the histogram. The order of the keys with the same digit is preserved.
+kernel void gaussians_radix_scatter(
+    device const ulong *keys,
+    device const uint *offsets,
+    device ulong *dst,
+    constant size_t &m,
+    constant uint &shift,
+    uint tg_id [[threadgroup_position_in_grid]],
+    uint num_tgs [[threadgroups_per_grid]],
+    uint tid [[thread_position_in_threadgroup]],
+    uint lane [[thread_index_in_simdgroup]],
+    uint simd [[simdgroup_index_in_threadgroup]]
+) {
+    threadgroup uint simd_counts[GS_SORT_SIMDGROUPS * GS_RADIX];
+    threadgroup uint counts[GS_RADIX];
+    const size_t i = (size_t)tg_id * GS_SORT_THREADS + tid;
+    const bool valid = i < m;
+    const ulong key = valid ? keys[i] : 0;
+    const uint digit = (uint)(key >> shift) & (GS_RADIX - 1);
+    const uint rank = gs_digit_rank(digit, valid, simd_counts, counts, tid, lane, simd);
+    if (valid) {
+        dst[offsets[digit * num_tgs + tg_id] + rank] = key;
+    }
+}
+
+// The range of the sorted keys of each tile, `ranges` holds a (start, end) pair per tile and has
+// to be zeroed.
+kernel void gaussians_tile_ranges(
+    device const ulong *keys,
+    device uint *ranges,
+    constant size_t &m,
+    uint i [[thread_position_in_grid]]
+) {
+    if (i >= m) {
+        return;
+    }
+    const uint tile = (uint)(keys[i] >> 32);
+    if (i == 0 || (uint)(keys[i - 1] >> 32) != tile) {
+        ranges[2 * tile] = i;
+    }
+    if (i == m - 1 || (uint)(keys[i + 1] >> 32) != tile) {
+        ranges[2 * tile + 1] = i + 1;
+    }
+}
+
+// Loads the params of the gaussians of the keys `start..end` in threadgroup memory.
+static void gs_load(
+    threadgroup float *shared,
+    device const float *params,
+    device const ulong *keys,
+    size_t start,
+    size_t end,
+    size_t stride,
+    uint tid
+) {
+    const size_t j = start + tid;
+    if (j < end) {
+        const size_t i = (uint)keys[j];
+        for (size_t k = 0; k < stride; ++k) {
+            shared[tid * stride + k] = params[i * stride + k];
+        }
+    }
+}
+
+// Returns true when all the threads of the threadgroup are done, all the threads have to call
+// this.
+static bool gs_all_done(threadgroup atomic_uint *num_done, bool done, uint tid) {
+    if (tid == 0) {
+        atomic_store_explicit(num_done, 0, memory_order_relaxed);
+    }
+    threadgroup_barrier(mem_flags::mem_threadgroup);
+    if (done) {
+        atomic_fetch_add_explicit(num_done, 1, memory_order_relaxed);
+    }
+    threadgroup_barrier(mem_flags::mem_threadgroup);
+    return atomic_load_explicit(num_done, memory_order_relaxed) == GS_THREADS;
+}
+
+kernel void rasterize_gaussians_fwd(
+    device const float *params,
+    device const ulong *keys,
+    device const uint *ranges,
+    constant float *background,
+    device float *dst,
+    constant size_t &channels,
+    constant size_t &width,
+    constant size_t &height,
+    threadgroup float *shared [[threadgroup(0)]],
+    uint2 tg_id [[threadgroup_position_in_grid]],
+    uint2 num_tgs [[threadgroups_per_grid]],
+    uint2 local [[thread_position_in_threadgroup]]
+) {
+    threadgroup atomic_uint num_done;
+    const uint tid = local.y * GS_TILE + local.x;
+    const size_t stride = GS_PARAMS + channels;
+    const size_t x = tg_id.x * GS_TILE + local.x;
+    const size_t y = tg_id.y * GS_TILE + local.y;
+    const size_t tile = tg_id.y * num_tgs.x + tg_id.x;
+    const size_t range_start = ranges[2 * tile];
+    const size_t range_end = ranges[2 * tile + 1];
+    const bool inside = x < width && y < height;
+    bool done = !inside;
+    float color[GS_MAX_CHANNELS];
+    for (size_t ch = 0; ch < channels; ++ch) {
+        color[ch] = 0.f;
+    }
+    float t = 1.f;
+    for (size_t start = range_start; start < range_end; start += GS_THREADS) {
+        if (gs_all_done(&num_done, done, tid)) {
+            break;
+        }
+        gs_load(shared, params, keys, start, range_end, stride, tid);
+        threadgroup_barrier(mem_flags::mem_threadgroup);
+        const size_t count = min((size_t)GS_THREADS, range_end - start);
+        for (size_t j = 0; j < count && !done; ++j) {
+            threadgroup const float *p = shared + j * stride;
+            Contribution contrib;
+            if (!gs_contribution(p, (float)x, (float)y, contrib)) {
+                continue;
+            }
+            const float next_t = t * (1.f - contrib.alpha);
+            if (next_t < GS_MIN_TRANSMITTANCE) {
+                done = true;
+                break;
+            }
+            for (size_t ch = 0; ch < channels; ++ch) {
+                color[ch] += p[GS_PARAMS + ch] * contrib.alpha * t;
+            }
+            t = next_t;
+        }
+    }
+    if (inside) {
+        device float *out = dst + (y * width + x) * channels;
+        for (size_t ch = 0; ch < channels; ++ch) {
+            out[ch] = color[ch] + t * background[ch];
+        }
+    }
+}
+
+// The gradient of the params, `grads` has to be zeroed. The forward pass is run again to get
+// the final transmittance and the last contributing gaussian of each pixel, then the gaussians
+// are processed back to front.
+kernel void rasterize_gaussians_bwd(
+    device const float *params,
+    device const ulong *keys,
+    device const uint *ranges,
+    device const float *grad_out,
+    constant float *background,
+    device atomic_float *grads,
+    constant size_t &channels,
+    constant size_t &width,
+    constant size_t &height,
+    threadgroup float *shared [[threadgroup(0)]],
+    uint2 tg_id [[threadgroup_position_in_grid]],
+    uint2 num_tgs [[threadgroups_per_grid]],
+    uint2 local [[thread_position_in_threadgroup]]
+) {
+    threadgroup atomic_uint num_done;
+    threadgroup atomic_uint block_last;
+    const uint tid = local.y * GS_TILE + local.x;
+    const size_t stride = GS_PARAMS + channels;
+    const size_t x = tg_id.x * GS_TILE + local.x;
+    const size_t y = tg_id.y * GS_TILE + local.y;
+    const size_t tile = tg_id.y * num_tgs.x + tg_id.x;
+    const size_t range_start = ranges[2 * tile];
+    const size_t range_end = ranges[2 * tile + 1];
+    const bool inside = x < width && y < height;
+    if (tid == 0) {
+        atomic_store_explicit(&block_last, 0, memory_order_relaxed);
+    }
+    bool done = !inside;
+    float t = 1.f;
+    // One past the key of the last contributing gaussian.
+    size_t last = 0;
+    for (size_t start = range_start; start < range_end; start += GS_THREADS) {
+        if (gs_all_done(&num_done, done, tid)) {
+            break;
+        }
+        gs_load(shared, params, keys, start, range_end, stride, tid);
+        threadgroup_barrier(mem_flags::mem_threadgroup);
+        const size_t count = min((size_t)GS_THREADS, range_end - start);
+        for (size_t j = 0; j < count && !done; ++j) {
+            Contribution contrib;
+            if (!gs_contribution(shared + j * stride, (float)x, (float)y, contrib)) {
+                continue;
+            }
+            const float next_t = t * (1.f - contrib.alpha);
+            if (next_t < GS_MIN_TRANSMITTANCE) {
+                done = true;
+                break;
+            }
+            t = next_t;
+            last = start + j + 1;
+        }
+    }
+    threadgroup_barrier(mem_flags::mem_threadgroup);
+    atomic_fetch_max_explicit(&block_last, (uint)last, memory_order_relaxed);
+    threadgroup_barrier(mem_flags::mem_threadgroup);
+    const size_t block_end = atomic_load_explicit(&block_last, memory_order_relaxed);
+
+    const float final_t = t;
+    float grad_pixel[GS_MAX_CHANNELS];
+    float suffix[GS_MAX_CHANNELS];
+    float bg_dot = 0.f;
+    for (size_t ch = 0; ch < channels; ++ch) {
+        grad_pixel[ch] = inside ? grad_out[(y * width + x) * channels + ch] : 0.f;
+        suffix[ch] = 0.f;
+        bg_dot += grad_pixel[ch] * background[ch];
+    }
+    if (block_end == 0) {
+        return;
+    }
+    const long first = range_start;
+    for (long start = first + ((block_end - 1 - range_start) / GS_THREADS) * GS_THREADS; start >= first; start -= GS_THREADS) {
+        threadgroup_barrier(mem_flags::mem_threadgroup);
+        gs_load(shared, params, keys, (size_t)start, range_end, stride, tid);
+        threadgroup_barrier(mem_flags::mem_threadgroup);
+        const long count = min((long)GS_THREADS, (long)range_end - start);
+        for (long j = count - 1; j >= 0; --j) {
+            const size_t position = (size_t)(start + j);
+            if (!inside || position >= last) {
+                continue;
+            }
+            const size_t i = (uint)keys[position];
+            threadgroup const float *p = shared + j * stride;
+            Contribution contrib;
+            if (!gs_contribution(p, (float)x, (float)y, contrib)) {
+                continue;
+            }
+            const float alpha = contrib.alpha;
+            // The transmittance before this gaussian.
+            t /= 1.f - alpha;
+            device atomic_float *grad = grads + i * stride;
+            float d_alpha = -final_t * bg_dot / (1.f - alpha);
+            for (size_t ch = 0; ch < channels; ++ch) {
+                const float color = p[GS_PARAMS + ch];
+                atomic_fetch_add_explicit(grad + GS_PARAMS + ch, alpha * t * grad_pixel[ch], memory_order_relaxed);
+                d_alpha += grad_pixel[ch] * (color * t - suffix[ch] / (1.f - alpha));
+                suffix[ch] += color * alpha * t;
+            }
+            if (contrib.clamped) {
+                continue;
+            }
+            const float a = p[2], b = p[3], c = p[4], opacity = p[5];
+            const float dx = contrib.dx, dy = contrib.dy;
+            const float d_g = opacity * d_alpha * contrib.g;
+            atomic_fetch_add_explicit(grad + 0, d_g * (a * dx + b * dy), memory_order_relaxed);
+            atomic_fetch_add_explicit(grad + 1, d_g * (c * dy + b * dx), memory_order_relaxed);
+            atomic_fetch_add_explicit(grad + 2, -0.5f * d_g * dx * dx, memory_order_relaxed);
+            atomic_fetch_add_explicit(grad + 3, -d_g * dx * dy, memory_order_relaxed);
+            atomic_fetch_add_explicit(grad + 4, -0.5f * d_g * dy * dy, memory_order_relaxed);
+            atomic_fetch_add_explicit(grad + 5, contrib.g * d_alpha, memory_order_relaxed);
+        }
+    }
+}
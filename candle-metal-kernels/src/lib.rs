@@ -15,6 +15,8 @@ const BINARY: &str = include_str!("binary.metal");
 const CAST: &str = include_str!("cast.metal");
 const CONV: &str = include_str!("conv.metal");
 const FILL: &str = include_str!("fill.metal");
+const FLASH_ATTN: &str = include_str!("flash_attention.metal");
+const GAUSSIAN_SPLATTING: &str = include_str!("gaussian_splatting.metal");
 const INDEXING: &str = include_str!("indexing.metal");
 // Current source: https://github.com/ivarflakstad/metal-flash-attention/tree/candle
 const MFA: &[u8] = include_bytes!("libMetalFlashAttention.metallib");
@@ -34,6 +36,8 @@ pub enum Source {
     Cast,
     Conv,
     Fill,
+    FlashAttn,
+    GaussianSplatting,
     Gemm,
     Indexing,
     Mfa,
@@ -212,6 +216,8 @@ impl Kernels {
             Source::Cast => CAST,
             Source::Conv => CONV,
             Source::Fill => FILL,
+            Source::FlashAttn => FLASH_ATTN,
+            Source::GaussianSplatting => GAUSSIAN_SPLATTING,
             Source::Gemm => MLX_GEMM,
             Source::Indexing => INDEXING,
             Source::Quantized => QUANTIZED,
@@ -957,6 +963,53 @@ pub fn call_rope(
     Ok(())
 }
 
+/// Rotates the first `rd` values of each head, `kernel_name` selects the interleaved or
+/// contiguous variant.
+#[allow(clippy::too_many_arguments)]
+pub fn call_rope_partial(
+    device: &Device,
+    ep: impl EncoderProvider,
+    kernels: &Kernels,
+    kernel_name: &'static str,
+    bh: usize,
+    t: usize,
+    d: usize,
+    rd: usize,
+    src: &Buffer,
+    src_offset: usize,
+    cos: &Buffer,
+    cos_offset: usize,
+    sin: &Buffer,
+    sin_offset: usize,
+    output: &Buffer,
+) -> Result<(), MetalKernelError> {
+    let pipeline = kernels.load_pipeline(device, Source::Reduce, kernel_name)?;
+    let encoder = ep.encoder();
+    let encoder: &ComputeCommandEncoderRef = encoder.as_ref();
+    encoder.set_compute_pipeline_state(&pipeline);
+
+    set_params!(
+        encoder,
+        (
+            bh,
+            t,
+            d,
+            rd,
+            (src, src_offset),
+            (cos, cos_offset),
+            (sin, sin_offset),
+            output
+        )
+    );
+    let (thread_group_count, thread_group_size) = linear_split(&pipeline, bh * t * (d - rd / 2));
+    encoder.use_resource(src, metal::MTLResourceUsage::Read);
+    encoder.use_resource(cos, metal::MTLResourceUsage::Read);
+    encoder.use_resource(sin, metal::MTLResourceUsage::Read);
+    encoder.use_resource(output, metal::MTLResourceUsage::Write);
+    encoder.dispatch_thread_groups(thread_group_count, thread_group_size);
+    Ok(())
+}
+
 #[allow(clippy::too_many_arguments)]
 pub fn call_affine(
     device: &Device,
@@ -1948,6 +2001,127 @@ pub fn call_sdpa_vector(
     Ok(())
 }
 
+/// Flash attention with an online softmax, the attention matrix is never materialized.
+///
+/// - q = (bs, seq, n_heads, head_dim), k/v = (bs, kv_seq, n_kv_heads, head_dim)
+/// - the strides are the batch, sequence and head strides in elements, the head dim has to
+///   be contiguous
+/// - n_heads has to be a multiple of n_kv_heads (GQA)
+/// - with `causal`, query i attends to the keys j <= i + kv_seq - seq
+/// - head dim == 32, 64, 80, 96, 128, 256
+///
+/// The output is contiguous with the shape of q.
+#[allow(clippy::too_many_arguments)]
+pub fn call_flash_attn(
+    device: &Device,
+    ep: impl EncoderProvider,
+    kernels: &Kernels,
+    q_shape: &[usize],
+    q_strides: &[usize],
+    q: BufferOffset,
+    k_shape: &[usize],
+    k_strides: &[usize],
+    k: BufferOffset,
+    v_strides: &[usize],
+    v: BufferOffset,
+    output: &Buffer,
+    scale: f32,
+    causal: bool,
+    itype: SdpaDType,
+) -> Result<(), MetalKernelError> {
+    #[derive(Debug)]
+    #[repr(C)]
+    struct FlashAttnParams {
+        q_len: i32,
+        kv_len: i32,
+        n_heads: i32,
+        n_kv_heads: i32,
+        q_strides: [i64; 3],
+        k_strides: [i64; 3],
+        v_strides: [i64; 3],
+        scale: f32,
+        causal: i32,
+    }
+
+    impl EncoderParam for FlashAttnParams {
+        fn set_param(encoder: &ComputeCommandEncoderRef, position: u64, data: Self) {
+            encoder.set_bytes(
+                position,
+                core::mem::size_of::<FlashAttnParams>() as u64,
+                &data as *const FlashAttnParams as *const c_void,
+            );
+        }
+    }
+
+    // The number of query positions per threadgroup.
+    const BQ: usize = 32;
+
+    let (b, q_len, n_heads, head_dim) = (q_shape[0], q_shape[1], q_shape[2], q_shape[3]);
+    let (kv_len, n_kv_heads) = (k_shape[1], k_shape[2]);
+    let name = match (head_dim, itype) {
+        (32, SdpaDType::F32) => "flash_attn_f32_32",
+        (64, SdpaDType::F32) => "flash_attn_f32_64",
+        (80, SdpaDType::F32) => "flash_attn_f32_80",
+        (96, SdpaDType::F32) => "flash_attn_f32_96",
+        (128, SdpaDType::F32) => "flash_attn_f32_128",
+        (256, SdpaDType::F32) => "flash_attn_f32_256",
+        (32, SdpaDType::F16) => "flash_attn_f16_32",
+        (64, SdpaDType::F16) => "flash_attn_f16_64",
+        (80, SdpaDType::F16) => "flash_attn_f16_80",
+        (96, SdpaDType::F16) => "flash_attn_f16_96",
+        (128, SdpaDType::F16) => "flash_attn_f16_128",
+        (256, SdpaDType::F16) => "flash_attn_f16_256",
+        (32, SdpaDType::BF16) => "flash_attn_bf16_32",
+        (64, SdpaDType::BF16) => "flash_attn_bf16_64",
+        (80, SdpaDType::BF16) => "flash_attn_bf16_80",
+        (96, SdpaDType::BF16) => "flash_attn_bf16_96",
+        (128, SdpaDType::BF16) => "flash_attn_bf16_128",
+        (256, SdpaDType::BF16) => "flash_attn_bf16_256",
+        (other, _) => {
+            return Err(MetalKernelError::SdpaHeadSizeMismatch {
+                variation: "flash",
+                got: other,
+                expected: vec![32, 64, 80, 96, 128, 256],
+            })
+        }
+    };
+    let strides = |s: &[usize]| [s[0] as i64, s[1] as i64, s[2] as i64];
+    let params = FlashAttnParams {
+        q_len: q_len as i32,
+        kv_len: kv_len as i32,
+        n_heads: n_heads as i32,
+        n_kv_heads: n_kv_heads as i32,
+        q_strides: strides(q_strides),
+        k_strides: strides(k_strides),
+        v_strides: strides(v_strides),
+        scale,
+        causal: causal as i32,
+    };
+
+    let pipeline = kernels.load_pipeline(device, Source::FlashAttn, name)?;
+    let encoder = ep.encoder();
+    let encoder: &ComputeCommandEncoderRef = encoder.as_ref();
+    encoder.set_compute_pipeline_state(&pipeline);
+    set_params!(encoder, (&q, &k, &v, output, params));
+
+    let grid_dims = MTLSize {
+        width: q_len.div_ceil(BQ) as u64,
+        height: n_heads as u64,
+        depth: b as u64,
+    };
+    let group_dims = MTLSize {
+        width: 128,
+        height: 1,
+        depth: 1,
+    };
+    encoder.use_resource(q.buffer, metal::MTLResourceUsage::Read);
+    encoder.use_resource(k.buffer, metal::MTLResourceUsage::Read);
+    encoder.use_resource(v.buffer, metal::MTLResourceUsage::Read);
+    encoder.use_resource(output, metal::MTLResourceUsage::Write);
+    encoder.dispatch_thread_groups(grid_dims, group_dims);
+    Ok(())
+}
+
 #[allow(clippy::too_many_arguments)]
 pub fn call_im2col1d_strided(
     device: &Device,
@@ -2079,43 +2253,61 @@ pub fn call_upsample_nearest_2d(
     Ok(())
 }
 
+/// Generates random values with the philox kernels, `a` and `b` are the bounds of the uniform
+/// distribution or the mean and standard deviation of the normal distribution. The kernels
+/// use the counters starting at `offset`, with one counter per block of four values.
 #[allow(clippy::too_many_arguments)]
-pub fn call_random_uniform(
+fn call_random(
     device: &Device,
     ep: impl EncoderProvider,
     kernels: &Kernels,
     name: &'static str,
-    min: f32,
-    max: f32,
+    a: f32,
+    b: f32,
     length: usize,
-    seed: &Buffer,
+    seed: u64,
+    offset: u64,
     buffer: &Buffer,
 ) -> Result<(), MetalKernelError> {
-    if min >= max {
-        return Err(MetalKernelError::LoadLibraryError(
-            "min must be less than max".to_string(),
-        ));
-    }
     let pipeline = kernels.load_pipeline(device, Source::Random, name)?;
     let encoder = ep.encoder();
     let encoder: &ComputeCommandEncoderRef = encoder.as_ref();
 
-    let odd = (length % 2 != 0) as usize;
-    let (thread_group_count, thread_group_size) = linear_split(&pipeline, length / 2 + odd);
+    let (thread_group_count, thread_group_size) =
+        linear_split(&pipeline, length.div_ceil(4).max(1));
 
     encoder.set_compute_pipeline_state(&pipeline);
 
-    set_params!(encoder, (length, min, max, seed, buffer));
+    set_params!(encoder, (length, seed, offset, a, b, buffer));
 
-    encoder.use_resource(
-        seed,
-        metal::MTLResourceUsage::Read | metal::MTLResourceUsage::Write,
-    );
     encoder.use_resource(buffer, metal::MTLResourceUsage::Write);
     encoder.dispatch_thread_groups(thread_group_count, thread_group_size);
     Ok(())
 }
 
+#[allow(clippy::too_many_arguments)]
+pub fn call_random_uniform(
+    device: &Device,
+    ep: impl EncoderProvider,
+    kernels: &Kernels,
+    name: &'static str,
+    min: f32,
+    max: f32,
+    length: usize,
+    seed: u64,
+    offset: u64,
+    buffer: &Buffer,
+) -> Result<(), MetalKernelError> {
+    if min >= max {
+        return Err(MetalKernelError::LoadLibraryError(
+            "min must be less than max".to_string(),
+        ));
+    }
+    call_random(
+        device, ep, kernels, name, min, max, length, seed, offset, buffer,
+    )
+}
+
 #[allow(clippy::too_many_arguments)]
 pub fn call_random_normal(
     device: &Device,
@@ -2125,27 +2317,13 @@ pub fn call_random_normal(
     mean: f32,
     stddev: f32,
     length: usize,
-    seed: &Buffer,
+    seed: u64,
+    offset: u64,
     buffer: &Buffer,
 ) -> Result<(), MetalKernelError> {
-    let pipeline = kernels.load_pipeline(device, Source::Random, name)?;
-    let encoder = ep.encoder();
-    let encoder: &ComputeCommandEncoderRef = encoder.as_ref();
-
-    let odd = (length % 2 != 0) as usize;
-    let (thread_group_count, thread_group_size) = linear_split(&pipeline, length / 2 + odd);
-
-    encoder.set_compute_pipeline_state(&pipeline);
-
-    set_params!(encoder, (length, mean, stddev, seed, buffer));
-
-    encoder.use_resource(
-        seed,
-        metal::MTLResourceUsage::Read | metal::MTLResourceUsage::Write,
-    );
-    encoder.use_resource(buffer, metal::MTLResourceUsage::Write);
-    encoder.dispatch_thread_groups(thread_group_count, thread_group_size);
-    Ok(())
+    call_random(
+        device, ep, kernels, name, mean, stddev, length, seed, offset, buffer,
+    )
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -2505,6 +2683,287 @@ pub fn call_arg_sort(
     Ok(())
 }
 
+// The threadgroup size of the prefix sum and of the radix sort kernels, GS_SCAN_THREADS and
+// GS_SORT_THREADS in the kernels.
+const GAUSSIANS_SCAN_THREADS: u64 = 256;
+const GAUSSIANS_SORT_THREADS: usize = 256;
+
+/// Writes the number of tiles of 16x16 pixels overlapped by each of the `n` gaussians to `counts`.
+#[allow(clippy::too_many_arguments)]
+pub fn call_gaussians_count_tiles(
+    device: &Device,
+    ep: impl EncoderProvider,
+    kernels: &Kernels,
+    (n, stride, width, height): (usize, usize, usize, usize),
+    params: BufferOffset,
+    counts: &Buffer,
+) -> Result<(), MetalKernelError> {
+    let pipeline =
+        kernels.load_pipeline(device, Source::GaussianSplatting, "gaussians_count_tiles")?;
+    let encoder = ep.encoder();
+    let encoder: &ComputeCommandEncoderRef = encoder.as_ref();
+    encoder.set_compute_pipeline_state(&pipeline);
+    set_params!(encoder, (&params, counts, n, stride, width, height));
+    let (thread_group_count, thread_group_size) = linear_split(&pipeline, n);
+    encoder.use_resource(params.buffer, metal::MTLResourceUsage::Read);
+    encoder.use_resource(counts, metal::MTLResourceUsage::Write);
+    encoder.dispatch_thread_groups(thread_group_count, thread_group_size);
+    Ok(())
+}
+
+/// Writes the exclusive prefix sum of the `n` first u32 values of `src` to `dst`, which gets
+/// `n + 1` values with the total last.
+pub fn call_gaussians_exclusive_scan(
+    device: &Device,
+    ep: impl EncoderProvider,
+    kernels: &Kernels,
+    n: usize,
+    src: &Buffer,
+    dst: &Buffer,
+) -> Result<(), MetalKernelError> {
+    let pipeline = kernels.load_pipeline(
+        device,
+        Source::GaussianSplatting,
+        "gaussians_exclusive_scan",
+    )?;
+    let encoder = ep.encoder();
+    let encoder: &ComputeCommandEncoderRef = encoder.as_ref();
+    encoder.set_compute_pipeline_state(&pipeline);
+    set_params!(encoder, (src, dst, n));
+    let thread_group_count = MTLSize {
+        width: 1,
+        height: 1,
+        depth: 1,
+    };
+    let thread_group_size = MTLSize {
+        width: GAUSSIANS_SCAN_THREADS,
+        height: 1,
+        depth: 1,
+    };
+    encoder.use_resource(src, metal::MTLResourceUsage::Read);
+    encoder.use_resource(dst, metal::MTLResourceUsage::Write);
+    encoder.dispatch_thread_groups(thread_group_count, thread_group_size);
+    Ok(())
+}
+
+/// Writes the u64 keys `(tile << 32) | index` of the tiles overlapped by each of the `n`
+/// gaussians to `keys`, starting at the exclusive prefix sum of the tile counts `offsets`.
+#[allow(clippy::too_many_arguments)]
+pub fn call_gaussians_emit_keys(
+    device: &Device,
+    ep: impl EncoderProvider,
+    kernels: &Kernels,
+    (n, stride, width, height): (usize, usize, usize, usize),
+    params: BufferOffset,
+    offsets: &Buffer,
+    keys: &Buffer,
+) -> Result<(), MetalKernelError> {
+    let pipeline =
+        kernels.load_pipeline(device, Source::GaussianSplatting, "gaussians_emit_keys")?;
+    let encoder = ep.encoder();
+    let encoder: &ComputeCommandEncoderRef = encoder.as_ref();
+    encoder.set_compute_pipeline_state(&pipeline);
+    set_params!(encoder, (&params, offsets, keys, n, stride, width, height));
+    let (thread_group_count, thread_group_size) = linear_split(&pipeline, n);
+    encoder.use_resource(params.buffer, metal::MTLResourceUsage::Read);
+    encoder.use_resource(offsets, metal::MTLResourceUsage::Read);
+    encoder.use_resource(keys, metal::MTLResourceUsage::Write);
+    encoder.dispatch_thread_groups(thread_group_count, thread_group_size);
+    Ok(())
+}
+
+/// The number of threadgroups of a radix sort pass over `m` keys, the histogram has
+/// `256 * num_threadgroups` values.
+pub fn gaussians_radix_threadgroups(m: usize) -> usize {
+    m.div_ceil(GAUSSIANS_SORT_THREADS)
+}
+
+fn gaussians_radix_dims(m: usize) -> (MTLSize, MTLSize) {
+    let thread_group_count = MTLSize {
+        width: gaussians_radix_threadgroups(m) as u64,
+        height: 1,
+        depth: 1,
+    };
+    let thread_group_size = MTLSize {
+        width: GAUSSIANS_SORT_THREADS as u64,
+        height: 1,
+        depth: 1,
+    };
+    (thread_group_count, thread_group_size)
+}
+
+/// Writes the histogram of the 8 bits digits `shift..shift + 8` of the `m` keys for each
+/// threadgroup to `hist`, digit major.
+pub fn call_gaussians_radix_histogram(
+    device: &Device,
+    ep: impl EncoderProvider,
+    kernels: &Kernels,
+    (m, shift): (usize, u32),
+    keys: &Buffer,
+    hist: &Buffer,
+) -> Result<(), MetalKernelError> {
+    let pipeline = kernels.load_pipeline(
+        device,
+        Source::GaussianSplatting,
+        "gaussians_radix_histogram",
+    )?;
+    let encoder = ep.encoder();
+    let encoder: &ComputeCommandEncoderRef = encoder.as_ref();
+    encoder.set_compute_pipeline_state(&pipeline);
+    set_params!(encoder, (keys, hist, m, shift));
+    let (thread_group_count, thread_group_size) = gaussians_radix_dims(m);
+    encoder.use_resource(keys, metal::MTLResourceUsage::Read);
+    encoder.use_resource(hist, metal::MTLResourceUsage::Write);
+    encoder.dispatch_thread_groups(thread_group_count, thread_group_size);
+    Ok(())
+}
+
+/// Moves the `m` keys to `dst` sorted by the digit `shift..shift + 8`, `offsets` is the
+/// exclusive prefix sum of the histogram. The order of the keys with the same digit is kept.
+#[allow(clippy::too_many_arguments)]
+pub fn call_gaussians_radix_scatter(
+    device: &Device,
+    ep: impl EncoderProvider,
+    kernels: &Kernels,
+    (m, shift): (usize, u32),
+    keys: &Buffer,
+    offsets: &Buffer,
+    dst: &Buffer,
+) -> Result<(), MetalKernelError> {
+    let pipeline =
+        kernels.load_pipeline(device, Source::GaussianSplatting, "gaussians_radix_scatter")?;
+    let encoder = ep.encoder();
+    let encoder: &ComputeCommandEncoderRef = encoder.as_ref();
+    encoder.set_compute_pipeline_state(&pipeline);
+    set_params!(encoder, (keys, offsets, dst, m, shift));
+    let (thread_group_count, thread_group_size) = gaussians_radix_dims(m);
+    encoder.use_resource(keys, metal::MTLResourceUsage::Read);
+    encoder.use_resource(offsets, metal::MTLResourceUsage::Read);
+    encoder.use_resource(dst, metal::MTLResourceUsage::Write);
+    encoder.dispatch_thread_groups(thread_group_count, thread_group_size);
+    Ok(())
+}
+
+/// Writes the `(start, end)` range of the `m` sorted keys of each tile to `ranges`, which has to
+/// be zeroed.
+pub fn call_gaussians_tile_ranges(
+    device: &Device,
+    ep: impl EncoderProvider,
+    kernels: &Kernels,
+    m: usize,
+    keys: &Buffer,
+    ranges: &Buffer,
+) -> Result<(), MetalKernelError> {
+    let pipeline =
+        kernels.load_pipeline(device, Source::GaussianSplatting, "gaussians_tile_ranges")?;
+    let encoder = ep.encoder();
+    let encoder: &ComputeCommandEncoderRef = encoder.as_ref();
+    encoder.set_compute_pipeline_state(&pipeline);
+    set_params!(encoder, (keys, ranges, m));
+    let (thread_group_count, thread_group_size) = linear_split(&pipeline, m);
+    encoder.use_resource(keys, metal::MTLResourceUsage::Read);
+    encoder.use_resource(
+        ranges,
+        metal::MTLResourceUsage::Read | metal::MTLResourceUsage::Write,
+    );
+    encoder.dispatch_thread_groups(thread_group_count, thread_group_size);
+    Ok(())
+}
+
+// One threadgroup per tile of 16x16 pixels, the gaussians of the tile are loaded in threadgroup
+// memory by batches of one per thread.
+fn rasterize_gaussians_dims(
+    encoder: &ComputeCommandEncoderRef,
+    channels: usize,
+    width: usize,
+    height: usize,
+) -> (MTLSize, MTLSize) {
+    const TILE: usize = 16;
+    encoder.set_threadgroup_memory_length(0, (TILE * TILE * (6 + channels) * 4) as u64);
+    let thread_group_count = MTLSize {
+        width: width.div_ceil(TILE) as u64,
+        height: height.div_ceil(TILE) as u64,
+        depth: 1,
+    };
+    let thread_group_size = MTLSize {
+        width: TILE as u64,
+        height: TILE as u64,
+        depth: 1,
+    };
+    (thread_group_count, thread_group_size)
+}
+
+/// Rasterizes depth sorted 2D gaussians with `channels` color channels to an image of shape
+/// `(height, width, channels)`. `keys` and `ranges` are the keys sorted by tile and the range of
+/// each tile, see [`call_gaussians_tile_ranges`].
+#[allow(clippy::too_many_arguments)]
+pub fn call_rasterize_gaussians(
+    device: &Device,
+    ep: impl EncoderProvider,
+    kernels: &Kernels,
+    (channels, width, height): (usize, usize, usize),
+    params: BufferOffset,
+    keys: &Buffer,
+    ranges: &Buffer,
+    background: &[f32],
+    output: &Buffer,
+) -> Result<(), MetalKernelError> {
+    let pipeline =
+        kernels.load_pipeline(device, Source::GaussianSplatting, "rasterize_gaussians_fwd")?;
+    let encoder = ep.encoder();
+    let encoder: &ComputeCommandEncoderRef = encoder.as_ref();
+    encoder.set_compute_pipeline_state(&pipeline);
+    set_params!(
+        encoder,
+        (&params, keys, ranges, background, output, channels, width, height)
+    );
+    let (thread_group_count, thread_group_size) =
+        rasterize_gaussians_dims(encoder, channels, width, height);
+    encoder.use_resource(params.buffer, metal::MTLResourceUsage::Read);
+    encoder.use_resource(keys, metal::MTLResourceUsage::Read);
+    encoder.use_resource(ranges, metal::MTLResourceUsage::Read);
+    encoder.use_resource(output, metal::MTLResourceUsage::Write);
+    encoder.dispatch_thread_groups(thread_group_count, thread_group_size);
+    Ok(())
+}
+
+/// Accumulates the gradient of the gaussian params in `grads`, which has to be zeroed.
+#[allow(clippy::too_many_arguments)]
+pub fn call_rasterize_gaussians_backward(
+    device: &Device,
+    ep: impl EncoderProvider,
+    kernels: &Kernels,
+    (channels, width, height): (usize, usize, usize),
+    params: BufferOffset,
+    keys: &Buffer,
+    ranges: &Buffer,
+    grad_out: BufferOffset,
+    background: &[f32],
+    grads: &Buffer,
+) -> Result<(), MetalKernelError> {
+    let pipeline =
+        kernels.load_pipeline(device, Source::GaussianSplatting, "rasterize_gaussians_bwd")?;
+    let encoder = ep.encoder();
+    let encoder: &ComputeCommandEncoderRef = encoder.as_ref();
+    encoder.set_compute_pipeline_state(&pipeline);
+    set_params!(
+        encoder,
+        (&params, keys, ranges, &grad_out, background, grads, channels, width, height)
+    );
+    let (thread_group_count, thread_group_size) =
+        rasterize_gaussians_dims(encoder, channels, width, height);
+    encoder.use_resource(params.buffer, metal::MTLResourceUsage::Read);
+    encoder.use_resource(keys, metal::MTLResourceUsage::Read);
+    encoder.use_resource(ranges, metal::MTLResourceUsage::Read);
+    encoder.use_resource(grad_out.buffer, metal::MTLResourceUsage::Read);
+    encoder.use_resource(
+        grads,
+        metal::MTLResourceUsage::Read | metal::MTLResourceUsage::Write,
+    );
+    encoder.dispatch_thread_groups(thread_group_count, thread_group_size);
+    Ok(())
+}
+
 #[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
 pub enum GemmDType {
     BF16,
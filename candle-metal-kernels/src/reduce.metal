@@ -223,6 +223,17 @@ kernel void NAME( \
     reduce<T>(num_dims, dims, strides, el_to_sum_per_block, src, dst, id, tid, dst_id, block_dim, shared_memory, NAME##_##op); \
 } \
 
+// Merges the max and sum of exponentials of two parts of a row, the sum is rescaled to the new
+// max so that half precision inputs with large logits cannot overflow.
+METAL_FUNC void online_softmax_merge(thread float & max_val, thread float & sum, float other_max, float other_sum) {
+    const float new_max = MAX(max_val, other_max);
+    if (new_max == -INFINITY) {
+        return;
+    }
+    sum = sum * exp(max_val - new_max) + other_sum * exp(other_max - new_max);
+    max_val = new_max;
+}
+
 template<typename T>
 METAL_FUNC void softmax(
     constant size_t & src_numel,
@@ -239,50 +250,34 @@ METAL_FUNC void softmax(
     size_t stop_idx = min(start_idx + el_to_sum_per_block, src_numel);
     size_t idx = start_idx + tid;
 
-    float tmp = -INFINITY;
+    /* the max and sum are computed in a single pass and accumulated in f32 */
+    float max_val = -INFINITY;
+    float sum = 0;
     while (idx < stop_idx) {
-        tmp = MAX(tmp, float(src[idx]));
+        online_softmax_merge(max_val, sum, float(src[idx]), 1);
         idx += block_dim;
     }
-    shared_memory[tid] = tmp;
+    shared_memory[tid] = max_val;
+    shared_memory[tid + block_dim] = sum;
 
     threadgroup_barrier(mem_flags::mem_threadgroup);
 
     for (uint s = block_dim / 2; s > 0; s >>= 1) {
         if (tid < s) {
-            shared_memory[tid] = MAX(shared_memory[tid], shared_memory[tid + s]);\
+            float m = shared_memory[tid];
+            float acc = shared_memory[tid + block_dim];
+            online_softmax_merge(m, acc, shared_memory[tid + s], shared_memory[tid + s + block_dim]);
+            shared_memory[tid] = m;
+            shared_memory[tid + block_dim] = acc;
         }
         threadgroup_barrier(mem_flags::mem_threadgroup);
     }
 
-    /* wait for shared_memory[0] to be filled */
-    threadgroup_barrier(mem_flags::mem_threadgroup);
-
-    float _max = shared_memory[0];
-
-    /* prevent tid=0 from overwriting _max before other threads have written it */
-    threadgroup_barrier(mem_flags::mem_threadgroup);
-    shared_memory[tid] = 0;
-
+    const float _max = shared_memory[0];
+    const float inv_acc = 1.0f / shared_memory[block_dim];
     idx = start_idx + tid;
     while (idx < stop_idx) {
-        const float val = exp(float(src[idx]) - _max);
-        dst[idx] = T(val);
-        shared_memory[tid] += val;
-        idx += block_dim;
-    }
-    threadgroup_barrier(mem_flags::mem_threadgroup);
-    for (uint s = block_dim / 2; s > 0; s >>= 1) {
-        if (tid < s) {
-            shared_memory[tid] += shared_memory[tid + s];
-        }
-        threadgroup_barrier(mem_flags::mem_threadgroup);
-    }
-
-    const T inv_acc = T(1.0 / shared_memory[0]);
-    idx = start_idx + tid;
-    while (idx < stop_idx) {
-        dst[idx] *= inv_acc;
+        dst[idx] = T(exp(float(src[idx]) - _max) * inv_acc);
         idx += block_dim;
     }
 }
@@ -372,31 +367,48 @@ METAL_FUNC void layernorm(
     size_t stop_idx = min(start_idx + el_to_sum_per_block, src_numel);
     size_t idx = start_idx + tid;
 
-    float tmp1 = 0;
-    float tmp2 = 0;
+    /* the variance is computed around the mean in a second pass, E[x^2] - E[x]^2 loses all the
+       precision when the mean is large compared to the standard deviation */
+    float tmp = 0;
     while (idx < stop_idx) {
-        tmp1 += float(src[idx]);
-        tmp2 += float(src[idx]) * float(src[idx]);
+        tmp += float(src[idx]);
         idx += block_dim;
     }
-    shared_memory[tid] = tmp1;
-    shared_memory[tid + block_dim] = tmp2;
+    shared_memory[tid] = tmp;
 
     threadgroup_barrier(mem_flags::mem_threadgroup);
 
     for (uint s = block_dim / 2; s > 0; s >>= 1) {
         if (tid < s) {
             shared_memory[tid] = shared_memory[tid] + shared_memory[tid + s];
-            shared_memory[block_dim + tid] = shared_memory[block_dim + tid] + shared_memory[block_dim + tid + s];
         }
         threadgroup_barrier(mem_flags::mem_threadgroup);
     }
 
-    /* wait for shared_memory[0] to be filled */
+    float mean = shared_memory[0] / float(el_to_sum_per_block);
+
+    /* prevent tid=0 from overwriting the sum before the other threads have read it */
     threadgroup_barrier(mem_flags::mem_threadgroup);
 
-    float mean = shared_memory[0] / float(el_to_sum_per_block);
-    float var = shared_memory[block_dim] / float(el_to_sum_per_block) - mean * mean;
+    tmp = 0;
+    idx = start_idx + tid;
+    while (idx < stop_idx) {
+        float centered = float(src[idx]) - mean;
+        tmp += centered * centered;
+        idx += block_dim;
+    }
+    shared_memory[tid] = tmp;
+
+    threadgroup_barrier(mem_flags::mem_threadgroup);
+
+    for (uint s = block_dim / 2; s > 0; s >>= 1) {
+        if (tid < s) {
+            shared_memory[tid] = shared_memory[tid] + shared_memory[tid + s];
+        }
+        threadgroup_barrier(mem_flags::mem_threadgroup);
+    }
+
+    float var = shared_memory[0] / float(el_to_sum_per_block);
     float inv_norm = 1.0f / sqrt(var + eps);
     idx = start_idx + tid;
     while (idx < stop_idx) {
@@ -523,6 +535,70 @@ METAL_FUNC void rope_thd(
     dst[i2] = src[i1] * s + src[i2] * c;
 }
 
+// Only the first `rd` values of each head are rotated, the other ones are copied. Each thread
+// handles either a rotated pair or a copied value.
+template<typename T, bool INTERLEAVED>
+METAL_FUNC void rope_partial(
+    constant size_t &bh,
+    constant size_t &t,
+    constant size_t &d,
+    constant size_t &rd,
+    device const T *src,
+    device const T *cos,
+    device const T *sin,
+    device T *dst,
+    uint idx
+) {
+    const size_t per_row = d - rd / 2;
+    if (idx >= bh * t * per_row) {
+        return;
+    }
+    const size_t i_row = idx / per_row;
+    const size_t j = idx - per_row * i_row;
+    const size_t i_t = i_row % t;
+    const size_t row = i_row * d;
+    if (j >= rd / 2) {
+        const size_t i = row + rd + (j - rd / 2);
+        dst[i] = src[i];
+        return;
+    }
+    const size_t i1 = INTERLEAVED ? row + 2 * j : row + j;
+    const size_t i2 = INTERLEAVED ? i1 + 1 : i1 + rd / 2;
+    const size_t i_cs = i_t * (rd / 2) + j;
+    T c = cos[i_cs];
+    T s = sin[i_cs];
+    dst[i1] = src[i1] * c - src[i2] * s;
+    dst[i2] = src[i1] * s + src[i2] * c;
+}
+
+#define ROPE_PARTIAL(FN_NAME, FN_NAME_I, TYPENAME) \
+kernel void FN_NAME( \
+    constant size_t &bh, \
+    constant size_t &t, \
+    constant size_t &d, \
+    constant size_t &rd, \
+    device const TYPENAME *src,  \
+    device const TYPENAME *cos,  \
+    device const TYPENAME *sin,  \
+    device TYPENAME *dst, \
+    uint idx [[ thread_position_in_grid ]] \
+) { \
+    rope_partial<TYPENAME, false>(bh, t, d, rd, src, cos, sin, dst, idx); \
+}\
+kernel void FN_NAME_I( \
+    constant size_t &bh, \
+    constant size_t &t, \
+    constant size_t &d, \
+    constant size_t &rd, \
+    device const TYPENAME *src,  \
+    device const TYPENAME *cos,  \
+    device const TYPENAME *sin,  \
+    device TYPENAME *dst, \
+    uint idx [[ thread_position_in_grid ]] \
+) { \
+    rope_partial<TYPENAME, true>(bh, t, d, rd, src, cos, sin, dst, idx); \
+}\
+
 #define ROPE(FN_NAME, FN_NAME_I, FN_NAME_THD, TYPENAME) \
 kernel void FN_NAME_I( \
     constant size_t &bh, \
@@ -593,6 +669,8 @@ LAYERNORM(layernorm_f32, float)
 LAYERNORM(layernorm_f16, half)
 ROPE(rope_f32, rope_i_f32, rope_thd_f32, float)
 ROPE(rope_f16, rope_i_f16, rope_thd_f16, half)
+ROPE_PARTIAL(rope_partial_f32, rope_i_partial_f32, float)
+ROPE_PARTIAL(rope_partial_f16, rope_i_partial_f16, half)
 
 #if __METAL_VERSION__ >= 220
 REDUCE(x + y, fast_sum_i64_strided, int64_t, 0)
@@ -617,4 +695,5 @@ SOFTMAX(softmax_bf16, bfloat)
 RMSNORM(rmsnorm_bf16, bfloat)
 LAYERNORM(layernorm_bf16, bfloat)
 ROPE(rope_bf16, rope_i_bf16, rope_thd_bf16, bfloat)
+ROPE_PARTIAL(rope_partial_bf16, rope_i_partial_bf16, bfloat)
 #endif
@@ -1406,7 +1406,7 @@ fn mlx_gemm() {
     }
 }
 
-fn run_random<T: Clone>(name: &'static str, seed: u32, length: usize, a: f32, b: f32) -> Vec<T> {
+fn run_random<T: Clone>(name: &'static str, seed: u64, length: usize, a: f32, b: f32) -> Vec<T> {
     let device = device();
     let kernels = Kernels::new();
     let command_queue = device.new_command_queue();
@@ -1415,12 +1415,6 @@ fn run_random<T: Clone>(name: &'static str, seed: u32, length: usize, a: f32, b:
     let options = MTLResourceOptions::StorageModeManaged;
     let output = device.new_buffer((length * core::mem::size_of::<T>()) as NSUInteger, options);
 
-    let seed = device.new_buffer_with_data(
-        &seed as *const u32 as *const core::ffi::c_void,
-        std::mem::size_of::<u32>() as NSUInteger,
-        options,
-    );
-
     if name.starts_with("rand_uniform") {
         call_random_uniform(
             &device,
@@ -1430,7 +1424,8 @@ fn run_random<T: Clone>(name: &'static str, seed: u32, length: usize, a: f32, b:
             a,
             b,
             length,
-            &seed,
+            seed,
+            0,
             &output,
         )
         .unwrap();
@@ -1443,7 +1438,8 @@ fn run_random<T: Clone>(name: &'static str, seed: u32, length: usize, a: f32, b:
             a,
             b,
             length,
-            &seed,
+            seed,
+            0,
             &output,
         )
         .unwrap();
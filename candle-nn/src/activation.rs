@@ -89,6 +89,18 @@ impl candle::Module for PReLU {
     }
 }
 
+impl crate::migrate::Migrate for PReLU {
+    fn visit_tensors(&mut self, f: &mut dyn FnMut(&mut Tensor) -> Result<()>) -> Result<()> {
+        f(&mut self.weight)
+    }
+}
+
+impl crate::layer::Layer for PReLU {
+    fn visit_parameters(&mut self, f: &mut crate::layer::TensorFn) -> Result<()> {
+        f("weight", &mut self.weight)
+    }
+}
+
 /// Create or initialize a new PReLU layer.
 ///
 /// This uses some default name for weights, namely `"weight"`.
@@ -0,0 +1,633 @@
+//! Attention masks, scaled dot product attention and multi-head attention.
+//!
+//! Masks are `u8` tensors where `1` marks the positions that cannot be attended to, matching the
+//! masks returned by the kv caches. Float masks are additive biases, `-inf` masking a position.
+//! The last two dimensions of a mask are the query and key positions.
+use crate::kv_cache::KvCache;
+use crate::{Linear, VarBuilder};
+use candle::{DType, Device, Module, Result, Tensor, D};
+
+/// A causal mask of shape `(seq_len, seq_len)` where each position can only attend to itself and
+/// the previous positions.
+pub fn causal_mask(seq_len: usize, device: &Device) -> Result<Tensor> {
+    let mask: Vec<_> = (0..seq_len)
+        .flat_map(|i| (0..seq_len).map(move |j| u8::from(j > i)))
+        .collect();
+    Tensor::from_slice(&mask, (seq_len, seq_len), device)
+}
+
+/// A block diagonal mask for packed sequences.
+///
+/// `segment_ids` has shape `(batch, seq_len)` and contains an integer id per position, the
+/// positions of each segment can only attend to the positions of the same segment in the same
+/// row. The resulting mask has shape `(batch, seq_len, seq_len)`, when `causal` is set the
+/// positions can additionally only attend to the previous positions.
+pub fn block_diagonal_mask(segment_ids: &Tensor, causal: bool) -> Result<Tensor> {
+    let (_b_size, seq_len) = segment_ids.dims2()?;
+    let mask = segment_ids
+        .unsqueeze(2)?
+        .broadcast_ne(&segment_ids.unsqueeze(1)?)?;
+    if causal {
+        mask.broadcast_maximum(&causal_mask(seq_len, segment_ids.device())?)
+    } else {
+        Ok(mask)
+    }
+}
+
+/// A mask of shape `(batch, 1, kv_seq_len)` from a `(batch, kv_seq_len)` tensor where `1` marks
+/// the padding keys, e.g. the padding tokens of the sequences of a batch.
+pub fn key_padding_mask(padding: &Tensor) -> Result<Tensor> {
+    let (_b_size, _kv_seq_len) = padding.dims2()?;
+    padding.unsqueeze(1)
+}
+
+/// Merges two masks, a position is masked when it is masked by any of them. The masks are
+/// broadcast to a common shape. When one of them is an additive float mask the result is the sum
+/// of the biases, with the dtype of the float mask.
+pub fn merge_masks(m1: Option<&Tensor>, m2: Option<&Tensor>) -> Result<Option<Tensor>> {
+    match (m1, m2) {
+        (None, None) => Ok(None),
+        (Some(m), None) | (None, Some(m)) => Ok(Some(m.clone())),
+        (Some(m1), Some(m2)) => match (m1.dtype().is_float(), m2.dtype().is_float()) {
+            (false, false) => Ok(Some(m1.broadcast_maximum(m2)?)),
+            (true, _) => Ok(Some(m1.broadcast_add(&mask_to_bias(m2, m1.dtype())?)?)),
+            (false, true) => Ok(Some(mask_to_bias(m1, m2.dtype())?.broadcast_add(m2)?)),
+        },
+    }
+}
+
+/// Applies `mask` to the attention `scores` by setting the masked positions to `-inf` so that
+/// they get a zero weight after the softmax, float masks are added to the scores. The mask is
+/// broadcast to the shape of the scores, a `(batch, seq_len, seq_len)` mask is applied to all the
+/// heads of `(batch, heads, seq_len, seq_len)` scores.
+pub fn apply_mask(scores: &Tensor, mask: &Tensor) -> Result<Tensor> {
+    let mask = if mask.rank() + 1 == scores.rank() {
+        mask.unsqueeze(mask.rank() - 2)?
+    } else {
+        mask.clone()
+    };
+    if mask.dtype().is_float() {
+        return scores.broadcast_add(&mask.to_dtype(scores.dtype())?);
+    }
+    let mask = mask.broadcast_as(scores.shape())?;
+    let neg_inf = Tensor::new(f32::NEG_INFINITY, scores.device())?
+        .to_dtype(scores.dtype())?
+        .broadcast_as(scores.shape())?;
+    mask.where_cond(&neg_inf, scores)
+}
+
+/// Converts `mask` to an additive bias with `0` for the positions that can be attended to and
+/// `-inf` for the masked ones, float masks are only converted to `dtype`.
+pub fn mask_to_bias(mask: &Tensor, dtype: DType) -> Result<Tensor> {
+    let zeros = Tensor::zeros(mask.shape(), dtype, mask.device())?;
+    apply_mask(&zeros, mask)
+}
+
+/// The configuration for [`scaled_dot_product_attention`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AttentionConfig {
+    /// The scale applied to the attention scores, `1 / sqrt(head_dim)` when not set.
+    pub scale: Option<f64>,
+    /// When set, the scores are soft-capped with `tanh(scores / cap) * cap` before the softmax.
+    pub softcapping: Option<f64>,
+    /// Computes the scores and the softmax in f32 for f16 and bf16 inputs, the attention weights
+    /// are converted back to the dtype of the values before being applied. The default is true
+    /// as the reduced precision scores can overflow or lose precision on long sequences.
+    pub f32_logits: bool,
+    /// When the attention matrix has more than `chunk_size * chunk_size` elements per head, the
+    /// queries and keys are processed by blocks of `chunk_size` positions with an online softmax
+    /// so that the memory grows linearly with the sequence lengths. The attention matrix is
+    /// always materialized when not set. The default is 512.
+    pub chunk_size: Option<usize>,
+    /// Applies a causal mask in addition to the mask argument, aligned to the bottom right of
+    /// the attention matrix: query `i` attends to the keys `j <= i + kv_seq_len - seq_len`. The
+    /// mask is only built for the blocks that need it.
+    pub causal: bool,
+}
+
+impl Default for AttentionConfig {
+    fn default() -> Self {
+        Self {
+            scale: None,
+            softcapping: None,
+            f32_logits: true,
+            chunk_size: Some(512),
+            causal: false,
+        }
+    }
+}
+
+pub(crate) fn repeat_heads(xs: &Tensor, n_heads: usize) -> Result<Tensor> {
+    let (b_size, n_kv_heads, seq_len, head_dim) = xs.dims4()?;
+    if n_heads == n_kv_heads {
+        return Ok(xs.clone());
+    }
+    if n_heads % n_kv_heads != 0 {
+        candle::bail!("the number of heads {n_heads} is not a multiple of {n_kv_heads}")
+    }
+    let n_rep = n_heads / n_kv_heads;
+    xs.unsqueeze(2)?
+        .broadcast_as((b_size, n_kv_heads, n_rep, seq_len, head_dim))?
+        .reshape((b_size, n_heads, seq_len, head_dim))
+}
+
+/// Computes `softmax(q k^T * scale) v`.
+///
+/// `q` has shape `(batch, heads, seq_len, head_dim)`, `k` and `v` have shape `(batch, kv_heads,
+/// kv_seq_len, head_dim)` where `heads` is a multiple of `kv_heads` for grouped query attention.
+/// The optional `mask` uses the same convention as [`apply_mask`]. The result has shape `(batch,
+/// heads, seq_len, head_dim)` and the dtype of `v`.
+///
+/// Long sequences are processed by blocks as set by [`AttentionConfig::chunk_size`], this works
+/// on all the devices and is the fallback for the devices without a flash attention kernel.
+pub fn scaled_dot_product_attention(
+    q: &Tensor,
+    k: &Tensor,
+    v: &Tensor,
+    mask: Option<&Tensor>,
+    config: &AttentionConfig,
+) -> Result<Tensor> {
+    let (_b_size, n_heads, seq_len, head_dim) = q.dims4()?;
+    let kv_seq_len = k.dim(2)?;
+    let k = repeat_heads(k, n_heads)?;
+    let v = repeat_heads(v, n_heads)?;
+    let scale = config.scale.unwrap_or(1. / (head_dim as f64).sqrt());
+    let logits_dtype = match q.dtype() {
+        DType::F16 | DType::BF16 if config.f32_logits => DType::F32,
+        dtype => dtype,
+    };
+    let q = q.to_dtype(logits_dtype)?.contiguous()?;
+    let k = k.to_dtype(logits_dtype)?;
+    match config.chunk_size {
+        Some(chunk_size) if seq_len * kv_seq_len > chunk_size * chunk_size => {
+            chunked_attention(&q, &k, &v, mask, scale, config, chunk_size)
+        }
+        _ => {
+            let causal = BlockMask::new(config.causal, seq_len, kv_seq_len);
+            let causal = causal.block((0, seq_len), (0, kv_seq_len), q.device())?;
+            let masks = mask.into_iter().chain(causal.as_ref());
+            let scores = attention_scores(&q, &k, masks, scale, config.softcapping)?;
+            let weights = crate::ops::softmax_last_dim(&scores)?.to_dtype(v.dtype())?;
+            weights.matmul(&v.contiguous()?)
+        }
+    }
+}
+
+fn attention_scores<'a>(
+    q: &Tensor,
+    k: &Tensor,
+    masks: impl Iterator<Item = &'a Tensor>,
+    scale: f64,
+    softcapping: Option<f64>,
+) -> Result<Tensor> {
+    let mut scores = (q.matmul(&k.t()?.contiguous()?)? * scale)?;
+    if let Some(cap) = softcapping {
+        scores = ((scores / cap)?.tanh()? * cap)?;
+    }
+    for mask in masks {
+        scores = apply_mask(&scores, mask)?;
+    }
+    Ok(scores)
+}
+
+// The causal mask of `AttentionConfig`, built by blocks.
+pub(crate) struct BlockMask {
+    causal: bool,
+    // The offset of the diagonal, `kv_seq_len - seq_len`.
+    offset: i64,
+}
+
+impl BlockMask {
+    pub(crate) fn new(causal: bool, seq_len: usize, kv_seq_len: usize) -> Self {
+        let offset = kv_seq_len as i64 - seq_len as i64;
+        Self { causal, offset }
+    }
+
+    // Whether all the keys of the block are masked for all the queries.
+    pub(crate) fn is_masked(
+        &self,
+        (q_start, q_len): (usize, usize),
+        (k_start, _): (usize, usize),
+    ) -> bool {
+        self.causal && k_start as i64 > (q_start + q_len) as i64 - 1 + self.offset
+    }
+
+    // The mask of the block, `None` when no position is masked.
+    pub(crate) fn block(
+        &self,
+        (q_start, q_len): (usize, usize),
+        (k_start, k_len): (usize, usize),
+        device: &Device,
+    ) -> Result<Option<Tensor>> {
+        if !self.causal || (k_start + k_len) as i64 - 1 <= q_start as i64 + self.offset {
+            return Ok(None);
+        }
+        let mask: Vec<_> = (q_start..q_start + q_len)
+            .flat_map(|i| {
+                (k_start..k_start + k_len).map(move |j| u8::from(j as i64 > i as i64 + self.offset))
+            })
+            .collect();
+        Ok(Some(Tensor::from_slice(&mask, (q_len, k_len), device)?))
+    }
+}
+
+// The block of the mask for the given queries and keys, the broadcast dimensions are kept.
+fn mask_block(mask: &Tensor, q: (usize, usize), k: (usize, usize)) -> Result<Tensor> {
+    let rank = mask.rank();
+    let mut mask = mask.clone();
+    for (dim, (start, len)) in [(rank - 2, q), (rank - 1, k)] {
+        if mask.dim(dim)? > 1 {
+            mask = mask.narrow(dim, start, len)?
+        }
+    }
+    Ok(mask)
+}
+
+// Whether all the positions of each `(query block, key block)` are masked by the boolean `mask`,
+// indexed by the block indices. The blocks are reduced on the device so that this only needs a
+// single copy to the host.
+fn masked_blocks(mask: &Tensor, chunk_size: usize) -> Result<Vec<Vec<bool>>> {
+    let rank = mask.rank();
+    let (q_len, k_len) = (mask.dim(rank - 2)?, mask.dim(rank - 1)?);
+    // The attended positions, merged over the broadcast dimensions.
+    let attended = mask.eq(0u8)?.reshape(((), q_len, k_len))?.max(0)?;
+    let pad = |len: usize| len.div_ceil(chunk_size) * chunk_size - len;
+    let (q_block, q_pad) = if q_len > 1 {
+        (chunk_size, pad(q_len))
+    } else {
+        (1, 0)
+    };
+    let (k_block, k_pad) = if k_len > 1 {
+        (chunk_size, pad(k_len))
+    } else {
+        (1, 0)
+    };
+    let (n_q, n_k) = ((q_len + q_pad) / q_block, (k_len + k_pad) / k_block);
+    attended
+        .pad_with_zeros(0, 0, q_pad)?
+        .pad_with_zeros(1, 0, k_pad)?
+        .reshape((n_q, q_block, n_k, k_block))?
+        .max(3)?
+        .max(1)?
+        .to_vec2::<u8>()
+        .map(|blocks| {
+            blocks
+                .into_iter()
+                .map(|row| row.into_iter().map(|a| a == 0).collect())
+                .collect()
+        })
+}
+
+// Attention with an online softmax over blocks of keys, for each block of queries. The running
+// maximum, sum and output are kept in f32.
+fn chunked_attention(
+    q: &Tensor,
+    k: &Tensor,
+    v: &Tensor,
+    mask: Option<&Tensor>,
+    scale: f64,
+    config: &AttentionConfig,
+    chunk_size: usize,
+) -> Result<Tensor> {
+    let (b_size, n_heads, seq_len, _head_dim) = q.dims4()?;
+    let (kv_seq_len, v_dim) = (v.dim(2)?, v.dim(3)?);
+    let causal = BlockMask::new(config.causal, seq_len, kv_seq_len);
+    // The blocks that are fully masked, e.g. above the diagonal of a causal mask, do not
+    // contribute to the output. The additive masks are always applied.
+    let masked = match mask {
+        Some(mask) if !mask.dtype().is_float() => Some(masked_blocks(mask, chunk_size)?),
+        _ => None,
+    };
+    let is_masked = |q_start: usize, k_start: usize| match &masked {
+        None => false,
+        Some(masked) => {
+            let row = &masked[(q_start / chunk_size).min(masked.len() - 1)];
+            row[(k_start / chunk_size).min(row.len() - 1)]
+        }
+    };
+    let mut outputs = Vec::with_capacity(seq_len.div_ceil(chunk_size));
+    for q_start in (0..seq_len).step_by(chunk_size) {
+        let q_len = chunk_size.min(seq_len - q_start);
+        let q_chunk = q.narrow(2, q_start, q_len)?;
+        let mut state: Option<(Tensor, Tensor, Tensor)> = None;
+        for k_start in (0..kv_seq_len).step_by(chunk_size) {
+            let k_len = chunk_size.min(kv_seq_len - k_start);
+            let (q_block, k_block) = ((q_start, q_len), (k_start, k_len));
+            if causal.is_masked(q_block, k_block) || is_masked(q_start, k_start) {
+                continue;
+            }
+            let mask = match mask {
+                None => None,
+                Some(mask) => Some(mask_block(mask, q_block, k_block)?),
+            };
+            let k_chunk = k.narrow(2, k_start, k_len)?;
+            let v_chunk = v.narrow(2, k_start, k_len)?.contiguous()?;
+            let causal = causal.block(q_block, k_block, q.device())?;
+            let masks = mask.iter().chain(causal.as_ref());
+            let scores = attention_scores(&q_chunk, &k_chunk, masks, scale, config.softcapping)?
+                .to_dtype(DType::F32)?;
+            // The maximum is clamped so that the rows where all the keys are masked give zero
+            // weights rather than nan.
+            let block_max = scores.max_keepdim(D::Minus1)?;
+            let max = match &state {
+                None => block_max,
+                Some((max, _, _)) => max.maximum(&block_max)?,
+            }
+            .maximum(f32::MIN)?;
+            let weights = scores.broadcast_sub(&max)?.exp()?;
+            let sum = weights.sum_keepdim(D::Minus1)?;
+            let out = weights
+                .to_dtype(v.dtype())?
+                .matmul(&v_chunk)?
+                .to_dtype(DType::F32)?;
+            state = Some(match state {
+                None => (max, sum, out),
+                Some((prev_max, prev_sum, prev_out)) => {
+                    let alpha = (prev_max - &max)?.exp()?;
+                    let sum = ((prev_sum * &alpha)? + sum)?;
+                    let out = (prev_out.broadcast_mul(&alpha)? + out)?;
+                    (max, sum, out)
+                }
+            });
+        }
+        let out = match state {
+            Some((_, sum, out)) => out.broadcast_div(&sum)?,
+            // All the keys are masked, this gives nan as with the softmax.
+            None => Tensor::full(f32::NAN, (b_size, n_heads, q_len, v_dim), q.device())?,
+        };
+        outputs.push(out.to_dtype(v.dtype())?)
+    }
+    Tensor::cat(&outputs, 2)
+}
+
+/// The configuration of a [`MultiheadAttention`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MultiheadAttentionConfig {
+    /// The number of key and value heads for grouped query attention, `1` for multi-query
+    /// attention. The default is the number of query heads.
+    pub num_kv_heads: Option<usize>,
+    /// The dimension of the heads, `embed_dim / num_heads` when not set.
+    pub head_dim: Option<usize>,
+    /// Whether the query, key and value projections have a bias.
+    pub qkv_bias: bool,
+    /// Whether the output projection has a bias.
+    pub out_bias: bool,
+    /// When set, the rotary embeddings with this base are applied to the queries and keys. The
+    /// tables are shared with the other layers through [`crate::device_cache`].
+    pub rope_theta: Option<f64>,
+    /// The capacity of the kv cache and the number of positions of the rotary embeddings.
+    pub max_seq_len: usize,
+    /// Uses [`crate::ops::flash_attn`] for the attentions without a mask nor soft-capping, the
+    /// other ones use [`scaled_dot_product_attention`].
+    pub use_flash_attn: bool,
+    pub attention: AttentionConfig,
+}
+
+impl Default for MultiheadAttentionConfig {
+    fn default() -> Self {
+        Self {
+            num_kv_heads: None,
+            head_dim: None,
+            qkv_bias: true,
+            out_bias: true,
+            rope_theta: None,
+            max_seq_len: 4096,
+            use_flash_attn: false,
+            attention: AttentionConfig::default(),
+        }
+    }
+}
+
+impl MultiheadAttentionConfig {
+    pub fn with_num_kv_heads(mut self, num_kv_heads: usize) -> Self {
+        self.num_kv_heads = Some(num_kv_heads);
+        self
+    }
+
+    pub fn with_head_dim(mut self, head_dim: usize) -> Self {
+        self.head_dim = Some(head_dim);
+        self
+    }
+
+    /// Sets the bias of all the projections.
+    pub fn with_bias(mut self, bias: bool) -> Self {
+        self.qkv_bias = bias;
+        self.out_bias = bias;
+        self
+    }
+
+    pub fn with_rope_theta(mut self, rope_theta: f64) -> Self {
+        self.rope_theta = Some(rope_theta);
+        self
+    }
+
+    pub fn with_max_seq_len(mut self, max_seq_len: usize) -> Self {
+        self.max_seq_len = max_seq_len;
+        self
+    }
+
+    pub fn with_flash_attn(mut self, use_flash_attn: bool) -> Self {
+        self.use_flash_attn = use_flash_attn;
+        self
+    }
+
+    pub fn with_attention(mut self, attention: AttentionConfig) -> Self {
+        self.attention = attention;
+        self
+    }
+}
+
+/// Multi-head attention with query, key, value and output projections named `q_proj`, `k_proj`,
+/// `v_proj` and `out_proj`.
+///
+/// The inputs have shape `(batch, seq_len, embed_dim)`, the keys and values can come from
+/// another sequence for cross-attention. The masks are boolean `u8` masks or additive float
+/// masks as described in the [module documentation](self), they are broadcast to all the heads.
+///
+/// [`Self::forward_cached`] runs the self-attention of a decoder one step at a time, the keys
+/// and values of the previous steps being kept in an internal [`KvCache`].
+#[derive(Debug, Clone)]
+pub struct MultiheadAttention {
+    q_proj: Linear,
+    k_proj: Linear,
+    v_proj: Linear,
+    out_proj: Linear,
+    num_heads: usize,
+    num_kv_heads: usize,
+    head_dim: usize,
+    config: MultiheadAttentionConfig,
+    kv_cache: KvCache,
+}
+
+/// Creates a [`MultiheadAttention`] with `num_heads` query heads.
+pub fn multihead_attention(
+    embed_dim: usize,
+    num_heads: usize,
+    config: MultiheadAttentionConfig,
+    vb: VarBuilder,
+) -> Result<MultiheadAttention> {
+    let num_kv_heads = config.num_kv_heads.unwrap_or(num_heads);
+    if num_heads == 0 || num_kv_heads == 0 || num_heads % num_kv_heads != 0 {
+        candle::bail!("the {num_heads} heads are not a multiple of the {num_kv_heads} kv heads")
+    }
+    let head_dim = match config.head_dim {
+        Some(head_dim) => head_dim,
+        None if embed_dim % num_heads == 0 => embed_dim / num_heads,
+        None => candle::bail!("embed dim {embed_dim} is not a multiple of the {num_heads} heads"),
+    };
+    let (q_dim, kv_dim) = (num_heads * head_dim, num_kv_heads * head_dim);
+    let qkv_bias = config.qkv_bias;
+    Ok(MultiheadAttention {
+        q_proj: crate::linear_b(embed_dim, q_dim, qkv_bias, vb.pp("q_proj"))?,
+        k_proj: crate::linear_b(embed_dim, kv_dim, qkv_bias, vb.pp("k_proj"))?,
+        v_proj: crate::linear_b(embed_dim, kv_dim, qkv_bias, vb.pp("v_proj"))?,
+        out_proj: crate::linear_b(q_dim, embed_dim, config.out_bias, vb.pp("out_proj"))?,
+        num_heads,
+        num_kv_heads,
+        head_dim,
+        config,
+        kv_cache: KvCache::new(2, config.max_seq_len),
+    })
+}
+
+impl MultiheadAttention {
+    pub fn new(embed_dim: usize, num_heads: usize, bias: bool, vb: VarBuilder) -> Result<Self> {
+        let config = MultiheadAttentionConfig::default().with_bias(bias);
+        multihead_attention(embed_dim, num_heads, config, vb)
+    }
+
+    /// Sets the configuration of the attention itself, e.g. its scale or soft-capping.
+    pub fn with_attention_config(mut self, attention: AttentionConfig) -> Self {
+        self.config.attention = attention;
+        self
+    }
+
+    pub fn config(&self) -> &MultiheadAttentionConfig {
+        &self.config
+    }
+
+    pub fn num_heads(&self) -> usize {
+        self.num_heads
+    }
+
+    pub fn num_kv_heads(&self) -> usize {
+        self.num_kv_heads
+    }
+
+    pub fn head_dim(&self) -> usize {
+        self.head_dim
+    }
+
+    /// The cache of [`Self::forward_cached`], the keys and values have shape `(batch,
+    /// num_kv_heads, seq_len, head_dim)`.
+    pub fn kv_cache(&self) -> &KvCache {
+        &self.kv_cache
+    }
+
+    /// The cache can be truncated, forked or reordered for beam search, see [`KvCache`].
+    pub fn kv_cache_mut(&mut self) -> &mut KvCache {
+        &mut self.kv_cache
+    }
+
+    pub fn reset_kv_cache(&mut self) {
+        self.kv_cache.reset()
+    }
+
+    // Projects `xs` and splits the heads, the result has shape `(batch, num_heads, seq_len,
+    // head_dim)`.
+    fn project(&self, proj: &Linear, xs: &Tensor, num_heads: usize) -> Result<Tensor> {
+        let (b_size, seq_len, _) = xs.dims3()?;
+        proj.forward(xs)?
+            .reshape((b_size, seq_len, num_heads, self.head_dim))?
+            .transpose(1, 2)?
+            .contiguous()
+    }
+
+    // Applies the rotary embeddings to `xs` for the positions starting at `offset`.
+    fn apply_rope(&self, xs: Tensor, offset: usize) -> Result<Tensor> {
+        let theta = match self.config.rope_theta {
+            None => return Ok(xs),
+            Some(theta) => theta,
+        };
+        let seq_len = xs.dim(2)?;
+        let max_seq_len = self.config.max_seq_len;
+        if offset + seq_len > max_seq_len {
+            candle::bail!("rope: positions {offset}+{seq_len} above max-seq-len {max_seq_len}")
+        }
+        let (cos, sin) = crate::device_cache::rope_tables(
+            self.head_dim,
+            theta,
+            max_seq_len,
+            None,
+            xs.dtype(),
+            xs.device(),
+        )?;
+        let cos = cos.narrow(0, offset, seq_len)?;
+        let sin = sin.narrow(0, offset, seq_len)?;
+        // The rope kernels have no backward pass.
+        if xs.track_op() {
+            crate::rotary_emb::rope_slow(&xs, &cos, &sin)
+        } else {
+            crate::rotary_emb::rope(&xs, &cos, &sin)
+        }
+    }
+
+    fn attend(
+        &self,
+        q: &Tensor,
+        k: &Tensor,
+        v: &Tensor,
+        mask: Option<&Tensor>,
+        causal: bool,
+    ) -> Result<Tensor> {
+        let (b_size, _, seq_len, _) = q.dims4()?;
+        let mut config = self.config.attention;
+        config.causal |= causal;
+        let ys = if self.config.use_flash_attn && mask.is_none() && config.softcapping.is_none() {
+            let scale = config.scale.unwrap_or(1. / (self.head_dim as f64).sqrt());
+            let (q, k, v) = (q.transpose(1, 2)?, k.transpose(1, 2)?, v.transpose(1, 2)?);
+            crate::ops::flash_attn(&q, &k, &v, scale as f32, config.causal)?
+        } else {
+            scaled_dot_product_attention(q, k, v, mask, &config)?.transpose(1, 2)?
+        };
+        let ys = ys.reshape((b_size, seq_len, self.num_heads * self.head_dim))?;
+        self.out_proj.forward(&ys)
+    }
+
+    /// The attention of `query` over `key` and `value`. The rotary embeddings, when enabled, use
+    /// the positions starting at 0 for both the queries and the keys.
+    pub fn forward(
+        &self,
+        query: &Tensor,
+        key: &Tensor,
+        value: &Tensor,
+        mask: Option<&Tensor>,
+    ) -> Result<Tensor> {
+        let q = self.apply_rope(self.project(&self.q_proj, query, self.num_heads)?, 0)?;
+        let k = self.apply_rope(self.project(&self.k_proj, key, self.num_kv_heads)?, 0)?;
+        let v = self.project(&self.v_proj, value, self.num_kv_heads)?;
+        self.attend(&q, &k, &v, mask, false)
+    }
+
+    /// The causal self-attention of `xs` over the positions of the previous calls and its own
+    /// positions, the keys and values of `xs` being added to the kv cache. The optional `mask`
+    /// applies on top of the causal mask, its last dimension has to cover all the positions of the
+    /// cache, e.g. a [`key_padding_mask`] of the whole sequences.
+    pub fn forward_cached(&mut self, xs: &Tensor, mask: Option<&Tensor>) -> Result<Tensor> {
+        let offset = self.kv_cache.current_seq_len();
+        let q = self.apply_rope(self.project(&self.q_proj, xs, self.num_heads)?, offset)?;
+        let k = self.apply_rope(self.project(&self.k_proj, xs, self.num_kv_heads)?, offset)?;
+        let v = self.project(&self.v_proj, xs, self.num_kv_heads)?;
+        let (k, v) = self.kv_cache.append(&k, &v)?;
+        self.attend(&q, &k, &v, mask, true)
+    }
+}
+
+impl crate::layer::Layer for MultiheadAttention {
+    fn visit_children(&mut self, f: &mut crate::layer::LayerFn) -> Result<()> {
+        f("q_proj", &mut self.q_proj)?;
+        f("k_proj", &mut self.k_proj)?;
+        f("v_proj", &mut self.v_proj)?;
+        f("out_proj", &mut self.out_proj)
+    }
+}
@@ -0,0 +1,57 @@
+//! Autocast, i.e. mixed precision layers.
+//!
+//! The layers whose weights use another dtype than their inputs, e.g. the f32 norms of a f16 model
+//! loaded with a [`crate::var_builder::DTypePolicy`], return an error by default. When autocast is
+//! enabled on the current thread, the [`crate::Linear`], [`crate::LayerNorm`] and
+//! [`crate::RmsNorm`] layers instead cast their inputs to the dtype of their weights and return
+//! a result in the dtype of the inputs.
+//!
+//! ```rust
+//! use candle::{DType, Device, Tensor};
+//! use candle_nn::{Linear, Module};
+//! # fn main() -> candle::Result<()> {
+//! let layer = Linear::new(Tensor::ones((3, 2), DType::F32, &Device::Cpu)?, None);
+//! let xs = Tensor::ones((1, 2), DType::F16, &Device::Cpu)?;
+//! assert!(layer.forward(&xs).is_err());
+//! let _guard = candle_nn::autocast::enable();
+//! assert_eq!(layer.forward(&xs)?.dtype(), DType::F16);
+//! # Ok(()) }
+//! ```
+use std::cell::Cell;
+
+thread_local! {
+    static ENABLED: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Restores the previous autocast state of the thread when dropped.
+#[derive(Debug)]
+#[must_use]
+pub struct AutocastGuard {
+    previous: bool,
+}
+
+impl Drop for AutocastGuard {
+    fn drop(&mut self) {
+        ENABLED.with(|e| e.set(self.previous))
+    }
+}
+
+/// Enables autocast on the current thread until the returned guard is dropped.
+pub fn enable() -> AutocastGuard {
+    set(true)
+}
+
+/// Disables autocast on the current thread until the returned guard is dropped.
+pub fn disable() -> AutocastGuard {
+    set(false)
+}
+
+fn set(enabled: bool) -> AutocastGuard {
+    let previous = ENABLED.with(|e| e.replace(enabled));
+    AutocastGuard { previous }
+}
+
+/// Whether autocast is enabled on the current thread.
+pub fn is_enabled() -> bool {
+    ENABLED.with(|e| e.get())
+}
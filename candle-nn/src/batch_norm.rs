@@ -52,6 +52,8 @@ pub struct BatchNorm {
     remove_mean: bool,
     eps: f64,
     momentum: f64,
+    // The mode used by [`crate::layer::Layer::forward_mode`].
+    training: bool,
 }
 
 impl BatchNorm {
@@ -109,6 +111,7 @@ impl BatchNorm {
             remove_mean: true,
             eps,
             momentum: 0.1,
+            training: false,
         };
         out.check_validity(num_features)?;
         Ok(out)
@@ -127,6 +130,7 @@ impl BatchNorm {
             remove_mean: true,
             eps,
             momentum: 0.1,
+            training: false,
         };
         out.check_validity(num_features)?;
         Ok(out)
@@ -148,6 +152,7 @@ impl BatchNorm {
             remove_mean: true,
             eps,
             momentum,
+            training: false,
         };
         out.check_validity(num_features)?;
         Ok(out)
@@ -167,6 +172,7 @@ impl BatchNorm {
             remove_mean: true,
             eps,
             momentum,
+            training: false,
         };
         out.check_validity(num_features)?;
         Ok(out)
@@ -298,6 +304,59 @@ impl crate::ModuleT for BatchNorm {
     }
 }
 
+impl crate::migrate::Migrate for BatchNorm {
+    fn visit_tensors(&mut self, f: &mut dyn FnMut(&mut Tensor) -> Result<()>) -> Result<()> {
+        // The running statistics are buffers, they are kept as variables so that they can still
+        // be updated in place.
+        for stat in [&mut self.running_mean, &mut self.running_var] {
+            let mut t = stat.as_tensor().clone();
+            f(&mut t)?;
+            *stat = Var::from_tensor(&t)?;
+        }
+        match self.weight_and_bias.as_mut() {
+            None => Ok(()),
+            Some((weight, bias)) => {
+                f(weight)?;
+                f(bias)
+            }
+        }
+    }
+}
+
+impl crate::layer::Layer for BatchNorm {
+    fn visit_parameters(&mut self, f: &mut crate::layer::TensorFn) -> Result<()> {
+        match self.weight_and_bias.as_mut() {
+            None => Ok(()),
+            Some((weight, bias)) => {
+                f("weight", weight)?;
+                f("bias", bias)
+            }
+        }
+    }
+
+    fn visit_buffers(&mut self, f: &mut crate::layer::TensorFn) -> Result<()> {
+        for (name, stat) in [
+            ("running_mean", &mut self.running_mean),
+            ("running_var", &mut self.running_var),
+        ] {
+            let mut t = stat.as_tensor().clone();
+            f(name, &mut t)?;
+            if t.id() != stat.as_tensor().id() {
+                *stat = Var::from_tensor(&t)?
+            }
+        }
+        Ok(())
+    }
+
+    fn set_own_training(&mut self, training: bool) {
+        self.training = training
+    }
+
+    fn is_training(&self) -> Option<bool> {
+        Some(self.training)
+    }
+}
+
 pub fn batch_norm<C: Into<BatchNormConfig>>(
     num_features: usize,
     config: C,
@@ -308,8 +367,8 @@ pub fn batch_norm<C: Into<BatchNormConfig>>(
     if config.eps < 0. {
         candle::bail!("batch-norm eps cannot be negative {}", config.eps)
     }
-    let running_mean = vb.get_with_hints(num_features, "running_mean", Init::Const(0.))?;
-    let running_var = vb.get_with_hints(num_features, "running_var", Init::Const(1.))?;
+    let running_mean = vb.get_buffer_with_hints(num_features, "running_mean", Init::Const(0.))?;
+    let running_var = vb.get_buffer_with_hints(num_features, "running_var", Init::Const(1.))?;
     let weight_and_bias = if config.affine {
         let weight = vb.get_with_hints(num_features, "weight", Init::Const(1.))?;
         let bias = vb.get_with_hints(num_features, "bias", Init::Const(0.))?;
@@ -324,5 +383,6 @@ pub fn batch_norm<C: Into<BatchNormConfig>>(
         remove_mean: config.remove_mean,
         eps: config.eps,
         momentum: config.momentum,
+        training: false,
     })
 }
@@ -0,0 +1,219 @@
+//! Training checkpoints that can be resumed exactly.
+//!
+//! A checkpoint is a single safetensors file holding the variables and buffers of a [`VarMap`]
+//! and the state of an optimizer, e.g. the AdamW moments. The json metadata of the file holds
+//! the scalars of the optimizer and a [`TrainingState`] with the number of steps, the position
+//! of the learning rate scheduler and the states of the random number generators, so that
+//! dropout and data shuffling go on with the same random values as an uninterrupted run.
+//!
+//! ```ignore
+//! let state = TrainingState::new(step).with_scheduler(&scheduler).with_rng(&[&device])?;
+//! checkpoint::save("checkpoint.safetensors", &varmap, &opt, &state)?;
+//!
+//! // When resuming.
+//! let state = checkpoint::load("checkpoint.safetensors", &varmap, &mut opt)?;
+//! state.restore_scheduler(&mut scheduler, &mut opt)?;
+//! state.restore_rng(&[&device])?;
+//! ```
+use crate::lr_scheduler::{LrSchedule, LrScheduler};
+use crate::{Optimizer, OptimizerState, VarMap};
+use candle::rng::RngState;
+use candle::{Device, Result, Tensor};
+use std::collections::{BTreeMap, HashMap};
+use std::path::Path;
+
+// The safetensors metadata key holding the json part of the checkpoint.
+const METADATA_KEY: &str = "checkpoint";
+const WEIGHTS_PREFIX: &str = "weights.";
+const OPTIMIZER_PREFIX: &str = "optimizer.";
+
+/// The progress of a training run, saved with the weights and the optimizer state.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TrainingState {
+    /// The number of optimizer steps done.
+    pub step: usize,
+    /// The number of epochs done.
+    pub epoch: usize,
+    /// The position of the learning rate scheduler.
+    pub scheduler: Option<usize>,
+    /// The states of the random number generators of some devices.
+    pub rng: Vec<RngState>,
+    /// Other values to save, e.g. the best validation loss.
+    pub scalars: BTreeMap<String, f64>,
+}
+
+impl TrainingState {
+    pub fn new(step: usize) -> Self {
+        Self {
+            step,
+            ..Default::default()
+        }
+    }
+
+    pub fn with_epoch(mut self, epoch: usize) -> Self {
+        self.epoch = epoch;
+        self
+    }
+
+    pub fn with_scheduler<S: LrSchedule>(mut self, scheduler: &LrScheduler<S>) -> Self {
+        self.scheduler = Some(scheduler.position());
+        self
+    }
+
+    /// Records the states of the generators of `devices`, in this order.
+    pub fn with_rng(mut self, devices: &[&Device]) -> Result<Self> {
+        self.rng = devices
+            .iter()
+            .map(|d| d.rng_state())
+            .collect::<Result<Vec<_>>>()?;
+        Ok(self)
+    }
+
+    pub fn with_scalar<S: Into<String>>(mut self, name: S, value: f64) -> Self {
+        self.scalars.insert(name.into(), value);
+        self
+    }
+
+    /// Moves the scheduler to its saved position and sets the learning rate of `opt`
+    /// accordingly.
+    pub fn restore_scheduler<S: LrSchedule, O: Optimizer>(
+        &self,
+        scheduler: &mut LrScheduler<S>,
+        opt: &mut O,
+    ) -> Result<()> {
+        match self.scheduler {
+            Some(position) => scheduler.set_position(position),
+            None => candle::bail!("the checkpoint has no scheduler position"),
+        }
+        scheduler.apply(opt);
+        Ok(())
+    }
+
+    /// Restores the generator states of `devices`, these have to be passed in the same order as
+    /// to [`Self::with_rng`].
+    pub fn restore_rng(&self, devices: &[&Device]) -> Result<()> {
+        if devices.len() != self.rng.len() {
+            candle::bail!(
+                "the checkpoint has {} generator states but {} devices were given",
+                self.rng.len(),
+                devices.len()
+            )
+        }
+        for (device, state) in devices.iter().zip(self.rng.iter()) {
+            device.set_rng_state(*state)?
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+struct Metadata {
+    step: usize,
+    epoch: usize,
+    scheduler: Option<usize>,
+    /// The seed and offset of each generator.
+    rng: Vec<(u64, u64)>,
+    scalars: BTreeMap<String, f64>,
+    optimizer_vars: usize,
+    optimizer_scalars: BTreeMap<String, f64>,
+}
+
+/// Saves the variables and buffers of `varmap`, the state of `optimizer` and `state` in a single
+/// safetensors file.
+pub fn save<P: AsRef<Path>, O: Optimizer>(
+    path: P,
+    varmap: &VarMap,
+    optimizer: &O,
+    state: &TrainingState,
+) -> Result<()> {
+    let mut tensors: BTreeMap<String, Tensor> = BTreeMap::new();
+    for (name, var) in varmap.data().lock().unwrap().iter() {
+        tensors.insert(format!("{WEIGHTS_PREFIX}{name}"), var.as_tensor().clone());
+    }
+    let opt_state = optimizer.state()?;
+    for (i, vars) in opt_state.vars.iter().enumerate() {
+        for (name, t) in vars.iter() {
+            tensors.insert(format!("{OPTIMIZER_PREFIX}{i}.{name}"), t.clone());
+        }
+    }
+    let metadata = Metadata {
+        step: state.step,
+        epoch: state.epoch,
+        scheduler: state.scheduler,
+        rng: state.rng.iter().map(|s| (s.seed, s.offset)).collect(),
+        scalars: state.scalars.clone(),
+        optimizer_vars: opt_state.vars.len(),
+        optimizer_scalars: opt_state.scalars.into_iter().collect(),
+    };
+    let metadata = serde_json::to_string(&metadata).map_err(candle::Error::wrap)?;
+    let metadata = HashMap::from([(METADATA_KEY.to_string(), metadata)]);
+    safetensors::tensor::serialize_to_file(tensors.iter(), &Some(metadata), path.as_ref())?;
+    Ok(())
+}
+
+/// Loads a checkpoint saved by [`save`], the variables of `varmap` and the state of `optimizer`
+/// are set to their saved values. The scheduler and generator states are not applied, use
+/// [`TrainingState::restore_scheduler`] and [`TrainingState::restore_rng`].
+///
+/// The state of the optimizer is restored by position, the optimizer has to be created with the
+/// variables in the same order as the saved one, e.g. using [`VarMap::all_vars`]. An error is
+/// returned if one of the variables of `varmap` is missing from the checkpoint, in which case
+/// some variables might have already been set.
+pub fn load<P: AsRef<Path>, O: Optimizer>(
+    path: P,
+    varmap: &VarMap,
+    optimizer: &mut O,
+) -> Result<TrainingState> {
+    let path = path.as_ref();
+    let buffer = std::fs::read(path)?;
+    let (_, st_metadata) = safetensors::SafeTensors::read_metadata(&buffer)?;
+    let metadata = st_metadata
+        .metadata()
+        .as_ref()
+        .and_then(|m| m.get(METADATA_KEY));
+    let metadata: Metadata = match metadata {
+        Some(m) => serde_json::from_str(m).map_err(candle::Error::wrap)?,
+        None => candle::bail!("{path:?} is not a training checkpoint"),
+    };
+    let st = candle::safetensors::SliceSafetensors::new(&buffer)?;
+    for (name, var) in varmap.data().lock().unwrap().iter() {
+        let value = match st.load(&format!("{WEIGHTS_PREFIX}{name}"), &Device::Cpu) {
+            Ok(value) => value,
+            Err(_) => candle::bail!("cannot find {name} in {path:?}"),
+        };
+        if let Err(err) = var.set(&value.to_device(var.device())?) {
+            candle::bail!("error setting {name} using data from {path:?}: {err}")
+        }
+    }
+    let mut vars = vec![HashMap::new(); metadata.optimizer_vars];
+    for (name, _) in st.tensors() {
+        let Some(key) = name.strip_prefix(OPTIMIZER_PREFIX) else {
+            continue;
+        };
+        let index_and_key = key
+            .split_once('.')
+            .and_then(|(i, key)| Some((i.parse::<usize>().ok()?, key)));
+        match index_and_key {
+            Some((i, key)) if i < vars.len() => {
+                vars[i].insert(key.to_string(), st.load(&name, &Device::Cpu)?);
+            }
+            _ => candle::bail!("invalid optimizer tensor {name} in {path:?}"),
+        }
+    }
+    let opt_state = OptimizerState {
+        vars,
+        scalars: metadata.optimizer_scalars.into_iter().collect(),
+    };
+    optimizer.set_state(&opt_state)?;
+    Ok(TrainingState {
+        step: metadata.step,
+        epoch: metadata.epoch,
+        scheduler: metadata.scheduler,
+        rng: metadata
+            .rng
+            .into_iter()
+            .map(|(seed, offset)| RngState { seed, offset })
+            .collect(),
+        scalars: metadata.scalars,
+    })
+}
@@ -0,0 +1,303 @@
+//! Compressed checkpoints, a variant of the safetensors format with zstd compressed tensors.
+//!
+//! The file starts with a magic, then the length of a json header as a little endian u64, the
+//! header and the tensor data. The header gives the dtype, shape and data offsets of each tensor
+//! as in safetensors, together with the transforms applied to its bytes:
+//!
+//! - The bytes of the float tensors can be transposed, i.e. the first bytes of all the elements
+//!   are stored first, then the second bytes, and so on. The sign and exponent bytes of the
+//!   weights of a model vary little so this usually improves the compression ratio.
+//! - The tensors of a fine-tune can be stored as a delta against the weights of a base model,
+//!   the delta is the xor of the bytes of the two tensors. This is lossless and the delta is
+//!   mostly made of zeros when the weights only changed slightly, the tensors that did not change
+//!   at all are not stored. The base weights are needed to load such a checkpoint.
+//! - The bytes are then compressed with zstd, tensors that do not compress are stored as is.
+//!
+//! ```ignore
+//! let config = CompressionConfig::default();
+//! compressed_checkpoint::save_delta(&finetuned, &base, "finetune.ctz", &config)?;
+//! let finetuned = compressed_checkpoint::load_delta("finetune.ctz", &base, &Device::Cpu)?;
+//! ```
+use candle::{DType, Device, Result, Tensor};
+use std::collections::{BTreeMap, HashMap};
+use std::path::Path;
+
+const MAGIC: &[u8; 8] = b"CANDLEZ1";
+
+/// How the tensors are encoded when saving a checkpoint.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CompressionConfig {
+    /// The zstd compression level, from 1 to 22, the tensors are not compressed when `None`.
+    pub level: Option<i32>,
+    /// Whether to transpose the bytes of the float tensors.
+    pub shuffle: bool,
+}
+
+impl Default for CompressionConfig {
+    fn default() -> Self {
+        Self {
+            level: Some(3),
+            shuffle: true,
+        }
+    }
+}
+
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+struct Entry {
+    dtype: String,
+    shape: Vec<usize>,
+    data_offsets: (usize, usize),
+    #[serde(default)]
+    zstd: bool,
+    #[serde(default)]
+    shuffle: bool,
+    /// The data is the xor of the tensor and of the base tensor with the same name.
+    #[serde(default)]
+    delta: bool,
+    /// The tensor is equal to the base tensor with the same name, no data is stored.
+    #[serde(default)]
+    unchanged: bool,
+}
+
+#[derive(Debug, Default, serde::Serialize, serde::Deserialize)]
+struct Header {
+    tensors: BTreeMap<String, Entry>,
+}
+
+fn to_bytes(tensor: &Tensor) -> Result<Vec<u8>> {
+    let tensor = tensor.to_device(&Device::Cpu)?;
+    Ok(safetensors::View::data(&tensor).into_owned())
+}
+
+// Groups the `i`-th bytes of all the elements together.
+fn shuffle(data: &[u8], size: usize) -> Vec<u8> {
+    let n = data.len() / size;
+    let mut out = vec![0u8; data.len()];
+    for (i, element) in data.chunks_exact(size).enumerate() {
+        for (j, &byte) in element.iter().enumerate() {
+            out[j * n + i] = byte
+        }
+    }
+    out
+}
+
+fn unshuffle(data: &[u8], size: usize) -> Vec<u8> {
+    let n = data.len() / size;
+    let mut out = vec![0u8; data.len()];
+    for (i, element) in out.chunks_exact_mut(size).enumerate() {
+        for (j, byte) in element.iter_mut().enumerate() {
+            *byte = data[j * n + i]
+        }
+    }
+    out
+}
+
+fn xor(data: &mut [u8], base: &[u8]) {
+    data.iter_mut().zip(base.iter()).for_each(|(d, b)| *d ^= b)
+}
+
+fn encode(
+    name: &str,
+    tensor: &Tensor,
+    base: Option<&Tensor>,
+    config: &CompressionConfig,
+    data: &mut Vec<u8>,
+) -> Result<Entry> {
+    let dtype = tensor.dtype();
+    let mut bytes = to_bytes(tensor)?;
+    let base = base.filter(|b| b.dtype() == dtype && b.dims() == tensor.dims());
+    let mut entry = Entry {
+        dtype: dtype.as_str().to_string(),
+        shape: tensor.dims().to_vec(),
+        data_offsets: (data.len(), data.len()),
+        zstd: false,
+        shuffle: false,
+        delta: false,
+        unchanged: false,
+    };
+    if let Some(base) = base {
+        let base = to_bytes(base)?;
+        if bytes == base {
+            entry.unchanged = true;
+            return Ok(entry);
+        }
+        xor(&mut bytes, &base);
+        entry.delta = true;
+    }
+    if config.shuffle && dtype.is_float() && dtype.size_in_bytes() > 1 {
+        bytes = shuffle(&bytes, dtype.size_in_bytes());
+        entry.shuffle = true;
+    }
+    if let Some(level) = config.level {
+        let compressed = zstd::bulk::compress(&bytes, level)
+            .map_err(|e| candle::Error::Msg(format!("cannot compress {name}: {e}")))?;
+        if compressed.len() < bytes.len() {
+            bytes = compressed;
+            entry.zstd = true;
+        }
+    }
+    data.extend_from_slice(&bytes);
+    entry.data_offsets.1 = data.len();
+    Ok(entry)
+}
+
+fn write<P: AsRef<Path>>(
+    tensors: &HashMap<String, Tensor>,
+    base: Option<&HashMap<String, Tensor>>,
+    path: P,
+    config: &CompressionConfig,
+) -> Result<()> {
+    let mut names = tensors.keys().collect::<Vec<_>>();
+    names.sort();
+    let mut header = Header::default();
+    let mut data = vec![];
+    for name in names {
+        let base = base.and_then(|b| b.get(name));
+        let entry = encode(name, &tensors[name], base, config, &mut data)?;
+        header.tensors.insert(name.clone(), entry);
+    }
+    let header = serde_json::to_vec(&header).map_err(candle::Error::wrap)?;
+    let mut out = Vec::with_capacity(MAGIC.len() + 8 + header.len() + data.len());
+    out.extend_from_slice(MAGIC);
+    out.extend_from_slice(&(header.len() as u64).to_le_bytes());
+    out.extend_from_slice(&header);
+    out.extend_from_slice(&data);
+    std::fs::write(path, out)?;
+    Ok(())
+}
+
+/// Saves the tensors in a compressed checkpoint.
+pub fn save<P: AsRef<Path>>(
+    tensors: &HashMap<String, Tensor>,
+    path: P,
+    config: &CompressionConfig,
+) -> Result<()> {
+    write(tensors, None, path, config)
+}
+
+/// Saves the tensors as a delta against `base`, the tensors that are missing from `base` or that
+/// have a different dtype or shape are saved in full.
+pub fn save_delta<P: AsRef<Path>>(
+    tensors: &HashMap<String, Tensor>,
+    base: &HashMap<String, Tensor>,
+    path: P,
+    config: &CompressionConfig,
+) -> Result<()> {
+    write(tensors, Some(base), path, config)
+}
+
+/// Whether the file at `path` is a compressed checkpoint.
+pub fn is_compressed<P: AsRef<Path>>(path: P) -> Result<bool> {
+    use std::io::Read;
+    let mut magic = [0u8; 8];
+    let mut file = std::fs::File::open(path)?;
+    match file.read_exact(&mut magic) {
+        Ok(()) => Ok(&magic == MAGIC),
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => Ok(false),
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn decode(
+    name: &str,
+    entry: &Entry,
+    data: &[u8],
+    base: Option<&HashMap<String, Tensor>>,
+    device: &Device,
+) -> Result<Tensor> {
+    let dtype: DType = match entry.dtype.parse() {
+        Ok(dtype) => dtype,
+        Err(_) => candle::bail!("unsupported dtype {} for {name}", entry.dtype),
+    };
+    let base = if entry.delta || entry.unchanged {
+        let base = match base.and_then(|b| b.get(name)) {
+            Some(base) => base,
+            None => candle::bail!("{name} is stored as a delta, the base tensor is required"),
+        };
+        if base.dtype() != dtype || base.dims() != entry.shape {
+            candle::bail!(
+                "base mismatch for {name}: {:?} {:?} <> {dtype:?} {:?}",
+                base.dtype(),
+                base.dims(),
+                entry.shape
+            )
+        }
+        Some(base)
+    } else {
+        None
+    };
+    if let Some(base) = base.filter(|_| entry.unchanged) {
+        return base.to_device(device)?.copy();
+    }
+    let (start, end) = entry.data_offsets;
+    if start > end || end > data.len() {
+        candle::bail!("invalid data offsets for {name}: {start}..{end}")
+    }
+    let len = entry
+        .shape
+        .iter()
+        .try_fold(dtype.size_in_bytes(), |len, &d| len.checked_mul(d));
+    let len = match len {
+        Some(len) => len,
+        None => candle::bail!("invalid shape for {name}: {:?}", entry.shape),
+    };
+    let mut bytes = data[start..end].to_vec();
+    if entry.zstd {
+        bytes = zstd::bulk::decompress(&bytes, len)
+            .map_err(|e| candle::Error::Msg(format!("cannot decompress {name}: {e}")))?;
+    }
+    if bytes.len() != len {
+        candle::bail!("{name} has {} bytes, expected {len}", bytes.len())
+    }
+    if entry.shuffle {
+        bytes = unshuffle(&bytes, dtype.size_in_bytes());
+    }
+    if let Some(base) = base {
+        xor(&mut bytes, &to_bytes(base)?);
+    }
+    Tensor::from_raw_buffer(&bytes, dtype, &entry.shape, device)
+}
+
+fn read<P: AsRef<Path>>(
+    path: P,
+    base: Option<&HashMap<String, Tensor>>,
+    device: &Device,
+) -> Result<HashMap<String, Tensor>> {
+    let path = path.as_ref();
+    let buffer = std::fs::read(path)?;
+    if buffer.len() < 16 || &buffer[..8] != MAGIC {
+        candle::bail!("{path:?} is not a compressed checkpoint")
+    }
+    let header_len = u64::from_le_bytes(buffer[8..16].try_into().unwrap());
+    let header_end = match usize::try_from(header_len)
+        .ok()
+        .and_then(|l| l.checked_add(16))
+    {
+        Some(end) if end <= buffer.len() => end,
+        _ => candle::bail!("invalid header length {header_len} in {path:?}"),
+    };
+    let header: Header =
+        serde_json::from_slice(&buffer[16..header_end]).map_err(candle::Error::wrap)?;
+    let data = &buffer[header_end..];
+    header
+        .tensors
+        .iter()
+        .map(|(name, entry)| Ok((name.clone(), decode(name, entry, data, base, device)?)))
+        .collect()
+}
+
+/// Loads a compressed checkpoint, an error is returned if some of the tensors were saved as a
+/// delta, see [`load_delta`].
+pub fn load<P: AsRef<Path>>(path: P, device: &Device) -> Result<HashMap<String, Tensor>> {
+    read(path, None, device)
+}
+
+/// Loads a compressed checkpoint saved with [`save_delta`], `base` has to contain the same base
+/// tensors that were used when saving.
+pub fn load_delta<P: AsRef<Path>>(
+    path: P,
+    base: &HashMap<String, Tensor>,
+    device: &Device,
+) -> Result<HashMap<String, Tensor>> {
+    read(path, Some(base), device)
+}
@@ -70,6 +70,26 @@ impl crate::Module for Conv1d {
     }
 }
 
+impl crate::migrate::Migrate for Conv1d {
+    fn visit_tensors(&mut self, f: &mut dyn FnMut(&mut Tensor) -> Result<()>) -> Result<()> {
+        f(&mut self.weight)?;
+        match self.bias.as_mut() {
+            None => Ok(()),
+            Some(bias) => f(bias),
+        }
+    }
+}
+
+impl crate::layer::Layer for Conv1d {
+    fn visit_parameters(&mut self, f: &mut crate::layer::TensorFn) -> Result<()> {
+        f("weight", &mut self.weight)?;
+        match self.bias.as_mut() {
+            None => Ok(()),
+            Some(bias) => f("bias", bias),
+        }
+    }
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct ConvTranspose1dConfig {
     pub padding: usize,
@@ -141,6 +161,26 @@ impl crate::Module for ConvTranspose1d {
     }
 }
 
+impl crate::migrate::Migrate for ConvTranspose1d {
+    fn visit_tensors(&mut self, f: &mut dyn FnMut(&mut Tensor) -> Result<()>) -> Result<()> {
+        f(&mut self.weight)?;
+        match self.bias.as_mut() {
+            None => Ok(()),
+            Some(bias) => f(bias),
+        }
+    }
+}
+
+impl crate::layer::Layer for ConvTranspose1d {
+    fn visit_parameters(&mut self, f: &mut crate::layer::TensorFn) -> Result<()> {
+        f("weight", &mut self.weight)?;
+        match self.bias.as_mut() {
+            None => Ok(()),
+            Some(bias) => f("bias", bias),
+        }
+    }
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct Conv2dConfig {
     pub padding: usize,
@@ -229,6 +269,26 @@ impl crate::Module for Conv2d {
     }
 }
 
+impl crate::migrate::Migrate for Conv2d {
+    fn visit_tensors(&mut self, f: &mut dyn FnMut(&mut Tensor) -> Result<()>) -> Result<()> {
+        f(&mut self.weight)?;
+        match self.bias.as_mut() {
+            None => Ok(()),
+            Some(bias) => f(bias),
+        }
+    }
+}
+
+impl crate::layer::Layer for Conv2d {
+    fn visit_parameters(&mut self, f: &mut crate::layer::TensorFn) -> Result<()> {
+        f("weight", &mut self.weight)?;
+        match self.bias.as_mut() {
+            None => Ok(()),
+            Some(bias) => f("bias", bias),
+        }
+    }
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct ConvTranspose2dConfig {
     pub padding: usize,
@@ -298,6 +358,26 @@ impl crate::Module for ConvTranspose2d {
     }
 }
 
+impl crate::migrate::Migrate for ConvTranspose2d {
+    fn visit_tensors(&mut self, f: &mut dyn FnMut(&mut Tensor) -> Result<()>) -> Result<()> {
+        f(&mut self.weight)?;
+        match self.bias.as_mut() {
+            None => Ok(()),
+            Some(bias) => f(bias),
+        }
+    }
+}
+
+impl crate::layer::Layer for ConvTranspose2d {
+    fn visit_parameters(&mut self, f: &mut crate::layer::TensorFn) -> Result<()> {
+        f("weight", &mut self.weight)?;
+        match self.bias.as_mut() {
+            None => Ok(()),
+            Some(bias) => f("bias", bias),
+        }
+    }
+}
+
 pub fn conv1d(
     in_channels: usize,
     out_channels: usize,
@@ -0,0 +1,263 @@
+//! Per-device caches of the rotary embedding tables and of the causal masks.
+//!
+//! These tensors only depend on the hyper-parameters of a model and are the same for all the
+//! models using these hyper-parameters, the functions of this module compute them once per
+//! device and return the same tensors to all the callers, e.g. to the models of a server
+//! hosting several models or several replicas of a model on a device.
+//!
+//! The tensors are computed for `max_len` positions and the callers narrow them to the positions
+//! they need:
+//!
+//! ```ignore
+//! let (cos, sin) = device_cache::rope_tables(head_dim, 10000., max_len, None, DType::F16, &dev)?;
+//! let cos = cos.narrow(0, index_pos, seq_len)?;
+//! let mask = device_cache::causal_mask(max_len, &dev)?;
+//! let mask = mask.narrow(0, 0, seq_len)?.narrow(1, 0, seq_len)?;
+//! ```
+//!
+//! The decoders that add the mask to the attention scores use [`causal_bias`] which is sized to
+//! the current sequence rather than to the maximum length.
+//!
+//! The cached tensors are kept until [`clear`] or [`clear_device`] is called. The tables with
+//! dynamic NTK scaling depend on the sequence length and are not cached, see
+//! [`crate::rotary_emb::rope_tables`].
+use crate::rotary_emb::RopeScaling;
+use candle::{DType, Device, Result, Tensor};
+use std::collections::HashMap;
+use std::sync::{Mutex, OnceLock};
+
+// The parameters of a `RopeScaling` with the f64 values stored as bits.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum ScalingKey {
+    Llama3 {
+        factor: u64,
+        low_freq_factor: u64,
+        high_freq_factor: u64,
+        original_max_position_embeddings: usize,
+    },
+    Yarn {
+        factor: u64,
+        original_max_position_embeddings: usize,
+        beta_fast: u64,
+        beta_slow: u64,
+        attention_factor: u64,
+    },
+}
+
+impl From<RopeScaling> for ScalingKey {
+    fn from(scaling: RopeScaling) -> Self {
+        match scaling {
+            RopeScaling::Llama3 {
+                factor,
+                low_freq_factor,
+                high_freq_factor,
+                original_max_position_embeddings,
+            } => Self::Llama3 {
+                factor: factor.to_bits(),
+                low_freq_factor: low_freq_factor.to_bits(),
+                high_freq_factor: high_freq_factor.to_bits(),
+                original_max_position_embeddings,
+            },
+            RopeScaling::Yarn {
+                factor,
+                original_max_position_embeddings,
+                beta_fast,
+                beta_slow,
+                attention_factor,
+            } => Self::Yarn {
+                factor: factor.to_bits(),
+                original_max_position_embeddings,
+                beta_fast: beta_fast.to_bits(),
+                beta_slow: beta_slow.to_bits(),
+                attention_factor: attention_factor.to_bits(),
+            },
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Key {
+    Rope {
+        rot_dim: usize,
+        // The bits of the f64 base.
+        base: u64,
+        max_len: usize,
+        scaling: Option<ScalingKey>,
+        dtype: DType,
+    },
+    CausalMask {
+        max_len: usize,
+    },
+    CausalBias {
+        len: usize,
+        sliding_window: Option<usize>,
+        dtype: DType,
+    },
+}
+
+#[derive(Debug, Clone)]
+enum Entry {
+    Rope(Tensor, Tensor),
+    Mask(Tensor),
+}
+
+impl Entry {
+    fn tensors(&self) -> Vec<&Tensor> {
+        match self {
+            Self::Rope(cos, sin) => vec![cos, sin],
+            Self::Mask(mask) => vec![mask],
+        }
+    }
+}
+
+// The entries of each device. Several `Device` values can share a location, e.g. two cuda
+// devices created on the same gpu, and the tensors of one cannot be used with the other so the
+// devices are compared with `same_device`.
+type Entries = Vec<(Device, HashMap<Key, Entry>)>;
+
+fn entries() -> &'static Mutex<Entries> {
+    static ENTRIES: OnceLock<Mutex<Entries>> = OnceLock::new();
+    ENTRIES.get_or_init(|| Mutex::new(vec![]))
+}
+
+// Returns the entry for `key` on `dev`, computing it with `f` if needed. The lock is not held
+// while computing the entry so that `f` can use the cache, two threads may compute the same
+// entry in which case the first one inserted is kept.
+fn get_or_insert<F>(dev: &Device, key: Key, f: F) -> Result<Entry>
+where
+    F: FnOnce() -> Result<Entry>,
+{
+    let find = |entries: &Entries| {
+        entries
+            .iter()
+            .find(|(d, _)| d.same_device(dev))
+            .and_then(|(_, e)| e.get(&key).cloned())
+    };
+    if let Some(entry) = find(&entries().lock().unwrap()) {
+        return Ok(entry);
+    }
+    let entry = f()?;
+    let mut entries = entries().lock().unwrap();
+    let position = entries.iter().position(|(d, _)| d.same_device(dev));
+    let device_entries = match position {
+        Some(position) => &mut entries[position].1,
+        None => {
+            entries.push((dev.clone(), HashMap::new()));
+            &mut entries.last_mut().unwrap().1
+        }
+    };
+    Ok(device_entries.entry(key).or_insert(entry).clone())
+}
+
+/// The cos and sin tables of [`crate::rotary_emb::rope_tables`] for the positions `0..max_len`,
+/// with shape `(max_len, rot_dim / 2)`. With `scaling` the tables are the ones of
+/// [`crate::rotary_emb::scaled_rope_tables`].
+pub fn rope_tables(
+    rot_dim: usize,
+    base: f64,
+    max_len: usize,
+    scaling: Option<RopeScaling>,
+    dtype: DType,
+    dev: &Device,
+) -> Result<(Tensor, Tensor)> {
+    let key = Key::Rope {
+        rot_dim,
+        base: base.to_bits(),
+        max_len,
+        scaling: scaling.map(ScalingKey::from),
+        dtype,
+    };
+    let entry = get_or_insert(dev, key, || {
+        let (cos, sin) = match scaling {
+            None => crate::rotary_emb::rope_tables(rot_dim, base, max_len, None, dtype, dev)?,
+            Some(scaling) => {
+                crate::rotary_emb::scaled_rope_tables(rot_dim, base, max_len, scaling, dtype, dev)?
+            }
+        };
+        Ok(Entry::Rope(cos, sin))
+    })?;
+    match entry {
+        Entry::Rope(cos, sin) => Ok((cos, sin)),
+        Entry::Mask(_) => unreachable!(),
+    }
+}
+
+/// The causal mask of [`crate::attention::causal_mask`] with shape `(max_len, max_len)`, the mask
+/// for `seq_len` positions is its top left `(seq_len, seq_len)` block.
+pub fn causal_mask(max_len: usize, dev: &Device) -> Result<Tensor> {
+    let entry = get_or_insert(dev, Key::CausalMask { max_len }, || {
+        Ok(Entry::Mask(crate::attention::causal_mask(max_len, dev)?))
+    })?;
+    match entry {
+        Entry::Mask(mask) => Ok(mask),
+        Entry::Rope(..) => unreachable!(),
+    }
+}
+
+/// The additive causal mask for `seq_len` positions following `offset` positions that are
+/// already in the kv cache, with shape `(seq_len, offset + seq_len)`. The masked positions are
+/// set to `-inf` and the others to `0`. With a `sliding_window` the positions that are more than
+/// `sliding_window` positions before a query are masked too.
+///
+/// The mask is a view of a cached square mask whose size is rounded up to a power of two, so
+/// that only a few masks are cached however the sequence grows.
+pub fn causal_bias(
+    seq_len: usize,
+    offset: usize,
+    sliding_window: Option<usize>,
+    dtype: DType,
+    dev: &Device,
+) -> Result<Tensor> {
+    let total_len = offset + seq_len;
+    let len = total_len.next_power_of_two();
+    let key = Key::CausalBias {
+        len,
+        sliding_window,
+        dtype,
+    };
+    let entry = get_or_insert(dev, key, || {
+        let bias: Vec<_> = (0..len)
+            .flat_map(|i| {
+                (0..len).map(move |j| {
+                    if j > i || sliding_window.is_some_and(|w| j + w < i) {
+                        f32::NEG_INFINITY
+                    } else {
+                        0.
+                    }
+                })
+            })
+            .collect();
+        let bias = Tensor::from_vec(bias, (len, len), dev)?.to_dtype(dtype)?;
+        Ok(Entry::Mask(bias))
+    })?;
+    match entry {
+        Entry::Mask(bias) => bias.narrow(0, offset, seq_len)?.narrow(1, 0, total_len),
+        Entry::Rope(..) => unreachable!(),
+    }
+}
+
+/// The number of bytes used by the cached tensors of `dev`.
+pub fn memory_usage(dev: &Device) -> usize {
+    let entries = entries().lock().unwrap();
+    entries
+        .iter()
+        .filter(|(d, _)| d.same_device(dev))
+        .flat_map(|(_, e)| e.values())
+        .flat_map(|e| e.tensors())
+        .map(|t| t.elem_count() * t.dtype().size_in_bytes())
+        .sum()
+}
+
+/// Removes the cached tensors of all the devices. The tensors that are still used by models are
+/// only freed once these models are dropped.
+pub fn clear() {
+    entries().lock().unwrap().clear()
+}
+
+/// Removes the cached tensors of `dev`.
+pub fn clear_device(dev: &Device) {
+    entries()
+        .lock()
+        .unwrap()
+        .retain(|(d, _)| !d.same_device(dev))
+}
@@ -0,0 +1,606 @@
+//! Collective communication between the ranks of a distributed training run.
+//!
+//! The [`Communicator`] trait provides the collectives used by the distributed training
+//! utilities such as [`crate::fsdp`]. [`ThreadGroup`] runs all the ranks as threads of the same
+//! process, which is mostly useful for testing, and `NcclCommunicator` uses nccl for multi-gpu
+//! training when the `nccl` feature is enabled.
+use candle::{Result, Tensor};
+use std::collections::HashMap;
+use std::sync::{Arc, Condvar, Mutex};
+
+/// The collectives are blocking: each rank has to call the same collectives in the same order.
+pub trait Communicator: Send + Sync {
+    fn rank(&self) -> usize;
+
+    fn world_size(&self) -> usize;
+
+    /// Sums `xs` over all the ranks.
+    fn all_reduce(&self, xs: &Tensor) -> Result<Tensor>;
+
+    /// Concatenates `xs` from all the ranks along the first dimension, in rank order.
+    fn all_gather(&self, xs: &Tensor) -> Result<Tensor>;
+
+    /// Sums `xs` over all the ranks and returns the chunk of this rank, the first dimension of
+    /// `xs` is split in `world_size` chunks of the same size.
+    fn reduce_scatter(&self, xs: &Tensor) -> Result<Tensor>;
+
+    /// Returns the value of `xs` on the `root` rank.
+    fn broadcast(&self, xs: &Tensor, root: usize) -> Result<Tensor>;
+
+    /// Sends `xs` to the next rank and receives the tensors sent by the previous one, the ranks
+    /// being arranged in a ring. The tensors must have the same shapes on all the ranks.
+    ///
+    /// The transfers may run asynchronously, the received tensors are returned by
+    /// [`PendingExchange::wait`]. The default implementation uses [`Self::all_gather`] and
+    /// completes before returning.
+    fn ring_exchange(&self, xs: &[&Tensor]) -> Result<PendingExchange> {
+        let world_size = self.world_size();
+        let prev = (self.rank() + world_size - 1) % world_size;
+        let received = xs
+            .iter()
+            .map(|xs| self.all_gather(&xs.unsqueeze(0)?)?.get(prev))
+            .collect::<Result<Vec<_>>>()?;
+        Ok(PendingExchange::ready(received))
+    }
+}
+
+/// The tensors being received by [`Communicator::ring_exchange`].
+pub struct PendingExchange {
+    wait: Box<dyn FnOnce() -> Result<Vec<Tensor>> + Send>,
+}
+
+impl PendingExchange {
+    /// An exchange completed by calling `wait`.
+    pub fn new<F: FnOnce() -> Result<Vec<Tensor>> + Send + 'static>(wait: F) -> Self {
+        Self {
+            wait: Box::new(wait),
+        }
+    }
+
+    /// An exchange that has already completed.
+    pub fn ready(tensors: Vec<Tensor>) -> Self {
+        Self::new(move || Ok(tensors))
+    }
+
+    /// Waits for the transfers to complete and returns the received tensors, in the order of
+    /// the sent ones.
+    pub fn wait(self) -> Result<Vec<Tensor>> {
+        (self.wait)()
+    }
+}
+
+// Flattens `t` and pads it with zeros so that it can be split in `world_size` chunks of the
+// same size.
+pub(crate) fn pad_flat(t: &Tensor, world_size: usize) -> Result<Tensor> {
+    let len = t.elem_count();
+    let padded_len = len.div_ceil(world_size) * world_size;
+    t.flatten_all()?.pad_with_zeros(0, 0, padded_len - len)
+}
+
+// A collective of the thread group, the tensors contributed by each rank.
+struct Round {
+    slots: Vec<Option<Vec<Tensor>>>,
+    arrived: usize,
+    // The number of ranks that have not collected the result yet.
+    unread: usize,
+}
+
+struct ExchangeState {
+    // The index of the next collective of each rank.
+    next: Vec<u64>,
+    rounds: HashMap<u64, Round>,
+}
+
+struct Exchange {
+    state: Mutex<ExchangeState>,
+    cvar: Condvar,
+}
+
+/// A communicator for one of the ranks of a [`ThreadGroup`].
+#[derive(Clone)]
+pub struct ThreadCommunicator {
+    rank: usize,
+    world_size: usize,
+    exchange: Arc<Exchange>,
+}
+
+/// A group of ranks running as threads of the same process, each rank uses its own
+/// communicator from a separate thread.
+pub struct ThreadGroup;
+
+impl ThreadGroup {
+    /// Returns the communicators for each of the `world_size` ranks.
+    #[allow(clippy::new_ret_no_self)]
+    pub fn new(world_size: usize) -> Vec<ThreadCommunicator> {
+        let exchange = Arc::new(Exchange {
+            state: Mutex::new(ExchangeState {
+                next: vec![0; world_size],
+                rounds: HashMap::new(),
+            }),
+            cvar: Condvar::new(),
+        });
+        (0..world_size)
+            .map(|rank| ThreadCommunicator {
+                rank,
+                world_size,
+                exchange: exchange.clone(),
+            })
+            .collect()
+    }
+}
+
+impl Exchange {
+    // Contributes the tensors of `rank` to its next collective and returns the index of this
+    // collective, this does not wait for the other ranks.
+    fn post(&self, rank: usize, xs: Vec<Tensor>) -> u64 {
+        let mut state = self.state.lock().unwrap();
+        let index = state.next[rank];
+        state.next[rank] += 1;
+        let world_size = state.next.len();
+        let round = state.rounds.entry(index).or_insert_with(|| Round {
+            slots: vec![None; world_size],
+            arrived: 0,
+            unread: world_size,
+        });
+        round.slots[rank] = Some(xs);
+        round.arrived += 1;
+        if round.arrived == world_size {
+            self.cvar.notify_all();
+        }
+        index
+    }
+
+    // Waits for all the ranks to contribute to the collective `index` and returns their
+    // tensors in rank order.
+    fn collect(&self, index: u64) -> Vec<Vec<Tensor>> {
+        let mut state = self.state.lock().unwrap();
+        let world_size = state.next.len();
+        while state.rounds[&index].arrived < world_size {
+            state = self.cvar.wait(state).unwrap();
+        }
+        let round = state.rounds.get_mut(&index).unwrap();
+        let tensors = round.slots.iter().flatten().cloned().collect();
+        round.unread -= 1;
+        if round.unread == 0 {
+            state.rounds.remove(&index);
+        }
+        tensors
+    }
+}
+
+impl ThreadCommunicator {
+    // Waits for all the ranks to contribute a tensor and returns them in rank order.
+    fn exchange(&self, xs: &Tensor) -> Vec<Tensor> {
+        let index = self.exchange.post(self.rank, vec![xs.clone()]);
+        let tensors = self.exchange.collect(index);
+        tensors.into_iter().flatten().collect()
+    }
+}
+
+impl Communicator for ThreadCommunicator {
+    fn rank(&self) -> usize {
+        self.rank
+    }
+
+    fn world_size(&self) -> usize {
+        self.world_size
+    }
+
+    fn all_reduce(&self, xs: &Tensor) -> Result<Tensor> {
+        let tensors = self.exchange(xs);
+        let mut sum = tensors[0].clone();
+        for t in tensors[1..].iter() {
+            sum = (sum + t)?
+        }
+        Ok(sum)
+    }
+
+    fn all_gather(&self, xs: &Tensor) -> Result<Tensor> {
+        let tensors = self.exchange(xs);
+        Tensor::cat(tensors.as_slice(), 0)
+    }
+
+    fn reduce_scatter(&self, xs: &Tensor) -> Result<Tensor> {
+        let len = xs.dim(0)?;
+        if len % self.world_size != 0 {
+            candle::bail!(
+                "reduce_scatter expects a first dimension divisible by {}, got {len}",
+                self.world_size
+            )
+        }
+        let sum = self.all_reduce(xs)?;
+        let chunk = len / self.world_size;
+        sum.narrow(0, self.rank * chunk, chunk)
+    }
+
+    fn broadcast(&self, xs: &Tensor, root: usize) -> Result<Tensor> {
+        if root >= self.world_size {
+            candle::bail!("broadcast root {root} out of range ({})", self.world_size)
+        }
+        let tensors = self.exchange(xs);
+        Ok(tensors[root].clone())
+    }
+
+    fn ring_exchange(&self, xs: &[&Tensor]) -> Result<PendingExchange> {
+        let xs = xs.iter().map(|&xs| xs.clone()).collect();
+        let index = self.exchange.post(self.rank, xs);
+        let exchange = self.exchange.clone();
+        let prev = (self.rank + self.world_size - 1) % self.world_size;
+        Ok(PendingExchange::new(move || {
+            Ok(exchange.collect(index).swap_remove(prev))
+        }))
+    }
+}
+
+#[cfg(feature = "nccl")]
+pub use nccl::NcclCommunicator;
+
+#[cfg(feature = "nccl")]
+mod nccl {
+    use super::PendingExchange;
+    use candle::backend::BackendStorage;
+    use candle::cuda_backend::cudarc::driver::result::{event, stream};
+    use candle::cuda_backend::cudarc::driver::{sys, CudaSlice, CudaView, DeviceRepr, DeviceSlice};
+    use candle::cuda_backend::cudarc::nccl::safe::{
+        group_end, group_start, Comm, Id, NcclType, ReduceOp,
+    };
+    use candle::cuda_backend::{CudaDType, WrapErr};
+    use candle::{
+        CpuStorage, CudaDevice, CudaStorage, CustomOp1, DType, Layout, Result, Shape, Storage,
+        Tensor,
+    };
+    use std::sync::Mutex;
+
+    /// A communicator using nccl, the tensors have to be on the cuda device of the communicator.
+    ///
+    /// The collectives run on the stream of the device of the nccl communicator and are ordered
+    /// with the kernels of the tensors through events. With a communicator created by
+    /// [`NcclCommunicator::from_rank`] this is a stream dedicated to the communications, so
+    /// that the transfers of [`super::Communicator::ring_exchange`] overlap with the kernels.
+    pub struct NcclCommunicator {
+        comm: Comm,
+    }
+
+    // SAFETY: the nccl communicator is only used from a single thread at a time, collectives
+    // on the same communicator must not be called concurrently.
+    unsafe impl Send for NcclCommunicator {}
+    unsafe impl Sync for NcclCommunicator {}
+
+    impl NcclCommunicator {
+        pub fn new(comm: Comm) -> Self {
+            Self { comm }
+        }
+
+        /// Creates the communicator of `rank` for the tensors of `device`, the collectives run
+        /// on a new stream of this device.
+        pub fn from_rank(
+            device: &CudaDevice,
+            rank: usize,
+            world_size: usize,
+            id: Id,
+        ) -> Result<Self> {
+            let stream_device =
+                candle::cuda_backend::cudarc::driver::CudaDevice::new_with_stream(device.ordinal())
+                    .w()?;
+            let comm = Comm::from_rank(stream_device, rank, world_size, id)
+                .map_err(candle::Error::debug)?;
+            Ok(Self::new(comm))
+        }
+
+        pub fn comm(&self) -> &Comm {
+            &self.comm
+        }
+    }
+
+    // An event marking the work queued on a stream.
+    struct Event(sys::CUevent);
+
+    // SAFETY: cuda events can be used from any thread.
+    unsafe impl Send for Event {}
+
+    impl Event {
+        fn record(stream: sys::CUstream) -> Result<Self> {
+            let event = Self(event::create(sys::CUevent_flags::CU_EVENT_DISABLE_TIMING).w()?);
+            // SAFETY: the event has just been created.
+            unsafe { event::record(event.0, stream) }.w()?;
+            Ok(event)
+        }
+
+        // Makes the work queued afterwards on `stream` wait for the recorded work.
+        fn wait(&self, stream: sys::CUstream) -> Result<()> {
+            let flags = sys::CUevent_wait_flags::CU_EVENT_WAIT_DEFAULT;
+            // SAFETY: the event is alive and has been recorded.
+            unsafe { stream::wait_event(stream, self.0, flags) }.w()
+        }
+    }
+
+    impl Drop for Event {
+        fn drop(&mut self) {
+            // SAFETY: the event is only destroyed here, the pending waits are not affected.
+            let _ = unsafe { event::destroy(self.0) };
+        }
+    }
+
+    #[derive(Clone, Copy)]
+    enum Collective {
+        AllReduce,
+        AllGather,
+        ReduceScatter,
+        Broadcast(usize),
+    }
+
+    struct NcclOp<'a> {
+        comm: &'a Comm,
+        collective: Collective,
+    }
+
+    impl NcclOp<'_> {
+        fn run<T: CudaDType + DeviceRepr + NcclType>(
+            &self,
+            s: &CudaStorage,
+            l: &Layout,
+        ) -> Result<(CudaSlice<T>, Shape)> {
+            let src = contiguous(s.as_cuda_slice::<T>()?, l)?;
+            let dev = s.device().clone();
+            let world_size = self.comm.world_size();
+            let dims = l.dims();
+            let err = candle::Error::debug;
+            let (dst_len, shape) = match self.collective {
+                Collective::AllReduce | Collective::Broadcast(_) => (src.len(), l.shape().clone()),
+                Collective::AllGather => {
+                    let mut dims = dims.to_vec();
+                    dims[0] *= world_size;
+                    (src.len() * world_size, Shape::from(dims))
+                }
+                Collective::ReduceScatter => {
+                    if dims[0] % world_size != 0 {
+                        candle::bail!(
+                            "reduce_scatter expects a first dimension divisible by {world_size}"
+                        )
+                    }
+                    let mut dims = dims.to_vec();
+                    dims[0] /= world_size;
+                    (src.len() / world_size, Shape::from(dims))
+                }
+            };
+            // SAFETY: Set later by running the collective.
+            let mut dst = unsafe { dev.alloc::<T>(dst_len) }.w()?;
+            let comm_stream = *self.comm.device().cu_stream();
+            Event::record(*dev.cu_stream())?.wait(comm_stream)?;
+            match self.collective {
+                Collective::AllReduce => self
+                    .comm
+                    .all_reduce(&src, &mut dst, &ReduceOp::Sum)
+                    .map_err(err)?,
+                Collective::AllGather => self.comm.all_gather(&src, &mut dst).map_err(err)?,
+                Collective::ReduceScatter => self
+                    .comm
+                    .reduce_scatter(&src, &mut dst, &ReduceOp::Sum)
+                    .map_err(err)?,
+                Collective::Broadcast(root) => self
+                    .comm
+                    .broadcast(&Some(src), &mut dst, root as i32)
+                    .map_err(err)?,
+            };
+            Event::record(comm_stream)?.wait(*dev.cu_stream())?;
+            Ok((dst, shape))
+        }
+    }
+
+    fn contiguous<'a, T>(s: &'a CudaSlice<T>, l: &Layout) -> Result<CudaView<'a, T>> {
+        match l.contiguous_offsets() {
+            Some((o1, o2)) => Ok(s.slice(o1..o2)),
+            None => candle::bail!("nccl collectives require contiguous tensors"),
+        }
+    }
+
+    impl CustomOp1 for NcclOp<'_> {
+        fn name(&self) -> &'static str {
+            "nccl"
+        }
+
+        fn cpu_fwd(&self, _: &CpuStorage, _: &Layout) -> Result<(CpuStorage, Shape)> {
+            candle::bail!("nccl collectives require cuda tensors")
+        }
+
+        fn cuda_fwd(&self, s: &CudaStorage, l: &Layout) -> Result<(CudaStorage, Shape)> {
+            use half::{bf16, f16};
+            let dev = s.device().clone();
+            macro_rules! run {
+                ($t:ty) => {{
+                    let (dst, shape) = self.run::<$t>(s, l)?;
+                    (CudaStorage::wrap_cuda_slice(dst, dev), shape)
+                }};
+            }
+            let res = match s.dtype() {
+                DType::U8 => run!(u8),
+                DType::U32 => run!(u32),
+                DType::I64 => run!(i64),
+                DType::BF16 => run!(bf16),
+                DType::F16 => run!(f16),
+                DType::F32 => run!(f32),
+                DType::F64 => run!(f64),
+            };
+            Ok(res)
+        }
+    }
+
+    // Sends the input and `others` to the next rank and receives the tensors of the previous
+    // rank in a single group, the received tensors are concatenated in the flat output.
+    struct RingOp<'a> {
+        comm: &'a Comm,
+        others: &'a [&'a Tensor],
+        // Recorded on the comm stream once the transfers have been queued.
+        done: Mutex<Option<Event>>,
+    }
+
+    impl RingOp<'_> {
+        fn run<T: CudaDType + DeviceRepr + NcclType>(
+            &self,
+            s: &CudaStorage,
+            l: &Layout,
+        ) -> Result<(CudaSlice<T>, Shape)> {
+            // The storages are kept locked until the transfers have been queued.
+            let others = self
+                .others
+                .iter()
+                .map(|t| t.storage_and_layout())
+                .collect::<Vec<_>>();
+            let mut srcs = vec![contiguous(s.as_cuda_slice::<T>()?, l)?];
+            for (storage, layout) in others.iter() {
+                match &**storage {
+                    Storage::Cuda(s) => srcs.push(contiguous(s.as_cuda_slice::<T>()?, layout)?),
+                    _ => candle::bail!("nccl collectives require cuda tensors"),
+                }
+            }
+            let len = srcs.iter().map(|s| s.len()).sum::<usize>();
+            let dev = s.device().clone();
+            // SAFETY: Set later by running the transfers.
+            let mut dst = unsafe { dev.alloc::<T>(len) }.w()?;
+            let comm_stream = *self.comm.device().cu_stream();
+            Event::record(*dev.cu_stream())?.wait(comm_stream)?;
+            let (rank, world_size) = (self.comm.rank(), self.comm.world_size());
+            let next = ((rank + 1) % world_size) as i32;
+            let prev = ((rank + world_size - 1) % world_size) as i32;
+            let err = candle::Error::debug;
+            // Grouping the sends and the receives avoids the deadlock of all the ranks sending
+            // first.
+            group_start().map_err(err)?;
+            let mut offset = 0;
+            for src in srcs.iter() {
+                self.comm.send(src, next).map_err(err)?;
+                let mut dst = dst.slice_mut(offset..offset + src.len());
+                self.comm.recv(&mut dst, prev).map_err(err)?;
+                offset += src.len();
+            }
+            group_end().map_err(err)?;
+            *self.done.lock().unwrap() = Some(Event::record(comm_stream)?);
+            Ok((dst, Shape::from(len)))
+        }
+    }
+
+    impl CustomOp1 for RingOp<'_> {
+        fn name(&self) -> &'static str {
+            "nccl-ring"
+        }
+
+        fn cpu_fwd(&self, _: &CpuStorage, _: &Layout) -> Result<(CpuStorage, Shape)> {
+            candle::bail!("nccl collectives require cuda tensors")
+        }
+
+        fn cuda_fwd(&self, s: &CudaStorage, l: &Layout) -> Result<(CudaStorage, Shape)> {
+            use half::{bf16, f16};
+            let dev = s.device().clone();
+            macro_rules! run {
+                ($t:ty) => {{
+                    let (dst, shape) = self.run::<$t>(s, l)?;
+                    (CudaStorage::wrap_cuda_slice(dst, dev), shape)
+                }};
+            }
+            let res = match s.dtype() {
+                DType::U8 => run!(u8),
+                DType::U32 => run!(u32),
+                DType::I64 => run!(i64),
+                DType::BF16 => run!(bf16),
+                DType::F16 => run!(f16),
+                DType::F32 => run!(f32),
+                DType::F64 => run!(f64),
+            };
+            Ok(res)
+        }
+    }
+
+    // The transfers of a ring exchange, the kernels queued on the device stream wait for them
+    // once finished or dropped. The sent tensors are kept alive until then.
+    struct Transfer {
+        done: Option<Event>,
+        device: CudaDevice,
+        _sent: Vec<Tensor>,
+    }
+
+    impl Transfer {
+        fn finish(mut self) -> Result<()> {
+            match self.done.take() {
+                Some(done) => done.wait(*self.device.cu_stream()),
+                None => Ok(()),
+            }
+        }
+    }
+
+    impl Drop for Transfer {
+        fn drop(&mut self) {
+            if let Some(done) = self.done.take() {
+                let _ = done.wait(*self.device.cu_stream());
+            }
+        }
+    }
+
+    impl NcclCommunicator {
+        fn collective(&self, xs: &Tensor, collective: Collective) -> Result<Tensor> {
+            let op = NcclOp {
+                comm: &self.comm,
+                collective,
+            };
+            xs.apply_op1_no_bwd(&op)
+        }
+    }
+
+    impl super::Communicator for NcclCommunicator {
+        fn rank(&self) -> usize {
+            self.comm.rank()
+        }
+
+        fn world_size(&self) -> usize {
+            self.comm.world_size()
+        }
+
+        fn all_reduce(&self, xs: &Tensor) -> Result<Tensor> {
+            self.collective(xs, Collective::AllReduce)
+        }
+
+        fn all_gather(&self, xs: &Tensor) -> Result<Tensor> {
+            self.collective(xs, Collective::AllGather)
+        }
+
+        fn reduce_scatter(&self, xs: &Tensor) -> Result<Tensor> {
+            self.collective(xs, Collective::ReduceScatter)
+        }
+
+        fn broadcast(&self, xs: &Tensor, root: usize) -> Result<Tensor> {
+            self.collective(xs, Collective::Broadcast(root))
+        }
+
+        fn ring_exchange(&self, xs: &[&Tensor]) -> Result<PendingExchange> {
+            let (first, others) = match xs.split_first() {
+                Some(xs) => xs,
+                None => return Ok(PendingExchange::ready(vec![])),
+            };
+            let device = match first.device() {
+                candle::Device::Cuda(device) => device.clone(),
+                _ => candle::bail!("nccl collectives require cuda tensors"),
+            };
+            let op = RingOp {
+                comm: &self.comm,
+                others,
+                done: Mutex::new(None),
+            };
+            let received = first.apply_op1_no_bwd(&op)?;
+            let transfer = Transfer {
+                done: op.done.into_inner().unwrap(),
+                device,
+                _sent: xs.iter().map(|&xs| xs.clone()).collect(),
+            };
+            let mut offset = 0;
+            let mut tensors = Vec::with_capacity(xs.len());
+            for xs in xs.iter() {
+                let len = xs.elem_count();
+                tensors.push(received.narrow(0, offset, len)?.reshape(xs.shape())?);
+                offset += len;
+            }
+            Ok(PendingExchange::new(move || {
+                transfer.finish()?;
+                Ok(tensors)
+            }))
+        }
+    }
+}
@@ -0,0 +1,208 @@
+//! Checkpoints of sharded training states that can be resumed with a different world size.
+//!
+//! Each rank saves the flat chunks it owns in its own safetensors file, and the first rank
+//! writes a `metadata.json` file with the full shapes of the tensors and the scalar values such
+//! as the number of optimizer steps. When loading, the chunks saved by all the ranks are
+//! reassembled and split again for the current number of ranks, so a run started on 8 gpus can
+//! be resumed on 4 of them.
+//!
+//! [`crate::fsdp::ShardedParams`] and [`crate::zero::DistributedOptimizer`] use this to save
+//! their parameters together with the optimizer states.
+use crate::distributed::{pad_flat, Communicator};
+use crate::Optimizer;
+use candle::{DType, Device, Result, Shape, Tensor};
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+
+const METADATA_FILE: &str = "metadata.json";
+
+fn shard_file(dir: &Path, rank: usize) -> PathBuf {
+    dir.join(format!("rank{rank}.safetensors"))
+}
+
+/// The flat chunk of a tensor owned by a rank.
+#[derive(Debug, Clone)]
+pub struct Shard {
+    pub name: String,
+    /// The shape of the full tensor.
+    pub shape: Shape,
+    pub data: Tensor,
+}
+
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+struct Metadata {
+    world_size: usize,
+    shapes: BTreeMap<String, Vec<usize>>,
+    scalars: BTreeMap<String, f64>,
+}
+
+/// Saves the shards of this rank in `dir`, all the ranks have to call this with the same tensor
+/// names and scalars. This returns once all the ranks have written their shards.
+pub fn save<P: AsRef<Path>>(
+    dir: P,
+    shards: &[Shard],
+    scalars: &HashMap<String, f64>,
+    comm: &dyn Communicator,
+) -> Result<()> {
+    let dir = dir.as_ref();
+    std::fs::create_dir_all(dir)?;
+    let tensors: HashMap<&str, Tensor> = shards
+        .iter()
+        .map(|s| (s.name.as_str(), s.data.clone()))
+        .collect();
+    candle::safetensors::save(&tensors, shard_file(dir, comm.rank()))?;
+    if comm.rank() == 0 {
+        let metadata = Metadata {
+            world_size: comm.world_size(),
+            shapes: shards
+                .iter()
+                .map(|s| (s.name.clone(), s.shape.dims().to_vec()))
+                .collect(),
+            scalars: scalars.iter().map(|(k, v)| (k.clone(), *v)).collect(),
+        };
+        let metadata = serde_json::to_string_pretty(&metadata).map_err(candle::Error::wrap)?;
+        std::fs::write(dir.join(METADATA_FILE), metadata)?;
+    }
+    // Wait for all the ranks to have written their files.
+    let device = shards
+        .first()
+        .map_or(Device::Cpu, |s| s.data.device().clone());
+    comm.all_reduce(&Tensor::zeros(1, DType::F32, &device)?)?;
+    Ok(())
+}
+
+/// A checkpoint loaded for one of the ranks of a possibly different world size.
+pub struct Checkpoint {
+    metadata: Metadata,
+    scalars: HashMap<String, f64>,
+    files: Vec<candle::safetensors::MmapedSafetensors>,
+    rank: usize,
+    world_size: usize,
+    device: Device,
+}
+
+impl Checkpoint {
+    /// Loads the checkpoint saved in `dir` for rank `rank` out of `world_size`.
+    pub fn load<P: AsRef<Path>>(
+        dir: P,
+        rank: usize,
+        world_size: usize,
+        device: &Device,
+    ) -> Result<Self> {
+        let dir = dir.as_ref();
+        if rank >= world_size {
+            candle::bail!("rank {rank} out of range for a world size of {world_size}")
+        }
+        let metadata = std::fs::read_to_string(dir.join(METADATA_FILE))?;
+        let metadata: Metadata = serde_json::from_str(&metadata).map_err(candle::Error::wrap)?;
+        let files = (0..metadata.world_size)
+            .map(|r| unsafe { candle::safetensors::MmapedSafetensors::new(shard_file(dir, r)) })
+            .collect::<Result<Vec<_>>>()?;
+        let scalars = metadata
+            .scalars
+            .iter()
+            .map(|(k, v)| (k.clone(), *v))
+            .collect();
+        Ok(Self {
+            metadata,
+            scalars,
+            files,
+            rank,
+            world_size,
+            device: device.clone(),
+        })
+    }
+
+    /// The number of ranks that saved the checkpoint.
+    pub fn saved_world_size(&self) -> usize {
+        self.metadata.world_size
+    }
+
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.metadata.shapes.keys().map(|k| k.as_str())
+    }
+
+    pub fn scalars(&self) -> &HashMap<String, f64> {
+        &self.scalars
+    }
+
+    pub fn shape(&self, name: &str) -> Result<Shape> {
+        match self.metadata.shapes.get(name) {
+            Some(dims) => Ok(Shape::from(dims.clone())),
+            None => candle::bail!("cannot find {name} in the checkpoint"),
+        }
+    }
+
+    /// Reassembles the full tensor from the shards of all the ranks.
+    pub fn full(&self, name: &str) -> Result<Tensor> {
+        let shape = self.shape(name)?;
+        let chunks = self
+            .files
+            .iter()
+            .map(|f| f.load(name, &self.device))
+            .collect::<Result<Vec<_>>>()?;
+        let flat = Tensor::cat(&chunks, 0)?;
+        flat.narrow(0, 0, shape.elem_count())?.reshape(shape)
+    }
+
+    /// The chunk of the tensor owned by the current rank.
+    pub fn shard(&self, name: &str) -> Result<Tensor> {
+        let flat = pad_flat(&self.full(name)?, self.world_size)?;
+        let chunk = flat.dim(0)? / self.world_size;
+        flat.narrow(0, self.rank * chunk, chunk)
+    }
+}
+
+/// The shards for the state of an optimizer over sharded variables, `names` and `shapes` are
+/// the names and full shapes of the variables of the optimizer.
+pub(crate) fn optimizer_shards<O: Optimizer>(
+    optimizer: &O,
+    names: &[String],
+    shapes: &[Shape],
+    shards: &mut Vec<Shard>,
+    scalars: &mut HashMap<String, f64>,
+) -> Result<()> {
+    let state = optimizer.state()?;
+    if !state.vars.is_empty() && state.vars.len() != names.len() {
+        candle::bail!(
+            "the optimizer has {} variables but there are {} shards",
+            state.vars.len(),
+            names.len()
+        )
+    }
+    for ((var_state, name), shape) in state.vars.iter().zip(names).zip(shapes) {
+        for (key, data) in var_state.iter() {
+            shards.push(Shard {
+                name: format!("optimizer.{name}.{key}"),
+                shape: shape.clone(),
+                data: data.clone(),
+            })
+        }
+    }
+    for (key, value) in state.scalars {
+        scalars.insert(format!("optimizer.{key}"), value);
+    }
+    Ok(())
+}
+
+/// Restores the state of an optimizer saved with [`optimizer_shards`].
+pub(crate) fn load_optimizer<O: Optimizer>(
+    optimizer: &mut O,
+    names: &[String],
+    checkpoint: &Checkpoint,
+) -> Result<()> {
+    // The current state is only used for the names of the state tensors.
+    let mut state = optimizer.state()?;
+    for (var_state, name) in state.vars.iter_mut().zip(names) {
+        for (key, data) in var_state.iter_mut() {
+            *data = checkpoint.shard(&format!("optimizer.{name}.{key}"))?;
+        }
+    }
+    for (key, value) in state.scalars.iter_mut() {
+        match checkpoint.scalars().get(&format!("optimizer.{key}")) {
+            Some(v) => *value = *v,
+            None => candle::bail!("cannot find optimizer.{key} in the checkpoint"),
+        }
+    }
+    optimizer.set_state(&state)
+}
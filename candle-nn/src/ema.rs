@@ -0,0 +1,267 @@
+//! Exponential moving average of model weights.
+//!
+//! An [`EmaModel`] keeps shadow copies of the variables of a [`VarMap`] and updates them after
+//! each optimizer step as `shadow = decay * shadow + (1 - decay) * value`. The averaged weights
+//! usually evaluate better than the raw ones, this is used by most diffusion and vision training
+//! pipelines. The decay schedule follows the `EMAModel` of diffusers.
+//!
+//! ```ignore
+//! let mut ema = EmaModel::new(&varmap, EmaConfig::default())?;
+//! for batch in batches {
+//!     opt.backward_step(&model.loss(&batch)?)?;
+//!     ema.update()?;
+//! }
+//! // Evaluate with the averaged weights, then go back to the trained ones.
+//! ema.apply()?;
+//! let accuracy = evaluate(&model)?;
+//! ema.restore()?;
+//! ema.save("ema.safetensors")?;
+//! ```
+use crate::VarMap;
+use candle::{DType, Device, Result, Tensor};
+use std::collections::{BTreeMap, HashMap};
+use std::path::Path;
+
+// The safetensors metadata key holding the number of updates.
+const STEP_KEY: &str = "ema_step";
+
+/// The decay schedule of an [`EmaModel`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct EmaConfig {
+    /// The maximum decay.
+    pub decay: f64,
+    pub min_decay: f64,
+    /// The number of updates during which the shadows are only copies of the variables.
+    pub update_after_step: usize,
+    /// When set, the decay at step `t` is `1 - (1 + t / inv_gamma)^-power`, otherwise it is
+    /// `(1 + t) / (10 + t)`. The decay is then clamped to `[min_decay, decay]`.
+    pub warmup: Option<EmaWarmup>,
+}
+
+/// The parameters of the EMA warmup, `inv_gamma = 1` and `power = 2 / 3` are good values for
+/// models trained for a million steps or more, `power = 3 / 4` for shorter trainings.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct EmaWarmup {
+    pub inv_gamma: f64,
+    pub power: f64,
+}
+
+impl Default for EmaConfig {
+    fn default() -> Self {
+        Self {
+            decay: 0.9999,
+            min_decay: 0.,
+            update_after_step: 0,
+            warmup: None,
+        }
+    }
+}
+
+impl EmaConfig {
+    /// The decay used by the update number `step`, starting from 1.
+    pub fn decay_at(&self, step: usize) -> f64 {
+        let step = step.saturating_sub(self.update_after_step + 1);
+        if step == 0 {
+            return 0.;
+        }
+        let step = step as f64;
+        let decay = match self.warmup {
+            Some(w) => 1. - (1. + step / w.inv_gamma).powf(-w.power),
+            None => (1. + step) / (10. + step),
+        };
+        decay.min(self.decay).max(self.min_decay)
+    }
+}
+
+// The shadows are kept in f32 for the half precision variables, the updates would be mostly
+// rounded away otherwise.
+fn shadow_dtype(dtype: DType) -> DType {
+    match dtype {
+        DType::BF16 | DType::F16 => DType::F32,
+        dtype => dtype,
+    }
+}
+
+/// Shadow copies of the variables of a [`VarMap`] updated with an exponential moving average.
+///
+/// The buffers and the variables that do not have a float dtype are copied rather than
+/// averaged. Variables added to the map after the creation of the EMA get a shadow at their
+/// next update.
+pub struct EmaModel {
+    varmap: VarMap,
+    config: EmaConfig,
+    shadows: BTreeMap<String, Tensor>,
+    // The values of the variables while the shadows are applied.
+    backup: Option<HashMap<String, Tensor>>,
+    step: usize,
+}
+
+impl EmaModel {
+    /// Tracks the variables of `varmap`, the shadows start from the current values.
+    pub fn new(varmap: &VarMap, config: EmaConfig) -> Result<Self> {
+        let mut shadows = BTreeMap::new();
+        for (name, var) in varmap.data().lock().unwrap().iter() {
+            let shadow = var
+                .as_tensor()
+                .to_dtype(shadow_dtype(var.dtype()))?
+                .copy()?;
+            shadows.insert(name.clone(), shadow);
+        }
+        Ok(Self {
+            varmap: varmap.clone(),
+            config,
+            shadows,
+            backup: None,
+            step: 0,
+        })
+    }
+
+    pub fn config(&self) -> &EmaConfig {
+        &self.config
+    }
+
+    /// The number of updates done so far.
+    pub fn step(&self) -> usize {
+        self.step
+    }
+
+    /// The decay of the last update.
+    pub fn decay(&self) -> f64 {
+        self.config.decay_at(self.step)
+    }
+
+    /// The shadow copy of the variable `name`.
+    pub fn shadow(&self, name: &str) -> Option<&Tensor> {
+        self.shadows.get(name)
+    }
+
+    pub fn shadows(&self) -> &BTreeMap<String, Tensor> {
+        &self.shadows
+    }
+
+    /// Whether the shadows are currently applied to the variables, see [`Self::apply`].
+    pub fn is_applied(&self) -> bool {
+        self.backup.is_some()
+    }
+
+    /// Updates the shadows with the current values of the variables, this should be called after
+    /// each optimizer step. Returns the decay that was used.
+    pub fn update(&mut self) -> Result<f64> {
+        if self.is_applied() {
+            candle::bail!("cannot update the EMA while its shadows are applied")
+        }
+        self.step += 1;
+        let decay = self.decay();
+        let data = self.varmap.data().lock().unwrap();
+        for (name, var) in data.iter() {
+            let value = var.as_tensor().to_dtype(shadow_dtype(var.dtype()))?;
+            let averaged = var.dtype().is_float() && !self.varmap.is_buffer(name);
+            let shadow = match self.shadows.get(name) {
+                Some(shadow) if averaged && decay > 0. => {
+                    ((shadow * decay)? + (value * (1. - decay))?)?
+                }
+                _ => value.copy()?,
+            };
+            self.shadows.insert(name.clone(), shadow);
+        }
+        Ok(decay)
+    }
+
+    /// Sets the variables to their shadows, e.g. to evaluate or export the averaged model. The
+    /// current values are kept and can be restored with [`Self::restore`].
+    pub fn apply(&mut self) -> Result<()> {
+        if self.is_applied() {
+            candle::bail!("the EMA shadows are already applied")
+        }
+        let data = self.varmap.data().lock().unwrap();
+        let mut backup = HashMap::new();
+        for (name, var) in data.iter() {
+            if let Some(shadow) = self.shadows.get(name) {
+                backup.insert(name.clone(), var.as_tensor().copy()?);
+                var.set(&shadow.to_dtype(var.dtype())?)?;
+            }
+        }
+        self.backup = Some(backup);
+        Ok(())
+    }
+
+    /// Restores the values the variables had before [`Self::apply`].
+    pub fn restore(&mut self) -> Result<()> {
+        let backup = match self.backup.take() {
+            Some(backup) => backup,
+            None => candle::bail!("the EMA shadows are not applied"),
+        };
+        let data = self.varmap.data().lock().unwrap();
+        for (name, value) in backup.iter() {
+            if let Some(var) = data.get(name) {
+                var.set(value)?
+            }
+        }
+        Ok(())
+    }
+
+    /// Copies the shadows to the variables with the same names in another map, e.g. the map of
+    /// a model used for evaluation. The variables of `varmap` without a shadow are left as is.
+    pub fn copy_to(&self, varmap: &VarMap) -> Result<()> {
+        let data = varmap.data().lock().unwrap();
+        for (name, var) in data.iter() {
+            if let Some(shadow) = self.shadows.get(name) {
+                var.set(&shadow.to_dtype(var.dtype())?.to_device(var.device())?)?
+            }
+        }
+        Ok(())
+    }
+
+    /// Saves the shadows in the safetensors format, with the names of the variables so that the
+    /// file can also be loaded as the weights of the model. The number of updates is stored in
+    /// the metadata.
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let metadata = HashMap::from([(STEP_KEY.to_string(), self.step.to_string())]);
+        safetensors::tensor::serialize_to_file(
+            self.shadows.iter(),
+            &Some(metadata),
+            path.as_ref(),
+        )?;
+        Ok(())
+    }
+
+    /// Loads the shadows and the number of updates saved by [`Self::save`]. The shadows of the
+    /// tracked variables that are missing from the file are left as is.
+    pub fn load<P: AsRef<Path>>(&mut self, path: P) -> Result<()> {
+        let path = path.as_ref();
+        let buffer = std::fs::read(path)?;
+        let (_, metadata) = safetensors::SafeTensors::read_metadata(&buffer)?;
+        let step = metadata
+            .metadata()
+            .as_ref()
+            .and_then(|m| m.get(STEP_KEY))
+            .and_then(|s| s.parse::<usize>().ok());
+        let step = match step {
+            Some(step) => step,
+            None => candle::bail!("no EMA step in the metadata of {path:?}"),
+        };
+        let st = candle::safetensors::SliceSafetensors::new(&buffer)?;
+        let names = st.tensors().into_iter().map(|(name, _)| name);
+        let names = names.collect::<std::collections::HashSet<_>>();
+        let data = self.varmap.data().lock().unwrap();
+        for (name, var) in data.iter() {
+            if !names.contains(name) {
+                continue;
+            }
+            let shadow = st.load(name, &Device::Cpu)?;
+            if shadow.shape() != var.shape() {
+                candle::bail!(
+                    "shape mismatch for the EMA of {name}: {:?} <> {:?}",
+                    shadow.shape(),
+                    var.shape()
+                )
+            }
+            let shadow = shadow
+                .to_dtype(shadow_dtype(var.dtype()))?
+                .to_device(var.device())?;
+            self.shadows.insert(name.clone(), shadow);
+        }
+        self.step = step;
+        Ok(())
+    }
+}
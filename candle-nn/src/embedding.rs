@@ -36,6 +36,18 @@ impl crate::Module for Embedding {
     }
 }
 
+impl crate::migrate::Migrate for Embedding {
+    fn visit_tensors(&mut self, f: &mut dyn FnMut(&mut Tensor) -> Result<()>) -> Result<()> {
+        f(&mut self.embeddings)
+    }
+}
+
+impl crate::layer::Layer for Embedding {
+    fn visit_parameters(&mut self, f: &mut crate::layer::TensorFn) -> Result<()> {
+        f("weight", &mut self.embeddings)
+    }
+}
+
 pub fn embedding(in_size: usize, out_size: usize, vb: crate::VarBuilder) -> Result<Embedding> {
     let embeddings = vb.get_with_hints(
         (in_size, out_size),
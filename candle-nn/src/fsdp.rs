@@ -0,0 +1,421 @@
+//! Fully sharded data parallel training.
+//!
+//! With data parallel training each rank processes a different part of the batch. Rather than
+//! having a full copy of the model on each rank, [`ShardedParams`] splits every parameter
+//! between the ranks: each rank only keeps a flat chunk of each parameter, and an optimizer
+//! created on these chunks with [`ShardedParams::shards`] only keeps the optimizer states for
+//! this chunk too.
+//!
+//! The model is split in units with [`Fsdp`], typically one per transformer block, each unit
+//! owning the parameters under its prefix. The forward pass of a unit all-gathers its full
+//! parameters, runs the unit and releases them. Its backward pass gathers them again,
+//! recomputes the unit and reduce-scatters the gradients of its parameters right away, so that
+//! only the full parameters and gradients of a single unit exist at any time. Each rank ends up
+//! with the gradients of its own chunks, averaged over all the ranks.
+//!
+//! ```ignore
+//! let params = Arc::new(ShardedParams::from_varmap(&varmap, comm)?);
+//! let blocks = (0..cfg.num_layers)
+//!     .map(|i| Fsdp::new(params.clone(), &format!("blocks.{i}"), move |vb| Block::new(cfg, vb)))
+//!     .collect::<Result<Vec<_>>>()?;
+//! let mut opt = AdamW::new(params.shards(), adamw_params)?;
+//! for batch in batches {
+//!     let loss = blocks.iter().try_fold(batch, |xs, b| b.forward(&xs))?.mean_all()?;
+//!     let mut grads = loss.backward()?;
+//!     params.reduce_grads(&mut grads)?;
+//!     opt.step(&grads)?;
+//! }
+//! ```
+//!
+//! As the units are recomputed in the backward pass, a unit should use the random number
+//! generator of the device for its randomness, e.g. dropout, so that the recomputation sees
+//! the same values. Parameters shared between units are not supported.
+use crate::distributed::{pad_flat, Communicator};
+use crate::distributed_checkpoint::{self, load_optimizer, optimizer_shards, Checkpoint, Shard};
+use crate::{Optimizer, VarBuilder, VarMap};
+use candle::backprop::GradStore;
+use candle::rng::RngState;
+use candle::{CpuStorage, CustomOp3, DType, Device, Layout, Module, Result, Shape, Tensor, Var};
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+struct ShardedParam {
+    name: String,
+    shape: Shape,
+    shard: Var,
+}
+
+/// The parameters of a model sharded between the ranks of a communicator.
+pub struct ShardedParams {
+    comm: Arc<dyn Communicator>,
+    params: Vec<ShardedParam>,
+    dtype: DType,
+    device: Device,
+    // The gradients of the shards reduce-scattered by the backward passes of the units.
+    reduced: Mutex<Vec<Option<Tensor>>>,
+}
+
+impl ShardedParams {
+    /// Shards full parameters, all the ranks should use the same parameter names and values.
+    pub fn new(params: Vec<(String, Tensor)>, comm: Arc<dyn Communicator>) -> Result<Self> {
+        let (rank, world_size) = (comm.rank(), comm.world_size());
+        let (dtype, device) = match params.first() {
+            None => candle::bail!("no parameters to shard"),
+            Some((_, t)) => (t.dtype(), t.device().clone()),
+        };
+        let params = params
+            .into_iter()
+            .map(|(name, t)| {
+                if t.dtype() != dtype || !t.device().same_device(&device) {
+                    candle::bail!("all the parameters should have the same dtype and device, {name} has {:?} on {:?}", t.dtype(), t.device())
+                }
+                let flat = pad_flat(&t, world_size)?;
+                let chunk = flat.dim(0)? / world_size;
+                // Detaching gives the shard its own storage, even with a single rank.
+                let shard = Var::from_tensor(&flat.narrow(0, rank * chunk, chunk)?.detach())?;
+                Ok(ShardedParam {
+                    name,
+                    shape: t.shape().clone(),
+                    shard,
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let reduced = Mutex::new(vec![None; params.len()]);
+        Ok(Self {
+            comm,
+            params,
+            dtype,
+            device,
+            reduced,
+        })
+    }
+
+    /// Shards the variables of `varmap`, the variables are sorted by name so that all the ranks
+    /// use the same order.
+    pub fn from_varmap(varmap: &VarMap, comm: Arc<dyn Communicator>) -> Result<Self> {
+        let mut params: Vec<_> = varmap
+            .data()
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(name, var)| (name.clone(), var.as_tensor().clone()))
+            .collect();
+        params.sort_by(|(n1, _), (n2, _)| n1.cmp(n2));
+        Self::new(params, comm)
+    }
+
+    pub fn communicator(&self) -> &Arc<dyn Communicator> {
+        &self.comm
+    }
+
+    /// The chunks of the parameters owned by this rank, these are the variables to optimize.
+    pub fn shards(&self) -> Vec<Var> {
+        self.params.iter().map(|p| p.shard.clone()).collect()
+    }
+
+    // The indexes of the parameters under `prefix`, an empty prefix selects all the parameters.
+    fn indexes(&self, prefix: &str) -> Vec<usize> {
+        self.params
+            .iter()
+            .enumerate()
+            .filter(|(_, p)| {
+                prefix.is_empty()
+                    || p.name == prefix
+                    || p.name
+                        .strip_prefix(prefix)
+                        .is_some_and(|n| n.starts_with('.'))
+            })
+            .map(|(i, _)| i)
+            .collect()
+    }
+
+    // The gathered parameters at `indexes`, in the same order. These do not track gradients.
+    fn all_gather(&self, indexes: &[usize]) -> Result<Vec<(String, Tensor)>> {
+        indexes
+            .iter()
+            .map(|&i| {
+                let p = &self.params[i];
+                let flat = self.comm.all_gather(&p.shard.as_tensor().detach())?;
+                let full = flat.narrow(0, 0, p.shape.elem_count())?.reshape(&p.shape)?;
+                Ok((p.name.clone(), full))
+            })
+            .collect()
+    }
+
+    // Reduce-scatters the gradients of the gathered parameters at `indexes` and adds them to the
+    // gradients of the shards. All the ranks have to take part in every reduction so parameters
+    // that did not contribute to the loss get a zero gradient.
+    fn reduce_scatter(&self, indexes: &[usize], full: &[Var], grads: &GradStore) -> Result<()> {
+        let world_size = self.comm.world_size();
+        for (&i, full) in indexes.iter().zip(full.iter()) {
+            let grad = match grads.get(full.as_tensor()) {
+                Some(grad) => grad.clone(),
+                None => full.zeros_like()?,
+            };
+            let grad = pad_flat(&grad, world_size)?;
+            let grad = (self.comm.reduce_scatter(&grad)? / world_size as f64)?;
+            let mut reduced = self.reduced.lock().unwrap();
+            reduced[i] = Some(match reduced[i].take() {
+                Some(prev) => (prev + grad)?,
+                None => grad,
+            });
+        }
+        Ok(())
+    }
+
+    /// The full values of the parameters, e.g. to save a checkpoint. No gradients are tracked.
+    pub fn full_tensors(&self) -> Result<HashMap<String, Tensor>> {
+        Ok(self.all_gather(&self.indexes(""))?.into_iter().collect())
+    }
+
+    /// Saves the shards of the parameters of this rank and the state of `optimizer`, which should
+    /// optimize [`Self::shards`], see [`crate::distributed_checkpoint`].
+    pub fn save_checkpoint<P: AsRef<Path>, O: Optimizer>(
+        &self,
+        dir: P,
+        optimizer: &O,
+        scalars: &HashMap<String, f64>,
+    ) -> Result<()> {
+        let (names, shapes) = self.names_and_shapes();
+        let mut shards: Vec<_> = self
+            .params
+            .iter()
+            .map(|p| Shard {
+                name: format!("params.{}", p.name),
+                shape: p.shape.clone(),
+                data: p.shard.as_tensor().clone(),
+            })
+            .collect();
+        let mut scalars = scalars.clone();
+        optimizer_shards(optimizer, &names, &shapes, &mut shards, &mut scalars)?;
+        distributed_checkpoint::save(dir, &shards, &scalars, self.comm.as_ref())
+    }
+
+    /// Restores the parameters and the optimizer state from a checkpoint, possibly saved with a
+    /// different number of ranks.
+    pub fn load_checkpoint<O: Optimizer>(
+        &self,
+        checkpoint: &Checkpoint,
+        optimizer: &mut O,
+    ) -> Result<()> {
+        for p in self.params.iter() {
+            let shard = checkpoint.shard(&format!("params.{}", p.name))?;
+            p.shard.set(&shard.to_dtype(self.dtype)?)?;
+        }
+        let (names, _) = self.names_and_shapes();
+        load_optimizer(optimizer, &names, checkpoint)
+    }
+
+    fn names_and_shapes(&self) -> (Vec<String>, Vec<Shape>) {
+        self.params
+            .iter()
+            .map(|p| (p.name.clone(), p.shape.clone()))
+            .unzip()
+    }
+
+    /// Inserts in `grads` the gradients of the shards of this rank, averaged over all the ranks.
+    /// These gradients are reduce-scattered by the backward passes of the [`Fsdp`] units, this
+    /// should be called once per backward pass.
+    pub fn reduce_grads(&self, grads: &mut GradStore) -> Result<()> {
+        let reduced = std::mem::replace(
+            &mut *self.reduced.lock().unwrap(),
+            vec![None; self.params.len()],
+        );
+        if reduced.iter().all(|g| g.is_none()) {
+            candle::bail!("reduce_grads called without a backward pass through the fsdp units")
+        }
+        for (p, grad) in self.params.iter().zip(reduced) {
+            let grad = match grad {
+                Some(grad) => grad,
+                None => p.shard.zeros_like()?,
+            };
+            grads.insert(p.shard.as_tensor(), grad);
+        }
+        Ok(())
+    }
+}
+
+struct Unit<M> {
+    params: Arc<ShardedParams>,
+    prefix: String,
+    indexes: Vec<usize>,
+    build: Box<dyn Fn(VarBuilder) -> Result<M> + Send + Sync>,
+}
+
+impl<M> Unit<M> {
+    // Gathers the parameters of the unit and builds it.
+    fn build(&self, tensors: HashMap<String, Tensor>) -> Result<M> {
+        let params = &self.params;
+        let vb = VarBuilder::from_tensors(tensors, params.dtype, &params.device);
+        let vb = if self.prefix.is_empty() {
+            vb
+        } else {
+            vb.pp(&self.prefix)
+        };
+        (self.build)(vb)
+    }
+}
+
+/// A unit of a model whose parameters are sharded, the module is rebuilt from the gathered
+/// parameters under its prefix on each forward pass and again on the backward pass.
+pub struct Fsdp<M> {
+    unit: Arc<Unit<M>>,
+}
+
+impl<M> Fsdp<M> {
+    /// Creates a unit owning the parameters under `prefix`, `build` gets a `VarBuilder` rooted
+    /// at this prefix. An empty prefix makes the whole model a single unit.
+    pub fn new<F>(params: Arc<ShardedParams>, prefix: &str, build: F) -> Result<Self>
+    where
+        F: Fn(VarBuilder) -> Result<M> + Send + Sync + 'static,
+    {
+        let indexes = params.indexes(prefix);
+        if indexes.is_empty() {
+            candle::bail!("no sharded parameters under {prefix}")
+        }
+        let unit = Unit {
+            params,
+            prefix: prefix.to_string(),
+            indexes,
+            build: Box::new(build),
+        };
+        Ok(Self {
+            unit: Arc::new(unit),
+        })
+    }
+
+    pub fn params(&self) -> &Arc<ShardedParams> {
+        &self.unit.params
+    }
+}
+
+impl<M: Module + 'static> Module for Fsdp<M> {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        let unit = &self.unit;
+        let rng = unit.params.device.rng_state().ok();
+        let tensors = unit.params.all_gather(&unit.indexes)?.into_iter().collect();
+        // The gathered parameters are released once the unit has run.
+        let ys = unit.build(tensors)?.forward(&xs.detach())?;
+        // The shard links the result to the graph, even if `xs` does not track gradients.
+        let shard = unit.params.params[unit.indexes[0]].shard.as_tensor();
+        let op = UnitOp {
+            unit: unit.clone(),
+            rng,
+        };
+        xs.apply_op3(&ys, shard, op)
+    }
+}
+
+// Returns the result of a unit computed in the forward pass, its backward pass recomputes the
+// unit with the gathered parameters.
+struct UnitOp<M> {
+    unit: Arc<Unit<M>>,
+    // The state of the generator when the unit was run.
+    rng: Option<RngState>,
+}
+
+impl<M: Module> UnitOp<M> {
+    fn recompute(&self, xs: &Tensor, tensors: HashMap<String, Tensor>) -> Result<Tensor> {
+        let device = &self.unit.params.device;
+        let current = match self.rng {
+            Some(rng) => {
+                let current = device.rng_state()?;
+                device.set_rng_state(rng)?;
+                Some(current)
+            }
+            None => None,
+        };
+        let ys = self.unit.build(tensors).and_then(|m| m.forward(xs));
+        if let Some(current) = current {
+            device.set_rng_state(current)?
+        }
+        ys
+    }
+}
+
+impl<M: Module> CustomOp3 for UnitOp<M> {
+    fn name(&self) -> &'static str {
+        "fsdp-unit"
+    }
+
+    fn cpu_fwd(
+        &self,
+        _: &CpuStorage,
+        _: &Layout,
+        storage: &CpuStorage,
+        layout: &Layout,
+        _: &CpuStorage,
+        _: &Layout,
+    ) -> Result<(CpuStorage, Shape)> {
+        use candle::backend::{BackendDevice, BackendStorage};
+        let mut dst = unsafe {
+            candle::cpu_backend::CpuDevice.alloc_uninit(layout.shape(), storage.dtype())?
+        };
+        storage.copy_strided_src(&mut dst, 0, layout)?;
+        Ok((dst, layout.shape().clone()))
+    }
+
+    #[cfg(feature = "cuda")]
+    fn cuda_fwd(
+        &self,
+        _: &candle::CudaStorage,
+        _: &Layout,
+        storage: &candle::CudaStorage,
+        layout: &Layout,
+        _: &candle::CudaStorage,
+        _: &Layout,
+    ) -> Result<(candle::CudaStorage, Shape)> {
+        use candle::backend::{BackendDevice, BackendStorage};
+        let mut dst = unsafe {
+            storage
+                .device()
+                .alloc_uninit(layout.shape(), storage.dtype())?
+        };
+        storage.copy_strided_src(&mut dst, 0, layout)?;
+        Ok((dst, layout.shape().clone()))
+    }
+
+    #[cfg(feature = "metal")]
+    fn metal_fwd(
+        &self,
+        _: &candle::MetalStorage,
+        _: &Layout,
+        storage: &candle::MetalStorage,
+        layout: &Layout,
+        _: &candle::MetalStorage,
+        _: &Layout,
+    ) -> Result<(candle::MetalStorage, Shape)> {
+        use candle::backend::{BackendDevice, BackendStorage};
+        let mut dst = unsafe {
+            storage
+                .device()
+                .alloc_uninit(layout.shape(), storage.dtype())?
+        };
+        storage.copy_strided_src(&mut dst, 0, layout)?;
+        Ok((dst, layout.shape().clone()))
+    }
+
+    fn bwd(
+        &self,
+        xs: &Tensor,
+        _: &Tensor,
+        _: &Tensor,
+        _res: &Tensor,
+        grad_res: &Tensor,
+    ) -> Result<(Option<Tensor>, Option<Tensor>, Option<Tensor>)> {
+        let unit = &self.unit;
+        let mut tensors = HashMap::new();
+        let mut full = vec![];
+        for (name, t) in unit.params.all_gather(&unit.indexes)? {
+            let var = Var::from_tensor(&t)?;
+            tensors.insert(name, var.as_tensor().clone());
+            full.push(var);
+        }
+        let xs = Var::from_tensor(&xs.detach())?;
+        let ys = self.recompute(xs.as_tensor(), tensors)?;
+        let grads = (ys * grad_res.detach())?.sum_all()?.backward()?;
+        unit.params.reduce_scatter(&unit.indexes, &full, &grads)?;
+        Ok((grads.get(xs.as_tensor()).cloned(), None, None))
+    }
+}
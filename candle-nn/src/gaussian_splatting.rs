@@ -0,0 +1,967 @@
+//! Differentiable 3D Gaussian splatting.
+//!
+//! A scene is represented by a set of 3D gaussians with a mean, a scale along each axis, a
+//! rotation, an opacity and a color. An image is rendered in two steps:
+//!
+//! - [`project_gaussians`] projects the gaussians to 2D gaussians in the image plane using the
+//!   local affine approximation of the perspective projection. This is written with tensor
+//!   operations so the gradients are handled by the usual backpropagation.
+//! - [`rasterize`] sorts the 2D gaussians by depth and alpha composites them front to back for
+//!   each pixel. This is a custom op with cpu, cuda and metal kernels for both the forward and
+//!   the backward pass.
+//!
+//! The colors can have an arbitrary number of channels, e.g. they can be obtained by evaluating
+//! spherical harmonics for the view direction. The gpu kernels support up to
+//! [`MAX_GPU_CHANNELS`] channels. See "3D Gaussian Splatting for Real-Time Radiance Field
+//! Rendering" <https://arxiv.org/abs/2308.04079>.
+//!
+//! ```ignore
+//! let camera = Camera::new(world_to_camera, (fx, fy), (cx, cy), (width, height));
+//! let image = render(&means, &scales, &rotations, &opacities, &colors, &camera, &[0., 0., 0.])?;
+//! let loss = (image - target)?.abs()?.mean_all()?;
+//! let grads = loss.backward()?;
+//! ```
+use candle::{CpuStorage, DType, Layout, Result, Shape, Tensor};
+use rayon::prelude::*;
+
+/// The maximum number of color channels for the cuda and metal kernels.
+pub const MAX_GPU_CHANNELS: usize = 16;
+
+// The gaussians closer to the camera than this are not rendered.
+const NEAR_PLANE: f32 = 0.01;
+// The kernels use tiles of 16x16 pixels.
+const TILE_SIZE: usize = 16;
+const MAX_ALPHA: f32 = 0.99;
+const MIN_ALPHA: f32 = 1. / 255.;
+// The compositing stops when the transmittance gets below this value.
+const MIN_TRANSMITTANCE: f32 = 1e-4;
+// Each gaussian is stored as its 2D mean, its conic, its opacity and its color.
+const NUM_GEOMETRY_PARAMS: usize = 6;
+
+/// A pinhole camera using the OpenCV convention: `x` goes right, `y` goes down and the camera
+/// looks along `z`.
+#[derive(Debug, Clone)]
+pub struct Camera {
+    /// The `(3, 4)` or `(4, 4)` transform from the world frame to the camera frame.
+    pub world_to_camera: Tensor,
+    pub fx: f64,
+    pub fy: f64,
+    pub cx: f64,
+    pub cy: f64,
+    pub width: usize,
+    pub height: usize,
+}
+
+impl Camera {
+    pub fn new(
+        world_to_camera: Tensor,
+        (fx, fy): (f64, f64),
+        (cx, cy): (f64, f64),
+        (width, height): (usize, usize),
+    ) -> Self {
+        Self {
+            world_to_camera,
+            fx,
+            fy,
+            cx,
+            cy,
+            width,
+            height,
+        }
+    }
+}
+
+/// The 2D gaussians returned by [`project_gaussians`].
+#[derive(Debug, Clone)]
+pub struct Projection {
+    /// The centers in pixel coordinates, shape `(n, 2)`.
+    pub means2d: Tensor,
+    /// The upper triangular part `(a, b, c)` of the inverse of the 2D covariance matrices,
+    /// shape `(n, 3)`.
+    pub conics: Tensor,
+    /// The depths in the camera frame, shape `(n,)`.
+    pub depths: Tensor,
+}
+
+/// The rotation matrices of shape `(n, 3, 3)` for quaternions `(w, x, y, z)` of shape `(n, 4)`,
+/// the quaternions do not have to be normalized.
+pub fn quaternion_to_rotation(quaternions: &Tensor) -> Result<Tensor> {
+    let n = quaternions.dim(0)?;
+    let norms = quaternions.sqr()?.sum_keepdim(1)?.sqrt()?;
+    let q = quaternions.broadcast_div(&norms)?;
+    let (w, x, y, z) = (
+        q.narrow(1, 0, 1)?,
+        q.narrow(1, 1, 1)?,
+        q.narrow(1, 2, 1)?,
+        q.narrow(1, 3, 1)?,
+    );
+    let (xx, yy, zz) = (x.sqr()?, y.sqr()?, z.sqr()?);
+    let (xy, xz, yz) = ((&x * &y)?, (&x * &z)?, (&y * &z)?);
+    let (wx, wy, wz) = ((&w * &x)?, (&w * &y)?, (&w * &z)?);
+    let entries = [
+        (1. - ((&yy + &zz)? * 2.)?)?,
+        ((&xy - &wz)? * 2.)?,
+        ((&xz + &wy)? * 2.)?,
+        ((&xy + &wz)? * 2.)?,
+        (1. - ((&xx + &zz)? * 2.)?)?,
+        ((&yz - &wx)? * 2.)?,
+        ((&xz - &wy)? * 2.)?,
+        ((&yz + &wx)? * 2.)?,
+        (1. - ((&xx + &yy)? * 2.)?)?,
+    ];
+    Tensor::cat(&entries, 1)?.reshape((n, 3, 3))
+}
+
+/// Projects 3D gaussians to the image plane of `camera`.
+///
+/// `means` and `scales` have shape `(n, 3)`, the scales are the standard deviations along the
+/// axes of the gaussians, and `rotations` are quaternions `(w, x, y, z)` of shape `(n, 4)`. As in
+/// the reference implementation, the 2D covariances are dilated by `0.3` pixels so that every
+/// gaussian covers at least a pixel.
+pub fn project_gaussians(
+    means: &Tensor,
+    scales: &Tensor,
+    rotations: &Tensor,
+    camera: &Camera,
+) -> Result<Projection> {
+    let n = means.dim(0)?;
+    let w2c = camera.world_to_camera.to_dtype(means.dtype())?;
+    let w = w2c.narrow(0, 0, 3)?.narrow(1, 0, 3)?;
+    let t = w2c.narrow(0, 0, 3)?.narrow(1, 3, 1)?.t()?;
+    let points = means.matmul(&w.t()?)?.broadcast_add(&t)?;
+    let depths = points.narrow(1, 2, 1)?;
+    let z = depths.maximum(NEAR_PLANE as f64)?;
+    let u = points.narrow(1, 0, 1)?.div(&z)?;
+    let v = points.narrow(1, 1, 1)?.div(&z)?;
+    let means2d = Tensor::cat(
+        &[
+            u.affine(camera.fx, camera.cx)?,
+            v.affine(camera.fy, camera.cy)?,
+        ],
+        1,
+    )?;
+
+    // cov3d = R S S^T R^T
+    let rs = quaternion_to_rotation(rotations)?.broadcast_mul(&scales.unsqueeze(1)?)?;
+    let cov3d = rs.matmul(&rs.t()?)?;
+    // The jacobian of the projection, clamping the points far outside of the field of view to
+    // avoid unstable covariances.
+    let lim_u = 1.3 * 0.5 * camera.width as f64 / camera.fx;
+    let lim_v = 1.3 * 0.5 * camera.height as f64 / camera.fy;
+    let u = u.clamp(-lim_u, lim_u)?;
+    let v = v.clamp(-lim_v, lim_v)?;
+    let zeros = z.zeros_like()?;
+    let fx_z = (z.recip()? * camera.fx)?;
+    let fy_z = (z.recip()? * camera.fy)?;
+    let jacobian = Tensor::cat(
+        &[
+            &fx_z,
+            &zeros,
+            &(u.neg()? * &fx_z)?,
+            &zeros,
+            &fy_z,
+            &(v.neg()? * &fy_z)?,
+        ],
+        1,
+    )?
+    .reshape((n, 2, 3))?;
+    let jw = jacobian.broadcast_matmul(&w)?;
+    let cov2d = jw.matmul(&cov3d)?.matmul(&jw.t()?)?.reshape((n, 4))?;
+    let a = (cov2d.narrow(1, 0, 1)? + 0.3)?;
+    let b = cov2d.narrow(1, 1, 1)?;
+    let c = (cov2d.narrow(1, 3, 1)? + 0.3)?;
+    let det = ((&a * &c)? - b.sqr()?)?;
+    let conics = Tensor::cat(&[&c, &b.neg()?, &a], 1)?.broadcast_div(&det)?;
+    Ok(Projection {
+        means2d,
+        conics,
+        depths: depths.squeeze(1)?,
+    })
+}
+
+/// Alpha composites the 2D gaussians and returns an image of shape `(height, width,
+/// channels)`.
+///
+/// `means2d`, `conics` and `depths` are as returned by [`project_gaussians`], `colors` has shape
+/// `(n, channels)` and `opacities` shape `(n,)`. The gaussians are sorted by increasing depth on
+/// the host, the ones closer than the near plane are ignored. A gaussian with center `m`
+/// contributes to the pixel `p` with `alpha = min(0.99, opacity * exp(-d^T conic d / 2))`
+/// where `d = p - m`, the pixel coordinates are the integer indexes of the pixels. As in the
+/// reference implementation, the contributions below `1 / 255` are skipped and the compositing
+/// stops when the transmittance is below `1e-4`. The transmittance left after all the gaussians
+/// is filled with `background`.
+#[allow(clippy::too_many_arguments)]
+pub fn rasterize(
+    means2d: &Tensor,
+    conics: &Tensor,
+    colors: &Tensor,
+    opacities: &Tensor,
+    depths: &Tensor,
+    width: usize,
+    height: usize,
+    background: &[f32],
+) -> Result<Tensor> {
+    let channels = colors.dim(1)?;
+    if background.len() != channels {
+        candle::bail!(
+            "rasterize: unexpected background size {} for {channels} channels",
+            background.len()
+        )
+    }
+    let depths_v = depths.to_dtype(DType::F32)?.to_vec1::<f32>()?;
+    let mut order = (0..depths_v.len() as u32).collect::<Vec<_>>();
+    order.sort_by(|&i, &j| depths_v[i as usize].total_cmp(&depths_v[j as usize]));
+    let visible = depths.ge(NEAR_PLANE as f64)?.to_dtype(opacities.dtype())?;
+    let opacities = (opacities * visible)?.unsqueeze(1)?;
+    let params = Tensor::cat(&[means2d, conics, &opacities, colors], 1)?
+        .to_dtype(DType::F32)?
+        .index_select(&Tensor::new(order, means2d.device())?, 0)?
+        .contiguous()?;
+    params.apply_op1(Rasterize {
+        width,
+        height,
+        background: background.to_vec(),
+    })
+}
+
+/// Projects and rasterizes the gaussians, see [`project_gaussians`] and [`rasterize`].
+pub fn render(
+    means: &Tensor,
+    scales: &Tensor,
+    rotations: &Tensor,
+    opacities: &Tensor,
+    colors: &Tensor,
+    camera: &Camera,
+    background: &[f32],
+) -> Result<Tensor> {
+    let p = project_gaussians(means, scales, rotations, camera)?;
+    rasterize(
+        &p.means2d,
+        &p.conics,
+        colors,
+        opacities,
+        &p.depths,
+        camera.width,
+        camera.height,
+        background,
+    )
+}
+
+// The contribution of a gaussian to a pixel, this has to match the kernels.
+struct Contribution {
+    alpha: f32,
+    // The value of the gaussian, before the opacity.
+    g: f32,
+    dx: f32,
+    dy: f32,
+    clamped: bool,
+}
+
+fn contribution(p: &[f32], px: f32, py: f32) -> Option<Contribution> {
+    let (a, b, c, opacity) = (p[2], p[3], p[4], p[5]);
+    if !(a > 0. && a * c - b * b > 0.) {
+        return None;
+    }
+    let dx = px - p[0];
+    let dy = py - p[1];
+    let power = -0.5 * (a * dx * dx + c * dy * dy) - b * dx * dy;
+    if power > 0. {
+        return None;
+    }
+    let g = power.exp();
+    let alpha = opacity * g;
+    let clamped = alpha > MAX_ALPHA;
+    let alpha = alpha.min(MAX_ALPHA);
+    if alpha < MIN_ALPHA {
+        return None;
+    }
+    Some(Contribution {
+        alpha,
+        g,
+        dx,
+        dy,
+        clamped,
+    })
+}
+
+// The pixels `(x0..x1, y0..y1)` where the gaussian can be above the minimal alpha.
+fn bounding_box(p: &[f32], width: usize, height: usize) -> Option<(usize, usize, usize, usize)> {
+    let (a, b, c, opacity) = (p[2], p[3], p[4], p[5]);
+    let det = a * c - b * b;
+    if !(a > 0. && det > 0.) || opacity * 255. <= 1. {
+        return None;
+    }
+    // The largest eigenvalue of the covariance, i.e. of the inverse of the conic.
+    let mid = 0.5 * (a + c) / det;
+    let lambda = mid + (mid * mid - 1. / det).max(0.).sqrt();
+    let radius = (2. * (opacity * 255.).ln() * lambda).sqrt() + 1.;
+    let x0 = (p[0] - radius).floor().max(0.);
+    let y0 = (p[1] - radius).floor().max(0.);
+    let x1 = (p[0] + radius).ceil().min(width as f32);
+    let y1 = (p[1] + radius).ceil().min(height as f32);
+    if !(x0 < x1 && y0 < y1) {
+        return None;
+    }
+    Some((x0 as usize, x1 as usize, y0 as usize, y1 as usize))
+}
+
+// The gaussians that can contribute to each tile, in depth order.
+fn tile_lists(params: &[f32], stride: usize, width: usize, height: usize) -> Vec<Vec<usize>> {
+    let tiles_x = width.div_ceil(TILE_SIZE);
+    let tiles_y = height.div_ceil(TILE_SIZE);
+    let mut lists = vec![vec![]; tiles_x * tiles_y];
+    for (i, p) in params.chunks_exact(stride).enumerate() {
+        if let Some((x0, x1, y0, y1)) = bounding_box(p, width, height) {
+            for ty in y0 / TILE_SIZE..y1.div_ceil(TILE_SIZE) {
+                for tx in x0 / TILE_SIZE..x1.div_ceil(TILE_SIZE) {
+                    lists[ty * tiles_x + tx].push(i)
+                }
+            }
+        }
+    }
+    lists
+}
+
+fn tile_pixels(tile: usize, width: usize, height: usize) -> impl Iterator<Item = (usize, usize)> {
+    let tiles_x = width.div_ceil(TILE_SIZE);
+    let (x0, y0) = ((tile % tiles_x) * TILE_SIZE, (tile / tiles_x) * TILE_SIZE);
+    let (x1, y1) = ((x0 + TILE_SIZE).min(width), (y0 + TILE_SIZE).min(height));
+    (y0..y1).flat_map(move |y| (x0..x1).map(move |x| (x, y)))
+}
+
+fn params_dims(layout: &Layout, background: &[f32]) -> Result<(usize, usize)> {
+    let (n, stride) = layout.shape().dims2()?;
+    let channels = background.len();
+    if stride != NUM_GEOMETRY_PARAMS + channels {
+        candle::bail!("rasterize: unexpected params shape {:?}", layout.shape())
+    }
+    Ok((n, channels))
+}
+
+fn contiguous_f32<'a>(storage: &'a CpuStorage, layout: &Layout) -> Result<&'a [f32]> {
+    let slice = storage.as_slice::<f32>()?;
+    match layout.contiguous_offsets() {
+        None => candle::bail!("rasterize: the inputs have to be contiguous"),
+        Some((o1, o2)) => Ok(&slice[o1..o2]),
+    }
+}
+
+struct Rasterize {
+    width: usize,
+    height: usize,
+    background: Vec<f32>,
+}
+
+impl candle::CustomOp1 for Rasterize {
+    fn name(&self) -> &'static str {
+        "rasterize-gaussians"
+    }
+
+    fn cpu_fwd(&self, storage: &CpuStorage, layout: &Layout) -> Result<(CpuStorage, Shape)> {
+        let (_, channels) = params_dims(layout, &self.background)?;
+        let params = contiguous_f32(storage, layout)?;
+        let (width, height) = (self.width, self.height);
+        let stride = NUM_GEOMETRY_PARAMS + channels;
+        let lists = tile_lists(params, stride, width, height);
+        let tiles = lists
+            .par_iter()
+            .enumerate()
+            .map(|(tile, list)| {
+                let mut pixels = vec![];
+                for (x, y) in tile_pixels(tile, width, height) {
+                    let mut color = vec![0f32; channels];
+                    let mut t = 1f32;
+                    for &i in list.iter() {
+                        let p = &params[i * stride..(i + 1) * stride];
+                        let Some(contrib) = contribution(p, x as f32, y as f32) else {
+                            continue;
+                        };
+                        let next_t = t * (1. - contrib.alpha);
+                        if next_t < MIN_TRANSMITTANCE {
+                            break;
+                        }
+                        for (c, v) in color.iter_mut().zip(p[NUM_GEOMETRY_PARAMS..].iter()) {
+                            *c += v * contrib.alpha * t
+                        }
+                        t = next_t;
+                    }
+                    for (c, bg) in color.iter_mut().zip(self.background.iter()) {
+                        *c += t * bg
+                    }
+                    pixels.push((x, y, color))
+                }
+                pixels
+            })
+            .collect::<Vec<_>>();
+        let mut dst = vec![0f32; height * width * channels];
+        for (x, y, color) in tiles.into_iter().flatten() {
+            let offset = (y * width + x) * channels;
+            dst[offset..offset + channels].copy_from_slice(&color)
+        }
+        Ok((CpuStorage::F32(dst), Shape::from((height, width, channels))))
+    }
+
+    #[cfg(feature = "cuda")]
+    fn cuda_fwd(
+        &self,
+        storage: &candle::CudaStorage,
+        layout: &Layout,
+    ) -> Result<(candle::CudaStorage, Shape)> {
+        use candle::cuda_backend::cudarc::driver::LaunchAsync;
+        use candle::cuda_backend::{kernels, CudaStorageSlice, WrapErr};
+
+        let (n, channels) = gpu_params_dims(layout, &self.background)?;
+        let (width, height) = (self.width, self.height);
+        let dev = storage.device.clone();
+        let params = cuda_contiguous(storage, layout)?;
+        let stride = NUM_GEOMETRY_PARAMS + channels;
+        let (keys, ranges) = cuda_tile_bins(&dev, &params, n, stride, width, height)?;
+        let background = dev.htod_sync_copy(&self.background).w()?;
+        // SAFETY: Set later by running the kernel.
+        let dst = unsafe { dev.alloc::<f32>(height * width * channels) }.w()?;
+        let cfg = cuda_launch_config(width, height, channels);
+        let func = dev.get_or_load_func("rasterize_gaussians_fwd", kernels::GAUSSIAN_SPLATTING)?;
+        let args = (
+            &params,
+            &keys,
+            &ranges,
+            &background,
+            &dst,
+            channels,
+            width,
+            height,
+        );
+        // SAFETY: ffi.
+        unsafe { func.launch(cfg, args) }.w()?;
+        let dst = candle::CudaStorage {
+            slice: CudaStorageSlice::F32(dst),
+            device: dev,
+        };
+        Ok((dst, Shape::from((height, width, channels))))
+    }
+
+    #[cfg(feature = "metal")]
+    fn metal_fwd(
+        &self,
+        storage: &candle::MetalStorage,
+        layout: &Layout,
+    ) -> Result<(candle::MetalStorage, Shape)> {
+        use candle::backend::BackendStorage;
+
+        let (n, channels) = gpu_params_dims(layout, &self.background)?;
+        let (width, height) = (self.width, self.height);
+        let device = storage.device();
+        let stride = NUM_GEOMETRY_PARAMS + channels;
+        let (keys, ranges) = metal_tile_bins(storage, layout, n, stride, width, height)?;
+        let command_buffer = device.command_buffer()?;
+        command_buffer.set_label("rasterize-gaussians");
+        let el_count = height * width * channels;
+        let output = device.new_buffer(el_count, DType::F32, "rasterize-gaussians")?;
+        candle_metal_kernels::call_rasterize_gaussians(
+            device.metal_device(),
+            &command_buffer,
+            device.kernels(),
+            (channels, width, height),
+            metal_buffer_offset(storage, layout),
+            &keys,
+            &ranges,
+            &self.background,
+            &output,
+        )
+        .map_err(candle::Error::wrap)?;
+        let dst = candle::MetalStorage::new(output, device.clone(), el_count, DType::F32);
+        Ok((dst, Shape::from((height, width, channels))))
+    }
+
+    fn bwd(&self, arg: &Tensor, _res: &Tensor, grad_res: &Tensor) -> Result<Option<Tensor>> {
+        let grad = arg.apply_op2_no_bwd(
+            &grad_res.contiguous()?,
+            &RasterizeBackward {
+                width: self.width,
+                height: self.height,
+                background: self.background.clone(),
+            },
+        )?;
+        Ok(Some(grad))
+    }
+}
+
+// The gpu kernels of the backward pass accumulate the gradients of the gaussians shared by
+// several pixels with atomic adds, in an order that depends on the scheduling.
+#[cfg(any(feature = "cuda", feature = "metal"))]
+const ATOMIC_GRADS: &str = "the gradients are accumulated with atomic adds";
+
+// Returns the gradient of the packed params given the gradient of the image.
+struct RasterizeBackward {
+    width: usize,
+    height: usize,
+    background: Vec<f32>,
+}
+
+impl candle::CustomOp2 for RasterizeBackward {
+    fn name(&self) -> &'static str {
+        "rasterize-gaussians-backward"
+    }
+
+    fn cpu_fwd(
+        &self,
+        s1: &CpuStorage,
+        l1: &Layout,
+        s2: &CpuStorage,
+        l2: &Layout,
+    ) -> Result<(CpuStorage, Shape)> {
+        let (n, channels) = params_dims(l1, &self.background)?;
+        let params = contiguous_f32(s1, l1)?;
+        let grad_out = contiguous_f32(s2, l2)?;
+        let (width, height) = (self.width, self.height);
+        let stride = NUM_GEOMETRY_PARAMS + channels;
+        let lists = tile_lists(params, stride, width, height);
+        let accumulate = |mut grads: Vec<f32>, (tile, list): (usize, &Vec<usize>)| {
+            let mut contribs = vec![];
+            let mut suffix = vec![0f32; channels];
+            for (x, y) in tile_pixels(tile, width, height) {
+                let offset = (y * width + x) * channels;
+                let grad_pixel = &grad_out[offset..offset + channels];
+                // Run the forward pass again to get the contributions and the final
+                // transmittance.
+                contribs.clear();
+                let mut t = 1f32;
+                for &i in list.iter() {
+                    let p = &params[i * stride..(i + 1) * stride];
+                    let Some(contrib) = contribution(p, x as f32, y as f32) else {
+                        continue;
+                    };
+                    let next_t = t * (1. - contrib.alpha);
+                    if next_t < MIN_TRANSMITTANCE {
+                        break;
+                    }
+                    contribs.push((i, t, contrib));
+                    t = next_t;
+                }
+                let final_t = t;
+                let bg_dot = grad_pixel
+                    .iter()
+                    .zip(self.background.iter())
+                    .map(|(g, b)| g * b)
+                    .sum::<f32>();
+                // The sum of the color contributions of the following gaussians.
+                suffix.iter_mut().for_each(|s| *s = 0.);
+                for (i, t, contrib) in contribs.iter().rev() {
+                    let p = &params[i * stride..(i + 1) * stride];
+                    let g = &mut grads[i * stride..(i + 1) * stride];
+                    let alpha = contrib.alpha;
+                    let mut d_alpha = -final_t * bg_dot / (1. - alpha);
+                    for ch in 0..channels {
+                        let color = p[NUM_GEOMETRY_PARAMS + ch];
+                        g[NUM_GEOMETRY_PARAMS + ch] += alpha * t * grad_pixel[ch];
+                        d_alpha += grad_pixel[ch] * (color * t - suffix[ch] / (1. - alpha));
+                        suffix[ch] += color * alpha * t;
+                    }
+                    if contrib.clamped {
+                        continue;
+                    }
+                    let (a, b, c, opacity) = (p[2], p[3], p[4], p[5]);
+                    let (dx, dy) = (contrib.dx, contrib.dy);
+                    g[5] += contrib.g * d_alpha;
+                    let d_g = opacity * d_alpha * contrib.g;
+                    g[0] += d_g * (a * dx + b * dy);
+                    g[1] += d_g * (c * dy + b * dx);
+                    g[2] += -0.5 * d_g * dx * dx;
+                    g[3] += -d_g * dx * dy;
+                    g[4] += -0.5 * d_g * dy * dy;
+                }
+            }
+            grads
+        };
+        // The tiles processed by each thread depend on the scheduling, so the tiles are
+        // accumulated sequentially in deterministic mode.
+        let grads = if candle::is_deterministic() {
+            lists
+                .iter()
+                .enumerate()
+                .fold(vec![0f32; n * stride], accumulate)
+        } else {
+            lists
+                .par_iter()
+                .enumerate()
+                .fold(|| vec![0f32; n * stride], accumulate)
+                .reduce(
+                    || vec![0f32; n * stride],
+                    |mut a, b| {
+                        a.iter_mut().zip(b.iter()).for_each(|(a, b)| *a += b);
+                        a
+                    },
+                )
+        };
+        Ok((CpuStorage::F32(grads), Shape::from((n, stride))))
+    }
+
+    #[cfg(feature = "cuda")]
+    fn cuda_fwd(
+        &self,
+        s1: &candle::CudaStorage,
+        l1: &Layout,
+        s2: &candle::CudaStorage,
+        l2: &Layout,
+    ) -> Result<(candle::CudaStorage, Shape)> {
+        use candle::backend::BackendDevice;
+        use candle::cuda_backend::cudarc::driver::LaunchAsync;
+        use candle::cuda_backend::{kernels, CudaStorageSlice, WrapErr};
+
+        let dev = s1.device.clone();
+        candle::determinism::ensure("rasterize-gaussians-backward", dev.location(), ATOMIC_GRADS)?;
+        let (n, channels) = gpu_params_dims(l1, &self.background)?;
+        let (width, height) = (self.width, self.height);
+        let stride = NUM_GEOMETRY_PARAMS + channels;
+        let params = cuda_contiguous(s1, l1)?;
+        let grad_out = cuda_contiguous(s2, l2)?;
+        let (keys, ranges) = cuda_tile_bins(&dev, &params, n, stride, width, height)?;
+        let background = dev.htod_sync_copy(&self.background).w()?;
+        let grads = dev.alloc_zeros::<f32>(n * stride).w()?;
+        let cfg = cuda_launch_config(width, height, channels);
+        let func = dev.get_or_load_func("rasterize_gaussians_bwd", kernels::GAUSSIAN_SPLATTING)?;
+        let args = (
+            &params,
+            &keys,
+            &ranges,
+            &grad_out,
+            &background,
+            &grads,
+            channels,
+            width,
+            height,
+        );
+        // SAFETY: ffi.
+        unsafe { func.launch(cfg, args) }.w()?;
+        let dst = candle::CudaStorage {
+            slice: CudaStorageSlice::F32(grads),
+            device: dev,
+        };
+        Ok((dst, Shape::from((n, stride))))
+    }
+
+    #[cfg(feature = "metal")]
+    fn metal_fwd(
+        &self,
+        s1: &candle::MetalStorage,
+        l1: &Layout,
+        s2: &candle::MetalStorage,
+        l2: &Layout,
+    ) -> Result<(candle::MetalStorage, Shape)> {
+        use candle::backend::{BackendDevice, BackendStorage};
+
+        let device = s1.device();
+        candle::determinism::ensure(
+            "rasterize-gaussians-backward",
+            device.location(),
+            ATOMIC_GRADS,
+        )?;
+        let (n, channels) = gpu_params_dims(l1, &self.background)?;
+        let (width, height) = (self.width, self.height);
+        let stride = NUM_GEOMETRY_PARAMS + channels;
+        let (keys, ranges) = metal_tile_bins(s1, l1, n, stride, width, height)?;
+        let shape = Shape::from((n, stride));
+        let grads = device.zeros_impl(&shape, DType::F32)?;
+        let command_buffer = device.command_buffer()?;
+        command_buffer.set_label("rasterize-gaussians-backward");
+        candle_metal_kernels::call_rasterize_gaussians_backward(
+            device.metal_device(),
+            &command_buffer,
+            device.kernels(),
+            (channels, width, height),
+            metal_buffer_offset(s1, l1),
+            &keys,
+            &ranges,
+            metal_buffer_offset(s2, l2),
+            &self.background,
+            grads.buffer(),
+        )
+        .map_err(candle::Error::wrap)?;
+        Ok((grads, shape))
+    }
+}
+
+#[cfg(any(feature = "cuda", feature = "metal"))]
+fn gpu_params_dims(layout: &Layout, background: &[f32]) -> Result<(usize, usize)> {
+    let (n, channels) = params_dims(layout, background)?;
+    if channels > MAX_GPU_CHANNELS {
+        candle::bail!("rasterize: at most {MAX_GPU_CHANNELS} channels are supported on gpu")
+    }
+    if !layout.is_contiguous() {
+        candle::bail!("rasterize: the inputs have to be contiguous")
+    }
+    Ok((n, channels))
+}
+
+#[cfg(feature = "cuda")]
+fn cuda_contiguous<'a>(
+    storage: &'a candle::CudaStorage,
+    layout: &Layout,
+) -> Result<candle::cuda_backend::cudarc::driver::CudaView<'a, f32>> {
+    match layout.contiguous_offsets() {
+        None => candle::bail!("rasterize: the inputs have to be contiguous"),
+        Some((o1, o2)) => Ok(storage.as_cuda_slice::<f32>()?.slice(o1..o2)),
+    }
+}
+
+// The threads of the single block of the prefix sum kernel, GS_SCAN_THREADS in the kernels.
+#[cfg(feature = "cuda")]
+const CUDA_SCAN_THREADS: usize = 1024;
+// The keys are sorted by blocks of 256 keys, 8 bits per pass.
+#[cfg(feature = "cuda")]
+const SORT_THREADS: usize = 256;
+#[cfg(any(feature = "cuda", feature = "metal"))]
+const RADIX_BITS: usize = 8;
+
+// The number of radix sort passes needed to sort the keys by tile.
+#[cfg(any(feature = "cuda", feature = "metal"))]
+fn radix_passes(width: usize, height: usize) -> usize {
+    let tiles = width.div_ceil(TILE_SIZE) * height.div_ceil(TILE_SIZE);
+    let bits = usize::BITS - tiles.saturating_sub(1).leading_zeros();
+    (bits as usize).div_ceil(RADIX_BITS)
+}
+
+#[cfg(any(feature = "cuda", feature = "metal"))]
+fn check_num_gaussians(n: usize) -> Result<()> {
+    // The keys store the index of the gaussians on 32 bits.
+    if n > u32::MAX as usize {
+        candle::bail!(
+            "rasterize: at most {} gaussians are supported on gpu",
+            u32::MAX
+        )
+    }
+    Ok(())
+}
+
+// The exclusive prefix sum of the `n` first values of `src`, with the total as the last value.
+#[cfg(feature = "cuda")]
+fn cuda_exclusive_scan(
+    dev: &candle::CudaDevice,
+    src: &candle::cuda_backend::cudarc::driver::CudaSlice<u32>,
+    n: usize,
+) -> Result<candle::cuda_backend::cudarc::driver::CudaSlice<u32>> {
+    use candle::cuda_backend::cudarc::driver::{LaunchAsync, LaunchConfig};
+    use candle::cuda_backend::{kernels, WrapErr};
+
+    // SAFETY: Set later by running the kernel.
+    let dst = unsafe { dev.alloc::<u32>(n + 1) }.w()?;
+    let cfg = LaunchConfig {
+        grid_dim: (1, 1, 1),
+        block_dim: (CUDA_SCAN_THREADS as u32, 1, 1),
+        shared_mem_bytes: 0,
+    };
+    let func = dev.get_or_load_func("gaussians_exclusive_scan", kernels::GAUSSIAN_SPLATTING)?;
+    // SAFETY: ffi.
+    unsafe { func.launch(cfg, (src, &dst, n)) }.w()?;
+    Ok(dst)
+}
+
+// The gpu version of `tile_lists`: the keys `(tile << 32) | index` of the tiles overlapped by
+// the gaussians sorted by tile and then by index, i.e. by depth, and the `(start, end)` range of
+// the keys of each tile. The number of keys is copied to the host to allocate them.
+#[cfg(feature = "cuda")]
+fn cuda_tile_bins(
+    dev: &candle::CudaDevice,
+    params: &candle::cuda_backend::cudarc::driver::CudaView<f32>,
+    n: usize,
+    stride: usize,
+    width: usize,
+    height: usize,
+) -> Result<(
+    candle::cuda_backend::cudarc::driver::CudaSlice<u64>,
+    candle::cuda_backend::cudarc::driver::CudaSlice<u32>,
+)> {
+    use candle::cuda_backend::cudarc::driver::{LaunchAsync, LaunchConfig};
+    use candle::cuda_backend::{kernels, WrapErr};
+
+    check_num_gaussians(n)?;
+    let tiles = width.div_ceil(TILE_SIZE) * height.div_ceil(TILE_SIZE);
+    let ranges = dev.alloc_zeros::<u32>(2 * tiles).w()?;
+    // When no gaussian overlaps the image, all the ranges are empty.
+    if n == 0 {
+        return Ok((dev.alloc_zeros::<u64>(1).w()?, ranges));
+    }
+    let load = |name| dev.get_or_load_func(name, kernels::GAUSSIAN_SPLATTING);
+    // SAFETY: Set later by running the kernel.
+    let counts = unsafe { dev.alloc::<u32>(n) }.w()?;
+    let cfg = LaunchConfig::for_num_elems(n as u32);
+    let args = (params, &counts, n, stride, width, height);
+    // SAFETY: ffi.
+    unsafe { load("gaussians_count_tiles")?.launch(cfg, args) }.w()?;
+    let offsets = cuda_exclusive_scan(dev, &counts, n)?;
+    let total = dev.dtoh_sync_copy(&offsets.slice(n..n + 1)).w()?[0] as usize;
+    if total == 0 {
+        return Ok((dev.alloc_zeros::<u64>(1).w()?, ranges));
+    }
+
+    // SAFETY: Set later by running the kernels.
+    let mut keys = unsafe { dev.alloc::<u64>(total) }.w()?;
+    let mut tmp = unsafe { dev.alloc::<u64>(total) }.w()?;
+    let args = (params, &offsets, &keys, n, stride, width, height);
+    // SAFETY: ffi.
+    unsafe { load("gaussians_emit_keys")?.launch(cfg, args) }.w()?;
+    // Stable radix sort on the tile bits, the keys of a tile keep the order of the gaussians.
+    let blocks = total.div_ceil(SORT_THREADS);
+    let cfg = LaunchConfig {
+        grid_dim: (blocks as u32, 1, 1),
+        block_dim: (SORT_THREADS as u32, 1, 1),
+        shared_mem_bytes: 0,
+    };
+    // SAFETY: Set later by running the kernel.
+    let hist = unsafe { dev.alloc::<u32>(blocks << RADIX_BITS) }.w()?;
+    for pass in 0..radix_passes(width, height) {
+        let shift = (32 + pass * RADIX_BITS) as u32;
+        // SAFETY: ffi.
+        unsafe { load("gaussians_radix_histogram")?.launch(cfg, (&keys, &hist, total, shift)) }
+            .w()?;
+        let offsets = cuda_exclusive_scan(dev, &hist, blocks << RADIX_BITS)?;
+        let args = (&keys, &offsets, &tmp, total, shift);
+        // SAFETY: ffi.
+        unsafe { load("gaussians_radix_scatter")?.launch(cfg, args) }.w()?;
+        std::mem::swap(&mut keys, &mut tmp)
+    }
+    let cfg = LaunchConfig::for_num_elems(total as u32);
+    // SAFETY: ffi.
+    unsafe { load("gaussians_tile_ranges")?.launch(cfg, (&keys, &ranges, total)) }.w()?;
+    Ok((keys, ranges))
+}
+
+// The metal version of `cuda_tile_bins`, the keys are stored as u64 values.
+#[cfg(feature = "metal")]
+fn metal_tile_bins(
+    storage: &candle::MetalStorage,
+    layout: &Layout,
+    n: usize,
+    stride: usize,
+    width: usize,
+    height: usize,
+) -> Result<(std::sync::Arc<metal::Buffer>, std::sync::Arc<metal::Buffer>)> {
+    use candle::backend::BackendStorage;
+    use candle_metal_kernels as kernels;
+
+    check_num_gaussians(n)?;
+    let device = storage.device();
+    let tiles = width.div_ceil(TILE_SIZE) * height.div_ceil(TILE_SIZE);
+    let ranges = device.allocate_zeros(2 * tiles * DType::U32.size_in_bytes())?;
+    // When no gaussian overlaps the image, all the ranges are empty.
+    if n == 0 {
+        return Ok((device.allocate_zeros(DType::I64.size_in_bytes())?, ranges));
+    }
+    let (mtl, k) = (device.metal_device(), device.kernels());
+    let dims = (n, stride, width, height);
+    let counts = device.new_buffer(n, DType::U32, "gaussians-counts")?;
+    let offsets = device.new_buffer(n + 1, DType::U32, "gaussians-offsets")?;
+    let total = device.new_buffer_managed(DType::U32.size_in_bytes() as u64)?;
+    {
+        let command_buffer = device.command_buffer()?;
+        command_buffer.set_label("gaussians-count-tiles");
+        kernels::call_gaussians_count_tiles(
+            mtl,
+            &command_buffer,
+            k,
+            dims,
+            metal_buffer_offset(storage, layout),
+            &counts,
+        )
+        .map_err(candle::Error::wrap)?;
+        kernels::call_gaussians_exclusive_scan(mtl, &command_buffer, k, n, &counts, &offsets)
+            .map_err(candle::Error::wrap)?;
+        let blit = command_buffer.new_blit_command_encoder();
+        blit.set_label("gaussians-total");
+        let size = DType::U32.size_in_bytes() as u64;
+        blit.copy_from_buffer(&offsets, n as u64 * size, &total, 0, size);
+        blit.end_encoding();
+    }
+    device.wait_until_completed()?;
+    // SAFETY: the buffer holds a single u32 written by the blit above, which has completed.
+    let total = unsafe { *(total.contents() as *const u32) } as usize;
+    if total == 0 {
+        return Ok((device.allocate_zeros(DType::I64.size_in_bytes())?, ranges));
+    }
+
+    // The keys are u64 values, i.e. 8 bytes like I64.
+    let mut keys = device.new_buffer(total, DType::I64, "gaussians-keys")?;
+    let mut tmp = device.new_buffer(total, DType::I64, "gaussians-keys")?;
+    let hist_len = kernels::gaussians_radix_threadgroups(total) << RADIX_BITS;
+    let hist = device.new_buffer(hist_len, DType::U32, "gaussians-hist")?;
+    let hist_offsets = device.new_buffer(hist_len + 1, DType::U32, "gaussians-hist")?;
+    let command_buffer = device.command_buffer()?;
+    command_buffer.set_label("gaussians-tile-bins");
+    kernels::call_gaussians_emit_keys(
+        mtl,
+        &command_buffer,
+        k,
+        dims,
+        metal_buffer_offset(storage, layout),
+        &offsets,
+        &keys,
+    )
+    .map_err(candle::Error::wrap)?;
+    // Stable radix sort on the tile bits, the keys of a tile keep the order of the gaussians.
+    for pass in 0..radix_passes(width, height) {
+        let shift = (32 + pass * RADIX_BITS) as u32;
+        kernels::call_gaussians_radix_histogram(
+            mtl,
+            &command_buffer,
+            k,
+            (total, shift),
+            &keys,
+            &hist,
+        )
+        .map_err(candle::Error::wrap)?;
+        kernels::call_gaussians_exclusive_scan(
+            mtl,
+            &command_buffer,
+            k,
+            hist_len,
+            &hist,
+            &hist_offsets,
+        )
+        .map_err(candle::Error::wrap)?;
+        kernels::call_gaussians_radix_scatter(
+            mtl,
+            &command_buffer,
+            k,
+            (total, shift),
+            &keys,
+            &hist_offsets,
+            &tmp,
+        )
+        .map_err(candle::Error::wrap)?;
+        std::mem::swap(&mut keys, &mut tmp)
+    }
+    kernels::call_gaussians_tile_ranges(mtl, &command_buffer, k, total, &keys, &ranges)
+        .map_err(candle::Error::wrap)?;
+    Ok((keys, ranges))
+}
+
+// A block of threads per tile, the gaussians are loaded in shared memory by batches of one per
+// thread.
+#[cfg(feature = "cuda")]
+fn cuda_launch_config(
+    width: usize,
+    height: usize,
+    channels: usize,
+) -> candle::cuda_backend::cudarc::driver::LaunchConfig {
+    let threads = TILE_SIZE * TILE_SIZE;
+    candle::cuda_backend::cudarc::driver::LaunchConfig {
+        grid_dim: (
+            width.div_ceil(TILE_SIZE) as u32,
+            height.div_ceil(TILE_SIZE) as u32,
+            1,
+        ),
+        block_dim: (TILE_SIZE as u32, TILE_SIZE as u32, 1),
+        shared_mem_bytes: (threads * (NUM_GEOMETRY_PARAMS + channels) * 4) as u32,
+    }
+}
+
+#[cfg(feature = "metal")]
+fn metal_buffer_offset<'a>(
+    storage: &'a candle::MetalStorage,
+    layout: &Layout,
+) -> candle_metal_kernels::BufferOffset<'a> {
+    candle_metal_kernels::BufferOffset {
+        buffer: storage.buffer(),
+        offset_in_bytes: layout.start_offset() * DType::F32.size_in_bytes(),
+    }
+}
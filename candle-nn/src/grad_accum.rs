@@ -0,0 +1,153 @@
+//! Gradient accumulation over multiple micro-batches.
+//!
+//! A [`GradAccumulator`] sums the gradients of the micro-batches and only returns them once
+//! enough micro-batches have been processed, normalized so that the optimizer step is the same
+//! as with a single large batch:
+//!
+//! - [`GradAccumulator::backward`] is for losses averaged over each micro-batch, every
+//!   micro-batch has the same weight in the final gradients.
+//! - [`GradAccumulator::backward_sum`] is for losses summed over a number of elements, e.g. the
+//!   non-padding tokens of the micro-batch. The gradients are divided by the total number of
+//!   elements so micro-batches with more tokens get a larger weight, averaging per-micro-batch
+//!   means would be incorrect in this case.
+//!
+//! When a communicator is set, the gradients are only all-reduced on the last micro-batch,
+//! which avoids one reduction per micro-batch for data parallel training. This should not be
+//! used with [`crate::zero::DistributedOptimizer`] which already reduces the gradients in its
+//! step. With [`crate::fsdp`] the gradients are reduce-scattered during each backward pass as the
+//! gathered parameters are released, the shard gradients can then be accumulated with
+//! [`GradAccumulator::accumulate`].
+//!
+//! ```ignore
+//! let mut accum = GradAccumulator::new(varmap.all_vars(), 4)?;
+//! for batch in micro_batches {
+//!     let loss = model.forward(&batch)?.mean_all()?;
+//!     if let Some(grads) = accum.backward(&loss)? {
+//!         opt.step(&grads)?;
+//!     }
+//! }
+//! ```
+use crate::distributed::Communicator;
+use candle::backprop::GradStore;
+use candle::{Result, Tensor, Var};
+use std::sync::Arc;
+
+pub struct GradAccumulator {
+    vars: Vec<Var>,
+    steps: usize,
+    micro_step: usize,
+    // The sum of the gradients for each variable and the normalization of this sum.
+    grads: Vec<Option<Tensor>>,
+    denominator: f64,
+    comm: Option<Arc<dyn Communicator>>,
+}
+
+impl GradAccumulator {
+    /// Accumulates the gradients of `vars` over `steps` micro-batches.
+    pub fn new(vars: Vec<Var>, steps: usize) -> Result<Self> {
+        if steps == 0 {
+            candle::bail!("the number of accumulation steps should be positive")
+        }
+        let grads = vec![None; vars.len()];
+        Ok(Self {
+            vars,
+            steps,
+            micro_step: 0,
+            grads,
+            denominator: 0.,
+            comm: None,
+        })
+    }
+
+    /// All-reduces the accumulated gradients over the ranks of `comm` on the last micro-batch.
+    pub fn with_communicator(mut self, comm: Arc<dyn Communicator>) -> Self {
+        self.comm = Some(comm);
+        self
+    }
+
+    pub fn steps(&self) -> usize {
+        self.steps
+    }
+
+    /// The number of micro-batches accumulated since the last optimizer step.
+    pub fn micro_step(&self) -> usize {
+        self.micro_step
+    }
+
+    /// Whether the next micro-batch completes the accumulation, this can be used to only
+    /// synchronize other states on the last micro-batch.
+    pub fn is_last_micro_step(&self) -> bool {
+        self.micro_step + 1 == self.steps
+    }
+
+    /// Drops the gradients accumulated so far.
+    pub fn reset(&mut self) {
+        self.micro_step = 0;
+        self.denominator = 0.;
+        self.grads.iter_mut().for_each(|g| *g = None)
+    }
+
+    /// Back-propagates a loss averaged over the micro-batch, returns the gradients to use for the
+    /// optimizer step on the last micro-batch and `None` otherwise.
+    pub fn backward(&mut self, loss: &Tensor) -> Result<Option<GradStore>> {
+        let grads = loss.backward()?;
+        self.accumulate_weighted(&grads, 1.)
+    }
+
+    /// Back-propagates a loss summed over `count` elements, the final gradients are divided by
+    /// the total number of elements over all the micro-batches.
+    pub fn backward_sum(&mut self, loss: &Tensor, count: usize) -> Result<Option<GradStore>> {
+        let grads = loss.backward()?;
+        self.accumulate_weighted(&grads, count as f64)
+    }
+
+    /// Accumulates gradients that have already been computed, each call counts as one
+    /// micro-batch with the same weight.
+    pub fn accumulate(&mut self, grads: &GradStore) -> Result<Option<GradStore>> {
+        self.accumulate_weighted(grads, 1.)
+    }
+
+    fn accumulate_weighted(&mut self, grads: &GradStore, weight: f64) -> Result<Option<GradStore>> {
+        for (var, acc) in self.vars.iter().zip(self.grads.iter_mut()) {
+            if let Some(grad) = grads.get(var) {
+                *acc = match acc.take() {
+                    None => Some(grad.copy()?),
+                    Some(acc) => Some((acc + grad)?),
+                }
+            }
+        }
+        self.denominator += weight;
+        self.micro_step += 1;
+        if self.micro_step < self.steps {
+            return Ok(None);
+        }
+        let mut denominator = self.denominator;
+        let mut accumulated = std::mem::replace(&mut self.grads, vec![None; self.vars.len()]);
+        if let Some(comm) = self.comm.as_ref() {
+            let device = self
+                .vars
+                .first()
+                .map_or(candle::Device::Cpu, |v| v.device().clone());
+            let d = Tensor::new(&[denominator as f32], &device)?;
+            denominator = comm.all_reduce(&d)?.to_vec1::<f32>()?[0] as f64;
+            // All the ranks have to take part in every reduction, so the variables without
+            // gradients use zeros.
+            for (var, acc) in self.vars.iter().zip(accumulated.iter_mut()) {
+                let grad = match acc.take() {
+                    Some(grad) => grad,
+                    None => var.zeros_like()?,
+                };
+                *acc = Some(comm.all_reduce(&grad)?)
+            }
+        }
+        self.micro_step = 0;
+        self.denominator = 0.;
+        let mut store = GradStore::new();
+        for (var, grad) in self.vars.iter().zip(accumulated) {
+            if let Some(grad) = grad {
+                store.insert(var, (grad / denominator)?);
+            }
+        }
+        Ok(Some(store))
+    }
+}
@@ -0,0 +1,192 @@
+//! Gradient clipping.
+//!
+//! [`clip_grad_norm_`] rescales the gradients of some variables so that their global L2 norm,
+//! the norm of all the gradients concatenated, is at most `max_norm`, and [`clip_grad_value_`]
+//! clamps each gradient element. Both modify the gradients in place in the [`GradStore`], as
+//! the PyTorch functions of the same names.
+//!
+//! ```ignore
+//! let mut grads = loss.backward()?;
+//! let norm = clip_grad_norm_(&varmap.all_vars(), &mut grads, 1.0)?;
+//! opt.step(&grads)?;
+//! ```
+//!
+//! On CUDA the sum of the squares of all the gradients with the same dtype is computed by a
+//! single kernel launch, the gradients are then only rescaled when the norm exceeds the limit.
+use candle::backprop::GradStore;
+use candle::{DType, Result, Tensor, Var};
+
+// The gradients of `vars` in `grads`, some variables may not have one.
+fn var_grads<'a>(vars: &'a [Var], grads: &'a GradStore) -> impl Iterator<Item = &'a Tensor> {
+    vars.iter().filter_map(|var| grads.get(var))
+}
+
+// The sum of the squares of the elements of `grads`, as a f32 scalar on their device.
+fn sum_sq(grads: &[&Tensor]) -> Result<Tensor> {
+    #[cfg(feature = "cuda")]
+    if let Some(sum_sq) = cuda::sum_sq(grads)? {
+        return Ok(sum_sq);
+    }
+    let mut total: Option<Tensor> = None;
+    for grad in grads {
+        let s = grad.sqr()?.sum_all()?.to_dtype(DType::F32)?;
+        total = Some(match total {
+            None => s,
+            Some(total) => (total + s)?,
+        })
+    }
+    match total {
+        Some(total) => Ok(total),
+        None => candle::bail!("no gradients to clip"),
+    }
+}
+
+/// The global L2 norm of the gradients of `vars`, the variables without a gradient are ignored.
+pub fn grad_norm(vars: &[Var], grads: &GradStore) -> Result<f64> {
+    let grads = var_grads(vars, grads).collect::<Vec<_>>();
+    if grads.is_empty() {
+        return Ok(0.);
+    }
+    // The sums are computed per device and only the final values are copied to the host.
+    let mut total = 0f64;
+    let mut done = vec![false; grads.len()];
+    for i in 0..grads.len() {
+        if done[i] {
+            continue;
+        }
+        let device = grads[i].device();
+        let group = (i..grads.len())
+            .filter(|&j| !done[j] && grads[j].device().same_device(device))
+            .collect::<Vec<_>>();
+        group.iter().for_each(|&j| done[j] = true);
+        let group = group.iter().map(|&j| grads[j]).collect::<Vec<_>>();
+        total += sum_sq(&group)?.to_vec0::<f32>()? as f64;
+    }
+    Ok(total.sqrt())
+}
+
+/// Rescales the gradients of `vars` so that their global L2 norm is at most `max_norm`, and
+/// returns the norm before clipping.
+///
+/// The gradients are multiplied by `max_norm / (norm + 1e-6)` when the norm is larger than
+/// `max_norm`. They are left unchanged when the norm is not finite, the optimizer step should
+/// usually be skipped in this case.
+pub fn clip_grad_norm_(vars: &[Var], grads: &mut GradStore, max_norm: f64) -> Result<f64> {
+    if max_norm < 0. {
+        candle::bail!("the maximum norm {max_norm} should not be negative")
+    }
+    let norm = grad_norm(vars, grads)?;
+    if norm.is_finite() && norm > max_norm {
+        let coef = max_norm / (norm + 1e-6);
+        for var in vars.iter() {
+            if let Some(grad) = grads.get(var) {
+                let grad = (grad * coef)?;
+                grads.insert(var, grad);
+            }
+        }
+    }
+    Ok(norm)
+}
+
+/// Clamps the elements of the gradients of `vars` to `[-clip_value, clip_value]`.
+pub fn clip_grad_value_(vars: &[Var], grads: &mut GradStore, clip_value: f64) -> Result<()> {
+    if clip_value < 0. {
+        candle::bail!("the clip value {clip_value} should not be negative")
+    }
+    for var in vars.iter() {
+        if let Some(grad) = grads.get(var) {
+            let grad = grad.clamp(-clip_value, clip_value)?;
+            grads.insert(var, grad);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(feature = "cuda")]
+mod cuda {
+    use candle::cuda_backend::cudarc::driver::{DevicePtr, LaunchAsync, LaunchConfig};
+    use candle::cuda_backend::{kernels, CudaStorageSlice, WrapErr};
+    use candle::{DType, Result, Storage, Tensor};
+
+    // The threads per block of the kernel, QBLOCK in optim.cu.
+    const BLOCK_SIZE: usize = 256;
+    const MAX_BLOCKS_PER_TENSOR: usize = 512;
+    // The maximum number of tensors per launch, the y dimension of the grid.
+    const MAX_TENSORS: usize = 65535;
+
+    /// The sum of the squares with the multi-tensor kernel, `None` if the gradients are not
+    /// all bf16, f16 or f32 cuda tensors with the same dtype. The kernel writes a partial sum
+    /// per block which are then reduced by a regular sum, so the result is deterministic.
+    pub(super) fn sum_sq(grads: &[&Tensor]) -> Result<Option<Tensor>> {
+        let (dev, dtype) = match grads.first() {
+            Some(g) => match g.device() {
+                candle::Device::Cuda(dev) => (dev.clone(), g.dtype()),
+                _ => return Ok(None),
+            },
+            None => return Ok(None),
+        };
+        if !matches!(dtype, DType::BF16 | DType::F16 | DType::F32)
+            || grads.iter().any(|g| g.dtype() != dtype)
+        {
+            return Ok(None);
+        }
+        let grads = grads
+            .iter()
+            .map(|g| g.contiguous())
+            .collect::<Result<Vec<_>>>()?;
+        let mut sums = Vec::with_capacity(grads.len().div_ceil(MAX_TENSORS));
+        let name = format!("sum_sq_multi_{}", dtype.as_str());
+        let func = dev.get_or_load_func(&name, kernels::OPTIM)?;
+        for grads in grads.chunks(MAX_TENSORS) {
+            // The storages are kept locked until the end of the launch.
+            let storages = grads
+                .iter()
+                .map(|g| g.storage_and_layout())
+                .collect::<Vec<_>>();
+            let mut ptrs = Vec::with_capacity(grads.len());
+            let mut lens = Vec::with_capacity(grads.len());
+            for (storage, layout) in storages.iter() {
+                let (start, end) = match layout.contiguous_offsets() {
+                    Some(offsets) => offsets,
+                    None => candle::bail!("the gradients should be contiguous"),
+                };
+                let ptr = match &**storage {
+                    Storage::Cuda(s) => match &s.slice {
+                        CudaStorageSlice::BF16(s) => *s.slice(start..end).device_ptr(),
+                        CudaStorageSlice::F16(s) => *s.slice(start..end).device_ptr(),
+                        CudaStorageSlice::F32(s) => *s.slice(start..end).device_ptr(),
+                        _ => candle::bail!("unexpected dtype for the gradients"),
+                    },
+                    _ => candle::bail!("the gradients should be on the same device"),
+                };
+                ptrs.push(ptr);
+                lens.push((end - start) as u64);
+            }
+            let max_len = lens.iter().copied().max().unwrap_or(0) as usize;
+            let blocks = max_len.div_ceil(BLOCK_SIZE).clamp(1, MAX_BLOCKS_PER_TENSOR);
+            let cfg = LaunchConfig {
+                grid_dim: (blocks as u32, grads.len() as u32, 1),
+                block_dim: (BLOCK_SIZE as u32, 1, 1),
+                shared_mem_bytes: 0,
+            };
+            let ptrs = dev.htod_sync_copy(&ptrs).w()?;
+            let lens = dev.htod_sync_copy(&lens).w()?;
+            let device = candle::Device::Cuda(dev.clone());
+            let out = Tensor::zeros((grads.len(), blocks), DType::F32, &device)?;
+            let (out_storage, out_layout) = out.storage_and_layout();
+            let out_ptr = match &*out_storage {
+                Storage::Cuda(s) => match &s.slice {
+                    CudaStorageSlice::F32(s) => *s.slice(out_layout.start_offset()..).device_ptr(),
+                    _ => candle::bail!("unexpected dtype for the sum"),
+                },
+                _ => candle::bail!("unexpected device for the sum"),
+            };
+            let params = (&ptrs, &lens, out_ptr);
+            // SAFETY: ffi, the pointers are valid while the storages are locked.
+            unsafe { func.clone().launch(cfg, params) }.w()?;
+            drop(out_storage);
+            sums.push(out.sum_all()?);
+        }
+        Ok(Some(Tensor::stack(&sums, 0)?.sum_all()?))
+    }
+}
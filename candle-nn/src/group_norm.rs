@@ -73,6 +73,20 @@ impl crate::Module for GroupNorm {
     }
 }
 
+impl crate::migrate::Migrate for GroupNorm {
+    fn visit_tensors(&mut self, f: &mut dyn FnMut(&mut Tensor) -> Result<()>) -> Result<()> {
+        f(&mut self.weight)?;
+        f(&mut self.bias)
+    }
+}
+
+impl crate::layer::Layer for GroupNorm {
+    fn visit_parameters(&mut self, f: &mut crate::layer::TensorFn) -> Result<()> {
+        f("weight", &mut self.weight)?;
+        f("bias", &mut self.bias)
+    }
+}
+
 pub fn group_norm(
     num_groups: usize,
     num_channels: usize,
@@ -0,0 +1,324 @@
+//! Forward hooks to capture or edit intermediate activations.
+//!
+//! A [`Hooks`] registry holds closures that are called before and after the forward pass of the
+//! modules wrapped with [`Hooks::wrap`], the modules are matched by name using the patterns of
+//! [`crate::var_map::matches_pattern`]. A pre-forward hook can replace the input of a module and
+//! a post-forward hook its output, returning `None` leaves the tensor unchanged. This is what
+//! feature extraction, logit lens or activation patching need, without changing the code of the
+//! modules.
+//!
+//! ```rust
+//! # fn main() -> candle::Result<()> {
+//! use candle::{DType, Device, Module, Tensor};
+//! use candle_nn::hooks::Hooks;
+//! let dev = Device::Cpu;
+//! let vb = candle_nn::VarBuilder::zeros(DType::F32, &dev);
+//! let hooks = Hooks::new();
+//! let fc1 = hooks.wrap("fc1", candle_nn::linear(4, 4, vb.pp("fc1"))?);
+//! let act = hooks.wrap_fn("act", |xs| xs.relu());
+//! let capture = hooks.capture("*");
+//! // Patches the output of fc1.
+//! let handle = hooks.add_post_hook("fc1", |_, _, ys| Ok(Some((ys + 1.)?)));
+//! let xs = Tensor::ones((1, 4), DType::F32, &dev)?;
+//! let ys = act.forward(&fc1.forward(&xs)?)?;
+//! assert_eq!(ys.to_vec2::<f32>()?, [[1., 1., 1., 1.]]);
+//! assert_eq!(capture.names(), ["fc1", "act"]);
+//! hooks.remove(handle);
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Hooks can also be run at arbitrary places of a forward pass using a [`HookPoint`].
+use crate::var_map::matches_pattern;
+use candle::{Module, Result, Tensor};
+use std::sync::{Arc, Mutex};
+
+/// A hook called with the name of the module and its input, the returned tensor if any replaces
+/// the input.
+pub type PreHook = dyn Fn(&str, &Tensor) -> Result<Option<Tensor>> + Send + Sync;
+
+/// A hook called with the name of the module, its input and its output, the returned tensor if
+/// any replaces the output.
+pub type PostHook = dyn Fn(&str, &Tensor, &Tensor) -> Result<Option<Tensor>> + Send + Sync;
+
+/// Identifies a registered hook, see [`Hooks::remove`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct HookHandle(usize);
+
+#[derive(Default)]
+struct Inner {
+    pre: Vec<(HookHandle, String, Arc<PreHook>)>,
+    post: Vec<(HookHandle, String, Arc<PostHook>)>,
+    next_id: usize,
+}
+
+impl Inner {
+    fn next_handle(&mut self) -> HookHandle {
+        self.next_id += 1;
+        HookHandle(self.next_id)
+    }
+}
+
+/// A registry of forward hooks, cloning it returns a handle to the same registry.
+#[derive(Clone, Default)]
+pub struct Hooks(Arc<Mutex<Inner>>);
+
+impl std::fmt::Debug for Hooks {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let inner = self.0.lock().unwrap();
+        f.debug_struct("Hooks")
+            .field("pre", &inner.pre.len())
+            .field("post", &inner.post.len())
+            .finish()
+    }
+}
+
+impl Hooks {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Registers a hook called before the forward pass of the modules matching `pattern`.
+    pub fn add_pre_hook<F>(&self, pattern: &str, f: F) -> HookHandle
+    where
+        F: Fn(&str, &Tensor) -> Result<Option<Tensor>> + Send + Sync + 'static,
+    {
+        let mut inner = self.0.lock().unwrap();
+        let handle = inner.next_handle();
+        inner.pre.push((handle, pattern.to_string(), Arc::new(f)));
+        handle
+    }
+
+    /// Registers a hook called after the forward pass of the modules matching `pattern`.
+    pub fn add_post_hook<F>(&self, pattern: &str, f: F) -> HookHandle
+    where
+        F: Fn(&str, &Tensor, &Tensor) -> Result<Option<Tensor>> + Send + Sync + 'static,
+    {
+        let mut inner = self.0.lock().unwrap();
+        let handle = inner.next_handle();
+        inner.post.push((handle, pattern.to_string(), Arc::new(f)));
+        handle
+    }
+
+    /// Records the outputs of the modules matching `pattern`, see [`Capture`].
+    pub fn capture(&self, pattern: &str) -> Capture {
+        let activations = Arc::new(Mutex::new(vec![]));
+        let recorded = activations.clone();
+        let handle = self.add_post_hook(pattern, move |name, _, ys| {
+            recorded
+                .lock()
+                .unwrap()
+                .push((name.to_string(), ys.detach()));
+            Ok(None)
+        });
+        Capture {
+            handle,
+            activations,
+        }
+    }
+
+    /// Removes a hook, returns false if the hook had already been removed.
+    pub fn remove(&self, handle: HookHandle) -> bool {
+        let mut inner = self.0.lock().unwrap();
+        let len = inner.pre.len() + inner.post.len();
+        inner.pre.retain(|(h, _, _)| *h != handle);
+        inner.post.retain(|(h, _, _)| *h != handle);
+        inner.pre.len() + inner.post.len() != len
+    }
+
+    /// Removes all the hooks.
+    pub fn clear(&self) {
+        let mut inner = self.0.lock().unwrap();
+        inner.pre.clear();
+        inner.post.clear();
+    }
+
+    pub fn is_empty(&self) -> bool {
+        let inner = self.0.lock().unwrap();
+        inner.pre.is_empty() && inner.post.is_empty()
+    }
+
+    /// Wraps `module` so that the hooks matching `name` get called on its forward pass.
+    pub fn wrap<M, S: Into<String>>(&self, name: S, module: M) -> Hooked<M> {
+        Hooked {
+            name: name.into(),
+            module,
+            hooks: self.clone(),
+        }
+    }
+
+    /// Same as [`Self::wrap`] for a closure.
+    pub fn wrap_fn<'a, S, F>(&self, name: S, f: F) -> Hooked<crate::Func<'a>>
+    where
+        S: Into<String>,
+        F: 'a + Fn(&Tensor) -> Result<Tensor> + Send + Sync,
+    {
+        self.wrap(name, crate::func(f))
+    }
+
+    /// A point where the hooks matching `name` get called, see [`HookPoint`].
+    pub fn hook_point<S: Into<String>>(&self, name: S) -> HookPoint {
+        HookPoint {
+            name: name.into(),
+            hooks: self.clone(),
+        }
+    }
+
+    /// Runs the pre-forward hooks matching `name` in registration order, each hook gets the
+    /// input returned by the previous one.
+    pub fn run_pre_hooks(&self, name: &str, xs: &Tensor) -> Result<Tensor> {
+        // The hooks are called without holding the lock so that they can register new hooks.
+        let hooks: Vec<_> = {
+            let inner = self.0.lock().unwrap();
+            inner
+                .pre
+                .iter()
+                .filter(|(_, p, _)| matches_pattern(p, name))
+                .map(|(_, _, f)| f.clone())
+                .collect()
+        };
+        let mut xs = xs.clone();
+        for f in hooks {
+            if let Some(new_xs) = f(name, &xs)? {
+                xs = new_xs
+            }
+        }
+        Ok(xs)
+    }
+
+    /// Runs the post-forward hooks matching `name` in registration order, each hook gets the
+    /// output returned by the previous one.
+    pub fn run_post_hooks(&self, name: &str, xs: &Tensor, ys: Tensor) -> Result<Tensor> {
+        let hooks: Vec<_> = {
+            let inner = self.0.lock().unwrap();
+            inner
+                .post
+                .iter()
+                .filter(|(_, p, _)| matches_pattern(p, name))
+                .map(|(_, _, f)| f.clone())
+                .collect()
+        };
+        let mut ys = ys;
+        for f in hooks {
+            if let Some(new_ys) = f(name, xs, &ys)? {
+                ys = new_ys
+            }
+        }
+        Ok(ys)
+    }
+}
+
+/// The activations recorded by [`Hooks::capture`], in the order of the forward pass. The
+/// activations are detached from the graph.
+#[derive(Debug, Clone)]
+pub struct Capture {
+    handle: HookHandle,
+    activations: Arc<Mutex<Vec<(String, Tensor)>>>,
+}
+
+impl Capture {
+    /// The hook recording the activations, removing it stops the recording.
+    pub fn handle(&self) -> HookHandle {
+        self.handle
+    }
+
+    pub fn names(&self) -> Vec<String> {
+        let activations = self.activations.lock().unwrap();
+        activations.iter().map(|(n, _)| n.clone()).collect()
+    }
+
+    /// The last activation recorded for `name`.
+    pub fn get(&self, name: &str) -> Option<Tensor> {
+        let activations = self.activations.lock().unwrap();
+        activations
+            .iter()
+            .rev()
+            .find(|(n, _)| n == name)
+            .map(|(_, t)| t.clone())
+    }
+
+    /// Returns the recorded activations and clears them.
+    pub fn take(&self) -> Vec<(String, Tensor)> {
+        std::mem::take(&mut *self.activations.lock().unwrap())
+    }
+}
+
+/// A module whose forward pass runs the hooks registered for its name.
+#[derive(Debug, Clone)]
+pub struct Hooked<M> {
+    name: String,
+    module: M,
+    hooks: Hooks,
+}
+
+impl<M> Hooked<M> {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn inner(&self) -> &M {
+        &self.module
+    }
+
+    pub fn inner_mut(&mut self) -> &mut M {
+        &mut self.module
+    }
+
+    pub fn into_inner(self) -> M {
+        self.module
+    }
+}
+
+impl<M: Module> Module for Hooked<M> {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        let xs = self.hooks.run_pre_hooks(&self.name, xs)?;
+        let ys = self.module.forward(&xs)?;
+        self.hooks.run_post_hooks(&self.name, &xs, ys)
+    }
+}
+
+/// The wrapper is transparent, its parameters and sub-layers are the ones of the module.
+impl<M: crate::Layer> crate::Layer for Hooked<M> {
+    fn visit_parameters(&mut self, f: &mut crate::layer::TensorFn) -> Result<()> {
+        self.module.visit_parameters(f)
+    }
+
+    fn visit_buffers(&mut self, f: &mut crate::layer::TensorFn) -> Result<()> {
+        self.module.visit_buffers(f)
+    }
+
+    fn visit_children(&mut self, f: &mut crate::layer::LayerFn) -> Result<()> {
+        self.module.visit_children(f)
+    }
+
+    fn set_own_training(&mut self, training: bool) {
+        self.module.set_own_training(training)
+    }
+
+    fn is_training(&self) -> Option<bool> {
+        self.module.is_training()
+    }
+}
+
+/// An identity module running the hooks registered for its name, to expose the intermediate
+/// values of a forward pass that are not the output of a module, e.g. the residual stream. The
+/// pre-forward hooks run first, the post-forward hooks then get their result as both input and
+/// output.
+#[derive(Debug, Clone)]
+pub struct HookPoint {
+    name: String,
+    hooks: Hooks,
+}
+
+impl HookPoint {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+}
+
+impl Module for HookPoint {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        let xs = self.hooks.run_pre_hooks(&self.name, xs)?;
+        self.hooks.run_post_hooks(&self.name, &xs, xs.clone())
+    }
+}
@@ -1,6 +1,11 @@
 //! Cache Implementations
 //!
-use candle::{Device, Result, Tensor};
+//! The caches can be rolled back with `truncate` and duplicated with `fork`, e.g. to explore
+//! several continuations of a prompt in a tree search or to drop the rejected tokens in
+//! speculative decoding. A fork shares the buffer of the original cache until one of them
+//! appends to it, at which point the buffer is copied.
+use candle::{DType, Device, Result, Tensor, D};
+use std::sync::Arc;
 
 #[derive(Debug, Clone)]
 pub struct Cache {
@@ -12,6 +17,9 @@ pub struct Cache {
     dim: usize,
     current_seq_len: usize,
     max_seq_len: usize,
+    // Shared by the forks that use the same buffer, as `slice_set` writes to the buffer in place
+    // it is copied before being written to when shared.
+    buffer_owners: Arc<()>,
 }
 
 impl Cache {
@@ -21,6 +29,7 @@ impl Cache {
             dim,
             current_seq_len: 0,
             max_seq_len,
+            buffer_owners: Arc::new(()),
         }
     }
 
@@ -71,10 +80,45 @@ impl Cache {
                 self.max_seq_len
             )
         }
+        if Arc::strong_count(&self.buffer_owners) > 1 {
+            *ad = ad.copy()?;
+            self.buffer_owners = Arc::new(())
+        }
         ad.slice_set(src, self.dim, self.current_seq_len)?;
         self.current_seq_len += seq_len;
         Ok(())
     }
+
+    /// Keeps the entries at `indices` along `dim`, which has to be different from the sequence
+    /// dimension. This is used to reorder the batch, e.g. to follow the selected beams in beam
+    /// search, the number of indices can differ from the current batch size.
+    pub fn index_select(&mut self, indices: &Tensor, dim: usize) -> Result<()> {
+        if dim == self.dim {
+            candle::bail!("kv-cache: cannot index-select along the sequence dimension {dim}")
+        }
+        if let Some(ad) = self.all_data.as_mut() {
+            *ad = ad.index_select(indices, dim)?;
+            self.buffer_owners = Arc::new(())
+        }
+        Ok(())
+    }
+
+    /// Drops the entries after the first `len` ones.
+    pub fn truncate(&mut self, len: usize) -> Result<()> {
+        if len > self.current_seq_len {
+            candle::bail!(
+                "kv-cache: cannot truncate to {len} above the current length {}",
+                self.current_seq_len
+            )
+        }
+        self.current_seq_len = len;
+        Ok(())
+    }
+
+    /// A copy of the cache that shares its buffer until one of the two caches is appended to.
+    pub fn fork(&self) -> Self {
+        self.clone()
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -146,6 +190,45 @@ impl KvCache {
         self.k.reset();
         self.v.reset();
     }
+
+    /// Keeps the keys and values at `indices` along `dim`, see [`Cache::index_select`].
+    pub fn index_select(&mut self, indices: &Tensor, dim: usize) -> Result<()> {
+        self.k.index_select(indices, dim)?;
+        self.v.index_select(indices, dim)
+    }
+
+    /// Drops the keys and values after the first `len` positions.
+    pub fn truncate(&mut self, len: usize) -> Result<()> {
+        self.k.truncate(len)?;
+        self.v.truncate(len)
+    }
+
+    /// A copy-on-write copy of the cache, see [`Cache::fork`].
+    pub fn fork(&self) -> Self {
+        self.clone()
+    }
+
+    /// Saves the state of the cache so that it can be restored after appending to it or
+    /// truncating it. The snapshot shares the buffer of the cache, so the next append copies it
+    /// once; to only drop the positions appended after the snapshot, [`KvCache::truncate`] is
+    /// cheaper.
+    pub fn snapshot(&self) -> KvCacheSnapshot {
+        KvCacheSnapshot(self.fork())
+    }
+
+    pub fn restore(&mut self, snapshot: &KvCacheSnapshot) {
+        *self = snapshot.0.fork()
+    }
+}
+
+/// The state of a [`KvCache`] saved by [`KvCache::snapshot`].
+#[derive(Debug, Clone)]
+pub struct KvCacheSnapshot(KvCache);
+
+impl KvCacheSnapshot {
+    pub fn current_seq_len(&self) -> usize {
+        self.0.current_seq_len()
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -159,6 +242,7 @@ pub struct RotatingCache {
     // max_seq_len is the size of the rotating buffer, it is actually allowed for the full
     // sequence to grow past this limit.
     max_seq_len: usize,
+    buffer_owners: Arc<()>,
 }
 
 impl RotatingCache {
@@ -169,6 +253,7 @@ impl RotatingCache {
             offset: 0,
             current_seq_len: 0,
             max_seq_len,
+            buffer_owners: Arc::new(()),
         }
     }
 
@@ -223,6 +308,10 @@ impl RotatingCache {
             self.all_data = Some(ad)
         };
         let ad = self.all_data.as_mut().unwrap();
+        if Arc::strong_count(&self.buffer_owners) > 1 {
+            *ad = ad.copy()?;
+            self.buffer_owners = Arc::new(())
+        }
 
         self.current_seq_len += seq_len;
         if seq_len >= self.max_seq_len {
@@ -292,6 +381,31 @@ impl RotatingCache {
         Tensor::from_slice(&mask, (size1, size2), device)
     }
 
+    /// Drops the entries after the first `len` ones. This is only possible while the buffer has
+    /// not wrapped around, as the older entries get overwritten afterwards.
+    pub fn truncate(&mut self, len: usize) -> Result<()> {
+        if len > self.current_seq_len {
+            candle::bail!(
+                "kv-cache: cannot truncate to {len} above the current length {}",
+                self.current_seq_len
+            )
+        }
+        if self.current_seq_len > self.max_seq_len {
+            candle::bail!(
+                "kv-cache: cannot truncate a rotating cache after {} positions",
+                self.max_seq_len
+            )
+        }
+        self.current_seq_len = len;
+        self.offset = len % self.max_seq_len;
+        Ok(())
+    }
+
+    /// A copy of the cache that shares its buffer until one of the two caches is appended to.
+    pub fn fork(&self) -> Self {
+        self.clone()
+    }
+
     /// Returns the attn_mask to be applied *after* adding `seq_len` to the cache.
     pub fn attn_mask(&self, seq_len: usize, device: &Device) -> Result<Option<Tensor>> {
         let mask = if seq_len == 1 {
@@ -368,4 +482,319 @@ impl RotatingKvCache {
         self.k.reset();
         self.v.reset();
     }
+
+    /// Drops the keys and values after the first `len` positions, see
+    /// [`RotatingCache::truncate`].
+    pub fn truncate(&mut self, len: usize) -> Result<()> {
+        self.k.truncate(len)?;
+        self.v.truncate(len)
+    }
+
+    /// A copy-on-write copy of the cache, see [`RotatingCache::fork`].
+    pub fn fork(&self) -> Self {
+        self.clone()
+    }
+}
+
+/// The storage format of a [`QuantizedKvCache`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KvCacheDType {
+    /// Symmetric int8 with a scale of `absmax / 127`.
+    Int8,
+    /// FP8 with 4 exponent bits and 3 mantissa bits, saturating at 448 with no infinities, the
+    /// values are scaled so that the absmax maps to 448.
+    F8E4M3,
+}
+
+impl KvCacheDType {
+    fn max_value(&self) -> f64 {
+        match self {
+            Self::Int8 => 127.,
+            Self::F8E4M3 => 448.,
+        }
+    }
+
+    // The values of the 256 FP8 codes.
+    fn f8e4m3_table(device: &Device) -> Result<Tensor> {
+        let values: Vec<f32> = (0..=255u8)
+            .map(|c| {
+                let (e, m) = ((c >> 3) & 0xF, c & 0x7);
+                let v = match (e, m) {
+                    (15, 7) => f32::NAN,
+                    (0, m) => m as f32 * 2f32.powi(-9),
+                    (e, m) => (1. + m as f32 / 8.) * 2f32.powi(e as i32 - 7),
+                };
+                if c & 0x80 == 0 {
+                    v
+                } else {
+                    -v
+                }
+            })
+            .collect();
+        Tensor::from_vec(values, 256, device)
+    }
+
+    // Quantizes `xs` along the last dimension, returns the u8 codes and the f32 scales.
+    fn quantize(&self, xs: &Tensor) -> Result<(Tensor, Tensor)> {
+        let xs = xs.to_dtype(DType::F32)?;
+        let amax = xs.abs()?.max_keepdim(D::Minus1)?;
+        let scales = (amax / self.max_value())?.maximum(f32::MIN_POSITIVE)?;
+        let xs = xs.broadcast_div(&scales)?;
+        let codes = match self {
+            Self::Int8 => (xs.round()?.clamp(-127f32, 127f32)? + 128.)?,
+            Self::F8E4M3 => {
+                // The code of the magnitude is `(e + 6) * 8 + q` where `e` is the exponent,
+                // clamped to the subnormal exponent, and `q` is the value in units of the
+                // mantissa step `2^(e - 3)`. A mantissa rounded up to 16 carries into the
+                // exponent as expected.
+                let a = xs.abs()?.clamp(0f32, 448f32)?;
+                let e = (a.maximum(2f32.powi(-6))?.log()? / std::f64::consts::LN_2)?
+                    .floor()?
+                    .clamp(-6f32, 8f32)?;
+                let step = ((&e - 3.)? * std::f64::consts::LN_2)?.exp()?;
+                let q = (a / step)?.round()?;
+                let magnitude = (((e + 6.)? * 8.)? + q)?;
+                let sign = (xs.lt(0f32)?.to_dtype(DType::F32)? * 128.)?;
+                (magnitude + sign)?
+            }
+        };
+        Ok((codes.to_dtype(DType::U8)?, scales))
+    }
+
+    fn dequantize(&self, codes: &Tensor, scales: &Tensor) -> Result<Tensor> {
+        let values = match self {
+            Self::Int8 => (codes.to_dtype(DType::F32)? - 128.)?,
+            Self::F8E4M3 => {
+                let table = Self::f8e4m3_table(codes.device())?;
+                table
+                    .index_select(&codes.flatten_all()?.to_dtype(DType::U32)?, 0)?
+                    .reshape(codes.shape())?
+            }
+        };
+        values.broadcast_mul(scales)
+    }
+}
+
+/// A cache storing the appended tensors as 8-bit codes with one f32 scale per vector along the
+/// last dimension.
+#[derive(Debug, Clone)]
+pub struct QuantizedCache {
+    codes: Cache,
+    scales: Cache,
+    kv_dtype: KvCacheDType,
+    dtype: Option<DType>,
+}
+
+impl QuantizedCache {
+    /// The quantized dimension is the last one so `dim` has to be another dimension.
+    pub fn new(dim: usize, max_seq_len: usize, kv_dtype: KvCacheDType) -> Self {
+        Self {
+            codes: Cache::new(dim, max_seq_len),
+            scales: Cache::new(dim, max_seq_len),
+            kv_dtype,
+            dtype: None,
+        }
+    }
+
+    pub fn kv_dtype(&self) -> KvCacheDType {
+        self.kv_dtype
+    }
+
+    pub fn dim(&self) -> usize {
+        self.codes.dim()
+    }
+
+    pub fn current_seq_len(&self) -> usize {
+        self.codes.current_seq_len()
+    }
+
+    pub fn max_seq_len(&self) -> usize {
+        self.codes.max_seq_len()
+    }
+
+    pub fn reset(&mut self) {
+        self.codes.reset();
+        self.scales.reset();
+        self.dtype = None
+    }
+
+    pub fn append(&mut self, src: &Tensor) -> Result<()> {
+        if self.dim() + 1 >= src.rank() {
+            candle::bail!(
+                "kv-cache: cannot quantize along the cache dim {}",
+                self.dim()
+            )
+        }
+        let (codes, scales) = self.kv_dtype.quantize(src)?;
+        self.codes.append(&codes)?;
+        self.scales.append(&scales)?;
+        self.dtype = Some(src.dtype());
+        Ok(())
+    }
+
+    /// Drops the entries after the first `len` ones.
+    pub fn truncate(&mut self, len: usize) -> Result<()> {
+        self.codes.truncate(len)?;
+        self.scales.truncate(len)
+    }
+
+    /// A copy of the cache that shares its buffers until one of the two caches is appended to.
+    pub fn fork(&self) -> Self {
+        self.clone()
+    }
+
+    /// Dequantizes `len` positions starting from `start` to `dtype`.
+    pub fn dequantize_range(&self, start: usize, len: usize, dtype: DType) -> Result<Tensor> {
+        match (self.codes.all_data(), self.scales.all_data()) {
+            (Some(codes), Some(scales)) => {
+                let codes = codes.narrow(self.dim(), start, len)?;
+                let scales = scales.narrow(self.dim(), start, len)?;
+                self.kv_dtype.dequantize(&codes, &scales)?.to_dtype(dtype)
+            }
+            _ => candle::bail!("kv-cache: the cache is empty"),
+        }
+    }
+
+    /// The dequantized content of the cache, using the dtype of the appended tensors.
+    pub fn current_data(&self) -> Result<Option<Tensor>> {
+        match self.dtype {
+            None => Ok(None),
+            Some(dtype) => Ok(Some(self.dequantize_range(
+                0,
+                self.current_seq_len(),
+                dtype,
+            )?)),
+        }
+    }
+}
+
+/// A kv cache storing the keys and values in int8 or FP8, which halves or quarters the memory
+/// used by the cache compared to f16 or f32 for long contexts.
+///
+/// The keys and values have shape `(batch, kv_heads, seq_len, head_dim)` and are quantized with
+/// one scale per head and position. [`QuantizedKvCache::attention`] processes the cache in
+/// chunks of positions that are only dequantized when used, so the full precision keys and
+/// values are never materialized.
+#[derive(Debug, Clone)]
+pub struct QuantizedKvCache {
+    k: QuantizedCache,
+    v: QuantizedCache,
+    chunk_size: usize,
+}
+
+impl QuantizedKvCache {
+    pub fn new(max_seq_len: usize, kv_dtype: KvCacheDType) -> Self {
+        let k = QuantizedCache::new(2, max_seq_len, kv_dtype);
+        let v = QuantizedCache::new(2, max_seq_len, kv_dtype);
+        Self {
+            k,
+            v,
+            chunk_size: 1024,
+        }
+    }
+
+    /// The number of positions dequantized at once by [`QuantizedKvCache::attention`].
+    pub fn with_chunk_size(mut self, chunk_size: usize) -> Self {
+        self.chunk_size = chunk_size.max(1);
+        self
+    }
+
+    pub fn k_cache(&self) -> &QuantizedCache {
+        &self.k
+    }
+
+    pub fn v_cache(&self) -> &QuantizedCache {
+        &self.v
+    }
+
+    /// The dequantized keys.
+    pub fn k(&self) -> Result<Option<Tensor>> {
+        self.k.current_data()
+    }
+
+    /// The dequantized values.
+    pub fn v(&self) -> Result<Option<Tensor>> {
+        self.v.current_data()
+    }
+
+    pub fn append(&mut self, k: &Tensor, v: &Tensor) -> Result<()> {
+        self.k.append(k)?;
+        self.v.append(v)
+    }
+
+    pub fn current_seq_len(&self) -> usize {
+        self.k.current_seq_len()
+    }
+
+    pub fn reset(&mut self) {
+        self.k.reset();
+        self.v.reset();
+    }
+
+    /// Drops the keys and values after the first `len` positions.
+    pub fn truncate(&mut self, len: usize) -> Result<()> {
+        self.k.truncate(len)?;
+        self.v.truncate(len)
+    }
+
+    /// A copy-on-write copy of the cache, see [`Cache::fork`].
+    pub fn fork(&self) -> Self {
+        self.clone()
+    }
+
+    /// Computes the scaled dot product attention of `q` over the cached keys and values, this
+    /// matches [`crate::attention::scaled_dot_product_attention`] on the dequantized cache.
+    ///
+    /// The scores are computed in f32 and combined over the chunks with an online softmax. The
+    /// last dimension of the optional `mask` covers all the cached positions. With
+    /// [`AttentionConfig::causal`](crate::attention::AttentionConfig::causal) the queries are the
+    /// last `seq_len` cached positions.
+    pub fn attention(
+        &self,
+        q: &Tensor,
+        mask: Option<&Tensor>,
+        config: &crate::attention::AttentionConfig,
+    ) -> Result<Tensor> {
+        use crate::attention::{apply_mask, repeat_heads, BlockMask};
+        let (b_size, n_heads, seq_len, head_dim) = q.dims4()?;
+        let kv_len = self.current_seq_len();
+        let dtype = match self.v.dtype {
+            Some(dtype) => dtype,
+            None => candle::bail!("kv-cache: attention on an empty cache"),
+        };
+        let scale = config.scale.unwrap_or(1. / (head_dim as f64).sqrt());
+        let q = q.to_dtype(DType::F32)?.contiguous()?;
+        let shape = (b_size, n_heads, seq_len, 1);
+        // The running max starts from a finite value so that fully masked chunks do not
+        // produce nans.
+        let mut max = Tensor::full(f32::MIN, shape, q.device())?;
+        let mut sum = Tensor::zeros(shape, DType::F32, q.device())?;
+        let mut acc = Tensor::zeros((b_size, n_heads, seq_len, head_dim), DType::F32, q.device())?;
+        let causal = BlockMask::new(config.causal, seq_len, kv_len);
+        for start in (0..kv_len).step_by(self.chunk_size) {
+            let len = self.chunk_size.min(kv_len - start);
+            if causal.is_masked((0, seq_len), (start, len)) {
+                continue;
+            }
+            let k = repeat_heads(&self.k.dequantize_range(start, len, DType::F32)?, n_heads)?;
+            let v = repeat_heads(&self.v.dequantize_range(start, len, DType::F32)?, n_heads)?;
+            let mut scores = (q.matmul(&k.t()?.contiguous()?)? * scale)?;
+            if let Some(cap) = config.softcapping {
+                scores = ((scores / cap)?.tanh()? * cap)?;
+            }
+            if let Some(mask) = mask {
+                scores = apply_mask(&scores, &mask.narrow(D::Minus1, start, len)?)?;
+            }
+            if let Some(mask) = causal.block((0, seq_len), (start, len), q.device())? {
+                scores = apply_mask(&scores, &mask)?;
+            }
+            let new_max = max.maximum(&scores.max_keepdim(D::Minus1)?)?;
+            let p = scores.broadcast_sub(&new_max)?.exp()?;
+            let alpha = (max - &new_max)?.exp()?;
+            sum = ((sum * &alpha)? + p.sum_keepdim(D::Minus1)?)?;
+            acc = (acc.broadcast_mul(&alpha)? + p.matmul(&v.contiguous()?)?)?;
+            max = new_max;
+        }
+        acc.broadcast_div(&sum)?.to_dtype(dtype)
+    }
 }
@@ -0,0 +1,150 @@
+//! Moving the kv cache of a sequence between workers, e.g. from a prefill worker to a decode
+//! worker when the two phases of the generation run on different devices or processes.
+//!
+//! [`crate::paged_attention::PagedKvCache::export_sequence`] returns the blocks of a sequence
+//! for all the layers as [`KvBlocks`], which can be serialized or sent to other ranks with a
+//! [`Communicator`], and [`crate::paged_attention::PagedKvCache::import_sequence`] adds them as
+//! a new sequence in the cache of the decode worker.
+//!
+//! ```ignore
+//! // On the prefill rank.
+//! let blocks = cache.export_sequence(seq_id)?;
+//! send_kv_blocks(&comm, &blocks)?;
+//! cache.remove_sequence(seq_id)?;
+//! // On the decode rank.
+//! let blocks = recv_kv_blocks(&comm, prefill_rank, &device)?;
+//! cache.import_sequence(seq_id, &blocks)?;
+//! ```
+use crate::distributed::Communicator;
+use candle::{DType, Device, Result, Tensor};
+use std::collections::HashMap;
+
+const DTYPES: [DType; 7] = [
+    DType::U8,
+    DType::U32,
+    DType::I64,
+    DType::BF16,
+    DType::F16,
+    DType::F32,
+    DType::F64,
+];
+
+/// The keys and values of a sequence for all the layers, stored by blocks of `block_size`
+/// positions. The positions after `seq_len` in the last block are unused.
+#[derive(Debug, Clone)]
+pub struct KvBlocks {
+    seq_len: usize,
+    block_size: usize,
+    // (num_layers, 2, num_blocks, block_size, kv_heads, head_dim), keys then values.
+    data: Tensor,
+}
+
+impl KvBlocks {
+    /// `data` has shape `(num_layers, 2, num_blocks, block_size, kv_heads, head_dim)` with the
+    /// keys then the values of each layer.
+    pub fn new(seq_len: usize, data: Tensor) -> Result<Self> {
+        let dims = data.dims();
+        if dims.len() != 6 || dims[1] != 2 {
+            candle::bail!("unexpected shape for the kv blocks {:?}", data.shape())
+        }
+        let (num_blocks, block_size) = (dims[2], dims[3]);
+        if block_size == 0 || seq_len.div_ceil(block_size) != num_blocks {
+            candle::bail!(
+                "{num_blocks} blocks of {block_size} positions for a sequence of {seq_len} tokens"
+            )
+        }
+        Ok(Self {
+            seq_len,
+            block_size,
+            data,
+        })
+    }
+
+    pub fn seq_len(&self) -> usize {
+        self.seq_len
+    }
+
+    pub fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    pub fn num_layers(&self) -> usize {
+        self.data.dims()[0]
+    }
+
+    pub fn num_blocks(&self) -> usize {
+        self.data.dims()[2]
+    }
+
+    pub fn data(&self) -> &Tensor {
+        &self.data
+    }
+
+    pub fn to_device(&self, device: &Device) -> Result<Self> {
+        Ok(Self {
+            data: self.data.to_device(device)?,
+            ..*self
+        })
+    }
+
+    // The sizes needed by the receivers to allocate the tensors of a broadcast.
+    fn header(&self) -> Result<Vec<u32>> {
+        let dtype = match DTYPES.iter().position(|&d| d == self.data.dtype()) {
+            Some(dtype) => dtype,
+            None => candle::bail!("unsupported dtype {:?}", self.data.dtype()),
+        };
+        let mut header = vec![self.seq_len as u32, dtype as u32];
+        header.extend(self.data.dims().iter().map(|&d| d as u32));
+        Ok(header)
+    }
+
+    /// Serializes the blocks in the safetensors format.
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        let seq_len = Tensor::new(&[self.seq_len as u32], &Device::Cpu)?;
+        let data = self.data.to_device(&Device::Cpu)?;
+        let tensors = HashMap::from([("seq_len", seq_len), ("data", data)]);
+        safetensors::serialize(&tensors, &None).map_err(candle::Error::wrap)
+    }
+
+    pub fn from_bytes(bytes: &[u8], device: &Device) -> Result<Self> {
+        let mut tensors = candle::safetensors::load_buffer(bytes, device)?;
+        let (seq_len, data) = match (tensors.remove("seq_len"), tensors.remove("data")) {
+            (Some(seq_len), Some(data)) => (seq_len, data),
+            _ => candle::bail!("missing tensors in the serialized kv blocks"),
+        };
+        let seq_len = seq_len.to_vec1::<u32>()?;
+        match seq_len.as_slice() {
+            [seq_len] => Self::new(*seq_len as usize, data),
+            _ => candle::bail!("unexpected sequence length {seq_len:?}"),
+        }
+    }
+}
+
+/// Sends `blocks` from this rank to the other ranks of `comm`, which have to call
+/// [`recv_kv_blocks`] with this rank as the source. This uses two broadcasts, one for the sizes
+/// and one for the data, so all the ranks of the communicator receive the blocks.
+pub fn send_kv_blocks(comm: &dyn Communicator, blocks: &KvBlocks) -> Result<()> {
+    let device = blocks.data.device();
+    let header = blocks.header()?;
+    let len = header.len();
+    comm.broadcast(&Tensor::from_vec(header, len, device)?, comm.rank())?;
+    comm.broadcast(&blocks.data.contiguous()?, comm.rank())?;
+    Ok(())
+}
+
+/// Receives the blocks sent by the rank `src` with [`send_kv_blocks`], `device` has to be the
+/// device used by the communicator.
+pub fn recv_kv_blocks(comm: &dyn Communicator, src: usize, device: &Device) -> Result<KvBlocks> {
+    if src == comm.rank() {
+        candle::bail!("rank {src} cannot receive its own kv blocks")
+    }
+    let header = comm.broadcast(&Tensor::zeros(8, DType::U32, device)?, src)?;
+    let header = header.to_vec1::<u32>()?;
+    let dtype = match DTYPES.get(header[1] as usize) {
+        Some(&dtype) => dtype,
+        None => candle::bail!("unexpected dtype in the kv blocks header {header:?}"),
+    };
+    let dims = header[2..].iter().map(|&d| d as usize).collect::<Vec<_>>();
+    let data = comm.broadcast(&Tensor::zeros(dims, dtype, device)?, src)?;
+    KvBlocks::new(header[0] as usize, data)
+}
@@ -0,0 +1,310 @@
+//! Introspection of the parameters, sub-layers and training mode of modules.
+//!
+//! [`candle::Module`] only provides a forward pass. The [`Layer`] trait lets a module expose its
+//! parameters, buffers and sub-layers by name, so that generic code can list the parameters of a
+//! model, freeze some of them or switch the whole model between training and evaluation.
+//!
+//! A layer only visits its own tensors and its direct sub-layers, the recursive walks are done by
+//! the provided methods:
+//!
+//! ```rust
+//! use candle::{Result, Tensor};
+//! use candle_nn::layer::{Layer, LayerFn, TensorFn};
+//! use candle_nn::{Dropout, Linear};
+//!
+//! struct Mlp {
+//!     fc1: Linear,
+//!     dropout: Dropout,
+//!     fc2: Linear,
+//! }
+//!
+//! impl Layer for Mlp {
+//!     fn visit_children(&mut self, f: &mut LayerFn) -> Result<()> {
+//!         f("fc1", &mut self.fc1)?;
+//!         f("dropout", &mut self.dropout)?;
+//!         f("fc2", &mut self.fc2)
+//!     }
+//! }
+//!
+//! impl Mlp {
+//!     fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+//!         let xs = xs.apply(&self.fc1)?.relu()?;
+//!         // Applies the dropout only in training mode.
+//!         self.dropout.forward_mode(&xs)?.apply(&self.fc2)
+//!     }
+//! }
+//! # fn main() -> Result<()> {
+//! # let dev = candle::Device::Cpu;
+//! # let vb = candle_nn::VarBuilder::zeros(candle::DType::F32, &dev);
+//! let mut mlp = Mlp {
+//!     fc1: candle_nn::linear(4, 8, vb.pp("fc1"))?,
+//!     dropout: Dropout::new(0.1),
+//!     fc2: candle_nn::linear(8, 2, vb.pp("fc2"))?,
+//! };
+//! let names: Vec<_> = mlp.named_parameters()?.into_iter().map(|(n, _)| n).collect();
+//! assert_eq!(names, ["fc1.weight", "fc1.bias", "fc2.weight", "fc2.bias"]);
+//! mlp.train()?;
+//! assert_eq!(mlp.dropout.is_training(), Some(true));
+//! # Ok(())
+//! # }
+//! ```
+use crate::VarMap;
+use candle::{ModuleT, Result, Tensor, TensorId, Var};
+use std::collections::HashMap;
+
+/// A callback on a named tensor, the callback may replace the tensor.
+pub type TensorFn<'a> = dyn FnMut(&str, &mut Tensor) -> Result<()> + 'a;
+
+/// A callback on a named layer.
+pub type LayerFn<'a> = dyn FnMut(&str, &mut dyn Layer) -> Result<()> + 'a;
+
+/// A module whose parameters, buffers and sub-layers can be visited by name.
+pub trait Layer {
+    /// Calls `f` on the trained tensors of this layer, without the ones of the sub-layers, e.g.
+    /// `weight` and `bias` for a linear layer.
+    fn visit_parameters(&mut self, _f: &mut TensorFn) -> Result<()> {
+        Ok(())
+    }
+
+    /// Calls `f` on the tensors of this layer that are part of its state but are not trained,
+    /// e.g. the running statistics of a batch norm.
+    fn visit_buffers(&mut self, _f: &mut TensorFn) -> Result<()> {
+        Ok(())
+    }
+
+    /// Calls `f` on the direct sub-layers.
+    fn visit_children(&mut self, _f: &mut LayerFn) -> Result<()> {
+        Ok(())
+    }
+
+    /// Sets the training mode of this layer, without the sub-layers. This only has to be
+    /// implemented by the layers that behave differently when training, e.g. dropout.
+    fn set_own_training(&mut self, _training: bool) {}
+
+    /// The training mode of this layer, `None` for layers without such mode.
+    fn is_training(&self) -> Option<bool> {
+        None
+    }
+
+    /// Calls `f` on all the parameters of this layer and of its sub-layers, with their names
+    /// relative to this layer, e.g. `layers.0.weight`.
+    fn visit_named_parameters(&mut self, f: &mut TensorFn) -> Result<()>
+    where
+        Self: Sized,
+    {
+        visit_named(self, "", f, false)
+    }
+
+    /// Same as [`Self::visit_named_parameters`] for the buffers.
+    fn visit_named_buffers(&mut self, f: &mut TensorFn) -> Result<()>
+    where
+        Self: Sized,
+    {
+        visit_named(self, "", f, true)
+    }
+
+    /// All the parameters of this layer and of its sub-layers, in visit order.
+    fn named_parameters(&mut self) -> Result<Vec<(String, Tensor)>>
+    where
+        Self: Sized,
+    {
+        named_parameters(self)
+    }
+
+    /// All the buffers of this layer and of its sub-layers, in visit order.
+    fn named_buffers(&mut self) -> Result<Vec<(String, Tensor)>>
+    where
+        Self: Sized,
+    {
+        let mut buffers = vec![];
+        let mut f = |name: &str, t: &mut Tensor| {
+            buffers.push((name.to_string(), t.clone()));
+            Ok(())
+        };
+        visit_named(self, "", &mut f, true)?;
+        Ok(buffers)
+    }
+
+    /// Calls `f` on this layer, with an empty name, and then on all its sub-layers recursively
+    /// with their names relative to this layer.
+    fn apply(&mut self, f: &mut LayerFn) -> Result<()>
+    where
+        Self: Sized,
+    {
+        apply(self, f)
+    }
+
+    /// Sets the training mode of this layer and of all its sub-layers.
+    fn set_training(&mut self, training: bool) -> Result<()>
+    where
+        Self: Sized,
+    {
+        set_training(self, training)
+    }
+
+    fn train(&mut self) -> Result<()>
+    where
+        Self: Sized,
+    {
+        self.set_training(true)
+    }
+
+    fn eval(&mut self) -> Result<()>
+    where
+        Self: Sized,
+    {
+        self.set_training(false)
+    }
+
+    /// Runs the forward pass using the training mode of the layer, the layers without a training
+    /// mode use the evaluation behavior.
+    fn forward_mode(&self, xs: &Tensor) -> Result<Tensor>
+    where
+        Self: ModuleT + Sized,
+    {
+        self.forward_t(xs, self.is_training().unwrap_or(false))
+    }
+}
+
+fn join(prefix: &str, name: &str) -> String {
+    match (prefix.is_empty(), name.is_empty()) {
+        (true, _) => name.to_string(),
+        (false, true) => prefix.to_string(),
+        (false, false) => format!("{prefix}.{name}"),
+    }
+}
+
+fn visit_named(layer: &mut dyn Layer, prefix: &str, f: &mut TensorFn, buffers: bool) -> Result<()> {
+    let mut own = |name: &str, t: &mut Tensor| f(&join(prefix, name), t);
+    if buffers {
+        layer.visit_buffers(&mut own)?
+    } else {
+        layer.visit_parameters(&mut own)?
+    }
+    layer.visit_children(&mut |name, child| visit_named(child, &join(prefix, name), f, buffers))
+}
+
+fn apply_named(layer: &mut dyn Layer, prefix: &str, f: &mut LayerFn) -> Result<()> {
+    f(prefix, layer)?;
+    layer.visit_children(&mut |name, child| apply_named(child, &join(prefix, name), f))
+}
+
+/// Same as [`Layer::named_parameters`], for trait objects.
+pub fn named_parameters(layer: &mut dyn Layer) -> Result<Vec<(String, Tensor)>> {
+    let mut params = vec![];
+    visit_named(
+        layer,
+        "",
+        &mut |name, t| {
+            params.push((name.to_string(), t.clone()));
+            Ok(())
+        },
+        false,
+    )?;
+    Ok(params)
+}
+
+/// Same as [`Layer::apply`], for trait objects.
+pub fn apply(layer: &mut dyn Layer, f: &mut LayerFn) -> Result<()> {
+    apply_named(layer, "", f)
+}
+
+/// Same as [`Layer::set_training`], for trait objects.
+pub fn set_training(layer: &mut dyn Layer, training: bool) -> Result<()> {
+    apply(layer, &mut |_, l| {
+        l.set_own_training(training);
+        Ok(())
+    })
+}
+
+/// The variables of `varmap` used by the parameters of `layer` whose names satisfy `filter`,
+/// e.g. to train only some parts of a model created from `varmap` and freeze the others:
+///
+/// ```ignore
+/// let vars = trainable_vars(&varmap, &mut model, |name| name.starts_with("head."))?;
+/// let mut opt = AdamW::new(vars, ParamsAdamW::default())?;
+/// ```
+///
+/// The parameters are matched with the variables through their storage, so the layer must have
+/// been created from the map, e.g. using [`crate::VarBuilder::from_varmap`].
+pub fn trainable_vars<F: Fn(&str) -> bool>(
+    varmap: &VarMap,
+    layer: &mut dyn Layer,
+    filter: F,
+) -> Result<Vec<Var>> {
+    let vars: HashMap<TensorId, Var> = varmap
+        .all_vars()
+        .into_iter()
+        .map(|v| (v.as_tensor().id(), v))
+        .collect();
+    let mut selected = vec![];
+    for (name, t) in named_parameters(layer)? {
+        if !filter(&name) {
+            continue;
+        }
+        match vars.get(&t.id()) {
+            Some(var) => {
+                if !selected.iter().any(|v: &Var| v.as_tensor().id() == t.id()) {
+                    selected.push(var.clone())
+                }
+            }
+            None => candle::bail!("parameter {name} is not a variable of the VarMap"),
+        }
+    }
+    Ok(selected)
+}
+
+impl<L: Layer> Layer for Option<L> {
+    fn visit_parameters(&mut self, f: &mut TensorFn) -> Result<()> {
+        match self {
+            None => Ok(()),
+            Some(l) => l.visit_parameters(f),
+        }
+    }
+
+    fn visit_buffers(&mut self, f: &mut TensorFn) -> Result<()> {
+        match self {
+            None => Ok(()),
+            Some(l) => l.visit_buffers(f),
+        }
+    }
+
+    fn visit_children(&mut self, f: &mut LayerFn) -> Result<()> {
+        match self {
+            None => Ok(()),
+            Some(l) => l.visit_children(f),
+        }
+    }
+
+    fn set_own_training(&mut self, training: bool) {
+        if let Some(l) = self {
+            l.set_own_training(training)
+        }
+    }
+
+    fn is_training(&self) -> Option<bool> {
+        self.as_ref().and_then(|l| l.is_training())
+    }
+}
+
+/// The elements are the sub-layers, named by their index.
+impl<L: Layer> Layer for Vec<L> {
+    fn visit_children(&mut self, f: &mut LayerFn) -> Result<()> {
+        for (i, l) in self.iter_mut().enumerate() {
+            f(&i.to_string(), l)?
+        }
+        Ok(())
+    }
+}
+
+/// The quantized weights are not tensors and are not visited, the weights that were dequantized
+/// when loading are visited as `weight`.
+impl Layer for candle::quantized::QMatMul {
+    fn visit_parameters(&mut self, f: &mut TensorFn) -> Result<()> {
+        use candle::quantized::QMatMul;
+        match self {
+            QMatMul::QTensor(_) => Ok(()),
+            QMatMul::Tensor(w) | QMatMul::TensorF16(w) => f("weight", w),
+        }
+    }
+}
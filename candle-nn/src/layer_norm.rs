@@ -107,6 +107,12 @@ impl LayerNorm {
 
 impl Module for LayerNorm {
     fn forward(&self, x: &Tensor) -> Result<Tensor> {
+        // Weights with another dtype than the input, e.g. f32 norms in a f16 model, see
+        // [`crate::autocast`].
+        if x.dtype() != self.weight.dtype() && crate::autocast::is_enabled() {
+            let ys = self.forward(&x.to_dtype(self.weight.dtype())?)?;
+            return ys.to_dtype(x.dtype());
+        }
         if x.is_contiguous() && self.remove_mean {
             if let Some(bias) = self.bias.as_ref() {
                 return crate::ops::layer_norm(x, &self.weight, bias, self.eps as f32);
@@ -135,6 +141,26 @@ impl Module for LayerNorm {
     }
 }
 
+impl crate::migrate::Migrate for LayerNorm {
+    fn visit_tensors(&mut self, f: &mut dyn FnMut(&mut Tensor) -> Result<()>) -> Result<()> {
+        f(&mut self.weight)?;
+        match self.bias.as_mut() {
+            None => Ok(()),
+            Some(bias) => f(bias),
+        }
+    }
+}
+
+impl crate::layer::Layer for LayerNorm {
+    fn visit_parameters(&mut self, f: &mut crate::layer::TensorFn) -> Result<()> {
+        f("weight", &mut self.weight)?;
+        match self.bias.as_mut() {
+            None => Ok(()),
+            Some(bias) => f("bias", bias),
+        }
+    }
+}
+
 pub fn layer_norm<C: Into<LayerNormConfig>>(
     size: usize,
     config: C,
@@ -176,6 +202,10 @@ impl RmsNorm {
 
 impl Module for RmsNorm {
     fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        if xs.dtype() != self.0.weight.dtype() && crate::autocast::is_enabled() {
+            let ys = self.forward(&xs.to_dtype(self.0.weight.dtype())?)?;
+            return ys.to_dtype(xs.dtype());
+        }
         if xs.is_contiguous() {
             crate::ops::rms_norm(xs, &self.0.weight, self.0.eps as f32)
         } else {
@@ -184,6 +214,18 @@ impl Module for RmsNorm {
     }
 }
 
+impl crate::migrate::Migrate for RmsNorm {
+    fn visit_tensors(&mut self, f: &mut dyn FnMut(&mut Tensor) -> Result<()>) -> Result<()> {
+        crate::migrate::Migrate::visit_tensors(&mut self.0, f)
+    }
+}
+
+impl crate::layer::Layer for RmsNorm {
+    fn visit_parameters(&mut self, f: &mut crate::layer::TensorFn) -> Result<()> {
+        crate::layer::Layer::visit_parameters(&mut self.0, f)
+    }
+}
+
 pub fn rms_norm(size: usize, eps: f64, vb: crate::VarBuilder) -> Result<RmsNorm> {
     let config = LayerNormConfig {
         eps,
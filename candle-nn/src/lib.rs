@@ -16,41 +16,82 @@
 //!
 
 pub mod activation;
+pub mod attention;
+pub mod autocast;
 pub mod batch_norm;
+pub mod checkpoint;
+pub mod compressed_checkpoint;
 pub mod conv;
+pub mod device_cache;
+pub mod distributed;
+pub mod distributed_checkpoint;
+pub mod ema;
 pub mod embedding;
 pub mod encoding;
+pub mod fsdp;
 pub mod func;
+pub mod gaussian_splatting;
+pub mod grad_accum;
+pub mod grad_clip;
 pub mod group_norm;
+pub mod hooks;
 pub mod init;
 pub mod kv_cache;
+pub mod kv_transfer;
+pub mod layer;
 pub mod layer_norm;
 pub mod linear;
+pub mod lora;
 pub mod loss;
+pub mod lr_scheduler;
+pub mod migrate;
+pub mod multi_lora;
+pub mod mup;
+pub mod offload;
 pub mod ops;
 pub mod optim;
+pub mod paged_attention;
+pub mod parametrize;
+pub mod ring_attention;
 pub mod rnn;
 pub mod rotary_emb;
 pub mod sequential;
+pub mod signal;
+pub mod summary;
+pub mod swa;
+pub mod tensorboard;
+pub mod transformer;
 pub mod var_builder;
 pub mod var_map;
+pub mod volume_rendering;
+pub mod weight_delta;
+pub mod weight_layout;
+pub mod zero;
 
 pub use activation::{prelu, Activation, PReLU};
+pub use attention::{multihead_attention, MultiheadAttention, MultiheadAttentionConfig};
 pub use batch_norm::{batch_norm, BatchNorm, BatchNormConfig};
 pub use conv::{
     conv1d, conv1d_no_bias, conv2d, conv2d_no_bias, conv_transpose1d, conv_transpose1d_no_bias,
     conv_transpose2d, conv_transpose2d_no_bias, Conv1d, Conv1dConfig, Conv2d, Conv2dConfig,
     ConvTranspose1d, ConvTranspose1dConfig, ConvTranspose2d, ConvTranspose2dConfig,
 };
+pub use ema::{EmaConfig, EmaModel};
 pub use embedding::{embedding, Embedding};
 pub use func::{func, func_t, Func, FuncT};
+pub use grad_clip::{clip_grad_norm_, clip_grad_value_};
 pub use group_norm::{group_norm, GroupNorm};
 pub use init::Init;
+pub use layer::Layer;
 pub use layer_norm::{layer_norm, rms_norm, LayerNorm, LayerNormConfig, RmsNorm};
 pub use linear::{linear, linear_b, linear_no_bias, Linear};
+pub use migrate::{Migrate, Migration};
 pub use ops::Dropout;
-pub use optim::{AdamW, Optimizer, ParamsAdamW, SGD};
-pub use rnn::{gru, lstm, GRUConfig, LSTMConfig, GRU, LSTM, RNN};
+pub use optim::{AdamW, Optimizer, OptimizerState, ParamsAdamW, SGD};
+pub use rnn::{
+    gru, lstm, stacked_gru, stacked_lstm, GRUConfig, LSTMConfig, StackedConfig, StackedRNN, GRU,
+    LSTM, RNN,
+};
 pub use sequential::{seq, Sequential};
 pub use var_builder::VarBuilder;
 pub use var_map::VarMap;
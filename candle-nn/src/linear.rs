@@ -41,6 +41,11 @@ impl Linear {
 
 impl super::Module for Linear {
     fn forward(&self, x: &Tensor) -> candle::Result<Tensor> {
+        // The weights can use another dtype than the input, see [`crate::autocast`].
+        if x.dtype() != self.weight.dtype() && crate::autocast::is_enabled() {
+            let ys = self.forward(&x.to_dtype(self.weight.dtype())?)?;
+            return ys.to_dtype(x.dtype());
+        }
         let w = match *x.dims() {
             [b1, b2, _, _] => self.weight.broadcast_left((b1, b2))?.t()?,
             [bsize, _, _] => self.weight.broadcast_left(bsize)?.t()?,
@@ -54,6 +59,26 @@ impl super::Module for Linear {
     }
 }
 
+impl crate::migrate::Migrate for Linear {
+    fn visit_tensors(&mut self, f: &mut dyn FnMut(&mut Tensor) -> Result<()>) -> Result<()> {
+        f(&mut self.weight)?;
+        match self.bias.as_mut() {
+            None => Ok(()),
+            Some(bias) => f(bias),
+        }
+    }
+}
+
+impl crate::layer::Layer for Linear {
+    fn visit_parameters(&mut self, f: &mut crate::layer::TensorFn) -> Result<()> {
+        f("weight", &mut self.weight)?;
+        match self.bias.as_mut() {
+            None => Ok(()),
+            Some(bias) => f("bias", bias),
+        }
+    }
+}
+
 /// Create or initialize a new linear layer.
 ///
 /// This uses some default names for weights and biases, namely `"weight"` and `"bias"`.
@@ -0,0 +1,515 @@
+//! LoRA adapters for linear, embedding and convolution layers.
+//!
+//! A [`Lora`] layer wraps a base layer and any number of named low rank adapters, at most one of
+//! them being active at a time. The active adapter is either applied on the fly, `base(x) +
+//! scale * b(a(dropout(x)))`, or merged in the base weights with [`Lora::merge_weights`] so that
+//! inference runs at the speed of the base layer. Adapters trained with PEFT can be loaded with
+//! [`PeftAdapter`].
+//!
+//! ```ignore
+//! let adapter = PeftAdapter::load("my-adapter", DType::F32, &device)?;
+//! let mut q_proj = LoraLinear::new(linear_no_bias(hidden, hidden, vb.pp("q_proj"))?);
+//! adapter.apply("chat", "model.layers.0.self_attn.q_proj", &mut q_proj)?;
+//! q_proj.set_active_adapter(Some("chat"))?;
+//! q_proj.merge_weights()?;
+//! ```
+//!
+//! For fine-tuning, the adapter weights can be created with [`LoraWeights::init`] on a
+//! [`crate::VarMap`], the parameters of the adapters are visited by the [`crate::Layer`] impl as
+//! `lora_A.{adapter}.weight` and `lora_B.{adapter}.weight`.
+//!
+//! [`QLoraLinear`] uses a quantized matmul, e.g. with GGUF or NF4 weights, as a frozen base:
+//! only the input gradient of the quantized matmul is computed so the adapters can be trained
+//! while the base model stays quantized, as with QLoRA. The optimizer states of the adapters can
+//! be kept in host memory with [`crate::optim::FusedAdamW::with_paged`].
+pub use crate::multi_lora::LoraWeights;
+use crate::{Conv2d, Dropout, Embedding, Linear};
+use candle::quantized::{QMatMul, QTensor};
+use candle::{DType, Device, Module, Result, Tensor};
+use std::collections::HashMap;
+use std::path::Path;
+
+/// A layer that LoRA adapters can be added to.
+pub trait LoraBase: Module + Sized {
+    /// The same layer with `delta` added to its weight.
+    fn add_to_weight(&self, delta: &Tensor) -> Result<Self>;
+
+    /// Checks that the shapes of `lora` match the layer.
+    fn check_adapter(&self, lora: &LoraWeights) -> Result<()>;
+
+    /// The update of the weight made by `lora`, with the shape of the weight.
+    fn delta(&self, lora: &LoraWeights) -> Result<Tensor>;
+
+    /// The output of the adapter branch for the input `xs`.
+    fn lora_forward(&self, xs: &Tensor, lora: &LoraWeights) -> Result<Tensor>;
+}
+
+fn check_dims(lora: &LoraWeights, in_dim: usize, out_dim: usize) -> Result<()> {
+    if lora.a().dim(1)? != in_dim || lora.b().dim(0)? != out_dim {
+        candle::bail!(
+            "lora adapter with a {:?} and b {:?} does not match ({out_dim}, {in_dim})",
+            lora.a().shape(),
+            lora.b().shape(),
+        )
+    }
+    Ok(())
+}
+
+// The output of the adapter for the input of a linear layer, `xs` has any number of leading
+// dimensions.
+fn linear_lora_forward(xs: &Tensor, lora: &LoraWeights) -> Result<Tensor> {
+    let mut dims = xs.dims().to_vec();
+    let in_dim = dims.pop().unwrap_or(1);
+    let ys = lora.forward(&xs.reshape(((), in_dim))?)?;
+    dims.push(ys.dim(1)?);
+    ys.reshape(dims)
+}
+
+fn add_to(weight: &Tensor, delta: &Tensor) -> Result<Tensor> {
+    weight + delta.to_dtype(weight.dtype())?
+}
+
+impl LoraBase for Linear {
+    fn add_to_weight(&self, delta: &Tensor) -> Result<Self> {
+        let weight = add_to(self.weight(), delta)?;
+        Ok(Linear::new(weight, self.bias().cloned()))
+    }
+
+    fn check_adapter(&self, lora: &LoraWeights) -> Result<()> {
+        let (out_dim, in_dim) = Linear::weight(self).dims2()?;
+        check_dims(lora, in_dim, out_dim)
+    }
+
+    fn delta(&self, lora: &LoraWeights) -> Result<Tensor> {
+        lora.delta()
+    }
+
+    fn lora_forward(&self, xs: &Tensor, lora: &LoraWeights) -> Result<Tensor> {
+        linear_lora_forward(xs, lora)
+    }
+}
+
+/// The quantized weight is frozen and the adapters are trained on top of it as with QLoRA, the
+/// backward pass of the quantized matmul only computes the gradient of its input. Merging
+/// dequantizes the weight and quantizes the result back with the same dtype: this is lossy, so
+/// unmerging only restores the base weight up to the quantization error.
+impl LoraBase for QMatMul {
+    fn add_to_weight(&self, delta: &Tensor) -> Result<Self> {
+        match self {
+            QMatMul::QTensor(w) => {
+                let device = w.device();
+                let merged = add_to(&w.dequantize(&device)?, &delta.to_device(&device)?)?;
+                QMatMul::from_qtensor(QTensor::quantize(&merged, w.dtype())?)
+            }
+            QMatMul::Tensor(w) => Ok(QMatMul::Tensor(add_to(w, delta)?)),
+            QMatMul::TensorF16(w) => Ok(QMatMul::TensorF16(add_to(w, delta)?)),
+        }
+    }
+
+    fn check_adapter(&self, lora: &LoraWeights) -> Result<()> {
+        let (out_dim, in_dim) = match self {
+            QMatMul::QTensor(w) => w.shape().dims2()?,
+            QMatMul::Tensor(w) | QMatMul::TensorF16(w) => w.dims2()?,
+        };
+        check_dims(lora, in_dim, out_dim)
+    }
+
+    fn delta(&self, lora: &LoraWeights) -> Result<Tensor> {
+        lora.delta()
+    }
+
+    fn lora_forward(&self, xs: &Tensor, lora: &LoraWeights) -> Result<Tensor> {
+        linear_lora_forward(xs, lora)
+    }
+}
+
+/// The PEFT layout is used, `a` has the shape `(rank, num_embeddings)` and `b` the shape
+/// `(hidden_size, rank)`.
+impl LoraBase for Embedding {
+    fn add_to_weight(&self, delta: &Tensor) -> Result<Self> {
+        let weight = add_to(self.embeddings(), delta)?;
+        Ok(Embedding::new(weight, self.hidden_size()))
+    }
+
+    fn check_adapter(&self, lora: &LoraWeights) -> Result<()> {
+        let (num_embeddings, hidden_size) = self.embeddings().dims2()?;
+        check_dims(lora, num_embeddings, hidden_size)
+    }
+
+    fn delta(&self, lora: &LoraWeights) -> Result<Tensor> {
+        lora.delta()?.t()
+    }
+
+    fn lora_forward(&self, xs: &Tensor, lora: &LoraWeights) -> Result<Tensor> {
+        let mut dims = xs.dims().to_vec();
+        dims.push(self.hidden_size());
+        let a = lora.a().t()?.contiguous()?;
+        let ys = a.index_select(&xs.flatten_all()?, 0)?;
+        let ys = (ys.matmul(&lora.b().t()?)? * lora.scale())?;
+        ys.reshape(dims)
+    }
+}
+
+/// `a` holds the flattened kernels of a convolution with `rank` output channels and `b` the
+/// ones of a 1x1 convolution, only convolutions without groups are supported.
+impl LoraBase for Conv2d {
+    fn add_to_weight(&self, delta: &Tensor) -> Result<Self> {
+        let weight = add_to(self.weight(), delta)?;
+        Ok(Conv2d::new(weight, self.bias().cloned(), *self.config()))
+    }
+
+    fn check_adapter(&self, lora: &LoraWeights) -> Result<()> {
+        if self.config().groups != 1 {
+            candle::bail!("lora does not support grouped convolutions")
+        }
+        let (out_c, in_c, k_h, k_w) = Conv2d::weight(self).dims4()?;
+        check_dims(lora, in_c * k_h * k_w, out_c)
+    }
+
+    fn delta(&self, lora: &LoraWeights) -> Result<Tensor> {
+        lora.delta()?.reshape(Conv2d::weight(self).shape())
+    }
+
+    fn lora_forward(&self, xs: &Tensor, lora: &LoraWeights) -> Result<Tensor> {
+        let (_, in_c, k_h, k_w) = Conv2d::weight(self).dims4()?;
+        let cfg = self.config();
+        let a = lora.a().reshape((lora.rank(), in_c, k_h, k_w))?;
+        let b = lora.b().reshape((lora.b().dim(0)?, lora.rank(), 1, 1))?;
+        let ys = xs.conv2d(&a, cfg.padding, cfg.stride, cfg.dilation, 1)?;
+        ys.conv2d(&b, 0, 1, 1, 1)? * lora.scale()
+    }
+}
+
+/// A layer with named LoRA adapters, see the [module documentation](self).
+#[derive(Debug, Clone)]
+pub struct Lora<B> {
+    base: B,
+    adapters: Vec<(String, LoraWeights)>,
+    active: Option<String>,
+    merged: bool,
+    dropout: Dropout,
+}
+
+pub type LoraLinear = Lora<Linear>;
+pub type LoraEmbedding = Lora<Embedding>;
+pub type LoraConv2d = Lora<Conv2d>;
+pub type QLoraLinear = Lora<QMatMul>;
+
+impl<B: LoraBase> Lora<B> {
+    pub fn new(base: B) -> Self {
+        Self {
+            base,
+            adapters: vec![],
+            active: None,
+            merged: false,
+            dropout: Dropout::new(0.),
+        }
+    }
+
+    /// The dropout applied to the input of the adapters in training mode, the integer inputs of
+    /// the embeddings are never dropped.
+    pub fn with_dropout(mut self, drop_p: f32) -> Self {
+        self.dropout = Dropout::new(drop_p);
+        self
+    }
+
+    /// The base layer, its weight includes the active adapter when merged.
+    pub fn base(&self) -> &B {
+        &self.base
+    }
+
+    /// Adds or replaces an adapter, adding an adapter does not activate it.
+    pub fn add_adapter(&mut self, name: &str, lora: LoraWeights) -> Result<()> {
+        self.base.check_adapter(&lora)?;
+        let active = self.active.as_deref() == Some(name);
+        let merged = self.merged && active;
+        if merged {
+            self.unmerge_weights()?
+        }
+        match self.adapters.iter_mut().find(|(n, _)| n == name) {
+            Some((_, w)) => *w = lora,
+            None => self.adapters.push((name.to_string(), lora)),
+        }
+        if merged {
+            self.merge_weights()?
+        }
+        Ok(())
+    }
+
+    /// Removes an adapter, it is unmerged and deactivated first if needed.
+    pub fn remove_adapter(&mut self, name: &str) -> Result<Option<LoraWeights>> {
+        if self.active.as_deref() == Some(name) {
+            self.set_active_adapter(None)?
+        }
+        let index = self.adapters.iter().position(|(n, _)| n == name);
+        Ok(index.map(|i| self.adapters.remove(i).1))
+    }
+
+    /// The names of the adapters, in insertion order.
+    pub fn adapter_names(&self) -> Vec<&str> {
+        self.adapters.iter().map(|(n, _)| n.as_str()).collect()
+    }
+
+    pub fn adapter(&self, name: &str) -> Option<&LoraWeights> {
+        self.adapters
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, w)| w)
+    }
+
+    pub fn active_adapter(&self) -> Option<&str> {
+        self.active.as_deref()
+    }
+
+    /// Selects the adapter to use, `None` uses the base layer only. When the weights are merged,
+    /// the previous adapter gets unmerged and the new one merged.
+    pub fn set_active_adapter(&mut self, name: Option<&str>) -> Result<()> {
+        if let Some(name) = name {
+            if self.adapter(name).is_none() {
+                candle::bail!("unknown lora adapter {name}")
+            }
+        }
+        let merged = self.merged;
+        self.unmerge_weights()?;
+        self.active = name.map(|n| n.to_string());
+        if merged {
+            self.merge_weights()?
+        }
+        Ok(())
+    }
+
+    pub fn is_merged(&self) -> bool {
+        self.merged
+    }
+
+    fn active_weights(&self) -> Option<&LoraWeights> {
+        self.active.as_deref().and_then(|n| self.adapter(n))
+    }
+
+    fn update_weight(&mut self, sign: f64) -> Result<()> {
+        if let Some(lora) = self.active_weights() {
+            let delta = (self.base.delta(lora)? * sign)?;
+            self.base = self.base.add_to_weight(&delta)?;
+        }
+        Ok(())
+    }
+
+    /// Adds the update of the active adapter to the base weight, the forward pass then only
+    /// uses the base layer. This does nothing if the weights are already merged.
+    pub fn merge_weights(&mut self) -> Result<()> {
+        if !self.merged {
+            self.update_weight(1.)?;
+            self.merged = true
+        }
+        Ok(())
+    }
+
+    /// Subtracts the update of the active adapter from the base weight, this restores the base
+    /// weight up to rounding errors.
+    pub fn unmerge_weights(&mut self) -> Result<()> {
+        if self.merged {
+            self.update_weight(-1.)?;
+            self.merged = false
+        }
+        Ok(())
+    }
+
+    /// Merges the active adapter and returns the base layer.
+    pub fn into_merged(mut self) -> Result<B> {
+        self.merge_weights()?;
+        Ok(self.base)
+    }
+}
+
+impl<B: LoraBase> Module for Lora<B> {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        let ys = self.base.forward(xs)?;
+        match self.active_weights() {
+            Some(lora) if !self.merged => {
+                let xs = if xs.dtype().is_float() {
+                    crate::Layer::forward_mode(&self.dropout, xs)?
+                } else {
+                    xs.clone()
+                };
+                ys + self.base.lora_forward(&xs, lora)?
+            }
+            _ => Ok(ys),
+        }
+    }
+}
+
+impl<B: crate::Layer> crate::Layer for Lora<B> {
+    fn visit_parameters(&mut self, f: &mut crate::layer::TensorFn) -> Result<()> {
+        self.base.visit_parameters(f)?;
+        for (name, lora) in self.adapters.iter_mut() {
+            f(&format!("lora_A.{name}.weight"), &mut lora.a)?;
+            f(&format!("lora_B.{name}.weight"), &mut lora.b)?;
+        }
+        Ok(())
+    }
+
+    fn visit_buffers(&mut self, f: &mut crate::layer::TensorFn) -> Result<()> {
+        self.base.visit_buffers(f)
+    }
+
+    fn visit_children(&mut self, f: &mut crate::layer::LayerFn) -> Result<()> {
+        self.base.visit_children(f)
+    }
+
+    fn set_own_training(&mut self, training: bool) {
+        self.dropout.set_own_training(training)
+    }
+
+    fn is_training(&self) -> Option<bool> {
+        self.dropout.is_training()
+    }
+}
+
+/// The hyper-parameters of a PEFT adapter, read from `adapter_config.json`.
+#[derive(Debug, Clone, serde::Deserialize)]
+pub struct LoraConfig {
+    #[serde(rename = "r")]
+    pub rank: usize,
+    #[serde(rename = "lora_alpha")]
+    pub alpha: f64,
+    #[serde(rename = "lora_dropout", default)]
+    pub dropout: f32,
+    /// Scales the updates by `alpha / sqrt(rank)` rather than `alpha / rank`.
+    #[serde(default)]
+    pub use_rslora: bool,
+    /// The alpha of the modules whose names end with the keys.
+    #[serde(default)]
+    pub alpha_pattern: HashMap<String, f64>,
+    #[serde(default)]
+    pub fan_in_fan_out: bool,
+}
+
+impl LoraConfig {
+    pub fn new(rank: usize, alpha: f64) -> Self {
+        Self {
+            rank,
+            alpha,
+            dropout: 0.,
+            use_rslora: false,
+            alpha_pattern: HashMap::new(),
+            fan_in_fan_out: false,
+        }
+    }
+
+    /// The scale of the update of `module` for an adapter of the given rank.
+    pub fn scale(&self, module: &str, rank: usize) -> f64 {
+        let alpha = self
+            .alpha_pattern
+            .iter()
+            .find(|(k, _)| module == k.as_str() || module.ends_with(&format!(".{k}")))
+            .map_or(self.alpha, |(_, &a)| a);
+        if self.use_rslora {
+            alpha / (rank as f64).sqrt()
+        } else {
+            alpha / rank as f64
+        }
+    }
+}
+
+const PEFT_PREFIX: &str = "base_model.model.";
+const PEFT_SUFFIXES: [(&str, &str); 2] = [
+    (".lora_A.weight", ".lora_B.weight"),
+    (".lora_embedding_A", ".lora_embedding_B"),
+];
+
+/// The adapter weights of a PEFT checkpoint, `adapter_model.safetensors`, indexed by module
+/// name, e.g. `model.layers.0.self_attn.q_proj`.
+#[derive(Debug, Clone)]
+pub struct PeftAdapter {
+    config: LoraConfig,
+    tensors: HashMap<String, Tensor>,
+}
+
+impl PeftAdapter {
+    /// Loads `adapter_config.json` and `adapter_model.safetensors` from a directory.
+    pub fn load<P: AsRef<Path>>(dir: P, dtype: DType, device: &Device) -> Result<Self> {
+        let dir = dir.as_ref();
+        let config = dir.join("adapter_config.json");
+        let config =
+            std::fs::read(&config).map_err(|e| candle::Error::from(e).with_path(&config))?;
+        let config: LoraConfig =
+            serde_json::from_slice(&config).map_err(|e| candle::Error::Msg(e.to_string()))?;
+        let tensors = candle::safetensors::load(dir.join("adapter_model.safetensors"), device)?;
+        let tensors = tensors
+            .into_iter()
+            .map(|(k, v)| Ok((k, v.to_dtype(dtype)?)))
+            .collect::<Result<_>>()?;
+        Self::new(config, tensors)
+    }
+
+    /// Uses tensors with the PEFT names, e.g.
+    /// `base_model.model.model.layers.0.self_attn.q_proj.lora_A.weight`, the prefix being
+    /// optional.
+    pub fn new(config: LoraConfig, tensors: HashMap<String, Tensor>) -> Result<Self> {
+        if config.fan_in_fan_out {
+            candle::bail!("lora adapters with fan_in_fan_out are not supported")
+        }
+        let tensors = tensors
+            .into_iter()
+            .map(|(k, v)| match k.strip_prefix(PEFT_PREFIX) {
+                Some(k) => (k.to_string(), v),
+                None => (k, v),
+            })
+            .collect();
+        Ok(Self { config, tensors })
+    }
+
+    pub fn config(&self) -> &LoraConfig {
+        &self.config
+    }
+
+    /// The names of the modules with an adapter, sorted.
+    pub fn module_names(&self) -> Vec<String> {
+        let mut names: Vec<_> = self
+            .tensors
+            .keys()
+            .filter_map(|k| PEFT_SUFFIXES.iter().find_map(|(a, _)| k.strip_suffix(a)))
+            .map(|k| k.to_string())
+            .collect();
+        names.sort();
+        names
+    }
+
+    /// The adapter weights of `module`, the convolution kernels are flattened.
+    pub fn weights(&self, module: &str) -> Result<Option<LoraWeights>> {
+        for (a_suffix, b_suffix) in PEFT_SUFFIXES {
+            let a_name = format!("{module}{a_suffix}");
+            let a = match self.tensors.get(&a_name) {
+                None => continue,
+                Some(a) => a,
+            };
+            let b = match self.tensors.get(&format!("{module}{b_suffix}")) {
+                None => candle::bail!("{a_name} has no matching lora B weight"),
+                Some(b) => b,
+            };
+            let rank = a.dim(0)?;
+            let a = a.flatten_from(1)?;
+            let b = b.flatten_from(1)?;
+            let scale = self.config.scale(module, rank);
+            return Ok(Some(LoraWeights::new(a, b, 1.)?.with_scale(scale)));
+        }
+        Ok(None)
+    }
+
+    /// Adds the adapter of `module` to `layer` under the name `name`, returns false if this
+    /// checkpoint has no adapter for `module`.
+    pub fn apply<B: LoraBase>(
+        &self,
+        name: &str,
+        module: &str,
+        layer: &mut Lora<B>,
+    ) -> Result<bool> {
+        match self.weights(module)? {
+            None => Ok(false),
+            Some(lora) => {
+                layer.add_adapter(name, lora)?;
+                Ok(true)
+            }
+        }
+    }
+}
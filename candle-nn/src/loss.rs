@@ -1,6 +1,6 @@
 //! Loss Calculations
 //!
-use candle::{Result, Tensor};
+use candle::{DType, Result, Tensor};
 
 /// The negative log likelihood loss.
 ///
@@ -46,6 +46,228 @@ pub fn cross_entropy(inp: &Tensor, target: &Tensor) -> Result<Tensor> {
     nll(&inp, target)
 }
 
+/// A cross-entropy loss with the options used by language model training recipes.
+///
+/// - `ignore_index`: the targets with this value, e.g. the padding or the prompt tokens, do not
+///   contribute to the loss.
+/// - per-token weights: the loss of each target is scaled by a weight and the weighted sum is
+///   divided by the sum of the weights of the targets that are not ignored, as in PyTorch.
+/// - `z_loss`: adds `z_loss * log(Z)^2` to the loss of each target, where `Z` is the softmax
+///   normalizer, to keep the logits from drifting, see "PaLM: Scaling Language Modeling with
+///   Pathways", Chowdhery, A. et al. (2022).
+///
+/// The mask is built from the targets so that the logits are never copied, the input can have
+/// any number of leading dimensions, e.g. `B, T, C` logits with `B, T` targets.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct CrossEntropy {
+    pub ignore_index: Option<u32>,
+    pub z_loss: f64,
+}
+
+impl CrossEntropy {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_ignore_index(mut self, ignore_index: u32) -> Self {
+        self.ignore_index = Some(ignore_index);
+        self
+    }
+
+    pub fn with_z_loss(mut self, z_loss: f64) -> Self {
+        self.z_loss = z_loss;
+        self
+    }
+
+    /// The loss of each target, with the shape of `target`, the ignored targets have a loss of
+    /// 0. `inp` contains raw logits with one more dimension than `target` for the categories.
+    pub fn per_token(&self, inp: &Tensor, target: &Tensor) -> Result<Tensor> {
+        let (loss, _) = self.per_token_and_mask(inp, target)?;
+        Ok(loss)
+    }
+
+    /// The reduced loss, `weights` has the shape of `target` and defaults to ones. The result
+    /// is 0 when all the targets are ignored.
+    pub fn loss(&self, inp: &Tensor, target: &Tensor, weights: Option<&Tensor>) -> Result<Tensor> {
+        let (total, weight) = self.sum_and_weight(inp, target, weights)?;
+        total / weight.maximum(1e-12)?
+    }
+
+    /// The next-token prediction loss of a causal language model, i.e. the loss of the logits
+    /// at each position `t < T - 1` against the token `t + 1` of `input_ids`.
+    ///
+    /// `logits` has dimensions `B, T, V` and `input_ids` `B, T`. The targets are not shifted in
+    /// a copy of the inputs, the loss is computed on views of each sequence, by chunks of
+    /// `chunk_size` positions if set so that the softmax intermediates only exist for a chunk at
+    /// a time. Half precision logits are converted to f32 one chunk at a time.
+    ///
+    /// This only bounds the memory when no gradient is tracked, e.g. for evaluation. When the
+    /// logits depend on variables, the graph used by the backward pass keeps the intermediates of
+    /// every chunk alive until the loss is dropped.
+    pub fn causal_lm_loss(
+        &self,
+        logits: &Tensor,
+        input_ids: &Tensor,
+        chunk_size: Option<usize>,
+    ) -> Result<Tensor> {
+        let (b_sz, seq_len, _) = logits.dims3()?;
+        if input_ids.dims() != [b_sz, seq_len] {
+            candle::bail!(
+                "the input ids {:?} do not match the logits {:?}",
+                input_ids.shape(),
+                logits.shape()
+            )
+        }
+        self.causal_lm_chunks(input_ids, chunk_size, |b, start, len| {
+            logits.get(b)?.narrow(0, start, len)
+        })
+    }
+
+    /// Same as [`Self::causal_lm_loss`] with the logits computed by `head` from the hidden
+    /// states of dimensions `B, T, D` for each chunk, so that the logits for the full sequences
+    /// are never materialized. As for [`Self::causal_lm_loss`], this only holds when no gradient
+    /// is tracked, the backward graph otherwise keeps the logits of all the chunks.
+    pub fn causal_lm_loss_with_head<M: candle::Module>(
+        &self,
+        hidden: &Tensor,
+        head: &M,
+        input_ids: &Tensor,
+        chunk_size: Option<usize>,
+    ) -> Result<Tensor> {
+        let (b_sz, seq_len, _) = hidden.dims3()?;
+        if input_ids.dims() != [b_sz, seq_len] {
+            candle::bail!(
+                "the input ids {:?} do not match the hidden states {:?}",
+                input_ids.shape(),
+                hidden.shape()
+            )
+        }
+        self.causal_lm_chunks(input_ids, chunk_size, |b, start, len| {
+            hidden.get(b)?.narrow(0, start, len)?.apply(head)
+        })
+    }
+
+    // Accumulates the loss over the chunks, `logits(b, start, len)` returns the `len, V` logits
+    // of the sequence `b` from the position `start`.
+    fn causal_lm_chunks<F>(
+        &self,
+        input_ids: &Tensor,
+        chunk_size: Option<usize>,
+        logits: F,
+    ) -> Result<Tensor>
+    where
+        F: Fn(usize, usize, usize) -> Result<Tensor>,
+    {
+        let (b_sz, seq_len) = input_ids.dims2()?;
+        if seq_len < 2 {
+            candle::bail!("a causal lm loss needs sequences of at least two tokens")
+        }
+        let num_preds = seq_len - 1;
+        let chunk_size = chunk_size.unwrap_or(num_preds).max(1);
+        let mut total: Option<(Tensor, Tensor)> = None;
+        for b in 0..b_sz {
+            let targets = input_ids.get(b)?;
+            for start in (0..num_preds).step_by(chunk_size) {
+                let len = chunk_size.min(num_preds - start);
+                let chunk = logits(b, start, len)?;
+                let chunk = match chunk.dtype() {
+                    DType::BF16 | DType::F16 => chunk.to_dtype(DType::F32)?,
+                    _ => chunk,
+                };
+                let target = targets.narrow(0, start + 1, len)?;
+                let (sum, weight) = self.sum_and_weight(&chunk, &target, None)?;
+                total = Some(match total {
+                    None => (sum, weight),
+                    Some((s, w)) => ((s + sum)?, (w + weight)?),
+                })
+            }
+        }
+        match total {
+            Some((total, weight)) => total / weight.maximum(1e-12)?,
+            None => candle::bail!("a causal lm loss needs a non-empty batch"),
+        }
+    }
+
+    // The sum of the weighted per-token losses and the sum of the weights, the weights of the
+    // ignored targets being 0.
+    fn sum_and_weight(
+        &self,
+        inp: &Tensor,
+        target: &Tensor,
+        weights: Option<&Tensor>,
+    ) -> Result<(Tensor, Tensor)> {
+        let (loss, mask) = self.per_token_and_mask(inp, target)?;
+        let weights = match weights {
+            None => None,
+            Some(weights) => {
+                if weights.dims() != target.dims() {
+                    candle::bail!(
+                        "the weights {:?} do not match the target {:?}",
+                        weights.shape(),
+                        target.shape()
+                    )
+                }
+                Some(weights.to_dtype(loss.dtype())?)
+            }
+        };
+        let weights = match (weights, mask) {
+            (None, None) => {
+                let count = Tensor::new(loss.elem_count() as f64, loss.device())?;
+                return Ok((loss.sum_all()?, count.to_dtype(loss.dtype())?));
+            }
+            (Some(w), None) | (None, Some(w)) => w,
+            (Some(weights), Some(mask)) => (weights * mask)?,
+        };
+        Ok(((loss * &weights)?.sum_all()?, weights.sum_all()?))
+    }
+
+    // The per-token loss and the mask of the targets that are not ignored, in the dtype of the
+    // input.
+    fn per_token_and_mask(
+        &self,
+        inp: &Tensor,
+        target: &Tensor,
+    ) -> Result<(Tensor, Option<Tensor>)> {
+        let num_classes = inp.dims().last().copied().unwrap_or(0);
+        if inp.rank() != target.rank() + 1 || inp.dims()[..target.rank()] != *target.dims() {
+            candle::bail!(
+                "the input {:?} does not match the target {:?}, expected a size for the categories",
+                inp.shape(),
+                target.shape()
+            )
+        }
+        let logits = inp.reshape(((), num_classes))?;
+        let flat_target = target.flatten_all()?;
+        let mask = match self.ignore_index {
+            None => None,
+            Some(ignore_index) => {
+                let ignored = Tensor::full(ignore_index, flat_target.shape(), target.device())?;
+                Some(flat_target.ne(&ignored)?)
+            }
+        };
+        // The ignored targets are replaced by a valid index for the gather.
+        let flat_target = match &mask {
+            None => flat_target,
+            Some(mask) => mask.where_cond(&flat_target, &flat_target.zeros_like()?)?,
+        };
+        let max = logits.max_keepdim(1)?.detach();
+        let lse = (logits.broadcast_sub(&max)?.exp()?.sum_keepdim(1)?.log()? + max)?;
+        let target_logits = logits.gather(&flat_target.unsqueeze(1)?, 1)?;
+        let mut loss = (lse.clone() - target_logits)?;
+        if self.z_loss != 0. {
+            loss = (loss + (lse.sqr()? * self.z_loss)?)?
+        }
+        let mask = mask.map(|m| m.to_dtype(loss.dtype())).transpose()?;
+        let loss = match &mask {
+            None => loss.squeeze(1)?,
+            Some(mask) => (loss.squeeze(1)? * mask)?,
+        };
+        let loss = loss.reshape(target.shape())?;
+        let mask = mask.map(|m| m.reshape(target.shape())).transpose()?;
+        Ok((loss, mask))
+    }
+}
+
 /// The mean squared error loss.
 pub fn mse(inp: &Tensor, target: &Tensor) -> Result<Tensor> {
     (inp - target)?.sqr()?.mean_all()
@@ -0,0 +1,389 @@
+//! Learning rate schedules.
+//!
+//! An [`LrSchedule`] maps a position, i.e. a number of optimizer steps or of epochs, to a
+//! learning rate. The schedules here follow the PyTorch and Hugging Face implementations:
+//!
+//! - [`CosineWithWarmup`], a linear warmup followed by a cosine decay.
+//! - [`LinearDecay`], a linear warmup followed by a linear decay.
+//! - [`Polynomial`], a linear warmup followed by a polynomial decay.
+//! - [`OneCycle`], the 1cycle policy with a cosine annealing up to a maximum learning rate and
+//!   back down.
+//! - [`StepDecay`], the learning rate is multiplied by a factor every few positions.
+//! - [`SwaLr`], another schedule followed by a constant learning rate for stochastic weight
+//!   averaging.
+//!
+//! The warmup of [`CosineWithWarmup`], [`LinearDecay`] and [`Polynomial`] increases the learning
+//! rate linearly from 0 at position 0 to `lr` at position `warmup_steps`. Their decay then runs
+//! from `warmup_steps` to `total_steps` and the final learning rate is kept afterwards.
+//!
+//! Any `Fn(usize) -> f64` can also be used as a schedule. An [`LrScheduler`] tracks the
+//! position and sets the learning rate of an optimizer, it is driven per step or per epoch so
+//! that the training loop can call both [`LrScheduler::step`] after each optimizer step and
+//! [`LrScheduler::epoch`] after each epoch.
+//!
+//! ```ignore
+//! let schedule = CosineWithWarmup::new(3e-4, 1000, 100_000).with_min_lr(3e-5);
+//! let mut scheduler = LrScheduler::per_step(schedule);
+//! scheduler.apply(&mut opt);
+//! for batch in batches {
+//!     opt.backward_step(&model.loss(&batch)?)?;
+//!     scheduler.step(&mut opt);
+//! }
+//! ```
+use crate::Optimizer;
+
+/// A learning rate as a function of the number of steps, or of epochs, already done.
+pub trait LrSchedule {
+    fn lr(&self, position: usize) -> f64;
+}
+
+impl<F: Fn(usize) -> f64> LrSchedule for F {
+    fn lr(&self, position: usize) -> f64 {
+        self(position)
+    }
+}
+
+impl LrSchedule for Box<dyn LrSchedule + Send> {
+    fn lr(&self, position: usize) -> f64 {
+        self.as_ref().lr(position)
+    }
+}
+
+// The linear warmup from 0 shared by the schedules, `None` after the warmup.
+fn warmup(lr: f64, warmup_steps: usize, position: usize) -> Option<f64> {
+    (position < warmup_steps).then(|| lr * position as f64 / warmup_steps as f64)
+}
+
+// The fraction of the decay done at `position`, in `[0, 1]`.
+fn progress(position: usize, warmup_steps: usize, total_steps: usize) -> f64 {
+    let decay_steps = total_steps.saturating_sub(warmup_steps).max(1);
+    (position.saturating_sub(warmup_steps) as f64 / decay_steps as f64).min(1.)
+}
+
+// The cosine annealing from `start` to `end`, `pct` being the fraction of the annealing done.
+fn anneal(start: f64, end: f64, pct: f64) -> f64 {
+    let pct = pct.clamp(0., 1.);
+    end + (start - end) / 2. * (1. + (std::f64::consts::PI * pct).cos())
+}
+
+/// A cosine decay from `lr` to `min_lr`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CosineWithWarmup {
+    pub lr: f64,
+    pub min_lr: f64,
+    pub warmup_steps: usize,
+    pub total_steps: usize,
+}
+
+impl CosineWithWarmup {
+    pub fn new(lr: f64, warmup_steps: usize, total_steps: usize) -> Self {
+        Self {
+            lr,
+            min_lr: 0.,
+            warmup_steps,
+            total_steps,
+        }
+    }
+
+    pub fn with_min_lr(mut self, min_lr: f64) -> Self {
+        self.min_lr = min_lr;
+        self
+    }
+}
+
+impl LrSchedule for CosineWithWarmup {
+    fn lr(&self, position: usize) -> f64 {
+        if let Some(lr) = warmup(self.lr, self.warmup_steps, position) {
+            return lr;
+        }
+        let progress = progress(position, self.warmup_steps, self.total_steps);
+        let cosine = 0.5 * (1. + (std::f64::consts::PI * progress).cos());
+        self.min_lr + (self.lr - self.min_lr) * cosine
+    }
+}
+
+/// A linear decay from `lr` to `end_lr`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LinearDecay {
+    pub lr: f64,
+    pub end_lr: f64,
+    pub warmup_steps: usize,
+    pub total_steps: usize,
+}
+
+impl LinearDecay {
+    pub fn new(lr: f64, warmup_steps: usize, total_steps: usize) -> Self {
+        Self {
+            lr,
+            end_lr: 0.,
+            warmup_steps,
+            total_steps,
+        }
+    }
+
+    pub fn with_end_lr(mut self, end_lr: f64) -> Self {
+        self.end_lr = end_lr;
+        self
+    }
+}
+
+impl LrSchedule for LinearDecay {
+    fn lr(&self, position: usize) -> f64 {
+        if let Some(lr) = warmup(self.lr, self.warmup_steps, position) {
+            return lr;
+        }
+        let progress = progress(position, self.warmup_steps, self.total_steps);
+        self.lr + (self.end_lr - self.lr) * progress
+    }
+}
+
+/// A polynomial decay from `lr` to `end_lr`, `(lr - end_lr) * (1 - progress)^power + end_lr`
+/// where `progress` is the fraction of the decay done. There is no warmup by default.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Polynomial {
+    pub lr: f64,
+    pub end_lr: f64,
+    pub power: f64,
+    pub warmup_steps: usize,
+    pub total_steps: usize,
+}
+
+impl Polynomial {
+    pub fn new(lr: f64, power: f64, total_steps: usize) -> Self {
+        Self {
+            lr,
+            end_lr: 0.,
+            power,
+            warmup_steps: 0,
+            total_steps,
+        }
+    }
+
+    pub fn with_end_lr(mut self, end_lr: f64) -> Self {
+        self.end_lr = end_lr;
+        self
+    }
+
+    pub fn with_warmup(mut self, warmup_steps: usize) -> Self {
+        self.warmup_steps = warmup_steps;
+        self
+    }
+}
+
+impl LrSchedule for Polynomial {
+    fn lr(&self, position: usize) -> f64 {
+        if let Some(lr) = warmup(self.lr, self.warmup_steps, position) {
+            return lr;
+        }
+        let progress = progress(position, self.warmup_steps, self.total_steps);
+        (self.lr - self.end_lr) * (1. - progress).powf(self.power) + self.end_lr
+    }
+}
+
+/// The 1cycle policy: the learning rate goes from `max_lr / div_factor` to `max_lr` during the
+/// first `pct_start` of the `total_steps`, then down to `max_lr / div_factor / final_div_factor`,
+/// both phases following a cosine. This is PyTorch `OneCycleLR` with the cosine annealing and
+/// without momentum cycling.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct OneCycle {
+    pub max_lr: f64,
+    pub total_steps: usize,
+    pub pct_start: f64,
+    pub div_factor: f64,
+    pub final_div_factor: f64,
+}
+
+impl OneCycle {
+    pub fn new(max_lr: f64, total_steps: usize) -> Self {
+        Self {
+            max_lr,
+            total_steps,
+            pct_start: 0.3,
+            div_factor: 25.,
+            final_div_factor: 1e4,
+        }
+    }
+
+    pub fn with_pct_start(mut self, pct_start: f64) -> Self {
+        self.pct_start = pct_start;
+        self
+    }
+
+    pub fn with_div_factors(mut self, div_factor: f64, final_div_factor: f64) -> Self {
+        self.div_factor = div_factor;
+        self.final_div_factor = final_div_factor;
+        self
+    }
+}
+
+impl LrSchedule for OneCycle {
+    fn lr(&self, position: usize) -> f64 {
+        let initial_lr = self.max_lr / self.div_factor;
+        let min_lr = initial_lr / self.final_div_factor;
+        // The positions at which the two phases end, as in PyTorch.
+        let warmup_end = self.pct_start * self.total_steps as f64 - 1.;
+        let end = self.total_steps as f64 - 1.;
+        let position = position as f64;
+        if position <= warmup_end {
+            // A warmup of a single step ends at the first position.
+            if warmup_end <= 0. {
+                return self.max_lr;
+            }
+            anneal(initial_lr, self.max_lr, position / warmup_end)
+        } else {
+            let pct = (position - warmup_end) / (end - warmup_end).max(1.);
+            anneal(self.max_lr, min_lr, pct)
+        }
+    }
+}
+
+/// Multiplies the learning rate by `gamma` every `step_size` positions.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct StepDecay {
+    pub lr: f64,
+    pub step_size: usize,
+    pub gamma: f64,
+}
+
+impl StepDecay {
+    pub fn new(lr: f64, step_size: usize, gamma: f64) -> Self {
+        Self {
+            lr,
+            step_size,
+            gamma,
+        }
+    }
+}
+
+impl LrSchedule for StepDecay {
+    fn lr(&self, position: usize) -> f64 {
+        self.lr * self.gamma.powi((position / self.step_size.max(1)) as i32)
+    }
+}
+
+/// Follows `schedule` until `swa_start`, then anneals the learning rate to `swa_lr` with a cosine
+/// over `anneal_steps` and keeps it constant, as the `SWALR` scheduler of PyTorch. This is the
+/// schedule used with stochastic weight averaging, see [`crate::swa`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SwaLr<S> {
+    pub schedule: S,
+    pub swa_start: usize,
+    pub swa_lr: f64,
+    pub anneal_steps: usize,
+}
+
+impl<S: LrSchedule> SwaLr<S> {
+    pub fn new(schedule: S, swa_start: usize, swa_lr: f64) -> Self {
+        Self {
+            schedule,
+            swa_start,
+            swa_lr,
+            anneal_steps: 0,
+        }
+    }
+
+    pub fn with_anneal_steps(mut self, anneal_steps: usize) -> Self {
+        self.anneal_steps = anneal_steps;
+        self
+    }
+}
+
+impl<S: LrSchedule> LrSchedule for SwaLr<S> {
+    fn lr(&self, position: usize) -> f64 {
+        if position < self.swa_start {
+            return self.schedule.lr(position);
+        }
+        if self.anneal_steps == 0 {
+            return self.swa_lr;
+        }
+        let pct = (position - self.swa_start) as f64 / self.anneal_steps as f64;
+        anneal(self.schedule.lr(self.swa_start), self.swa_lr, pct)
+    }
+}
+
+/// What the positions of a schedule count.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Interval {
+    Step,
+    Epoch,
+}
+
+/// Sets the learning rate of an optimizer following a schedule.
+#[derive(Debug, Clone)]
+pub struct LrScheduler<S: LrSchedule> {
+    schedule: S,
+    interval: Interval,
+    position: usize,
+}
+
+impl<S: LrSchedule> LrScheduler<S> {
+    pub fn new(schedule: S, interval: Interval) -> Self {
+        Self {
+            schedule,
+            interval,
+            position: 0,
+        }
+    }
+
+    /// The position counts the optimizer steps.
+    pub fn per_step(schedule: S) -> Self {
+        Self::new(schedule, Interval::Step)
+    }
+
+    /// The position counts the epochs.
+    pub fn per_epoch(schedule: S) -> Self {
+        Self::new(schedule, Interval::Epoch)
+    }
+
+    pub fn schedule(&self) -> &S {
+        &self.schedule
+    }
+
+    pub fn interval(&self) -> Interval {
+        self.interval
+    }
+
+    pub fn position(&self) -> usize {
+        self.position
+    }
+
+    /// Moves to a position, e.g. when resuming from a checkpoint, the learning rate of the
+    /// optimizer is only updated by the next call to [`Self::apply`].
+    pub fn set_position(&mut self, position: usize) {
+        self.position = position
+    }
+
+    /// The learning rate at the current position.
+    pub fn learning_rate(&self) -> f64 {
+        self.schedule.lr(self.position)
+    }
+
+    /// Sets the learning rate of `opt` for the current position, this should be called before
+    /// the first optimizer step.
+    pub fn apply<O: Optimizer>(&self, opt: &mut O) -> f64 {
+        let lr = self.learning_rate();
+        opt.set_learning_rate(lr);
+        lr
+    }
+
+    fn advance<O: Optimizer>(&mut self, interval: Interval, opt: &mut O) -> f64 {
+        if self.interval == interval {
+            self.position += 1;
+            self.apply(opt)
+        } else {
+            self.learning_rate()
+        }
+    }
+
+    /// To be called after each optimizer step, moves to the next position and updates the
+    /// learning rate of `opt` when driven per step. Returns the new learning rate.
+    pub fn step<O: Optimizer>(&mut self, opt: &mut O) -> f64 {
+        self.advance(Interval::Step, opt)
+    }
+
+    /// To be called after each epoch, moves to the next position and updates the learning rate
+    /// of `opt` when driven per epoch. Returns the new learning rate.
+    pub fn epoch<O: Optimizer>(&mut self, opt: &mut O) -> f64 {
+        self.advance(Interval::Epoch, opt)
+    }
+}
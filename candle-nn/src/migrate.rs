@@ -0,0 +1,218 @@
+//! Moving the parameters and buffers of modules to another device or dtype.
+//!
+//! Models hold their weights as tensors which cannot change device or dtype in place. The
+//! [`Migrate`] trait lets a module expose its tensors so that they can be replaced, which avoids
+//! rebuilding the model from a new `VarBuilder`. A [`Migration`] can be run on multiple modules
+//! and on the [`crate::VarMap`] they were created from: tensors shared between them, e.g. tied
+//! embeddings or the variables of a model being trained, are only moved once and stay shared.
+//!
+//! ```ignore
+//! let mut migration = Migration::new()
+//!     .device(&Device::new_cuda(0)?)
+//!     .dtype(DType::BF16)
+//!     .nonblocking(true)
+//!     .on_progress(|p| println!("{}/{} bytes", p.bytes, p.total_bytes));
+//! migration.run(&mut varmap)?;
+//! migration.run(&mut model)?;
+//! migration.synchronize()?;
+//! ```
+use candle::{DType, Device, Result, Tensor, TensorId};
+use std::collections::{HashMap, HashSet};
+
+/// Modules whose tensors can be moved to another device or converted to another dtype.
+pub trait Migrate {
+    /// Calls `f` on all the parameters and buffers of the module, `f` may replace the tensors.
+    fn visit_tensors(&mut self, f: &mut dyn FnMut(&mut Tensor) -> Result<()>) -> Result<()>;
+
+    /// Moves all the tensors of the module to `device`.
+    fn to_device(&mut self, device: &Device) -> Result<()>
+    where
+        Self: Sized,
+    {
+        Migration::new().device(device).run(self)
+    }
+
+    /// Converts the floating point tensors of the module to `dtype`, integer tensors such as
+    /// quantized weights or indexes are left as is.
+    fn to_dtype(&mut self, dtype: DType) -> Result<()>
+    where
+        Self: Sized,
+    {
+        Migration::new().dtype(dtype).run(self)
+    }
+}
+
+/// The progress of [`Migration::run`], the totals only cover the tensors of the module being
+/// migrated that have not been migrated by a previous run.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MigrationProgress {
+    pub tensors: usize,
+    pub total_tensors: usize,
+    pub bytes: usize,
+    pub total_bytes: usize,
+}
+
+type ProgressFn<'a> = Box<dyn FnMut(&MigrationProgress) + 'a>;
+
+/// The target device and dtype for the tensors of some modules.
+pub struct Migration<'a> {
+    device: Option<Device>,
+    dtype: Option<DType>,
+    nonblocking: bool,
+    progress: Option<ProgressFn<'a>>,
+    // The migrated tensors, indexed by the id of the original tensors.
+    migrated: HashMap<TensorId, Tensor>,
+}
+
+impl<'a> Default for Migration<'a> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<'a> Migration<'a> {
+    /// A migration keeping the device and dtype of all the tensors.
+    pub fn new() -> Self {
+        Self {
+            device: None,
+            dtype: None,
+            nonblocking: false,
+            progress: None,
+            migrated: HashMap::new(),
+        }
+    }
+
+    pub fn device(mut self, device: &Device) -> Self {
+        self.device = Some(device.clone());
+        self
+    }
+
+    /// The dtype for the floating point tensors.
+    pub fn dtype(mut self, dtype: DType) -> Self {
+        self.dtype = Some(dtype);
+        self
+    }
+
+    /// When moving cpu tensors to a cuda device, copy them to page-locked memory and use
+    /// asynchronous copies so that the transfers overlap with the preparation of the following
+    /// tensors, see [`Tensor::to_device_nonblocking`].
+    pub fn nonblocking(mut self, nonblocking: bool) -> Self {
+        self.nonblocking = nonblocking;
+        self
+    }
+
+    /// Calls `f` after each migrated tensor.
+    pub fn on_progress<F: FnMut(&MigrationProgress) + 'a>(mut self, f: F) -> Self {
+        self.progress = Some(Box::new(f));
+        self
+    }
+
+    /// Migrates all the tensors of `module`.
+    pub fn run<M: Migrate + ?Sized>(&mut self, module: &mut M) -> Result<()> {
+        let mut seen = HashSet::new();
+        let (mut total_tensors, mut total_bytes) = (0, 0);
+        module.visit_tensors(&mut |t| {
+            if !self.migrated.contains_key(&t.id()) && seen.insert(t.id()) {
+                total_tensors += 1;
+                total_bytes += t.elem_count() * t.dtype().size_in_bytes();
+            }
+            Ok(())
+        })?;
+        let mut progress = MigrationProgress {
+            tensors: 0,
+            total_tensors,
+            bytes: 0,
+            total_bytes,
+        };
+        module.visit_tensors(&mut |t| {
+            if let Some(migrated) = self.migrated.get(&t.id()) {
+                *t = migrated.clone();
+                return Ok(());
+            }
+            let migrated = self.migrate(t)?;
+            self.migrated.insert(t.id(), migrated.clone());
+            progress.tensors += 1;
+            progress.bytes += t.elem_count() * t.dtype().size_in_bytes();
+            *t = migrated;
+            if let Some(f) = self.progress.as_mut() {
+                f(&progress)
+            }
+            Ok(())
+        })
+    }
+
+    /// The migrated version of a tensor, this is the same tensor as used by the modules migrated
+    /// with this migration.
+    pub fn tensor(&mut self, t: &Tensor) -> Result<Tensor> {
+        if let Some(migrated) = self.migrated.get(&t.id()) {
+            return Ok(migrated.clone());
+        }
+        let migrated = self.migrate(t)?;
+        self.migrated.insert(t.id(), migrated.clone());
+        Ok(migrated)
+    }
+
+    /// Waits for the asynchronous copies to the target device to complete.
+    pub fn synchronize(&self) -> Result<()> {
+        match &self.device {
+            Some(device) if self.nonblocking => device.synchronize(),
+            _ => Ok(()),
+        }
+    }
+
+    fn migrate(&self, t: &Tensor) -> Result<Tensor> {
+        let dtype = match self.dtype {
+            Some(dtype) if t.dtype().is_float() && t.dtype() != dtype => Some(dtype),
+            _ => None,
+        };
+        let device = self.device.as_ref().filter(|d| !t.device().same_device(d));
+        if dtype.is_none() && device.is_none() {
+            return Ok(t.clone());
+        }
+        // Convert on the side where the tensor is smallest to reduce the transfer size.
+        let convert_first = dtype.is_some_and(|d| d.size_in_bytes() < t.dtype().size_in_bytes());
+        let mut migrated = t.detach();
+        if let (true, Some(dtype)) = (convert_first, dtype) {
+            migrated = migrated.to_dtype(dtype)?
+        }
+        if let Some(device) = device {
+            migrated = match device {
+                Device::Cuda(_) if self.nonblocking && migrated.device().is_cpu() => {
+                    migrated.pin_memory(device)?.to_device_nonblocking(device)?
+                }
+                _ => migrated.to_device(device)?,
+            }
+        }
+        if let (false, Some(dtype)) = (convert_first, dtype) {
+            migrated = migrated.to_dtype(dtype)?
+        }
+        if t.is_variable() {
+            migrated = candle::Var::from_tensor(&migrated)?.into_inner()
+        }
+        Ok(migrated)
+    }
+}
+
+impl Migrate for Tensor {
+    fn visit_tensors(&mut self, f: &mut dyn FnMut(&mut Tensor) -> Result<()>) -> Result<()> {
+        f(self)
+    }
+}
+
+impl<M: Migrate> Migrate for Option<M> {
+    fn visit_tensors(&mut self, f: &mut dyn FnMut(&mut Tensor) -> Result<()>) -> Result<()> {
+        match self {
+            None => Ok(()),
+            Some(m) => m.visit_tensors(f),
+        }
+    }
+}
+
+impl<M: Migrate> Migrate for Vec<M> {
+    fn visit_tensors(&mut self, f: &mut dyn FnMut(&mut Tensor) -> Result<()>) -> Result<()> {
+        for m in self.iter_mut() {
+            m.visit_tensors(f)?
+        }
+        Ok(())
+    }
+}
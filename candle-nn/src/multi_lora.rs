@@ -0,0 +1,278 @@
+//! Serving many LoRA adapters with a single base model.
+//!
+//! A [`MultiLoraLinear`] holds the weights of a base linear layer and of any number of LoRA
+//! adapters identified by an id. The adapter used by each token of a batch is set on an
+//! [`AdapterSelection`] that is shared by all the layers of the model, so that the sequences of
+//! a batch can use different adapters, or none. The base weights are applied to the whole batch
+//! at once, the low rank weights of each row are gathered and the updates of all the rows are
+//! computed with a pair of batched matmuls, as done by the BGMV kernels of Punica and S-LoRA.
+//!
+//! ```ignore
+//! let selection = AdapterSelection::new();
+//! let mut q_proj = MultiLoraLinear::new(linear_no_bias(hidden, hidden, vb.pp("q_proj"))?, &selection);
+//! q_proj.add_adapter(0, LoraWeights::load(adapter_vb.pp("q_proj"), hidden, hidden, 8, 16.)?)?;
+//! // The first 5 tokens use the adapter 0, the next 3 ones the base model.
+//! selection.set(vec![AdapterSegment::new(Some(0), 5), AdapterSegment::new(None, 3)])?;
+//! let ys = q_proj.forward(&xs)?;
+//! ```
+use crate::{Linear, VarBuilder};
+use candle::{Module, Result, Tensor};
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+
+/// The low rank update `scale * b a` of a linear layer.
+#[derive(Debug, Clone)]
+pub struct LoraWeights {
+    /// The down projection, with shape `(rank, in_dim)`.
+    pub(crate) a: Tensor,
+    /// The up projection, with shape `(out_dim, rank)`.
+    pub(crate) b: Tensor,
+    scale: f64,
+}
+
+impl LoraWeights {
+    /// The update is scaled by `alpha / rank` as in the LoRA paper.
+    pub fn new(a: Tensor, b: Tensor, alpha: f64) -> Result<Self> {
+        let (rank, _in_dim) = a.dims2()?;
+        let (_out_dim, b_rank) = b.dims2()?;
+        if rank != b_rank {
+            candle::bail!("lora rank mismatch, a {:?}, b {:?}", a.shape(), b.shape())
+        }
+        Ok(Self {
+            a,
+            b,
+            scale: alpha / rank as f64,
+        })
+    }
+
+    /// Loads the weights using the PEFT names, `lora_A.weight` and `lora_B.weight`.
+    pub fn load(
+        vb: VarBuilder,
+        in_dim: usize,
+        out_dim: usize,
+        rank: usize,
+        alpha: f64,
+    ) -> Result<Self> {
+        let a = vb.get((rank, in_dim), "lora_A.weight")?;
+        let b = vb.get((out_dim, rank), "lora_B.weight")?;
+        Self::new(a, b, alpha)
+    }
+
+    /// Creates trainable weights, `lora_A.weight` is initialized as a linear weight and
+    /// `lora_B.weight` with zeros so that the update is zero before training.
+    pub fn init(
+        vb: VarBuilder,
+        in_dim: usize,
+        out_dim: usize,
+        rank: usize,
+        alpha: f64,
+    ) -> Result<Self> {
+        let a = vb.get_with_hints(
+            (rank, in_dim),
+            "lora_A.weight",
+            crate::init::DEFAULT_KAIMING_NORMAL,
+        )?;
+        let b = vb.get_with_hints((out_dim, rank), "lora_B.weight", crate::Init::Const(0.))?;
+        Self::new(a, b, alpha)
+    }
+
+    /// Replaces the `alpha / rank` scale, e.g. with the `alpha / sqrt(rank)` of rsLoRA.
+    pub fn with_scale(mut self, scale: f64) -> Self {
+        self.scale = scale;
+        self
+    }
+
+    pub fn a(&self) -> &Tensor {
+        &self.a
+    }
+
+    pub fn b(&self) -> &Tensor {
+        &self.b
+    }
+
+    pub fn rank(&self) -> usize {
+        self.a.dims()[0]
+    }
+
+    pub fn scale(&self) -> f64 {
+        self.scale
+    }
+
+    /// The update of the weight, `scale * b a` with shape `(out_dim, in_dim)`.
+    pub fn delta(&self) -> Result<Tensor> {
+        self.b.matmul(&self.a)? * self.scale
+    }
+
+    pub(crate) fn delta_shape(&self) -> (usize, usize) {
+        (self.b.dims()[0], self.a.dims()[1])
+    }
+
+    /// Computes the update for `xs` with shape `(rows, in_dim)`.
+    pub(crate) fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        xs.matmul(&self.a.t()?)?.matmul(&self.b.t()?)? * self.scale
+    }
+}
+
+/// Consecutive tokens of a batch that use the same adapter, `None` for the base model.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AdapterSegment {
+    pub adapter: Option<usize>,
+    pub len: usize,
+}
+
+impl AdapterSegment {
+    pub fn new(adapter: Option<usize>, len: usize) -> Self {
+        Self { adapter, len }
+    }
+}
+
+/// The adapters used by the tokens of the current batch, the clones refer to the same
+/// selection. No adapter is used when the selection is empty.
+#[derive(Debug, Clone, Default)]
+pub struct AdapterSelection(Arc<RwLock<Vec<AdapterSegment>>>);
+
+impl AdapterSelection {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the segments for the next forward passes, they cover the rows of the inputs of
+    /// the layers in order, the leading dimensions of the inputs being flattened.
+    pub fn set(&self, segments: Vec<AdapterSegment>) -> Result<()> {
+        *self.0.write().map_err(poisoned)? = segments;
+        Ok(())
+    }
+
+    /// Uses the base model for all the tokens.
+    pub fn clear(&self) -> Result<()> {
+        self.0.write().map_err(poisoned)?.clear();
+        Ok(())
+    }
+
+    pub fn segments(&self) -> Result<Vec<AdapterSegment>> {
+        Ok(self.0.read().map_err(poisoned)?.clone())
+    }
+}
+
+fn poisoned<T>(_: std::sync::PoisonError<T>) -> candle::Error {
+    candle::Error::Msg("the adapter selection lock is poisoned".to_string())
+}
+
+/// A linear layer with any number of LoRA adapters, the adapter applied to each token is
+/// given by an [`AdapterSelection`].
+#[derive(Debug, Clone)]
+pub struct MultiLoraLinear {
+    base: Linear,
+    adapters: HashMap<usize, LoraWeights>,
+    selection: AdapterSelection,
+}
+
+impl MultiLoraLinear {
+    pub fn new(base: Linear, selection: &AdapterSelection) -> Self {
+        Self {
+            base,
+            adapters: HashMap::new(),
+            selection: selection.clone(),
+        }
+    }
+
+    pub fn base(&self) -> &Linear {
+        &self.base
+    }
+
+    /// Adds or replaces the adapter with this id.
+    pub fn add_adapter(&mut self, id: usize, weights: LoraWeights) -> Result<()> {
+        let (out_dim, in_dim) = self.base.weight().dims2()?;
+        if weights.a.dim(1)? != in_dim || weights.b.dim(0)? != out_dim {
+            candle::bail!(
+                "lora adapter {id} with a {:?} and b {:?} does not match the weight {:?}",
+                weights.a.shape(),
+                weights.b.shape(),
+                self.base.weight().shape()
+            )
+        }
+        self.adapters.insert(id, weights);
+        Ok(())
+    }
+
+    pub fn remove_adapter(&mut self, id: usize) -> Option<LoraWeights> {
+        self.adapters.remove(&id)
+    }
+
+    pub fn has_adapter(&self, id: usize) -> bool {
+        self.adapters.contains_key(&id)
+    }
+
+    pub fn adapter_ids(&self) -> Vec<usize> {
+        let mut ids = self.adapters.keys().copied().collect::<Vec<_>>();
+        ids.sort();
+        ids
+    }
+}
+
+impl Module for MultiLoraLinear {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        let ys = self.base.forward(xs)?;
+        let segments = self.selection.segments()?;
+        // The adapters of this layer used by the batch, and the index of the adapter of each
+        // row in this list shifted by one, 0 being for the rows that use the base weights. The
+        // tokens using an adapter that this layer does not have use the base weights.
+        let mut used: Vec<usize> = vec![];
+        let mut slots: Vec<u32> = vec![];
+        for segment in segments.iter() {
+            let slot = match segment.adapter.filter(|id| self.adapters.contains_key(id)) {
+                None => 0,
+                Some(id) => match used.iter().position(|&i| i == id) {
+                    Some(pos) => pos + 1,
+                    None => {
+                        used.push(id);
+                        used.len()
+                    }
+                },
+            };
+            slots.extend(std::iter::repeat_n(slot as u32, segment.len))
+        }
+        if used.is_empty() {
+            return Ok(ys);
+        }
+        let in_dim = xs.dim(candle::D::Minus1)?;
+        let xs = xs.reshape(((), in_dim))?;
+        let num_rows = xs.dim(0)?;
+        if slots.len() != num_rows {
+            let len = slots.len();
+            candle::bail!("the adapter segments cover {len} rows, the input has {num_rows}")
+        }
+        let delta = if used.len() == 1 && slots.iter().all(|&s| s == 1) {
+            self.adapters[&used[0]].forward(&xs)?
+        } else {
+            // The weights are stacked with the smaller ranks padded with zeros, the scales are
+            // folded in the up projections.
+            let rank = used
+                .iter()
+                .map(|id| self.adapters[id].rank())
+                .max()
+                .unwrap_or(0);
+            let first = &self.adapters[&used[0]];
+            let (out_dim, _) = first.delta_shape();
+            let (dtype, dev) = (first.a.dtype(), first.a.device());
+            let mut a = vec![Tensor::zeros((rank, in_dim), dtype, dev)?];
+            let mut b = vec![Tensor::zeros((out_dim, rank), dtype, dev)?];
+            for id in used.iter() {
+                let adapter = &self.adapters[id];
+                let pad = rank - adapter.rank();
+                a.push(adapter.a.pad_with_zeros(0, 0, pad)?);
+                b.push((&adapter.b * adapter.scale)?.pad_with_zeros(1, 0, pad)?);
+            }
+            let slots = Tensor::new(slots.as_slice(), xs.device())?;
+            // (rows, in_dim, rank) and (rows, rank, out_dim).
+            let a = Tensor::stack(&a, 0)?
+                .index_select(&slots, 0)?
+                .transpose(1, 2)?;
+            let b = Tensor::stack(&b, 0)?
+                .index_select(&slots, 0)?
+                .transpose(1, 2)?;
+            xs.unsqueeze(1)?.matmul(&a)?.matmul(&b)?.squeeze(1)?
+        };
+        &ys + delta.reshape(ys.shape())?
+    }
+}
@@ -0,0 +1,333 @@
+//! Maximal update parametrization (muP).
+//!
+//! With the standard parametrization the best learning rate and initialization depend on the
+//! width of the model, so the hyperparameters tuned on a small model do not transfer to a larger
+//! one. muP rescales the initialization, the learning rates and the output of the model as a
+//! function of the width so that they do, see "Tensor Programs V: Tuning Large Neural Networks
+//! via Zero-Shot Hyperparameter Transfer", Yang, G. et al. (2022).
+//!
+//! The widths are found by comparing the variables of the model with the ones of a base model,
+//! the same model built with a smaller width: the dimensions that differ are the width
+//! dimensions and their ratio is the width multiplier. The hyperparameters tuned on the base
+//! model can then be used as is, muP with the base width is the standard parametrization.
+//!
+//! ```rust
+//! # fn main() -> candle::Result<()> {
+//! use candle::{DType, Device};
+//! use candle_nn::mup::{MupOptimizer, MupShapes};
+//! use candle_nn::{AdamW, ParamsAdamW, VarBuilder, VarMap};
+//! let mlp = |hidden: usize, varmap: &VarMap| -> candle::Result<()> {
+//!     let vb = VarBuilder::from_varmap(varmap, DType::F32, &Device::Cpu);
+//!     candle_nn::linear(8, hidden, vb.pp("fc1"))?;
+//!     candle_nn::linear(hidden, hidden, vb.pp("fc2"))?;
+//!     candle_nn::linear(hidden, 2, vb.pp("head"))?;
+//!     Ok(())
+//! };
+//! let (base, model) = (VarMap::new(), VarMap::new());
+//! mlp(16, &base)?;
+//! mlp(64, &model)?;
+//! let shapes = MupShapes::new(&base, &model)?;
+//! assert_eq!(shapes.get("fc2.weight").unwrap().fan_in_mult(), 4.);
+//! let params = ParamsAdamW { lr: 1e-3, ..Default::default() };
+//! let opt = shapes.param_groups::<AdamW>(&model, params, MupOptimizer::Adam)?;
+//! assert_eq!(opt.group_learning_rate(opt.group_of("fc2.weight").unwrap())?, 2.5e-4);
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! The output layer should be wrapped in a [`MupReadout`], and the attention scores are usually
+//! scaled by `1 / head_dim` rather than `1 / sqrt(head_dim)`.
+use crate::init::Init;
+use crate::optim::{Optimizer, ParamGroup, ParamGroups};
+use crate::var_map::{matches_pattern, VarMap};
+use crate::Linear;
+use candle::{Module, Result, Shape, Tensor};
+use std::collections::HashMap;
+
+/// The update rule used by the optimizer, the learning rates are scaled differently for plain
+/// gradient descent and for the optimizers normalizing the gradients.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MupOptimizer {
+    /// Adaptive optimizers such as [`crate::AdamW`], [`crate::optim::Lion`] or
+    /// [`crate::optim::Lamb`].
+    Adam,
+    /// [`crate::SGD`] and [`crate::optim::SGDMomentum`].
+    Sgd,
+}
+
+/// The shape of a variable together with the shape it has in the base model.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MupShape {
+    dims: Vec<usize>,
+    base_dims: Vec<usize>,
+}
+
+impl MupShape {
+    pub fn new(base: &Shape, shape: &Shape) -> Result<Self> {
+        if base.rank() != shape.rank() {
+            candle::bail!("mup: shape {shape:?} does not match the base shape {base:?}")
+        }
+        let s = Self {
+            dims: shape.dims().to_vec(),
+            base_dims: base.dims().to_vec(),
+        };
+        if s.num_width_dims() > 2 {
+            candle::bail!("mup: shape {shape:?} has more than two width dimensions ({base:?})")
+        }
+        Ok(s)
+    }
+
+    pub fn dims(&self) -> &[usize] {
+        &self.dims
+    }
+
+    pub fn base_dims(&self) -> &[usize] {
+        &self.base_dims
+    }
+
+    fn dim_mult(&self, dim: usize) -> f64 {
+        match (self.dims.get(dim), self.base_dims.get(dim)) {
+            (Some(&d), Some(&b)) if b != 0 => d as f64 / b as f64,
+            _ => 1.,
+        }
+    }
+
+    /// The number of dimensions that scale with the width: 0 for scalar-like variables, 1 for
+    /// vector-like variables such as the biases, the embeddings and the output weights, and 2
+    /// for the hidden weights.
+    pub fn num_width_dims(&self) -> usize {
+        self.dims
+            .iter()
+            .zip(self.base_dims.iter())
+            .filter(|(d, b)| d != b)
+            .count()
+    }
+
+    /// The multiplier of the input dimension, i.e. dimension 1 as for the weights of
+    /// [`crate::Linear`] and [`crate::Conv2d`].
+    pub fn fan_in_mult(&self) -> f64 {
+        self.dim_mult(1)
+    }
+
+    /// The multiplier of the output dimension, i.e. dimension 0.
+    pub fn fan_out_mult(&self) -> f64 {
+        self.dim_mult(0)
+    }
+
+    /// The multiplier of the width dimension of vector-like variables, and of the fan-in of the
+    /// hidden weights.
+    pub fn width_mult(&self) -> f64 {
+        match self.num_width_dims() {
+            0 => 1.,
+            1 => {
+                let dim = (0..self.dims.len()).find(|&i| self.dims[i] != self.base_dims[i]);
+                dim.map_or(1., |dim| self.dim_mult(dim))
+            }
+            _ => self.fan_in_mult(),
+        }
+    }
+
+    /// The factor applied to the learning rate of the variable.
+    pub fn lr_mult(&self, optimizer: MupOptimizer) -> f64 {
+        match (optimizer, self.num_width_dims()) {
+            (MupOptimizer::Adam, 2) => 1. / self.fan_in_mult(),
+            (MupOptimizer::Adam, _) => 1.,
+            (MupOptimizer::Sgd, 0) => 1.,
+            (MupOptimizer::Sgd, 1) => self.width_mult(),
+            (MupOptimizer::Sgd, _) => self.fan_out_mult() / self.fan_in_mult(),
+        }
+    }
+
+    /// Scales the standard deviation of a random initialization of the hidden weights by
+    /// `1 / sqrt(fan_in_mult)`. The Kaiming initializations already depend on the actual fan-in
+    /// and are returned unchanged, as are the ones of the other variables.
+    pub fn init(&self, init: Init) -> Init {
+        if self.num_width_dims() != 2 {
+            return init;
+        }
+        let scale = self.fan_in_mult().sqrt().recip();
+        match init {
+            Init::Randn { mean, stdev } => Init::Randn {
+                mean,
+                stdev: stdev * scale,
+            },
+            Init::Uniform { lo, up } => {
+                let (mid, half) = ((lo + up) / 2., (up - lo) / 2.);
+                Init::Uniform {
+                    lo: mid - half * scale,
+                    up: mid + half * scale,
+                }
+            }
+            init @ (Init::Const(_) | Init::Kaiming { .. }) => init,
+        }
+    }
+}
+
+/// The shapes of the variables of a model relative to a base model.
+#[derive(Debug, Clone, Default)]
+pub struct MupShapes {
+    shapes: HashMap<String, MupShape>,
+}
+
+impl MupShapes {
+    /// Compares the trainable variables of `model` with the ones of `base`, both maps must have
+    /// the same variable names.
+    pub fn new(base: &VarMap, model: &VarMap) -> Result<Self> {
+        let shapes = |varmap: &VarMap| {
+            varmap
+                .named_vars()
+                .into_iter()
+                .map(|(name, var)| (name, var.shape().clone()))
+                .collect::<HashMap<_, _>>()
+        };
+        Self::from_shapes(&shapes(base), &shapes(model))
+    }
+
+    /// Same as [`Self::new`] with explicit shapes, e.g. base shapes saved by an earlier run.
+    pub fn from_shapes(
+        base: &HashMap<String, Shape>,
+        model: &HashMap<String, Shape>,
+    ) -> Result<Self> {
+        let mut shapes = HashMap::new();
+        for (name, shape) in model.iter() {
+            let base = match base.get(name) {
+                Some(base) => base,
+                None => candle::bail!("mup: {name} is not in the base model"),
+            };
+            match MupShape::new(base, shape) {
+                Ok(shape) => shapes.insert(name.clone(), shape),
+                Err(err) => candle::bail!("mup: invalid variable {name}, {err}"),
+            };
+        }
+        if let Some(name) = base.keys().find(|name| !model.contains_key(*name)) {
+            candle::bail!("mup: {name} is not in the model")
+        }
+        Ok(Self { shapes })
+    }
+
+    pub fn get(&self, name: &str) -> Option<&MupShape> {
+        self.shapes.get(name)
+    }
+
+    fn get_or_err(&self, name: &str) -> Result<&MupShape> {
+        match self.shapes.get(name) {
+            Some(shape) => Ok(shape),
+            None => candle::bail!("mup: unknown variable {name}"),
+        }
+    }
+
+    /// The variable names, sorted.
+    pub fn names(&self) -> Vec<&str> {
+        let mut names: Vec<_> = self.shapes.keys().map(|n| n.as_str()).collect();
+        names.sort();
+        names
+    }
+
+    /// Re-initializes the variables of `varmap` whose names match `pattern` with `init`,
+    /// rescaled as per [`MupShape::init`]. The readout weights are often initialized to zero.
+    pub fn reinit(&self, varmap: &VarMap, pattern: &str, init: Init) -> Result<()> {
+        for (name, var) in varmap.named_vars() {
+            if !matches_pattern(pattern, &name) {
+                continue;
+            }
+            let init = self.get_or_err(&name)?.init(init);
+            let t = init.var(var.shape(), var.dtype(), var.device())?;
+            var.set(t.as_tensor())?
+        }
+        Ok(())
+    }
+
+    /// Creates an optimizer for the trainable variables of `varmap`, with the learning rate of
+    /// `config` scaled for each variable. The variables whose learning rate is not scaled use
+    /// the default group, the other ones are grouped by multiplier in groups named `mup0`,
+    /// `mup1`, etc, use [`ParamGroups::group_of`] to find the group of a variable. The learning
+    /// rates of the groups keep their ratio to the default one so that the learning rate
+    /// schedulers can be used as with a single group.
+    pub fn param_groups<O: Optimizer>(
+        &self,
+        varmap: &VarMap,
+        config: O::Config,
+        optimizer: MupOptimizer,
+    ) -> Result<ParamGroups<O>>
+    where
+        O::Config: Clone,
+    {
+        // The learning rate multiplier and the variable names of each group.
+        let mut groups: Vec<(f64, Vec<String>)> = vec![];
+        for (name, _) in varmap.named_vars() {
+            let lr_mult = self.get_or_err(&name)?.lr_mult(optimizer);
+            if lr_mult == 1. {
+                continue;
+            }
+            match groups.iter_mut().find(|(m, _)| *m == lr_mult) {
+                Some((_, names)) => names.push(name),
+                None => groups.push((lr_mult, vec![name])),
+            }
+        }
+        let param_groups = groups
+            .iter()
+            .enumerate()
+            .map(|(i, (_, names))| {
+                // Patterns without a `*` only match the exact variable name.
+                ParamGroup::new(format!("mup{i}"), config.clone()).with_patterns(names)
+            })
+            .collect();
+        let mut opt = ParamGroups::<O>::from_varmap(varmap, config, param_groups)?;
+        let lr = opt.learning_rate();
+        for (i, (lr_mult, _)) in groups.iter().enumerate() {
+            opt.set_group_learning_rate(&format!("mup{i}"), lr * lr_mult)?
+        }
+        Ok(opt)
+    }
+}
+
+/// The output layer of a muP model, the logits are divided by the width multiplier of the
+/// layer so that they do not grow with the width.
+#[derive(Debug, Clone)]
+pub struct MupReadout {
+    linear: Linear,
+    width_mult: f64,
+    output_mult: f64,
+}
+
+impl MupReadout {
+    pub fn new(linear: Linear, width_mult: f64) -> Self {
+        Self {
+            linear,
+            width_mult,
+            output_mult: 1.,
+        }
+    }
+
+    /// Uses the width multiplier of the variable `name`, typically `"lm_head.weight"`.
+    pub fn from_shapes(linear: Linear, shapes: &MupShapes, name: &str) -> Result<Self> {
+        let width_mult = shapes.get_or_err(name)?.width_mult();
+        Ok(Self::new(linear, width_mult))
+    }
+
+    /// A constant factor applied to the logits, a hyperparameter tuned on the base model.
+    pub fn with_output_mult(mut self, output_mult: f64) -> Self {
+        self.output_mult = output_mult;
+        self
+    }
+
+    pub fn linear(&self) -> &Linear {
+        &self.linear
+    }
+
+    pub fn width_mult(&self) -> f64 {
+        self.width_mult
+    }
+}
+
+impl Module for MupReadout {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        self.linear.forward(xs)? * (self.output_mult / self.width_mult)
+    }
+}
+
+impl crate::layer::Layer for MupReadout {
+    fn visit_parameters(&mut self, f: &mut crate::layer::TensorFn) -> Result<()> {
+        self.linear.visit_parameters(f)
+    }
+}
@@ -0,0 +1,322 @@
+//! Offloading of the activations saved for the backward pass to host memory.
+//!
+//! When training on long sequences most of the device memory is used by the activations that the
+//! backward pass needs, rather than by the weights. An [`ActivationOffloader`] splits the forward
+//! pass in segments, typically one per transformer block: once a segment has been computed, the
+//! tensors saved by its ops are copied to page-locked host memory by a worker thread and their
+//! device memory is released. The backward pass goes through the segments in reverse order, when
+//! it reaches a segment the saved tensors are copied back and the ones of the previous segment
+//! are prefetched so that their copy overlaps with the backward computations.
+//!
+//! ```rust
+//! # fn main() -> candle::Result<()> {
+//! use candle::{DType, Device, Module, Tensor};
+//! use candle_nn::offload::ActivationOffloader;
+//! let dev = Device::Cpu;
+//! let varmap = candle_nn::VarMap::new();
+//! let vb = candle_nn::VarBuilder::from_varmap(&varmap, DType::F32, &dev);
+//! let blocks = (0..2)
+//!     .map(|i| candle_nn::linear(4, 4, vb.pp(i)))
+//!     .collect::<Result<Vec<_>, _>>()?;
+//! let offloader = ActivationOffloader::new();
+//! let mut xs = Tensor::ones((3, 4), DType::F32, &dev)?;
+//! for block in blocks.iter() {
+//!     xs = offloader.run(&xs, |xs| block.forward(xs)?.tanh())?;
+//! }
+//! let grads = xs.sum_all()?.backward()?;
+//! assert_eq!(offloader.stats().segments, 2);
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! The tensors created before a segment and reused by it, e.g. the activations of a previous
+//! block, are kept on the device. Only cuda tensors are offloaded, on other devices the segments
+//! are tracked but the tensors stay where they are.
+use candle::offload::{self, Offloaded};
+use candle::{CpuStorage, CustomOp1, Layout, Result, Shape, Tensor};
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+
+/// Counters for the activations handled by an [`ActivationOffloader`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct OffloadStats {
+    /// The number of segments run through the offloader.
+    pub segments: usize,
+    /// The number of saved tensors found in these segments that are above the size threshold.
+    pub saved_tensors: usize,
+    pub offloaded_tensors: usize,
+    pub offloaded_bytes: usize,
+    pub reloaded_bytes: usize,
+}
+
+enum Segment {
+    // The tensors are being copied to the host by a worker thread.
+    Offloading(std::thread::JoinHandle<Result<Vec<Offloaded>>>),
+    Offloaded(Vec<Offloaded>),
+}
+
+#[derive(Default)]
+struct State {
+    segments: BTreeMap<usize, Segment>,
+    next_id: usize,
+    stats: OffloadStats,
+}
+
+impl State {
+    // Waits for the worker thread of a segment, the segment must exist.
+    fn wait(&mut self, id: usize) -> Result<&mut Vec<Offloaded>> {
+        let segment = self.segments.get_mut(&id).expect("unknown segment");
+        if let Segment::Offloading(_) = segment {
+            let Segment::Offloading(handle) =
+                std::mem::replace(segment, Segment::Offloaded(vec![]))
+            else {
+                unreachable!()
+            };
+            let offloaded = match handle.join() {
+                Ok(offloaded) => offloaded?,
+                Err(_) => candle::bail!("activation offloading thread panicked"),
+            };
+            self.stats.offloaded_tensors += offloaded.len();
+            self.stats.offloaded_bytes += offloaded.iter().map(|o| o.bytes()).sum::<usize>();
+            *segment = Segment::Offloaded(offloaded)
+        }
+        match segment {
+            Segment::Offloaded(offloaded) => Ok(offloaded),
+            Segment::Offloading(_) => unreachable!(),
+        }
+    }
+
+    fn reload(&mut self, id: usize) -> Result<()> {
+        if !self.segments.contains_key(&id) {
+            return Ok(());
+        }
+        let offloaded = std::mem::take(self.wait(id)?);
+        self.segments.remove(&id);
+        for o in offloaded {
+            self.stats.reloaded_bytes += o.bytes();
+            o.reload()?
+        }
+        Ok(())
+    }
+
+    // Copies back the offloaded storages used by `tensors`.
+    fn keep_on_device(&mut self, tensors: &[Tensor]) -> Result<()> {
+        let ids: Vec<usize> = self.segments.keys().copied().collect();
+        for id in ids {
+            let offloaded = self.wait(id)?;
+            let (used, kept): (Vec<_>, Vec<_>) = std::mem::take(offloaded)
+                .into_iter()
+                .partition(|o| tensors.iter().any(|t| o.shares_storage(t)));
+            *offloaded = kept;
+            for o in used {
+                self.stats.reloaded_bytes += o.bytes();
+                o.reload()?
+            }
+        }
+        Ok(())
+    }
+
+    // Forgets the storages that are not used by any graph anymore, e.g. when a forward pass was
+    // not followed by a backward pass.
+    fn prune(&mut self) {
+        self.segments.retain(|_, segment| match segment {
+            Segment::Offloading(_) => true,
+            Segment::Offloaded(offloaded) => {
+                offloaded.retain(|o| o.is_used());
+                !offloaded.is_empty()
+            }
+        })
+    }
+}
+
+/// Offloads the activations of the segments of a forward pass, see the [module
+/// documentation](self).
+#[derive(Clone)]
+pub struct ActivationOffloader {
+    state: Arc<Mutex<State>>,
+    enabled: bool,
+    prefetch: bool,
+    min_bytes: usize,
+}
+
+impl Default for ActivationOffloader {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl std::fmt::Debug for ActivationOffloader {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ActivationOffloader")
+            .field("enabled", &self.enabled)
+            .field("prefetch", &self.prefetch)
+            .field("min_bytes", &self.min_bytes)
+            .field("stats", &self.stats())
+            .finish()
+    }
+}
+
+impl ActivationOffloader {
+    pub fn new() -> Self {
+        Self {
+            state: Arc::new(Mutex::new(State::default())),
+            enabled: true,
+            prefetch: true,
+            min_bytes: 1 << 20,
+        }
+    }
+
+    /// When disabled, [`Self::run`] only applies the segment function.
+    pub fn with_enabled(mut self, enabled: bool) -> Self {
+        self.enabled = enabled;
+        self
+    }
+
+    /// Whether the backward pass of a segment prefetches the tensors of the previous one, this
+    /// is enabled by default.
+    pub fn with_prefetch(mut self, prefetch: bool) -> Self {
+        self.prefetch = prefetch;
+        self
+    }
+
+    /// The smaller tensors are kept on the device, the transfer of a tensor has a fixed cost that
+    /// does not pay off for small ones. This defaults to 1MiB.
+    pub fn with_min_bytes(mut self, min_bytes: usize) -> Self {
+        self.min_bytes = min_bytes;
+        self
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    /// Computes `f(xs)` and offloads the tensors saved by the ops of `f`. The returned tensor has
+    /// the value of `f(xs)`, its backward pass copies the saved tensors back to the device.
+    pub fn run<F: FnOnce(&Tensor) -> Result<Tensor>>(&self, xs: &Tensor, f: F) -> Result<Tensor> {
+        let ys = f(xs)?;
+        if !self.enabled {
+            return Ok(ys);
+        }
+        let saved = offload::saved_tensors(&ys, &[xs]);
+        let tensors: Vec<Tensor> = saved
+            .tensors
+            .into_iter()
+            .filter(|t| t.elem_count() * t.dtype().size_in_bytes() >= self.min_bytes)
+            .collect();
+        let mut state = self.state.lock().unwrap();
+        state.prune();
+        if !saved.frontier.is_empty() {
+            state.keep_on_device(&saved.frontier)?
+        }
+        state.stats.segments += 1;
+        state.stats.saved_tensors += tensors.len();
+        let segment = if tensors.iter().all(|t| !t.device().is_cuda()) {
+            Segment::Offloaded(vec![])
+        } else {
+            Segment::Offloading(std::thread::spawn(move || {
+                let mut offloaded = Vec::with_capacity(tensors.len());
+                for t in tensors.iter() {
+                    offloaded.extend(offload::offload(t)?)
+                }
+                Ok(offloaded)
+            }))
+        };
+        let id = state.next_id;
+        state.next_id += 1;
+        state.segments.insert(id, segment);
+        drop(state);
+        ys.apply_op1(Boundary {
+            id,
+            offloader: self.clone(),
+        })
+    }
+
+    /// Copies back all the tensors that are still offloaded and forgets the segments.
+    pub fn clear(&self) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        let ids: Vec<usize> = state.segments.keys().copied().collect();
+        for id in ids {
+            state.reload(id)?
+        }
+        Ok(())
+    }
+
+    /// The number of segments whose tensors have not been copied back yet.
+    pub fn pending_segments(&self) -> usize {
+        self.state.lock().unwrap().segments.len()
+    }
+
+    pub fn stats(&self) -> OffloadStats {
+        self.state.lock().unwrap().stats
+    }
+
+    fn backward(&self, id: usize) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        state.reload(id)?;
+        if self.prefetch {
+            if let Some(&prev) = state.segments.range(..id).next_back().map(|(id, _)| id) {
+                state.reload(prev)?
+            }
+        }
+        Ok(())
+    }
+}
+
+// The identity op marking the end of a segment, its backward pass runs before the ones of the
+// ops of the segment.
+struct Boundary {
+    id: usize,
+    offloader: ActivationOffloader,
+}
+
+impl CustomOp1 for Boundary {
+    fn name(&self) -> &'static str {
+        "offload-boundary"
+    }
+
+    fn cpu_fwd(&self, storage: &CpuStorage, layout: &Layout) -> Result<(CpuStorage, Shape)> {
+        use candle::backend::{BackendDevice, BackendStorage};
+        let mut dst = unsafe {
+            candle::cpu_backend::CpuDevice.alloc_uninit(layout.shape(), storage.dtype())?
+        };
+        storage.copy_strided_src(&mut dst, 0, layout)?;
+        Ok((dst, layout.shape().clone()))
+    }
+
+    #[cfg(feature = "cuda")]
+    fn cuda_fwd(
+        &self,
+        storage: &candle::CudaStorage,
+        layout: &Layout,
+    ) -> Result<(candle::CudaStorage, Shape)> {
+        use candle::backend::{BackendDevice, BackendStorage};
+        let mut dst = unsafe {
+            storage
+                .device()
+                .alloc_uninit(layout.shape(), storage.dtype())?
+        };
+        storage.copy_strided_src(&mut dst, 0, layout)?;
+        Ok((dst, layout.shape().clone()))
+    }
+
+    #[cfg(feature = "metal")]
+    fn metal_fwd(
+        &self,
+        storage: &candle::MetalStorage,
+        layout: &Layout,
+    ) -> Result<(candle::MetalStorage, Shape)> {
+        use candle::backend::{BackendDevice, BackendStorage};
+        let mut dst = unsafe {
+            storage
+                .device()
+                .alloc_uninit(layout.shape(), storage.dtype())?
+        };
+        storage.copy_strided_src(&mut dst, 0, layout)?;
+        Ok((dst, layout.shape().clone()))
+    }
+
+    fn bwd(&self, _arg: &Tensor, _res: &Tensor, grad_res: &Tensor) -> Result<Option<Tensor>> {
+        self.offloader.backward(self.id)?;
+        Ok(Some(grad_res.clone()))
+    }
+}
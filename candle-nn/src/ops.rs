@@ -19,22 +19,37 @@ use rayon::prelude::*;
 ///     ]);
 /// # Ok::<(), candle::Error>(())
 /// ```
+///
+/// Half precision inputs are computed in f32.
 pub fn softmax<D: candle::shape::Dim>(xs: &Tensor, dim: D) -> Result<Tensor> {
     let dim = dim.to_index(xs.shape(), "softmax")?;
+    let x_dtype = xs.dtype();
+    let xs = xs.to_dtype(internal_dtype(x_dtype))?;
     let max = xs.max_keepdim(dim)?;
     let diff = xs.broadcast_sub(&max)?;
     let num = diff.exp()?;
     let den = num.sum_keepdim(dim)?;
-    num.broadcast_div(&den)
+    num.broadcast_div(&den)?.to_dtype(x_dtype)
 }
 
+/// The log of the softmax, half precision inputs are computed in f32.
 pub fn log_softmax<D: candle::shape::Dim>(xs: &Tensor, d: D) -> Result<Tensor> {
     let d = d.to_index(xs.shape(), "log-softmax")?;
+    let x_dtype = xs.dtype();
+    let xs = xs.to_dtype(internal_dtype(x_dtype))?;
     let max = xs.max_keepdim(d)?;
     let diff = xs.broadcast_sub(&max)?;
     let sum_exp = diff.exp()?.sum_keepdim(d)?;
     let log_sm = diff.broadcast_sub(&sum_exp.log()?)?;
-    Ok(log_sm)
+    log_sm.to_dtype(x_dtype)
+}
+
+// The dtype used for the intermediary values of the normalization ops.
+fn internal_dtype(dtype: DType) -> DType {
+    match dtype {
+        DType::F16 | DType::BF16 => DType::F32,
+        d => d,
+    }
 }
 
 pub fn silu(xs: &Tensor) -> Result<Tensor> {
@@ -245,6 +260,37 @@ pub fn leaky_relu(xs: &Tensor, negative_slope: f64) -> Result<Tensor> {
     xs.maximum(&zeros)? + xs.minimum(&zeros)? * negative_slope
 }
 
+/// Computes `activation(xs + bias) + residual` as a single fused elementwise operation when
+/// possible, `bias` and `residual` are broadcast to the shape of `xs`.
+///
+/// The fusion is done using [`candle::LazyTensor`], activations that are not supported by the
+/// lazy mode are applied eagerly.
+pub fn bias_activation_residual(
+    xs: &Tensor,
+    bias: Option<&Tensor>,
+    activation: crate::Activation,
+    residual: Option<&Tensor>,
+) -> Result<Tensor> {
+    use crate::Activation;
+
+    let mut lazy = xs.lazy();
+    if let Some(bias) = bias {
+        lazy = lazy.broadcast_add(&bias.lazy())?
+    }
+    let lazy = match activation {
+        Activation::Gelu => lazy.gelu_erf()?,
+        Activation::NewGelu | Activation::GeluPytorchTanh => lazy.gelu()?,
+        Activation::Relu => lazy.relu()?,
+        Activation::Relu2 => lazy.relu()?.sqr()?,
+        Activation::Silu | Activation::Swish => lazy.silu()?,
+        activation => activation.forward(&lazy.realize()?)?.lazy(),
+    };
+    match residual {
+        None => lazy.realize(),
+        Some(residual) => lazy.broadcast_add(&residual.lazy())?.realize(),
+    }
+}
+
 pub fn dropout(xs: &Tensor, drop_p: f32) -> Result<Tensor> {
     // This implementation is inefficient as it stores the full mask for the backward pass.
     // Instead we could just store the seed and have a specialized kernel that would both
@@ -264,11 +310,16 @@ pub fn dropout(xs: &Tensor, drop_p: f32) -> Result<Tensor> {
 #[derive(Clone, Debug)]
 pub struct Dropout {
     drop_p: f32,
+    // The mode used by [`crate::layer::Layer::forward_mode`].
+    training: bool,
 }
 
 impl Dropout {
     pub fn new(drop_p: f32) -> Dropout {
-        Self { drop_p }
+        Self {
+            drop_p,
+            training: false,
+        }
     }
 
     pub fn forward(&self, xs: &Tensor, train: bool) -> Result<Tensor> {
@@ -286,6 +337,16 @@ impl candle::ModuleT for Dropout {
     }
 }
 
+impl crate::layer::Layer for Dropout {
+    fn set_own_training(&mut self, training: bool) {
+        self.training = training
+    }
+
+    fn is_training(&self) -> Option<bool> {
+        Some(self.training)
+    }
+}
+
 struct SoftmaxLastDim;
 
 impl candle::CustomOp1 for SoftmaxLastDim {
@@ -324,9 +385,50 @@ impl candle::CustomOp1 for SoftmaxLastDim {
             Ok((storage, Shape::from_dims(dims)))
         }
 
+        // The half precision inputs use f32 accumulators so that large logits do not overflow,
+        // the max and the sum of exponentials are computed in a single pass, rescaling the sum
+        // whenever the max changes. A nan makes the whole row nan.
+        fn softmax_half<T>(src: &[T], layout: &Layout) -> Result<(CpuStorage, Shape)>
+        where
+            T: candle::WithDType + num_traits::AsPrimitive<f32>,
+        {
+            let src = match layout.contiguous_offsets() {
+                None => candle::bail!("input has to be contiguous"),
+                Some((o1, o2)) => &src[o1..o2],
+            };
+            let el_count = layout.shape().elem_count();
+            let dims = layout.shape().dims();
+            let dim_m1 = dims[dims.len() - 1];
+            let mut dst = vec![T::zero(); el_count];
+            src.par_chunks(dim_m1)
+                .zip(dst.par_chunks_mut(dim_m1))
+                .for_each(|(src, dst)| {
+                    let mut max = f32::NEG_INFINITY;
+                    let mut sum_exp = 0f32;
+                    for &s in src {
+                        let s: f32 = s.as_();
+                        if s.is_nan() {
+                            sum_exp = f32::NAN
+                        } else if s > max {
+                            sum_exp = sum_exp * (max - s).exp() + 1.;
+                            max = s
+                        } else if s > f32::NEG_INFINITY {
+                            sum_exp += (s - max).exp()
+                        }
+                    }
+                    let inv_sum_exp = sum_exp.recip();
+                    for (s, d) in src.iter().zip(dst.iter_mut()) {
+                        let s: f32 = s.as_();
+                        *d = T::from_f64(((s - max).exp() * inv_sum_exp) as f64)
+                    }
+                });
+            let storage = candle::WithDType::to_cpu_storage_owned(dst);
+            Ok((storage, Shape::from_dims(dims)))
+        }
+
         match storage {
-            CpuStorage::BF16(slice) => softmax::<half::bf16>(slice, layout),
-            CpuStorage::F16(slice) => softmax::<half::f16>(slice, layout),
+            CpuStorage::BF16(slice) => softmax_half::<half::bf16>(slice, layout),
+            CpuStorage::F16(slice) => softmax_half::<half::f16>(slice, layout),
             CpuStorage::F32(slice) => softmax::<f32>(slice, layout),
             CpuStorage::F64(slice) => softmax::<f64>(slice, layout),
             _ => candle::bail!("unsupported dtype for softmax {:?}", storage),
@@ -488,10 +590,10 @@ impl candle::CustomOp2 for RmsNorm {
                             v * v
                         })
                         .sum::<f32>();
-                    let m = (sum2 / dim_m1 as f32 + eps).sqrt();
-                    let m = T::from_f32(m).unwrap_or_else(T::nan);
+                    let inv_m = (sum2 / dim_m1 as f32 + eps).sqrt().recip();
                     for ((d, s), alpha) in dst.iter_mut().zip(src.iter()).zip(alpha) {
-                        *d = *s / m * *alpha
+                        let d_ = s.as_() * inv_m * alpha.as_();
+                        *d = T::from_f32(d_).unwrap_or_else(T::nan);
                     }
                 });
             let storage = candle::WithDType::to_cpu_storage_owned(dst);
@@ -623,16 +725,23 @@ impl candle::CustomOp2 for RmsNorm {
         let newstorage = candle::MetalStorage::new(output, device.clone(), elem_count, s1.dtype());
         Ok((newstorage, l1.shape().clone()))
     }
+
+    fn bwd(
+        &self,
+        x: &Tensor,
+        alpha: &Tensor,
+        _res: &Tensor,
+        grad_res: &Tensor,
+    ) -> Result<(Option<Tensor>, Option<Tensor>)> {
+        let (dx, dalpha) = norm_bwd(x, alpha, grad_res, self.eps, NormKind::Rms)?;
+        Ok((Some(dx), Some(dalpha)))
+    }
 }
 
 pub fn rms_norm_slow(x: &Tensor, alpha: &Tensor, eps: f32) -> Result<Tensor> {
     let x_dtype = x.dtype();
-    let internal_dtype = match x_dtype {
-        DType::F16 | DType::BF16 => DType::F32,
-        d => d,
-    };
     let hidden_size = x.dim(D::Minus1)?;
-    let x = x.to_dtype(internal_dtype)?;
+    let x = x.to_dtype(internal_dtype(x_dtype))?;
     let norm_x = (x.sqr()?.sum_keepdim(D::Minus1)? / hidden_size as f64)?;
     let x_normed = x.broadcast_div(&(norm_x + eps as f64)?.sqrt()?)?;
     x_normed.to_dtype(x_dtype)?.broadcast_mul(alpha)
@@ -648,7 +757,7 @@ pub fn rms_norm(xs: &Tensor, alpha: &Tensor, eps: f32) -> Result<Tensor> {
             alpha.shape()
         )
     }
-    xs.apply_op2_no_bwd(alpha, &RmsNorm { eps })
+    xs.apply_op2(alpha, RmsNorm { eps })
 }
 
 #[derive(Debug, Clone)]
@@ -706,15 +815,18 @@ impl candle::CustomOp3 for LayerNorm {
             src.par_chunks(dim_m1)
                 .zip(dst.par_chunks_mut(dim_m1))
                 .for_each(|(src, dst)| {
-                    let mut sum = 0f32;
-                    let mut sum2 = 0f32;
-                    for v in src {
-                        let v = v.as_();
-                        sum += v;
-                        sum2 += v * v;
-                    }
+                    // The variance is computed around the mean, E[x^2] - E[x]^2 loses all the
+                    // precision when the mean is large compared to the standard deviation.
+                    let sum = src.iter().map(|v| v.as_()).sum::<f32>();
                     let mean = sum / dim_m1 as f32;
-                    let var = sum2 / dim_m1 as f32 - mean * mean;
+                    let sum2 = src
+                        .iter()
+                        .map(|v| {
+                            let v = v.as_() - mean;
+                            v * v
+                        })
+                        .sum::<f32>();
+                    let var = sum2 / dim_m1 as f32;
                     let inv_std = (var + eps).sqrt().recip();
                     for ((d, s), (alpha, beta)) in
                         dst.iter_mut().zip(src.iter()).zip(alpha.iter().zip(beta))
@@ -871,16 +983,30 @@ impl candle::CustomOp3 for LayerNorm {
         let newstorage = candle::MetalStorage::new(output, device.clone(), elem_count, s1.dtype());
         Ok((newstorage, l1.shape().clone()))
     }
+
+    fn bwd(
+        &self,
+        x: &Tensor,
+        alpha: &Tensor,
+        _beta: &Tensor,
+        _res: &Tensor,
+        grad_res: &Tensor,
+    ) -> Result<(Option<Tensor>, Option<Tensor>, Option<Tensor>)> {
+        let (dx, dalpha) = norm_bwd(x, alpha, grad_res, self.eps, NormKind::Layer)?;
+        let dim = x.dim(D::Minus1)?;
+        let dbeta = grad_res
+            .reshape(((), dim))?
+            .to_dtype(DType::F32)?
+            .sum(0)?
+            .to_dtype(alpha.dtype())?;
+        Ok((Some(dx), Some(dalpha), Some(dbeta)))
+    }
 }
 
 pub fn layer_norm_slow(x: &Tensor, alpha: &Tensor, beta: &Tensor, eps: f32) -> Result<Tensor> {
     let x_dtype = x.dtype();
-    let internal_dtype = match x_dtype {
-        DType::F16 | DType::BF16 => DType::F32,
-        d => d,
-    };
     let hidden_size = x.dim(D::Minus1)?;
-    let x = x.to_dtype(internal_dtype)?;
+    let x = x.to_dtype(internal_dtype(x_dtype))?;
     let x = {
         let mean_x = (x.sum_keepdim(D::Minus1)? / hidden_size as f64)?;
         x.broadcast_sub(&mean_x)?
@@ -905,7 +1031,271 @@ pub fn layer_norm(xs: &Tensor, alpha: &Tensor, beta: &Tensor, eps: f32) -> Resul
             beta.shape()
         )
     }
-    xs.apply_op3_no_bwd(alpha, beta, &LayerNorm { eps })
+    xs.apply_op3(alpha, beta, LayerNorm { eps })
+}
+/// The normalization computed by a [`NormBwd`] op.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum NormKind {
+    Rms,
+    Layer,
+}
+
+/// The backward pass of the fused norms, computed from the input `x`, the weight `alpha` and
+/// the gradient of the output. The result has the shape of `x` with twice as many columns: the
+/// gradient of `x` followed by `grad * x_normed`, whose sum over the rows is the gradient of
+/// `alpha`.
+#[derive(Debug, Clone, Copy)]
+struct NormBwd {
+    eps: f32,
+    kind: NormKind,
+}
+
+impl candle::CustomOp3 for NormBwd {
+    fn name(&self) -> &'static str {
+        match self.kind {
+            NormKind::Rms => "rms-norm-bwd",
+            NormKind::Layer => "layer-norm-bwd",
+        }
+    }
+
+    fn cpu_fwd(
+        &self,
+        s1: &CpuStorage,
+        l1: &Layout,
+        s2: &CpuStorage,
+        l2: &Layout,
+        s3: &CpuStorage,
+        l3: &Layout,
+    ) -> Result<(CpuStorage, Shape)> {
+        use candle::backend::BackendStorage;
+
+        fn inner<
+            T: candle::WithDType
+                + num_traits::Float
+                + num_traits::AsPrimitive<f32>
+                + num_traits::FromPrimitive,
+        >(
+            src: &[T],
+            layout: &Layout,
+            alpha: &[T],
+            alpha_layout: &Layout,
+            grad: &[T],
+            grad_layout: &Layout,
+            op: &NormBwd,
+        ) -> Result<(CpuStorage, Shape)> {
+            let NormBwd { eps, kind } = *op;
+            let src = match layout.contiguous_offsets() {
+                None => candle::bail!("input has to be contiguous"),
+                Some((o1, o2)) => &src[o1..o2],
+            };
+            let alpha = match alpha_layout.contiguous_offsets() {
+                None => candle::bail!("alpha has to be contiguous"),
+                Some((o1, o2)) => &alpha[o1..o2],
+            };
+            let grad = match grad_layout.contiguous_offsets() {
+                None => candle::bail!("grad has to be contiguous"),
+                Some((o1, o2)) => &grad[o1..o2],
+            };
+            let mut dims = layout.shape().dims().to_vec();
+            let dim_m1 = dims[dims.len() - 1];
+            let n = dim_m1 as f32;
+            let mut dst = vec![T::zero(); 2 * src.len()];
+            src.par_chunks(dim_m1)
+                .zip(grad.par_chunks(dim_m1))
+                .zip(dst.par_chunks_mut(2 * dim_m1))
+                .for_each(|((src, grad), dst)| {
+                    let mean = match kind {
+                        NormKind::Rms => 0f32,
+                        NormKind::Layer => src.iter().map(|v| v.as_()).sum::<f32>() / n,
+                    };
+                    // The sums of the centered squares, of grad * alpha, and of
+                    // grad * alpha * x_centered.
+                    let (mut sum2, mut sum_ga, mut sum_gax) = (0f32, 0f32, 0f32);
+                    for ((x, g), a) in src.iter().zip(grad).zip(alpha) {
+                        let x = x.as_() - mean;
+                        let ga = g.as_() * a.as_();
+                        sum2 += x * x;
+                        sum_ga += ga;
+                        sum_gax += ga * x;
+                    }
+                    let inv_std = (sum2 / n + eps).sqrt().recip();
+                    let mean_ga = match kind {
+                        NormKind::Rms => 0f32,
+                        NormKind::Layer => sum_ga / n,
+                    };
+                    let mean_gax = sum_gax * inv_std / n;
+                    let (dx, dalpha) = dst.split_at_mut(dim_m1);
+                    for (((x, g), a), (dx, dalpha)) in src
+                        .iter()
+                        .zip(grad)
+                        .zip(alpha)
+                        .zip(dx.iter_mut().zip(dalpha.iter_mut()))
+                    {
+                        let x_normed = (x.as_() - mean) * inv_std;
+                        let g = g.as_();
+                        let d = inv_std * (g * a.as_() - mean_ga - x_normed * mean_gax);
+                        *dx = T::from_f32(d).unwrap_or_else(T::nan);
+                        *dalpha = T::from_f32(g * x_normed).unwrap_or_else(T::nan);
+                    }
+                });
+            let last = dims.len() - 1;
+            dims[last] *= 2;
+            let storage = candle::WithDType::to_cpu_storage_owned(dst);
+            Ok((storage, Shape::from_dims(&dims)))
+        }
+
+        use CpuStorage as C;
+        match (s1, s2, s3) {
+            (C::BF16(s1), C::BF16(s2), C::BF16(s3)) => {
+                inner::<half::bf16>(s1, l1, s2, l2, s3, l3, self)
+            }
+            (C::F16(s1), C::F16(s2), C::F16(s3)) => {
+                inner::<half::f16>(s1, l1, s2, l2, s3, l3, self)
+            }
+            (C::F32(s1), C::F32(s2), C::F32(s3)) => inner::<f32>(s1, l1, s2, l2, s3, l3, self),
+            _ => candle::bail!("unsupported dtype for {} {:?}", self.name(), s1.dtype()),
+        }
+    }
+
+    #[cfg(feature = "cuda")]
+    fn cuda_fwd(
+        &self,
+        s1: &candle::CudaStorage,
+        l1: &Layout,
+        s2: &candle::CudaStorage,
+        l2: &Layout,
+        s3: &candle::CudaStorage,
+        l3: &Layout,
+    ) -> Result<(candle::CudaStorage, Shape)> {
+        use candle::cuda_backend::cudarc::driver::{
+            CudaSlice, DeviceRepr, LaunchAsync, LaunchConfig,
+        };
+        use candle::cuda_backend::{kernel_name, kernels, Map3, WrapErr};
+        use candle::{CudaDevice, WithDType};
+
+        struct S {
+            eps: f32,
+            kind: NormKind,
+        }
+        impl Map3 for S {
+            fn f<T: DeviceRepr + WithDType>(
+                &self,
+                src: &CudaSlice<T>,
+                layout: &Layout,
+                alpha: &CudaSlice<T>,
+                alpha_layout: &Layout,
+                grad: &CudaSlice<T>,
+                grad_layout: &Layout,
+                dev: &CudaDevice,
+            ) -> Result<CudaSlice<T>> {
+                let src = match layout.contiguous_offsets() {
+                    None => candle::bail!("input has to be contiguous"),
+                    Some((o1, o2)) => src.slice(o1..o2),
+                };
+                let alpha = match alpha_layout.contiguous_offsets() {
+                    None => candle::bail!("alpha has to be contiguous"),
+                    Some((o1, o2)) => alpha.slice(o1..o2),
+                };
+                let grad = match grad_layout.contiguous_offsets() {
+                    None => candle::bail!("grad has to be contiguous"),
+                    Some((o1, o2)) => grad.slice(o1..o2),
+                };
+                let el = layout.shape().elem_count();
+                let dims = layout.shape().dims();
+                let dim_m1 = dims[dims.len() - 1];
+                let (n_rows, n_cols) = (el / dim_m1, dim_m1);
+
+                let block_size = if n_cols < 1024 { 32 } else { 1024 };
+                let cfg = LaunchConfig {
+                    grid_dim: (n_rows as u32, 1, 1),
+                    block_dim: (block_size, 1, 1),
+                    shared_mem_bytes: 0,
+                };
+                let name = match self.kind {
+                    NormKind::Rms => "rmsnorm_bwd",
+                    NormKind::Layer => "layernorm_bwd",
+                };
+                let func = dev.get_or_load_func(&kernel_name::<T>(name), kernels::REDUCE)?;
+                // SAFETY: Set later by running the kernel.
+                let dst = unsafe { dev.alloc::<T>(2 * el) }.w()?;
+                let params = (
+                    &src,
+                    &alpha,
+                    &grad,
+                    &dst,
+                    n_cols as i32,
+                    block_size as i32,
+                    self.eps,
+                );
+                // SAFETY: ffi.
+                unsafe { func.launch(cfg, params) }.w()?;
+                Ok(dst)
+            }
+        }
+
+        use candle::backend::BackendStorage;
+        let dev = s1.device();
+        let s = S {
+            eps: self.eps,
+            kind: self.kind,
+        };
+        let slice = s.map(&s1.slice, l1, &s2.slice, l2, &s3.slice, l3, dev)?;
+        let dst = candle::cuda_backend::CudaStorage {
+            slice,
+            device: dev.clone(),
+        };
+        let mut dims = l1.dims().to_vec();
+        let last = dims.len() - 1;
+        dims[last] *= 2;
+        Ok((dst, Shape::from_dims(&dims)))
+    }
+}
+
+// The gradients of the fused norms for `x` and `alpha`, the devices without a fused backward
+// kernel use the same formulas with tensor ops.
+fn norm_bwd(
+    x: &Tensor,
+    alpha: &Tensor,
+    grad: &Tensor,
+    eps: f32,
+    kind: NormKind,
+) -> Result<(Tensor, Tensor)> {
+    let dim = x.dim(D::Minus1)?;
+    let grad = grad.to_dtype(x.dtype())?.contiguous()?;
+    let (dx, dalpha) = if x.device().is_cpu() || x.device().is_cuda() {
+        let op = NormBwd { eps, kind };
+        let packed = x.apply_op3_no_bwd(&alpha.contiguous()?, &grad, &op)?;
+        let dx = packed.narrow(D::Minus1, 0, dim)?.contiguous()?;
+        (dx, packed.narrow(D::Minus1, dim, dim)?)
+    } else {
+        let x_dtype = x.dtype();
+        let x = x.to_dtype(internal_dtype(x_dtype))?;
+        let grad = grad.to_dtype(x.dtype())?;
+        let x = match kind {
+            NormKind::Rms => x,
+            NormKind::Layer => x.broadcast_sub(&x.mean_keepdim(D::Minus1)?)?,
+        };
+        let inv_std = (x.sqr()?.mean_keepdim(D::Minus1)? + eps as f64)?
+            .sqrt()?
+            .recip()?;
+        let x_normed = x.broadcast_mul(&inv_std)?;
+        let ga = grad.broadcast_mul(&alpha.to_dtype(x.dtype())?)?;
+        let mean_gax = (&ga * &x_normed)?.mean_keepdim(D::Minus1)?;
+        let ga = match kind {
+            NormKind::Rms => ga,
+            NormKind::Layer => ga.broadcast_sub(&ga.mean_keepdim(D::Minus1)?)?,
+        };
+        let dx = ga
+            .sub(&x_normed.broadcast_mul(&mean_gax)?)?
+            .broadcast_mul(&inv_std)?;
+        (dx.to_dtype(x_dtype)?, (grad * x_normed)?)
+    };
+    let dalpha = dalpha
+        .reshape(((), dim))?
+        .to_dtype(DType::F32)?
+        .sum(0)?
+        .to_dtype(alpha.dtype())?;
+    Ok((dx, dalpha))
 }
 
 // https://pytorch.org/docs/stable/generated/torch.nn.PixelShuffle.html
@@ -1157,3 +1547,192 @@ impl candle::CustomOp3 for Sdpa {
 pub fn sdpa(q: &Tensor, k: &Tensor, v: &Tensor, scale: f32, softcapping: f32) -> Result<Tensor> {
     q.apply_op3_no_bwd(k, v, &Sdpa { scale, softcapping })
 }
+
+#[allow(dead_code)]
+struct FlashAttn {
+    softmax_scale: f32,
+    causal: bool,
+}
+
+impl candle::CustomOp3 for FlashAttn {
+    fn name(&self) -> &'static str {
+        "metal-flash-attn"
+    }
+
+    fn cpu_fwd(
+        &self,
+        _s1: &CpuStorage,
+        _l1: &Layout,
+        _s2: &CpuStorage,
+        _l2: &Layout,
+        _s3: &CpuStorage,
+        _l3: &Layout,
+    ) -> Result<(CpuStorage, Shape)> {
+        candle::bail!("flash-attn has no cpu impl, use flash_attn_slow")
+    }
+
+    #[cfg(feature = "metal")]
+    fn metal_fwd(
+        &self,
+        q: &candle::MetalStorage,
+        q_l: &Layout,
+        k: &candle::MetalStorage,
+        k_l: &Layout,
+        v: &candle::MetalStorage,
+        v_l: &Layout,
+    ) -> Result<(candle::MetalStorage, Shape)> {
+        use candle::backend::BackendStorage;
+        use candle_metal_kernels::{BufferOffset, SdpaDType};
+
+        for t in [k.dtype(), v.dtype()] {
+            if q.dtype() != t {
+                candle::bail!("all q, k, v dtypes must match.");
+            }
+        }
+        let itype = match q.dtype() {
+            DType::BF16 => SdpaDType::BF16,
+            DType::F16 => SdpaDType::F16,
+            DType::F32 => SdpaDType::F32,
+            other => candle::bail!("unsupported flash-attn type {other:?}"),
+        };
+        for l in [q_l, k_l, v_l] {
+            if l.stride()[3] != 1 {
+                candle::bail!(
+                    "flash-attn expects a contiguous last dim, got {:?}",
+                    l.stride()
+                )
+            }
+        }
+
+        let device = q.device();
+        let elem_count = q_l.shape().elem_count();
+        let output = device.new_buffer(elem_count, q.dtype(), "flash_attn_o")?;
+        let buffer_offset = |s: &'_ candle::MetalStorage, l: &Layout| BufferOffset {
+            buffer: s.buffer(),
+            offset_in_bytes: l.start_offset() * s.dtype().size_in_bytes(),
+        };
+        let command_buffer = device.command_buffer()?;
+        command_buffer.set_label("flash_attention");
+        candle_metal_kernels::call_flash_attn(
+            device.device(),
+            &command_buffer,
+            device.kernels(),
+            q_l.dims(),
+            q_l.stride(),
+            buffer_offset(q, q_l),
+            k_l.dims(),
+            k_l.stride(),
+            buffer_offset(k, k_l),
+            v_l.stride(),
+            buffer_offset(v, v_l),
+            &output,
+            self.softmax_scale,
+            self.causal,
+            itype,
+        )
+        .map_err(candle::Error::wrap)?;
+
+        let newstorage = candle::MetalStorage::new(output, device.clone(), elem_count, q.dtype());
+        Ok((newstorage, q_l.shape().clone()))
+    }
+}
+
+fn check_flash_attn_dims(q: &Tensor, k: &Tensor, v: &Tensor, causal: bool) -> Result<()> {
+    let (b_size, seq_len, n_heads, head_dim) = q.dims4()?;
+    let (k_b_size, kv_seq_len, n_kv_heads, k_head_dim) = k.dims4()?;
+    if v.dims4()? != (k_b_size, kv_seq_len, n_kv_heads, k_head_dim) {
+        candle::bail!(
+            "flash-attn: shape mismatch k {:?}, v {:?}",
+            k.shape(),
+            v.shape()
+        )
+    }
+    if b_size != k_b_size || head_dim != k_head_dim {
+        candle::bail!(
+            "flash-attn: shape mismatch q {:?}, k {:?}",
+            q.shape(),
+            k.shape()
+        )
+    }
+    if n_heads % n_kv_heads != 0 {
+        candle::bail!("flash-attn: n_heads {n_heads} is not a multiple of n_kv_heads {n_kv_heads}")
+    }
+    if causal && seq_len > kv_seq_len {
+        candle::bail!(
+            "flash-attn: causal attention with seq_len {seq_len} > kv_seq_len {kv_seq_len}"
+        )
+    }
+    Ok(())
+}
+
+/// Flash attention, with the same arguments and layout as `candle_flash_attn::flash_attn`.
+///
+/// **Inputs shapes:**
+/// - `q`: (bs, seq, n_heads, head_dim)
+/// - `k` and `v`: (bs, kv_seq, n_kv_heads, head_dim), `n_heads` has to be a multiple of
+///   `n_kv_heads` for grouped query attention.
+/// - `softmax_scale` is applied to `q k^T` before the softmax.
+/// - With `causal`, the mask is aligned to the bottom right of the attention matrix: query `i`
+///   attends to the keys `j <= i + kv_seq - seq`, so that the queries are the last positions
+///   when using a kv cache. `seq` cannot be larger than `kv_seq` in this case.
+///
+/// **Output shape:** (bs, seq, n_heads, head_dim)
+///
+/// On Metal, this uses a fused kernel that computes the softmax online by blocks of keys so
+/// that the memory does not grow with `seq * kv_seq`, the supported head dims are 32, 64, 80,
+/// 96, 128 and 256. On the other devices this falls back to the attention by blocks of
+/// [`crate::attention::scaled_dot_product_attention`].
+pub fn flash_attn(
+    q: &Tensor,
+    k: &Tensor,
+    v: &Tensor,
+    softmax_scale: f32,
+    causal: bool,
+) -> Result<Tensor> {
+    check_flash_attn_dims(q, k, v, causal)?;
+    if !q.device().is_metal() {
+        let chunk_size = crate::attention::AttentionConfig::default().chunk_size;
+        return flash_attn_fallback(q, k, v, softmax_scale, causal, chunk_size);
+    }
+    let op = FlashAttn {
+        softmax_scale,
+        causal,
+    };
+    q.apply_op3_no_bwd(k, v, &op)
+}
+
+/// The reference implementation of [`flash_attn`] that materializes the attention matrix.
+pub fn flash_attn_slow(
+    q: &Tensor,
+    k: &Tensor,
+    v: &Tensor,
+    softmax_scale: f32,
+    causal: bool,
+) -> Result<Tensor> {
+    check_flash_attn_dims(q, k, v, causal)?;
+    flash_attn_fallback(q, k, v, softmax_scale, causal, None)
+}
+
+fn flash_attn_fallback(
+    q: &Tensor,
+    k: &Tensor,
+    v: &Tensor,
+    softmax_scale: f32,
+    causal: bool,
+    chunk_size: Option<usize>,
+) -> Result<Tensor> {
+    let config = crate::attention::AttentionConfig {
+        scale: Some(softmax_scale as f64),
+        causal,
+        chunk_size,
+        ..Default::default()
+    };
+    crate::attention::scaled_dot_product_attention(
+        &q.transpose(1, 2)?,
+        &k.transpose(1, 2)?,
+        &v.transpose(1, 2)?,
+        None,
+        &config,
+    )?
+    .transpose(1, 2)
+}
@@ -0,0 +1,205 @@
+//! The Adafactor optimizer, see "Adafactor: Adaptive Learning Rates with Sublinear Memory Cost"
+//! <https://arxiv.org/abs/1804.04235>.
+//!
+//! For the variables with at least two dimensions, Adafactor only stores the running averages of
+//! the squared gradients over the rows and over the columns of the last two dimensions, and
+//! estimates the second moment from their outer product. The state of a `(n, m)` matrix takes
+//! `n + m` values rather than the `2 n m` of Adam. There is no first moment, and the step size is
+//! relative to the root mean square of the variable.
+//!
+//! The semantics are the ones of the PyTorch implementation: the learning rate caps the relative
+//! step size `1 / sqrt(t)` and the decay of the second moments is `1 - t^beta2_decay`.
+use super::{check_num_vars, restore_step, restore_var, Optimizer, OptimizerState};
+use candle::{DType, Result, Tensor, Var, D};
+use std::collections::HashMap;
+
+#[derive(Clone, Debug)]
+pub struct ParamsAdafactor {
+    pub lr: f64,
+    /// The exponent of the decay of the second moments, `-0.8` in the paper.
+    pub beta2_decay: f64,
+    /// The lower bound of the second moment estimates, the machine epsilon of the dtype of the
+    /// variable when not set.
+    pub eps1: Option<f64>,
+    /// The lower bound of the root mean square of the variables in the step size.
+    pub eps2: f64,
+    /// The clipping threshold of the root mean square of the updates.
+    pub d: f64,
+    /// The decoupled weight decay, as in AdamW.
+    pub weight_decay: f64,
+}
+
+impl Default for ParamsAdafactor {
+    fn default() -> Self {
+        Self {
+            lr: 0.01,
+            beta2_decay: -0.8,
+            eps1: None,
+            eps2: 1e-3,
+            d: 1.,
+            weight_decay: 0.,
+        }
+    }
+}
+
+#[derive(Debug)]
+enum SecondMoment {
+    // The averages over the last dimension, and over the dimension before it.
+    Factored { row: Var, col: Var },
+    Full(Var),
+}
+
+#[derive(Debug)]
+struct VarAdafactor {
+    var: Var,
+    second_moment: SecondMoment,
+}
+
+#[derive(Debug)]
+pub struct Adafactor {
+    vars: Vec<VarAdafactor>,
+    step_t: usize,
+    params: ParamsAdafactor,
+}
+
+fn epsilon(dtype: DType) -> f64 {
+    match dtype {
+        DType::BF16 => half::bf16::EPSILON.to_f64(),
+        DType::F16 => half::f16::EPSILON.to_f64(),
+        DType::F32 => f32::EPSILON as f64,
+        _ => f64::EPSILON,
+    }
+}
+
+fn rms(xs: &Tensor) -> Result<Tensor> {
+    xs.sqr()?.mean_all()?.sqrt()
+}
+
+impl Optimizer for Adafactor {
+    type Config = ParamsAdafactor;
+
+    fn new(vars: Vec<Var>, params: ParamsAdafactor) -> Result<Self> {
+        let vars = vars
+            .into_iter()
+            .filter(|var| var.dtype().is_float())
+            .map(|var| {
+                let (dtype, device) = (var.dtype(), var.device());
+                let dims = var.dims();
+                let second_moment = if dims.len() >= 2 {
+                    let mut row = dims.to_vec();
+                    let mut col = dims.to_vec();
+                    row[dims.len() - 1] = 1;
+                    col[dims.len() - 2] = 1;
+                    SecondMoment::Factored {
+                        row: Var::zeros(row, dtype, device)?,
+                        col: Var::zeros(col, dtype, device)?,
+                    }
+                } else {
+                    SecondMoment::Full(Var::zeros(dims, dtype, device)?)
+                };
+                Ok(VarAdafactor { var, second_moment })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Self {
+            vars,
+            step_t: 0,
+            params,
+        })
+    }
+
+    fn learning_rate(&self) -> f64 {
+        self.params.lr
+    }
+
+    fn set_learning_rate(&mut self, lr: f64) {
+        self.params.lr = lr
+    }
+
+    fn step(&mut self, grads: &candle::backprop::GradStore) -> Result<()> {
+        self.step_t += 1;
+        let p = &self.params;
+        let t = self.step_t as f64;
+        // The weight of the new squared gradients in the running averages.
+        let w = t.powf(p.beta2_decay);
+        let rho_t = p.lr.min(1. / t.sqrt());
+        let lerp = |avg: &Var, xs: Tensor| (avg.as_tensor() * (1. - w))? + (xs * w)?;
+        for var in self.vars.iter() {
+            let theta = var.var.as_tensor();
+            if let Some(g) = grads.get(theta) {
+                let eps1 = p.eps1.unwrap_or_else(|| epsilon(theta.dtype()));
+                let alpha = (rms(theta)?.maximum(p.eps2)? * rho_t)?;
+                let next_theta = (theta * (1. - p.lr * p.weight_decay))?;
+                let g2 = g.sqr()?;
+                let estimate = match &var.second_moment {
+                    SecondMoment::Factored { row, col } => {
+                        let next_row = lerp(row, g2.mean_keepdim(D::Minus1)?)?;
+                        let next_col = lerp(col, g2.mean_keepdim(D::Minus2)?)?;
+                        let row_mean = next_row.mean_keepdim(D::Minus2)?.maximum(eps1)?;
+                        let estimate = next_row
+                            .broadcast_mul(&next_col)?
+                            .broadcast_div(&row_mean)?;
+                        row.set(&next_row)?;
+                        col.set(&next_col)?;
+                        estimate
+                    }
+                    SecondMoment::Full(v) => {
+                        let next_v = lerp(v, g2)?;
+                        v.set(&next_v)?;
+                        next_v
+                    }
+                };
+                let update = (estimate.maximum(eps1 * eps1)?.sqrt()?.recip()? * g)?;
+                let denom = (rms(&update)? / p.d)?.maximum(1.)?;
+                let update = update.broadcast_mul(&(alpha / denom)?)?;
+                var.var.set(&(next_theta - update)?)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn state(&self) -> Result<OptimizerState> {
+        let vars = self
+            .vars
+            .iter()
+            .map(|v| {
+                let state = match &v.second_moment {
+                    SecondMoment::Factored { row, col } => HashMap::from([
+                        ("row_second_moment".to_string(), row.as_tensor().copy()?),
+                        ("col_second_moment".to_string(), col.as_tensor().copy()?),
+                    ]),
+                    SecondMoment::Full(v) => {
+                        HashMap::from([("second_moment".to_string(), v.as_tensor().copy()?)])
+                    }
+                };
+                Ok(state)
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let scalars = HashMap::from([("step".to_string(), self.step_t as f64)]);
+        Ok(OptimizerState { vars, scalars })
+    }
+
+    fn set_state(&mut self, state: &OptimizerState) -> Result<()> {
+        check_num_vars(state, self.vars.len())?;
+        for (var, s) in self.vars.iter().zip(state.vars.iter()) {
+            match &var.second_moment {
+                SecondMoment::Factored { row, col } => {
+                    restore_var(s, "row_second_moment", row)?;
+                    restore_var(s, "col_second_moment", col)?;
+                }
+                SecondMoment::Full(v) => restore_var(s, "second_moment", v)?,
+            }
+        }
+        self.step_t = restore_step(state)?;
+        Ok(())
+    }
+}
+
+impl Adafactor {
+    pub fn params(&self) -> &ParamsAdafactor {
+        &self.params
+    }
+
+    pub fn set_params(&mut self, params: ParamsAdafactor) {
+        self.params = params;
+    }
+}
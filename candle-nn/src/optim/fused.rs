@@ -0,0 +1,813 @@
+//! Fused variants of [`super::AdamW`].
+//!
+//! [`super::AdamW`] updates each variable with a chain of about ten tensor operations, each of
+//! them allocating a new tensor and reading the moments again. [`FusedAdamW`] updates the
+//! moments and the variable in place with two passes over the data, on cpu the passes run in
+//! parallel. On cuda a single multi-tensor kernel updates the moments and the variables of a
+//! given dtype in one pass, so a step costs one launch per dtype rather than a few per variable.
+//! The moments are kept in f32 whatever the dtype of the variable.
+//!
+//! [`AdamW8bit`] also stores the moments with 8 bits per value, using a scale per block of 256
+//! values, so the state takes about 2 bytes per parameter rather than 8 for the f32 moments.
+//! This is the approach of the bitsandbytes 8-bit optimizers, with a square mapping rather than
+//! their dynamic one: the first moment `m` is stored as `sign(r) r^2 max|m|` and the second
+//! moment `v` as the square root of `r^2 max sqrt(v)`, where `r` is the 8-bit value scaled to
+//! `[-1, 1]`, resp. `[0, 1]`.
+//!
+//! Both optimizers use the same configuration and the same [`OptimizerState`] layout as
+//! [`super::AdamW`], so a checkpoint saved with one of them can be restored with the others.
+//!
+//! Both optimizers can also keep their states in page-locked host memory between the steps, as
+//! the bitsandbytes paged optimizers do, see [`FusedAdamW::with_paged`]. The device then only
+//! holds the states of the variable being updated and of the next one.
+use super::{check_num_vars, restore_step, Optimizer, OptimizerState, ParamsAdamW};
+use candle::backend::BackendStorage;
+use candle::offload::{self, Offloaded};
+use candle::{CpuStorage, DType, Device, InplaceOp2, Layout, Result, Tensor, Var, WithDType};
+use rayon::prelude::*;
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+/// The number of values sharing a scale in the 8-bit states.
+const QBLOCK: usize = 256;
+
+// The size of the work items for the cpu passes.
+const CHUNK: usize = 4096;
+
+fn dequantize_signed(q: u8, scale: f32) -> f32 {
+    let r = (q as f32 - 128.) / 127.;
+    r * r.abs() * scale
+}
+
+fn quantize_signed(x: f32, scale: f32) -> u8 {
+    if scale <= 0. {
+        return 128;
+    }
+    let r = (x / scale).clamp(-1., 1.);
+    (128. + (r.abs().sqrt() * 127.).round_ties_even().copysign(r)) as u8
+}
+
+fn dequantize_unsigned(q: u8, scale: f32) -> f32 {
+    let r = q as f32 / 255.;
+    r * r * scale
+}
+
+fn quantize_unsigned(x: f32, scale: f32) -> u8 {
+    if scale <= 0. {
+        return 0;
+    }
+    let r = (x / scale).clamp(0., 1.);
+    (r.sqrt() * 255.).round_ties_even() as u8
+}
+
+fn packed_len(n: usize) -> usize {
+    2 * n + 8 * n.div_ceil(QBLOCK)
+}
+
+// The 8-bit state of `n` values is stored as the f32 scales of the blocks of m, then of v,
+// followed by the n bytes of m and the n bytes of v.
+struct Packed<'a> {
+    m_scales: &'a mut [u8],
+    v_scales: &'a mut [u8],
+    qm: &'a mut [u8],
+    qv: &'a mut [u8],
+}
+
+impl<'a> Packed<'a> {
+    fn new(data: &'a mut [u8], n: usize) -> Result<Self> {
+        if data.len() != packed_len(n) {
+            candle::bail!("unexpected 8-bit state size {} for {n} values", data.len())
+        }
+        let (scales, q) = data.split_at_mut(8 * n.div_ceil(QBLOCK));
+        let (m_scales, v_scales) = scales.split_at_mut(scales.len() / 2);
+        let (qm, qv) = q.split_at_mut(n);
+        Ok(Self {
+            m_scales,
+            v_scales,
+            qm,
+            qv,
+        })
+    }
+}
+
+fn scale(bytes: &[u8]) -> f32 {
+    f32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+}
+
+fn quantize_block(
+    m: &[f32],
+    u: &[f32],
+    m_scale: &mut [u8],
+    v_scale: &mut [u8],
+    qm: &mut [u8],
+    qv: &mut [u8],
+) {
+    let m_max = m.iter().fold(0f32, |acc, x| acc.max(x.abs()));
+    let u_max = u.iter().fold(0f32, |acc, &x| acc.max(x));
+    m_scale.copy_from_slice(&m_max.to_le_bytes());
+    v_scale.copy_from_slice(&u_max.to_le_bytes());
+    for (q, &x) in qm.iter_mut().zip(m.iter()) {
+        *q = quantize_signed(x, m_max)
+    }
+    for (q, &x) in qv.iter_mut().zip(u.iter()) {
+        *q = quantize_unsigned(x, u_max)
+    }
+}
+
+fn contiguous<'a, T>(data: &'a [T], layout: &Layout, name: &str) -> Result<&'a [T]> {
+    match layout.contiguous_offsets() {
+        Some((o1, o2)) => Ok(&data[o1..o2]),
+        None => candle::bail!("{name} has to be contiguous"),
+    }
+}
+
+fn contiguous_mut<'a, T>(data: &'a mut [T], layout: &Layout, name: &str) -> Result<&'a mut [T]> {
+    match layout.contiguous_offsets() {
+        Some((o1, o2)) => Ok(&mut data[o1..o2]),
+        None => candle::bail!("{name} has to be contiguous"),
+    }
+}
+
+// Updates the moments stored in the first operand with the gradient in the second one.
+struct AdamMoments {
+    beta1: f32,
+    beta2: f32,
+    quantized: bool,
+}
+
+impl AdamMoments {
+    fn cpu<T: WithDType>(&self, state: &mut CpuStorage, l1: &Layout, grad: &[T]) -> Result<()> {
+        let (beta1, beta2) = (self.beta1, self.beta2);
+        let n = grad.len();
+        match state {
+            CpuStorage::F32(state) if !self.quantized => {
+                let state = contiguous_mut(state, l1, "state")?;
+                if state.len() != 2 * n {
+                    candle::bail!("unexpected state size {} for {n} values", state.len())
+                }
+                let (m, v) = state.split_at_mut(n);
+                m.par_chunks_mut(CHUNK)
+                    .zip(v.par_chunks_mut(CHUNK))
+                    .zip(grad.par_chunks(CHUNK))
+                    .for_each(|((m, v), g)| {
+                        for ((m, v), g) in m.iter_mut().zip(v.iter_mut()).zip(g.iter()) {
+                            let g = g.to_f64() as f32;
+                            *m = beta1 * *m + (1. - beta1) * g;
+                            *v = beta2 * *v + (1. - beta2) * g * g;
+                        }
+                    })
+            }
+            CpuStorage::U8(state) if self.quantized => {
+                let state = Packed::new(contiguous_mut(state, l1, "state")?, n)?;
+                state
+                    .m_scales
+                    .par_chunks_mut(4)
+                    .zip(state.v_scales.par_chunks_mut(4))
+                    .zip(state.qm.par_chunks_mut(QBLOCK))
+                    .zip(state.qv.par_chunks_mut(QBLOCK))
+                    .zip(grad.par_chunks(QBLOCK))
+                    .for_each(|((((m_scale, v_scale), qm), qv), g)| {
+                        let (m_old, v_old) = (scale(m_scale), scale(v_scale));
+                        let mut m = [0f32; QBLOCK];
+                        let mut u = [0f32; QBLOCK];
+                        for i in 0..g.len() {
+                            let g = g[i].to_f64() as f32;
+                            m[i] = beta1 * dequantize_signed(qm[i], m_old) + (1. - beta1) * g;
+                            let u_old = dequantize_unsigned(qv[i], v_old);
+                            u[i] = (beta2 * u_old * u_old + (1. - beta2) * g * g).sqrt();
+                        }
+                        let len = g.len();
+                        quantize_block(&m[..len], &u[..len], m_scale, v_scale, qm, qv)
+                    })
+            }
+            _ => candle::bail!(
+                "unexpected dtype {:?} for the optimizer state",
+                state.dtype()
+            ),
+        }
+        Ok(())
+    }
+}
+
+impl InplaceOp2 for AdamMoments {
+    fn name(&self) -> &'static str {
+        "adam-moments"
+    }
+
+    fn cpu_fwd(
+        &self,
+        s1: &mut CpuStorage,
+        l1: &Layout,
+        s2: &CpuStorage,
+        l2: &Layout,
+    ) -> Result<()> {
+        match s2 {
+            CpuStorage::BF16(g) => self.cpu(s1, l1, contiguous(g, l2, "grad")?),
+            CpuStorage::F16(g) => self.cpu(s1, l1, contiguous(g, l2, "grad")?),
+            CpuStorage::F32(g) => self.cpu(s1, l1, contiguous(g, l2, "grad")?),
+            _ => candle::bail!("unsupported dtype {:?} for {}", s2.dtype(), self.name()),
+        }
+    }
+}
+
+// Applies the update computed from the moments in the second operand to the variable in the
+// first one.
+struct AdamApply {
+    lr: f32,
+    decay: f32,
+    scale_m: f32,
+    scale_v: f32,
+    eps: f32,
+    quantized: bool,
+}
+
+impl AdamApply {
+    fn update(&self, theta: f32, m: f32, v: f32) -> f32 {
+        let m_hat = m * self.scale_m;
+        let v_hat = v * self.scale_v;
+        theta * self.decay - self.lr * m_hat / (v_hat.sqrt() + self.eps)
+    }
+
+    fn cpu<T: WithDType>(&self, theta: &mut [T], state: &CpuStorage, l2: &Layout) -> Result<()> {
+        let n = theta.len();
+        match state {
+            CpuStorage::F32(state) if !self.quantized => {
+                let state = contiguous(state, l2, "state")?;
+                if state.len() != 2 * n {
+                    candle::bail!("unexpected state size {} for {n} values", state.len())
+                }
+                let (m, v) = state.split_at(n);
+                theta
+                    .par_chunks_mut(CHUNK)
+                    .zip(m.par_chunks(CHUNK))
+                    .zip(v.par_chunks(CHUNK))
+                    .for_each(|((theta, m), v)| {
+                        for ((t, &m), &v) in theta.iter_mut().zip(m.iter()).zip(v.iter()) {
+                            *t = T::from_f64(self.update(t.to_f64() as f32, m, v) as f64)
+                        }
+                    })
+            }
+            CpuStorage::U8(state) if self.quantized => {
+                let state = contiguous(state, l2, "state")?;
+                if state.len() != packed_len(n) {
+                    candle::bail!("unexpected 8-bit state size {} for {n} values", state.len())
+                }
+                let (scales, q) = state.split_at(8 * n.div_ceil(QBLOCK));
+                let (m_scales, v_scales) = scales.split_at(scales.len() / 2);
+                let (qm, qv) = q.split_at(n);
+                theta
+                    .par_chunks_mut(QBLOCK)
+                    .zip(m_scales.par_chunks(4))
+                    .zip(v_scales.par_chunks(4))
+                    .zip(qm.par_chunks(QBLOCK))
+                    .zip(qv.par_chunks(QBLOCK))
+                    .for_each(|((((theta, m_scale), v_scale), qm), qv)| {
+                        let (m_scale, v_scale) = (scale(m_scale), scale(v_scale));
+                        for ((t, &qm), &qv) in theta.iter_mut().zip(qm.iter()).zip(qv.iter()) {
+                            let m = dequantize_signed(qm, m_scale);
+                            let u = dequantize_unsigned(qv, v_scale);
+                            *t = T::from_f64(self.update(t.to_f64() as f32, m, u * u) as f64)
+                        }
+                    })
+            }
+            _ => candle::bail!(
+                "unexpected dtype {:?} for the optimizer state",
+                state.dtype()
+            ),
+        }
+        Ok(())
+    }
+}
+
+impl InplaceOp2 for AdamApply {
+    fn name(&self) -> &'static str {
+        "adam-apply"
+    }
+
+    fn cpu_fwd(
+        &self,
+        s1: &mut CpuStorage,
+        l1: &Layout,
+        s2: &CpuStorage,
+        l2: &Layout,
+    ) -> Result<()> {
+        match s1 {
+            CpuStorage::BF16(t) => self.cpu(contiguous_mut(t, l1, "var")?, s2, l2),
+            CpuStorage::F16(t) => self.cpu(contiguous_mut(t, l1, "var")?, s2, l2),
+            CpuStorage::F32(t) => self.cpu(contiguous_mut(t, l1, "var")?, s2, l2),
+            _ => candle::bail!("unsupported dtype {:?} for {}", s1.dtype(), self.name()),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct VarState {
+    var: Var,
+    // The moments, f32 values for FusedAdamW and the packed 8-bit values for AdamW8bit.
+    state: Var,
+}
+
+impl VarState {
+    fn elem_count(&self) -> usize {
+        self.var.elem_count()
+    }
+}
+
+fn check_var(var: &Var) -> Result<()> {
+    if !matches!(var.dtype(), DType::BF16 | DType::F16 | DType::F32) {
+        candle::bail!(
+            "fused optimizers only support f32, f16 and bf16, got {:?}",
+            var.dtype()
+        )
+    }
+    if !var.is_contiguous() {
+        candle::bail!("fused optimizers only support contiguous variables")
+    }
+    Ok(())
+}
+
+// The shared implementation of the two optimizers, the state layout depends on `quantized`.
+#[derive(Debug)]
+struct FusedAdam {
+    vars: Vec<VarState>,
+    step_t: usize,
+    params: ParamsAdamW,
+    quantized: bool,
+    paged: bool,
+    // The states moved to host memory, one slot per variable.
+    offloaded: Mutex<Vec<Option<Offloaded>>>,
+}
+
+impl FusedAdam {
+    fn new(vars: Vec<Var>, params: ParamsAdamW, quantized: bool) -> Result<Self> {
+        let vars = vars
+            .into_iter()
+            .filter(|var| var.dtype().is_float())
+            .map(|var| {
+                check_var(&var)?;
+                let n = var.elem_count();
+                let state = if quantized {
+                    if !var.device().is_cpu() && !var.device().is_cuda() {
+                        candle::bail!("8-bit optimizers are only supported on cpu and cuda")
+                    }
+                    // The values of the blocks with a zero scale are all zeros.
+                    Var::zeros(packed_len(n), DType::U8, var.device())?
+                } else {
+                    Var::zeros(2 * n, DType::F32, var.device())?
+                };
+                Ok(VarState { var, state })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let offloaded = Mutex::new(vars.iter().map(|_| None).collect());
+        Ok(Self {
+            vars,
+            step_t: 0,
+            params,
+            quantized,
+            paged: false,
+            offloaded,
+        })
+    }
+
+    // Copies the state of the variable `i` back to the device, the copy is asynchronous.
+    fn page_in(&self, i: usize) -> Result<()> {
+        let offloaded = self.offloaded.lock().unwrap()[i].take();
+        match offloaded {
+            Some(offloaded) => offloaded.reload(),
+            None => Ok(()),
+        }
+    }
+
+    // Moves the state of the variable `i` to host memory, this only applies to cuda states.
+    fn page_out(&self, i: usize) -> Result<()> {
+        if let Some(offloaded) = offload::offload(self.vars[i].state.as_tensor())? {
+            self.offloaded.lock().unwrap()[i] = Some(offloaded)
+        }
+        Ok(())
+    }
+
+    fn page_in_all(&self) -> Result<()> {
+        (0..self.vars.len()).try_for_each(|i| self.page_in(i))
+    }
+
+    fn page_out_all(&self) -> Result<()> {
+        if self.paged {
+            (0..self.vars.len()).try_for_each(|i| self.page_out(i))?
+        }
+        Ok(())
+    }
+
+    fn set_paged(&mut self, paged: bool) -> Result<()> {
+        self.paged = paged;
+        if paged {
+            self.page_out_all()
+        } else {
+            self.page_in_all()
+        }
+    }
+
+    fn step(&mut self, grads: &candle::backprop::GradStore) -> Result<()> {
+        self.step_t += 1;
+        let p = &self.params;
+        let moments = AdamMoments {
+            beta1: p.beta1 as f32,
+            beta2: p.beta2 as f32,
+            quantized: self.quantized,
+        };
+        let apply = AdamApply {
+            lr: p.lr as f32,
+            decay: (1. - p.lr * p.weight_decay) as f32,
+            scale_m: (1. / (1. - p.beta1.powi(self.step_t as i32))) as f32,
+            scale_v: (1. / (1. - p.beta2.powi(self.step_t as i32))) as f32,
+            eps: p.eps as f32,
+            quantized: self.quantized,
+        };
+        #[cfg(feature = "cuda")]
+        let mut pending = Vec::new();
+        for (i, var) in self.vars.iter().enumerate() {
+            if self.paged {
+                if i == 0 {
+                    self.page_in(0)?
+                }
+                // Prefetches the next state so that its copy overlaps with this update.
+                if i + 1 < self.vars.len() {
+                    self.page_in(i + 1)?
+                }
+            }
+            let theta = var.var.as_tensor();
+            let grad = match grads.get(theta) {
+                Some(grad) => grad,
+                None => {
+                    if self.paged {
+                        self.page_out(i)?
+                    }
+                    continue;
+                }
+            };
+            if grad.dtype() != theta.dtype() {
+                candle::bail!(
+                    "gradient dtype {:?} for a {:?} variable",
+                    grad.dtype(),
+                    theta.dtype()
+                )
+            }
+            match theta.device() {
+                Device::Cpu => {
+                    let grad = grad.flatten_all()?.contiguous()?;
+                    var.state.inplace_op2(&grad, &moments)?;
+                    theta.inplace_op2(var.state.as_tensor(), &apply)?;
+                }
+                // The variables are updated after the loop with a single launch per dtype, or
+                // right away when paged as the device only holds the states of two variables.
+                #[cfg(feature = "cuda")]
+                Device::Cuda(_) => {
+                    pending.push((var, grad.flatten_all()?.contiguous()?));
+                    if self.paged {
+                        cuda::adam_multi(&pending, &moments, &apply)?;
+                        pending.clear()
+                    }
+                }
+                // The quantized states are only created on cpu and cuda.
+                _ => self.step_unfused(var, grad, &apply)?,
+            }
+            if self.paged {
+                self.page_out(i)?
+            }
+        }
+        #[cfg(feature = "cuda")]
+        cuda::adam_multi(&pending, &moments, &apply)?;
+        Ok(())
+    }
+
+    // The same computation as the fused passes with tensor operations.
+    fn step_unfused(&self, var: &VarState, grad: &Tensor, apply: &AdamApply) -> Result<()> {
+        let p = &self.params;
+        let n = var.elem_count();
+        let grad = grad.flatten_all()?.to_dtype(DType::F32)?;
+        let m = var.state.narrow(0, 0, n)?;
+        let v = var.state.narrow(0, n, n)?;
+        let next_m = ((m * p.beta1)? + (&grad * (1. - p.beta1))?)?;
+        let next_v = ((v * p.beta2)? + (grad.sqr()? * (1. - p.beta2))?)?;
+        let m_hat = (&next_m * apply.scale_m as f64)?;
+        let v_hat = (&next_v * apply.scale_v as f64)?;
+        let theta = var.var.as_tensor();
+        let next_theta = (theta.flatten_all()?.to_dtype(DType::F32)? * apply.decay as f64)?;
+        let adjusted_grad = (m_hat / (v_hat.sqrt()? + p.eps)?)?;
+        let next_theta = (next_theta - (adjusted_grad * p.lr)?)?;
+        var.state.set(&Tensor::cat(&[&next_m, &next_v], 0)?)?;
+        var.var
+            .set(&next_theta.to_dtype(theta.dtype())?.reshape(theta.shape())?)?;
+        Ok(())
+    }
+
+    // The f32 moments of a variable.
+    fn moments(&self, var: &VarState) -> Result<(Tensor, Tensor)> {
+        let n = var.elem_count();
+        let (shape, device) = (var.var.shape(), var.var.device());
+        if !self.quantized {
+            let m = var.state.narrow(0, 0, n)?.reshape(shape)?.copy()?;
+            let v = var.state.narrow(0, n, n)?.reshape(shape)?.copy()?;
+            return Ok((m, v));
+        }
+        let mut data = var.state.to_vec1::<u8>()?;
+        let state = Packed::new(&mut data, n)?;
+        let mut m = Vec::with_capacity(n);
+        let mut v = Vec::with_capacity(n);
+        for (i, (&qm, &qv)) in state.qm.iter().zip(state.qv.iter()).enumerate() {
+            let b = 4 * (i / QBLOCK);
+            m.push(dequantize_signed(qm, scale(&state.m_scales[b..])));
+            let u = dequantize_unsigned(qv, scale(&state.v_scales[b..]));
+            v.push(u * u)
+        }
+        let m = Tensor::from_vec(m, shape, device)?;
+        let v = Tensor::from_vec(v, shape, device)?;
+        Ok((m, v))
+    }
+
+    fn set_moments(&self, var: &VarState, m: &Tensor, v: &Tensor) -> Result<()> {
+        let n = var.elem_count();
+        if m.elem_count() != n || v.elem_count() != n {
+            candle::bail!(
+                "unexpected moments shape for a variable of shape {:?}",
+                var.var.shape()
+            )
+        }
+        let m = m.flatten_all()?.to_dtype(DType::F32)?;
+        let v = v.flatten_all()?.to_dtype(DType::F32)?;
+        if !self.quantized {
+            return var
+                .state
+                .set(&Tensor::cat(&[&m, &v], 0)?.to_device(var.state.device())?);
+        }
+        let m = m.to_vec1::<f32>()?;
+        let u = v.sqrt()?.to_vec1::<f32>()?;
+        let mut data = vec![0u8; packed_len(n)];
+        let state = Packed::new(&mut data, n)?;
+        state
+            .m_scales
+            .chunks_mut(4)
+            .zip(state.v_scales.chunks_mut(4))
+            .zip(state.qm.chunks_mut(QBLOCK))
+            .zip(state.qv.chunks_mut(QBLOCK))
+            .zip(m.chunks(QBLOCK).zip(u.chunks(QBLOCK)))
+            .for_each(|((((m_scale, v_scale), qm), qv), (m, u))| {
+                quantize_block(m, u, m_scale, v_scale, qm, qv)
+            });
+        let len = data.len();
+        var.state
+            .set(&Tensor::from_vec(data, len, var.state.device())?)
+    }
+
+    fn state(&self) -> Result<OptimizerState> {
+        self.page_in_all()?;
+        let vars = self
+            .vars
+            .iter()
+            .map(|var| {
+                let (m, v) = self.moments(var)?;
+                Ok(HashMap::from([
+                    ("first_moment".to_string(), m),
+                    ("second_moment".to_string(), v),
+                ]))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        self.page_out_all()?;
+        let scalars = HashMap::from([("step".to_string(), self.step_t as f64)]);
+        Ok(OptimizerState { vars, scalars })
+    }
+
+    fn set_state(&mut self, state: &OptimizerState) -> Result<()> {
+        check_num_vars(state, self.vars.len())?;
+        self.page_in_all()?;
+        for (var, s) in self.vars.iter().zip(state.vars.iter()) {
+            match (s.get("first_moment"), s.get("second_moment")) {
+                (Some(m), Some(v)) => self.set_moments(var, m, v)?,
+                _ => candle::bail!("missing moments in the optimizer state"),
+            }
+        }
+        self.page_out_all()?;
+        self.step_t = restore_step(state)?;
+        Ok(())
+    }
+
+    fn state_size(&self) -> usize {
+        let bytes = if self.quantized { 1 } else { 4 };
+        self.vars.iter().map(|v| v.state.elem_count() * bytes).sum()
+    }
+}
+
+#[cfg(feature = "cuda")]
+mod cuda {
+    use super::{packed_len, AdamApply, AdamMoments, VarState};
+    use candle::cuda_backend::cudarc::driver::{DevicePtr, LaunchAsync, LaunchConfig};
+    use candle::cuda_backend::{kernels, CudaStorageSlice, WrapErr};
+    use candle::{Device, Layout, Result, Storage, Tensor};
+
+    // The threads per block of the kernels, QBLOCK in optim.cu.
+    const BLOCK_SIZE: usize = 256;
+    const MAX_BLOCKS_PER_TENSOR: usize = 512;
+    // The maximum number of tensors per launch, the y dimension of the grid.
+    const MAX_TENSORS: usize = 65535;
+
+    fn device_ptr(storage: &Storage, layout: &Layout, name: &str) -> Result<(u64, usize)> {
+        let (start, end) = match layout.contiguous_offsets() {
+            Some(offsets) => offsets,
+            None => candle::bail!("the {name} has to be contiguous"),
+        };
+        let ptr = match storage {
+            Storage::Cuda(s) => match &s.slice {
+                CudaStorageSlice::U8(s) => *s.slice(start..end).device_ptr(),
+                CudaStorageSlice::BF16(s) => *s.slice(start..end).device_ptr(),
+                CudaStorageSlice::F16(s) => *s.slice(start..end).device_ptr(),
+                CudaStorageSlice::F32(s) => *s.slice(start..end).device_ptr(),
+                _ => candle::bail!("unexpected dtype for the {name}"),
+            },
+            _ => candle::bail!("the {name} has to be on a cuda device"),
+        };
+        Ok((ptr, end - start))
+    }
+
+    /// Updates the moments and the cuda variables with their gradients, the variables sharing a
+    /// device and a dtype are updated by a single kernel launch.
+    pub(super) fn adam_multi(
+        vars: &[(&VarState, Tensor)],
+        moments: &AdamMoments,
+        apply: &AdamApply,
+    ) -> Result<()> {
+        let mut groups: Vec<Vec<&(&VarState, Tensor)>> = vec![];
+        for v in vars.iter() {
+            let theta = v.0.var.as_tensor();
+            let group = groups.iter_mut().find(|group| {
+                let other = group[0].0.var.as_tensor();
+                other.device().same_device(theta.device()) && other.dtype() == theta.dtype()
+            });
+            match group {
+                Some(group) => group.push(v),
+                None => groups.push(vec![v]),
+            }
+        }
+        for group in groups.iter() {
+            let theta = group[0].0.var.as_tensor();
+            let dtype = theta.dtype();
+            let dev = match theta.device() {
+                Device::Cuda(dev) => dev,
+                device => candle::bail!("unexpected device {device:?} for the multi-tensor update"),
+            };
+            let name = if moments.quantized {
+                format!("adam_multi_8bit_{}", dtype.as_str())
+            } else {
+                format!("adam_multi_{}", dtype.as_str())
+            };
+            let func = dev.get_or_load_func(&name, kernels::OPTIM)?;
+            for vars in group.chunks(MAX_TENSORS) {
+                // The storages are kept locked until the end of the launch.
+                let storages = vars
+                    .iter()
+                    .map(|(var, grad)| {
+                        (
+                            var.var.storage_and_layout(),
+                            grad.storage_and_layout(),
+                            var.state.storage_and_layout(),
+                        )
+                    })
+                    .collect::<Vec<_>>();
+                let mut thetas = Vec::with_capacity(vars.len());
+                let mut grads = Vec::with_capacity(vars.len());
+                let mut states = Vec::with_capacity(vars.len());
+                let mut lens = Vec::with_capacity(vars.len());
+                for ((theta, theta_l), (grad, grad_l), (state, state_l)) in storages.iter() {
+                    let (theta, n) = device_ptr(theta, theta_l, "variable")?;
+                    let (grad, grad_n) = device_ptr(grad, grad_l, "gradient")?;
+                    let (state, state_n) = device_ptr(state, state_l, "optimizer state")?;
+                    let expected = if moments.quantized {
+                        packed_len(n)
+                    } else {
+                        2 * n
+                    };
+                    if grad_n != n || state_n != expected {
+                        candle::bail!("unexpected gradient or optimizer state for {n} values")
+                    }
+                    thetas.push(theta);
+                    grads.push(grad);
+                    states.push(state);
+                    lens.push(n as u64);
+                }
+                let max_len = lens.iter().copied().max().unwrap_or(0) as usize;
+                let blocks = max_len.div_ceil(BLOCK_SIZE).clamp(1, MAX_BLOCKS_PER_TENSOR);
+                let cfg = LaunchConfig {
+                    grid_dim: (blocks as u32, vars.len() as u32, 1),
+                    block_dim: (BLOCK_SIZE as u32, 1, 1),
+                    shared_mem_bytes: 0,
+                };
+                let thetas = dev.htod_sync_copy(&thetas).w()?;
+                let grads = dev.htod_sync_copy(&grads).w()?;
+                let states = dev.htod_sync_copy(&states).w()?;
+                let lens = dev.htod_sync_copy(&lens).w()?;
+                let params = (
+                    &thetas,
+                    &grads,
+                    &states,
+                    &lens,
+                    moments.beta1,
+                    moments.beta2,
+                    apply.lr,
+                    apply.decay,
+                    apply.scale_m,
+                    apply.scale_v,
+                    apply.eps,
+                );
+                // SAFETY: ffi, the pointers are valid while the storages are locked.
+                unsafe { func.clone().launch(cfg, params) }.w()?;
+            }
+        }
+        Ok(())
+    }
+}
+
+macro_rules! fused_optimizer {
+    ($name:ident, $quantized:expr) => {
+        impl Optimizer for $name {
+            type Config = ParamsAdamW;
+
+            fn new(vars: Vec<Var>, params: ParamsAdamW) -> Result<Self> {
+                Ok(Self(FusedAdam::new(vars, params, $quantized)?))
+            }
+
+            fn learning_rate(&self) -> f64 {
+                self.0.params.lr
+            }
+
+            fn set_learning_rate(&mut self, lr: f64) {
+                self.0.params.lr = lr
+            }
+
+            fn step(&mut self, grads: &candle::backprop::GradStore) -> Result<()> {
+                self.0.step(grads)
+            }
+
+            fn state(&self) -> Result<OptimizerState> {
+                self.0.state()
+            }
+
+            fn set_state(&mut self, state: &OptimizerState) -> Result<()> {
+                self.0.set_state(state)
+            }
+        }
+
+        impl $name {
+            pub fn new_lr(vars: Vec<Var>, learning_rate: f64) -> Result<Self> {
+                let params = ParamsAdamW {
+                    lr: learning_rate,
+                    ..ParamsAdamW::default()
+                };
+                Self::new(vars, params)
+            }
+
+            pub fn params(&self) -> &ParamsAdamW {
+                &self.0.params
+            }
+
+            pub fn set_params(&mut self, params: ParamsAdamW) {
+                self.0.params = params;
+            }
+
+            /// The size of the moments of all the variables, in bytes.
+            pub fn state_size(&self) -> usize {
+                self.0.state_size()
+            }
+
+            /// Keeps the moments in page-locked host memory between the steps, they are copied
+            /// to the device one variable at a time during [`Optimizer::step`], the copy of the
+            /// next variable overlapping with the update of the current one. This only has an
+            /// effect on cuda.
+            pub fn with_paged(mut self, paged: bool) -> Result<Self> {
+                self.0.set_paged(paged)?;
+                Ok(self)
+            }
+
+            pub fn set_paged(&mut self, paged: bool) -> Result<()> {
+                self.0.set_paged(paged)
+            }
+
+            pub fn is_paged(&self) -> bool {
+                self.0.paged
+            }
+        }
+    };
+}
+
+/// AdamW with the moments and the variables updated in place, see the module documentation.
+///
+/// The variables have to be contiguous and use f32, f16 or bf16. The update is fused on cpu and
+/// cuda, other devices use tensor operations.
+#[derive(Debug)]
+pub struct FusedAdamW(FusedAdam);
+
+fused_optimizer!(FusedAdamW, false);
+
+/// AdamW with the moments stored with 8 bits per value, see the module documentation. This is
+/// only supported on cpu and cuda.
+#[derive(Debug)]
+pub struct AdamW8bit(FusedAdam);
+
+fused_optimizer!(AdamW8bit, true);
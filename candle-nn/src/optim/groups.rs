@@ -0,0 +1,296 @@
+//! Parameter groups, each group of variables is updated with its own hyperparameters.
+//!
+//! The variables of a [`VarMap`] are assigned to the groups using patterns on their names, see
+//! [`crate::var_map::matches_pattern`]. A common use is to disable the weight decay of the biases
+//! and normalization weights, or to use a larger learning rate for the head of a model than for
+//! its backbone.
+//!
+//! ```ignore
+//! let params = ParamsAdamW { lr: 1e-4, weight_decay: 0.05, ..Default::default() };
+//! let no_decay = ParamGroup::new("no_decay", ParamsAdamW { weight_decay: 0., ..params })
+//!     .with_patterns(&["*.bias", "*norm*"]);
+//! let head = ParamGroup::new("head", ParamsAdamW { lr: 1e-3, ..params }).with_pattern("head.*");
+//! let mut opt = ParamGroups::<AdamW>::from_varmap(&varmap, params, vec![no_decay, head])?;
+//! ```
+use super::{Optimizer, OptimizerState};
+use crate::var_map::{matches_pattern, VarMap};
+use candle::{Result, Var};
+
+/// The name of the group holding the variables that do not match any pattern.
+pub const DEFAULT_GROUP: &str = "default";
+
+// The scalar holding the number of variable states of each group.
+const NUM_VARS: &str = "num_vars";
+
+/// The description of a parameter group, the variables whose names match any of the patterns are
+/// updated with `config`.
+#[derive(Debug, Clone)]
+pub struct ParamGroup<C> {
+    pub name: String,
+    pub patterns: Vec<String>,
+    pub config: C,
+}
+
+impl<C> ParamGroup<C> {
+    pub fn new<S: Into<String>>(name: S, config: C) -> Self {
+        Self {
+            name: name.into(),
+            patterns: vec![],
+            config,
+        }
+    }
+
+    pub fn with_pattern<S: Into<String>>(mut self, pattern: S) -> Self {
+        self.patterns.push(pattern.into());
+        self
+    }
+
+    pub fn with_patterns<S: AsRef<str>>(mut self, patterns: &[S]) -> Self {
+        self.patterns
+            .extend(patterns.iter().map(|p| p.as_ref().to_string()));
+        self
+    }
+
+    /// Whether the variable `name` belongs to this group.
+    pub fn matches(&self, name: &str) -> bool {
+        self.patterns.iter().any(|p| matches_pattern(p, name))
+    }
+}
+
+#[derive(Debug)]
+struct Group<O> {
+    name: String,
+    vars: Vec<String>,
+    optimizer: O,
+    // The learning rate the group was created with, used to keep the ratios between the
+    // learning rates of the groups when a schedule is applied.
+    base_lr: f64,
+}
+
+/// An optimizer made of one inner optimizer per parameter group.
+///
+/// The default group comes first and holds the variables that do not match any of the groups, a
+/// variable matching several groups belongs to the first one. Groups without any variable are
+/// kept so that their hyperparameters can still be changed.
+///
+/// [`Optimizer::set_learning_rate`] sets the learning rate of the default group, the learning
+/// rates of the other groups are scaled to keep their ratio with the default one so that the
+/// learning rate schedulers apply to all the groups. Use [`Self::set_group_learning_rate`] to
+/// change the learning rate of a single group.
+#[derive(Debug)]
+pub struct ParamGroups<O> {
+    groups: Vec<Group<O>>,
+}
+
+impl<O: Optimizer> ParamGroups<O> {
+    /// Creates the groups for the trainable variables of `varmap`, buffers are not optimized. The
+    /// variables that do not match any group are updated with `default`.
+    pub fn from_varmap(
+        varmap: &VarMap,
+        default: O::Config,
+        groups: Vec<ParamGroup<O::Config>>,
+    ) -> Result<Self> {
+        Self::from_named_vars(varmap.named_vars(), default, groups)
+    }
+
+    /// Same as [`Self::from_varmap`] with an explicit list of named variables.
+    pub fn from_named_vars(
+        vars: Vec<(String, Var)>,
+        default: O::Config,
+        groups: Vec<ParamGroup<O::Config>>,
+    ) -> Result<Self> {
+        for (i, group) in groups.iter().enumerate() {
+            if group.name == DEFAULT_GROUP || groups[..i].iter().any(|g| g.name == group.name) {
+                candle::bail!("duplicate parameter group {}", group.name)
+            }
+            // The group names are used as prefixes of the scalars of the state.
+            if group.name.contains('.') {
+                candle::bail!("invalid parameter group name {}", group.name)
+            }
+        }
+        let mut assigned = vec![vec![]; groups.len() + 1];
+        for (name, var) in vars {
+            let index = match groups.iter().position(|g| g.matches(&name)) {
+                Some(index) => index + 1,
+                None => 0,
+            };
+            assigned[index].push((name, var))
+        }
+        let configs = std::iter::once((DEFAULT_GROUP.to_string(), default))
+            .chain(groups.into_iter().map(|g| (g.name, g.config)));
+        let groups = configs
+            .zip(assigned)
+            .map(|((name, config), vars)| {
+                let (var_names, vars): (Vec<_>, Vec<_>) = vars.into_iter().unzip();
+                let optimizer = O::new(vars, config)?;
+                Ok(Group {
+                    name,
+                    vars: var_names,
+                    base_lr: optimizer.learning_rate(),
+                    optimizer,
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Self { groups })
+    }
+
+    /// The names of the groups, starting with [`DEFAULT_GROUP`].
+    pub fn group_names(&self) -> Vec<&str> {
+        self.groups.iter().map(|g| g.name.as_str()).collect()
+    }
+
+    fn get(&self, name: &str) -> Result<&Group<O>> {
+        match self.groups.iter().find(|g| g.name == name) {
+            Some(group) => Ok(group),
+            None => candle::bail!("unknown parameter group {name}"),
+        }
+    }
+
+    fn get_mut(&mut self, name: &str) -> Result<&mut Group<O>> {
+        match self.groups.iter_mut().find(|g| g.name == name) {
+            Some(group) => Ok(group),
+            None => candle::bail!("unknown parameter group {name}"),
+        }
+    }
+
+    /// The optimizer of a group, e.g. to change its hyperparameters with `set_params`.
+    pub fn group(&self, name: &str) -> Result<&O> {
+        Ok(&self.get(name)?.optimizer)
+    }
+
+    pub fn group_mut(&mut self, name: &str) -> Result<&mut O> {
+        Ok(&mut self.get_mut(name)?.optimizer)
+    }
+
+    /// The names of the variables of a group.
+    pub fn group_vars(&self, name: &str) -> Result<&[String]> {
+        Ok(&self.get(name)?.vars)
+    }
+
+    /// The name of the group a variable belongs to.
+    pub fn group_of(&self, var_name: &str) -> Option<&str> {
+        self.groups
+            .iter()
+            .find(|g| g.vars.iter().any(|v| v == var_name))
+            .map(|g| g.name.as_str())
+    }
+
+    pub fn group_learning_rate(&self, name: &str) -> Result<f64> {
+        Ok(self.get(name)?.optimizer.learning_rate())
+    }
+
+    /// Sets the learning rate of a single group, the learning rates of the other groups are left
+    /// as is. The new ratios between the learning rates of the groups are kept by the later calls
+    /// to [`Optimizer::set_learning_rate`].
+    pub fn set_group_learning_rate(&mut self, name: &str, lr: f64) -> Result<()> {
+        self.get_mut(name)?.optimizer.set_learning_rate(lr);
+        let default_lr = self.groups[0].optimizer.learning_rate();
+        let default_base_lr = self.groups[0].base_lr;
+        if default_lr != 0. {
+            for group in self.groups[1..].iter_mut() {
+                group.base_lr = group.optimizer.learning_rate() * default_base_lr / default_lr;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<O: Optimizer> Optimizer for ParamGroups<O> {
+    type Config = O::Config;
+
+    /// A single default group holding all the variables, names are needed to create other
+    /// groups, see [`Self::from_varmap`].
+    fn new(vars: Vec<Var>, config: O::Config) -> Result<Self> {
+        let optimizer = O::new(vars, config)?;
+        let group = Group {
+            name: DEFAULT_GROUP.to_string(),
+            vars: vec![],
+            base_lr: optimizer.learning_rate(),
+            optimizer,
+        };
+        Ok(Self {
+            groups: vec![group],
+        })
+    }
+
+    fn step(&mut self, grads: &candle::backprop::GradStore) -> Result<()> {
+        for group in self.groups.iter_mut() {
+            group.optimizer.step(grads)?
+        }
+        Ok(())
+    }
+
+    fn learning_rate(&self) -> f64 {
+        self.groups[0].optimizer.learning_rate()
+    }
+
+    fn set_learning_rate(&mut self, lr: f64) {
+        let default_base_lr = self.groups[0].base_lr;
+        for group in self.groups.iter_mut() {
+            let lr = if default_base_lr == 0. {
+                lr
+            } else {
+                lr * group.base_lr / default_base_lr
+            };
+            group.optimizer.set_learning_rate(lr)
+        }
+    }
+
+    /// The states of the groups, the variable states are concatenated in the group order and the
+    /// scalars are prefixed with the group name, e.g. `head.step`.
+    fn state(&self) -> Result<OptimizerState> {
+        let mut state = OptimizerState::default();
+        for group in self.groups.iter() {
+            let s = group.optimizer.state()?;
+            let num_vars = s.vars.len() as f64;
+            state
+                .scalars
+                .insert(format!("{}.{NUM_VARS}", group.name), num_vars);
+            state.vars.extend(s.vars);
+            for (name, value) in s.scalars {
+                state
+                    .scalars
+                    .insert(format!("{}.{name}", group.name), value);
+            }
+        }
+        Ok(state)
+    }
+
+    fn set_state(&mut self, state: &OptimizerState) -> Result<()> {
+        let mut vars = state.vars.as_slice();
+        for group in self.groups.iter_mut() {
+            let prefix = format!("{}.", group.name);
+            let scalars = state
+                .scalars
+                .iter()
+                .filter_map(|(k, v)| Some((k.strip_prefix(&prefix)?, *v)))
+                .filter(|(k, _)| *k != NUM_VARS)
+                .map(|(k, v)| (k.to_string(), v))
+                .collect();
+            let n = match state.scalars.get(&format!("{prefix}{NUM_VARS}")) {
+                Some(n) => *n as usize,
+                None => candle::bail!("missing parameter group {} in the state", group.name),
+            };
+            if vars.len() < n {
+                candle::bail!(
+                    "missing variables for the group {} in the state",
+                    group.name
+                )
+            }
+            let (group_vars, rest) = vars.split_at(n);
+            vars = rest;
+            let s = OptimizerState {
+                vars: group_vars.to_vec(),
+                scalars,
+            };
+            group.optimizer.set_state(&s)?
+        }
+        if !vars.is_empty() {
+            candle::bail!(
+                "the state has {} more variables than the optimizer",
+                vars.len()
+            )
+        }
+        Ok(())
+    }
+}
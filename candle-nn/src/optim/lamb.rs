@@ -0,0 +1,153 @@
+//! The LAMB optimizer, see "Large Batch Optimization for Deep Learning: Training BERT in 76
+//! minutes" <https://arxiv.org/abs/1904.00962>.
+//!
+//! LAMB computes the AdamW update and scales it for each variable by the trust ratio, the norm of
+//! the variable over the norm of the update, so that the step size is relative to the size of the
+//! weights of each layer. This keeps the training stable with very large batches.
+use super::{check_num_vars, restore_step, restore_var, Optimizer, OptimizerState};
+use candle::{Result, Tensor, Var};
+use std::collections::HashMap;
+
+#[derive(Clone, Debug)]
+pub struct ParamsLamb {
+    pub lr: f64,
+    pub beta1: f64,
+    pub beta2: f64,
+    pub eps: f64,
+    /// The weight decay, added to the update before computing the trust ratio.
+    pub weight_decay: f64,
+}
+
+impl Default for ParamsLamb {
+    fn default() -> Self {
+        Self {
+            lr: 0.001,
+            beta1: 0.9,
+            beta2: 0.999,
+            eps: 1e-6,
+            weight_decay: 0.01,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct VarLamb {
+    var: Var,
+    first_moment: Var,
+    second_moment: Var,
+}
+
+#[derive(Debug)]
+pub struct Lamb {
+    vars: Vec<VarLamb>,
+    step_t: usize,
+    params: ParamsLamb,
+}
+
+impl Optimizer for Lamb {
+    type Config = ParamsLamb;
+
+    fn new(vars: Vec<Var>, params: ParamsLamb) -> Result<Self> {
+        let vars = vars
+            .into_iter()
+            .filter(|var| var.dtype().is_float())
+            .map(|var| {
+                let (shape, dtype, device) = (var.shape(), var.dtype(), var.device());
+                let first_moment = Var::zeros(shape, dtype, device)?;
+                let second_moment = Var::zeros(shape, dtype, device)?;
+                Ok(VarLamb {
+                    var,
+                    first_moment,
+                    second_moment,
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Self {
+            vars,
+            step_t: 0,
+            params,
+        })
+    }
+
+    fn learning_rate(&self) -> f64 {
+        self.params.lr
+    }
+
+    fn set_learning_rate(&mut self, lr: f64) {
+        self.params.lr = lr
+    }
+
+    fn step(&mut self, grads: &candle::backprop::GradStore) -> Result<()> {
+        self.step_t += 1;
+        let p = &self.params;
+        let scale_m = 1. / (1. - p.beta1.powi(self.step_t as i32));
+        let scale_v = 1. / (1. - p.beta2.powi(self.step_t as i32));
+        for var in self.vars.iter() {
+            let theta = var.var.as_tensor();
+            let m = &var.first_moment;
+            let v = &var.second_moment;
+            if let Some(g) = grads.get(theta) {
+                let next_m = ((m.as_tensor() * p.beta1)? + (g * (1. - p.beta1))?)?;
+                let next_v = ((v.as_tensor() * p.beta2)? + (g.sqr()? * (1. - p.beta2))?)?;
+                let m_hat = (&next_m * scale_m)?;
+                let v_hat = (&next_v * scale_v)?;
+                let update = ((m_hat / (v_hat.sqrt()? + p.eps)?)? + (theta * p.weight_decay)?)?;
+                // The trust ratio is 1 when one of the norms is zero, e.g. for the variables
+                // initialized to zero. This stays on the device to avoid a synchronization.
+                let theta_norm = theta.sqr()?.sum_all()?.sqrt()?;
+                let update_norm = update.sqr()?.sum_all()?.sqrt()?;
+                let valid = (theta_norm.gt(0.)? * update_norm.gt(0.)?)?;
+                let ratio = valid.where_cond(
+                    &(&theta_norm / &update_norm)?,
+                    &Tensor::ones((), theta.dtype(), theta.device())?,
+                )?;
+                let next_theta = (theta - update.broadcast_mul(&(ratio * p.lr)?)?)?;
+                m.set(&next_m)?;
+                v.set(&next_v)?;
+                var.var.set(&next_theta)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn state(&self) -> Result<OptimizerState> {
+        let vars = self
+            .vars
+            .iter()
+            .map(|v| {
+                Ok(HashMap::from([
+                    (
+                        "first_moment".to_string(),
+                        v.first_moment.as_tensor().copy()?,
+                    ),
+                    (
+                        "second_moment".to_string(),
+                        v.second_moment.as_tensor().copy()?,
+                    ),
+                ]))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let scalars = HashMap::from([("step".to_string(), self.step_t as f64)]);
+        Ok(OptimizerState { vars, scalars })
+    }
+
+    fn set_state(&mut self, state: &OptimizerState) -> Result<()> {
+        check_num_vars(state, self.vars.len())?;
+        for (var, s) in self.vars.iter().zip(state.vars.iter()) {
+            restore_var(s, "first_moment", &var.first_moment)?;
+            restore_var(s, "second_moment", &var.second_moment)?;
+        }
+        self.step_t = restore_step(state)?;
+        Ok(())
+    }
+}
+
+impl Lamb {
+    pub fn params(&self) -> &ParamsLamb {
+        &self.params
+    }
+
+    pub fn set_params(&mut self, params: ParamsLamb) {
+        self.params = params;
+    }
+}
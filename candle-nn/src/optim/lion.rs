@@ -0,0 +1,122 @@
+//! The Lion optimizer, see "Symbolic Discovery of Optimization Algorithms"
+//! <https://arxiv.org/abs/2302.06675>.
+//!
+//! Lion only tracks the momentum and uses the sign of the interpolation between the momentum and
+//! the gradient as the update, so all the coordinates move by the learning rate. This usually
+//! requires a learning rate 3 to 10 times smaller than AdamW, and a larger weight decay.
+use super::{check_num_vars, restore_step, restore_var, Optimizer, OptimizerState};
+use candle::{Result, Var};
+use std::collections::HashMap;
+
+#[derive(Clone, Debug)]
+pub struct ParamsLion {
+    pub lr: f64,
+    /// The interpolation factor between the momentum and the gradient for the update.
+    pub beta1: f64,
+    /// The decay of the momentum.
+    pub beta2: f64,
+    /// The decoupled weight decay, as in AdamW.
+    pub weight_decay: f64,
+}
+
+impl Default for ParamsLion {
+    fn default() -> Self {
+        Self {
+            lr: 1e-4,
+            beta1: 0.9,
+            beta2: 0.99,
+            weight_decay: 0.,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct VarLion {
+    var: Var,
+    momentum: Var,
+}
+
+#[derive(Debug)]
+pub struct Lion {
+    vars: Vec<VarLion>,
+    step_t: usize,
+    params: ParamsLion,
+}
+
+impl Optimizer for Lion {
+    type Config = ParamsLion;
+
+    fn new(vars: Vec<Var>, params: ParamsLion) -> Result<Self> {
+        let vars = vars
+            .into_iter()
+            .filter(|var| var.dtype().is_float())
+            .map(|var| {
+                let momentum = Var::zeros(var.shape(), var.dtype(), var.device())?;
+                Ok(VarLion { var, momentum })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Self {
+            vars,
+            step_t: 0,
+            params,
+        })
+    }
+
+    fn learning_rate(&self) -> f64 {
+        self.params.lr
+    }
+
+    fn set_learning_rate(&mut self, lr: f64) {
+        self.params.lr = lr
+    }
+
+    fn step(&mut self, grads: &candle::backprop::GradStore) -> Result<()> {
+        self.step_t += 1;
+        let p = &self.params;
+        for var in self.vars.iter() {
+            let theta = &var.var;
+            let m = var.momentum.as_tensor();
+            if let Some(g) = grads.get(theta) {
+                let c = ((m * p.beta1)? + (g * (1. - p.beta1))?)?;
+                let next_theta = (theta.as_tensor() * (1. - p.lr * p.weight_decay))?;
+                let next_theta = (next_theta - (c.sign()? * p.lr)?)?;
+                let next_m = ((m * p.beta2)? + (g * (1. - p.beta2))?)?;
+                var.momentum.set(&next_m)?;
+                theta.set(&next_theta)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn state(&self) -> Result<OptimizerState> {
+        let vars = self
+            .vars
+            .iter()
+            .map(|v| {
+                let momentum = v.momentum.as_tensor().copy()?;
+                Ok(HashMap::from([("momentum".to_string(), momentum)]))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let scalars = HashMap::from([("step".to_string(), self.step_t as f64)]);
+        Ok(OptimizerState { vars, scalars })
+    }
+
+    fn set_state(&mut self, state: &OptimizerState) -> Result<()> {
+        check_num_vars(state, self.vars.len())?;
+        for (var, s) in self.vars.iter().zip(state.vars.iter()) {
+            restore_var(s, "momentum", &var.momentum)?
+        }
+        self.step_t = restore_step(state)?;
+        Ok(())
+    }
+}
+
+impl Lion {
+    pub fn params(&self) -> &ParamsLion {
+        &self.params
+    }
+
+    pub fn set_params(&mut self, params: ParamsLion) {
+        self.params = params;
+    }
+}
@@ -0,0 +1,423 @@
+//! Various optimization algorithms.
+use candle::{Result, Tensor, Var};
+use std::collections::HashMap;
+
+mod adafactor;
+mod fused;
+mod groups;
+mod lamb;
+mod lion;
+mod radam;
+pub use adafactor::{Adafactor, ParamsAdafactor};
+pub use fused::{AdamW8bit, FusedAdamW};
+pub use groups::{ParamGroup, ParamGroups, DEFAULT_GROUP};
+pub use lamb::{Lamb, ParamsLamb};
+pub use lion::{Lion, ParamsLion};
+pub use radam::{ParamsRAdam, RAdam};
+
+/// The internal state of an optimizer, e.g. to save it in a checkpoint.
+#[derive(Debug, Clone, Default)]
+pub struct OptimizerState {
+    /// The states of each variable, in the order of the variables of the optimizer, e.g. the
+    /// moments with the shape of the variable.
+    pub vars: Vec<HashMap<String, Tensor>>,
+    /// The states that are not specific to a variable, e.g. the number of steps.
+    pub scalars: HashMap<String, f64>,
+}
+
+/// The interface optimizers should implement.
+pub trait Optimizer: Sized {
+    type Config: Sized;
+
+    fn new(vars: Vec<Var>, config: Self::Config) -> Result<Self>;
+
+    fn step(&mut self, grads: &candle::backprop::GradStore) -> Result<()>;
+
+    fn learning_rate(&self) -> f64;
+
+    fn set_learning_rate(&mut self, lr: f64);
+
+    fn empty(config: Self::Config) -> Result<Self> {
+        Self::new(vec![], config)
+    }
+
+    fn backward_step(&mut self, loss: &Tensor) -> Result<()> {
+        let grads = loss.backward()?;
+        self.step(&grads)
+    }
+
+    fn from_slice(vars: &[&Var], config: Self::Config) -> Result<Self> {
+        let vars: Vec<_> = vars.iter().map(|&v| v.clone()).collect();
+        Self::new(vars, config)
+    }
+
+    /// The state of the optimizer, stateless optimizers return an empty state.
+    fn state(&self) -> Result<OptimizerState> {
+        Ok(OptimizerState::default())
+    }
+
+    /// Restores a state returned by [`Self::state`].
+    fn set_state(&mut self, state: &OptimizerState) -> Result<()> {
+        if state.vars.iter().any(|s| !s.is_empty()) || !state.scalars.is_empty() {
+            candle::bail!("this optimizer does not have any state")
+        }
+        Ok(())
+    }
+}
+
+// Checks that a state has an entry for each of the `num_vars` variables of an optimizer.
+fn check_num_vars(state: &OptimizerState, num_vars: usize) -> Result<()> {
+    if state.vars.len() != num_vars {
+        candle::bail!(
+            "the state has {} variables but the optimizer has {num_vars}",
+            state.vars.len(),
+        )
+    }
+    Ok(())
+}
+
+// Restores the `name` buffer of a variable.
+fn restore_var(state: &HashMap<String, Tensor>, name: &str, dst: &Var) -> Result<()> {
+    match state.get(name) {
+        Some(t) => dst.set(&t.to_dtype(dst.dtype())?.to_device(dst.device())?),
+        None => candle::bail!("missing {name} in the optimizer state"),
+    }
+}
+
+fn restore_step(state: &OptimizerState) -> Result<usize> {
+    match state.scalars.get("step") {
+        Some(step) => Ok(*step as usize),
+        None => candle::bail!("missing step in the optimizer state"),
+    }
+}
+
+/// Optimizer for Stochastic Gradient Descent.
+///
+/// Contrary to the PyTorch implementation of SGD, this version does not support momentum, see
+/// [`SGDMomentum`] for this.
+#[derive(Debug)]
+pub struct SGD {
+    vars: Vec<Var>,
+    learning_rate: f64,
+}
+
+impl Optimizer for SGD {
+    type Config = f64;
+
+    fn new(vars: Vec<Var>, learning_rate: f64) -> Result<Self> {
+        let vars = vars
+            .into_iter()
+            .filter(|var| var.dtype().is_float())
+            .collect();
+        Ok(Self {
+            vars,
+            learning_rate,
+        })
+    }
+
+    fn learning_rate(&self) -> f64 {
+        self.learning_rate
+    }
+
+    fn step(&mut self, grads: &candle::backprop::GradStore) -> Result<()> {
+        for var in self.vars.iter() {
+            if let Some(grad) = grads.get(var) {
+                var.set(&var.sub(&(grad * self.learning_rate)?)?)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn set_learning_rate(&mut self, lr: f64) {
+        self.learning_rate = lr
+    }
+}
+
+impl SGD {
+    pub fn into_inner(self) -> Vec<Var> {
+        self.vars
+    }
+
+    pub fn push(&mut self, var: &Var) {
+        self.vars.push(var.clone())
+    }
+}
+
+/// The parameters for [`SGDMomentum`], with the same semantics as the PyTorch implementation of
+/// SGD. The weight decay is added to the gradient.
+#[derive(Clone, Debug)]
+pub struct ParamsSGDMomentum {
+    pub lr: f64,
+    pub momentum: f64,
+    pub dampening: f64,
+    pub weight_decay: f64,
+    /// Uses Nesterov momentum, the update looks ahead along the momentum.
+    pub nesterov: bool,
+}
+
+impl Default for ParamsSGDMomentum {
+    fn default() -> Self {
+        Self {
+            lr: 0.01,
+            momentum: 0.9,
+            dampening: 0.,
+            weight_decay: 0.,
+            nesterov: false,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct VarSGDMomentum {
+    var: Var,
+    momentum: Var,
+}
+
+/// Stochastic Gradient Descent with momentum, optionally Nesterov momentum.
+#[derive(Debug)]
+pub struct SGDMomentum {
+    vars: Vec<VarSGDMomentum>,
+    step_t: usize,
+    params: ParamsSGDMomentum,
+}
+
+impl Optimizer for SGDMomentum {
+    type Config = ParamsSGDMomentum;
+
+    fn new(vars: Vec<Var>, params: ParamsSGDMomentum) -> Result<Self> {
+        let vars = vars
+            .into_iter()
+            .filter(|var| var.dtype().is_float())
+            .map(|var| {
+                let momentum = Var::zeros(var.shape(), var.dtype(), var.device())?;
+                Ok(VarSGDMomentum { var, momentum })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Self {
+            vars,
+            step_t: 0,
+            params,
+        })
+    }
+
+    fn learning_rate(&self) -> f64 {
+        self.params.lr
+    }
+
+    fn set_learning_rate(&mut self, lr: f64) {
+        self.params.lr = lr
+    }
+
+    fn step(&mut self, grads: &candle::backprop::GradStore) -> Result<()> {
+        self.step_t += 1;
+        let p = &self.params;
+        for var in self.vars.iter() {
+            let theta = &var.var;
+            if let Some(g) = grads.get(theta) {
+                let g = if p.weight_decay != 0. {
+                    (g + (theta.as_tensor() * p.weight_decay)?)?
+                } else {
+                    g.clone()
+                };
+                // The momentum buffer starts from the first gradient.
+                let next_b = if self.step_t == 1 {
+                    g.clone()
+                } else {
+                    ((var.momentum.as_tensor() * p.momentum)? + (&g * (1. - p.dampening))?)?
+                };
+                let update = if p.nesterov {
+                    (g + (&next_b * p.momentum)?)?
+                } else {
+                    next_b.clone()
+                };
+                var.momentum.set(&next_b)?;
+                theta.set(&theta.sub(&(update * p.lr)?)?)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn state(&self) -> Result<OptimizerState> {
+        let vars = self
+            .vars
+            .iter()
+            .map(|v| {
+                let momentum = v.momentum.as_tensor().copy()?;
+                Ok(HashMap::from([("momentum".to_string(), momentum)]))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let scalars = HashMap::from([("step".to_string(), self.step_t as f64)]);
+        Ok(OptimizerState { vars, scalars })
+    }
+
+    fn set_state(&mut self, state: &OptimizerState) -> Result<()> {
+        check_num_vars(state, self.vars.len())?;
+        for (var, s) in self.vars.iter().zip(state.vars.iter()) {
+            restore_var(s, "momentum", &var.momentum)?
+        }
+        self.step_t = restore_step(state)?;
+        Ok(())
+    }
+}
+
+impl SGDMomentum {
+    pub fn params(&self) -> &ParamsSGDMomentum {
+        &self.params
+    }
+
+    pub fn set_params(&mut self, params: ParamsSGDMomentum) {
+        self.params = params;
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct ParamsAdamW {
+    pub lr: f64,
+    pub beta1: f64,
+    pub beta2: f64,
+    pub eps: f64,
+    pub weight_decay: f64,
+}
+
+impl Default for ParamsAdamW {
+    fn default() -> Self {
+        Self {
+            lr: 0.001,
+            beta1: 0.9,
+            beta2: 0.999,
+            eps: 1e-8,
+            weight_decay: 0.01,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct VarAdamW {
+    var: Var,
+    first_moment: Var,
+    second_moment: Var,
+}
+
+#[derive(Debug)]
+pub struct AdamW {
+    vars: Vec<VarAdamW>,
+    step_t: usize,
+    params: ParamsAdamW,
+}
+
+impl Optimizer for AdamW {
+    type Config = ParamsAdamW;
+
+    fn new(vars: Vec<Var>, params: ParamsAdamW) -> Result<Self> {
+        let vars = vars
+            .into_iter()
+            .filter(|var| var.dtype().is_float())
+            .map(|var| {
+                let dtype = var.dtype();
+                let shape = var.shape();
+                let device = var.device();
+                let first_moment = Var::zeros(shape, dtype, device)?;
+                let second_moment = Var::zeros(shape, dtype, device)?;
+                Ok(VarAdamW {
+                    var,
+                    first_moment,
+                    second_moment,
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Self {
+            vars,
+            params,
+            step_t: 0,
+        })
+    }
+
+    fn learning_rate(&self) -> f64 {
+        self.params.lr
+    }
+
+    fn set_learning_rate(&mut self, lr: f64) {
+        self.params.lr = lr
+    }
+
+    fn step(&mut self, grads: &candle::backprop::GradStore) -> Result<()> {
+        self.step_t += 1;
+        let lr = self.params.lr;
+        let lambda = self.params.weight_decay;
+        let lr_lambda = lr * lambda;
+        let beta1 = self.params.beta1;
+        let beta2 = self.params.beta2;
+        let scale_m = 1f64 / (1f64 - beta1.powi(self.step_t as i32));
+        let scale_v = 1f64 / (1f64 - beta2.powi(self.step_t as i32));
+        for var in self.vars.iter() {
+            let theta = &var.var;
+            let m = &var.first_moment;
+            let v = &var.second_moment;
+            if let Some(g) = grads.get(theta) {
+                // This involves locking 3 RWLocks per params, if the parameters are large this
+                // should not be an issue but this may be problematic with models with lots of
+                // small parameters.
+                let next_m = ((m.as_tensor() * beta1)? + (g * (1.0 - beta1))?)?;
+                let next_v = ((v.as_tensor() * beta2)? + (g.sqr()? * (1.0 - beta2))?)?;
+                let m_hat = (&next_m * scale_m)?;
+                let v_hat = (&next_v * scale_v)?;
+                let next_theta = (theta.as_tensor() * (1f64 - lr_lambda))?;
+                let adjusted_grad = (m_hat / (v_hat.sqrt()? + self.params.eps)?)?;
+                let next_theta = (next_theta - (adjusted_grad * lr)?)?;
+                m.set(&next_m)?;
+                v.set(&next_v)?;
+                theta.set(&next_theta)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn state(&self) -> Result<OptimizerState> {
+        let vars = self
+            .vars
+            .iter()
+            .map(|v| {
+                Ok(HashMap::from([
+                    (
+                        "first_moment".to_string(),
+                        v.first_moment.as_tensor().copy()?,
+                    ),
+                    (
+                        "second_moment".to_string(),
+                        v.second_moment.as_tensor().copy()?,
+                    ),
+                ]))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let scalars = HashMap::from([("step".to_string(), self.step_t as f64)]);
+        Ok(OptimizerState { vars, scalars })
+    }
+
+    fn set_state(&mut self, state: &OptimizerState) -> Result<()> {
+        check_num_vars(state, self.vars.len())?;
+        for (var, s) in self.vars.iter().zip(state.vars.iter()) {
+            restore_var(s, "first_moment", &var.first_moment)?;
+            restore_var(s, "second_moment", &var.second_moment)?;
+        }
+        self.step_t = restore_step(state)?;
+        Ok(())
+    }
+}
+
+impl AdamW {
+    pub fn new_lr(vars: Vec<Var>, learning_rate: f64) -> Result<Self> {
+        let params = ParamsAdamW {
+            lr: learning_rate,
+            ..ParamsAdamW::default()
+        };
+        Self::new(vars, params)
+    }
+
+    pub fn params(&self) -> &ParamsAdamW {
+        &self.params
+    }
+
+    pub fn set_params(&mut self, params: ParamsAdamW) {
+        self.params = params;
+    }
+}
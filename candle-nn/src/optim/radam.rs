@@ -0,0 +1,167 @@
+//! The RAdam optimizer, see "On the Variance of the Adaptive Learning Rate and Beyond"
+//! <https://arxiv.org/abs/1908.03265>.
+//!
+//! RAdam rectifies the adaptive learning rate of Adam, whose variance is large during the first
+//! steps: the first steps use the momentum alone, and the adaptive term is scaled up as more
+//! gradients are seen. This removes the need for a warmup. The semantics are the ones of the
+//! PyTorch implementation.
+use super::{check_num_vars, restore_step, restore_var, Optimizer, OptimizerState};
+use candle::{Result, Var};
+use std::collections::HashMap;
+
+#[derive(Clone, Debug)]
+pub struct ParamsRAdam {
+    pub lr: f64,
+    pub beta1: f64,
+    pub beta2: f64,
+    pub eps: f64,
+    pub weight_decay: f64,
+    /// Applies the weight decay to the variables as in AdamW rather than adding it to the
+    /// gradients.
+    pub decoupled_weight_decay: bool,
+}
+
+impl Default for ParamsRAdam {
+    fn default() -> Self {
+        Self {
+            lr: 0.001,
+            beta1: 0.9,
+            beta2: 0.999,
+            eps: 1e-8,
+            weight_decay: 0.,
+            decoupled_weight_decay: false,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct VarRAdam {
+    var: Var,
+    first_moment: Var,
+    second_moment: Var,
+}
+
+#[derive(Debug)]
+pub struct RAdam {
+    vars: Vec<VarRAdam>,
+    step_t: usize,
+    params: ParamsRAdam,
+}
+
+impl Optimizer for RAdam {
+    type Config = ParamsRAdam;
+
+    fn new(vars: Vec<Var>, params: ParamsRAdam) -> Result<Self> {
+        let vars = vars
+            .into_iter()
+            .filter(|var| var.dtype().is_float())
+            .map(|var| {
+                let (shape, dtype, device) = (var.shape(), var.dtype(), var.device());
+                let first_moment = Var::zeros(shape, dtype, device)?;
+                let second_moment = Var::zeros(shape, dtype, device)?;
+                Ok(VarRAdam {
+                    var,
+                    first_moment,
+                    second_moment,
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Self {
+            vars,
+            step_t: 0,
+            params,
+        })
+    }
+
+    fn learning_rate(&self) -> f64 {
+        self.params.lr
+    }
+
+    fn set_learning_rate(&mut self, lr: f64) {
+        self.params.lr = lr
+    }
+
+    fn step(&mut self, grads: &candle::backprop::GradStore) -> Result<()> {
+        self.step_t += 1;
+        let p = &self.params;
+        let t = self.step_t as i32;
+        let bias_correction1 = 1. - p.beta1.powi(t);
+        let bias_correction2 = 1. - p.beta2.powi(t);
+        // The length of the approximated simple moving average.
+        let rho_inf = 2. / (1. - p.beta2) - 1.;
+        let rho_t = rho_inf - 2. * t as f64 * p.beta2.powi(t) / bias_correction2;
+        for var in self.vars.iter() {
+            let theta = &var.var;
+            let m = &var.first_moment;
+            let v = &var.second_moment;
+            if let Some(g) = grads.get(theta) {
+                let mut next_theta = theta.as_tensor().clone();
+                let g = if p.weight_decay == 0. {
+                    g.clone()
+                } else if p.decoupled_weight_decay {
+                    next_theta = (next_theta * (1. - p.lr * p.weight_decay))?;
+                    g.clone()
+                } else {
+                    (g + (theta.as_tensor() * p.weight_decay)?)?
+                };
+                let next_m = ((m.as_tensor() * p.beta1)? + (&g * (1. - p.beta1))?)?;
+                let next_v = ((v.as_tensor() * p.beta2)? + (g.sqr()? * (1. - p.beta2))?)?;
+                let m_hat = (&next_m / bias_correction1)?;
+                let update = if rho_t > 5. {
+                    let r = ((rho_t - 4.) * (rho_t - 2.) * rho_inf
+                        / ((rho_inf - 4.) * (rho_inf - 2.) * rho_t))
+                        .sqrt();
+                    let adaptive_lr = (next_v.sqrt()? + p.eps)?.recip()?;
+                    ((m_hat * adaptive_lr)? * (r * bias_correction2.sqrt()))?
+                } else {
+                    m_hat
+                };
+                m.set(&next_m)?;
+                v.set(&next_v)?;
+                theta.set(&(next_theta - (update * p.lr)?)?)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn state(&self) -> Result<OptimizerState> {
+        let vars = self
+            .vars
+            .iter()
+            .map(|v| {
+                Ok(HashMap::from([
+                    (
+                        "first_moment".to_string(),
+                        v.first_moment.as_tensor().copy()?,
+                    ),
+                    (
+                        "second_moment".to_string(),
+                        v.second_moment.as_tensor().copy()?,
+                    ),
+                ]))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let scalars = HashMap::from([("step".to_string(), self.step_t as f64)]);
+        Ok(OptimizerState { vars, scalars })
+    }
+
+    fn set_state(&mut self, state: &OptimizerState) -> Result<()> {
+        check_num_vars(state, self.vars.len())?;
+        for (var, s) in self.vars.iter().zip(state.vars.iter()) {
+            restore_var(s, "first_moment", &var.first_moment)?;
+            restore_var(s, "second_moment", &var.second_moment)?;
+        }
+        self.step_t = restore_step(state)?;
+        Ok(())
+    }
+}
+
+impl RAdam {
+    pub fn params(&self) -> &ParamsRAdam {
+        &self.params
+    }
+
+    pub fn set_params(&mut self, params: ParamsRAdam) {
+        self.params = params;
+    }
+}
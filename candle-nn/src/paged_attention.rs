@@ -0,0 +1,596 @@
+//! A block based kv cache for serving many sequences concurrently, a.k.a. paged attention.
+//!
+//! The keys and values of all the sequences are stored in fixed-size blocks of `block_size`
+//! positions taken from a shared pool, each sequence has a block table listing its blocks in
+//! order. Blocks are only allocated when a sequence grows and are returned to the pool when it
+//! is removed, so no memory is reserved up to a maximum sequence length and the cache does not
+//! get fragmented. Sequences can share blocks, e.g. for a common prompt or when sampling
+//! multiple completions: the blocks are reference counted and a shared block is copied before
+//! being written to.
+//!
+//! A forward pass processes a batch of sequences with different numbers of new tokens, packed
+//! along the first dimension of the queries, keys, and values. [`PagedKvCache::prepare`]
+//! reserves the slots for the new tokens and returns the [`PagedBatch`] metadata used by all the
+//! layers. Each layer then writes its keys and values with [`PagedKvCache::write`] and computes
+//! the attention with [`PagedKvCache::attention`], which gathers the keys and values of each
+//! sequence from its block table.
+//!
+//! ```ignore
+//! let mut cache = PagedKvCache::new(n_layers, n_blocks, 16, n_kv_heads, head_dim, dtype, &dev)?;
+//! cache.add_sequence(0)?;
+//! cache.add_sequence(1)?;
+//! // Prompts of 5 and 3 tokens, the following steps use one token per sequence.
+//! let batch = cache.prepare(&[(0, 5), (1, 3)])?;
+//! for (layer_idx, layer) in layers.iter().enumerate() {
+//!     // q has shape (8, n_heads, head_dim), k and v have shape (8, n_kv_heads, head_dim).
+//!     cache.write(layer_idx, &batch, &k, &v)?;
+//!     let attn = cache.attention(layer_idx, &batch, &q, &AttentionConfig::default())?;
+//! }
+//! ```
+use crate::attention::{scaled_dot_product_attention, AttentionConfig};
+use crate::kv_transfer::KvBlocks;
+use candle::{DType, Device, Result, Tensor};
+use std::collections::HashMap;
+
+/// A pool of reference counted blocks.
+#[derive(Debug, Clone)]
+pub struct BlockAllocator {
+    free: Vec<usize>,
+    ref_counts: Vec<usize>,
+}
+
+impl BlockAllocator {
+    pub fn new(num_blocks: usize) -> Self {
+        Self {
+            free: (0..num_blocks).rev().collect(),
+            ref_counts: vec![0; num_blocks],
+        }
+    }
+
+    pub fn num_blocks(&self) -> usize {
+        self.ref_counts.len()
+    }
+
+    pub fn num_free_blocks(&self) -> usize {
+        self.free.len()
+    }
+
+    pub fn ref_count(&self, block: usize) -> usize {
+        self.ref_counts[block]
+    }
+
+    /// Returns a free block with a reference count of one.
+    pub fn allocate(&mut self) -> Result<usize> {
+        match self.free.pop() {
+            None => candle::bail!("no free blocks left in the kv cache"),
+            Some(block) => {
+                self.ref_counts[block] = 1;
+                Ok(block)
+            }
+        }
+    }
+
+    /// Adds a reference to an allocated block.
+    pub fn share(&mut self, block: usize) -> Result<()> {
+        if self.ref_counts[block] == 0 {
+            candle::bail!("cannot share block {block} which is not allocated")
+        }
+        self.ref_counts[block] += 1;
+        Ok(())
+    }
+
+    /// Removes a reference to a block, the block is freed when there are no references left.
+    pub fn release(&mut self, block: usize) -> Result<()> {
+        match self.ref_counts[block] {
+            0 => candle::bail!("cannot release block {block} which is not allocated"),
+            1 => {
+                self.ref_counts[block] = 0;
+                self.free.push(block)
+            }
+            _ => self.ref_counts[block] -= 1,
+        }
+        Ok(())
+    }
+}
+
+/// The blocks used by a sequence, position `i` is stored in block `blocks[i / block_size]`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BlockTable {
+    blocks: Vec<usize>,
+    len: usize,
+}
+
+impl BlockTable {
+    pub fn blocks(&self) -> &[usize] {
+        &self.blocks
+    }
+
+    /// The number of positions stored for the sequence.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    fn slot(&self, pos: usize, block_size: usize) -> usize {
+        self.blocks[pos / block_size] * block_size + pos % block_size
+    }
+}
+
+/// The positions of the new tokens of a forward pass and the indexes used to gather the keys
+/// and values of each sequence, returned by [`PagedKvCache::prepare`].
+#[derive(Debug, Clone)]
+pub struct PagedBatch {
+    seq_ids: Vec<usize>,
+    // The number of new tokens and the total number of positions for each sequence.
+    num_new_tokens: Vec<usize>,
+    seq_lens: Vec<usize>,
+    // Contiguous runs of new tokens written to contiguous slots: (token index, slot, len).
+    writes: Vec<(usize, usize, usize)>,
+    num_tokens: usize,
+    max_q_len: usize,
+    max_kv_len: usize,
+    // The slots of the keys and values for each sequence, padded to max_kv_len.
+    kv_index: Tensor,
+    // The packed token used for each padded query position, and the reverse mapping.
+    q_index: Tensor,
+    out_index: Tensor,
+    // The (batch, max_q_len, max_kv_len) attention mask, 1 for the masked positions.
+    mask: Tensor,
+}
+
+impl PagedBatch {
+    pub fn seq_ids(&self) -> &[usize] {
+        &self.seq_ids
+    }
+
+    pub fn num_new_tokens(&self) -> &[usize] {
+        &self.num_new_tokens
+    }
+
+    /// The number of positions of each sequence including the new tokens.
+    pub fn seq_lens(&self) -> &[usize] {
+        &self.seq_lens
+    }
+
+    /// The total number of new tokens, i.e. the size of the first dimension of the inputs.
+    pub fn num_tokens(&self) -> usize {
+        self.num_tokens
+    }
+
+    /// The attention mask with shape `(batch, max_new_tokens, max_seq_len)`.
+    pub fn mask(&self) -> &Tensor {
+        &self.mask
+    }
+}
+
+/// Keys and values for all the layers of a model, stored in blocks shared between sequences.
+#[derive(Debug, Clone)]
+pub struct PagedKvCache {
+    // The keys and values of each layer with shape (num_blocks * block_size, kv_heads, head_dim).
+    layers: Vec<(Tensor, Tensor)>,
+    block_size: usize,
+    allocator: BlockAllocator,
+    sequences: HashMap<usize, BlockTable>,
+}
+
+impl PagedKvCache {
+    pub fn new(
+        num_layers: usize,
+        num_blocks: usize,
+        block_size: usize,
+        num_kv_heads: usize,
+        head_dim: usize,
+        dtype: DType,
+        device: &Device,
+    ) -> Result<Self> {
+        if block_size == 0 {
+            candle::bail!("the block size should be positive")
+        }
+        let shape = (num_blocks * block_size, num_kv_heads, head_dim);
+        let layers = (0..num_layers)
+            .map(|_| {
+                let k = Tensor::zeros(shape, dtype, device)?;
+                let v = Tensor::zeros(shape, dtype, device)?;
+                Ok((k, v))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Self {
+            layers,
+            block_size,
+            allocator: BlockAllocator::new(num_blocks),
+            sequences: HashMap::new(),
+        })
+    }
+
+    pub fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    pub fn num_layers(&self) -> usize {
+        self.layers.len()
+    }
+
+    pub fn allocator(&self) -> &BlockAllocator {
+        &self.allocator
+    }
+
+    pub fn num_free_blocks(&self) -> usize {
+        self.allocator.num_free_blocks()
+    }
+
+    pub fn sequence(&self, seq_id: usize) -> Option<&BlockTable> {
+        self.sequences.get(&seq_id)
+    }
+
+    pub fn seq_ids(&self) -> impl Iterator<Item = usize> + '_ {
+        self.sequences.keys().copied()
+    }
+
+    /// The keys and values of a layer, with shape `(num_blocks * block_size, kv_heads,
+    /// head_dim)`.
+    pub fn layer(&self, layer_idx: usize) -> Result<&(Tensor, Tensor)> {
+        match self.layers.get(layer_idx) {
+            None => candle::bail!("layer {layer_idx} is out of range"),
+            Some(layer) => Ok(layer),
+        }
+    }
+
+    pub fn add_sequence(&mut self, seq_id: usize) -> Result<()> {
+        if self.sequences.contains_key(&seq_id) {
+            candle::bail!("sequence {seq_id} already exists")
+        }
+        self.sequences.insert(seq_id, BlockTable::default());
+        Ok(())
+    }
+
+    /// Creates sequence `dst` with the same content as `src`, the blocks are shared until one of
+    /// the sequences writes to them.
+    pub fn fork_sequence(&mut self, src: usize, dst: usize) -> Result<()> {
+        if self.sequences.contains_key(&dst) {
+            candle::bail!("sequence {dst} already exists")
+        }
+        let table = self.table(src)?.clone();
+        for &block in table.blocks.iter() {
+            self.allocator.share(block)?
+        }
+        self.sequences.insert(dst, table);
+        Ok(())
+    }
+
+    /// Removes a sequence, the blocks that are not shared with other sequences are freed.
+    pub fn remove_sequence(&mut self, seq_id: usize) -> Result<()> {
+        let table = match self.sequences.remove(&seq_id) {
+            None => candle::bail!("unknown sequence {seq_id}"),
+            Some(table) => table,
+        };
+        for block in table.blocks {
+            self.allocator.release(block)?
+        }
+        Ok(())
+    }
+
+    fn table(&self, seq_id: usize) -> Result<&BlockTable> {
+        match self.sequences.get(&seq_id) {
+            None => candle::bail!("unknown sequence {seq_id}"),
+            Some(table) => Ok(table),
+        }
+    }
+
+    /// The number of blocks to allocate for appending `num_tokens` positions to a sequence,
+    /// including the copy of a shared last block.
+    pub fn blocks_needed(&self, seq_id: usize, num_tokens: usize) -> Result<usize> {
+        let table = self.table(seq_id)?;
+        let new_len = table.len + num_tokens;
+        let mut needed = new_len.div_ceil(self.block_size) - table.blocks.len();
+        if num_tokens > 0 && table.len % self.block_size != 0 {
+            if let Some(&last) = table.blocks.last() {
+                if self.allocator.ref_count(last) > 1 {
+                    needed += 1
+                }
+            }
+        }
+        Ok(needed)
+    }
+
+    /// Whether there are enough free blocks to append `num_tokens` positions to a sequence.
+    pub fn can_append(&self, seq_id: usize, num_tokens: usize) -> bool {
+        self.blocks_needed(seq_id, num_tokens)
+            .is_ok_and(|needed| needed <= self.num_free_blocks())
+    }
+
+    // Replaces a shared block with a copy so that it can be written to.
+    fn copy_on_write(&mut self, seq_id: usize, block_idx: usize) -> Result<()> {
+        let old = self.table(seq_id)?.blocks[block_idx];
+        let new = self.allocator.allocate()?;
+        let bs = self.block_size;
+        for (k, v) in self.layers.iter() {
+            // The source is copied first as it uses the same storage as the destination.
+            k.slice_set(&k.narrow(0, old * bs, bs)?.copy()?, 0, new * bs)?;
+            v.slice_set(&v.narrow(0, old * bs, bs)?.copy()?, 0, new * bs)?;
+        }
+        self.allocator.release(old)?;
+        if let Some(table) = self.sequences.get_mut(&seq_id) {
+            table.blocks[block_idx] = new
+        }
+        Ok(())
+    }
+
+    /// Reserves the slots for `num_tokens` new tokens for each `(seq_id, num_tokens)` pair, the
+    /// tokens of the forward pass are packed in this order. Either all the sequences are
+    /// extended or, when there are not enough free blocks, none of them.
+    pub fn prepare(&mut self, seqs: &[(usize, usize)]) -> Result<PagedBatch> {
+        let mut needed = 0;
+        for (i, &(seq_id, num_tokens)) in seqs.iter().enumerate() {
+            if num_tokens == 0 {
+                candle::bail!("no new tokens for sequence {seq_id}")
+            }
+            if seqs[..i].iter().any(|&(id, _)| id == seq_id) {
+                candle::bail!("sequence {seq_id} appears multiple times in the batch")
+            }
+            needed += self.blocks_needed(seq_id, num_tokens)?;
+        }
+        if needed > self.num_free_blocks() {
+            candle::bail!(
+                "not enough free blocks in the kv cache, {needed} needed, {} free",
+                self.num_free_blocks()
+            )
+        }
+        let bs = self.block_size;
+        let mut writes = vec![];
+        let mut token_offset = 0;
+        for &(seq_id, num_tokens) in seqs.iter() {
+            let len = self.table(seq_id)?.len;
+            if len % bs != 0 {
+                let last = len / bs;
+                if self.allocator.ref_count(self.table(seq_id)?.blocks[last]) > 1 {
+                    self.copy_on_write(seq_id, last)?
+                }
+            }
+            let new_len = len + num_tokens;
+            let mut blocks = vec![];
+            for _ in self.table(seq_id)?.blocks.len()..new_len.div_ceil(bs) {
+                blocks.push(self.allocator.allocate()?)
+            }
+            let table = match self.sequences.get_mut(&seq_id) {
+                None => candle::bail!("unknown sequence {seq_id}"),
+                Some(table) => table,
+            };
+            table.blocks.extend(blocks);
+            let mut pos = len;
+            while pos < new_len {
+                let run = usize::min(bs - pos % bs, new_len - pos);
+                writes.push((token_offset + pos - len, table.slot(pos, bs), run));
+                pos += run;
+            }
+            table.len = new_len;
+            token_offset += num_tokens;
+        }
+        self.batch(seqs, writes, token_offset)
+    }
+
+    fn batch(
+        &self,
+        seqs: &[(usize, usize)],
+        writes: Vec<(usize, usize, usize)>,
+        num_tokens: usize,
+    ) -> Result<PagedBatch> {
+        let device = match self.layers.first() {
+            None => &Device::Cpu,
+            Some((k, _)) => k.device(),
+        };
+        let b_size = seqs.len();
+        let seq_lens = seqs
+            .iter()
+            .map(|&(seq_id, _)| Ok(self.table(seq_id)?.len))
+            .collect::<Result<Vec<_>>>()?;
+        let num_new_tokens = seqs.iter().map(|&(_, n)| n).collect::<Vec<_>>();
+        let max_q_len = num_new_tokens.iter().copied().max().unwrap_or(0);
+        let max_kv_len = seq_lens.iter().copied().max().unwrap_or(0);
+        let mut kv_index = Vec::with_capacity(b_size * max_kv_len);
+        let mut q_index = Vec::with_capacity(b_size * max_q_len);
+        let mut out_index = Vec::with_capacity(num_tokens);
+        let mut mask = Vec::with_capacity(b_size * max_q_len * max_kv_len);
+        let mut token_offset = 0;
+        for (i, &(seq_id, n)) in seqs.iter().enumerate() {
+            let table = self.table(seq_id)?;
+            let len = table.len;
+            for pos in 0..max_kv_len {
+                let slot = if pos < len {
+                    table.slot(pos, self.block_size)
+                } else {
+                    0
+                };
+                kv_index.push(slot as u32)
+            }
+            for j in 0..max_q_len {
+                if j < n {
+                    q_index.push((token_offset + j) as u32);
+                    out_index.push((i * max_q_len + j) as u32);
+                    // The new tokens are at the end of the sequence.
+                    let q_pos = len - n + j;
+                    mask.extend((0..max_kv_len).map(|pos| u8::from(pos > q_pos)))
+                } else {
+                    // Padding queries attend to the first position so that the softmax does
+                    // not produce NaNs, their outputs are discarded.
+                    q_index.push(token_offset as u32);
+                    mask.extend((0..max_kv_len).map(|pos| u8::from(pos > 0)))
+                }
+            }
+            token_offset += n;
+        }
+        Ok(PagedBatch {
+            seq_ids: seqs.iter().map(|&(seq_id, _)| seq_id).collect(),
+            num_new_tokens,
+            seq_lens,
+            writes,
+            num_tokens,
+            max_q_len,
+            max_kv_len,
+            kv_index: Tensor::from_vec(kv_index, b_size * max_kv_len, device)?,
+            q_index: Tensor::from_vec(q_index, b_size * max_q_len, device)?,
+            out_index: Tensor::from_vec(out_index, num_tokens, device)?,
+            mask: Tensor::from_vec(mask, (b_size, max_q_len, max_kv_len), device)?,
+        })
+    }
+
+    /// Writes the keys and values of the new tokens of `batch` for a layer, `k` and `v` have
+    /// shape `(num_tokens, kv_heads, head_dim)`.
+    pub fn write(
+        &self,
+        layer_idx: usize,
+        batch: &PagedBatch,
+        k: &Tensor,
+        v: &Tensor,
+    ) -> Result<()> {
+        let (k_cache, v_cache) = self.layer(layer_idx)?;
+        let (num_tokens, _, _) = k.dims3()?;
+        if num_tokens != batch.num_tokens || v.dims() != k.dims() {
+            candle::bail!(
+                "unexpected shapes for the keys {:?} and values {:?}, {} tokens in the batch",
+                k.shape(),
+                v.shape(),
+                batch.num_tokens
+            )
+        }
+        let k = k.to_dtype(k_cache.dtype())?;
+        let v = v.to_dtype(v_cache.dtype())?;
+        for &(token, slot, len) in batch.writes.iter() {
+            k_cache.slice_set(&k.narrow(0, token, len)?.contiguous()?, 0, slot)?;
+            v_cache.slice_set(&v.narrow(0, token, len)?.contiguous()?, 0, slot)?;
+        }
+        Ok(())
+    }
+
+    /// The keys and values of a sequence for a layer, with shape `(seq_len, kv_heads,
+    /// head_dim)`.
+    pub fn gather(&self, layer_idx: usize, seq_id: usize) -> Result<(Tensor, Tensor)> {
+        let (k_cache, v_cache) = self.layer(layer_idx)?;
+        let table = self.table(seq_id)?;
+        let index = (0..table.len)
+            .map(|pos| table.slot(pos, self.block_size) as u32)
+            .collect::<Vec<_>>();
+        let index = Tensor::from_vec(index, table.len, k_cache.device())?;
+        Ok((
+            k_cache.index_select(&index, 0)?,
+            v_cache.index_select(&index, 0)?,
+        ))
+    }
+
+    /// The blocks of a sequence for all the layers, e.g. to hand the sequence over to another
+    /// worker, see [`crate::kv_transfer`].
+    pub fn export_sequence(&self, seq_id: usize) -> Result<KvBlocks> {
+        let table = self.table(seq_id)?;
+        let bs = self.block_size;
+        let num_blocks = table.blocks.len();
+        let index = table
+            .blocks
+            .iter()
+            .flat_map(|&block| (block * bs..(block + 1) * bs).map(|slot| slot as u32))
+            .collect::<Vec<_>>();
+        let mut layers = Vec::with_capacity(self.layers.len());
+        for (k_cache, v_cache) in self.layers.iter() {
+            let (_, kv_heads, head_dim) = k_cache.dims3()?;
+            let index = Tensor::from_vec(index.clone(), num_blocks * bs, k_cache.device())?;
+            let shape = (num_blocks, bs, kv_heads, head_dim);
+            let k = k_cache.index_select(&index, 0)?.reshape(shape)?;
+            let v = v_cache.index_select(&index, 0)?.reshape(shape)?;
+            layers.push(Tensor::stack(&[k, v], 0)?)
+        }
+        KvBlocks::new(table.len, Tensor::stack(&layers, 0)?)
+    }
+
+    /// Adds sequence `seq_id` with the content of `blocks`, which are converted to the device
+    /// and dtype of the cache. The block size, number of layers, and kv heads have to match.
+    pub fn import_sequence(&mut self, seq_id: usize, blocks: &KvBlocks) -> Result<()> {
+        if self.sequences.contains_key(&seq_id) {
+            candle::bail!("sequence {seq_id} already exists")
+        }
+        let (k_cache, _) = self.layer(0)?;
+        let (_, kv_heads, head_dim) = k_cache.dims3()?;
+        let dims = blocks.data().dims();
+        let num_blocks = blocks.num_blocks();
+        let expected = [
+            self.layers.len(),
+            2,
+            num_blocks,
+            self.block_size,
+            kv_heads,
+            head_dim,
+        ];
+        if dims != expected {
+            candle::bail!("kv blocks with shape {dims:?} do not match the cache, {expected:?}")
+        }
+        if num_blocks > self.num_free_blocks() {
+            candle::bail!(
+                "{num_blocks} blocks needed for sequence {seq_id}, {} are free",
+                self.num_free_blocks()
+            )
+        }
+        let data = blocks
+            .data()
+            .to_device(k_cache.device())?
+            .to_dtype(k_cache.dtype())?;
+        let shape = (
+            self.layers.len(),
+            2,
+            num_blocks * self.block_size,
+            kv_heads,
+            head_dim,
+        );
+        let data = data.reshape(shape)?;
+        let table = BlockTable {
+            blocks: (0..num_blocks)
+                .map(|_| self.allocator.allocate())
+                .collect::<Result<Vec<_>>>()?,
+            len: blocks.seq_len(),
+        };
+        let bs = self.block_size;
+        for (layer_idx, (k_cache, v_cache)) in self.layers.iter().enumerate() {
+            let (k, v) = (data.get(layer_idx)?.get(0)?, data.get(layer_idx)?.get(1)?);
+            for (i, &block) in table.blocks.iter().enumerate() {
+                k_cache.slice_set(&k.narrow(0, i * bs, bs)?.contiguous()?, 0, block * bs)?;
+                v_cache.slice_set(&v.narrow(0, i * bs, bs)?.contiguous()?, 0, block * bs)?;
+            }
+        }
+        self.sequences.insert(seq_id, table);
+        Ok(())
+    }
+
+    /// Causal attention of the new tokens of `batch` over the keys and values of their
+    /// sequences, `q` has shape `(num_tokens, heads, head_dim)` and the result has the same
+    /// shape. The keys and values for the new tokens have to be written first.
+    pub fn attention(
+        &self,
+        layer_idx: usize,
+        batch: &PagedBatch,
+        q: &Tensor,
+        config: &AttentionConfig,
+    ) -> Result<Tensor> {
+        let (k_cache, v_cache) = self.layer(layer_idx)?;
+        let (num_tokens, n_heads, head_dim) = q.dims3()?;
+        if num_tokens != batch.num_tokens {
+            candle::bail!("{num_tokens} queries for {} tokens", batch.num_tokens)
+        }
+        let (_, n_kv_heads, _) = k_cache.dims3()?;
+        let b_size = batch.seq_ids.len();
+        let (max_q_len, max_kv_len) = (batch.max_q_len, batch.max_kv_len);
+        let gather = |t: &Tensor| {
+            t.index_select(&batch.kv_index, 0)?
+                .reshape((b_size, max_kv_len, n_kv_heads, head_dim))?
+                .transpose(1, 2)
+        };
+        let k = gather(k_cache)?;
+        let v = gather(v_cache)?;
+        let q = q
+            .index_select(&batch.q_index, 0)?
+            .reshape((b_size, max_q_len, n_heads, head_dim))?
+            .transpose(1, 2)?;
+        let attn = scaled_dot_product_attention(&q, &k, &v, Some(&batch.mask), config)?;
+        attn.transpose(1, 2)?
+            .reshape((b_size * max_q_len, n_heads, head_dim))?
+            .index_select(&batch.out_index, 0)
+    }
+}
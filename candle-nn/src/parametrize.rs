@@ -0,0 +1,601 @@
+//! Weight normalization and spectral normalization.
+//!
+//! These wrappers reparameterize the weight of a layer, the weight used by the forward pass is
+//! recomputed from the trained tensors each time.
+//!
+//! - [`WeightNorm`] splits the weight in a magnitude `weight_g` and a direction `weight_v`, see
+//!   "Weight Normalization: A Simple Reparameterization to Accelerate Training of Deep Neural
+//!   Networks", Salimans, T. et al. (2016). This is used by vocoders such as HiFi-GAN whose
+//!   checkpoints store the `weight_g` and `weight_v` tensors.
+//! - [`SpectralNorm`] divides the weight by an estimate of its largest singular value obtained
+//!   by power iteration, see "Spectral Normalization for Generative Adversarial Networks",
+//!   Miyato, T. et al. (2018). The tensors are named `weight_orig`, `weight_u` and `weight_v` as
+//!   in PyTorch.
+//!
+//! Both wrappers can be applied to an existing layer, e.g. `weight_norm(linear)`, or loaded from
+//! a [`VarBuilder`] with builders such as [`conv1d_weight_norm`] or [`conv2d_spectral_norm`].
+use crate::{
+    Conv1d, Conv1dConfig, Conv2d, Conv2dConfig, ConvTranspose1d, ConvTranspose1dConfig,
+    ConvTranspose2d, Init, Linear, VarBuilder,
+};
+use candle::{Module, Result, Tensor, Var};
+
+/// A layer whose weight can be replaced, see [`WeightNorm`] and [`SpectralNorm`].
+pub trait WithWeight: Module + Sized {
+    fn weight(&self) -> &Tensor;
+
+    /// The same layer using `weight`, which has the shape of the current weight.
+    fn with_weight(&self, weight: Tensor) -> Self;
+}
+
+impl WithWeight for Linear {
+    fn weight(&self) -> &Tensor {
+        Linear::weight(self)
+    }
+
+    fn with_weight(&self, weight: Tensor) -> Self {
+        Linear::new(weight, self.bias().cloned())
+    }
+}
+
+macro_rules! with_weight_conv {
+    ($ty:ty) => {
+        impl WithWeight for $ty {
+            fn weight(&self) -> &Tensor {
+                <$ty>::weight(self)
+            }
+
+            fn with_weight(&self, weight: Tensor) -> Self {
+                <$ty>::new(weight, self.bias().cloned(), *self.config())
+            }
+        }
+    };
+}
+
+with_weight_conv!(Conv1d);
+with_weight_conv!(Conv2d);
+with_weight_conv!(ConvTranspose1d);
+with_weight_conv!(ConvTranspose2d);
+
+// Visits the parameters of the wrapped layer except its weight, which is not trained.
+fn visit_other_parameters<M: crate::Layer>(
+    layer: &mut M,
+    f: &mut crate::layer::TensorFn,
+) -> Result<()> {
+    layer.visit_parameters(&mut |name, t| if name == "weight" { Ok(()) } else { f(name, t) })
+}
+
+// The norm of `v` over all the dimensions but `dim`, with the rank of `v`.
+fn norm_except_dim(v: &Tensor, dim: usize) -> Result<Tensor> {
+    let dims: Vec<usize> = (0..v.rank()).filter(|&d| d != dim).collect();
+    v.sqr()?.sum_keepdim(dims)?.sqrt()
+}
+
+/// A layer whose weight is `weight_g * weight_v / ||weight_v||`, the norm being computed over
+/// all the dimensions but `dim`.
+#[derive(Debug, Clone)]
+pub struct WeightNorm<M> {
+    layer: M,
+    weight_g: Tensor,
+    weight_v: Tensor,
+    dim: usize,
+}
+
+impl<M: WithWeight> WeightNorm<M> {
+    /// Reparameterizes the weight of `layer`, the new parameters are variables holding the same
+    /// weight. These variables are not part of a [`crate::VarMap`], they can be retrieved with
+    /// [`crate::Layer::visit_parameters`].
+    pub fn new(layer: M, dim: usize) -> Result<Self> {
+        let weight_v = Var::from_tensor(&layer.weight().copy()?)?;
+        let weight_g = Var::from_tensor(&norm_except_dim(weight_v.as_tensor(), dim)?)?;
+        Self::from_parts(layer, weight_g.into_inner(), weight_v.into_inner(), dim)
+    }
+
+    /// Uses `weight_g` and `weight_v` for the weight of `layer`, `weight_g` has the size of
+    /// `weight_v` on `dim` and a size of 1 on the other dimensions.
+    pub fn from_parts(layer: M, weight_g: Tensor, weight_v: Tensor, dim: usize) -> Result<Self> {
+        let shape = layer.weight().dims();
+        if weight_v.dims() != shape {
+            candle::bail!(
+                "weight_v {:?} does not match the weight {shape:?}",
+                weight_v.shape()
+            )
+        }
+        if dim >= shape.len() {
+            candle::bail!("weight norm dim {dim} out of range for the weight {shape:?}")
+        }
+        let g_shape: Vec<usize> = (0..shape.len())
+            .map(|d| if d == dim { shape[d] } else { 1 })
+            .collect();
+        if weight_g.dims() != g_shape {
+            candle::bail!(
+                "weight_g {:?} does not match the weight {shape:?}, expected {g_shape:?}",
+                weight_g.shape()
+            )
+        }
+        Ok(Self {
+            layer,
+            weight_g,
+            weight_v,
+            dim,
+        })
+    }
+
+    /// The weight used by the forward pass.
+    pub fn weight(&self) -> Result<Tensor> {
+        let norm = norm_except_dim(&self.weight_v, self.dim)?;
+        self.weight_v
+            .broadcast_mul(&self.weight_g.broadcast_div(&norm)?)
+    }
+
+    /// The layer with the current weight and without the reparameterization, e.g. for inference.
+    pub fn remove(&self) -> Result<M> {
+        Ok(self.layer.with_weight(self.weight()?.detach()))
+    }
+}
+
+impl<M> WeightNorm<M> {
+    pub fn weight_g(&self) -> &Tensor {
+        &self.weight_g
+    }
+
+    pub fn weight_v(&self) -> &Tensor {
+        &self.weight_v
+    }
+
+    pub fn dim(&self) -> usize {
+        self.dim
+    }
+
+    /// The wrapped layer, its weight is not used.
+    pub fn layer(&self) -> &M {
+        &self.layer
+    }
+}
+
+impl<M: WithWeight> Module for WeightNorm<M> {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        self.layer.with_weight(self.weight()?).forward(xs)
+    }
+}
+
+impl<M: crate::migrate::Migrate> crate::migrate::Migrate for WeightNorm<M> {
+    fn visit_tensors(&mut self, f: &mut dyn FnMut(&mut Tensor) -> Result<()>) -> Result<()> {
+        f(&mut self.weight_g)?;
+        f(&mut self.weight_v)?;
+        self.layer.visit_tensors(f)
+    }
+}
+
+/// The parameters are `weight_g`, `weight_v` and the parameters of the wrapped layer other than
+/// its weight, e.g. `bias`.
+impl<M: crate::Layer> crate::Layer for WeightNorm<M> {
+    fn visit_parameters(&mut self, f: &mut crate::layer::TensorFn) -> Result<()> {
+        f("weight_g", &mut self.weight_g)?;
+        f("weight_v", &mut self.weight_v)?;
+        visit_other_parameters(&mut self.layer, f)
+    }
+
+    fn visit_buffers(&mut self, f: &mut crate::layer::TensorFn) -> Result<()> {
+        self.layer.visit_buffers(f)
+    }
+}
+
+/// Weight normalization of `layer` over its first dimension, the default of PyTorch.
+pub fn weight_norm<M: WithWeight>(layer: M) -> Result<WeightNorm<M>> {
+    WeightNorm::new(layer, 0)
+}
+
+// Gets `weight_v` with the shape of the weight and `weight_g` for the dimension 0. A new
+// `weight_g` is set to `g_init`, the expected norm of the rows of the initial `weight_v`, so that
+// the weight has the same scale as without the normalization.
+fn weight_norm_parts(
+    shape: &[usize],
+    init_v: Init,
+    g_init: f64,
+    vb: &VarBuilder,
+) -> Result<(Tensor, Tensor)> {
+    let weight_v = vb.get_with_hints(shape, "weight_v", init_v)?;
+    let mut g_shape = vec![1; shape.len()];
+    g_shape[0] = shape[0];
+    let weight_g = vb.get_with_hints(g_shape, "weight_g", Init::Const(g_init))?;
+    Ok((weight_g, weight_v))
+}
+
+// The expected norm of a row initialized with `DEFAULT_KAIMING_NORMAL`, the fan-in being the
+// length of the rows.
+const KAIMING_NORMAL_NORM: f64 = std::f64::consts::SQRT_2;
+
+// The expected norm of `len` values uniform in `[-bound, bound]`.
+fn uniform_norm(len: usize, bound: f64) -> f64 {
+    (len as f64 / 3.).sqrt() * bound
+}
+
+fn uniform_bias(out_dim: usize, bound: f64, vb: &VarBuilder) -> Result<Tensor> {
+    let init = Init::Uniform {
+        lo: -bound,
+        up: bound,
+    };
+    vb.get_with_hints(out_dim, "bias", init)
+}
+
+/// A weight normalized linear layer using the `weight_g`, `weight_v` and `bias` tensors.
+pub fn linear_weight_norm(
+    in_dim: usize,
+    out_dim: usize,
+    vb: VarBuilder,
+) -> Result<WeightNorm<Linear>> {
+    let init_v = crate::init::DEFAULT_KAIMING_NORMAL;
+    let (weight_g, weight_v) =
+        weight_norm_parts(&[out_dim, in_dim], init_v, KAIMING_NORMAL_NORM, &vb)?;
+    let bias = uniform_bias(out_dim, 1. / (in_dim as f64).sqrt(), &vb)?;
+    let layer = Linear::new(weight_v.clone(), Some(bias));
+    WeightNorm::from_parts(layer, weight_g, weight_v, 0)
+}
+
+/// A weight normalized 1d convolution using the `weight_g`, `weight_v` and `bias` tensors.
+pub fn conv1d_weight_norm(
+    in_channels: usize,
+    out_channels: usize,
+    kernel_size: usize,
+    cfg: Conv1dConfig,
+    vb: VarBuilder,
+) -> Result<WeightNorm<Conv1d>> {
+    let init_v = crate::init::DEFAULT_KAIMING_NORMAL;
+    let shape = [out_channels, in_channels / cfg.groups, kernel_size];
+    let (weight_g, weight_v) = weight_norm_parts(&shape, init_v, KAIMING_NORMAL_NORM, &vb)?;
+    let bias = uniform_bias(out_channels, 1. / (in_channels as f64).sqrt(), &vb)?;
+    let layer = Conv1d::new(weight_v.clone(), Some(bias), cfg);
+    WeightNorm::from_parts(layer, weight_g, weight_v, 0)
+}
+
+/// A weight normalized 2d convolution using the `weight_g`, `weight_v` and `bias` tensors.
+pub fn conv2d_weight_norm(
+    in_channels: usize,
+    out_channels: usize,
+    kernel_size: usize,
+    cfg: Conv2dConfig,
+    vb: VarBuilder,
+) -> Result<WeightNorm<Conv2d>> {
+    let init_v = crate::init::DEFAULT_KAIMING_NORMAL;
+    let shape = [
+        out_channels,
+        in_channels / cfg.groups,
+        kernel_size,
+        kernel_size,
+    ];
+    let (weight_g, weight_v) = weight_norm_parts(&shape, init_v, KAIMING_NORMAL_NORM, &vb)?;
+    let bias = uniform_bias(out_channels, 1. / (in_channels as f64).sqrt(), &vb)?;
+    let layer = Conv2d::new(weight_v.clone(), Some(bias), cfg);
+    WeightNorm::from_parts(layer, weight_g, weight_v, 0)
+}
+
+/// A weight normalized 1d transposed convolution using the `weight_g`, `weight_v` and `bias`
+/// tensors, the norm is computed over the input channels as for the upsampling layers of
+/// HiFi-GAN.
+pub fn conv_transpose1d_weight_norm(
+    in_channels: usize,
+    out_channels: usize,
+    kernel_size: usize,
+    cfg: ConvTranspose1dConfig,
+    vb: VarBuilder,
+) -> Result<WeightNorm<ConvTranspose1d>> {
+    let bound = 1. / (out_channels as f64 * kernel_size as f64).sqrt();
+    let init_v = Init::Uniform {
+        lo: -bound,
+        up: bound,
+    };
+    let shape = [in_channels, out_channels / cfg.groups, kernel_size];
+    let g_init = uniform_norm(shape[1] * kernel_size, bound);
+    let (weight_g, weight_v) = weight_norm_parts(&shape, init_v, g_init, &vb)?;
+    let bias = uniform_bias(out_channels, bound, &vb)?;
+    let layer = ConvTranspose1d::new(weight_v.clone(), Some(bias), cfg);
+    WeightNorm::from_parts(layer, weight_g, weight_v, 0)
+}
+
+/// The configuration of a [`SpectralNorm`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SpectralNormConfig {
+    /// The dimension of the output channels, 0 except for the transposed convolutions where it
+    /// is 1.
+    pub dim: usize,
+    /// The number of power iterations for each training forward pass.
+    pub n_power_iterations: usize,
+    pub eps: f64,
+}
+
+impl Default for SpectralNormConfig {
+    fn default() -> Self {
+        Self {
+            dim: 0,
+            n_power_iterations: 1,
+            eps: 1e-12,
+        }
+    }
+}
+
+impl SpectralNormConfig {
+    pub fn with_dim(mut self, dim: usize) -> Self {
+        self.dim = dim;
+        self
+    }
+
+    pub fn with_n_power_iterations(mut self, n_power_iterations: usize) -> Self {
+        self.n_power_iterations = n_power_iterations;
+        self
+    }
+}
+
+// The number of power iterations run when the singular vectors are initialized.
+const WARMUP_ITERATIONS: usize = 15;
+
+// The weight as a matrix with the `dim` dimension first.
+fn weight_mat(weight: &Tensor, dim: usize) -> Result<Tensor> {
+    let weight = if dim == 0 {
+        weight.clone()
+    } else {
+        let mut dims = vec![dim];
+        dims.extend((0..weight.rank()).filter(|&d| d != dim));
+        weight.permute(dims)?
+    };
+    let h = weight.dim(0)?;
+    weight.reshape((h, ()))
+}
+
+fn normalize(xs: &Tensor, eps: f64) -> Result<Tensor> {
+    let norm = xs.sqr()?.sum_keepdim(0)?.sqrt()?.maximum(eps)?;
+    xs.broadcast_div(&norm)
+}
+
+/// A layer whose weight is `weight_orig / sigma`, where `sigma` is the largest singular value
+/// of `weight_orig` seen as a matrix with the `dim` dimension first.
+///
+/// `sigma` is estimated from the singular vectors `weight_u` and `weight_v`, which are buffers
+/// improved by power iteration on each forward pass in training mode. The evaluation mode uses
+/// the current vectors as is.
+#[derive(Debug, Clone)]
+pub struct SpectralNorm<M> {
+    layer: M,
+    weight_orig: Tensor,
+    weight_u: Var,
+    weight_v: Var,
+    config: SpectralNormConfig,
+    // The mode used by [`crate::layer::Layer::forward_mode`].
+    training: bool,
+}
+
+impl<M: WithWeight> SpectralNorm<M> {
+    /// Reparameterizes the weight of `layer`, `weight_orig` is a new variable holding the
+    /// weight, see [`WeightNorm::new`]. The singular vectors are initialized randomly and
+    /// refined with a few power iterations.
+    pub fn new(layer: M, config: SpectralNormConfig) -> Result<Self> {
+        let weight_orig = Var::from_tensor(&layer.weight().copy()?)?.into_inner();
+        let mat = weight_mat(&weight_orig, config.dim)?;
+        let (h, w) = mat.dims2()?;
+        let (dtype, device) = (weight_orig.dtype(), weight_orig.device());
+        let weight_u = Tensor::randn(0f32, 1f32, h, device)?.to_dtype(dtype)?;
+        let weight_v = Tensor::randn(0f32, 1f32, w, device)?.to_dtype(dtype)?;
+        let s = Self::from_parts(layer, weight_orig, weight_u, weight_v, config)?;
+        s.power_iterations(WARMUP_ITERATIONS)?;
+        Ok(s)
+    }
+
+    /// Uses `weight_orig` for the weight of `layer` with the singular vector estimates
+    /// `weight_u` and `weight_v`, these vectors are normalized.
+    pub fn from_parts(
+        layer: M,
+        weight_orig: Tensor,
+        weight_u: Tensor,
+        weight_v: Tensor,
+        config: SpectralNormConfig,
+    ) -> Result<Self> {
+        let shape = layer.weight().dims();
+        if weight_orig.dims() != shape {
+            candle::bail!(
+                "weight_orig {:?} does not match the weight {shape:?}",
+                weight_orig.shape()
+            )
+        }
+        if config.dim >= shape.len() {
+            candle::bail!(
+                "spectral norm dim {} out of range for the weight {shape:?}",
+                config.dim
+            )
+        }
+        let (h, w) = weight_mat(&weight_orig, config.dim)?.dims2()?;
+        if weight_u.dims() != [h] || weight_v.dims() != [w] {
+            candle::bail!(
+                "weight_u {:?} and weight_v {:?} do not match the weight {shape:?}, expected ({h}) and ({w})",
+                weight_u.shape(),
+                weight_v.shape()
+            )
+        }
+        let weight_u = Var::from_tensor(&weight_u)?;
+        let weight_v = Var::from_tensor(&weight_v)?;
+        weight_u.set(&normalize(weight_u.as_tensor(), config.eps)?)?;
+        weight_v.set(&normalize(weight_v.as_tensor(), config.eps)?)?;
+        Ok(Self {
+            layer,
+            weight_orig,
+            weight_u,
+            weight_v,
+            config,
+            training: false,
+        })
+    }
+
+    /// Runs `n` power iterations to refine the singular vectors.
+    pub fn power_iterations(&self, n: usize) -> Result<()> {
+        let eps = self.config.eps;
+        let mat = weight_mat(&self.weight_orig.detach(), self.config.dim)?;
+        let mut u = self.weight_u.as_tensor().detach();
+        let mut v = self.weight_v.as_tensor().detach();
+        for _ in 0..n {
+            v = normalize(&mat.t()?.matmul(&u.unsqueeze(1)?)?.squeeze(1)?, eps)?;
+            u = normalize(&mat.matmul(&v.unsqueeze(1)?)?.squeeze(1)?, eps)?;
+        }
+        self.weight_u.set(&u)?;
+        self.weight_v.set(&v)
+    }
+
+    /// The estimate of the largest singular value of `weight_orig`, it is differentiable with
+    /// respect to `weight_orig` but not to the singular vectors.
+    pub fn sigma(&self) -> Result<Tensor> {
+        let mat = weight_mat(&self.weight_orig, self.config.dim)?;
+        // The vectors are copied as they are updated in place by the next training pass.
+        let u = self.weight_u.as_tensor().detach().copy()?.unsqueeze(0)?;
+        let v = self.weight_v.as_tensor().detach().copy()?.unsqueeze(1)?;
+        u.matmul(&mat.matmul(&v)?)?.squeeze(1)?.squeeze(0)
+    }
+
+    /// The weight used by the forward pass, the power iterations are only run by
+    /// [`candle::ModuleT::forward_t`].
+    pub fn weight(&self) -> Result<Tensor> {
+        self.weight_orig.broadcast_div(&self.sigma()?)
+    }
+
+    /// The layer with the current weight and without the reparameterization, e.g. for inference.
+    pub fn remove(&self) -> Result<M> {
+        Ok(self.layer.with_weight(self.weight()?.detach()))
+    }
+}
+
+impl<M> SpectralNorm<M> {
+    pub fn weight_orig(&self) -> &Tensor {
+        &self.weight_orig
+    }
+
+    pub fn weight_u(&self) -> &Tensor {
+        self.weight_u.as_tensor()
+    }
+
+    pub fn weight_v(&self) -> &Tensor {
+        self.weight_v.as_tensor()
+    }
+
+    pub fn config(&self) -> &SpectralNormConfig {
+        &self.config
+    }
+
+    /// The wrapped layer, its weight is not used.
+    pub fn layer(&self) -> &M {
+        &self.layer
+    }
+}
+
+impl<M: WithWeight> crate::ModuleT for SpectralNorm<M> {
+    fn forward_t(&self, xs: &Tensor, train: bool) -> Result<Tensor> {
+        if train {
+            self.power_iterations(self.config.n_power_iterations)?
+        }
+        self.layer.with_weight(self.weight()?).forward(xs)
+    }
+}
+
+impl<M: crate::migrate::Migrate> crate::migrate::Migrate for SpectralNorm<M> {
+    fn visit_tensors(&mut self, f: &mut dyn FnMut(&mut Tensor) -> Result<()>) -> Result<()> {
+        f(&mut self.weight_orig)?;
+        // The singular vectors are kept as variables so that they can be updated in place.
+        for vec in [&mut self.weight_u, &mut self.weight_v] {
+            let mut t = vec.as_tensor().clone();
+            f(&mut t)?;
+            *vec = Var::from_tensor(&t)?;
+        }
+        self.layer.visit_tensors(f)
+    }
+}
+
+/// The parameters are `weight_orig` and the parameters of the wrapped layer other than its
+/// weight, the buffers are `weight_u` and `weight_v`.
+impl<M: crate::Layer> crate::Layer for SpectralNorm<M> {
+    fn visit_parameters(&mut self, f: &mut crate::layer::TensorFn) -> Result<()> {
+        f("weight_orig", &mut self.weight_orig)?;
+        visit_other_parameters(&mut self.layer, f)
+    }
+
+    fn visit_buffers(&mut self, f: &mut crate::layer::TensorFn) -> Result<()> {
+        for (name, vec) in [
+            ("weight_u", &mut self.weight_u),
+            ("weight_v", &mut self.weight_v),
+        ] {
+            let mut t = vec.as_tensor().clone();
+            f(name, &mut t)?;
+            if t.id() != vec.as_tensor().id() {
+                *vec = Var::from_tensor(&t)?
+            }
+        }
+        self.layer.visit_buffers(f)
+    }
+
+    fn set_own_training(&mut self, training: bool) {
+        self.training = training
+    }
+
+    fn is_training(&self) -> Option<bool> {
+        Some(self.training)
+    }
+}
+
+/// Spectral normalization of `layer` with the default configuration.
+pub fn spectral_norm<M: WithWeight>(layer: M) -> Result<SpectralNorm<M>> {
+    SpectralNorm::new(layer, SpectralNormConfig::default())
+}
+
+// Gets `weight_orig` and the singular vector buffers, new vectors are refined with a few power
+// iterations.
+fn spectral_norm_from_vb<M: WithWeight>(
+    layer: M,
+    weight_orig: Tensor,
+    config: SpectralNormConfig,
+    vb: &VarBuilder,
+) -> Result<SpectralNorm<M>> {
+    let (h, w) = weight_mat(&weight_orig, config.dim)?.dims2()?;
+    let is_new = !vb.contains_tensor("weight_u");
+    let init = Init::Randn {
+        mean: 0.,
+        stdev: 1.,
+    };
+    let weight_u = vb.get_buffer_with_hints(h, "weight_u", init)?;
+    let weight_v = vb.get_buffer_with_hints(w, "weight_v", init)?;
+    let s = SpectralNorm::from_parts(layer, weight_orig, weight_u, weight_v, config)?;
+    if is_new {
+        s.power_iterations(WARMUP_ITERATIONS)?
+    }
+    Ok(s)
+}
+
+/// A spectral normalized linear layer using the `weight_orig` and `bias` tensors and the
+/// `weight_u` and `weight_v` buffers.
+pub fn linear_spectral_norm(
+    in_dim: usize,
+    out_dim: usize,
+    vb: VarBuilder,
+) -> Result<SpectralNorm<Linear>> {
+    let init_ws = crate::init::DEFAULT_KAIMING_NORMAL;
+    let weight_orig = vb.get_with_hints((out_dim, in_dim), "weight_orig", init_ws)?;
+    let bias = uniform_bias(out_dim, 1. / (in_dim as f64).sqrt(), &vb)?;
+    let layer = Linear::new(weight_orig.clone(), Some(bias));
+    spectral_norm_from_vb(layer, weight_orig, SpectralNormConfig::default(), &vb)
+}
+
+/// A spectral normalized 2d convolution using the `weight_orig` and `bias` tensors and the
+/// `weight_u` and `weight_v` buffers.
+pub fn conv2d_spectral_norm(
+    in_channels: usize,
+    out_channels: usize,
+    kernel_size: usize,
+    cfg: Conv2dConfig,
+    vb: VarBuilder,
+) -> Result<SpectralNorm<Conv2d>> {
+    let init_ws = crate::init::DEFAULT_KAIMING_NORMAL;
+    let shape = (
+        out_channels,
+        in_channels / cfg.groups,
+        kernel_size,
+        kernel_size,
+    );
+    let weight_orig = vb.get_with_hints(shape, "weight_orig", init_ws)?;
+    let bias = uniform_bias(out_channels, 1. / (in_channels as f64).sqrt(), &vb)?;
+    let layer = Conv2d::new(weight_orig.clone(), Some(bias), cfg);
+    spectral_norm_from_vb(layer, weight_orig, SpectralNormConfig::default(), &vb)
+}
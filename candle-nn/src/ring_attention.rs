@@ -0,0 +1,362 @@
+//! Ring attention, attention over a sequence sharded across the ranks of a communicator.
+//!
+//! With context parallelism each rank holds a contiguous chunk of the sequence, rank `r` holding
+//! the positions `r * chunk..(r + 1) * chunk`, so that the activations of sequences that do not
+//! fit on a single device get split across devices. The attention still needs all the keys and
+//! values: [`ring_attention`] passes the key and value blocks around the ring of ranks, each
+//! rank combining the attention over the blocks it receives with an online softmax. The
+//! exchange of the next key and value blocks is issued before computing the current one and only
+//! waited for afterwards, the nccl communicator created with `NcclCommunicator::from_rank` runs
+//! it on a dedicated stream so that the transfers overlap with the attention kernels.
+//!
+//! The backward pass makes a second trip around the ring, the gradients of the keys and values
+//! travelling along with their block until they get back to the rank owning it. All the ranks
+//! have to run the same forward and backward passes.
+//!
+//! ```ignore
+//! let comm: Arc<dyn Communicator> = ...;
+//! let xs = shard_sequence(&xs, 1, comm.as_ref())?;
+//! let offset = sequence_offset(xs.dim(1)?, comm.as_ref());
+//! // Computes q, k, v with shape (batch, heads, chunk, head_dim), the rotary embeddings use the
+//! // positions starting at offset.
+//! let ys = ring_attention(&q, &k, &v, &comm, RingAttentionConfig::causal(head_dim))?;
+//! ```
+use crate::attention::{apply_mask, causal_mask};
+use crate::distributed::{Communicator, PendingExchange};
+use candle::{CpuStorage, CustomOp3, DType, Layout, Result, Shape, Storage, Tensor, D};
+use std::sync::Arc;
+
+/// The configuration for [`ring_attention`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RingAttentionConfig {
+    pub scale: f64,
+    pub causal: bool,
+}
+
+impl RingAttentionConfig {
+    /// A causal attention with the usual `1 / sqrt(head_dim)` scale.
+    pub fn causal(head_dim: usize) -> Self {
+        Self {
+            scale: 1. / (head_dim as f64).sqrt(),
+            causal: true,
+        }
+    }
+
+    /// A bidirectional attention with the usual `1 / sqrt(head_dim)` scale.
+    pub fn bidirectional(head_dim: usize) -> Self {
+        Self {
+            scale: 1. / (head_dim as f64).sqrt(),
+            causal: false,
+        }
+    }
+}
+
+/// The chunk of `xs` along `dim` for the rank of `comm`, the size of the dimension must be
+/// divisible by the world size.
+pub fn shard_sequence<D: candle::shape::Dim>(
+    xs: &Tensor,
+    dim: D,
+    comm: &dyn Communicator,
+) -> Result<Tensor> {
+    let dim = dim.to_index(xs.shape(), "shard_sequence")?;
+    let len = xs.dim(dim)?;
+    let world_size = comm.world_size();
+    if len % world_size != 0 {
+        candle::bail!("sequence length {len} is not divisible by the world size {world_size}")
+    }
+    let chunk = len / world_size;
+    xs.narrow(dim, comm.rank() * chunk, chunk)
+}
+
+/// The position of the first element of the chunk of this rank, for chunks of `chunk_len`
+/// positions.
+pub fn sequence_offset(chunk_len: usize, comm: &dyn Communicator) -> usize {
+    comm.rank() * chunk_len
+}
+
+// How the queries of this rank attend to the keys of the block owned by another rank.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum BlockMask {
+    Full,
+    Causal,
+    Skip,
+}
+
+fn block_mask(cfg: &RingAttentionConfig, rank: usize, src: usize) -> BlockMask {
+    if !cfg.causal || src < rank {
+        BlockMask::Full
+    } else if src == rank {
+        BlockMask::Causal
+    } else {
+        BlockMask::Skip
+    }
+}
+
+// Repeats the key and value heads to match the query heads, (b, h_kv, s, d) -> (b, h, s, d).
+fn repeat_kv(xs: &Tensor, n_rep: usize) -> Result<Tensor> {
+    if n_rep == 1 {
+        return Ok(xs.clone());
+    }
+    let (b, h_kv, s, d) = xs.dims4()?;
+    xs.unsqueeze(2)?
+        .broadcast_as((b, h_kv, n_rep, s, d))?
+        .reshape((b, h_kv * n_rep, s, d))
+}
+
+// The gradient of repeat_kv.
+fn sum_kv(xs: &Tensor, n_rep: usize) -> Result<Tensor> {
+    if n_rep == 1 {
+        return Ok(xs.clone());
+    }
+    let (b, h, s, d) = xs.dims4()?;
+    xs.reshape((b, h / n_rep, n_rep, s, d))?.sum(2)
+}
+
+fn received_kv(pending: PendingExchange) -> Result<(Tensor, Tensor)> {
+    match <[Tensor; 2]>::try_from(pending.wait()?) {
+        Ok([k, v]) => Ok((k, v)),
+        Err(tensors) => candle::bail!("expected two tensors from the ring, got {}", tensors.len()),
+    }
+}
+
+struct Ring<'a> {
+    comm: &'a dyn Communicator,
+    cfg: RingAttentionConfig,
+    n_rep: usize,
+}
+
+impl Ring<'_> {
+    fn scores(&self, q: &Tensor, k: &Tensor, mask: BlockMask) -> Result<Tensor> {
+        let scores = (q.matmul(&k.t()?)? * self.cfg.scale)?;
+        match mask {
+            BlockMask::Causal => apply_mask(&scores, &causal_mask(q.dim(2)?, q.device())?),
+            _ => Ok(scores),
+        }
+    }
+
+    // Returns the output and the log-sum-exp of the scores, all the tensors are in f32.
+    fn forward(&self, q: &Tensor, k: &Tensor, v: &Tensor) -> Result<(Tensor, Tensor)> {
+        let (rank, world_size) = (self.comm.rank(), self.comm.world_size());
+        let (b, h, s, d) = q.dims4()?;
+        let mut max = Tensor::full(f32::NEG_INFINITY, (b, h, s, 1), q.device())?;
+        let mut sum = Tensor::zeros((b, h, s, 1), DType::F32, q.device())?;
+        let mut acc = Tensor::zeros((b, h, s, d), DType::F32, q.device())?;
+        let (mut k, mut v) = (k.clone(), v.clone());
+        let mut pending = None;
+        for step in 0..world_size {
+            if let Some(pending) = pending.take() {
+                (k, v) = received_kv(pending)?
+            }
+            if step + 1 < world_size {
+                pending = Some(self.comm.ring_exchange(&[&k, &v])?)
+            }
+            let src = (rank + world_size - step) % world_size;
+            let mask = block_mask(&self.cfg, rank, src);
+            if mask == BlockMask::Skip {
+                continue;
+            }
+            let (k, v) = (repeat_kv(&k, self.n_rep)?, repeat_kv(&v, self.n_rep)?);
+            let scores = self.scores(q, &k, mask)?;
+            // The block of the rank itself comes first and has no fully masked rows, so the
+            // running max is always finite here.
+            let new_max = max.maximum(&scores.max_keepdim(D::Minus1)?)?;
+            let alpha = (max - &new_max)?.exp()?;
+            let p = scores.broadcast_sub(&new_max)?.exp()?;
+            sum = (sum.mul(&alpha)? + p.sum_keepdim(D::Minus1)?)?;
+            acc = (acc.broadcast_mul(&alpha)? + p.matmul(&v)?)?;
+            max = new_max;
+        }
+        let out = acc.broadcast_div(&sum)?;
+        let lse = (max + sum.log()?)?;
+        Ok((out, lse))
+    }
+
+    fn backward(
+        &self,
+        q: &Tensor,
+        k: &Tensor,
+        v: &Tensor,
+        out: &Tensor,
+        lse: &Tensor,
+        grad: &Tensor,
+    ) -> Result<(Tensor, Tensor, Tensor)> {
+        let (rank, world_size) = (self.comm.rank(), self.comm.world_size());
+        let delta = (grad * out)?.sum_keepdim(D::Minus1)?;
+        let mut dq = q.zeros_like()?;
+        let mut dk = k.zeros_like()?;
+        let mut dv = v.zeros_like()?;
+        let (mut k, mut v) = (k.clone(), v.clone());
+        let mut pending = None;
+        for step in 0..world_size {
+            if let Some(pending) = pending.take() {
+                (k, v) = received_kv(pending)?
+            }
+            if step + 1 < world_size {
+                pending = Some(self.comm.ring_exchange(&[&k, &v])?)
+            }
+            let src = (rank + world_size - step) % world_size;
+            let mask = block_mask(&self.cfg, rank, src);
+            if mask != BlockMask::Skip {
+                let (k, v) = (repeat_kv(&k, self.n_rep)?, repeat_kv(&v, self.n_rep)?);
+                let p = self.scores(q, &k, mask)?.broadcast_sub(lse)?.exp()?;
+                let dv_block = p.t()?.matmul(grad)?;
+                let dp = grad.matmul(&v.t()?)?;
+                let ds = (p * dp.broadcast_sub(&delta)?)?;
+                dq = (dq + (ds.matmul(&k)? * self.cfg.scale)?)?;
+                let dk_block = (ds.t()?.matmul(q)? * self.cfg.scale)?;
+                dk = (dk + sum_kv(&dk_block, self.n_rep)?)?;
+                dv = (dv + sum_kv(&dv_block, self.n_rep)?)?;
+            }
+            // The gradients follow their block, the last exchange brings them back to the rank
+            // owning the block.
+            (dk, dv) = received_kv(self.comm.ring_exchange(&[&dk, &dv])?)?;
+        }
+        Ok((dq, dk, dv))
+    }
+}
+
+// The op recording the ring attention in the graph, the output is computed beforehand with
+// tensor ops as the forward pass needs the communicator.
+struct RingAttention {
+    comm: Arc<dyn Communicator>,
+    cfg: RingAttentionConfig,
+    n_rep: usize,
+    out: Tensor,
+    lse: Tensor,
+}
+
+impl RingAttention {
+    fn ring(&self) -> Ring<'_> {
+        Ring {
+            comm: self.comm.as_ref(),
+            cfg: self.cfg,
+            n_rep: self.n_rep,
+        }
+    }
+}
+
+impl CustomOp3 for RingAttention {
+    fn name(&self) -> &'static str {
+        "ring-attention"
+    }
+
+    fn cpu_fwd(
+        &self,
+        _: &CpuStorage,
+        _: &Layout,
+        _: &CpuStorage,
+        _: &Layout,
+        _: &CpuStorage,
+        _: &Layout,
+    ) -> Result<(CpuStorage, Shape)> {
+        use candle::backend::BackendStorage;
+        let (storage, layout) = self.out.storage_and_layout();
+        match &*storage {
+            Storage::Cpu(s) => Ok((s.try_clone(layout)?, layout.shape().clone())),
+            _ => candle::bail!("ring attention output on an unexpected device"),
+        }
+    }
+
+    #[cfg(feature = "cuda")]
+    fn cuda_fwd(
+        &self,
+        _: &candle::CudaStorage,
+        _: &Layout,
+        _: &candle::CudaStorage,
+        _: &Layout,
+        _: &candle::CudaStorage,
+        _: &Layout,
+    ) -> Result<(candle::CudaStorage, Shape)> {
+        use candle::backend::BackendStorage;
+        let (storage, layout) = self.out.storage_and_layout();
+        match &*storage {
+            Storage::Cuda(s) => Ok((s.try_clone(layout)?, layout.shape().clone())),
+            _ => candle::bail!("ring attention output on an unexpected device"),
+        }
+    }
+
+    #[cfg(feature = "metal")]
+    fn metal_fwd(
+        &self,
+        _: &candle::MetalStorage,
+        _: &Layout,
+        _: &candle::MetalStorage,
+        _: &Layout,
+        _: &candle::MetalStorage,
+        _: &Layout,
+    ) -> Result<(candle::MetalStorage, Shape)> {
+        use candle::backend::BackendStorage;
+        let (storage, layout) = self.out.storage_and_layout();
+        match &*storage {
+            Storage::Metal(s) => Ok((s.try_clone(layout)?, layout.shape().clone())),
+            _ => candle::bail!("ring attention output on an unexpected device"),
+        }
+    }
+
+    fn bwd(
+        &self,
+        q: &Tensor,
+        k: &Tensor,
+        v: &Tensor,
+        _res: &Tensor,
+        grad: &Tensor,
+    ) -> Result<(Option<Tensor>, Option<Tensor>, Option<Tensor>)> {
+        let dtype = q.dtype();
+        let f32 = |t: &Tensor| t.to_dtype(DType::F32);
+        let (dq, dk, dv) = self.ring().backward(
+            &f32(q)?,
+            &f32(k)?,
+            &f32(v)?,
+            &f32(&self.out)?,
+            &self.lse,
+            &f32(grad)?,
+        )?;
+        Ok((
+            Some(dq.to_dtype(dtype)?),
+            Some(dk.to_dtype(dtype)?),
+            Some(dv.to_dtype(dtype)?),
+        ))
+    }
+}
+
+/// The attention of the local queries over the keys and values of all the ranks of `comm`.
+///
+/// `q` has shape `(batch, heads, chunk, head_dim)` and `k`, `v` the shape `(batch, kv_heads,
+/// chunk, head_dim)`, `heads` being a multiple of `kv_heads`. All the ranks must use the same
+/// shapes, the chunks being ordered by rank. The computations are done in f32 and the result
+/// has the dtype of `q`.
+pub fn ring_attention(
+    q: &Tensor,
+    k: &Tensor,
+    v: &Tensor,
+    comm: &Arc<dyn Communicator>,
+    cfg: RingAttentionConfig,
+) -> Result<Tensor> {
+    let (_, heads, chunk, _) = q.dims4()?;
+    let (_, kv_heads, kv_chunk, _) = k.dims4()?;
+    if k.shape() != v.shape() || kv_chunk != chunk || kv_heads == 0 || heads % kv_heads != 0 {
+        candle::bail!(
+            "ring attention shape mismatch, q {:?}, k {:?}, v {:?}",
+            q.shape(),
+            k.shape(),
+            v.shape()
+        )
+    }
+    let n_rep = heads / kv_heads;
+    let ring = Ring {
+        comm: comm.as_ref(),
+        cfg,
+        n_rep,
+    };
+    let f32 = |t: &Tensor| t.detach().to_dtype(DType::F32)?.contiguous();
+    let (out, lse) = ring.forward(&f32(q)?, &f32(k)?, &f32(v)?)?;
+    let dtype = q.dtype();
+    let op = RingAttention {
+        comm: comm.clone(),
+        cfg,
+        n_rep,
+        out: out.to_dtype(dtype)?.contiguous()?,
+        lse,
+    };
+    q.apply_op3(k, v, op)
+}
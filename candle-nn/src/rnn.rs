@@ -44,6 +44,61 @@ pub trait RNN {
 
     /// Converts a sequence of state to a tensor.
     fn states_to_tensor(&self, states: &[Self::State]) -> Result<Tensor>;
+
+    /// Applies the recurrent network to variable length sequences, only the valid time steps
+    /// of each sequence are processed, see [`PackedSequence`].
+    ///
+    /// `init_state` has one element per sequence in the original batch order. Returns the
+    /// packed outputs and the final state of each sequence, i.e. the state after its last
+    /// valid time step, in the original batch order.
+    fn seq_packed(
+        &self,
+        input: &PackedSequence,
+        init_state: &Self::State,
+    ) -> Result<(PackedSequence, Self::State)>
+    where
+        Self::State: BatchState,
+    {
+        seq_packed_steps(
+            input,
+            init_state,
+            |xs, state| self.step(xs, state),
+            |state| self.states_to_tensor(std::slice::from_ref(state)),
+        )
+    }
+}
+
+// The loop of [`RNN::seq_packed`] with the steps and the conversion of the states to outputs
+// given as closures.
+fn seq_packed_steps<S: BatchState>(
+    input: &PackedSequence,
+    init_state: &S,
+    mut step: impl FnMut(&Tensor, &S) -> Result<S>,
+    to_tensor: impl Fn(&S) -> Result<Tensor>,
+) -> Result<(PackedSequence, S)> {
+    let mut state = init_state.select_batch(&input.sorted_indices_tensor()?)?;
+    let mut prev_bs = input.batch_size();
+    // The final states of the sequences that already ended, the shortest ones last.
+    let mut finished = vec![];
+    let mut outputs = Vec::with_capacity(input.batch_sizes().len());
+    let mut offset = 0;
+    for &bs in input.batch_sizes() {
+        if bs < prev_bs {
+            finished.push(state.narrow_batch(bs, prev_bs - bs)?);
+            state = state.narrow_batch(0, bs)?;
+        }
+        let xs = input.data().narrow(0, offset, bs)?;
+        state = step(&xs, &state)?;
+        let ys = to_tensor(&state)?;
+        outputs.push(ys.squeeze(1)?);
+        offset += bs;
+        prev_bs = bs;
+    }
+    finished.push(state);
+    finished.reverse();
+    let last = BatchState::cat_batch(&finished)?;
+    let last = last.select_batch(&input.unsorted_indices_tensor()?)?;
+    Ok((input.with_data(Tensor::cat(&outputs, 0)?)?, last))
 }
 
 /// The state for a LSTM network, this contains two tensors.
@@ -70,12 +125,42 @@ impl LSTMState {
     }
 }
 
-#[derive(Debug, Clone, Copy)]
+impl BatchState for LSTMState {
+    fn narrow_batch(&self, start: usize, len: usize) -> Result<Self> {
+        let h = self.h.narrow(0, start, len)?;
+        let c = self.c.narrow(0, start, len)?;
+        Ok(Self { h, c })
+    }
+
+    fn cat_batch(states: &[Self]) -> Result<Self> {
+        let h = Tensor::cat(&states.iter().map(|s| &s.h).collect::<Vec<_>>(), 0)?;
+        let c = Tensor::cat(&states.iter().map(|s| &s.c).collect::<Vec<_>>(), 0)?;
+        Ok(Self { h, c })
+    }
+
+    fn select_batch(&self, indexes: &Tensor) -> Result<Self> {
+        let h = self.h.index_select(indexes, 0)?;
+        let c = self.c.index_select(indexes, 0)?;
+        Ok(Self { h, c })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Direction {
     Forward,
     Backward,
 }
 
+impl Direction {
+    // The suffix of the PyTorch weight names for the layer `layer_idx`, e.g. `l1_reverse`.
+    fn suffix(&self, layer_idx: usize) -> String {
+        match self {
+            Direction::Forward => format!("l{layer_idx}"),
+            Direction::Backward => format!("l{layer_idx}_reverse"),
+        }
+    }
+}
+
 #[allow(clippy::upper_case_acronyms)]
 #[derive(Debug, Clone, Copy)]
 pub struct LSTMConfig {
@@ -137,35 +222,27 @@ impl LSTM {
         config: LSTMConfig,
         vb: crate::VarBuilder,
     ) -> Result<Self> {
-        let layer_idx = config.layer_idx;
-        let direction_str = match config.direction {
-            Direction::Forward => "",
-            Direction::Backward => "_reverse",
-        };
+        let suffix = config.direction.suffix(config.layer_idx);
         let w_ih = vb.get_with_hints(
             (4 * hidden_dim, in_dim),
-            &format!("weight_ih_l{layer_idx}{direction_str}"), // Only a single layer is supported.
+            &format!("weight_ih_{suffix}"),
             config.w_ih_init,
         )?;
         let w_hh = vb.get_with_hints(
             (4 * hidden_dim, hidden_dim),
-            &format!("weight_hh_l{layer_idx}{direction_str}"), // Only a single layer is supported.
+            &format!("weight_hh_{suffix}"),
             config.w_hh_init,
         )?;
         let b_ih = match config.b_ih_init {
-            Some(init) => Some(vb.get_with_hints(
-                4 * hidden_dim,
-                &format!("bias_ih_l{layer_idx}{direction_str}"),
-                init,
-            )?),
+            Some(init) => {
+                Some(vb.get_with_hints(4 * hidden_dim, &format!("bias_ih_{suffix}"), init)?)
+            }
             None => None,
         };
         let b_hh = match config.b_hh_init {
-            Some(init) => Some(vb.get_with_hints(
-                4 * hidden_dim,
-                &format!("bias_hh_l{layer_idx}{direction_str}"),
-                init,
-            )?),
+            Some(init) => {
+                Some(vb.get_with_hints(4 * hidden_dim, &format!("bias_hh_{suffix}"), init)?)
+            }
             None => None,
         };
         Ok(Self {
@@ -246,12 +323,29 @@ pub struct GRUState {
 }
 
 impl GRUState {
-    /// The hidden state vector, which is also the output of the LSTM.
+    /// The hidden state vector, which is also the output of the GRU.
     pub fn h(&self) -> &Tensor {
         &self.h
     }
 }
 
+impl BatchState for GRUState {
+    fn narrow_batch(&self, start: usize, len: usize) -> Result<Self> {
+        let h = self.h.narrow(0, start, len)?;
+        Ok(Self { h })
+    }
+
+    fn cat_batch(states: &[Self]) -> Result<Self> {
+        let h = Tensor::cat(&states.iter().map(|s| &s.h).collect::<Vec<_>>(), 0)?;
+        Ok(Self { h })
+    }
+
+    fn select_batch(&self, indexes: &Tensor) -> Result<Self> {
+        let h = self.h.index_select(indexes, 0)?;
+        Ok(Self { h })
+    }
+}
+
 #[allow(clippy::upper_case_acronyms)]
 #[derive(Debug, Clone, Copy)]
 pub struct GRUConfig {
@@ -259,6 +353,8 @@ pub struct GRUConfig {
     pub w_hh_init: super::Init,
     pub b_ih_init: Option<super::Init>,
     pub b_hh_init: Option<super::Init>,
+    pub layer_idx: usize,
+    pub direction: Direction,
 }
 
 impl Default for GRUConfig {
@@ -268,6 +364,8 @@ impl Default for GRUConfig {
             w_hh_init: super::init::DEFAULT_KAIMING_UNIFORM,
             b_ih_init: Some(super::Init::Const(0.)),
             b_hh_init: Some(super::Init::Const(0.)),
+            layer_idx: 0,
+            direction: Direction::Forward,
         }
     }
 }
@@ -279,6 +377,8 @@ impl GRUConfig {
             w_hh_init: super::init::DEFAULT_KAIMING_UNIFORM,
             b_ih_init: None,
             b_hh_init: None,
+            layer_idx: 0,
+            direction: Direction::Forward,
         }
     }
 }
@@ -307,22 +407,27 @@ impl GRU {
         config: GRUConfig,
         vb: crate::VarBuilder,
     ) -> Result<Self> {
+        let suffix = config.direction.suffix(config.layer_idx);
         let w_ih = vb.get_with_hints(
             (3 * hidden_dim, in_dim),
-            "weight_ih_l0", // Only a single layer is supported.
+            &format!("weight_ih_{suffix}"),
             config.w_ih_init,
         )?;
         let w_hh = vb.get_with_hints(
             (3 * hidden_dim, hidden_dim),
-            "weight_hh_l0", // Only a single layer is supported.
+            &format!("weight_hh_{suffix}"),
             config.w_hh_init,
         )?;
         let b_ih = match config.b_ih_init {
-            Some(init) => Some(vb.get_with_hints(3 * hidden_dim, "bias_ih_l0", init)?),
+            Some(init) => {
+                Some(vb.get_with_hints(3 * hidden_dim, &format!("bias_ih_{suffix}"), init)?)
+            }
             None => None,
         };
         let b_hh = match config.b_hh_init {
-            Some(init) => Some(vb.get_with_hints(3 * hidden_dim, "bias_hh_l0", init)?),
+            Some(init) => {
+                Some(vb.get_with_hints(3 * hidden_dim, &format!("bias_hh_{suffix}"), init)?)
+            }
             None => None,
         };
         Ok(Self {
@@ -383,6 +488,582 @@ impl RNN for GRU {
 
     fn states_to_tensor(&self, states: &[Self::State]) -> Result<Tensor> {
         let states = states.iter().map(|s| s.h.clone()).collect::<Vec<_>>();
-        Tensor::cat(&states, 1)
+        Tensor::stack(&states, 1)
+    }
+}
+
+impl crate::layer::Layer for LSTM {
+    fn visit_parameters(&mut self, f: &mut crate::layer::TensorFn) -> Result<()> {
+        let suffix = self.config.direction.suffix(self.config.layer_idx);
+        visit_rnn_parameters(
+            &suffix,
+            [
+                Some(&mut self.w_ih),
+                Some(&mut self.w_hh),
+                self.b_ih.as_mut(),
+                self.b_hh.as_mut(),
+            ],
+            f,
+        )
+    }
+}
+
+impl crate::layer::Layer for GRU {
+    fn visit_parameters(&mut self, f: &mut crate::layer::TensorFn) -> Result<()> {
+        let suffix = self.config.direction.suffix(self.config.layer_idx);
+        visit_rnn_parameters(
+            &suffix,
+            [
+                Some(&mut self.w_ih),
+                Some(&mut self.w_hh),
+                self.b_ih.as_mut(),
+                self.b_hh.as_mut(),
+            ],
+            f,
+        )
+    }
+}
+
+// Visits the weights and biases of a recurrent layer with their PyTorch names.
+fn visit_rnn_parameters(
+    suffix: &str,
+    tensors: [Option<&mut Tensor>; 4],
+    f: &mut crate::layer::TensorFn,
+) -> Result<()> {
+    let names = ["weight_ih", "weight_hh", "bias_ih", "bias_hh"];
+    for (name, t) in names.iter().zip(tensors) {
+        if let Some(t) = t {
+            f(&format!("{name}_{suffix}"), t)?
+        }
+    }
+    Ok(())
+}
+
+/// The options of a [`StackedRNN`], with the same meaning as for the PyTorch recurrent layers.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct StackedConfig {
+    pub num_layers: usize,
+    pub bidirectional: bool,
+    /// The dropout probability applied to the outputs of each layer but the last one, in
+    /// training mode.
+    pub dropout: f32,
+}
+
+impl Default for StackedConfig {
+    fn default() -> Self {
+        Self {
+            num_layers: 1,
+            bidirectional: false,
+            dropout: 0.,
+        }
+    }
+}
+
+impl StackedConfig {
+    pub fn with_num_layers(mut self, num_layers: usize) -> Self {
+        self.num_layers = num_layers;
+        self
+    }
+
+    pub fn with_bidirectional(mut self, bidirectional: bool) -> Self {
+        self.bidirectional = bidirectional;
+        self
+    }
+
+    pub fn with_dropout(mut self, dropout: f32) -> Self {
+        self.dropout = dropout;
+        self
+    }
+
+    pub fn num_directions(&self) -> usize {
+        if self.bidirectional {
+            2
+        } else {
+            1
+        }
+    }
+}
+
+// Reverses `xs` along the time dimension.
+fn reverse_seq(xs: &Tensor) -> Result<Tensor> {
+    let seq_len = xs.dim(1)?;
+    let indexes: Vec<u32> = (0..seq_len as u32).rev().collect();
+    let indexes = Tensor::new(indexes, xs.device())?;
+    xs.index_select(&indexes, 1)
+}
+
+/// Multiple recurrent layers, each layer getting the outputs of the previous one, optionally
+/// in both directions. The weights use the names of the PyTorch layers, e.g. `weight_ih_l1` or
+/// `bias_hh_l0_reverse`.
+///
+/// The state is made of one state per layer and direction, in the order of the PyTorch hidden
+/// states: the forward then backward states of the first layer, of the second layer, etc. The
+/// states of the backward directions returned by [`RNN::seq_init`] for a time step are the ones
+/// after processing the sequence from its end to this step, [`Self::last_state`] returns the
+/// final states. The outputs of the directions are concatenated on the feature dimension.
+///
+/// A bidirectional network cannot be run step by step, [`RNN::step`] returns an error.
+#[derive(Clone, Debug)]
+pub struct StackedRNN<R> {
+    layers: Vec<R>,
+    config: StackedConfig,
+    // The mode used by [`crate::layer::Layer::forward_mode`], the dropout is only applied when
+    // training.
+    training: bool,
+}
+
+impl<R: RNN> StackedRNN<R> {
+    /// Creates the layers with `f`, which gets the input dimension, the layer index and the
+    /// direction of each layer.
+    pub fn new<F>(in_dim: usize, hidden_dim: usize, config: StackedConfig, mut f: F) -> Result<Self>
+    where
+        F: FnMut(usize, usize, Direction) -> Result<R>,
+    {
+        if config.num_layers == 0 {
+            candle::bail!("a stacked rnn needs at least one layer")
+        }
+        let mut layers = vec![];
+        for layer_idx in 0..config.num_layers {
+            let in_dim = if layer_idx == 0 {
+                in_dim
+            } else {
+                hidden_dim * config.num_directions()
+            };
+            layers.push(f(in_dim, layer_idx, Direction::Forward)?);
+            if config.bidirectional {
+                layers.push(f(in_dim, layer_idx, Direction::Backward)?);
+            }
+        }
+        Ok(Self {
+            layers,
+            config,
+            training: false,
+        })
+    }
+
+    pub fn config(&self) -> &StackedConfig {
+        &self.config
+    }
+
+    /// The layers in the order of the states.
+    pub fn layers(&self) -> &[R] {
+        &self.layers
+    }
+
+    /// The final state of each layer and direction from the states returned by
+    /// [`RNN::seq_init`], i.e. the last state for the forward directions and the first one for
+    /// the backward directions.
+    pub fn last_state(&self, states: &[Vec<R::State>]) -> Result<Vec<R::State>> {
+        let (first, last) = match (states.first(), states.last()) {
+            (Some(first), Some(last)) => (first, last),
+            _ => candle::bail!("no states for an empty sequence"),
+        };
+        let dirs = self.config.num_directions();
+        let states = (0..self.layers.len())
+            .map(|i| {
+                if i % dirs == 0 {
+                    last[i].clone()
+                } else {
+                    first[i].clone()
+                }
+            })
+            .collect();
+        Ok(states)
+    }
+
+    fn dropout(&self, xs: Tensor) -> Result<Tensor> {
+        if self.training && self.config.dropout > 0. {
+            crate::ops::dropout(&xs, self.config.dropout)
+        } else {
+            Ok(xs)
+        }
+    }
+}
+
+impl<R: RNN> RNN for StackedRNN<R> {
+    type State = Vec<R::State>;
+
+    fn zero_state(&self, batch_dim: usize) -> Result<Self::State> {
+        self.layers
+            .iter()
+            .map(|l| l.zero_state(batch_dim))
+            .collect()
+    }
+
+    fn step(&self, input: &Tensor, state: &Self::State) -> Result<Self::State> {
+        if self.config.bidirectional {
+            candle::bail!("a bidirectional rnn cannot be run step by step")
+        }
+        let mut xs = input.clone();
+        let mut next_state = Vec::with_capacity(self.layers.len());
+        for (i, (layer, state)) in self.layers.iter().zip(state.iter()).enumerate() {
+            if i > 0 {
+                xs = self.dropout(xs)?
+            }
+            let state = layer.step(&xs, state)?;
+            xs = layer
+                .states_to_tensor(std::slice::from_ref(&state))?
+                .squeeze(1)?;
+            next_state.push(state);
+        }
+        Ok(next_state)
+    }
+
+    fn seq_init(&self, input: &Tensor, init_state: &Self::State) -> Result<Vec<Self::State>> {
+        let seq_len = input.dim(1)?;
+        let dirs = self.config.num_directions();
+        let mut xs = input.clone();
+        // The states of each layer and direction for all the time steps.
+        let mut layer_states = Vec::with_capacity(self.layers.len());
+        for layer_idx in 0..self.config.num_layers {
+            if layer_idx > 0 {
+                xs = self.dropout(xs)?
+            }
+            let mut outputs = Vec::with_capacity(dirs);
+            for dir in 0..dirs {
+                let i = layer_idx * dirs + dir;
+                let layer = &self.layers[i];
+                let states = if dir == 0 {
+                    layer.seq_init(&xs, &init_state[i])?
+                } else {
+                    let mut states = layer.seq_init(&reverse_seq(&xs)?, &init_state[i])?;
+                    states.reverse();
+                    states
+                };
+                outputs.push(layer.states_to_tensor(&states)?);
+                layer_states.push(states);
+            }
+            xs = Tensor::cat(&outputs, 2)?
+        }
+        let states = (0..seq_len)
+            .map(|t| layer_states.iter().map(|s| s[t].clone()).collect())
+            .collect();
+        Ok(states)
+    }
+
+    /// The backward directions process each sequence from its last valid time step.
+    fn seq_packed(
+        &self,
+        input: &PackedSequence,
+        init_state: &Self::State,
+    ) -> Result<(PackedSequence, Self::State)>
+    where
+        Self::State: BatchState,
+    {
+        // Only the states of the stack are known to be `BatchState`, so each layer runs on a
+        // stack of one state.
+        let packed = |layer: &R, xs: &PackedSequence, init_state: &R::State| {
+            let (ys, mut state) = seq_packed_steps(
+                xs,
+                &vec![init_state.clone()],
+                |xs, state| Ok(vec![layer.step(xs, &state[0])?]),
+                |state| layer.states_to_tensor(state),
+            )?;
+            Ok::<_, candle::Error>((ys, state.remove(0)))
+        };
+        let dirs = self.config.num_directions();
+        let mut xs = input.clone();
+        let mut last = Vec::with_capacity(self.layers.len());
+        for layer_idx in 0..self.config.num_layers {
+            if layer_idx > 0 {
+                xs = xs.with_data(self.dropout(xs.data().clone())?)?
+            }
+            let mut outputs = Vec::with_capacity(dirs);
+            for dir in 0..dirs {
+                let i = layer_idx * dirs + dir;
+                let layer = &self.layers[i];
+                let (ys, state) = if dir == 0 {
+                    packed(layer, &xs, &init_state[i])?
+                } else {
+                    let (ys, state) = packed(layer, &xs.reverse_sequences()?, &init_state[i])?;
+                    (ys.reverse_sequences()?, state)
+                };
+                outputs.push(ys.data().clone());
+                last.push(state);
+            }
+            xs = xs.with_data(Tensor::cat(&outputs, 1)?)?
+        }
+        Ok((xs, last))
+    }
+
+    fn states_to_tensor(&self, states: &[Self::State]) -> Result<Tensor> {
+        let dirs = self.config.num_directions();
+        let top = self.layers.len() - dirs;
+        let outputs = (top..self.layers.len())
+            .map(|i| {
+                let layer_states: Vec<_> = states.iter().map(|s| s[i].clone()).collect();
+                self.layers[i].states_to_tensor(&layer_states)
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Tensor::cat(&outputs, 2)
+    }
+}
+
+/// The layers are visited without prefix as their parameters have distinct PyTorch names.
+impl<R: crate::layer::Layer> crate::layer::Layer for StackedRNN<R> {
+    fn visit_parameters(&mut self, f: &mut crate::layer::TensorFn) -> Result<()> {
+        for layer in self.layers.iter_mut() {
+            layer.visit_parameters(f)?
+        }
+        Ok(())
+    }
+
+    fn set_own_training(&mut self, training: bool) {
+        self.training = training
+    }
+
+    fn is_training(&self) -> Option<bool> {
+        Some(self.training)
+    }
+}
+
+/// Creates a multi-layer or bidirectional LSTM, the `layer_idx` and `direction` fields of
+/// `config` are ignored.
+pub fn stacked_lstm(
+    in_dim: usize,
+    hidden_dim: usize,
+    config: LSTMConfig,
+    stacked: StackedConfig,
+    vb: crate::VarBuilder,
+) -> Result<StackedRNN<LSTM>> {
+    StackedRNN::new(
+        in_dim,
+        hidden_dim,
+        stacked,
+        |in_dim, layer_idx, direction| {
+            let config = LSTMConfig {
+                layer_idx,
+                direction,
+                ..config
+            };
+            LSTM::new(in_dim, hidden_dim, config, vb.clone())
+        },
+    )
+}
+
+/// Creates a multi-layer or bidirectional GRU, the `layer_idx` and `direction` fields of
+/// `config` are ignored.
+pub fn stacked_gru(
+    in_dim: usize,
+    hidden_dim: usize,
+    config: GRUConfig,
+    stacked: StackedConfig,
+    vb: crate::VarBuilder,
+) -> Result<StackedRNN<GRU>> {
+    StackedRNN::new(
+        in_dim,
+        hidden_dim,
+        stacked,
+        |in_dim, layer_idx, direction| {
+            let config = GRUConfig {
+                layer_idx,
+                direction,
+                ..config
+            };
+            GRU::new(in_dim, hidden_dim, config, vb.clone())
+        },
+    )
+}
+
+/// The states of a recurrent network that can be split and merged along their batch
+/// dimension, which [`RNN::seq_packed`] needs to drop the sequences that ended.
+pub trait BatchState: Sized + Clone {
+    /// The states of the `len` batch elements from `start`.
+    fn narrow_batch(&self, start: usize, len: usize) -> Result<Self>;
+
+    /// Concatenates the states along the batch dimension.
+    fn cat_batch(states: &[Self]) -> Result<Self>;
+
+    /// The states of the batch elements at `indexes`, a tensor of u32.
+    fn select_batch(&self, indexes: &Tensor) -> Result<Self>;
+}
+
+/// The states of a [`StackedRNN`], one per layer and direction.
+impl<S: BatchState> BatchState for Vec<S> {
+    fn narrow_batch(&self, start: usize, len: usize) -> Result<Self> {
+        self.iter().map(|s| s.narrow_batch(start, len)).collect()
+    }
+
+    fn cat_batch(states: &[Self]) -> Result<Self> {
+        let num_states = states.first().map_or(0, |s| s.len());
+        (0..num_states)
+            .map(|i| {
+                let states: Vec<S> = states.iter().map(|s| s[i].clone()).collect();
+                S::cat_batch(&states)
+            })
+            .collect()
+    }
+
+    fn select_batch(&self, indexes: &Tensor) -> Result<Self> {
+        self.iter().map(|s| s.select_batch(indexes)).collect()
+    }
+}
+
+/// A batch of variable length sequences without padding, as in PyTorch.
+///
+/// The sequences are sorted by decreasing length and `data` holds the valid elements of the
+/// first time step of all the sequences, then of the second time step of the sequences that are
+/// long enough, etc. `batch_sizes[t]` is the number of sequences with more than `t` elements.
+#[derive(Debug, Clone)]
+pub struct PackedSequence {
+    data: Tensor,
+    batch_sizes: Vec<usize>,
+    // The index in the original batch of each sorted sequence.
+    sorted_indices: Vec<usize>,
+}
+
+impl PackedSequence {
+    /// The packed elements with dimensions `[sum of lengths, features]`.
+    pub fn data(&self) -> &Tensor {
+        &self.data
+    }
+
+    pub fn batch_sizes(&self) -> &[usize] {
+        &self.batch_sizes
+    }
+
+    /// The index in the original batch of each sequence, by decreasing length.
+    pub fn sorted_indices(&self) -> &[usize] {
+        &self.sorted_indices
+    }
+
+    /// The number of sequences.
+    pub fn batch_size(&self) -> usize {
+        self.sorted_indices.len()
+    }
+
+    /// The length of each sequence in the original batch order.
+    pub fn lengths(&self) -> Vec<usize> {
+        let mut lengths = vec![0; self.batch_size()];
+        for (i, &b) in self.sorted_indices.iter().enumerate() {
+            lengths[b] = self.batch_sizes.iter().filter(|&&bs| bs > i).count()
+        }
+        lengths
+    }
+
+    /// The same sequences with other packed elements, e.g. the outputs of a layer.
+    pub fn with_data(&self, data: Tensor) -> Result<Self> {
+        if data.dim(0)? != self.data.dim(0)? {
+            candle::bail!(
+                "packed data {:?} does not match the {} packed elements",
+                data.shape(),
+                self.data.dim(0)?
+            )
+        }
+        Ok(Self {
+            data,
+            batch_sizes: self.batch_sizes.clone(),
+            sorted_indices: self.sorted_indices.clone(),
+        })
+    }
+
+    fn sorted_indices_tensor(&self) -> Result<Tensor> {
+        let indexes: Vec<u32> = self.sorted_indices.iter().map(|&i| i as u32).collect();
+        Tensor::new(indexes, self.data.device())
+    }
+
+    fn unsorted_indices_tensor(&self) -> Result<Tensor> {
+        let mut indexes = vec![0u32; self.batch_size()];
+        for (i, &b) in self.sorted_indices.iter().enumerate() {
+            indexes[b] = i as u32
+        }
+        Tensor::new(indexes, self.data.device())
+    }
+
+    // The offset in `data` of each time step.
+    fn offsets(&self) -> Vec<usize> {
+        let mut offsets = Vec::with_capacity(self.batch_sizes.len());
+        let mut offset = 0;
+        for &bs in self.batch_sizes.iter() {
+            offsets.push(offset);
+            offset += bs
+        }
+        offsets
+    }
+
+    // Reverses the valid elements of each sequence.
+    fn reverse_sequences(&self) -> Result<Self> {
+        let offsets = self.offsets();
+        let lengths: Vec<usize> = (0..self.batch_size())
+            .map(|i| self.batch_sizes.iter().filter(|&&bs| bs > i).count())
+            .collect();
+        let mut indexes = Vec::with_capacity(self.data.dim(0)?);
+        for (t, &bs) in self.batch_sizes.iter().enumerate() {
+            for (i, len) in lengths.iter().enumerate().take(bs) {
+                indexes.push((offsets[len - 1 - t] + i) as u32)
+            }
+        }
+        let indexes = Tensor::new(indexes, self.data.device())?;
+        self.with_data(self.data.index_select(&indexes, 0)?)
+    }
+}
+
+/// Packs the padded sequences of `input`, with dimensions `[batch_size, seq_len, features]`,
+/// that have the given lengths. The lengths do not have to be sorted.
+pub fn pack_padded_sequence(input: &Tensor, lengths: &[usize]) -> Result<PackedSequence> {
+    let (b_size, seq_len, _features) = input.dims3()?;
+    if lengths.len() != b_size {
+        candle::bail!(
+            "{} lengths for a batch of {b_size} sequences",
+            lengths.len()
+        )
+    }
+    if let Some(&len) = lengths.iter().find(|&&l| l == 0 || l > seq_len) {
+        candle::bail!("invalid sequence length {len}, the padded length is {seq_len}")
+    }
+    let mut sorted_indices: Vec<usize> = (0..b_size).collect();
+    sorted_indices.sort_by_key(|&i| std::cmp::Reverse(lengths[i]));
+    let max_len = lengths[sorted_indices[0]];
+    let batch_sizes: Vec<usize> = (0..max_len)
+        .map(|t| lengths.iter().filter(|&&l| l > t).count())
+        .collect();
+    let indexes: Vec<u32> = sorted_indices.iter().map(|&i| i as u32).collect();
+    let sorted = input.index_select(&Tensor::new(indexes, input.device())?, 0)?;
+    let steps = batch_sizes
+        .iter()
+        .enumerate()
+        .map(|(t, &bs)| sorted.narrow(0, 0, bs)?.i((.., t)))
+        .collect::<Result<Vec<_>>>()?;
+    Ok(PackedSequence {
+        data: Tensor::cat(&steps, 0)?,
+        batch_sizes,
+        sorted_indices,
+    })
+}
+
+/// Pads the packed sequences to a tensor with dimensions `[batch_size, seq_len, features]` in
+/// the original batch order, `seq_len` being `total_length` or the length of the longest
+/// sequence. Returns the padded tensor and the length of each sequence.
+pub fn pad_packed_sequence(
+    packed: &PackedSequence,
+    padding_value: f64,
+    total_length: Option<usize>,
+) -> Result<(Tensor, Vec<usize>)> {
+    let data = packed.data();
+    let (_, features) = data.dims2()?;
+    let b_size = packed.batch_size();
+    let max_len = packed.batch_sizes.len();
+    let seq_len = total_length.unwrap_or(max_len);
+    if seq_len < max_len {
+        candle::bail!("total length {seq_len} is smaller than the longest sequence {max_len}")
+    }
+    let padding = |n: usize| {
+        Tensor::full(padding_value, (n, features), data.device())?.to_dtype(data.dtype())
+    };
+    let mut steps = Vec::with_capacity(seq_len);
+    for (&bs, offset) in packed.batch_sizes.iter().zip(packed.offsets()) {
+        let step = data.narrow(0, offset, bs)?;
+        let step = if bs == b_size {
+            step
+        } else {
+            Tensor::cat(&[step, padding(b_size - bs)?], 0)?
+        };
+        steps.push(step)
+    }
+    for _ in max_len..seq_len {
+        steps.push(padding(b_size)?)
     }
+    let padded = Tensor::stack(&steps, 1)?;
+    let padded = padded.index_select(&packed.unsorted_indices_tensor()?, 0)?;
+    Ok((padded, packed.lengths()))
 }
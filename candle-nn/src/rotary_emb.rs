@@ -730,3 +730,427 @@ pub fn rope_thd(xs: &Tensor, cos: &Tensor, sin: &Tensor) -> Result<Tensor> {
     }
     xs.apply_op3_no_bwd(cos, sin, &RotaryEmbThd)
 }
+
+/// Rotary embeddings applied to the first `rot_dim` values of each head only, the other values
+/// are left as is, e.g. for the models using a `partial_rotary_factor`. The rotated values use
+/// the interleaved or the contiguous layout.
+#[derive(Debug, Clone)]
+struct RotaryEmbPartial {
+    interleaved: bool,
+}
+
+impl candle::CustomOp3 for RotaryEmbPartial {
+    fn name(&self) -> &'static str {
+        if self.interleaved {
+            "rotary-emb-int-partial"
+        } else {
+            "rotary-emb-partial"
+        }
+    }
+
+    fn cpu_fwd(
+        &self,
+        s1: &CpuStorage,
+        l1: &Layout,
+        s2: &CpuStorage,
+        l2: &Layout,
+        s3: &CpuStorage,
+        l3: &Layout,
+    ) -> Result<(CpuStorage, Shape)> {
+        fn inner<T: candle::WithDType + num_traits::Float>(
+            src: &[T],
+            l_src: &Layout,
+            cos: &[T],
+            l_cos: &Layout,
+            sin: &[T],
+            l_sin: &Layout,
+            interleaved: bool,
+        ) -> Result<(CpuStorage, Shape)> {
+            let src = match l_src.contiguous_offsets() {
+                None => candle::bail!("input src has to be contiguous"),
+                Some((o1, o2)) => &src[o1..o2],
+            };
+            let cos = match l_cos.contiguous_offsets() {
+                None => candle::bail!("input cos has to be contiguous"),
+                Some((o1, o2)) => &cos[o1..o2],
+            };
+            let sin = match l_sin.contiguous_offsets() {
+                None => candle::bail!("input sin has to be contiguous"),
+                Some((o1, o2)) => &sin[o1..o2],
+            };
+            let (b, h, t, d) = l_src.shape().dims4()?;
+            let half_rd = l_cos.dims()[1];
+            let el_count = b * h * t * d;
+            let mut dst = vec![T::zero(); el_count];
+            src.par_chunks(t * d)
+                .zip(dst.par_chunks_mut(t * d))
+                .for_each(|(src, dst)| {
+                    for i_t in 0..t {
+                        let (src, dst) = (&src[i_t * d..], &mut dst[i_t * d..(i_t + 1) * d]);
+                        for i_d in 0..half_rd {
+                            let (i1, i2) = if interleaved {
+                                (2 * i_d, 2 * i_d + 1)
+                            } else {
+                                (i_d, i_d + half_rd)
+                            };
+                            let i_cs = i_t * half_rd + i_d;
+                            dst[i1] = src[i1] * cos[i_cs] - src[i2] * sin[i_cs];
+                            dst[i2] = src[i1] * sin[i_cs] + src[i2] * cos[i_cs];
+                        }
+                        dst[2 * half_rd..].copy_from_slice(&src[2 * half_rd..d]);
+                    }
+                });
+            let storage = candle::WithDType::to_cpu_storage_owned(dst);
+            Ok((storage, (b, h, t, d).into()))
+        }
+
+        use candle::backend::BackendStorage;
+        use CpuStorage::{BF16, F16, F32, F64};
+        let i = self.interleaved;
+        match (s1, s2, s3) {
+            (BF16(s1), BF16(s2), BF16(s3)) => inner(s1, l1, s2, l2, s3, l3, i),
+            (F16(s1), F16(s2), F16(s3)) => inner(s1, l1, s2, l2, s3, l3, i),
+            (F32(s1), F32(s2), F32(s3)) => inner(s1, l1, s2, l2, s3, l3, i),
+            (F64(s1), F64(s2), F64(s3)) => inner(s1, l1, s2, l2, s3, l3, i),
+            _ => candle::bail!(
+                "unsupported dtype for rope {:?} {:?} {:?}",
+                s1.dtype(),
+                s2.dtype(),
+                s3.dtype()
+            ),
+        }
+    }
+
+    #[cfg(feature = "metal")]
+    fn metal_fwd(
+        &self,
+        src: &candle::MetalStorage,
+        l_src: &Layout,
+        cos: &candle::MetalStorage,
+        l_cos: &Layout,
+        sin: &candle::MetalStorage,
+        l_sin: &Layout,
+    ) -> Result<(candle::MetalStorage, Shape)> {
+        use candle::backend::BackendStorage;
+        let device = src.device();
+        let command_buffer = device.command_buffer()?;
+        let kernels = device.kernels();
+        if cos.dtype() != src.dtype() || sin.dtype() != src.dtype() {
+            candle::bail!(
+                "dtype mismatch in rope {:?} {:?} {:?}",
+                src.dtype(),
+                cos.dtype(),
+                sin.dtype()
+            )
+        }
+        let name = match (src.dtype(), self.interleaved) {
+            (candle::DType::F32, false) => "rope_partial_f32",
+            (candle::DType::F16, false) => "rope_partial_f16",
+            (candle::DType::BF16, false) => "rope_partial_bf16",
+            (candle::DType::F32, true) => "rope_i_partial_f32",
+            (candle::DType::F16, true) => "rope_i_partial_f16",
+            (candle::DType::BF16, true) => "rope_i_partial_bf16",
+            (dtype, _) => candle::bail!("rope-partial is not implemented for {dtype:?}"),
+        };
+        let (b, h, t, d) = l_src.shape().dims4()?;
+        let rot_dim = l_cos.dims()[1] * 2;
+        let el = b * h * t * d;
+        let output = device.new_buffer(el, src.dtype(), "rope-partial")?;
+        candle_metal_kernels::call_rope_partial(
+            device.metal_device(),
+            &command_buffer,
+            kernels,
+            name,
+            b * h,
+            t,
+            d,
+            rot_dim,
+            src.buffer(),
+            l_src.start_offset() * src.dtype().size_in_bytes(),
+            cos.buffer(),
+            l_cos.start_offset() * cos.dtype().size_in_bytes(),
+            sin.buffer(),
+            l_sin.start_offset() * sin.dtype().size_in_bytes(),
+            &output,
+        )
+        .map_err(candle::Error::wrap)?;
+        let out = candle::MetalStorage::new(output, device.clone(), el, src.dtype());
+        Ok((out, l_src.shape().clone()))
+    }
+}
+
+fn rope_partial_check(xs: &Tensor, cos: &Tensor, sin: &Tensor) -> Result<usize> {
+    let (_b_sz, _n_head, seq_len, n_embd) = xs.dims4()?;
+    let (cos_seq_len, cos_n_embd) = cos.dims2()?;
+    let (sin_seq_len, sin_n_embd) = sin.dims2()?;
+    if cos_n_embd * 2 > n_embd
+        || sin_n_embd != cos_n_embd
+        || seq_len > cos_seq_len
+        || seq_len > sin_seq_len
+    {
+        candle::bail!(
+            "inconsistent last dim size in rope {:?} {:?} {:?}",
+            xs.shape(),
+            cos.shape(),
+            sin.shape()
+        )
+    }
+    Ok(cos_n_embd * 2)
+}
+
+fn rope_partial_impl(xs: &Tensor, cos: &Tensor, sin: &Tensor, interleaved: bool) -> Result<Tensor> {
+    let rot_dim = rope_partial_check(xs, cos, sin)?;
+    if !xs.is_contiguous() {
+        candle::bail!("xs has to be contiguous in rope")
+    }
+    let seq_len = xs.dim(2)?;
+    // The cos and sin tables only have to cover the sequence, the kernels expect exactly
+    // `seq_len` rows.
+    let cos = cos.narrow(0, 0, seq_len)?.contiguous()?;
+    let sin = sin.narrow(0, 0, seq_len)?.contiguous()?;
+    if xs.device().is_cuda() {
+        let d = xs.dim(D::Minus1)?;
+        let rot = xs.narrow(D::Minus1, 0, rot_dim)?.contiguous()?;
+        let rot = if interleaved {
+            rope_i(&rot, &cos, &sin)?
+        } else {
+            rope(&rot, &cos, &sin)?
+        };
+        if rot_dim == d {
+            return Ok(rot);
+        }
+        let pass = xs.narrow(D::Minus1, rot_dim, d - rot_dim)?;
+        return Tensor::cat(&[&rot, &pass], D::Minus1);
+    }
+    xs.apply_op3_no_bwd(&cos, &sin, &RotaryEmbPartial { interleaved })
+}
+
+/// Rotary embeddings on the first `rot_dim` values of each head with the contiguous layout, the
+/// other values are left as is. `xs` has shape `(b, h, t, d)` and `cos`, `sin` have shape
+/// `(t', rot_dim / 2)` with `t <= t'` and `rot_dim <= d`.
+pub fn rope_partial(xs: &Tensor, cos: &Tensor, sin: &Tensor) -> Result<Tensor> {
+    rope_partial_impl(xs, cos, sin, false)
+}
+
+/// The interleaved variant of [`rope_partial`].
+pub fn rope_i_partial(xs: &Tensor, cos: &Tensor, sin: &Tensor) -> Result<Tensor> {
+    rope_partial_impl(xs, cos, sin, true)
+}
+
+fn rope_partial_slow_impl(
+    x: &Tensor,
+    cos: &Tensor,
+    sin: &Tensor,
+    interleaved: bool,
+) -> Result<Tensor> {
+    let rot_dim = rope_partial_check(x, cos, sin)?;
+    let d = x.dim(D::Minus1)?;
+    let rot = x.narrow(D::Minus1, 0, rot_dim)?;
+    let rot = if interleaved {
+        rope_i_slow(&rot, cos, sin)?
+    } else {
+        rope_slow(&rot, cos, sin)?
+    };
+    if rot_dim == d {
+        return Ok(rot);
+    }
+    Tensor::cat(
+        &[&rot, &x.narrow(D::Minus1, rot_dim, d - rot_dim)?],
+        D::Minus1,
+    )
+}
+
+pub fn rope_partial_slow(x: &Tensor, cos: &Tensor, sin: &Tensor) -> Result<Tensor> {
+    rope_partial_slow_impl(x, cos, sin, false)
+}
+
+pub fn rope_i_partial_slow(x: &Tensor, cos: &Tensor, sin: &Tensor) -> Result<Tensor> {
+    rope_partial_slow_impl(x, cos, sin, true)
+}
+
+/// Dynamic NTK scaling of the rotary base: the base is increased for the sequences that are
+/// longer than the context the model was trained on so that the rotations of the low frequencies
+/// stay in the trained range.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DynamicNtkScaling {
+    pub factor: f64,
+    pub max_position_embeddings: usize,
+}
+
+impl DynamicNtkScaling {
+    /// The base for a sequence of `seq_len` tokens, the original base is used up to
+    /// `max_position_embeddings` tokens.
+    pub fn base(&self, base: f64, rot_dim: usize, seq_len: usize) -> f64 {
+        if seq_len <= self.max_position_embeddings || rot_dim <= 2 {
+            return base;
+        }
+        let ratio = self.factor * seq_len as f64 / self.max_position_embeddings as f64;
+        let exponent = rot_dim as f64 / (rot_dim as f64 - 2.);
+        base * (ratio - (self.factor - 1.)).powf(exponent)
+    }
+}
+
+/// The cos and sin tables for the positions `0..seq_len`, with shape `(seq_len, rot_dim / 2)`, to
+/// use with the rope functions. With dynamic NTK scaling the tables have to be recomputed when
+/// the sequence grows past the trained context.
+pub fn rope_tables(
+    rot_dim: usize,
+    base: f64,
+    seq_len: usize,
+    ntk: Option<DynamicNtkScaling>,
+    dtype: candle::DType,
+    dev: &candle::Device,
+) -> Result<(Tensor, Tensor)> {
+    if rot_dim % 2 != 0 {
+        candle::bail!("the rotary dim has to be even, got {rot_dim}")
+    }
+    let base = match ntk {
+        Some(ntk) => ntk.base(base, rot_dim, seq_len),
+        None => base,
+    };
+    tables(&inv_freq(base, rot_dim), seq_len, 1., dtype, dev)
+}
+
+/// The scaling of the rotary frequencies used by the models whose context was extended past the
+/// one they were pre-trained on. Unlike [`DynamicNtkScaling`] the scaled frequencies do not depend
+/// on the sequence length.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RopeScaling {
+    /// The llama 3.1 scaling: the frequencies whose wavelength is above
+    /// `original_max_position_embeddings / low_freq_factor` are divided by `factor`, the ones
+    /// whose wavelength is below `original_max_position_embeddings / high_freq_factor` are kept
+    /// and the ones in between are interpolated.
+    Llama3 {
+        factor: f64,
+        low_freq_factor: f64,
+        high_freq_factor: f64,
+        original_max_position_embeddings: usize,
+    },
+    /// YaRN, see <https://arxiv.org/abs/2309.00071>: the dimensions that make less than
+    /// `beta_slow` rotations over the original context are divided by `factor`, the ones that
+    /// make more than `beta_fast` rotations are kept and the ones in between are interpolated.
+    /// The tables are multiplied by `attention_factor`, usually `0.1 * ln(factor) + 1`.
+    Yarn {
+        factor: f64,
+        original_max_position_embeddings: usize,
+        beta_fast: f64,
+        beta_slow: f64,
+        attention_factor: f64,
+    },
+}
+
+impl RopeScaling {
+    /// The scaled inverse frequencies for the rotary dim `rot_dim`.
+    pub fn inv_freq(&self, base: f64, rot_dim: usize) -> Vec<f32> {
+        let inv_freq = inv_freq(base, rot_dim);
+        match *self {
+            Self::Llama3 {
+                factor,
+                low_freq_factor,
+                high_freq_factor,
+                original_max_position_embeddings,
+            } => {
+                let original = original_max_position_embeddings as f64;
+                let low_freq_wavelen = original / low_freq_factor;
+                let high_freq_wavelen = original / high_freq_factor;
+                inv_freq
+                    .into_iter()
+                    .map(|freq| {
+                        let freq = freq as f64;
+                        let wavelen = 2. * std::f64::consts::PI / freq;
+                        let freq = if wavelen < high_freq_wavelen {
+                            freq
+                        } else if wavelen > low_freq_wavelen {
+                            freq / factor
+                        } else {
+                            let smooth = (original / wavelen - low_freq_factor)
+                                / (high_freq_factor - low_freq_factor);
+                            (1. - smooth) * freq / factor + smooth * freq
+                        };
+                        freq as f32
+                    })
+                    .collect()
+            }
+            Self::Yarn {
+                factor,
+                original_max_position_embeddings,
+                beta_fast,
+                beta_slow,
+                ..
+            } => {
+                // The dimension that makes `rotations` rotations over the original context.
+                let correction_dim = |rotations: f64| {
+                    let original = original_max_position_embeddings as f64;
+                    rot_dim as f64 * (original / (rotations * 2. * std::f64::consts::PI)).ln()
+                        / (2. * base.ln())
+                };
+                let low = correction_dim(beta_fast).floor().max(0.);
+                let high = correction_dim(beta_slow).ceil().min(rot_dim as f64 - 1.);
+                let high = if low == high { high + 0.001 } else { high };
+                inv_freq
+                    .into_iter()
+                    .enumerate()
+                    .map(|(i, freq)| {
+                        let freq = freq as f64;
+                        let ramp = ((i as f64 - low) / (high - low)).clamp(0., 1.);
+                        let extrapolation = 1. - ramp;
+                        (freq / factor * ramp + freq * extrapolation) as f32
+                    })
+                    .collect()
+            }
+        }
+    }
+
+    /// The factor applied to the cos and sin tables.
+    pub fn attention_factor(&self) -> f64 {
+        match *self {
+            Self::Llama3 { .. } => 1.,
+            Self::Yarn {
+                attention_factor, ..
+            } => attention_factor,
+        }
+    }
+}
+
+/// The cos and sin tables of [`rope_tables`] with the frequencies scaled by `scaling`.
+pub fn scaled_rope_tables(
+    rot_dim: usize,
+    base: f64,
+    seq_len: usize,
+    scaling: RopeScaling,
+    dtype: candle::DType,
+    dev: &candle::Device,
+) -> Result<(Tensor, Tensor)> {
+    if rot_dim % 2 != 0 {
+        candle::bail!("the rotary dim has to be even, got {rot_dim}")
+    }
+    let inv_freq = scaling.inv_freq(base, rot_dim);
+    tables(&inv_freq, seq_len, scaling.attention_factor(), dtype, dev)
+}
+
+fn inv_freq(base: f64, rot_dim: usize) -> Vec<f32> {
+    (0..rot_dim)
+        .step_by(2)
+        .map(|i| 1f32 / base.powf(i as f64 / rot_dim as f64) as f32)
+        .collect()
+}
+
+fn tables(
+    inv_freq: &[f32],
+    seq_len: usize,
+    scale: f64,
+    dtype: candle::DType,
+    dev: &candle::Device,
+) -> Result<(Tensor, Tensor)> {
+    let inv_freq = Tensor::new(inv_freq, dev)?;
+    let positions = Tensor::arange(0u32, seq_len as u32, dev)?.to_dtype(candle::DType::F32)?;
+    let freqs = positions
+        .unsqueeze(1)?
+        .broadcast_mul(&inv_freq.unsqueeze(0)?)?;
+    let (cos, sin) = if scale == 1. {
+        (freqs.cos()?, freqs.sin()?)
+    } else {
+        ((freqs.cos()? * scale)?, (freqs.sin()? * scale)?)
+    };
+    Ok((cos.to_dtype(dtype)?, sin.to_dtype(dtype)?))
+}
@@ -0,0 +1,294 @@
+//! Signal processing helpers for audio models.
+//!
+//! The windows follow the PyTorch conventions: a periodic window of size `n` is the symmetric
+//! window of size `n + 1` without its last sample, as used for spectral analysis. [`frame`] and
+//! [`overlap_add`] split a signal in overlapping frames and sum them back, e.g. for the
+//! synthesis of vocoders. All the functions are differentiable tensor operations that run on
+//! the device of their inputs, as does [`resample`] which converts the sample rate of a signal.
+use candle::{DType, Device, Result, Tensor, D};
+
+// a - b cos(2 pi n / N)
+fn cosine_window(
+    size: usize,
+    periodic: bool,
+    a: f64,
+    b: f64,
+    dtype: DType,
+    device: &Device,
+) -> Result<Tensor> {
+    if size <= 1 {
+        return Tensor::ones(size, dtype, device);
+    }
+    let denom = if periodic { size } else { size - 1 };
+    Tensor::arange(0u32, size as u32, device)?
+        .to_dtype(DType::F32)?
+        .affine(2. * std::f64::consts::PI / denom as f64, 0.)?
+        .cos()?
+        .affine(-b, a)?
+        .to_dtype(dtype)
+}
+
+/// The Hann window `0.5 - 0.5 cos(2 pi n / N)`.
+pub fn hann_window(size: usize, periodic: bool, dtype: DType, device: &Device) -> Result<Tensor> {
+    cosine_window(size, periodic, 0.5, 0.5, dtype, device)
+}
+
+/// The Hamming window `0.54 - 0.46 cos(2 pi n / N)`.
+pub fn hamming_window(
+    size: usize,
+    periodic: bool,
+    dtype: DType,
+    device: &Device,
+) -> Result<Tensor> {
+    cosine_window(size, periodic, 0.54, 0.46, dtype, device)
+}
+
+/// Splits the last dimension of `xs` in frames of `frame_length` samples starting every
+/// `hop_length` samples, the frames are stacked on a new second to last dimension. The samples
+/// after the last full frame are dropped.
+pub fn frame(xs: &Tensor, frame_length: usize, hop_length: usize) -> Result<Tensor> {
+    if frame_length == 0 || hop_length == 0 {
+        candle::bail!("frame: frame_length and hop_length have to be positive")
+    }
+    let len = xs.dim(D::Minus1)?;
+    if len < frame_length {
+        candle::bail!("frame: the signal length {len} is smaller than the frame {frame_length}")
+    }
+    let num_frames = 1 + (len - frame_length) / hop_length;
+    let dev = xs.device();
+    let end = (num_frames * hop_length) as u32;
+    let starts = Tensor::arange_step(0u32, end, hop_length as u32, dev)?;
+    let offsets = Tensor::arange(0u32, frame_length as u32, dev)?;
+    let indices = starts
+        .unsqueeze(1)?
+        .broadcast_add(&offsets.unsqueeze(0)?)?
+        .flatten_all()?;
+    let frames = xs.index_select(&indices, xs.rank() - 1)?;
+    let mut dims = xs.dims()[..xs.rank() - 1].to_vec();
+    dims.push(num_frames);
+    dims.push(frame_length);
+    frames.reshape(dims)
+}
+
+/// Sums the overlapping frames of `frames`, with shape `(..., num_frames, frame_length)`, where
+/// the frames start every `hop_length` samples. Returns a tensor of shape
+/// `(..., (num_frames - 1) * hop_length + frame_length)`.
+///
+/// This is the inverse of [`frame`] up to the overlap: for a window `w` that satisfies the
+/// constant overlap-add condition, e.g. a periodic hann window with a hop of half its size,
+/// `overlap_add(frame(xs) * w)` is `xs` away from the edges. Otherwise the signal can be
+/// normalized by the overlap-add of the squared window.
+pub fn overlap_add(frames: &Tensor, hop_length: usize) -> Result<Tensor> {
+    if hop_length == 0 {
+        candle::bail!("overlap_add: hop_length has to be positive")
+    }
+    let rank = frames.rank();
+    if rank < 2 {
+        candle::bail!(
+            "overlap_add: expected at least two dims, got {:?}",
+            frames.shape()
+        )
+    }
+    let (num_frames, frame_length) = (frames.dim(rank - 2)?, frames.dim(rank - 1)?);
+    let batch_dims = &frames.dims()[..rank - 2];
+    // Each frame is split in chunks of hop_length samples, the chunk j of frame i lands on the
+    // block i + j of the output.
+    let num_chunks = frame_length.div_ceil(hop_length);
+    let padded = frames.pad_with_zeros(rank - 1, 0, num_chunks * hop_length - frame_length)?;
+    let mut dims = batch_dims.to_vec();
+    dims.extend([num_frames, num_chunks, hop_length]);
+    let chunks = padded.reshape(dims)?;
+    let mut out: Option<Tensor> = None;
+    for j in 0..num_chunks {
+        let chunk = chunks.narrow(rank - 1, j, 1)?.squeeze(rank - 1)?;
+        let chunk = chunk.pad_with_zeros(rank - 2, j, num_chunks - 1 - j)?;
+        out = Some(match out {
+            None => chunk,
+            Some(out) => (out + chunk)?,
+        })
+    }
+    let out = match out {
+        Some(out) => out,
+        None => candle::bail!("overlap_add: empty frames"),
+    };
+    let out = out.flatten_from(rank - 2)?;
+    out.narrow(rank - 2, 0, (num_frames - 1) * hop_length + frame_length)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CorrelationMode {
+    /// All the overlaps, the output has `len + kernel - 1` samples.
+    Full,
+    /// The output has the size of the larger of the input and the kernel, centered with respect
+    /// to `Full`.
+    Same,
+    /// Only the positions where the kernel fully overlaps the input, `len - kernel + 1` samples.
+    Valid,
+}
+
+/// The cross-correlation `out[k] = sum_n xs[n + k] * kernel[n]` over the last dimension of `xs`,
+/// as computed by `numpy.correlate`.
+pub fn correlate1d(xs: &Tensor, kernel: &Tensor, mode: CorrelationMode) -> Result<Tensor> {
+    let k = kernel.dims1()?;
+    let dims = xs.dims().to_vec();
+    let len = xs.dim(D::Minus1)?;
+    if k == 0 || len == 0 {
+        candle::bail!(
+            "correlate1d: empty input {:?} or kernel {:?}",
+            xs.shape(),
+            kernel.shape()
+        )
+    }
+    let xs = xs.reshape(((), 1, len))?;
+    let kernel = kernel.reshape((1, 1, k))?;
+    let out = match mode {
+        CorrelationMode::Valid => {
+            if len < k {
+                candle::bail!("correlate1d: the input {len} is smaller than the kernel {k}")
+            }
+            xs.conv1d(&kernel, 0, 1, 1, 1)?
+        }
+        CorrelationMode::Full => xs.conv1d(&kernel, k - 1, 1, 1, 1)?,
+        CorrelationMode::Same => {
+            let full = xs.conv1d(&kernel, k - 1, 1, 1, 1)?;
+            let out_len = len.max(k);
+            full.narrow(2, (len + k - 1 - out_len) / 2, out_len)?
+        }
+    };
+    let mut out_dims = dims[..dims.len() - 1].to_vec();
+    out_dims.push(out.dim(2)?);
+    out.reshape(out_dims)
+}
+
+/// The convolution of the last dimension of `xs` with `kernel`, as computed by `numpy.convolve`.
+pub fn convolve1d(xs: &Tensor, kernel: &Tensor, mode: CorrelationMode) -> Result<Tensor> {
+    let k = kernel.dims1()?;
+    let reversed = Tensor::new((0..k as u32).rev().collect::<Vec<_>>(), kernel.device())?;
+    let kernel = kernel.index_select(&reversed, 0)?;
+    correlate1d(xs, &kernel, mode)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ResamplingWindow {
+    /// A squared cosine window over the width of the filter.
+    Hann,
+    /// A Kaiser window, larger values of `beta` give a sharper attenuation of the aliasing.
+    Kaiser { beta: f64 },
+}
+
+/// The parameters of the sinc interpolation used by [`resample`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ResampleConfig {
+    /// The number of zero crossings of the windowed sinc on each side, more is sharper and slower.
+    pub lowpass_filter_width: usize,
+    /// The cutoff of the low pass filter as a fraction of the lower of the two Nyquist
+    /// frequencies, values below one reduce the aliasing.
+    pub rolloff: f64,
+    pub window: ResamplingWindow,
+}
+
+impl Default for ResampleConfig {
+    fn default() -> Self {
+        Self {
+            lowpass_filter_width: 6,
+            rolloff: 0.99,
+            window: ResamplingWindow::Hann,
+        }
+    }
+}
+
+// The modified Bessel function of the first kind of order zero.
+fn bessel_i0(x: f64) -> f64 {
+    let (mut sum, mut term) = (1., 1.);
+    for k in 1..64 {
+        term *= (x / (2. * k as f64)).powi(2);
+        sum += term;
+        if term < sum * 1e-16 {
+            break;
+        }
+    }
+    sum
+}
+
+fn gcd(a: usize, b: usize) -> usize {
+    if b == 0 {
+        a
+    } else {
+        gcd(b, a % b)
+    }
+}
+
+// One windowed sinc filter per output phase, with shape (new_freq, 1, kernel_size), and the
+// number of padding samples on each side.
+fn sinc_kernels(
+    orig_freq: usize,
+    new_freq: usize,
+    config: &ResampleConfig,
+) -> (Vec<f32>, usize, usize) {
+    let lowpass = config.lowpass_filter_width as f64;
+    let base_freq = orig_freq.min(new_freq) as f64 * config.rolloff;
+    let width = (lowpass * orig_freq as f64 / base_freq).ceil() as usize;
+    let kernel_size = 2 * width + orig_freq;
+    let mut kernels = Vec::with_capacity(new_freq * kernel_size);
+    for phase in 0..new_freq {
+        for i in 0..kernel_size {
+            // The distance between the input sample and the output sample, in input periods.
+            let t = (i as f64 - width as f64) / orig_freq as f64 - phase as f64 / new_freq as f64;
+            let t = (t * base_freq).clamp(-lowpass, lowpass);
+            let window = match config.window {
+                ResamplingWindow::Hann => (t * std::f64::consts::PI / lowpass / 2.).cos().powi(2),
+                ResamplingWindow::Kaiser { beta } => {
+                    let r = t / lowpass;
+                    bessel_i0(beta * (1. - r * r).max(0.).sqrt()) / bessel_i0(beta)
+                }
+            };
+            let t = t * std::f64::consts::PI;
+            let sinc = if t == 0. { 1. } else { t.sin() / t };
+            kernels.push((sinc * window * base_freq / orig_freq as f64) as f32)
+        }
+    }
+    (kernels, width, kernel_size)
+}
+
+/// Resamples the last dimension of `xs` from `orig_freq` to `new_freq` with a windowed sinc
+/// interpolation, as done by `torchaudio.functional.resample`. The output has
+/// `ceil(len * new_freq / orig_freq)` samples.
+///
+/// The frequencies are reduced by their greatest common divisor and each of the `new_freq`
+/// output phases is a strided convolution of the input, e.g. for 44.1kHz to 16kHz there are 160
+/// filters of 461 taps applied with a stride of 441. The convolution runs on the device of `xs`.
+pub fn resample(
+    xs: &Tensor,
+    orig_freq: usize,
+    new_freq: usize,
+    config: &ResampleConfig,
+) -> Result<Tensor> {
+    if orig_freq == 0 || new_freq == 0 {
+        candle::bail!("resample: the frequencies have to be positive, got {orig_freq} {new_freq}")
+    }
+    if !(config.rolloff > 0. && config.rolloff <= 1.) || config.lowpass_filter_width == 0 {
+        candle::bail!("resample: invalid config {config:?}")
+    }
+    if orig_freq == new_freq {
+        return Ok(xs.clone());
+    }
+    let g = gcd(orig_freq, new_freq);
+    let (orig, new) = (orig_freq / g, new_freq / g);
+    let dims = xs.dims().to_vec();
+    let len = xs.dim(D::Minus1)?;
+    let (kernels, width, kernel_size) = sinc_kernels(orig, new, config);
+    let kernels =
+        Tensor::from_vec(kernels, (new, 1, kernel_size), xs.device())?.to_dtype(xs.dtype())?;
+    let xs = xs
+        .reshape(((), 1, len))?
+        .pad_with_zeros(2, width, width + orig)?;
+    let ys = xs.conv1d(&kernels, 0, orig, 1, 1)?;
+    let b = ys.dim(0)?;
+    // (batch, phase, frame) to (batch, frame * new + phase).
+    let ys = ys.transpose(1, 2)?.reshape((b, ()))?;
+    let target_len = (len * new).div_ceil(orig);
+    let ys = ys.narrow(1, 0, target_len)?;
+    let mut out_dims = dims[..dims.len() - 1].to_vec();
+    out_dims.push(target_len);
+    ys.reshape(out_dims)
+}
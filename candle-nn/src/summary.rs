@@ -0,0 +1,390 @@
+//! Summaries of the parameters of a model.
+//!
+//! A [`ModelSummary`] lists the tensors of a model grouped by layer, the layer of a tensor being
+//! its name without the last component, e.g. `model.layers.0.mlp.up_proj` for
+//! `model.layers.0.mlp.up_proj.weight`. For each layer it reports the shapes, dtypes, parameter
+//! count and memory, as well as the kind of layer and the number of floating point operations
+//! when these can be inferred from the shapes.
+//!
+//! ```no_run
+//! # fn main() -> candle::Result<()> {
+//! let st = unsafe { candle::safetensors::MmapedSafetensors::new("model.safetensors")? };
+//! let summary = candle_nn::summary::ModelSummary::from_safetensors(&st)?;
+//! println!("{summary}");
+//! println!("{}", summary.grouped(3).len());
+//! # Ok(())
+//! # }
+//! ```
+use crate::VarMap;
+use candle::{DType, Result, Shape, Tensor};
+use std::collections::HashMap;
+
+/// The kind of a layer, guessed from the names and shapes of its tensors.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LayerKind {
+    /// A `weight` with shape `(out, in)` and an optional `bias`.
+    Linear,
+    /// A `weight` with shape `(vocab, hidden)` in a layer whose name contains `emb`, `wte` or
+    /// `wpe`.
+    Embedding,
+    /// A `weight` with shape `(out, in / groups, kernel)`.
+    Conv1d,
+    /// A `weight` with shape `(out, in / groups, kernel_h, kernel_w)`.
+    Conv2d,
+    /// Only one dimensional tensors, e.g. the weight and bias of a layer norm.
+    Norm,
+    Other,
+}
+
+impl std::fmt::Display for LayerKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let s = match self {
+            Self::Linear => "linear",
+            Self::Embedding => "embedding",
+            Self::Conv1d => "conv1d",
+            Self::Conv2d => "conv2d",
+            Self::Norm => "norm",
+            Self::Other => "-",
+        };
+        f.write_str(s)
+    }
+}
+
+/// A tensor of a model.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParamSummary {
+    /// The name of the tensor within its layer, e.g. `weight`.
+    pub name: String,
+    pub shape: Shape,
+    pub dtype: DType,
+    /// Whether the tensor is trained, the buffers of a [`VarMap`] are not.
+    pub trainable: bool,
+}
+
+impl ParamSummary {
+    pub fn elem_count(&self) -> usize {
+        self.shape.elem_count()
+    }
+
+    pub fn memory(&self) -> usize {
+        self.elem_count() * self.dtype.size_in_bytes()
+    }
+}
+
+/// The tensors of a layer, or of a group of layers, see [`ModelSummary::grouped`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct LayerSummary {
+    pub name: String,
+    pub kind: LayerKind,
+    pub params: Vec<ParamSummary>,
+    /// The number of floating point operations for each output position, i.e. for each token
+    /// of a linear layer or each output pixel of a convolution. `None` when unknown.
+    pub flops: Option<usize>,
+}
+
+impl LayerSummary {
+    fn new(name: String, params: Vec<ParamSummary>) -> Self {
+        let kind = layer_kind(&name, &params);
+        let flops = layer_flops(kind, &params);
+        Self {
+            name,
+            kind,
+            params,
+            flops,
+        }
+    }
+
+    pub fn num_params(&self) -> usize {
+        self.params.iter().map(|p| p.elem_count()).sum()
+    }
+
+    pub fn num_trainable_params(&self) -> usize {
+        let params = self.params.iter().filter(|p| p.trainable);
+        params.map(|p| p.elem_count()).sum()
+    }
+
+    /// The memory used by the tensors, in bytes.
+    pub fn memory(&self) -> usize {
+        self.params.iter().map(|p| p.memory()).sum()
+    }
+}
+
+fn layer_kind(name: &str, params: &[ParamSummary]) -> LayerKind {
+    let weight = params.iter().find(|p| p.name == "weight");
+    let others_1d = params
+        .iter()
+        .all(|p| p.name == "weight" || (p.name == "bias" && p.shape.rank() == 1));
+    let last = name.rsplit('.').next().unwrap_or(name);
+    match weight.map(|w| w.shape.rank()) {
+        Some(2) if last.contains("emb") || last == "wte" || last == "wpe" => LayerKind::Embedding,
+        Some(2) if others_1d => LayerKind::Linear,
+        Some(3) if others_1d => LayerKind::Conv1d,
+        Some(4) if others_1d => LayerKind::Conv2d,
+        _ if params.iter().all(|p| p.shape.rank() == 1) => LayerKind::Norm,
+        _ => LayerKind::Other,
+    }
+}
+
+fn layer_flops(kind: LayerKind, params: &[ParamSummary]) -> Option<usize> {
+    let weight = params.iter().find(|p| p.name == "weight");
+    let bias = params.iter().find(|p| p.name == "bias");
+    let bias = bias.map_or(0, |b| b.elem_count());
+    match kind {
+        // A multiply and an add for each weight.
+        LayerKind::Linear | LayerKind::Conv1d | LayerKind::Conv2d => {
+            Some(2 * weight?.elem_count() + bias)
+        }
+        LayerKind::Embedding => Some(0),
+        LayerKind::Norm | LayerKind::Other => None,
+    }
+}
+
+fn natural_key(name: &str) -> Vec<(Option<usize>, &str)> {
+    let key = name.split('.').map(|c| match c.parse::<usize>() {
+        Ok(n) => (Some(n), ""),
+        Err(_) => (None, c),
+    });
+    key.collect()
+}
+
+/// The parameters of a model grouped by layer, see the [module level docs](self).
+#[derive(Debug, Clone, PartialEq)]
+pub struct ModelSummary {
+    layers: Vec<LayerSummary>,
+}
+
+impl ModelSummary {
+    /// Creates a summary from the full names of some tensors, the layers are sorted by name with
+    /// the numeric components compared as numbers, e.g. `layers.2` comes before `layers.10`.
+    pub fn new<I: IntoIterator<Item = (String, Shape, DType, bool)>>(tensors: I) -> Self {
+        let mut layers: HashMap<String, Vec<ParamSummary>> = HashMap::new();
+        for (name, shape, dtype, trainable) in tensors {
+            let (layer, name) = match name.rsplit_once('.') {
+                Some((layer, name)) => (layer.to_string(), name.to_string()),
+                None => (String::new(), name),
+            };
+            layers.entry(layer).or_default().push(ParamSummary {
+                name,
+                shape,
+                dtype,
+                trainable,
+            })
+        }
+        let mut layers = layers
+            .into_iter()
+            .map(|(name, mut params)| {
+                params.sort_by(|a, b| a.name.cmp(&b.name));
+                LayerSummary::new(name, params)
+            })
+            .collect::<Vec<_>>();
+        layers.sort_by(|a, b| natural_key(&a.name).cmp(&natural_key(&b.name)));
+        Self { layers }
+    }
+
+    /// Summarizes the variables and buffers of `varmap`.
+    pub fn from_varmap(varmap: &VarMap) -> Self {
+        let data = varmap.data().lock().unwrap();
+        let tensors = data
+            .iter()
+            .map(|(name, var)| {
+                let trainable = !varmap.is_buffer(name);
+                (name.clone(), var.shape().clone(), var.dtype(), trainable)
+            })
+            .collect::<Vec<_>>();
+        Self::new(tensors)
+    }
+
+    /// Summarizes some tensors, all of them are considered trainable.
+    pub fn from_tensors(tensors: &HashMap<String, Tensor>) -> Self {
+        Self::new(
+            tensors
+                .iter()
+                .map(|(name, t)| (name.clone(), t.shape().clone(), t.dtype(), true)),
+        )
+    }
+
+    /// Summarizes the tensors of some safetensors files without loading them.
+    pub fn from_safetensors(st: &candle::safetensors::MmapedSafetensors) -> Result<Self> {
+        let tensors = st
+            .tensors()
+            .into_iter()
+            .map(|(name, view)| {
+                let dtype = DType::try_from(view.dtype())?;
+                Ok((name, Shape::from(view.shape()), dtype, true))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Self::new(tensors))
+    }
+
+    pub fn layers(&self) -> &[LayerSummary] {
+        &self.layers
+    }
+
+    /// Merges the layers sharing the first `depth` components of their names, e.g. with a depth
+    /// of 3 all the tensors of `model.layers.0` make a single group. The kind and flops of a
+    /// group are the ones of its layers if they all have the same kind.
+    pub fn grouped(&self, depth: usize) -> Vec<LayerSummary> {
+        let mut groups: Vec<(String, Vec<&LayerSummary>)> = vec![];
+        for layer in self.layers.iter() {
+            let name = layer
+                .name
+                .split('.')
+                .take(depth)
+                .collect::<Vec<_>>()
+                .join(".");
+            match groups.last_mut() {
+                Some((group, layers)) if *group == name => layers.push(layer),
+                _ => groups.push((name, vec![layer])),
+            }
+        }
+        groups
+            .into_iter()
+            .map(|(name, layers)| {
+                if let [layer] = layers.as_slice() {
+                    return LayerSummary {
+                        name,
+                        ..(*layer).clone()
+                    };
+                }
+                let same_kind = layers.iter().all(|l| l.kind == layers[0].kind);
+                let kind = if same_kind {
+                    layers[0].kind
+                } else {
+                    LayerKind::Other
+                };
+                let flops = layers.iter().map(|l| l.flops).sum::<Option<usize>>();
+                let params = layers
+                    .iter()
+                    .flat_map(|l| {
+                        let prefix = l.name.strip_prefix(&name).unwrap_or(&l.name);
+                        let prefix = prefix.trim_start_matches('.');
+                        l.params.iter().map(move |p| ParamSummary {
+                            name: if prefix.is_empty() {
+                                p.name.clone()
+                            } else {
+                                format!("{prefix}.{}", p.name)
+                            },
+                            ..p.clone()
+                        })
+                    })
+                    .collect();
+                LayerSummary {
+                    name,
+                    kind,
+                    params,
+                    flops: if same_kind { flops } else { None },
+                }
+            })
+            .collect()
+    }
+
+    pub fn num_params(&self) -> usize {
+        self.layers.iter().map(|l| l.num_params()).sum()
+    }
+
+    pub fn num_trainable_params(&self) -> usize {
+        self.layers.iter().map(|l| l.num_trainable_params()).sum()
+    }
+
+    /// The memory used by all the tensors, in bytes.
+    pub fn memory(&self) -> usize {
+        self.layers.iter().map(|l| l.memory()).sum()
+    }
+
+    /// The memory that the tensors would use if the floating point ones were converted to
+    /// `dtype`, the integer tensors being kept as is.
+    pub fn memory_as(&self, dtype: DType) -> usize {
+        let params = self.layers.iter().flat_map(|l| l.params.iter());
+        params
+            .map(|p| {
+                if p.dtype.is_float() {
+                    p.elem_count() * dtype.size_in_bytes()
+                } else {
+                    p.memory()
+                }
+            })
+            .sum()
+    }
+
+    /// The floating point operations of the linear layers for each token, i.e. roughly the
+    /// inference cost of a transformer per token excluding the attention scores.
+    pub fn flops_per_token(&self) -> usize {
+        let linears = self.layers.iter().filter(|l| l.kind == LayerKind::Linear);
+        linears.filter_map(|l| l.flops).sum()
+    }
+}
+
+fn human(v: usize, units: &[&str], base: f64) -> String {
+    let mut v = v as f64;
+    let mut unit = 0;
+    while v >= base && unit + 1 < units.len() {
+        v /= base;
+        unit += 1
+    }
+    if unit == 0 {
+        format!("{v}{}", units[0])
+    } else {
+        format!("{v:.2}{}", units[unit])
+    }
+}
+
+fn human_count(v: usize) -> String {
+    human(v, &["", "K", "M", "B", "T"], 1000.)
+}
+
+fn human_bytes(v: usize) -> String {
+    human(v, &["B", "KiB", "MiB", "GiB", "TiB"], 1024.)
+}
+
+impl std::fmt::Display for ModelSummary {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let header = [
+            "layer", "kind", "params", "dtype", "count", "memory", "flops",
+        ];
+        let mut rows = vec![header.map(|s| s.to_string())];
+        for layer in self.layers.iter() {
+            let shapes = layer
+                .params
+                .iter()
+                .map(|p| format!("{} {:?}", p.name, p.shape.dims()))
+                .collect::<Vec<_>>();
+            let mut dtypes = layer
+                .params
+                .iter()
+                .map(|p| p.dtype.as_str())
+                .collect::<Vec<_>>();
+            dtypes.dedup();
+            rows.push([
+                layer.name.clone(),
+                layer.kind.to_string(),
+                shapes.join(", "),
+                dtypes.join(", "),
+                human_count(layer.num_params()),
+                human_bytes(layer.memory()),
+                layer.flops.map_or("-".to_string(), human_count),
+            ])
+        }
+        let mut widths = [0; 7];
+        for row in rows.iter() {
+            for (w, cell) in widths.iter_mut().zip(row.iter()) {
+                *w = (*w).max(cell.chars().count())
+            }
+        }
+        for row in rows.iter() {
+            let line = row
+                .iter()
+                .zip(widths.iter())
+                .map(|(cell, w)| format!("{cell:w$}"))
+                .collect::<Vec<_>>();
+            writeln!(f, "{}", line.join("  ").trim_end())?
+        }
+        writeln!(
+            f,
+            "total: {} params ({} trainable), {}, {} flops per token",
+            human_count(self.num_params()),
+            human_count(self.num_trainable_params()),
+            human_bytes(self.memory()),
+            human_count(self.flops_per_token()),
+        )
+    }
+}
@@ -0,0 +1,355 @@
+//! Stochastic weight averaging and checkpoint averaging.
+//!
+//! A [`SwaModel`] keeps the running mean of the variables of a [`VarMap`] over the end of a
+//! training, with all the averaged weights having the same contribution, see "Averaging Weights
+//! Leads to Wider Optima and Better Generalization", Izmailov, P. et al. (2018). It is usually
+//! combined with a constant or cyclical learning rate after `start_step`, see
+//! [`crate::lr_scheduler::SwaLr`].
+//!
+//! ```ignore
+//! let mut swa = SwaModel::new(&varmap, SwaConfig::new(75_000).with_frequency(100))?;
+//! for batch in batches {
+//!     opt.backward_step(&model.loss(&batch)?)?;
+//!     swa.update()?;
+//! }
+//! swa.apply()?;
+//! varmap.save("swa.safetensors")?;
+//! ```
+//!
+//! [`average_checkpoints`] computes the same average offline from checkpoints saved during the
+//! training, as is common for speech recognition and translation models.
+use crate::VarMap;
+use candle::safetensors::MmapedSafetensors;
+use candle::{DType, Device, Result, Tensor};
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+
+// The safetensors metadata keys holding the number of updates and of averaged weights.
+const STEP_KEY: &str = "swa_step";
+const NUM_AVERAGED_KEY: &str = "swa_num_averaged";
+
+/// When a [`SwaModel`] averages the weights.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SwaConfig {
+    /// The number of updates before the averaging starts.
+    pub start_step: usize,
+    /// The weights are averaged every `frequency` updates once started.
+    pub frequency: usize,
+}
+
+impl SwaConfig {
+    pub fn new(start_step: usize) -> Self {
+        Self {
+            start_step,
+            frequency: 1,
+        }
+    }
+
+    pub fn with_frequency(mut self, frequency: usize) -> Self {
+        self.frequency = frequency;
+        self
+    }
+
+    /// Whether the update number `step`, starting from 1, averages the weights.
+    pub fn averages_at(&self, step: usize) -> bool {
+        step > self.start_step && (step - self.start_step) % self.frequency.max(1) == 0
+    }
+}
+
+// The averages are kept in f32 for the half precision variables.
+fn average_dtype(dtype: DType) -> DType {
+    match dtype {
+        DType::BF16 | DType::F16 => DType::F32,
+        dtype => dtype,
+    }
+}
+
+/// The running mean of the variables of a [`VarMap`].
+///
+/// The buffers and the variables that do not have a float dtype are copied rather than
+/// averaged, in particular the batch norm statistics are the ones of the last averaged weights
+/// and may have to be recomputed with a pass over the training data.
+pub struct SwaModel {
+    varmap: VarMap,
+    config: SwaConfig,
+    averages: BTreeMap<String, Tensor>,
+    // The values of the variables while the averages are applied.
+    backup: Option<HashMap<String, Tensor>>,
+    step: usize,
+    num_averaged: usize,
+}
+
+impl SwaModel {
+    pub fn new(varmap: &VarMap, config: SwaConfig) -> Result<Self> {
+        Ok(Self {
+            varmap: varmap.clone(),
+            config,
+            averages: BTreeMap::new(),
+            backup: None,
+            step: 0,
+            num_averaged: 0,
+        })
+    }
+
+    pub fn config(&self) -> &SwaConfig {
+        &self.config
+    }
+
+    /// The number of updates done so far.
+    pub fn step(&self) -> usize {
+        self.step
+    }
+
+    /// The number of weights that have been averaged.
+    pub fn num_averaged(&self) -> usize {
+        self.num_averaged
+    }
+
+    /// The average of the variable `name`.
+    pub fn average(&self, name: &str) -> Option<&Tensor> {
+        self.averages.get(name)
+    }
+
+    pub fn averages(&self) -> &BTreeMap<String, Tensor> {
+        &self.averages
+    }
+
+    /// Whether the averages are currently applied to the variables, see [`Self::apply`].
+    pub fn is_applied(&self) -> bool {
+        self.backup.is_some()
+    }
+
+    /// Adds the current values of the variables to the averages if the configuration says so,
+    /// this should be called after each optimizer step. Returns whether the weights were
+    /// averaged.
+    pub fn update(&mut self) -> Result<bool> {
+        if self.is_applied() {
+            candle::bail!("cannot update the SWA while its averages are applied")
+        }
+        self.step += 1;
+        if !self.config.averages_at(self.step) {
+            return Ok(false);
+        }
+        let n = self.num_averaged as f64;
+        let data = self.varmap.data().lock().unwrap();
+        for (name, var) in data.iter() {
+            let value = var.as_tensor().to_dtype(average_dtype(var.dtype()))?;
+            let averaged = var.dtype().is_float() && !self.varmap.is_buffer(name);
+            let average = match self.averages.get(name) {
+                Some(average) if averaged => (average + ((value - average)? / (n + 1.))?)?,
+                _ => value.copy()?,
+            };
+            self.averages.insert(name.clone(), average);
+        }
+        self.num_averaged += 1;
+        Ok(true)
+    }
+
+    /// Sets the variables to their averages, the current values are kept and can be restored
+    /// with [`Self::restore`].
+    pub fn apply(&mut self) -> Result<()> {
+        if self.is_applied() {
+            candle::bail!("the SWA averages are already applied")
+        }
+        if self.num_averaged == 0 {
+            candle::bail!("no weights have been averaged yet")
+        }
+        let data = self.varmap.data().lock().unwrap();
+        let mut backup = HashMap::new();
+        for (name, var) in data.iter() {
+            if let Some(average) = self.averages.get(name) {
+                backup.insert(name.clone(), var.as_tensor().copy()?);
+                var.set(&average.to_dtype(var.dtype())?)?;
+            }
+        }
+        self.backup = Some(backup);
+        Ok(())
+    }
+
+    /// Restores the values the variables had before [`Self::apply`].
+    pub fn restore(&mut self) -> Result<()> {
+        let backup = match self.backup.take() {
+            Some(backup) => backup,
+            None => candle::bail!("the SWA averages are not applied"),
+        };
+        let data = self.varmap.data().lock().unwrap();
+        for (name, value) in backup.iter() {
+            if let Some(var) = data.get(name) {
+                var.set(value)?
+            }
+        }
+        Ok(())
+    }
+
+    /// Copies the averages to the variables with the same names in another map. The variables
+    /// of `varmap` without an average are left as is.
+    pub fn copy_to(&self, varmap: &VarMap) -> Result<()> {
+        let data = varmap.data().lock().unwrap();
+        for (name, var) in data.iter() {
+            if let Some(average) = self.averages.get(name) {
+                var.set(&average.to_dtype(var.dtype())?.to_device(var.device())?)?
+            }
+        }
+        Ok(())
+    }
+
+    /// Saves the averages in the safetensors format with the names of the variables, the
+    /// number of updates and of averaged weights are stored in the metadata.
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let metadata = HashMap::from([
+            (STEP_KEY.to_string(), self.step.to_string()),
+            (NUM_AVERAGED_KEY.to_string(), self.num_averaged.to_string()),
+        ]);
+        safetensors::tensor::serialize_to_file(
+            self.averages.iter(),
+            &Some(metadata),
+            path.as_ref(),
+        )?;
+        Ok(())
+    }
+
+    /// Loads the averages saved by [`Self::save`], e.g. when resuming a training.
+    pub fn load<P: AsRef<Path>>(&mut self, path: P) -> Result<()> {
+        let path = path.as_ref();
+        let buffer = std::fs::read(path)?;
+        let (_, metadata) = safetensors::SafeTensors::read_metadata(&buffer)?;
+        let get = |key: &str| {
+            metadata
+                .metadata()
+                .as_ref()
+                .and_then(|m| m.get(key))
+                .and_then(|s| s.parse::<usize>().ok())
+        };
+        let (step, num_averaged) = match (get(STEP_KEY), get(NUM_AVERAGED_KEY)) {
+            (Some(step), Some(num_averaged)) => (step, num_averaged),
+            _ => candle::bail!("no SWA state in the metadata of {path:?}"),
+        };
+        let st = candle::safetensors::SliceSafetensors::new(&buffer)?;
+        let mut averages = BTreeMap::new();
+        let data = self.varmap.data().lock().unwrap();
+        for (name, _) in st.tensors() {
+            let var = match data.get(&name) {
+                Some(var) => var,
+                None => candle::bail!("unknown variable {name} in {path:?}"),
+            };
+            let average = st.load(&name, &Device::Cpu)?;
+            if average.shape() != var.shape() {
+                candle::bail!(
+                    "shape mismatch for the SWA of {name}: {:?} <> {:?}",
+                    average.shape(),
+                    var.shape()
+                )
+            }
+            let average = average
+                .to_dtype(average_dtype(var.dtype()))?
+                .to_device(var.device())?;
+            averages.insert(name, average);
+        }
+        self.averages = averages;
+        self.step = step;
+        self.num_averaged = num_averaged;
+        Ok(())
+    }
+}
+
+// The safetensors files of a checkpoint, either a single file or a directory of shards.
+fn checkpoint_files(path: &Path) -> Result<Vec<PathBuf>> {
+    if !path.is_dir() {
+        return Ok(vec![path.to_path_buf()]);
+    }
+    let mut files = vec![];
+    for entry in std::fs::read_dir(path)? {
+        let file = entry?.path();
+        if file.extension().is_some_and(|e| e == "safetensors") {
+            files.push(file)
+        }
+    }
+    if files.is_empty() {
+        candle::bail!("no safetensors files in {path:?}")
+    }
+    files.sort();
+    Ok(files)
+}
+
+/// Writes the average of the weights of several checkpoints to `output`.
+///
+/// A checkpoint is either a safetensors file or a directory of safetensors shards, possibly
+/// with different shardings. The output uses the layout of the first checkpoint: a single file,
+/// or a directory with the same shard files, the other files of the first directory such as the
+/// shard index or the model config being copied. The checkpoints are memory mapped and only one
+/// output shard is held in memory at a time.
+///
+/// The float tensors are averaged in f32, or f64 for f64 tensors, and converted back to their
+/// dtype. The other tensors, e.g. step counters, are taken from the first checkpoint. All the
+/// checkpoints must have the same tensors with the same shapes.
+pub fn average_checkpoints<P: AsRef<Path>, Q: AsRef<Path>>(inputs: &[P], output: Q) -> Result<()> {
+    let (first, others) = match inputs.split_first() {
+        Some((first, others)) => (first.as_ref(), others),
+        None => candle::bail!("no checkpoints to average"),
+    };
+    let output = output.as_ref();
+    let first_files = checkpoint_files(first)?;
+    let others = others
+        .iter()
+        .map(|path| {
+            let files = checkpoint_files(path.as_ref())?;
+            unsafe { MmapedSafetensors::multi(&files) }
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let num_tensors = unsafe { MmapedSafetensors::multi(&first_files)? }
+        .tensors()
+        .len();
+    if others.iter().any(|o| o.tensors().len() != num_tensors) {
+        candle::bail!("the checkpoints do not have the same tensors")
+    }
+    if first.is_dir() {
+        std::fs::create_dir_all(output)?;
+        for entry in std::fs::read_dir(first)? {
+            let file = entry?.path();
+            if file.is_file() && !first_files.contains(&file) {
+                if let Some(name) = file.file_name() {
+                    std::fs::copy(&file, output.join(name))?;
+                }
+            }
+        }
+    }
+    let n = inputs.len() as f64;
+    for file in first_files.iter() {
+        let st = unsafe { MmapedSafetensors::new(file)? };
+        let mut averaged = HashMap::new();
+        for (name, _) in st.tensors() {
+            let t = st.load(&name, &Device::Cpu)?;
+            let dtype = t.dtype();
+            let acc_dtype = match dtype {
+                DType::F64 => DType::F64,
+                _ => DType::F32,
+            };
+            let mut acc = t.to_dtype(acc_dtype)?;
+            for other in others.iter() {
+                let o = other.load(&name, &Device::Cpu)?;
+                if o.shape() != t.shape() {
+                    candle::bail!(
+                        "shape mismatch for {name} between the checkpoints: {:?} <> {:?}",
+                        t.shape(),
+                        o.shape()
+                    )
+                }
+                if dtype.is_float() {
+                    acc = (acc + o.to_dtype(acc_dtype)?)?
+                }
+            }
+            let average = if dtype.is_float() {
+                (acc / n)?.to_dtype(dtype)?
+            } else {
+                t
+            };
+            averaged.insert(name, average);
+        }
+        let path = match file.file_name() {
+            Some(name) if first.is_dir() => output.join(name),
+            _ => output.to_path_buf(),
+        };
+        candle::safetensors::save(&averaged, path)?
+    }
+    Ok(())
+}
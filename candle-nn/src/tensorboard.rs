@@ -0,0 +1,325 @@
+//! Logging of training metrics in the TensorBoard format.
+//!
+//! A [`SummaryWriter`] appends events to a `events.out.tfevents.*` file in a log directory, the
+//! runs can then be monitored with `tensorboard --logdir <dir>`. The events are encoded as
+//! `tensorflow.Event` protobuf messages framed as TFRecords, the few messages needed for
+//! scalars, histograms and images are encoded by hand.
+//!
+//! ```no_run
+//! # fn main() -> candle::Result<()> {
+//! let mut writer = candle_nn::tensorboard::SummaryWriter::new("runs/mnist")?;
+//! for step in 0..100 {
+//!     let loss = 1. / (step + 1) as f64;
+//!     writer.add_scalar("train/loss", loss, step)?;
+//! }
+//! writer.flush()?;
+//! # Ok(())
+//! # }
+//! ```
+use candle::{DType, Device, Result, Tensor};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+/// The number of buckets of the histograms.
+pub const HISTOGRAM_BUCKETS: usize = 30;
+
+// The event field numbers, see tensorflow/core/util/event.proto and
+// tensorflow/core/framework/summary.proto.
+const EVENT_WALL_TIME: u32 = 1;
+const EVENT_STEP: u32 = 2;
+const EVENT_FILE_VERSION: u32 = 3;
+const EVENT_SUMMARY: u32 = 5;
+const SUMMARY_VALUE: u32 = 1;
+const VALUE_TAG: u32 = 1;
+const VALUE_SIMPLE_VALUE: u32 = 2;
+const VALUE_IMAGE: u32 = 4;
+const VALUE_HISTO: u32 = 5;
+
+/// Writes scalars, histograms and images to a TensorBoard event file.
+pub struct SummaryWriter {
+    file: std::io::BufWriter<std::fs::File>,
+    path: PathBuf,
+}
+
+impl SummaryWriter {
+    /// Creates the log directory if needed and a new event file in it.
+    pub fn new<P: AsRef<Path>>(log_dir: P) -> Result<Self> {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        // Distinguishes the files created by the same process in the same second.
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+        let log_dir = log_dir.as_ref();
+        std::fs::create_dir_all(log_dir)?;
+        let secs = wall_time() as u64;
+        let counter = COUNTER.fetch_add(1, Ordering::Relaxed);
+        let pid = std::process::id();
+        let path = log_dir.join(format!("events.out.tfevents.{secs}.candle.{pid}.{counter}"));
+        let file = std::fs::File::create(&path)?;
+        let mut writer = Self {
+            file: std::io::BufWriter::new(file),
+            path,
+        };
+        let mut event = Proto::default();
+        event.double(EVENT_WALL_TIME, wall_time());
+        event.bytes(EVENT_FILE_VERSION, b"brain.Event:2");
+        writer.write_record(&event.0)?;
+        writer.flush()?;
+        Ok(writer)
+    }
+
+    /// The path of the event file.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn add_scalar(&mut self, tag: &str, value: f64, step: usize) -> Result<()> {
+        let mut summary_value = Proto::default();
+        summary_value.bytes(VALUE_TAG, tag.as_bytes());
+        summary_value.float(VALUE_SIMPLE_VALUE, value as f32);
+        self.write_summary(summary_value, step)
+    }
+
+    /// Adds a histogram of the values of `xs`, with [`HISTOGRAM_BUCKETS`] buckets of the same width
+    /// between the smallest and largest values. The non-finite values are ignored.
+    pub fn add_histogram(&mut self, tag: &str, xs: &Tensor, step: usize) -> Result<()> {
+        let values = xs.flatten_all()?.to_dtype(DType::F64)?.to_vec1::<f64>()?;
+        let values: Vec<_> = values.into_iter().filter(|v| v.is_finite()).collect();
+        let mut histo = Proto::default();
+        if !values.is_empty() {
+            let min = values.iter().copied().fold(f64::INFINITY, f64::min);
+            let max = values.iter().copied().fold(f64::NEG_INFINITY, f64::max);
+            let n_buckets = if min == max { 1 } else { HISTOGRAM_BUCKETS };
+            let width = (max - min) / n_buckets as f64;
+            let mut buckets = vec![0f64; n_buckets];
+            for &v in values.iter() {
+                let i = if width > 0. {
+                    ((v - min) / width) as usize
+                } else {
+                    0
+                };
+                buckets[i.min(n_buckets - 1)] += 1.
+            }
+            // The limits are the right edges of the buckets.
+            let mut limits: Vec<_> = (1..n_buckets).map(|i| min + width * i as f64).collect();
+            limits.push(max);
+            // The HistogramProto fields: min, max, num, sum, sum_squares, bucket_limit, bucket.
+            histo.double(1, min);
+            histo.double(2, max);
+            histo.double(3, values.len() as f64);
+            histo.double(4, values.iter().sum());
+            histo.double(5, values.iter().map(|v| v * v).sum());
+            histo.packed_doubles(6, &limits);
+            histo.packed_doubles(7, &buckets);
+        }
+        let mut summary_value = Proto::default();
+        summary_value.bytes(VALUE_TAG, tag.as_bytes());
+        summary_value.bytes(VALUE_HISTO, &histo.0);
+        self.write_summary(summary_value, step)
+    }
+
+    /// Adds an image with shape `(channels, height, width)`, the number of channels being 1
+    /// (grayscale), 3 (rgb) or 4 (rgba). The `u8` images are used as is, the values of the other
+    /// dtypes are expected to be between 0 and 1 and are clamped to this range.
+    pub fn add_image(&mut self, tag: &str, image: &Tensor, step: usize) -> Result<()> {
+        let (channels, height, width) = image.dims3()?;
+        if !matches!(channels, 1 | 3 | 4) {
+            candle::bail!(
+                "images must have 1, 3 or 4 channels, got shape {:?}",
+                image.shape()
+            )
+        }
+        let image = image.to_device(&Device::Cpu)?;
+        let image = if image.dtype() == DType::U8 {
+            image
+        } else {
+            (image.to_dtype(DType::F32)?.clamp(0f32, 1f32)? * 255.)?
+                .round()?
+                .to_dtype(DType::U8)?
+        };
+        let pixels = image.permute((1, 2, 0))?.flatten_all()?.to_vec1::<u8>()?;
+        let png = encode_png(&pixels, width, height, channels)?;
+        // The Summary.Image fields: height, width, colorspace, encoded_image_string.
+        let mut proto = Proto::default();
+        proto.varint_field(1, height as u64);
+        proto.varint_field(2, width as u64);
+        proto.varint_field(3, channels as u64);
+        proto.bytes(4, &png);
+        let mut summary_value = Proto::default();
+        summary_value.bytes(VALUE_TAG, tag.as_bytes());
+        summary_value.bytes(VALUE_IMAGE, &proto.0);
+        self.write_summary(summary_value, step)
+    }
+
+    /// Writes the buffered events to the file, they are also written when the writer is dropped.
+    pub fn flush(&mut self) -> Result<()> {
+        self.file.flush()?;
+        Ok(())
+    }
+
+    fn write_summary(&mut self, summary_value: Proto, step: usize) -> Result<()> {
+        let mut summary = Proto::default();
+        summary.bytes(SUMMARY_VALUE, &summary_value.0);
+        let mut event = Proto::default();
+        event.double(EVENT_WALL_TIME, wall_time());
+        event.varint_field(EVENT_STEP, step as u64);
+        event.bytes(EVENT_SUMMARY, &summary.0);
+        self.write_record(&event.0)
+    }
+
+    // A TFRecord: the length, its masked crc, the data and its masked crc.
+    fn write_record(&mut self, data: &[u8]) -> Result<()> {
+        let len = (data.len() as u64).to_le_bytes();
+        self.file.write_all(&len)?;
+        self.file.write_all(&masked_crc32c(&len).to_le_bytes())?;
+        self.file.write_all(data)?;
+        self.file.write_all(&masked_crc32c(data).to_le_bytes())?;
+        Ok(())
+    }
+}
+
+impl Drop for SummaryWriter {
+    fn drop(&mut self) {
+        let _ = self.file.flush();
+    }
+}
+
+/// Reads the records of a TFRecord file such as a TensorBoard event file, returning an error if
+/// one of the checksums does not match.
+pub fn read_records<P: AsRef<Path>>(path: P) -> Result<Vec<Vec<u8>>> {
+    let path = path.as_ref();
+    let data = std::fs::read(path)?;
+    let mut records = vec![];
+    let mut rest = data.as_slice();
+    while !rest.is_empty() {
+        if rest.len() < 12 {
+            candle::bail!("truncated record in {path:?}")
+        }
+        let (header, tail) = rest.split_at(12);
+        let len_bytes: [u8; 8] = header[..8].try_into().unwrap();
+        let len_crc = u32::from_le_bytes(header[8..].try_into().unwrap());
+        if masked_crc32c(&len_bytes) != len_crc {
+            candle::bail!("invalid record length checksum in {path:?}")
+        }
+        let len = u64::from_le_bytes(len_bytes) as usize;
+        if tail.len() < len + 4 {
+            candle::bail!("truncated record in {path:?}")
+        }
+        let (record, tail) = tail.split_at(len);
+        let crc = u32::from_le_bytes(tail[..4].try_into().unwrap());
+        if masked_crc32c(record) != crc {
+            candle::bail!("invalid record checksum in {path:?}")
+        }
+        records.push(record.to_vec());
+        rest = &tail[4..];
+    }
+    Ok(records)
+}
+
+fn wall_time() -> f64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0., |d| d.as_secs_f64())
+}
+
+// The bitwise crc32 with the reversed polynomial `poly`, the event files are small enough for the
+// lack of lookup table not to matter.
+fn crc32(poly: u32, data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &b in data.iter() {
+        crc ^= b as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ poly
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+fn masked_crc32c(data: &[u8]) -> u32 {
+    let crc = crc32(0x82F6_3B78, data);
+    crc.rotate_right(15).wrapping_add(0xA282_EAD8)
+}
+
+// An 8 bits per channel png without filtering.
+fn encode_png(pixels: &[u8], width: usize, height: usize, channels: usize) -> Result<Vec<u8>> {
+    let color_type = match channels {
+        1 => 0u8,
+        3 => 2,
+        _ => 6,
+    };
+    let mut raw = Vec::with_capacity((width * channels + 1) * height);
+    for row in pixels.chunks(width * channels) {
+        raw.push(0u8);
+        raw.extend_from_slice(row);
+    }
+    let mut encoder = flate2::write::ZlibEncoder::new(vec![], flate2::Compression::default());
+    encoder.write_all(&raw)?;
+    let compressed = encoder.finish()?;
+
+    let mut png = b"\x89PNG\r\n\x1a\n".to_vec();
+    let mut chunk = |kind: &[u8], data: &[u8]| {
+        png.extend_from_slice(&(data.len() as u32).to_be_bytes());
+        let start = png.len();
+        png.extend_from_slice(kind);
+        png.extend_from_slice(data);
+        let crc = crc32(0xEDB8_8320, &png[start..]);
+        png.extend_from_slice(&crc.to_be_bytes());
+    };
+    let mut header = vec![];
+    header.extend_from_slice(&(width as u32).to_be_bytes());
+    header.extend_from_slice(&(height as u32).to_be_bytes());
+    header.extend_from_slice(&[8, color_type, 0, 0, 0]);
+    chunk(b"IHDR", &header);
+    chunk(b"IDAT", &compressed);
+    chunk(b"IEND", &[]);
+    Ok(png)
+}
+
+// A protobuf message being encoded.
+#[derive(Default)]
+struct Proto(Vec<u8>);
+
+impl Proto {
+    fn varint(&mut self, mut v: u64) {
+        while v >= 0x80 {
+            self.0.push((v as u8) | 0x80);
+            v >>= 7;
+        }
+        self.0.push(v as u8)
+    }
+
+    fn key(&mut self, field: u32, wire_type: u8) {
+        self.varint(((field as u64) << 3) | wire_type as u64)
+    }
+
+    fn varint_field(&mut self, field: u32, v: u64) {
+        self.key(field, 0);
+        self.varint(v)
+    }
+
+    fn double(&mut self, field: u32, v: f64) {
+        self.key(field, 1);
+        self.0.extend_from_slice(&v.to_le_bytes())
+    }
+
+    fn float(&mut self, field: u32, v: f32) {
+        self.key(field, 5);
+        self.0.extend_from_slice(&v.to_le_bytes())
+    }
+
+    fn bytes(&mut self, field: u32, v: &[u8]) {
+        self.key(field, 2);
+        self.varint(v.len() as u64);
+        self.0.extend_from_slice(v)
+    }
+
+    fn packed_doubles(&mut self, field: u32, vs: &[f64]) {
+        self.key(field, 2);
+        self.varint(vs.len() as u64 * 8);
+        for v in vs.iter() {
+            self.0.extend_from_slice(&v.to_le_bytes())
+        }
+    }
+}
@@ -0,0 +1,382 @@
+//! Transformer encoder and decoder layers.
+//!
+//! The layers follow the structure of the PyTorch `nn.TransformerEncoderLayer` and
+//! `nn.TransformerDecoderLayer` modules: a self-attention block, a cross-attention block for the
+//! decoder, and a feed-forward block, each with a residual connection and a layer norm applied
+//! after the residual sum or, with [`TransformerConfig::norm_first`], at the start of the block.
+//!
+//! ```ignore
+//! let config = TransformerConfig::new(512, 8).with_norm_first(true);
+//! let encoder = TransformerEncoder::new(&config, 6, vb.pp("encoder"))?;
+//! let padding = key_padding_mask(&src_padding)?;
+//! let memory = encoder.forward(&src, None, Some(&padding))?;
+//! ```
+//!
+//! The masks use the convention of [`crate::attention`], `1` marking the positions that cannot
+//! be attended to. The key padding masks have shape `(batch, kv_seq_len)`. The dropout is only
+//! applied in training mode, see [`crate::Layer::set_training`].
+use crate::attention::{key_padding_mask, merge_masks, MultiheadAttention};
+use crate::{Activation, Dropout, Layer, LayerNorm, Linear, VarBuilder};
+use candle::{Module, Result, Tensor};
+
+/// The configuration shared by the layers of a transformer.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TransformerConfig {
+    pub d_model: usize,
+    pub num_heads: usize,
+    /// The hidden size of the feed-forward blocks.
+    pub dim_feedforward: usize,
+    /// The dropout probability of the outputs of the blocks and of the feed-forward activations.
+    pub dropout: f32,
+    pub activation: Activation,
+    /// Applies the layer norms at the start of the blocks rather than after the residual sums.
+    pub norm_first: bool,
+    pub layer_norm_eps: f64,
+    /// Whether the linear layers have a bias.
+    pub bias: bool,
+}
+
+impl TransformerConfig {
+    /// A configuration with the defaults of PyTorch, except for the feed-forward dimension
+    /// which is `4 * d_model`.
+    pub fn new(d_model: usize, num_heads: usize) -> Self {
+        Self {
+            d_model,
+            num_heads,
+            dim_feedforward: 4 * d_model,
+            dropout: 0.1,
+            activation: Activation::Relu,
+            norm_first: false,
+            layer_norm_eps: 1e-5,
+            bias: true,
+        }
+    }
+
+    pub fn with_dim_feedforward(mut self, dim_feedforward: usize) -> Self {
+        self.dim_feedforward = dim_feedforward;
+        self
+    }
+
+    pub fn with_dropout(mut self, dropout: f32) -> Self {
+        self.dropout = dropout;
+        self
+    }
+
+    pub fn with_activation(mut self, activation: Activation) -> Self {
+        self.activation = activation;
+        self
+    }
+
+    pub fn with_norm_first(mut self, norm_first: bool) -> Self {
+        self.norm_first = norm_first;
+        self
+    }
+
+    pub fn with_layer_norm_eps(mut self, layer_norm_eps: f64) -> Self {
+        self.layer_norm_eps = layer_norm_eps;
+        self
+    }
+
+    pub fn with_bias(mut self, bias: bool) -> Self {
+        self.bias = bias;
+        self
+    }
+
+    fn layer_norm(&self, vb: VarBuilder) -> Result<LayerNorm> {
+        crate::layer_norm(self.d_model, self.layer_norm_eps, vb)
+    }
+
+    fn attention(&self, vb: VarBuilder) -> Result<MultiheadAttention> {
+        MultiheadAttention::new(self.d_model, self.num_heads, self.bias, vb)
+    }
+}
+
+// The attention mask and the key padding mask merged in a single mask.
+fn attention_mask(mask: Option<&Tensor>, key_padding: Option<&Tensor>) -> Result<Option<Tensor>> {
+    let key_padding = key_padding.map(key_padding_mask).transpose()?;
+    merge_masks(mask, key_padding.as_ref())
+}
+
+// The feed-forward block, `linear2(dropout(activation(linear1(xs))))`.
+#[derive(Debug, Clone)]
+struct FeedForward {
+    linear1: Linear,
+    linear2: Linear,
+    activation: Activation,
+}
+
+impl FeedForward {
+    fn new(config: &TransformerConfig, vb: &VarBuilder) -> Result<Self> {
+        let (d_model, dim_ff) = (config.d_model, config.dim_feedforward);
+        Ok(Self {
+            linear1: crate::linear_b(d_model, dim_ff, config.bias, vb.pp("linear1"))?,
+            linear2: crate::linear_b(dim_ff, d_model, config.bias, vb.pp("linear2"))?,
+            activation: config.activation,
+        })
+    }
+
+    fn forward(&self, xs: &Tensor, dropout: &Dropout) -> Result<Tensor> {
+        let xs = xs.apply(&self.linear1)?.apply(&self.activation)?;
+        dropout.forward_mode(&xs)?.apply(&self.linear2)
+    }
+}
+
+// Applies `block` to `xs` with a residual connection and the layer norm before or after.
+fn residual<F>(
+    xs: &Tensor,
+    norm: &LayerNorm,
+    norm_first: bool,
+    dropout: &Dropout,
+    block: F,
+) -> Result<Tensor>
+where
+    F: FnOnce(&Tensor) -> Result<Tensor>,
+{
+    if norm_first {
+        let ys = block(&xs.apply(norm)?)?;
+        xs + dropout.forward_mode(&ys)?
+    } else {
+        let ys = block(xs)?;
+        (xs + dropout.forward_mode(&ys)?)?.apply(norm)
+    }
+}
+
+/// A transformer encoder layer: self-attention and feed-forward blocks.
+#[derive(Debug, Clone)]
+pub struct TransformerEncoderLayer {
+    self_attn: MultiheadAttention,
+    ff: FeedForward,
+    norm1: LayerNorm,
+    norm2: LayerNorm,
+    dropout: Dropout,
+    norm_first: bool,
+}
+
+impl TransformerEncoderLayer {
+    pub fn new(config: &TransformerConfig, vb: VarBuilder) -> Result<Self> {
+        Ok(Self {
+            self_attn: config.attention(vb.pp("self_attn"))?,
+            ff: FeedForward::new(config, &vb)?,
+            norm1: config.layer_norm(vb.pp("norm1"))?,
+            norm2: config.layer_norm(vb.pp("norm2"))?,
+            dropout: Dropout::new(config.dropout),
+            norm_first: config.norm_first,
+        })
+    }
+
+    pub fn self_attn(&self) -> &MultiheadAttention {
+        &self.self_attn
+    }
+
+    /// Applies the layer to `src` of shape `(batch, seq_len, d_model)`.
+    pub fn forward(
+        &self,
+        src: &Tensor,
+        mask: Option<&Tensor>,
+        key_padding_mask: Option<&Tensor>,
+    ) -> Result<Tensor> {
+        let mask = attention_mask(mask, key_padding_mask)?;
+        let xs = residual(src, &self.norm1, self.norm_first, &self.dropout, |xs| {
+            self.self_attn.forward(xs, xs, xs, mask.as_ref())
+        })?;
+        residual(&xs, &self.norm2, self.norm_first, &self.dropout, |xs| {
+            self.ff.forward(xs, &self.dropout)
+        })
+    }
+}
+
+impl Layer for TransformerEncoderLayer {
+    fn visit_children(&mut self, f: &mut crate::layer::LayerFn) -> Result<()> {
+        f("self_attn", &mut self.self_attn)?;
+        f("linear1", &mut self.ff.linear1)?;
+        f("linear2", &mut self.ff.linear2)?;
+        f("norm1", &mut self.norm1)?;
+        f("norm2", &mut self.norm2)?;
+        f("dropout", &mut self.dropout)
+    }
+}
+
+/// A transformer decoder layer: self-attention, cross-attention over the encoder output and
+/// feed-forward blocks.
+#[derive(Debug, Clone)]
+pub struct TransformerDecoderLayer {
+    self_attn: MultiheadAttention,
+    multihead_attn: MultiheadAttention,
+    ff: FeedForward,
+    norm1: LayerNorm,
+    norm2: LayerNorm,
+    norm3: LayerNorm,
+    dropout: Dropout,
+    norm_first: bool,
+}
+
+impl TransformerDecoderLayer {
+    pub fn new(config: &TransformerConfig, vb: VarBuilder) -> Result<Self> {
+        Ok(Self {
+            self_attn: config.attention(vb.pp("self_attn"))?,
+            multihead_attn: config.attention(vb.pp("multihead_attn"))?,
+            ff: FeedForward::new(config, &vb)?,
+            norm1: config.layer_norm(vb.pp("norm1"))?,
+            norm2: config.layer_norm(vb.pp("norm2"))?,
+            norm3: config.layer_norm(vb.pp("norm3"))?,
+            dropout: Dropout::new(config.dropout),
+            norm_first: config.norm_first,
+        })
+    }
+
+    pub fn self_attn(&self) -> &MultiheadAttention {
+        &self.self_attn
+    }
+
+    /// The cross-attention over the encoder output.
+    pub fn multihead_attn(&self) -> &MultiheadAttention {
+        &self.multihead_attn
+    }
+
+    /// Applies the layer to `tgt` of shape `(batch, seq_len, d_model)` with the encoder output
+    /// `memory` of shape `(batch, memory_len, d_model)`. `tgt_mask` is usually a
+    /// [`crate::attention::causal_mask`].
+    pub fn forward(
+        &self,
+        tgt: &Tensor,
+        memory: &Tensor,
+        tgt_mask: Option<&Tensor>,
+        memory_mask: Option<&Tensor>,
+        tgt_key_padding_mask: Option<&Tensor>,
+        memory_key_padding_mask: Option<&Tensor>,
+    ) -> Result<Tensor> {
+        let tgt_mask = attention_mask(tgt_mask, tgt_key_padding_mask)?;
+        let memory_mask = attention_mask(memory_mask, memory_key_padding_mask)?;
+        let xs = residual(tgt, &self.norm1, self.norm_first, &self.dropout, |xs| {
+            self.self_attn.forward(xs, xs, xs, tgt_mask.as_ref())
+        })?;
+        let xs = residual(&xs, &self.norm2, self.norm_first, &self.dropout, |xs| {
+            self.multihead_attn
+                .forward(xs, memory, memory, memory_mask.as_ref())
+        })?;
+        residual(&xs, &self.norm3, self.norm_first, &self.dropout, |xs| {
+            self.ff.forward(xs, &self.dropout)
+        })
+    }
+}
+
+impl Layer for TransformerDecoderLayer {
+    fn visit_children(&mut self, f: &mut crate::layer::LayerFn) -> Result<()> {
+        f("self_attn", &mut self.self_attn)?;
+        f("multihead_attn", &mut self.multihead_attn)?;
+        f("linear1", &mut self.ff.linear1)?;
+        f("linear2", &mut self.ff.linear2)?;
+        f("norm1", &mut self.norm1)?;
+        f("norm2", &mut self.norm2)?;
+        f("norm3", &mut self.norm3)?;
+        f("dropout", &mut self.dropout)
+    }
+}
+
+/// A stack of [`TransformerEncoderLayer`] named `layers.{i}`. The pre-norm stacks end with a
+/// layer norm named `norm` as the outputs of their layers are not normalized.
+#[derive(Debug, Clone)]
+pub struct TransformerEncoder {
+    layers: Vec<TransformerEncoderLayer>,
+    norm: Option<LayerNorm>,
+}
+
+impl TransformerEncoder {
+    pub fn new(config: &TransformerConfig, num_layers: usize, vb: VarBuilder) -> Result<Self> {
+        let layers = (0..num_layers)
+            .map(|i| TransformerEncoderLayer::new(config, vb.pp(format!("layers.{i}"))))
+            .collect::<Result<Vec<_>>>()?;
+        let norm = if config.norm_first {
+            Some(config.layer_norm(vb.pp("norm"))?)
+        } else {
+            None
+        };
+        Ok(Self { layers, norm })
+    }
+
+    pub fn layers(&self) -> &[TransformerEncoderLayer] {
+        &self.layers
+    }
+
+    pub fn forward(
+        &self,
+        src: &Tensor,
+        mask: Option<&Tensor>,
+        key_padding_mask: Option<&Tensor>,
+    ) -> Result<Tensor> {
+        let mut xs = src.clone();
+        for layer in self.layers.iter() {
+            xs = layer.forward(&xs, mask, key_padding_mask)?
+        }
+        match &self.norm {
+            None => Ok(xs),
+            Some(norm) => norm.forward(&xs),
+        }
+    }
+}
+
+impl Layer for TransformerEncoder {
+    fn visit_children(&mut self, f: &mut crate::layer::LayerFn) -> Result<()> {
+        f("layers", &mut self.layers)?;
+        f("norm", &mut self.norm)
+    }
+}
+
+/// A stack of [`TransformerDecoderLayer`], with the same layout as [`TransformerEncoder`].
+#[derive(Debug, Clone)]
+pub struct TransformerDecoder {
+    layers: Vec<TransformerDecoderLayer>,
+    norm: Option<LayerNorm>,
+}
+
+impl TransformerDecoder {
+    pub fn new(config: &TransformerConfig, num_layers: usize, vb: VarBuilder) -> Result<Self> {
+        let layers = (0..num_layers)
+            .map(|i| TransformerDecoderLayer::new(config, vb.pp(format!("layers.{i}"))))
+            .collect::<Result<Vec<_>>>()?;
+        let norm = if config.norm_first {
+            Some(config.layer_norm(vb.pp("norm"))?)
+        } else {
+            None
+        };
+        Ok(Self { layers, norm })
+    }
+
+    pub fn layers(&self) -> &[TransformerDecoderLayer] {
+        &self.layers
+    }
+
+    /// See [`TransformerDecoderLayer::forward`].
+    pub fn forward(
+        &self,
+        tgt: &Tensor,
+        memory: &Tensor,
+        tgt_mask: Option<&Tensor>,
+        memory_mask: Option<&Tensor>,
+        tgt_key_padding_mask: Option<&Tensor>,
+        memory_key_padding_mask: Option<&Tensor>,
+    ) -> Result<Tensor> {
+        let mut xs = tgt.clone();
+        for layer in self.layers.iter() {
+            xs = layer.forward(
+                &xs,
+                memory,
+                tgt_mask,
+                memory_mask,
+                tgt_key_padding_mask,
+                memory_key_padding_mask,
+            )?
+        }
+        match &self.norm {
+            None => Ok(xs),
+            Some(norm) => norm.forward(&xs),
+        }
+    }
+}
+
+impl Layer for TransformerDecoder {
+    fn visit_children(&mut self, f: &mut crate::layer::LayerFn) -> Result<()> {
+        f("layers", &mut self.layers)?;
+        f("norm", &mut self.norm)
+    }
+}
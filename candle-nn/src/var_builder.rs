@@ -17,6 +17,7 @@ pub struct VarBuilderArgs<'a, B: Backend> {
     data: Arc<TensorData<B>>,
     path: Vec<String>,
     pub dtype: DType,
+    policy: Option<Arc<DTypePolicy>>,
     _phantom: std::marker::PhantomData<&'a B>,
 }
 
@@ -26,6 +27,7 @@ impl<B: Backend> Clone for VarBuilderArgs<'_, B> {
             data: self.data.clone(),
             path: self.path.clone(),
             dtype: self.dtype,
+            policy: self.policy.clone(),
             _phantom: self._phantom,
         }
     }
@@ -35,6 +37,57 @@ impl<B: Backend> Clone for VarBuilderArgs<'_, B> {
 /// use cases.
 pub type VarBuilder<'a> = VarBuilderArgs<'a, Box<dyn SimpleBackend + 'a>>;
 
+/// Per tensor dtypes, e.g. to keep the norms in f32 in a model loaded in f16.
+///
+/// The rules are patterns on the full tensor names, see [`crate::var_map::matches_pattern`], the
+/// first matching rule gives the dtype and the tensors matching no rule use the dtype of the
+/// `VarBuilder`. The norm and linear layers whose weights use a different dtype than their inputs
+/// only run with [`crate::autocast`] enabled.
+///
+/// ```rust
+/// use candle::DType;
+/// use candle_nn::var_builder::DTypePolicy;
+/// let policy = DTypePolicy::norms_in_f32().with_rule("lm_head.*", DType::F32);
+/// assert_eq!(policy.dtype_for("model.norm.weight"), Some(DType::F32));
+/// assert_eq!(policy.dtype_for("model.embed_tokens.weight"), None);
+/// ```
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct DTypePolicy {
+    rules: Vec<(String, DType)>,
+}
+
+impl DTypePolicy {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Keeps the norm weights and biases in f32, using the names of most checkpoints, e.g.
+    /// `input_layernorm`, `model.norm` or `ln_f`.
+    pub fn norms_in_f32() -> Self {
+        Self::new()
+            .with_rule("*norm*", DType::F32)
+            .with_rule("*ln_*", DType::F32)
+    }
+
+    /// Adds a rule, the rules are tried in the order in which they were added.
+    pub fn with_rule<S: Into<String>>(mut self, pattern: S, dtype: DType) -> Self {
+        self.rules.push((pattern.into(), dtype));
+        self
+    }
+
+    pub fn rules(&self) -> &[(String, DType)] {
+        &self.rules
+    }
+
+    /// The dtype of the first rule matching `name`, `None` if there is no such rule.
+    pub fn dtype_for(&self, name: &str) -> Option<DType> {
+        self.rules
+            .iter()
+            .find(|(pattern, _)| crate::var_map::matches_pattern(pattern, name))
+            .map(|(_, dtype)| *dtype)
+    }
+}
+
 struct TensorData<B: Backend> {
     backend: B,
     pub device: Device,
@@ -59,6 +112,19 @@ pub trait Backend: Send + Sync {
         dev: &Device,
     ) -> Result<Tensor>;
 
+    /// Retrieve a buffer, i.e. a tensor that is part of the model state but that is not trained.
+    /// By default this is the same as [`Backend::get`].
+    fn get_buffer(
+        &self,
+        s: Shape,
+        name: &str,
+        h: Self::Hints,
+        dtype: DType,
+        dev: &Device,
+    ) -> Result<Tensor> {
+        self.get(s, name, h, dtype, dev)
+    }
+
     fn contains_tensor(&self, name: &str) -> bool;
 }
 
@@ -73,6 +139,19 @@ pub trait SimpleBackend: Send + Sync {
         dev: &Device,
     ) -> Result<Tensor>;
 
+    /// Retrieve a buffer, i.e. a tensor that is part of the model state but that is not trained.
+    /// By default this is the same as [`SimpleBackend::get`].
+    fn get_buffer(
+        &self,
+        s: Shape,
+        name: &str,
+        h: crate::Init,
+        dtype: DType,
+        dev: &Device,
+    ) -> Result<Tensor> {
+        self.get(s, name, h, dtype, dev)
+    }
+
     fn contains_tensor(&self, name: &str) -> bool;
 }
 
@@ -89,6 +168,17 @@ impl Backend for Box<dyn SimpleBackend + '_> {
         self.as_ref().get(s, name, h, dtype, dev)
     }
 
+    fn get_buffer(
+        &self,
+        s: Shape,
+        name: &str,
+        h: Self::Hints,
+        dtype: DType,
+        dev: &Device,
+    ) -> Result<Tensor> {
+        self.as_ref().get_buffer(s, name, h, dtype, dev)
+    }
+
     fn contains_tensor(&self, name: &str) -> bool {
         self.as_ref().contains_tensor(name)
     }
@@ -104,6 +194,7 @@ impl<B: Backend> VarBuilderArgs<'_, B> {
             data: Arc::new(data),
             path: vec![],
             dtype,
+            policy: None,
             _phantom: std::marker::PhantomData,
         }
     }
@@ -119,6 +210,7 @@ impl<B: Backend> VarBuilderArgs<'_, B> {
             data: self.data.clone(),
             path: vec![],
             dtype: self.dtype,
+            policy: self.policy.clone(),
             _phantom: std::marker::PhantomData,
         }
     }
@@ -129,6 +221,7 @@ impl<B: Backend> VarBuilderArgs<'_, B> {
             data: self.data.clone(),
             path: vec![prefix.to_string()],
             dtype: self.dtype,
+            policy: self.policy.clone(),
             _phantom: std::marker::PhantomData,
         }
     }
@@ -142,6 +235,7 @@ impl<B: Backend> VarBuilderArgs<'_, B> {
             data: self.data.clone(),
             path,
             dtype: self.dtype,
+            policy: self.policy.clone(),
             _phantom: std::marker::PhantomData,
         }
     }
@@ -167,10 +261,34 @@ impl<B: Backend> VarBuilderArgs<'_, B> {
             data: self.data.clone(),
             path: self.path.clone(),
             dtype,
+            policy: self.policy.clone(),
+            _phantom: std::marker::PhantomData,
+        }
+    }
+
+    /// Clone the VarBuilder using `policy` for the dtypes of the tensors, this replaces the
+    /// previous policy if any. The dtypes explicitly requested with
+    /// [`Self::get_with_hints_dtype`] are not affected.
+    pub fn with_dtype_policy(&self, policy: DTypePolicy) -> Self {
+        Self {
+            data: self.data.clone(),
+            path: self.path.clone(),
+            dtype: self.dtype,
+            policy: Some(Arc::new(policy)),
             _phantom: std::marker::PhantomData,
         }
     }
 
+    pub fn dtype_policy(&self) -> Option<&DTypePolicy> {
+        self.policy.as_deref()
+    }
+
+    // The dtype of the tensor at the full path `path`.
+    fn dtype_for(&self, path: &str) -> DType {
+        let dtype = self.policy.as_ref().and_then(|p| p.dtype_for(path));
+        dtype.unwrap_or(self.dtype)
+    }
+
     fn path(&self, tensor_name: &str) -> String {
         if self.path.is_empty() {
             tensor_name.to_string()
@@ -194,7 +312,8 @@ impl<B: Backend> VarBuilderArgs<'_, B> {
         name: &str,
         hints: B::Hints,
     ) -> Result<Tensor> {
-        self.get_with_hints_dtype(s, name, hints, self.dtype)
+        let dtype = self.dtype_for(&self.path(name));
+        self.get_with_hints_dtype(s, name, hints, dtype)
     }
 
     /// Retrieve the tensor associated with the given name at the current path.
@@ -215,6 +334,27 @@ impl<B: Backend> VarBuilderArgs<'_, B> {
             .backend
             .get(s.into(), &path, hints, dtype, &self.data.device)
     }
+
+    /// Retrieve the buffer associated with the given name at the current path. Buffers are part
+    /// of the model state but are not trained, e.g. the running statistics of a batch norm, when
+    /// using a `VarMap` they are not returned by [`VarMap::all_vars`].
+    pub fn get_buffer_with_hints<S: Into<Shape>>(
+        &self,
+        s: S,
+        name: &str,
+        hints: B::Hints,
+    ) -> Result<Tensor> {
+        let path = self.path(name);
+        let dtype = self.dtype_for(&path);
+        self.data
+            .backend
+            .get_buffer(s.into(), &path, hints, dtype, &self.data.device)
+    }
+
+    /// Retrieve the buffer associated with the given name at the current path.
+    pub fn get_buffer<S: Into<Shape>>(&self, s: S, name: &str) -> Result<Tensor> {
+        self.get_buffer_with_hints(s, name, Default::default())
+    }
 }
 
 struct Zeros;
@@ -275,6 +415,17 @@ impl SimpleBackend for VarMap {
         VarMap::get(self, s, name, h, dtype, dev)
     }
 
+    fn get_buffer(
+        &self,
+        s: Shape,
+        name: &str,
+        h: crate::Init,
+        dtype: DType,
+        dev: &Device,
+    ) -> Result<Tensor> {
+        VarMap::get_buffer(self, s, name, h, dtype, dev)
+    }
+
     fn contains_tensor(&self, name: &str) -> bool {
         self.data().lock().unwrap().contains_key(name)
     }
@@ -465,6 +616,106 @@ impl SimpleBackend for candle::safetensors::SliceSafetensors<'_> {
     }
 }
 
+#[derive(serde::Deserialize)]
+struct SafetensorsIndexFile {
+    weight_map: HashMap<String, String>,
+}
+
+/// Safetensors files listed in a `model.safetensors.index.json` file, as used on the hugging
+/// face hub for large models. A file is only memory-mapped the first time one of its tensors
+/// is requested.
+pub struct IndexedSafetensors {
+    dir: std::path::PathBuf,
+    // The file for each tensor name, relative to `dir`.
+    weight_map: HashMap<String, String>,
+    files: std::sync::Mutex<HashMap<String, Arc<candle::safetensors::MmapedSafetensors>>>,
+}
+
+impl IndexedSafetensors {
+    /// Reads the index file, the safetensors files are looked up in the same directory.
+    ///
+    /// # Safety
+    ///
+    /// The unsafe is inherited from [`memmap2::MmapOptions`], the files are mapped lazily
+    /// when their tensors are loaded.
+    pub unsafe fn new<P: AsRef<std::path::Path>>(index_json_path: P) -> Result<Self> {
+        let index_json_path = index_json_path.as_ref();
+        let index = std::fs::read_to_string(index_json_path)
+            .map_err(|e| Error::from(e).with_path(index_json_path))?;
+        let index: SafetensorsIndexFile =
+            serde_json::from_str(&index).map_err(|e| Error::wrap(e).with_path(index_json_path))?;
+        let dir = match index_json_path.parent() {
+            Some(dir) => dir.to_path_buf(),
+            None => std::path::PathBuf::new(),
+        };
+        Ok(Self {
+            dir,
+            weight_map: index.weight_map,
+            files: std::sync::Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// The names of all the tensors listed in the index.
+    pub fn tensor_names(&self) -> impl Iterator<Item = &str> {
+        self.weight_map.keys().map(|k| k.as_str())
+    }
+
+    /// The distinct safetensors files referenced by the index.
+    pub fn files(&self) -> Vec<std::path::PathBuf> {
+        let files: std::collections::BTreeSet<_> = self.weight_map.values().collect();
+        files.into_iter().map(|f| self.dir.join(f)).collect()
+    }
+
+    fn file(&self, name: &str) -> Result<Arc<candle::safetensors::MmapedSafetensors>> {
+        let file = match self.weight_map.get(name) {
+            Some(file) => file,
+            None => Err(Error::CannotFindTensor {
+                path: name.to_string(),
+            }
+            .bt())?,
+        };
+        let mut files = self.files.lock().unwrap();
+        if let Some(st) = files.get(file) {
+            return Ok(st.clone());
+        }
+        let st = unsafe { candle::safetensors::MmapedSafetensors::new(self.dir.join(file))? };
+        let st = Arc::new(st);
+        files.insert(file.clone(), st.clone());
+        Ok(st)
+    }
+
+    /// Loads a single tensor, only the file that contains it gets memory-mapped.
+    pub fn load(&self, name: &str, dev: &Device) -> Result<Tensor> {
+        self.file(name)?.load(name, dev)
+    }
+}
+
+impl SimpleBackend for IndexedSafetensors {
+    fn get(
+        &self,
+        s: Shape,
+        name: &str,
+        _: crate::Init,
+        dtype: DType,
+        dev: &Device,
+    ) -> Result<Tensor> {
+        let tensor = self.load(name, dev)?.to_dtype(dtype)?;
+        if tensor.shape() != &s {
+            Err(candle::Error::UnexpectedShape {
+                msg: format!("shape mismatch for {name}"),
+                expected: s,
+                got: tensor.shape().clone(),
+            }
+            .bt())?
+        }
+        Ok(tensor)
+    }
+
+    fn contains_tensor(&self, name: &str) -> bool {
+        self.weight_map.contains_key(name)
+    }
+}
+
 impl<'a> VarBuilder<'a> {
     /// Initializes a `VarBuilder` using a custom backend.
     ///
@@ -481,6 +732,7 @@ impl<'a> VarBuilder<'a> {
             data: Arc::new(data),
             path: vec![],
             dtype,
+            policy: None,
             _phantom: std::marker::PhantomData,
         }
     }
@@ -521,6 +773,40 @@ impl<'a> VarBuilder<'a> {
         Ok(Self::from_backend(Box::new(tensors), dtype, dev.clone()))
     }
 
+    /// Initializes a `VarBuilder` that retrieves tensors from the safetensors files listed in a
+    /// `model.safetensors.index.json` file, see [`IndexedSafetensors`].
+    ///
+    /// # Safety
+    ///
+    /// The unsafe is inherited from [`memmap2::MmapOptions`].
+    pub unsafe fn from_sharded_safetensors<P: AsRef<std::path::Path>>(
+        index_json_path: P,
+        dtype: DType,
+        dev: &Device,
+    ) -> Result<Self> {
+        let tensors = IndexedSafetensors::new(index_json_path)?;
+        Ok(Self::from_backend(Box::new(tensors), dtype, dev.clone()))
+    }
+
+    /// Initializes a `VarBuilder` that retrieves tensors from safetensors files, the weights
+    /// selected by `packer` being converted to its layout once and cached in `cache_dir`, see
+    /// [`crate::weight_layout`].
+    ///
+    /// # Safety
+    ///
+    /// The unsafe is inherited from [`memmap2::MmapOptions`].
+    pub unsafe fn from_packed_safetensors<P: AsRef<std::path::Path>, Q: AsRef<std::path::Path>>(
+        paths: &[P],
+        packer: Box<dyn crate::weight_layout::WeightPacker>,
+        cache_dir: Q,
+        dtype: DType,
+        dev: &Device,
+    ) -> Result<Self> {
+        let tensors =
+            crate::weight_layout::PackedSafetensors::new(paths, packer, cache_dir, dtype)?;
+        Ok(Self::from_backend(Box::new(tensors), dtype, dev.clone()))
+    }
+
     /// Initializes a `VarBuilder` from a binary buffer in the safetensor format.
     pub fn from_buffered_safetensors(data: Vec<u8>, dtype: DType, dev: &Device) -> Result<Self> {
         let tensors = candle::safetensors::BufferedSafetensors::new(data)?;
@@ -588,6 +874,7 @@ impl<'a> VarBuilder<'a> {
         let dtype = self.dtype();
         let device = self.device().clone();
         let path = self.path.clone();
+        let policy = self.policy.clone();
         let backend = Rename::new(self, renamer);
         let backend: Box<dyn SimpleBackend + 'a> = Box::new(backend);
         let data = TensorData { backend, device };
@@ -595,6 +882,7 @@ impl<'a> VarBuilder<'a> {
             data: Arc::new(data),
             dtype,
             path,
+            policy,
             _phantom: std::marker::PhantomData,
         }
     }
@@ -747,6 +1035,21 @@ impl<R: Renamer + Sync + Send> SimpleBackend for Rename<'_, R> {
             .to_device(dev)
     }
 
+    fn get_buffer(
+        &self,
+        s: Shape,
+        name: &str,
+        h: crate::Init,
+        dtype: DType,
+        dev: &Device,
+    ) -> Result<Tensor> {
+        let name = self.renamer.rename(name);
+        self.inner
+            .to_dtype(dtype)
+            .get_buffer_with_hints(s, &name, h)?
+            .to_device(dev)
+    }
+
     fn contains_tensor(&self, name: &str) -> bool {
         let name = self.renamer.rename(name);
         self.inner.contains_tensor(&name)
@@ -1,16 +1,22 @@
 //! A `VarMap` is a store that holds named variables.
 //!
 use candle::{DType, Device, Result, Shape, Tensor, Var};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::sync::{Arc, Mutex};
 
 /// A `VarMap` is a store that holds named variables. Variables can be retrieved from the stores
 /// and new variables can be added by providing some initialization config in case they are
 /// missing.
 /// `VarMap` structures can be serialized in the safetensors format.
+///
+/// Some of the variables can be registered as buffers, these are part of the model state and
+/// saved with the other variables but are not trained, e.g. the running statistics of a batch
+/// norm or some attention masks. Buffers are not returned by [`VarMap::all_vars`] so they are
+/// not updated by the optimizers.
 #[derive(Clone)]
 pub struct VarMap {
     data: Arc<Mutex<HashMap<String, Var>>>,
+    buffers: Arc<Mutex<HashSet<String>>>,
 }
 
 impl VarMap {
@@ -18,14 +24,71 @@ impl VarMap {
     #[allow(clippy::new_without_default)]
     pub fn new() -> Self {
         let data = Arc::new(Mutex::new(HashMap::new()));
-        Self { data }
+        let buffers = Arc::new(Mutex::new(HashSet::new()));
+        Self { data, buffers }
     }
 
-    /// Retrieve all the variables currently stored in the map.
+    /// Retrieve all the trainable variables currently stored in the map, buffers are excluded.
+    ///
+    /// The variables are sorted by name so that the optimizers created from maps with the same
+    /// names have the same variable order, e.g. to restore a saved optimizer state.
     pub fn all_vars(&self) -> Vec<Var> {
+        self.named_vars().into_iter().map(|(_, var)| var).collect()
+    }
+
+    /// Retrieve all the trainable variables with their names, sorted by name. Buffers are
+    /// excluded.
+    pub fn named_vars(&self) -> Vec<(String, Var)> {
         let tensor_data = self.data.lock().unwrap();
-        #[allow(clippy::map_clone)]
-        tensor_data.values().map(|c| c.clone()).collect::<Vec<_>>()
+        let buffers = self.buffers.lock().unwrap();
+        let mut vars = tensor_data
+            .iter()
+            .filter(|(name, _)| !buffers.contains(*name))
+            .map(|(name, var)| (name.clone(), var.clone()))
+            .collect::<Vec<_>>();
+        vars.sort_by(|(a, _), (b, _)| a.cmp(b));
+        vars
+    }
+
+    /// Retrieve the trainable variables whose names match `pattern`, see [`matches_pattern`].
+    pub fn vars_matching(&self, pattern: &str) -> Vec<Var> {
+        self.named_vars()
+            .into_iter()
+            .filter(|(name, _)| matches_pattern(pattern, name))
+            .map(|(_, var)| var)
+            .collect()
+    }
+
+    /// Retrieve all the buffers currently stored in the map.
+    pub fn all_buffers(&self) -> Vec<Var> {
+        let tensor_data = self.data.lock().unwrap();
+        let buffers = self.buffers.lock().unwrap();
+        tensor_data
+            .iter()
+            .filter(|(name, _)| buffers.contains(*name))
+            .map(|(_, var)| var.clone())
+            .collect::<Vec<_>>()
+    }
+
+    /// Whether the variable `name` has been registered as a buffer.
+    pub fn is_buffer(&self, name: &str) -> bool {
+        self.buffers.lock().unwrap().contains(name)
+    }
+
+    /// Add a buffer holding a copy of `value`, an error is returned if a variable already uses
+    /// this name.
+    pub fn register_buffer<K: AsRef<str>>(&self, name: K, value: &Tensor) -> Result<Tensor> {
+        let name = name.as_ref();
+        let mut tensor_data = self.data.lock().unwrap();
+        if tensor_data.contains_key(name) {
+            candle::bail!("{name} is already in VarMap")
+        }
+        // Detaching ensures that the buffer does not share its storage with `value`.
+        let var = Var::from_tensor(&value.detach())?;
+        let tensor = var.as_tensor().clone();
+        tensor_data.insert(name.to_string(), var);
+        self.buffers.lock().unwrap().insert(name.to_string());
+        Ok(tensor)
     }
 
     /// Save the map in the safetensors format.
@@ -53,6 +116,37 @@ impl VarMap {
         Ok(())
     }
 
+    /// Save the map in a compressed checkpoint, see [`crate::compressed_checkpoint`].
+    pub fn save_compressed<P: AsRef<std::path::Path>>(
+        &self,
+        path: P,
+        config: &crate::compressed_checkpoint::CompressionConfig,
+    ) -> Result<()> {
+        let tensor_data = self.data.lock().unwrap();
+        let data = tensor_data
+            .iter()
+            .map(|(k, v)| (k.clone(), v.as_tensor().clone()))
+            .collect();
+        crate::compressed_checkpoint::save(&data, path, config)
+    }
+
+    /// Same as [`VarMap::load`] for a compressed checkpoint.
+    pub fn load_compressed<P: AsRef<std::path::Path>>(&mut self, path: P) -> Result<()> {
+        let path = path.as_ref();
+        let data = crate::compressed_checkpoint::load(path, &Device::Cpu)?;
+        let tensor_data = self.data.lock().unwrap();
+        for (name, var) in tensor_data.iter() {
+            let data = match data.get(name) {
+                Some(data) => data.to_device(var.device())?,
+                None => candle::bail!("cannot find {name} in {path:?}"),
+            };
+            if let Err(err) = var.set(&data) {
+                candle::bail!("error setting {name} using data from {path:?}: {err}",)
+            }
+        }
+        Ok(())
+    }
+
     /// Set a named variable to some value.
     pub fn set_one<K: AsRef<str>, V: AsRef<Tensor>>(&mut self, name: K, value: V) -> Result<()> {
         let tensor_data = self.data.lock().unwrap();
@@ -115,7 +209,66 @@ impl VarMap {
         Ok(tensor)
     }
 
+    /// Retrieve or add a new buffer, this is the same as [`VarMap::get`] but the variable is
+    /// registered as a buffer.
+    pub fn get_buffer<S: Into<Shape>>(
+        &self,
+        shape: S,
+        path: &str,
+        init: crate::Init,
+        dtype: DType,
+        device: &Device,
+    ) -> Result<Tensor> {
+        let tensor = self.get(shape, path, init, dtype, device)?;
+        self.buffers.lock().unwrap().insert(path.to_string());
+        Ok(tensor)
+    }
+
+    /// The variables and buffers of the map.
     pub fn data(&self) -> &Mutex<HashMap<String, Var>> {
         &self.data
     }
 }
+
+/// Whether the variable name `name` matches `pattern`, where `*` matches any sequence of
+/// characters, including dots, e.g. `*.bias`, `*norm*` or `encoder.layers.*.attn.*`. Patterns
+/// without a `*` have to be equal to the name.
+pub fn matches_pattern(pattern: &str, name: &str) -> bool {
+    let mut parts = pattern.split('*');
+    // The pattern always has a first part, possibly empty.
+    let first = parts.next().unwrap_or("");
+    let mut rest = match name.strip_prefix(first) {
+        Some(rest) => rest,
+        None => return false,
+    };
+    let parts = parts.collect::<Vec<_>>();
+    let Some((last, middle)) = parts.split_last() else {
+        return rest.is_empty();
+    };
+    for part in middle {
+        match rest.find(part) {
+            Some(index) => rest = &rest[index + part.len()..],
+            None => return false,
+        }
+    }
+    rest.ends_with(last)
+}
+
+/// Migrating a `VarMap` replaces its variables and buffers, modules created from the map should
+/// be migrated with the same [`crate::migrate::Migration`] so that they keep using the variables
+/// of the map.
+impl crate::migrate::Migrate for VarMap {
+    fn visit_tensors(&mut self, f: &mut dyn FnMut(&mut Tensor) -> Result<()>) -> Result<()> {
+        let mut tensor_data = self.data.lock().unwrap();
+        let mut names: Vec<_> = tensor_data.keys().cloned().collect();
+        names.sort();
+        for name in names {
+            if let Some(var) = tensor_data.get_mut(&name) {
+                let mut t = var.as_tensor().clone();
+                f(&mut t)?;
+                *var = Var::from_tensor(&t)?;
+            }
+        }
+        Ok(())
+    }
+}
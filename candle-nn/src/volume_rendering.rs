@@ -0,0 +1,240 @@
+//! Differentiable volume rendering, the building blocks of neural radiance fields (NeRF).
+//!
+//! A pixel is rendered by casting a ray from the camera, evaluating a field at points sampled
+//! along the ray and compositing the densities and colors of the points:
+//!
+//! - [`camera_rays`] returns the origin and direction of the ray for each pixel.
+//! - [`stratified_samples`] splits the rays in bins and samples a distance in each bin,
+//!   [`sample_points`] converts the distances to 3d points.
+//! - [`positional_encoding`] maps the points to sinusoidal features before the field network.
+//! - [`composite`] computes the opacity of each sample from its density and the transmittance,
+//!   i.e. the probability that the ray reaches the sample, and returns the expected color, depth
+//!   and opacity of the rays. All the operations are differentiable.
+//! - [`importance_samples`] samples new distances from the weights of a first rendering pass,
+//!   as done in the hierarchical sampling of NeRF.
+//!
+//! ```ignore
+//! let (origins, directions) = camera_rays(height, width, focal, &camera_to_world)?;
+//! let t_vals = stratified_samples(2., 6., origins.dim(0)?, 64, true, &device)?;
+//! let points = sample_points(&origins, &directions, &t_vals)?;
+//! let (densities, rgb) = field.forward(&positional_encoding(&points, 10, true)?)?;
+//! let render = composite(&densities, &rgb, &t_vals, &directions, false)?;
+//! let loss = (render.rgb - target)?.sqr()?.mean_all()?;
+//! ```
+use candle::{DType, Device, Result, Tensor, D};
+
+/// The rays of a pinhole camera looking along `-z` in its own frame, with `y` up.
+///
+/// `camera_to_world` has shape `(3, 4)` or `(4, 4)`, its first three columns rotate the camera
+/// frame to the world frame and the fourth one is the position of the camera. Returns the
+/// origins and the directions of the rays with shape `(height * width, 3)`, in row major order
+/// over the pixels. The directions are not normalized, their `z` component in the camera frame
+/// is `-1` so that the sample distances are depths.
+pub fn camera_rays(
+    height: usize,
+    width: usize,
+    focal: f64,
+    camera_to_world: &Tensor,
+) -> Result<(Tensor, Tensor)> {
+    let dev = camera_to_world.device();
+    let dtype = camera_to_world.dtype();
+    let mut dirs = Vec::with_capacity(height * width * 3);
+    for i in 0..height {
+        for j in 0..width {
+            let x = (j as f64 - width as f64 * 0.5) / focal;
+            let y = -(i as f64 - height as f64 * 0.5) / focal;
+            dirs.extend([x as f32, y as f32, -1.])
+        }
+    }
+    let dirs = Tensor::from_vec(dirs, (height * width, 3), dev)?.to_dtype(dtype)?;
+    let rotation = camera_to_world.narrow(0, 0, 3)?.narrow(1, 0, 3)?;
+    let translation = camera_to_world.narrow(0, 0, 3)?.narrow(1, 3, 1)?;
+    let directions = dirs.matmul(&rotation.t()?)?;
+    let origins = translation
+        .t()?
+        .broadcast_as((height * width, 3))?
+        .contiguous()?;
+    Ok((origins, directions))
+}
+
+/// Sample distances along `num_rays` rays, the interval `[near, far]` is split in
+/// `num_samples` bins of the same size. With `perturb`, a random distance is uniformly sampled in
+/// each bin, otherwise the start of each bin is used. Returns a tensor of shape `(num_rays,
+/// num_samples)` with increasing distances along each ray.
+pub fn stratified_samples(
+    near: f64,
+    far: f64,
+    num_rays: usize,
+    num_samples: usize,
+    perturb: bool,
+    device: &Device,
+) -> Result<Tensor> {
+    let bin = (far - near) / num_samples as f64;
+    let starts = Tensor::arange(0u32, num_samples as u32, device)?
+        .to_dtype(DType::F32)?
+        .affine(bin, near)?
+        .unsqueeze(0)?
+        .broadcast_as((num_rays, num_samples))?;
+    if perturb {
+        let offsets = (Tensor::rand(0f32, 1., (num_rays, num_samples), device)? * bin)?;
+        starts + offsets
+    } else {
+        starts.contiguous()
+    }
+}
+
+/// The points at distances `t_vals` of shape `(num_rays, num_samples)` along the rays, the
+/// result has shape `(num_rays, num_samples, 3)`.
+pub fn sample_points(origins: &Tensor, directions: &Tensor, t_vals: &Tensor) -> Result<Tensor> {
+    let t_vals = t_vals.unsqueeze(D::Minus1)?;
+    origins
+        .unsqueeze(1)?
+        .broadcast_add(&directions.unsqueeze(1)?.broadcast_mul(&t_vals)?)
+}
+
+/// Sinusoidal features for low dimensional inputs such as positions or view directions.
+///
+/// Each value `x` of the last dimension is mapped to `sin(2^k * pi * x)` and `cos(2^k * pi * x)`
+/// for `k` in `0..num_freqs`, the features are ordered by frequency and are preceded by the
+/// input when `include_input` is set. The last dimension of the result has size `dim * (2 *
+/// num_freqs + include_input)`.
+pub fn positional_encoding(xs: &Tensor, num_freqs: usize, include_input: bool) -> Result<Tensor> {
+    let mut features = Vec::with_capacity(2 * num_freqs + 1);
+    if include_input {
+        features.push(xs.clone())
+    }
+    for k in 0..num_freqs {
+        let scaled = (xs * (std::f64::consts::PI * (1u64 << k) as f64))?;
+        features.push(scaled.sin()?);
+        features.push(scaled.cos()?);
+    }
+    Tensor::cat(&features, D::Minus1)
+}
+
+/// The result of [`composite`], all the values are differentiable.
+#[derive(Debug, Clone)]
+pub struct Render {
+    /// The expected color of each ray, shape `(num_rays, channels)`.
+    pub rgb: Tensor,
+    /// The expected distance of each ray, shape `(num_rays,)`.
+    pub depth: Tensor,
+    /// The accumulated opacity of each ray, shape `(num_rays,)`.
+    pub opacity: Tensor,
+    /// The contribution of each sample, shape `(num_rays, num_samples)`.
+    pub weights: Tensor,
+}
+
+/// Alpha compositing of the samples along the rays.
+///
+/// `densities` has shape `(num_rays, num_samples)` and is passed through a relu, `colors` has
+/// shape `(num_rays, num_samples, channels)`, `t_vals` are the increasing distances of the
+/// samples and `directions` the ray directions of shape `(num_rays, 3)`. Each sample covers the
+/// interval up to the next one, the last sample extends to infinity. The opacity of a sample is
+/// `1 - exp(-density * length)` with the length of its interval and its weight is its opacity
+/// times the transmittance `exp(-sum of density * length over the previous samples)`.
+///
+/// With `white_background`, the missing opacity of the rays is filled with white.
+pub fn composite(
+    densities: &Tensor,
+    colors: &Tensor,
+    t_vals: &Tensor,
+    directions: &Tensor,
+    white_background: bool,
+) -> Result<Render> {
+    let (num_rays, num_samples) = t_vals.dims2()?;
+    let deltas = if num_samples > 1 {
+        let next = t_vals.narrow(1, 1, num_samples - 1)?;
+        let prev = t_vals.narrow(1, 0, num_samples - 1)?;
+        let last =
+            Tensor::full(1e10f32, (num_rays, 1), t_vals.device())?.to_dtype(t_vals.dtype())?;
+        Tensor::cat(&[(next - prev)?, last], 1)?
+    } else {
+        Tensor::full(1e10f32, (num_rays, 1), t_vals.device())?.to_dtype(t_vals.dtype())?
+    };
+    let norms = directions.sqr()?.sum_keepdim(1)?.sqrt()?;
+    let deltas = deltas.broadcast_mul(&norms)?;
+    let optical_depth = (densities.relu()? * deltas)?;
+    let alpha = (1. - optical_depth.neg()?.exp()?)?;
+    // The transmittance of a sample only includes the previous samples, the optical depths are
+    // shifted rather than subtracting the inclusive sum as the last one is very large.
+    let zeros = optical_depth.narrow(1, 0, 1)?.zeros_like()?;
+    let previous = optical_depth.narrow(1, 0, num_samples - 1)?;
+    let transmittance = Tensor::cat(&[zeros, previous], 1)?
+        .cumsum(1)?
+        .neg()?
+        .exp()?;
+    let weights = (alpha * transmittance)?;
+    let rgb = colors.broadcast_mul(&weights.unsqueeze(2)?)?.sum(1)?;
+    let depth = (&weights * t_vals)?.sum(1)?;
+    let opacity = weights.sum(1)?;
+    let rgb = if white_background {
+        rgb.broadcast_add(&(1. - &opacity)?.unsqueeze(1)?)?
+    } else {
+        rgb
+    };
+    Ok(Render {
+        rgb,
+        depth,
+        opacity,
+        weights,
+    })
+}
+
+/// Samples `num_samples` distances per ray from the piecewise constant distribution defined by
+/// `weights` of shape `(num_rays, num_bins)` over the bins delimited by `bin_edges` of shape
+/// `(num_rays, num_bins + 1)`. With `deterministic`, the quantiles are evenly spaced, otherwise
+/// they are random. The distances are sorted along each ray.
+///
+/// The sampling is not differentiable, the result is detached from the graph. The new
+/// distances are usually merged with the initial ones before evaluating the fine network.
+pub fn importance_samples(
+    bin_edges: &Tensor,
+    weights: &Tensor,
+    num_samples: usize,
+    deterministic: bool,
+) -> Result<Tensor> {
+    let (num_rays, num_bins) = weights.dims2()?;
+    if bin_edges.dims2()? != (num_rays, num_bins + 1) {
+        candle::bail!(
+            "unexpected bin edges shape {:?} for weights {:?}",
+            bin_edges.shape(),
+            weights.shape()
+        )
+    }
+    let dev = weights.device();
+    let edges = bin_edges.to_dtype(DType::F32)?.to_vec2::<f32>()?;
+    let weights = weights.to_dtype(DType::F32)?.to_vec2::<f32>()?;
+    let quantiles = if deterministic {
+        let q = (0..num_samples)
+            .map(|i| (i as f32 + 0.5) / num_samples as f32)
+            .collect::<Vec<_>>();
+        vec![q; num_rays]
+    } else {
+        let q = Tensor::rand(0f32, 1., (num_rays, num_samples), &Device::Cpu)?;
+        let mut q = q.to_vec2::<f32>()?;
+        q.iter_mut().for_each(|q| q.sort_by(|a, b| a.total_cmp(b)));
+        q
+    };
+    let mut samples = Vec::with_capacity(num_rays * num_samples);
+    for ((edges, weights), quantiles) in edges.iter().zip(weights.iter()).zip(quantiles.iter()) {
+        // Avoid empty distributions, e.g. for rays that do not hit anything.
+        let weights = weights.iter().map(|w| w.max(0.) + 1e-5).collect::<Vec<_>>();
+        let total: f32 = weights.iter().sum();
+        let mut cdf = Vec::with_capacity(num_bins + 1);
+        cdf.push(0f32);
+        for w in weights.iter() {
+            cdf.push(cdf[cdf.len() - 1] + w / total)
+        }
+        let mut bin = 0;
+        for &q in quantiles.iter() {
+            while bin + 1 < num_bins && cdf[bin + 1] <= q {
+                bin += 1
+            }
+            let (c0, c1) = (cdf[bin], cdf[bin + 1]);
+            let frac = if c1 > c0 { (q - c0) / (c1 - c0) } else { 0. };
+            let frac = frac.clamp(0., 1.);
+            samples.push(edges[bin] + frac * (edges[bin + 1] - edges[bin]))
+        }
+    }
+    Tensor::from_vec(samples, (num_rays, num_samples), dev)?.to_dtype(bin_edges.dtype())
+}
@@ -0,0 +1,353 @@
+//! Patching the weights of a loaded model in place.
+//!
+//! The weights of a model are shared by all the clones of their tensors, a [`WeightPatcher`]
+//! updates them in place so that a model keeps running with the patched weights without having
+//! to reload the base checkpoint. This can be used to merge LoRA adapters or to add task vectors,
+//! i.e. the difference between the weights of a fine-tune and of the base model.
+//!
+//! The patcher keeps a copy of the original value of each patched tensor, removing a patch
+//! recomputes the tensors it touched from these copies and the remaining patches, so that the
+//! weights come back exactly to the base values, e.g. when A/B testing fine-tunes in a server.
+//! The tensors are updated one after the other so the patches should be applied between two
+//! forward passes.
+//!
+//! ```ignore
+//! let (vb, weights) = track_weights(vb);
+//! let model = Model::new(&cfg, vb)?;
+//! let mut patcher = WeightPatcher::new(weights.tensors());
+//! let delta = WeightDelta::new()
+//!     .with_lora("model.layers.0.self_attn.q_proj.weight", lora)
+//!     .with_dense("lm_head.weight", task_vector);
+//! let patch = patcher.apply(&delta)?;
+//! // ... run the model with the patched weights.
+//! patcher.remove(patch)?;
+//! ```
+use crate::multi_lora::LoraWeights;
+use crate::var_builder::SimpleBackend;
+use crate::{VarBuilder, VarMap};
+use candle::{DType, Device, Result, Shape, Tensor};
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+
+/// The change to a weight tensor.
+#[derive(Debug, Clone)]
+enum Update {
+    /// A tensor with the shape of the weight.
+    Dense(Tensor),
+    /// A low rank update, merged into a `(out_dim, in_dim)` weight.
+    Lora(LoraWeights),
+}
+
+impl Update {
+    fn delta(&self) -> Result<Tensor> {
+        match self {
+            Self::Dense(t) => Ok(t.clone()),
+            Self::Lora(lora) => lora.delta(),
+        }
+    }
+
+    fn shape(&self) -> Result<Shape> {
+        match self {
+            Self::Dense(t) => Ok(t.shape().clone()),
+            Self::Lora(lora) => Ok(lora.delta_shape().into()),
+        }
+    }
+}
+
+/// Changes to apply to some weights, identified by their full names, each change is multiplied
+/// by the scale of the delta.
+#[derive(Debug, Clone)]
+pub struct WeightDelta {
+    updates: Vec<(String, Update)>,
+    scale: f64,
+}
+
+impl Default for WeightDelta {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl WeightDelta {
+    pub fn new() -> Self {
+        Self {
+            updates: vec![],
+            scale: 1.0,
+        }
+    }
+
+    /// Adds `delta` to the weight `name`, e.g. a task vector.
+    pub fn with_dense(mut self, name: impl ToString, delta: Tensor) -> Self {
+        self.updates.push((name.to_string(), Update::Dense(delta)));
+        self
+    }
+
+    /// Merges a LoRA adapter in the weight `name`, adding `scale * b a` to it.
+    pub fn with_lora(mut self, name: impl ToString, lora: LoraWeights) -> Self {
+        self.updates.push((name.to_string(), Update::Lora(lora)));
+        self
+    }
+
+    /// Multiplies all the changes by `scale`, e.g. to interpolate between a base model and a
+    /// fine-tune.
+    pub fn with_scale(mut self, scale: f64) -> Self {
+        self.scale = scale;
+        self
+    }
+
+    /// Builds a delta from tensors named as the weights they change.
+    pub fn from_dense(deltas: HashMap<String, Tensor>) -> Self {
+        let mut deltas = deltas.into_iter().collect::<Vec<_>>();
+        deltas.sort_by(|(a, _), (b, _)| a.cmp(b));
+        deltas
+            .into_iter()
+            .fold(Self::new(), |delta, (name, t)| delta.with_dense(name, t))
+    }
+
+    /// The names of the weights that are changed.
+    pub fn names(&self) -> Vec<&str> {
+        self.updates.iter().map(|(name, _)| name.as_str()).collect()
+    }
+}
+
+/// The id of a patch applied by a [`WeightPatcher`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct PatchId(usize);
+
+/// The weights recorded by a [`VarBuilder`] returned by [`track_weights`].
+#[derive(Debug, Clone, Default)]
+pub struct TrackedWeights(Arc<Mutex<HashMap<String, Vec<Tensor>>>>);
+
+impl TrackedWeights {
+    /// The tensors returned for each name, a name fetched several times has several tensors
+    /// unless the backend returned the same tensor.
+    pub fn tensors(&self) -> HashMap<String, Vec<Tensor>> {
+        self.0.lock().unwrap().clone()
+    }
+
+    fn record(&self, name: &str, t: &Tensor) {
+        let mut tensors = self.0.lock().unwrap();
+        let tensors = tensors.entry(name.to_string()).or_default();
+        if !tensors.iter().any(|v| v.id() == t.id()) {
+            tensors.push(t.clone())
+        }
+    }
+}
+
+struct Tracker<'a> {
+    inner: VarBuilder<'a>,
+    weights: TrackedWeights,
+}
+
+impl SimpleBackend for Tracker<'_> {
+    fn get(
+        &self,
+        s: Shape,
+        name: &str,
+        h: crate::Init,
+        dtype: DType,
+        dev: &Device,
+    ) -> Result<Tensor> {
+        let t = self
+            .inner
+            .get_with_hints_dtype(s, name, h, dtype)?
+            .to_device(dev)?;
+        self.weights.record(name, &t);
+        Ok(t)
+    }
+
+    fn get_buffer(
+        &self,
+        s: Shape,
+        name: &str,
+        h: crate::Init,
+        dtype: DType,
+        dev: &Device,
+    ) -> Result<Tensor> {
+        self.inner
+            .to_dtype(dtype)
+            .get_buffer_with_hints(s, name, h)?
+            .to_device(dev)
+    }
+
+    fn contains_tensor(&self, name: &str) -> bool {
+        self.inner.contains_tensor(name)
+    }
+}
+
+/// Wraps `vb` so that the weights it returns are recorded with their full names, the buffers are
+/// not recorded. The weights that a model transforms when loading them, e.g. by concatenating
+/// them, cannot be patched.
+pub fn track_weights(vb: VarBuilder<'_>) -> (VarBuilder<'_>, TrackedWeights) {
+    let weights = TrackedWeights::default();
+    let (prefix, dtype, device) = (vb.prefix(), vb.dtype(), vb.device().clone());
+    let tracker = Tracker {
+        inner: vb.root(),
+        weights: weights.clone(),
+    };
+    let vb = VarBuilder::from_backend(Box::new(tracker), dtype, device);
+    let vb = if prefix.is_empty() {
+        vb
+    } else {
+        vb.set_prefix(prefix)
+    };
+    (vb, weights)
+}
+
+/// Applies and removes [`WeightDelta`]s in place on named weights.
+pub struct WeightPatcher {
+    weights: HashMap<String, Vec<Tensor>>,
+    // The original values of the patched weights.
+    originals: HashMap<String, Tensor>,
+    patches: Vec<(PatchId, WeightDelta)>,
+    next_id: usize,
+}
+
+impl WeightPatcher {
+    /// The weights can be shared with a running model, the tensors are modified in place.
+    pub fn new(weights: HashMap<String, Vec<Tensor>>) -> Self {
+        Self {
+            weights,
+            originals: HashMap::new(),
+            patches: vec![],
+            next_id: 0,
+        }
+    }
+
+    /// Patches the variables of a `VarMap` and of the modules created from it.
+    pub fn from_varmap(varmap: &VarMap) -> Self {
+        let weights = varmap
+            .data()
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(name, var)| (name.clone(), vec![var.as_tensor().clone()]))
+            .collect();
+        Self::new(weights)
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.weights.contains_key(name)
+    }
+
+    /// The ids of the applied patches, in the order they were applied.
+    pub fn patches(&self) -> Vec<PatchId> {
+        self.patches.iter().map(|(id, _)| *id).collect()
+    }
+
+    fn weight(&self, name: &str) -> Result<&Tensor> {
+        match self.weights.get(name).and_then(|ts| ts.first()) {
+            Some(t) => Ok(t),
+            None => candle::bail!("no weight named {name} to patch"),
+        }
+    }
+
+    // The value of the weight `name` with the original value and the applied patches.
+    fn patched_value(&self, name: &str, extra: Option<&WeightDelta>) -> Result<Tensor> {
+        let weight = self.weight(name)?;
+        let original = self.originals.get(name).unwrap_or(weight);
+        let mut value = original.to_dtype(DType::F32)?;
+        let patches = self.patches.iter().map(|(_, delta)| delta).chain(extra);
+        for delta in patches {
+            for (_, update) in delta.updates.iter().filter(|(n, _)| n == name) {
+                let update = update
+                    .delta()?
+                    .to_device(weight.device())?
+                    .to_dtype(DType::F32)?;
+                value = (value + (update * delta.scale)?)?;
+            }
+        }
+        value.to_dtype(weight.dtype())
+    }
+
+    // Writes the new values, all of them being computed before any weight is modified.
+    fn write(&mut self, values: Vec<(String, Tensor)>) -> Result<()> {
+        for (name, value) in values {
+            let weights = &self.weights[&name];
+            if !self.originals.contains_key(&name) {
+                self.originals.insert(name.clone(), weights[0].copy()?);
+            }
+            for weight in weights.iter() {
+                weight.slice_set(&value, 0, 0)?
+            }
+        }
+        Ok(())
+    }
+
+    /// Applies `delta` on top of the already applied patches. Nothing is modified if one of the
+    /// changes does not match its weight.
+    pub fn apply(&mut self, delta: &WeightDelta) -> Result<PatchId> {
+        for (name, update) in delta.updates.iter() {
+            let weight = self.weight(name)?;
+            let shape = update.shape()?;
+            if &shape != weight.shape() {
+                candle::bail!(
+                    "delta for {name} with shape {shape:?} does not match the weight {:?}",
+                    weight.shape()
+                )
+            }
+            if !weight.is_contiguous() {
+                candle::bail!("cannot patch {name} in place, the weight is not contiguous")
+            }
+        }
+        let names = unique_names(delta.names());
+        let values = names
+            .into_iter()
+            .map(|name| Ok((name.to_string(), self.patched_value(name, Some(delta))?)))
+            .collect::<Result<Vec<_>>>()?;
+        self.write(values)?;
+        let id = PatchId(self.next_id);
+        self.next_id += 1;
+        self.patches.push((id, delta.clone()));
+        Ok(id)
+    }
+
+    /// Removes a patch, the weights it changed are recomputed from their original values and the
+    /// other patches.
+    pub fn remove(&mut self, id: PatchId) -> Result<WeightDelta> {
+        let index = match self.patches.iter().position(|(i, _)| *i == id) {
+            Some(index) => index,
+            None => candle::bail!("no patch with id {id:?}"),
+        };
+        let (_, delta) = self.patches.remove(index);
+        let values = unique_names(delta.names())
+            .into_iter()
+            .map(|name| Ok((name.to_string(), self.patched_value(name, None)?)))
+            .collect::<Result<Vec<_>>>();
+        match values.and_then(|values| self.write(values)) {
+            Ok(()) => Ok(delta),
+            Err(err) => {
+                self.patches.insert(index, (id, delta));
+                Err(err)
+            }
+        }
+    }
+
+    /// Removes the most recent patch, if any.
+    pub fn rollback(&mut self) -> Result<Option<WeightDelta>> {
+        match self.patches.last() {
+            Some((id, _)) => self.remove(*id).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// Removes all the patches, restoring the original weights.
+    pub fn reset(&mut self) -> Result<()> {
+        for (name, original) in self.originals.iter() {
+            for weight in self.weights[name].iter() {
+                weight.slice_set(original, 0, 0)?
+            }
+        }
+        self.originals.clear();
+        self.patches.clear();
+        Ok(())
+    }
+}
+
+fn unique_names(names: Vec<&str>) -> Vec<&str> {
+    let mut seen = HashSet::new();
+    names
+        .into_iter()
+        .filter(|name| seen.insert(*name))
+        .collect()
+}
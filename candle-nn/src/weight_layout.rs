@@ -0,0 +1,271 @@
+//! Weights stored in the layout preferred by the matmul kernels.
+//!
+//! Some kernels are faster when the weights use a different layout than the one of the
+//! checkpoints, e.g. a contiguous `(in, out)` matrix rather than the `(out, in)` matrix of a
+//! linear layer, or some interleaved 4-bit values. Converting the weights on each load can take
+//! a significant part of the start-up time of large models, so [`PackedSafetensors`] caches the
+//! converted tensors on disk. The cache files are keyed by the source files, their size and
+//! modification time, the target dtype and the name and version of the [`WeightPacker`], so
+//! changing any of these, e.g. when a kernel starts using a new layout, repacks the weights.
+//!
+//! ```no_run
+//! # fn main() -> candle::Result<()> {
+//! use candle_nn::weight_layout::Transposed;
+//! let (dtype, device) = (candle::DType::F16, candle::Device::Cpu);
+//! let vb = unsafe {
+//!     candle_nn::VarBuilder::from_packed_safetensors(
+//!         &["model.safetensors"],
+//!         Box::new(Transposed::default()),
+//!         "/tmp/candle-packed",
+//!         dtype,
+//!         &device,
+//!     )?
+//! };
+//! # Ok(())
+//! # }
+//! ```
+use crate::var_map::matches_pattern;
+use candle::safetensors::MmapedSafetensors;
+use candle::{DType, Device, Result, Shape, Tensor};
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+
+// The safetensors metadata key holding the full cache key, this guards against hash collisions.
+const CACHE_KEY: &str = "candle.packed_key";
+// Bumped when the cache file format changes.
+const CACHE_VERSION: u32 = 1;
+
+/// A conversion of some weights to a kernel specific layout.
+pub trait WeightPacker: Send + Sync {
+    /// The name of the layout, part of the cache key.
+    fn name(&self) -> &str;
+
+    /// The version of the layout, to be bumped when the packed form changes.
+    fn version(&self) -> u32;
+
+    /// Whether the tensor `name` has to be packed.
+    fn should_pack(&self, name: &str, shape: &[usize]) -> bool;
+
+    /// Converts a tensor to the packed layout, this is done on the cpu.
+    fn pack(&self, tensor: &Tensor) -> Result<Tensor>;
+
+    /// Returns a tensor with the original shape, ideally a view on the packed storage. This is
+    /// what gets returned to the layers created through a `VarBuilder`, the kernels that use the
+    /// packed form directly should get it with [`PackedSafetensors::load_packed`] instead.
+    fn unpack(&self, packed: Tensor) -> Result<Tensor>;
+}
+
+/// Stores the two dimensional weights transposed, the linear layers compute `x @ w.t()` so their
+/// matmuls get a contiguous right-hand side rather than a transposed one.
+#[derive(Debug, Clone)]
+pub struct Transposed {
+    /// The patterns of the weights to keep as is, see [`matches_pattern`]. The embeddings are
+    /// skipped by default as their rows get selected.
+    pub skip: Vec<String>,
+}
+
+impl Default for Transposed {
+    fn default() -> Self {
+        let skip = ["*embed*", "*wte*", "*wpe*"];
+        Self {
+            skip: skip.iter().map(|s| s.to_string()).collect(),
+        }
+    }
+}
+
+impl Transposed {
+    pub fn with_skip<S: Into<String>>(mut self, pattern: S) -> Self {
+        self.skip.push(pattern.into());
+        self
+    }
+}
+
+impl WeightPacker for Transposed {
+    fn name(&self) -> &str {
+        "transposed"
+    }
+
+    fn version(&self) -> u32 {
+        1
+    }
+
+    fn should_pack(&self, name: &str, shape: &[usize]) -> bool {
+        shape.len() == 2
+            && name.ends_with("weight")
+            && !self.skip.iter().any(|p| matches_pattern(p, name))
+    }
+
+    fn pack(&self, tensor: &Tensor) -> Result<Tensor> {
+        tensor.t()?.contiguous()
+    }
+
+    fn unpack(&self, packed: Tensor) -> Result<Tensor> {
+        packed.t()
+    }
+}
+
+// The 64 bits FNV-1a hash, used for the cache file names as it is stable across platforms and
+// compiler versions.
+fn fnv1a(data: &[u8]) -> u64 {
+    data.iter().fold(0xcbf2_9ce4_8422_2325, |h, &b| {
+        (h ^ b as u64).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+fn cache_key(path: &Path, packer: &dyn WeightPacker, dtype: DType) -> Result<String> {
+    let metadata = std::fs::metadata(path).map_err(|e| candle::Error::from(e).with_path(path))?;
+    let modified = metadata
+        .modified()
+        .ok()
+        .and_then(|m| m.duration_since(std::time::UNIX_EPOCH).ok())
+        .map_or(0, |d| d.as_nanos());
+    let path = std::fs::canonicalize(path)?;
+    Ok(format!(
+        "v{CACHE_VERSION} candle-{} {}:{} {} {path:?} {} {modified}",
+        env!("CARGO_PKG_VERSION"),
+        packer.name(),
+        packer.version(),
+        dtype.as_str(),
+        metadata.len(),
+    ))
+}
+
+// Reads the cache key of a cache file without mapping the whole file.
+fn read_cache_key(path: &Path) -> Option<String> {
+    use std::io::Read;
+    let mut file = std::fs::File::open(path).ok()?;
+    let mut len = [0u8; 8];
+    file.read_exact(&mut len).ok()?;
+    let mut header = vec![0u8; u64::from_le_bytes(len).min(100_000_000) as usize];
+    file.read_exact(&mut header).ok()?;
+    let header: serde_json::Value = serde_json::from_slice(&header).ok()?;
+    let key = header.get("__metadata__")?.get(CACHE_KEY)?.as_str()?;
+    Some(key.to_string())
+}
+
+/// Safetensors files whose weights are served in a packed layout, the packed tensors are cached
+/// in a directory, the other ones are read from the source files.
+pub struct PackedSafetensors {
+    source: MmapedSafetensors,
+    packed: MmapedSafetensors,
+    packed_names: HashSet<String>,
+    packer: Box<dyn WeightPacker>,
+    cache_files: Vec<PathBuf>,
+    created: usize,
+}
+
+impl PackedSafetensors {
+    /// Maps the source files and the cache files for `packer` and `dtype` in `cache_dir`, the
+    /// missing or outdated cache files are created first.
+    ///
+    /// # Safety
+    ///
+    /// The unsafe is inherited from [`memmap2::MmapOptions`].
+    pub unsafe fn new<P: AsRef<Path>, Q: AsRef<Path>>(
+        paths: &[P],
+        packer: Box<dyn WeightPacker>,
+        cache_dir: Q,
+        dtype: DType,
+    ) -> Result<Self> {
+        let cache_dir = cache_dir.as_ref();
+        std::fs::create_dir_all(cache_dir)?;
+        let mut cache_files = Vec::with_capacity(paths.len());
+        let mut created = 0;
+        for path in paths.iter() {
+            let path = path.as_ref();
+            let key = cache_key(path, packer.as_ref(), dtype)?;
+            let file = cache_dir.join(format!("{:016x}.safetensors", fnv1a(key.as_bytes())));
+            if read_cache_key(&file).as_ref() != Some(&key) {
+                let st = MmapedSafetensors::new(path)?;
+                let mut tensors = HashMap::new();
+                for (name, view) in st.tensors() {
+                    if packer.should_pack(&name, view.shape()) {
+                        let tensor = st.load(&name, &Device::Cpu)?.to_dtype(dtype)?;
+                        let packed = packer.pack(&tensor)?.contiguous()?;
+                        tensors.insert(name, packed);
+                    }
+                }
+                // Writing to a temporary file first so that an interrupted run does not leave
+                // a truncated cache file.
+                let tmp = file.with_extension(format!("tmp{}", std::process::id()));
+                let metadata = HashMap::from([(CACHE_KEY.to_string(), key)]);
+                safetensors::tensor::serialize_to_file(&tensors, &Some(metadata), &tmp)?;
+                std::fs::rename(&tmp, &file)?;
+                created += 1;
+            }
+            cache_files.push(file)
+        }
+        let source = MmapedSafetensors::multi(paths)?;
+        let packed = MmapedSafetensors::multi(&cache_files)?;
+        let packed_names = packed.tensors().into_iter().map(|(n, _)| n).collect();
+        Ok(Self {
+            source,
+            packed,
+            packed_names,
+            packer,
+            cache_files,
+            created,
+        })
+    }
+
+    /// The cache files, one per source file.
+    pub fn cache_files(&self) -> &[PathBuf] {
+        &self.cache_files
+    }
+
+    /// The number of cache files that had to be created, zero when all the packed weights were
+    /// already cached.
+    pub fn created_cache_files(&self) -> usize {
+        self.created
+    }
+
+    pub fn is_packed(&self, name: &str) -> bool {
+        self.packed_names.contains(name)
+    }
+
+    /// Loads a tensor in its packed layout, returns an error if the tensor is not packed.
+    pub fn load_packed(&self, name: &str, dev: &Device) -> Result<Tensor> {
+        if !self.is_packed(name) {
+            candle::bail!("{name} is not packed by {}", self.packer.name())
+        }
+        self.packed.load(name, dev)
+    }
+
+    /// Loads a tensor with its original shape, using [`WeightPacker::unpack`] on the packed
+    /// tensors.
+    pub fn load(&self, name: &str, dev: &Device) -> Result<Tensor> {
+        if self.is_packed(name) {
+            self.packer.unpack(self.packed.load(name, dev)?)
+        } else {
+            self.source.load(name, dev)
+        }
+    }
+}
+
+impl crate::var_builder::SimpleBackend for PackedSafetensors {
+    fn get(
+        &self,
+        s: Shape,
+        name: &str,
+        _: crate::Init,
+        dtype: DType,
+        dev: &Device,
+    ) -> Result<Tensor> {
+        // The packed tensors already use the cache dtype so the conversion is a no-op, using
+        // another dtype gives a tensor in the original layout.
+        let tensor = self.load(name, dev)?.to_dtype(dtype)?;
+        if tensor.shape() != &s {
+            Err(candle::Error::UnexpectedShape {
+                msg: format!("shape mismatch for {name}"),
+                expected: s,
+                got: tensor.shape().clone(),
+            }
+            .bt())?
+        }
+        Ok(tensor)
+    }
+
+    fn contains_tensor(&self, name: &str) -> bool {
+        self.source.get(name).is_ok()
+    }
+}
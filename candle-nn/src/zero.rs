@@ -0,0 +1,157 @@
+//! Optimizer state sharding for data parallel training, a.k.a. ZeRO stage 1.
+//!
+//! Each rank keeps a full copy of the parameters and computes the gradients on its own part of
+//! the batch. The [`DistributedOptimizer`] reduce-scatters the gradients so that each rank gets
+//! the averaged gradients for a flat chunk of every parameter, runs the wrapped optimizer on
+//! these chunks only, and all-gathers the updated chunks back into the parameters. The
+//! optimizer states, e.g. the moments of [`crate::AdamW`], are only kept for the chunks of this
+//! rank. See [`crate::fsdp`] to also shard the parameters and gradients.
+//!
+//! ```ignore
+//! let mut opt = DistributedOptimizer::<AdamW>::new(varmap.all_vars(), adamw_params, comm)?;
+//! for batch in batches {
+//!     let loss = model.forward(&batch)?.mean_all()?;
+//!     opt.backward_step(&loss)?;
+//! }
+//! ```
+use crate::distributed::{pad_flat, Communicator};
+use crate::distributed_checkpoint::{self, load_optimizer, optimizer_shards, Checkpoint, Shard};
+use crate::Optimizer;
+use candle::backprop::GradStore;
+use candle::{Result, Shape, Tensor, Var};
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Arc;
+
+/// Wraps an optimizer so that its states are sharded between the ranks of a communicator.
+pub struct DistributedOptimizer<O> {
+    comm: Arc<dyn Communicator>,
+    vars: Vec<Var>,
+    // The chunk of each variable owned by this rank, these are optimized by `optimizer`.
+    shards: Vec<Var>,
+    optimizer: O,
+}
+
+impl<O: Optimizer> DistributedOptimizer<O> {
+    /// All the ranks should use the same variables, in the same order and with the same values.
+    pub fn new(vars: Vec<Var>, config: O::Config, comm: Arc<dyn Communicator>) -> Result<Self> {
+        let (rank, world_size) = (comm.rank(), comm.world_size());
+        let vars: Vec<Var> = vars
+            .into_iter()
+            .filter(|var| var.dtype().is_float())
+            .collect();
+        let shards = vars
+            .iter()
+            .map(|var| {
+                let flat = pad_flat(var, world_size)?;
+                let chunk = flat.dim(0)? / world_size;
+                // Detaching gives the shard its own storage, even with a single rank.
+                Var::from_tensor(&flat.narrow(0, rank * chunk, chunk)?.detach())
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let optimizer = O::new(shards.clone(), config)?;
+        Ok(Self {
+            comm,
+            vars,
+            shards,
+            optimizer,
+        })
+    }
+
+    pub fn communicator(&self) -> &Arc<dyn Communicator> {
+        &self.comm
+    }
+
+    /// The wrapped optimizer, it only tracks the chunks of the variables owned by this rank.
+    pub fn optimizer(&self) -> &O {
+        &self.optimizer
+    }
+
+    pub fn optimizer_mut(&mut self) -> &mut O {
+        &mut self.optimizer
+    }
+
+    /// The chunks of the variables owned by this rank, in the same order as the variables.
+    pub fn shards(&self) -> &[Var] {
+        &self.shards
+    }
+
+    /// Averages the gradients over all the ranks and updates the variables.
+    ///
+    /// Variables without a gradient in `grads` use a zero gradient as all the ranks have to take
+    /// part in every reduction.
+    pub fn step(&mut self, grads: &GradStore) -> Result<()> {
+        let world_size = self.comm.world_size();
+        let mut shard_grads = GradStore::new();
+        for (var, shard) in self.vars.iter().zip(self.shards.iter()) {
+            let grad = match grads.get(var) {
+                Some(grad) => grad.clone(),
+                None => var.zeros_like()?,
+            };
+            let grad = pad_flat(&grad, world_size)?;
+            let grad = (self.comm.reduce_scatter(&grad)? / world_size as f64)?;
+            shard_grads.insert(shard, grad);
+        }
+        self.optimizer.step(&shard_grads)?;
+        for (var, shard) in self.vars.iter().zip(self.shards.iter()) {
+            let flat = self.comm.all_gather(shard.as_tensor())?;
+            let full = flat.narrow(0, 0, var.elem_count())?.reshape(var.shape())?;
+            var.set(&full)?;
+        }
+        Ok(())
+    }
+
+    pub fn backward_step(&mut self, loss: &Tensor) -> Result<()> {
+        let grads = loss.backward()?;
+        self.step(&grads)
+    }
+
+    /// Saves the chunks of the variables owned by this rank and their optimizer states, see
+    /// [`crate::distributed_checkpoint`]. The variables are named after their index.
+    pub fn save_checkpoint<P: AsRef<Path>>(
+        &self,
+        dir: P,
+        scalars: &HashMap<String, f64>,
+    ) -> Result<()> {
+        let (names, shapes) = self.names_and_shapes();
+        let mut shards: Vec<_> = self
+            .shards
+            .iter()
+            .zip(names.iter().zip(shapes.iter()))
+            .map(|(shard, (name, shape))| Shard {
+                name: format!("params.{name}"),
+                shape: shape.clone(),
+                data: shard.as_tensor().clone(),
+            })
+            .collect();
+        let mut scalars = scalars.clone();
+        optimizer_shards(&self.optimizer, &names, &shapes, &mut shards, &mut scalars)?;
+        distributed_checkpoint::save(dir, &shards, &scalars, self.comm.as_ref())
+    }
+
+    /// Restores the variables and the optimizer state from a checkpoint, possibly saved with a
+    /// different number of ranks.
+    pub fn load_checkpoint(&mut self, checkpoint: &Checkpoint) -> Result<()> {
+        let (names, _) = self.names_and_shapes();
+        for ((var, shard), name) in self.vars.iter().zip(self.shards.iter()).zip(names.iter()) {
+            let name = format!("params.{name}");
+            var.set(&checkpoint.full(&name)?.to_dtype(var.dtype())?)?;
+            shard.set(&checkpoint.shard(&name)?.to_dtype(shard.dtype())?)?;
+        }
+        load_optimizer(&mut self.optimizer, &names, checkpoint)
+    }
+
+    fn names_and_shapes(&self) -> (Vec<String>, Vec<Shape>) {
+        let names = (0..self.vars.len()).map(|i| i.to_string()).collect();
+        let shapes = self.vars.iter().map(|v| v.shape().clone()).collect();
+        (names, shapes)
+    }
+
+    pub fn learning_rate(&self) -> f64 {
+        self.optimizer.learning_rate()
+    }
+
+    pub fn set_learning_rate(&mut self, lr: f64) {
+        self.optimizer.set_learning_rate(lr)
+    }
+}
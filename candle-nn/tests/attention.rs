@@ -0,0 +1,300 @@
+#[cfg(feature = "mkl")]
+extern crate intel_mkl_src;
+
+#[cfg(feature = "accelerate")]
+extern crate accelerate_src;
+
+use candle::test_utils::max_diff;
+use candle::{DType, Device, IndexOp, Result, Tensor};
+use candle_nn::attention::{
+    apply_mask, block_diagonal_mask, causal_mask, key_padding_mask, mask_to_bias, merge_masks,
+    scaled_dot_product_attention, AttentionConfig,
+};
+use candle_nn::{multihead_attention, MultiheadAttentionConfig, VarBuilder, VarMap};
+
+#[test]
+fn masks() -> Result<()> {
+    let dev = &Device::Cpu;
+    let mask = causal_mask(3, dev)?;
+    assert_eq!(mask.to_vec2::<u8>()?, [[0, 1, 1], [0, 0, 1], [0, 0, 0]]);
+
+    let segment_ids = Tensor::new(&[[1u32, 1, 2, 0], [1, 1, 1, 1]], dev)?;
+    let mask = block_diagonal_mask(&segment_ids, false)?;
+    assert_eq!(
+        mask.i(0)?.to_vec2::<u8>()?,
+        [[0, 0, 1, 1], [0, 0, 1, 1], [1, 1, 0, 1], [1, 1, 1, 0]]
+    );
+    assert_eq!(mask.i(1)?.to_vec2::<u8>()?, [[0; 4]; 4]);
+    let mask = block_diagonal_mask(&segment_ids, true)?;
+    assert_eq!(
+        mask.i(0)?.to_vec2::<u8>()?,
+        [[0, 1, 1, 1], [0, 0, 1, 1], [1, 1, 0, 1], [1, 1, 1, 0]]
+    );
+    assert_eq!(
+        mask.i(1)?.to_vec2::<u8>()?,
+        causal_mask(4, dev)?.to_vec2::<u8>()?
+    );
+
+    // The mask applies to all the heads.
+    let scores = Tensor::zeros((2, 3, 4, 4), DType::F32, dev)?;
+    let weights = candle_nn::ops::softmax_last_dim(&apply_mask(&scores, &mask)?)?;
+    assert_eq!(
+        weights.i((0, 2))?.to_vec2::<f32>()?,
+        [
+            [1., 0., 0., 0.],
+            [0.5, 0.5, 0., 0.],
+            [0., 0., 1., 0.],
+            [0., 0., 0., 1.]
+        ]
+    );
+    let bias = mask_to_bias(&causal_mask(2, dev)?, DType::F32)?;
+    assert_eq!(bias.to_vec2::<f32>()?, [[0., f32::NEG_INFINITY], [0., 0.]]);
+    Ok(())
+}
+
+#[test]
+fn sdpa_f32_logits() -> Result<()> {
+    let dev = &Device::Cpu;
+    let q = Tensor::randn(0f32, 1., (1, 4, 5, 8), dev)?;
+    let k = Tensor::randn(0f32, 1., (1, 2, 5, 8), dev)?;
+    let v = Tensor::randn(0f32, 1., (1, 2, 5, 8), dev)?;
+    let mask = causal_mask(5, dev)?;
+
+    // Reference implementation in f32 with the kv heads repeated.
+    let repeat = |xs: &Tensor| {
+        Tensor::cat(
+            &[
+                xs.narrow(1, 0, 1)?.repeat((1, 2, 1, 1))?,
+                xs.narrow(1, 1, 1)?.repeat((1, 2, 1, 1))?,
+            ],
+            1,
+        )
+    };
+    let (k4, v4) = (repeat(&k)?, repeat(&v)?);
+    let scores = (q.matmul(&k4.t()?)? / 8f64.sqrt())?;
+    let weights = candle_nn::ops::softmax_last_dim(&apply_mask(&scores, &mask)?)?;
+    let expected = weights.matmul(&v4)?;
+
+    let cfg = AttentionConfig::default();
+    assert!(cfg.f32_logits);
+    let ys = scaled_dot_product_attention(&q, &k, &v, Some(&mask), &cfg)?;
+    let diff = (&ys - &expected)?.abs()?.max_all()?.to_scalar::<f32>()?;
+    assert!(diff < 1e-5, "{diff}");
+
+    let (q16, k16, v16) = (
+        q.to_dtype(DType::F16)?,
+        k.to_dtype(DType::F16)?,
+        v.to_dtype(DType::F16)?,
+    );
+    let ys = scaled_dot_product_attention(&q16, &k16, &v16, Some(&mask), &cfg)?;
+    assert_eq!(ys.dtype(), DType::F16);
+    let diff = (ys.to_dtype(DType::F32)? - &expected)?
+        .abs()?
+        .max_all()?
+        .to_scalar::<f32>()?;
+    assert!(diff < 1e-2, "{diff}");
+
+    // Large activations overflow the f16 range in q k^T, the f32 logits avoid this. All the
+    // products are above 1e4 so that each dot product overflows.
+    let (q16, k16) = (((q16.abs()? + 1.)? * 100.)?, ((k16.abs()? + 1.)? * 100.)?);
+    let ys = scaled_dot_product_attention(&q16, &k16, &v16, None, &cfg)?;
+    let ys = ys.to_dtype(DType::F32)?.flatten_all()?.to_vec1::<f32>()?;
+    assert!(ys.iter().all(|v| v.is_finite()));
+    let cfg = AttentionConfig {
+        f32_logits: false,
+        ..Default::default()
+    };
+    let ys = scaled_dot_product_attention(&q16, &k16, &v16, None, &cfg)?;
+    let ys = ys.to_dtype(DType::F32)?.flatten_all()?.to_vec1::<f32>()?;
+    assert!(ys.iter().any(|v| !v.is_finite()));
+    Ok(())
+}
+
+#[test]
+fn sdpa_chunked() -> Result<()> {
+    let dev = &Device::Cpu;
+    let q = Tensor::randn(0f32, 1., (2, 4, 11, 8), dev)?;
+    let k = Tensor::randn(0f32, 1., (2, 2, 13, 8), dev)?;
+    let v = Tensor::randn(0f32, 1., (2, 2, 13, 8), dev)?;
+    // Reference causal mask aligned to the bottom right, with a padded key in the first batch.
+    let causal: Vec<_> = (0..11)
+        .flat_map(|i| (0..13).map(move |j| u8::from(j > i + 2)))
+        .collect();
+    let causal = Tensor::from_slice(&causal, (11, 13), dev)?;
+    let padding = Tensor::new(&[[1u8, 0], [0, 0]], dev)?
+        .pad_with_zeros(1, 0, 11)?
+        .reshape((2, 1, 13))?;
+    let diff =
+        |a: &Tensor, b: &Tensor| -> Result<f32> { (a - b)?.abs()?.max_all()?.to_scalar::<f32>() };
+
+    let full = AttentionConfig {
+        chunk_size: None,
+        softcapping: Some(3.),
+        ..Default::default()
+    };
+    let chunked = AttentionConfig {
+        chunk_size: Some(4),
+        ..full
+    };
+    for mask in [None, Some(&padding), Some(&causal)] {
+        let expected = scaled_dot_product_attention(&q, &k, &v, mask, &full)?;
+        let ys = scaled_dot_product_attention(&q, &k, &v, mask, &chunked)?;
+        assert!(diff(&ys, &expected)? < 1e-5);
+    }
+
+    // The causal flag gives the same result as the causal mask, with or without chunks.
+    let expected = scaled_dot_product_attention(&q, &k, &v, Some(&causal), &full)?;
+    for config in [full, chunked] {
+        let config = AttentionConfig {
+            causal: true,
+            ..config
+        };
+        let ys = scaled_dot_product_attention(&q, &k, &v, None, &config)?;
+        assert!(diff(&ys, &expected)? < 1e-5);
+    }
+    let expected = scaled_dot_product_attention(
+        &q,
+        &k,
+        &v,
+        Some(&causal.broadcast_maximum(&padding)?),
+        &full,
+    )?;
+    let config = AttentionConfig {
+        causal: true,
+        ..chunked
+    };
+    let ys = scaled_dot_product_attention(&q, &k, &v, Some(&padding), &config)?;
+    assert!(diff(&ys, &expected)? < 1e-5);
+
+    // The running statistics are in f32, the result is in the dtype of v.
+    let (q16, k16, v16) = (
+        q.to_dtype(DType::F16)?,
+        k.to_dtype(DType::F16)?,
+        v.to_dtype(DType::F16)?,
+    );
+    let ys = scaled_dot_product_attention(&q16, &k16, &v16, Some(&causal), &chunked)?;
+    assert_eq!(ys.dtype(), DType::F16);
+    let expected = scaled_dot_product_attention(&q, &k, &v, Some(&causal), &full)?;
+    assert!(diff(&ys.to_dtype(DType::F32)?, &expected)? < 1e-2);
+    Ok(())
+}
+
+#[test]
+fn additive_masks() -> Result<()> {
+    let dev = &Device::Cpu;
+    let q = Tensor::randn(0f32, 1., (2, 2, 3, 4), dev)?;
+    let k = Tensor::randn(0f32, 1., (2, 2, 3, 4), dev)?;
+    let v = Tensor::randn(0f32, 1., (2, 2, 3, 4), dev)?;
+    let padding = key_padding_mask(&Tensor::new(&[[0u8, 0, 0], [0, 1, 0]], dev)?)?;
+    let mask = merge_masks(Some(&causal_mask(3, dev)?), Some(&padding))?.unwrap();
+    let config = AttentionConfig::default();
+    let expected = scaled_dot_product_attention(&q, &k, &v, Some(&mask), &config)?;
+
+    // The boolean masks, their biases and the mix of both give the same attention.
+    let bias = mask_to_bias(&mask, DType::F32)?;
+    let ys = scaled_dot_product_attention(&q, &k, &v, Some(&bias), &config)?;
+    assert!(max_diff(&ys, &expected)? < 1e-6);
+    let bias = mask_to_bias(&padding, DType::F32)?;
+    let merged = merge_masks(Some(&causal_mask(3, dev)?), Some(&bias))?.unwrap();
+    assert_eq!(merged.dtype(), DType::F32);
+    let ys = scaled_dot_product_attention(&q, &k, &v, Some(&merged), &config)?;
+    assert!(max_diff(&ys, &expected)? < 1e-6);
+    // The chunked attention does not skip the blocks of the additive masks.
+    let chunked = AttentionConfig {
+        chunk_size: Some(1),
+        ..config
+    };
+    let ones = Tensor::ones((3, 3), DType::F32, dev)?;
+    let ys = scaled_dot_product_attention(&q, &k, &v, Some(&ones), &chunked)?;
+    let no_mask = scaled_dot_product_attention(&q, &k, &v, None, &config)?;
+    assert!(max_diff(&ys, &no_mask)? < 1e-5);
+    Ok(())
+}
+
+#[test]
+fn multihead_attention_gqa() -> Result<()> {
+    let dev = &Device::Cpu;
+    let varmap = VarMap::new();
+    let vb = VarBuilder::from_varmap(&varmap, DType::F32, dev);
+    let config = MultiheadAttentionConfig::default()
+        .with_num_kv_heads(2)
+        .with_head_dim(3)
+        .with_bias(false);
+    let mha = multihead_attention(8, 4, config, vb.pp("attn"))?;
+    let mut shapes: Vec<_> = varmap
+        .named_vars()
+        .into_iter()
+        .map(|(n, v)| format!("{n} {:?}", v.dims()))
+        .collect();
+    shapes.sort();
+    assert_eq!(
+        shapes,
+        [
+            "attn.k_proj.weight [6, 8]",
+            "attn.out_proj.weight [8, 12]",
+            "attn.q_proj.weight [12, 8]",
+            "attn.v_proj.weight [6, 8]",
+        ]
+    );
+    let xs = Tensor::randn(0f32, 1., (2, 5, 8), dev)?;
+    let memory = Tensor::randn(0f32, 1., (2, 7, 8), dev)?;
+    assert_eq!(mha.forward(&xs, &memory, &memory, None)?.dims(), [2, 5, 8]);
+
+    // The flash attention dispatch gives the same results.
+    let flash = mha.clone().with_attention_config(AttentionConfig {
+        causal: true,
+        ..Default::default()
+    });
+    let expected = flash.forward(&xs, &xs, &xs, None)?;
+    let mask = causal_mask(5, dev)?;
+    assert!(max_diff(&mha.forward(&xs, &xs, &xs, Some(&mask))?, &expected)? < 1e-5);
+    let vb = VarBuilder::from_varmap(&varmap, DType::F32, dev);
+    let flash = multihead_attention(8, 4, config.with_flash_attn(true), vb.pp("attn"))?;
+    assert!(max_diff(&flash.forward(&xs, &xs, &xs, Some(&mask))?, &expected)? < 1e-5);
+
+    let vb = VarBuilder::from_varmap(&varmap, DType::F32, dev);
+    assert!(multihead_attention(8, 4, config.with_num_kv_heads(3), vb).is_err());
+    Ok(())
+}
+
+#[test]
+fn multihead_attention_kv_cache() -> Result<()> {
+    let dev = &Device::Cpu;
+    let vb = VarBuilder::from_varmap(&VarMap::new(), DType::F32, dev);
+    let config = MultiheadAttentionConfig::default()
+        .with_num_kv_heads(1)
+        .with_rope_theta(10000.)
+        .with_max_seq_len(8);
+    let mut mha = multihead_attention(8, 2, config, vb)?;
+    let xs = Tensor::randn(0f32, 1., (1, 6, 8), dev)?;
+    let mask = causal_mask(6, dev)?;
+    let expected = mha.forward(&xs, &xs, &xs, Some(&mask))?;
+
+    // A prompt followed by single token steps.
+    let prompt = mha.forward_cached(&xs.narrow(1, 0, 4)?, None)?;
+    assert!(max_diff(&prompt, &expected.narrow(1, 0, 4)?)? < 1e-5);
+    for i in 4..6 {
+        let ys = mha.forward_cached(&xs.narrow(1, i, 1)?, None)?;
+        assert!(max_diff(&ys, &expected.narrow(1, i, 1)?)? < 1e-5);
+    }
+    assert_eq!(mha.kv_cache().current_seq_len(), 6);
+    assert_eq!(mha.kv_cache().k()?.unwrap().dims(), [1, 1, 6, 4]);
+    // The cache only holds 8 positions.
+    assert!(mha.forward_cached(&xs.narrow(1, 0, 3)?, None).is_err());
+
+    // The padding masks cover the cached positions.
+    mha.reset_kv_cache();
+    let padding = key_padding_mask(&Tensor::new(&[[0u8, 1, 0, 0]], dev)?)?;
+    let ys = mha.forward_cached(&xs.narrow(1, 0, 3)?, Some(&padding.narrow(2, 0, 3)?))?;
+    let mask = merge_masks(Some(&causal_mask(3, dev)?), Some(&padding.narrow(2, 0, 3)?))?;
+    let expected = mha.forward(
+        &xs.narrow(1, 0, 3)?,
+        &xs.narrow(1, 0, 3)?,
+        &xs.narrow(1, 0, 3)?,
+        mask.as_ref(),
+    )?;
+    assert!(max_diff(&ys, &expected)? < 1e-5);
+    let ys = mha.forward_cached(&xs.narrow(1, 3, 1)?, Some(&padding))?;
+    assert_eq!(ys.dims(), [1, 1, 8]);
+    Ok(())
+}
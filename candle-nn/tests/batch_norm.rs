@@ -126,3 +126,43 @@ fn train_batch_norm() -> Result<()> {
     );
     Ok(())
 }
+
+// The running statistics are buffers, they are saved with the variables but not trained.
+#[test]
+fn batch_norm_buffers() -> Result<()> {
+    let vm = VarMap::new();
+    let vb = VarBuilder::from_varmap(&vm, DType::F32, &Device::Cpu);
+    let bn = batch_norm(2, BatchNormConfig::default(), vb.pp("bn"))?;
+    let mut vars: Vec<_> = vm.all_vars().iter().map(|v| v.dims().to_vec()).collect();
+    vars.sort();
+    assert_eq!(vars, [[2], [2]]);
+    assert_eq!(vm.all_buffers().len(), 2);
+    assert!(vm.is_buffer("bn.running_mean"));
+    assert!(vm.is_buffer("bn.running_var"));
+    assert!(!vm.is_buffer("bn.weight"));
+
+    let mask = Tensor::new(&[1f32, 0., 1.], &Device::Cpu)?;
+    vm.register_buffer("mask", &mask)?;
+    assert!(vm.register_buffer("bn.weight", &mask).is_err());
+    assert_eq!(vm.all_buffers().len(), 3);
+
+    // Buffers are part of the checkpoints.
+    let xs = Tensor::new(&[[1f32, 2.], [3., 5.]], &Device::Cpu)?;
+    bn.forward_train(&xs)?;
+    let file = std::env::temp_dir().join(format!("candle-buffers-{}.st", std::process::id()));
+    vm.save(&file)?;
+    let mut vm2 = VarMap::new();
+    let vb2 = VarBuilder::from_varmap(&vm2, DType::F32, &Device::Cpu);
+    let bn2 = batch_norm(2, BatchNormConfig::default(), vb2.pp("bn"))?;
+    vm2.register_buffer("mask", &mask.zeros_like()?)?;
+    vm2.load(&file)?;
+    std::fs::remove_file(&file)?;
+    assert_eq!(
+        bn2.running_mean().to_vec1::<f32>()?,
+        bn.running_mean().to_vec1::<f32>()?
+    );
+    let mask2 = vm2.data().lock().unwrap()["mask"].to_vec1::<f32>()?;
+    assert_eq!(mask2, [1., 0., 1.]);
+    assert_eq!(vm2.all_vars().len(), 2);
+    Ok(())
+}
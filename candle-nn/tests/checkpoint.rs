@@ -0,0 +1,92 @@
+#[cfg(feature = "mkl")]
+extern crate intel_mkl_src;
+
+#[cfg(feature = "accelerate")]
+extern crate accelerate_src;
+
+use anyhow::Result;
+use candle::{DType, Device, Tensor};
+use candle_nn::checkpoint::{self, TrainingState};
+use candle_nn::lr_scheduler::{LrScheduler, StepDecay};
+use candle_nn::{AdamW, Linear, Module, Optimizer, ParamsAdamW, VarBuilder, VarMap};
+
+struct Run {
+    varmap: VarMap,
+    model: Linear,
+    opt: AdamW,
+    scheduler: LrScheduler<StepDecay>,
+}
+
+fn run(dev: &Device) -> Result<Run> {
+    let varmap = VarMap::new();
+    let vb = VarBuilder::from_varmap(&varmap, DType::F32, dev);
+    let model = candle_nn::linear(4, 2, vb.pp("linear"))?;
+    varmap.get_buffer(2, "running_mean", candle_nn::init::ZERO, DType::F32, dev)?;
+    let mut opt = AdamW::new(varmap.all_vars(), ParamsAdamW::default())?;
+    let scheduler = LrScheduler::per_step(StepDecay::new(0.01, 3, 0.5));
+    scheduler.apply(&mut opt);
+    Ok(Run {
+        varmap,
+        model,
+        opt,
+        scheduler,
+    })
+}
+
+// A training step with random inputs and dropout, so that the weights depend on the generator.
+fn train_step(run: &mut Run, dev: &Device) -> Result<()> {
+    let xs = Tensor::randn(0f32, 1., (8, 4), dev)?;
+    let xs = candle_nn::ops::dropout(&xs, 0.5)?;
+    let loss = run.model.forward(&xs)?.sqr()?.mean_all()?;
+    run.opt.backward_step(&loss)?;
+    run.scheduler.step(&mut run.opt);
+    Ok(())
+}
+
+#[test]
+fn checkpoint_resume() -> Result<()> {
+    let dev = &Device::Cpu;
+    dev.set_seed(42)?;
+    let mut reference = run(dev)?;
+    for _ in 0..4 {
+        train_step(&mut reference, dev)?;
+    }
+    let state = TrainingState::new(4)
+        .with_epoch(1)
+        .with_scheduler(&reference.scheduler)
+        .with_rng(&[dev])?
+        .with_scalar("best_loss", 0.25);
+    let file = std::env::temp_dir().join(format!("candle-checkpoint-{}.st", std::process::id()));
+    checkpoint::save(&file, &reference.varmap, &reference.opt, &state)?;
+    for _ in 0..4 {
+        train_step(&mut reference, dev)?;
+    }
+
+    // Resume from the checkpoint with fresh weights and optimizer.
+    dev.set_seed(1337)?;
+    let mut resumed = run(dev)?;
+    let loaded = checkpoint::load(&file, &resumed.varmap, &mut resumed.opt)?;
+    assert_eq!(loaded, state);
+    loaded.restore_scheduler(&mut resumed.scheduler, &mut resumed.opt)?;
+    loaded.restore_rng(&[dev])?;
+    assert_eq!(resumed.opt.learning_rate(), 0.005);
+    for _ in 0..4 {
+        train_step(&mut resumed, dev)?;
+    }
+    let expected = reference.model.weight().to_vec2::<f32>()?;
+    assert_eq!(resumed.model.weight().to_vec2::<f32>()?, expected);
+    assert_eq!(resumed.opt.learning_rate(), reference.opt.learning_rate());
+    assert_eq!(resumed.opt.state()?.scalars, reference.opt.state()?.scalars);
+
+    // The checkpoint has to contain all the variables of the map.
+    let mut other = run(dev)?;
+    let vb = VarBuilder::from_varmap(&other.varmap, DType::F32, dev);
+    vb.get(3, "extra")?;
+    assert!(checkpoint::load(&file, &other.varmap, &mut other.opt).is_err());
+    let safetensors_file = file.with_extension("weights");
+    reference.varmap.save(&safetensors_file)?;
+    assert!(checkpoint::load(&safetensors_file, &reference.varmap, &mut other.opt).is_err());
+    std::fs::remove_file(&file)?;
+    std::fs::remove_file(&safetensors_file)?;
+    Ok(())
+}
@@ -0,0 +1,141 @@
+#[cfg(feature = "mkl")]
+extern crate intel_mkl_src;
+
+#[cfg(feature = "accelerate")]
+extern crate accelerate_src;
+
+use anyhow::Result;
+use candle::{DType, Device, Tensor};
+use candle_nn::compressed_checkpoint::{self, CompressionConfig};
+use std::collections::HashMap;
+
+fn tmp_file(name: &str) -> std::path::PathBuf {
+    std::env::temp_dir().join(format!("candle-{name}-{}.ctz", std::process::id()))
+}
+
+fn assert_same(a: &HashMap<String, Tensor>, b: &HashMap<String, Tensor>) -> Result<()> {
+    assert_eq!(a.len(), b.len());
+    for (name, t) in a.iter() {
+        let other = &b[name];
+        assert_eq!(t.dtype(), other.dtype(), "{name}");
+        assert_eq!(t.dims(), other.dims(), "{name}");
+        let diff = t.to_dtype(DType::F64)?.ne(&other.to_dtype(DType::F64)?)?;
+        let diff = diff.to_dtype(DType::U32)?.sum_all()?.to_scalar::<u32>()?;
+        assert_eq!(diff, 0, "{name}");
+    }
+    Ok(())
+}
+
+#[test]
+fn compressed_round_trip() -> Result<()> {
+    let dev = &Device::Cpu;
+    let weight = Tensor::randn(0f32, 0.02, (64, 32), dev)?;
+    let tensors = HashMap::from([
+        ("weight".to_string(), weight.clone()),
+        ("half".to_string(), weight.to_dtype(DType::BF16)?),
+        ("zeros".to_string(), Tensor::zeros(1024, DType::F32, dev)?),
+        ("ids".to_string(), Tensor::arange(0u32, 100, dev)?),
+        ("scalar".to_string(), Tensor::new(3.5f64, dev)?),
+    ]);
+    let configs = [
+        CompressionConfig::default(),
+        CompressionConfig {
+            level: Some(19),
+            shuffle: false,
+        },
+        CompressionConfig {
+            level: None,
+            shuffle: true,
+        },
+    ];
+    let file = tmp_file("compressed");
+    for config in configs {
+        compressed_checkpoint::save(&tensors, &file, &config)?;
+        assert!(compressed_checkpoint::is_compressed(&file)?);
+        let loaded = compressed_checkpoint::load(&file, dev)?;
+        assert_same(&tensors, &loaded)?;
+    }
+
+    // The zeros compress to almost nothing.
+    let raw = 64 * 32 * 6 + 1024 * 4 + 100 * 4 + 8;
+    compressed_checkpoint::save(&tensors, &file, &CompressionConfig::default())?;
+    assert!((std::fs::metadata(&file)?.len() as usize) < raw - 4000);
+
+    candle::safetensors::save(&tensors, &file)?;
+    assert!(!compressed_checkpoint::is_compressed(&file)?);
+    assert!(compressed_checkpoint::load(&file, dev).is_err());
+    std::fs::remove_file(&file)?;
+    Ok(())
+}
+
+#[test]
+fn compressed_delta() -> Result<()> {
+    let dev = &Device::Cpu;
+    let base = HashMap::from([
+        ("a".to_string(), Tensor::randn(0f32, 1., (128, 64), dev)?),
+        ("b".to_string(), Tensor::randn(0f32, 1., 256, dev)?),
+        ("c".to_string(), Tensor::randn(0f32, 1., 16, dev)?),
+    ]);
+    let update = (Tensor::randn(0f32, 1e-4, (128, 64), dev)? * base["a"].abs()?)?;
+    let finetuned = HashMap::from([
+        ("a".to_string(), (&base["a"] + update)?),
+        ("b".to_string(), base["b"].clone()),
+        // A different shape than in the base, saved in full.
+        ("c".to_string(), Tensor::randn(0f32, 1., 8, dev)?),
+        ("head".to_string(), Tensor::randn(0f32, 1., 32, dev)?),
+    ]);
+    let config = CompressionConfig::default();
+    let full_file = tmp_file("delta-full");
+    let delta_file = tmp_file("delta");
+    compressed_checkpoint::save(&finetuned, &full_file, &config)?;
+    compressed_checkpoint::save_delta(&finetuned, &base, &delta_file, &config)?;
+    let full_len = std::fs::metadata(&full_file)?.len();
+    let delta_len = std::fs::metadata(&delta_file)?.len();
+    assert!(delta_len * 3 < full_len * 2, "{delta_len} {full_len}");
+
+    let loaded = compressed_checkpoint::load_delta(&delta_file, &base, dev)?;
+    assert_same(&finetuned, &loaded)?;
+    // The base is required to load the deltas.
+    assert!(compressed_checkpoint::load(&delta_file, dev).is_err());
+    let wrong_base = HashMap::from([("a".to_string(), base["b"].clone())]);
+    assert!(compressed_checkpoint::load_delta(&delta_file, &wrong_base, dev).is_err());
+    std::fs::remove_file(&full_file)?;
+    std::fs::remove_file(&delta_file)?;
+    Ok(())
+}
+
+#[test]
+fn compressed_varmap() -> Result<()> {
+    let dev = &Device::Cpu;
+    let varmap = candle_nn::VarMap::new();
+    let w = varmap.get((4, 3), "w", candle_nn::init::ZERO, DType::F32, dev)?;
+    varmap
+        .data()
+        .lock()
+        .unwrap()
+        .get("w")
+        .unwrap()
+        .set(&Tensor::arange(0f32, 12., dev)?.reshape((4, 3))?)?;
+    let file = tmp_file("varmap");
+    varmap.save_compressed(&file, &CompressionConfig::default())?;
+    let mut other = candle_nn::VarMap::new();
+    let w2 = other.get((4, 3), "w", candle_nn::init::ZERO, DType::F32, dev)?;
+    other.load_compressed(&file)?;
+    assert_eq!(w2.to_vec2::<f32>()?, w.to_vec2::<f32>()?);
+    std::fs::remove_file(&file)?;
+    Ok(())
+}
+
+#[test]
+fn compressed_invalid_header() -> Result<()> {
+    let file = tmp_file("invalid-header");
+    for header_len in [u64::MAX, u64::MAX - 15, 1 << 40] {
+        let mut bytes = b"CANDLEZ1".to_vec();
+        bytes.extend_from_slice(&header_len.to_le_bytes());
+        bytes.extend_from_slice(b"{}");
+        std::fs::write(&file, &bytes)?;
+        assert!(compressed_checkpoint::load(&file, &Device::Cpu).is_err());
+    }
+    std::fs::remove_file(&file)?;
+    Ok(())
+}
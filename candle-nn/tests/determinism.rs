@@ -0,0 +1,73 @@
+#[cfg(feature = "mkl")]
+extern crate intel_mkl_src;
+
+#[cfg(feature = "accelerate")]
+extern crate accelerate_src;
+
+use candle::{DType, Device, Result, Tensor, Var};
+use candle_nn::gaussian_splatting::rasterize;
+
+// Values in [lo, hi] that do not depend on the random number generator, which cannot be used
+// unseeded in deterministic mode.
+fn values(n: usize, freq: f64, lo: f64, hi: f64, dev: &Device) -> Result<Tensor> {
+    let xs = (Tensor::arange(0u32, n as u32, dev)?.to_dtype(DType::F32)? * freq)?.sin()?;
+    xs.affine((hi - lo) / 2., (hi + lo) / 2.)
+}
+
+// A single test as the deterministic mode is shared by all the tests of this binary.
+#[test]
+fn rasterize_backward() -> Result<()> {
+    let dev = &Device::Cpu;
+    let (n, width, height) = (48, 40, 36);
+    let means2d = Var::from_tensor(&Tensor::stack(
+        &[
+            values(n, 0.7, -2., 42., dev)?,
+            values(n, 1.3, -2., 38., dev)?,
+        ],
+        1,
+    )?)?;
+    let conics = Var::from_tensor(&Tensor::stack(
+        &[
+            values(n, 0.3, 0.05, 0.3, dev)?,
+            values(n, 0.9, -0.02, 0.02, dev)?,
+            values(n, 1.7, 0.05, 0.3, dev)?,
+        ],
+        1,
+    )?)?;
+    let colors = Var::from_tensor(&values(3 * n, 0.4, 0., 1., dev)?.reshape((n, 3))?)?;
+    let opacities = Var::from_tensor(&values(n, 2.1, 0.2, 0.8, dev)?)?;
+    let depths = values(n, 0.11, 1., 10., dev)?;
+    let weights = values(height * width * 3, 0.05, -1., 1., dev)?.reshape((height, width, 3))?;
+    let grads = || -> Result<Vec<Vec<f32>>> {
+        let image = rasterize(
+            &means2d,
+            &conics,
+            &colors,
+            &opacities,
+            &depths,
+            width,
+            height,
+            &[0.1, 0.2, 0.3],
+        )?;
+        let grads = (image * &weights)?.sum_all()?.backward()?;
+        [&means2d, &conics, &colors, &opacities]
+            .iter()
+            .map(|v| grads.get(v).unwrap().flatten_all()?.to_vec1::<f32>())
+            .collect()
+    };
+
+    let parallel = grads()?;
+    candle::set_deterministic(true);
+    let first = grads();
+    let second = grads();
+    candle::set_deterministic(false);
+    let (first, second) = (first?, second?);
+    // The sequential accumulation is reproducible and only differs from the parallel one by the
+    // rounding errors.
+    assert_eq!(first, second);
+    assert!(first.iter().all(|g| g.iter().any(|v| *v != 0.)));
+    for (p, f) in parallel.iter().flatten().zip(first.iter().flatten()) {
+        assert!((p - f).abs() <= 1e-4 * (1. + f.abs()), "{p} {f}")
+    }
+    Ok(())
+}
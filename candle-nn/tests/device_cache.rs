@@ -0,0 +1,94 @@
+#[cfg(feature = "mkl")]
+extern crate intel_mkl_src;
+
+#[cfg(feature = "accelerate")]
+extern crate accelerate_src;
+
+use anyhow::Result;
+use candle::{DType, Device};
+use candle_nn::device_cache;
+use candle_nn::rotary_emb::RopeScaling;
+
+// A single test as the cache is shared by all the tests of this binary.
+#[test]
+fn device_cache() -> Result<()> {
+    let dev = &Device::Cpu;
+    let (cos, sin) = device_cache::rope_tables(8, 10000., 16, None, DType::F32, dev)?;
+    let (expected_cos, expected_sin) =
+        candle_nn::rotary_emb::rope_tables(8, 10000., 16, None, DType::F32, dev)?;
+    assert_eq!(cos.to_vec2::<f32>()?, expected_cos.to_vec2::<f32>()?);
+    assert_eq!(sin.to_vec2::<f32>()?, expected_sin.to_vec2::<f32>()?);
+    assert_eq!(device_cache::memory_usage(dev), 2 * 16 * 4 * 4);
+
+    // The same parameters return the same tensors, the others are computed separately.
+    let (cos2, sin2) = device_cache::rope_tables(8, 10000., 16, None, DType::F32, dev)?;
+    assert_eq!((cos2.id(), sin2.id()), (cos.id(), sin.id()));
+    let (cos2, _) = device_cache::rope_tables(8, 10000., 16, None, DType::F16, dev)?;
+    assert_eq!(cos2.dtype(), DType::F16);
+    let (cos2, _) = device_cache::rope_tables(8, 500000., 16, None, DType::F32, dev)?;
+    assert_ne!(cos2.id(), cos.id());
+    assert_eq!(cos2.dims(), [16, 4]);
+    let (cos2, _) = device_cache::rope_tables(8, 10000., 32, None, DType::F32, dev)?;
+    assert_eq!(cos2.dims(), [32, 4]);
+    let usage = 2 * 16 * 4 * (4 + 2 + 4) + 2 * 32 * 4 * 4;
+    assert_eq!(device_cache::memory_usage(dev), usage);
+
+    // The scaling parameters are part of the key.
+    let yarn = |factor| RopeScaling::Yarn {
+        factor,
+        original_max_position_embeddings: 8,
+        beta_fast: 32.,
+        beta_slow: 1.,
+        attention_factor: 1.,
+    };
+    let (cos2, _) = device_cache::rope_tables(8, 10000., 16, Some(yarn(2.)), DType::F32, dev)?;
+    let (expected, _) =
+        candle_nn::rotary_emb::scaled_rope_tables(8, 10000., 16, yarn(2.), DType::F32, dev)?;
+    assert_eq!(cos2.to_vec2::<f32>()?, expected.to_vec2::<f32>()?);
+    assert_ne!(cos2.to_vec2::<f32>()?, cos.to_vec2::<f32>()?);
+    let (cos3, _) = device_cache::rope_tables(8, 10000., 16, Some(yarn(4.)), DType::F32, dev)?;
+    assert_ne!(cos3.id(), cos2.id());
+    let (cos3, _) = device_cache::rope_tables(8, 10000., 16, Some(yarn(2.)), DType::F32, dev)?;
+    assert_eq!(cos3.id(), cos2.id());
+    let usage = usage + 2 * 2 * 16 * 4 * 4;
+    assert_eq!(device_cache::memory_usage(dev), usage);
+
+    let mask = device_cache::causal_mask(4, dev)?;
+    assert_eq!(
+        mask.to_vec2::<u8>()?,
+        [[0, 1, 1, 1], [0, 0, 1, 1], [0, 0, 0, 1], [0, 0, 0, 0]]
+    );
+    assert_eq!(device_cache::causal_mask(4, dev)?.id(), mask.id());
+    assert_eq!(device_cache::memory_usage(dev), usage + 16);
+
+    // The additive masks are views of a square mask rounded up to a power of two.
+    let inf = f32::NEG_INFINITY;
+    let bias = device_cache::causal_bias(2, 1, None, DType::F32, dev)?;
+    assert_eq!(bias.to_vec2::<f32>()?, [[0., 0., inf], [0., 0., 0.]]);
+    assert_eq!(device_cache::memory_usage(dev), usage + 16 + 4 * 4 * 4);
+    let bias = device_cache::causal_bias(4, 0, Some(1), DType::F32, dev)?;
+    assert_eq!(
+        bias.to_vec2::<f32>()?,
+        [
+            [0., inf, inf, inf],
+            [0., 0., inf, inf],
+            [inf, 0., 0., inf],
+            [inf, inf, 0., 0.]
+        ]
+    );
+    device_cache::causal_bias(3, 0, None, DType::F32, dev)?;
+    assert_eq!(device_cache::memory_usage(dev), usage + 16 + 2 * 4 * 4 * 4);
+
+    // Threads share the cache.
+    let id = std::thread::spawn(|| device_cache::causal_mask(4, &Device::Cpu).map(|m| m.id()))
+        .join()
+        .unwrap()?;
+    assert_eq!(id, mask.id());
+
+    device_cache::clear_device(dev);
+    assert_eq!(device_cache::memory_usage(dev), 0);
+    assert_ne!(device_cache::causal_mask(4, dev)?.id(), mask.id());
+    device_cache::clear();
+    assert_eq!(device_cache::memory_usage(dev), 0);
+    Ok(())
+}
@@ -0,0 +1,335 @@
+#[cfg(feature = "mkl")]
+extern crate intel_mkl_src;
+
+#[cfg(feature = "accelerate")]
+extern crate accelerate_src;
+
+use anyhow::Result;
+use candle::{DType, Device, Tensor};
+use candle_nn::distributed::{Communicator, ThreadGroup};
+use candle_nn::distributed_checkpoint::Checkpoint;
+use candle_nn::fsdp::{Fsdp, ShardedParams};
+use candle_nn::zero::DistributedOptimizer;
+use candle_nn::{linear, AdamW, Linear, Module, Optimizer, ParamsAdamW, VarBuilder, VarMap, SGD};
+use std::collections::HashMap;
+use std::sync::Arc;
+
+// Runs `f` on each rank of a thread group and returns the results in rank order.
+fn run_ranks<T, F>(world_size: usize, f: F) -> Result<Vec<T>>
+where
+    T: Send + 'static,
+    F: Fn(Arc<dyn Communicator>) -> Result<T> + Send + Sync + 'static,
+{
+    let f = Arc::new(f);
+    let handles: Vec<_> = ThreadGroup::new(world_size)
+        .into_iter()
+        .map(|comm| {
+            let f = f.clone();
+            std::thread::spawn(move || f(Arc::new(comm)))
+        })
+        .collect();
+    handles.into_iter().map(|h| h.join().unwrap()).collect()
+}
+
+#[test]
+fn collectives() -> Result<()> {
+    let results = run_ranks(3, |comm| {
+        let rank = comm.rank() as f32;
+        let xs = Tensor::new(&[[rank, 1.], [2. * rank, 2.], [3., rank]], &Device::Cpu)?;
+        let sum = comm.all_reduce(&xs)?.to_vec2::<f32>()?;
+        let gathered = comm.all_gather(&xs.narrow(0, 0, 1)?)?.to_vec2::<f32>()?;
+        let scattered = comm.reduce_scatter(&xs)?.to_vec2::<f32>()?;
+        let root = comm.broadcast(&xs, 1)?.to_vec2::<f32>()?;
+        Ok((sum, gathered, scattered, root))
+    })?;
+    for (rank, (sum, gathered, scattered, root)) in results.into_iter().enumerate() {
+        assert_eq!(sum, [[3., 3.], [6., 6.], [9., 3.]]);
+        assert_eq!(gathered, [[0., 1.], [1., 1.], [2., 1.]]);
+        assert_eq!(scattered, [sum[rank].clone()]);
+        assert_eq!(root, [[1., 1.], [2., 2.], [3., 1.]]);
+    }
+    Ok(())
+}
+
+fn init_model(dev: &Device) -> Result<(VarMap, Linear)> {
+    let varmap = VarMap::new();
+    let vb = VarBuilder::from_varmap(&varmap, DType::F32, dev);
+    let model = linear(5, 3, vb.pp("lin"))?;
+    let weight: Vec<f32> = (0..15).map(|i| (i as f32 - 7.) / 10.).collect();
+    let vars = varmap.data().lock().unwrap();
+    vars["lin.weight"].set(&Tensor::new(weight, dev)?.reshape((3, 5))?)?;
+    vars["lin.bias"].set(&Tensor::new(&[0.1f32, -0.2, 0.3], dev)?)?;
+    drop(vars);
+    Ok((varmap, model))
+}
+
+fn batch(dev: &Device) -> Result<(Tensor, Tensor)> {
+    let xs = Tensor::arange(0f32, 20., dev)?.reshape((4, 5))?.cos()?;
+    let ys = Tensor::arange(0f32, 12., dev)?.reshape((4, 3))?.sin()?;
+    Ok((xs, ys))
+}
+
+#[test]
+fn fsdp_matches_single_process() -> Result<()> {
+    let dev = Device::Cpu;
+    let (varmap, model) = init_model(&dev)?;
+    let (xs, ys) = batch(&dev)?;
+    let mut opt = SGD::new(varmap.all_vars(), 0.1)?;
+    for _step in 0..3 {
+        let loss = (model.forward(&xs)? - &ys)?.sqr()?.mean_all()?;
+        opt.backward_step(&loss)?;
+    }
+    let expected_weight = model.weight().to_vec2::<f32>()?;
+    let expected_bias = model.bias().unwrap().to_vec1::<f32>()?;
+
+    let world_size = 2;
+    let results = run_ranks(world_size, move |comm| {
+        let dev = Device::Cpu;
+        let rank = comm.rank();
+        let (varmap, _) = init_model(&dev)?;
+        let params = Arc::new(ShardedParams::from_varmap(&varmap, comm)?);
+        // The bias has 3 elements so the last shard is padded.
+        assert_eq!(params.shards()[0].dims(), [2]);
+        assert_eq!(params.shards()[1].dims(), [8]);
+        let model = Fsdp::new(params.clone(), "lin", |vb| linear(5, 3, vb))?;
+        let mut opt = SGD::new(params.shards(), 0.1)?;
+        let (xs, ys) = batch(&dev)?;
+        let xs = xs.narrow(0, 2 * rank, 2)?;
+        let ys = ys.narrow(0, 2 * rank, 2)?;
+        for _step in 0..3 {
+            let loss = (model.forward(&xs)? - &ys)?.sqr()?.mean_all()?;
+            let mut grads = loss.backward()?;
+            params.reduce_grads(&mut grads)?;
+            opt.step(&grads)?;
+        }
+        let full = params.full_tensors()?;
+        Ok((
+            full["lin.weight"].to_vec2::<f32>()?,
+            full["lin.bias"].to_vec1::<f32>()?,
+        ))
+    })?;
+    for (weight, bias) in results {
+        for (w, e) in weight
+            .iter()
+            .flatten()
+            .zip(expected_weight.iter().flatten())
+        {
+            assert!((w - e).abs() < 1e-5, "{weight:?} {expected_weight:?}");
+        }
+        for (b, e) in bias.iter().zip(expected_bias.iter()) {
+            assert!((b - e).abs() < 1e-5, "{bias:?} {expected_bias:?}");
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn fsdp_units() -> Result<()> {
+    fn init(dev: &Device) -> Result<VarMap> {
+        let varmap = VarMap::new();
+        let vb = VarBuilder::from_varmap(&varmap, DType::F32, dev);
+        linear(5, 4, vb.pp("l1"))?;
+        linear(4, 3, vb.pp("l2"))?;
+        let data = varmap.data().lock().unwrap();
+        for (i, name) in ["l1.weight", "l1.bias", "l2.weight", "l2.bias"]
+            .iter()
+            .enumerate()
+        {
+            let var = &data[*name];
+            let values = Tensor::arange(0f32, var.elem_count() as f32, dev)?;
+            var.set(&((values + i as f64)?.cos()? * 0.5)?.reshape(var.shape())?)?;
+        }
+        drop(data);
+        Ok(varmap)
+    }
+    fn step(l1: &dyn Module, l2: &dyn Module, xs: &Tensor, ys: &Tensor) -> Result<Tensor> {
+        let hs = l1.forward(xs)?.tanh()?;
+        Ok((l2.forward(&hs)? - ys)?.sqr()?.mean_all()?)
+    }
+
+    let dev = Device::Cpu;
+    let varmap = init(&dev)?;
+    let vb = VarBuilder::from_varmap(&varmap, DType::F32, &dev);
+    let (l1, l2) = (linear(5, 4, vb.pp("l1"))?, linear(4, 3, vb.pp("l2"))?);
+    let (xs, ys) = batch(&dev)?;
+    let mut opt = SGD::new(varmap.all_vars(), 0.1)?;
+    for _step in 0..3 {
+        opt.backward_step(&step(&l1, &l2, &xs, &ys)?)?;
+    }
+    let expected = l2.weight().to_vec2::<f32>()?;
+
+    let results = run_ranks(2, move |comm| {
+        let dev = Device::Cpu;
+        let rank = comm.rank();
+        let params = Arc::new(ShardedParams::from_varmap(&init(&dev)?, comm)?);
+        // Each unit only gathers the parameters under its prefix.
+        let l1 = Fsdp::new(params.clone(), "l1", |vb| linear(5, 4, vb))?;
+        let l2 = Fsdp::new(params.clone(), "l2", |vb| linear(4, 3, vb))?;
+        assert!(Fsdp::new(params.clone(), "l", |vb| linear(4, 3, vb)).is_err());
+        let mut opt = SGD::new(params.shards(), 0.1)?;
+        let (xs, ys) = batch(&dev)?;
+        let xs = xs.narrow(0, 2 * rank, 2)?;
+        let ys = ys.narrow(0, 2 * rank, 2)?;
+        for _step in 0..3 {
+            let mut grads = step(&l1, &l2, &xs, &ys)?.backward()?;
+            params.reduce_grads(&mut grads)?;
+            opt.step(&grads)?;
+        }
+        assert!(params.reduce_grads(&mut Default::default()).is_err());
+        Ok(params.full_tensors()?["l2.weight"].to_vec2::<f32>()?)
+    })?;
+    for weight in results {
+        assert_close(&weight, &expected);
+    }
+    Ok(())
+}
+
+#[test]
+fn zero_matches_single_process() -> Result<()> {
+    let dev = Device::Cpu;
+    let params = ParamsAdamW {
+        lr: 0.05,
+        ..Default::default()
+    };
+    let (varmap, model) = init_model(&dev)?;
+    let (xs, ys) = batch(&dev)?;
+    let mut opt = AdamW::new(varmap.all_vars(), params.clone())?;
+    for _step in 0..3 {
+        let loss = (model.forward(&xs)? - &ys)?.sqr()?.mean_all()?;
+        opt.backward_step(&loss)?;
+    }
+    let expected_weight = model.weight().to_vec2::<f32>()?;
+
+    let results = run_ranks(2, move |comm| {
+        let dev = Device::Cpu;
+        let rank = comm.rank();
+        let (varmap, model) = init_model(&dev)?;
+        // All the ranks have to use the same order for the variables.
+        let vars = {
+            let data = varmap.data().lock().unwrap();
+            vec![data["lin.weight"].clone(), data["lin.bias"].clone()]
+        };
+        let mut opt = DistributedOptimizer::<AdamW>::new(vars, params.clone(), comm)?;
+        assert_eq!(opt.shards()[0].dims(), [8]);
+        assert_eq!(opt.shards()[1].dims(), [2]);
+        let (xs, ys) = batch(&dev)?;
+        let xs = xs.narrow(0, 2 * rank, 2)?;
+        let ys = ys.narrow(0, 2 * rank, 2)?;
+        for _step in 0..3 {
+            let loss = (model.forward(&xs)? - &ys)?.sqr()?.mean_all()?;
+            opt.backward_step(&loss)?;
+        }
+        Ok(model.weight().to_vec2::<f32>()?)
+    })?;
+    for weight in results {
+        for (w, e) in weight
+            .iter()
+            .flatten()
+            .zip(expected_weight.iter().flatten())
+        {
+            assert!((w - e).abs() < 1e-5, "{weight:?} {expected_weight:?}");
+        }
+    }
+    Ok(())
+}
+
+fn adamw_params() -> ParamsAdamW {
+    ParamsAdamW {
+        lr: 0.05,
+        ..Default::default()
+    }
+}
+
+// The weights after `steps` steps of AdamW on the full batch.
+fn reference_weight(steps: usize) -> Result<Vec<Vec<f32>>> {
+    let dev = Device::Cpu;
+    let (varmap, model) = init_model(&dev)?;
+    let (xs, ys) = batch(&dev)?;
+    let mut opt = AdamW::new(varmap.all_vars(), adamw_params())?;
+    for _step in 0..steps {
+        let loss = (model.forward(&xs)? - &ys)?.sqr()?.mean_all()?;
+        opt.backward_step(&loss)?;
+    }
+    Ok(model.weight().to_vec2::<f32>()?)
+}
+
+fn assert_close(weight: &[Vec<f32>], expected: &[Vec<f32>]) {
+    for (w, e) in weight.iter().flatten().zip(expected.iter().flatten()) {
+        assert!((w - e).abs() < 1e-5, "{weight:?} {expected:?}");
+    }
+}
+
+#[test]
+fn elastic_checkpoint() -> Result<()> {
+    let expected = reference_weight(3)?;
+    let dir = std::env::temp_dir().join(format!("candle-elastic-{}", std::process::id()));
+
+    // Train for two steps with FSDP on 2 ranks, then resume on 4 ranks for the last step.
+    let fsdp_step = |world_size: usize, steps: usize, load: bool, dir: std::path::PathBuf| {
+        run_ranks(world_size, move |comm| {
+            let dev = Device::Cpu;
+            let (rank, world_size) = (comm.rank(), comm.world_size());
+            let (varmap, _) = init_model(&dev)?;
+            let params = Arc::new(ShardedParams::from_varmap(&varmap, comm)?);
+            let mut opt = AdamW::new(params.shards(), adamw_params())?;
+            if load {
+                let checkpoint = Checkpoint::load(&dir, rank, world_size, &dev)?;
+                assert_eq!(checkpoint.saved_world_size(), 2);
+                assert_eq!(checkpoint.scalars()["epoch"], 1.);
+                params.load_checkpoint(&checkpoint, &mut opt)?;
+            }
+            let model = Fsdp::new(params.clone(), "", |vb| linear(5, 3, vb.pp("lin")))?;
+            let (xs, ys) = batch(&dev)?;
+            let rows = 4 / world_size;
+            let xs = xs.narrow(0, rows * rank, rows)?;
+            let ys = ys.narrow(0, rows * rank, rows)?;
+            for _step in 0..steps {
+                let loss = (model.forward(&xs)? - &ys)?.sqr()?.mean_all()?;
+                let mut grads = loss.backward()?;
+                params.reduce_grads(&mut grads)?;
+                opt.step(&grads)?;
+            }
+            let scalars = HashMap::from([("epoch".to_string(), 1.)]);
+            params.save_checkpoint(&dir, &opt, &scalars)?;
+            let full = params.full_tensors()?;
+            Ok(full["lin.weight"].to_vec2::<f32>()?)
+        })
+    };
+    fsdp_step(2, 2, false, dir.join("fsdp"))?;
+    for weight in fsdp_step(4, 1, true, dir.join("fsdp"))? {
+        assert_close(&weight, &expected);
+    }
+
+    // Same with the ZeRO optimizer, going from 3 ranks to a single one.
+    let zero_step = |world_size: usize, steps: usize, load: bool, dir: std::path::PathBuf| {
+        run_ranks(world_size, move |comm| {
+            let dev = Device::Cpu;
+            let (rank, world_size) = (comm.rank(), comm.world_size());
+            let (varmap, model) = init_model(&dev)?;
+            let vars = {
+                let data = varmap.data().lock().unwrap();
+                vec![data["lin.weight"].clone(), data["lin.bias"].clone()]
+            };
+            let mut opt = DistributedOptimizer::<AdamW>::new(vars, adamw_params(), comm)?;
+            if load {
+                let checkpoint = Checkpoint::load(&dir, rank, world_size, &dev)?;
+                assert_eq!(checkpoint.saved_world_size(), 3);
+                opt.load_checkpoint(&checkpoint)?;
+            }
+            // The batch is replicated so that it does not have to be split in 3.
+            let (xs, ys) = batch(&dev)?;
+            for _step in 0..steps {
+                let loss = (model.forward(&xs)? - &ys)?.sqr()?.mean_all()?;
+                opt.backward_step(&loss)?;
+            }
+            opt.save_checkpoint(&dir, &HashMap::new())?;
+            Ok(model.weight().to_vec2::<f32>()?)
+        })
+    };
+    zero_step(3, 2, false, dir.join("zero"))?;
+    for weight in zero_step(1, 1, true, dir.join("zero"))? {
+        assert_close(&weight, &expected);
+    }
+    std::fs::remove_dir_all(dir)?;
+    Ok(())
+}
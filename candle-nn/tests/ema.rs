@@ -0,0 +1,109 @@
+#[cfg(feature = "mkl")]
+extern crate intel_mkl_src;
+
+#[cfg(feature = "accelerate")]
+extern crate accelerate_src;
+
+use anyhow::Result;
+use candle::{DType, Device, Tensor};
+use candle_nn::ema::EmaWarmup;
+use candle_nn::{EmaConfig, EmaModel, Init, VarMap};
+
+#[test]
+fn ema_decay() {
+    let config = EmaConfig::default();
+    assert_eq!(config.decay_at(1), 0.);
+    assert!((config.decay_at(2) - 2. / 11.).abs() < 1e-12);
+    assert_eq!(config.decay_at(1_000_000), 0.9999);
+    let config = EmaConfig {
+        update_after_step: 5,
+        min_decay: 0.5,
+        warmup: Some(EmaWarmup {
+            inv_gamma: 1.,
+            power: 0.75,
+        }),
+        ..Default::default()
+    };
+    assert_eq!(config.decay_at(6), 0.);
+    assert_eq!(config.decay_at(7), 0.5);
+    let expected = 1. - 11f64.powf(-0.75);
+    assert!((config.decay_at(16) - expected).abs() < 1e-12);
+}
+
+#[test]
+fn ema_update_apply_restore() -> Result<()> {
+    let dev = &Device::Cpu;
+    let varmap = VarMap::new();
+    let w = varmap.get(2, "w", Init::Const(0.), DType::F32, dev)?;
+    let _ = varmap.get_buffer(1, "running", Init::Const(0.), DType::F32, dev)?;
+    let config = EmaConfig {
+        decay: 0.5,
+        ..Default::default()
+    };
+    let mut ema = EmaModel::new(&varmap, config)?;
+    let mut varmap = varmap;
+
+    // The first update copies the values, then the decay is capped at 0.5.
+    varmap.set_one("w", Tensor::new(&[4f32, 8.], dev)?)?;
+    assert_eq!(ema.update()?, 0.);
+    assert_eq!(ema.shadow("w").unwrap().to_vec1::<f32>()?, [4., 8.]);
+    varmap.set_one("w", Tensor::new(&[0f32, 0.], dev)?)?;
+    varmap.set_one("running", Tensor::new(&[3f32], dev)?)?;
+    assert_eq!(ema.update()?, 2. / 11.);
+    let decay = 2f32 / 11.;
+    let shadow = ema.shadow("w").unwrap().to_vec1::<f32>()?;
+    assert!((shadow[0] - 4. * decay).abs() < 1e-6 && (shadow[1] - 8. * decay).abs() < 1e-6);
+    // The buffers are copied.
+    assert_eq!(ema.shadow("running").unwrap().to_vec1::<f32>()?, [3.]);
+    assert_eq!(ema.step(), 2);
+
+    ema.apply()?;
+    assert!(ema.is_applied());
+    assert_eq!(w.to_vec1::<f32>()?, shadow);
+    assert!(ema.apply().is_err());
+    assert!(ema.update().is_err());
+    ema.restore()?;
+    assert_eq!(w.to_vec1::<f32>()?, [0., 0.]);
+    assert!(ema.restore().is_err());
+
+    // Variables added later get a shadow at the next update.
+    varmap.get(1, "b", Init::Const(1.), DType::F32, dev)?;
+    ema.update()?;
+    assert_eq!(ema.shadow("b").unwrap().to_vec1::<f32>()?, [1.]);
+
+    let other = VarMap::new();
+    let other_w = other.get(2, "w", Init::Const(0.), DType::F64, dev)?;
+    ema.copy_to(&other)?;
+    let copied = other_w.to_vec1::<f64>()?;
+    let shadow = ema.shadow("w").unwrap().to_vec1::<f32>()?;
+    assert!((copied[0] - shadow[0] as f64).abs() < 1e-6);
+    Ok(())
+}
+
+#[test]
+fn ema_half_precision_and_save() -> Result<()> {
+    let dev = &Device::Cpu;
+    let varmap = VarMap::new();
+    let w = varmap.get(3, "w", Init::Const(1.), DType::BF16, dev)?;
+    let mut ema = EmaModel::new(&varmap, EmaConfig::default())?;
+    // The shadows of half precision variables are kept in f32.
+    assert_eq!(ema.shadow("w").unwrap().dtype(), DType::F32);
+    for _ in 0..3 {
+        ema.update()?;
+    }
+
+    let path = std::env::temp_dir().join(format!("candle-ema-{}.safetensors", std::process::id()));
+    ema.save(&path)?;
+    let mut loaded = EmaModel::new(&varmap, EmaConfig::default())?;
+    loaded.load(&path)?;
+    assert_eq!(loaded.step(), 3);
+    assert_eq!(
+        loaded.shadow("w").unwrap().to_vec1::<f32>()?,
+        ema.shadow("w").unwrap().to_vec1::<f32>()?
+    );
+    // The file can be loaded as model weights.
+    let weights = candle::safetensors::load(&path, dev)?;
+    assert_eq!(weights["w"].dims(), w.dims());
+    std::fs::remove_file(path)?;
+    Ok(())
+}
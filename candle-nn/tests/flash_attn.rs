@@ -0,0 +1,81 @@
+#[cfg(feature = "mkl")]
+extern crate intel_mkl_src;
+
+#[cfg(feature = "accelerate")]
+extern crate accelerate_src;
+
+use candle::test_utils::max_diff;
+use candle::{Device, IndexOp, Result, Tensor};
+use candle_nn::ops::flash_attn;
+
+#[test]
+fn flash_attn_causal() -> Result<()> {
+    let dev = &Device::Cpu;
+    let q = Tensor::randn(0f32, 1., (2, 7, 4, 32), dev)?;
+    let k = Tensor::randn(0f32, 1., (2, 7, 4, 32), dev)?;
+    let v = Tensor::randn(0f32, 1., (2, 7, 4, 32), dev)?;
+    let full = flash_attn(&q, &k, &v, 0.5, true)?;
+    assert_eq!(full.dims(), &[2, 7, 4, 32]);
+    // The first position only attends to itself.
+    assert!(max_diff(&full.i((.., 0))?, &v.i((.., 0))?)? < 1e-6);
+    // The last queries attend to all the keys, as when decoding with a kv cache.
+    let last = flash_attn(&q.narrow(1, 4, 3)?, &k, &v, 0.5, true)?;
+    assert!(max_diff(&last, &full.narrow(1, 4, 3)?)? < 1e-5);
+    // The last query attends to all the keys, as without the causal mask.
+    let non_causal = flash_attn(&q, &k, &v, 0.5, false)?;
+    let diff = max_diff(&full.i((.., 6))?, &non_causal.i((.., 6))?)?;
+    assert!(diff < 1e-5);
+    assert!(flash_attn(&q, &k.narrow(1, 0, 5)?, &v.narrow(1, 0, 5)?, 0.5, true).is_err());
+    Ok(())
+}
+
+#[test]
+fn flash_attn_gqa() -> Result<()> {
+    let dev = &Device::Cpu;
+    let q = Tensor::randn(0f32, 1., (1, 5, 6, 16), dev)?;
+    let k = Tensor::randn(0f32, 1., (1, 9, 2, 16), dev)?;
+    let v = Tensor::randn(0f32, 1., (1, 9, 2, 16), dev)?;
+    let ys = flash_attn(&q, &k, &v, 0.25, true)?;
+    // The query heads 0, 1, 2 share the first kv head.
+    for head in 0..6 {
+        let kv_head = head / 3;
+        let expected = flash_attn(
+            &q.narrow(2, head, 1)?,
+            &k.narrow(2, kv_head, 1)?,
+            &v.narrow(2, kv_head, 1)?,
+            0.25,
+            true,
+        )?;
+        assert!(max_diff(&ys.narrow(2, head, 1)?, &expected)? < 1e-6);
+    }
+    assert!(flash_attn(&q.narrow(2, 0, 5)?, &k, &v, 0.25, true).is_err());
+    Ok(())
+}
+
+#[cfg(feature = "metal")]
+#[test]
+fn flash_attn_metal() -> Result<()> {
+    let device = Device::new_metal(0)?;
+    for (seq_len, kv_seq_len, head_dim) in [(1, 70, 64), (37, 37, 80), (45, 100, 128), (3, 19, 256)]
+    {
+        let q = Tensor::randn(0f32, 1., (2, seq_len, 8, head_dim), &device)?;
+        let k = Tensor::randn(0f32, 1., (2, kv_seq_len, 2, head_dim), &device)?;
+        let v = Tensor::randn(0f32, 1., (2, kv_seq_len, 2, head_dim), &device)?;
+        let scale = (head_dim as f32).powf(-0.5);
+        for causal in [false, true] {
+            let ys = flash_attn(&q, &k, &v, scale, causal)?;
+            let expected = candle_nn::ops::flash_attn_slow(&q, &k, &v, scale, causal)?;
+            assert!(max_diff(&ys, &expected)? < 1e-4);
+            let ys = flash_attn(
+                &q.to_dtype(candle::DType::F16)?,
+                &k.to_dtype(candle::DType::F16)?,
+                &v.to_dtype(candle::DType::F16)?,
+                scale,
+                causal,
+            )?;
+            let diff = max_diff(&ys.to_dtype(candle::DType::F32)?, &expected)?;
+            assert!(diff < 1e-2, "{diff}");
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,256 @@
+#[cfg(feature = "mkl")]
+extern crate intel_mkl_src;
+
+#[cfg(feature = "accelerate")]
+extern crate accelerate_src;
+
+use candle::{DType, Device, IndexOp, Result, Tensor, Var};
+use candle_nn::gaussian_splatting::{
+    project_gaussians, quaternion_to_rotation, rasterize, render, Camera,
+};
+
+fn identity_camera(width: usize, height: usize, dev: &Device) -> Result<Camera> {
+    let w2c = Tensor::new(
+        &[[1f32, 0., 0., 0.], [0., 1., 0., 0.], [0., 0., 1., 0.]],
+        dev,
+    )?;
+    let center = (width as f64 * 0.5, height as f64 * 0.5);
+    Ok(Camera::new(w2c, (20., 20.), center, (width, height)))
+}
+
+// The compositing written with dense tensor operations, the gaussians are sorted by depth and
+// the contributions below 1/255 are skipped as in the kernels.
+fn dense_rasterize(
+    means2d: &Tensor,
+    conics: &Tensor,
+    colors: &Tensor,
+    opacities: &Tensor,
+    order: &[u32],
+    (width, height): (usize, usize),
+    background: &[f32],
+) -> Result<Tensor> {
+    let dev = means2d.device();
+    let order = Tensor::new(order, dev)?;
+    let (means2d, conics) = (
+        means2d.index_select(&order, 0)?,
+        conics.index_select(&order, 0)?,
+    );
+    let colors = colors.index_select(&order, 0)?;
+    let opacities = opacities.index_select(&order, 0)?;
+    let n = order.dim(0)?;
+    let px = Tensor::arange(0u32, width as u32, dev)?
+        .to_dtype(DType::F32)?
+        .unsqueeze(0)?
+        .broadcast_as((height, width))?
+        .flatten_all()?;
+    let py = Tensor::arange(0u32, height as u32, dev)?
+        .to_dtype(DType::F32)?
+        .unsqueeze(1)?
+        .broadcast_as((height, width))?
+        .flatten_all()?;
+    let dx = px
+        .unsqueeze(1)?
+        .broadcast_sub(&means2d.i((.., 0))?.unsqueeze(0)?)?;
+    let dy = py
+        .unsqueeze(1)?
+        .broadcast_sub(&means2d.i((.., 1))?.unsqueeze(0)?)?;
+    let a = conics.i((.., 0))?.unsqueeze(0)?;
+    let b = conics.i((.., 1))?.unsqueeze(0)?;
+    let c = conics.i((.., 2))?.unsqueeze(0)?;
+    let power = ((dx.sqr()?.broadcast_mul(&a)? + dy.sqr()?.broadcast_mul(&c)?)? * -0.5)?
+        .sub(&(&dx * &dy)?.broadcast_mul(&b)?)?;
+    let alpha = power.exp()?.broadcast_mul(&opacities.unsqueeze(0)?)?;
+    let alpha = (&alpha * alpha.ge(1. / 255.)?.to_dtype(DType::F32)?)?;
+    let log_t = (1. - &alpha)?.log()?;
+    let zeros = log_t.narrow(1, 0, 1)?.zeros_like()?;
+    let t = Tensor::cat(&[&zeros, &log_t.narrow(1, 0, n - 1)?], 1)?
+        .cumsum(1)?
+        .exp()?;
+    let final_t = log_t.sum_keepdim(1)?.exp()?;
+    let background = Tensor::new(background, dev)?.unsqueeze(0)?;
+    let image = (&alpha * t)?
+        .matmul(&colors)?
+        .add(&final_t.broadcast_mul(&background)?)?;
+    image.reshape((height, width, colors.dim(1)?))
+}
+
+#[test]
+fn projection() -> Result<()> {
+    let dev = &Device::Cpu;
+    let q = Tensor::new(&[[2f32, 0., 0., 0.], [0., 0., 0., 1.]], dev)?;
+    let r = quaternion_to_rotation(&q)?.to_vec3::<f32>()?;
+    assert_eq!(r[0], [[1., 0., 0.], [0., 1., 0.], [0., 0., 1.]]);
+    // A rotation of pi around z.
+    assert_eq!(r[1], [[-1., 0., 0.], [0., -1., 0.], [0., 0., 1.]]);
+
+    let camera = identity_camera(32, 24, dev)?;
+    let means = Tensor::new(&[[0f32, 0., 5.], [1., -0.5, 2.]], dev)?;
+    let scales = Tensor::new(&[[0.5f32, 0.5, 0.5], [0.1, 0.2, 0.3]], dev)?;
+    let p = project_gaussians(&means, &scales, &q, &camera)?;
+    assert_eq!(p.means2d.to_vec2::<f32>()?, [[16., 12.], [26., 7.]]);
+    assert_eq!(p.depths.to_vec1::<f32>()?, [5., 2.]);
+    // The isotropic gaussian at the center has a standard deviation of fx * s / z pixels.
+    let conics = p.conics.to_vec2::<f32>()?;
+    let var = (20. * 0.5 / 5f32).powi(2) + 0.3;
+    assert!((conics[0][0] - 1. / var).abs() < 1e-6, "{conics:?}");
+    assert!(conics[0][1].abs() < 1e-6 && (conics[0][2] - 1. / var).abs() < 1e-6);
+    // Off center gaussians are sheared.
+    assert!(conics[1][1].abs() > 1e-3);
+    Ok(())
+}
+
+#[test]
+fn rasterize_ordering() -> Result<()> {
+    let dev = &Device::Cpu;
+    let means2d = Tensor::new(&[[3f32, 2.], [3., 2.]], dev)?;
+    let conics = Tensor::new(&[[1f32, 0., 1.], [0.5, 0., 0.5]], dev)?;
+    let colors = Tensor::new(&[[1f32, 0.], [0., 1.]], dev)?;
+    let opacities = Tensor::new(&[0.5f32, 0.8], dev)?;
+    let background = [0.25, 0.5];
+    for (depths, front) in [([1f32, 2.], 0), ([2., 1.], 1)] {
+        let depths = Tensor::new(&depths, dev)?;
+        let image = rasterize(
+            &means2d,
+            &conics,
+            &colors,
+            &opacities,
+            &depths,
+            5,
+            4,
+            &background,
+        )?;
+        assert_eq!(image.dims(), [4, 5, 2]);
+        let pixel = image.i((2, 3))?.to_vec1::<f32>()?;
+        let (a_front, a_back) = if front == 0 { (0.5, 0.8) } else { (0.8, 0.5) };
+        let mut expected = [0f32; 2];
+        expected[front] = a_front;
+        expected[1 - front] = (1. - a_front) * a_back;
+        let t = (1. - a_front) * (1. - a_back);
+        for ch in 0..2 {
+            let e = expected[ch] + t * background[ch];
+            assert!((pixel[ch] - e).abs() < 1e-6, "{pixel:?} {expected:?}")
+        }
+    }
+    // The gaussians behind the camera are not rendered.
+    let depths = Tensor::new(&[-1f32, -2.], dev)?;
+    let image = rasterize(
+        &means2d,
+        &conics,
+        &colors,
+        &opacities,
+        &depths,
+        5,
+        4,
+        &background,
+    )?;
+    assert_eq!(image.i((2, 3))?.to_vec1::<f32>()?, background);
+    Ok(())
+}
+
+#[test]
+fn rasterize_matches_dense() -> Result<()> {
+    let dev = &Device::Cpu;
+    let (n, width, height) = (16, 20, 18);
+    // The scene spans two tiles in each direction.
+    let centers = Tensor::rand(0f32, 1., (n, 2), dev)?
+        .broadcast_mul(&Tensor::new(&[[24f32, 22.]], dev)?)?
+        .affine(1., -2.)?;
+    let sx = Tensor::rand(1f32, 4., n, dev)?;
+    let sy = Tensor::rand(1f32, 4., n, dev)?;
+    let rho = Tensor::rand(-0.5f32, 0.5, n, dev)?;
+    let det = (sx.sqr()? * sy.sqr()? * (1. - rho.sqr()?)?)?;
+    let conics = Tensor::stack(
+        &[
+            (sy.sqr()? / &det)?,
+            ((&rho * &sx * &sy)?.neg()? / &det)?,
+            (sx.sqr()? / &det)?,
+        ],
+        1,
+    )?;
+    let colors = Tensor::rand(0f32, 1., (n, 3), dev)?;
+    let opacities = Tensor::rand(0.1f32, 0.5, n, dev)?;
+    let depths = Tensor::rand(1f32, 10., n, dev)?;
+    let background = [0.1, 0.2, 0.3];
+
+    let means2d = Var::from_tensor(&centers)?;
+    let conics = Var::from_tensor(&conics)?;
+    let colors = Var::from_tensor(&colors)?;
+    let opacities = Var::from_tensor(&opacities)?;
+    let weights = Tensor::randn(0f32, 1., (height, width, 3), dev)?;
+
+    let image = rasterize(
+        &means2d,
+        &conics,
+        &colors,
+        &opacities,
+        &depths,
+        width,
+        height,
+        &background,
+    )?;
+    let depths_v = depths.to_vec1::<f32>()?;
+    let mut order = (0..n as u32).collect::<Vec<_>>();
+    order.sort_by(|&i, &j| depths_v[i as usize].total_cmp(&depths_v[j as usize]));
+    let expected = dense_rasterize(
+        &means2d,
+        &conics,
+        &colors,
+        &opacities,
+        &order,
+        (width, height),
+        &background,
+    )?;
+    let diff = (&image - &expected)?.abs()?.max_all()?.to_scalar::<f32>()?;
+    assert!(diff < 1e-5, "{diff}");
+
+    let grads = (image * &weights)?.sum_all()?.backward()?;
+    let expected_grads = (expected * &weights)?.sum_all()?.backward()?;
+    for var in [&means2d, &conics, &colors, &opacities] {
+        let g = grads.get(var).unwrap();
+        let e = expected_grads.get(var).unwrap();
+        let scale = e.abs()?.max_all()?.to_scalar::<f32>()?;
+        let diff = (g - e)?.abs()?.max_all()?.to_scalar::<f32>()?;
+        assert!(diff < 1e-4 * (1. + scale), "{diff} {scale}");
+    }
+    Ok(())
+}
+
+#[test]
+fn render_gradients() -> Result<()> {
+    let dev = &Device::Cpu;
+    let camera = identity_camera(16, 12, dev)?;
+    // The second gaussian is behind the camera.
+    let means = Var::new(&[[0.2f32, -0.1, 4.], [0., 0., -3.]], dev)?;
+    let scales = Var::new(&[[0.3f32, 0.2, 0.1], [1., 1., 1.]], dev)?;
+    let rotations = Var::new(&[[1f32, 0.2, 0., 0.1], [1., 0., 0., 0.]], dev)?;
+    let opacities = Var::new(&[0.7f32, 0.9], dev)?;
+    let colors = Var::new(&[[1f32, 0.5, 0.], [0., 0., 1.]], dev)?;
+    let image = render(
+        &means,
+        &scales,
+        &rotations,
+        &opacities,
+        &colors,
+        &camera,
+        &[0., 0., 0.],
+    )?;
+    assert_eq!(image.dims(), [12, 16, 3]);
+    // There is no blue in the image.
+    assert_eq!(image.i((.., .., 2))?.max_all()?.to_scalar::<f32>()?, 0.);
+    // Moving the gaussian to the right increases the red in the right half of the image.
+    let right = image.i((.., 8.., 0))?.sum_all()?;
+    let grads = right.backward()?;
+    let grad_means = grads.get(&means).unwrap().to_vec2::<f32>()?;
+    assert!(grad_means[0][0] > 0., "{grad_means:?}");
+    assert_eq!(grad_means[1], [0., 0., 0.]);
+    for var in [&scales, &rotations] {
+        let g = grads
+            .get(var)
+            .unwrap()
+            .abs()?
+            .sum_all()?
+            .to_scalar::<f32>()?;
+        assert!(g > 0. && g.is_finite())
+    }
+    Ok(())
+}
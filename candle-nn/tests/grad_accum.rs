@@ -0,0 +1,108 @@
+#[cfg(feature = "mkl")]
+extern crate intel_mkl_src;
+
+#[cfg(feature = "accelerate")]
+extern crate accelerate_src;
+
+use anyhow::Result;
+use candle::{Device, Tensor, Var};
+use candle_nn::distributed::{Communicator, ThreadGroup};
+use candle_nn::grad_accum::GradAccumulator;
+use std::sync::Arc;
+
+// The squared errors of a linear model without bias, summed over the rows of `xs`.
+fn sum_loss(w: &Var, xs: &Tensor, ys: &Tensor) -> Result<Tensor> {
+    Ok((xs.matmul(&w.as_tensor().t()?)? - ys)?.sqr()?.sum_all()?)
+}
+
+fn data() -> Result<(Tensor, Tensor)> {
+    let xs = Tensor::new(&[[1f32, 2.], [3., -1.], [0., 4.], [2., 2.]], &Device::Cpu)?;
+    let ys = Tensor::new(&[[1f32], [0.], [2.], [-1.]], &Device::Cpu)?;
+    Ok((xs, ys))
+}
+
+fn assert_close(grads: &Tensor, expected: &Tensor) -> Result<()> {
+    let diff = (grads - expected)?.abs()?.max_all()?.to_scalar::<f32>()?;
+    assert!(diff < 1e-5, "{grads} {expected}");
+    Ok(())
+}
+
+#[test]
+fn accumulate_mean() -> Result<()> {
+    let (xs, ys) = data()?;
+    let w = Var::new(&[[0.5f32, -0.5]], &Device::Cpu)?;
+    let expected = (sum_loss(&w, &xs, &ys)? / 4.)?.backward()?;
+    let expected = expected.get(&w).unwrap();
+
+    // Micro-batches of 2 rows, the mean loss of each micro-batch has the same weight.
+    let mut accum = GradAccumulator::new(vec![w.clone()], 2)?;
+    let loss = (sum_loss(&w, &xs.narrow(0, 0, 2)?, &ys.narrow(0, 0, 2)?)? / 2.)?;
+    assert!(accum.backward(&loss)?.is_none());
+    assert!(accum.is_last_micro_step());
+    let loss = (sum_loss(&w, &xs.narrow(0, 2, 2)?, &ys.narrow(0, 2, 2)?)? / 2.)?;
+    let grads = accum.backward(&loss)?.unwrap();
+    assert_close(grads.get(&w).unwrap(), expected)?;
+    assert_eq!(accum.micro_step(), 0);
+    Ok(())
+}
+
+#[test]
+fn accumulate_sum() -> Result<()> {
+    let (xs, ys) = data()?;
+    let w = Var::new(&[[0.5f32, -0.5]], &Device::Cpu)?;
+    let expected = (sum_loss(&w, &xs, &ys)? / 4.)?.backward()?;
+    let expected = expected.get(&w).unwrap();
+
+    // Micro-batches of uneven sizes, the gradients are normalized by the total count.
+    let mut accum = GradAccumulator::new(vec![w.clone()], 2)?;
+    let loss = sum_loss(&w, &xs.narrow(0, 0, 1)?, &ys.narrow(0, 0, 1)?)?;
+    assert!(accum.backward_sum(&loss, 1)?.is_none());
+    let loss = sum_loss(&w, &xs.narrow(0, 1, 3)?, &ys.narrow(0, 1, 3)?)?;
+    let grads = accum.backward_sum(&loss, 3)?.unwrap();
+    assert_close(grads.get(&w).unwrap(), expected)?;
+
+    // Resetting drops the partial accumulation.
+    let loss = sum_loss(&w, &xs, &ys)?;
+    assert!(accum.backward_sum(&loss, 4)?.is_none());
+    accum.reset();
+    assert!(accum.backward_sum(&loss, 4)?.is_none());
+    let grads = accum.backward_sum(&loss, 4)?.unwrap();
+    assert_close(grads.get(&w).unwrap(), expected)?;
+    Ok(())
+}
+
+#[test]
+fn accumulate_distributed() -> Result<()> {
+    let (xs, ys) = data()?;
+    let w = Var::new(&[[0.5f32, -0.5]], &Device::Cpu)?;
+    let expected = (sum_loss(&w, &xs, &ys)? / 4.)?.backward()?;
+    let expected = expected.get(&w).unwrap().clone();
+
+    // Rank 0 gets the first row and rank 1 the three others, each in two micro-batches.
+    let micro_batches = [[(0, 0), (0, 1)], [(1, 1), (2, 2)]];
+    let handles: Vec<_> = ThreadGroup::new(2)
+        .into_iter()
+        .map(|comm| {
+            let (xs, ys) = (xs.clone(), ys.clone());
+            let expected = expected.clone();
+            std::thread::spawn(move || -> Result<()> {
+                let rank = comm.rank();
+                let w = Var::new(&[[0.5f32, -0.5]], &Device::Cpu)?;
+                let mut accum =
+                    GradAccumulator::new(vec![w.clone()], 2)?.with_communicator(Arc::new(comm));
+                let mut result = None;
+                for (start, len) in micro_batches[rank] {
+                    let loss =
+                        sum_loss(&w, &xs.narrow(0, start, len)?, &ys.narrow(0, start, len)?)?;
+                    result = accum.backward_sum(&loss, len)?;
+                }
+                let grads = result.unwrap();
+                assert_close(grads.get(&w).unwrap(), &expected)
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap()?
+    }
+    Ok(())
+}
@@ -0,0 +1,57 @@
+#[cfg(feature = "mkl")]
+extern crate intel_mkl_src;
+
+#[cfg(feature = "accelerate")]
+extern crate accelerate_src;
+
+use anyhow::Result;
+use candle::{Device, Var};
+use candle_nn::grad_clip::grad_norm;
+use candle_nn::{clip_grad_norm_, clip_grad_value_};
+
+#[test]
+fn clip_norm() -> Result<()> {
+    let dev = &Device::Cpu;
+    let a = Var::new(&[1f32, 2.], dev)?;
+    let b = Var::new(&[[3f32], [4.]], dev)?;
+    let unused = Var::new(&[0f32], dev)?;
+    let vars = [a.clone(), b.clone(), unused];
+    // The gradients are [3, 6] and [[6], [8]], with a global norm of sqrt(145).
+    let loss = ((a.sqr()?.sum_all()? * 1.5)? + b.sqr()?.sum_all()?)?;
+    let mut grads = loss.backward()?;
+    assert!((grad_norm(&vars, &grads)? - 145f64.sqrt()).abs() < 1e-5);
+
+    // No clipping below the limit.
+    let norm = clip_grad_norm_(&vars, &mut grads, 20.)?;
+    assert!((norm - 145f64.sqrt()).abs() < 1e-5);
+    assert_eq!(grads.get(&a).unwrap().to_vec1::<f32>()?, [3., 6.]);
+
+    let norm = clip_grad_norm_(&vars, &mut grads, 1.)?;
+    assert!((norm - 145f64.sqrt()).abs() < 1e-5);
+    assert!((grad_norm(&vars, &grads)? - 1.).abs() < 1e-5);
+    let scale = 1. / 145f32.sqrt();
+    let ga = grads.get(&a).unwrap().to_vec1::<f32>()?;
+    assert!((ga[0] - 3. * scale).abs() < 1e-5 && (ga[1] - 6. * scale).abs() < 1e-5);
+    assert!(clip_grad_norm_(&vars, &mut grads, -1.).is_err());
+
+    // The gradients are not modified when the norm is not finite.
+    let mut grads = (a.sqr()?.sum_all()? * f64::INFINITY)?.backward()?;
+    let norm = clip_grad_norm_(&vars, &mut grads, 1.)?;
+    assert!(norm.is_infinite());
+    assert_eq!(
+        grads.get(&a).unwrap().to_vec1::<f32>()?,
+        [f32::INFINITY, f32::INFINITY]
+    );
+    Ok(())
+}
+
+#[test]
+fn clip_value() -> Result<()> {
+    let dev = &Device::Cpu;
+    let a = Var::new(&[1f32, -2., 0.25], dev)?;
+    let mut grads = (a.sqr()?.sum_all()?).backward()?;
+    clip_grad_value_(std::slice::from_ref(&a), &mut grads, 1.)?;
+    assert_eq!(grads.get(&a).unwrap().to_vec1::<f32>()?, [1., -1., 0.5]);
+    assert!(clip_grad_value_(&[a], &mut grads, -1.).is_err());
+    Ok(())
+}
@@ -0,0 +1,119 @@
+#[cfg(feature = "mkl")]
+extern crate intel_mkl_src;
+
+#[cfg(feature = "accelerate")]
+extern crate accelerate_src;
+
+use anyhow::Result;
+use candle::{Device, Module, Tensor};
+use candle_nn::hooks::{HookPoint, Hooked, Hooks};
+use candle_nn::{Layer, Linear};
+
+struct Model {
+    layers: Vec<Hooked<Linear>>,
+    residual: HookPoint,
+}
+
+impl Model {
+    fn new(hooks: &Hooks, dev: &Device) -> Result<Self> {
+        let layers = (0..2)
+            .map(|i| {
+                let w = Tensor::new(&[[1f32, 0.], [0., 2.]], dev)?;
+                let b = Tensor::new(&[1f32, -1.], dev)?;
+                Ok(hooks.wrap(format!("layers.{i}"), Linear::new(w, Some(b))))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Self {
+            layers,
+            residual: hooks.hook_point("residual"),
+        })
+    }
+
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        let mut xs = xs.clone();
+        for layer in self.layers.iter() {
+            xs = self.residual.forward(&(layer.forward(&xs)? + &xs)?)?;
+        }
+        Ok(xs)
+    }
+}
+
+#[test]
+fn hooks_capture_and_patch() -> Result<()> {
+    let dev = &Device::Cpu;
+    let hooks = Hooks::new();
+    let model = Model::new(&hooks, dev)?;
+    let xs = Tensor::new(&[[1f32, 1.]], dev)?;
+    let plain = model.forward(&xs)?.to_vec2::<f32>()?;
+    // [1, 1] -> [3, 2] -> [7, 5]
+    assert_eq!(plain, [[7., 5.]]);
+
+    let capture = hooks.capture("layers.*");
+    let residual = hooks.capture("residual");
+    assert_eq!(model.forward(&xs)?.to_vec2::<f32>()?, plain);
+    assert_eq!(capture.names(), ["layers.0", "layers.1"]);
+    assert_eq!(
+        capture.get("layers.1").unwrap().to_vec2::<f32>()?,
+        [[4., 3.]]
+    );
+    assert_eq!(residual.take().len(), 2);
+    assert!(residual.names().is_empty());
+
+    // Zero the output of the first layer and double the input of the second one.
+    let zero = hooks.add_post_hook("layers.0", |_, _, ys| Ok(Some(ys.zeros_like()?)));
+    let double = hooks.add_pre_hook("layers.1", |name, xs| {
+        assert_eq!(name, "layers.1");
+        Ok(Some((xs * 2.)?))
+    });
+    // [1, 1] -> [1, 1] -> [3, 3] + [1, 1]
+    assert_eq!(model.forward(&xs)?.to_vec2::<f32>()?, [[4., 4.]]);
+    assert!(hooks.remove(zero));
+    assert!(!hooks.remove(zero));
+    assert!(hooks.remove(double));
+    assert!(hooks.remove(capture.handle()));
+
+    // Patching the residual stream.
+    let handle = hooks.add_post_hook("residual", |_, xs, ys| {
+        assert_eq!(xs.to_vec2::<f32>()?, ys.to_vec2::<f32>()?);
+        Ok(Some(ys.neg()?))
+    });
+    // [1, 1] -> -[3, 2] -> -([-2, -5] + [-3, -2])
+    assert_eq!(model.forward(&xs)?.to_vec2::<f32>()?, [[5., 7.]]);
+    hooks.remove(handle);
+    hooks.remove(residual.handle());
+    assert!(hooks.is_empty());
+
+    // The hooks can register other hooks.
+    let inner = hooks.clone();
+    hooks.add_post_hook("layers.0", move |_, _, _| {
+        inner.add_pre_hook("never", |_, _| Ok(None));
+        Ok(None)
+    });
+    model.forward(&xs)?;
+    hooks.clear();
+    assert!(hooks.is_empty());
+
+    // Closures can be wrapped too.
+    let f = hooks.wrap_fn("act", |xs| xs.relu());
+    let act = hooks.capture("act");
+    f.forward(&Tensor::new(&[-1f32, 2.], dev)?)?;
+    assert_eq!(act.get("act").unwrap().to_vec1::<f32>()?, [0., 2.]);
+    Ok(())
+}
+
+#[test]
+fn hooked_layers_are_transparent() -> Result<()> {
+    let dev = &Device::Cpu;
+    let hooks = Hooks::new();
+    let mut model = Model::new(&hooks, dev)?;
+    let names: Vec<_> = model
+        .layers
+        .named_parameters()?
+        .into_iter()
+        .map(|(n, _)| n)
+        .collect();
+    assert_eq!(names, ["0.weight", "0.bias", "1.weight", "1.bias"]);
+    assert_eq!(model.layers[0].name(), "layers.0");
+    assert_eq!(model.residual.name(), "residual");
+    Ok(())
+}
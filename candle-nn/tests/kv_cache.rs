@@ -31,6 +31,79 @@ fn kv_cache() -> Result<()> {
     Ok(())
 }
 
+#[test]
+fn kv_cache_index_select() -> Result<()> {
+    let mut cache = candle_nn::kv_cache::KvCache::new(1, 8);
+    let k = Tensor::new(&[[1f32, 2.], [3., 4.]], &Device::Cpu)?;
+    let v = k.neg()?;
+    cache.append(&k, &v)?;
+    // Duplicate the second entry of the batch and drop the first one.
+    let indices = Tensor::new(&[1u32, 1, 0], &Device::Cpu)?;
+    cache.index_select(&indices, 0)?;
+    let k = Tensor::new(&[[5f32], [6.], [7.]], &Device::Cpu)?;
+    let (k, v) = cache.append(&k, &k.neg()?)?;
+    assert_eq!(
+        k.to_vec2::<f32>()?,
+        [[3., 4., 5.], [3., 4., 6.], [1., 2., 7.]]
+    );
+    assert_eq!(v.to_vec2::<f32>()?[0], [-3., -4., -5.]);
+    assert_eq!(cache.current_seq_len(), 3);
+    assert!(cache.index_select(&indices, 1).is_err());
+    Ok(())
+}
+
+#[test]
+fn kv_cache_fork_and_truncate() -> Result<()> {
+    let dev = &Device::Cpu;
+    let t = |v: &[f32]| Tensor::new(v, dev);
+    let mut cache = candle_nn::kv_cache::KvCache::new(0, 8);
+    cache.append(&t(&[1., 2., 3.])?, &t(&[-1., -2., -3.])?)?;
+    // The fork and the original write to the same positions of their buffers.
+    let mut fork = cache.fork();
+    let (k, _) = fork.append(&t(&[4.])?, &t(&[-4.])?)?;
+    assert_eq!(k.to_vec1::<f32>()?, [1., 2., 3., 4.]);
+    let (k, v) = cache.append(&t(&[5., 6.])?, &t(&[-5., -6.])?)?;
+    assert_eq!(k.to_vec1::<f32>()?, [1., 2., 3., 5., 6.]);
+    assert_eq!(v.to_vec1::<f32>()?, [-1., -2., -3., -5., -6.]);
+    assert_eq!(fork.k()?.unwrap().to_vec1::<f32>()?, [1., 2., 3., 4.]);
+
+    let snapshot = cache.snapshot();
+    cache.truncate(2)?;
+    assert_eq!(cache.current_seq_len(), 2);
+    let (k, _) = cache.append(&t(&[7.])?, &t(&[-7.])?)?;
+    assert_eq!(k.to_vec1::<f32>()?, [1., 2., 7.]);
+    cache.restore(&snapshot);
+    assert_eq!(snapshot.current_seq_len(), 5);
+    let (k, _) = cache.append(&t(&[8.])?, &t(&[-8.])?)?;
+    assert_eq!(k.to_vec1::<f32>()?, [1., 2., 3., 5., 6., 8.]);
+    assert!(cache.truncate(7).is_err());
+
+    // The rotating caches can be truncated until they wrap around.
+    let mut cache = candle_nn::kv_cache::RotatingKvCache::new(0, 4);
+    cache.append(&t(&[1., 2., 3.])?, &t(&[1., 2., 3.])?)?;
+    let fork = cache.fork();
+    cache.truncate(1)?;
+    let (k, _) = cache.append(&t(&[4., 5.])?, &t(&[4., 5.])?)?;
+    assert_eq!(k.to_vec1::<f32>()?, [1., 4., 5.]);
+    assert_eq!(fork.k()?.unwrap().to_vec1::<f32>()?, [1., 2., 3.]);
+    cache.append(&t(&[6., 7.])?, &t(&[6., 7.])?)?;
+    assert!(cache.truncate(2).is_err());
+
+    let kv_dtype = candle_nn::kv_cache::KvCacheDType::Int8;
+    let mut cache = candle_nn::kv_cache::QuantizedKvCache::new(8, kv_dtype);
+    let k = Tensor::ones((1, 1, 3, 2), candle::DType::F32, dev)?;
+    cache.append(&k, &k)?;
+    let fork = cache.fork();
+    cache.truncate(1)?;
+    cache.append(&(&k * 2.)?, &k)?;
+    assert_eq!(cache.current_seq_len(), 4);
+    let k = cache.k()?.unwrap().flatten_all()?.to_vec1::<f32>()?;
+    assert_eq!(k, [1., 1., 2., 2., 2., 2., 2., 2.]);
+    let k = fork.k()?.unwrap().flatten_all()?.to_vec1::<f32>()?;
+    assert_eq!(k, [1.; 6]);
+    Ok(())
+}
+
 #[test]
 fn rotating_kv_cache() -> Result<()> {
     let mut cache = candle_nn::kv_cache::RotatingCache::new(0, 6);
@@ -108,3 +181,76 @@ fn rotating_kv_cache() -> Result<()> {
     }
     Ok(())
 }
+
+#[test]
+fn quantized_kv_cache() -> Result<()> {
+    use candle_nn::attention::{causal_mask, scaled_dot_product_attention, AttentionConfig};
+    use candle_nn::kv_cache::{KvCacheDType, QuantizedKvCache};
+    let dev = &Device::Cpu;
+    let max_abs_diff = |a: &Tensor, b: &Tensor| -> Result<f32> {
+        (a - b)?.abs()?.flatten_all()?.max(0)?.to_scalar::<f32>()
+    };
+
+    // FP8 values with a power of two scale are represented exactly.
+    let xs = Tensor::new(
+        &[[[[448f32, -0.25, 3.5, 2f32.powi(-7), 0., -448., 20., 1.]]]],
+        dev,
+    )?;
+    let mut cache = QuantizedKvCache::new(8, KvCacheDType::F8E4M3);
+    cache.append(&xs, &xs)?;
+    let k = cache.k()?.unwrap().flatten_all()?.to_vec1::<f32>()?;
+    assert_eq!(k, xs.flatten_all()?.to_vec1::<f32>()?);
+    // Rounding to the nearest value with 3 mantissa bits, 2.3 is between 2.25 and 2.5.
+    let xs = Tensor::new(&[[[[448f32, 2.3, 101., 0.01]]]], dev)?;
+    let mut cache = QuantizedKvCache::new(8, KvCacheDType::F8E4M3);
+    cache.append(&xs, &xs)?;
+    let k = cache.k()?.unwrap().flatten_all()?.to_vec1::<f32>()?;
+    assert_eq!(k[..3], [448., 2.25, 104.]);
+    assert!((k[3] - 0.009765625).abs() < 1e-6, "{k:?}");
+
+    let (b, h, kv_h, d) = (2, 4, 2, 16);
+    // Deterministic values in [-1, 1].
+    let values = |shape: (usize, usize, usize, usize), freq: f64| {
+        let n = shape.0 * shape.1 * shape.2 * shape.3;
+        (Tensor::arange(0u32, n as u32, dev)?.to_dtype(candle::DType::F32)? * freq)?
+            .sin()?
+            .reshape(shape)
+    };
+    let q = values((b, h, 10, d), 0.37)?;
+    let k = values((b, kv_h, 10, d), 1.13)?;
+    let v = values((b, kv_h, 10, d), 0.71)?;
+    let mask = causal_mask(10, dev)?;
+    let config = AttentionConfig::default();
+    let expected = scaled_dot_product_attention(&q, &k, &v, Some(&mask), &config)?;
+    for (kv_dtype, tol) in [(KvCacheDType::Int8, 0.005), (KvCacheDType::F8E4M3, 0.04)] {
+        let mut cache = QuantizedKvCache::new(16, kv_dtype).with_chunk_size(3);
+        cache.append(&k.narrow(2, 0, 7)?, &v.narrow(2, 0, 7)?)?;
+        cache.append(&k.narrow(2, 7, 3)?, &v.narrow(2, 7, 3)?)?;
+        assert_eq!(cache.current_seq_len(), 10);
+        let (k_deq, v_deq) = (cache.k()?.unwrap(), cache.v()?.unwrap());
+        assert!(max_abs_diff(&k_deq, &k)? < tol);
+        assert!(max_abs_diff(&v_deq, &v)? < tol);
+
+        // The chunked attention matches the attention over the dequantized cache.
+        let attn = cache.attention(&q, Some(&mask), &config)?;
+        let attn_deq = scaled_dot_product_attention(&q, &k_deq, &v_deq, Some(&mask), &config)?;
+        assert!(max_abs_diff(&attn, &attn_deq)? < 1e-5);
+        assert!(max_abs_diff(&attn, &expected)? < tol);
+
+        // Decoding a single position.
+        let attn = cache.attention(&q.narrow(2, 9, 1)?, None, &config)?;
+        assert!(max_abs_diff(&attn, &expected.narrow(2, 9, 1)?)? < tol);
+
+        // The causal configs attend from the last cached positions, as for a chunked prefill.
+        let causal = AttentionConfig {
+            causal: true,
+            ..config
+        };
+        let q_last = q.narrow(2, 5, 5)?;
+        let attn = cache.attention(&q_last, None, &causal)?;
+        let attn_deq = scaled_dot_product_attention(&q_last, &k_deq, &v_deq, None, &causal)?;
+        assert!(max_abs_diff(&attn, &attn_deq)? < 1e-5);
+        assert!(max_abs_diff(&attn, &expected.narrow(2, 5, 5)?)? < tol);
+    }
+    Ok(())
+}
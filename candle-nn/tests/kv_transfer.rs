@@ -0,0 +1,123 @@
+#[cfg(feature = "mkl")]
+extern crate intel_mkl_src;
+
+#[cfg(feature = "accelerate")]
+extern crate accelerate_src;
+
+use candle::{DType, Device, Result, Tensor};
+use candle_nn::distributed::{Communicator, ThreadGroup};
+use candle_nn::kv_transfer::{recv_kv_blocks, send_kv_blocks, KvBlocks};
+use candle_nn::paged_attention::PagedKvCache;
+
+// A cache with two layers, 2 kv heads, and a head dim of 3, sequence 0 has `len` positions.
+fn filled_cache(len: usize, dev: &Device) -> Result<PagedKvCache> {
+    let mut cache = PagedKvCache::new(2, 8, 4, 2, 3, DType::F32, dev)?;
+    // Sequence 1 takes the first block so that the blocks of sequence 0 are not the first ones.
+    cache.add_sequence(1)?;
+    cache.add_sequence(0)?;
+    let batch = cache.prepare(&[(1, 2), (0, len)])?;
+    for layer_idx in 0..2 {
+        let k = Tensor::randn(0f32, 1., (len + 2, 2, 3), dev)?;
+        let v = Tensor::randn(0f32, 1., (len + 2, 2, 3), dev)?;
+        cache.write(layer_idx, &batch, &k, &v)?;
+    }
+    Ok(cache)
+}
+
+fn assert_same_sequence(a: &PagedKvCache, b: &PagedKvCache, seq_a: usize, seq_b: usize) {
+    for layer_idx in 0..2 {
+        let (ka, va) = a.gather(layer_idx, seq_a).unwrap();
+        let (kb, vb) = b.gather(layer_idx, seq_b).unwrap();
+        assert_eq!(ka.to_vec3::<f32>().unwrap(), kb.to_vec3::<f32>().unwrap());
+        assert_eq!(va.to_vec3::<f32>().unwrap(), vb.to_vec3::<f32>().unwrap());
+    }
+}
+
+#[test]
+fn kv_export_import() -> Result<()> {
+    let dev = &Device::Cpu;
+    let src = filled_cache(6, dev)?;
+    let blocks = src.export_sequence(0)?;
+    assert_eq!(blocks.seq_len(), 6);
+    assert_eq!(blocks.num_blocks(), 2);
+    assert_eq!(blocks.data().dims(), &[2, 2, 2, 4, 2, 3]);
+
+    let mut dst = PagedKvCache::new(2, 4, 4, 2, 3, DType::F32, dev)?;
+    dst.add_sequence(3)?;
+    dst.prepare(&[(3, 1)])?;
+    dst.import_sequence(7, &blocks)?;
+    assert_eq!(dst.num_free_blocks(), 1);
+    assert_eq!(dst.sequence(7).unwrap().len(), 6);
+    assert_same_sequence(&src, &dst, 0, 7);
+    // The imported sequence can be extended.
+    let batch = dst.prepare(&[(7, 1)])?;
+    assert_eq!(batch.seq_lens(), &[7]);
+
+    assert!(dst.import_sequence(7, &blocks).is_err());
+    // Not enough free blocks.
+    assert!(dst.import_sequence(8, &blocks).is_err());
+    // A different block size.
+    let mut other = PagedKvCache::new(2, 4, 2, 2, 3, DType::F32, dev)?;
+    assert!(other.import_sequence(0, &blocks).is_err());
+
+    // The blocks are converted to the dtype of the cache.
+    let mut half = PagedKvCache::new(2, 4, 4, 2, 3, DType::F16, dev)?;
+    half.import_sequence(0, &blocks)?;
+    let (k, _) = half.gather(1, 0)?;
+    let (expected, _) = src.gather(1, 0)?;
+    let diff = (k.to_dtype(DType::F32)? - expected)?
+        .abs()?
+        .max_keepdim(0)?;
+    assert!(diff.flatten_all()?.max(0)?.to_scalar::<f32>()? < 1e-2);
+    Ok(())
+}
+
+#[test]
+fn kv_blocks_bytes() -> Result<()> {
+    let dev = &Device::Cpu;
+    let src = filled_cache(5, dev)?;
+    let blocks = src.export_sequence(0)?;
+    let decoded = KvBlocks::from_bytes(&blocks.to_bytes()?, dev)?;
+    assert_eq!(decoded.seq_len(), 5);
+    assert_eq!(decoded.block_size(), 4);
+    assert_eq!(decoded.num_layers(), 2);
+    let mut dst = PagedKvCache::new(2, 4, 4, 2, 3, DType::F32, dev)?;
+    dst.import_sequence(0, &decoded)?;
+    assert_same_sequence(&src, &dst, 0, 0);
+
+    assert!(KvBlocks::from_bytes(b"not safetensors", dev).is_err());
+    // Two blocks of four positions cannot hold 9 positions.
+    assert!(KvBlocks::new(9, blocks.data().clone()).is_err());
+    Ok(())
+}
+
+#[test]
+fn kv_blocks_send_recv() -> Result<()> {
+    let dev = &Device::Cpu;
+    let src = filled_cache(7, dev)?;
+    let blocks = src.export_sequence(0)?;
+    let comms = ThreadGroup::new(2);
+    let received = std::thread::scope(|s| {
+        let mut handles = vec![];
+        for comm in comms.into_iter() {
+            let blocks = &blocks;
+            handles.push(s.spawn(move || {
+                if comm.rank() == 0 {
+                    send_kv_blocks(&comm, blocks).map(|()| None)
+                } else {
+                    recv_kv_blocks(&comm, 0, &Device::Cpu).map(Some)
+                }
+            }))
+        }
+        handles
+            .into_iter()
+            .map(|h| h.join().unwrap())
+            .collect::<Result<Vec<_>>>()
+    })?;
+    let received = received[1].as_ref().unwrap();
+    assert_eq!(received.seq_len(), 7);
+    let mut dst = PagedKvCache::new(2, 4, 4, 2, 3, DType::F32, dev)?;
+    dst.import_sequence(0, received)?;
+    assert_same_sequence(&src, &dst, 0, 0);
+    Ok(())
+}
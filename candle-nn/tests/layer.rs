@@ -0,0 +1,136 @@
+#[cfg(feature = "mkl")]
+extern crate intel_mkl_src;
+
+#[cfg(feature = "accelerate")]
+extern crate accelerate_src;
+
+use anyhow::Result;
+use candle::{DType, Device, Module, Tensor};
+use candle_nn::layer::{trainable_vars, LayerFn};
+use candle_nn::{BatchNorm, Dropout, Layer, Linear, Optimizer, VarBuilder, VarMap, SGD};
+
+struct Model {
+    layers: Vec<Linear>,
+    norm: BatchNorm,
+    dropout: Dropout,
+    head: Option<Linear>,
+}
+
+impl Layer for Model {
+    fn visit_children(&mut self, f: &mut LayerFn) -> candle::Result<()> {
+        f("layers", &mut self.layers)?;
+        f("norm", &mut self.norm)?;
+        f("dropout", &mut self.dropout)?;
+        f("head", &mut self.head)
+    }
+}
+
+impl Model {
+    fn new(vb: VarBuilder) -> Result<Self> {
+        let layers = (0..2)
+            .map(|i| candle_nn::linear(4, 4, vb.pp(format!("layers.{i}"))))
+            .collect::<candle::Result<Vec<_>>>()?;
+        Ok(Self {
+            layers,
+            norm: candle_nn::batch_norm(4, 1e-5, vb.pp("norm"))?,
+            dropout: Dropout::new(0.5),
+            head: Some(candle_nn::linear_no_bias(4, 1, vb.pp("head"))?),
+        })
+    }
+
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        let mut xs = xs.clone();
+        for layer in self.layers.iter() {
+            xs = layer.forward(&xs)?.relu()?
+        }
+        let xs = self.norm.forward_mode(&xs)?;
+        let xs = self.dropout.forward_mode(&xs)?;
+        Ok(self.head.as_ref().unwrap().forward(&xs)?)
+    }
+}
+
+#[test]
+fn layer_introspection() -> Result<()> {
+    let dev = &Device::Cpu;
+    let varmap = VarMap::new();
+    let vb = VarBuilder::from_varmap(&varmap, DType::F32, dev);
+    let mut model = Model::new(vb)?;
+
+    let names: Vec<_> = model
+        .named_parameters()?
+        .into_iter()
+        .map(|(n, _)| n)
+        .collect();
+    assert_eq!(
+        names,
+        [
+            "layers.0.weight",
+            "layers.0.bias",
+            "layers.1.weight",
+            "layers.1.bias",
+            "norm.weight",
+            "norm.bias",
+            "head.weight",
+        ]
+    );
+    let buffers: Vec<_> = model.named_buffers()?.into_iter().map(|(n, _)| n).collect();
+    assert_eq!(buffers, ["norm.running_mean", "norm.running_var"]);
+    // The names match the ones of the VarMap.
+    let mut all: Vec<_> = names.iter().chain(buffers.iter()).cloned().collect();
+    all.sort();
+    let mut varmap_names: Vec<_> = varmap.data().lock().unwrap().keys().cloned().collect();
+    varmap_names.sort();
+    assert_eq!(all, varmap_names);
+
+    let mut layers = vec![];
+    model.apply(&mut |name, layer| {
+        layers.push((name.to_string(), layer.is_training()));
+        Ok(())
+    })?;
+    assert_eq!(layers.len(), 7);
+    assert_eq!(layers[0], ("".to_string(), None));
+    assert_eq!(layers[2], ("layers.0".to_string(), None));
+    assert_eq!(layers[4], ("norm".to_string(), Some(false)));
+
+    // The training mode is propagated to the dropout and the batch norm.
+    let xs = Tensor::randn(0f32, 1., (16, 4), dev)?;
+    let eval = model.forward(&xs)?;
+    assert_eq!(
+        model.forward(&xs)?.to_vec2::<f32>()?,
+        eval.to_vec2::<f32>()?
+    );
+    model.train()?;
+    assert_eq!(model.dropout.is_training(), Some(true));
+    assert_eq!(model.norm.is_training(), Some(true));
+    model.forward(&xs)?;
+    let running_mean = model.norm.running_mean().to_vec1::<f32>()?;
+    assert!(running_mean.iter().any(|&v| v != 0.));
+    model.eval()?;
+    assert_eq!(model.norm.is_training(), Some(false));
+    assert_eq!(model.norm.running_mean().to_vec1::<f32>()?, running_mean);
+
+    // Only train the head.
+    let vars = trainable_vars(&varmap, &mut model, |name| name.starts_with("head."))?;
+    assert_eq!(vars.len(), 1);
+    let before = model.layers[0].weight().to_vec2::<f32>()?;
+    let head_before = model.head.as_ref().unwrap().weight().to_vec2::<f32>()?;
+    let mut opt = SGD::new(vars, 0.1)?;
+    let loss = model.forward(&xs)?.sqr()?.mean_all()?;
+    opt.backward_step(&loss)?;
+    assert_eq!(model.layers[0].weight().to_vec2::<f32>()?, before);
+    let head_after = model.head.as_ref().unwrap().weight().to_vec2::<f32>()?;
+    assert_ne!(head_after, head_before);
+
+    // The parameters can be replaced while visiting them.
+    model.visit_named_parameters(&mut |name, t| {
+        if name == "head.weight" {
+            *t = t.zeros_like()?
+        }
+        Ok(())
+    })?;
+    assert_eq!(model.forward(&xs)?.sum_all()?.to_scalar::<f32>()?, 0.);
+    let other = Linear::new(Tensor::zeros((1, 4), DType::F32, dev)?, None);
+    model.head = Some(other);
+    assert!(trainable_vars(&varmap, &mut model, |_| true).is_err());
+    Ok(())
+}
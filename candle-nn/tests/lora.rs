@@ -0,0 +1,277 @@
+#[cfg(feature = "mkl")]
+extern crate intel_mkl_src;
+
+#[cfg(feature = "accelerate")]
+extern crate accelerate_src;
+
+use anyhow::Result;
+use candle::quantized::{GgmlDType, QMatMul, QTensor};
+use candle::test_utils::max_diff;
+use candle::{DType, Device, Module, Tensor};
+use candle_nn::lora::{
+    LoraConv2d, LoraEmbedding, LoraLinear, LoraWeights, PeftAdapter, QLoraLinear,
+};
+use candle_nn::optim::{FusedAdamW, Optimizer};
+use candle_nn::{Conv2d, Conv2dConfig, Embedding, Layer, Linear, ParamsAdamW, VarBuilder, VarMap};
+use std::collections::HashMap;
+
+fn lora(
+    rank: usize,
+    in_dim: usize,
+    out_dim: usize,
+    alpha: f64,
+    dev: &Device,
+) -> Result<LoraWeights> {
+    let a = Tensor::randn(0f32, 1., (rank, in_dim), dev)?;
+    let b = Tensor::randn(0f32, 1., (out_dim, rank), dev)?;
+    Ok(LoraWeights::new(a, b, alpha)?)
+}
+
+#[test]
+fn lora_linear() -> Result<()> {
+    let dev = &Device::Cpu;
+    let w = Tensor::randn(0f32, 1., (3, 4), dev)?;
+    let bias = Tensor::randn(0f32, 1., 3, dev)?;
+    let base = Linear::new(w.clone(), Some(bias));
+    let mut layer = LoraLinear::new(base.clone());
+    let first = lora(2, 4, 3, 4., dev)?;
+    let second = lora(1, 4, 3, 1., dev)?;
+    layer.add_adapter("first", first.clone())?;
+    layer.add_adapter("second", second.clone())?;
+    assert_eq!(layer.adapter_names(), ["first", "second"]);
+    assert!(layer.add_adapter("bad", lora(2, 3, 3, 1., dev)?).is_err());
+    assert!(layer.set_active_adapter(Some("unknown")).is_err());
+
+    let xs = Tensor::randn(0f32, 1., (2, 5, 4), dev)?;
+    let base_ys = base.forward(&xs)?;
+    assert_eq!(max_diff(&layer.forward(&xs)?, &base_ys)?, 0.);
+
+    layer.set_active_adapter(Some("first"))?;
+    let expected = (&base_ys + xs.broadcast_matmul(&first.delta()?.t()?)?)?;
+    let ys = layer.forward(&xs)?;
+    assert!(max_diff(&ys, &expected)? < 1e-4);
+
+    layer.merge_weights()?;
+    assert!(layer.is_merged());
+    assert!(max_diff(layer.base().weight(), &w)? > 0.);
+    assert!(max_diff(&layer.forward(&xs)?, &ys)? < 1e-4);
+
+    // Switching adapters while merged merges the new one.
+    layer.set_active_adapter(Some("second"))?;
+    assert!(layer.is_merged());
+    let expected = (&base_ys + xs.broadcast_matmul(&second.delta()?.t()?)?)?;
+    assert!(max_diff(&layer.forward(&xs)?, &expected)? < 1e-4);
+
+    layer.unmerge_weights()?;
+    assert!(max_diff(layer.base().weight(), &w)? < 1e-5);
+    assert!(max_diff(&layer.forward(&xs)?, &expected)? < 1e-4);
+
+    // Removing the active adapter falls back to the base layer.
+    assert!(layer.remove_adapter("second")?.is_some());
+    assert_eq!(layer.active_adapter(), None);
+    // The base weight has been merged and unmerged, up to rounding errors.
+    assert!(max_diff(&layer.forward(&xs)?, &base_ys)? < 1e-5);
+
+    layer.set_active_adapter(Some("first"))?;
+    let merged = layer.clone().into_merged()?;
+    assert!(max_diff(&merged.forward(&xs)?, &layer.forward(&xs)?)? < 1e-4);
+    Ok(())
+}
+
+#[test]
+fn lora_embedding_and_conv() -> Result<()> {
+    let dev = &Device::Cpu;
+    let embeddings = Tensor::randn(0f32, 1., (10, 4), dev)?;
+    let mut emb = LoraEmbedding::new(Embedding::new(embeddings, 4)).with_dropout(0.5);
+    emb.add_adapter("a", lora(2, 10, 4, 2., dev)?)?;
+    emb.set_active_adapter(Some("a"))?;
+    // The dropout is not applied to the token ids.
+    emb.train()?;
+    let ids = Tensor::new(&[[1u32, 3, 9], [0, 0, 2]], dev)?;
+    let ys = emb.forward(&ids)?;
+    assert_eq!(ys.dims(), [2, 3, 4]);
+    emb.merge_weights()?;
+    assert!(max_diff(&emb.forward(&ids)?, &ys)? < 1e-4);
+
+    let w = Tensor::randn(0f32, 1., (5, 3, 3, 3), dev)?;
+    let cfg = Conv2dConfig {
+        padding: 1,
+        stride: 2,
+        ..Default::default()
+    };
+    let mut conv = LoraConv2d::new(Conv2d::new(w, None, cfg));
+    conv.add_adapter("a", lora(2, 27, 5, 2., dev)?)?;
+    assert!(conv.add_adapter("bad", lora(2, 9, 5, 2., dev)?).is_err());
+    conv.set_active_adapter(Some("a"))?;
+    let xs = Tensor::randn(0f32, 1., (2, 3, 8, 8), dev)?;
+    let ys = conv.forward(&xs)?;
+    assert_eq!(ys.dims(), [2, 5, 4, 4]);
+    conv.merge_weights()?;
+    assert!(max_diff(&conv.forward(&xs)?, &ys)? < 1e-4);
+    Ok(())
+}
+
+#[test]
+fn lora_peft_adapter() -> Result<()> {
+    let dev = &Device::Cpu;
+    let dir = std::env::temp_dir().join(format!("candle-lora-{}", std::process::id()));
+    std::fs::create_dir_all(&dir)?;
+    let config = r#"{"r": 4, "lora_alpha": 8, "lora_dropout": 0.1, "use_rslora": true,
+        "alpha_pattern": {"v_proj": 16}, "target_modules": ["q_proj", "v_proj"],
+        "peft_type": "LORA"}"#;
+    std::fs::write(dir.join("adapter_config.json"), config)?;
+    let prefix = "base_model.model.model.layers.0.self_attn";
+    let a = Tensor::randn(0f32, 1., (4, 6), dev)?;
+    let b = Tensor::randn(0f32, 1., (6, 4), dev)?;
+    let tensors = HashMap::from([
+        (format!("{prefix}.q_proj.lora_A.weight"), a.clone()),
+        (format!("{prefix}.q_proj.lora_B.weight"), b.clone()),
+        (format!("{prefix}.v_proj.lora_A.weight"), a.clone()),
+        (format!("{prefix}.v_proj.lora_B.weight"), b.clone()),
+        (
+            "base_model.model.embed.lora_embedding_A".to_string(),
+            a.clone(),
+        ),
+        (
+            "base_model.model.embed.lora_embedding_B".to_string(),
+            b.clone(),
+        ),
+    ]);
+    candle::safetensors::save(&tensors, dir.join("adapter_model.safetensors"))?;
+
+    let adapter = PeftAdapter::load(&dir, DType::F32, dev)?;
+    std::fs::remove_dir_all(&dir)?;
+    assert_eq!(adapter.config().rank, 4);
+    assert_eq!(adapter.config().dropout, 0.1);
+    assert_eq!(
+        adapter.module_names(),
+        [
+            "embed",
+            "model.layers.0.self_attn.q_proj",
+            "model.layers.0.self_attn.v_proj"
+        ]
+    );
+    let q = adapter.weights("model.layers.0.self_attn.q_proj")?.unwrap();
+    assert_eq!(q.scale(), 8. / 2.);
+    let v = adapter.weights("model.layers.0.self_attn.v_proj")?.unwrap();
+    assert_eq!(v.scale(), 16. / 2.);
+    assert!(adapter
+        .weights("model.layers.0.self_attn.k_proj")?
+        .is_none());
+
+    let mut layer = LoraLinear::new(Linear::new(Tensor::zeros((6, 6), DType::F32, dev)?, None));
+    assert!(adapter.apply("peft", "model.layers.0.self_attn.q_proj", &mut layer)?);
+    assert!(!adapter.apply("peft", "model.layers.0.self_attn.k_proj", &mut layer)?);
+    layer.set_active_adapter(Some("peft"))?;
+    let merged = layer.into_merged()?;
+    let expected = (b.matmul(&a)? * 4.)?;
+    assert!(max_diff(merged.weight(), &expected)? < 1e-4);
+
+    let mut emb = LoraEmbedding::new(Embedding::new(Tensor::zeros((6, 6), DType::F32, dev)?, 6));
+    assert!(adapter.apply("peft", "embed", &mut emb)?);
+    Ok(())
+}
+
+#[test]
+fn lora_training() -> Result<()> {
+    let dev = &Device::Cpu;
+    let varmap = VarMap::new();
+    let vb = VarBuilder::from_varmap(&varmap, DType::F32, dev);
+    let base = candle_nn::linear(4, 3, vb.pp("base"))?;
+    let mut layer = LoraLinear::new(base);
+    layer.add_adapter("train", LoraWeights::init(vb.pp("lora"), 4, 3, 2, 4.)?)?;
+    layer.set_active_adapter(Some("train"))?;
+    // The update starts at zero.
+    let xs = Tensor::randn(0f32, 1., (5, 4), dev)?;
+    assert_eq!(
+        max_diff(&layer.forward(&xs)?, &layer.base().forward(&xs)?)?,
+        0.
+    );
+
+    let names: Vec<_> = layer
+        .named_parameters()?
+        .into_iter()
+        .map(|(n, _)| n)
+        .collect();
+    assert_eq!(
+        names,
+        [
+            "weight",
+            "bias",
+            "lora_A.train.weight",
+            "lora_B.train.weight"
+        ]
+    );
+    let vars = candle_nn::layer::trainable_vars(&varmap, &mut layer, |n| n.starts_with("lora_"))?;
+    assert_eq!(vars.len(), 2);
+    Ok(())
+}
+
+#[test]
+fn qlora_linear() -> Result<()> {
+    let dev = &Device::Cpu;
+    let w = Tensor::randn(0f32, 1., (8, 256), dev)?;
+    let qw = QTensor::quantize(&w, GgmlDType::Nf4)?;
+    let dequantized = Linear::new(qw.dequantize(dev)?, None);
+    let varmap = VarMap::new();
+    let vb = VarBuilder::from_varmap(&varmap, DType::F32, dev);
+    let adapter = LoraWeights::init(vb.pp("adapter"), 256, 8, 4, 8.)?;
+    // The b weights start at zero, some random values exercise both gradients.
+    varmap.data().lock().unwrap()["adapter.lora_B.weight"].set(&Tensor::randn(
+        0f32,
+        0.1,
+        (8, 4),
+        dev,
+    )?)?;
+    let mut layer = QLoraLinear::new(QMatMul::from_qtensor(qw)?);
+    layer.add_adapter("a", adapter.clone())?;
+    layer.set_active_adapter(Some("a"))?;
+    let mut reference = LoraLinear::new(dequantized.clone());
+    reference.add_adapter("a", adapter.clone())?;
+    reference.set_active_adapter(Some("a"))?;
+
+    // The gradients go through the frozen quantized weight, e.g. to the layers below.
+    let xs = candle::Var::randn(0f32, 1., (2, 3, 256), dev)?;
+    let ys = layer.forward(&xs)?;
+    let expected = reference.forward(&xs)?;
+    // The quantized matmul also quantizes its input on cpu, hence the tolerance.
+    let rel_diff = |a: &Tensor, b: &Tensor| -> Result<f32> {
+        Ok(max_diff(a, b)? / b.abs()?.max_all()?.to_scalar::<f32>()?)
+    };
+    assert!(rel_diff(&ys, &expected)? < 2e-2);
+    let grads = ys.sqr()?.sum_all()?.backward()?;
+    let expected_grads = expected.sqr()?.sum_all()?.backward()?;
+    for t in [xs.as_tensor(), adapter.a(), adapter.b()] {
+        let g = grads.get(t).unwrap();
+        let e = expected_grads.get(t).unwrap();
+        assert!(rel_diff(g, e)? < 2e-2);
+    }
+
+    // Training the adapter with a paged optimizer.
+    let target = Tensor::randn(0f32, 1., (2, 3, 8), dev)?;
+    let params = ParamsAdamW {
+        lr: 0.01,
+        weight_decay: 0.,
+        ..Default::default()
+    };
+    let mut opt = FusedAdamW::new(varmap.all_vars(), params)?.with_paged(true)?;
+    let loss = |layer: &QLoraLinear| -> Result<Tensor> {
+        Ok((layer.forward(&xs)? - &target)?.sqr()?.mean_all()?)
+    };
+    let initial = loss(&layer)?.to_scalar::<f32>()?;
+    for _ in 0..20 {
+        opt.backward_step(&loss(&layer)?)?;
+    }
+    assert!(loss(&layer)?.to_scalar::<f32>()? < initial);
+
+    // Merging requantizes the weight.
+    let merged = layer.clone().into_merged()?;
+    let merged_w = (dequantized.weight() + adapter.delta()?)?;
+    let expected = QMatMul::from_qtensor(QTensor::quantize(&merged_w, GgmlDType::Nf4)?)?;
+    assert_eq!(
+        max_diff(&merged.forward(&xs)?, &expected.forward(&xs)?)?,
+        0.
+    );
+    assert!(matches!(merged, QMatMul::QTensor(_)));
+    Ok(())
+}
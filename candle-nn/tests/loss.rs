@@ -4,7 +4,7 @@ extern crate intel_mkl_src;
 #[cfg(feature = "accelerate")]
 extern crate accelerate_src;
 
-use candle::test_utils::to_vec0_round;
+use candle::test_utils::{to_vec0_round, to_vec1_round};
 use candle::{Device, Result, Tensor};
 
 /* Equivalent python code:
@@ -40,6 +40,62 @@ fn nll_and_cross_entropy() -> Result<()> {
     Ok(())
 }
 
+/* Equivalent python code, with the inputs of the previous test:
+weights = torch.tensor([2., 1., 0.5])
+print(F.cross_entropy(input, target, ignore_index=0))
+loss = F.cross_entropy(input, target, reduction="none")
+print((loss * weights).sum() / weights.sum())
+print((loss * weights)[[0, 2]].sum() / weights[[0, 2]].sum())
+print((loss + 1e-2 * torch.logsumexp(input, dim=1) ** 2).mean())
+*/
+#[test]
+fn cross_entropy_options() -> Result<()> {
+    use candle_nn::loss::CrossEntropy;
+    let cpu = Device::Cpu;
+    let input = Tensor::new(
+        &[
+            [1.1050f32, 0.3013, -1.5394, -2.1528, -0.8634],
+            [1.0730, -0.9419, -0.1670, -0.6582, 0.5061],
+            [0.8318, 1.1154, -0.3610, 0.5351, 1.0830],
+        ],
+        &cpu,
+    )?;
+    let target = Tensor::new(&[1u32, 0, 4], &cpu)?;
+    let weights = Tensor::new(&[2f32, 1., 0.5], &cpu)?;
+
+    let ce = CrossEntropy::new();
+    assert_eq!(to_vec0_round(&ce.loss(&input, &target, None)?, 4)?, 1.1312);
+    assert_eq!(
+        to_vec1_round(&ce.per_token(&input, &target)?, 4)?,
+        [1.3325, 0.7734, 1.2878]
+    );
+    let loss = ce.loss(&input, &target, Some(&weights))?;
+    assert_eq!(to_vec0_round(&loss, 4)?, 1.1664);
+
+    let masked = CrossEntropy::new().with_ignore_index(0);
+    assert_eq!(
+        to_vec0_round(&masked.loss(&input, &target, None)?, 4)?,
+        1.3102
+    );
+    let loss = masked.loss(&input, &target, Some(&weights))?;
+    assert_eq!(to_vec0_round(&loss, 4)?, 1.3236);
+    // The logits of the ignored targets get no gradient.
+    let var = candle::Var::from_tensor(&input)?;
+    let grads = masked.loss(var.as_tensor(), &target, None)?.backward()?;
+    let grad = grads.get(&var).expect("no grad for the input");
+    assert_eq!(grad.get(1)?.abs()?.sum_all()?.to_scalar::<f32>()?, 0.);
+    // All the targets can be ignored.
+    let ignored = Tensor::zeros(3, candle::DType::U32, &cpu)?;
+    assert_eq!(to_vec0_round(&masked.loss(&input, &ignored, None)?, 4)?, 0.);
+
+    // The input can have a batch and a time dimension.
+    let z_loss = CrossEntropy::new().with_z_loss(1e-2);
+    let loss = z_loss.loss(&input.unsqueeze(0)?, &target.unsqueeze(0)?, None)?;
+    assert_eq!(to_vec0_round(&loss, 4)?, 1.1702);
+    assert!(ce.loss(&input, &target.unsqueeze(0)?, None).is_err());
+    Ok(())
+}
+
 /* Equivalent python code:
 import torch
 import torch.nn.functional as F
@@ -86,3 +142,47 @@ fn binary_cross_entropy_with_logit() -> Result<()> {
     assert_eq!(to_vec0_round(&loss, 4)?, 0.8224);
     Ok(())
 }
+
+#[test]
+fn causal_lm_loss() -> Result<()> {
+    use candle_nn::loss::CrossEntropy;
+    let cpu = Device::Cpu;
+    let logits = Tensor::arange(0f32, 60., &cpu)?.reshape((2, 5, 6))?.sin()?;
+    // The last token of the second sequence is padding.
+    let input_ids = Tensor::new(&[[1u32, 4, 0, 2, 5], [3, 3, 1, 5, 0]], &cpu)?;
+    let ce = CrossEntropy::new().with_ignore_index(0);
+
+    let shifted_logits = logits.narrow(1, 0, 4)?.contiguous()?;
+    let shifted_ids = input_ids.narrow(1, 1, 4)?.contiguous()?;
+    let expected = ce.loss(&shifted_logits, &shifted_ids, None)?;
+    for chunk_size in [None, Some(1), Some(3), Some(10)] {
+        let loss = ce.causal_lm_loss(&logits, &input_ids, chunk_size)?;
+        assert_eq!(to_vec0_round(&loss, 4)?, to_vec0_round(&expected, 4)?);
+    }
+
+    // The head is applied chunk by chunk, with the same loss and gradients.
+    let hidden =
+        candle::Var::from_tensor(&Tensor::arange(0f32, 30., &cpu)?.reshape((2, 5, 3))?.cos()?)?;
+    let head = candle_nn::Linear::new(
+        Tensor::arange(0f32, 18., &cpu)?.reshape((6, 3))?.cos()?,
+        None,
+    );
+    let full = ce.causal_lm_loss(&hidden.apply(&head)?, &input_ids, None)?;
+    let chunked = ce.causal_lm_loss_with_head(&hidden, &head, &input_ids, Some(2))?;
+    assert_eq!(to_vec0_round(&chunked, 4)?, to_vec0_round(&full, 4)?);
+    let grad_full = full
+        .backward()?
+        .remove(&hidden)
+        .expect("no grad for hidden");
+    let grad_chunked = chunked
+        .backward()?
+        .remove(&hidden)
+        .expect("no grad for hidden");
+    let diff = (grad_full - grad_chunked)?.abs()?.max_all()?;
+    assert!(diff.to_scalar::<f32>()? < 1e-6);
+
+    assert!(ce
+        .causal_lm_loss(&logits, &input_ids.narrow(1, 0, 4)?, None)
+        .is_err());
+    Ok(())
+}
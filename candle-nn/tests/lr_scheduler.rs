@@ -0,0 +1,82 @@
+#[cfg(feature = "mkl")]
+extern crate intel_mkl_src;
+
+#[cfg(feature = "accelerate")]
+extern crate accelerate_src;
+
+use anyhow::Result;
+use candle::{Device, Var};
+use candle_nn::lr_scheduler::{
+    CosineWithWarmup, LinearDecay, LrSchedule, LrScheduler, OneCycle, Polynomial, StepDecay, SwaLr,
+};
+use candle_nn::{Optimizer, SGD};
+
+fn lrs<S: LrSchedule>(schedule: &S, n: usize) -> Vec<f64> {
+    (0..n)
+        .map(|p| (schedule.lr(p) * 1e6).round() / 1e6)
+        .collect()
+}
+
+#[test]
+fn schedules() -> Result<()> {
+    let cosine = CosineWithWarmup::new(1., 2, 10).with_min_lr(0.1);
+    assert_eq!(
+        lrs(&cosine, 12),
+        [
+            0.0, 0.5, 1.0, 0.965746, 0.868198, 0.722208, 0.55, 0.377792, 0.231802, 0.134254, 0.1,
+            0.1
+        ]
+    );
+    let linear = LinearDecay::new(1., 2, 6);
+    assert_eq!(lrs(&linear, 8), [0.0, 0.5, 1.0, 0.75, 0.5, 0.25, 0.0, 0.0]);
+    let polynomial = Polynomial::new(1., 2., 4).with_end_lr(0.2);
+    assert_eq!(lrs(&polynomial, 6), [1.0, 0.65, 0.4, 0.25, 0.2, 0.2]);
+    // Same values as PyTorch OneCycleLR(max_lr=1, total_steps=10).
+    let one_cycle = OneCycle::new(1., 10);
+    assert_eq!(
+        lrs(&one_cycle, 10),
+        [0.04, 0.52, 1.0, 0.950485, 0.811746, 0.611262, 0.388742, 0.188258, 0.049519, 0.000004]
+    );
+    // Too few steps for the phases to span a full position.
+    let one_cycle = OneCycle::new(1., 1);
+    assert_eq!(lrs(&one_cycle, 2), [0.206111, 0.000004]);
+    let one_cycle = OneCycle::new(1., 2).with_pct_start(0.5);
+    assert_eq!(lrs(&one_cycle, 3), [1.0, 0.000004, 0.000004]);
+    let step = StepDecay::new(1., 3, 0.5);
+    assert_eq!(lrs(&step, 7), [1.0, 1.0, 1.0, 0.5, 0.5, 0.5, 0.25]);
+    let swa = SwaLr::new(LinearDecay::new(1., 0, 10), 4, 0.1).with_anneal_steps(2);
+    assert_eq!(lrs(&swa, 8), [1.0, 0.9, 0.8, 0.7, 0.6, 0.35, 0.1, 0.1]);
+    let swa = SwaLr::new(|_| 1., 2, 0.1);
+    assert_eq!(lrs(&swa, 4), [1.0, 1.0, 0.1, 0.1]);
+    Ok(())
+}
+
+#[test]
+fn scheduler() -> Result<()> {
+    let var = Var::new(&[0f32], &Device::Cpu)?;
+    let mut opt = SGD::new(vec![var], 1.)?;
+
+    let mut scheduler = LrScheduler::per_step(StepDecay::new(0.1, 2, 0.1));
+    scheduler.apply(&mut opt);
+    assert_eq!(opt.learning_rate(), 0.1);
+    scheduler.step(&mut opt);
+    scheduler.epoch(&mut opt);
+    assert_eq!(opt.learning_rate(), 0.1);
+    scheduler.step(&mut opt);
+    assert_eq!(scheduler.position(), 2);
+    assert!((opt.learning_rate() - 0.01).abs() < 1e-12);
+
+    // Per epoch, the optimizer steps do not move the schedule.
+    let mut scheduler = LrScheduler::per_epoch(|epoch: usize| 1. / (1 + epoch) as f64);
+    scheduler.apply(&mut opt);
+    for _ in 0..5 {
+        scheduler.step(&mut opt);
+    }
+    assert_eq!(opt.learning_rate(), 1.);
+    assert_eq!(scheduler.epoch(&mut opt), 0.5);
+    assert_eq!(opt.learning_rate(), 0.5);
+    scheduler.set_position(3);
+    scheduler.apply(&mut opt);
+    assert_eq!(opt.learning_rate(), 0.25);
+    Ok(())
+}
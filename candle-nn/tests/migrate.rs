@@ -0,0 +1,105 @@
+#[cfg(feature = "mkl")]
+extern crate intel_mkl_src;
+
+#[cfg(feature = "accelerate")]
+extern crate accelerate_src;
+
+use anyhow::Result;
+use candle::{DType, Device, Module, ModuleT, Tensor};
+use candle_nn::migrate::MigrationProgress;
+use candle_nn::{BatchNormConfig, Linear, Migrate, Migration, VarBuilder, VarMap};
+
+#[test]
+fn migrate_dtype() -> Result<()> {
+    let dev = &Device::Cpu;
+    let weight = Tensor::new(&[[1f32, 2.], [3., 4.], [5., 6.]], dev)?;
+    let bias = Tensor::new(&[0.5f32, -1., 2.], dev)?;
+    let mut linear = Linear::new(weight, Some(bias));
+    let xs = Tensor::new(&[[1f32, -1.]], dev)?;
+    let expected = linear.forward(&xs)?.to_vec2::<f32>()?;
+    linear.to_dtype(DType::F16)?;
+    assert_eq!(linear.weight().dtype(), DType::F16);
+    assert_eq!(linear.bias().map(|b| b.dtype()), Some(DType::F16));
+    let ys = linear.forward(&xs.to_dtype(DType::F16)?)?;
+    assert_eq!(ys.to_dtype(DType::F32)?.to_vec2::<f32>()?, expected);
+
+    // Integer tensors are kept as is.
+    let mut tensors = vec![Tensor::new(&[1u32, 2], dev)?, Tensor::new(&[1f64], dev)?];
+    tensors.to_dtype(DType::BF16)?;
+    assert_eq!(tensors[0].dtype(), DType::U32);
+    assert_eq!(tensors[1].dtype(), DType::BF16);
+
+    // The sub-layers of layers are migrated too.
+    let mut layers = vec![linear.clone(), linear];
+    layers.to_dtype(DType::F32)?;
+    assert!(layers.iter().all(|l| l.weight().dtype() == DType::F32));
+    Ok(())
+}
+
+#[test]
+fn migrate_varmap_and_model() -> Result<()> {
+    let dev = &Device::Cpu;
+    let mut varmap = VarMap::new();
+    let vb = VarBuilder::from_varmap(&varmap, DType::F32, dev);
+    let mut linear = candle_nn::linear(2, 3, vb.pp("linear"))?;
+    let mut bn = candle_nn::batch_norm(3, BatchNormConfig::default(), vb.pp("bn"))?;
+    // The weight is shared by both modules, e.g. tied embeddings.
+    let mut tied = vec![linear.weight().clone()];
+
+    let mut reports = vec![];
+    let mut migration = Migration::new()
+        .dtype(DType::F64)
+        .on_progress(|p: &MigrationProgress| reports.push(*p));
+    migration.run(&mut varmap)?;
+    migration.run(&mut linear)?;
+    migration.run(&mut bn)?;
+    migration.run(&mut tied)?;
+    drop(migration);
+    // The varmap holds the weight and bias of each module and the running statistics, the
+    // following runs only reuse the migrated tensors.
+    assert_eq!(reports.len(), 6);
+    let last = reports.last().unwrap();
+    assert_eq!((last.tensors, last.total_tensors), (6, 6));
+    assert_eq!((last.bytes, last.total_bytes), (21 * 4, 21 * 4));
+
+    assert_eq!(varmap.all_vars().len(), 4);
+    assert_eq!(varmap.all_buffers().len(), 2);
+    let data = varmap.data().lock().unwrap();
+    assert!(data.values().all(|v| v.dtype() == DType::F64));
+    // The modules keep using the variables of the map so they can still be trained.
+    let weight = &data["linear.weight"];
+    assert_eq!(linear.weight().id(), weight.id());
+    assert_eq!(tied[0].id(), weight.id());
+    assert_eq!(bn.running_mean().id(), data["bn.running_mean"].id());
+    weight.set(&Tensor::ones((3, 2), DType::F64, dev)?)?;
+    data["linear.bias"].set(&Tensor::zeros(3, DType::F64, dev)?)?;
+    let ys = linear.forward(&Tensor::new(&[[1f64, 2.]], dev)?)?;
+    assert_eq!(ys.to_vec2::<f64>()?, [[3., 3., 3.]]);
+    drop(data);
+
+    // Training steps update the migrated running statistics in place.
+    let xs = Tensor::new(&[[1f64, 2., 3.], [3., 4., 5.]], dev)?;
+    bn.forward_t(&xs, true)?;
+    let running_mean = varmap.data().lock().unwrap()["bn.running_mean"].to_vec1::<f64>()?;
+    assert!(running_mean.iter().all(|&v| v > 0.));
+
+    // Migrating to the same device and dtype does not copy the tensors.
+    let before = linear.weight().id();
+    linear.to_device(dev)?;
+    assert_eq!(linear.weight().id(), before);
+    Ok(())
+}
+
+#[test]
+fn migrate_tensor() -> Result<()> {
+    let dev = &Device::Cpu;
+    let t = Tensor::new(&[1f32, 2.], dev)?;
+    let mut migration = Migration::new().dtype(DType::F16).nonblocking(true);
+    let t1 = migration.tensor(&t)?;
+    let mut a = t.clone();
+    migration.run(&mut a)?;
+    assert_eq!(t1.id(), a.id());
+    assert_eq!(t1.dtype(), DType::F16);
+    migration.synchronize()?;
+    Ok(())
+}
@@ -0,0 +1,89 @@
+#[cfg(feature = "mkl")]
+extern crate intel_mkl_src;
+
+#[cfg(feature = "accelerate")]
+extern crate accelerate_src;
+
+use candle::{Device, Module, Result, Tensor};
+use candle_nn::multi_lora::{AdapterSegment, AdapterSelection, LoraWeights, MultiLoraLinear};
+use candle_nn::Linear;
+
+#[test]
+fn multi_lora_segments() -> Result<()> {
+    let dev = &Device::Cpu;
+    let w = Tensor::randn(0f32, 1., (6, 4), dev)?;
+    let bias = Tensor::randn(0f32, 1., 6, dev)?;
+    let adapters = [
+        (
+            Tensor::randn(0f32, 1., (2, 4), dev)?,
+            Tensor::randn(0f32, 1., (6, 2), dev)?,
+        ),
+        (
+            Tensor::randn(0f32, 1., (3, 4), dev)?,
+            Tensor::randn(0f32, 1., (6, 3), dev)?,
+        ),
+    ];
+    let selection = AdapterSelection::new();
+    let mut layer = MultiLoraLinear::new(Linear::new(w.clone(), Some(bias.clone())), &selection);
+    for (id, (a, b)) in adapters.iter().enumerate() {
+        layer.add_adapter(id, LoraWeights::new(a.clone(), b.clone(), 4.)?)?;
+    }
+    assert_eq!(layer.adapter_ids(), [0, 1]);
+    assert!(layer
+        .add_adapter(
+            2,
+            LoraWeights::new(adapters[0].1.t()?, adapters[0].0.t()?, 1.)?
+        )
+        .is_err());
+
+    // The adapter of each row is the same as a linear layer with the merged weights.
+    let merged = |id: Option<usize>| -> Result<Linear> {
+        let w = match id {
+            None => w.clone(),
+            Some(id) => {
+                let (a, b) = &adapters[id];
+                let scale = 4. / a.dim(0)? as f64;
+                (&w + (b.matmul(a)? * scale)?)?
+            }
+        };
+        Ok(Linear::new(w, Some(bias.clone())))
+    };
+    let xs = Tensor::randn(0f32, 1., (1, 7, 4), dev)?;
+    assert_eq!(
+        layer.forward(&xs)?.to_vec3::<f32>()?,
+        merged(None)?.forward(&xs)?.to_vec3::<f32>()?
+    );
+    let rows = [Some(1), Some(1), None, Some(0), Some(1), Some(5), Some(0)];
+    selection.set(vec![
+        AdapterSegment::new(Some(1), 2),
+        AdapterSegment::new(None, 1),
+        AdapterSegment::new(Some(0), 1),
+        AdapterSegment::new(Some(1), 1),
+        // The layer does not have this adapter and uses the base weights.
+        AdapterSegment::new(Some(5), 1),
+        AdapterSegment::new(Some(0), 1),
+    ])?;
+    let ys = layer.forward(&xs)?;
+    assert_eq!(ys.dims(), &[1, 7, 6]);
+    for (i, adapter) in rows.iter().enumerate() {
+        let adapter = adapter.filter(|&a| a < 2);
+        let x = xs.narrow(1, i, 1)?;
+        let expected = merged(adapter)?.forward(&x)?;
+        let diff = (ys.narrow(1, i, 1)? - expected)?.abs()?.max_all()?;
+        assert!(diff.to_scalar::<f32>()? < 1e-5);
+    }
+
+    // All the rows using the same adapter.
+    selection.set(vec![AdapterSegment::new(Some(1), 7)])?;
+    let diff = (layer.forward(&xs)? - merged(Some(1))?.forward(&xs)?)?
+        .abs()?
+        .max_all()?;
+    assert!(diff.to_scalar::<f32>()? < 1e-5);
+
+    selection.set(vec![AdapterSegment::new(Some(0), 3)])?;
+    assert!(layer.forward(&xs).is_err());
+    selection.clear()?;
+    assert!(layer.remove_adapter(0).is_some());
+    assert!(!layer.has_adapter(0));
+    Ok(())
+}
@@ -0,0 +1,133 @@
+#[cfg(feature = "mkl")]
+extern crate intel_mkl_src;
+
+#[cfg(feature = "accelerate")]
+extern crate accelerate_src;
+
+use anyhow::Result;
+use candle::{DType, Device, Module, Tensor};
+use candle_nn::mup::{MupOptimizer, MupReadout, MupShapes};
+use candle_nn::optim::DEFAULT_GROUP;
+use candle_nn::{linear, AdamW, Init, Optimizer, ParamsAdamW, VarBuilder, VarMap, SGD};
+
+fn mlp(hidden: usize, varmap: &VarMap) -> Result<()> {
+    let vb = VarBuilder::from_varmap(varmap, DType::F32, &Device::Cpu);
+    linear(3, hidden, vb.pp("fc1"))?;
+    linear(hidden, 2 * hidden, vb.pp("fc2"))?;
+    linear(2 * hidden, 5, vb.pp("head"))?;
+    vb.get_with_hints(1, "scale", Init::Const(1.))?;
+    Ok(())
+}
+
+fn mup_model(width: usize) -> Result<(MupShapes, VarMap)> {
+    let (base, model) = (VarMap::new(), VarMap::new());
+    mlp(8, &base)?;
+    mlp(width, &model)?;
+    Ok((MupShapes::new(&base, &model)?, model))
+}
+
+#[test]
+fn mup_shapes() -> Result<()> {
+    let (shapes, _) = mup_model(32)?;
+    let dims = |name: &str| {
+        let s = shapes.get(name).unwrap();
+        (
+            s.num_width_dims(),
+            s.width_mult(),
+            s.fan_in_mult(),
+            s.fan_out_mult(),
+        )
+    };
+    assert_eq!(dims("fc1.weight"), (1, 4., 1., 4.));
+    assert_eq!(dims("fc1.bias"), (1, 4., 1., 4.));
+    assert_eq!(dims("fc2.weight"), (2, 4., 4., 4.));
+    assert_eq!(dims("head.weight"), (1, 4., 4., 1.));
+    assert_eq!(dims("head.bias"), (0, 1., 1., 1.));
+    assert_eq!(dims("scale"), (0, 1., 1., 1.));
+    let lr_mult = |name: &str, opt| shapes.get(name).unwrap().lr_mult(opt);
+    assert_eq!(lr_mult("fc2.weight", MupOptimizer::Adam), 0.25);
+    assert_eq!(lr_mult("fc1.weight", MupOptimizer::Adam), 1.);
+    assert_eq!(lr_mult("fc1.weight", MupOptimizer::Sgd), 4.);
+    assert_eq!(lr_mult("fc2.weight", MupOptimizer::Sgd), 1.);
+    assert_eq!(lr_mult("head.bias", MupOptimizer::Sgd), 1.);
+
+    // The base model uses the standard parametrization.
+    let (base, _) = mup_model(8)?;
+    for name in base.names() {
+        assert_eq!(base.get(name).unwrap().lr_mult(MupOptimizer::Adam), 1.);
+    }
+
+    let (small, other) = (VarMap::new(), VarMap::new());
+    mlp(8, &small)?;
+    let vb = VarBuilder::from_varmap(&other, DType::F32, &Device::Cpu);
+    linear(3, 8, vb.pp("fc1"))?;
+    assert!(MupShapes::new(&small, &other).is_err());
+    Ok(())
+}
+
+#[test]
+fn mup_param_groups() -> Result<()> {
+    let (shapes, varmap) = mup_model(32)?;
+    let params = ParamsAdamW {
+        lr: 0.1,
+        ..Default::default()
+    };
+    let mut opt = shapes.param_groups::<AdamW>(&varmap, params, MupOptimizer::Adam)?;
+    assert_eq!(opt.group_names(), [DEFAULT_GROUP, "mup0"]);
+    assert_eq!(opt.group_vars("mup0")?, ["fc2.weight"]);
+    assert_eq!(opt.group_of("fc1.weight"), Some(DEFAULT_GROUP));
+    assert_eq!(opt.group_learning_rate("mup0")?, 0.025);
+    // The ratio between the learning rates is kept by the schedules.
+    opt.set_learning_rate(0.01);
+    assert!((opt.group_learning_rate("mup0")? - 0.0025).abs() < 1e-12);
+
+    let opt = shapes.param_groups::<SGD>(&varmap, 0.1, MupOptimizer::Sgd)?;
+    assert_eq!(opt.group_names(), [DEFAULT_GROUP, "mup0"]);
+    let mut sgd_vars = opt.group_vars("mup0")?.to_vec();
+    sgd_vars.sort();
+    assert_eq!(
+        sgd_vars,
+        ["fc1.bias", "fc1.weight", "fc2.bias", "head.weight"]
+    );
+    assert_eq!(opt.group_learning_rate("mup0")?, 0.4);
+    Ok(())
+}
+
+#[test]
+fn mup_init() -> Result<()> {
+    Device::Cpu.set_seed(42)?;
+    // Wide enough for the std of the samples to be within the bounds whatever the position in
+    // the random stream, as the other tests draw from it concurrently.
+    let (shapes, varmap) = mup_model(2048)?;
+    let init = Init::Randn {
+        mean: 0.,
+        stdev: 0.2,
+    };
+    shapes.reinit(&varmap, "*.weight", init)?;
+    shapes.reinit(&varmap, "head.weight", Init::Const(0.))?;
+    let std = |name: &str| -> Result<f32> {
+        let data = varmap.data().lock().unwrap();
+        let w = data.get(name).unwrap().as_tensor().flatten_all()?;
+        Ok(w.sqr()?.mean_all()?.sqrt()?.to_scalar::<f32>()?)
+    };
+    // The hidden weights have a fan-in multiplier of 256.
+    assert!((std("fc2.weight")? - 0.0125).abs() < 1e-3);
+    assert!((std("fc1.weight")? - 0.2).abs() < 1e-2);
+    assert_eq!(std("head.weight")?, 0.);
+    Ok(())
+}
+
+#[test]
+fn mup_readout() -> Result<()> {
+    let (shapes, varmap) = mup_model(32)?;
+    let vb = VarBuilder::from_varmap(&varmap, DType::F32, &Device::Cpu);
+    let head = linear(64, 5, vb.pp("head"))?;
+    let readout =
+        MupReadout::from_shapes(head.clone(), &shapes, "head.weight")?.with_output_mult(2.);
+    assert_eq!(readout.width_mult(), 4.);
+    let xs = Tensor::randn(0f32, 1., (3, 64), &Device::Cpu)?;
+    let expected = (head.forward(&xs)? * 0.5)?;
+    let diff = (readout.forward(&xs)? - expected)?.abs()?.max_all()?;
+    assert!(diff.to_scalar::<f32>()? < 1e-6);
+    Ok(())
+}
@@ -0,0 +1,103 @@
+#[cfg(feature = "mkl")]
+extern crate intel_mkl_src;
+
+#[cfg(feature = "accelerate")]
+extern crate accelerate_src;
+
+use anyhow::Result;
+use candle::{DType, Device, Module, Tensor};
+use candle_nn::offload::ActivationOffloader;
+use candle_nn::{Linear, VarBuilder, VarMap};
+
+fn blocks(varmap: &VarMap, dev: &Device) -> Result<Vec<Linear>> {
+    let vb = VarBuilder::from_varmap(varmap, DType::F32, dev);
+    let blocks = (0..3)
+        .map(|i| candle_nn::linear(8, 8, vb.pp(i)))
+        .collect::<candle::Result<Vec<_>>>()?;
+    Ok(blocks)
+}
+
+fn forward(blocks: &[Linear], xs: &Tensor, offloader: &ActivationOffloader) -> Result<Tensor> {
+    let mut xs = xs.clone();
+    for block in blocks.iter() {
+        xs = offloader.run(&xs, |xs| {
+            let ys = block.forward(xs)?.gelu()?;
+            xs + ys.tanh()?
+        })?;
+    }
+    Ok(xs.sqr()?.sum_all()?)
+}
+
+#[test]
+fn offload_gradients() -> Result<()> {
+    let dev = &Device::Cpu;
+    let varmap = VarMap::new();
+    let blocks = blocks(&varmap, dev)?;
+    let xs = Tensor::randn(0f32, 1., (4, 8), dev)?;
+
+    let disabled = ActivationOffloader::new().with_enabled(false);
+    let loss = forward(&blocks, &xs, &disabled)?;
+    let expected = loss.backward()?;
+    assert_eq!(disabled.stats().segments, 0);
+
+    let offloader = ActivationOffloader::new().with_min_bytes(0);
+    let loss2 = forward(&blocks, &xs, &offloader)?;
+    assert_eq!(loss.to_scalar::<f32>()?, loss2.to_scalar::<f32>()?);
+    let grads = loss2.backward()?;
+    for var in varmap.all_vars() {
+        let g1 = expected
+            .get(&var)
+            .unwrap()
+            .flatten_all()?
+            .to_vec1::<f32>()?;
+        let g2 = grads.get(&var).unwrap().flatten_all()?.to_vec1::<f32>()?;
+        assert_eq!(g1, g2)
+    }
+    let stats = offloader.stats();
+    assert_eq!(stats.segments, 3);
+    // The linear output, the gelu and the tanh of each block.
+    assert!(stats.saved_tensors >= 9, "{stats:?}");
+    // Nothing gets offloaded on the cpu.
+    assert_eq!(stats.offloaded_bytes, 0);
+    offloader.clear()?;
+    assert_eq!(offloader.pending_segments(), 0);
+
+    // The default threshold skips these small tensors.
+    let offloader = ActivationOffloader::new();
+    forward(&blocks, &xs, &offloader)?.backward()?;
+    assert_eq!(offloader.stats().saved_tensors, 0);
+    Ok(())
+}
+
+#[test]
+fn offload_reused_activations() -> Result<()> {
+    let dev = &Device::Cpu;
+    let varmap = VarMap::new();
+    let blocks = blocks(&varmap, dev)?;
+    let xs = Tensor::randn(0f32, 1., (4, 8), dev)?;
+    let offloader = ActivationOffloader::new().with_min_bytes(0);
+    let mut hidden = None;
+    let ys = offloader.run(&xs, |xs| {
+        let hs = blocks[0].forward(xs)?;
+        hidden = Some(hs.clone());
+        hs.relu()
+    })?;
+    // The second segment uses an activation of the first one.
+    let hidden = hidden.unwrap();
+    let zs = offloader.run(&ys, |ys| blocks[1].forward(&(ys + &hidden)?))?;
+    let grads = zs.sum_all()?.backward()?;
+    let plain = blocks[1]
+        .forward(&(blocks[0].forward(&xs)?.relu()? + blocks[0].forward(&xs)?)?)?
+        .sum_all()?
+        .backward()?;
+    for var in varmap.all_vars().iter() {
+        if let Some(g) = plain.get(var) {
+            let g1 = g.flatten_all()?.to_vec1::<f32>()?;
+            let g2 = grads.get(var).unwrap().flatten_all()?.to_vec1::<f32>()?;
+            for (a, b) in g1.iter().zip(g2.iter()) {
+                assert!((a - b).abs() < 1e-5, "{g1:?} {g2:?}")
+            }
+        }
+    }
+    Ok(())
+}
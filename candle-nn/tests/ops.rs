@@ -124,6 +124,54 @@ fn layer_norm(device: &Device) -> Result<()> {
     Ok(())
 }
 
+fn norm_backward(device: &Device) -> Result<()> {
+    use candle::{DType, Var};
+
+    let max_diff = |a: &Tensor, b: &Tensor| -> Result<f32> {
+        (a - b)?.abs()?.flatten_all()?.max(0)?.to_scalar::<f32>()
+    };
+    for dims in [vec![3, 5, 40], vec![2, 1100]] {
+        let hidden = dims[dims.len() - 1];
+        let x = Var::from_tensor(&(Tensor::randn(0f32, 1., dims.as_slice(), device)? + 0.5)?)?;
+        let alpha = Var::from_tensor(&Tensor::randn(1f32, 0.5, hidden, device)?)?;
+        let beta = Var::from_tensor(&Tensor::randn(0f32, 0.5, hidden, device)?)?;
+        let w = Tensor::randn(0f32, 1., dims.as_slice(), device)?;
+
+        let fused = candle_nn::ops::rms_norm(&x, &alpha, 1e-5)?;
+        let slow = candle_nn::ops::rms_norm_slow(&x, &alpha, 1e-5)?;
+        let grads = (fused * &w)?.sum_all()?.backward()?;
+        let slow_grads = (slow * &w)?.sum_all()?.backward()?;
+        for v in [x.as_tensor(), alpha.as_tensor()] {
+            let (g, expected) = (grads.get(v).unwrap(), slow_grads.get(v).unwrap());
+            assert!(max_diff(g, expected)? < 1e-3, "rms-norm {dims:?}");
+        }
+
+        let fused = candle_nn::ops::layer_norm(&x, &alpha, &beta, 1e-5)?;
+        let slow = candle_nn::ops::layer_norm_slow(&x, &alpha, &beta, 1e-5)?;
+        let grads = (fused * &w)?.sum_all()?.backward()?;
+        let slow_grads = (slow * &w)?.sum_all()?.backward()?;
+        for v in [x.as_tensor(), alpha.as_tensor(), beta.as_tensor()] {
+            let (g, expected) = (grads.get(v).unwrap(), slow_grads.get(v).unwrap());
+            assert!(max_diff(g, expected)? < 1e-3, "layer-norm {dims:?}");
+        }
+    }
+
+    // Half precision inputs accumulate in f32.
+    let x = Var::from_tensor(&Tensor::randn(0f32, 1., (4, 64), device)?.to_dtype(DType::BF16)?)?;
+    let alpha = Var::from_tensor(&Tensor::ones(64, DType::BF16, device)?)?;
+    let grads = candle_nn::ops::rms_norm(&x, &alpha, 1e-5)?
+        .sum_all()?
+        .backward()?;
+    let x32 = Var::from_tensor(&x.to_dtype(DType::F32)?)?;
+    let alpha32 = Var::from_tensor(&alpha.to_dtype(DType::F32)?)?;
+    let grads32 = candle_nn::ops::rms_norm_slow(&x32, &alpha32, 1e-5)?
+        .sum_all()?
+        .backward()?;
+    let dx = grads.get(&x).unwrap().to_dtype(DType::F32)?;
+    assert!(max_diff(&dx, grads32.get(&x32).unwrap())? < 5e-2);
+    Ok(())
+}
+
 fn layer_norml(device: &Device) -> Result<()> {
     use rand::{rngs::StdRng, Rng, SeedableRng};
 
@@ -155,6 +203,57 @@ fn softmax_numerical_stability() -> Result<()> {
     Ok(())
 }
 
+#[test]
+fn softmax_nan() -> Result<()> {
+    use candle::DType;
+
+    // A nan makes the whole row nan, for the full and half precision paths.
+    let xs = Tensor::new(&[[1f32, f32::NAN, 2.], [1., 2., 3.]], &Device::Cpu)?;
+    for dtype in [DType::F32, DType::F64, DType::F16, DType::BF16] {
+        let sm = candle_nn::ops::softmax_last_dim(&xs.to_dtype(dtype)?)?;
+        let sm = sm.to_dtype(DType::F32)?.to_vec2::<f32>()?;
+        assert!(sm[0].iter().all(|v| v.is_nan()), "{dtype:?} {sm:?}");
+        assert!(sm[1].iter().all(|v| v.is_finite()), "{dtype:?} {sm:?}");
+    }
+    Ok(())
+}
+
+fn half_precision_normalization(device: &Device) -> Result<()> {
+    use candle::DType;
+
+    // The sum of the exponentials overflows when accumulated in f16.
+    let xs = Tensor::zeros((2, 70000), DType::F16, device)?;
+    let sm = candle_nn::ops::softmax_last_dim(&xs)?;
+    let sum = sm.to_dtype(DType::F32)?.sum(1)?.to_vec1::<f32>()?;
+    assert!(sum.iter().all(|s| (s - 1.).abs() < 1e-2), "{sum:?}");
+
+    // Large logits, the max subtraction has to be done before any exponential.
+    let xs = Tensor::new(&[[60000f32, 59999., -60000.], [-1e4, 1e4, 0.]], device)?;
+    for dtype in [DType::F16, DType::BF16] {
+        let xs = xs.to_dtype(dtype)?;
+        let sm = candle_nn::ops::softmax_last_dim(&xs)?.to_dtype(DType::F32)?;
+        let expected = candle_nn::ops::softmax(&xs, 1)?.to_dtype(DType::F32)?;
+        let diff = (&sm - &expected)?.abs()?.max_all()?.to_scalar::<f32>()?;
+        assert!(diff < 1e-2, "{dtype:?} {diff}");
+        assert!(sm
+            .flatten_all()?
+            .to_vec1::<f32>()?
+            .iter()
+            .all(|v| v.is_finite()));
+    }
+
+    // The variance of inputs with a large mean.
+    let xs = Tensor::new(&[[10000f32, 10001., 10002., 10003.]], device)?;
+    let alpha = Tensor::ones(4, DType::F32, device)?;
+    let beta = Tensor::zeros(4, DType::F32, device)?;
+    let ln = candle_nn::ops::layer_norm(&xs, &alpha, &beta, 1e-5)?;
+    let expected = [-1.3416f32, -0.4472, 0.4472, 1.3416];
+    for (v, e) in ln.flatten_all()?.to_vec1::<f32>()?.iter().zip(expected) {
+        assert!((v - e).abs() < 1e-3, "{v} {e}")
+    }
+    Ok(())
+}
+
 fn ropei(device: &Device) -> Result<()> {
     use rand::{rngs::StdRng, Rng, SeedableRng};
 
@@ -239,6 +338,147 @@ fn rope_thd(device: &Device) -> Result<()> {
     Ok(())
 }
 
+fn rope_partial(device: &Device) -> Result<()> {
+    use candle_nn::rotary_emb::{
+        rope_i_partial, rope_i_partial_slow, rope_partial, rope_partial_slow,
+    };
+
+    let (b_size, num_head, seq_len, head_dim, rot_dim) = (2, 5, 10, 16, 6);
+    let src = Tensor::randn(0f32, 1., (b_size, num_head, seq_len, head_dim), device)?;
+    // The tables can have more positions than the sequence.
+    let cos = Tensor::randn(0f32, 1., (seq_len + 3, rot_dim / 2), device)?;
+    let sin = Tensor::randn(0f32, 1., (seq_len + 3, rot_dim / 2), device)?;
+    for interleaved in [false, true] {
+        let (rope1, rope2) = if interleaved {
+            (
+                rope_i_partial(&src, &cos, &sin)?,
+                rope_i_partial_slow(&src, &cos, &sin)?,
+            )
+        } else {
+            (
+                rope_partial(&src, &cos, &sin)?,
+                rope_partial_slow(&src, &cos, &sin)?,
+            )
+        };
+        let sum_diff = (&rope1 - rope2)?.abs()?.sum_all()?.to_vec0::<f32>()?;
+        assert!(sum_diff < 1e-4, "{interleaved} {sum_diff}");
+        let pass = rope1.narrow(3, rot_dim, head_dim - rot_dim)?;
+        let expected = src.narrow(3, rot_dim, head_dim - rot_dim)?;
+        assert_eq!(
+            pass.flatten_all()?.to_vec1::<f32>()?,
+            expected.flatten_all()?.to_vec1::<f32>()?
+        );
+    }
+    // With the full head dim this is the same as the usual rope.
+    let cos = cos
+        .narrow(0, 0, seq_len)?
+        .repeat((1, head_dim / rot_dim + 1))?
+        .narrow(1, 0, head_dim / 2)?
+        .contiguous()?;
+    let sin = sin
+        .narrow(0, 0, seq_len)?
+        .repeat((1, head_dim / rot_dim + 1))?
+        .narrow(1, 0, head_dim / 2)?
+        .contiguous()?;
+    let rope1 = rope_partial(&src, &cos, &sin)?;
+    let rope2 = candle_nn::rotary_emb::rope(&src, &cos, &sin)?;
+    let sum_diff = (rope1 - rope2)?.abs()?.sum_all()?.to_vec0::<f32>()?;
+    assert!(sum_diff < 1e-4);
+    assert!(rope_partial(&src, &cos.repeat((1, 2))?, &sin.repeat((1, 2))?).is_err());
+    Ok(())
+}
+
+#[test]
+fn rope_tables_dynamic_ntk() -> Result<()> {
+    use candle::{DType, IndexOp};
+    use candle_nn::rotary_emb::{rope_tables, DynamicNtkScaling};
+
+    let device = &Device::Cpu;
+    let ntk = DynamicNtkScaling {
+        factor: 2.,
+        max_position_embeddings: 8,
+    };
+    assert_eq!(ntk.base(10000., 16, 8), 10000.);
+    // Twice the context with a factor 2 gives a base multiplied by 3^(16/14).
+    let base = ntk.base(10000., 16, 16);
+    assert!((base - 10000. * 3f64.powf(16. / 14.)).abs() < 1e-6);
+
+    let (cos, sin) = rope_tables(16, 10000., 8, Some(ntk), DType::F32, device)?;
+    let (cos8, _) = rope_tables(16, 10000., 8, None, DType::F32, device)?;
+    assert_eq!(cos.dims(), &[8, 8]);
+    assert_eq!(cos.to_vec2::<f32>()?, cos8.to_vec2::<f32>()?);
+    assert_eq!(sin.i((.., 0))?.to_vec1::<f32>()?[1], 1f32.sin());
+    let (cos, _) = rope_tables(16, 10000., 16, Some(ntk), DType::F32, device)?;
+    let expected = (3. / base.powf(14. / 16.)).cos() as f32;
+    assert!((cos.i((3, 7))?.to_vec0::<f32>()? - expected).abs() < 1e-5);
+    assert!(rope_tables(7, 10000., 16, None, DType::F32, device).is_err());
+    Ok(())
+}
+
+#[test]
+fn rope_tables_scaled() -> Result<()> {
+    use candle::{DType, IndexOp};
+    use candle_nn::rotary_emb::{rope_tables, scaled_rope_tables, RopeScaling};
+
+    let device = &Device::Cpu;
+    let inv_freq: Vec<f64> = (0..8).map(|i| 1. / 10000f64.powf(i as f64 / 8.)).collect();
+    // With an original context of 64, the wavelengths above 64 are divided by the factor and
+    // the ones below 16 are kept.
+    let llama3 = RopeScaling::Llama3 {
+        factor: 8.,
+        low_freq_factor: 1.,
+        high_freq_factor: 4.,
+        original_max_position_embeddings: 64,
+    };
+    let scaled = llama3.inv_freq(10000., 16);
+    for (&freq, &scaled) in inv_freq.iter().zip(scaled.iter()) {
+        let wavelen = 2. * std::f64::consts::PI / freq;
+        let expected = if wavelen < 16. {
+            freq
+        } else if wavelen > 64. {
+            freq / 8.
+        } else {
+            let smooth = (64. / wavelen - 1.) / 3.;
+            (1. - smooth) * freq / 8. + smooth * freq
+        };
+        assert!(
+            (scaled as f64 - expected).abs() < 1e-6,
+            "{scaled} {expected}"
+        );
+    }
+
+    // The yarn ramp goes from the dimension 0, which makes more than beta_fast rotations and is
+    // kept, to the dimension 3, from which the dimensions make less than beta_slow rotations
+    // and are divided by the factor.
+    let yarn = RopeScaling::Yarn {
+        factor: 4.,
+        original_max_position_embeddings: 64,
+        beta_fast: 4.,
+        beta_slow: 1.,
+        attention_factor: 0.5,
+    };
+    let scaled = yarn.inv_freq(10000., 16);
+    assert!((scaled[0] as f64 - inv_freq[0]).abs() < 1e-6);
+    assert!((scaled[7] as f64 - inv_freq[7] / 4.).abs() < 1e-9);
+    let expected = inv_freq[2] / 4. * 2. / 3. + inv_freq[2] / 3.;
+    assert!((scaled[2] as f64 - expected).abs() < 1e-6);
+    assert!((scaled[4] as f64 - inv_freq[4] / 4.).abs() < 1e-9);
+
+    let (cos, sin) = scaled_rope_tables(16, 10000., 4, yarn, DType::F32, device)?;
+    assert_eq!(cos.dims(), &[4, 8]);
+    let expected = 0.5 * (3. * scaled[5]).sin();
+    assert!((sin.i((3, 5))?.to_vec0::<f32>()? - expected).abs() < 1e-6);
+    assert_eq!(cos.i((0, 3))?.to_vec0::<f32>()?, 0.5);
+    let (cos, _) = scaled_rope_tables(16, 10000., 4, llama3, DType::F32, device)?;
+    let (unscaled, _) = rope_tables(16, 10000., 4, None, DType::F32, device)?;
+    assert_eq!(
+        cos.i((.., 0))?.to_vec1::<f32>()?,
+        unscaled.i((.., 0))?.to_vec1::<f32>()?
+    );
+    assert!(scaled_rope_tables(7, 10000., 4, yarn, DType::F32, device).is_err());
+    Ok(())
+}
+
 fn sigmoid(device: &Device) -> Result<()> {
     let data = &[[[3f32, 1., 4.], [1., 5., 9.]], [[2., 1., 7.], [8., 2., 8.]]];
     let tensor = Tensor::new(data, device)?;
@@ -249,12 +489,47 @@ fn sigmoid(device: &Device) -> Result<()> {
     Ok(())
 }
 
+fn bias_activation_residual(device: &Device) -> Result<()> {
+    use candle_nn::Activation;
+
+    let xs = Tensor::randn(0f32, 1., (2, 3, 8), device)?;
+    let bias = Tensor::randn(0f32, 1., 8, device)?;
+    let residual = Tensor::randn(0f32, 1., (2, 3, 8), device)?;
+    for act in [Activation::Gelu, Activation::Relu2, Activation::Sigmoid] {
+        let ys = candle_nn::ops::bias_activation_residual(&xs, Some(&bias), act, Some(&residual))?;
+        let expected = (xs.broadcast_add(&bias)?.apply(&act)? + &residual)?;
+        assert_eq!(to_vec3_round(&ys, 4)?, to_vec3_round(&expected, 4)?);
+    }
+    let ys = candle_nn::ops::bias_activation_residual(&xs, None, Activation::Silu, None)?;
+    assert_eq!(to_vec3_round(&ys, 4)?, to_vec3_round(&xs.silu()?, 4)?);
+    Ok(())
+}
+
 test_device!(ropei, ropei_cpu, ropei_gpu, ropei_metal);
 test_device!(rope, rope_cpu, rope_gpu, rope_metal);
 test_device!(rope_thd, rope_thd_cpu, rope_thd_gpu, rope_thd_metal);
+test_device!(
+    rope_partial,
+    rope_partial_cpu,
+    rope_partial_gpu,
+    rope_partial_metal
+);
 test_device!(softmax, softmax_cpu, softmax_gpu, softmax_metal);
 test_device!(rms_norm, rms_norm_cpu, rms_norm_gpu, rms_norm_metal);
 test_device!(rms_norml, rms_norml_cpu, rms_norml_gpu, rms_norml_metal);
 test_device!(layer_norm, ln_cpu, ln_gpu, ln_metal);
 test_device!(layer_norml, lnl_cpu, lnl_gpu, lnl_metal);
+test_device!(norm_backward, nbwd_cpu, nbwd_gpu, nbwd_metal);
 test_device!(sigmoid, sigmoid_cpu, sigmoid_gpu, sigmoid_metal);
+test_device!(
+    half_precision_normalization,
+    half_precision_normalization_cpu,
+    half_precision_normalization_gpu,
+    half_precision_normalization_metal
+);
+test_device!(
+    bias_activation_residual,
+    bias_act_res_cpu,
+    bias_act_res_gpu,
+    bias_act_res_metal
+);
@@ -4,10 +4,14 @@ extern crate intel_mkl_src;
 #[cfg(feature = "accelerate")]
 extern crate accelerate_src;
 
-use candle::test_utils::{to_vec0_round, to_vec2_round};
+use candle::test_utils::{to_vec0_round, to_vec1_round, to_vec2_round};
 
 use anyhow::Result;
 use candle::{DType, Device, Tensor, Var};
+use candle_nn::optim::{
+    Adafactor, AdamW8bit, FusedAdamW, Lamb, Lion, ParamsAdafactor, ParamsLamb, ParamsLion,
+    ParamsRAdam, ParamsSGDMomentum, RAdam, SGDMomentum,
+};
 use candle_nn::{AdamW, Linear, Module, Optimizer, ParamsAdamW, SGD};
 
 #[test]
@@ -158,3 +162,415 @@ fn adamw_linear_regression_varmap() -> Result<()> {
     assert_eq!(to_vec0_round(lin.bias().unwrap(), 4)?, 1.);
     Ok(())
 }
+
+#[test]
+fn adamw_state() -> Result<()> {
+    // Same regression as above, switching to a new optimizer restored from the state of the
+    // previous one half way.
+    let w_gen = Tensor::new(&[[3f32, 1.]], &Device::Cpu)?;
+    let b_gen = Tensor::new(-2f32, &Device::Cpu)?;
+    let gen = Linear::new(w_gen, Some(b_gen));
+    let sample_xs = Tensor::new(&[[2f32, 1.], [7., 4.], [-4., 12.], [5., 8.]], &Device::Cpu)?;
+    let sample_ys = gen.forward(&sample_xs)?;
+
+    let w = Var::new(&[[0f32, 0.]], &Device::Cpu)?;
+    let b = Var::new(0f32, &Device::Cpu)?;
+    let params = ParamsAdamW {
+        lr: 0.1,
+        ..Default::default()
+    };
+    let vars = vec![w.clone(), b.clone()];
+    let mut opt = AdamW::new(vars.clone(), params.clone())?;
+    let lin = Linear::new(w.as_tensor().clone(), Some(b.as_tensor().clone()));
+    for step in 0..100 {
+        if step == 50 {
+            let state = opt.state()?;
+            assert_eq!(state.scalars["step"], 50.);
+            opt = AdamW::new(vars.clone(), params.clone())?;
+            opt.set_state(&state)?;
+        }
+        let ys = lin.forward(&sample_xs)?;
+        let loss = ys.sub(&sample_ys)?.sqr()?.sum_all()?;
+        opt.backward_step(&loss)?;
+    }
+    assert_eq!(to_vec2_round(w.as_tensor(), 4)?, &[[2.7257, 0.7097]]);
+    assert_eq!(to_vec0_round(b.as_tensor(), 4)?, 0.7873);
+
+    // SGD does not have any state.
+    let mut sgd = SGD::new(vars, 0.1)?;
+    assert!(sgd.state()?.vars.is_empty());
+    assert!(sgd.set_state(&opt.state()?).is_err());
+    Ok(())
+}
+
+fn adam_steps<O: Optimizer>(opt: &mut O, vars: &[Var], targets: &[Tensor], n: usize) -> Result<()> {
+    for _step in 0..n {
+        let losses = vars
+            .iter()
+            .zip(targets.iter())
+            .map(|(var, target)| var.as_tensor().sub(target)?.sqr()?.sum_all())
+            .collect::<candle::Result<Vec<_>>>()?;
+        let loss = Tensor::stack(&losses, 0)?.sum_all()?;
+        opt.backward_step(&loss)?;
+    }
+    Ok(())
+}
+
+#[test]
+fn fused_adamw() -> Result<()> {
+    let dev = &Device::Cpu;
+    let init = [
+        Tensor::randn(0f32, 1., (17, 33), dev)?,
+        Tensor::randn(0f32, 1., 5000, dev)?,
+    ];
+    let targets = [
+        Tensor::randn(0f32, 1., (17, 33), dev)?,
+        Tensor::randn(0f32, 1., 5000, dev)?,
+    ];
+    let params = ParamsAdamW {
+        lr: 0.05,
+        ..Default::default()
+    };
+    let vars = init
+        .iter()
+        .map(Var::from_tensor)
+        .collect::<candle::Result<Vec<_>>>()?;
+    let mut opt = AdamW::new(vars.clone(), params.clone())?;
+    adam_steps(&mut opt, &vars, &targets, 10)?;
+    let fused_vars = init
+        .iter()
+        .map(Var::from_tensor)
+        .collect::<candle::Result<Vec<_>>>()?;
+    let mut fused = FusedAdamW::new(fused_vars.clone(), params.clone())?;
+    adam_steps(&mut fused, &fused_vars, &targets, 10)?;
+    for (v, f) in vars.iter().zip(fused_vars.iter()) {
+        let diff = (v.as_tensor() - f.as_tensor())?
+            .abs()?
+            .flatten_all()?
+            .max(0)?;
+        assert!(diff.to_scalar::<f32>()? < 1e-5);
+    }
+    assert_eq!(fused.state_size(), 8 * (17 * 33 + 5000));
+
+    // The states are interchangeable.
+    let mut opt = AdamW::new(vars.clone(), params.clone())?;
+    opt.set_state(&fused.state()?)?;
+    fused.set_state(&opt.state()?)?;
+    adam_steps(&mut opt, &vars, &targets, 5)?;
+    adam_steps(&mut fused, &fused_vars, &targets, 5)?;
+    for (v, f) in vars.iter().zip(fused_vars.iter()) {
+        let diff = (v.as_tensor() - f.as_tensor())?
+            .abs()?
+            .flatten_all()?
+            .max(0)?;
+        assert!(diff.to_scalar::<f32>()? < 1e-5);
+    }
+
+    // Paging the states does not change the updates.
+    let paged_vars = init
+        .iter()
+        .map(Var::from_tensor)
+        .collect::<candle::Result<Vec<_>>>()?;
+    let mut paged = FusedAdamW::new(paged_vars.clone(), params.clone())?.with_paged(true)?;
+    assert!(paged.is_paged());
+    paged.set_state(&fused.state()?)?;
+    for (p, f) in paged_vars.iter().zip(fused_vars.iter()) {
+        p.set(f.as_tensor())?
+    }
+    adam_steps(&mut fused, &fused_vars, &targets, 3)?;
+    adam_steps(&mut paged, &paged_vars, &targets, 3)?;
+    for (p, f) in paged_vars.iter().zip(fused_vars.iter()) {
+        let diff = (p.as_tensor() - f.as_tensor())?.abs()?.max_all()?;
+        assert_eq!(diff.to_scalar::<f32>()?, 0.);
+    }
+
+    // Half precision variables keep f32 moments.
+    let var = Var::from_tensor(&init[1].to_dtype(DType::BF16)?)?;
+    let mut fused = FusedAdamW::new(vec![var.clone()], params)?;
+    let target = targets[1].to_dtype(DType::BF16)?;
+    adam_steps(
+        &mut fused,
+        std::slice::from_ref(&var),
+        std::slice::from_ref(&target),
+        1,
+    )?;
+    let state = fused.state()?;
+    assert_eq!(state.vars[0]["first_moment"].dtype(), DType::F32);
+    let before = (init[1].to_dtype(DType::BF16)? - &target)?
+        .abs()?
+        .sum_all()?;
+    let after = (var.as_tensor() - &target)?.abs()?.sum_all()?;
+    assert!(
+        after.to_dtype(DType::F32)?.to_scalar::<f32>()?
+            < before.to_dtype(DType::F32)?.to_scalar::<f32>()?
+    );
+    Ok(())
+}
+
+#[test]
+fn adamw_8bit() -> Result<()> {
+    let dev = &Device::Cpu;
+    let init = Tensor::randn(0f32, 1., (40, 30), dev)?;
+    let target = Tensor::randn(0f32, 1., (40, 30), dev)?;
+    let params = ParamsAdamW {
+        lr: 0.05,
+        ..Default::default()
+    };
+    let var = Var::from_tensor(&init)?;
+    let mut opt = AdamW::new(vec![var.clone()], params.clone())?;
+    adam_steps(
+        &mut opt,
+        std::slice::from_ref(&var),
+        std::slice::from_ref(&target),
+        30,
+    )?;
+    let var8 = Var::from_tensor(&init)?;
+    let mut opt8 = AdamW8bit::new(vec![var8.clone()], params.clone())?;
+    adam_steps(
+        &mut opt8,
+        std::slice::from_ref(&var8),
+        std::slice::from_ref(&target),
+        30,
+    )?;
+    // 2 bytes per value and two f32 scales per block of 256 values.
+    assert_eq!(opt8.state_size(), 2 * 1200 + 8 * 5);
+
+    // The quantized moments follow the f32 ones closely.
+    let diff = (var.as_tensor() - var8.as_tensor())?.abs()?.mean_all()?;
+    assert!(diff.to_scalar::<f32>()? < 5e-2);
+    let dist = |v: &Var| -> Result<f32> {
+        Ok((v.as_tensor() - &target)?
+            .abs()?
+            .mean_all()?
+            .to_scalar::<f32>()?)
+    };
+    assert!(dist(&var8)? < 0.5 * (&init - &target)?.abs()?.mean_all()?.to_scalar::<f32>()?);
+
+    // The state round trips through the f32 moments up to the quantization error.
+    let state = opt8.state()?;
+    assert_eq!(state.scalars["step"], 30.);
+    let m = state.vars[0]["first_moment"].clone();
+    opt8.set_state(&state)?;
+    let m2 = opt8.state()?.vars[0]["first_moment"].clone();
+    let diff = (&m - &m2)?
+        .abs()?
+        .flatten_all()?
+        .max(0)?
+        .to_scalar::<f32>()?;
+    let max = m.abs()?.flatten_all()?.max(0)?.to_scalar::<f32>()?;
+    assert!(diff <= 1e-2 * max);
+    let mut opt = AdamW::new(vec![var8.clone()], params)?;
+    opt.set_state(&state)?;
+    opt8.set_state(&opt.state()?)?;
+    Ok(())
+}
+
+type Weights = (Vec<Vec<f32>>, Vec<f32>);
+
+// Runs a linear regression with three outputs from zero weights, the expected values in the
+// tests below come from a float64 reference implementation of each optimizer.
+fn linear_regression<O: Optimizer>(config: O::Config, steps: usize) -> Result<(Weights, O)> {
+    let w_gen = Tensor::new(&[[3f32, 1.], [-1., 2.], [0.5, -2.]], &Device::Cpu)?;
+    let b_gen = Tensor::new(&[-2f32, 1., 0.5], &Device::Cpu)?;
+    let gen = Linear::new(w_gen, Some(b_gen));
+    let sample_xs = Tensor::new(&[[2f32, 1.], [7., 4.], [-4., 12.], [5., 8.]], &Device::Cpu)?;
+    let sample_ys = gen.forward(&sample_xs)?;
+
+    let w = Var::zeros((3, 2), DType::F32, &Device::Cpu)?;
+    let b = Var::zeros(3, DType::F32, &Device::Cpu)?;
+    let mut opt = O::new(vec![w.clone(), b.clone()], config)?;
+    let lin = Linear::new(w.as_tensor().clone(), Some(b.as_tensor().clone()));
+    for _step in 0..steps {
+        let ys = lin.forward(&sample_xs)?;
+        let loss = ys.sub(&sample_ys)?.sqr()?.sum_all()?;
+        opt.backward_step(&loss)?;
+    }
+    let weights = (to_vec2_round(&w, 4)?, to_vec1_round(&b, 4)?);
+    Ok((weights, opt))
+}
+
+#[test]
+fn sgd_momentum_optim() -> Result<()> {
+    let params = ParamsSGDMomentum {
+        lr: 0.001,
+        nesterov: true,
+        ..Default::default()
+    };
+    let ((w, b), _) = linear_regression::<SGDMomentum>(params, 50)?;
+    assert_eq!(w, [[2.8893, 0.8599], [-0.9589, 2.0522], [0.5321, -1.9605]]);
+    assert_eq!(b, [-0.6456, 0.4953, 0.1169]);
+    let params = ParamsSGDMomentum {
+        lr: 0.001,
+        dampening: 0.1,
+        weight_decay: 0.01,
+        ..Default::default()
+    };
+    let ((w, b), _) = linear_regression::<SGDMomentum>(params, 50)?;
+    assert_eq!(w, [[2.9742, 0.9158], [-0.9735, 2.1835], [0.5407, -2.0779]]);
+    assert_eq!(b, [-0.5423, 0.474, 0.0799]);
+    Ok(())
+}
+
+#[test]
+fn lion_optim() -> Result<()> {
+    let params = ParamsLion {
+        lr: 0.05,
+        weight_decay: 0.01,
+        ..Default::default()
+    };
+    let ((w, b), _) = linear_regression::<Lion>(params, 50)?;
+    assert_eq!(w, [[2.4696, 0.5809], [-1.2794, 2.4696], [0.8876, -2.4696]]);
+    assert_eq!(b, [2.4696, 2.4696, -2.4696]);
+    Ok(())
+}
+
+#[test]
+fn radam_optim() -> Result<()> {
+    let params = ParamsRAdam {
+        lr: 0.002,
+        ..Default::default()
+    };
+    let ((w, b), _) = linear_regression::<RAdam>(params, 50)?;
+    assert_eq!(w, [[3.381, 1.132], [-1.0511, 2.8326], [0.6086, -2.6314]]);
+    assert_eq!(b, [0.3626, 0.2093, -0.2071]);
+    let params = ParamsRAdam {
+        lr: 0.002,
+        weight_decay: 0.1,
+        decoupled_weight_decay: true,
+        ..Default::default()
+    };
+    let ((w, b), _) = linear_regression::<RAdam>(params, 50)?;
+    assert_eq!(w, [[3.3494, 1.1214], [-1.0413, 2.806], [0.6029, -2.6067]]);
+    assert_eq!(b, [0.3592, 0.2074, -0.2051]);
+    Ok(())
+}
+
+#[test]
+fn lamb_optim() -> Result<()> {
+    let params = ParamsLamb {
+        lr: 0.05,
+        ..Default::default()
+    };
+    let ((w, b), _) = linear_regression::<Lamb>(params, 50)?;
+    assert_eq!(w, [[0.6019, 0.556], [-0.5261, 0.5979], [0.2804, -0.5946]]);
+    assert_eq!(b, [0.5337, 0.5552, -0.5489]);
+    Ok(())
+}
+
+#[test]
+fn adafactor_optim() -> Result<()> {
+    let params = ParamsAdafactor {
+        lr: 0.5,
+        ..Default::default()
+    };
+    let ((w, b), opt) = linear_regression::<Adafactor>(params, 50)?;
+    assert_eq!(w, [[2.7509, 0.7049], [-0.9713, 1.9231], [0.6056, -1.7987]]);
+    assert_eq!(b, [0.8691, 1.3539, -1.1903]);
+    // The second moments of the weight are factored in a row and a column.
+    let state = opt.state()?;
+    assert_eq!(state.vars[0]["row_second_moment"].dims(), [3, 1]);
+    assert_eq!(state.vars[0]["col_second_moment"].dims(), [1, 2]);
+    assert_eq!(state.vars[1]["second_moment"].dims(), [3]);
+    assert_eq!(state.scalars["step"], 50.);
+    Ok(())
+}
+
+#[test]
+fn matches_pattern() {
+    use candle_nn::var_map::matches_pattern;
+    assert!(matches_pattern("*.bias", "layers.0.bias"));
+    assert!(!matches_pattern("*.bias", "layers.0.bias_scale"));
+    assert!(matches_pattern("*norm*", "layers.0.norm1.weight"));
+    assert!(matches_pattern(
+        "layers.*.attn.*",
+        "layers.12.attn.q.weight"
+    ));
+    assert!(!matches_pattern("layers.*.attn.*", "layers.12.mlp.weight"));
+    assert!(matches_pattern("head.weight", "head.weight"));
+    assert!(!matches_pattern("head", "head.weight"));
+    assert!(matches_pattern("*", "anything"));
+    assert!(!matches_pattern("*ab*b", "ab"));
+}
+
+#[test]
+fn param_groups() -> Result<()> {
+    use candle_nn::lr_scheduler::LrScheduler;
+    use candle_nn::optim::{ParamGroup, ParamGroups};
+    use candle_nn::{Init, VarMap};
+    let dev = &Device::Cpu;
+    let varmap = VarMap::new();
+    let init = Init::Const(1.);
+    let backbone = varmap.get(2, "backbone.weight", init, DType::F32, dev)?;
+    let bias = varmap.get(2, "backbone.bias", init, DType::F32, dev)?;
+    let head = varmap.get(2, "head.weight", init, DType::F32, dev)?;
+    let head_bias = varmap.get(2, "head.bias", init, DType::F32, dev)?;
+    varmap.get_buffer(2, "backbone.running_mean", init, DType::F32, dev)?;
+    let params = ParamsAdamW {
+        lr: 0.1,
+        weight_decay: 0.5,
+        ..Default::default()
+    };
+    let no_decay = ParamGroup::new(
+        "no_decay",
+        ParamsAdamW {
+            weight_decay: 0.,
+            ..params.clone()
+        },
+    )
+    .with_pattern("*.bias");
+    let head_group = ParamGroup::new(
+        "head",
+        ParamsAdamW {
+            lr: 0.2,
+            ..params.clone()
+        },
+    )
+    .with_pattern("head.*");
+    let mut opt =
+        ParamGroups::<AdamW>::from_varmap(&varmap, params.clone(), vec![no_decay, head_group])?;
+    assert_eq!(opt.group_names(), ["default", "no_decay", "head"]);
+    assert_eq!(opt.group_vars("default")?, ["backbone.weight"]);
+    // The first matching group wins.
+    assert_eq!(opt.group_vars("no_decay")?, ["backbone.bias", "head.bias"]);
+    assert_eq!(opt.group_vars("head")?, ["head.weight"]);
+    assert_eq!(opt.group_of("head.bias"), Some("no_decay"));
+    assert_eq!(opt.group_of("backbone.running_mean"), None);
+
+    // The first AdamW step moves each variable by its learning rate, after the weight decay.
+    let loss = ((&backbone + &bias)? + (&head + &head_bias)?)?.sum_all()?;
+    opt.backward_step(&loss)?;
+    assert_eq!(to_vec1_round(&backbone, 4)?, [0.85, 0.85]);
+    assert_eq!(to_vec1_round(&bias, 4)?, [0.9, 0.9]);
+    assert_eq!(to_vec1_round(&head_bias, 4)?, [0.9, 0.9]);
+    assert_eq!(to_vec1_round(&head, 4)?, [0.7, 0.7]);
+
+    // The schedules keep the ratios between the learning rates.
+    LrScheduler::per_step(|_| 0.01).apply(&mut opt);
+    assert_eq!(opt.learning_rate(), 0.01);
+    assert!((opt.group_learning_rate("head")? - 0.02).abs() < 1e-9);
+    opt.set_group_learning_rate("head", 0.05)?;
+    opt.set_learning_rate(0.02);
+    assert!((opt.group_learning_rate("no_decay")? - 0.02).abs() < 1e-9);
+    assert!((opt.group_learning_rate("head")? - 0.1).abs() < 1e-9);
+
+    // The states of all the groups are saved and restored.
+    let state = opt.state()?;
+    assert_eq!(state.vars.len(), 4);
+    assert_eq!(state.scalars.get("head.step"), Some(&1.));
+    let mut other = ParamGroups::<AdamW>::from_varmap(
+        &varmap,
+        params.clone(),
+        vec![
+            ParamGroup::new("no_decay", params.clone()).with_pattern("*.bias"),
+            ParamGroup::new("head", params.clone()).with_pattern("head.*"),
+        ],
+    )?;
+    other.set_state(&state)?;
+    assert_eq!(other.state()?.scalars, state.scalars);
+    let no_head = ParamGroups::<AdamW>::from_varmap(&varmap, params.clone(), vec![]);
+    assert!(no_head?.set_state(&state).is_err());
+
+    let duplicate = ParamGroup::new("default", params.clone()).with_pattern("*");
+    assert!(ParamGroups::<AdamW>::from_varmap(&varmap, params, vec![duplicate]).is_err());
+    Ok(())
+}
@@ -0,0 +1,153 @@
+#[cfg(feature = "mkl")]
+extern crate intel_mkl_src;
+
+#[cfg(feature = "accelerate")]
+extern crate accelerate_src;
+
+use candle::test_utils::max_diff;
+use candle::{DType, Device, IndexOp, Result, Tensor};
+use candle_nn::attention::{causal_mask, scaled_dot_product_attention, AttentionConfig};
+use candle_nn::paged_attention::{BlockAllocator, PagedKvCache};
+
+// The attention of the last `n` positions of a single sequence, q has shape (seq_len, heads,
+// head_dim) and k, v have shape (seq_len, kv_heads, head_dim).
+fn reference(q: &Tensor, k: &Tensor, v: &Tensor, n: usize) -> Result<Tensor> {
+    let seq_len = q.dim(0)?;
+    let to_bhsd = |t: &Tensor| t.transpose(0, 1)?.unsqueeze(0);
+    let mask = causal_mask(seq_len, q.device())?;
+    let attn = scaled_dot_product_attention(
+        &to_bhsd(q)?,
+        &to_bhsd(k)?,
+        &to_bhsd(v)?,
+        Some(&mask),
+        &AttentionConfig::default(),
+    )?;
+    attn.squeeze(0)?.transpose(0, 1)?.narrow(0, seq_len - n, n)
+}
+
+#[test]
+fn block_allocator() -> Result<()> {
+    let mut alloc = BlockAllocator::new(2);
+    let b0 = alloc.allocate()?;
+    let b1 = alloc.allocate()?;
+    assert_ne!(b0, b1);
+    assert!(alloc.allocate().is_err());
+    alloc.share(b0)?;
+    alloc.release(b0)?;
+    assert_eq!(alloc.num_free_blocks(), 0);
+    alloc.release(b0)?;
+    assert_eq!(alloc.num_free_blocks(), 1);
+    assert!(alloc.release(b0).is_err());
+    assert_eq!(alloc.allocate()?, b0);
+    Ok(())
+}
+
+#[test]
+fn paged_attention() -> Result<()> {
+    let dev = &Device::Cpu;
+    let (n_heads, n_kv_heads, head_dim, block_size) = (4, 2, 8, 4);
+    let mut cache = PagedKvCache::new(2, 16, block_size, n_kv_heads, head_dim, DType::F32, dev)?;
+    cache.add_sequence(7)?;
+    cache.add_sequence(3)?;
+    let rand = |n: usize, h: usize| Tensor::randn(0f32, 1., (n, h, head_dim), dev);
+    let (lens_a, lens_b) = (11, 6);
+    let (qa, ka, va) = (
+        rand(lens_a, n_heads)?,
+        rand(lens_a, n_kv_heads)?,
+        rand(lens_a, n_kv_heads)?,
+    );
+    let (qb, kb, vb) = (
+        rand(lens_b, n_heads)?,
+        rand(lens_b, n_kv_heads)?,
+        rand(lens_b, n_kv_heads)?,
+    );
+
+    // A prefill with prompts of different lengths followed by some steps with a single token
+    // for one of the sequences and two tokens for the other.
+    let steps = [((0, 5), (0, 3)), ((5, 1), (3, 2)), ((6, 5), (5, 1))];
+    for ((start_a, na), (start_b, nb)) in steps {
+        let batch = cache.prepare(&[(7, na), (3, nb)])?;
+        assert_eq!(batch.seq_lens(), [start_a + na, start_b + nb]);
+        let cat = |a: &Tensor, b: &Tensor| {
+            Tensor::cat(&[a.narrow(0, start_a, na)?, b.narrow(0, start_b, nb)?], 0)
+        };
+        for layer in 0..2 {
+            // Use different values per layer.
+            let scale = (layer + 1) as f64;
+            let (k, v) = (cat(&ka, &kb)?, (cat(&va, &vb)? * scale)?);
+            cache.write(layer, &batch, &k, &v)?;
+            let attn = cache.attention(layer, &batch, &cat(&qa, &qb)?, &Default::default())?;
+            assert_eq!(attn.dims(), [na + nb, n_heads, head_dim]);
+            let va = (&va * scale)?;
+            let vb = (&vb * scale)?;
+            let ea = reference(
+                &qa.narrow(0, 0, start_a + na)?,
+                &ka.narrow(0, 0, start_a + na)?,
+                &va.narrow(0, 0, start_a + na)?,
+                na,
+            )?;
+            let eb = reference(
+                &qb.narrow(0, 0, start_b + nb)?,
+                &kb.narrow(0, 0, start_b + nb)?,
+                &vb.narrow(0, 0, start_b + nb)?,
+                nb,
+            )?;
+            assert!(max_diff(&attn.narrow(0, 0, na)?, &ea)? < 1e-5);
+            assert!(max_diff(&attn.narrow(0, na, nb)?, &eb)? < 1e-5);
+        }
+    }
+    assert_eq!(cache.sequence(7).unwrap().len(), lens_a);
+    assert_eq!(cache.sequence(7).unwrap().blocks().len(), 3);
+    let (k, v) = cache.gather(1, 3)?;
+    assert_eq!(max_diff(&k, &kb)?, 0.);
+    assert_eq!(max_diff(&v, &(&vb * 2.)?)?, 0.);
+
+    assert_eq!(cache.num_free_blocks(), 16 - 5);
+    cache.remove_sequence(7)?;
+    assert_eq!(cache.num_free_blocks(), 16 - 2);
+    Ok(())
+}
+
+#[test]
+fn paged_attention_fork() -> Result<()> {
+    let dev = &Device::Cpu;
+    let mut cache = PagedKvCache::new(1, 4, 4, 1, 2, DType::F32, dev)?;
+    cache.add_sequence(0)?;
+    let kv = Tensor::arange(0f32, 12., dev)?.reshape((6, 1, 2))?;
+    let batch = cache.prepare(&[(0, 6)])?;
+    cache.write(0, &batch, &kv, &kv)?;
+
+    // The forked sequence shares both blocks.
+    cache.fork_sequence(0, 1)?;
+    assert_eq!(
+        cache.sequence(1).unwrap().blocks(),
+        cache.sequence(0).unwrap().blocks()
+    );
+    assert_eq!(cache.num_free_blocks(), 2);
+    assert_eq!(cache.blocks_needed(1, 1)?, 1);
+
+    // Writing to the shared partial block copies it.
+    let batch = cache.prepare(&[(1, 1)])?;
+    let new = Tensor::new(&[[[-1f32, -2.]]], dev)?;
+    cache.write(0, &batch, &new, &new)?;
+    let (b0, b1) = (cache.sequence(0).unwrap(), cache.sequence(1).unwrap());
+    assert_eq!(b0.blocks()[0], b1.blocks()[0]);
+    assert_ne!(b0.blocks()[1], b1.blocks()[1]);
+    assert_eq!(cache.num_free_blocks(), 1);
+    let (k0, _) = cache.gather(0, 0)?;
+    let (k1, _) = cache.gather(0, 1)?;
+    assert_eq!(max_diff(&k0, &kv)?, 0.);
+    assert_eq!(max_diff(&k1.narrow(0, 0, 6)?, &kv)?, 0.);
+    assert_eq!(k1.i(6)?.to_vec2::<f32>()?, [[-1., -2.]]);
+
+    // Not enough blocks, none of the sequences is extended.
+    assert!(!cache.can_append(0, 8));
+    assert!(cache.prepare(&[(0, 2), (1, 8)]).is_err());
+    assert_eq!(cache.sequence(0).unwrap().len(), 6);
+    assert_eq!(cache.num_free_blocks(), 1);
+
+    cache.remove_sequence(0)?;
+    cache.remove_sequence(1)?;
+    assert_eq!(cache.num_free_blocks(), 4);
+    Ok(())
+}
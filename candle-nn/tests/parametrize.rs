@@ -0,0 +1,177 @@
+#[cfg(feature = "mkl")]
+extern crate intel_mkl_src;
+
+#[cfg(feature = "accelerate")]
+extern crate accelerate_src;
+
+use anyhow::Result;
+use candle::test_utils::{to_vec1_round, to_vec2_round};
+use candle::{DType, Device, Module, ModuleT, Tensor};
+use candle_nn::parametrize::{
+    conv1d_weight_norm, conv2d_spectral_norm, conv_transpose1d_weight_norm, spectral_norm,
+    weight_norm, SpectralNorm, SpectralNormConfig,
+};
+use candle_nn::{Conv1dConfig, ConvTranspose1dConfig, Layer, Linear, VarBuilder, VarMap};
+use std::collections::HashMap;
+
+fn parameter_names<L: Layer>(layer: &mut L) -> Result<Vec<String>> {
+    let mut names = vec![];
+    layer.visit_named_parameters(&mut |name, _| {
+        names.push(name.to_string());
+        Ok(())
+    })?;
+    Ok(names)
+}
+
+#[test]
+fn weight_norm_linear() -> Result<()> {
+    let dev = &Device::Cpu;
+    let w = Tensor::new(&[[3f32, 4.], [0., -2.]], dev)?;
+    let b = Tensor::new(&[1f32, 0.], dev)?;
+    let linear = Linear::new(w, Some(b));
+    let xs = Tensor::new(&[[1f32, 2.], [-1., 0.5]], dev)?;
+    let expected = linear.forward(&xs)?;
+    let mut wn = weight_norm(linear)?;
+    assert_eq!(to_vec2_round(wn.weight_g(), 4)?, [[5.], [2.]]);
+    assert_eq!(
+        to_vec2_round(&wn.forward(&xs)?, 4)?,
+        to_vec2_round(&expected, 4)?
+    );
+    assert_eq!(parameter_names(&mut wn)?, ["weight_g", "weight_v", "bias"]);
+
+    // The magnitude and the direction are trained separately.
+    let grads = wn.forward(&xs)?.sum_all()?.backward()?;
+    let grad_g = grads.get(wn.weight_g()).expect("no grad for weight_g");
+    let grad_v = grads.get(wn.weight_v()).expect("no grad for weight_v");
+    // The gradient of the direction is orthogonal to it.
+    let dot = (grad_v * wn.weight_v())?.sum(1)?;
+    assert_eq!(to_vec1_round(&dot, 4)?, [0., 0.]);
+    // The directions of the rows are (0.6, 0.8) and (0, -1), the gradient of the weight rows is
+    // (0, 2.5).
+    assert_eq!(to_vec2_round(grad_g, 4)?, [[2.], [-2.5]]);
+
+    let removed = wn.remove()?;
+    assert_eq!(to_vec2_round(removed.weight(), 4)?, [[3., 4.], [0., -2.]]);
+    Ok(())
+}
+
+#[test]
+fn weight_norm_load() -> Result<()> {
+    let dev = &Device::Cpu;
+    // The layout of the weight normalized convolutions of HiFi-GAN checkpoints.
+    let weight_v = Tensor::new(&[[[1f32, 0., 0.]], [[0., 3., 4.]]], dev)?;
+    let weight_g = Tensor::new(&[[[2f32]], [[10.]]], dev)?;
+    let bias = Tensor::new(&[0f32, 1.], dev)?;
+    let ts = HashMap::from([
+        ("conv.weight_v".to_string(), weight_v),
+        ("conv.weight_g".to_string(), weight_g),
+        ("conv.bias".to_string(), bias),
+    ]);
+    let vb = VarBuilder::from_tensors(ts, DType::F32, dev);
+    let cfg = Conv1dConfig {
+        padding: 1,
+        ..Default::default()
+    };
+    let conv = conv1d_weight_norm(1, 2, 3, cfg, vb.pp("conv"))?;
+    assert_eq!(
+        to_vec2_round(&conv.weight()?.squeeze(1)?, 4)?,
+        [[2., 0., 0.], [0., 6., 8.]]
+    );
+    let xs = Tensor::new(&[[[1f32, 2., 3.]]], dev)?;
+    let ys = conv.forward(&xs)?;
+    assert_eq!(
+        to_vec2_round(&ys.squeeze(0)?, 4)?,
+        [[0., 2., 4.], [23., 37., 19.]]
+    );
+    let conv = conv.remove()?;
+    assert_eq!(
+        to_vec2_round(&conv.forward(&xs)?.squeeze(0)?, 4)?,
+        to_vec2_round(&ys.squeeze(0)?, 4)?
+    );
+
+    // The new parameters of a var map, the norm is over the input channels for the transposed
+    // convolutions.
+    let varmap = VarMap::new();
+    let vb = VarBuilder::from_varmap(&varmap, DType::F32, dev);
+    let cfg = ConvTranspose1dConfig::default();
+    let conv = conv_transpose1d_weight_norm(4, 2, 3, cfg, vb.pp("up"))?;
+    assert_eq!(conv.weight_g().dims(), [4, 1, 1]);
+    assert_eq!(conv.weight()?.dims(), [4, 2, 3]);
+    let names: Vec<_> = varmap.named_vars().into_iter().map(|(n, _)| n).collect();
+    assert_eq!(names, ["up.bias", "up.weight_g", "up.weight_v"]);
+    Ok(())
+}
+
+#[test]
+fn spectral_norm_linear() -> Result<()> {
+    let dev = &Device::Cpu;
+    let w = Tensor::new(&[[3f32, 0.], [0., 1.], [0., 0.]], dev)?;
+    let linear = Linear::new(w, None);
+    let mut sn = spectral_norm(linear)?;
+    assert_eq!(to_vec1_round(&sn.sigma()?.unsqueeze(0)?, 3)?, [3.]);
+    assert_eq!(
+        to_vec2_round(&sn.weight()?, 3)?,
+        [[1., 0.], [0., 0.333], [0., 0.]]
+    );
+    assert_eq!(parameter_names(&mut sn)?, ["weight_orig"]);
+    let xs = Tensor::new(&[[3f32, 3.]], dev)?;
+    assert_eq!(
+        to_vec2_round(&sn.forward_t(&xs, false)?, 3)?,
+        [[3., 1., 0.]]
+    );
+
+    // The singular vectors must match the weight.
+    let linear = Linear::new(Tensor::zeros((3, 2), DType::F32, dev)?, None);
+    let u = Tensor::zeros(2, DType::F32, dev)?;
+    let v = Tensor::zeros(2, DType::F32, dev)?;
+    let weight = linear.weight().clone();
+    let config = SpectralNormConfig::default();
+    assert!(SpectralNorm::from_parts(linear, weight, u, v, config).is_err());
+    Ok(())
+}
+
+#[test]
+fn spectral_norm_conv2d() -> Result<()> {
+    let dev = &Device::Cpu;
+    let varmap = VarMap::new();
+    let vb = VarBuilder::from_varmap(&varmap, DType::F32, dev);
+    let conv = conv2d_spectral_norm(2, 3, 3, Default::default(), vb.pp("conv"))?;
+    let names: Vec<_> = varmap.named_vars().into_iter().map(|(n, _)| n).collect();
+    assert_eq!(names, ["conv.bias", "conv.weight_orig"]);
+    assert!(varmap.is_buffer("conv.weight_u"));
+    assert!(varmap.is_buffer("conv.weight_v"));
+
+    // The largest singular value of the normalized weight is 1.
+    let weight = conv.weight()?.reshape((3, 18))?;
+    let gram = weight.matmul(&weight.t()?)?;
+    let mut x = Tensor::ones(3, DType::F32, dev)?;
+    for _ in 0..100 {
+        let y = gram.matmul(&x.unsqueeze(1)?)?.squeeze(1)?;
+        x = y.broadcast_div(&y.sqr()?.sum_all()?.sqrt()?)?;
+    }
+    let eig = (gram.matmul(&x.unsqueeze(1)?)?.squeeze(1)? * &x)?.sum_all()?;
+    assert!((eig.to_scalar::<f32>()? - 1.).abs() < 1e-3);
+
+    // Only the training passes update the buffers of the var map.
+    let u = || -> Result<Vec<f32>> {
+        let data = varmap.data().lock().unwrap();
+        Ok(data["conv.weight_u"].as_tensor().to_vec1::<f32>()?)
+    };
+    let before = u()?;
+    let xs = Tensor::ones((1, 2, 5, 5), DType::F32, dev)?;
+    randomize_weight(&varmap)?;
+    conv.forward_t(&xs, false)?;
+    assert_eq!(u()?, before);
+    let ys = conv.forward_t(&xs, true)?;
+    assert_eq!(ys.dims(), [1, 3, 3, 3]);
+    assert_ne!(u()?, before);
+    Ok(())
+}
+
+// Sets the weight to a new value so that the power iterations have to move the singular vectors.
+fn randomize_weight(varmap: &VarMap) -> Result<()> {
+    let data = varmap.data().lock().unwrap();
+    let w = &data["conv.weight_orig"];
+    w.set(&w.as_tensor().randn_like(0., 1.)?)?;
+    Ok(())
+}
@@ -0,0 +1,110 @@
+#[cfg(feature = "mkl")]
+extern crate intel_mkl_src;
+
+#[cfg(feature = "accelerate")]
+extern crate accelerate_src;
+
+use anyhow::Result;
+use candle::test_utils::max_diff;
+use candle::{Device, Tensor, Var, D};
+use candle_nn::distributed::{Communicator, ThreadGroup};
+use candle_nn::ring_attention::{ring_attention, shard_sequence, RingAttentionConfig};
+use std::sync::Arc;
+
+// The plain attention over the whole sequence.
+fn reference(q: &Tensor, k: &Tensor, v: &Tensor, cfg: RingAttentionConfig) -> Result<Tensor> {
+    let n_rep = q.dim(1)? / k.dim(1)?;
+    let repeat = |t: &Tensor| -> candle::Result<Tensor> {
+        Tensor::cat(&vec![t.unsqueeze(2)?; n_rep], 2)?.flatten(1, 2)
+    };
+    let (k, v) = (repeat(k)?, repeat(v)?);
+    let mut scores = (q.matmul(&k.t()?)? * cfg.scale)?;
+    if cfg.causal {
+        let mask = candle_nn::attention::causal_mask(q.dim(2)?, q.device())?;
+        scores = candle_nn::attention::apply_mask(&scores, &mask)?;
+    }
+    Ok(candle_nn::ops::softmax(&scores, D::Minus1)?.matmul(&v)?)
+}
+
+fn check(cfg: RingAttentionConfig, world_size: usize) -> Result<()> {
+    let dev = &Device::Cpu;
+    let (b, h, h_kv, s, d) = (2, 4, 2, 12, 8);
+    let q = Var::randn(0f32, 1., (b, h, s, d), dev)?;
+    let k = Var::randn(0f32, 1., (b, h_kv, s, d), dev)?;
+    let v = Var::randn(0f32, 1., (b, h_kv, s, d), dev)?;
+    let w = Tensor::randn(0f32, 1., (b, h, s, d), dev)?;
+    let out = reference(&q, &k, &v, cfg)?;
+    let grads = (&out * &w)?.sum_all()?.backward()?;
+    let expected = [
+        out,
+        grads.get(&q).unwrap().clone(),
+        grads.get(&k).unwrap().clone(),
+        grads.get(&v).unwrap().clone(),
+    ];
+
+    let handles: Vec<_> = ThreadGroup::new(world_size)
+        .into_iter()
+        .map(|comm| {
+            let (q, k, v, w) = (q.clone(), k.clone(), v.clone(), w.clone());
+            std::thread::spawn(move || -> Result<Vec<Tensor>> {
+                let comm: Arc<dyn Communicator> = Arc::new(comm);
+                let shard = |t: &Tensor| -> Result<Var> {
+                    Ok(Var::from_tensor(&shard_sequence(t, 2, comm.as_ref())?)?)
+                };
+                let (q, k, v) = (shard(&q)?, shard(&k)?, shard(&v)?);
+                let w = shard_sequence(&w, 2, comm.as_ref())?;
+                let out = ring_attention(&q, &k, &v, &comm, cfg)?;
+                let grads = (&out * &w)?.sum_all()?.backward()?;
+                Ok(vec![
+                    out,
+                    grads.get(&q).unwrap().clone(),
+                    grads.get(&k).unwrap().clone(),
+                    grads.get(&v).unwrap().clone(),
+                ])
+            })
+        })
+        .collect();
+    let results = handles
+        .into_iter()
+        .map(|h| h.join().unwrap())
+        .collect::<Result<Vec<_>>>()?;
+    for (i, expected) in expected.iter().enumerate() {
+        let chunks: Vec<_> = results.iter().map(|r| r[i].clone()).collect();
+        let got = Tensor::cat(&chunks, 2)?;
+        assert!(max_diff(&got, expected)? < 1e-4, "{i} {cfg:?} {world_size}");
+    }
+    Ok(())
+}
+
+#[test]
+fn ring_attention_matches_attention() -> Result<()> {
+    for world_size in [1, 2, 3] {
+        check(RingAttentionConfig::causal(8), world_size)?;
+        check(RingAttentionConfig::bidirectional(8), world_size)?;
+    }
+    Ok(())
+}
+
+#[test]
+fn ring_exchange() -> Result<()> {
+    let handles: Vec<_> = ThreadGroup::new(3)
+        .into_iter()
+        .map(|comm| {
+            std::thread::spawn(move || -> Result<Vec<f32>> {
+                let xs = Tensor::new(&[comm.rank() as f32], &Device::Cpu)?;
+                let ys = (&xs * 10.)?;
+                let pending = comm.ring_exchange(&[&xs, &ys])?;
+                // Other collectives can run while the exchange is pending.
+                let sum = comm.all_reduce(&xs)?;
+                let received = Tensor::cat(&pending.wait()?, 0)?;
+                Ok(Tensor::cat(&[received, sum], 0)?.to_vec1::<f32>()?)
+            })
+        })
+        .collect();
+    let results = handles
+        .into_iter()
+        .map(|h| h.join().unwrap())
+        .collect::<Result<Vec<_>>>()?;
+    assert_eq!(results, [[2., 20., 3.], [0., 0., 3.], [1., 10., 3.]]);
+    Ok(())
+}
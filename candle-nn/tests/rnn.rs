@@ -4,8 +4,10 @@ extern crate intel_mkl_src;
 #[cfg(feature = "accelerate")]
 extern crate accelerate_src;
 
-use candle::{test_utils::to_vec2_round, DType, Device, Result, Tensor};
-use candle_nn::RNN;
+use candle::test_utils::{to_vec2_round, to_vec3_round};
+use candle::{DType, Device, IndexOp, Result, Tensor};
+use candle_nn::rnn::Direction;
+use candle_nn::{GRUConfig, Layer, StackedConfig, VarBuilder, VarMap, GRU, RNN};
 
 /* The following test can be verified against PyTorch using the following snippet.
 import torch
@@ -97,5 +99,257 @@ fn gru() -> Result<()> {
     }
     let h = state.h();
     assert_eq!(to_vec2_round(h, 4)?, &[[0.0579, 0.8836, -0.9991]]);
+    let xs = Tensor::new(&[[[3f32, 1.5], [1., 0.5]]], cpu)?;
+    let states = gru.seq(&xs)?;
+    assert_eq!(gru.states_to_tensor(&states)?.dims(), [1, 2, 3]);
+    Ok(())
+}
+
+fn varmap_names(varmap: &VarMap) -> Vec<String> {
+    varmap.named_vars().into_iter().map(|(n, _)| n).collect()
+}
+
+#[test]
+fn stacked_gru_bidirectional() -> Result<()> {
+    let cpu = &Device::Cpu;
+    let varmap = VarMap::new();
+    let vb = VarBuilder::from_varmap(&varmap, DType::F32, cpu);
+    let stacked = StackedConfig::default()
+        .with_num_layers(2)
+        .with_bidirectional(true);
+    let gru = candle_nn::stacked_gru(2, 3, Default::default(), stacked, vb.clone())?;
+    let names = varmap_names(&varmap);
+    assert_eq!(names.len(), 16);
+    assert!(names.contains(&"weight_ih_l1_reverse".to_string()));
+    assert_eq!(gru.layers()[2].config().layer_idx, 1);
+    {
+        let data = varmap.data().lock().unwrap();
+        assert_eq!(data["weight_ih_l1"].dims(), [9, 6]);
+    }
+
+    // The same computation with single layers sharing the weights.
+    let layer = |layer_idx, direction, in_dim| {
+        let config = GRUConfig {
+            layer_idx,
+            direction,
+            ..Default::default()
+        };
+        candle_nn::gru(in_dim, 3, config, vb.clone())
+    };
+    let run = |gru: &GRU, xs: &Tensor, reverse: bool| -> Result<(Tensor, Tensor)> {
+        let steps: Vec<Tensor> = (0..xs.dim(1)?)
+            .map(|t| xs.i((.., t)))
+            .collect::<Result<_>>()?;
+        let mut state = gru.zero_state(xs.dim(0)?)?;
+        let mut hs = vec![];
+        let order: Vec<usize> = if reverse {
+            (0..steps.len()).rev().collect()
+        } else {
+            (0..steps.len()).collect()
+        };
+        for t in order {
+            state = gru.step(&steps[t], &state)?;
+            hs.push(state.h().clone());
+        }
+        if reverse {
+            hs.reverse()
+        }
+        Ok((Tensor::stack(&hs, 1)?, state.h().clone()))
+    };
+    let xs = Tensor::arange(0f32, 16., cpu)?.reshape((2, 4, 2))?.sin()?;
+    let (f0, _) = run(&layer(0, Direction::Forward, 2)?, &xs, false)?;
+    let (b0, _) = run(&layer(0, Direction::Backward, 2)?, &xs, true)?;
+    let xs1 = Tensor::cat(&[f0, b0], 2)?;
+    let (f1, h_f1) = run(&layer(1, Direction::Forward, 6)?, &xs1, false)?;
+    let (b1, h_b1) = run(&layer(1, Direction::Backward, 6)?, &xs1, true)?;
+    let expected = Tensor::cat(&[f1, b1], 2)?;
+
+    let states = gru.seq(&xs)?;
+    let ys = gru.states_to_tensor(&states)?;
+    assert_eq!(ys.dims(), [2, 4, 6]);
+    assert_eq!(to_vec3_round(&ys, 4)?, to_vec3_round(&expected, 4)?);
+    let last = gru.last_state(&states)?;
+    assert_eq!(last.len(), 4);
+    assert_eq!(to_vec2_round(last[2].h(), 4)?, to_vec2_round(&h_f1, 4)?);
+    assert_eq!(to_vec2_round(last[3].h(), 4)?, to_vec2_round(&h_b1, 4)?);
+    assert!(gru.step(&xs.i((.., 0))?, &gru.zero_state(2)?).is_err());
+    Ok(())
+}
+
+#[test]
+fn stacked_lstm() -> Result<()> {
+    let cpu = &Device::Cpu;
+    let varmap = VarMap::new();
+    let vb = VarBuilder::from_varmap(&varmap, DType::F32, cpu);
+    let stacked = StackedConfig::default()
+        .with_num_layers(3)
+        .with_dropout(0.5);
+    let mut lstm = candle_nn::stacked_lstm(2, 4, Default::default(), stacked, vb)?;
+    let mut names = vec![];
+    lstm.visit_named_parameters(&mut |name, _| {
+        names.push(name.to_string());
+        Ok(())
+    })?;
+    names.sort();
+    assert_eq!(names, varmap_names(&varmap));
+
+    // Without dropout outside of training, stepping gives the same states as a sequence.
+    let xs = Tensor::arange(0f32, 10., cpu)?.reshape((1, 5, 2))?.cos()?;
+    let states = lstm.seq(&xs)?;
+    let mut state = lstm.zero_state(1)?;
+    for t in 0..5 {
+        state = lstm.step(&xs.i((.., t))?, &state)?;
+    }
+    let last = lstm.last_state(&states)?;
+    for (s, l) in state.iter().zip(last.iter()) {
+        assert_eq!(to_vec2_round(s.c(), 4)?, to_vec2_round(l.c(), 4)?);
+    }
+    assert_eq!(lstm.states_to_tensor(&states)?.dims(), [1, 5, 4]);
+    lstm.set_training(true)?;
+    let ys = lstm.states_to_tensor(&lstm.seq(&xs)?)?;
+    assert_eq!(ys.dims(), [1, 5, 4]);
+    Ok(())
+}
+
+#[test]
+fn pack_padded_sequence() -> Result<()> {
+    use candle_nn::rnn::{pack_padded_sequence, pad_packed_sequence};
+    let cpu = &Device::Cpu;
+    let xs = Tensor::arange(1f32, 13., cpu)?.reshape((3, 4, 1))?;
+    let packed = pack_padded_sequence(&xs, &[2, 4, 1])?;
+    assert_eq!(packed.batch_sizes(), [3, 2, 1, 1]);
+    assert_eq!(packed.sorted_indices(), [1, 0, 2]);
+    assert_eq!(packed.lengths(), [2, 4, 1]);
+    assert_eq!(
+        packed.data().flatten_all()?.to_vec1::<f32>()?,
+        [5., 1., 9., 6., 2., 7., 8.]
+    );
+    let (padded, lengths) = pad_packed_sequence(&packed, -1., Some(5))?;
+    assert_eq!(lengths, [2, 4, 1]);
+    assert_eq!(
+        padded.squeeze(2)?.to_vec2::<f32>()?,
+        [
+            [1., 2., -1., -1., -1.],
+            [5., 6., 7., 8., -1.],
+            [9., -1., -1., -1., -1.]
+        ]
+    );
+    assert!(pack_padded_sequence(&xs, &[2, 5, 1]).is_err());
+    Ok(())
+}
+
+// Runs `rnn` on each sequence separately, returns the padded outputs and the final states.
+fn run_separately<R: RNN>(
+    rnn: &R,
+    xs: &Tensor,
+    lengths: &[usize],
+) -> Result<(Vec<Tensor>, Vec<R::State>)> {
+    let mut outputs = vec![];
+    let mut states = vec![];
+    for (b, &len) in lengths.iter().enumerate() {
+        let seq = xs.narrow(0, b, 1)?.narrow(1, 0, len)?.contiguous()?;
+        let seq_states = rnn.seq(&seq)?;
+        outputs.push(rnn.states_to_tensor(&seq_states)?.squeeze(0)?);
+        states.push(seq_states);
+    }
+    Ok((
+        outputs,
+        states
+            .into_iter()
+            .map(|s| s.last().unwrap().clone())
+            .collect(),
+    ))
+}
+
+#[test]
+fn gru_packed() -> Result<()> {
+    use candle_nn::rnn::{pack_padded_sequence, pad_packed_sequence};
+    let cpu = &Device::Cpu;
+    let varmap = VarMap::new();
+    let vb = VarBuilder::from_varmap(&varmap, DType::F32, cpu);
+    let gru = candle_nn::gru(2, 3, Default::default(), vb)?;
+    let xs = Tensor::arange(0f32, 24., cpu)?.reshape((3, 4, 2))?.sin()?;
+    let lengths = [3, 4, 1];
+    let packed = pack_padded_sequence(&xs, &lengths)?;
+    let (ys, last) = gru.seq_packed(&packed, &gru.zero_state(3)?)?;
+    let (padded, _) = pad_packed_sequence(&ys, 0., None)?;
+    let (outputs, states) = run_separately(&gru, &xs, &lengths)?;
+    for (b, &len) in lengths.iter().enumerate() {
+        let h = last.h().narrow(0, b, 1)?;
+        assert_eq!(to_vec2_round(&h, 4)?, to_vec2_round(states[b].h(), 4)?);
+        let ys = padded.get(b)?.narrow(0, 0, len)?;
+        assert_eq!(to_vec2_round(&ys, 4)?, to_vec2_round(&outputs[b], 4)?);
+    }
+    Ok(())
+}
+
+#[test]
+fn stacked_lstm_packed() -> Result<()> {
+    use candle_nn::rnn::{pack_padded_sequence, pad_packed_sequence};
+    let cpu = &Device::Cpu;
+    let varmap = VarMap::new();
+    let vb = VarBuilder::from_varmap(&varmap, DType::F32, cpu);
+    let stacked = StackedConfig::default()
+        .with_num_layers(2)
+        .with_bidirectional(true);
+    let lstm = candle_nn::stacked_lstm(2, 3, Default::default(), stacked, vb)?;
+    let xs = Tensor::arange(0f32, 30., cpu)?.reshape((3, 5, 2))?.cos()?;
+    let lengths = [2, 5, 4];
+    let packed = pack_padded_sequence(&xs, &lengths)?;
+    let (ys, last) = lstm.seq_packed(&packed, &lstm.zero_state(3)?)?;
+    let (padded, _) = pad_packed_sequence(&ys, 0., None)?;
+    assert_eq!(padded.dims(), [3, 5, 6]);
+    for (b, &len) in lengths.iter().enumerate() {
+        let seq = xs.narrow(0, b, 1)?.narrow(1, 0, len)?.contiguous()?;
+        let states = lstm.seq(&seq)?;
+        let expected = lstm.states_to_tensor(&states)?.squeeze(0)?;
+        let ys = padded.get(b)?.narrow(0, 0, len)?;
+        assert_eq!(to_vec2_round(&ys, 4)?, to_vec2_round(&expected, 4)?);
+        for (s, e) in last.iter().zip(lstm.last_state(&states)?.iter()) {
+            let h = s.h().narrow(0, b, 1)?;
+            assert_eq!(to_vec2_round(&h, 4)?, to_vec2_round(e.h(), 4)?);
+        }
+    }
+    Ok(())
+}
+
+// A recurrent layer whose state cannot be split along the batch dimension.
+#[derive(Clone)]
+struct Accumulate;
+
+#[derive(Clone)]
+struct Sum(Tensor);
+
+impl RNN for Accumulate {
+    type State = Sum;
+
+    fn zero_state(&self, batch_dim: usize) -> Result<Sum> {
+        Ok(Sum(Tensor::zeros(
+            (batch_dim, 2),
+            DType::F32,
+            &Device::Cpu,
+        )?))
+    }
+
+    fn step(&self, input: &Tensor, state: &Sum) -> Result<Sum> {
+        Ok(Sum((input + &state.0)?))
+    }
+
+    fn states_to_tensor(&self, states: &[Sum]) -> Result<Tensor> {
+        let states: Vec<_> = states.iter().map(|s| &s.0).collect();
+        Tensor::stack(&states, 1)
+    }
+}
+
+#[test]
+fn stacked_without_batch_state() -> Result<()> {
+    let stacked = StackedConfig::default().with_num_layers(2);
+    let rnn = candle_nn::StackedRNN::new(2, 2, stacked, |_, _, _| Ok(Accumulate))?;
+    let xs = Tensor::arange(0f32, 6., &Device::Cpu)?.reshape((1, 3, 2))?;
+    let states = rnn.seq(&xs)?;
+    assert_eq!(
+        rnn.states_to_tensor(&states)?.to_vec3::<f32>()?,
+        [[[0., 1.], [2., 5.], [8., 14.]]]
+    );
     Ok(())
 }
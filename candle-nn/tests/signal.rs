@@ -0,0 +1,175 @@
+#[cfg(feature = "mkl")]
+extern crate intel_mkl_src;
+
+#[cfg(feature = "accelerate")]
+extern crate accelerate_src;
+
+use candle::{DType, Device, IndexOp, Result, Tensor, Var};
+use candle_nn::signal::{
+    convolve1d, correlate1d, frame, hamming_window, hann_window, overlap_add, resample,
+    CorrelationMode, ResampleConfig, ResamplingWindow,
+};
+
+fn assert_close(a: &[f32], b: &[f32], tol: f32) {
+    assert_eq!(a.len(), b.len(), "{a:?} {b:?}");
+    for (x, y) in a.iter().zip(b.iter()) {
+        assert!((x - y).abs() < tol, "{a:?} {b:?}")
+    }
+}
+
+#[test]
+fn windows() -> Result<()> {
+    let dev = &Device::Cpu;
+    let w = hann_window(4, true, DType::F32, dev)?.to_vec1::<f32>()?;
+    assert_close(&w, &[0., 0.5, 1., 0.5], 1e-6);
+    let w = hann_window(5, false, DType::F32, dev)?.to_vec1::<f32>()?;
+    assert_close(&w, &[0., 0.5, 1., 0.5, 0.], 1e-6);
+    let w = hamming_window(4, true, DType::F32, dev)?.to_vec1::<f32>()?;
+    assert_close(&w, &[0.08, 0.54, 1., 0.54], 1e-6);
+    let w = hamming_window(3, false, DType::F64, dev)?;
+    assert_eq!(w.dtype(), DType::F64);
+    assert_eq!(
+        hann_window(1, true, DType::F32, dev)?.to_vec1::<f32>()?,
+        [1.]
+    );
+    assert_eq!(hann_window(0, true, DType::F32, dev)?.dims(), [0]);
+    Ok(())
+}
+
+#[test]
+fn frame_and_overlap_add() -> Result<()> {
+    let dev = &Device::Cpu;
+    let xs = Tensor::arange(0f32, 10., dev)?.reshape((1, 10))?;
+    let frames = frame(&xs, 4, 3)?;
+    assert_eq!(
+        frames.to_vec3::<f32>()?,
+        [[[0., 1., 2., 3.], [3., 4., 5., 6.], [6., 7., 8., 9.]]]
+    );
+    // The overlapping samples are summed.
+    let out = overlap_add(&frames, 3)?.to_vec2::<f32>()?;
+    assert_eq!(out, [[0., 1., 2., 6., 4., 5., 12., 7., 8., 9.]]);
+    // Frames longer than a multiple of the hop and no overlap.
+    let ones = Tensor::ones((2, 5), DType::F32, dev)?;
+    let out = overlap_add(&ones, 2)?.to_vec1::<f32>()?;
+    assert_eq!(out, [1., 1., 2., 2., 2., 1., 1.]);
+    let out = overlap_add(&ones, 6)?.to_vec1::<f32>()?;
+    assert_eq!(out, [1., 1., 1., 1., 1., 0., 1., 1., 1., 1., 1.]);
+    assert!(frame(&xs, 11, 1).is_err());
+
+    // A periodic hann window with a hop of half its size sums to one.
+    let signal = Tensor::arange(0f32, 64., dev)?.affine(0.3, 0.)?.sin()?;
+    let window = hann_window(16, true, DType::F32, dev)?;
+    let frames = frame(&signal, 16, 8)?.broadcast_mul(&window)?;
+    let rebuilt = overlap_add(&frames, 8)?;
+    assert_eq!(rebuilt.dims(), [64]);
+    let interior = rebuilt.i(8..56)?.to_vec1::<f32>()?;
+    assert_close(&interior, &signal.i(8..56)?.to_vec1::<f32>()?, 1e-5);
+
+    // The gradient of each frame sample is the gradient of the output sample it lands on.
+    let frames = Var::new(&[[1f32, 2., 3.], [4., 5., 6.]], dev)?;
+    let weights = Tensor::new(&[1f32, 10., 100., 1000.], dev)?;
+    let grads = (overlap_add(&frames, 1)? * &weights)?
+        .sum_all()?
+        .backward()?;
+    let grad = grads.get(&frames).unwrap().to_vec2::<f32>()?;
+    assert_eq!(grad, [[1., 10., 100.], [10., 100., 1000.]]);
+    Ok(())
+}
+
+#[test]
+fn correlation() -> Result<()> {
+    let dev = &Device::Cpu;
+    let xs = Tensor::new(&[1f32, 2., 3., 4., 5.], dev)?;
+    let kernel = Tensor::new(&[1f32, 0., -1.], dev)?;
+    // numpy.correlate([1, 2, 3, 4, 5], [1, 0, -1], mode)
+    let c = correlate1d(&xs, &kernel, CorrelationMode::Valid)?;
+    assert_eq!(c.to_vec1::<f32>()?, [-2., -2., -2.]);
+    let c = correlate1d(&xs, &kernel, CorrelationMode::Full)?;
+    assert_eq!(c.to_vec1::<f32>()?, [-1., -2., -2., -2., -2., 4., 5.]);
+    let c = correlate1d(&xs, &kernel, CorrelationMode::Same)?;
+    assert_eq!(c.to_vec1::<f32>()?, [-2., -2., -2., -2., 4.]);
+    // numpy.convolve([1, 2, 3, 4, 5], [1, 2, 0, 1], mode)
+    let kernel = Tensor::new(&[1f32, 2., 0., 1.], dev)?;
+    let c = convolve1d(&xs, &kernel, CorrelationMode::Full)?;
+    assert_eq!(c.to_vec1::<f32>()?, [1., 4., 7., 11., 15., 13., 4., 5.]);
+    let c = convolve1d(&xs, &kernel, CorrelationMode::Same)?;
+    assert_eq!(c.to_vec1::<f32>()?, [4., 7., 11., 15., 13.]);
+
+    // Batched inputs.
+    let xs = Tensor::stack(&[&xs, &(&xs * 2.)?], 0)?.unsqueeze(0)?;
+    let c = convolve1d(&xs, &kernel, CorrelationMode::Valid)?;
+    assert_eq!(c.dims(), [1, 2, 2]);
+    assert_eq!(c.to_vec3::<f32>()?, [[[11., 15.], [22., 30.]]]);
+    assert!(correlate1d(
+        &xs,
+        &Tensor::ones(6, DType::F32, dev)?,
+        CorrelationMode::Valid
+    )
+    .is_err());
+    Ok(())
+}
+
+fn sine(freq: f64, sample_rate: usize, len: usize, dev: &Device) -> Result<Tensor> {
+    let step = 2. * std::f64::consts::PI * freq / sample_rate as f64;
+    Tensor::arange(0u32, len as u32, dev)?
+        .to_dtype(DType::F32)?
+        .affine(step, 0.)?
+        .sin()
+}
+
+#[test]
+fn resampling() -> Result<()> {
+    let dev = &Device::Cpu;
+    let xs = sine(440., 44_100, 4410, dev)?;
+    let config = ResampleConfig::default();
+    for (config, tol) in [
+        (config, 2e-3),
+        (
+            ResampleConfig {
+                window: ResamplingWindow::Kaiser {
+                    beta: 14.769656459379492,
+                },
+                ..config
+            },
+            2e-3,
+        ),
+    ] {
+        let ys = resample(&xs, 44_100, 16_000, &config)?;
+        assert_eq!(ys.dims(), [1600]);
+        // Away from the edges, the output is the same sine sampled at the new rate.
+        let expected = sine(440., 16_000, 1600, dev)?;
+        let diff = (ys - expected)?.narrow(0, 100, 1400)?.abs()?.max_all()?;
+        let diff = diff.to_scalar::<f32>()?;
+        assert!(diff < tol, "{config:?} {diff}");
+    }
+
+    // Upsampling, with a batch and a length that is not a multiple of the frequencies.
+    let xs = Tensor::stack(
+        &[sine(300., 8000, 801, dev)?, sine(1000., 8000, 801, dev)?],
+        0,
+    )?;
+    let ys = resample(&xs, 8000, 24_000, &config)?;
+    assert_eq!(ys.dims(), [2, 2403]);
+    // Every third sample is an input sample.
+    let diff = (ys
+        .narrow(1, 150, 2100)?
+        .reshape((2, 700, 3))?
+        .narrow(2, 0, 1)?
+        .squeeze(2)?
+        - xs.narrow(1, 50, 700)?)?;
+    assert!(diff.abs()?.max_all()?.to_scalar::<f32>()? < 2e-3);
+    // Frequencies above the new Nyquist frequency are filtered out.
+    let ys = resample(&sine(6000., 16_000, 1600, dev)?, 16_000, 8_000, &config)?;
+    assert_eq!(ys.dims(), [800]);
+    let residual = ys
+        .narrow(0, 100, 600)?
+        .abs()?
+        .max_all()?
+        .to_scalar::<f32>()?;
+    assert!(residual < 1e-2, "{residual}");
+
+    let ys = resample(&xs, 16_000, 16_000, &config)?;
+    assert_eq!(ys.to_vec2::<f32>()?, xs.to_vec2::<f32>()?);
+    assert!(resample(&xs, 0, 16_000, &config).is_err());
+    Ok(())
+}
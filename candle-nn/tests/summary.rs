@@ -0,0 +1,117 @@
+#[cfg(feature = "mkl")]
+extern crate intel_mkl_src;
+
+#[cfg(feature = "accelerate")]
+extern crate accelerate_src;
+
+use anyhow::Result;
+use candle::{DType, Device, Tensor};
+use candle_nn::summary::{LayerKind, ModelSummary};
+use candle_nn::{VarBuilder, VarMap};
+use std::collections::HashMap;
+
+#[test]
+fn model_summary() -> Result<()> {
+    let dev = &Device::Cpu;
+    let varmap = VarMap::new();
+    let vb = VarBuilder::from_varmap(&varmap, DType::F32, dev);
+    candle_nn::embedding(100, 8, vb.pp("model.embed_tokens"))?;
+    for i in [0, 1, 2, 10] {
+        let vb = vb.pp(format!("model.layers.{i}"));
+        candle_nn::linear(8, 32, vb.pp("mlp.up_proj"))?;
+        candle_nn::linear_no_bias(32, 8, vb.pp("mlp.down_proj"))?;
+        candle_nn::layer_norm(8, 1e-5, vb.pp("norm"))?;
+    }
+    candle_nn::conv2d(3, 4, 3, Default::default(), vb.pp("patch"))?;
+    varmap.get_buffer((2, 2), "model.mask", candle_nn::init::ZERO, DType::U8, dev)?;
+
+    let summary = ModelSummary::from_varmap(&varmap);
+    let names: Vec<_> = summary.layers().iter().map(|l| l.name.as_str()).collect();
+    assert_eq!(
+        &names[..6],
+        [
+            "model",
+            "model.embed_tokens",
+            "model.layers.0.mlp.down_proj",
+            "model.layers.0.mlp.up_proj",
+            "model.layers.0.norm",
+            "model.layers.1.mlp.down_proj",
+        ]
+    );
+    // The numeric components are sorted as numbers.
+    assert_eq!(names[11], "model.layers.10.mlp.down_proj");
+    assert_eq!(names[14], "patch");
+    let kinds: Vec<_> = summary.layers().iter().map(|l| l.kind).collect();
+    assert_eq!(
+        &kinds[..5],
+        [
+            LayerKind::Other,
+            LayerKind::Embedding,
+            LayerKind::Linear,
+            LayerKind::Linear,
+            LayerKind::Norm,
+        ]
+    );
+    assert_eq!(kinds[14], LayerKind::Conv2d);
+
+    let up_proj = &summary.layers()[3];
+    assert_eq!(up_proj.num_params(), 8 * 32 + 32);
+    assert_eq!(up_proj.memory(), (8 * 32 + 32) * 4);
+    assert_eq!(up_proj.flops, Some(2 * 8 * 32 + 32));
+    assert_eq!(summary.layers()[4].flops, None);
+    assert_eq!(summary.layers()[14].flops, Some(2 * 4 * 3 * 3 * 3 + 4));
+
+    let per_layer = 8 * 32 + 32 + 32 * 8 + 2 * 8;
+    let num_params = 100 * 8 + 4 * per_layer + 4 * 3 * 3 * 3 + 4;
+    assert_eq!(summary.num_trainable_params(), num_params);
+    assert_eq!(summary.num_params(), num_params + 4);
+    assert_eq!(summary.memory(), num_params * 4 + 4);
+    assert_eq!(summary.memory_as(DType::BF16), num_params * 2 + 4);
+    assert_eq!(
+        summary.flops_per_token(),
+        4 * (2 * 8 * 32 + 32 + 2 * 32 * 8)
+    );
+
+    // One group per transformer layer.
+    let grouped = summary.grouped(3);
+    let names: Vec<_> = grouped.iter().map(|l| l.name.as_str()).collect();
+    assert_eq!(
+        names,
+        [
+            "model",
+            "model.embed_tokens",
+            "model.layers.0",
+            "model.layers.1",
+            "model.layers.2",
+            "model.layers.10",
+            "patch",
+        ]
+    );
+    assert_eq!(grouped[2].num_params(), per_layer);
+    assert_eq!(grouped[2].kind, LayerKind::Other);
+    assert_eq!(grouped[2].params[0].name, "mlp.down_proj.weight");
+    assert_eq!(summary.grouped(0).len(), 1);
+
+    let table = summary.to_string();
+    assert!(table.contains("model.layers.0.mlp.up_proj"));
+    assert!(table.contains("bias [32], weight [32, 8]"));
+    assert!(table.contains("u8"));
+    let total = table.lines().last().unwrap();
+    assert!(
+        total.starts_with("total: 3.16K params (3.15K trainable), 12.32KiB"),
+        "{total}"
+    );
+
+    let tensors = HashMap::from([
+        (
+            "lm_head.weight".to_string(),
+            Tensor::zeros((5, 3), DType::F16, dev)?,
+        ),
+        ("scale".to_string(), Tensor::zeros(3, DType::F32, dev)?),
+    ]);
+    let summary = ModelSummary::from_tensors(&tensors);
+    assert_eq!(summary.layers().len(), 2);
+    assert_eq!(summary.layers()[0].name, "");
+    assert_eq!(summary.memory(), 5 * 3 * 2 + 3 * 4);
+    Ok(())
+}
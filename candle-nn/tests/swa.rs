@@ -0,0 +1,126 @@
+#[cfg(feature = "mkl")]
+extern crate intel_mkl_src;
+
+#[cfg(feature = "accelerate")]
+extern crate accelerate_src;
+
+use anyhow::Result;
+use candle::{DType, Device, Tensor};
+use candle_nn::swa::{average_checkpoints, SwaConfig, SwaModel};
+use candle_nn::VarMap;
+use std::collections::HashMap;
+
+fn values(varmap: &VarMap, name: &str) -> Result<Vec<f32>> {
+    let data = varmap.data().lock().unwrap();
+    Ok(data[name].as_tensor().flatten_all()?.to_vec1::<f32>()?)
+}
+
+fn set(varmap: &VarMap, name: &str, v: &[f32]) -> Result<()> {
+    let data = varmap.data().lock().unwrap();
+    let var = &data[name];
+    var.set(&Tensor::new(v, &Device::Cpu)?.reshape(var.shape())?)?;
+    Ok(())
+}
+
+#[test]
+fn swa_update_apply_restore() -> Result<()> {
+    let dev = &Device::Cpu;
+    let varmap = VarMap::new();
+    varmap.get(2, "w", candle_nn::Init::Const(0.), DType::F32, dev)?;
+    varmap.register_buffer("count", &Tensor::zeros(1, DType::F32, dev)?)?;
+    let mut swa = SwaModel::new(&varmap, SwaConfig::new(2).with_frequency(2))?;
+    assert!(swa.apply().is_err());
+    let mut averaged = vec![];
+    for step in 1..=8 {
+        set(&varmap, "w", &[step as f32, -(step as f32)])?;
+        set(&varmap, "count", &[step as f32])?;
+        averaged.push(swa.update()?);
+    }
+    assert_eq!(
+        averaged,
+        [false, false, false, true, false, true, false, true]
+    );
+    assert_eq!(swa.num_averaged(), 3);
+    assert_eq!(swa.step(), 8);
+
+    // The mean of the weights of steps 4, 6 and 8, the buffer is copied.
+    swa.apply()?;
+    assert!(swa.update().is_err());
+    assert_eq!(values(&varmap, "w")?, [6., -6.]);
+    assert_eq!(values(&varmap, "count")?, [8.]);
+    swa.restore()?;
+    assert_eq!(values(&varmap, "w")?, [8., -8.]);
+
+    let path = std::env::temp_dir().join(format!("candle-swa-{}.safetensors", std::process::id()));
+    swa.save(&path)?;
+    let mut loaded = SwaModel::new(&varmap, *swa.config())?;
+    loaded.load(&path)?;
+    std::fs::remove_file(&path)?;
+    assert_eq!((loaded.step(), loaded.num_averaged()), (8, 3));
+    let other = VarMap::new();
+    other.get(2, "w", candle_nn::Init::Const(0.), DType::F32, dev)?;
+    loaded.copy_to(&other)?;
+    assert_eq!(values(&other, "w")?, [6., -6.]);
+    Ok(())
+}
+
+#[test]
+fn checkpoint_averaging() -> Result<()> {
+    let dev = &Device::Cpu;
+    let dir = std::env::temp_dir().join(format!("candle-avg-{}", std::process::id()));
+    let tensors = |i: f32| -> Result<HashMap<String, Tensor>> {
+        Ok(HashMap::from([
+            ("a".to_string(), Tensor::new(&[i, 2. * i], dev)?),
+            (
+                "b".to_string(),
+                Tensor::new(&[i], dev)?.to_dtype(DType::BF16)?,
+            ),
+            ("step".to_string(), Tensor::new(&[i as u32], dev)?),
+        ]))
+    };
+    // Single file checkpoints.
+    std::fs::create_dir_all(&dir)?;
+    let files = (1..=3)
+        .map(|i| -> Result<_> {
+            let path = dir.join(format!("ckpt{i}.safetensors"));
+            candle::safetensors::save(&tensors(i as f32)?, &path)?;
+            Ok(path)
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let out = dir.join("avg.safetensors");
+    average_checkpoints(&files, &out)?;
+    let avg = candle::safetensors::load(&out, dev)?;
+    assert_eq!(avg["a"].to_vec1::<f32>()?, [2., 4.]);
+    assert_eq!(avg["b"].dtype(), DType::BF16);
+    assert_eq!(avg["b"].to_dtype(DType::F32)?.to_vec1::<f32>()?, [2.]);
+    assert_eq!(avg["step"].to_vec1::<u32>()?, [1]);
+
+    // Sharded checkpoints, the second one uses a different sharding.
+    let (sharded, other, out_dir) = (dir.join("sharded"), dir.join("other"), dir.join("out"));
+    std::fs::create_dir_all(&sharded)?;
+    std::fs::create_dir_all(&other)?;
+    let mut t = tensors(1.)?;
+    let shard = HashMap::from([("a".to_string(), t.remove("a").unwrap())]);
+    candle::safetensors::save(&shard, sharded.join("model-00001.safetensors"))?;
+    candle::safetensors::save(&t, sharded.join("model-00002.safetensors"))?;
+    std::fs::write(sharded.join("config.json"), "{}")?;
+    candle::safetensors::save(&tensors(5.)?, other.join("model.safetensors"))?;
+    average_checkpoints(&[&sharded, &other], &out_dir)?;
+    let first = candle::safetensors::load(out_dir.join("model-00001.safetensors"), dev)?;
+    assert_eq!(first["a"].to_vec1::<f32>()?, [3., 6.]);
+    let second = candle::safetensors::load(out_dir.join("model-00002.safetensors"), dev)?;
+    assert_eq!(second["b"].to_dtype(DType::F32)?.to_vec1::<f32>()?, [3.]);
+    assert!(out_dir.join("config.json").exists());
+
+    // Mismatched checkpoints.
+    let bad = dir.join("bad.safetensors");
+    let mut t = tensors(1.)?;
+    t.insert("a".to_string(), Tensor::new(&[1f32], dev)?);
+    candle::safetensors::save(&t, &bad)?;
+    assert!(average_checkpoints(&[&files[0], &bad], dir.join("x.safetensors")).is_err());
+    t.remove("a");
+    candle::safetensors::save(&t, &bad)?;
+    assert!(average_checkpoints(&[&files[0], &bad], dir.join("x.safetensors")).is_err());
+    std::fs::remove_dir_all(&dir)?;
+    Ok(())
+}
@@ -0,0 +1,64 @@
+#[cfg(feature = "mkl")]
+extern crate intel_mkl_src;
+
+#[cfg(feature = "accelerate")]
+extern crate accelerate_src;
+
+use anyhow::Result;
+use candle::{DType, Device, Tensor};
+use candle_nn::tensorboard::{read_records, SummaryWriter};
+
+fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+    haystack.windows(needle.len()).any(|w| w == needle)
+}
+
+#[test]
+fn summary_writer() -> Result<()> {
+    let dev = &Device::Cpu;
+    let log_dir = std::env::temp_dir().join(format!("candle-tensorboard-{}", std::process::id()));
+    let mut writer = SummaryWriter::new(&log_dir)?;
+    let path = writer.path().to_path_buf();
+    assert!(path.starts_with(&log_dir));
+    assert!(path
+        .file_name()
+        .unwrap()
+        .to_str()
+        .unwrap()
+        .contains("tfevents"));
+    writer.add_scalar("train/loss", 0.5, 1)?;
+    writer.add_scalar("train/loss", 0.25, 300)?;
+    writer.add_histogram("weights", &Tensor::arange(0f32, 100., dev)?, 2)?;
+    writer.add_histogram("constant", &Tensor::ones(4, DType::F32, dev)?, 2)?;
+    let image = Tensor::rand(0f32, 1., (3, 4, 5), dev)?;
+    writer.add_image("samples", &image, 3)?;
+    assert!(writer
+        .add_image("bad", &Tensor::zeros((2, 4, 4), DType::U8, dev)?, 3)
+        .is_err());
+    drop(writer);
+
+    let records = read_records(&path)?;
+    assert_eq!(records.len(), 6);
+    assert!(contains(&records[0], b"brain.Event:2"));
+    // The step, a varint with the field number 2, and the f32 value.
+    assert!(contains(&records[1], b"train/loss"));
+    assert!(contains(&records[1], &[0x10, 1]));
+    assert!(contains(&records[1], &0.5f32.to_le_bytes()));
+    assert!(contains(&records[2], &[0x10, 0xac, 0x02]));
+    assert!(contains(&records[2], &0.25f32.to_le_bytes()));
+    // The histogram max and total count.
+    assert!(contains(&records[3], b"weights"));
+    assert!(contains(&records[3], &99f64.to_le_bytes()));
+    assert!(contains(&records[3], &100f64.to_le_bytes()));
+    assert!(contains(&records[4], &4f64.to_le_bytes()));
+    assert!(contains(&records[5], b"\x89PNG\r\n\x1a\n"));
+    assert!(contains(&records[5], b"IHDR"));
+
+    // Corrupted files are detected.
+    let mut data = std::fs::read(&path)?;
+    let last = data.len() - 10;
+    data[last] ^= 1;
+    std::fs::write(&path, data)?;
+    assert!(read_records(&path).is_err());
+    std::fs::remove_dir_all(&log_dir)?;
+    Ok(())
+}
@@ -0,0 +1,172 @@
+#[cfg(feature = "mkl")]
+extern crate intel_mkl_src;
+
+#[cfg(feature = "accelerate")]
+extern crate accelerate_src;
+
+use anyhow::Result;
+use candle::test_utils::to_vec3_round;
+use candle::{DType, Device, Tensor, D};
+use candle_nn::attention::causal_mask;
+use candle_nn::transformer::{
+    TransformerConfig, TransformerDecoder, TransformerEncoder, TransformerEncoderLayer,
+};
+use candle_nn::{Activation, Layer, VarBuilder, VarMap};
+
+#[test]
+fn encoder_names() -> Result<()> {
+    let dev = &Device::Cpu;
+    let varmap = VarMap::new();
+    let vb = VarBuilder::from_varmap(&varmap, DType::F32, dev);
+    let config = TransformerConfig::new(8, 2)
+        .with_dim_feedforward(16)
+        .with_norm_first(true);
+    let mut encoder = TransformerEncoder::new(&config, 2, vb.pp("encoder"))?;
+    let mut names = vec![];
+    encoder.visit_named_parameters(&mut |name, t| {
+        names.push(format!("{name} {:?}", t.dims()));
+        Ok(())
+    })?;
+    assert_eq!(names.len(), 2 * 16 + 2);
+    assert_eq!(
+        names[..6],
+        [
+            "layers.0.self_attn.q_proj.weight [8, 8]",
+            "layers.0.self_attn.q_proj.bias [8]",
+            "layers.0.self_attn.k_proj.weight [8, 8]",
+            "layers.0.self_attn.k_proj.bias [8]",
+            "layers.0.self_attn.v_proj.weight [8, 8]",
+            "layers.0.self_attn.v_proj.bias [8]",
+        ]
+    );
+    assert_eq!(
+        names[8..12],
+        [
+            "layers.0.linear1.weight [16, 8]",
+            "layers.0.linear1.bias [16]",
+            "layers.0.linear2.weight [8, 16]",
+            "layers.0.linear2.bias [8]",
+        ]
+    );
+    assert_eq!(names[32..], ["norm.weight [8]", "norm.bias [8]"]);
+    assert_eq!(varmap.all_vars().len(), names.len());
+
+    let xs = Tensor::randn(0f32, 1., (2, 5, 8), dev)?;
+    assert_eq!(encoder.forward(&xs, None, None)?.dims(), [2, 5, 8]);
+    Ok(())
+}
+
+#[test]
+fn encoder_layer_post_norm() -> Result<()> {
+    let dev = &Device::Cpu;
+    let varmap = VarMap::new();
+    let vb = VarBuilder::from_varmap(&varmap, DType::F32, dev);
+    let config = TransformerConfig::new(4, 2)
+        .with_dim_feedforward(6)
+        .with_activation(Activation::Gelu)
+        .with_dropout(0.);
+    let layer = TransformerEncoderLayer::new(&config, vb)?;
+    let xs = Tensor::randn(0f32, 1., (1, 3, 4), dev)?;
+
+    // The reference computation, `norm2(x + ff(x))` with `x = norm1(src + self_attn(src))`.
+    let data = varmap.data().lock().unwrap();
+    let var = |name: &str| data[name].as_tensor().clone();
+    let linear = |xs: &Tensor, name: &str| -> Result<Tensor> {
+        let w = var(&format!("{name}.weight"));
+        let b = var(&format!("{name}.bias"));
+        Ok(xs.broadcast_matmul(&w.t()?)?.broadcast_add(&b)?)
+    };
+    let norm = |xs: &Tensor, name: &str| -> Result<Tensor> {
+        let mean = xs.mean_keepdim(D::Minus1)?;
+        let xs = xs.broadcast_sub(&mean)?;
+        let std = (xs.sqr()?.mean_keepdim(D::Minus1)? + 1e-5)?.sqrt()?;
+        let xs = xs.broadcast_div(&std)?;
+        let w = var(&format!("{name}.weight"));
+        let b = var(&format!("{name}.bias"));
+        Ok(xs.broadcast_mul(&w)?.broadcast_add(&b)?)
+    };
+    let heads = |xs: &Tensor| xs.reshape((1, 3, 2, 2))?.transpose(1, 2);
+    let q = heads(&linear(&xs, "self_attn.q_proj")?)?;
+    let k = heads(&linear(&xs, "self_attn.k_proj")?)?;
+    let v = heads(&linear(&xs, "self_attn.v_proj")?)?;
+    let scores = (q.matmul(&k.t()?)? / 2f64.sqrt())?;
+    let attn = candle_nn::ops::softmax_last_dim(&scores)?.matmul(&v)?;
+    let attn = attn.transpose(1, 2)?.reshape((1, 3, 4))?;
+    let ys = norm(&(&xs + linear(&attn, "self_attn.out_proj")?)?, "norm1")?;
+    let ff = linear(&linear(&ys, "linear1")?.gelu_erf()?, "linear2")?;
+    let expected = norm(&(&ys + ff)?, "norm2")?;
+    drop(data);
+
+    let diff = (layer.forward(&xs, None, None)? - expected)?
+        .abs()?
+        .max_all()?;
+    assert!(diff.to_scalar::<f32>()? < 1e-5, "{diff}");
+    Ok(())
+}
+
+#[test]
+fn encoder_key_padding() -> Result<()> {
+    let dev = &Device::Cpu;
+    let vb = VarBuilder::from_varmap(&VarMap::new(), DType::F32, dev);
+    let config = TransformerConfig::new(8, 2).with_dropout(0.);
+    let encoder = TransformerEncoder::new(&config, 2, vb)?;
+    // The second sequence has two padding tokens.
+    let padding = Tensor::new(&[[0u8, 0, 0, 0], [0, 0, 1, 1]], dev)?;
+    let xs = Tensor::randn(0f32, 1., (2, 4, 8), dev)?;
+    let ys = encoder.forward(&xs, None, Some(&padding))?;
+    let noise = Tensor::randn(0f32, 1., (1, 2, 8), dev)?;
+    let xs2 = Tensor::cat(&[&xs.narrow(0, 1, 1)?.narrow(1, 0, 2)?, &noise], 1)?;
+    let xs2 = Tensor::cat(&[&xs.narrow(0, 0, 1)?, &xs2], 0)?;
+    let ys2 = encoder.forward(&xs2, None, Some(&padding))?;
+    assert_eq!(
+        to_vec3_round(&ys.narrow(1, 0, 2)?, 4)?,
+        to_vec3_round(&ys2.narrow(1, 0, 2)?, 4)?
+    );
+    // Without the padding mask the first tokens attend to the padding ones.
+    let ys = encoder.forward(&xs, None, None)?.narrow(0, 1, 1)?;
+    let ys2 = encoder.forward(&xs2, None, None)?.narrow(0, 1, 1)?;
+    assert_ne!(
+        to_vec3_round(&ys.narrow(1, 0, 2)?, 4)?,
+        to_vec3_round(&ys2.narrow(1, 0, 2)?, 4)?
+    );
+    Ok(())
+}
+
+#[test]
+fn decoder() -> Result<()> {
+    let dev = &Device::Cpu;
+    let varmap = VarMap::new();
+    let vb = VarBuilder::from_varmap(&varmap, DType::F32, dev);
+    let config = TransformerConfig::new(8, 4).with_norm_first(true);
+    let mut decoder = TransformerDecoder::new(&config, 2, vb)?;
+    let names: Vec<_> = varmap.named_vars().into_iter().map(|(n, _)| n).collect();
+    assert!(names.contains(&"layers.1.multihead_attn.out_proj.weight".to_string()));
+    assert!(names.contains(&"layers.1.norm3.weight".to_string()));
+    decoder.eval()?;
+
+    let tgt = Tensor::randn(0f32, 1., (1, 5, 8), dev)?;
+    let memory = Tensor::randn(0f32, 1., (1, 3, 8), dev)?;
+    let mask = causal_mask(5, dev)?;
+    let ys = decoder.forward(&tgt, &memory, Some(&mask), None, None, None)?;
+    assert_eq!(ys.dims(), [1, 5, 8]);
+    // With the causal mask, the outputs do not depend on the following tokens.
+    let tgt2 = Tensor::cat(&[&tgt.narrow(1, 0, 3)?, &tgt.narrow(1, 3, 2)?.neg()?], 1)?;
+    let ys2 = decoder.forward(&tgt2, &memory, Some(&mask), None, None, None)?;
+    assert_eq!(
+        to_vec3_round(&ys.narrow(1, 0, 3)?, 4)?,
+        to_vec3_round(&ys2.narrow(1, 0, 3)?, 4)?
+    );
+    // All the outputs depend on the memory.
+    let ys2 = decoder.forward(&tgt, &memory.neg()?, Some(&mask), None, None, None)?;
+    let diff = (ys - ys2)?.abs()?.max_keepdim(D::Minus1)?.flatten_all()?;
+    assert!(diff.to_vec1::<f32>()?.iter().all(|&d| d > 1e-4));
+
+    // The dropout is only active in training mode.
+    let ys = decoder.forward(&tgt, &memory, Some(&mask), None, None, None)?;
+    let ys2 = decoder.forward(&tgt, &memory, Some(&mask), None, None, None)?;
+    assert_eq!(to_vec3_round(&ys, 4)?, to_vec3_round(&ys2, 4)?);
+    decoder.train()?;
+    let ys2 = decoder.forward(&tgt, &memory, Some(&mask), None, None, None)?;
+    assert_ne!(to_vec3_round(&ys, 4)?, to_vec3_round(&ys2, 4)?);
+    Ok(())
+}
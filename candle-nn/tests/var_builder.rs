@@ -0,0 +1,112 @@
+#[cfg(feature = "mkl")]
+extern crate intel_mkl_src;
+
+#[cfg(feature = "accelerate")]
+extern crate accelerate_src;
+
+use anyhow::Result;
+use candle::{DType, Device, Tensor};
+use candle_nn::VarBuilder;
+use std::collections::HashMap;
+
+#[test]
+fn sharded_safetensors() -> Result<()> {
+    let dir = std::env::temp_dir().join(format!("candle-index-{}", std::process::id()));
+    std::fs::create_dir_all(&dir)?;
+    let w = Tensor::new(&[[1f32, 2.], [3., 4.]], &Device::Cpu)?;
+    let b = Tensor::new(&[5f32, 6.], &Device::Cpu)?;
+    candle::safetensors::save(
+        &HashMap::from([("lin.weight", w.clone())]),
+        dir.join("model-00001-of-00003.safetensors"),
+    )?;
+    candle::safetensors::save(
+        &HashMap::from([("lin.bias", b.clone())]),
+        dir.join("model-00002-of-00003.safetensors"),
+    )?;
+    // The third file does not exist, it should only be opened when its tensor is requested.
+    let index = r#"{
+        "metadata": {"total_size": 24},
+        "weight_map": {
+            "lin.weight": "model-00001-of-00003.safetensors",
+            "lin.bias": "model-00002-of-00003.safetensors",
+            "head.weight": "model-00003-of-00003.safetensors"
+        }
+    }"#;
+    std::fs::write(dir.join("model.safetensors.index.json"), index)?;
+
+    let vb = unsafe {
+        VarBuilder::from_sharded_safetensors(
+            dir.join("model.safetensors.index.json"),
+            DType::F32,
+            &Device::Cpu,
+        )?
+    };
+    let lin = candle_nn::linear(2, 2, vb.pp("lin"))?;
+    assert_eq!(lin.weight().to_vec2::<f32>()?, w.to_vec2::<f32>()?);
+    assert_eq!(lin.bias().unwrap().to_vec1::<f32>()?, b.to_vec1::<f32>()?);
+    assert!(vb.contains_tensor("head.weight"));
+    assert!(!vb.contains_tensor("head.bias"));
+    assert!(vb.get(2, "head.weight").is_err());
+    assert!(vb.get((2, 2), "lin.bias").is_err());
+    std::fs::remove_dir_all(&dir)?;
+    Ok(())
+}
+
+#[test]
+fn dtype_policy() -> Result<()> {
+    use candle_nn::var_builder::DTypePolicy;
+    use candle_nn::Module;
+    let dev = &Device::Cpu;
+    let tensors = HashMap::from([
+        (
+            "model.norm.weight".to_string(),
+            Tensor::ones(4, DType::F32, dev)?,
+        ),
+        (
+            "model.up.weight".to_string(),
+            Tensor::ones((2, 4), DType::F32, dev)?,
+        ),
+        (
+            "lm_head.weight".to_string(),
+            Tensor::ones((3, 4), DType::F32, dev)?,
+        ),
+    ]);
+    let policy = DTypePolicy::norms_in_f32().with_rule("lm_head.*", DType::F32);
+    let vb = VarBuilder::from_tensors(tensors, DType::F16, dev).with_dtype_policy(policy.clone());
+    let vb_m = vb.pp("model");
+    assert_eq!(vb_m.dtype_policy(), Some(&policy));
+    assert_eq!(vb_m.get(4, "norm.weight")?.dtype(), DType::F32);
+    assert_eq!(vb_m.get((2, 4), "up.weight")?.dtype(), DType::F16);
+    assert_eq!(vb.get((3, 4), "lm_head.weight")?.dtype(), DType::F32);
+    // The explicit dtypes take precedence.
+    let w = vb_m.get_with_hints_dtype(4, "norm.weight", Default::default(), DType::BF16)?;
+    assert_eq!(w.dtype(), DType::BF16);
+    assert_eq!(
+        vb.to_dtype(DType::BF16)
+            .get((3, 4), "lm_head.weight")?
+            .dtype(),
+        DType::F32
+    );
+
+    // With autocast, the layers with f32 weights return results in the dtype of their inputs.
+    let xs = Tensor::new(&[[1f32, 2., 3., 4.]], dev)?.to_dtype(DType::F16)?;
+    let norm = candle_nn::rms_norm(4, 1e-5, vb_m.pp("norm"))?;
+    let lm_head = candle_nn::linear_no_bias(4, 3, vb.pp("lm_head"))?;
+    assert!(norm.forward(&xs).is_err());
+    assert!(lm_head.forward(&xs).is_err());
+    let _guard = candle_nn::autocast::enable();
+    let ys = norm.forward(&xs)?;
+    assert_eq!(ys.dtype(), DType::F16);
+    let expected = norm.forward(&xs.to_dtype(DType::F32)?)?;
+    let diff = (ys.to_dtype(DType::F32)? - expected)?.abs()?.max_all()?;
+    assert!(diff.to_scalar::<f32>()? < 1e-3);
+    let layer_norm = candle_nn::LayerNorm::new_no_bias(Tensor::ones(4, DType::F32, dev)?, 1e-5);
+    assert_eq!(layer_norm.forward(&xs)?.dtype(), DType::F16);
+    let logits = lm_head.forward(&xs)?;
+    assert_eq!(logits.dtype(), DType::F16);
+    assert_eq!(
+        logits.to_dtype(DType::F32)?.to_vec2::<f32>()?,
+        [[10f32, 10., 10.]]
+    );
+    Ok(())
+}
@@ -0,0 +1,145 @@
+#[cfg(feature = "mkl")]
+extern crate intel_mkl_src;
+
+#[cfg(feature = "accelerate")]
+extern crate accelerate_src;
+
+use candle::{Device, IndexOp, Result, Tensor, Var};
+use candle_nn::volume_rendering::{
+    camera_rays, composite, importance_samples, positional_encoding, sample_points,
+    stratified_samples,
+};
+
+#[test]
+fn rays_and_samples() -> Result<()> {
+    let dev = &Device::Cpu;
+    // A camera at (1, 2, 3) with the identity rotation.
+    let c2w = Tensor::new(
+        &[[1f32, 0., 0., 1.], [0., 1., 0., 2.], [0., 0., 1., 3.]],
+        dev,
+    )?;
+    let (origins, directions) = camera_rays(2, 4, 2., &c2w)?;
+    assert_eq!(origins.dims(), [8, 3]);
+    assert_eq!(origins.i(5)?.to_vec1::<f32>()?, [1., 2., 3.]);
+    // The pixel (1, 2) is at the center of the image.
+    assert_eq!(directions.i(6)?.to_vec1::<f32>()?, [0., 0., -1.]);
+    assert_eq!(directions.i(0)?.to_vec1::<f32>()?, [-1., 0.5, -1.]);
+
+    let t_vals = stratified_samples(2., 6., 8, 4, false, dev)?;
+    assert_eq!(t_vals.i(3)?.to_vec1::<f32>()?, [2., 3., 4., 5.]);
+    let t_vals = stratified_samples(2., 6., 8, 4, true, dev)?.to_vec2::<f32>()?;
+    for ray in t_vals.iter() {
+        for (i, &t) in ray.iter().enumerate() {
+            assert!(t >= 2. + i as f32 && t <= 3. + i as f32)
+        }
+    }
+    let t_vals = Tensor::new(t_vals, dev)?;
+    let points = sample_points(&origins, &directions, &t_vals)?;
+    assert_eq!(points.dims(), [8, 4, 3]);
+    let t = t_vals.i((6, 2))?.to_scalar::<f32>()?;
+    assert_eq!(points.i((6, 2))?.to_vec1::<f32>()?, [1., 2., 3. - t]);
+    Ok(())
+}
+
+#[test]
+fn encoding() -> Result<()> {
+    let dev = &Device::Cpu;
+    let xs = Tensor::new(&[[0.25f32, 0.5]], dev)?;
+    let enc = positional_encoding(&xs, 2, true)?;
+    assert_eq!(enc.dims(), [1, 10]);
+    let enc = enc.i(0)?.to_vec1::<f32>()?;
+    let pi = std::f32::consts::PI;
+    let expected = [
+        0.25,
+        0.5,
+        (0.25 * pi).sin(),
+        (0.5 * pi).sin(),
+        (0.25 * pi).cos(),
+        (0.5 * pi).cos(),
+        (0.5 * pi).sin(),
+        (pi).sin(),
+        (0.5 * pi).cos(),
+        (pi).cos(),
+    ];
+    for (a, b) in enc.iter().zip(expected.iter()) {
+        assert!((a - b).abs() < 1e-6, "{enc:?} {expected:?}")
+    }
+    Ok(())
+}
+
+#[test]
+fn compositing() -> Result<()> {
+    let dev = &Device::Cpu;
+    let t_vals = Tensor::new(&[[1f32, 2., 4.], [1., 2., 3.]], dev)?;
+    let directions = Tensor::new(&[[0f32, 0., -1.], [0., 0., -2.]], dev)?;
+    let densities = Var::new(&[[0.5f32, 1., 2.], [0., -1., 0.1]], dev)?;
+    let colors = Tensor::new(
+        &[
+            [[1f32, 0., 0.], [0., 1., 0.], [0., 0., 1.]],
+            [[1., 1., 1.], [0.5, 0.5, 0.5], [0., 1., 0.]],
+        ],
+        dev,
+    )?;
+    let render = composite(&densities, &colors, &t_vals, &directions, false)?;
+
+    // The weights of the first ray: alpha_i * prod_{j < i} (1 - alpha_j).
+    let a0 = 1. - (-0.5f32).exp();
+    let a1 = 1. - (-2f32).exp();
+    let w = [a0, (1. - a0) * a1, (1. - a0) * (1. - a1)];
+    let weights = render.weights.to_vec2::<f32>()?;
+    for (a, b) in weights[0].iter().zip(w.iter()) {
+        assert!((a - b).abs() < 1e-6, "{weights:?}")
+    }
+    // The negative density is ignored and the interval lengths are scaled by the direction norm.
+    assert!(weights[1][0].abs() < 1e-7 && weights[1][1].abs() < 1e-7);
+    assert!((weights[1][2] - 1.).abs() < 1e-6);
+    let rgb = render.rgb.to_vec2::<f32>()?;
+    assert!((rgb[0][0] - w[0]).abs() < 1e-6 && (rgb[0][2] - w[2]).abs() < 1e-6);
+    assert!((rgb[1][1] - 1.).abs() < 1e-6);
+    let depth = render.depth.to_vec1::<f32>()?;
+    assert!((depth[0] - (w[0] + 2. * w[1] + 4. * w[2])).abs() < 1e-5);
+    let opacity = render.opacity.to_vec1::<f32>()?;
+    assert!((opacity[0] - 1.).abs() < 1e-6);
+
+    // The gradients flow back to the densities.
+    let grads = render.rgb.i((0, 2))?.backward()?;
+    let grad = grads.get(&densities).unwrap().to_vec2::<f32>()?;
+    // Increasing the densities in front of the blue sample decreases its weight.
+    assert!(grad[0][0] < 0. && grad[0][1] < 0.);
+    assert_eq!(grad[1], [0., 0., 0.]);
+
+    // All the missing opacity is white.
+    let densities = Tensor::zeros((2, 3), candle::DType::F32, dev)?;
+    let render = composite(&densities, &colors, &t_vals, &directions, true)?;
+    assert_eq!(render.rgb.to_vec2::<f32>()?, [[1., 1., 1.], [1., 1., 1.]]);
+    Ok(())
+}
+
+#[test]
+fn importance_sampling() -> Result<()> {
+    let dev = &Device::Cpu;
+    let edges = Tensor::new(&[[0f32, 1., 2., 3.]], dev)?;
+    // All the weight is in the middle bin.
+    let weights = Tensor::new(&[[0f32, 1., 0.]], dev)?;
+    let samples = importance_samples(&edges, &weights, 4, true)?.to_vec2::<f32>()?;
+    assert!(
+        samples[0].iter().all(|&t| (1. ..=2.).contains(&t)),
+        "{samples:?}"
+    );
+    assert!(samples[0].windows(2).all(|w| w[0] <= w[1]));
+    let samples = importance_samples(&edges, &weights, 16, false)?.to_vec2::<f32>()?;
+    assert!(samples[0].windows(2).all(|w| w[0] <= w[1]));
+    assert!(samples[0].iter().all(|&t| (0. ..=3.).contains(&t)));
+    let in_bin = samples[0]
+        .iter()
+        .filter(|&&t| (1. ..=2.).contains(&t))
+        .count();
+    assert!(in_bin >= 15, "{samples:?}");
+    // The uniform weights give the evenly spaced quantiles.
+    let weights = Tensor::new(&[[1f32, 1., 1.]], dev)?;
+    let samples = importance_samples(&edges, &weights, 3, true)?.to_vec2::<f32>()?;
+    for (a, b) in samples[0].iter().zip([0.5, 1.5, 2.5]) {
+        assert!((a - b).abs() < 1e-4, "{samples:?}")
+    }
+    Ok(())
+}
@@ -0,0 +1,92 @@
+#[cfg(feature = "mkl")]
+extern crate intel_mkl_src;
+
+#[cfg(feature = "accelerate")]
+extern crate accelerate_src;
+
+use candle::test_utils::max_diff;
+use candle::{DType, Device, Module, Result, Tensor};
+use candle_nn::multi_lora::LoraWeights;
+use candle_nn::weight_delta::{track_weights, WeightDelta, WeightPatcher};
+use candle_nn::{linear, VarBuilder};
+use std::collections::HashMap;
+
+#[test]
+fn weight_delta_apply_remove() -> Result<()> {
+    let dev = &Device::Cpu;
+    let weight = Tensor::randn(0f32, 1., (6, 4), dev)?;
+    let bias = Tensor::randn(0f32, 1., 6, dev)?;
+    let tensors = HashMap::from([
+        ("model.proj.weight".to_string(), weight.copy()?),
+        ("model.proj.bias".to_string(), bias.copy()?),
+    ]);
+    let vb = VarBuilder::from_tensors(tensors, DType::F32, dev);
+    let (vb, weights) = track_weights(vb.pp("model"));
+    let proj = linear(4, 6, vb.pp("proj"))?;
+    let mut patcher = WeightPatcher::new(weights.tensors());
+    assert!(patcher.contains("model.proj.weight"));
+
+    let xs = Tensor::randn(0f32, 1., (3, 4), dev)?;
+    let base = proj.forward(&xs)?;
+    let a = Tensor::randn(0f32, 1., (2, 4), dev)?;
+    let b = Tensor::randn(0f32, 1., (6, 2), dev)?;
+    let lora = LoraWeights::new(a, b, 4.)?;
+    let lora_delta = lora.delta()?;
+    let lora_patch = patcher.apply(&WeightDelta::new().with_lora("model.proj.weight", lora))?;
+    // The module sees the merged weights without being rebuilt.
+    let expected = (&base + xs.matmul(&lora_delta.t()?)?)?;
+    assert!(max_diff(&proj.forward(&xs)?, &expected)? < 1e-4);
+
+    let task_vector = Tensor::randn(0f32, 1., 6, dev)?;
+    let dense = WeightDelta::new()
+        .with_dense("model.proj.bias", task_vector.clone())
+        .with_scale(0.5);
+    patcher.apply(&dense)?;
+    let expected = expected.broadcast_add(&(&task_vector * 0.5)?)?;
+    assert!(max_diff(&proj.forward(&xs)?, &expected)? < 1e-4);
+
+    // Removing the first patch keeps the second one.
+    patcher.remove(lora_patch)?;
+    assert_eq!(patcher.patches().len(), 1);
+    assert_eq!(proj.weight().to_vec2::<f32>()?, weight.to_vec2::<f32>()?);
+    let expected = base.broadcast_add(&(&task_vector * 0.5)?)?;
+    assert!(max_diff(&proj.forward(&xs)?, &expected)? < 1e-4);
+    assert!(patcher.remove(lora_patch).is_err());
+
+    // The original weights are restored exactly.
+    assert!(patcher.rollback()?.is_some());
+    assert!(patcher.rollback()?.is_none());
+    assert_eq!(
+        proj.bias().unwrap().to_vec1::<f32>()?,
+        bias.to_vec1::<f32>()?
+    );
+    Ok(())
+}
+
+#[test]
+fn weight_delta_mismatch() -> Result<()> {
+    let dev = &Device::Cpu;
+    let weight = Tensor::randn(0f32, 1., (6, 4), dev)?;
+    let mut patcher = WeightPatcher::new(HashMap::from([("w".to_string(), vec![weight.clone()])]));
+    let original = weight.to_vec2::<f32>()?;
+    // Nothing is modified when one of the changes is invalid.
+    let delta = WeightDelta::new()
+        .with_dense("w", Tensor::ones((6, 4), DType::F32, dev)?)
+        .with_dense("w", Tensor::ones((4, 6), DType::F32, dev)?);
+    assert!(patcher.apply(&delta).is_err());
+    let delta = WeightDelta::new()
+        .with_dense("w", Tensor::ones((6, 4), DType::F32, dev)?)
+        .with_dense("unknown", Tensor::ones((6, 4), DType::F32, dev)?);
+    assert!(patcher.apply(&delta).is_err());
+    assert_eq!(weight.to_vec2::<f32>()?, original);
+
+    patcher.apply(&WeightDelta::from_dense(HashMap::from([(
+        "w".to_string(),
+        Tensor::ones((6, 4), DType::F32, dev)?,
+    )])))?;
+    let expected = (Tensor::new(original.clone(), dev)? + 1.)?;
+    assert!(max_diff(&weight, &expected)? < 1e-6);
+    patcher.reset()?;
+    assert_eq!(weight.to_vec2::<f32>()?, original);
+    Ok(())
+}
@@ -0,0 +1,105 @@
+#[cfg(feature = "mkl")]
+extern crate intel_mkl_src;
+
+#[cfg(feature = "accelerate")]
+extern crate accelerate_src;
+
+use anyhow::Result;
+use candle::{DType, Device, Tensor};
+use candle_nn::weight_layout::{PackedSafetensors, Transposed, WeightPacker};
+use candle_nn::{Module, VarBuilder};
+use std::collections::HashMap;
+
+// The transposed layout with another version, as done when a kernel changes its layout.
+struct TransposedV2;
+
+impl WeightPacker for TransposedV2 {
+    fn name(&self) -> &str {
+        "transposed"
+    }
+
+    fn version(&self) -> u32 {
+        2
+    }
+
+    fn should_pack(&self, name: &str, shape: &[usize]) -> bool {
+        Transposed::default().should_pack(name, shape)
+    }
+
+    fn pack(&self, tensor: &Tensor) -> candle::Result<Tensor> {
+        Transposed::default().pack(tensor)
+    }
+
+    fn unpack(&self, packed: Tensor) -> candle::Result<Tensor> {
+        Transposed::default().unpack(packed)
+    }
+}
+
+#[test]
+fn packed_weights() -> Result<()> {
+    let dev = &Device::Cpu;
+    let dir = std::env::temp_dir().join(format!("candle-packed-{}", std::process::id()));
+    let cache_dir = dir.join("cache");
+    std::fs::create_dir_all(&dir)?;
+    let model = dir.join("model.safetensors");
+    let mut tensors = HashMap::from([
+        (
+            "linear.weight".to_string(),
+            Tensor::randn(0f32, 1., (3, 4), dev)?,
+        ),
+        ("linear.bias".to_string(), Tensor::randn(0f32, 1., 3, dev)?),
+        (
+            "embed_tokens.weight".to_string(),
+            Tensor::randn(0f32, 1., (5, 4), dev)?,
+        ),
+    ]);
+    candle::safetensors::save(&tensors, &model)?;
+
+    let packer = || Box::new(Transposed::default());
+    let st = unsafe { PackedSafetensors::new(&[&model], packer(), &cache_dir, DType::F32)? };
+    assert_eq!(st.created_cache_files(), 1);
+    assert!(st.is_packed("linear.weight"));
+    assert!(!st.is_packed("linear.bias"));
+    assert!(!st.is_packed("embed_tokens.weight"));
+    let packed = st.load_packed("linear.weight", dev)?;
+    assert_eq!(packed.dims(), &[4, 3]);
+    assert!(packed.is_contiguous());
+    assert!(st.load_packed("linear.bias", dev).is_err());
+    // The second load uses the cache.
+    let st = unsafe { PackedSafetensors::new(&[&model], packer(), &cache_dir, DType::F32)? };
+    assert_eq!(st.created_cache_files(), 0);
+
+    let vb = unsafe {
+        VarBuilder::from_packed_safetensors(&[&model], packer(), &cache_dir, DType::F32, dev)?
+    };
+    let linear = candle_nn::linear(4, 3, vb.pp("linear"))?;
+    assert!(linear.weight().t()?.is_contiguous());
+    let expected = candle_nn::Linear::new(
+        tensors["linear.weight"].clone(),
+        Some(tensors["linear.bias"].clone()),
+    );
+    let xs = Tensor::randn(0f32, 1., (2, 5, 4), dev)?;
+    let diff = (linear.forward(&xs)? - expected.forward(&xs)?)?.abs()?;
+    assert!(diff.max_all()?.to_scalar::<f32>()? < 1e-6);
+    let embed = candle_nn::embedding(5, 4, vb.pp("embed_tokens"))?;
+    assert!(embed.embeddings().is_contiguous());
+
+    // Another dtype, packer version or source file uses a new cache file.
+    let st = unsafe { PackedSafetensors::new(&[&model], packer(), &cache_dir, DType::BF16)? };
+    assert_eq!(st.created_cache_files(), 1);
+    assert_eq!(st.load_packed("linear.weight", dev)?.dtype(), DType::BF16);
+    let v2 = Box::new(TransposedV2);
+    let st = unsafe { PackedSafetensors::new(&[&model], v2, &cache_dir, DType::F32)? };
+    assert_eq!(st.created_cache_files(), 1);
+    tensors.insert(
+        "extra.weight".to_string(),
+        Tensor::zeros((2, 2), DType::F32, dev)?,
+    );
+    candle::safetensors::save(&tensors, &model)?;
+    let st = unsafe { PackedSafetensors::new(&[&model], packer(), &cache_dir, DType::F32)? };
+    assert_eq!(st.created_cache_files(), 1);
+    assert!(st.is_packed("extra.weight"));
+    assert_eq!(st.cache_files().len(), 1);
+    std::fs::remove_dir_all(&dir)?;
+    Ok(())
+}
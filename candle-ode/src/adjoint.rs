@@ -0,0 +1,161 @@
+//! Gradients of ODE solutions with the adjoint method.
+//!
+//! The adjoint `a(t) = dL/dy(t)` follows `da/dt = -a f_y(t, y)` and the gradient of the
+//! parameters is the integral of `a f_theta(t, y)`, see "Neural Ordinary Differential Equations",
+//! Chen et al. 2018. These are solved backward in time together with the state, the vector
+//! jacobian products are computed by backpropagating through a single evaluation of `f`, so the
+//! memory does not depend on the number of steps.
+use crate::solvers::{solve, OdeFunc, OdeOptions};
+use candle::backprop::GradStore;
+use candle::{Result, Shape, Tensor, Var};
+
+/// A solution computed by [`odeint_adjoint`].
+pub struct AdjointSolution<'a, F: OdeFunc + ?Sized> {
+    f: &'a F,
+    y0: Tensor,
+    ts: Vec<f64>,
+    params: Vec<Var>,
+    options: OdeOptions,
+    ys: Var,
+}
+
+/// The gradients of the loss with respect to the initial state and to the parameters.
+#[derive(Debug, Clone)]
+pub struct AdjointGradients {
+    pub y0: Tensor,
+    pub params: Vec<Tensor>,
+}
+
+// The dynamics of the flattened state (y, a, a_theta).
+struct Augmented<'a, F: OdeFunc + ?Sized> {
+    f: &'a F,
+    params: &'a [Var],
+    shape: Shape,
+}
+
+impl<F: OdeFunc + ?Sized> OdeFunc for Augmented<'_, F> {
+    fn eval(&self, t: f64, z: &Tensor) -> Result<Tensor> {
+        let n = self.shape.elem_count();
+        let y = z.narrow(0, 0, n)?.reshape(&self.shape)?;
+        let a = z.narrow(0, n, n)?.reshape(&self.shape)?;
+        let y = Var::from_tensor(&y.detach())?;
+        let dy = self.f.eval(t, &y)?;
+        let grads = (&dy * a.detach())?.sum_all()?.backward()?;
+        let mut dz = vec![dy.detach().flatten_all()?];
+        match grads.get(&y) {
+            Some(g) => dz.push(g.neg()?.flatten_all()?),
+            None => dz.push(a.zeros_like()?.flatten_all()?),
+        }
+        for p in self.params.iter() {
+            match grads.get(p) {
+                Some(g) => dz.push(g.neg()?.flatten_all()?.to_dtype(z.dtype())?),
+                None => dz.push(Tensor::zeros(p.elem_count(), z.dtype(), z.device())?),
+            }
+        }
+        Tensor::cat(&dz, 0)
+    }
+}
+
+/// Solves the ODE like [`crate::odeint`] without tracking the operations of the solver. `params`
+/// are the variables used by `f` that require gradients, these are computed by
+/// [`AdjointSolution::backward`].
+pub fn odeint_adjoint<'a, F: OdeFunc + ?Sized>(
+    f: &'a F,
+    y0: &Tensor,
+    ts: &[f64],
+    params: &[Var],
+    options: &OdeOptions,
+) -> Result<AdjointSolution<'a, F>> {
+    let ys = solve(f, &y0.detach(), ts, options, true)?;
+    Ok(AdjointSolution {
+        f,
+        y0: y0.clone(),
+        ts: ts.to_vec(),
+        params: params.to_vec(),
+        options: *options,
+        ys: Var::from_tensor(&ys.detach())?,
+    })
+}
+
+impl<F: OdeFunc + ?Sized> AdjointSolution<'_, F> {
+    /// The states at the output times, stacked along the first dimension.
+    pub fn ys(&self) -> &Tensor {
+        self.ys.as_tensor()
+    }
+
+    /// Computes the gradients from the gradient of the loss with respect to [`Self::ys`].
+    pub fn adjoint(&self, grad_ys: &Tensor) -> Result<AdjointGradients> {
+        let ys = self.ys.as_tensor();
+        if grad_ys.dims() != ys.dims() {
+            candle::bail!(
+                "ode: the gradient shape {:?} does not match the solution shape {:?}",
+                grad_ys.shape(),
+                ys.shape()
+            )
+        }
+        let shape = self.y0.shape().clone();
+        let n = shape.elem_count();
+        let num_params = self.params.iter().map(|p| p.elem_count()).sum::<usize>();
+        let last = self.ts.len() - 1;
+        let mut a = grad_ys.get(last)?.flatten_all()?;
+        let mut a_params = Tensor::zeros(num_params, ys.dtype(), ys.device())?;
+        let augmented = Augmented {
+            f: self.f,
+            params: &self.params,
+            shape,
+        };
+        for i in (1..=last).rev() {
+            let z = Tensor::cat(&[&ys.get(i)?.flatten_all()?, &a, &a_params], 0)?;
+            let z = solve(
+                &augmented,
+                &z,
+                &[self.ts[i], self.ts[i - 1]],
+                &self.options,
+                true,
+            )?
+            .get(1)?;
+            // The state is taken from the forward solution rather than from the backward solve.
+            a = (z.narrow(0, n, n)? + grad_ys.get(i - 1)?.flatten_all()?)?;
+            a_params = z.narrow(0, 2 * n, num_params)?;
+        }
+        let mut params = Vec::with_capacity(self.params.len());
+        let mut offset = 0;
+        for p in self.params.iter() {
+            let g = a_params
+                .narrow(0, offset, p.elem_count())?
+                .reshape(p.shape())?
+                .to_dtype(p.dtype())?;
+            params.push(g);
+            offset += p.elem_count()
+        }
+        Ok(AdjointGradients {
+            y0: a.reshape(self.y0.shape())?,
+            params,
+        })
+    }
+
+    /// Backpropagates `loss`, a function of [`Self::ys`], and returns the gradients including the
+    /// ones of the parameters and of the initial state computed with the adjoint method. The
+    /// gradient of the initial state is not propagated to the operations that produced it.
+    pub fn backward(&self, loss: &Tensor) -> Result<GradStore> {
+        let mut grads = loss.backward()?;
+        let grad_ys = match grads.remove(self.ys.as_tensor()) {
+            Some(g) => g,
+            None => self.ys.zeros_like()?,
+        };
+        let adjoint = self.adjoint(&grad_ys)?;
+        let mut accumulate = |tensor: &Tensor, g: Tensor| -> Result<()> {
+            let g = match grads.remove(tensor) {
+                Some(prev) => (prev + g)?,
+                None => g,
+            };
+            grads.insert(tensor, g);
+            Ok(())
+        };
+        for (p, g) in self.params.iter().zip(adjoint.params) {
+            accumulate(p, g)?
+        }
+        accumulate(&self.y0, adjoint.y0)?;
+        Ok(grads)
+    }
+}
@@ -0,0 +1,11 @@
+//! Ordinary differential equation solvers for Candle.
+//!
+//! The states are tensors of any shape, e.g. a batch of systems with a leading batch dimension,
+//! and the solutions are differentiable: [`odeint`] can be backpropagated through directly,
+//! whereas [`odeint_adjoint`] solves the adjoint equation backward in time so that the memory
+//! does not grow with the number of steps.
+pub mod adjoint;
+pub mod solvers;
+
+pub use adjoint::{odeint_adjoint, AdjointGradients, AdjointSolution};
+pub use solvers::{odeint, Method, OdeFunc, OdeOptions};
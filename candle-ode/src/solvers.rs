@@ -0,0 +1,344 @@
+//! Fixed step and adaptive step solvers.
+use candle::{DType, Result, Tensor};
+
+/// The right hand side of an ODE `dy/dt = f(t, y)`.
+pub trait OdeFunc {
+    fn eval(&self, t: f64, y: &Tensor) -> Result<Tensor>;
+}
+
+impl<F: Fn(f64, &Tensor) -> Result<Tensor>> OdeFunc for F {
+    fn eval(&self, t: f64, y: &Tensor) -> Result<Tensor> {
+        self(t, y)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Method {
+    Euler,
+    Midpoint,
+    /// The classical fourth order Runge-Kutta method.
+    Rk4,
+    /// The adaptive Dormand-Prince method of order 5(4).
+    Dopri5,
+}
+
+impl Method {
+    pub fn is_adaptive(&self) -> bool {
+        matches!(self, Self::Dopri5)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct OdeOptions {
+    pub method: Method,
+    /// The maximal step size of the fixed step methods. Each interval between two output times
+    /// is split in equal steps, a single step is used when not set.
+    pub step_size: Option<f64>,
+    /// The relative and absolute tolerances of the adaptive methods. The local error is scaled by
+    /// `atol + rtol * |y|` and its root mean square over all the elements of the state has to be
+    /// at most one.
+    pub rtol: f64,
+    pub atol: f64,
+    /// The initial step of the adaptive methods, estimated from the derivatives when not set.
+    pub first_step: Option<f64>,
+    /// The maximal number of steps of the adaptive methods, including the rejected steps.
+    pub max_steps: usize,
+}
+
+impl Default for OdeOptions {
+    fn default() -> Self {
+        Self::new(Method::Dopri5)
+    }
+}
+
+impl OdeOptions {
+    pub fn new(method: Method) -> Self {
+        Self {
+            method,
+            step_size: None,
+            rtol: 1e-7,
+            atol: 1e-9,
+            first_step: None,
+            max_steps: 10_000,
+        }
+    }
+
+    pub fn with_step_size(mut self, step_size: f64) -> Self {
+        self.step_size = Some(step_size);
+        self
+    }
+
+    pub fn with_tolerances(mut self, rtol: f64, atol: f64) -> Self {
+        self.rtol = rtol;
+        self.atol = atol;
+        self
+    }
+
+    pub fn with_first_step(mut self, first_step: f64) -> Self {
+        self.first_step = Some(first_step);
+        self
+    }
+
+    pub fn with_max_steps(mut self, max_steps: usize) -> Self {
+        self.max_steps = max_steps;
+        self
+    }
+}
+
+// y + sum_i c_i k_i
+fn lincomb(y: &Tensor, terms: &[(f64, &Tensor)]) -> Result<Tensor> {
+    let mut acc = y.clone();
+    for &(c, k) in terms.iter() {
+        if c != 0. {
+            acc = (acc + (k * c)?)?
+        }
+    }
+    Ok(acc)
+}
+
+fn rms(x: &Tensor) -> Result<f64> {
+    x.to_dtype(DType::F64)?
+        .sqr()?
+        .mean_all()?
+        .sqrt()?
+        .to_scalar::<f64>()
+}
+
+// The error scaled by the tolerances, using the largest of the two states for the relative part.
+fn scaled_rms(err: &Tensor, y0: &Tensor, y1: &Tensor, options: &OdeOptions) -> Result<f64> {
+    let scale = y0
+        .abs()?
+        .maximum(&y1.abs()?)?
+        .affine(options.rtol, options.atol)?;
+    rms(&(err / scale)?)
+}
+
+fn fixed_step<F: OdeFunc + ?Sized>(
+    f: &F,
+    method: Method,
+    t: f64,
+    y: &Tensor,
+    h: f64,
+) -> Result<Tensor> {
+    match method {
+        Method::Euler => lincomb(y, &[(h, &f.eval(t, y)?)]),
+        Method::Midpoint => {
+            let k1 = f.eval(t, y)?;
+            let k2 = f.eval(t + h / 2., &lincomb(y, &[(h / 2., &k1)])?)?;
+            lincomb(y, &[(h, &k2)])
+        }
+        Method::Rk4 => {
+            let k1 = f.eval(t, y)?;
+            let k2 = f.eval(t + h / 2., &lincomb(y, &[(h / 2., &k1)])?)?;
+            let k3 = f.eval(t + h / 2., &lincomb(y, &[(h / 2., &k2)])?)?;
+            let k4 = f.eval(t + h, &lincomb(y, &[(h, &k3)])?)?;
+            lincomb(
+                y,
+                &[(h / 6., &k1), (h / 3., &k2), (h / 3., &k3), (h / 6., &k4)],
+            )
+        }
+        Method::Dopri5 => candle::bail!("ode: {method:?} is not a fixed step method"),
+    }
+}
+
+const DOPRI5_C: [f64; 6] = [1. / 5., 3. / 10., 4. / 5., 8. / 9., 1., 1.];
+const DOPRI5_A: [&[f64]; 6] = [
+    &[1. / 5.],
+    &[3. / 40., 9. / 40.],
+    &[44. / 45., -56. / 15., 32. / 9.],
+    &[
+        19372. / 6561.,
+        -25360. / 2187.,
+        64448. / 6561.,
+        -212. / 729.,
+    ],
+    &[
+        9017. / 3168.,
+        -355. / 33.,
+        46732. / 5247.,
+        49. / 176.,
+        -5103. / 18656.,
+    ],
+    // The fifth order solution, the last stage is the derivative at the new state.
+    &[
+        35. / 384.,
+        0.,
+        500. / 1113.,
+        125. / 192.,
+        -2187. / 6784.,
+        11. / 84.,
+    ],
+];
+// The difference between the fifth and fourth order weights.
+const DOPRI5_E: [f64; 7] = [
+    35. / 384. - 5179. / 57600.,
+    0.,
+    500. / 1113. - 7571. / 16695.,
+    125. / 192. - 393. / 640.,
+    -2187. / 6784. + 92097. / 339200.,
+    11. / 84. - 187. / 2100.,
+    -1. / 40.,
+];
+
+// Returns the new state, its derivative and the local error estimate.
+fn dopri5_step<F: OdeFunc + ?Sized>(
+    f: &F,
+    t: f64,
+    y: &Tensor,
+    k1: &Tensor,
+    h: f64,
+) -> Result<(Tensor, Tensor, Tensor)> {
+    let mut ks = vec![k1.clone()];
+    let mut y_new = y.clone();
+    for (c, a) in DOPRI5_C.iter().zip(DOPRI5_A.iter()) {
+        let terms = a
+            .iter()
+            .zip(ks.iter())
+            .map(|(a, k)| (h * a, k))
+            .collect::<Vec<_>>();
+        y_new = lincomb(y, &terms)?;
+        ks.push(f.eval(t + c * h, &y_new)?);
+    }
+    let terms = DOPRI5_E
+        .iter()
+        .zip(ks.iter())
+        .map(|(e, k)| (h * e, k))
+        .collect::<Vec<_>>();
+    let err = lincomb(&ks[0].zeros_like()?, &terms)?;
+    let k_new = ks.pop().unwrap();
+    Ok((y_new, k_new, err))
+}
+
+// The initial step from "Solving Ordinary Differential Equations I", Hairer et al., section II.4.
+fn initial_step<F: OdeFunc + ?Sized>(
+    f: &F,
+    t: f64,
+    y: &Tensor,
+    k: &Tensor,
+    direction: f64,
+    options: &OdeOptions,
+) -> Result<f64> {
+    let scale = y.abs()?.affine(options.rtol, options.atol)?;
+    let d0 = rms(&(y / &scale)?)?;
+    let d1 = rms(&(k / &scale)?)?;
+    let h0 = if d0 < 1e-5 || d1 < 1e-5 {
+        1e-6
+    } else {
+        0.01 * d0 / d1
+    };
+    let y1 = lincomb(y, &[(direction * h0, k)])?;
+    let k1 = f.eval(t + direction * h0, &y1)?;
+    let d2 = rms(&((k1 - k)? / &scale)?)? / h0;
+    let h1 = if d1.max(d2) <= 1e-15 {
+        (h0 * 1e-3).max(1e-6)
+    } else {
+        (0.01 / d1.max(d2)).powf(1. / 5.)
+    };
+    Ok(direction * (100. * h0).min(h1))
+}
+
+pub(crate) fn solve<F: OdeFunc + ?Sized>(
+    f: &F,
+    y0: &Tensor,
+    ts: &[f64],
+    options: &OdeOptions,
+    detach: bool,
+) -> Result<Tensor> {
+    if ts.is_empty() {
+        candle::bail!("ode: no output times")
+    }
+    if ts.iter().any(|t| !t.is_finite()) {
+        candle::bail!("ode: the output times have to be finite {ts:?}")
+    }
+    let direction = if ts.len() > 1 && ts[1] < ts[0] {
+        -1.
+    } else {
+        1.
+    };
+    if ts.windows(2).any(|w| (w[1] - w[0]) * direction <= 0.) {
+        candle::bail!("ode: the output times have to be strictly monotonic {ts:?}")
+    }
+    let detach = |y: Tensor| if detach { y.detach() } else { y };
+    let mut ys = Vec::with_capacity(ts.len());
+    ys.push(y0.clone());
+    let mut y = y0.clone();
+    if !options.method.is_adaptive() {
+        for w in ts.windows(2) {
+            let dt = w[1] - w[0];
+            let num_steps = match options.step_size {
+                Some(step_size) if step_size > 0. => {
+                    ((dt.abs() / step_size).ceil() as usize).max(1)
+                }
+                Some(step_size) => candle::bail!("ode: invalid step size {step_size}"),
+                None => 1,
+            };
+            let h = dt / num_steps as f64;
+            for i in 0..num_steps {
+                y = detach(fixed_step(f, options.method, w[0] + i as f64 * h, &y, h)?)
+            }
+            ys.push(y.clone())
+        }
+        return Tensor::stack(&ys, 0);
+    }
+
+    let mut t = ts[0];
+    let mut k = f.eval(t, &y)?;
+    let mut h = match options.first_step {
+        Some(h) => h.abs() * direction,
+        None => initial_step(f, t, &y, &k, direction, options)?,
+    };
+    let mut num_steps = 0;
+    for &t_end in ts[1..].iter() {
+        while (t_end - t) * direction > 0. {
+            if num_steps >= options.max_steps {
+                candle::bail!("ode: reached the maximal number of steps {num_steps} at t={t}")
+            }
+            num_steps += 1;
+            // The steps are shortened so that the output times are reached exactly.
+            let clamped = (t + h - t_end) * direction > 0.;
+            let step = if clamped { t_end - t } else { h };
+            if step.abs() <= f64::EPSILON * t.abs().max(1.) {
+                candle::bail!("ode: the step size {step} is too small at t={t}")
+            }
+            let (y_new, k_new, err) = dopri5_step(f, t, &y, &k, step)?;
+            let err = scaled_rms(&err, &y, &y_new, options)?;
+            let accepted = err <= 1.;
+            let factor = if err == 0. {
+                10.
+            } else if err.is_finite() {
+                (0.9 * err.powf(-0.2)).clamp(0.2, 10.)
+            } else {
+                0.2
+            };
+            if accepted {
+                t = if clamped { t_end } else { t + step };
+                y = detach(y_new);
+                k = detach(k_new);
+                // A shortened step does not change the proposed step size.
+                if !clamped {
+                    h = step * factor
+                }
+            } else {
+                h = step * factor.min(1.)
+            }
+        }
+        ys.push(y.clone())
+    }
+    Tensor::stack(&ys, 0)
+}
+
+/// Solves `dy/dt = f(t, y)` with `y(ts[0]) = y0` and returns the states at the times `ts`,
+/// stacked along a new first dimension. The times have to be strictly increasing or strictly
+/// decreasing, decreasing times integrate backward in time.
+///
+/// The solution is computed with differentiable tensor operations so the gradients can be
+/// obtained by backpropagating through all the steps, see [`crate::odeint_adjoint`] for a
+/// constant memory alternative.
+pub fn odeint<F: OdeFunc + ?Sized>(
+    f: &F,
+    y0: &Tensor,
+    ts: &[f64],
+    options: &OdeOptions,
+) -> Result<Tensor> {
+    solve(f, y0, ts, options, false)
+}
@@ -0,0 +1,198 @@
+#[cfg(feature = "mkl")]
+extern crate intel_mkl_src;
+
+#[cfg(feature = "accelerate")]
+extern crate accelerate_src;
+
+use candle::test_utils::max_diff;
+use candle::{DType, Device, IndexOp, Result, Tensor, Var};
+use candle_nn::optim::{AdamW, Optimizer, ParamsAdamW};
+use candle_ode::{odeint, odeint_adjoint, Method, OdeOptions};
+
+// Deterministic pseudo random values in [-1, 1].
+fn values(shape: &[usize], offset: f64, dev: &Device) -> Result<Tensor> {
+    let n = shape.iter().product::<usize>();
+    Tensor::arange(0u32, n as u32, dev)?
+        .to_dtype(DType::F64)?
+        .affine(12.9898, offset)?
+        .sin()?
+        .reshape(shape)
+}
+
+#[test]
+fn exponential_decay() -> Result<()> {
+    let dev = &Device::Cpu;
+    // A batch of three systems with different rates.
+    let rates = Tensor::new(&[[0.5f64], [1.], [2.]], dev)?;
+    let y0 = Tensor::new(&[[1f64, 2.], [1., -1.], [3., 0.5]], dev)?;
+    let f = |_t: f64, y: &Tensor| y.broadcast_mul(&rates.neg()?);
+    let ts = [0., 0.5, 1., 2.];
+    let expected = ts
+        .iter()
+        .map(|&t| y0.broadcast_mul(&(&rates * -t)?.exp()?))
+        .collect::<Result<Vec<_>>>()?;
+    let expected = Tensor::stack(&expected, 0)?;
+    for (options, tol) in [
+        (OdeOptions::new(Method::Euler).with_step_size(1e-3), 1e-2),
+        (OdeOptions::new(Method::Midpoint).with_step_size(1e-2), 1e-4),
+        (OdeOptions::new(Method::Rk4).with_step_size(1e-2), 1e-8),
+        (OdeOptions::new(Method::Dopri5), 1e-6),
+        (
+            OdeOptions::new(Method::Dopri5).with_tolerances(1e-3, 1e-6),
+            1e-2,
+        ),
+    ] {
+        let ys = odeint(&f, &y0, &ts, &options)?;
+        assert_eq!(ys.dims(), [4, 3, 2]);
+        let diff = max_diff(&ys, &expected)?;
+        assert!(diff < tol, "{options:?} {diff}");
+    }
+    // A single rk4 step per interval is less accurate.
+    let ys = odeint(&f, &y0, &ts, &OdeOptions::new(Method::Rk4))?;
+    assert!(max_diff(&ys, &expected)? > 1e-4);
+    Ok(())
+}
+
+#[test]
+fn time_dependent_and_backward() -> Result<()> {
+    let dev = &Device::Cpu;
+    // dy/dt = cos(t), y = sin(t).
+    let f = |t: f64, y: &Tensor| y.ones_like()? * t.cos();
+    let y0 = Tensor::zeros(2, DType::F64, dev)?;
+    let ts = [0., 1., 2., 3.];
+    let ys = odeint(&f, &y0, &ts, &OdeOptions::default())?;
+    for (i, t) in ts.iter().enumerate() {
+        let y = ys.i(i)?.to_vec1::<f64>()?;
+        assert!((y[0] - t.sin()).abs() < 1e-7, "{t} {y:?}")
+    }
+
+    // The harmonic oscillator integrated forward then backward goes back to the initial state.
+    let f = |_t: f64, y: &Tensor| Tensor::cat(&[y.i((.., 1..))?, y.i((.., ..1))?.neg()?], 1);
+    let y0 = Tensor::new(&[[1f64, 0.], [0., 2.]], dev)?;
+    let options = OdeOptions::new(Method::Dopri5).with_tolerances(1e-9, 1e-11);
+    let ys = odeint(&f, &y0, &[0., 5.], &options)?;
+    let expected = Tensor::new(
+        &[
+            [5f64.cos(), -5f64.sin()],
+            [2. * 5f64.sin(), 2. * 5f64.cos()],
+        ],
+        dev,
+    )?;
+    assert!(max_diff(&ys.i(1)?, &expected)? < 1e-7);
+    let back = odeint(&f, &ys.i(1)?, &[5., 0.], &options)?;
+    assert!(max_diff(&back.i(1)?, &y0)? < 1e-7);
+    Ok(())
+}
+
+#[test]
+fn invalid_inputs() -> Result<()> {
+    let dev = &Device::Cpu;
+    let f = |_t: f64, y: &Tensor| y.sqr();
+    let y0 = Tensor::new(&[1f64], dev)?;
+    let options = OdeOptions::default();
+    // A single output time returns the initial state.
+    let ys = odeint(&f, &y0, &[0.], &options)?;
+    assert_eq!(ys.to_vec2::<f64>()?, [[1.]]);
+    for ts in [&[][..], &[0., 1., 1.], &[0., 1., 0.5], &[0., f64::NAN]] {
+        assert!(odeint(&f, &y0, ts, &options).is_err(), "{ts:?}")
+    }
+    // The solution y = 1 / (1 - t) blows up at t = 1.
+    let err = odeint(&f, &y0, &[0., 2.], &options.with_max_steps(200)).unwrap_err();
+    assert!(err.to_string().contains("ode:"), "{err}");
+    Ok(())
+}
+
+#[test]
+fn adjoint_gradients() -> Result<()> {
+    let dev = &Device::Cpu;
+    // dy/dt = theta * y, y(t) = y0 exp(theta t).
+    let theta = Var::new(&[-0.5f64, 0.3], dev)?;
+    let y0 = Var::new(&[1f64, 2.], dev)?;
+    let f = |_t: f64, y: &Tensor| y * theta.as_tensor();
+    let ts = [0., 0.5, 1.5];
+    let weights = Tensor::new(&[[1f64, 0.5], [-1., 2.], [0.5, 1.]], dev)?;
+    let options = OdeOptions::new(Method::Dopri5).with_tolerances(1e-9, 1e-11);
+    let solution = odeint_adjoint(&f, &y0, &ts, std::slice::from_ref(&theta), &options)?;
+    let loss = (solution.ys() * &weights)?.sum_all()?;
+    let grads = solution.backward(&loss)?;
+
+    let (th, y) = (theta.to_vec1::<f64>()?, y0.to_vec1::<f64>()?);
+    let w = weights.to_vec2::<f64>()?;
+    for j in 0..2 {
+        let mut grad_theta = 0.;
+        let mut grad_y0 = 0.;
+        for (i, t) in ts.iter().enumerate() {
+            grad_theta += w[i][j] * y[j] * t * (th[j] * t).exp();
+            grad_y0 += w[i][j] * (th[j] * t).exp();
+        }
+        let g = grads.get(&theta).unwrap().to_vec1::<f64>()?;
+        assert!((g[j] - grad_theta).abs() < 1e-6, "{g:?} {grad_theta}");
+        let g = grads.get(&y0).unwrap().to_vec1::<f64>()?;
+        assert!((g[j] - grad_y0).abs() < 1e-6, "{g:?} {grad_y0}");
+    }
+    Ok(())
+}
+
+#[test]
+fn adjoint_matches_backprop() -> Result<()> {
+    let dev = &Device::Cpu;
+    // A small neural ODE on a batch of states.
+    let w = Var::from_tensor(&values(&[3, 3], 0., dev)?)?;
+    let b = Var::from_tensor(&(values(&[3], 1., dev)? * 0.1)?)?;
+    let f = |t: f64, y: &Tensor| {
+        y.matmul(w.as_tensor())?
+            .broadcast_add(b.as_tensor())?
+            .tanh()?
+            * (1. + t)
+    };
+    let y0 = Var::from_tensor(&values(&[4, 3], 2., dev)?)?;
+    let targets = values(&[3, 4, 3], 3., dev)?;
+    let ts = [0., 0.4, 1.];
+    let params = [w.clone(), b.clone()];
+    for options in [
+        OdeOptions::new(Method::Rk4).with_step_size(1e-2),
+        OdeOptions::new(Method::Dopri5).with_tolerances(1e-8, 1e-10),
+    ] {
+        let ys = odeint(&f, &y0, &ts, &options)?;
+        let loss = (&ys - &targets)?.sqr()?.sum_all()?;
+        let expected = loss.backward()?;
+
+        let solution = odeint_adjoint(&f, &y0, &ts, &params, &options)?;
+        assert!(max_diff(solution.ys(), &ys)? < 1e-12);
+        let loss = (solution.ys() - &targets)?.sqr()?.sum_all()?;
+        let grads = solution.backward(&loss)?;
+        for var in [&w, &b, &y0] {
+            let g = grads.get(var).unwrap();
+            let e = expected.get(var).unwrap();
+            let diff = max_diff(g, e)?;
+            assert!(diff < 1e-5, "{options:?} {diff}");
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn fit_with_adjoint() -> Result<()> {
+    let dev = &Device::Cpu;
+    // Recover the decay rate from observations.
+    let ts = [0f64, 0.5, 1., 1.5];
+    let y0 = Tensor::new(&[2f64], dev)?;
+    let targets = Tensor::new(&ts.map(|t| 2. * (-0.8 * t).exp()), dev)?.unsqueeze(1)?;
+    let rate = Var::new(&[0.1f64], dev)?;
+    let f = |_t: f64, y: &Tensor| (y * rate.as_tensor())?.neg();
+    let options = OdeOptions::new(Method::Dopri5).with_tolerances(1e-6, 1e-8);
+    let params = ParamsAdamW {
+        lr: 0.05,
+        weight_decay: 0.,
+        ..Default::default()
+    };
+    let mut optimizer = AdamW::new(vec![rate.clone()], params)?;
+    for _ in 0..300 {
+        let solution = odeint_adjoint(&f, &y0, &ts, std::slice::from_ref(&rate), &options)?;
+        let loss = (solution.ys() - &targets)?.sqr()?.mean_all()?;
+        optimizer.step(&solution.backward(&loss)?)?;
+    }
+    let rate = rate.to_vec1::<f64>()?[0];
+    assert!((rate - 0.8).abs() < 1e-3, "{rate}");
+    Ok(())
+}
@@ -13,6 +13,15 @@ def cat(tensors: List[Tensor], dim: int) -> Tensor:
     """
     pass
 
+@staticmethod
+def from_dlpack(data: Any) -> Tensor:
+    """
+    Creates a tensor from an object supporting the DLPack protocol, e.g. a pytorch tensor or a
+    numpy array, or from a DLPack capsule. The data is shared with the producer rather than copied,
+    cpu tensors get copied before being modified in place.
+    """
+    pass
+
 class f16(DType):
     pass
 
@@ -130,6 +139,19 @@ class Tensor:
         """
         pass
 
+    def __dlpack__(self, stream: Optional[int] = None) -> Any:
+        """
+        Exports the tensor as a DLPack capsule sharing its data, this is used by the `from_dlpack`
+        functions of other frameworks.
+        """
+        pass
+
+    def __dlpack_device__(self) -> Tuple[int, int]:
+        """
+        Returns the DLPack device type and id of the tensor.
+        """
+        pass
+
     def __eq__(self, rhs: Union[Tensor, Scalar]) -> "Tensor":
         """
         Compare a tensor with a scalar or one tensor with another.
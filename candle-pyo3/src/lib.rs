@@ -16,6 +16,7 @@ extern crate intel_mkl_src;
 #[cfg(feature = "accelerate")]
 extern crate accelerate_src;
 
+use ::candle::dlpack::{DLDevice, DLManagedTensor, DL_CUDA};
 use ::candle::{quantized::QTensor, DType, Device, Module, Tensor, WithDType};
 
 mod utils;
@@ -69,6 +70,21 @@ impl PyDType {
     }
 }
 
+// The capsule names of the DLPack protocol, consumers rename the capsules they take ownership of.
+const DLTENSOR_NAME: &[u8] = b"dltensor\0";
+const USED_DLTENSOR_NAME: &[u8] = b"used_dltensor\0";
+
+unsafe extern "C" fn dlpack_capsule_destructor(capsule: *mut pyo3::ffi::PyObject) {
+    let name = DLTENSOR_NAME.as_ptr() as *const std::ffi::c_char;
+    // Only release the tensor if it has not been consumed.
+    if pyo3::ffi::PyCapsule_IsValid(capsule, name) == 1 {
+        let managed = pyo3::ffi::PyCapsule_GetPointer(capsule, name) as *mut DLManagedTensor;
+        if let Some(deleter) = (*managed).deleter {
+            deleter(managed)
+        }
+    }
+}
+
 static CUDA_DEVICE: std::sync::Mutex<Option<Device>> = std::sync::Mutex::new(None);
 static METAL_DEVICE: std::sync::Mutex<Option<Device>> = std::sync::Mutex::new(None);
 
@@ -1057,6 +1073,38 @@ impl PyTensor {
         Ok(PyTensor(result))
     }
 
+    #[pyo3(signature = (stream=None), text_signature = "(self, stream:Optional[int]=None)")]
+    /// Exports the tensor as a DLPack capsule sharing its data, this is used by the `from_dlpack`
+    /// functions of other frameworks.
+    /// &RETURNS&: Any
+    fn __dlpack__(&self, py: Python<'_>, stream: Option<i64>) -> PyResult<PyObject> {
+        // The candle kernels run asynchronously, the consumer stream waits for them to complete.
+        unsafe { ::candle::dlpack::wait_for_device(self.0.device(), stream) }.map_err(wrap_err)?;
+        let managed = self.0.to_dlpack().map_err(wrap_err)?;
+        let name = DLTENSOR_NAME.as_ptr() as *const std::ffi::c_char;
+        let capsule = unsafe {
+            pyo3::ffi::PyCapsule_New(
+                managed as *mut std::ffi::c_void,
+                name,
+                Some(dlpack_capsule_destructor),
+            )
+        };
+        if capsule.is_null() {
+            if let Some(deleter) = unsafe { (*managed).deleter } {
+                unsafe { deleter(managed) }
+            }
+            return Err(PyErr::fetch(py));
+        }
+        Ok(unsafe { PyObject::from_owned_ptr(py, capsule) })
+    }
+
+    /// Returns the DLPack device type and id of the tensor.
+    /// &RETURNS&: Tuple[int, int]
+    fn __dlpack_device__(&self) -> (i32, i32) {
+        let device = DLDevice::from_location(self.0.device().location());
+        (device.device_type, device.device_id)
+    }
+
     #[pyo3(text_signature = "(self, dtype:Union[str,DType])")]
     /// Convert the tensor to a new dtype.
     /// &RETURNS&: Tensor
@@ -1091,6 +1139,9 @@ impl PyTensor {
             "q8_0" => quantized::QTensor::quantize(self, quantized::GgmlDType::Q8_0),
             "q8_1" => quantized::QTensor::quantize(self, quantized::GgmlDType::Q8_1),
             "q8k" => quantized::QTensor::quantize(self, quantized::GgmlDType::Q8K),
+            "iq2xxs" => quantized::QTensor::quantize(self, quantized::GgmlDType::Iq2Xxs),
+            "iq4nl" => quantized::QTensor::quantize(self, quantized::GgmlDType::Iq4Nl),
+            "nf4" => quantized::QTensor::quantize(self, quantized::GgmlDType::Nf4),
             "f16" => quantized::QTensor::quantize(self, quantized::GgmlDType::F16),
             "f32" => quantized::QTensor::quantize(self, quantized::GgmlDType::F32),
             dt => {
@@ -1127,6 +1178,38 @@ fn stack(tensors: Vec<PyTensor>, dim: usize) -> PyResult<PyTensor> {
     Ok(PyTensor(tensor))
 }
 
+#[pyfunction]
+#[pyo3(text_signature = "(data:Any)")]
+/// Creates a tensor from an object supporting the DLPack protocol, e.g. a pytorch tensor or a
+/// numpy array, or from a DLPack capsule. The data is shared with the producer rather than copied,
+/// cpu tensors get copied before being modified in place.
+/// &RETURNS&: Tensor
+fn from_dlpack(py: Python<'_>, data: PyObject) -> PyResult<PyTensor> {
+    let data = data.bind(py);
+    let capsule = if data.hasattr("__dlpack__")? {
+        data.call_method0("__dlpack__")?
+    } else {
+        data.clone()
+    };
+    let name = DLTENSOR_NAME.as_ptr() as *const std::ffi::c_char;
+    let managed =
+        unsafe { pyo3::ffi::PyCapsule_GetPointer(capsule.as_ptr(), name) } as *mut DLManagedTensor;
+    if managed.is_null() {
+        return Err(PyErr::fetch(py));
+    }
+    // Mark the capsule as consumed, the tensor gets released by `Tensor::from_dlpack`.
+    let used_name = USED_DLTENSOR_NAME.as_ptr() as *const std::ffi::c_char;
+    if unsafe { pyo3::ffi::PyCapsule_SetName(capsule.as_ptr(), used_name) } != 0 {
+        return Err(PyErr::fetch(py));
+    }
+    let device = match unsafe { (*managed).dl_tensor.device.device_type } {
+        DL_CUDA => PyDevice::Cuda.as_device()?,
+        _ => Device::Cpu,
+    };
+    let tensor = unsafe { Tensor::from_dlpack(managed, &device) }.map_err(wrap_err)?;
+    Ok(PyTensor(tensor))
+}
+
 #[pyfunction]
 #[pyo3(text_signature = "(data:_ArrayLike)")]
 /// Creates a new tensor from a Python value. The value can be a scalar or array-like object.
@@ -1622,6 +1705,7 @@ fn candle(py: Python<'_>, m: &Bound<'_, PyModule>) -> PyResult<()> {
     m.add("f32", PyDType(DType::F32))?;
     m.add("f64", PyDType(DType::F64))?;
     m.add_function(wrap_pyfunction!(cat, m)?)?;
+    m.add_function(wrap_pyfunction!(from_dlpack, m)?)?;
     m.add_function(wrap_pyfunction!(ones, m)?)?;
     m.add_function(wrap_pyfunction!(rand, m)?)?;
     m.add_function(wrap_pyfunction!(randn, m)?)?;
@@ -321,3 +321,14 @@ def test_tensor_can_be_divided():
         d = candle.rand((3, 4, 5))
         e = candle.rand((4, 6))
         f = d / e
+
+
+def test_tensor_dlpack_round_trip():
+    np = pytest.importorskip("numpy")
+    t = Tensor([[3.0, 1, 4], [1, 5, 9]])
+    a = np.from_dlpack(t)
+    assert a.shape == (2, 3)
+    assert a.tolist() == t.values()
+    t2 = candle.from_dlpack(a.T)
+    assert t2.shape == (3, 2)
+    assert t2.values() == t.t().values()
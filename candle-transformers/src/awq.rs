@@ -0,0 +1,421 @@
+//! Linear layers for AWQ quantized checkpoints.
+//!
+//! AWQ checkpoints store the weights of each linear layer as 4-bit values with one zero point and
+//! one scale per group of input features:
+//! - `qweight` has shape `(in_dim, out_dim / 8)`, each u32 packs 8 values of consecutive output
+//!   features using the AWQ order.
+//! - `qzeros` has shape `(in_dim / group_size, out_dim / 8)` with the same packing.
+//! - `scales` has shape `(in_dim / group_size, out_dim)`.
+//!
+//! The weights are dequantized as `(q - zero) * scale`. On cuda, inputs with a small number of
+//! rows as used when decoding use a fused kernel that never materializes the dequantized weights.
+//! Models can use [`AwqLinear`] in place of [`candle_nn::Linear`], the checkpoint `config.json`
+//! file describes the quantization in its `quantization_config` field, see
+//! [`crate::config::QuantizationConfig::awq`].
+use candle::{CpuStorage, DType, Layout, Module, Result, Shape, Tensor, WithDType};
+use candle_nn::VarBuilder;
+
+// The position of the k-th output feature of a pack of 8 in the packed u32.
+const AWQ_NIBBLE: [usize; 8] = [0, 4, 1, 5, 2, 6, 3, 7];
+
+// On cuda, inputs with at most this number of rows use the fused kernel, larger ones are faster
+// when dequantizing the weights and using a regular matmul.
+const FUSED_MAX_ROWS: usize = 8;
+
+/// The parameters of AWQ checkpoints, see [`crate::config::QuantizationConfig::awq`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AwqConfig {
+    pub bits: usize,
+    pub group_size: usize,
+    pub zero_point: bool,
+    pub version: String,
+}
+
+impl AwqConfig {
+    /// Checks that the checkpoint uses the only variant supported here, 4 bits with zero points
+    /// and the gemm packing.
+    pub fn check(&self) -> Result<()> {
+        if self.bits != 4 {
+            candle::bail!(
+                "only 4 bits AWQ checkpoints are supported, got {}",
+                self.bits
+            )
+        }
+        if !self.zero_point {
+            candle::bail!("AWQ checkpoints without zero points are not supported")
+        }
+        if !self.version.eq_ignore_ascii_case("gemm") {
+            candle::bail!(
+                "unsupported AWQ version {}, only gemm is supported",
+                self.version
+            )
+        }
+        Ok(())
+    }
+}
+
+fn dequantize_slice<T: WithDType>(
+    qweight: &[u32],
+    qzeros: &[u32],
+    scales: &[T],
+    out_dim: usize,
+    group_size: usize,
+) -> Vec<T> {
+    use rayon::prelude::*;
+
+    let packed_out = out_dim / 8;
+    let mut dst = vec![T::zero(); qweight.len() * 8];
+    dst.par_chunks_mut(out_dim)
+        .enumerate()
+        .for_each(|(row, dst)| {
+            let group = row / group_size;
+            let qweight = &qweight[row * packed_out..(row + 1) * packed_out];
+            let qzeros = &qzeros[group * packed_out..(group + 1) * packed_out];
+            let scales = &scales[group * out_dim..(group + 1) * out_dim];
+            for (col, (&q, &z)) in qweight.iter().zip(qzeros.iter()).enumerate() {
+                for (k, nibble) in AWQ_NIBBLE.iter().enumerate() {
+                    let shift = nibble * 4;
+                    let w = ((q >> shift) & 0xF) as f64 - ((z >> shift) & 0xF) as f64;
+                    let o = col * 8 + k;
+                    dst[o] = T::from_f64(w * scales[o].to_f64())
+                }
+            }
+        });
+    dst
+}
+
+// Returns `(in_dim, out_dim, group_size)` after checking the shapes of the packed tensors.
+fn awq_dims(qweight: &Layout, qzeros: &Layout, scales: &Layout) -> Result<(usize, usize, usize)> {
+    let (in_dim, packed_out) = qweight.shape().dims2()?;
+    let (n_groups, out_dim) = scales.shape().dims2()?;
+    if out_dim != packed_out * 8 || qzeros.shape().dims2()? != (n_groups, packed_out) {
+        candle::bail!(
+            "awq shape mismatch, qweight {:?}, qzeros {:?}, scales {:?}",
+            qweight.shape(),
+            qzeros.shape(),
+            scales.shape()
+        )
+    }
+    if n_groups == 0 || in_dim % n_groups != 0 {
+        candle::bail!("awq, {in_dim} input features cannot be split in {n_groups} groups")
+    }
+    Ok((in_dim, out_dim, in_dim / n_groups))
+}
+
+struct AwqDequantize;
+
+impl candle::CustomOp3 for AwqDequantize {
+    fn name(&self) -> &'static str {
+        "awq-dequantize"
+    }
+
+    fn cpu_fwd(
+        &self,
+        s1: &CpuStorage,
+        l1: &Layout,
+        s2: &CpuStorage,
+        l2: &Layout,
+        s3: &CpuStorage,
+        l3: &Layout,
+    ) -> Result<(CpuStorage, Shape)> {
+        use candle::backend::BackendStorage;
+
+        fn contiguous<'a, T: WithDType>(s: &'a CpuStorage, l: &Layout) -> Result<&'a [T]> {
+            match l.contiguous_offsets() {
+                None => candle::bail!("awq-dequantize, inputs have to be contiguous"),
+                Some((o1, o2)) => Ok(&s.as_slice::<T>()?[o1..o2]),
+            }
+        }
+        let (in_dim, out_dim, group_size) = awq_dims(l1, l2, l3)?;
+        let qweight = contiguous::<u32>(s1, l1)?;
+        let qzeros = contiguous::<u32>(s2, l2)?;
+        let dst = match s3 {
+            CpuStorage::F16(_) => {
+                let scales = contiguous(s3, l3)?;
+                CpuStorage::F16(dequantize_slice(
+                    qweight, qzeros, scales, out_dim, group_size,
+                ))
+            }
+            CpuStorage::BF16(_) => {
+                let scales = contiguous(s3, l3)?;
+                CpuStorage::BF16(dequantize_slice(
+                    qweight, qzeros, scales, out_dim, group_size,
+                ))
+            }
+            CpuStorage::F32(_) => {
+                let scales = contiguous(s3, l3)?;
+                CpuStorage::F32(dequantize_slice(
+                    qweight, qzeros, scales, out_dim, group_size,
+                ))
+            }
+            s => candle::bail!(
+                "awq-dequantize, unsupported dtype for scales {:?}",
+                s.dtype()
+            ),
+        };
+        Ok((dst, Shape::from((in_dim, out_dim))))
+    }
+
+    #[cfg(feature = "cuda")]
+    fn cuda_fwd(
+        &self,
+        s1: &candle::CudaStorage,
+        l1: &Layout,
+        s2: &candle::CudaStorage,
+        l2: &Layout,
+        s3: &candle::CudaStorage,
+        l3: &Layout,
+    ) -> Result<(candle::CudaStorage, Shape)> {
+        use candle::backend::BackendStorage;
+        use candle::cuda_backend::cudarc::driver::{DeviceRepr, LaunchAsync, LaunchConfig};
+        use candle::cuda_backend::{kernel_name, kernels, CudaDType, CudaStorageSlice, WrapErr};
+        use candle::CudaDevice;
+
+        fn launch<T: CudaDType + DeviceRepr + WithDType>(
+            dev: &CudaDevice,
+            (s1, l1): (&candle::CudaStorage, &Layout),
+            (s2, l2): (&candle::CudaStorage, &Layout),
+            (s3, l3): (&candle::CudaStorage, &Layout),
+            (in_dim, out_dim, group_size): (usize, usize, usize),
+        ) -> Result<candle::cuda_backend::cudarc::driver::CudaSlice<T>> {
+            let qweight = cuda_contiguous(s1.as_cuda_slice::<u32>()?, l1)?;
+            let qzeros = cuda_contiguous(s2.as_cuda_slice::<u32>()?, l2)?;
+            let scales = cuda_contiguous(s3.as_cuda_slice::<T>()?, l3)?;
+            let n_packed = in_dim * out_dim / 8;
+            let cfg = LaunchConfig::for_num_elems(n_packed as u32);
+            let func = dev.get_or_load_func(&kernel_name::<T>("awq_dequantize"), kernels::AWQ)?;
+            // SAFETY: Set later by running the kernel.
+            let dst = unsafe { dev.alloc::<T>(in_dim * out_dim) }.w()?;
+            let params = (
+                &qweight, &qzeros, &scales, &dst, in_dim, out_dim, group_size,
+            );
+            // SAFETY: ffi.
+            unsafe { func.launch(cfg, params) }.w()?;
+            Ok(dst)
+        }
+
+        let dims = awq_dims(l1, l2, l3)?;
+        let dev = s1.device().clone();
+        let args = ((s1, l1), (s2, l2), (s3, l3));
+        let slice = match s3.dtype() {
+            DType::F16 => CudaStorageSlice::F16(launch(&dev, args.0, args.1, args.2, dims)?),
+            DType::BF16 => CudaStorageSlice::BF16(launch(&dev, args.0, args.1, args.2, dims)?),
+            DType::F32 => CudaStorageSlice::F32(launch(&dev, args.0, args.1, args.2, dims)?),
+            dtype => candle::bail!("awq-dequantize, unsupported dtype for scales {dtype:?}"),
+        };
+        let dst = candle::cuda_backend::CudaStorage { slice, device: dev };
+        Ok((dst, Shape::from((dims.0, dims.1))))
+    }
+}
+
+#[cfg(feature = "cuda")]
+pub(crate) fn cuda_contiguous<'a, T>(
+    slice: &'a candle::cuda_backend::cudarc::driver::CudaSlice<T>,
+    layout: &Layout,
+) -> Result<candle::cuda_backend::cudarc::driver::CudaView<'a, T>> {
+    match layout.contiguous_offsets() {
+        None => candle::bail!("awq, inputs have to be contiguous"),
+        Some((o1, o2)) => Ok(slice.slice(o1..o2)),
+    }
+}
+
+// Fused dequantization and matmul, the inputs are the `(n_rows, in_dim)` activations and the
+// packed weights. This is only implemented on cuda.
+#[cfg_attr(not(feature = "cuda"), allow(dead_code))]
+struct AwqMatmul {
+    qzeros: Tensor,
+    scales: Tensor,
+}
+
+impl candle::CustomOp2 for AwqMatmul {
+    fn name(&self) -> &'static str {
+        "awq-matmul"
+    }
+
+    fn cpu_fwd(
+        &self,
+        _: &CpuStorage,
+        _: &Layout,
+        _: &CpuStorage,
+        _: &Layout,
+    ) -> Result<(CpuStorage, Shape)> {
+        candle::bail!("awq-matmul is only implemented on cuda")
+    }
+
+    #[cfg(feature = "cuda")]
+    fn cuda_fwd(
+        &self,
+        s1: &candle::CudaStorage,
+        l1: &Layout,
+        s2: &candle::CudaStorage,
+        l2: &Layout,
+    ) -> Result<(candle::CudaStorage, Shape)> {
+        use candle::backend::BackendStorage;
+        use candle::cuda_backend::cudarc::driver::{DeviceRepr, LaunchAsync, LaunchConfig};
+        use candle::cuda_backend::{kernel_name, kernels, CudaDType, CudaStorageSlice, WrapErr};
+        use candle::{CudaDevice, Storage};
+
+        fn launch<T: CudaDType + DeviceRepr + WithDType>(
+            dev: &CudaDevice,
+            (xs, xs_l): (&candle::CudaStorage, &Layout),
+            (s2, l2): (&candle::CudaStorage, &Layout),
+            (s3, l3): (&candle::CudaStorage, &Layout),
+            (s4, l4): (&candle::CudaStorage, &Layout),
+            (n_rows, in_dim, out_dim, group_size): (usize, usize, usize, usize),
+        ) -> Result<candle::cuda_backend::cudarc::driver::CudaSlice<T>> {
+            let xs = cuda_contiguous(xs.as_cuda_slice::<T>()?, xs_l)?;
+            let qweight = cuda_contiguous(s2.as_cuda_slice::<u32>()?, l2)?;
+            let qzeros = cuda_contiguous(s3.as_cuda_slice::<u32>()?, l3)?;
+            let scales = cuda_contiguous(s4.as_cuda_slice::<T>()?, l4)?;
+            let packed_out = out_dim / 8;
+            let block = 128;
+            let cfg = LaunchConfig {
+                grid_dim: (packed_out.div_ceil(block) as u32, n_rows as u32, 1),
+                block_dim: (block as u32, 1, 1),
+                shared_mem_bytes: 0,
+            };
+            let func = dev.get_or_load_func(&kernel_name::<T>("awq_matmul"), kernels::AWQ)?;
+            // SAFETY: Set later by running the kernel.
+            let dst = unsafe { dev.alloc::<T>(n_rows * out_dim) }.w()?;
+            let params = (
+                &xs, &qweight, &qzeros, &scales, &dst, in_dim, out_dim, group_size,
+            );
+            // SAFETY: ffi.
+            unsafe { func.launch(cfg, params) }.w()?;
+            Ok(dst)
+        }
+
+        let (qzeros, l3) = self.qzeros.storage_and_layout();
+        let (scales, l4) = self.scales.storage_and_layout();
+        let (s3, s4) = match (&*qzeros, &*scales) {
+            (Storage::Cuda(s3), Storage::Cuda(s4)) => (s3, s4),
+            _ => candle::bail!("awq-matmul, qzeros and scales have to be on a cuda device"),
+        };
+        let (in_dim, out_dim, group_size) = awq_dims(l2, l3, l4)?;
+        let (n_rows, xs_in_dim) = l1.shape().dims2()?;
+        if xs_in_dim != in_dim {
+            candle::bail!(
+                "awq-matmul, shape mismatch {:?} {:?}",
+                l1.shape(),
+                l2.shape()
+            )
+        }
+        let dims = (n_rows, in_dim, out_dim, group_size);
+        let dev = s1.device().clone();
+        let args = ((s1, l1), (s2, l2), (s3, l3), (s4, l4));
+        let slice = match s1.dtype() {
+            DType::F16 => {
+                CudaStorageSlice::F16(launch(&dev, args.0, args.1, args.2, args.3, dims)?)
+            }
+            DType::BF16 => {
+                CudaStorageSlice::BF16(launch(&dev, args.0, args.1, args.2, args.3, dims)?)
+            }
+            DType::F32 => {
+                CudaStorageSlice::F32(launch(&dev, args.0, args.1, args.2, args.3, dims)?)
+            }
+            dtype => candle::bail!("awq-matmul, unsupported dtype {dtype:?}"),
+        };
+        let dst = candle::cuda_backend::CudaStorage { slice, device: dev };
+        Ok((dst, Shape::from((n_rows, out_dim))))
+    }
+}
+
+/// A linear layer using AWQ 4-bit weights, the activations should use the dtype of the scales.
+#[derive(Debug, Clone)]
+pub struct AwqLinear {
+    qweight: Tensor,
+    qzeros: Tensor,
+    scales: Tensor,
+    bias: Option<Tensor>,
+}
+
+impl AwqLinear {
+    pub fn from_tensors(
+        qweight: Tensor,
+        qzeros: Tensor,
+        scales: Tensor,
+        bias: Option<Tensor>,
+    ) -> Result<Self> {
+        if qweight.dtype() != DType::U32 || qzeros.dtype() != DType::U32 {
+            candle::bail!(
+                "awq, qweight and qzeros should use u32, got {:?} and {:?}",
+                qweight.dtype(),
+                qzeros.dtype()
+            )
+        }
+        awq_dims(qweight.layout(), qzeros.layout(), scales.layout())?;
+        Ok(Self {
+            qweight: qweight.contiguous()?,
+            qzeros: qzeros.contiguous()?,
+            scales: scales.contiguous()?,
+            bias,
+        })
+    }
+
+    /// Loads the `qweight`, `qzeros`, `scales` and optional `bias` tensors of a linear layer, the
+    /// packed values are stored as i32 in the checkpoints.
+    pub fn new(
+        in_dim: usize,
+        out_dim: usize,
+        group_size: usize,
+        bias: bool,
+        vb: VarBuilder,
+    ) -> Result<Self> {
+        if out_dim % 8 != 0 || group_size == 0 || in_dim % group_size != 0 {
+            candle::bail!(
+                "awq, unsupported dimensions in: {in_dim}, out: {out_dim}, group: {group_size}"
+            )
+        }
+        let n_groups = in_dim / group_size;
+        let init = Default::default();
+        let qweight =
+            vb.get_with_hints_dtype((in_dim, out_dim / 8), "qweight", init, DType::U32)?;
+        let qzeros =
+            vb.get_with_hints_dtype((n_groups, out_dim / 8), "qzeros", init, DType::U32)?;
+        let scales = vb.get((n_groups, out_dim), "scales")?;
+        let bias = if bias {
+            Some(vb.get(out_dim, "bias")?)
+        } else {
+            None
+        };
+        Self::from_tensors(qweight, qzeros, scales, bias)
+    }
+
+    /// The dequantized weights, with shape `(in_dim, out_dim)`.
+    pub fn dequantize(&self) -> Result<Tensor> {
+        self.qweight
+            .apply_op3_no_bwd(&self.qzeros, &self.scales, &AwqDequantize)
+    }
+
+    pub fn bias(&self) -> Option<&Tensor> {
+        self.bias.as_ref()
+    }
+}
+
+impl Module for AwqLinear {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        let (in_dim, _) = self.qweight.dims2()?;
+        let out_dim = self.scales.dim(1)?;
+        let mut dims = xs.dims().to_vec();
+        let n_rows = xs.elem_count() / in_dim;
+        let ys = if xs.device().is_cuda() && n_rows <= FUSED_MAX_ROWS {
+            let op = AwqMatmul {
+                qzeros: self.qzeros.clone(),
+                scales: self.scales.clone(),
+            };
+            let xs = xs.reshape((n_rows, in_dim))?.contiguous()?;
+            let ys = xs.apply_op2_no_bwd(&self.qweight, &op)?;
+            match dims.last_mut() {
+                None => candle::bail!("awq, unexpected scalar input"),
+                Some(d) => *d = out_dim,
+            }
+            ys.reshape(dims)?
+        } else {
+            xs.broadcast_matmul(&self.dequantize()?)?
+        };
+        match &self.bias {
+            None => Ok(ys),
+            Some(bias) => ys.broadcast_add(bias),
+        }
+    }
+}
@@ -0,0 +1,611 @@
+//! Parsing and validation of model configurations.
+//!
+//! Most models are configured through a Hugging Face style `config.json` file. The
+//! [`ModelConfig`] trait provides a common way to parse these files: unknown fields are reported
+//! rather than silently dropped, and both parsing and validation errors name the offending field
+//! using a dotted path, e.g. `rope_scaling.factor`.
+//!
+//! ```rust
+//! use candle_transformers::config::{ModelConfig, RopeScaling};
+//! let cfg = RopeScaling::from_json(r#"{"type": "linear", "factor": 2.0}"#)?;
+//! assert_eq!(cfg.rope_type, "linear");
+//! let err = RopeScaling::from_json(r#"{"type": "linear"}"#).unwrap_err();
+//! assert!(err.to_string().contains("`factor`"));
+//! # Ok::<(), candle::Error>(())
+//! ```
+use serde::de::{self, DeserializeOwned, DeserializeSeed, IntoDeserializer, Visitor};
+use serde_json::Value;
+use std::cell::RefCell;
+
+/// Fields that are present in most Hugging Face configs but that are not used to build the
+/// models. They are not reported as unknown in the warnings.
+const IGNORED_HF_FIELDS: &[&str] = &[
+    "_name_or_path",
+    "architectures",
+    "auto_map",
+    "model_type",
+    "torch_dtype",
+    "transformers_version",
+    "use_cache",
+];
+
+/// An error that occurred while parsing or validating a configuration.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConfigError {
+    /// The dotted path of the offending field, empty when the error applies to the whole config.
+    pub field: String,
+    pub message: String,
+}
+
+impl ConfigError {
+    pub fn new(field: impl Into<String>, message: impl Into<String>) -> Self {
+        Self {
+            field: field.into(),
+            message: message.into(),
+        }
+    }
+
+    /// Prefixes the field path, this is used when validating nested configs.
+    pub fn nested(self, parent: &str) -> Self {
+        Self {
+            field: join_path(parent, &self.field),
+            message: self.message,
+        }
+    }
+}
+
+impl std::fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.field.is_empty() {
+            write!(f, "invalid config: {}", self.message)
+        } else {
+            write!(
+                f,
+                "invalid config, field `{}`: {}",
+                self.field, self.message
+            )
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl From<ConfigError> for candle::Error {
+    fn from(err: ConfigError) -> Self {
+        candle::Error::wrap(err)
+    }
+}
+
+pub type ValidationResult = std::result::Result<(), ConfigError>;
+
+/// Returns an error if `value` is zero.
+pub fn ensure_positive(field: &str, value: usize) -> ValidationResult {
+    if value == 0 {
+        Err(ConfigError::new(field, "must be positive"))
+    } else {
+        Ok(())
+    }
+}
+
+/// Returns an error if `value` is not a multiple of `divisor`.
+pub fn ensure_divisible(
+    field: &str,
+    value: usize,
+    divisor_field: &str,
+    divisor: usize,
+) -> ValidationResult {
+    ensure_positive(divisor_field, divisor)?;
+    if value % divisor != 0 {
+        let msg = format!("{value} is not divisible by {divisor_field} ({divisor})");
+        Err(ConfigError::new(field, msg))
+    } else {
+        Ok(())
+    }
+}
+
+/// Validates an optional nested config, errors are reported with the `field` prefix.
+pub fn validate_nested<C: ModelConfig>(field: &str, cfg: Option<&C>) -> ValidationResult {
+    match cfg {
+        None => Ok(()),
+        Some(cfg) => cfg.validate().map_err(|e| e.nested(field)),
+    }
+}
+
+/// The result of parsing a config together with the fields that were not used.
+#[derive(Debug, Clone)]
+pub struct Parsed<T> {
+    pub config: T,
+    /// The dotted paths of the fields that are not part of the config type, including the ones
+    /// in nested configs.
+    pub unknown_fields: Vec<String>,
+}
+
+/// A model configuration that can be parsed from a Hugging Face style `config.json`.
+pub trait ModelConfig: DeserializeOwned {
+    /// Checks the consistency of the config values, e.g. that the hidden size is divisible by
+    /// the number of attention heads.
+    fn validate(&self) -> ValidationResult {
+        Ok(())
+    }
+
+    /// Parses and validates a config, a warning is emitted for each unknown field.
+    fn from_json(json: &str) -> candle::Result<Self> {
+        let parsed = parse_json::<Self>(json)?;
+        for field in parsed.unknown_fields.iter() {
+            if !IGNORED_HF_FIELDS.contains(&field.as_str()) {
+                tracing::warn!("unknown config field `{field}`")
+            }
+        }
+        Ok(parsed.config)
+    }
+
+    /// Parses and validates a config file.
+    fn from_file<P: AsRef<std::path::Path>>(path: P) -> candle::Result<Self> {
+        let path = path.as_ref();
+        let json =
+            std::fs::read_to_string(path).map_err(|e| candle::Error::from(e).with_path(path))?;
+        Self::from_json(&json).map_err(|e| e.with_path(path))
+    }
+}
+
+/// Parses and validates a config, returning the unknown fields rather than logging them.
+pub fn parse_json<T: ModelConfig>(json: &str) -> std::result::Result<Parsed<T>, ConfigError> {
+    let value: Value =
+        serde_json::from_str(json).map_err(|e| ConfigError::new("", e.to_string()))?;
+    let state = State::default();
+    let tracked = Tracked {
+        value,
+        path: String::new(),
+        state: &state,
+    };
+    let config = T::deserialize(tracked).map_err(|e| {
+        let field = state.error_path.borrow_mut().take().unwrap_or_default();
+        ConfigError::new(field, e.to_string())
+    })?;
+    config.validate()?;
+    Ok(Parsed {
+        config,
+        unknown_fields: state.unknown.into_inner(),
+    })
+}
+
+fn join_path(parent: &str, child: &str) -> String {
+    match (parent.is_empty(), child.is_empty()) {
+        (true, _) => child.to_string(),
+        (_, true) => parent.to_string(),
+        (false, false) if child.starts_with('[') => format!("{parent}{child}"),
+        (false, false) => format!("{parent}.{child}"),
+    }
+}
+
+#[derive(Default)]
+struct State {
+    unknown: RefCell<Vec<String>>,
+    error_path: RefCell<Option<String>>,
+}
+
+impl State {
+    fn record_error(&self, path: &str, err: serde_json::Error) -> serde_json::Error {
+        let mut error_path = self.error_path.borrow_mut();
+        // The innermost value fails first so it gets to set the path.
+        if error_path.is_none() {
+            *error_path = Some(path.to_string())
+        }
+        err
+    }
+}
+
+/// A deserializer over a json value that keeps track of the path of the value being
+/// deserialized, so that errors and ignored fields can be reported with their location.
+struct Tracked<'a> {
+    value: Value,
+    path: String,
+    state: &'a State,
+}
+
+impl<'a> Tracked<'a> {
+    fn child(path: &str, state: &'a State, value: Value, name: &str) -> Self {
+        Self {
+            value,
+            path: join_path(path, name),
+            state,
+        }
+    }
+}
+
+impl<'de, 'a> de::Deserializer<'de> for Tracked<'a> {
+    type Error = serde_json::Error;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        match self.value {
+            Value::Object(_) => self.deserialize_map(visitor),
+            Value::Array(_) => self.deserialize_seq(visitor),
+            value => value.deserialize_any(visitor),
+        }
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        match self.value {
+            Value::Null => visitor.visit_none(),
+            _ => visitor.visit_some(self),
+        }
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, Self::Error> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        name: &'static str,
+        variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Self::Error> {
+        self.value.deserialize_enum(name, variants, visitor)
+    }
+
+    fn deserialize_map<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        match self.value {
+            Value::Object(map) => {
+                let map = TrackedMap {
+                    iter: map.into_iter(),
+                    value: None,
+                    path: self.path,
+                    state: self.state,
+                };
+                visitor.visit_map(map)
+            }
+            value => value.deserialize_map(visitor),
+        }
+    }
+
+    fn deserialize_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Self::Error> {
+        self.deserialize_map(visitor)
+    }
+
+    fn deserialize_seq<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        match self.value {
+            Value::Array(values) => {
+                let len = values.len();
+                let mut seq = TrackedSeq {
+                    iter: values.into_iter().enumerate(),
+                    path: self.path,
+                    state: self.state,
+                };
+                let value = visitor.visit_seq(&mut seq)?;
+                if seq.iter.len() == 0 {
+                    Ok(value)
+                } else {
+                    Err(de::Error::invalid_length(len, &"fewer elements in array"))
+                }
+            }
+            value => value.deserialize_seq(visitor),
+        }
+    }
+
+    fn deserialize_tuple<V: Visitor<'de>>(
+        self,
+        _len: usize,
+        visitor: V,
+    ) -> Result<V::Value, Self::Error> {
+        self.deserialize_seq(visitor)
+    }
+
+    fn deserialize_ignored_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        self.state.unknown.borrow_mut().push(self.path);
+        visitor.visit_unit()
+    }
+
+    serde::forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
+        bytes byte_buf unit unit_struct tuple_struct identifier
+    }
+}
+
+struct TrackedMap<'a> {
+    iter: serde_json::map::IntoIter,
+    value: Option<(String, Value)>,
+    path: String,
+    state: &'a State,
+}
+
+impl<'de, 'a> de::MapAccess<'de> for TrackedMap<'a> {
+    type Error = serde_json::Error;
+
+    fn next_key_seed<K: DeserializeSeed<'de>>(
+        &mut self,
+        seed: K,
+    ) -> Result<Option<K::Value>, Self::Error> {
+        match self.iter.next() {
+            None => Ok(None),
+            Some((key, value)) => {
+                let de: de::value::StringDeserializer<serde_json::Error> =
+                    key.clone().into_deserializer();
+                self.value = Some((key, value));
+                seed.deserialize(de).map(Some)
+            }
+        }
+    }
+
+    fn next_value_seed<V: DeserializeSeed<'de>>(
+        &mut self,
+        seed: V,
+    ) -> Result<V::Value, Self::Error> {
+        let (key, value) = match self.value.take() {
+            None => return Err(de::Error::custom("value is missing")),
+            Some(v) => v,
+        };
+        let child = Tracked::child(&self.path, self.state, value, &key);
+        let path = child.path.clone();
+        seed.deserialize(child)
+            .map_err(|e| self.state.record_error(&path, e))
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.iter.len())
+    }
+}
+
+struct TrackedSeq<'a> {
+    iter: std::iter::Enumerate<std::vec::IntoIter<Value>>,
+    path: String,
+    state: &'a State,
+}
+
+impl<'de, 'a> de::SeqAccess<'de> for &mut TrackedSeq<'a> {
+    type Error = serde_json::Error;
+
+    fn next_element_seed<T: DeserializeSeed<'de>>(
+        &mut self,
+        seed: T,
+    ) -> Result<Option<T::Value>, Self::Error> {
+        match self.iter.next() {
+            None => Ok(None),
+            Some((index, value)) => {
+                let child = Tracked::child(&self.path, self.state, value, &format!("[{index}]"));
+                let path = child.path.clone();
+                seed.deserialize(child)
+                    .map(Some)
+                    .map_err(|e| self.state.record_error(&path, e))
+            }
+        }
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.iter.len())
+    }
+}
+
+/// Default values that are shared by most architectures, these can be used with
+/// `#[serde(default = "...")]`.
+pub mod defaults {
+    pub fn rope_theta() -> f32 {
+        10_000.0
+    }
+
+    pub fn rms_norm_eps() -> f64 {
+        1e-6
+    }
+
+    pub fn layer_norm_eps() -> f64 {
+        1e-5
+    }
+
+    pub fn max_position_embeddings() -> usize {
+        4096
+    }
+
+    pub fn tie_word_embeddings() -> bool {
+        false
+    }
+
+    pub fn hidden_act() -> candle_nn::Activation {
+        candle_nn::Activation::Silu
+    }
+
+    pub fn num_channels() -> usize {
+        3
+    }
+}
+
+/// The `rope_scaling` entry of Hugging Face configs.
+///
+/// The meaning of the fields depends on `rope_type`, older configs use `type` rather than
+/// `rope_type`.
+#[derive(Debug, Clone, PartialEq, serde::Deserialize)]
+pub struct RopeScaling {
+    #[serde(alias = "type")]
+    pub rope_type: String,
+    pub factor: Option<f64>,
+    pub original_max_position_embeddings: Option<usize>,
+    pub low_freq_factor: Option<f64>,
+    pub high_freq_factor: Option<f64>,
+    pub beta_fast: Option<f64>,
+    pub beta_slow: Option<f64>,
+    pub mscale: Option<f64>,
+    pub mscale_all_dim: Option<f64>,
+    pub attention_factor: Option<f64>,
+    pub short_factor: Option<Vec<f64>>,
+    pub long_factor: Option<Vec<f64>>,
+}
+
+fn ensure_present<T>(field: &str, value: &Option<T>) -> ValidationResult {
+    match value {
+        None => Err(ConfigError::new(field, "is required for this rope type")),
+        Some(_) => Ok(()),
+    }
+}
+
+impl ModelConfig for RopeScaling {
+    fn validate(&self) -> ValidationResult {
+        if let Some(factor) = self.factor {
+            if factor <= 0. {
+                return Err(ConfigError::new("factor", "must be positive"));
+            }
+        }
+        match self.rope_type.as_str() {
+            "default" => Ok(()),
+            "linear" | "dynamic" => ensure_present("factor", &self.factor),
+            "llama3" => {
+                ensure_present("factor", &self.factor)?;
+                ensure_present("low_freq_factor", &self.low_freq_factor)?;
+                ensure_present("high_freq_factor", &self.high_freq_factor)?;
+                let orig = "original_max_position_embeddings";
+                ensure_present(orig, &self.original_max_position_embeddings)
+            }
+            "yarn" => ensure_present("factor", &self.factor),
+            "longrope" | "su" => {
+                ensure_present("short_factor", &self.short_factor)?;
+                ensure_present("long_factor", &self.long_factor)?;
+                let short = self.short_factor.as_ref().map_or(0, |v| v.len());
+                let long = self.long_factor.as_ref().map_or(0, |v| v.len());
+                if short != long {
+                    let msg = format!("has {long} elements but short_factor has {short}");
+                    return Err(ConfigError::new("long_factor", msg));
+                }
+                Ok(())
+            }
+            rope_type => Err(ConfigError::new(
+                "rope_type",
+                format!("unsupported rope type {rope_type}"),
+            )),
+        }
+    }
+}
+
+/// The `quantization_config` entry of Hugging Face configs, as used by GPTQ or AWQ checkpoints.
+///
+/// The layers are built with [`crate::quantized_nn::QuantizedLinear`], which dispatches on
+/// `quant_method`.
+#[derive(Debug, Clone, PartialEq, serde::Deserialize)]
+pub struct QuantizationConfig {
+    pub quant_method: String,
+    #[serde(alias = "w_bit")]
+    pub bits: Option<usize>,
+    /// The number of input features sharing a scale, `-1` means that a single scale is used
+    /// per output feature.
+    #[serde(alias = "q_group_size")]
+    pub group_size: Option<i64>,
+    #[serde(default)]
+    pub desc_act: bool,
+    pub sym: Option<bool>,
+    pub zero_point: Option<bool>,
+    /// The packing of AWQ checkpoints, only `gemm` is supported.
+    pub version: Option<String>,
+    /// `gptq` for the original GPTQ format where zero points are stored minus one, or `gptq_v2`.
+    pub checkpoint_format: Option<String>,
+    #[serde(default)]
+    pub modules_to_not_convert: Vec<String>,
+}
+
+impl QuantizationConfig {
+    fn bits(&self) -> candle::Result<usize> {
+        match self.bits {
+            Some(bits) => Ok(bits),
+            None => candle::bail!("the {} quantization config has no bits", self.quant_method),
+        }
+    }
+
+    /// The parameters of an AWQ checkpoint, an error if `quant_method` is not `awq`.
+    pub fn awq(&self) -> candle::Result<crate::awq::AwqConfig> {
+        if self.quant_method != "awq" {
+            candle::bail!(
+                "expected an awq quantization config, got {}",
+                self.quant_method
+            )
+        }
+        let group_size = match self.group_size {
+            Some(group_size) if group_size > 0 => group_size as usize,
+            group_size => candle::bail!("unsupported AWQ group size {group_size:?}"),
+        };
+        Ok(crate::awq::AwqConfig {
+            bits: self.bits()?,
+            group_size,
+            zero_point: self.zero_point.unwrap_or(true),
+            version: self.version.clone().unwrap_or_else(|| "gemm".to_string()),
+        })
+    }
+
+    /// The parameters of a GPTQ checkpoint, an error if `quant_method` is not `gptq`.
+    pub fn gptq(&self) -> candle::Result<crate::gptq::GptqConfig> {
+        if self.quant_method != "gptq" {
+            candle::bail!(
+                "expected a gptq quantization config, got {}",
+                self.quant_method
+            )
+        }
+        Ok(crate::gptq::GptqConfig {
+            bits: self.bits()?,
+            group_size: self.group_size.unwrap_or(-1) as isize,
+            desc_act: self.desc_act,
+            sym: self.sym.unwrap_or(true),
+            checkpoint_format: self
+                .checkpoint_format
+                .clone()
+                .unwrap_or_else(|| "gptq".to_string()),
+        })
+    }
+}
+
+impl ModelConfig for QuantizationConfig {
+    fn validate(&self) -> ValidationResult {
+        if let Some(bits) = self.bits {
+            if !(2..=8).contains(&bits) {
+                let msg = format!("{bits} is not between 2 and 8");
+                return Err(ConfigError::new("bits", msg));
+            }
+        }
+        if let Some(group_size) = self.group_size {
+            if group_size <= 0 && group_size != -1 {
+                let msg = format!("{group_size} should be positive or -1");
+                return Err(ConfigError::new("group_size", msg));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The `vision_config` entry of the configs used by multimodal models.
+#[derive(Debug, Clone, PartialEq, serde::Deserialize)]
+pub struct VisionConfig {
+    pub hidden_size: usize,
+    pub intermediate_size: usize,
+    pub num_hidden_layers: usize,
+    pub num_attention_heads: usize,
+    pub image_size: usize,
+    pub patch_size: usize,
+    #[serde(default = "defaults::num_channels")]
+    pub num_channels: usize,
+    pub hidden_act: Option<candle_nn::Activation>,
+    #[serde(default = "defaults::layer_norm_eps")]
+    pub layer_norm_eps: f64,
+}
+
+impl VisionConfig {
+    pub fn num_patches(&self) -> usize {
+        let side = self.image_size / self.patch_size;
+        side * side
+    }
+}
+
+impl ModelConfig for VisionConfig {
+    fn validate(&self) -> ValidationResult {
+        ensure_positive("num_hidden_layers", self.num_hidden_layers)?;
+        ensure_divisible(
+            "hidden_size",
+            self.hidden_size,
+            "num_attention_heads",
+            self.num_attention_heads,
+        )?;
+        ensure_divisible("image_size", self.image_size, "patch_size", self.patch_size)
+    }
+}
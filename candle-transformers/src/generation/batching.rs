@@ -0,0 +1,183 @@
+//! Batching policies for the [`super::Engine`] scheduler.
+//!
+//! Before each step the engine asks its [`BatchingPolicy`] for a [`StepBudget`]: the maximum
+//! number of sequences and of tokens to process, and whether long prompts can be split over
+//! several steps (chunked prefill). After the step the policy gets the measured [`StepStats`],
+//! so that it can adapt the budget to the observed latencies.
+//!
+//! [`FixedBudget`] always returns the same budget, this is what [`super::EngineConfig`] uses.
+//! [`SloPolicy`] targets a [`LatencySlo`]: larger steps give a better throughput, and use less
+//! energy per token, but every running sequence waits for the whole step before getting its
+//! next token. The token budget is halved when a step exceeds the inter-token latency target
+//! and grows back slowly otherwise, prompts are processed by chunks so that they do not stall
+//! the decoding, and the full budget is used while requests wait longer than the time to first
+//! token target.
+//!
+//! ```ignore
+//! let slo = LatencySlo::new(Duration::from_millis(500), Duration::from_millis(50));
+//! let engine = Engine::new(model, cache, EngineConfig::default())
+//!     .with_policy(SloPolicy::new(slo, 64, 4096));
+//! ```
+use std::time::Duration;
+
+/// The limits for a scheduler step.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StepBudget {
+    /// The maximum number of running sequences, new sequences are not admitted beyond it.
+    pub max_batch_size: usize,
+    /// The maximum number of tokens processed in the step. The running sequences always decode
+    /// their next token, the budget limits the prompt tokens.
+    pub max_batch_tokens: usize,
+    /// Whether prompts that do not fit in the remaining budget are processed by chunks over
+    /// several steps, otherwise a prompt longer than the budget is only processed when no other
+    /// sequence is scheduled.
+    pub chunked_prefill: bool,
+}
+
+/// The state of the scheduler at the start of a step.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SchedulerState {
+    pub num_running: usize,
+    pub num_waiting: usize,
+    /// How long the oldest waiting request has been waiting for its first token.
+    pub oldest_waiting: Option<Duration>,
+}
+
+/// The measurements for a step.
+#[derive(Debug, Clone, PartialEq)]
+pub struct StepStats {
+    /// The duration of the forward pass and of the sampling.
+    pub duration: Duration,
+    pub num_sequences: usize,
+    /// The sequences that decoded one token.
+    pub num_decode_sequences: usize,
+    pub num_tokens: usize,
+    /// The tokens of the prompts, or of the preempted sequences, processed in the step.
+    pub num_prefill_tokens: usize,
+    /// The delay between the submission and the first token for the requests that got their
+    /// first token in the step.
+    pub time_to_first_token: Vec<Duration>,
+}
+
+/// Decides the budget of each scheduler step.
+pub trait BatchingPolicy: Send {
+    fn budget(&mut self, state: &SchedulerState) -> StepBudget;
+
+    /// Called after each step that processed some tokens.
+    fn record(&mut self, _stats: &StepStats) {}
+}
+
+/// Always returns the same budget.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FixedBudget(pub StepBudget);
+
+impl BatchingPolicy for FixedBudget {
+    fn budget(&mut self, _state: &SchedulerState) -> StepBudget {
+        self.0
+    }
+}
+
+/// Latency targets for serving.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LatencySlo {
+    /// The maximum delay between the submission of a request and its first token.
+    pub max_time_to_first_token: Duration,
+    /// The maximum delay between two tokens of a running request, i.e. the maximum duration of
+    /// a step with some decoding sequences.
+    pub max_inter_token_latency: Duration,
+}
+
+impl LatencySlo {
+    pub fn new(max_time_to_first_token: Duration, max_inter_token_latency: Duration) -> Self {
+        Self {
+            max_time_to_first_token,
+            max_inter_token_latency,
+        }
+    }
+}
+
+/// Adapts the batch size and the prefill budget to meet a [`LatencySlo`], see the module
+/// documentation.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SloPolicy {
+    slo: LatencySlo,
+    max_batch_size: usize,
+    min_batch_tokens: usize,
+    max_batch_tokens: usize,
+    batch_size: usize,
+    batch_tokens: usize,
+}
+
+impl SloPolicy {
+    /// Starts with the largest budget, `max_batch_size` sequences and `max_batch_tokens` tokens.
+    pub fn new(slo: LatencySlo, max_batch_size: usize, max_batch_tokens: usize) -> Self {
+        let max_batch_size = max_batch_size.max(1);
+        let max_batch_tokens = max_batch_tokens.max(1);
+        Self {
+            slo,
+            max_batch_size,
+            min_batch_tokens: max_batch_tokens.min(16),
+            max_batch_tokens,
+            batch_size: max_batch_size,
+            batch_tokens: max_batch_tokens,
+        }
+    }
+
+    /// The token budget does not go below this value, 16 by default.
+    pub fn with_min_batch_tokens(mut self, min_batch_tokens: usize) -> Self {
+        self.min_batch_tokens = min_batch_tokens.clamp(1, self.max_batch_tokens);
+        self.batch_tokens = self.batch_tokens.max(self.min_batch_tokens);
+        self
+    }
+
+    pub fn slo(&self) -> &LatencySlo {
+        &self.slo
+    }
+
+    /// The current limit on the number of sequences.
+    pub fn batch_size(&self) -> usize {
+        self.batch_size
+    }
+
+    /// The current token budget.
+    pub fn batch_tokens(&self) -> usize {
+        self.batch_tokens
+    }
+}
+
+impl BatchingPolicy for SloPolicy {
+    fn budget(&mut self, state: &SchedulerState) -> StepBudget {
+        let ttft_at_risk = state
+            .oldest_waiting
+            .is_some_and(|waited| waited >= self.slo.max_time_to_first_token);
+        let max_batch_tokens = if ttft_at_risk {
+            self.max_batch_tokens
+        } else {
+            self.batch_tokens
+        };
+        StepBudget {
+            max_batch_size: self.batch_size,
+            max_batch_tokens,
+            chunked_prefill: true,
+        }
+    }
+
+    fn record(&mut self, stats: &StepStats) {
+        let too_slow = stats.duration > self.slo.max_inter_token_latency;
+        if stats.num_decode_sequences > 0 && too_slow {
+            // Multiplicative decrease, the decoding sequences missed their target.
+            self.batch_tokens = (self.batch_tokens / 2).max(self.min_batch_tokens);
+            if stats.num_prefill_tokens == 0 {
+                // The decoding alone is too slow, fewer sequences are admitted.
+                self.batch_size = (stats.num_sequences * 3 / 4).max(1);
+            }
+        } else if !too_slow {
+            // Additive increase.
+            let step = (self.max_batch_tokens / 16).max(1);
+            self.batch_tokens = (self.batch_tokens + step).min(self.max_batch_tokens);
+            if stats.num_sequences >= self.batch_size {
+                self.batch_size = (self.batch_size + 1).min(self.max_batch_size);
+            }
+        }
+    }
+}
@@ -0,0 +1,308 @@
+//! Beam search and diverse beam search.
+//!
+//! Rather than sampling a single token at each step, beam search keeps the `num_beams` most
+//! likely partial sequences. At each step, every beam is extended with all the tokens of the
+//! vocabulary and the best candidates become the new beams. A candidate that ends with an end of
+//! sequence token becomes a finished hypothesis, its score is its log probability divided by
+//! `length ^ length_penalty` so that a positive penalty favors longer sequences.
+//!
+//! The model processes all the beams as a batch. After each step the beams may have moved, e.g.
+//! two new beams can extend the same previous beam, so the model is asked to reorder its kv
+//! caches along the batch dimension with [`BeamSearchModel::reorder_cache`].
+//!
+//! Diverse beam search splits the beams in groups that are processed one after the other, the
+//! log probability of a token is decreased by `diversity_penalty` times the number of times it
+//! has been selected by the previous groups in the current step, see "Diverse Beam Search"
+//! <https://arxiv.org/abs/1610.02424>.
+//!
+//! ```ignore
+//! let config = BeamSearchConfig::new(4, 128)
+//!     .with_eos_tokens(vec![eos_token])
+//!     .with_num_return_sequences(2);
+//! let hypotheses = beam_search(&mut model, &[decoder_start_token], &config, &device)?;
+//! println!("{}", tokenizer.decode(&hypotheses[0].tokens, true)?);
+//! ```
+use candle::{DType, Device, Result, Tensor, D};
+
+/// A model that processes the beams as a batch and caches the keys and values of the processed
+/// tokens.
+pub trait BeamSearchModel {
+    /// Processes `tokens`, a `u32` tensor of shape `(batch_size, seq_len)`, placed after the
+    /// `seqlen_offset` tokens that are already in the cache. Returns the logits for the last
+    /// token of each sequence, with shape `(batch_size, vocab_size)` or `(batch_size, seq_len,
+    /// vocab_size)`.
+    fn forward(&mut self, tokens: &Tensor, seqlen_offset: usize) -> Result<Tensor>;
+
+    /// Reorders the caches along the batch dimension: the new entry `i` is a copy of the entry
+    /// `indices[i]`. `indices` is a `u32` tensor, its size can differ from the current batch size.
+    /// The cached encoder outputs of seq2seq models have to be reordered too.
+    ///
+    /// With [`candle_nn::kv_cache::KvCache`], this is an `index_select` along the batch
+    /// dimension, and for the caches stored as tensors this is `Tensor::index_select`.
+    fn reorder_cache(&mut self, indices: &Tensor) -> Result<()>;
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct BeamSearchConfig {
+    pub num_beams: usize,
+    /// The number of groups for diverse beam search, `num_beams` has to be a multiple of it.
+    pub num_beam_groups: usize,
+    pub diversity_penalty: f64,
+    /// The exponent of the length in the score of the finished hypotheses.
+    pub length_penalty: f64,
+    /// When set, a group stops as soon as it has `num_beams / num_beam_groups` finished
+    /// hypotheses. Otherwise it stops when its best running beam cannot beat the worst finished
+    /// hypothesis anymore, assuming that the beams do not get longer.
+    pub early_stopping: bool,
+    pub num_return_sequences: usize,
+    pub max_new_tokens: usize,
+    pub eos_tokens: Vec<u32>,
+}
+
+impl BeamSearchConfig {
+    pub fn new(num_beams: usize, max_new_tokens: usize) -> Self {
+        Self {
+            num_beams,
+            num_beam_groups: 1,
+            diversity_penalty: 0.,
+            length_penalty: 1.,
+            early_stopping: false,
+            num_return_sequences: 1,
+            max_new_tokens,
+            eos_tokens: vec![],
+        }
+    }
+
+    pub fn with_eos_tokens(mut self, eos_tokens: Vec<u32>) -> Self {
+        self.eos_tokens = eos_tokens;
+        self
+    }
+
+    pub fn with_length_penalty(mut self, length_penalty: f64) -> Self {
+        self.length_penalty = length_penalty;
+        self
+    }
+
+    pub fn with_early_stopping(mut self, early_stopping: bool) -> Self {
+        self.early_stopping = early_stopping;
+        self
+    }
+
+    pub fn with_num_return_sequences(mut self, num_return_sequences: usize) -> Self {
+        self.num_return_sequences = num_return_sequences;
+        self
+    }
+
+    pub fn with_diversity(mut self, num_beam_groups: usize, diversity_penalty: f64) -> Self {
+        self.num_beam_groups = num_beam_groups;
+        self.diversity_penalty = diversity_penalty;
+        self
+    }
+}
+
+/// A generated sequence.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Hypothesis {
+    /// The generated tokens, including the final end of sequence token if any.
+    pub tokens: Vec<u32>,
+    /// The sum of the log probabilities of the tokens.
+    pub log_prob: f64,
+    /// The length normalized score used to rank the hypotheses, this includes the diversity
+    /// penalties.
+    pub score: f64,
+}
+
+#[derive(Debug, Clone)]
+struct Beam {
+    tokens: Vec<u32>,
+    log_prob: f64,
+    // The sum of the penalized log probabilities.
+    score: f64,
+    // The index of the beam in the model batch.
+    row: usize,
+}
+
+struct Group {
+    beams: Vec<Beam>,
+    // The best finished hypotheses sorted by decreasing score, at most `size` of them.
+    finished: Vec<Hypothesis>,
+    size: usize,
+    done: bool,
+}
+
+impl Group {
+    fn add(&mut self, beam: &Beam, length_penalty: f64) {
+        let len = beam.tokens.len().max(1) as f64;
+        let hyp = Hypothesis {
+            tokens: beam.tokens.clone(),
+            log_prob: beam.log_prob,
+            score: beam.score / len.powf(length_penalty),
+        };
+        let pos = self.finished.partition_point(|h| h.score >= hyp.score);
+        if pos < self.size {
+            self.finished.insert(pos, hyp);
+            self.finished.truncate(self.size)
+        }
+    }
+
+    fn is_done(&self, config: &BeamSearchConfig, len: usize) -> bool {
+        if self.beams.is_empty() {
+            return true;
+        }
+        if self.finished.len() < self.size {
+            return false;
+        }
+        if config.early_stopping {
+            return true;
+        }
+        let best = self
+            .beams
+            .iter()
+            .map(|b| b.score)
+            .fold(f64::NEG_INFINITY, f64::max);
+        let worst = self.finished[self.finished.len() - 1].score;
+        best / (len.max(1) as f64).powf(config.length_penalty) <= worst
+    }
+}
+
+/// Runs beam search after `prompt`, the model caches have to be empty. Returns the
+/// `num_return_sequences` best hypotheses sorted by decreasing score.
+///
+/// The beams that reach `max_new_tokens` tokens without an end of sequence token are returned
+/// as hypotheses too.
+pub fn beam_search<M: BeamSearchModel>(
+    model: &mut M,
+    prompt: &[u32],
+    config: &BeamSearchConfig,
+    device: &Device,
+) -> Result<Vec<Hypothesis>> {
+    let num_groups = config.num_beam_groups;
+    if prompt.is_empty() {
+        candle::bail!("beam search requires a non-empty prompt")
+    }
+    if config.num_beams == 0 || num_groups == 0 || config.num_beams % num_groups != 0 {
+        candle::bail!(
+            "num_beams {} has to be a positive multiple of num_beam_groups {num_groups}",
+            config.num_beams
+        )
+    }
+    if config.num_return_sequences > config.num_beams {
+        candle::bail!(
+            "num_return_sequences {} is larger than num_beams {}",
+            config.num_return_sequences,
+            config.num_beams
+        )
+    }
+    let size = config.num_beams / num_groups;
+    // Each group starts with a single beam, all of them use the first entry of the batch.
+    let initial = Beam {
+        tokens: vec![],
+        log_prob: 0.,
+        score: 0.,
+        row: 0,
+    };
+    let mut groups = (0..num_groups)
+        .map(|_| Group {
+            beams: vec![initial.clone()],
+            finished: vec![],
+            size,
+            done: false,
+        })
+        .collect::<Vec<_>>();
+
+    let mut input = Tensor::new(prompt, device)?.unsqueeze(0)?;
+    let mut seqlen_offset = 0;
+    for step in 0..config.max_new_tokens {
+        let logits = model.forward(&input, seqlen_offset)?;
+        seqlen_offset += input.dim(1)?;
+        let logits = match logits.rank() {
+            3 => logits.narrow(1, logits.dim(1)? - 1, 1)?.squeeze(1)?,
+            _ => logits,
+        };
+        let log_probs = candle_nn::ops::log_softmax(&logits.to_dtype(DType::F32)?, D::Minus1)?
+            .to_vec2::<f32>()?;
+        let vocab_size = log_probs.first().map_or(0, |l| l.len());
+
+        // The number of times each token has been selected by the previous groups.
+        let mut token_counts = vec![0usize; vocab_size];
+        let mut rows = vec![];
+        let mut next_tokens = vec![];
+        for group in groups.iter_mut().filter(|g| !g.done) {
+            let mut candidates = Vec::with_capacity(group.beams.len() * vocab_size);
+            for (beam_idx, beam) in group.beams.iter().enumerate() {
+                for (token, &lp) in log_probs[beam.row].iter().enumerate() {
+                    // The tokens masked out by the model cannot be selected.
+                    if !lp.is_finite() {
+                        continue;
+                    }
+                    let penalty = config.diversity_penalty * token_counts[token] as f64;
+                    let score = beam.score + lp as f64 - penalty;
+                    candidates.push((score, beam_idx, token as u32, lp as f64));
+                }
+            }
+            // Keeping twice as many candidates as beams ensures that there are enough of them
+            // after removing the finished ones.
+            let num_candidates = usize::min(2 * size, candidates.len());
+            if num_candidates < candidates.len() {
+                candidates.select_nth_unstable_by(num_candidates, |a, b| b.0.total_cmp(&a.0));
+                candidates.truncate(num_candidates);
+            }
+            candidates.sort_by(|a, b| b.0.total_cmp(&a.0));
+
+            let mut beams = Vec::with_capacity(size);
+            for (rank, &(score, beam_idx, token, lp)) in candidates.iter().enumerate() {
+                let parent = &group.beams[beam_idx];
+                let mut tokens = parent.tokens.clone();
+                tokens.push(token);
+                let beam = Beam {
+                    tokens,
+                    log_prob: parent.log_prob + lp,
+                    score,
+                    row: parent.row,
+                };
+                if config.eos_tokens.contains(&token) {
+                    // Only the candidates that would have been selected as beams can finish.
+                    if rank < size {
+                        group.add(&beam, config.length_penalty)
+                    }
+                } else {
+                    beams.push(beam);
+                    if beams.len() == size {
+                        break;
+                    }
+                }
+            }
+            for beam in beams.iter() {
+                token_counts[beam.tokens[beam.tokens.len() - 1] as usize] += 1
+            }
+            group.beams = beams;
+            group.done = group.is_done(config, step + 1);
+            if !group.done {
+                for beam in group.beams.iter_mut() {
+                    rows.push(beam.row as u32);
+                    next_tokens.push(beam.tokens[beam.tokens.len() - 1]);
+                    beam.row = rows.len() - 1;
+                }
+            }
+        }
+        if rows.is_empty() || step + 1 == config.max_new_tokens {
+            break;
+        }
+        model.reorder_cache(&Tensor::new(rows, device)?)?;
+        input = Tensor::new(next_tokens, device)?.unsqueeze(1)?;
+    }
+
+    let mut hypotheses = vec![];
+    for mut group in groups.into_iter() {
+        if !group.done {
+            let beams = std::mem::take(&mut group.beams);
+            for beam in beams.iter() {
+                group.add(beam, config.length_penalty)
+            }
+        }
+        hypotheses.extend(group.finished)
+    }
+    hypotheses.sort_by(|a, b| b.score.total_cmp(&a.score));
+    hypotheses.truncate(config.num_return_sequences);
+    Ok(hypotheses)
+}
@@ -0,0 +1,164 @@
+//! Incremental detokenization for streaming the generated text.
+//!
+//! Decoding each token on its own loses the spaces that depend on the previous token and splits
+//! the characters that span several tokens, e.g. the SentencePiece byte-fallback tokens such as
+//! `<0xE2><0x82><0xAC>` for `€`. The [`Detokenizer`] decodes a small window of the previous
+//! tokens together with the new ones and only returns the text once it does not end with an
+//! incomplete character, so that the concatenation of the streamed pieces is the decoded text.
+//! The special tokens are not passed to the decoder and are skipped or included according to a
+//! [`SpecialTokenPolicy`].
+//!
+//! ```ignore
+//! let special_tokens = tokenizer
+//!     .get_added_tokens_decoder()
+//!     .into_iter()
+//!     .filter(|(_, t)| t.special)
+//!     .map(|(id, t)| (id, t.content))
+//!     .collect();
+//! let decode = move |tokens: &[u32]| tokenizer.decode(tokens, false).map_err(E::msg);
+//! let mut detokenizer = Detokenizer::new(decode).with_special_tokens(special_tokens);
+//! for token in tokens {
+//!     if let Some(text) = detokenizer.next_token(token)? {
+//!         print!("{text}")
+//!     }
+//! }
+//! print!("{}", detokenizer.flush()?);
+//! ```
+use candle::Result;
+use std::collections::HashMap;
+
+// A character is at most 4 bytes so at most 4 byte-fallback tokens, the text is returned even
+// if it still ends with a replacement character after holding this many tokens back, e.g. when
+// the model generates invalid utf-8.
+const MAX_HELD_TOKENS: usize = 4;
+
+/// Whether the text of the special tokens is part of the streamed text.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub enum SpecialTokenPolicy {
+    #[default]
+    Skip,
+    Include,
+    /// Only the special tokens with these ids are included, e.g. the tool call delimiters.
+    IncludeOnly(Vec<u32>),
+}
+
+impl SpecialTokenPolicy {
+    fn includes(&self, token: u32) -> bool {
+        match self {
+            Self::Skip => false,
+            Self::Include => true,
+            Self::IncludeOnly(tokens) => tokens.contains(&token),
+        }
+    }
+}
+
+type Decoder = Box<dyn Fn(&[u32]) -> Result<String> + Send>;
+
+pub struct Detokenizer {
+    decode: Decoder,
+    special_tokens: HashMap<u32, String>,
+    policy: SpecialTokenPolicy,
+    // The non-special tokens from the start of the decoding window.
+    tokens: Vec<u32>,
+    // The text of `tokens[..read_offset]` was returned, the tokens before `read_offset` are only
+    // kept for the context of the next ones.
+    read_offset: usize,
+}
+
+impl Detokenizer {
+    /// `decode` returns the text for a sequence of tokens that does not contain special tokens,
+    /// e.g. with `tokenizer.decode(tokens, false)`.
+    pub fn new<F>(decode: F) -> Self
+    where
+        F: Fn(&[u32]) -> Result<String> + Send + 'static,
+    {
+        Self {
+            decode: Box::new(decode),
+            special_tokens: HashMap::new(),
+            policy: SpecialTokenPolicy::default(),
+            tokens: vec![],
+            read_offset: 0,
+        }
+    }
+
+    /// The ids and texts of the special tokens.
+    pub fn with_special_tokens(mut self, special_tokens: HashMap<u32, String>) -> Self {
+        self.special_tokens = special_tokens;
+        self
+    }
+
+    pub fn with_policy(mut self, policy: SpecialTokenPolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    pub fn is_special(&self, token: u32) -> bool {
+        self.special_tokens.contains_key(&token)
+    }
+
+    // The text of the held back tokens, i.e. the difference between the decoding of the whole
+    // window and the decoding of the tokens that were already returned.
+    fn held_text(&self) -> Result<Option<String>> {
+        let prefix_text = (self.decode)(&self.tokens[..self.read_offset])?;
+        let text = (self.decode)(&self.tokens)?;
+        if text.len() <= prefix_text.len() {
+            return Ok(None);
+        }
+        // The decoding of the window can differ from the returned text when the first tokens
+        // were part of a character, the new text starts at the next char boundary.
+        let mut start = prefix_text.len();
+        while !text.is_char_boundary(start) {
+            start += 1
+        }
+        Ok(Some(text[start..].to_string()))
+    }
+
+    fn advance(&mut self) {
+        // Only the last returned tokens are kept as the context of the next window.
+        if self.read_offset == self.tokens.len() {
+            return;
+        }
+        self.tokens.drain(..self.read_offset);
+        self.read_offset = self.tokens.len();
+    }
+
+    /// Adds a generated token and returns the text that became complete, if any.
+    pub fn next_token(&mut self, token: u32) -> Result<Option<String>> {
+        if let Some(special) = self.special_tokens.get(&token) {
+            let special = self.policy.includes(token).then(|| special.clone());
+            let text = self.flush()?;
+            return Ok(match special {
+                Some(special) => Some(text + &special),
+                None => (!text.is_empty()).then_some(text),
+            });
+        }
+        self.tokens.push(token);
+        let held = self.tokens.len() - self.read_offset;
+        match self.held_text()? {
+            Some(text) if !text.ends_with('\u{FFFD}') || held >= MAX_HELD_TOKENS => {
+                self.advance();
+                Ok(Some(text))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// The text of the tokens that are held back, without consuming them.
+    pub fn pending_text(&self) -> Result<String> {
+        Ok(self.held_text()?.unwrap_or_default())
+    }
+
+    /// Returns the text of the tokens that are held back, to call once the generation has
+    /// stopped. Incomplete characters are returned as replacement characters.
+    pub fn flush(&mut self) -> Result<String> {
+        let text = self.pending_text()?;
+        self.advance();
+        Ok(text)
+    }
+
+    /// Resets the state for a new sequence.
+    pub fn reset(&mut self) {
+        self.tokens.clear();
+        self.read_offset = 0;
+    }
+}
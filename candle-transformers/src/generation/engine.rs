@@ -0,0 +1,824 @@
+//! Continuous batching for text generation.
+//!
+//! The [`Engine`] runs the generation for many requests at once: each step processes the
+//! prompts of the newly admitted requests together with one token for each of the running
+//! ones, so that requests do not wait for the whole batch to finish and the sequences can have
+//! different lengths. The keys and values are stored in a [`PagedKvCache`] shared by all the
+//! sequences, a finished sequence releases its blocks right away. When the cache runs out of
+//! blocks, the running sequences with the lowest [`Priority`], the most recently admitted first,
+//! are preempted: they release their blocks and go back to the queue, and are processed again
+//! from their prompt and generated tokens once enough blocks are available. With
+//! [`EngineConfig::offload_preempted`], their keys and values are moved to the cpu memory
+//! instead and restored when they resume.
+//!
+//! The waiting requests are admitted by priority, then by arrival. A request that does not fit
+//! in the cache or in the batch preempts the running sequences with a lower priority. Requests
+//! can also be tagged with a client, with [`Engine::with_fairness`] the clients that used more
+//! than their [`FairShare`] of the tokens go after the others of the same priority.
+//!
+//! The number of sequences and tokens processed in each step is decided by a
+//! [`BatchingPolicy`], e.g. to meet latency targets with [`super::batching::SloPolicy`]. When
+//! chunked prefill is enabled, a long prompt is processed by chunks over several steps together
+//! with the decoding of the running sequences.
+//!
+//! Requests can be submitted from other threads through an [`EngineHandle`] while the engine
+//! runs with [`Engine::run`], the generated tokens are streamed on a channel per request.
+//!
+//! The prefill and the decoding can run on different workers: [`prefill`] processes a prompt
+//! and returns its kv cache as [`KvBlocks`], which can be sent to a decode worker with
+//! [`candle_nn::kv_transfer`] and submitted there with [`EngineHandle::submit_prefilled`] so
+//! that the prompt is not processed again.
+//!
+//! ```ignore
+//! let mut engine = Engine::new(model, cache, EngineConfig::default());
+//! let handle = engine.handle();
+//! std::thread::spawn(move || engine.run());
+//! let stream = handle.submit(GenerationRequest::new(prompt_tokens, 256))?;
+//! for event in stream {
+//!     match event {
+//!         GenerationEvent::Token(token) => print!("{}", tokenizer.decode(&[token], false)?),
+//!         GenerationEvent::Finished(_) => break,
+//!         GenerationEvent::Error(err) => anyhow::bail!(err),
+//!     }
+//! }
+//! ```
+use super::batching::{BatchingPolicy, FixedBudget, SchedulerState, StepBudget, StepStats};
+use super::fairness::{FairShare, TokenBuckets};
+use super::{CancellationToken, LogitsProcessor, Sampling};
+use candle::{Device, Result, Tensor};
+use candle_nn::kv_transfer::KvBlocks;
+use candle_nn::multi_lora::AdapterSegment;
+use candle_nn::paged_attention::{PagedBatch, PagedKvCache};
+use std::cmp::Reverse;
+use std::sync::mpsc;
+use std::time::Instant;
+
+/// A model that can process the tokens of multiple sequences in a single forward pass.
+pub trait EngineModel {
+    /// Runs the model on the new tokens of `batch`.
+    ///
+    /// `tokens` and `positions` are `u32` tensors with shape `(batch.num_tokens(),)`, the
+    /// tokens of the sequences are packed in the order of `batch.seq_ids()` and the positions
+    /// are the indexes of the tokens in their sequence. The keys and values of each layer have
+    /// to be written to `cache` before computing the attention, see [`PagedKvCache`]. Returns
+    /// the logits for the last token of each sequence with shape `(batch_size, vocab_size)`.
+    fn forward(
+        &mut self,
+        tokens: &Tensor,
+        positions: &Tensor,
+        batch: &PagedBatch,
+        cache: &PagedKvCache,
+    ) -> Result<Tensor>;
+
+    /// Whether the model has the LoRA adapter with this id, the requests for other adapters
+    /// are rejected.
+    fn has_adapter(&self, _id: usize) -> bool {
+        false
+    }
+
+    /// Called before each `forward` with the adapter used by the tokens of the batch, in the
+    /// order of the tokens, e.g. to set the [`candle_nn::multi_lora::AdapterSelection`] of the
+    /// model.
+    fn set_adapters(&mut self, _segments: &[AdapterSegment]) -> Result<()> {
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EngineConfig {
+    /// The maximum number of sequences processed in a step.
+    pub max_batch_size: usize,
+    /// The maximum number of tokens processed in a step, a prompt longer than this is only
+    /// processed when no other sequence is scheduled in the step unless `chunked_prefill` is
+    /// set.
+    pub max_batch_tokens: usize,
+    /// Splits the prompts over several steps to fit in `max_batch_tokens`.
+    pub chunked_prefill: bool,
+    /// Moves the kv cache of the preempted sequences to the cpu memory, so that their tokens
+    /// are not processed again when they resume.
+    pub offload_preempted: bool,
+}
+
+impl Default for EngineConfig {
+    fn default() -> Self {
+        Self {
+            max_batch_size: 64,
+            max_batch_tokens: 4096,
+            chunked_prefill: false,
+            offload_preempted: false,
+        }
+    }
+}
+
+/// The priority class of a request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub enum Priority {
+    Low,
+    #[default]
+    Normal,
+    High,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct GenerationRequest {
+    pub prompt: Vec<u32>,
+    pub max_new_tokens: usize,
+    pub sampling: Sampling,
+    pub seed: u64,
+    /// The generation stops after producing one of these tokens.
+    pub stop_tokens: Vec<u32>,
+    /// Cancels the request when triggered, the engine checks it before each step.
+    pub cancellation: Option<CancellationToken>,
+    /// The LoRA adapter used for the request, the base model is used when not set.
+    pub adapter: Option<usize>,
+    pub priority: Priority,
+    /// The client that submitted the request, for the fairness between clients.
+    pub client: Option<String>,
+}
+
+impl GenerationRequest {
+    /// A request using greedy sampling.
+    pub fn new(prompt: Vec<u32>, max_new_tokens: usize) -> Self {
+        Self {
+            prompt,
+            max_new_tokens,
+            sampling: Sampling::ArgMax,
+            seed: 299792458,
+            stop_tokens: vec![],
+            cancellation: None,
+            adapter: None,
+            priority: Priority::Normal,
+            client: None,
+        }
+    }
+
+    pub fn with_sampling(mut self, sampling: Sampling, seed: u64) -> Self {
+        self.sampling = sampling;
+        self.seed = seed;
+        self
+    }
+
+    pub fn with_stop_tokens(mut self, stop_tokens: Vec<u32>) -> Self {
+        self.stop_tokens = stop_tokens;
+        self
+    }
+
+    pub fn with_cancellation(mut self, cancellation: CancellationToken) -> Self {
+        self.cancellation = Some(cancellation);
+        self
+    }
+
+    pub fn with_adapter(mut self, adapter: usize) -> Self {
+        self.adapter = Some(adapter);
+        self
+    }
+
+    pub fn with_priority(mut self, priority: Priority) -> Self {
+        self.priority = priority;
+        self
+    }
+
+    pub fn with_client(mut self, client: impl ToString) -> Self {
+        self.client = Some(client.to_string());
+        self
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FinishReason {
+    /// A stop token was generated, it is included in the streamed tokens.
+    Stop,
+    /// The maximum number of new tokens was reached.
+    Length,
+    /// The cancellation token of the request was triggered.
+    Cancelled,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum GenerationEvent {
+    Token(u32),
+    Finished(FinishReason),
+    Error(String),
+}
+
+/// The events for a request, dropping the stream cancels the request.
+#[derive(Debug)]
+pub struct RequestStream {
+    id: usize,
+    receiver: mpsc::Receiver<GenerationEvent>,
+}
+
+impl RequestStream {
+    pub fn id(&self) -> usize {
+        self.id
+    }
+
+    /// Returns the next event if there is one, without waiting.
+    pub fn try_next(&mut self) -> Option<GenerationEvent> {
+        self.receiver.try_recv().ok()
+    }
+
+    /// Waits for the generation to finish and returns all the generated tokens.
+    pub fn tokens(self) -> Result<(Vec<u32>, FinishReason)> {
+        let mut tokens = vec![];
+        for event in self {
+            match event {
+                GenerationEvent::Token(token) => tokens.push(token),
+                GenerationEvent::Finished(reason) => return Ok((tokens, reason)),
+                GenerationEvent::Error(err) => candle::bail!("{err}"),
+            }
+        }
+        candle::bail!("the engine stopped before the end of the generation")
+    }
+}
+
+impl Iterator for RequestStream {
+    type Item = GenerationEvent;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.receiver.recv().ok()
+    }
+}
+
+struct Submission {
+    id: usize,
+    request: GenerationRequest,
+    prefilled: Option<KvBlocks>,
+    sender: mpsc::Sender<GenerationEvent>,
+}
+
+/// Submits requests to an engine, possibly from other threads.
+#[derive(Clone)]
+pub struct EngineHandle {
+    sender: mpsc::Sender<Submission>,
+    next_id: std::sync::Arc<std::sync::atomic::AtomicUsize>,
+}
+
+impl EngineHandle {
+    pub fn submit(&self, request: GenerationRequest) -> Result<RequestStream> {
+        self.send(request, None)
+    }
+
+    /// Submits a request whose first tokens have already been processed by another worker,
+    /// `blocks` holds their keys and values, e.g. as returned by [`prefill`]. The blocks have
+    /// to cover a strict prefix of the prompt: the usual setup is to submit the prompt followed
+    /// by the token sampled by the prefill worker, with the blocks for the prompt. The request
+    /// is processed from its prompt if the sequence is preempted.
+    pub fn submit_prefilled(
+        &self,
+        request: GenerationRequest,
+        blocks: KvBlocks,
+    ) -> Result<RequestStream> {
+        self.send(request, Some(blocks))
+    }
+
+    fn send(
+        &self,
+        request: GenerationRequest,
+        prefilled: Option<KvBlocks>,
+    ) -> Result<RequestStream> {
+        let id = self
+            .next_id
+            .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        let (sender, receiver) = mpsc::channel();
+        let submission = Submission {
+            id,
+            request,
+            prefilled,
+            sender,
+        };
+        if self.sender.send(submission).is_err() {
+            candle::bail!("the engine has stopped")
+        }
+        Ok(RequestStream { id, receiver })
+    }
+}
+
+struct Sequence {
+    id: usize,
+    // The prompt followed by the generated tokens.
+    tokens: Vec<u32>,
+    num_generated: usize,
+    // The number of tokens stored in the kv cache.
+    num_cached: usize,
+    max_new_tokens: usize,
+    stop_tokens: Vec<u32>,
+    cancellation: Option<CancellationToken>,
+    adapter: Option<usize>,
+    priority: Priority,
+    client: Option<String>,
+    // The keys and values computed by a prefill worker or offloaded on preemption, imported
+    // when admitting the sequence.
+    prefilled: Option<KvBlocks>,
+    arrival: Instant,
+    logits_processor: LogitsProcessor,
+    sender: mpsc::Sender<GenerationEvent>,
+}
+
+impl Sequence {
+    fn send(&self, event: GenerationEvent) -> bool {
+        self.sender.send(event).is_ok()
+    }
+
+    fn is_cancelled(&self) -> bool {
+        self.cancellation.as_ref().is_some_and(|c| c.is_cancelled())
+    }
+
+    fn num_uncached(&self) -> usize {
+        self.tokens.len() - self.num_cached
+    }
+}
+
+pub struct Engine<M: EngineModel> {
+    model: M,
+    cache: PagedKvCache,
+    policy: Box<dyn BatchingPolicy>,
+    offload_preempted: bool,
+    fairness: Option<TokenBuckets>,
+    device: Device,
+    handle: Option<EngineHandle>,
+    submissions: mpsc::Receiver<Submission>,
+    waiting: Vec<Sequence>,
+    // The running sequences in admission order, all of them have their tokens in the cache.
+    running: Vec<Sequence>,
+}
+
+impl<M: EngineModel> Engine<M> {
+    pub fn new(model: M, cache: PagedKvCache, config: EngineConfig) -> Self {
+        let device = match cache.layer(0) {
+            Ok((k, _)) => k.device().clone(),
+            Err(_) => Device::Cpu,
+        };
+        let (sender, submissions) = mpsc::channel();
+        let handle = EngineHandle {
+            sender,
+            next_id: std::sync::Arc::new(std::sync::atomic::AtomicUsize::new(0)),
+        };
+        Self {
+            model,
+            cache,
+            policy: Box::new(FixedBudget(StepBudget {
+                max_batch_size: config.max_batch_size,
+                max_batch_tokens: config.max_batch_tokens,
+                chunked_prefill: config.chunked_prefill,
+            })),
+            offload_preempted: config.offload_preempted,
+            fairness: None,
+            device,
+            handle: Some(handle),
+            submissions,
+            waiting: vec![],
+            running: vec![],
+        }
+    }
+
+    /// Replaces the fixed budget given by the config.
+    pub fn with_policy(mut self, policy: impl BatchingPolicy + 'static) -> Self {
+        self.policy = Box::new(policy);
+        self
+    }
+
+    /// Balances the tokens processed for the clients of the requests, see
+    /// [`super::fairness`].
+    pub fn with_fairness(mut self, share: FairShare) -> Self {
+        self.fairness = Some(TokenBuckets::new(share));
+        self
+    }
+
+    pub fn handle(&self) -> EngineHandle {
+        match self.handle.as_ref() {
+            Some(handle) => handle.clone(),
+            // The handle is only dropped by `run` which consumes the engine.
+            None => unreachable!(),
+        }
+    }
+
+    pub fn submit(&self, request: GenerationRequest) -> Result<RequestStream> {
+        self.handle().submit(request)
+    }
+
+    /// See [`EngineHandle::submit_prefilled`].
+    pub fn submit_prefilled(
+        &self,
+        request: GenerationRequest,
+        blocks: KvBlocks,
+    ) -> Result<RequestStream> {
+        self.handle().submit_prefilled(request, blocks)
+    }
+
+    pub fn model(&self) -> &M {
+        &self.model
+    }
+
+    pub fn cache(&self) -> &PagedKvCache {
+        &self.cache
+    }
+
+    pub fn num_waiting(&self) -> usize {
+        self.waiting.len()
+    }
+
+    pub fn num_running(&self) -> usize {
+        self.running.len()
+    }
+
+    pub fn is_idle(&self) -> bool {
+        self.waiting.is_empty() && self.running.is_empty()
+    }
+
+    fn receive(&mut self, submission: Submission) {
+        let Submission {
+            id,
+            request,
+            prefilled,
+            sender,
+        } = submission;
+        if request.prompt.is_empty() {
+            let _ = sender.send(GenerationEvent::Error("empty prompt".to_string()));
+            return;
+        }
+        if let Some(adapter) = request.adapter.filter(|&a| !self.model.has_adapter(a)) {
+            let _ = sender.send(GenerationEvent::Error(format!("unknown adapter {adapter}")));
+            return;
+        }
+        if let Some(blocks) = prefilled.as_ref() {
+            let error = if blocks.seq_len() >= request.prompt.len() {
+                Some(format!(
+                    "{} prefilled tokens for a prompt of {} tokens",
+                    blocks.seq_len(),
+                    request.prompt.len()
+                ))
+            } else if blocks.block_size() != self.cache.block_size() {
+                Some(format!(
+                    "prefilled blocks of {} positions, the cache uses {}",
+                    blocks.block_size(),
+                    self.cache.block_size()
+                ))
+            } else {
+                None
+            };
+            if let Some(error) = error {
+                let _ = sender.send(GenerationEvent::Error(error));
+                return;
+            }
+        }
+        if request.max_new_tokens == 0 {
+            let _ = sender.send(GenerationEvent::Finished(FinishReason::Length));
+            return;
+        }
+        self.waiting.push(Sequence {
+            id,
+            tokens: request.prompt,
+            num_generated: 0,
+            num_cached: 0,
+            max_new_tokens: request.max_new_tokens,
+            stop_tokens: request.stop_tokens,
+            cancellation: request.cancellation,
+            adapter: request.adapter,
+            priority: request.priority,
+            client: request.client,
+            prefilled,
+            arrival: Instant::now(),
+            logits_processor: LogitsProcessor::from_sampling(request.seed, request.sampling),
+            sender,
+        })
+    }
+
+    // The blocks needed to process all the uncached tokens of a sequence.
+    fn blocks_needed(&self, seq: &Sequence) -> usize {
+        let bs = self.cache.block_size();
+        seq.tokens.len().div_ceil(bs) - seq.num_cached.div_ceil(bs)
+    }
+
+    fn state(&self) -> SchedulerState {
+        SchedulerState {
+            num_running: self.running.len(),
+            num_waiting: self.waiting.len(),
+            oldest_waiting: self
+                .waiting
+                .iter()
+                .filter(|s| s.num_generated == 0)
+                .map(|s| s.arrival.elapsed())
+                .max(),
+        }
+    }
+
+    // The free blocks once the running sequences have the blocks for all their tokens.
+    fn free_blocks(&self) -> usize {
+        let needed: usize = self.running.iter().map(|s| self.blocks_needed(s)).sum();
+        self.cache.num_free_blocks().saturating_sub(needed)
+    }
+
+    fn has_tokens(&self, seq: &Sequence) -> bool {
+        match (self.fairness.as_ref(), seq.client.as_ref()) {
+            (Some(fairness), Some(client)) => fairness.has_tokens(client),
+            _ => true,
+        }
+    }
+
+    // The index of the next waiting sequence to admit.
+    fn next_waiting(&self) -> Option<usize> {
+        (0..self.waiting.len()).max_by_key(|&i| {
+            let seq = &self.waiting[i];
+            (
+                seq.priority,
+                self.has_tokens(seq),
+                Reverse((seq.arrival, seq.id)),
+            )
+        })
+    }
+
+    // The index of the running sequence to preempt first, only the sequences with a priority
+    // lower than `below` are considered when set.
+    fn victim(&self, below: Option<Priority>) -> Option<usize> {
+        (0..self.running.len())
+            .filter(|&i| below.is_none_or(|p| self.running[i].priority < p))
+            .min_by_key(|&i| {
+                let seq = &self.running[i];
+                (seq.priority, self.has_tokens(seq), Reverse(i))
+            })
+    }
+
+    // Moves a running sequence back to the queue, its kv cache is offloaded if enabled.
+    fn preempt(&mut self, index: usize) -> Result<()> {
+        let mut seq = self.running.remove(index);
+        if self.offload_preempted && seq.num_cached > 0 {
+            let blocks = self.cache.export_sequence(seq.id)?;
+            seq.prefilled = Some(blocks.to_device(&Device::Cpu)?)
+        }
+        self.cache.remove_sequence(seq.id)?;
+        seq.num_cached = 0;
+        self.waiting.push(seq);
+        Ok(())
+    }
+
+    // Finishes the requests whose cancellation token has been triggered.
+    fn remove_cancelled(&mut self) -> Result<()> {
+        let cancelled = GenerationEvent::Finished(FinishReason::Cancelled);
+        for seq in self.waiting.iter().filter(|s| s.is_cancelled()) {
+            seq.send(cancelled.clone());
+        }
+        self.waiting.retain(|s| !s.is_cancelled());
+        let mut i = 0;
+        while i < self.running.len() {
+            if self.running[i].is_cancelled() {
+                let seq = self.running.remove(i);
+                self.cache.remove_sequence(seq.id)?;
+                seq.send(cancelled.clone());
+            } else {
+                i += 1
+            }
+        }
+        Ok(())
+    }
+
+    /// Runs a generation step, returns false if there was nothing to process.
+    pub fn step(&mut self) -> Result<bool> {
+        while let Ok(submission) = self.submissions.try_recv() {
+            self.receive(submission)
+        }
+        self.remove_cancelled()?;
+        if let Some(fairness) = self.fairness.as_mut() {
+            fairness.refill(Instant::now())
+        }
+        let budget = self.policy.budget(&self.state());
+        let max_batch_tokens = budget.max_batch_tokens.max(1);
+        // Cancelled requests whose stream has been dropped are detected when sending tokens,
+        // the running sequences decode one token each and get the blocks first.
+        while !self.running.is_empty() {
+            let needed: usize = self.running.iter().map(|s| self.blocks_needed(s)).sum();
+            if needed <= self.cache.num_free_blocks() {
+                break;
+            }
+            if self.running.len() == 1 {
+                let seq = self.running.remove(0);
+                self.cache.remove_sequence(seq.id)?;
+                seq.send(GenerationEvent::Error(
+                    "the kv cache is too small for the sequence".to_string(),
+                ));
+            } else if let Some(victim) = self.victim(None) {
+                self.preempt(victim)?
+            }
+        }
+        let mut free_blocks = self.free_blocks();
+        // The number of tokens processed for each running sequence, the decoding sequences get
+        // their token first and the prompts being processed by chunks share the rest of the
+        // budget, they are skipped for this step when there is nothing left.
+        let mut plan = self
+            .running
+            .iter()
+            .map(|s| s.num_uncached())
+            .collect::<Vec<_>>();
+        let mut num_tokens = plan.iter().filter(|&&n| n == 1).count();
+        for n in plan.iter_mut().filter(|n| **n > 1) {
+            if budget.chunked_prefill {
+                *n = (*n).min(max_batch_tokens.saturating_sub(num_tokens))
+            }
+            num_tokens += *n
+        }
+        while let Some(index) = self.next_waiting() {
+            let seq = &self.waiting[index];
+            let needed = self.blocks_needed(seq);
+            // Make room for the sequence by preempting the ones with a lower priority.
+            if self.running.len() >= budget.max_batch_size || needed > free_blocks {
+                if let Some(victim) = self.victim(Some(seq.priority)) {
+                    num_tokens -= plan.remove(victim);
+                    self.preempt(victim)?;
+                    free_blocks = self.free_blocks();
+                    continue;
+                }
+            }
+            if self.running.len() >= budget.max_batch_size {
+                break;
+            }
+            let new_tokens = seq.tokens.len() - seq.prefilled.as_ref().map_or(0, |b| b.seq_len());
+            let tokens_left = max_batch_tokens.saturating_sub(num_tokens);
+            let (chunk, fits_budget) = if budget.chunked_prefill {
+                (new_tokens.min(tokens_left), tokens_left > 0)
+            } else {
+                (new_tokens, new_tokens <= tokens_left)
+            };
+            if needed > free_blocks || !(fits_budget || self.running.is_empty()) {
+                if self.running.is_empty() && needed > self.cache.allocator().num_blocks() {
+                    let seq = self.waiting.remove(index);
+                    seq.send(GenerationEvent::Error(
+                        "the kv cache is too small for the sequence".to_string(),
+                    ));
+                    continue;
+                }
+                break;
+            }
+            let mut seq = self.waiting.remove(index);
+            match seq.prefilled.take() {
+                Some(blocks) => {
+                    if let Err(err) = self.cache.import_sequence(seq.id, &blocks) {
+                        seq.send(GenerationEvent::Error(err.to_string()));
+                        continue;
+                    }
+                    seq.num_cached = blocks.seq_len()
+                }
+                None => self.cache.add_sequence(seq.id)?,
+            }
+            free_blocks -= needed;
+            num_tokens += chunk;
+            plan.push(chunk);
+            self.running.push(seq)
+        }
+        if self.running.is_empty() {
+            return Ok(false);
+        }
+        match self.forward(&plan) {
+            Ok(stats) => self.policy.record(&stats),
+            Err(err) => {
+                // Report the error to all the scheduled requests.
+                for seq in self.running.drain(..) {
+                    self.cache.remove_sequence(seq.id)?;
+                    seq.send(GenerationEvent::Error(err.to_string()));
+                }
+                return Err(err);
+            }
+        }
+        Ok(true)
+    }
+
+    // Processes `plan[i]` tokens for the i-th running sequence, the sequences with no tokens
+    // are not part of the batch.
+    fn forward(&mut self, plan: &[usize]) -> Result<StepStats> {
+        let start = Instant::now();
+        let scheduled = self
+            .running
+            .iter()
+            .zip(plan.iter())
+            .filter(|(_, &n)| n > 0)
+            .collect::<Vec<_>>();
+        let seqs = scheduled
+            .iter()
+            .map(|(s, &n)| (s.id, n))
+            .collect::<Vec<_>>();
+        let batch = self.cache.prepare(&seqs)?;
+        let mut tokens = Vec::with_capacity(batch.num_tokens());
+        let mut positions = Vec::with_capacity(batch.num_tokens());
+        let mut num_decode_sequences = 0;
+        for &(seq, &n) in scheduled.iter() {
+            let end = seq.num_cached + n;
+            tokens.extend_from_slice(&seq.tokens[seq.num_cached..end]);
+            positions.extend(seq.num_cached as u32..end as u32);
+            if n == 1 && end == seq.tokens.len() && seq.num_generated > 0 {
+                num_decode_sequences += 1
+            }
+        }
+        // The sequences that use the same adapter are processed together by the layers, the
+        // consecutive ones are merged in a single segment.
+        let mut segments: Vec<AdapterSegment> = vec![];
+        for &(seq, &len) in scheduled.iter() {
+            match segments.last_mut() {
+                Some(last) if last.adapter == seq.adapter => last.len += len,
+                _ => segments.push(AdapterSegment::new(seq.adapter, len)),
+            }
+        }
+        self.model.set_adapters(&segments)?;
+        let num_tokens = tokens.len();
+        let tokens = Tensor::from_vec(tokens, num_tokens, &self.device)?;
+        let positions = Tensor::from_vec(positions, num_tokens, &self.device)?;
+        let logits = self
+            .model
+            .forward(&tokens, &positions, &batch, &self.cache)?;
+        let mut stats = StepStats {
+            duration: Default::default(),
+            num_sequences: seqs.len(),
+            num_decode_sequences,
+            num_tokens,
+            num_prefill_tokens: num_tokens - num_decode_sequences,
+            time_to_first_token: vec![],
+        };
+        let mut finished = vec![];
+        let mut row = 0;
+        for (i, (seq, &n)) in self.running.iter_mut().zip(plan.iter()).enumerate() {
+            if n == 0 {
+                continue;
+            }
+            row += 1;
+            seq.num_cached += n;
+            if let (Some(fairness), Some(client)) = (self.fairness.as_mut(), seq.client.as_ref()) {
+                fairness.consume(client, n, start)
+            }
+            if seq.num_cached < seq.tokens.len() {
+                // Only a chunk of the prompt has been processed.
+                continue;
+            }
+            let token = seq.logits_processor.sample(&logits.get(row - 1)?)?;
+            if seq.num_generated == 0 {
+                stats.time_to_first_token.push(seq.arrival.elapsed())
+            }
+            seq.tokens.push(token);
+            seq.num_generated += 1;
+            let alive = seq.send(GenerationEvent::Token(token));
+            let reason = if seq.stop_tokens.contains(&token) {
+                Some(FinishReason::Stop)
+            } else if seq.num_generated >= seq.max_new_tokens {
+                Some(FinishReason::Length)
+            } else {
+                None
+            };
+            match reason {
+                Some(reason) => {
+                    seq.send(GenerationEvent::Finished(reason));
+                    finished.push(i)
+                }
+                None if !alive => finished.push(i),
+                None => {}
+            }
+        }
+        for &i in finished.iter().rev() {
+            let seq = self.running.remove(i);
+            self.cache.remove_sequence(seq.id)?
+        }
+        stats.duration = start.elapsed();
+        Ok(stats)
+    }
+
+    /// Processes the requests until all the handles have been dropped and all the requests are
+    /// done. Errors are reported to the affected requests and the engine keeps running.
+    pub fn run(mut self) {
+        self.handle = None;
+        loop {
+            if self.is_idle() {
+                match self.submissions.recv() {
+                    Ok(submission) => self.receive(submission),
+                    Err(_) => return,
+                }
+            }
+            let _ = self.step();
+        }
+    }
+}
+
+/// Processes `prompt` on a prefill worker, returns the logits for its last token with shape
+/// `(vocab_size,)` and the keys and values of the prompt, to be sent to a decode worker. The
+/// sequence only uses `cache` during the call.
+pub fn prefill<M: EngineModel>(
+    model: &mut M,
+    cache: &mut PagedKvCache,
+    prompt: &[u32],
+    adapter: Option<usize>,
+) -> Result<(Tensor, KvBlocks)> {
+    if prompt.is_empty() {
+        candle::bail!("empty prompt")
+    }
+    if let Some(adapter) = adapter.filter(|&a| !model.has_adapter(a)) {
+        candle::bail!("unknown adapter {adapter}")
+    }
+    let seq_id = cache.seq_ids().max().map_or(0, |id| id + 1);
+    let device = cache.layer(0)?.0.device().clone();
+    cache.add_sequence(seq_id)?;
+    let result = (|| {
+        let batch = cache.prepare(&[(seq_id, prompt.len())])?;
+        model.set_adapters(&[AdapterSegment::new(adapter, prompt.len())])?;
+        let tokens = Tensor::new(prompt, &device)?;
+        let positions = Tensor::arange(0u32, prompt.len() as u32, &device)?;
+        let logits = model.forward(&tokens, &positions, &batch, cache)?.get(0)?;
+        Ok((logits, cache.export_sequence(seq_id)?))
+    })();
+    cache.remove_sequence(seq_id)?;
+    result
+}
@@ -0,0 +1,79 @@
+//! Per-client fairness for the [`super::Engine`] scheduler.
+//!
+//! Each client, as set with [`super::GenerationRequest::with_client`], gets a token bucket that
+//! refills at [`FairShare::tokens_per_second`] up to [`FairShare::burst`] tokens. Every prompt
+//! or generated token processed for a client is taken from its bucket, which can go negative.
+//! Among the requests with the same priority, the ones from clients with an empty bucket are
+//! only admitted after the others and are preempted first, so that one client submitting many
+//! requests does not delay the requests of the other clients.
+use std::collections::HashMap;
+use std::time::Instant;
+
+/// The share of the engine throughput guaranteed to each client.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FairShare {
+    /// The rate at which the bucket of a client refills.
+    pub tokens_per_second: f64,
+    /// The capacity of the buckets, i.e. the number of tokens a client can use at once before
+    /// being deprioritized.
+    pub burst: f64,
+}
+
+impl FairShare {
+    pub fn new(tokens_per_second: f64, burst: f64) -> Self {
+        Self {
+            tokens_per_second,
+            burst,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Bucket {
+    tokens: f64,
+    last: Instant,
+}
+
+/// The token buckets of the clients, a client without a bucket has a full one.
+#[derive(Debug, Clone)]
+pub(super) struct TokenBuckets {
+    share: FairShare,
+    buckets: HashMap<String, Bucket>,
+}
+
+impl TokenBuckets {
+    pub(super) fn new(share: FairShare) -> Self {
+        Self {
+            share,
+            buckets: HashMap::new(),
+        }
+    }
+
+    /// Refills the buckets, the full ones are dropped.
+    pub(super) fn refill(&mut self, now: Instant) {
+        let FairShare {
+            tokens_per_second,
+            burst,
+        } = self.share;
+        self.buckets.retain(|_, b| {
+            let elapsed = now.saturating_duration_since(b.last).as_secs_f64();
+            b.tokens = (b.tokens + elapsed * tokens_per_second).min(burst);
+            b.last = now;
+            b.tokens < burst
+        })
+    }
+
+    pub(super) fn consume(&mut self, client: &str, tokens: usize, now: Instant) {
+        let burst = self.share.burst;
+        let bucket = self.buckets.entry(client.to_string()).or_insert(Bucket {
+            tokens: burst,
+            last: now,
+        });
+        bucket.tokens -= tokens as f64
+    }
+
+    /// Whether the client has some tokens left in its bucket.
+    pub(super) fn has_tokens(&self, client: &str) -> bool {
+        self.buckets.get(client).is_none_or(|b| b.tokens > 0.)
+    }
+}
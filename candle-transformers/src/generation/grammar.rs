@@ -0,0 +1,776 @@
+//! Grammar constrained decoding.
+//!
+//! A [`Grammar`] is parsed from the GBNF format used by llama.cpp, or generated from a JSON
+//! schema with [`Grammar::from_json_schema`]. A [`GrammarConstraint`] tracks the state of the
+//! grammar while the tokens are generated and masks the logits of the tokens that would not be
+//! valid, the tokens of the vocabulary are stored in a prefix tree so that the allowed tokens
+//! are found with a single walk of the tree from the current state.
+//!
+//! ```text
+//! root   ::= answer ws
+//! answer ::= "yes" | "no" | "maybe (" [0-9]+ "%)"
+//! ws     ::= [ \t\n]*
+//! ```
+//!
+//! The grammar supports literals, character classes `[a-z]` and `[^"]`, the any character `.`,
+//! groups, alternatives and the `*`, `+`, `?`, `{m}`, `{m,}` and `{m,n}` repetitions. Comments start
+//! with `#` and the start rule is `root`. Left recursive rules are rejected.
+//!
+//! ```ignore
+//! let grammar = Grammar::parse(GBNF)?;
+//! let mut constraint = GrammarConstraint::new(grammar, &token_bytes, &[eos_token])?;
+//! let mut logits_processor = LogitsProcessor::new(42, Some(0.8), None);
+//! loop {
+//!     let logits = model.forward(&input, pos)?;
+//!     let token = logits_processor.sample_constrained(&logits, &mut constraint)?;
+//!     if token == eos_token {
+//!         break;
+//!     }
+//!     ...
+//! }
+//! ```
+use candle::{Result, Tensor};
+use std::collections::HashMap;
+use std::sync::Arc;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Element {
+    // Matches a character in one of the ranges, or not in any of them when negated.
+    Chars {
+        ranges: Vec<(char, char)>,
+        negated: bool,
+    },
+    Rule(usize),
+}
+
+impl Element {
+    fn char(c: char) -> Self {
+        Self::Chars {
+            ranges: vec![(c, c)],
+            negated: false,
+        }
+    }
+
+    fn matches(&self, c: char) -> bool {
+        match self {
+            Self::Chars { ranges, negated } => {
+                ranges.iter().any(|&(lo, hi)| lo <= c && c <= hi) != *negated
+            }
+            Self::Rule(_) => false,
+        }
+    }
+
+    // Returns true when some character with a code point in `lo..=hi` is matched.
+    fn matches_any(&self, lo: u32, hi: u32) -> bool {
+        match self {
+            Self::Chars {
+                ranges,
+                negated: false,
+            } => ranges
+                .iter()
+                .any(|&(l, h)| l as u32 <= hi && lo <= h as u32),
+            Self::Chars {
+                ranges,
+                negated: true,
+            } => {
+                // Matched unless the ranges cover the whole interval.
+                let mut ranges = ranges.clone();
+                ranges.sort();
+                let mut next = lo;
+                for (l, h) in ranges {
+                    if l as u32 > next {
+                        break;
+                    }
+                    next = next.max(h as u32 + 1);
+                }
+                next <= hi
+            }
+            Self::Rule(_) => false,
+        }
+    }
+}
+
+/// A context free grammar over characters.
+#[derive(Debug, Clone)]
+pub struct Grammar {
+    // The alternatives of each rule, each alternative is a sequence of elements.
+    rules: Vec<Vec<Vec<Element>>>,
+    names: Vec<String>,
+    root: usize,
+}
+
+struct Parser<'a> {
+    src: &'a [char],
+    pos: usize,
+    rules: Vec<Option<Vec<Vec<Element>>>>,
+    names: Vec<String>,
+    ids: HashMap<String, usize>,
+}
+
+impl Parser<'_> {
+    fn error<T>(&self, msg: &str) -> Result<T> {
+        let line = self.src[..self.pos.min(self.src.len())]
+            .iter()
+            .filter(|&&c| c == '\n')
+            .count();
+        candle::bail!("grammar: {msg} on line {}", line + 1)
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.src.get(self.pos).copied()
+    }
+
+    // Skips the spaces and comments, the newlines are only skipped when `newlines` is set.
+    fn skip_space(&mut self, newlines: bool) {
+        while let Some(c) = self.peek() {
+            if c == '#' {
+                while self.peek().is_some_and(|c| c != '\n') {
+                    self.pos += 1
+                }
+            } else if c == ' ' || c == '\t' || c == '\r' || (newlines && c == '\n') {
+                self.pos += 1
+            } else {
+                break;
+            }
+        }
+    }
+
+    fn rule_id(&mut self, name: &str) -> usize {
+        if let Some(&id) = self.ids.get(name) {
+            return id;
+        }
+        let id = self.rules.len();
+        self.rules.push(None);
+        self.names.push(name.to_string());
+        self.ids.insert(name.to_string(), id);
+        id
+    }
+
+    fn new_rule(&mut self, base: usize, alternatives: Vec<Vec<Element>>) -> usize {
+        let name = format!("{}_{}", self.names[base], self.rules.len());
+        let id = self.rule_id(&name);
+        self.rules[id] = Some(alternatives);
+        id
+    }
+
+    fn parse_name(&mut self) -> Result<String> {
+        let start = self.pos;
+        while self
+            .peek()
+            .is_some_and(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        {
+            self.pos += 1
+        }
+        if start == self.pos {
+            return self.error("expected a rule name");
+        }
+        Ok(self.src[start..self.pos].iter().collect())
+    }
+
+    fn parse_hex(&mut self, len: usize) -> Result<char> {
+        let mut value = 0u32;
+        for _ in 0..len {
+            let Some(d) = self.peek().and_then(|c| c.to_digit(16)) else {
+                return self.error("invalid hex escape");
+            };
+            value = value * 16 + d;
+            self.pos += 1;
+        }
+        match char::from_u32(value) {
+            Some(c) => Ok(c),
+            None => self.error("invalid unicode escape"),
+        }
+    }
+
+    fn parse_char(&mut self) -> Result<char> {
+        let Some(c) = self.peek() else {
+            return self.error("unexpected end of input");
+        };
+        self.pos += 1;
+        if c != '\\' {
+            return Ok(c);
+        }
+        let Some(e) = self.peek() else {
+            return self.error("unexpected end of input");
+        };
+        self.pos += 1;
+        match e {
+            'n' => Ok('\n'),
+            'r' => Ok('\r'),
+            't' => Ok('\t'),
+            'x' => self.parse_hex(2),
+            'u' => self.parse_hex(4),
+            'U' => self.parse_hex(8),
+            e => Ok(e),
+        }
+    }
+
+    fn parse_usize(&mut self) -> Result<usize> {
+        let start = self.pos;
+        while self.peek().is_some_and(|c| c.is_ascii_digit()) {
+            self.pos += 1
+        }
+        let digits: String = self.src[start..self.pos].iter().collect();
+        match digits.parse() {
+            Ok(v) => Ok(v),
+            Err(_) => self.error("expected a number"),
+        }
+    }
+
+    fn parse_alternatives(&mut self, rule: usize, nested: bool) -> Result<Vec<Vec<Element>>> {
+        let mut alternatives = vec![self.parse_sequence(rule, nested)?];
+        loop {
+            let pos = self.pos;
+            self.skip_space(true);
+            if self.peek() == Some('|') {
+                self.pos += 1;
+                self.skip_space(true);
+                alternatives.push(self.parse_sequence(rule, nested)?)
+            } else {
+                if !nested {
+                    self.pos = pos
+                }
+                return Ok(alternatives);
+            }
+        }
+    }
+
+    fn parse_sequence(&mut self, rule: usize, nested: bool) -> Result<Vec<Element>> {
+        let mut seq = vec![];
+        loop {
+            self.skip_space(nested);
+            let start = seq.len();
+            match self.peek() {
+                Some('"') => {
+                    self.pos += 1;
+                    while self.peek() != Some('"') {
+                        let c = self.parse_char()?;
+                        seq.push(Element::char(c))
+                    }
+                    self.pos += 1;
+                }
+                Some('[') => {
+                    self.pos += 1;
+                    let negated = self.peek() == Some('^');
+                    if negated {
+                        self.pos += 1
+                    }
+                    let mut ranges = vec![];
+                    while self.peek() != Some(']') {
+                        let lo = self.parse_char()?;
+                        let hi = if self.peek() == Some('-')
+                            && self.src.get(self.pos + 1) != Some(&']')
+                        {
+                            self.pos += 1;
+                            self.parse_char()?
+                        } else {
+                            lo
+                        };
+                        ranges.push((lo, hi))
+                    }
+                    self.pos += 1;
+                    seq.push(Element::Chars { ranges, negated })
+                }
+                Some('.') => {
+                    self.pos += 1;
+                    seq.push(Element::Chars {
+                        ranges: vec![],
+                        negated: true,
+                    })
+                }
+                Some('(') => {
+                    self.pos += 1;
+                    self.skip_space(true);
+                    let alternatives = self.parse_alternatives(rule, true)?;
+                    self.skip_space(true);
+                    if self.peek() != Some(')') {
+                        return self.error("expected ')'");
+                    }
+                    self.pos += 1;
+                    seq.push(Element::Rule(self.new_rule(rule, alternatives)))
+                }
+                Some(c) if c.is_ascii_alphanumeric() || c == '-' || c == '_' => {
+                    let name = self.parse_name()?;
+                    seq.push(Element::Rule(self.rule_id(&name)))
+                }
+                _ => return Ok(seq),
+            }
+            // Repetitions apply to the last item, multi character literals are wrapped in a rule.
+            let (min, max) = match self.peek() {
+                Some('*') => (0, None),
+                Some('+') => (1, None),
+                Some('?') => (0, Some(1)),
+                Some('{') => {
+                    self.pos += 1;
+                    self.skip_space(false);
+                    let min = self.parse_usize()?;
+                    self.skip_space(false);
+                    let max = if self.peek() == Some(',') {
+                        self.pos += 1;
+                        self.skip_space(false);
+                        if self.peek() == Some('}') {
+                            None
+                        } else {
+                            Some(self.parse_usize()?)
+                        }
+                    } else {
+                        Some(min)
+                    };
+                    self.skip_space(false);
+                    if self.peek() != Some('}') {
+                        return self.error("expected '}'");
+                    }
+                    if max.is_some_and(|max| max < min) {
+                        return self.error("invalid repetition bounds");
+                    }
+                    (min, max)
+                }
+                _ => continue,
+            };
+            self.pos += 1;
+            let item = seq.split_off(start);
+            let item = match item.as_slice() {
+                [] => return self.error("nothing to repeat"),
+                [e] => e.clone(),
+                _ => Element::Rule(self.new_rule(rule, vec![item])),
+            };
+            seq.extend(std::iter::repeat_n(item.clone(), min));
+            match max {
+                None => {
+                    // r ::= item r | empty
+                    let id = self.new_rule(rule, vec![]);
+                    self.rules[id] = Some(vec![vec![item, Element::Rule(id)], vec![]]);
+                    seq.push(Element::Rule(id))
+                }
+                Some(max) if max > min => {
+                    // r_k ::= item r_{k+1} | empty, nested for the optional repetitions.
+                    let mut tail = self.new_rule(rule, vec![vec![item.clone()], vec![]]);
+                    for _ in min + 1..max {
+                        tail = self
+                            .new_rule(rule, vec![vec![item.clone(), Element::Rule(tail)], vec![]]);
+                    }
+                    seq.push(Element::Rule(tail))
+                }
+                Some(_) => {}
+            }
+        }
+    }
+
+    fn parse_grammar(mut self) -> Result<Grammar> {
+        loop {
+            self.skip_space(true);
+            if self.peek().is_none() {
+                break;
+            }
+            let name = self.parse_name()?;
+            self.skip_space(false);
+            if !self.src[self.pos..].starts_with(&[':', ':', '=']) {
+                return self.error("expected '::='");
+            }
+            self.pos += 3;
+            let id = self.rule_id(&name);
+            if self.rules[id].is_some() {
+                return self.error(&format!("rule {name} is defined twice"));
+            }
+            let alternatives = self.parse_alternatives(id, false)?;
+            self.rules[id] = Some(alternatives);
+            self.skip_space(false);
+            match self.peek() {
+                None | Some('\n') => {}
+                Some(c) => return self.error(&format!("unexpected character '{c}'")),
+            }
+        }
+        let mut rules = Vec::with_capacity(self.rules.len());
+        for (rule, name) in self.rules.into_iter().zip(self.names.iter()) {
+            match rule {
+                Some(rule) => rules.push(rule),
+                None => candle::bail!("grammar: undefined rule {name}"),
+            }
+        }
+        let root = match self.ids.get("root") {
+            Some(&root) => root,
+            None => candle::bail!("grammar: missing root rule"),
+        };
+        let grammar = Grammar {
+            rules,
+            names: self.names,
+            root,
+        };
+        grammar.check_left_recursion()?;
+        Ok(grammar)
+    }
+}
+
+// A position in the grammar: the element `pos` of the alternative `alt` of `rule`.
+type Frame = (u32, u32, u32);
+// The frames that remain to be matched, the top of the stack is the last frame.
+type Stack = Vec<Frame>;
+
+impl Grammar {
+    /// Parses a grammar in the GBNF format.
+    pub fn parse(src: &str) -> Result<Self> {
+        let src = src.chars().collect::<Vec<_>>();
+        let parser = Parser {
+            src: &src,
+            pos: 0,
+            rules: vec![],
+            names: vec![],
+            ids: HashMap::new(),
+        };
+        parser.parse_grammar()
+    }
+
+    /// The grammar of the JSON documents that follow `schema`, see
+    /// [`super::json_schema::json_schema_to_gbnf`].
+    pub fn from_json_schema(schema: &serde_json::Value) -> Result<Self> {
+        Self::parse(&super::json_schema::json_schema_to_gbnf(schema)?)
+    }
+
+    fn check_left_recursion(&self) -> Result<()> {
+        let n = self.rules.len();
+        let mut nullable = vec![false; n];
+        loop {
+            let mut changed = false;
+            for (r, alternatives) in self.rules.iter().enumerate() {
+                if nullable[r] {
+                    continue;
+                }
+                let is_nullable = alternatives.iter().any(|alt| {
+                    alt.iter().all(|e| match e {
+                        Element::Rule(k) => nullable[*k],
+                        Element::Chars { .. } => false,
+                    })
+                });
+                if is_nullable {
+                    nullable[r] = true;
+                    changed = true
+                }
+            }
+            if !changed {
+                break;
+            }
+        }
+        // The rules that can be expanded without consuming any character.
+        let mut edges = vec![vec![]; n];
+        for (r, alternatives) in self.rules.iter().enumerate() {
+            for alt in alternatives.iter() {
+                for e in alt.iter() {
+                    match e {
+                        Element::Rule(k) => {
+                            edges[r].push(*k);
+                            if !nullable[*k] {
+                                break;
+                            }
+                        }
+                        Element::Chars { .. } => break,
+                    }
+                }
+            }
+        }
+        // 0: not visited, 1: in progress, 2: done.
+        let mut state = vec![0u8; n];
+        fn visit(r: usize, edges: &[Vec<usize>], state: &mut [u8]) -> Option<usize> {
+            state[r] = 1;
+            for &k in edges[r].iter() {
+                match state[k] {
+                    1 => return Some(k),
+                    0 => {
+                        if let Some(k) = visit(k, edges, state) {
+                            return Some(k);
+                        }
+                    }
+                    _ => {}
+                }
+            }
+            state[r] = 2;
+            None
+        }
+        for r in 0..n {
+            if state[r] == 0 {
+                if let Some(k) = visit(r, &edges, &mut state) {
+                    candle::bail!("grammar: rule {} is left recursive", self.names[k])
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn element(&self, (rule, alt, pos): Frame) -> &Element {
+        &self.rules[rule as usize][alt as usize][pos as usize]
+    }
+
+    // Pushes the position after `frame` if any.
+    fn push_next(&self, stack: &mut Stack, (rule, alt, pos): Frame) {
+        if (pos as usize) + 1 < self.rules[rule as usize][alt as usize].len() {
+            stack.push((rule, alt, pos + 1))
+        }
+    }
+
+    // Expands the rule references at the top of the stack until a character element is at the
+    // top, or the stack is empty.
+    fn expand(&self, stack: Stack, out: &mut Vec<Stack>) {
+        let Some(&top) = stack.last() else {
+            out.push(stack);
+            return;
+        };
+        match self.element(top) {
+            Element::Chars { .. } => out.push(stack),
+            Element::Rule(k) => {
+                let mut rest = stack;
+                rest.pop();
+                self.push_next(&mut rest, top);
+                for (alt, elements) in self.rules[*k].iter().enumerate() {
+                    let mut stack = rest.clone();
+                    if !elements.is_empty() {
+                        stack.push((*k as u32, alt as u32, 0))
+                    }
+                    self.expand(stack, out)
+                }
+            }
+        }
+    }
+
+    fn initial_stacks(&self) -> Vec<Stack> {
+        let mut stacks = vec![];
+        for (alt, elements) in self.rules[self.root].iter().enumerate() {
+            let stack = if elements.is_empty() {
+                vec![]
+            } else {
+                vec![(self.root as u32, alt as u32, 0)]
+            };
+            self.expand(stack, &mut stacks)
+        }
+        stacks.sort();
+        stacks.dedup();
+        stacks
+    }
+
+    // The stacks after matching `c`, empty if `c` is not valid.
+    fn advance(&self, stacks: &[Stack], c: char) -> Vec<Stack> {
+        let mut out = vec![];
+        for stack in stacks.iter() {
+            let Some(&top) = stack.last() else {
+                continue;
+            };
+            if self.element(top).matches(c) {
+                let mut stack = stack.clone();
+                stack.pop();
+                self.push_next(&mut stack, top);
+                self.expand(stack, &mut out)
+            }
+        }
+        out.sort();
+        out.dedup();
+        out
+    }
+
+    // Returns true when a character whose code point is in `lo..=hi` can be matched.
+    fn accepts_any(&self, stacks: &[Stack], lo: u32, hi: u32) -> bool {
+        stacks.iter().any(|s| {
+            s.last()
+                .is_some_and(|&top| self.element(top).matches_any(lo, hi))
+        })
+    }
+
+    /// Returns true when the whole `text` is matched by the grammar.
+    pub fn matches(&self, text: &str) -> bool {
+        let mut stacks = self.initial_stacks();
+        for c in text.chars() {
+            stacks = self.advance(&stacks, c);
+            if stacks.is_empty() {
+                return false;
+            }
+        }
+        stacks.iter().any(|s| s.is_empty())
+    }
+}
+
+#[derive(Debug, Default)]
+struct TrieNode {
+    children: Vec<(u8, usize)>,
+    // The tokens that end at this node.
+    tokens: Vec<u32>,
+}
+
+// A prefix tree of the tokens of the vocabulary.
+#[derive(Debug)]
+struct TokenTrie {
+    nodes: Vec<TrieNode>,
+}
+
+impl TokenTrie {
+    fn new(tokens: &[Vec<u8>], skip: &[u32]) -> Self {
+        let mut nodes = vec![TrieNode::default()];
+        for (id, token) in tokens.iter().enumerate() {
+            if token.is_empty() || skip.contains(&(id as u32)) {
+                continue;
+            }
+            let mut node = 0;
+            for &b in token.iter() {
+                node = match nodes[node].children.iter().find(|(k, _)| *k == b) {
+                    Some(&(_, child)) => child,
+                    None => {
+                        nodes.push(TrieNode::default());
+                        let child = nodes.len() - 1;
+                        nodes[node].children.push((b, child));
+                        child
+                    }
+                }
+            }
+            nodes[node].tokens.push(id as u32)
+        }
+        Self { nodes }
+    }
+}
+
+// The grammar stacks, and the bytes of a character that has only been partially generated.
+type State = (Vec<Stack>, Vec<u8>);
+
+/// The state of a grammar during the generation.
+#[derive(Debug, Clone)]
+pub struct GrammarConstraint {
+    grammar: Arc<Grammar>,
+    trie: Arc<TokenTrie>,
+    tokens: Arc<Vec<Vec<u8>>>,
+    eos_tokens: Vec<u32>,
+    stacks: Vec<Stack>,
+    partial: Vec<u8>,
+}
+
+impl GrammarConstraint {
+    /// `tokens` contains the bytes of each token of the vocabulary, indexed by token id. A token
+    /// does not have to be valid UTF-8 on its own as with byte level BPE, a character can be split
+    /// over multiple tokens. The empty tokens are never allowed, e.g. they can be used for the
+    /// special tokens. The end of sequence tokens are only allowed once the grammar has been
+    /// fully matched.
+    pub fn new(grammar: Grammar, tokens: &[Vec<u8>], eos_tokens: &[u32]) -> Result<Self> {
+        let stacks = grammar.initial_stacks();
+        Ok(Self {
+            trie: Arc::new(TokenTrie::new(tokens, eos_tokens)),
+            tokens: Arc::new(tokens.to_vec()),
+            grammar: Arc::new(grammar),
+            eos_tokens: eos_tokens.to_vec(),
+            stacks,
+            partial: vec![],
+        })
+    }
+
+    // The state after the byte `b`, `None` if no valid text starts with the new bytes.
+    fn advance_byte(&self, (stacks, partial): &State, b: u8) -> Option<State> {
+        let mut bytes = partial.clone();
+        bytes.push(b);
+        match std::str::from_utf8(&bytes) {
+            Ok(text) => {
+                let c = text.chars().next()?;
+                let stacks = self.grammar.advance(stacks, c);
+                (!stacks.is_empty()).then_some((stacks, vec![]))
+            }
+            // A valid prefix of a character, the code points it can still become have to be
+            // matched by the grammar.
+            Err(err) if err.valid_up_to() == 0 && err.error_len().is_none() => {
+                let len = match bytes[0] {
+                    0xc0..=0xdf => 2,
+                    0xe0..=0xef => 3,
+                    _ => 4,
+                };
+                let mut value = (bytes[0] & (0x7f >> len)) as u32;
+                for &c in bytes[1..].iter() {
+                    value = value << 6 | (c & 0x3f) as u32
+                }
+                let shift = 6 * (len - bytes.len());
+                let lo = value << shift;
+                let hi = lo | ((1 << shift) - 1);
+                self.grammar
+                    .accepts_any(stacks, lo, hi)
+                    .then(|| (stacks.clone(), bytes))
+            }
+            Err(_) => None,
+        }
+    }
+
+    pub fn grammar(&self) -> &Grammar {
+        &self.grammar
+    }
+
+    /// Goes back to the start of the grammar.
+    pub fn reset(&mut self) {
+        self.stacks = self.grammar.initial_stacks();
+        self.partial.clear()
+    }
+
+    /// Returns true when the text generated so far is matched by the grammar, the generation can
+    /// then stop with an end of sequence token.
+    pub fn is_complete(&self) -> bool {
+        self.partial.is_empty() && self.stacks.iter().any(|s| s.is_empty())
+    }
+
+    /// Returns true when no more characters can be generated.
+    pub fn is_finished(&self) -> bool {
+        self.partial.is_empty() && self.stacks.iter().all(|s| s.is_empty())
+    }
+
+    /// The tokens that can be generated next, sorted by id.
+    pub fn allowed_tokens(&self) -> Vec<u32> {
+        let mut allowed = vec![];
+        let mut todo = vec![(0, (self.stacks.clone(), self.partial.clone()))];
+        while let Some((node, state)) = todo.pop() {
+            for &(b, child) in self.trie.nodes[node].children.iter() {
+                if let Some(state) = self.advance_byte(&state, b) {
+                    allowed.extend_from_slice(&self.trie.nodes[child].tokens);
+                    todo.push((child, state))
+                }
+            }
+        }
+        if self.is_complete() {
+            allowed.extend_from_slice(&self.eos_tokens)
+        }
+        allowed.sort();
+        allowed.dedup();
+        allowed
+    }
+
+    /// Sets the logits of the tokens that are not allowed to minus infinity, `logits` has the
+    /// vocabulary as its last dimension.
+    pub fn mask_logits(&self, logits: &Tensor) -> Result<Tensor> {
+        let vocab_size = logits.dim(candle::D::Minus1)?;
+        let allowed = self.allowed_tokens();
+        if allowed.is_empty() {
+            candle::bail!("grammar: no token can be generated")
+        }
+        let mut mask = vec![f32::NEG_INFINITY; vocab_size];
+        for &token in allowed.iter() {
+            if let Some(m) = mask.get_mut(token as usize) {
+                *m = 0.
+            }
+        }
+        let mask = Tensor::new(mask, logits.device())?.to_dtype(logits.dtype())?;
+        logits.broadcast_add(&mask)
+    }
+
+    /// Updates the state with a generated token, returns an error if the token is not allowed.
+    pub fn advance(&mut self, token: u32) -> Result<()> {
+        if self.eos_tokens.contains(&token) {
+            if !self.is_complete() {
+                candle::bail!("grammar: end of sequence token {token} before the end")
+            }
+            self.stacks = vec![vec![]];
+            return Ok(());
+        }
+        let bytes = match self.tokens.get(token as usize) {
+            Some(bytes) if !bytes.is_empty() => bytes,
+            _ => candle::bail!("grammar: token {token} is not allowed"),
+        };
+        let mut state = (self.stacks.clone(), self.partial.clone());
+        for &b in bytes.iter() {
+            state = match self.advance_byte(&state, b) {
+                Some(state) => state,
+                None => candle::bail!(
+                    "grammar: token {token} {:?} is not allowed",
+                    String::from_utf8_lossy(bytes)
+                ),
+            }
+        }
+        (self.stacks, self.partial) = state;
+        Ok(())
+    }
+}
@@ -0,0 +1,278 @@
+//! Conversion of JSON schemas to GBNF grammars.
+//!
+//! The supported keywords are `type` (a single type or a list of types), `properties` and
+//! `required` for objects, `items` for arrays, `enum`, `const`, `anyOf`, `oneOf` and `$ref` to
+//! the `#/$defs` and `#/definitions` of the root schema. The required properties are generated
+//! first, followed by the optional properties in the iteration order of the `properties` map, i.e.
+//! sorted by name unless the `preserve_order` feature of serde_json is enabled. The other keywords
+//! are ignored so the grammar can accept documents that are not valid for the schema, e.g. the
+//! string formats or the numeric bounds are not checked.
+use candle::Result;
+use serde_json::Value;
+use std::collections::HashMap;
+
+const PRIMITIVES: &str = r#"ws ::= [ \t\n]*
+string ::= "\"" char* "\""
+char ::= [^"\\\x00-\x1f] | "\\" (["\\/bfnrt] | "u" [0-9a-fA-F]{4})
+integer ::= "-"? ("0" | [1-9] [0-9]*)
+number ::= integer ("." [0-9]+)? ([eE] [-+]? [0-9]+)?
+boolean ::= "true" | "false"
+null ::= "null"
+value ::= object | array | string | number | boolean | null
+object ::= "{" ws (string ws ":" ws value ws ("," ws string ws ":" ws value ws)*)? "}"
+array ::= "[" ws (value ws ("," ws value ws)*)? "]"
+"#;
+
+struct Converter<'a> {
+    root: &'a Value,
+    rules: Vec<(String, String)>,
+    // The rule names of the converted references.
+    refs: HashMap<String, String>,
+}
+
+// A GBNF literal matching the JSON encoding of `value`.
+fn literal(value: &Value) -> String {
+    let json = value.to_string();
+    let mut out = String::from("\"");
+    for c in json.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+impl Converter<'_> {
+    fn add_rule(&mut self, name: &str, body: String) -> String {
+        let mut name = name
+            .chars()
+            .map(|c| if c.is_ascii_alphanumeric() { c } else { '-' })
+            .collect::<String>();
+        if self.rules.iter().any(|(n, _)| *n == name) {
+            name = format!("{name}-{}", self.rules.len())
+        }
+        self.rules.push((name.clone(), body));
+        name
+    }
+
+    // Returns the body of a rule matching `schema`.
+    fn visit(&mut self, schema: &Value, name: &str) -> Result<String> {
+        let schema = match schema {
+            Value::Bool(true) => return Ok("value".to_string()),
+            Value::Bool(false) => candle::bail!("json schema: false schemas are not supported"),
+            Value::Object(schema) => schema,
+            _ => candle::bail!("json schema: invalid schema {schema}"),
+        };
+        if let Some(r) = schema.get("$ref") {
+            return self.visit_ref(r);
+        }
+        if let Some(c) = schema.get("const") {
+            return Ok(literal(c));
+        }
+        if let Some(values) = schema.get("enum") {
+            let Some(values) = values.as_array() else {
+                candle::bail!("json schema: enum has to be an array")
+            };
+            let alternatives = values.iter().map(literal).collect::<Vec<_>>();
+            return Ok(format!("({})", alternatives.join(" | ")));
+        }
+        for key in ["anyOf", "oneOf"] {
+            if let Some(schemas) = schema.get(key) {
+                let Some(schemas) = schemas.as_array() else {
+                    candle::bail!("json schema: {key} has to be an array")
+                };
+                let alternatives = schemas
+                    .iter()
+                    .enumerate()
+                    .map(|(i, s)| self.visit_rule(s, &format!("{name}-{i}")))
+                    .collect::<Result<Vec<_>>>()?;
+                return Ok(format!("({})", alternatives.join(" | ")));
+            }
+        }
+        match schema.get("type") {
+            None => {
+                if schema.contains_key("properties") {
+                    self.visit_object(schema, name)
+                } else if schema.contains_key("items") {
+                    self.visit_array(schema, name)
+                } else {
+                    Ok("value".to_string())
+                }
+            }
+            Some(Value::String(t)) => self.visit_type(schema, t, name),
+            Some(Value::Array(types)) => {
+                let alternatives = types
+                    .iter()
+                    .map(|t| match t.as_str() {
+                        Some(t) => self.visit_type(schema, t, &format!("{name}-{t}")),
+                        None => candle::bail!("json schema: invalid type {t}"),
+                    })
+                    .collect::<Result<Vec<_>>>()?;
+                Ok(format!("({})", alternatives.join(" | ")))
+            }
+            Some(t) => candle::bail!("json schema: invalid type {t}"),
+        }
+    }
+
+    // Like `visit` but wraps the complex bodies in a new rule.
+    fn visit_rule(&mut self, schema: &Value, name: &str) -> Result<String> {
+        let body = self.visit(schema, name)?;
+        let is_name = body.chars().all(|c| c.is_ascii_alphanumeric() || c == '-');
+        if is_name {
+            Ok(body)
+        } else {
+            Ok(self.add_rule(name, body))
+        }
+    }
+
+    fn visit_ref(&mut self, r: &Value) -> Result<String> {
+        let Some(r) = r.as_str() else {
+            candle::bail!("json schema: invalid $ref {r}")
+        };
+        if let Some(name) = self.refs.get(r) {
+            return Ok(name.clone());
+        }
+        let root = self.root;
+        let def = ["#/$defs/", "#/definitions/"].iter().find_map(|prefix| {
+            let name = r.strip_prefix(prefix)?;
+            let defs = &root[&prefix[2..prefix.len() - 1]];
+            Some((name, defs.get(name)?))
+        });
+        let Some((name, schema)) = def else {
+            candle::bail!("json schema: unsupported $ref {r}")
+        };
+        // The rule is registered before visiting the schema for recursive definitions.
+        let rule = self.add_rule(&format!("ref-{name}"), String::new());
+        self.refs.insert(r.to_string(), rule.clone());
+        let body = self.visit(schema, &rule)?;
+        if let Some(entry) = self.rules.iter_mut().find(|(n, _)| *n == rule) {
+            entry.1 = body
+        }
+        Ok(rule)
+    }
+
+    fn visit_type(
+        &mut self,
+        schema: &serde_json::Map<String, Value>,
+        t: &str,
+        name: &str,
+    ) -> Result<String> {
+        match t {
+            "object" => self.visit_object(schema, name),
+            "array" => self.visit_array(schema, name),
+            "string" | "number" | "integer" | "boolean" | "null" => Ok(t.to_string()),
+            t => candle::bail!("json schema: unsupported type {t}"),
+        }
+    }
+
+    fn visit_object(
+        &mut self,
+        schema: &serde_json::Map<String, Value>,
+        name: &str,
+    ) -> Result<String> {
+        let Some(properties) = schema.get("properties").and_then(|p| p.as_object()) else {
+            return Ok("object".to_string());
+        };
+        let required = match schema.get("required") {
+            Some(Value::Array(required)) => required
+                .iter()
+                .filter_map(|r| r.as_str())
+                .collect::<Vec<_>>(),
+            _ => vec![],
+        };
+        let (mut required_props, mut optional_props) = (vec![], vec![]);
+        for r in required.iter() {
+            if let Some(p) = properties.get(*r) {
+                required_props.push((*r, p))
+            }
+        }
+        for (k, p) in properties.iter() {
+            if !required.contains(&k.as_str()) {
+                optional_props.push((k.as_str(), p))
+            }
+        }
+        let kv = |this: &mut Self, key: &str, prop: &Value| -> Result<String> {
+            let value = this.visit_rule(prop, &format!("{name}-{key}"))?;
+            Ok(format!(
+                "{} ws \":\" ws {value} ws",
+                literal(&Value::String(key.to_string()))
+            ))
+        };
+        let mut body = String::from("\"{\" ws");
+        let mut first = true;
+        for (key, prop) in required_props {
+            if !first {
+                body.push_str(" \",\" ws");
+            }
+            body.push(' ');
+            body.push_str(&kv(self, key, prop)?);
+            first = false;
+        }
+        if !optional_props.is_empty() {
+            // Each optional property can start the optional part, then the properties that
+            // follow it can be added in order.
+            let pairs = optional_props
+                .iter()
+                .map(|(key, prop)| kv(self, key, prop))
+                .collect::<Result<Vec<_>>>()?;
+            let mut tail = String::new();
+            let mut alternatives = vec![];
+            for (i, pair) in pairs.iter().enumerate().rev() {
+                let rest = if tail.is_empty() {
+                    String::new()
+                } else {
+                    format!(" {tail}")
+                };
+                let start = if first {
+                    format!("{pair}{rest}")
+                } else {
+                    format!("\",\" ws {pair}{rest}")
+                };
+                alternatives.push(start);
+                if i > 0 {
+                    tail = self.add_rule(
+                        &format!("{name}-rest-{i}"),
+                        format!("(\",\" ws {pair})?{rest}"),
+                    );
+                }
+            }
+            alternatives.reverse();
+            body.push_str(&format!(" ({})?", alternatives.join(" | ")));
+        }
+        body.push_str(" \"}\"");
+        Ok(body)
+    }
+
+    fn visit_array(
+        &mut self,
+        schema: &serde_json::Map<String, Value>,
+        name: &str,
+    ) -> Result<String> {
+        let item = match schema.get("items") {
+            Some(items) => self.visit_rule(items, &format!("{name}-item"))?,
+            None => "value".to_string(),
+        };
+        Ok(format!("\"[\" ws ({item} ws (\",\" ws {item} ws)*)? \"]\""))
+    }
+}
+
+/// Returns a GBNF grammar matching the JSON documents that follow `schema`, the documents can
+/// be followed by whitespace.
+pub fn json_schema_to_gbnf(schema: &Value) -> Result<String> {
+    let mut converter = Converter {
+        root: schema,
+        rules: vec![],
+        refs: HashMap::new(),
+    };
+    let body = converter.visit(schema, "root")?;
+    let mut gbnf = format!("root ::= {body} ws\n");
+    for (name, body) in converter.rules.iter() {
+        gbnf.push_str(&format!("{name} ::= {body}\n"));
+    }
+    gbnf.push_str(PRIMITIVES);
+    Ok(gbnf)
+}
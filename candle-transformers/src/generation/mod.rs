@@ -2,10 +2,33 @@
 //!
 //! Functionality for modeling sampling strategies and logits processing in text generation
 //! with support for temperature-based sampling, top-k filtering, nucleus sampling (top-p),
-//! and combinations thereof.
+//! and combinations thereof. More processors such as min-p, mirostat or the repetition
+//! penalties can be chained before the sampling, see [`processors`].
 use candle::{DType, Error, Result, Tensor};
 use rand::{distributions::Distribution, SeedableRng};
 
+pub mod batching;
+pub use batching::{BatchingPolicy, LatencySlo, SloPolicy};
+pub mod beam_search;
+pub use beam_search::{beam_search, BeamSearchConfig, BeamSearchModel, Hypothesis};
+pub mod detokenizer;
+pub use detokenizer::{Detokenizer, SpecialTokenPolicy};
+pub mod engine;
+pub use engine::{
+    Engine, EngineConfig, EngineHandle, EngineModel, FinishReason, GenerationEvent,
+    GenerationRequest, Priority, RequestStream,
+};
+pub mod fairness;
+pub use fairness::FairShare;
+pub mod grammar;
+pub use grammar::{Grammar, GrammarConstraint};
+pub mod json_schema;
+pub mod processors;
+pub mod speculative;
+pub use speculative::{SpeculativeDecoder, SpeculativeModel};
+pub mod stopping;
+pub use stopping::{CancellationToken, StopReason, StoppingCriteria};
+
 #[derive(Clone, PartialEq, Debug)]
 pub enum Sampling {
     ArgMax,
@@ -15,15 +38,96 @@ pub enum Sampling {
     TopKThenTopP { k: usize, p: f64, temperature: f64 },
 }
 
+impl Sampling {
+    /// The distribution that the tokens are sampled from for the given logits, the probabilities
+    /// of the tokens filtered out by top-k or top-p are zero and argmax gives a one-hot
+    /// distribution.
+    pub fn probabilities(&self, logits: &Tensor) -> Result<Vec<f32>> {
+        let logits = logits.to_dtype(DType::F32)?;
+        let prs = |temperature: f64| -> Result<Vec<f32>> {
+            candle_nn::ops::softmax_last_dim(&(&logits / temperature)?)?.to_vec1()
+        };
+        let mut prs = match self {
+            Self::ArgMax => {
+                let logits: Vec<f32> = logits.to_vec1()?;
+                let mut prs = vec![0f32; logits.len()];
+                if let Some((i, _)) = logits
+                    .iter()
+                    .enumerate()
+                    .max_by(|(_, u), (_, v)| u.total_cmp(v))
+                {
+                    prs[i] = 1.
+                }
+                return Ok(prs);
+            }
+            Self::All { temperature } => return prs(*temperature),
+            Self::TopP { p, temperature } => {
+                let mut prs = prs(*temperature)?;
+                if *p > 0.0 && *p < 1.0 {
+                    keep_top_p(&mut prs, *p as f32)
+                }
+                prs
+            }
+            Self::TopK { k, temperature } => {
+                let mut prs = prs(*temperature)?;
+                keep_top_k(&mut prs, *k);
+                prs
+            }
+            Self::TopKThenTopP { k, p, temperature } => {
+                let mut prs = prs(*temperature)?;
+                keep_top_k(&mut prs, *k);
+                let sum_p = prs.iter().sum::<f32>();
+                if *p > 0.0 && (*p as f32) < sum_p {
+                    keep_top_p(&mut prs, *p as f32)
+                }
+                prs
+            }
+        };
+        let sum_p = prs.iter().sum::<f32>();
+        prs.iter_mut().for_each(|p| *p /= sum_p);
+        Ok(prs)
+    }
+}
+
+// Zeroes the probabilities outside of the smallest set of tokens that exceed top_p, as done by
+// `LogitsProcessor::sample_topp`.
+fn keep_top_p(prs: &mut [f32], top_p: f32) {
+    let mut argsort_indices = (0..prs.len()).collect::<Vec<_>>();
+    argsort_indices.sort_by(|&i, &j| prs[j].total_cmp(&prs[i]));
+    let mut cumsum = 0.;
+    for index in argsort_indices {
+        if cumsum >= top_p {
+            prs[index] = 0.0;
+        } else {
+            cumsum += prs[index];
+        }
+    }
+}
+
+fn keep_top_k(prs: &mut [f32], top_k: usize) {
+    if top_k < prs.len() {
+        let mut argsort_indices = (0..prs.len()).collect::<Vec<_>>();
+        argsort_indices.select_nth_unstable_by(top_k, |&i, &j| prs[j].total_cmp(&prs[i]));
+        for &index in argsort_indices[top_k..].iter() {
+            prs[index] = 0.0
+        }
+    }
+}
+
 pub struct LogitsProcessor {
     rng: rand::rngs::StdRng,
     sampling: Sampling,
+    processors: Vec<Box<dyn processors::Processor>>,
 }
 
 impl LogitsProcessor {
     pub fn from_sampling(seed: u64, sampling: Sampling) -> Self {
         let rng = rand::rngs::StdRng::seed_from_u64(seed);
-        Self { rng, sampling }
+        Self {
+            rng,
+            sampling,
+            processors: vec![],
+        }
     }
 
     pub fn new(seed: u64, temperature: Option<f64>, top_p: Option<f64>) -> Self {
@@ -38,6 +142,18 @@ impl LogitsProcessor {
         Self::from_sampling(seed, sampling)
     }
 
+    /// Adds a processor that runs on the logits before the sampling, the processors run in the
+    /// order in which they were added.
+    pub fn with_processor(mut self, processor: impl processors::Processor + 'static) -> Self {
+        self.processors.push(Box::new(processor));
+        self
+    }
+
+    /// Resets the state of the processors for a new sequence.
+    pub fn reset(&mut self) {
+        self.processors.iter_mut().for_each(|p| p.reset())
+    }
+
     fn sample_argmax(&mut self, logits: Tensor) -> Result<u32> {
         let logits_v: Vec<f32> = logits.to_vec1()?;
         let next_token = logits_v
@@ -115,7 +231,41 @@ impl LogitsProcessor {
         self.sample_f(logits, |_| {})
     }
 
+    /// Samples the next token, `context` contains the previous tokens and is used by the
+    /// processors, e.g. for the repetition penalties.
+    pub fn sample_with_context(&mut self, logits: &Tensor, context: &[u32]) -> Result<u32> {
+        self.sample_impl(logits, context, |_| {})
+    }
+
     pub fn sample_f(&mut self, logits: &Tensor, f: impl FnOnce(&mut [f32])) -> Result<u32> {
+        self.sample_impl(logits, &[], f)
+    }
+
+    fn sample_impl(
+        &mut self,
+        logits: &Tensor,
+        context: &[u32],
+        f: impl FnOnce(&mut [f32]),
+    ) -> Result<u32> {
+        if self.processors.is_empty() {
+            return self.sample_processed(logits, f);
+        }
+        let mut logits_v = logits
+            .to_dtype(DType::F32)?
+            .flatten_all()?
+            .to_vec1::<f32>()?;
+        for processor in self.processors.iter_mut() {
+            processor.process(&mut logits_v, context)?
+        }
+        let processed = Tensor::new(logits_v.as_slice(), logits.device())?;
+        let next_token = self.sample_processed(&processed, f)?;
+        for processor in self.processors.iter_mut() {
+            processor.accept(next_token, &logits_v)?
+        }
+        Ok(next_token)
+    }
+
+    fn sample_processed(&mut self, logits: &Tensor, f: impl FnOnce(&mut [f32])) -> Result<u32> {
         let logits = logits.to_dtype(DType::F32)?;
         let prs = |temperature: f64| -> Result<Vec<f32>> {
             let logits = (&logits / temperature)?;
@@ -152,4 +302,16 @@ impl LogitsProcessor {
         };
         Ok(next_token)
     }
+
+    /// Samples a token that is allowed by the grammar and advances the grammar state with it.
+    pub fn sample_constrained(
+        &mut self,
+        logits: &Tensor,
+        constraint: &mut GrammarConstraint,
+    ) -> Result<u32> {
+        let logits = constraint.mask_logits(logits)?;
+        let next_token = self.sample(&logits)?;
+        constraint.advance(next_token)?;
+        Ok(next_token)
+    }
 }
@@ -0,0 +1,450 @@
+//! Composable logits processors.
+//!
+//! A [`Processor`] rewrites the logits before sampling, the tokens that it filters out get a
+//! logit of minus infinity. Processors are added to a [`super::LogitsProcessor`] with
+//! [`super::LogitsProcessor::with_processor`] and run in order before its sampling step, so
+//! filtering processors are usually combined with `Sampling::All { temperature: 1.0 }`.
+//!
+//! ```ignore
+//! let mut logits_processor = LogitsProcessor::from_sampling(42, Sampling::All { temperature: 1. })
+//!     .with_processor(RepetitionPenalty::new(1.1, 64))
+//!     .with_processor(Dry::new(0.8, 1.75, 2, 256).with_sequence_breakers(&[newline]))
+//!     .with_processor(Temperature::new(0.8))
+//!     .with_processor(MinP::new(0.05));
+//! let token = logits_processor.sample_with_context(&logits, &tokens)?;
+//! ```
+use candle::Result;
+use std::collections::HashMap;
+
+pub trait Processor: Send {
+    /// Modifies the logits of the next token, `context` contains the previous tokens.
+    fn process(&mut self, logits: &mut [f32], context: &[u32]) -> Result<()>;
+
+    /// Called with the sampled token and the logits it was sampled from.
+    fn accept(&mut self, _token: u32, _logits: &[f32]) -> Result<()> {
+        Ok(())
+    }
+
+    /// Resets the state of the processor for a new sequence.
+    fn reset(&mut self) {}
+}
+
+fn softmax(logits: &[f32]) -> Vec<f32> {
+    let max = logits.iter().fold(f32::NEG_INFINITY, |m, &l| m.max(l));
+    if max == f32::NEG_INFINITY {
+        return vec![0.; logits.len()];
+    }
+    let mut prs = logits.iter().map(|&l| (l - max).exp()).collect::<Vec<_>>();
+    let sum = prs.iter().sum::<f32>();
+    prs.iter_mut().for_each(|p| *p /= sum);
+    prs
+}
+
+// The token ids sorted by decreasing probability.
+fn argsort_desc(prs: &[f32]) -> Vec<usize> {
+    let mut indices = (0..prs.len()).collect::<Vec<_>>();
+    indices.sort_by(|&i, &j| prs[j].total_cmp(&prs[i]));
+    indices
+}
+
+// Keeps the first `keep` tokens of `sorted` and removes the others.
+fn keep_sorted(logits: &mut [f32], sorted: &[usize], keep: usize) {
+    for &i in sorted[keep.max(1)..].iter() {
+        logits[i] = f32::NEG_INFINITY
+    }
+}
+
+// The last `last_n` tokens of the context, the whole context when `last_n` is zero.
+fn window(context: &[u32], last_n: usize) -> &[u32] {
+    if last_n == 0 || last_n >= context.len() {
+        context
+    } else {
+        &context[context.len() - last_n..]
+    }
+}
+
+/// Divides the logits by the temperature.
+#[derive(Debug, Clone)]
+pub struct Temperature(pub f64);
+
+impl Temperature {
+    pub fn new(temperature: f64) -> Self {
+        Self(temperature)
+    }
+}
+
+impl Processor for Temperature {
+    fn process(&mut self, logits: &mut [f32], _context: &[u32]) -> Result<()> {
+        if self.0 <= 0. {
+            candle::bail!("temperature has to be positive, got {}", self.0)
+        }
+        let t = self.0 as f32;
+        logits.iter_mut().for_each(|l| *l /= t);
+        Ok(())
+    }
+}
+
+/// Keeps the `k` most likely tokens.
+#[derive(Debug, Clone)]
+pub struct TopK(pub usize);
+
+impl TopK {
+    pub fn new(k: usize) -> Self {
+        Self(k)
+    }
+}
+
+impl Processor for TopK {
+    fn process(&mut self, logits: &mut [f32], _context: &[u32]) -> Result<()> {
+        if self.0 < logits.len() {
+            let sorted = argsort_desc(logits);
+            keep_sorted(logits, &sorted, self.0)
+        }
+        Ok(())
+    }
+}
+
+/// Keeps the smallest set of most likely tokens with a cumulative probability of at least `p`.
+#[derive(Debug, Clone)]
+pub struct TopP(pub f64);
+
+impl TopP {
+    pub fn new(p: f64) -> Self {
+        Self(p)
+    }
+}
+
+impl Processor for TopP {
+    fn process(&mut self, logits: &mut [f32], _context: &[u32]) -> Result<()> {
+        if self.0 >= 1. {
+            return Ok(());
+        }
+        let prs = softmax(logits);
+        let sorted = argsort_desc(&prs);
+        let mut cumsum = 0.;
+        let mut keep = sorted.len();
+        for (n, &i) in sorted.iter().enumerate() {
+            cumsum += prs[i] as f64;
+            if cumsum >= self.0 {
+                keep = n + 1;
+                break;
+            }
+        }
+        keep_sorted(logits, &sorted, keep);
+        Ok(())
+    }
+}
+
+/// Keeps the tokens with a probability of at least `p` times the probability of the most likely
+/// token, see "Turning Up the Heat: Min-p Sampling for Creative and Coherent LLM Outputs".
+#[derive(Debug, Clone)]
+pub struct MinP(pub f64);
+
+impl MinP {
+    pub fn new(p: f64) -> Self {
+        Self(p)
+    }
+}
+
+impl Processor for MinP {
+    fn process(&mut self, logits: &mut [f32], _context: &[u32]) -> Result<()> {
+        if self.0 <= 0. {
+            return Ok(());
+        }
+        // p_i >= p * p_max is l_i >= l_max + ln(p).
+        let max = logits.iter().fold(f32::NEG_INFINITY, |m, &l| m.max(l));
+        let threshold = max + (self.0 as f32).ln();
+        logits.iter_mut().for_each(|l| {
+            if *l < threshold {
+                *l = f32::NEG_INFINITY
+            }
+        });
+        Ok(())
+    }
+}
+
+/// Locally typical sampling, keeps the tokens whose surprise is the closest to the entropy of
+/// the distribution up to a cumulative probability of `p`, see "Locally Typical Sampling".
+#[derive(Debug, Clone)]
+pub struct Typical(pub f64);
+
+impl Typical {
+    pub fn new(p: f64) -> Self {
+        Self(p)
+    }
+}
+
+impl Processor for Typical {
+    fn process(&mut self, logits: &mut [f32], _context: &[u32]) -> Result<()> {
+        if self.0 >= 1. {
+            return Ok(());
+        }
+        let prs = softmax(logits);
+        let entropy = prs
+            .iter()
+            .filter(|&&p| p > 0.)
+            .map(|&p| -p * p.ln())
+            .sum::<f32>();
+        let distance = prs
+            .iter()
+            .map(|&p| {
+                if p > 0. {
+                    (-p.ln() - entropy).abs()
+                } else {
+                    f32::INFINITY
+                }
+            })
+            .collect::<Vec<_>>();
+        let mut sorted = (0..prs.len()).collect::<Vec<_>>();
+        sorted.sort_by(|&i, &j| distance[i].total_cmp(&distance[j]));
+        let mut cumsum = 0.;
+        let mut keep = sorted.len();
+        for (n, &i) in sorted.iter().enumerate() {
+            cumsum += prs[i] as f64;
+            if cumsum >= self.0 {
+                keep = n + 1;
+                break;
+            }
+        }
+        keep_sorted(logits, &sorted, keep);
+        Ok(())
+    }
+}
+
+/// Tail free sampling, removes the tail of the sorted distribution where the normalized second
+/// derivative of the probabilities accumulates past `z`.
+#[derive(Debug, Clone)]
+pub struct TailFree(pub f64);
+
+impl TailFree {
+    pub fn new(z: f64) -> Self {
+        Self(z)
+    }
+}
+
+impl Processor for TailFree {
+    fn process(&mut self, logits: &mut [f32], _context: &[u32]) -> Result<()> {
+        if self.0 >= 1. || logits.len() <= 2 {
+            return Ok(());
+        }
+        let prs = softmax(logits);
+        let sorted = argsort_desc(&prs);
+        let sorted_prs = sorted.iter().map(|&i| prs[i]).collect::<Vec<_>>();
+        let second = sorted_prs
+            .windows(3)
+            .map(|w| (w[0] - 2. * w[1] + w[2]).abs())
+            .collect::<Vec<_>>();
+        let sum = second.iter().sum::<f32>();
+        if sum <= 0. {
+            return Ok(());
+        }
+        let mut cumsum = 0.;
+        // The second derivative at position n relates to the token n + 1.
+        let mut keep = sorted.len();
+        for (n, d) in second.iter().enumerate() {
+            cumsum += (d / sum) as f64;
+            if cumsum > self.0 {
+                keep = n + 1;
+                break;
+            }
+        }
+        keep_sorted(logits, &sorted, keep);
+        Ok(())
+    }
+}
+
+/// The repetition penalty of "CTRL: A Conditional Transformer Language Model for Controllable
+/// Generation", the positive logits of the tokens from the last `last_n` tokens are divided by
+/// `penalty` and the negative ones are multiplied by it.
+#[derive(Debug, Clone)]
+pub struct RepetitionPenalty {
+    pub penalty: f32,
+    pub last_n: usize,
+}
+
+impl RepetitionPenalty {
+    pub fn new(penalty: f32, last_n: usize) -> Self {
+        Self { penalty, last_n }
+    }
+}
+
+impl Processor for RepetitionPenalty {
+    fn process(&mut self, logits: &mut [f32], context: &[u32]) -> Result<()> {
+        let mut seen = std::collections::HashSet::new();
+        for &token in window(context, self.last_n) {
+            if !seen.insert(token) {
+                continue;
+            }
+            if let Some(logit) = logits.get_mut(token as usize) {
+                if *logit >= 0. {
+                    *logit /= self.penalty
+                } else {
+                    *logit *= self.penalty
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The frequency and presence penalties of the OpenAI API, the logit of each token is reduced
+/// by `frequency` times its number of occurrences in the last `last_n` tokens, plus `presence`
+/// if it occurs at least once.
+#[derive(Debug, Clone)]
+pub struct FrequencyPenalty {
+    pub frequency: f32,
+    pub presence: f32,
+    pub last_n: usize,
+}
+
+impl FrequencyPenalty {
+    pub fn new(frequency: f32, presence: f32, last_n: usize) -> Self {
+        Self {
+            frequency,
+            presence,
+            last_n,
+        }
+    }
+}
+
+impl Processor for FrequencyPenalty {
+    fn process(&mut self, logits: &mut [f32], context: &[u32]) -> Result<()> {
+        let mut counts = HashMap::new();
+        for &token in window(context, self.last_n) {
+            *counts.entry(token).or_insert(0usize) += 1
+        }
+        for (token, count) in counts {
+            if let Some(logit) = logits.get_mut(token as usize) {
+                *logit -= count as f32 * self.frequency + self.presence
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The "Don't Repeat Yourself" penalty. When the context ends with a sequence of tokens that
+/// already occurred, the token that followed that earlier occurrence is penalized by
+/// `multiplier * base^(len - allowed_length)` where `len` is the length of the longest such
+/// sequence, if it is at least `allowed_length`. The sequences cannot extend over the sequence
+/// breakers, e.g. the newline tokens, and are searched in the last `last_n` tokens.
+#[derive(Debug, Clone)]
+pub struct Dry {
+    pub multiplier: f32,
+    pub base: f32,
+    pub allowed_length: usize,
+    pub last_n: usize,
+    pub sequence_breakers: Vec<u32>,
+}
+
+impl Dry {
+    pub fn new(multiplier: f32, base: f32, allowed_length: usize, last_n: usize) -> Self {
+        Self {
+            multiplier,
+            base,
+            allowed_length,
+            last_n,
+            sequence_breakers: vec![],
+        }
+    }
+
+    pub fn with_sequence_breakers(mut self, sequence_breakers: &[u32]) -> Self {
+        self.sequence_breakers = sequence_breakers.to_vec();
+        self
+    }
+
+    /// The length of the longest repeated sequence that precedes each token of the context.
+    pub fn match_lengths(&self, context: &[u32]) -> HashMap<u32, usize> {
+        let context = window(context, self.last_n);
+        let mut lengths = HashMap::new();
+        let Some(end) = context.len().checked_sub(1) else {
+            return lengths;
+        };
+        for j in 1..context.len() {
+            // The sequence ending at j - 1 is compared with the one ending at the end.
+            let mut len = 0;
+            while len < j {
+                let (a, b) = (context[j - 1 - len], context[end - len]);
+                if a != b || self.sequence_breakers.contains(&a) {
+                    break;
+                }
+                len += 1
+            }
+            if len > 0 {
+                let entry = lengths.entry(context[j]).or_insert(0);
+                *entry = (*entry).max(len)
+            }
+        }
+        lengths
+    }
+}
+
+impl Processor for Dry {
+    fn process(&mut self, logits: &mut [f32], context: &[u32]) -> Result<()> {
+        if self.multiplier == 0. || self.allowed_length == 0 {
+            return Ok(());
+        }
+        for (token, len) in self.match_lengths(context) {
+            if len < self.allowed_length {
+                continue;
+            }
+            if let Some(logit) = logits.get_mut(token as usize) {
+                let exponent = (len - self.allowed_length) as f32;
+                *logit -= self.multiplier * self.base.powf(exponent)
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Mirostat v2 from "Mirostat: A Neural Text Decoding Algorithm that Directly Controls
+/// Perplexity". The tokens with a surprise above `mu` are removed and `mu` is updated after each
+/// token so that the average surprise of the sampled tokens is `tau`, in bits. This should be the
+/// last processor and be followed by sampling with a temperature of one.
+#[derive(Debug, Clone)]
+pub struct Mirostat {
+    pub tau: f32,
+    pub eta: f32,
+    mu: f32,
+}
+
+impl Mirostat {
+    pub fn new(tau: f32, eta: f32) -> Self {
+        Self {
+            tau,
+            eta,
+            mu: 2. * tau,
+        }
+    }
+
+    /// The current maximal surprise.
+    pub fn mu(&self) -> f32 {
+        self.mu
+    }
+}
+
+impl Processor for Mirostat {
+    fn process(&mut self, logits: &mut [f32], _context: &[u32]) -> Result<()> {
+        let prs = softmax(logits);
+        let sorted = argsort_desc(&prs);
+        let keep = sorted
+            .iter()
+            .take_while(|&&i| -prs[i].log2() <= self.mu)
+            .count();
+        keep_sorted(logits, &sorted, keep);
+        Ok(())
+    }
+
+    fn accept(&mut self, token: u32, logits: &[f32]) -> Result<()> {
+        let prs = softmax(logits);
+        let Some(&p) = prs.get(token as usize) else {
+            candle::bail!("mirostat: token {token} is out of range")
+        };
+        let surprise = -p.log2();
+        self.mu -= self.eta * (surprise - self.tau);
+        Ok(())
+    }
+
+    fn reset(&mut self) {
+        self.mu = 2. * self.tau
+    }
+}
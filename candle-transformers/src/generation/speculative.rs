@@ -0,0 +1,239 @@
+//! Speculative decoding.
+//!
+//! A small draft model proposes a few tokens autoregressively, the target model then scores all
+//! of them in a single forward pass. Each draft token `x` is accepted with probability `min(1,
+//! p(x) / q(x))` where `p` and `q` are the target and draft distributions, on the first
+//! rejection a token is sampled from the normalized `max(0, p - q)` instead and the remaining
+//! draft tokens are discarded. When all the draft tokens are accepted, an additional token is
+//! sampled from the last target distribution. The generated tokens follow the same distribution
+//! as when sampling from the target model alone, see "Fast Inference from Transformers via
+//! Speculative Decoding" <https://arxiv.org/abs/2211.17192>.
+//!
+//! ```ignore
+//! let mut decoder = SpeculativeDecoder::new(draft, target, 4, Sampling::ArgMax, 42, &prompt);
+//! let tokens = decoder.generate(256, Some(eos_token))?;
+//! println!("acceptance rate {:.2}", decoder.acceptance_rate());
+//! ```
+use super::{Sampling, StopReason, StoppingCriteria};
+use candle::{Result, Tensor};
+use rand::{distributions::Distribution, Rng, SeedableRng};
+
+/// A model with a kv cache that can be rolled back.
+pub trait SpeculativeModel {
+    /// Processes `tokens` placed after the `seqlen_offset` tokens that are already in the cache
+    /// and returns the logits for each of them, with shape `(tokens.len(), vocab_size)`.
+    fn forward(&mut self, tokens: &[u32], seqlen_offset: usize) -> Result<Tensor>;
+
+    /// Drops the cache entries for the tokens after the first `len` ones.
+    fn truncate_cache(&mut self, len: usize) -> Result<()>;
+}
+
+pub struct SpeculativeDecoder<D, T> {
+    draft: D,
+    target: T,
+    num_draft_tokens: usize,
+    sampling: Sampling,
+    rng: rand::rngs::StdRng,
+    // The prompt followed by the generated tokens.
+    tokens: Vec<u32>,
+    prompt_len: usize,
+    // The number of tokens in the kv cache of each model.
+    draft_len: usize,
+    target_len: usize,
+    num_drafted: usize,
+    num_accepted: usize,
+}
+
+fn sample(prs: &[f32], rng: &mut rand::rngs::StdRng) -> Result<u32> {
+    let distr = rand::distributions::WeightedIndex::new(prs).map_err(candle::Error::wrap)?;
+    Ok(distr.sample(rng) as u32)
+}
+
+impl<D: SpeculativeModel, T: SpeculativeModel> SpeculativeDecoder<D, T> {
+    /// Both models start with an empty cache, `prompt` must not be empty.
+    pub fn new(
+        draft: D,
+        target: T,
+        num_draft_tokens: usize,
+        sampling: Sampling,
+        seed: u64,
+        prompt: &[u32],
+    ) -> Self {
+        Self {
+            draft,
+            target,
+            num_draft_tokens: num_draft_tokens.max(1),
+            sampling,
+            rng: rand::rngs::StdRng::seed_from_u64(seed),
+            tokens: prompt.to_vec(),
+            prompt_len: prompt.len(),
+            draft_len: 0,
+            target_len: 0,
+            num_drafted: 0,
+            num_accepted: 0,
+        }
+    }
+
+    pub fn draft(&self) -> &D {
+        &self.draft
+    }
+
+    pub fn target(&self) -> &T {
+        &self.target
+    }
+
+    /// The prompt followed by the generated tokens.
+    pub fn tokens(&self) -> &[u32] {
+        &self.tokens
+    }
+
+    pub fn generated_tokens(&self) -> &[u32] {
+        &self.tokens[self.prompt_len..]
+    }
+
+    /// The proportion of the draft tokens that have been accepted so far.
+    pub fn acceptance_rate(&self) -> f64 {
+        if self.num_drafted == 0 {
+            0.
+        } else {
+            self.num_accepted as f64 / self.num_drafted as f64
+        }
+    }
+
+    /// Runs a draft and verification round and returns the new tokens, between one and
+    /// `num_draft_tokens + 1` of them.
+    pub fn step(&mut self) -> Result<Vec<u32>> {
+        if self.tokens.is_empty() {
+            candle::bail!("speculative decoding requires a non-empty prompt")
+        }
+        let len = self.tokens.len();
+        let k = self.num_draft_tokens;
+
+        // Draft k tokens, the last one is not processed by the draft model.
+        let mut drafts = Vec::with_capacity(k);
+        let mut draft_prs = Vec::with_capacity(k);
+        let mut input = self.tokens[self.draft_len..].to_vec();
+        for i in 0..k {
+            let logits = self.draft.forward(&input, self.draft_len)?;
+            self.draft_len += input.len();
+            let prs = self
+                .sampling
+                .probabilities(&logits.get(logits.dim(0)? - 1)?)?;
+            let token = sample(&prs, &mut self.rng)?;
+            drafts.push(token);
+            draft_prs.push(prs);
+            if i + 1 < k {
+                input = vec![token]
+            }
+        }
+
+        // Score the draft tokens with the target model.
+        let mut input = self.tokens[self.target_len..].to_vec();
+        let first = input.len() - 1;
+        input.extend_from_slice(&drafts);
+        let logits = self.target.forward(&input, self.target_len)?;
+        self.target_len += input.len();
+
+        let mut num_accepted = 0;
+        let mut correction = None;
+        for (i, (&token, q)) in drafts.iter().zip(draft_prs.iter()).enumerate() {
+            let p = self.sampling.probabilities(&logits.get(first + i)?)?;
+            let (p_x, q_x) = (p[token as usize], q[token as usize]);
+            if q_x <= p_x || self.rng.gen::<f32>() * q_x < p_x {
+                num_accepted += 1;
+                continue;
+            }
+            let residual = p
+                .iter()
+                .zip(q.iter())
+                .map(|(p, q)| (p - q).max(0.))
+                .collect::<Vec<_>>();
+            let token = if residual.iter().any(|&r| r > 0.) {
+                sample(&residual, &mut self.rng)?
+            } else {
+                sample(&p, &mut self.rng)?
+            };
+            correction = Some(token);
+            break;
+        }
+        let next = match correction {
+            Some(token) => token,
+            None => {
+                let p = self.sampling.probabilities(&logits.get(first + k)?)?;
+                sample(&p, &mut self.rng)?
+            }
+        };
+        let mut new_tokens = drafts[..num_accepted].to_vec();
+        new_tokens.push(next);
+        self.num_drafted += k;
+        self.num_accepted += num_accepted;
+
+        // Roll back the caches to the accepted tokens, the last new token is in neither cache.
+        self.target_len = len + num_accepted;
+        self.target.truncate_cache(self.target_len)?;
+        let draft_len = usize::min(self.draft_len, len + num_accepted);
+        if draft_len < self.draft_len {
+            self.draft_len = draft_len;
+            self.draft.truncate_cache(draft_len)?;
+        }
+        self.tokens.extend_from_slice(&new_tokens);
+        Ok(new_tokens)
+    }
+
+    /// Generates up to `max_new_tokens` tokens, stopping after `eos_token`. Returns the
+    /// generated tokens.
+    pub fn generate(&mut self, max_new_tokens: usize, eos_token: Option<u32>) -> Result<Vec<u32>> {
+        let start = self.tokens.len();
+        while self.tokens.len() - start < max_new_tokens {
+            let new_tokens = self.step()?;
+            if let Some(eos) = eos_token {
+                if let Some(pos) = new_tokens.iter().position(|&t| t == eos) {
+                    let end = self.tokens.len() - new_tokens.len() + pos + 1;
+                    self.truncate(end)?;
+                    break;
+                }
+            }
+        }
+        let end = usize::min(self.tokens.len(), start + max_new_tokens);
+        self.truncate(end)?;
+        Ok(self.tokens[start..].to_vec())
+    }
+
+    /// Generates tokens until `criteria` stops, each of the tokens accepted in a step is checked
+    /// in turn and the tokens after the one that triggered the criteria are discarded. Returns the
+    /// generated tokens and the reason to stop.
+    pub fn generate_until(
+        &mut self,
+        criteria: &mut dyn StoppingCriteria,
+    ) -> Result<(Vec<u32>, StopReason)> {
+        let start = self.tokens.len();
+        loop {
+            let new_tokens = self.step()?;
+            let step_start = self.tokens.len() - new_tokens.len();
+            for i in 0..new_tokens.len() {
+                let end = step_start + i + 1;
+                if let Some(reason) = criteria.check(&self.tokens[start..end])? {
+                    self.truncate(end)?;
+                    return Ok((self.tokens[start..].to_vec(), reason));
+                }
+            }
+        }
+    }
+
+    // Drops the tokens after the first `len` ones.
+    fn truncate(&mut self, len: usize) -> Result<()> {
+        if len >= self.tokens.len() {
+            return Ok(());
+        }
+        self.tokens.truncate(len);
+        if self.target_len > len {
+            self.target_len = len;
+            self.target.truncate_cache(len)?
+        }
+        if self.draft_len > len {
+            self.draft_len = len;
+            self.draft.truncate_cache(len)?
+        }
+        Ok(())
+    }
+}
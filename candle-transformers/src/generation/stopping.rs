@@ -0,0 +1,239 @@
+//! Stopping criteria for the generation loops.
+//!
+//! A [`StoppingCriteria`] is checked after each generated token and returns the reason to stop,
+//! criteria are combined by putting them in a `Vec`, the first one that triggers wins.
+//! [`StopStrings`] decodes the generated tokens to detect stop strings that span several tokens
+//! and holds back the text that may be the start of a stop string, so that the streamed text
+//! never contains part of a stop string.
+//!
+//! ```ignore
+//! let cancellation = CancellationToken::new();
+//! let decode = move |tokens: &[u32]| tokenizer.decode(tokens, true).map_err(E::msg);
+//! let mut criteria: Vec<Box<dyn StoppingCriteria>> = vec![
+//!     Box::new(MaxTokens(256)),
+//!     Box::new(EosTokens(vec![eos_token])),
+//!     Box::new(cancellation.clone()),
+//! ];
+//! let mut stop_strings = StopStrings::new(vec!["\nUser:".to_string()], decode);
+//! let mut tokens = vec![];
+//! let reason = loop {
+//!     tokens.push(logits_processor.sample(&model.forward(&input, tokens.len())?)?);
+//!     let reason = criteria.check(&tokens)?.or(stop_strings.check(&tokens)?);
+//!     print!("{}", stop_strings.next_text());
+//!     if let Some(reason) = reason {
+//!         break reason;
+//!     }
+//! };
+//! print!("{}", stop_strings.finish_text());
+//! ```
+use candle::Result;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StopReason {
+    /// The maximum number of new tokens was reached.
+    MaxTokens,
+    /// An end of sequence token was generated.
+    Eos(u32),
+    /// The decoded text contains this stop string.
+    StopString(String),
+    Cancelled,
+}
+
+pub trait StoppingCriteria: Send {
+    /// Called after each new token, `tokens` contains all the generated tokens including the
+    /// new one. The prompt is not included.
+    fn check(&mut self, tokens: &[u32]) -> Result<Option<StopReason>>;
+
+    /// Resets the state of the criteria for a new sequence.
+    fn reset(&mut self) {}
+}
+
+impl StoppingCriteria for Vec<Box<dyn StoppingCriteria>> {
+    fn check(&mut self, tokens: &[u32]) -> Result<Option<StopReason>> {
+        for criteria in self.iter_mut() {
+            if let Some(reason) = criteria.check(tokens)? {
+                return Ok(Some(reason));
+            }
+        }
+        Ok(None)
+    }
+
+    fn reset(&mut self) {
+        self.iter_mut().for_each(|c| c.reset())
+    }
+}
+
+/// Stops after this number of new tokens.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MaxTokens(pub usize);
+
+impl StoppingCriteria for MaxTokens {
+    fn check(&mut self, tokens: &[u32]) -> Result<Option<StopReason>> {
+        Ok((tokens.len() >= self.0).then_some(StopReason::MaxTokens))
+    }
+}
+
+/// Stops after generating one of these tokens, the token is kept in the generated tokens.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EosTokens(pub Vec<u32>);
+
+impl StoppingCriteria for EosTokens {
+    fn check(&mut self, tokens: &[u32]) -> Result<Option<StopReason>> {
+        Ok(tokens
+            .last()
+            .filter(|t| self.0.contains(t))
+            .map(|&t| StopReason::Eos(t)))
+    }
+}
+
+/// A flag shared between threads to cancel a generation, the clones of a token refer to the
+/// same flag.
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken(Arc<AtomicBool>);
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed)
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+impl PartialEq for CancellationToken {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl Eq for CancellationToken {}
+
+impl StoppingCriteria for CancellationToken {
+    fn check(&mut self, _tokens: &[u32]) -> Result<Option<StopReason>> {
+        Ok(self.is_cancelled().then_some(StopReason::Cancelled))
+    }
+}
+
+type Decoder = Box<dyn FnMut(&[u32]) -> Result<String> + Send>;
+
+/// Stops when the decoded text contains one of the stop strings.
+///
+/// The text is obtained by decoding all the generated tokens with the tokenizer rather than by
+/// concatenating the text of each token, so that the spaces and the characters that span
+/// several tokens are the same as in the final output.
+pub struct StopStrings {
+    stop_strings: Vec<String>,
+    decode: Decoder,
+    text: String,
+    num_tokens: usize,
+    // The position of the first stop string in the text and its index.
+    stop: Option<(usize, usize)>,
+    // The length of the text already returned by `next_text`.
+    emitted: usize,
+}
+
+impl StopStrings {
+    /// `decode` returns the text for a sequence of tokens, e.g. with
+    /// `tokenizer.decode(tokens, true)`. Empty stop strings are ignored.
+    pub fn new<F>(stop_strings: Vec<String>, decode: F) -> Self
+    where
+        F: FnMut(&[u32]) -> Result<String> + Send + 'static,
+    {
+        let stop_strings = stop_strings.into_iter().filter(|s| !s.is_empty()).collect();
+        Self {
+            stop_strings,
+            decode: Box::new(decode),
+            text: String::new(),
+            num_tokens: 0,
+            stop: None,
+            emitted: 0,
+        }
+    }
+
+    /// The decoded text before the stop string.
+    pub fn text(&self) -> &str {
+        &self.text[..self.stop.map_or(self.text.len(), |(pos, _)| pos)]
+    }
+
+    // The end of the text that can be streamed: the text before the stop string, without the
+    // incomplete characters and the longest suffix that is the start of a stop string.
+    fn safe_len(&self) -> usize {
+        if let Some((pos, _)) = self.stop {
+            return pos;
+        }
+        let text = self.text.trim_end_matches('\u{FFFD}');
+        let mut held = 0;
+        for s in self.stop_strings.iter() {
+            for (i, _) in s.char_indices().skip(1) {
+                if i > held && text.ends_with(&s[..i]) {
+                    held = i
+                }
+            }
+        }
+        text.len() - held
+    }
+
+    fn take_text(&mut self, end: usize) -> String {
+        if end <= self.emitted {
+            return String::new();
+        }
+        let text = self.text[self.emitted..end].to_string();
+        self.emitted = end;
+        text
+    }
+
+    /// The text that was not yet returned and that cannot be part of a stop string.
+    pub fn next_text(&mut self) -> String {
+        self.take_text(self.safe_len())
+    }
+
+    /// The remaining text before the stop string, to call once the generation has stopped.
+    pub fn finish_text(&mut self) -> String {
+        self.take_text(self.text().len())
+    }
+}
+
+impl StoppingCriteria for StopStrings {
+    fn check(&mut self, tokens: &[u32]) -> Result<Option<StopReason>> {
+        if tokens.len() < self.num_tokens {
+            self.reset()
+        }
+        if let Some((_, index)) = self.stop {
+            return Ok(Some(StopReason::StopString(
+                self.stop_strings[index].clone(),
+            )));
+        }
+        let previous_len = self.emitted;
+        self.text = (self.decode)(tokens)?;
+        self.num_tokens = tokens.len();
+        // A prefix of the previous text was returned and is not searched again, the decoded
+        // text can change at the end when a character spans several tokens.
+        let mut start = previous_len.min(self.text.len());
+        while !self.text.is_char_boundary(start) {
+            start -= 1
+        }
+        self.stop = self
+            .stop_strings
+            .iter()
+            .enumerate()
+            .filter_map(|(index, s)| Some((start + self.text[start..].find(s.as_str())?, index)))
+            .min();
+        Ok(self
+            .stop
+            .map(|(_, index)| StopReason::StopString(self.stop_strings[index].clone())))
+    }
+
+    fn reset(&mut self) {
+        self.text.clear();
+        self.num_tokens = 0;
+        self.stop = None;
+        self.emitted = 0;
+    }
+}
@@ -0,0 +1,528 @@
+//! Linear layers for GPTQ quantized checkpoints.
+//!
+//! GPTQ checkpoints store the weights of each linear layer using `bits` bits per value with one
+//! zero point and one scale per group of input features:
+//! - `qweight` has shape `(in_dim * bits / 32, out_dim)`, the values are packed in u32 along the
+//!   input dimension.
+//! - `qzeros` has shape `(n_groups, out_dim * bits / 32)`, packed along the output dimension.
+//! - `scales` has shape `(n_groups, out_dim)`.
+//! - `g_idx` has shape `(in_dim,)` and gives the group of each input feature, the groups are not
+//!   contiguous when the checkpoint was quantized with `desc_act`.
+//!
+//! The weights are dequantized as `(q - zero) * scale`, the original GPTQ format stores the zero
+//! points minus one. As for [`crate::awq`], inputs with few rows use a fused kernel on cuda.
+use candle::{CpuStorage, DType, Layout, Module, Result, Shape, Tensor, WithDType};
+use candle_nn::VarBuilder;
+
+// On cuda, inputs with at most this number of rows use the fused kernel.
+const FUSED_MAX_ROWS: usize = 8;
+
+/// The parameters of GPTQ checkpoints, see [`crate::config::QuantizationConfig::gptq`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GptqConfig {
+    pub bits: usize,
+    pub group_size: isize,
+    pub desc_act: bool,
+    pub sym: bool,
+    /// `gptq` for the original format where zero points are stored minus one, or `gptq_v2`.
+    pub checkpoint_format: String,
+}
+
+impl GptqConfig {
+    /// Checks that the bits and checkpoint format are supported.
+    pub fn check(&self) -> Result<()> {
+        if !matches!(self.bits, 2 | 4 | 8) {
+            candle::bail!(
+                "only 2, 4 and 8 bits GPTQ checkpoints are supported, got {}",
+                self.bits
+            )
+        }
+        self.zero_offset()?;
+        Ok(())
+    }
+
+    /// The group size, a group size of -1 means that all the input features use the same group.
+    pub fn group_size(&self, in_dim: usize) -> usize {
+        if self.group_size <= 0 {
+            in_dim
+        } else {
+            self.group_size as usize
+        }
+    }
+
+    fn zero_offset(&self) -> Result<u32> {
+        match self.checkpoint_format.as_str() {
+            "gptq" => Ok(1),
+            "gptq_v2" => Ok(0),
+            f => candle::bail!("unsupported GPTQ checkpoint format {f}"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Packing {
+    bits: u32,
+    // The value added to the stored zero points.
+    zero_offset: u32,
+}
+
+impl Packing {
+    fn pack(&self) -> usize {
+        32 / self.bits as usize
+    }
+
+    fn mask(&self) -> u32 {
+        (1 << self.bits) - 1
+    }
+}
+
+// Returns `(in_dim, out_dim)` after checking the shapes of the packed tensors.
+fn gptq_dims(
+    p: Packing,
+    qweight: &Layout,
+    qzeros: &Layout,
+    scales: &Layout,
+    g_idx: &Layout,
+) -> Result<(usize, usize)> {
+    let (packed_in, out_dim) = qweight.shape().dims2()?;
+    let (n_groups, scales_out) = scales.shape().dims2()?;
+    let in_dim = g_idx.shape().dims1()?;
+    let pack = p.pack();
+    if in_dim != packed_in * pack
+        || scales_out != out_dim
+        || out_dim % pack != 0
+        || qzeros.shape().dims2()? != (n_groups, out_dim / pack)
+    {
+        candle::bail!(
+            "gptq shape mismatch, qweight {:?}, qzeros {:?}, scales {:?}, g_idx {:?}",
+            qweight.shape(),
+            qzeros.shape(),
+            scales.shape(),
+            g_idx.shape()
+        )
+    }
+    Ok((in_dim, out_dim))
+}
+
+fn dequantize_slice<T: WithDType>(
+    p: Packing,
+    qweight: &[u32],
+    qzeros: &[u32],
+    scales: &[T],
+    g_idx: &[u32],
+    out_dim: usize,
+) -> Result<Vec<T>> {
+    use rayon::prelude::*;
+
+    let (pack, mask) = (p.pack(), p.mask());
+    let n_groups = scales.len() / out_dim;
+    if let Some(g) = g_idx.iter().find(|&&g| g as usize >= n_groups) {
+        candle::bail!("gptq, group index {g} is out of range for {n_groups} groups")
+    }
+    let mut dst = vec![T::zero(); g_idx.len() * out_dim];
+    dst.par_chunks_mut(out_dim)
+        .zip(g_idx.par_iter())
+        .enumerate()
+        .for_each(|(i, (dst, &group))| {
+            let group = group as usize;
+            let qweight = &qweight[(i / pack) * out_dim..(i / pack + 1) * out_dim];
+            let qzeros = &qzeros[group * out_dim / pack..(group + 1) * out_dim / pack];
+            let scales = &scales[group * out_dim..(group + 1) * out_dim];
+            let shift = (i % pack) as u32 * p.bits;
+            for (o, dst) in dst.iter_mut().enumerate() {
+                let q = (qweight[o] >> shift) & mask;
+                let z = (qzeros[o / pack] >> ((o % pack) as u32 * p.bits)) & mask;
+                let w = q as f64 - (z + p.zero_offset) as f64;
+                *dst = T::from_f64(w * scales[o].to_f64())
+            }
+        });
+    Ok(dst)
+}
+
+struct GptqDequantize {
+    packing: Packing,
+    g_idx: Tensor,
+}
+
+impl candle::CustomOp3 for GptqDequantize {
+    fn name(&self) -> &'static str {
+        "gptq-dequantize"
+    }
+
+    fn cpu_fwd(
+        &self,
+        s1: &CpuStorage,
+        l1: &Layout,
+        s2: &CpuStorage,
+        l2: &Layout,
+        s3: &CpuStorage,
+        l3: &Layout,
+    ) -> Result<(CpuStorage, Shape)> {
+        use candle::backend::BackendStorage;
+
+        fn contiguous<'a, T: WithDType>(s: &'a CpuStorage, l: &Layout) -> Result<&'a [T]> {
+            match l.contiguous_offsets() {
+                None => candle::bail!("gptq-dequantize, inputs have to be contiguous"),
+                Some((o1, o2)) => Ok(&s.as_slice::<T>()?[o1..o2]),
+            }
+        }
+        let (g_idx, l4) = self.g_idx.storage_and_layout();
+        let g_idx = match &*g_idx {
+            candle::Storage::Cpu(s4) => contiguous::<u32>(s4, l4)?.to_vec(),
+            _ => candle::bail!("gptq-dequantize, g_idx has to be on the cpu"),
+        };
+        let p = self.packing;
+        let (in_dim, out_dim) = gptq_dims(p, l1, l2, l3, l4)?;
+        let qweight = contiguous::<u32>(s1, l1)?;
+        let qzeros = contiguous::<u32>(s2, l2)?;
+        let dst = match s3 {
+            CpuStorage::F16(_) => {
+                let scales = contiguous(s3, l3)?;
+                CpuStorage::F16(dequantize_slice(
+                    p, qweight, qzeros, scales, &g_idx, out_dim,
+                )?)
+            }
+            CpuStorage::BF16(_) => {
+                let scales = contiguous(s3, l3)?;
+                CpuStorage::BF16(dequantize_slice(
+                    p, qweight, qzeros, scales, &g_idx, out_dim,
+                )?)
+            }
+            CpuStorage::F32(_) => {
+                let scales = contiguous(s3, l3)?;
+                CpuStorage::F32(dequantize_slice(
+                    p, qweight, qzeros, scales, &g_idx, out_dim,
+                )?)
+            }
+            s => candle::bail!(
+                "gptq-dequantize, unsupported dtype for scales {:?}",
+                s.dtype()
+            ),
+        };
+        Ok((dst, Shape::from((in_dim, out_dim))))
+    }
+
+    #[cfg(feature = "cuda")]
+    fn cuda_fwd(
+        &self,
+        s1: &candle::CudaStorage,
+        l1: &Layout,
+        s2: &candle::CudaStorage,
+        l2: &Layout,
+        s3: &candle::CudaStorage,
+        l3: &Layout,
+    ) -> Result<(candle::CudaStorage, Shape)> {
+        use crate::awq::cuda_contiguous;
+        use candle::backend::BackendStorage;
+        use candle::cuda_backend::cudarc::driver::{
+            CudaSlice, CudaView, DeviceRepr, LaunchAsync, LaunchConfig,
+        };
+        use candle::cuda_backend::{kernel_name, kernels, CudaDType, CudaStorageSlice, WrapErr};
+        use candle::CudaDevice;
+
+        #[allow(clippy::too_many_arguments)]
+        fn launch<T: CudaDType + DeviceRepr + WithDType>(
+            dev: &CudaDevice,
+            p: Packing,
+            (s1, l1): (&candle::CudaStorage, &Layout),
+            (s2, l2): (&candle::CudaStorage, &Layout),
+            (s3, l3): (&candle::CudaStorage, &Layout),
+            g_idx: &CudaView<u32>,
+            (in_dim, out_dim): (usize, usize),
+        ) -> Result<CudaSlice<T>> {
+            let qweight = cuda_contiguous(s1.as_cuda_slice::<u32>()?, l1)?;
+            let qzeros = cuda_contiguous(s2.as_cuda_slice::<u32>()?, l2)?;
+            let scales = cuda_contiguous(s3.as_cuda_slice::<T>()?, l3)?;
+            let cfg = LaunchConfig::for_num_elems((in_dim * out_dim) as u32);
+            let func = dev.get_or_load_func(&kernel_name::<T>("gptq_dequantize"), kernels::GPTQ)?;
+            // SAFETY: Set later by running the kernel.
+            let dst = unsafe { dev.alloc::<T>(in_dim * out_dim) }.w()?;
+            let params = (
+                &qweight,
+                &qzeros,
+                &scales,
+                g_idx,
+                &dst,
+                in_dim,
+                out_dim,
+                p.bits,
+                p.zero_offset,
+            );
+            // SAFETY: ffi.
+            unsafe { func.launch(cfg, params) }.w()?;
+            Ok(dst)
+        }
+
+        let (g_idx, l4) = self.g_idx.storage_and_layout();
+        let g_idx = match &*g_idx {
+            candle::Storage::Cuda(s4) => s4,
+            _ => candle::bail!("gptq-dequantize, g_idx has to be on a cuda device"),
+        };
+        let g_idx = cuda_contiguous(g_idx.as_cuda_slice::<u32>()?, l4)?;
+        let p = self.packing;
+        let dims = gptq_dims(p, l1, l2, l3, l4)?;
+        let dev = s1.device().clone();
+        let (a1, a2, a3) = ((s1, l1), (s2, l2), (s3, l3));
+        let slice = match s3.dtype() {
+            DType::F16 => CudaStorageSlice::F16(launch(&dev, p, a1, a2, a3, &g_idx, dims)?),
+            DType::BF16 => CudaStorageSlice::BF16(launch(&dev, p, a1, a2, a3, &g_idx, dims)?),
+            DType::F32 => CudaStorageSlice::F32(launch(&dev, p, a1, a2, a3, &g_idx, dims)?),
+            dtype => candle::bail!("gptq-dequantize, unsupported dtype for scales {dtype:?}"),
+        };
+        let dst = candle::cuda_backend::CudaStorage { slice, device: dev };
+        Ok((dst, Shape::from(dims)))
+    }
+}
+
+// Fused dequantization and matmul, the inputs are the `(n_rows, in_dim)` activations and the
+// packed weights. This is only implemented on cuda.
+#[cfg_attr(not(feature = "cuda"), allow(dead_code))]
+struct GptqMatmul {
+    packing: Packing,
+    qzeros: Tensor,
+    scales: Tensor,
+    g_idx: Tensor,
+}
+
+impl candle::CustomOp2 for GptqMatmul {
+    fn name(&self) -> &'static str {
+        "gptq-matmul"
+    }
+
+    fn cpu_fwd(
+        &self,
+        _: &CpuStorage,
+        _: &Layout,
+        _: &CpuStorage,
+        _: &Layout,
+    ) -> Result<(CpuStorage, Shape)> {
+        candle::bail!("gptq-matmul is only implemented on cuda")
+    }
+
+    #[cfg(feature = "cuda")]
+    fn cuda_fwd(
+        &self,
+        s1: &candle::CudaStorage,
+        l1: &Layout,
+        s2: &candle::CudaStorage,
+        l2: &Layout,
+    ) -> Result<(candle::CudaStorage, Shape)> {
+        use crate::awq::cuda_contiguous;
+        use candle::backend::BackendStorage;
+        use candle::cuda_backend::cudarc::driver::{
+            CudaSlice, CudaView, DeviceRepr, LaunchAsync, LaunchConfig,
+        };
+        use candle::cuda_backend::{kernel_name, kernels, CudaDType, CudaStorageSlice, WrapErr};
+        use candle::{CudaDevice, Storage};
+
+        #[allow(clippy::too_many_arguments)]
+        fn launch<T: CudaDType + DeviceRepr + WithDType>(
+            dev: &CudaDevice,
+            p: Packing,
+            (xs, xs_l): (&candle::CudaStorage, &Layout),
+            (s2, l2): (&candle::CudaStorage, &Layout),
+            qzeros: &CudaView<u32>,
+            (s4, l4): (&candle::CudaStorage, &Layout),
+            g_idx: &CudaView<u32>,
+            (n_rows, in_dim, out_dim): (usize, usize, usize),
+        ) -> Result<CudaSlice<T>> {
+            let xs = cuda_contiguous(xs.as_cuda_slice::<T>()?, xs_l)?;
+            let qweight = cuda_contiguous(s2.as_cuda_slice::<u32>()?, l2)?;
+            let scales = cuda_contiguous(s4.as_cuda_slice::<T>()?, l4)?;
+            let block = 128;
+            let cfg = LaunchConfig {
+                grid_dim: (out_dim.div_ceil(block) as u32, n_rows as u32, 1),
+                block_dim: (block as u32, 1, 1),
+                shared_mem_bytes: 0,
+            };
+            let func = dev.get_or_load_func(&kernel_name::<T>("gptq_matmul"), kernels::GPTQ)?;
+            // SAFETY: Set later by running the kernel.
+            let dst = unsafe { dev.alloc::<T>(n_rows * out_dim) }.w()?;
+            let params = (
+                &xs,
+                &qweight,
+                qzeros,
+                &scales,
+                g_idx,
+                &dst,
+                in_dim,
+                out_dim,
+                p.bits,
+                p.zero_offset,
+            );
+            // SAFETY: ffi.
+            unsafe { func.launch(cfg, params) }.w()?;
+            Ok(dst)
+        }
+
+        let (qzeros, l3) = self.qzeros.storage_and_layout();
+        let (scales, l4) = self.scales.storage_and_layout();
+        let (g_idx, l5) = self.g_idx.storage_and_layout();
+        let (s3, s4, s5) = match (&*qzeros, &*scales, &*g_idx) {
+            (Storage::Cuda(s3), Storage::Cuda(s4), Storage::Cuda(s5)) => (s3, s4, s5),
+            _ => candle::bail!("gptq-matmul, all the tensors have to be on a cuda device"),
+        };
+        let qzeros = cuda_contiguous(s3.as_cuda_slice::<u32>()?, l3)?;
+        let g_idx = cuda_contiguous(s5.as_cuda_slice::<u32>()?, l5)?;
+        let p = self.packing;
+        let (in_dim, out_dim) = gptq_dims(p, l2, l3, l4, l5)?;
+        let (n_rows, xs_in_dim) = l1.shape().dims2()?;
+        if xs_in_dim != in_dim {
+            candle::bail!(
+                "gptq-matmul, shape mismatch {:?} {:?}",
+                l1.shape(),
+                l2.shape()
+            )
+        }
+        let dims = (n_rows, in_dim, out_dim);
+        let dev = s1.device().clone();
+        let (a1, a2, a4) = ((s1, l1), (s2, l2), (s4, l4));
+        let slice = match s1.dtype() {
+            DType::F16 => {
+                CudaStorageSlice::F16(launch(&dev, p, a1, a2, &qzeros, a4, &g_idx, dims)?)
+            }
+            DType::BF16 => {
+                CudaStorageSlice::BF16(launch(&dev, p, a1, a2, &qzeros, a4, &g_idx, dims)?)
+            }
+            DType::F32 => {
+                CudaStorageSlice::F32(launch(&dev, p, a1, a2, &qzeros, a4, &g_idx, dims)?)
+            }
+            dtype => candle::bail!("gptq-matmul, unsupported dtype {dtype:?}"),
+        };
+        let dst = candle::cuda_backend::CudaStorage { slice, device: dev };
+        Ok((dst, Shape::from((n_rows, out_dim))))
+    }
+}
+
+/// A linear layer using GPTQ quantized weights, the activations should use the dtype of the
+/// scales.
+#[derive(Debug, Clone)]
+pub struct GptqLinear {
+    packing: Packing,
+    qweight: Tensor,
+    qzeros: Tensor,
+    scales: Tensor,
+    g_idx: Tensor,
+    bias: Option<Tensor>,
+}
+
+impl GptqLinear {
+    pub fn from_tensors(
+        cfg: &GptqConfig,
+        qweight: Tensor,
+        qzeros: Tensor,
+        scales: Tensor,
+        g_idx: Tensor,
+        bias: Option<Tensor>,
+    ) -> Result<Self> {
+        cfg.check()?;
+        let packing = Packing {
+            bits: cfg.bits as u32,
+            zero_offset: cfg.zero_offset()?,
+        };
+        for (name, t) in [
+            ("qweight", &qweight),
+            ("qzeros", &qzeros),
+            ("g_idx", &g_idx),
+        ] {
+            if t.dtype() != DType::U32 {
+                candle::bail!("gptq, {name} should use u32, got {:?}", t.dtype())
+            }
+        }
+        gptq_dims(
+            packing,
+            qweight.layout(),
+            qzeros.layout(),
+            scales.layout(),
+            g_idx.layout(),
+        )?;
+        Ok(Self {
+            packing,
+            qweight: qweight.contiguous()?,
+            qzeros: qzeros.contiguous()?,
+            scales: scales.contiguous()?,
+            g_idx: g_idx.contiguous()?,
+            bias,
+        })
+    }
+
+    /// Loads the `qweight`, `qzeros`, `scales`, `g_idx` and optional `bias` tensors of a linear
+    /// layer. When `g_idx` is missing the groups are made of consecutive input features.
+    pub fn new(
+        in_dim: usize,
+        out_dim: usize,
+        bias: bool,
+        cfg: &GptqConfig,
+        vb: VarBuilder,
+    ) -> Result<Self> {
+        cfg.check()?;
+        let pack = 32 / cfg.bits;
+        let group_size = cfg.group_size(in_dim);
+        if in_dim % pack != 0 || out_dim % pack != 0 || group_size == 0 {
+            candle::bail!("gptq, unsupported dimensions in: {in_dim}, out: {out_dim}")
+        }
+        let n_groups = in_dim.div_ceil(group_size);
+        let init = Default::default();
+        let qweight =
+            vb.get_with_hints_dtype((in_dim / pack, out_dim), "qweight", init, DType::U32)?;
+        let qzeros =
+            vb.get_with_hints_dtype((n_groups, out_dim / pack), "qzeros", init, DType::U32)?;
+        let scales = vb.get((n_groups, out_dim), "scales")?;
+        let g_idx = if vb.contains_tensor("g_idx") {
+            vb.get_with_hints_dtype(in_dim, "g_idx", init, DType::U32)?
+        } else {
+            let g_idx: Vec<u32> = (0..in_dim).map(|i| (i / group_size) as u32).collect();
+            Tensor::new(g_idx, vb.device())?
+        };
+        let bias = if bias {
+            Some(vb.get(out_dim, "bias")?)
+        } else {
+            None
+        };
+        Self::from_tensors(cfg, qweight, qzeros, scales, g_idx, bias)
+    }
+
+    /// The dequantized weights, with shape `(in_dim, out_dim)`.
+    pub fn dequantize(&self) -> Result<Tensor> {
+        let op = GptqDequantize {
+            packing: self.packing,
+            g_idx: self.g_idx.clone(),
+        };
+        self.qweight
+            .apply_op3_no_bwd(&self.qzeros, &self.scales, &op)
+    }
+
+    pub fn bias(&self) -> Option<&Tensor> {
+        self.bias.as_ref()
+    }
+}
+
+impl Module for GptqLinear {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        let in_dim = self.g_idx.dim(0)?;
+        let out_dim = self.scales.dim(1)?;
+        let mut dims = xs.dims().to_vec();
+        let n_rows = xs.elem_count() / in_dim;
+        let ys = if xs.device().is_cuda() && n_rows <= FUSED_MAX_ROWS {
+            let op = GptqMatmul {
+                packing: self.packing,
+                qzeros: self.qzeros.clone(),
+                scales: self.scales.clone(),
+                g_idx: self.g_idx.clone(),
+            };
+            let xs = xs.reshape((n_rows, in_dim))?.contiguous()?;
+            let ys = xs.apply_op2_no_bwd(&self.qweight, &op)?;
+            match dims.last_mut() {
+                None => candle::bail!("gptq, unexpected scalar input"),
+                Some(d) => *d = out_dim,
+            }
+            ys.reshape(dims)?
+        } else {
+            xs.broadcast_matmul(&self.dequantize()?)?
+        };
+        match &self.bias {
+            None => Ok(ys),
+            Some(bias) => ys.broadcast_add(bias),
+        }
+    }
+}
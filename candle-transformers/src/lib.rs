@@ -1,7 +1,11 @@
+pub mod awq;
+pub mod config;
 pub mod generation;
+pub mod gptq;
 pub mod models;
 pub mod object_detection;
 pub mod pipelines;
 pub mod quantized_nn;
 pub mod quantized_var_builder;
 pub mod utils;
+pub mod w8a8;
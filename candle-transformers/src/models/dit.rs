@@ -0,0 +1,273 @@
+//! Building blocks shared by the diffusion transformers, a.k.a. DiT, such as FLUX or the MMDiT
+//! of Stable Diffusion 3.
+//!
+//! - adaLN-Zero modulation: the conditioning vector, e.g. the timestep and pooled text
+//!   embeddings, is projected to a shift, a scale and a gate for each sub-block. The normalized
+//!   inputs of the sub-block are scaled and shifted, and its output is multiplied by the gate
+//!   before the residual connection.
+//! - joint attention: the text and image tokens use their own projections but a single
+//!   attention runs over the concatenation of both sequences.
+//! - rotary embeddings over multiple position axes, and sinusoidal timestep embeddings.
+//!
+//! The layers are generic over the linear layers so that the same blocks can be used with full
+//! precision or quantized weights.
+use candle::{DType, IndexOp, Module, Result, Tensor, D};
+use candle_nn::RmsNorm;
+
+/// The shift, scale and gate for a modulated sub-block.
+#[derive(Debug, Clone)]
+pub struct ModulationOut {
+    pub shift: Tensor,
+    pub scale: Tensor,
+    pub gate: Tensor,
+}
+
+impl ModulationOut {
+    pub fn scale_shift(&self, xs: &Tensor) -> Result<Tensor> {
+        modulate(xs, &self.shift, &self.scale)
+    }
+
+    pub fn gate(&self, xs: &Tensor) -> Result<Tensor> {
+        self.gate.broadcast_mul(xs)
+    }
+}
+
+/// Computes `xs * (1 + scale) + shift`.
+pub fn modulate(xs: &Tensor, shift: &Tensor, scale: &Tensor) -> Result<Tensor> {
+    xs.broadcast_mul(&(scale + 1.)?)?.broadcast_add(shift)
+}
+
+/// adaLN-Zero modulation, `lin` projects the conditioning vector of size `dim` to `3 * n * dim`
+/// values, i.e. a shift, a scale and a gate for `n` sub-blocks.
+#[derive(Debug, Clone)]
+pub struct Modulation<L> {
+    lin: L,
+    n: usize,
+}
+
+impl<L: Module> Modulation<L> {
+    pub fn new(lin: L, n: usize) -> Self {
+        Self { lin, n }
+    }
+
+    /// The modulation of `n` sub-blocks for a conditioning vector of size `dim`, `linear` builds
+    /// the projection from its input and output sizes.
+    pub fn load(
+        dim: usize,
+        n: usize,
+        linear: impl FnOnce(usize, usize) -> Result<L>,
+    ) -> Result<Self> {
+        Ok(Self::new(linear(dim, 3 * n * dim)?, n))
+    }
+
+    /// Returns the modulations for `vec_` of shape `(batch, dim)`, the values have shape
+    /// `(batch, 1, dim)` so that they broadcast over the sequence.
+    pub fn forward(&self, vec_: &Tensor) -> Result<Vec<ModulationOut>> {
+        let ys = vec_
+            .silu()?
+            .apply(&self.lin)?
+            .unsqueeze(1)?
+            .chunk(3 * self.n, D::Minus1)?;
+        if ys.len() != 3 * self.n {
+            candle::bail!("unexpected len from chunk {ys:?}")
+        }
+        let mods = ys
+            .chunks_exact(3)
+            .map(|ys| ModulationOut {
+                shift: ys[0].clone(),
+                scale: ys[1].clone(),
+                gate: ys[2].clone(),
+            })
+            .collect();
+        Ok(mods)
+    }
+}
+
+/// RMS normalization of the queries and keys over the head dimension.
+#[derive(Debug, Clone)]
+pub struct QkNorm {
+    pub query_norm: RmsNorm,
+    pub key_norm: RmsNorm,
+}
+
+impl QkNorm {
+    pub fn new(query_scale: Tensor, key_scale: Tensor, eps: f64) -> Self {
+        Self {
+            query_norm: RmsNorm::new(query_scale, eps),
+            key_norm: RmsNorm::new(key_scale, eps),
+        }
+    }
+
+    /// Loads the `query_norm.scale` and `key_norm.scale` weights with `get`, as in FLUX.
+    pub fn load(get: impl Fn(&str) -> Result<Tensor>) -> Result<Self> {
+        Ok(Self::new(
+            get("query_norm.scale")?,
+            get("key_norm.scale")?,
+            1e-6,
+        ))
+    }
+
+    pub fn forward(&self, q: &Tensor, k: &Tensor) -> Result<(Tensor, Tensor)> {
+        Ok((q.apply(&self.query_norm)?, k.apply(&self.key_norm)?))
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Qkv {
+    pub q: Tensor,
+    pub k: Tensor,
+    pub v: Tensor,
+}
+
+impl Qkv {
+    /// Splits the output of a fused qkv projection with shape `(batch, seq_len, 3 * dim)` into
+    /// queries, keys, and values with shape `(batch, num_heads, seq_len, head_dim)`.
+    pub fn split_heads(qkv: &Tensor, num_heads: usize) -> Result<Self> {
+        let (b, l, _khd) = qkv.dims3()?;
+        let qkv = qkv.reshape((b, l, 3, num_heads, ()))?;
+        let q = qkv.i((.., .., 0))?.transpose(1, 2)?;
+        let k = qkv.i((.., .., 1))?.transpose(1, 2)?;
+        let v = qkv.i((.., .., 2))?.transpose(1, 2)?;
+        Ok(Self { q, k, v })
+    }
+}
+
+/// Runs `attn` on the concatenation of the context and image tokens along `seq_dim`, and splits
+/// the result back into the context and image parts. The output of `attn` has to use dimension
+/// 1 for the sequence.
+pub fn joint_attention<F>(
+    context: &Qkv,
+    x: &Qkv,
+    seq_dim: usize,
+    attn: F,
+) -> Result<(Tensor, Tensor)>
+where
+    F: FnOnce(&Qkv) -> Result<Tensor>,
+{
+    let qkv = Qkv {
+        q: Tensor::cat(&[&context.q, &x.q], seq_dim)?,
+        k: Tensor::cat(&[&context.k, &x.k], seq_dim)?,
+        v: Tensor::cat(&[&context.v, &x.v], seq_dim)?,
+    };
+    let attn = attn(&qkv)?;
+    let context_len = context.q.dim(seq_dim)?;
+    let context_attn = attn.narrow(1, 0, context_len)?;
+    let x_attn = attn.narrow(1, context_len, attn.dim(1)? - context_len)?;
+    Ok((context_attn, x_attn))
+}
+
+pub fn scaled_dot_product_attention(q: &Tensor, k: &Tensor, v: &Tensor) -> Result<Tensor> {
+    let dim = q.dim(D::Minus1)?;
+    let scale_factor = 1.0 / (dim as f64).sqrt();
+    let mut batch_dims = q.dims().to_vec();
+    batch_dims.pop();
+    batch_dims.pop();
+    let q = q.flatten_to(batch_dims.len() - 1)?;
+    let k = k.flatten_to(batch_dims.len() - 1)?;
+    let v = v.flatten_to(batch_dims.len() - 1)?;
+    let attn_weights = (q.matmul(&k.t()?)? * scale_factor)?;
+    let attn_scores = candle_nn::ops::softmax_last_dim(&attn_weights)?.matmul(&v)?;
+    batch_dims.push(attn_scores.dim(D::Minus2)?);
+    batch_dims.push(attn_scores.dim(D::Minus1)?);
+    attn_scores.reshape(batch_dims)
+}
+
+/// The rotation matrices for positions `pos` of shape `(batch, seq_len)`, the result has shape
+/// `(batch, seq_len, dim / 2, 2, 2)`.
+pub fn rope(pos: &Tensor, dim: usize, theta: usize) -> Result<Tensor> {
+    if dim % 2 == 1 {
+        candle::bail!("dim {dim} is odd")
+    }
+    let dev = pos.device();
+    let theta = theta as f64;
+    let inv_freq: Vec<_> = (0..dim)
+        .step_by(2)
+        .map(|i| 1f32 / theta.powf(i as f64 / dim as f64) as f32)
+        .collect();
+    let inv_freq_len = inv_freq.len();
+    let inv_freq = Tensor::from_vec(inv_freq, (1, 1, inv_freq_len), dev)?;
+    let inv_freq = inv_freq.to_dtype(pos.dtype())?;
+    let freqs = pos.unsqueeze(2)?.broadcast_mul(&inv_freq)?;
+    let cos = freqs.cos()?;
+    let sin = freqs.sin()?;
+    let out = Tensor::stack(&[&cos, &sin.neg()?, &sin, &cos], 3)?;
+    let (b, n, d, _ij) = out.dims4()?;
+    out.reshape((b, n, d, 2, 2))
+}
+
+pub fn apply_rope(x: &Tensor, freq_cis: &Tensor) -> Result<Tensor> {
+    let dims = x.dims();
+    let (b_sz, n_head, seq_len, n_embd) = x.dims4()?;
+    let x = x.reshape((b_sz, n_head, seq_len, n_embd / 2, 2))?;
+    let x0 = x.narrow(D::Minus1, 0, 1)?;
+    let x1 = x.narrow(D::Minus1, 1, 1)?;
+    let fr0 = freq_cis.get_on_dim(D::Minus1, 0)?;
+    let fr1 = freq_cis.get_on_dim(D::Minus1, 1)?;
+    (fr0.broadcast_mul(&x0)? + fr1.broadcast_mul(&x1)?)?.reshape(dims.to_vec())
+}
+
+/// Attention with rotary embeddings on `q` and `k` of shape `(batch, num_heads, seq_len,
+/// head_dim)`, the result has shape `(batch, seq_len, num_heads * head_dim)`.
+pub fn attention(q: &Tensor, k: &Tensor, v: &Tensor, pe: &Tensor) -> Result<Tensor> {
+    let q = apply_rope(q, pe)?.contiguous()?;
+    let k = apply_rope(k, pe)?.contiguous()?;
+    let x = scaled_dot_product_attention(&q, &k, v)?;
+    x.transpose(1, 2)?.flatten_from(2)
+}
+
+/// Sinusoidal embeddings for timesteps `t` in `[0, 1]`.
+pub fn timestep_embedding(t: &Tensor, dim: usize, dtype: DType) -> Result<Tensor> {
+    const TIME_FACTOR: f64 = 1000.;
+    const MAX_PERIOD: f64 = 10000.;
+    if dim % 2 == 1 {
+        candle::bail!("{dim} is odd")
+    }
+    let dev = t.device();
+    let half = dim / 2;
+    let t = (t * TIME_FACTOR)?;
+    let arange = Tensor::arange(0, half as u32, dev)?.to_dtype(candle::DType::F32)?;
+    let freqs = (arange * (-MAX_PERIOD.ln() / half as f64))?.exp()?;
+    let args = t
+        .unsqueeze(1)?
+        .to_dtype(candle::DType::F32)?
+        .broadcast_mul(&freqs.unsqueeze(0)?)?;
+    let emb = Tensor::cat(&[args.cos()?, args.sin()?], D::Minus1)?.to_dtype(dtype)?;
+    Ok(emb)
+}
+
+/// Rotary embeddings for positions with multiple axes, e.g. the text index and the row and
+/// column of the image patches, each axis uses `axes_dim[i]` dimensions of the heads.
+#[derive(Debug, Clone)]
+pub struct EmbedNd {
+    #[allow(unused)]
+    dim: usize,
+    theta: usize,
+    axes_dim: Vec<usize>,
+}
+
+impl EmbedNd {
+    pub fn new(dim: usize, theta: usize, axes_dim: Vec<usize>) -> Self {
+        Self {
+            dim,
+            theta,
+            axes_dim,
+        }
+    }
+}
+
+impl Module for EmbedNd {
+    fn forward(&self, ids: &Tensor) -> Result<Tensor> {
+        let n_axes = ids.dim(D::Minus1)?;
+        let mut emb = Vec::with_capacity(n_axes);
+        for idx in 0..n_axes {
+            let r = rope(
+                &ids.get_on_dim(D::Minus1, idx)?,
+                self.axes_dim[idx],
+                self.theta,
+            )?;
+            emb.push(r)
+        }
+        let emb = Tensor::cat(&emb, 2)?;
+        emb.unsqueeze(1)
+    }
+}
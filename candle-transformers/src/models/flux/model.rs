@@ -1,5 +1,8 @@
-use candle::{DType, IndexOp, Result, Tensor, D};
-use candle_nn::{LayerNorm, Linear, RmsNorm, VarBuilder};
+use crate::models::dit::{attention, joint_attention, timestep_embedding, Modulation, Qkv};
+use candle::{IndexOp, Result, Tensor, D};
+use candle_nn::{LayerNorm, Linear, VarBuilder};
+
+pub use crate::models::dit::{EmbedNd, QkNorm};
 
 // https://github.com/black-forest-labs/flux/blob/727e3a71faf37390f318cf9434f0939653302b60/src/flux/model.py#L12
 #[derive(Debug, Clone)]
@@ -61,115 +64,6 @@ fn layer_norm(dim: usize, vb: VarBuilder) -> Result<LayerNorm> {
     Ok(LayerNorm::new_no_bias(ws, 1e-6))
 }
 
-fn scaled_dot_product_attention(q: &Tensor, k: &Tensor, v: &Tensor) -> Result<Tensor> {
-    let dim = q.dim(D::Minus1)?;
-    let scale_factor = 1.0 / (dim as f64).sqrt();
-    let mut batch_dims = q.dims().to_vec();
-    batch_dims.pop();
-    batch_dims.pop();
-    let q = q.flatten_to(batch_dims.len() - 1)?;
-    let k = k.flatten_to(batch_dims.len() - 1)?;
-    let v = v.flatten_to(batch_dims.len() - 1)?;
-    let attn_weights = (q.matmul(&k.t()?)? * scale_factor)?;
-    let attn_scores = candle_nn::ops::softmax_last_dim(&attn_weights)?.matmul(&v)?;
-    batch_dims.push(attn_scores.dim(D::Minus2)?);
-    batch_dims.push(attn_scores.dim(D::Minus1)?);
-    attn_scores.reshape(batch_dims)
-}
-
-fn rope(pos: &Tensor, dim: usize, theta: usize) -> Result<Tensor> {
-    if dim % 2 == 1 {
-        candle::bail!("dim {dim} is odd")
-    }
-    let dev = pos.device();
-    let theta = theta as f64;
-    let inv_freq: Vec<_> = (0..dim)
-        .step_by(2)
-        .map(|i| 1f32 / theta.powf(i as f64 / dim as f64) as f32)
-        .collect();
-    let inv_freq_len = inv_freq.len();
-    let inv_freq = Tensor::from_vec(inv_freq, (1, 1, inv_freq_len), dev)?;
-    let inv_freq = inv_freq.to_dtype(pos.dtype())?;
-    let freqs = pos.unsqueeze(2)?.broadcast_mul(&inv_freq)?;
-    let cos = freqs.cos()?;
-    let sin = freqs.sin()?;
-    let out = Tensor::stack(&[&cos, &sin.neg()?, &sin, &cos], 3)?;
-    let (b, n, d, _ij) = out.dims4()?;
-    out.reshape((b, n, d, 2, 2))
-}
-
-fn apply_rope(x: &Tensor, freq_cis: &Tensor) -> Result<Tensor> {
-    let dims = x.dims();
-    let (b_sz, n_head, seq_len, n_embd) = x.dims4()?;
-    let x = x.reshape((b_sz, n_head, seq_len, n_embd / 2, 2))?;
-    let x0 = x.narrow(D::Minus1, 0, 1)?;
-    let x1 = x.narrow(D::Minus1, 1, 1)?;
-    let fr0 = freq_cis.get_on_dim(D::Minus1, 0)?;
-    let fr1 = freq_cis.get_on_dim(D::Minus1, 1)?;
-    (fr0.broadcast_mul(&x0)? + fr1.broadcast_mul(&x1)?)?.reshape(dims.to_vec())
-}
-
-pub(crate) fn attention(q: &Tensor, k: &Tensor, v: &Tensor, pe: &Tensor) -> Result<Tensor> {
-    let q = apply_rope(q, pe)?.contiguous()?;
-    let k = apply_rope(k, pe)?.contiguous()?;
-    let x = scaled_dot_product_attention(&q, &k, v)?;
-    x.transpose(1, 2)?.flatten_from(2)
-}
-
-pub(crate) fn timestep_embedding(t: &Tensor, dim: usize, dtype: DType) -> Result<Tensor> {
-    const TIME_FACTOR: f64 = 1000.;
-    const MAX_PERIOD: f64 = 10000.;
-    if dim % 2 == 1 {
-        candle::bail!("{dim} is odd")
-    }
-    let dev = t.device();
-    let half = dim / 2;
-    let t = (t * TIME_FACTOR)?;
-    let arange = Tensor::arange(0, half as u32, dev)?.to_dtype(candle::DType::F32)?;
-    let freqs = (arange * (-MAX_PERIOD.ln() / half as f64))?.exp()?;
-    let args = t
-        .unsqueeze(1)?
-        .to_dtype(candle::DType::F32)?
-        .broadcast_mul(&freqs.unsqueeze(0)?)?;
-    let emb = Tensor::cat(&[args.cos()?, args.sin()?], D::Minus1)?.to_dtype(dtype)?;
-    Ok(emb)
-}
-
-#[derive(Debug, Clone)]
-pub struct EmbedNd {
-    #[allow(unused)]
-    dim: usize,
-    theta: usize,
-    axes_dim: Vec<usize>,
-}
-
-impl EmbedNd {
-    pub fn new(dim: usize, theta: usize, axes_dim: Vec<usize>) -> Self {
-        Self {
-            dim,
-            theta,
-            axes_dim,
-        }
-    }
-}
-
-impl candle::Module for EmbedNd {
-    fn forward(&self, ids: &Tensor) -> Result<Tensor> {
-        let n_axes = ids.dim(D::Minus1)?;
-        let mut emb = Vec::with_capacity(n_axes);
-        for idx in 0..n_axes {
-            let r = rope(
-                &ids.get_on_dim(D::Minus1, idx)?,
-                self.axes_dim[idx],
-                self.theta,
-            )?;
-            emb.push(r)
-        }
-        let emb = Tensor::cat(&emb, 2)?;
-        emb.unsqueeze(1)
-    }
-}
-
 #[derive(Debug, Clone)]
 pub struct MlpEmbedder {
     in_layer: Linear,
@@ -193,104 +87,6 @@ impl candle::Module for MlpEmbedder {
     }
 }
 
-#[derive(Debug, Clone)]
-pub struct QkNorm {
-    query_norm: RmsNorm,
-    key_norm: RmsNorm,
-}
-
-impl QkNorm {
-    fn new(dim: usize, vb: VarBuilder) -> Result<Self> {
-        let query_norm = vb.get(dim, "query_norm.scale")?;
-        let query_norm = RmsNorm::new(query_norm, 1e-6);
-        let key_norm = vb.get(dim, "key_norm.scale")?;
-        let key_norm = RmsNorm::new(key_norm, 1e-6);
-        Ok(Self {
-            query_norm,
-            key_norm,
-        })
-    }
-}
-
-struct ModulationOut {
-    shift: Tensor,
-    scale: Tensor,
-    gate: Tensor,
-}
-
-impl ModulationOut {
-    fn scale_shift(&self, xs: &Tensor) -> Result<Tensor> {
-        xs.broadcast_mul(&(&self.scale + 1.)?)?
-            .broadcast_add(&self.shift)
-    }
-
-    fn gate(&self, xs: &Tensor) -> Result<Tensor> {
-        self.gate.broadcast_mul(xs)
-    }
-}
-
-#[derive(Debug, Clone)]
-struct Modulation1 {
-    lin: Linear,
-}
-
-impl Modulation1 {
-    fn new(dim: usize, vb: VarBuilder) -> Result<Self> {
-        let lin = candle_nn::linear(dim, 3 * dim, vb.pp("lin"))?;
-        Ok(Self { lin })
-    }
-
-    fn forward(&self, vec_: &Tensor) -> Result<ModulationOut> {
-        let ys = vec_
-            .silu()?
-            .apply(&self.lin)?
-            .unsqueeze(1)?
-            .chunk(3, D::Minus1)?;
-        if ys.len() != 3 {
-            candle::bail!("unexpected len from chunk {ys:?}")
-        }
-        Ok(ModulationOut {
-            shift: ys[0].clone(),
-            scale: ys[1].clone(),
-            gate: ys[2].clone(),
-        })
-    }
-}
-
-#[derive(Debug, Clone)]
-struct Modulation2 {
-    lin: Linear,
-}
-
-impl Modulation2 {
-    fn new(dim: usize, vb: VarBuilder) -> Result<Self> {
-        let lin = candle_nn::linear(dim, 6 * dim, vb.pp("lin"))?;
-        Ok(Self { lin })
-    }
-
-    fn forward(&self, vec_: &Tensor) -> Result<(ModulationOut, ModulationOut)> {
-        let ys = vec_
-            .silu()?
-            .apply(&self.lin)?
-            .unsqueeze(1)?
-            .chunk(6, D::Minus1)?;
-        if ys.len() != 6 {
-            candle::bail!("unexpected len from chunk {ys:?}")
-        }
-        let mod1 = ModulationOut {
-            shift: ys[0].clone(),
-            scale: ys[1].clone(),
-            gate: ys[2].clone(),
-        };
-        let mod2 = ModulationOut {
-            shift: ys[3].clone(),
-            scale: ys[4].clone(),
-            gate: ys[5].clone(),
-        };
-        Ok((mod1, mod2))
-    }
-}
-
 #[derive(Debug, Clone)]
 pub struct SelfAttention {
     qkv: Linear,
@@ -303,7 +99,7 @@ impl SelfAttention {
     fn new(dim: usize, num_heads: usize, qkv_bias: bool, vb: VarBuilder) -> Result<Self> {
         let head_dim = dim / num_heads;
         let qkv = candle_nn::linear_b(dim, dim * 3, qkv_bias, vb.pp("qkv"))?;
-        let norm = QkNorm::new(head_dim, vb.pp("norm"))?;
+        let norm = QkNorm::load(|name| vb.pp("norm").get(head_dim, name))?;
         let proj = candle_nn::linear(dim, dim, vb.pp("proj"))?;
         Ok(Self {
             qkv,
@@ -313,21 +109,15 @@ impl SelfAttention {
         })
     }
 
-    fn qkv(&self, xs: &Tensor) -> Result<(Tensor, Tensor, Tensor)> {
-        let qkv = xs.apply(&self.qkv)?;
-        let (b, l, _khd) = qkv.dims3()?;
-        let qkv = qkv.reshape((b, l, 3, self.num_heads, ()))?;
-        let q = qkv.i((.., .., 0))?.transpose(1, 2)?;
-        let k = qkv.i((.., .., 1))?.transpose(1, 2)?;
-        let v = qkv.i((.., .., 2))?.transpose(1, 2)?;
-        let q = q.apply(&self.norm.query_norm)?;
-        let k = k.apply(&self.norm.key_norm)?;
-        Ok((q, k, v))
+    fn qkv(&self, xs: &Tensor) -> Result<Qkv> {
+        let Qkv { q, k, v } = Qkv::split_heads(&xs.apply(&self.qkv)?, self.num_heads)?;
+        let (q, k) = self.norm.forward(&q, &k)?;
+        Ok(Qkv { q, k, v })
     }
 
     #[allow(unused)]
     fn forward(&self, xs: &Tensor, pe: &Tensor) -> Result<Tensor> {
-        let (q, k, v) = self.qkv(xs)?;
+        let Qkv { q, k, v } = self.qkv(xs)?;
         attention(&q, &k, &v, pe)?.apply(&self.proj)
     }
 }
@@ -354,12 +144,12 @@ impl candle::Module for Mlp {
 
 #[derive(Debug, Clone)]
 pub struct DoubleStreamBlock {
-    img_mod: Modulation2,
+    img_mod: Modulation<Linear>,
     img_norm1: LayerNorm,
     img_attn: SelfAttention,
     img_norm2: LayerNorm,
     img_mlp: Mlp,
-    txt_mod: Modulation2,
+    txt_mod: Modulation<Linear>,
     txt_norm1: LayerNorm,
     txt_attn: SelfAttention,
     txt_norm2: LayerNorm,
@@ -370,12 +160,16 @@ impl DoubleStreamBlock {
     fn new(cfg: &Config, vb: VarBuilder) -> Result<Self> {
         let h_sz = cfg.hidden_size;
         let mlp_sz = (h_sz as f64 * cfg.mlp_ratio) as usize;
-        let img_mod = Modulation2::new(h_sz, vb.pp("img_mod"))?;
+        let img_mod = Modulation::load(h_sz, 2, |i, o| {
+            candle_nn::linear(i, o, vb.pp("img_mod.lin"))
+        })?;
         let img_norm1 = layer_norm(h_sz, vb.pp("img_norm1"))?;
         let img_attn = SelfAttention::new(h_sz, cfg.num_heads, cfg.qkv_bias, vb.pp("img_attn"))?;
         let img_norm2 = layer_norm(h_sz, vb.pp("img_norm2"))?;
         let img_mlp = Mlp::new(h_sz, mlp_sz, vb.pp("img_mlp"))?;
-        let txt_mod = Modulation2::new(h_sz, vb.pp("txt_mod"))?;
+        let txt_mod = Modulation::load(h_sz, 2, |i, o| {
+            candle_nn::linear(i, o, vb.pp("txt_mod.lin"))
+        })?;
         let txt_norm1 = layer_norm(h_sz, vb.pp("txt_norm1"))?;
         let txt_attn = SelfAttention::new(h_sz, cfg.num_heads, cfg.qkv_bias, vb.pp("txt_attn"))?;
         let txt_norm2 = layer_norm(h_sz, vb.pp("txt_norm2"))?;
@@ -401,23 +195,21 @@ impl DoubleStreamBlock {
         vec_: &Tensor,
         pe: &Tensor,
     ) -> Result<(Tensor, Tensor)> {
-        let (img_mod1, img_mod2) = self.img_mod.forward(vec_)?; // shift, scale, gate
-        let (txt_mod1, txt_mod2) = self.txt_mod.forward(vec_)?; // shift, scale, gate
+        let img_mods = self.img_mod.forward(vec_)?;
+        let txt_mods = self.txt_mod.forward(vec_)?;
+        let (img_mod1, img_mod2) = (&img_mods[0], &img_mods[1]);
+        let (txt_mod1, txt_mod2) = (&txt_mods[0], &txt_mods[1]);
         let img_modulated = img.apply(&self.img_norm1)?;
         let img_modulated = img_mod1.scale_shift(&img_modulated)?;
-        let (img_q, img_k, img_v) = self.img_attn.qkv(&img_modulated)?;
+        let img_qkv = self.img_attn.qkv(&img_modulated)?;
 
         let txt_modulated = txt.apply(&self.txt_norm1)?;
         let txt_modulated = txt_mod1.scale_shift(&txt_modulated)?;
-        let (txt_q, txt_k, txt_v) = self.txt_attn.qkv(&txt_modulated)?;
+        let txt_qkv = self.txt_attn.qkv(&txt_modulated)?;
 
-        let q = Tensor::cat(&[txt_q, img_q], 2)?;
-        let k = Tensor::cat(&[txt_k, img_k], 2)?;
-        let v = Tensor::cat(&[txt_v, img_v], 2)?;
-
-        let attn = attention(&q, &k, &v, pe)?;
-        let txt_attn = attn.narrow(1, 0, txt.dim(1)?)?;
-        let img_attn = attn.narrow(1, txt.dim(1)?, attn.dim(1)? - txt.dim(1)?)?;
+        let (txt_attn, img_attn) = joint_attention(&txt_qkv, &img_qkv, 2, |qkv| {
+            attention(&qkv.q, &qkv.k, &qkv.v, pe)
+        })?;
 
         let img = (img + img_mod1.gate(&img_attn.apply(&self.img_attn.proj)?))?;
         let img = (&img
@@ -445,7 +237,7 @@ pub struct SingleStreamBlock {
     linear2: Linear,
     norm: QkNorm,
     pre_norm: LayerNorm,
-    modulation: Modulation1,
+    modulation: Modulation<Linear>,
     h_sz: usize,
     mlp_sz: usize,
     num_heads: usize,
@@ -458,9 +250,11 @@ impl SingleStreamBlock {
         let head_dim = h_sz / cfg.num_heads;
         let linear1 = candle_nn::linear(h_sz, h_sz * 3 + mlp_sz, vb.pp("linear1"))?;
         let linear2 = candle_nn::linear(h_sz + mlp_sz, h_sz, vb.pp("linear2"))?;
-        let norm = QkNorm::new(head_dim, vb.pp("norm"))?;
+        let norm = QkNorm::load(|name| vb.pp("norm").get(head_dim, name))?;
         let pre_norm = layer_norm(h_sz, vb.pp("pre_norm"))?;
-        let modulation = Modulation1::new(h_sz, vb.pp("modulation"))?;
+        let modulation = Modulation::load(h_sz, 1, |i, o| {
+            candle_nn::linear(i, o, vb.pp("modulation.lin"))
+        })?;
         Ok(Self {
             linear1,
             linear2,
@@ -474,18 +268,13 @@ impl SingleStreamBlock {
     }
 
     fn forward(&self, xs: &Tensor, vec_: &Tensor, pe: &Tensor) -> Result<Tensor> {
-        let mod_ = self.modulation.forward(vec_)?;
+        let mod_ = &self.modulation.forward(vec_)?[0];
         let x_mod = mod_.scale_shift(&xs.apply(&self.pre_norm)?)?;
         let x_mod = x_mod.apply(&self.linear1)?;
         let qkv = x_mod.narrow(D::Minus1, 0, 3 * self.h_sz)?;
-        let (b, l, _khd) = qkv.dims3()?;
-        let qkv = qkv.reshape((b, l, 3, self.num_heads, ()))?;
-        let q = qkv.i((.., .., 0))?.transpose(1, 2)?;
-        let k = qkv.i((.., .., 1))?.transpose(1, 2)?;
-        let v = qkv.i((.., .., 2))?.transpose(1, 2)?;
+        let Qkv { q, k, v } = Qkv::split_heads(&qkv, self.num_heads)?;
         let mlp = x_mod.narrow(D::Minus1, 3 * self.h_sz, self.mlp_sz)?;
-        let q = q.apply(&self.norm.query_norm)?;
-        let k = k.apply(&self.norm.key_norm)?;
+        let (q, k) = self.norm.forward(&q, &k)?;
         let attn = attention(&q, &k, &v, pe)?;
         let output = Tensor::cat(&[attn, mlp.gelu()?], 2)?.apply(&self.linear2)?;
         xs + mod_.gate(&output)
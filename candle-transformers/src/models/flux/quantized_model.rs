@@ -1,8 +1,13 @@
-use super::model::{attention, timestep_embedding, Config, EmbedNd};
+use super::model::Config;
+use crate::models::dit::{
+    attention, joint_attention, timestep_embedding, EmbedNd, Modulation, Qkv,
+};
 use crate::quantized_nn::{linear, linear_b, Linear};
 use crate::quantized_var_builder::VarBuilder;
 use candle::{DType, IndexOp, Result, Tensor, D};
-use candle_nn::{LayerNorm, RmsNorm};
+use candle_nn::LayerNorm;
+
+pub use crate::models::dit::QkNorm;
 
 fn layer_norm(dim: usize, vb: VarBuilder) -> Result<LayerNorm> {
     let ws = Tensor::ones(dim, DType::F32, vb.device())?;
@@ -32,104 +37,6 @@ impl candle::Module for MlpEmbedder {
     }
 }
 
-#[derive(Debug, Clone)]
-pub struct QkNorm {
-    query_norm: RmsNorm,
-    key_norm: RmsNorm,
-}
-
-impl QkNorm {
-    fn new(dim: usize, vb: VarBuilder) -> Result<Self> {
-        let query_norm = vb.get(dim, "query_norm.scale")?.dequantize(vb.device())?;
-        let query_norm = RmsNorm::new(query_norm, 1e-6);
-        let key_norm = vb.get(dim, "key_norm.scale")?.dequantize(vb.device())?;
-        let key_norm = RmsNorm::new(key_norm, 1e-6);
-        Ok(Self {
-            query_norm,
-            key_norm,
-        })
-    }
-}
-
-struct ModulationOut {
-    shift: Tensor,
-    scale: Tensor,
-    gate: Tensor,
-}
-
-impl ModulationOut {
-    fn scale_shift(&self, xs: &Tensor) -> Result<Tensor> {
-        xs.broadcast_mul(&(&self.scale + 1.)?)?
-            .broadcast_add(&self.shift)
-    }
-
-    fn gate(&self, xs: &Tensor) -> Result<Tensor> {
-        self.gate.broadcast_mul(xs)
-    }
-}
-
-#[derive(Debug, Clone)]
-struct Modulation1 {
-    lin: Linear,
-}
-
-impl Modulation1 {
-    fn new(dim: usize, vb: VarBuilder) -> Result<Self> {
-        let lin = linear(dim, 3 * dim, vb.pp("lin"))?;
-        Ok(Self { lin })
-    }
-
-    fn forward(&self, vec_: &Tensor) -> Result<ModulationOut> {
-        let ys = vec_
-            .silu()?
-            .apply(&self.lin)?
-            .unsqueeze(1)?
-            .chunk(3, D::Minus1)?;
-        if ys.len() != 3 {
-            candle::bail!("unexpected len from chunk {ys:?}")
-        }
-        Ok(ModulationOut {
-            shift: ys[0].clone(),
-            scale: ys[1].clone(),
-            gate: ys[2].clone(),
-        })
-    }
-}
-
-#[derive(Debug, Clone)]
-struct Modulation2 {
-    lin: Linear,
-}
-
-impl Modulation2 {
-    fn new(dim: usize, vb: VarBuilder) -> Result<Self> {
-        let lin = linear(dim, 6 * dim, vb.pp("lin"))?;
-        Ok(Self { lin })
-    }
-
-    fn forward(&self, vec_: &Tensor) -> Result<(ModulationOut, ModulationOut)> {
-        let ys = vec_
-            .silu()?
-            .apply(&self.lin)?
-            .unsqueeze(1)?
-            .chunk(6, D::Minus1)?;
-        if ys.len() != 6 {
-            candle::bail!("unexpected len from chunk {ys:?}")
-        }
-        let mod1 = ModulationOut {
-            shift: ys[0].clone(),
-            scale: ys[1].clone(),
-            gate: ys[2].clone(),
-        };
-        let mod2 = ModulationOut {
-            shift: ys[3].clone(),
-            scale: ys[4].clone(),
-            gate: ys[5].clone(),
-        };
-        Ok((mod1, mod2))
-    }
-}
-
 #[derive(Debug, Clone)]
 pub struct SelfAttention {
     qkv: Linear,
@@ -142,7 +49,7 @@ impl SelfAttention {
     fn new(dim: usize, num_heads: usize, qkv_bias: bool, vb: VarBuilder) -> Result<Self> {
         let head_dim = dim / num_heads;
         let qkv = linear_b(dim, dim * 3, qkv_bias, vb.pp("qkv"))?;
-        let norm = QkNorm::new(head_dim, vb.pp("norm"))?;
+        let norm = QkNorm::load(|name| vb.pp("norm").get(head_dim, name)?.dequantize(vb.device()))?;
         let proj = linear(dim, dim, vb.pp("proj"))?;
         Ok(Self {
             qkv,
@@ -152,21 +59,15 @@ impl SelfAttention {
         })
     }
 
-    fn qkv(&self, xs: &Tensor) -> Result<(Tensor, Tensor, Tensor)> {
-        let qkv = xs.apply(&self.qkv)?;
-        let (b, l, _khd) = qkv.dims3()?;
-        let qkv = qkv.reshape((b, l, 3, self.num_heads, ()))?;
-        let q = qkv.i((.., .., 0))?.transpose(1, 2)?;
-        let k = qkv.i((.., .., 1))?.transpose(1, 2)?;
-        let v = qkv.i((.., .., 2))?.transpose(1, 2)?;
-        let q = q.apply(&self.norm.query_norm)?;
-        let k = k.apply(&self.norm.key_norm)?;
-        Ok((q, k, v))
+    fn qkv(&self, xs: &Tensor) -> Result<Qkv> {
+        let Qkv { q, k, v } = Qkv::split_heads(&xs.apply(&self.qkv)?, self.num_heads)?;
+        let (q, k) = self.norm.forward(&q, &k)?;
+        Ok(Qkv { q, k, v })
     }
 
     #[allow(unused)]
     fn forward(&self, xs: &Tensor, pe: &Tensor) -> Result<Tensor> {
-        let (q, k, v) = self.qkv(xs)?;
+        let Qkv { q, k, v } = self.qkv(xs)?;
         attention(&q, &k, &v, pe)?.apply(&self.proj)
     }
 }
@@ -193,12 +94,12 @@ impl candle::Module for Mlp {
 
 #[derive(Debug, Clone)]
 pub struct DoubleStreamBlock {
-    img_mod: Modulation2,
+    img_mod: Modulation<Linear>,
     img_norm1: LayerNorm,
     img_attn: SelfAttention,
     img_norm2: LayerNorm,
     img_mlp: Mlp,
-    txt_mod: Modulation2,
+    txt_mod: Modulation<Linear>,
     txt_norm1: LayerNorm,
     txt_attn: SelfAttention,
     txt_norm2: LayerNorm,
@@ -209,12 +110,12 @@ impl DoubleStreamBlock {
     fn new(cfg: &Config, vb: VarBuilder) -> Result<Self> {
         let h_sz = cfg.hidden_size;
         let mlp_sz = (h_sz as f64 * cfg.mlp_ratio) as usize;
-        let img_mod = Modulation2::new(h_sz, vb.pp("img_mod"))?;
+        let img_mod = Modulation::load(h_sz, 2, |i, o| linear(i, o, vb.pp("img_mod.lin")))?;
         let img_norm1 = layer_norm(h_sz, vb.pp("img_norm1"))?;
         let img_attn = SelfAttention::new(h_sz, cfg.num_heads, cfg.qkv_bias, vb.pp("img_attn"))?;
         let img_norm2 = layer_norm(h_sz, vb.pp("img_norm2"))?;
         let img_mlp = Mlp::new(h_sz, mlp_sz, vb.pp("img_mlp"))?;
-        let txt_mod = Modulation2::new(h_sz, vb.pp("txt_mod"))?;
+        let txt_mod = Modulation::load(h_sz, 2, |i, o| linear(i, o, vb.pp("txt_mod.lin")))?;
         let txt_norm1 = layer_norm(h_sz, vb.pp("txt_norm1"))?;
         let txt_attn = SelfAttention::new(h_sz, cfg.num_heads, cfg.qkv_bias, vb.pp("txt_attn"))?;
         let txt_norm2 = layer_norm(h_sz, vb.pp("txt_norm2"))?;
@@ -240,23 +141,21 @@ impl DoubleStreamBlock {
         vec_: &Tensor,
         pe: &Tensor,
     ) -> Result<(Tensor, Tensor)> {
-        let (img_mod1, img_mod2) = self.img_mod.forward(vec_)?; // shift, scale, gate
-        let (txt_mod1, txt_mod2) = self.txt_mod.forward(vec_)?; // shift, scale, gate
+        let img_mods = self.img_mod.forward(vec_)?;
+        let txt_mods = self.txt_mod.forward(vec_)?;
+        let (img_mod1, img_mod2) = (&img_mods[0], &img_mods[1]);
+        let (txt_mod1, txt_mod2) = (&txt_mods[0], &txt_mods[1]);
         let img_modulated = img.apply(&self.img_norm1)?;
         let img_modulated = img_mod1.scale_shift(&img_modulated)?;
-        let (img_q, img_k, img_v) = self.img_attn.qkv(&img_modulated)?;
+        let img_qkv = self.img_attn.qkv(&img_modulated)?;
 
         let txt_modulated = txt.apply(&self.txt_norm1)?;
         let txt_modulated = txt_mod1.scale_shift(&txt_modulated)?;
-        let (txt_q, txt_k, txt_v) = self.txt_attn.qkv(&txt_modulated)?;
+        let txt_qkv = self.txt_attn.qkv(&txt_modulated)?;
 
-        let q = Tensor::cat(&[txt_q, img_q], 2)?;
-        let k = Tensor::cat(&[txt_k, img_k], 2)?;
-        let v = Tensor::cat(&[txt_v, img_v], 2)?;
-
-        let attn = attention(&q, &k, &v, pe)?;
-        let txt_attn = attn.narrow(1, 0, txt.dim(1)?)?;
-        let img_attn = attn.narrow(1, txt.dim(1)?, attn.dim(1)? - txt.dim(1)?)?;
+        let (txt_attn, img_attn) = joint_attention(&txt_qkv, &img_qkv, 2, |qkv| {
+            attention(&qkv.q, &qkv.k, &qkv.v, pe)
+        })?;
 
         let img = (img + img_mod1.gate(&img_attn.apply(&self.img_attn.proj)?))?;
         let img = (&img
@@ -284,7 +183,7 @@ pub struct SingleStreamBlock {
     linear2: Linear,
     norm: QkNorm,
     pre_norm: LayerNorm,
-    modulation: Modulation1,
+    modulation: Modulation<Linear>,
     h_sz: usize,
     mlp_sz: usize,
     num_heads: usize,
@@ -297,9 +196,9 @@ impl SingleStreamBlock {
         let head_dim = h_sz / cfg.num_heads;
         let linear1 = linear(h_sz, h_sz * 3 + mlp_sz, vb.pp("linear1"))?;
         let linear2 = linear(h_sz + mlp_sz, h_sz, vb.pp("linear2"))?;
-        let norm = QkNorm::new(head_dim, vb.pp("norm"))?;
+        let norm = QkNorm::load(|name| vb.pp("norm").get(head_dim, name)?.dequantize(vb.device()))?;
         let pre_norm = layer_norm(h_sz, vb.pp("pre_norm"))?;
-        let modulation = Modulation1::new(h_sz, vb.pp("modulation"))?;
+        let modulation = Modulation::load(h_sz, 1, |i, o| linear(i, o, vb.pp("modulation.lin")))?;
         Ok(Self {
             linear1,
             linear2,
@@ -313,18 +212,13 @@ impl SingleStreamBlock {
     }
 
     fn forward(&self, xs: &Tensor, vec_: &Tensor, pe: &Tensor) -> Result<Tensor> {
-        let mod_ = self.modulation.forward(vec_)?;
+        let mod_ = &self.modulation.forward(vec_)?[0];
         let x_mod = mod_.scale_shift(&xs.apply(&self.pre_norm)?)?;
         let x_mod = x_mod.apply(&self.linear1)?;
         let qkv = x_mod.narrow(D::Minus1, 0, 3 * self.h_sz)?;
-        let (b, l, _khd) = qkv.dims3()?;
-        let qkv = qkv.reshape((b, l, 3, self.num_heads, ()))?;
-        let q = qkv.i((.., .., 0))?.transpose(1, 2)?;
-        let k = qkv.i((.., .., 1))?.transpose(1, 2)?;
-        let v = qkv.i((.., .., 2))?.transpose(1, 2)?;
+        let Qkv { q, k, v } = Qkv::split_heads(&qkv, self.num_heads)?;
         let mlp = x_mod.narrow(D::Minus1, 3 * self.h_sz, self.mlp_sz)?;
-        let q = q.apply(&self.norm.query_norm)?;
-        let k = k.apply(&self.norm.key_norm)?;
+        let (q, k) = self.norm.forward(&q, &k)?;
         let attn = attention(&q, &k, &v, pe)?;
         let output = Tensor::cat(&[attn, mlp.gelu()?], 2)?.apply(&self.linear2)?;
         xs + mod_.gate(&output)
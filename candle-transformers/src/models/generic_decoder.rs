@@ -0,0 +1,729 @@
+//! Decoder-only transformers built from a declarative description.
+//!
+//! Most recent language models are variations of the same Llama-like decoder: a stack of blocks
+//! made of a self-attention and a feed-forward network, each preceded by a normalization. The
+//! variations are in a few choices such as the number of key-value heads, the position encoding,
+//! the activation, the norm type or the biases of the projections. An [`Architecture`] lists
+//! these choices together with the names of the weights so that a new model of this family can
+//! be supported with a config file rather than a new module.
+//!
+//! The description can be read from json with [`ModelConfig::from_json`], or from any other
+//! serde format such as toml. All the fields except the sizes have defaults matching Llama:
+//!
+//! ```json
+//! {
+//!   "vocab_size": 32000,
+//!   "hidden_size": 2048,
+//!   "intermediate_size": 5632,
+//!   "num_layers": 22,
+//!   "num_attention_heads": 32,
+//!   "num_key_value_heads": 4,
+//!   "attention": { "qkv_bias": true, "sliding_window": 4096 },
+//!   "positions": { "type": "rope", "theta": 1000000.0 },
+//!   "mlp": { "kind": "gated", "activation": "silu" },
+//!   "norm": { "kind": "rms", "eps": 1e-6 },
+//!   "tie_word_embeddings": true
+//! }
+//! ```
+//!
+//! The defaults of [`WeightNames`] follow the Hugging Face Llama checkpoints, e.g.
+//! `model.layers.0.self_attn.q_proj.weight`.
+use crate::config::{defaults, ValidationResult};
+use crate::config::{ensure_divisible, ensure_positive, ConfigError, ModelConfig};
+use crate::models::with_tracing::{layer_norm, linear_b, LayerNorm, Linear, RmsNorm};
+use candle::{DType, Device, Module, Result, Tensor};
+use candle_nn::{Activation, Embedding, VarBuilder};
+use std::sync::Arc;
+
+/// The options of the self-attention layers.
+#[derive(Debug, Clone, Default, PartialEq, serde::Deserialize)]
+#[serde(default)]
+pub struct AttentionSpec {
+    /// Biases on the query, key and value projections, as in Qwen2.
+    pub qkv_bias: bool,
+    /// A bias on the output projection.
+    pub out_bias: bool,
+    /// An RMS norm on each query and key head before the rotary embeddings, as in Qwen3.
+    pub qk_norm: bool,
+    /// The number of tokens each token attends to, itself included.
+    pub sliding_window: Option<usize>,
+    /// The attention logits are soft-capped to `[-softcap, softcap]` with a tanh.
+    pub softcap: Option<f64>,
+    /// The scale of the attention logits, `1 / sqrt(head_dim)` by default.
+    pub scale: Option<f64>,
+}
+
+/// How the positions of the tokens are encoded.
+#[derive(Debug, Clone, PartialEq, serde::Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Positions {
+    /// Rotary embeddings on the queries and keys, on the first `partial_rotary_factor *
+    /// head_dim` values of each head.
+    Rope {
+        #[serde(default = "default_rope_theta")]
+        theta: f64,
+        /// Rotate pairs of consecutive values rather than the two halves of the heads.
+        #[serde(default)]
+        interleaved: bool,
+        #[serde(default)]
+        partial_rotary_factor: Option<f64>,
+    },
+    /// A learned embedding of the positions added to the token embeddings.
+    Learned,
+    None,
+}
+
+fn default_rope_theta() -> f64 {
+    defaults::rope_theta() as f64
+}
+
+impl Default for Positions {
+    fn default() -> Self {
+        Self::Rope {
+            theta: default_rope_theta(),
+            interleaved: false,
+            partial_rotary_factor: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MlpKind {
+    /// `down(act(gate(x)) * up(x))`.
+    #[default]
+    Gated,
+    /// `down(act(up(x)))`.
+    Plain,
+}
+
+/// The options of the feed-forward networks.
+#[derive(Debug, Clone, PartialEq, serde::Deserialize)]
+#[serde(default)]
+pub struct MlpSpec {
+    pub kind: MlpKind,
+    pub activation: Activation,
+    pub bias: bool,
+}
+
+impl Default for MlpSpec {
+    fn default() -> Self {
+        Self {
+            kind: MlpKind::Gated,
+            activation: defaults::hidden_act(),
+            bias: false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum NormKind {
+    #[default]
+    Rms,
+    Layer,
+}
+
+/// The options of the normalization layers.
+#[derive(Debug, Clone, PartialEq, serde::Deserialize)]
+#[serde(default)]
+pub struct NormSpec {
+    pub kind: NormKind,
+    pub eps: f64,
+    /// Whether the layer norms have a bias, unused for the RMS norms.
+    pub bias: bool,
+}
+
+impl Default for NormSpec {
+    fn default() -> Self {
+        Self {
+            kind: NormKind::Rms,
+            eps: defaults::rms_norm_eps(),
+            bias: true,
+        }
+    }
+}
+
+/// How the attention and the feed-forward network of a block are combined.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BlockKind {
+    /// `x = x + attn(norm1(x)); x = x + mlp(norm2(x))`.
+    #[default]
+    Sequential,
+    /// `x = x + attn(norm1(x)) + mlp(norm1(x))`, as in GPT-J or Falcon, the blocks have a
+    /// single norm.
+    Parallel,
+}
+
+/// The names of the weights, relative to their parent module.
+#[derive(Debug, Clone, PartialEq, serde::Deserialize)]
+#[serde(default)]
+pub struct WeightNames {
+    /// The prefix of all the weights but the output projection, can be empty.
+    pub model: String,
+    pub embed_tokens: String,
+    pub embed_positions: String,
+    pub layers: String,
+    pub final_norm: String,
+    pub lm_head: String,
+    pub attention: String,
+    pub q_proj: String,
+    pub k_proj: String,
+    pub v_proj: String,
+    pub o_proj: String,
+    pub q_norm: String,
+    pub k_norm: String,
+    pub mlp: String,
+    pub gate_proj: String,
+    pub up_proj: String,
+    pub down_proj: String,
+    pub input_norm: String,
+    pub post_attention_norm: String,
+}
+
+impl Default for WeightNames {
+    fn default() -> Self {
+        let s = |s: &str| s.to_string();
+        Self {
+            model: s("model"),
+            embed_tokens: s("embed_tokens"),
+            embed_positions: s("embed_positions"),
+            layers: s("layers"),
+            final_norm: s("norm"),
+            lm_head: s("lm_head"),
+            attention: s("self_attn"),
+            q_proj: s("q_proj"),
+            k_proj: s("k_proj"),
+            v_proj: s("v_proj"),
+            o_proj: s("o_proj"),
+            q_norm: s("q_norm"),
+            k_norm: s("k_norm"),
+            mlp: s("mlp"),
+            gate_proj: s("gate_proj"),
+            up_proj: s("up_proj"),
+            down_proj: s("down_proj"),
+            input_norm: s("input_layernorm"),
+            post_attention_norm: s("post_attention_layernorm"),
+        }
+    }
+}
+
+/// The description of a decoder-only architecture.
+#[derive(Debug, Clone, PartialEq, serde::Deserialize)]
+pub struct Architecture {
+    pub vocab_size: usize,
+    pub hidden_size: usize,
+    pub intermediate_size: usize,
+    pub num_layers: usize,
+    pub num_attention_heads: usize,
+    /// Defaults to `num_attention_heads`, use 1 for multi-query attention.
+    #[serde(default)]
+    pub num_key_value_heads: Option<usize>,
+    /// Defaults to `hidden_size / num_attention_heads`.
+    #[serde(default)]
+    pub head_dim: Option<usize>,
+    #[serde(default = "defaults::max_position_embeddings")]
+    pub max_position_embeddings: usize,
+    #[serde(default)]
+    pub attention: AttentionSpec,
+    #[serde(default)]
+    pub positions: Positions,
+    #[serde(default)]
+    pub mlp: MlpSpec,
+    #[serde(default)]
+    pub norm: NormSpec,
+    #[serde(default)]
+    pub block: BlockKind,
+    #[serde(default = "defaults::tie_word_embeddings")]
+    pub tie_word_embeddings: bool,
+    /// A factor applied to the token embeddings, e.g. `sqrt(hidden_size)` for Gemma.
+    #[serde(default)]
+    pub embedding_scale: Option<f64>,
+    /// The logits are soft-capped to `[-softcap, softcap]` with a tanh.
+    #[serde(default)]
+    pub final_logit_softcap: Option<f64>,
+    #[serde(default)]
+    pub weights: WeightNames,
+}
+
+impl Architecture {
+    pub fn num_key_value_heads(&self) -> usize {
+        self.num_key_value_heads.unwrap_or(self.num_attention_heads)
+    }
+
+    pub fn head_dim(&self) -> usize {
+        self.head_dim
+            .unwrap_or(self.hidden_size / self.num_attention_heads.max(1))
+    }
+
+    // The number of values of each head that are rotated.
+    fn rotary_dim(&self) -> usize {
+        match self.positions {
+            Positions::Rope {
+                partial_rotary_factor: Some(f),
+                ..
+            } => (self.head_dim() as f64 * f) as usize,
+            _ => self.head_dim(),
+        }
+    }
+}
+
+impl ModelConfig for Architecture {
+    fn validate(&self) -> ValidationResult {
+        ensure_positive("vocab_size", self.vocab_size)?;
+        ensure_positive("intermediate_size", self.intermediate_size)?;
+        ensure_positive("num_layers", self.num_layers)?;
+        ensure_positive("max_position_embeddings", self.max_position_embeddings)?;
+        let heads = "num_attention_heads";
+        ensure_positive(heads, self.num_attention_heads)?;
+        if self.head_dim.is_none() {
+            ensure_divisible(
+                "hidden_size",
+                self.hidden_size,
+                heads,
+                self.num_attention_heads,
+            )?;
+        }
+        let kv_heads = self.num_key_value_heads();
+        ensure_divisible(
+            heads,
+            self.num_attention_heads,
+            "num_key_value_heads",
+            kv_heads,
+        )?;
+        ensure_positive("head_dim", self.head_dim())?;
+        if let Positions::Rope {
+            partial_rotary_factor,
+            ..
+        } = self.positions
+        {
+            if partial_rotary_factor.is_some_and(|f| f <= 0. || f > 1.) {
+                let msg = "must be in (0, 1]";
+                return Err(ConfigError::new("positions.partial_rotary_factor", msg));
+            }
+            let rotary_dim = self.rotary_dim();
+            if rotary_dim == 0 || rotary_dim % 2 != 0 {
+                let msg = format!("the rotary dimension {rotary_dim} has to be even");
+                return Err(ConfigError::new("positions", msg));
+            }
+        }
+        if let Some(window) = self.attention.sliding_window {
+            ensure_positive("attention.sliding_window", window)?
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone)]
+enum Norm {
+    Rms(RmsNorm),
+    Layer(LayerNorm),
+}
+
+impl Norm {
+    fn new(size: usize, spec: &NormSpec, vb: VarBuilder) -> Result<Self> {
+        match spec.kind {
+            NormKind::Rms => Ok(Self::Rms(RmsNorm::new(size, spec.eps, vb)?)),
+            NormKind::Layer => {
+                let config = candle_nn::LayerNormConfig {
+                    eps: spec.eps,
+                    affine: spec.bias,
+                    remove_mean: true,
+                };
+                Ok(Self::Layer(layer_norm(size, config, vb)?))
+            }
+        }
+    }
+}
+
+impl Module for Norm {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        match self {
+            Self::Rms(norm) => norm.forward(xs),
+            Self::Layer(norm) => norm.forward(xs),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct RotaryEmbedding {
+    sin: Tensor,
+    cos: Tensor,
+    interleaved: bool,
+    partial: bool,
+}
+
+impl RotaryEmbedding {
+    fn new(arch: &Architecture, theta: f64, interleaved: bool, vb: &VarBuilder) -> Result<Self> {
+        let dim = arch.rotary_dim();
+        let max_seq_len = arch.max_position_embeddings;
+        let inv_freq: Vec<_> = (0..dim)
+            .step_by(2)
+            .map(|i| 1f32 / theta.powf(i as f64 / dim as f64) as f32)
+            .collect();
+        let inv_freq_len = inv_freq.len();
+        let inv_freq = Tensor::from_vec(inv_freq, (1, inv_freq_len), vb.device())?;
+        let t = Tensor::arange(0u32, max_seq_len as u32, vb.device())?
+            .to_dtype(DType::F32)?
+            .reshape((max_seq_len, 1))?;
+        let freqs = t.matmul(&inv_freq)?;
+        Ok(Self {
+            sin: freqs.sin()?.to_dtype(vb.dtype())?,
+            cos: freqs.cos()?.to_dtype(vb.dtype())?,
+            interleaved,
+            partial: dim < arch.head_dim(),
+        })
+    }
+
+    fn apply(&self, xs: &Tensor, seqlen_offset: usize) -> Result<Tensor> {
+        let (_b_sz, _h, seq_len, _d) = xs.dims4()?;
+        let cos = self.cos.narrow(0, seqlen_offset, seq_len)?;
+        let sin = self.sin.narrow(0, seqlen_offset, seq_len)?;
+        let xs = xs.contiguous()?;
+        use candle_nn::rotary_emb as re;
+        match (self.interleaved, self.partial) {
+            (false, false) => re::rope(&xs, &cos, &sin),
+            (true, false) => re::rope_i(&xs, &cos, &sin),
+            (false, true) => re::rope_partial(&xs, &cos, &sin),
+            (true, true) => re::rope_i_partial(&xs, &cos, &sin),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Mlp {
+    gate_proj: Option<Linear>,
+    up_proj: Linear,
+    down_proj: Linear,
+    act_fn: Activation,
+}
+
+impl Mlp {
+    fn new(arch: &Architecture, vb: VarBuilder) -> Result<Self> {
+        let (h, i, spec, names) = (
+            arch.hidden_size,
+            arch.intermediate_size,
+            &arch.mlp,
+            &arch.weights,
+        );
+        let gate_proj = match spec.kind {
+            MlpKind::Gated => Some(linear_b(h, i, spec.bias, vb.pp(&names.gate_proj))?),
+            MlpKind::Plain => None,
+        };
+        Ok(Self {
+            gate_proj,
+            up_proj: linear_b(h, i, spec.bias, vb.pp(&names.up_proj))?,
+            down_proj: linear_b(i, h, spec.bias, vb.pp(&names.down_proj))?,
+            act_fn: spec.activation,
+        })
+    }
+}
+
+impl Module for Mlp {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        let xs = match &self.gate_proj {
+            Some(gate_proj) => {
+                let lhs = xs.apply(gate_proj)?.apply(&self.act_fn)?;
+                (lhs * xs.apply(&self.up_proj)?)?
+            }
+            None => xs.apply(&self.up_proj)?.apply(&self.act_fn)?,
+        };
+        xs.apply(&self.down_proj)
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Attention {
+    q_proj: Linear,
+    k_proj: Linear,
+    v_proj: Linear,
+    o_proj: Linear,
+    qk_norm: Option<(RmsNorm, RmsNorm)>,
+    num_heads: usize,
+    num_kv_heads: usize,
+    head_dim: usize,
+    scale: f64,
+    softcap: Option<f64>,
+    rotary_emb: Option<Arc<RotaryEmbedding>>,
+    kv_cache: Option<(Tensor, Tensor)>,
+}
+
+impl Attention {
+    fn new(
+        rotary_emb: Option<Arc<RotaryEmbedding>>,
+        arch: &Architecture,
+        vb: VarBuilder,
+    ) -> Result<Self> {
+        let (spec, names) = (&arch.attention, &arch.weights);
+        let h = arch.hidden_size;
+        let num_heads = arch.num_attention_heads;
+        let num_kv_heads = arch.num_key_value_heads();
+        let head_dim = arch.head_dim();
+        let bias = spec.qkv_bias;
+        let q_proj = linear_b(h, num_heads * head_dim, bias, vb.pp(&names.q_proj))?;
+        let k_proj = linear_b(h, num_kv_heads * head_dim, bias, vb.pp(&names.k_proj))?;
+        let v_proj = linear_b(h, num_kv_heads * head_dim, bias, vb.pp(&names.v_proj))?;
+        let o_proj = linear_b(num_heads * head_dim, h, spec.out_bias, vb.pp(&names.o_proj))?;
+        let qk_norm = if spec.qk_norm {
+            let eps = arch.norm.eps;
+            let q_norm = RmsNorm::new(head_dim, eps, vb.pp(&names.q_norm))?;
+            let k_norm = RmsNorm::new(head_dim, eps, vb.pp(&names.k_norm))?;
+            Some((q_norm, k_norm))
+        } else {
+            None
+        };
+        Ok(Self {
+            q_proj,
+            k_proj,
+            v_proj,
+            o_proj,
+            qk_norm,
+            num_heads,
+            num_kv_heads,
+            head_dim,
+            scale: spec.scale.unwrap_or(1. / (head_dim as f64).sqrt()),
+            softcap: spec.softcap,
+            rotary_emb,
+            kv_cache: None,
+        })
+    }
+
+    fn forward(
+        &mut self,
+        xs: &Tensor,
+        mask: Option<&Tensor>,
+        seqlen_offset: usize,
+    ) -> Result<Tensor> {
+        let (b_sz, q_len, _) = xs.dims3()?;
+        let heads =
+            |xs: Tensor, n: usize| xs.reshape((b_sz, q_len, n, self.head_dim))?.transpose(1, 2);
+        let q = heads(self.q_proj.forward(xs)?, self.num_heads)?;
+        let k = heads(self.k_proj.forward(xs)?, self.num_kv_heads)?;
+        let v = heads(self.v_proj.forward(xs)?, self.num_kv_heads)?;
+        let (q, k) = match &self.qk_norm {
+            Some((q_norm, k_norm)) => (q.apply(q_norm)?, k.apply(k_norm)?),
+            None => (q, k),
+        };
+        let (q, k) = match &self.rotary_emb {
+            Some(rotary_emb) => (
+                rotary_emb.apply(&q, seqlen_offset)?,
+                rotary_emb.apply(&k, seqlen_offset)?,
+            ),
+            None => (q, k),
+        };
+        let (k, v) = match &self.kv_cache {
+            None => (k, v),
+            Some((prev_k, prev_v)) => (
+                Tensor::cat(&[prev_k, &k], 2)?,
+                Tensor::cat(&[prev_v, &v], 2)?,
+            ),
+        };
+        self.kv_cache = Some((k.clone(), v.clone()));
+        let num_kv_groups = self.num_heads / self.num_kv_heads;
+        let k = crate::utils::repeat_kv(k, num_kv_groups)?.contiguous()?;
+        let v = crate::utils::repeat_kv(v, num_kv_groups)?.contiguous()?;
+        let attn_weights = (q.contiguous()?.matmul(&k.t()?)? * self.scale)?;
+        let attn_weights = match self.softcap {
+            Some(softcap) => ((attn_weights / softcap)?.tanh()? * softcap)?,
+            None => attn_weights,
+        };
+        let attn_weights = match mask {
+            None => attn_weights,
+            Some(mask) => attn_weights.broadcast_add(mask)?,
+        };
+        let attn_weights = candle_nn::ops::softmax_last_dim(&attn_weights)?;
+        attn_weights
+            .matmul(&v)?
+            .transpose(1, 2)?
+            .reshape((b_sz, q_len, self.num_heads * self.head_dim))?
+            .apply(&self.o_proj)
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Block {
+    attention: Attention,
+    mlp: Mlp,
+    input_norm: Norm,
+    post_attention_norm: Option<Norm>,
+}
+
+impl Block {
+    fn new(
+        rotary_emb: Option<Arc<RotaryEmbedding>>,
+        arch: &Architecture,
+        vb: VarBuilder,
+    ) -> Result<Self> {
+        let names = &arch.weights;
+        let attention = Attention::new(rotary_emb, arch, vb.pp(&names.attention))?;
+        let mlp = Mlp::new(arch, vb.pp(&names.mlp))?;
+        let input_norm = Norm::new(arch.hidden_size, &arch.norm, vb.pp(&names.input_norm))?;
+        let post_attention_norm = match arch.block {
+            BlockKind::Sequential => {
+                let vb = vb.pp(&names.post_attention_norm);
+                Some(Norm::new(arch.hidden_size, &arch.norm, vb)?)
+            }
+            BlockKind::Parallel => None,
+        };
+        Ok(Self {
+            attention,
+            mlp,
+            input_norm,
+            post_attention_norm,
+        })
+    }
+
+    fn forward(
+        &mut self,
+        xs: &Tensor,
+        mask: Option<&Tensor>,
+        seqlen_offset: usize,
+    ) -> Result<Tensor> {
+        let residual = xs;
+        let hidden = xs.apply(&self.input_norm)?;
+        let attn = self.attention.forward(&hidden, mask, seqlen_offset)?;
+        match &self.post_attention_norm {
+            Some(norm) => {
+                let xs = (attn + residual)?;
+                let mlp = xs.apply(norm)?.apply(&self.mlp)?;
+                xs + mlp
+            }
+            None => (residual + attn)? + hidden.apply(&self.mlp)?,
+        }
+    }
+
+    fn clear_kv_cache(&mut self) {
+        self.attention.kv_cache = None
+    }
+}
+
+/// A decoder-only language model built from an [`Architecture`].
+#[derive(Debug, Clone)]
+pub struct Model {
+    embed_tokens: Embedding,
+    embed_positions: Option<Embedding>,
+    layers: Vec<Block>,
+    norm: Norm,
+    lm_head: Linear,
+    arch: Architecture,
+    device: Device,
+    dtype: DType,
+}
+
+impl Model {
+    pub fn new(arch: &Architecture, vb: VarBuilder) -> Result<Self> {
+        arch.validate()?;
+        let names = &arch.weights;
+        let vb_m = if names.model.is_empty() {
+            vb.clone()
+        } else {
+            vb.pp(&names.model)
+        };
+        let h = arch.hidden_size;
+        let embed_tokens = candle_nn::embedding(arch.vocab_size, h, vb_m.pp(&names.embed_tokens))?;
+        let (embed_positions, rotary_emb) = match arch.positions {
+            Positions::Rope {
+                theta, interleaved, ..
+            } => {
+                let rotary_emb = RotaryEmbedding::new(arch, theta, interleaved, &vb)?;
+                (None, Some(Arc::new(rotary_emb)))
+            }
+            Positions::Learned => {
+                let vb = vb_m.pp(&names.embed_positions);
+                let embed = candle_nn::embedding(arch.max_position_embeddings, h, vb)?;
+                (Some(embed), None)
+            }
+            Positions::None => (None, None),
+        };
+        let vb_l = vb_m.pp(&names.layers);
+        let layers = (0..arch.num_layers)
+            .map(|i| Block::new(rotary_emb.clone(), arch, vb_l.pp(i)))
+            .collect::<Result<Vec<_>>>()?;
+        let norm = Norm::new(h, &arch.norm, vb_m.pp(&names.final_norm))?;
+        let lm_head = if arch.tie_word_embeddings {
+            Linear::from_weights(embed_tokens.embeddings().clone(), None)
+        } else {
+            linear_b(h, arch.vocab_size, false, vb.pp(&names.lm_head))?
+        };
+        Ok(Self {
+            embed_tokens,
+            embed_positions,
+            layers,
+            norm,
+            lm_head,
+            arch: arch.clone(),
+            device: vb.device().clone(),
+            dtype: vb.dtype(),
+        })
+    }
+
+    pub fn architecture(&self) -> &Architecture {
+        &self.arch
+    }
+
+    // The additive mask of shape `(seq_len, seqlen_offset + seq_len)`, `None` when all the
+    // tokens can be attended to.
+    fn mask(&self, seq_len: usize, seqlen_offset: usize) -> Result<Option<Tensor>> {
+        let window = self.arch.attention.sliding_window;
+        let kv_len = seqlen_offset + seq_len;
+        if seq_len == 1 && window.is_none_or(|w| kv_len <= w) {
+            return Ok(None);
+        }
+        let mask: Vec<_> = (0..seq_len)
+            .flat_map(|i| {
+                let pos = seqlen_offset + i;
+                (0..kv_len).map(move |j| {
+                    if j > pos || window.is_some_and(|w| pos >= j + w) {
+                        f32::NEG_INFINITY
+                    } else {
+                        0.
+                    }
+                })
+            })
+            .collect();
+        let mask = Tensor::from_vec(mask, (seq_len, kv_len), &self.device)?;
+        Ok(Some(mask.to_dtype(self.dtype)?))
+    }
+
+    /// The hidden states after the final norm, with shape `(batch, seq_len, hidden_size)`.
+    pub fn forward_hidden(&mut self, input_ids: &Tensor, seqlen_offset: usize) -> Result<Tensor> {
+        let (_b_sz, seq_len) = input_ids.dims2()?;
+        let mut xs = self.embed_tokens.forward(input_ids)?;
+        if let Some(scale) = self.arch.embedding_scale {
+            xs = (xs * scale)?
+        }
+        if let Some(embed_positions) = &self.embed_positions {
+            let positions = Tensor::arange(
+                seqlen_offset as u32,
+                (seqlen_offset + seq_len) as u32,
+                &self.device,
+            )?;
+            xs = xs.broadcast_add(&embed_positions.forward(&positions)?)?
+        }
+        let mask = self.mask(seq_len, seqlen_offset)?;
+        for layer in self.layers.iter_mut() {
+            xs = layer.forward(&xs, mask.as_ref(), seqlen_offset)?
+        }
+        xs.apply(&self.norm)
+    }
+
+    /// The logits for the last token of each sequence, with shape `(batch, vocab_size)`.
+    pub fn forward(&mut self, input_ids: &Tensor, seqlen_offset: usize) -> Result<Tensor> {
+        let xs = self.forward_hidden(input_ids, seqlen_offset)?;
+        let logits = xs
+            .narrow(1, xs.dim(1)? - 1, 1)?
+            .squeeze(1)?
+            .apply(&self.lm_head)?;
+        match self.arch.final_logit_softcap {
+            Some(softcap) => (logits / softcap)?.tanh()? * softcap,
+            None => Ok(logits),
+        }
+    }
+
+    pub fn clear_kv_cache(&mut self) {
+        for layer in self.layers.iter_mut() {
+            layer.clear_kv_cache()
+        }
+    }
+}
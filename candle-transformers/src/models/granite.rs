@@ -183,6 +183,41 @@ impl Cache {
             Ok(mask)
         }
     }
+
+    /// The number of positions in the kv cache.
+    pub fn seq_len(&self) -> usize {
+        match self.kvs.first() {
+            Some(Some((k, _))) => k.dims()[2],
+            _ => 0,
+        }
+    }
+
+    /// Drops the keys and values after the first `len` positions, the generation then continues
+    /// with `index_pos = len`.
+    pub fn truncate(&mut self, len: usize) -> Result<()> {
+        for kv in self.kvs.iter_mut() {
+            if let Some((k, v)) = kv.as_ref() {
+                let seq_len = k.dim(2)?;
+                if len > seq_len {
+                    candle::bail!("cannot truncate the kv cache to {len} above {seq_len}")
+                }
+                let truncated = if len == 0 {
+                    None
+                } else {
+                    Some((k.narrow(2, 0, len)?, v.narrow(2, 0, len)?))
+                };
+                *kv = truncated
+            }
+        }
+        Ok(())
+    }
+
+    /// A copy of the cache to generate a different continuation. The cached keys and values are
+    /// never modified in place so the copies share them, this is also a cheap snapshot that can
+    /// be restored with `*cache = snapshot.fork()`.
+    pub fn fork(&self) -> Self {
+        self.clone()
+    }
 }
 
 #[derive(Debug, Clone)]
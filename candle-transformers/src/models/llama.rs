@@ -5,9 +5,11 @@
 //! Implementation based on Hugging Face's [transformers](https://github.com/huggingface/transformers/blob/main/src/transformers/models/llama/modeling_llama.py)
 
 use super::with_tracing::{linear_no_bias as linear, Linear, RmsNorm};
+use crate::config::QuantizationConfig;
+use crate::quantized_nn::QuantizedLinear;
 use candle::{DType, Device, IndexOp, Result, Tensor, D};
+use candle_nn::rotary_emb::RopeScaling;
 use candle_nn::{embedding, Embedding, Module, VarBuilder};
-use std::{collections::HashMap, f32::consts::PI};
 
 pub const DEFAULT_MAX_SEQ_LEN: usize = 4096;
 
@@ -44,13 +46,14 @@ pub struct LlamaConfig {
     pub num_attention_heads: usize,
     pub num_key_value_heads: Option<usize>,
     pub rms_norm_eps: f64,
-    #[serde(default = "default_rope")]
+    #[serde(default = "crate::config::defaults::rope_theta")]
     pub rope_theta: f32,
     pub bos_token_id: Option<u32>,
     pub eos_token_id: Option<LlamaEosToks>,
     pub rope_scaling: Option<Llama3RopeConfig>,
     pub max_position_embeddings: usize,
     pub tie_word_embeddings: Option<bool>,
+    pub quantization_config: Option<QuantizationConfig>,
 }
 
 impl LlamaConfig {
@@ -59,8 +62,24 @@ impl LlamaConfig {
     }
 }
 
-fn default_rope() -> f32 {
-    10_000.0
+impl crate::config::ModelConfig for LlamaConfig {
+    fn validate(&self) -> crate::config::ValidationResult {
+        use crate::config::{ensure_divisible, ensure_positive, validate_nested};
+        ensure_positive("num_hidden_layers", self.num_hidden_layers)?;
+        ensure_divisible(
+            "hidden_size",
+            self.hidden_size,
+            "num_attention_heads",
+            self.num_attention_heads,
+        )?;
+        ensure_divisible(
+            "num_attention_heads",
+            self.num_attention_heads,
+            "num_key_value_heads",
+            self.num_key_value_heads(),
+        )?;
+        validate_nested("quantization_config", self.quantization_config.as_ref())
+    }
 }
 
 impl LlamaConfig {
@@ -80,6 +99,7 @@ impl LlamaConfig {
             rope_scaling: self.rope_scaling,
             max_position_embeddings: self.max_position_embeddings,
             tie_word_embeddings: self.tie_word_embeddings.unwrap_or(false),
+            quantization_config: self.quantization_config,
         }
     }
 }
@@ -100,6 +120,8 @@ pub struct Config {
     pub rope_scaling: Option<Llama3RopeConfig>,
     pub max_position_embeddings: usize,
     pub tie_word_embeddings: bool,
+    /// The AWQ or GPTQ quantization of the attention and mlp projections, if any.
+    pub quantization_config: Option<QuantizationConfig>,
 }
 
 impl Config {
@@ -119,6 +141,7 @@ impl Config {
             rope_scaling: None,
             max_position_embeddings: DEFAULT_MAX_SEQ_LEN,
             tie_word_embeddings: false,
+            quantization_config: None,
         }
     }
 
@@ -138,103 +161,93 @@ impl Config {
             rope_scaling: None,
             max_position_embeddings: DEFAULT_MAX_SEQ_LEN,
             tie_word_embeddings: false,
+            quantization_config: None,
         }
     }
 }
 
 #[derive(Debug, Clone)]
 pub struct Cache {
-    masks: HashMap<usize, Tensor>,
     pub use_kv_cache: bool,
     kvs: Vec<Option<(Tensor, Tensor)>>,
     cos: Tensor,
     sin: Tensor,
-    device: Device,
-}
-
-fn calculate_default_inv_freq(cfg: &Config) -> Vec<f32> {
-    let head_dim = cfg.hidden_size / cfg.num_attention_heads;
-    (0..head_dim)
-        .step_by(2)
-        .map(|i| 1f32 / cfg.rope_theta.powf(i as f32 / head_dim as f32))
-        .collect()
 }
 
 impl Cache {
     pub fn new(use_kv_cache: bool, dtype: DType, config: &Config, device: &Device) -> Result<Self> {
-        // precompute freqs_cis
-        let theta = match &config.rope_scaling {
+        let scaling = match &config.rope_scaling {
             None
             | Some(Llama3RopeConfig {
                 rope_type: Llama3RopeType::Default,
                 ..
-            }) => calculate_default_inv_freq(config),
-            Some(rope_scaling) => {
-                let low_freq_wavelen = rope_scaling.original_max_position_embeddings as f32
-                    / rope_scaling.low_freq_factor;
-                let high_freq_wavelen = rope_scaling.original_max_position_embeddings as f32
-                    / rope_scaling.high_freq_factor;
-
-                calculate_default_inv_freq(config)
-                    .into_iter()
-                    .map(|freq| {
-                        let wavelen = 2. * PI / freq;
-                        if wavelen < high_freq_wavelen {
-                            freq
-                        } else if wavelen > low_freq_wavelen {
-                            freq / rope_scaling.factor
-                        } else {
-                            let smooth = (rope_scaling.original_max_position_embeddings as f32
-                                / wavelen
-                                - rope_scaling.low_freq_factor)
-                                / (rope_scaling.high_freq_factor - rope_scaling.low_freq_factor);
-                            (1. - smooth) * freq / rope_scaling.factor + smooth * freq
-                        }
-                    })
-                    .collect::<Vec<_>>()
-            }
+            }) => None,
+            Some(rope_scaling) => Some(RopeScaling::Llama3 {
+                factor: rope_scaling.factor as f64,
+                low_freq_factor: rope_scaling.low_freq_factor as f64,
+                high_freq_factor: rope_scaling.high_freq_factor as f64,
+                original_max_position_embeddings: rope_scaling.original_max_position_embeddings,
+            }),
         };
-
-        let theta = Tensor::new(theta, device)?;
-
-        let idx_theta = Tensor::arange(0, config.max_position_embeddings as u32, device)?
-            .to_dtype(DType::F32)?
-            .reshape((config.max_position_embeddings, 1))?
-            .matmul(&theta.reshape((1, theta.elem_count()))?)?;
-        // This is different from the paper, see:
-        // https://github.com/huggingface/transformers/blob/6112b1c6442aaf7affd2b0676a1cd4eee30c45cf/src/transformers/models/llama/modeling_llama.py#L112
-        let cos = idx_theta.cos()?.to_dtype(dtype)?;
-        let sin = idx_theta.sin()?.to_dtype(dtype)?;
+        // The tables are shared with the other models using the same parameters on the device.
+        let (cos, sin) = candle_nn::device_cache::rope_tables(
+            config.hidden_size / config.num_attention_heads,
+            config.rope_theta as f64,
+            config.max_position_embeddings,
+            scaling,
+            dtype,
+            device,
+        )?;
         Ok(Self {
-            masks: HashMap::new(),
             use_kv_cache,
             kvs: vec![None; config.num_hidden_layers],
-            device: device.clone(),
             cos,
             sin,
         })
     }
 
-    fn mask(&mut self, t: usize) -> Result<Tensor> {
-        if let Some(mask) = self.masks.get(&t) {
-            Ok(mask.clone())
-        } else {
-            let mask: Vec<_> = (0..t)
-                .flat_map(|i| (0..t).map(move |j| u8::from(j > i)))
-                .collect();
-            let mask = Tensor::from_slice(&mask, (t, t), &self.device)?;
-            self.masks.insert(t, mask.clone());
-            Ok(mask)
+    /// The number of positions in the kv cache.
+    pub fn seq_len(&self) -> usize {
+        match self.kvs.first() {
+            Some(Some((k, _))) => k.dims()[2],
+            _ => 0,
         }
     }
+
+    /// Drops the keys and values after the first `len` positions, the generation then continues
+    /// with `index_pos = len`.
+    pub fn truncate(&mut self, len: usize) -> Result<()> {
+        for kv in self.kvs.iter_mut() {
+            if let Some((k, v)) = kv.as_ref() {
+                let seq_len = k.dim(2)?;
+                if len > seq_len {
+                    candle::bail!("cannot truncate the kv cache to {len} above {seq_len}")
+                }
+                let truncated = if len == 0 {
+                    None
+                } else {
+                    Some((k.narrow(2, 0, len)?, v.narrow(2, 0, len)?))
+                };
+                *kv = truncated
+            }
+        }
+        Ok(())
+    }
+
+    /// A copy of the cache to generate a different continuation. The cached keys and values are
+    /// never modified in place so the copies share them, this is also a cheap snapshot that can
+    /// be restored with `*cache = snapshot.fork()`.
+    pub fn fork(&self) -> Self {
+        self.clone()
+    }
 }
 
 #[derive(Debug, Clone)]
 struct CausalSelfAttention {
-    q_proj: Linear,
-    k_proj: Linear,
-    v_proj: Linear,
-    o_proj: Linear,
+    q_proj: QuantizedLinear,
+    k_proj: QuantizedLinear,
+    v_proj: QuantizedLinear,
+    o_proj: QuantizedLinear,
     num_attention_heads: usize,
     num_key_value_heads: usize,
     head_dim: usize,
@@ -344,8 +357,15 @@ impl CausalSelfAttention {
             let att = if seq_len == 1 {
                 att
             } else {
-                let mask = cache.mask(seq_len)?.broadcast_as(att.shape())?;
-                masked_fill(&att, &mask, f32::NEG_INFINITY)?
+                let offset = att.dim(D::Minus1)? - seq_len;
+                let mask = candle_nn::device_cache::causal_bias(
+                    seq_len,
+                    offset,
+                    None,
+                    DType::F32,
+                    att.device(),
+                )?;
+                att.broadcast_add(&mask)?
             };
 
             let att = candle_nn::ops::softmax_last_dim(&att)?;
@@ -367,10 +387,11 @@ impl CausalSelfAttention {
         let size_in = cfg.hidden_size;
         let size_q = (cfg.hidden_size / cfg.num_attention_heads) * cfg.num_attention_heads;
         let size_kv = (cfg.hidden_size / cfg.num_attention_heads) * cfg.num_key_value_heads;
-        let q_proj = linear(size_in, size_q, vb.pp("q_proj"))?;
-        let k_proj = linear(size_in, size_kv, vb.pp("k_proj"))?;
-        let v_proj = linear(size_in, size_kv, vb.pp("v_proj"))?;
-        let o_proj = linear(size_q, size_in, vb.pp("o_proj"))?;
+        let q_cfg = cfg.quantization_config.as_ref();
+        let q_proj = QuantizedLinear::new(size_in, size_q, false, q_cfg, vb.pp("q_proj"))?;
+        let k_proj = QuantizedLinear::new(size_in, size_kv, false, q_cfg, vb.pp("k_proj"))?;
+        let v_proj = QuantizedLinear::new(size_in, size_kv, false, q_cfg, vb.pp("v_proj"))?;
+        let o_proj = QuantizedLinear::new(size_q, size_in, false, q_cfg, vb.pp("o_proj"))?;
         Ok(Self {
             q_proj,
             k_proj,
@@ -387,18 +408,11 @@ impl CausalSelfAttention {
     }
 }
 
-fn masked_fill(on_false: &Tensor, mask: &Tensor, on_true: f32) -> Result<Tensor> {
-    let shape = mask.shape();
-    let on_true = Tensor::new(on_true, on_false.device())?.broadcast_as(shape.dims())?;
-    let m = mask.where_cond(&on_true, on_false)?;
-    Ok(m)
-}
-
 #[derive(Debug, Clone)]
 struct Mlp {
-    c_fc1: Linear,
-    c_fc2: Linear,
-    c_proj: Linear,
+    c_fc1: QuantizedLinear,
+    c_fc2: QuantizedLinear,
+    c_proj: QuantizedLinear,
     span: tracing::Span,
 }
 
@@ -413,9 +427,10 @@ impl Mlp {
         let span = tracing::span!(tracing::Level::TRACE, "mlp");
         let h_size = cfg.hidden_size;
         let i_size = cfg.intermediate_size;
-        let c_fc1 = linear(h_size, i_size, vb.pp("gate_proj"))?;
-        let c_fc2 = linear(h_size, i_size, vb.pp("up_proj"))?;
-        let c_proj = linear(i_size, h_size, vb.pp("down_proj"))?;
+        let q_cfg = cfg.quantization_config.as_ref();
+        let c_fc1 = QuantizedLinear::new(h_size, i_size, false, q_cfg, vb.pp("gate_proj"))?;
+        let c_fc2 = QuantizedLinear::new(h_size, i_size, false, q_cfg, vb.pp("up_proj"))?;
+        let c_proj = QuantizedLinear::new(i_size, h_size, false, q_cfg, vb.pp("down_proj"))?;
         Ok(Self {
             c_fc1,
             c_fc2,
@@ -522,9 +537,9 @@ impl Llama {
             linear(cfg.hidden_size, cfg.vocab_size, vb.pp("lm_head"))?
         };
         let ln_f = RmsNorm::new(cfg.hidden_size, cfg.rms_norm_eps, vb.pp("model.norm"))?;
-        let blocks: Vec<_> = (0..cfg.num_hidden_layers)
-            .map(|i| Block::load(vb.pp(format!("model.layers.{i}")), cfg).unwrap())
-            .collect();
+        let blocks = (0..cfg.num_hidden_layers)
+            .map(|i| Block::load(vb.pp(format!("model.layers.{i}")), cfg))
+            .collect::<Result<Vec<_>>>()?;
 
         Ok(Self {
             wte,
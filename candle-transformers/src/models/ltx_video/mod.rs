@@ -0,0 +1,125 @@
+//! LTX-Video
+//!
+//! LTX-Video is a 2B diffusion transformer generating videos from text descriptions. The
+//! transformer denoises latents with shape `(batch, 128, frames, height, width)`, compressed 8
+//! times over the frames and 32 times over the height and width by a causal video autoencoder.
+//! The text embeddings come from the encoder of T5-XXL, see [`crate::models::t5`].
+//!
+//! The autoencoder can decode the latents by chunks of frames and spatial tiles so that long and
+//! high resolution videos can be decoded with a bounded amount of memory, see
+//! [`vae::AutoEncoder::decode_tiled`].
+//!
+//! - 🤗 [Hugging Face Model](https://huggingface.co/Lightricks/LTX-Video)
+//! - 💻 [GitHub Repository](https://github.com/Lightricks/LTX-Video)
+//!
+//! ```ignore
+//! let timesteps = ltx_video::get_schedule(50, f * h * w);
+//! let latents = ltx_video::pack_latents(&noise)?;
+//! let rope_scale = ltx_video::rope_scale(vae.config(), 25.);
+//! let latents = ltx_video::denoise(&transformer, &latents, &context, Some(&mask), &timesteps,
+//!     3.0, (f, h, w), rope_scale)?;
+//! let latents = vae.denormalize_latents(&ltx_video::unpack_latents(&latents, (f, h, w))?)?;
+//! let video = vae.decode_tiled(&latents, &Default::default())?;
+//! ```
+use candle::{Result, Tensor};
+
+pub mod transformer;
+pub mod vae;
+
+/// Flattens latents of shape `(batch, channels, frames, height, width)` to the sequence of
+/// shape `(batch, frames * height * width, channels)` processed by the transformer.
+pub fn pack_latents(xs: &Tensor) -> Result<Tensor> {
+    let (b, c, f, h, w) = xs.dims5()?;
+    xs.permute((0, 2, 3, 4, 1))?.reshape((b, f * h * w, c))
+}
+
+/// The inverse of [`pack_latents`].
+pub fn unpack_latents(xs: &Tensor, (f, h, w): (usize, usize, usize)) -> Result<Tensor> {
+    let (b, _seq_len, c) = xs.dims3()?;
+    xs.reshape((b, f, h, w, c))?.permute((0, 4, 1, 2, 3))
+}
+
+/// The number of video frames and pixels per latent frame and pixel used to scale the rotary
+/// embeddings of the transformer.
+pub fn rope_scale(vae: &vae::Config, frame_rate: f64) -> (f64, f64, f64) {
+    let spatial = vae.spatial_compression_ratio() as f64;
+    let temporal = vae.temporal_compression_ratio() as f64;
+    (temporal / frame_rate, spatial, spatial)
+}
+
+// https://huggingface.co/Lightricks/LTX-Video/blob/main/scheduler/scheduler_config.json
+const BASE_SEQ_LEN: f64 = 1024.;
+const MAX_SEQ_LEN: f64 = 4096.;
+const BASE_SHIFT: f64 = 0.95;
+const MAX_SHIFT: f64 = 2.05;
+
+/// The flow matching noise levels from `1` to `0`, shifted towards the high noise levels for
+/// longer sequences.
+pub fn get_schedule(num_steps: usize, seq_len: usize) -> Vec<f64> {
+    let m = (MAX_SHIFT - BASE_SHIFT) / (MAX_SEQ_LEN - BASE_SEQ_LEN);
+    let mu = BASE_SHIFT + m * (seq_len as f64 - BASE_SEQ_LEN);
+    let e = mu.exp();
+    (0..=num_steps)
+        .rev()
+        .map(|v| {
+            let t = v as f64 / num_steps as f64;
+            if t <= 0. {
+                0.
+            } else {
+                e / (e + (1. / t - 1.))
+            }
+        })
+        .collect()
+}
+
+/// Runs the Euler sampler over `timesteps` starting from the packed noise `latents`.
+///
+/// When `guidance_scale` is above one, classifier free guidance is used and `context` and
+/// `context_mask` have to contain the unconditional embeddings followed by the conditional ones
+/// along the batch dimension, i.e. twice the batch size of `latents`.
+#[allow(clippy::too_many_arguments)]
+pub fn denoise(
+    model: &transformer::Transformer,
+    latents: &Tensor,
+    context: &Tensor,
+    context_mask: Option<&Tensor>,
+    timesteps: &[f64],
+    guidance_scale: f64,
+    latent_dims: (usize, usize, usize),
+    rope_scale: (f64, f64, f64),
+) -> Result<Tensor> {
+    let b_sz = latents.dim(0)?;
+    let dev = latents.device();
+    let use_cfg = guidance_scale > 1.;
+    let mut latents = latents.clone();
+    for window in timesteps.windows(2) {
+        let (t_curr, t_prev) = match window {
+            [a, b] => (*a, *b),
+            _ => continue,
+        };
+        let model_in = if use_cfg {
+            Tensor::cat(&[&latents, &latents], 0)?
+        } else {
+            latents.clone()
+        };
+        let t_vec = Tensor::full((t_curr * 1000.) as f32, model_in.dim(0)?, dev)?;
+        let pred = model.forward(
+            &model_in,
+            context,
+            context_mask,
+            &t_vec,
+            latent_dims,
+            rope_scale,
+        )?;
+        let pred = if use_cfg {
+            let uncond = pred.narrow(0, 0, b_sz)?;
+            let cond = pred.narrow(0, b_sz, b_sz)?;
+            (&uncond + ((cond - &uncond)? * guidance_scale)?)?
+        } else {
+            pred
+        };
+        let pred = pred.to_dtype(latents.dtype())?;
+        latents = (latents + (pred * (t_prev - t_curr))?)?
+    }
+    Ok(latents)
+}
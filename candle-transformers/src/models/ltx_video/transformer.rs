@@ -0,0 +1,406 @@
+//! The LTX-Video transformer, a DiT with self-attention over the video latents using 3d rotary
+//! embeddings and cross-attention to the T5 text embeddings.
+use crate::models::dit::modulate;
+use candle::{DType, Device, Module, Result, Tensor, D};
+use candle_nn::attention::{scaled_dot_product_attention, AttentionConfig};
+use candle_nn::{LayerNorm, Linear, RmsNorm, VarBuilder};
+
+// https://huggingface.co/Lightricks/LTX-Video/blob/main/transformer/config.json
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub in_channels: usize,
+    pub out_channels: usize,
+    pub num_attention_heads: usize,
+    pub attention_head_dim: usize,
+    pub cross_attention_dim: usize,
+    pub num_layers: usize,
+    pub caption_channels: usize,
+    pub norm_eps: f64,
+    pub base_num_frames: usize,
+    pub base_height: usize,
+    pub base_width: usize,
+    pub theta: f64,
+}
+
+impl Config {
+    pub fn ltx_video() -> Self {
+        Self {
+            in_channels: 128,
+            out_channels: 128,
+            num_attention_heads: 32,
+            attention_head_dim: 64,
+            cross_attention_dim: 2048,
+            num_layers: 28,
+            caption_channels: 4096,
+            norm_eps: 1e-6,
+            base_num_frames: 20,
+            base_height: 2048,
+            base_width: 2048,
+            theta: 10000.,
+        }
+    }
+
+    pub fn inner_dim(&self) -> usize {
+        self.num_attention_heads * self.attention_head_dim
+    }
+}
+
+fn rms_norm_no_weight(dim: usize, eps: f64, vb: &VarBuilder) -> Result<RmsNorm> {
+    let ws = Tensor::ones(dim, vb.dtype(), vb.device())?;
+    Ok(RmsNorm::new(ws, eps))
+}
+
+/// The rotary embeddings for the latents of a video, the position of each latent pixel along
+/// the frames, height and width is rescaled to the size of the generated video.
+#[derive(Debug, Clone)]
+pub struct RotaryEmbedding {
+    dim: usize,
+    base_num_frames: usize,
+    base_height: usize,
+    base_width: usize,
+    theta: f64,
+}
+
+impl RotaryEmbedding {
+    pub fn new(cfg: &Config) -> Self {
+        Self {
+            dim: cfg.inner_dim(),
+            base_num_frames: cfg.base_num_frames,
+            base_height: cfg.base_height,
+            base_width: cfg.base_width,
+            theta: cfg.theta,
+        }
+    }
+
+    /// Returns the cosine and sine with shape `(seq_len, dim)` for latents of size `(num_frames,
+    /// height, width)` flattened in this order. `scale` is the number of video frames and pixels
+    /// per latent frame and pixel, e.g. `(8 / frame_rate, 32, 32)`.
+    pub fn forward(
+        &self,
+        num_frames: usize,
+        height: usize,
+        width: usize,
+        scale: (f64, f64, f64),
+        device: &Device,
+    ) -> Result<(Tensor, Tensor)> {
+        let n = self.dim / 6;
+        let log_theta = self.theta.ln();
+        let freqs = (0..n)
+            .map(|i| {
+                let t = if n > 1 { i as f64 / (n - 1) as f64 } else { 0. };
+                (t * log_theta).exp() * std::f64::consts::PI / 2.
+            })
+            .collect::<Vec<_>>();
+        let axes = [
+            (num_frames, scale.0 / self.base_num_frames as f64),
+            (height, scale.1 / self.base_height as f64),
+            (width, scale.2 / self.base_width as f64),
+        ];
+        let pad = self.dim % 6;
+        let seq_len = num_frames * height * width;
+        let mut cos = Vec::with_capacity(seq_len * self.dim);
+        let mut sin = Vec::with_capacity(seq_len * self.dim);
+        for idx in 0..seq_len {
+            let pos = [idx / (height * width), (idx / width) % height, idx % width];
+            cos.extend(std::iter::repeat_n(1f32, pad));
+            sin.extend(std::iter::repeat_n(0f32, pad));
+            for &freq in freqs.iter() {
+                for (axis, &(_, s)) in axes.iter().enumerate() {
+                    let angle = freq * (pos[axis] as f64 * s * 2. - 1.);
+                    let (s, c) = angle.sin_cos();
+                    cos.extend([c as f32, c as f32]);
+                    sin.extend([s as f32, s as f32]);
+                }
+            }
+        }
+        let cos = Tensor::from_vec(cos, (seq_len, self.dim), device)?;
+        let sin = Tensor::from_vec(sin, (seq_len, self.dim), device)?;
+        Ok((cos, sin))
+    }
+}
+
+/// Applies the interleaved rotary embeddings to `xs` of shape `(batch, seq_len, dim)`.
+pub fn apply_rotary_emb(xs: &Tensor, cos: &Tensor, sin: &Tensor) -> Result<Tensor> {
+    let (b, l, d) = xs.dims3()?;
+    let dtype = xs.dtype();
+    let xs = xs.to_dtype(DType::F32)?;
+    let pairs = xs.reshape((b, l, d / 2, 2))?;
+    let real = pairs.narrow(D::Minus1, 0, 1)?;
+    let imag = pairs.narrow(D::Minus1, 1, 1)?;
+    let rotated = Tensor::cat(&[imag.neg()?, real], D::Minus1)?.reshape((b, l, d))?;
+    let ys = (xs.broadcast_mul(cos)? + rotated.broadcast_mul(sin)?)?;
+    ys.to_dtype(dtype)
+}
+
+#[derive(Debug, Clone)]
+struct Attention {
+    to_q: Linear,
+    to_k: Linear,
+    to_v: Linear,
+    norm_q: RmsNorm,
+    norm_k: RmsNorm,
+    to_out: Linear,
+    num_heads: usize,
+}
+
+impl Attention {
+    fn new(query_dim: usize, context_dim: usize, cfg: &Config, vb: VarBuilder) -> Result<Self> {
+        let inner_dim = cfg.inner_dim();
+        let to_q = candle_nn::linear(query_dim, inner_dim, vb.pp("to_q"))?;
+        let to_k = candle_nn::linear(context_dim, inner_dim, vb.pp("to_k"))?;
+        let to_v = candle_nn::linear(context_dim, inner_dim, vb.pp("to_v"))?;
+        let norm_q = candle_nn::rms_norm(inner_dim, 1e-5, vb.pp("norm_q"))?;
+        let norm_k = candle_nn::rms_norm(inner_dim, 1e-5, vb.pp("norm_k"))?;
+        let to_out = candle_nn::linear(inner_dim, query_dim, vb.pp("to_out").pp(0))?;
+        Ok(Self {
+            to_q,
+            to_k,
+            to_v,
+            norm_q,
+            norm_k,
+            to_out,
+            num_heads: cfg.num_attention_heads,
+        })
+    }
+
+    fn forward(
+        &self,
+        xs: &Tensor,
+        context: Option<&Tensor>,
+        rotary_emb: Option<&(Tensor, Tensor)>,
+        mask: Option<&Tensor>,
+    ) -> Result<Tensor> {
+        let context = context.unwrap_or(xs);
+        let q = xs.apply(&self.to_q)?.apply(&self.norm_q)?;
+        let k = context.apply(&self.to_k)?.apply(&self.norm_k)?;
+        let v = context.apply(&self.to_v)?;
+        let (q, k) = match rotary_emb {
+            None => (q, k),
+            Some((cos, sin)) => (
+                apply_rotary_emb(&q, cos, sin)?,
+                apply_rotary_emb(&k, cos, sin)?,
+            ),
+        };
+        let (b, l, d) = q.dims3()?;
+        let split = |xs: Tensor| -> Result<Tensor> {
+            let l = xs.dim(1)?;
+            xs.reshape((b, l, self.num_heads, ()))?.transpose(1, 2)
+        };
+        let attn = scaled_dot_product_attention(
+            &split(q)?,
+            &split(k)?,
+            &split(v)?,
+            mask,
+            &AttentionConfig::default(),
+        )?;
+        attn.transpose(1, 2)?
+            .reshape((b, l, d))?
+            .apply(&self.to_out)
+    }
+}
+
+#[derive(Debug, Clone)]
+struct FeedForward {
+    proj: Linear,
+    out: Linear,
+}
+
+impl FeedForward {
+    fn new(dim: usize, vb: VarBuilder) -> Result<Self> {
+        let proj = candle_nn::linear(dim, 4 * dim, vb.pp("net.0.proj"))?;
+        let out = candle_nn::linear(4 * dim, dim, vb.pp("net.2"))?;
+        Ok(Self { proj, out })
+    }
+}
+
+impl Module for FeedForward {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        xs.apply(&self.proj)?.gelu()?.apply(&self.out)
+    }
+}
+
+#[derive(Debug, Clone)]
+struct TransformerBlock {
+    norm1: RmsNorm,
+    attn1: Attention,
+    norm2: RmsNorm,
+    attn2: Attention,
+    ff: FeedForward,
+    scale_shift_table: Tensor,
+}
+
+impl TransformerBlock {
+    fn new(cfg: &Config, vb: VarBuilder) -> Result<Self> {
+        let dim = cfg.inner_dim();
+        let norm1 = rms_norm_no_weight(dim, cfg.norm_eps, &vb)?;
+        let attn1 = Attention::new(dim, dim, cfg, vb.pp("attn1"))?;
+        let norm2 = rms_norm_no_weight(dim, cfg.norm_eps, &vb)?;
+        let attn2 = Attention::new(dim, cfg.cross_attention_dim, cfg, vb.pp("attn2"))?;
+        let ff = FeedForward::new(dim, vb.pp("ff"))?;
+        let scale_shift_table = vb.get((6, dim), "scale_shift_table")?;
+        Ok(Self {
+            norm1,
+            attn1,
+            norm2,
+            attn2,
+            ff,
+            scale_shift_table,
+        })
+    }
+
+    fn forward(
+        &self,
+        xs: &Tensor,
+        context: &Tensor,
+        temb: &Tensor,
+        rotary_emb: &(Tensor, Tensor),
+        context_mask: Option<&Tensor>,
+    ) -> Result<Tensor> {
+        // temb has shape (batch, 1, 6 * dim).
+        let (b, l, d) = temb.dims3()?;
+        let ada = temb
+            .reshape((b, l, 6, d / 6))?
+            .broadcast_add(&self.scale_shift_table)?;
+        let ada = (0..6)
+            .map(|i| ada.get_on_dim(2, i))
+            .collect::<Result<Vec<_>>>()?;
+        let (shift_msa, scale_msa, gate_msa) = (&ada[0], &ada[1], &ada[2]);
+        let (shift_mlp, scale_mlp, gate_mlp) = (&ada[3], &ada[4], &ada[5]);
+
+        let ys = modulate(&xs.apply(&self.norm1)?, shift_msa, scale_msa)?;
+        let ys = self.attn1.forward(&ys, None, Some(rotary_emb), None)?;
+        let xs = (xs + ys.broadcast_mul(gate_msa)?)?;
+        let ys = self.attn2.forward(&xs, Some(context), None, context_mask)?;
+        let xs = (xs + ys)?;
+        let ys = modulate(&xs.apply(&self.norm2)?, shift_mlp, scale_mlp)?;
+        let ys = self.ff.forward(&ys)?;
+        xs + ys.broadcast_mul(gate_mlp)?
+    }
+}
+
+/// The sinusoidal timestep embeddings followed by a two layers MLP.
+#[derive(Debug, Clone)]
+struct TimestepEmbedder {
+    linear_1: Linear,
+    linear_2: Linear,
+}
+
+impl TimestepEmbedder {
+    const FREQUENCY_DIM: usize = 256;
+
+    fn new(dim: usize, vb: VarBuilder) -> Result<Self> {
+        let linear_1 = candle_nn::linear(Self::FREQUENCY_DIM, dim, vb.pp("linear_1"))?;
+        let linear_2 = candle_nn::linear(dim, dim, vb.pp("linear_2"))?;
+        Ok(Self { linear_1, linear_2 })
+    }
+
+    /// `t` has shape `(batch,)` and contains timesteps in `[0, 1000]`.
+    fn forward(&self, t: &Tensor, dtype: DType) -> Result<Tensor> {
+        let half = Self::FREQUENCY_DIM / 2;
+        let dev = t.device();
+        let freqs = Tensor::arange(0u32, half as u32, dev)?.to_dtype(DType::F32)?;
+        let freqs = (freqs * (-(10000f64.ln()) / half as f64))?.exp()?;
+        let args = t
+            .to_dtype(DType::F32)?
+            .unsqueeze(1)?
+            .broadcast_mul(&freqs.unsqueeze(0)?)?;
+        let emb = Tensor::cat(&[args.cos()?, args.sin()?], D::Minus1)?.to_dtype(dtype)?;
+        emb.apply(&self.linear_1)?.silu()?.apply(&self.linear_2)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Transformer {
+    proj_in: Linear,
+    scale_shift_table: Tensor,
+    timestep_embedder: TimestepEmbedder,
+    time_linear: Linear,
+    caption_linear_1: Linear,
+    caption_linear_2: Linear,
+    rope: RotaryEmbedding,
+    blocks: Vec<TransformerBlock>,
+    norm_out: LayerNorm,
+    proj_out: Linear,
+}
+
+impl Transformer {
+    pub fn new(cfg: &Config, vb: VarBuilder) -> Result<Self> {
+        let dim = cfg.inner_dim();
+        let proj_in = candle_nn::linear(cfg.in_channels, dim, vb.pp("proj_in"))?;
+        let scale_shift_table = vb.get((2, dim), "scale_shift_table")?;
+        let vb_t = vb.pp("time_embed");
+        let timestep_embedder = TimestepEmbedder::new(dim, vb_t.pp("emb.timestep_embedder"))?;
+        let time_linear = candle_nn::linear(dim, 6 * dim, vb_t.pp("linear"))?;
+        let vb_c = vb.pp("caption_projection");
+        let caption_linear_1 = candle_nn::linear(cfg.caption_channels, dim, vb_c.pp("linear_1"))?;
+        let caption_linear_2 = candle_nn::linear(dim, dim, vb_c.pp("linear_2"))?;
+        let vb_b = vb.pp("transformer_blocks");
+        let blocks = (0..cfg.num_layers)
+            .map(|i| TransformerBlock::new(cfg, vb_b.pp(i)))
+            .collect::<Result<Vec<_>>>()?;
+        let ws = Tensor::ones(dim, vb.dtype(), vb.device())?;
+        let norm_out = LayerNorm::new_no_bias(ws, 1e-6);
+        let proj_out = candle_nn::linear(dim, cfg.out_channels, vb.pp("proj_out"))?;
+        Ok(Self {
+            proj_in,
+            scale_shift_table,
+            timestep_embedder,
+            time_linear,
+            caption_linear_1,
+            caption_linear_2,
+            rope: RotaryEmbedding::new(cfg),
+            blocks,
+            norm_out,
+            proj_out,
+        })
+    }
+
+    /// Predicts the velocity for the packed latents `xs` of shape `(batch, num_frames * height *
+    /// width, in_channels)`, see [`super::pack_latents`].
+    ///
+    /// `context` contains the text embeddings with shape `(batch, text_len, caption_channels)`
+    /// and `context_mask` is the optional text attention mask of shape `(batch, text_len)` with
+    /// ones for the tokens to attend to. `timestep` has shape `(batch,)` with values in `[0,
+    /// 1000]`, `rope_scale` is described in [`RotaryEmbedding::forward`].
+    #[allow(clippy::too_many_arguments)]
+    pub fn forward(
+        &self,
+        xs: &Tensor,
+        context: &Tensor,
+        context_mask: Option<&Tensor>,
+        timestep: &Tensor,
+        (num_frames, height, width): (usize, usize, usize),
+        rope_scale: (f64, f64, f64),
+    ) -> Result<Tensor> {
+        let dtype = xs.dtype();
+        let rotary_emb = self
+            .rope
+            .forward(num_frames, height, width, rope_scale, xs.device())?;
+        let context_mask = match context_mask {
+            None => None,
+            Some(mask) => Some(mask.eq(0.)?.unsqueeze(1)?.unsqueeze(1)?),
+        };
+        let xs = xs.apply(&self.proj_in)?;
+        let embedded_timestep = self.timestep_embedder.forward(timestep, dtype)?;
+        let temb = embedded_timestep
+            .silu()?
+            .apply(&self.time_linear)?
+            .unsqueeze(1)?;
+        let context = context
+            .apply(&self.caption_linear_1)?
+            .gelu()?
+            .apply(&self.caption_linear_2)?;
+        let mut xs = xs;
+        for block in self.blocks.iter() {
+            xs = block.forward(&xs, &context, &temb, &rotary_emb, context_mask.as_ref())?
+        }
+        // (batch, 1, 2, dim)
+        let ada = embedded_timestep
+            .unsqueeze(1)?
+            .unsqueeze(2)?
+            .broadcast_add(&self.scale_shift_table)?;
+        let shift = ada.get_on_dim(2, 0)?;
+        let scale = ada.get_on_dim(2, 1)?;
+        modulate(&xs.apply(&self.norm_out)?, &shift, &scale)?.apply(&self.proj_out)
+    }
+}
@@ -0,0 +1,505 @@
+//! The causal video autoencoder of LTX-Video.
+//!
+//! The decoder maps latents with shape `(batch, 128, frames, height, width)` to videos with
+//! shape `(batch, 3, 8 * (frames - 1) + 1, 32 * height, 32 * width)`: each upsampling block
+//! doubles the number of frames and drops the first generated one, so that the first latent
+//! frame decodes to a single frame. The normalizations only operate over the channels, the
+//! decoder runs on any tile of the latents and [`AutoEncoder::decode_tiled`] uses this to bound
+//! the memory used for long or high resolution videos.
+use candle::{DType, Module, Result, Tensor};
+use candle_nn::{LayerNorm, VarBuilder};
+
+// https://huggingface.co/Lightricks/LTX-Video/blob/main/vae/config.json
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub latent_channels: usize,
+    pub out_channels: usize,
+    /// The output channels of the decoder blocks, from the highest to the lowest resolution.
+    pub block_out_channels: Vec<usize>,
+    /// The number of resnets in each block, from the highest to the lowest resolution, the last
+    /// value is for the middle block.
+    pub layers_per_block: Vec<usize>,
+    /// Whether each block samples by a factor two over the frames, height and width.
+    pub spatio_temporal_scaling: Vec<bool>,
+    pub patch_size: usize,
+    pub patch_size_t: usize,
+    pub resnet_norm_eps: f64,
+    pub decoder_causal: bool,
+    pub scaling_factor: f64,
+}
+
+impl Config {
+    pub fn ltx_video() -> Self {
+        Self {
+            latent_channels: 128,
+            out_channels: 3,
+            block_out_channels: vec![128, 256, 512, 512],
+            layers_per_block: vec![4, 3, 3, 3, 4],
+            spatio_temporal_scaling: vec![true, true, true, false],
+            patch_size: 4,
+            patch_size_t: 1,
+            resnet_norm_eps: 1e-6,
+            decoder_causal: false,
+            scaling_factor: 1.0,
+        }
+    }
+
+    pub fn spatial_compression_ratio(&self) -> usize {
+        let n = self.spatio_temporal_scaling.iter().filter(|&&s| s).count();
+        self.patch_size << n
+    }
+
+    pub fn temporal_compression_ratio(&self) -> usize {
+        let n = self.spatio_temporal_scaling.iter().filter(|&&s| s).count();
+        self.patch_size_t << n
+    }
+}
+
+/// Normalizes `xs` of shape `(batch, channels, frames, height, width)` over the channels, this
+/// is the RMS norm without weights used by the LTX-Video autoencoder.
+fn pixel_norm(xs: &Tensor, eps: f64) -> Result<Tensor> {
+    let dtype = xs.dtype();
+    let xs = xs.to_dtype(DType::F32)?;
+    let norm = (xs.sqr()?.mean_keepdim(1)? + eps)?.sqrt()?;
+    xs.broadcast_div(&norm)?.to_dtype(dtype)
+}
+
+/// A 3d convolution with replicate padding over the frames and zero padding over the height and
+/// width. The causal version only pads at the beginning so that each output frame only depends
+/// on the current and previous input frames.
+#[derive(Debug, Clone)]
+pub struct CausalConv3d {
+    // The weights are stored with shape (out, kernel_size * in, kernel_size, kernel_size) so that
+    // the convolution runs as a 2d convolution over the stacked input frames.
+    weight: Tensor,
+    bias: Tensor,
+    kernel_size: usize,
+    is_causal: bool,
+}
+
+impl CausalConv3d {
+    pub fn new(
+        in_channels: usize,
+        out_channels: usize,
+        kernel_size: usize,
+        is_causal: bool,
+        vb: VarBuilder,
+    ) -> Result<Self> {
+        let vb = vb.pp("conv");
+        let k = kernel_size;
+        let weight = vb.get((out_channels, in_channels, k, k, k), "weight")?;
+        let weight =
+            weight
+                .permute((0, 2, 1, 3, 4))?
+                .reshape((out_channels, k * in_channels, k, k))?;
+        let bias = vb.get(out_channels, "bias")?;
+        Ok(Self {
+            weight,
+            bias,
+            kernel_size,
+            is_causal,
+        })
+    }
+}
+
+impl Module for CausalConv3d {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        let k = self.kernel_size;
+        let xs = if self.is_causal {
+            xs.pad_with_same(2, k - 1, 0)?
+        } else {
+            xs.pad_with_same(2, (k - 1) / 2, (k - 1) / 2)?
+        };
+        let (b, c, f, h, w) = xs.dims5()?;
+        let f_out = f + 1 - k;
+        let xs = xs.transpose(1, 2)?;
+        let taps = (0..k)
+            .map(|i| xs.narrow(1, i, f_out))
+            .collect::<Result<Vec<_>>>()?;
+        let xs = Tensor::cat(&taps, 2)?.reshape((b * f_out, k * c, h, w))?;
+        let ys = xs
+            .conv2d(&self.weight, k / 2, 1, 1, 1)?
+            .broadcast_add(&self.bias.reshape((1, (), 1, 1))?)?;
+        let (_, c, h, w) = ys.dims4()?;
+        ys.reshape((b, f_out, c, h, w))?.transpose(1, 2)
+    }
+}
+
+#[derive(Debug, Clone)]
+struct ResnetBlock3d {
+    conv1: CausalConv3d,
+    conv2: CausalConv3d,
+    shortcut: Option<(LayerNorm, CausalConv3d)>,
+}
+
+impl ResnetBlock3d {
+    fn new(
+        in_channels: usize,
+        out_channels: usize,
+        cfg: &Config,
+        is_causal: bool,
+        vb: VarBuilder,
+    ) -> Result<Self> {
+        let conv1 = CausalConv3d::new(in_channels, out_channels, 3, is_causal, vb.pp("conv1"))?;
+        let conv2 = CausalConv3d::new(out_channels, out_channels, 3, is_causal, vb.pp("conv2"))?;
+        let shortcut = if in_channels != out_channels {
+            let norm3 = candle_nn::layer_norm(in_channels, cfg.resnet_norm_eps, vb.pp("norm3"))?;
+            let conv = CausalConv3d::new(
+                in_channels,
+                out_channels,
+                1,
+                is_causal,
+                vb.pp("conv_shortcut"),
+            )?;
+            Some((norm3, conv))
+        } else {
+            None
+        };
+        Ok(Self {
+            conv1,
+            conv2,
+            shortcut,
+        })
+    }
+}
+
+impl Module for ResnetBlock3d {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        let ys = pixel_norm(xs, 1e-8)?.silu()?.apply(&self.conv1)?;
+        let ys = pixel_norm(&ys, 1e-8)?.silu()?.apply(&self.conv2)?;
+        let xs = match &self.shortcut {
+            None => xs.clone(),
+            Some((norm3, conv)) => xs
+                .permute((0, 2, 3, 4, 1))?
+                .apply(norm3)?
+                .permute((0, 4, 1, 2, 3))?
+                .apply(conv)?,
+        };
+        ys + xs
+    }
+}
+
+/// Upsamples by a factor two over the frames, height and width by predicting the channels of
+/// the new pixels, the first generated frame is dropped.
+#[derive(Debug, Clone)]
+struct Upsampler3d {
+    conv: CausalConv3d,
+}
+
+impl Upsampler3d {
+    fn new(in_channels: usize, is_causal: bool, vb: VarBuilder) -> Result<Self> {
+        let conv = CausalConv3d::new(in_channels, in_channels * 8, 3, is_causal, vb.pp("conv"))?;
+        Ok(Self { conv })
+    }
+}
+
+impl Module for Upsampler3d {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        let (b, _c, f, h, w) = xs.dims5()?;
+        let xs = self.conv.forward(xs)?;
+        let xs = xs
+            .reshape(vec![b, xs.dim(1)? / 8, 2, 2, 2, f, h, w])?
+            .permute(vec![0, 1, 5, 2, 6, 3, 7, 4])?
+            .reshape((b, (), 2 * f, 2 * h, 2 * w))?;
+        xs.narrow(2, 1, 2 * f - 1)
+    }
+}
+
+#[derive(Debug, Clone)]
+struct UpBlock3d {
+    conv_in: Option<ResnetBlock3d>,
+    upsampler: Option<Upsampler3d>,
+    resnets: Vec<ResnetBlock3d>,
+}
+
+impl UpBlock3d {
+    fn new(
+        in_channels: usize,
+        out_channels: usize,
+        num_layers: usize,
+        spatio_temporal_scale: bool,
+        cfg: &Config,
+        vb: VarBuilder,
+    ) -> Result<Self> {
+        let is_causal = cfg.decoder_causal;
+        let conv_in = if in_channels != out_channels {
+            let block =
+                ResnetBlock3d::new(in_channels, out_channels, cfg, is_causal, vb.pp("conv_in"))?;
+            Some(block)
+        } else {
+            None
+        };
+        let upsampler = if spatio_temporal_scale {
+            let vb = vb.pp("upsamplers").pp(0);
+            Some(Upsampler3d::new(out_channels, is_causal, vb)?)
+        } else {
+            None
+        };
+        let vb = vb.pp("resnets");
+        let resnets = (0..num_layers)
+            .map(|i| ResnetBlock3d::new(out_channels, out_channels, cfg, is_causal, vb.pp(i)))
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Self {
+            conv_in,
+            upsampler,
+            resnets,
+        })
+    }
+}
+
+impl Module for UpBlock3d {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        let mut xs = xs.clone();
+        if let Some(conv_in) = &self.conv_in {
+            xs = xs.apply(conv_in)?
+        }
+        if let Some(upsampler) = &self.upsampler {
+            xs = xs.apply(upsampler)?
+        }
+        for resnet in self.resnets.iter() {
+            xs = xs.apply(resnet)?
+        }
+        Ok(xs)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Decoder {
+    conv_in: CausalConv3d,
+    mid_block: Vec<ResnetBlock3d>,
+    up_blocks: Vec<UpBlock3d>,
+    conv_out: CausalConv3d,
+    patch_size: usize,
+    patch_size_t: usize,
+}
+
+impl Decoder {
+    pub fn new(cfg: &Config, vb: VarBuilder) -> Result<Self> {
+        let n = cfg.block_out_channels.len();
+        if cfg.layers_per_block.len() != n + 1 || cfg.spatio_temporal_scaling.len() != n {
+            candle::bail!("inconsistent number of blocks in the vae config {cfg:?}")
+        }
+        let is_causal = cfg.decoder_causal;
+        let block_out_channels = cfg.block_out_channels.iter().rev().collect::<Vec<_>>();
+        let layers_per_block = cfg.layers_per_block.iter().rev().collect::<Vec<_>>();
+        let scaling = cfg.spatio_temporal_scaling.iter().rev().collect::<Vec<_>>();
+
+        let mut channels = *block_out_channels[0];
+        let conv_in = CausalConv3d::new(
+            cfg.latent_channels,
+            channels,
+            3,
+            is_causal,
+            vb.pp("conv_in"),
+        )?;
+        let vb_m = vb.pp("mid_block").pp("resnets");
+        let mid_block = (0..*layers_per_block[0])
+            .map(|i| ResnetBlock3d::new(channels, channels, cfg, is_causal, vb_m.pp(i)))
+            .collect::<Result<Vec<_>>>()?;
+        let vb_u = vb.pp("up_blocks");
+        let mut up_blocks = Vec::with_capacity(n);
+        for i in 0..n {
+            let out_channels = *block_out_channels[i];
+            let block = UpBlock3d::new(
+                channels,
+                out_channels,
+                *layers_per_block[i + 1],
+                *scaling[i],
+                cfg,
+                vb_u.pp(i),
+            )?;
+            up_blocks.push(block);
+            channels = out_channels
+        }
+        let out_channels = cfg.out_channels * cfg.patch_size_t * cfg.patch_size * cfg.patch_size;
+        let conv_out = CausalConv3d::new(channels, out_channels, 3, is_causal, vb.pp("conv_out"))?;
+        Ok(Self {
+            conv_in,
+            mid_block,
+            up_blocks,
+            conv_out,
+            patch_size: cfg.patch_size,
+            patch_size_t: cfg.patch_size_t,
+        })
+    }
+}
+
+impl Module for Decoder {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        let mut xs = xs.apply(&self.conv_in)?;
+        for resnet in self.mid_block.iter() {
+            xs = xs.apply(resnet)?
+        }
+        for block in self.up_blocks.iter() {
+            xs = xs.apply(block)?
+        }
+        let xs = pixel_norm(&xs, 1e-8)?.silu()?.apply(&self.conv_out)?;
+        // The channels are laid out as (channels, patch_size_t, patch_width, patch_height).
+        let (b, _c, f, h, w) = xs.dims5()?;
+        let (p, pt) = (self.patch_size, self.patch_size_t);
+        xs.reshape(vec![b, xs.dim(1)? / (pt * p * p), pt, p, p, f, h, w])?
+            .permute(vec![0, 1, 5, 2, 6, 4, 7, 3])?
+            .reshape((b, (), f * pt, h * p, w * p))
+    }
+}
+
+/// The tile sizes used by [`AutoEncoder::decode_tiled`], all the sizes are in latent pixels and
+/// frames. Neighbouring tiles overlap and are linearly blended to avoid seams.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TilingConfig {
+    pub tile_size: usize,
+    pub tile_overlap: usize,
+    pub tile_frames: usize,
+    pub tile_frame_overlap: usize,
+}
+
+impl Default for TilingConfig {
+    fn default() -> Self {
+        Self {
+            tile_size: 16,
+            tile_overlap: 2,
+            tile_frames: 8,
+            tile_frame_overlap: 2,
+        }
+    }
+}
+
+// The start offsets of tiles of `size` with `overlap` covering `len`.
+fn tile_starts(len: usize, size: usize, overlap: usize) -> Result<Vec<usize>> {
+    if overlap >= size {
+        candle::bail!("the tile overlap {overlap} has to be smaller than the tile size {size}")
+    }
+    let mut starts = vec![0];
+    let mut start = 0;
+    while start + size < len {
+        start += size - overlap;
+        starts.push(start);
+    }
+    Ok(starts)
+}
+
+// Concatenates `tiles` along `dim` where each tile is given with its start offset, the
+// overlapping parts are linearly blended from the previous tile to the next one.
+fn stitch(tiles: Vec<(usize, Tensor)>, dim: usize) -> Result<Tensor> {
+    let mut pieces = vec![];
+    let mut pending: Option<(usize, Tensor)> = None;
+    for (start, tile) in tiles {
+        let (prev_start, prev) = match pending.take() {
+            None => {
+                pending = Some((start, tile));
+                continue;
+            }
+            Some(p) => p,
+        };
+        let prev_len = prev.dim(dim)?;
+        let overlap = (prev_start + prev_len).saturating_sub(start);
+        pieces.push(prev.narrow(dim, 0, prev_len - overlap)?);
+        let tile = if overlap > 0 {
+            let ramp = (1..=overlap)
+                .map(|i| i as f32 / (overlap + 1) as f32)
+                .collect::<Vec<_>>();
+            let mut shape = vec![1; tile.rank()];
+            shape[dim] = overlap;
+            let ramp = Tensor::from_vec(ramp, shape, tile.device())?.to_dtype(tile.dtype())?;
+            let prev = prev.narrow(dim, prev_len - overlap, overlap)?;
+            let next = tile.narrow(dim, 0, overlap)?;
+            let blended = (prev.broadcast_mul(&(1. - &ramp)?)? + next.broadcast_mul(&ramp)?)?;
+            let rest = tile.narrow(dim, overlap, tile.dim(dim)? - overlap)?;
+            Tensor::cat(&[blended, rest], dim)?
+        } else {
+            tile
+        };
+        pending = Some((start, tile))
+    }
+    if let Some((_, tile)) = pending {
+        pieces.push(tile)
+    }
+    Tensor::cat(&pieces, dim)
+}
+
+#[derive(Debug, Clone)]
+pub struct AutoEncoder {
+    decoder: Decoder,
+    latents_mean: Option<Tensor>,
+    latents_std: Option<Tensor>,
+    config: Config,
+}
+
+impl AutoEncoder {
+    /// Only the decoder is loaded, this is all that is needed for text to video generation.
+    pub fn new(cfg: &Config, vb: VarBuilder) -> Result<Self> {
+        let decoder = Decoder::new(cfg, vb.pp("decoder"))?;
+        let c = cfg.latent_channels;
+        let (latents_mean, latents_std) =
+            if vb.contains_tensor("latents_mean") && vb.contains_tensor("latents_std") {
+                (
+                    Some(vb.get(c, "latents_mean")?),
+                    Some(vb.get(c, "latents_std")?),
+                )
+            } else {
+                (None, None)
+            };
+        Ok(Self {
+            decoder,
+            latents_mean,
+            latents_std,
+            config: cfg.clone(),
+        })
+    }
+
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    /// Converts the latents produced by the transformer to the latent space of the decoder
+    /// using the per-channel statistics of the autoencoder.
+    pub fn denormalize_latents(&self, xs: &Tensor) -> Result<Tensor> {
+        let xs = (xs / self.config.scaling_factor)?;
+        let shape = (1, (), 1, 1, 1);
+        let xs = match &self.latents_std {
+            None => xs,
+            Some(std) => xs.broadcast_mul(&std.reshape(shape)?.to_dtype(xs.dtype())?)?,
+        };
+        match &self.latents_mean {
+            None => Ok(xs),
+            Some(mean) => xs.broadcast_add(&mean.reshape(shape)?.to_dtype(xs.dtype())?),
+        }
+    }
+
+    /// Decodes the whole latents at once.
+    pub fn decode(&self, xs: &Tensor) -> Result<Tensor> {
+        self.decoder.forward(xs)
+    }
+
+    /// Decodes the latents by chunks of frames and spatial tiles, the peak memory only depends
+    /// on the tile sizes. The chunks of frames are decoded as separate videos: a chunk starting
+    /// at latent frame `t` covers the output frames from `t * temporal_compression_ratio`.
+    pub fn decode_tiled(&self, xs: &Tensor, cfg: &TilingConfig) -> Result<Tensor> {
+        let num_frames = xs.dim(2)?;
+        let ratio = self.config.temporal_compression_ratio();
+        let mut chunks = vec![];
+        for start in tile_starts(num_frames, cfg.tile_frames, cfg.tile_frame_overlap)? {
+            let len = usize::min(cfg.tile_frames, num_frames - start);
+            let chunk = self.decode_spatial_tiles(&xs.narrow(2, start, len)?, cfg)?;
+            chunks.push((start * ratio, chunk))
+        }
+        stitch(chunks, 2)
+    }
+
+    fn decode_spatial_tiles(&self, xs: &Tensor, cfg: &TilingConfig) -> Result<Tensor> {
+        let (_b, _c, _f, h, w) = xs.dims5()?;
+        let ratio = self.config.spatial_compression_ratio();
+        let mut rows = vec![];
+        for y in tile_starts(h, cfg.tile_size, cfg.tile_overlap)? {
+            let th = usize::min(cfg.tile_size, h - y);
+            let mut row = vec![];
+            for x in tile_starts(w, cfg.tile_size, cfg.tile_overlap)? {
+                let tw = usize::min(cfg.tile_size, w - x);
+                let tile = xs.narrow(3, y, th)?.narrow(4, x, tw)?;
+                row.push((x * ratio, self.decode(&tile)?))
+            }
+            rows.push((y * ratio, stitch(row, 4)?))
+        }
+        stitch(rows, 3)
+    }
+}
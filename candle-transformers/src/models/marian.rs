@@ -223,6 +223,13 @@ impl Attention {
     fn reset_kv_cache(&mut self) {
         self.kv_cache = None
     }
+
+    fn reorder_kv_cache(&mut self, indices: &Tensor) -> Result<()> {
+        if let Some((k, v)) = &self.kv_cache {
+            self.kv_cache = Some((k.index_select(indices, 0)?, v.index_select(indices, 0)?))
+        }
+        Ok(())
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -333,6 +340,10 @@ impl DecoderLayer {
         self.self_attn.reset_kv_cache();
         self.encoder_attn.reset_kv_cache()
     }
+
+    fn reorder_kv_cache(&mut self, indices: &Tensor) -> Result<()> {
+        self.self_attn.reorder_kv_cache(indices)
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -447,6 +458,14 @@ impl Decoder {
             layer.reset_kv_cache()
         }
     }
+
+    /// Reorders the kv caches along the batch dimension.
+    pub fn reorder_kv_cache(&mut self, indices: &Tensor) -> Result<()> {
+        for layer in self.layers.iter_mut() {
+            layer.reorder_kv_cache(indices)?
+        }
+        Ok(())
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -472,6 +491,10 @@ impl Model {
         self.encoder.reset_kv_cache();
         self.decoder.reset_kv_cache();
     }
+
+    fn reorder_kv_cache(&mut self, indices: &Tensor) -> Result<()> {
+        self.decoder.reorder_kv_cache(indices)
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -523,4 +546,10 @@ impl MTModel {
     pub fn reset_kv_cache(&mut self) {
         self.model.reset_kv_cache();
     }
+
+    /// Reorders the decoder kv caches along the batch dimension, e.g. when the beams of a beam
+    /// search get pruned. The entry `i` becomes a copy of the entry `indices[i]`.
+    pub fn reorder_kv_cache(&mut self, indices: &Tensor) -> Result<()> {
+        self.model.reorder_kv_cache(indices)
+    }
 }
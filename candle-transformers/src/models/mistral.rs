@@ -5,9 +5,11 @@
 //! - [Github](https://github.com/mistralai/mistral-src)
 //!
 
+use crate::config::QuantizationConfig;
 use crate::models::with_tracing::{linear_no_bias, Linear, RmsNorm};
+use crate::quantized_nn::QuantizedLinear;
 /// Mistral LLM, https://github.com/mistralai/mistral-src
-use candle::{DType, Device, Module, Result, Tensor, D};
+use candle::{DType, Device, Module, Result, Tensor};
 use candle_nn::{Activation, VarBuilder};
 use std::sync::Arc;
 
@@ -41,6 +43,8 @@ pub struct Config {
     pub sliding_window: Option<usize>,
     #[serde(default = "default_use_flash_attn")]
     pub use_flash_attn: bool,
+    /// The AWQ or GPTQ quantization of the attention and mlp projections, if any.
+    pub quantization_config: Option<QuantizationConfig>,
 }
 
 impl Config {
@@ -60,6 +64,7 @@ impl Config {
             rope_theta: 10_000.,
             sliding_window: Some(4096),
             use_flash_attn,
+            quantization_config: None,
         }
     }
 
@@ -80,6 +85,7 @@ impl Config {
             rope_theta: 10_000.,
             sliding_window: Some(4096),
             use_flash_attn,
+            quantization_config: None,
         }
     }
 
@@ -99,6 +105,7 @@ impl Config {
             rope_theta: 10_000.,
             sliding_window: Some(4096),
             use_flash_attn,
+            quantization_config: None,
         }
     }
 
@@ -116,23 +123,16 @@ struct RotaryEmbedding {
 
 impl RotaryEmbedding {
     fn new(dtype: DType, cfg: &Config, dev: &Device) -> Result<Self> {
-        let rope_theta = cfg.rope_theta as f32;
-        let dim = cfg.head_dim();
-        let max_seq_len = cfg.max_position_embeddings;
-        let inv_freq: Vec<_> = (0..dim)
-            .step_by(2)
-            .map(|i| 1f32 / rope_theta.powf(i as f32 / dim as f32))
-            .collect();
-        let inv_freq_len = inv_freq.len();
-        let inv_freq = Tensor::from_vec(inv_freq, (1, inv_freq_len), dev)?.to_dtype(DType::F32)?;
-        let t = Tensor::arange(0u32, max_seq_len as u32, dev)?
-            .to_dtype(DType::F32)?
-            .reshape((max_seq_len, 1))?;
-        let freqs = t.matmul(&inv_freq)?;
-        Ok(Self {
-            sin: freqs.sin()?.to_dtype(dtype)?,
-            cos: freqs.cos()?.to_dtype(dtype)?,
-        })
+        // The tables are shared with the other models using the same parameters on the device.
+        let (cos, sin) = candle_nn::device_cache::rope_tables(
+            cfg.head_dim(),
+            cfg.rope_theta,
+            cfg.max_position_embeddings,
+            None,
+            dtype,
+            dev,
+        )?;
+        Ok(Self { sin, cos })
     }
 
     fn apply_rotary_emb_qkv(
@@ -153,9 +153,9 @@ impl RotaryEmbedding {
 #[derive(Debug, Clone)]
 #[allow(clippy::upper_case_acronyms)]
 struct MLP {
-    gate_proj: Linear,
-    up_proj: Linear,
-    down_proj: Linear,
+    gate_proj: QuantizedLinear,
+    up_proj: QuantizedLinear,
+    down_proj: QuantizedLinear,
     act_fn: Activation,
 }
 
@@ -163,9 +163,13 @@ impl MLP {
     fn new(cfg: &Config, vb: VarBuilder) -> Result<Self> {
         let hidden_sz = cfg.hidden_size;
         let intermediate_sz = cfg.intermediate_size;
-        let gate_proj = linear_no_bias(hidden_sz, intermediate_sz, vb.pp("gate_proj"))?;
-        let up_proj = linear_no_bias(hidden_sz, intermediate_sz, vb.pp("up_proj"))?;
-        let down_proj = linear_no_bias(intermediate_sz, hidden_sz, vb.pp("down_proj"))?;
+        let q_cfg = cfg.quantization_config.as_ref();
+        let linear = |in_dim, out_dim, name| {
+            QuantizedLinear::new(in_dim, out_dim, false, q_cfg, vb.pp(name))
+        };
+        let gate_proj = linear(hidden_sz, intermediate_sz, "gate_proj")?;
+        let up_proj = linear(hidden_sz, intermediate_sz, "up_proj")?;
+        let down_proj = linear(intermediate_sz, hidden_sz, "down_proj")?;
         Ok(Self {
             gate_proj,
             up_proj,
@@ -201,10 +205,10 @@ fn flash_attn(_: &Tensor, _: &Tensor, _: &Tensor, _: f32, _: bool) -> Result<Ten
 
 #[derive(Debug, Clone)]
 struct Attention {
-    q_proj: Linear,
-    k_proj: Linear,
-    v_proj: Linear,
-    o_proj: Linear,
+    q_proj: QuantizedLinear,
+    k_proj: QuantizedLinear,
+    v_proj: QuantizedLinear,
+    o_proj: QuantizedLinear,
     num_heads: usize,
     num_kv_heads: usize,
     num_kv_groups: usize,
@@ -221,10 +225,14 @@ impl Attention {
         let num_kv_heads = cfg.num_key_value_heads;
         let num_kv_groups = num_heads / num_kv_heads;
         let head_dim = cfg.head_dim();
-        let q_proj = linear_no_bias(hidden_sz, num_heads * head_dim, vb.pp("q_proj"))?;
-        let k_proj = linear_no_bias(hidden_sz, num_kv_heads * head_dim, vb.pp("k_proj"))?;
-        let v_proj = linear_no_bias(hidden_sz, num_kv_heads * head_dim, vb.pp("v_proj"))?;
-        let o_proj = linear_no_bias(num_heads * head_dim, hidden_sz, vb.pp("o_proj"))?;
+        let q_cfg = cfg.quantization_config.as_ref();
+        let linear = |in_dim, out_dim, name| {
+            QuantizedLinear::new(in_dim, out_dim, false, q_cfg, vb.pp(name))
+        };
+        let q_proj = linear(hidden_sz, num_heads * head_dim, "q_proj")?;
+        let k_proj = linear(hidden_sz, num_kv_heads * head_dim, "k_proj")?;
+        let v_proj = linear(hidden_sz, num_kv_heads * head_dim, "v_proj")?;
+        let o_proj = linear(num_heads * head_dim, hidden_sz, "o_proj")?;
         Ok(Self {
             q_proj,
             k_proj,
@@ -398,27 +406,14 @@ impl Model {
         tgt_len: usize,
         seqlen_offset: usize,
     ) -> Result<Tensor> {
-        let sliding_window = self.sliding_window.unwrap_or(tgt_len + 1);
-        let mask: Vec<_> = (0..tgt_len)
-            .flat_map(|i| {
-                (0..tgt_len).map(move |j| {
-                    if i < j || j + sliding_window < i {
-                        f32::NEG_INFINITY
-                    } else {
-                        0.
-                    }
-                })
-            })
-            .collect();
-        let mask = Tensor::from_slice(&mask, (tgt_len, tgt_len), &self.device)?;
-        let mask = if seqlen_offset > 0 {
-            let mask0 = Tensor::zeros((tgt_len, seqlen_offset), DType::F32, &self.device)?;
-            Tensor::cat(&[&mask0, &mask], D::Minus1)?
-        } else {
-            mask
-        };
-        mask.expand((1, 1, tgt_len, tgt_len + seqlen_offset))?
-            .to_dtype(self.dtype)
+        let mask = candle_nn::device_cache::causal_bias(
+            tgt_len,
+            seqlen_offset,
+            self.sliding_window,
+            self.dtype,
+            &self.device,
+        )?;
+        mask.expand((1, 1, tgt_len, tgt_len + seqlen_offset))
     }
 
     pub fn embed_tokens(&self) -> &candle_nn::Embedding {
@@ -2,6 +2,7 @@ use candle::{Module, Result, Tensor, D};
 use candle_nn as nn;
 
 use super::projections::{AttnProjections, Mlp, Qkv, QkvOnlyAttnProjections};
+use crate::models::dit;
 
 pub struct ModulateIntermediates {
     gate_msa: Tensor,
@@ -294,10 +295,7 @@ impl FinalLayer {
 }
 
 fn modulate(x: &Tensor, shift: &Tensor, scale: &Tensor) -> Result<Tensor> {
-    let shift = shift.unsqueeze(1)?;
-    let scale = scale.unsqueeze(1)?;
-    let scale_plus_one = scale.add(&Tensor::ones_like(&scale)?)?;
-    shift.broadcast_add(&x.broadcast_mul(&scale_plus_one)?)
+    dit::modulate(x, &shift.unsqueeze(1)?, &scale.unsqueeze(1)?)
 }
 
 pub trait JointBlock {
@@ -462,19 +460,9 @@ fn joint_attn(
     num_heads: usize,
     use_flash_attn: bool,
 ) -> Result<(Tensor, Tensor)> {
-    let qkv = Qkv {
-        q: Tensor::cat(&[&context_qkv.q, &x_qkv.q], 1)?,
-        k: Tensor::cat(&[&context_qkv.k, &x_qkv.k], 1)?,
-        v: Tensor::cat(&[&context_qkv.v, &x_qkv.v], 1)?,
-    };
-
-    let seqlen = qkv.q.dim(1)?;
-    let attn = attn(&qkv, num_heads, use_flash_attn)?;
-    let context_qkv_seqlen = context_qkv.q.dim(1)?;
-    let context_attn = attn.narrow(1, 0, context_qkv_seqlen)?;
-    let x_attn = attn.narrow(1, context_qkv_seqlen, seqlen - context_qkv_seqlen)?;
-
-    Ok((context_attn, x_attn))
+    dit::joint_attention(context_qkv, x_qkv, 1, |qkv| {
+        attn(qkv, num_heads, use_flash_attn)
+    })
 }
 
 fn attn(qkv: &Qkv, num_heads: usize, use_flash_attn: bool) -> Result<Tensor> {
@@ -1,11 +1,7 @@
 use candle::{Module, Result, Tensor};
 use candle_nn as nn;
 
-pub struct Qkv {
-    pub q: Tensor,
-    pub k: Tensor,
-    pub v: Tensor,
-}
+pub use crate::models::dit::Qkv;
 
 pub struct Mlp {
     fc1: nn::Linear,
@@ -6,6 +6,7 @@
 //!  - Text to text models: [`t5`], ...
 //!  - Image to text models: [`blip`], ...
 //!  - Text to image models: [`stable_diffusion`] and [`wuerstchen`], ...
+//!  - Text to video models: [`ltx_video`], ...
 //!  - Audio models: [`whisper`], [`encodec`], [`metavoice`], [`parler_tts`], ...
 //!  - Computer vision models: [`dinov2`], [`convmixer`], [`efficientnet`], ...
 //!  
@@ -32,6 +33,7 @@ pub mod depth_anything_v2;
 pub mod dinov2;
 pub mod dinov2reg4;
 pub mod distilbert;
+pub mod dit;
 pub mod efficientnet;
 pub mod efficientvit;
 pub mod encodec;
@@ -41,6 +43,7 @@ pub mod fastvit;
 pub mod flux;
 pub mod gemma;
 pub mod gemma2;
+pub mod generic_decoder;
 pub mod glm4;
 pub mod granite;
 pub mod hiera;
@@ -49,6 +52,7 @@ pub mod llama;
 pub mod llama2_c;
 pub mod llama2_c_weights;
 pub mod llava;
+pub mod ltx_video;
 pub mod mamba;
 pub mod marian;
 pub mod metavoice;
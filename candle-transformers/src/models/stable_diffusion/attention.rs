@@ -1,17 +1,21 @@
 //! Attention Based Building Blocks
+use super::var_builder::{
+    conv2d, group_norm, layer_norm, linear, linear_no_bias, Linear, VarBuilder,
+};
+use crate::models::with_tracing::Conv2d;
 use candle::{DType, IndexOp, Result, Tensor, D};
 use candle_nn as nn;
 use candle_nn::Module;
 
 #[derive(Debug)]
 struct GeGlu {
-    proj: nn::Linear,
+    proj: Linear,
     span: tracing::Span,
 }
 
 impl GeGlu {
-    fn new(vs: nn::VarBuilder, dim_in: usize, dim_out: usize) -> Result<Self> {
-        let proj = nn::linear(dim_in, dim_out * 2, vs.pp("proj"))?;
+    fn new(vs: VarBuilder, dim_in: usize, dim_out: usize) -> Result<Self> {
+        let proj = linear(dim_in, dim_out * 2, vs.pp("proj"))?;
         let span = tracing::span!(tracing::Level::TRACE, "geglu");
         Ok(Self { proj, span })
     }
@@ -29,7 +33,7 @@ impl Module for GeGlu {
 #[derive(Debug)]
 struct FeedForward {
     project_in: GeGlu,
-    linear: nn::Linear,
+    linear: Linear,
     span: tracing::Span,
 }
 
@@ -38,12 +42,12 @@ impl FeedForward {
     // https://github.com/huggingface/diffusers/blob/d3d22ce5a894becb951eec03e663951b28d45135/src/diffusers/models/attention.py#L347
     /// Creates a new feed-forward layer based on some given input dimension, some
     /// output dimension, and a multiplier to be used for the intermediary layer.
-    fn new(vs: nn::VarBuilder, dim: usize, dim_out: Option<usize>, mult: usize) -> Result<Self> {
+    fn new(vs: VarBuilder, dim: usize, dim_out: Option<usize>, mult: usize) -> Result<Self> {
         let inner_dim = dim * mult;
         let dim_out = dim_out.unwrap_or(dim);
         let vs = vs.pp("net");
         let project_in = GeGlu::new(vs.pp("0"), dim, inner_dim)?;
-        let linear = nn::linear(inner_dim, dim_out, vs.pp("2"))?;
+        let linear = linear(inner_dim, dim_out, vs.pp("2"))?;
         let span = tracing::span!(tracing::Level::TRACE, "ff");
         Ok(Self {
             project_in,
@@ -79,10 +83,10 @@ fn flash_attn(_: &Tensor, _: &Tensor, _: &Tensor, _: f32, _: bool) -> Result<Ten
 
 #[derive(Debug)]
 pub struct CrossAttention {
-    to_q: nn::Linear,
-    to_k: nn::Linear,
-    to_v: nn::Linear,
-    to_out: nn::Linear,
+    to_q: Linear,
+    to_k: Linear,
+    to_v: Linear,
+    to_out: Linear,
     heads: usize,
     scale: f64,
     slice_size: Option<usize>,
@@ -95,7 +99,7 @@ pub struct CrossAttention {
 impl CrossAttention {
     // Defaults should be heads = 8, dim_head = 64, context_dim = None
     pub fn new(
-        vs: nn::VarBuilder,
+        vs: VarBuilder,
         query_dim: usize,
         context_dim: Option<usize>,
         heads: usize,
@@ -106,10 +110,10 @@ impl CrossAttention {
         let inner_dim = dim_head * heads;
         let context_dim = context_dim.unwrap_or(query_dim);
         let scale = 1.0 / f64::sqrt(dim_head as f64);
-        let to_q = nn::linear_no_bias(query_dim, inner_dim, vs.pp("to_q"))?;
-        let to_k = nn::linear_no_bias(context_dim, inner_dim, vs.pp("to_k"))?;
-        let to_v = nn::linear_no_bias(context_dim, inner_dim, vs.pp("to_v"))?;
-        let to_out = nn::linear(inner_dim, query_dim, vs.pp("to_out.0"))?;
+        let to_q = linear_no_bias(query_dim, inner_dim, vs.pp("to_q"))?;
+        let to_k = linear_no_bias(context_dim, inner_dim, vs.pp("to_k"))?;
+        let to_v = linear_no_bias(context_dim, inner_dim, vs.pp("to_v"))?;
+        let to_out = linear(inner_dim, query_dim, vs.pp("to_out.0"))?;
         let span = tracing::span!(tracing::Level::TRACE, "xa");
         let span_attn = tracing::span!(tracing::Level::TRACE, "xa-attn");
         let span_softmax = tracing::span!(tracing::Level::TRACE, "xa-softmax");
@@ -244,7 +248,7 @@ struct BasicTransformerBlock {
 
 impl BasicTransformerBlock {
     fn new(
-        vs: nn::VarBuilder,
+        vs: VarBuilder,
         dim: usize,
         n_heads: usize,
         d_head: usize,
@@ -271,9 +275,9 @@ impl BasicTransformerBlock {
             sliced_attention_size,
             use_flash_attn,
         )?;
-        let norm1 = nn::layer_norm(dim, 1e-5, vs.pp("norm1"))?;
-        let norm2 = nn::layer_norm(dim, 1e-5, vs.pp("norm2"))?;
-        let norm3 = nn::layer_norm(dim, 1e-5, vs.pp("norm3"))?;
+        let norm1 = layer_norm(dim, 1e-5, vs.pp("norm1"))?;
+        let norm2 = layer_norm(dim, 1e-5, vs.pp("norm2"))?;
+        let norm3 = layer_norm(dim, 1e-5, vs.pp("norm3"))?;
         let span = tracing::span!(tracing::Level::TRACE, "basic-transformer");
         Ok(Self {
             attn1,
@@ -317,8 +321,8 @@ impl Default for SpatialTransformerConfig {
 
 #[derive(Debug)]
 enum Proj {
-    Conv2d(nn::Conv2d),
-    Linear(nn::Linear),
+    Conv2d(Conv2d),
+    Linear(Linear),
 }
 
 // Aka Transformer2DModel
@@ -334,7 +338,7 @@ pub struct SpatialTransformer {
 
 impl SpatialTransformer {
     pub fn new(
-        vs: nn::VarBuilder,
+        vs: VarBuilder,
         in_channels: usize,
         n_heads: usize,
         d_head: usize,
@@ -342,11 +346,11 @@ impl SpatialTransformer {
         config: SpatialTransformerConfig,
     ) -> Result<Self> {
         let inner_dim = n_heads * d_head;
-        let norm = nn::group_norm(config.num_groups, in_channels, 1e-6, vs.pp("norm"))?;
+        let norm = group_norm(config.num_groups, in_channels, 1e-6, vs.pp("norm"))?;
         let proj_in = if config.use_linear_projection {
-            Proj::Linear(nn::linear(in_channels, inner_dim, vs.pp("proj_in"))?)
+            Proj::Linear(linear(in_channels, inner_dim, vs.pp("proj_in"))?)
         } else {
-            Proj::Conv2d(nn::conv2d(
+            Proj::Conv2d(conv2d(
                 in_channels,
                 inner_dim,
                 1,
@@ -369,9 +373,9 @@ impl SpatialTransformer {
             transformer_blocks.push(tb)
         }
         let proj_out = if config.use_linear_projection {
-            Proj::Linear(nn::linear(in_channels, inner_dim, vs.pp("proj_out"))?)
+            Proj::Linear(linear(in_channels, inner_dim, vs.pp("proj_out"))?)
         } else {
-            Proj::Conv2d(nn::conv2d(
+            Proj::Conv2d(conv2d(
                 inner_dim,
                 in_channels,
                 1,
@@ -457,10 +461,10 @@ impl Default for AttentionBlockConfig {
 #[derive(Debug)]
 pub struct AttentionBlock {
     group_norm: nn::GroupNorm,
-    query: nn::Linear,
-    key: nn::Linear,
-    value: nn::Linear,
-    proj_attn: nn::Linear,
+    query: Linear,
+    key: Linear,
+    value: Linear,
+    proj_attn: Linear,
     channels: usize,
     num_heads: usize,
     span: tracing::Span,
@@ -472,25 +476,24 @@ pub struct AttentionBlock {
 // Linear layer may use a different dimension for the weight in the linear, which is
 // incompatible with the current implementation of the nn::linear constructor.
 // This is a workaround to handle the different dimensions.
-fn get_qkv_linear(channels: usize, vs: nn::VarBuilder) -> Result<nn::Linear> {
+fn get_qkv_linear(channels: usize, vs: VarBuilder) -> Result<Linear> {
     match vs.get((channels, channels), "weight") {
-        Ok(_) => nn::linear(channels, channels, vs),
+        Ok(_) => linear(channels, channels, vs),
         Err(_) => {
             let weight = vs
                 .get((channels, channels, 1, 1), "weight")?
                 .reshape((channels, channels))?;
             let bias = vs.get((channels,), "bias")?;
-            Ok(nn::Linear::new(weight, Some(bias)))
+            vs.linear_from_weights(weight, Some(bias))
         }
     }
 }
 
 impl AttentionBlock {
-    pub fn new(vs: nn::VarBuilder, channels: usize, config: AttentionBlockConfig) -> Result<Self> {
+    pub fn new(vs: VarBuilder, channels: usize, config: AttentionBlockConfig) -> Result<Self> {
         let num_head_channels = config.num_head_channels.unwrap_or(channels);
         let num_heads = channels / num_head_channels;
-        let group_norm =
-            nn::group_norm(config.num_groups, channels, config.eps, vs.pp("group_norm"))?;
+        let group_norm = group_norm(config.num_groups, channels, config.eps, vs.pp("group_norm"))?;
         let (q_path, k_path, v_path, out_path) = if vs.contains_tensor("to_q.weight") {
             ("to_q", "to_k", "to_v", "to_out.0")
         } else {
@@ -49,6 +49,7 @@ pub mod unet_2d;
 pub mod unet_2d_blocks;
 pub mod utils;
 pub mod vae;
+pub mod var_builder;
 
 use std::sync::Arc;
 
@@ -490,6 +491,27 @@ impl StableDiffusionConfig {
         Ok(unet)
     }
 
+    /// Builds the UNet from a gguf file, the linear layers keep their quantized weights while the
+    /// other layers are dequantized to `dtype`.
+    pub fn build_quantized_unet<P: AsRef<std::path::Path>>(
+        &self,
+        unet_weights: P,
+        device: &Device,
+        in_channels: usize,
+        use_flash_attn: bool,
+        dtype: DType,
+    ) -> Result<unet_2d::UNet2DConditionModel> {
+        let vs_unet = crate::quantized_var_builder::VarBuilder::from_gguf(unet_weights, device)?;
+        let unet = unet_2d::UNet2DConditionModel::new(
+            var_builder::VarBuilder::from_gguf(vs_unet, dtype),
+            in_channels,
+            4,
+            use_flash_attn,
+            self.unet.clone(),
+        )?;
+        Ok(unet)
+    }
+
     pub fn build_scheduler(&self, n_steps: usize) -> Result<Box<dyn Scheduler>> {
         self.scheduler.build(n_steps)
     }
@@ -5,7 +5,8 @@
 //! Denoising Diffusion Implicit Models, K. He and al, 2015.
 //! - [Paper](https://arxiv.org/abs/1512.03385)
 //!
-use crate::models::with_tracing::{conv2d, Conv2d};
+use super::var_builder::{conv2d, group_norm, linear, Linear, VarBuilder};
+use crate::models::with_tracing::Conv2d;
 use candle::{Result, Tensor, D};
 use candle_nn as nn;
 use candle_nn::Module;
@@ -50,18 +51,14 @@ pub struct ResnetBlock2D {
     conv1: Conv2d,
     norm2: nn::GroupNorm,
     conv2: Conv2d,
-    time_emb_proj: Option<nn::Linear>,
+    time_emb_proj: Option<Linear>,
     conv_shortcut: Option<Conv2d>,
     span: tracing::Span,
     config: ResnetBlock2DConfig,
 }
 
 impl ResnetBlock2D {
-    pub fn new(
-        vs: nn::VarBuilder,
-        in_channels: usize,
-        config: ResnetBlock2DConfig,
-    ) -> Result<Self> {
+    pub fn new(vs: VarBuilder, in_channels: usize, config: ResnetBlock2DConfig) -> Result<Self> {
         let out_channels = config.out_channels.unwrap_or(in_channels);
         let conv_cfg = nn::Conv2dConfig {
             stride: 1,
@@ -69,10 +66,10 @@ impl ResnetBlock2D {
             groups: 1,
             dilation: 1,
         };
-        let norm1 = nn::group_norm(config.groups, in_channels, config.eps, vs.pp("norm1"))?;
+        let norm1 = group_norm(config.groups, in_channels, config.eps, vs.pp("norm1"))?;
         let conv1 = conv2d(in_channels, out_channels, 3, conv_cfg, vs.pp("conv1"))?;
         let groups_out = config.groups_out.unwrap_or(config.groups);
-        let norm2 = nn::group_norm(groups_out, out_channels, config.eps, vs.pp("norm2"))?;
+        let norm2 = group_norm(groups_out, out_channels, config.eps, vs.pp("norm2"))?;
         let conv2 = conv2d(out_channels, out_channels, 3, conv_cfg, vs.pp("conv2"))?;
         let use_in_shortcut = config
             .use_in_shortcut
@@ -96,11 +93,9 @@ impl ResnetBlock2D {
         };
         let time_emb_proj = match config.temb_channels {
             None => None,
-            Some(temb_channels) => Some(nn::linear(
-                temb_channels,
-                out_channels,
-                vs.pp("time_emb_proj"),
-            )?),
+            Some(temb_channels) => {
+                Some(linear(temb_channels, out_channels, vs.pp("time_emb_proj"))?)
+            }
         };
         let span = tracing::span!(tracing::Level::TRACE, "resnet2d");
         Ok(Self {
@@ -4,7 +4,8 @@
 //! timestep and return a denoised version of the input.
 use super::embeddings::{TimestepEmbedding, Timesteps};
 use super::unet_2d_blocks::*;
-use crate::models::with_tracing::{conv2d, Conv2d};
+use super::var_builder::{conv2d, group_norm, VarBuilder};
+use crate::models::with_tracing::Conv2d;
 use candle::{Result, Tensor};
 use candle_nn as nn;
 use candle_nn::Module;
@@ -101,13 +102,16 @@ pub struct UNet2DConditionModel {
 }
 
 impl UNet2DConditionModel {
-    pub fn new(
-        vs: nn::VarBuilder,
+    /// Creates the UNet, `vs` can be a [`candle_nn::VarBuilder`] or a quantized
+    /// [`VarBuilder`].
+    pub fn new<'a>(
+        vs: impl Into<VarBuilder<'a>>,
         in_channels: usize,
         out_channels: usize,
         use_flash_attn: bool,
         config: UNet2DConditionModelConfig,
     ) -> Result<Self> {
+        let vs = vs.into();
         let n_blocks = config.blocks.len();
         let b_channels = config.blocks[0].out_channels;
         let bl_channels = config.blocks.last().unwrap().out_channels;
@@ -274,7 +278,7 @@ impl UNet2DConditionModel {
             })
             .collect::<Result<Vec<_>>>()?;
 
-        let conv_norm_out = nn::group_norm(
+        let conv_norm_out = group_norm(
             config.norm_num_groups,
             b_channels,
             config.norm_eps,
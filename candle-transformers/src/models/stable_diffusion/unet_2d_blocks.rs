@@ -4,7 +4,8 @@ use super::attention::{
     AttentionBlock, AttentionBlockConfig, SpatialTransformer, SpatialTransformerConfig,
 };
 use super::resnet::{ResnetBlock2D, ResnetBlock2DConfig};
-use crate::models::with_tracing::{conv2d, Conv2d};
+use super::var_builder::{conv2d, VarBuilder};
+use crate::models::with_tracing::Conv2d;
 use candle::{Module, Result, Tensor, D};
 use candle_nn as nn;
 
@@ -17,7 +18,7 @@ struct Downsample2D {
 
 impl Downsample2D {
     fn new(
-        vs: nn::VarBuilder,
+        vs: VarBuilder,
         in_channels: usize,
         use_conv: bool,
         out_channels: usize,
@@ -70,7 +71,7 @@ struct Upsample2D {
 }
 
 impl Upsample2D {
-    fn new(vs: nn::VarBuilder, in_channels: usize, out_channels: usize) -> Result<Self> {
+    fn new(vs: VarBuilder, in_channels: usize, out_channels: usize) -> Result<Self> {
         let config = nn::Conv2dConfig {
             padding: 1,
             ..Default::default()
@@ -128,7 +129,7 @@ pub struct DownEncoderBlock2D {
 
 impl DownEncoderBlock2D {
     pub fn new(
-        vs: nn::VarBuilder,
+        vs: VarBuilder,
         in_channels: usize,
         out_channels: usize,
         config: DownEncoderBlock2DConfig,
@@ -217,7 +218,7 @@ pub struct UpDecoderBlock2D {
 
 impl UpDecoderBlock2D {
     pub fn new(
-        vs: nn::VarBuilder,
+        vs: VarBuilder,
         in_channels: usize,
         out_channels: usize,
         config: UpDecoderBlock2DConfig,
@@ -302,7 +303,7 @@ pub struct UNetMidBlock2D {
 
 impl UNetMidBlock2D {
     pub fn new(
-        vs: nn::VarBuilder,
+        vs: VarBuilder,
         in_channels: usize,
         temb_channels: Option<usize>,
         config: UNetMidBlock2DConfig,
@@ -395,7 +396,7 @@ pub struct UNetMidBlock2DCrossAttn {
 
 impl UNetMidBlock2DCrossAttn {
     pub fn new(
-        vs: nn::VarBuilder,
+        vs: VarBuilder,
         in_channels: usize,
         temb_channels: Option<usize>,
         use_flash_attn: bool,
@@ -498,7 +499,7 @@ pub struct DownBlock2D {
 
 impl DownBlock2D {
     pub fn new(
-        vs: nn::VarBuilder,
+        vs: VarBuilder,
         in_channels: usize,
         out_channels: usize,
         temb_channels: Option<usize>,
@@ -593,7 +594,7 @@ pub struct CrossAttnDownBlock2D {
 
 impl CrossAttnDownBlock2D {
     pub fn new(
-        vs: nn::VarBuilder,
+        vs: VarBuilder,
         in_channels: usize,
         out_channels: usize,
         temb_channels: Option<usize>,
@@ -696,7 +697,7 @@ pub struct UpBlock2D {
 
 impl UpBlock2D {
     pub fn new(
-        vs: nn::VarBuilder,
+        vs: VarBuilder,
         in_channels: usize,
         prev_output_channels: usize,
         out_channels: usize,
@@ -798,7 +799,7 @@ pub struct CrossAttnUpBlock2D {
 
 impl CrossAttnUpBlock2D {
     pub fn new(
-        vs: nn::VarBuilder,
+        vs: VarBuilder,
         in_channels: usize,
         prev_output_channels: usize,
         out_channels: usize,
@@ -80,7 +80,7 @@ impl Encoder {
                 ..Default::default()
             };
             let down_block = DownEncoderBlock2D::new(
-                vs_down_blocks.pp(index.to_string()),
+                vs_down_blocks.pp(index.to_string()).into(),
                 in_channels,
                 out_channels,
                 cfg,
@@ -95,8 +95,12 @@ impl Encoder {
             resnet_groups: Some(config.norm_num_groups),
             ..Default::default()
         };
-        let mid_block =
-            UNetMidBlock2D::new(vs.pp("mid_block"), last_block_out_channels, None, mid_cfg)?;
+        let mid_block = UNetMidBlock2D::new(
+            vs.pp("mid_block").into(),
+            last_block_out_channels,
+            None,
+            mid_cfg,
+        )?;
         let conv_norm_out = nn::group_norm(
             config.norm_num_groups,
             last_block_out_channels,
@@ -200,8 +204,12 @@ impl Decoder {
             resnet_groups: Some(config.norm_num_groups),
             ..Default::default()
         };
-        let mid_block =
-            UNetMidBlock2D::new(vs.pp("mid_block"), last_block_out_channels, None, mid_cfg)?;
+        let mid_block = UNetMidBlock2D::new(
+            vs.pp("mid_block").into(),
+            last_block_out_channels,
+            None,
+            mid_cfg,
+        )?;
         let mut up_blocks = vec![];
         let vs_up_blocks = vs.pp("up_blocks");
         let reversed_block_out_channels: Vec<_> =
@@ -222,7 +230,7 @@ impl Decoder {
                 ..Default::default()
             };
             let up_block = UpDecoderBlock2D::new(
-                vs_up_blocks.pp(index.to_string()),
+                vs_up_blocks.pp(index.to_string()).into(),
                 in_channels,
                 out_channels,
                 cfg,
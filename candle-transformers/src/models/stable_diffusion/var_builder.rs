@@ -0,0 +1,216 @@
+//! Weights for the UNet, either in full precision or quantized.
+//!
+//! The UNet blocks take a [`VarBuilder`] which can wrap:
+//! - a [`candle_nn::VarBuilder`], e.g. using the original safetensors weights.
+//! - the same var builder with [`VarBuilder::with_int8_linear`], the weights of the linear
+//!   layers, which include the attention projections, are quantized to int8 when loading the
+//!   model and the matmuls use int8 activations, see [`crate::w8a8`].
+//! - a [`crate::quantized_var_builder::VarBuilder`] using a gguf file. The linear layers use the
+//!   quantized matmul kernels, which dequantize the weights on the fly, while the convolutions
+//!   and normalization layers are dequantized to the model dtype when loading.
+//!
+//! ```ignore
+//! let vb = quantized_var_builder::VarBuilder::from_gguf("unet-q4_0.gguf", &device)?;
+//! let vb = stable_diffusion::var_builder::VarBuilder::from_gguf(vb, DType::F16);
+//! let unet = UNet2DConditionModel::new(vb, 4, 4, false, sd_config.unet.clone())?;
+//! ```
+use crate::models::with_tracing::Conv2d;
+use crate::quantized_var_builder;
+use crate::w8a8::W8A8Linear;
+use candle::{DType, Device, Module, Result, Shape, Tensor};
+use candle_nn as nn;
+
+#[derive(Clone)]
+pub enum VarBuilder<'a> {
+    Full {
+        vb: nn::VarBuilder<'a>,
+        int8_linear: bool,
+    },
+    Quantized {
+        vb: quantized_var_builder::VarBuilder,
+        dtype: DType,
+    },
+}
+
+impl<'a> From<nn::VarBuilder<'a>> for VarBuilder<'a> {
+    fn from(vb: nn::VarBuilder<'a>) -> Self {
+        Self::Full {
+            vb,
+            int8_linear: false,
+        }
+    }
+}
+
+impl<'a> VarBuilder<'a> {
+    /// Uses the gguf weights of `vb`, the non-quantized layers use `dtype`.
+    pub fn from_gguf(vb: quantized_var_builder::VarBuilder, dtype: DType) -> Self {
+        Self::Quantized { vb, dtype }
+    }
+
+    /// Quantizes the weights of the linear layers to int8, this has no effect on gguf weights
+    /// which are already quantized.
+    pub fn with_int8_linear(self, int8_linear: bool) -> Self {
+        match self {
+            Self::Full { vb, .. } => Self::Full { vb, int8_linear },
+            vb @ Self::Quantized { .. } => vb,
+        }
+    }
+
+    pub fn pp<S: ToString>(&self, s: S) -> Self {
+        match self {
+            Self::Full { vb, int8_linear } => Self::Full {
+                vb: vb.pp(s),
+                int8_linear: *int8_linear,
+            },
+            Self::Quantized { vb, dtype } => Self::Quantized {
+                vb: vb.pp(s),
+                dtype: *dtype,
+            },
+        }
+    }
+
+    pub fn dtype(&self) -> DType {
+        match self {
+            Self::Full { vb, .. } => vb.dtype(),
+            Self::Quantized { dtype, .. } => *dtype,
+        }
+    }
+
+    pub fn device(&self) -> &Device {
+        match self {
+            Self::Full { vb, .. } => vb.device(),
+            Self::Quantized { vb, .. } => vb.device(),
+        }
+    }
+
+    /// Retrieves a tensor, quantized tensors are dequantized to the dtype of the var builder.
+    pub fn get<S: Into<Shape>>(&self, s: S, name: &str) -> Result<Tensor> {
+        match self {
+            Self::Full { vb, .. } => vb.get(s, name),
+            Self::Quantized { vb, dtype } => {
+                vb.get(s, name)?.dequantize(vb.device())?.to_dtype(*dtype)
+            }
+        }
+    }
+
+    pub fn contains_tensor(&self, name: &str) -> bool {
+        match self {
+            Self::Full { vb, .. } => vb.contains_tensor(name),
+            Self::Quantized { vb, .. } => vb.contains_tensor(name),
+        }
+    }
+
+    /// A linear layer using the given floating point weights, quantized to int8 if required.
+    pub fn linear_from_weights(&self, weight: Tensor, bias: Option<Tensor>) -> Result<Linear> {
+        match self {
+            Self::Full {
+                int8_linear: true, ..
+            } => Ok(Linear::Int8(W8A8Linear::quantize(&weight, bias)?)),
+            _ => Ok(Linear::Full(nn::Linear::new(weight, bias))),
+        }
+    }
+
+    fn linear_b(&self, in_dim: usize, out_dim: usize, bias: bool) -> Result<Linear> {
+        match self {
+            Self::Full { vb, int8_linear } => {
+                let vb = vb.clone();
+                if *int8_linear {
+                    Ok(Linear::Int8(W8A8Linear::new(in_dim, out_dim, bias, vb)?))
+                } else {
+                    Ok(Linear::Full(nn::linear_b(in_dim, out_dim, bias, vb)?))
+                }
+            }
+            Self::Quantized { vb, dtype } => {
+                let linear = crate::quantized_nn::linear_b(in_dim, out_dim, bias, vb.clone())?;
+                Ok(Linear::Quantized {
+                    linear,
+                    dtype: *dtype,
+                })
+            }
+        }
+    }
+}
+
+/// A linear layer with either floating point, gguf quantized, or int8 weights.
+#[derive(Debug, Clone)]
+pub enum Linear {
+    Full(nn::Linear),
+    Quantized {
+        linear: crate::quantized_nn::Linear,
+        dtype: DType,
+    },
+    Int8(W8A8Linear),
+}
+
+impl Module for Linear {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        match self {
+            Self::Full(l) => l.forward(xs),
+            // The quantized matmuls take f32 activations.
+            Self::Quantized { linear, dtype } => {
+                xs.to_dtype(DType::F32)?.apply(linear)?.to_dtype(*dtype)
+            }
+            Self::Int8(l) => l.forward(xs),
+        }
+    }
+}
+
+pub fn linear(in_dim: usize, out_dim: usize, vb: VarBuilder) -> Result<Linear> {
+    vb.linear_b(in_dim, out_dim, true)
+}
+
+pub fn linear_no_bias(in_dim: usize, out_dim: usize, vb: VarBuilder) -> Result<Linear> {
+    vb.linear_b(in_dim, out_dim, false)
+}
+
+pub fn conv2d(
+    in_channels: usize,
+    out_channels: usize,
+    kernel_size: usize,
+    cfg: nn::Conv2dConfig,
+    vb: VarBuilder,
+) -> Result<Conv2d> {
+    match vb {
+        VarBuilder::Full { vb, .. } => {
+            crate::models::with_tracing::conv2d(in_channels, out_channels, kernel_size, cfg, vb)
+        }
+        vb => {
+            let shape = (
+                out_channels,
+                in_channels / cfg.groups,
+                kernel_size,
+                kernel_size,
+            );
+            let weight = vb.get(shape, "weight")?;
+            let bias = vb.get(out_channels, "bias")?;
+            Ok(Conv2d::from_weights(weight, Some(bias), cfg))
+        }
+    }
+}
+
+pub fn group_norm(
+    num_groups: usize,
+    num_channels: usize,
+    eps: f64,
+    vb: VarBuilder,
+) -> Result<nn::GroupNorm> {
+    match vb {
+        VarBuilder::Full { vb, .. } => nn::group_norm(num_groups, num_channels, eps, vb),
+        vb => {
+            let weight = vb.get(num_channels, "weight")?;
+            let bias = vb.get(num_channels, "bias")?;
+            nn::GroupNorm::new(weight, bias, num_channels, num_groups, eps)
+        }
+    }
+}
+
+pub fn layer_norm(size: usize, eps: f64, vb: VarBuilder) -> Result<nn::LayerNorm> {
+    match vb {
+        VarBuilder::Full { vb, .. } => nn::layer_norm(size, eps, vb),
+        vb => {
+            let weight = vb.get(size, "weight")?;
+            let bias = vb.get(size, "bias")?;
+            Ok(nn::LayerNorm::new(weight, bias, eps))
+        }
+    }
+}
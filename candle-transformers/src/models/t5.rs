@@ -579,6 +579,13 @@ impl T5Attention {
     fn clear_kv_cache(&mut self) {
         self.kv_cache = None
     }
+
+    fn reorder_kv_cache(&mut self, indices: &Tensor) -> Result<()> {
+        if let Some((k, v)) = &self.kv_cache {
+            self.kv_cache = Some((k.index_select(indices, 0)?, v.index_select(indices, 0)?))
+        }
+        Ok(())
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -618,6 +625,10 @@ impl T5LayerSelfAttention {
     fn clear_kv_cache(&mut self) {
         self.self_attention.clear_kv_cache()
     }
+
+    fn reorder_kv_cache(&mut self, indices: &Tensor) -> Result<()> {
+        self.self_attention.reorder_kv_cache(indices)
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -731,6 +742,12 @@ impl T5Block {
         self.self_attn.clear_kv_cache();
         self.cross_attn.iter_mut().for_each(|c| c.clear_kv_cache());
     }
+
+    // Only the self-attention keys and values are cached, the cross-attention ones are computed
+    // from the encoder output.
+    fn reorder_kv_cache(&mut self, indices: &Tensor) -> Result<()> {
+        self.self_attn.reorder_kv_cache(indices)
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -794,6 +811,12 @@ impl T5Stack {
     fn clear_kv_cache(&mut self) {
         self.block.iter_mut().for_each(|b| b.clear_kv_cache())
     }
+
+    fn reorder_kv_cache(&mut self, indices: &Tensor) -> Result<()> {
+        self.block
+            .iter_mut()
+            .try_for_each(|b| b.reorder_kv_cache(indices))
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -849,6 +872,7 @@ pub struct T5ForConditionalGeneration {
     tie_word_embeddings: bool,
     lm_head: Option<Linear>,
     shared: Arc<Embedding>,
+    use_cache: bool,
     device: Device,
     span_decode: tracing::Span,
     span_decode_head: tracing::Span,
@@ -896,6 +920,7 @@ impl T5ForConditionalGeneration {
             tie_word_embeddings,
             lm_head,
             shared,
+            use_cache: cfg.use_cache,
             device: vb.device().clone(),
             span_decode: tracing::span!(tracing::Level::TRACE, "decode"),
             span_decode_head: tracing::span!(tracing::Level::TRACE, "decode-head"),
@@ -946,8 +971,20 @@ impl T5ForConditionalGeneration {
         &self.device
     }
 
+    /// Whether the decoder uses a kv cache, in which case only the new tokens should be passed
+    /// to [`Self::decode`].
+    pub fn use_cache(&self) -> bool {
+        self.use_cache
+    }
+
     pub fn clear_kv_cache(&mut self) {
         self.encoder.clear_kv_cache();
         self.decoder.clear_kv_cache();
     }
+
+    /// Reorders the decoder kv caches along the batch dimension, e.g. when the beams of a beam
+    /// search get pruned. The entry `i` becomes a copy of the entry `indices[i]`.
+    pub fn reorder_kv_cache(&mut self, indices: &Tensor) -> Result<()> {
+        self.decoder.reorder_kv_cache(indices)
+    }
 }
@@ -202,8 +202,9 @@ pub fn log_mel_spectrogram_<T: Float>(
         samples_padded
     };
 
-    // ensure that the number of threads is even and less than 12
-    let n_threads = std::cmp::min(get_num_threads() - get_num_threads() % 2, 12);
+    // ensure that the number of threads is even and less than 12, a single thread is used on
+    // single core machines.
+    let n_threads = std::cmp::min(get_num_threads() - get_num_threads() % 2, 12).max(1);
 
     let hann = Arc::new(hann);
     let samples = Arc::new(samples);
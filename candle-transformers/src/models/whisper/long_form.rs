@@ -0,0 +1,375 @@
+//! Batched transcription of long audio files.
+//!
+//! The mel spectrogram is split in overlapping chunks of 30 seconds which are decoded in
+//! batches, so that a single forward pass of the model handles several chunks at once. The
+//! segments of consecutive chunks are then merged using their timestamps: the middle of the
+//! overlap between two chunks is used as the boundary and segments that were transcribed in both
+//! chunks are only kept once.
+use super::{Config, NO_SPEECH_THRESHOLD};
+use candle::{Result, Tensor, D};
+
+/// The tolerance in seconds used when deduplicating segments that have been transcribed in two
+/// consecutive chunks.
+const MERGE_TOLERANCE_S: f64 = 0.1;
+
+/// The subset of the whisper models used for batched decoding.
+pub trait WhisperModel {
+    fn config(&self) -> &Config;
+    fn encoder_forward(&mut self, x: &Tensor, flush: bool) -> Result<Tensor>;
+    fn decoder_forward(&mut self, x: &Tensor, xa: &Tensor, flush: bool) -> Result<Tensor>;
+    fn decoder_final_linear(&self, x: &Tensor) -> Result<Tensor>;
+}
+
+impl WhisperModel for super::model::Whisper {
+    fn config(&self) -> &Config {
+        &self.config
+    }
+
+    fn encoder_forward(&mut self, x: &Tensor, flush: bool) -> Result<Tensor> {
+        self.encoder.forward(x, flush)
+    }
+
+    fn decoder_forward(&mut self, x: &Tensor, xa: &Tensor, flush: bool) -> Result<Tensor> {
+        self.decoder.forward(x, xa, flush)
+    }
+
+    fn decoder_final_linear(&self, x: &Tensor) -> Result<Tensor> {
+        self.decoder.final_linear(x)
+    }
+}
+
+impl WhisperModel for super::quantized_model::Whisper {
+    fn config(&self) -> &Config {
+        &self.config
+    }
+
+    fn encoder_forward(&mut self, x: &Tensor, flush: bool) -> Result<Tensor> {
+        self.encoder.forward(x, flush)
+    }
+
+    fn decoder_forward(&mut self, x: &Tensor, xa: &Tensor, flush: bool) -> Result<Tensor> {
+        self.decoder.forward(x, xa, flush)
+    }
+
+    fn decoder_final_linear(&self, x: &Tensor) -> Result<Tensor> {
+        self.decoder.final_linear(x)
+    }
+}
+
+/// How the audio gets split in chunks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChunkConfig {
+    /// The number of mel frames in a chunk, at most `N_FRAMES`.
+    pub chunk_frames: usize,
+    /// The number of frames shared by two consecutive chunks.
+    pub overlap_frames: usize,
+    /// The number of chunks decoded in a single batch.
+    pub batch_size: usize,
+}
+
+impl Default for ChunkConfig {
+    fn default() -> Self {
+        Self {
+            chunk_frames: super::N_FRAMES,
+            // 5 seconds overlap.
+            overlap_frames: 500,
+            batch_size: 8,
+        }
+    }
+}
+
+/// A window over the mel frames.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Chunk {
+    pub start_frame: usize,
+    pub num_frames: usize,
+}
+
+fn frames_to_s(frames: usize) -> f64 {
+    (frames * super::HOP_LENGTH) as f64 / super::SAMPLE_RATE as f64
+}
+
+impl Chunk {
+    pub fn start_s(&self) -> f64 {
+        frames_to_s(self.start_frame)
+    }
+
+    pub fn end_s(&self) -> f64 {
+        frames_to_s(self.start_frame + self.num_frames)
+    }
+}
+
+/// Splits `content_frames` mel frames in overlapping chunks.
+pub fn chunks(content_frames: usize, cfg: &ChunkConfig) -> Result<Vec<Chunk>> {
+    if cfg.chunk_frames == 0 || cfg.chunk_frames > super::N_FRAMES {
+        candle::bail!(
+            "chunk_frames should be between 1 and {}, got {}",
+            super::N_FRAMES,
+            cfg.chunk_frames
+        )
+    }
+    if cfg.overlap_frames >= cfg.chunk_frames {
+        candle::bail!(
+            "overlap_frames {} should be smaller than chunk_frames {}",
+            cfg.overlap_frames,
+            cfg.chunk_frames
+        )
+    }
+    let stride = cfg.chunk_frames - cfg.overlap_frames;
+    let mut chunks = vec![];
+    let mut start_frame = 0;
+    while start_frame < content_frames {
+        let num_frames = usize::min(cfg.chunk_frames, content_frames - start_frame);
+        chunks.push(Chunk {
+            start_frame,
+            num_frames,
+        });
+        if start_frame + num_frames >= content_frames {
+            break;
+        }
+        start_frame += stride
+    }
+    Ok(chunks)
+}
+
+/// A transcribed segment, times are in seconds from the beginning of the audio.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TimedSegment {
+    pub start: f64,
+    pub end: f64,
+    /// The text tokens of the segment, special and timestamp tokens are not included.
+    pub tokens: Vec<u32>,
+    /// Whether the segment was closed by a timestamp token, segments that are cut by the end of
+    /// their chunk are not complete.
+    pub complete: bool,
+}
+
+/// The special tokens used when decoding.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DecodeOptions {
+    /// The tokens used to start each sequence, e.g. sot, language and task tokens. This must
+    /// not contain the no-timestamps token as timestamps are used to merge the chunks.
+    pub prompt: Vec<u32>,
+    pub eot_token: u32,
+    pub no_speech_token: u32,
+    /// The first timestamp token, i.e. `<|0.00|>`. Each subsequent token adds 20ms.
+    pub timestamp_begin: u32,
+    pub suppress_tokens: Vec<u32>,
+    /// The maximum number of tokens generated per chunk.
+    pub max_tokens: usize,
+}
+
+/// The decoded tokens for a chunk.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChunkTranscript {
+    pub chunk: Chunk,
+    /// The generated tokens, the prompt and the final eot token are not included.
+    pub tokens: Vec<u32>,
+    pub no_speech_prob: f64,
+}
+
+/// Greedily decodes a batch of mel spectrograms of shape `(batch, n_mels, frames)`, returning
+/// the generated tokens for each element of the batch together with the no-speech probability.
+pub fn decode_batch<M: WhisperModel>(
+    model: &mut M,
+    mel: &Tensor,
+    opts: &DecodeOptions,
+) -> Result<Vec<(Vec<u32>, f64)>> {
+    let (b_size, _, _) = mel.dims3()?;
+    let device = mel.device();
+    let vocab_size = model.config().vocab_size;
+    let max_target_positions = model.config().max_target_positions;
+    let mut suppress = vec![0f32; vocab_size];
+    for &t in opts.suppress_tokens.iter() {
+        if let Some(s) = suppress.get_mut(t as usize) {
+            *s = f32::NEG_INFINITY
+        }
+    }
+    let suppress = Tensor::from_vec(suppress, vocab_size, device)?;
+    let audio_features = model.encoder_forward(mel, true)?;
+
+    let prompt_len = opts.prompt.len();
+    let mut tokens: Vec<u32> = opts.prompt.repeat(b_size);
+    let mut generated: Vec<Vec<u32>> = vec![vec![]; b_size];
+    let mut finished = vec![false; b_size];
+    let mut no_speech_probs = vec![f64::NAN; b_size];
+    for i in 0..opts.max_tokens {
+        let seq_len = prompt_len + i;
+        if seq_len >= max_target_positions {
+            break;
+        }
+        let tokens_t = Tensor::from_slice(&tokens, (b_size, seq_len), device)?;
+        let ys = model.decoder_forward(&tokens_t, &audio_features, i == 0)?;
+        if i == 0 {
+            // The no-speech probability is given by the logits at the sot position.
+            let logits = model
+                .decoder_final_linear(&ys.narrow(1, 0, 1)?)?
+                .squeeze(1)?;
+            let probs = candle_nn::ops::softmax_last_dim(&logits)?
+                .narrow(D::Minus1, opts.no_speech_token as usize, 1)?
+                .squeeze(1)?
+                .to_dtype(candle::DType::F64)?
+                .to_vec1::<f64>()?;
+            no_speech_probs = probs;
+        }
+        let logits = model
+            .decoder_final_linear(&ys.narrow(1, seq_len - 1, 1)?)?
+            .squeeze(1)?
+            .broadcast_add(&suppress)?;
+        let next_tokens = logits.argmax(D::Minus1)?.to_vec1::<u32>()?;
+        let mut new_tokens = Vec::with_capacity(b_size * (seq_len + 1));
+        for (b, &next_token) in next_tokens.iter().enumerate() {
+            new_tokens.extend_from_slice(&tokens[b * seq_len..(b + 1) * seq_len]);
+            if finished[b] {
+                new_tokens.push(opts.eot_token);
+                continue;
+            }
+            new_tokens.push(next_token);
+            if next_token == opts.eot_token {
+                finished[b] = true
+            } else {
+                generated[b].push(next_token)
+            }
+        }
+        tokens = new_tokens;
+        if finished.iter().all(|&f| f) {
+            break;
+        }
+    }
+    Ok(generated.into_iter().zip(no_speech_probs).collect())
+}
+
+/// Splits the tokens generated for a chunk in segments using the timestamp tokens.
+///
+/// Tokens from `eot_token` onwards are considered as special tokens and are not included in the
+/// segments. When no timestamp is present, a single segment covering the chunk is returned.
+pub fn segments_from_tokens(
+    tokens: &[u32],
+    chunk: &Chunk,
+    eot_token: u32,
+    timestamp_begin: u32,
+) -> Vec<TimedSegment> {
+    let offset = chunk.start_s();
+    let chunk_end = chunk.end_s();
+    let mut segments = vec![];
+    let mut start = None;
+    let mut text = vec![];
+    let mut has_timestamps = false;
+    for &token in tokens.iter() {
+        if token >= timestamp_begin {
+            has_timestamps = true;
+            let ts = f64::min(offset + (token - timestamp_begin) as f64 / 50., chunk_end);
+            match start {
+                Some(start_ts) if !text.is_empty() => {
+                    segments.push(TimedSegment {
+                        start: start_ts,
+                        end: f64::max(ts, start_ts),
+                        tokens: std::mem::take(&mut text),
+                        complete: true,
+                    });
+                    start = None
+                }
+                _ => start = Some(ts),
+            }
+        } else if token < eot_token {
+            if start.is_none() {
+                // Text without an opening timestamp starts where the previous segment ended.
+                start = Some(segments.last().map_or(offset, |s: &TimedSegment| s.end))
+            }
+            text.push(token)
+        }
+    }
+    if !text.is_empty() {
+        segments.push(TimedSegment {
+            start: start.unwrap_or(offset),
+            end: chunk_end,
+            tokens: text,
+            complete: !has_timestamps,
+        })
+    }
+    segments
+}
+
+/// Merges the segments of consecutive overlapping chunks, the chunks must be sorted by start
+/// frame.
+pub fn merge_chunks(chunks: &[(Chunk, Vec<TimedSegment>)]) -> Vec<TimedSegment> {
+    let mut merged: Vec<TimedSegment> = vec![];
+    for (i, (chunk, segments)) in chunks.iter().enumerate() {
+        let next = chunks.get(i + 1).map(|(c, _)| c);
+        // Segments starting after the middle of the overlap with the next chunk are taken from
+        // the next chunk.
+        let upper = next.map_or(f64::INFINITY, |n| (n.start_s() + chunk.end_s()) / 2.);
+        let lower = merged.last().map_or(f64::NEG_INFINITY, |s| s.end);
+        for segment in segments.iter() {
+            if segment.start + MERGE_TOLERANCE_S < lower || segment.start >= upper {
+                continue;
+            }
+            if let Some(next) = next {
+                // A segment cut by the end of the chunk is transcribed again by the next chunk
+                // if it starts within the overlap.
+                if !segment.complete && segment.start >= next.start_s() {
+                    continue;
+                }
+            }
+            merged.push(segment.clone())
+        }
+    }
+    merged
+}
+
+/// Transcribes a mel spectrogram of shape `(1, n_mels, frames)` of arbitrary length.
+///
+/// The chunks are padded with silence to the same length and decoded in batches of
+/// `cfg.batch_size`. Chunks that are unlikely to contain speech are skipped.
+pub fn transcribe<M: WhisperModel>(
+    model: &mut M,
+    mel: &Tensor,
+    cfg: &ChunkConfig,
+    opts: &DecodeOptions,
+) -> Result<Vec<TimedSegment>> {
+    let (_, n_mels, content_frames) = mel.dims3()?;
+    let no_timestamps_token = opts.timestamp_begin - 1;
+    if cfg.overlap_frames > 0 && opts.prompt.contains(&no_timestamps_token) {
+        candle::bail!("timestamps are required to merge overlapping chunks")
+    }
+    let chunks = chunks(content_frames, cfg)?;
+    // Padding uses the minimum value of the spectrogram which corresponds to silence.
+    let pad_value = mel.min_keepdim(2)?.min_keepdim(1)?;
+    let mut transcripts = Vec::with_capacity(chunks.len());
+    for batch in chunks.chunks(cfg.batch_size.max(1)) {
+        let mels = batch
+            .iter()
+            .map(|c| {
+                let xs = mel.narrow(2, c.start_frame, c.num_frames)?;
+                let pad = cfg.chunk_frames - c.num_frames;
+                if pad == 0 {
+                    Ok(xs)
+                } else {
+                    let pad = pad_value.broadcast_as((1, n_mels, pad))?;
+                    Tensor::cat(&[&xs, &pad], 2)
+                }
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let mels = Tensor::cat(&mels, 0)?;
+        let decoded = decode_batch(model, &mels, opts)?;
+        for (chunk, (tokens, no_speech_prob)) in batch.iter().zip(decoded) {
+            transcripts.push(ChunkTranscript {
+                chunk: *chunk,
+                tokens,
+                no_speech_prob,
+            })
+        }
+    }
+    let segments = transcripts
+        .iter()
+        .map(|t| {
+            let segments = if t.no_speech_prob > NO_SPEECH_THRESHOLD {
+                vec![]
+            } else {
+                segments_from_tokens(&t.tokens, &t.chunk, opts.eot_token, opts.timestamp_begin)
+            };
+            (t.chunk, segments)
+        })
+        .collect::<Vec<_>>();
+    Ok(merge_chunks(&segments))
+}
@@ -11,6 +11,7 @@
 //!
 //!
 pub mod audio;
+pub mod long_form;
 pub mod model;
 pub mod quantized_model;
 
@@ -79,6 +79,18 @@ pub struct Conv2d {
     span: tracing::Span,
 }
 
+impl Conv2d {
+    pub fn from_weights(
+        weight: Tensor,
+        bias: Option<Tensor>,
+        cfg: candle_nn::Conv2dConfig,
+    ) -> Self {
+        let inner = candle_nn::Conv2d::new(weight, bias, cfg);
+        let span = tracing::span!(tracing::Level::TRACE, "conv2d");
+        Self { inner, span }
+    }
+}
+
 impl Module for Conv2d {
     fn forward(&self, x: &Tensor) -> Result<Tensor> {
         let _enter = self.span.enter();
@@ -1 +1,3 @@
+pub mod seq2seq;
 pub mod text_generation;
+pub mod translation;
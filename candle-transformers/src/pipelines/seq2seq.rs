@@ -0,0 +1,365 @@
+//! Generation with encoder-decoder models such as t5, marian or bart.
+//!
+//! Models are plugged in through the [`Seq2SeqModel`] trait. The pipeline caches the encoder
+//! outputs, and supports greedy decoding, sampling, and beam search with a length penalty.
+//! The generated length can be bounded with `min_new_tokens` and `max_new_tokens`, and repeated
+//! n-grams can be blocked, which is commonly used for summarization.
+use crate::generation::beam_search::{beam_search, BeamSearchConfig, BeamSearchModel};
+use crate::generation::{LogitsProcessor, Sampling};
+use candle::{DType, Device, Result, Tensor};
+use std::collections::VecDeque;
+
+/// An encoder-decoder model with a kv cache in the decoder.
+pub trait Seq2SeqModel {
+    /// Runs the encoder on `input_ids` of shape `(batch, seq_len)`.
+    fn encode(&mut self, input_ids: &Tensor) -> Result<Tensor>;
+
+    /// Returns the logits for the next token, of shape `(batch, vocab_size)`.
+    ///
+    /// `decoder_input_ids` contains the whole decoded sequences so far, the first `seqlen_offset`
+    /// tokens have already been processed and are in the kv cache.
+    fn decode(
+        &mut self,
+        decoder_input_ids: &Tensor,
+        encoder_output: &Tensor,
+        seqlen_offset: usize,
+    ) -> Result<Tensor>;
+
+    fn reset_kv_cache(&mut self);
+
+    /// Reorders the decoder kv caches along the batch dimension, the new entry `i` is a copy of
+    /// the entry `indices[i]`. This is used by beam search when the beams get pruned.
+    fn reorder_kv_cache(&mut self, indices: &Tensor) -> Result<()>;
+}
+
+impl Seq2SeqModel for crate::models::marian::MTModel {
+    fn encode(&mut self, input_ids: &Tensor) -> Result<Tensor> {
+        self.encoder().forward(input_ids, 0)
+    }
+
+    fn decode(
+        &mut self,
+        decoder_input_ids: &Tensor,
+        encoder_output: &Tensor,
+        seqlen_offset: usize,
+    ) -> Result<Tensor> {
+        let seq_len = decoder_input_ids.dim(1)?;
+        let xs = decoder_input_ids.narrow(1, seqlen_offset, seq_len - seqlen_offset)?;
+        let logits =
+            crate::models::marian::MTModel::decode(self, &xs, encoder_output, seqlen_offset)?;
+        logits.narrow(1, logits.dim(1)? - 1, 1)?.squeeze(1)
+    }
+
+    fn reset_kv_cache(&mut self) {
+        crate::models::marian::MTModel::reset_kv_cache(self)
+    }
+
+    fn reorder_kv_cache(&mut self, indices: &Tensor) -> Result<()> {
+        crate::models::marian::MTModel::reorder_kv_cache(self, indices)
+    }
+}
+
+impl Seq2SeqModel for crate::models::t5::T5ForConditionalGeneration {
+    fn encode(&mut self, input_ids: &Tensor) -> Result<Tensor> {
+        crate::models::t5::T5ForConditionalGeneration::encode(self, input_ids)
+    }
+
+    fn decode(
+        &mut self,
+        decoder_input_ids: &Tensor,
+        encoder_output: &Tensor,
+        seqlen_offset: usize,
+    ) -> Result<Tensor> {
+        let xs = if self.use_cache() {
+            let seq_len = decoder_input_ids.dim(1)?;
+            decoder_input_ids.narrow(1, seqlen_offset, seq_len - seqlen_offset)?
+        } else {
+            decoder_input_ids.clone()
+        };
+        crate::models::t5::T5ForConditionalGeneration::decode(self, &xs, encoder_output)
+    }
+
+    fn reset_kv_cache(&mut self) {
+        self.clear_kv_cache()
+    }
+
+    fn reorder_kv_cache(&mut self, indices: &Tensor) -> Result<()> {
+        crate::models::t5::T5ForConditionalGeneration::reorder_kv_cache(self, indices)
+    }
+}
+
+/// The generation settings, the defaults match the ones used by the `transformers` library.
+#[derive(Debug, Clone, PartialEq)]
+pub struct GenerationConfig {
+    pub decoder_start_token_id: u32,
+    pub eos_token_id: u32,
+    /// The eos token cannot be generated before this number of new tokens.
+    pub min_new_tokens: usize,
+    pub max_new_tokens: usize,
+    /// The number of beams, greedy decoding or sampling is used when set to 1.
+    pub num_beams: usize,
+    /// The exponent applied to the number of generated tokens when normalizing the beam scores,
+    /// values above 0 favor longer sequences and values below 0 shorter ones.
+    pub length_penalty: f64,
+    /// When positive, n-grams of this size can only occur once in the generated tokens.
+    pub no_repeat_ngram_size: usize,
+}
+
+impl GenerationConfig {
+    pub fn new(decoder_start_token_id: u32, eos_token_id: u32) -> Self {
+        Self {
+            decoder_start_token_id,
+            eos_token_id,
+            min_new_tokens: 0,
+            max_new_tokens: 128,
+            num_beams: 1,
+            length_penalty: 1.0,
+            no_repeat_ngram_size: 0,
+        }
+    }
+
+    pub fn marian(cfg: &crate::models::marian::Config) -> Self {
+        Self::new(cfg.decoder_start_token_id, cfg.eos_token_id)
+    }
+
+    pub fn t5(cfg: &crate::models::t5::Config) -> Self {
+        let decoder_start_token_id = cfg.decoder_start_token_id.unwrap_or(cfg.pad_token_id);
+        Self::new(decoder_start_token_id as u32, cfg.eos_token_id as u32)
+    }
+}
+
+/// Returns the tokens that would complete an n-gram already present in `tokens`.
+pub fn banned_ngram_tokens(tokens: &[u32], ngram_size: usize) -> Vec<u32> {
+    if ngram_size == 0 || tokens.len() < ngram_size {
+        return vec![];
+    }
+    let prefix = &tokens[tokens.len() + 1 - ngram_size..];
+    tokens
+        .windows(ngram_size)
+        .filter(|w| &w[..ngram_size - 1] == prefix)
+        .map(|w| w[ngram_size - 1])
+        .collect()
+}
+
+/// A bounded cache of encoder outputs, keyed by the input ids.
+///
+/// Generating multiple times from the same input, e.g. with different generation settings,
+/// only runs the encoder once.
+#[derive(Debug, Clone)]
+pub struct EncoderCache {
+    capacity: usize,
+    entries: VecDeque<(Vec<u32>, Tensor)>,
+}
+
+impl EncoderCache {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            entries: VecDeque::new(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear()
+    }
+
+    /// Returns the cached encoder output for `input_ids`, running `f` to compute it if needed.
+    /// The least recently used entry is evicted when the cache is full.
+    pub fn get_or_insert_with<F: FnOnce() -> Result<Tensor>>(
+        &mut self,
+        input_ids: &[u32],
+        f: F,
+    ) -> Result<Tensor> {
+        if let Some(pos) = self.entries.iter().position(|(ids, _)| ids == input_ids) {
+            let entry = self.entries.remove(pos).unwrap();
+            let encoder_output = entry.1.clone();
+            self.entries.push_back(entry);
+            return Ok(encoder_output);
+        }
+        let encoder_output = f()?;
+        if self.capacity > 0 {
+            if self.entries.len() >= self.capacity {
+                self.entries.pop_front();
+            }
+            self.entries
+                .push_back((input_ids.to_vec(), encoder_output.clone()));
+        }
+        Ok(encoder_output)
+    }
+}
+
+/// Applies the min length and n-gram constraints to the logits of a sequence, `tokens` includes
+/// the decoder start token.
+fn constrain(config: &GenerationConfig, logits: &mut [f32], tokens: &[u32]) {
+    let eos = config.eos_token_id as usize;
+    if tokens.len() - 1 < config.min_new_tokens && eos < logits.len() {
+        logits[eos] = f32::NEG_INFINITY
+    }
+    for token in banned_ngram_tokens(tokens, config.no_repeat_ngram_size) {
+        if let Some(l) = logits.get_mut(token as usize) {
+            *l = f32::NEG_INFINITY
+        }
+    }
+}
+
+// Runs the decoder on the beams of [`beam_search`], the decoded sequences and the encoder
+// outputs are reordered together with the kv caches.
+struct BeamDecoder<'a, M> {
+    model: &'a mut M,
+    config: &'a GenerationConfig,
+    encoder_output: Tensor,
+    sequences: Vec<Vec<u32>>,
+}
+
+impl<M: Seq2SeqModel> BeamSearchModel for BeamDecoder<'_, M> {
+    fn forward(&mut self, tokens: &Tensor, seqlen_offset: usize) -> Result<Tensor> {
+        let tokens = tokens.to_vec2::<u32>()?;
+        self.sequences.resize(tokens.len(), vec![]);
+        for (sequence, tokens) in self.sequences.iter_mut().zip(tokens) {
+            sequence.extend(tokens)
+        }
+        let (b_size, seq_len) = (self.sequences.len(), self.sequences[0].len());
+        let ids = self.sequences.concat();
+        let ids = Tensor::from_vec(ids, (b_size, seq_len), self.encoder_output.device())?;
+        let logits = self
+            .model
+            .decode(&ids, &self.encoder_output, seqlen_offset)?;
+        let mut logits = logits.to_dtype(DType::F32)?.to_vec2::<f32>()?;
+        for (logits, sequence) in logits.iter_mut().zip(self.sequences.iter()) {
+            constrain(self.config, logits, sequence)
+        }
+        Tensor::new(logits, self.encoder_output.device())
+    }
+
+    fn reorder_cache(&mut self, indices: &Tensor) -> Result<()> {
+        self.sequences = indices
+            .to_vec1::<u32>()?
+            .into_iter()
+            .map(|i| self.sequences[i as usize].clone())
+            .collect();
+        self.encoder_output = self.encoder_output.index_select(indices, 0)?;
+        self.model.reorder_kv_cache(indices)
+    }
+}
+
+/// Generation with an encoder-decoder model.
+///
+/// Any model implementing [`Seq2SeqModel`] can be used, e.g. t5 or marian.
+pub struct Seq2SeqPipeline<M> {
+    model: M,
+    config: GenerationConfig,
+    logits_processor: LogitsProcessor,
+    encoder_cache: EncoderCache,
+    device: Device,
+}
+
+impl<M: Seq2SeqModel> Seq2SeqPipeline<M> {
+    pub fn new(model: M, config: GenerationConfig, device: &Device) -> Self {
+        Self {
+            model,
+            config,
+            logits_processor: LogitsProcessor::from_sampling(0, Sampling::ArgMax),
+            encoder_cache: EncoderCache::new(8),
+            device: device.clone(),
+        }
+    }
+
+    /// Sets the sampling used when `num_beams` is 1, the default is greedy decoding.
+    pub fn with_sampling(mut self, seed: u64, sampling: Sampling) -> Self {
+        self.logits_processor = LogitsProcessor::from_sampling(seed, sampling);
+        self
+    }
+
+    pub fn with_encoder_cache_capacity(mut self, capacity: usize) -> Self {
+        self.encoder_cache = EncoderCache::new(capacity);
+        self
+    }
+
+    pub fn config(&self) -> &GenerationConfig {
+        &self.config
+    }
+
+    pub fn config_mut(&mut self) -> &mut GenerationConfig {
+        &mut self.config
+    }
+
+    pub fn model(&mut self) -> &mut M {
+        &mut self.model
+    }
+
+    pub fn encoder_cache(&mut self) -> &mut EncoderCache {
+        &mut self.encoder_cache
+    }
+
+    fn encode(&mut self, input_ids: &[u32]) -> Result<Tensor> {
+        let model = &mut self.model;
+        let device = &self.device;
+        self.encoder_cache.get_or_insert_with(input_ids, || {
+            let input_ids = Tensor::new(input_ids, device)?.unsqueeze(0)?;
+            model.encode(&input_ids)
+        })
+    }
+
+    /// Generates the output tokens for some input tokens, the returned tokens do not include the
+    /// decoder start token and the final eos token.
+    pub fn generate(&mut self, input_ids: &[u32]) -> Result<Vec<u32>> {
+        let encoder_output = self.encode(input_ids)?;
+        if self.config.num_beams > 1 {
+            self.beam_search(&encoder_output)
+        } else {
+            self.sample(&encoder_output)
+        }
+    }
+
+    fn sample(&mut self, encoder_output: &Tensor) -> Result<Vec<u32>> {
+        self.model.reset_kv_cache();
+        let mut tokens = vec![self.config.decoder_start_token_id];
+        for index in 0..self.config.max_new_tokens {
+            let ids = Tensor::new(tokens.as_slice(), &self.device)?.unsqueeze(0)?;
+            let seqlen_offset = if index == 0 { 0 } else { tokens.len() - 1 };
+            let logits = self.model.decode(&ids, encoder_output, seqlen_offset)?;
+            let logits = logits.squeeze(0)?.to_dtype(DType::F32)?;
+            let mut logits = logits.to_vec1::<f32>()?;
+            constrain(&self.config, &mut logits, &tokens);
+            let logits = Tensor::new(logits, &self.device)?;
+            let next_token = self.logits_processor.sample(&logits)?;
+            if next_token == self.config.eos_token_id {
+                break;
+            }
+            tokens.push(next_token)
+        }
+        Ok(tokens[1..].to_vec())
+    }
+
+    fn beam_search(&mut self, encoder_output: &Tensor) -> Result<Vec<u32>> {
+        let config = &self.config;
+        let beam_config = BeamSearchConfig::new(config.num_beams, config.max_new_tokens)
+            .with_eos_tokens(vec![config.eos_token_id])
+            .with_length_penalty(config.length_penalty);
+        self.model.reset_kv_cache();
+        let mut decoder = BeamDecoder {
+            model: &mut self.model,
+            config,
+            encoder_output: encoder_output.clone(),
+            sequences: vec![],
+        };
+        let prompt = [config.decoder_start_token_id];
+        let hypotheses = beam_search(&mut decoder, &prompt, &beam_config, &self.device)?;
+        let mut tokens = match hypotheses.into_iter().next() {
+            Some(best) => best.tokens,
+            None => candle::bail!("beam search did not produce any hypothesis"),
+        };
+        if tokens.last() == Some(&config.eos_token_id) {
+            tokens.pop();
+        }
+        Ok(tokens)
+    }
+}
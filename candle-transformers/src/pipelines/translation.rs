@@ -0,0 +1,355 @@
+//! Translation of documents with encoder-decoder models.
+//!
+//! The pipeline takes care of the conventions used by the different translation models to
+//! select the languages, e.g. a `>>fra<<` prefix for multilingual opus-mt models, a task prefix
+//! for t5, or a forced first decoder token for m2m100 and nllb. Documents are split in sentences
+//! which are translated in batches and reassembled, preserving the whitespace between sentences.
+//!
+//! Padding is not supported by the encoders, so only sentences with the same number of tokens
+//! are batched together.
+use candle::{Device, Result, Tensor, D};
+use std::collections::BTreeMap;
+
+pub use super::seq2seq::Seq2SeqModel;
+
+/// The tokenization used by the pipeline, this is typically implemented by wrapping a
+/// `tokenizers::Tokenizer`.
+pub trait TextTokenizer {
+    /// Encodes some text, special tokens such as eos should not be added.
+    fn encode(&self, text: &str) -> Result<Vec<u32>>;
+    fn decode(&self, ids: &[u32]) -> Result<String>;
+    fn token_to_id(&self, token: &str) -> Option<u32>;
+}
+
+/// How the source and target languages are selected.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct LanguageConfig {
+    /// Some text added before each source sentence.
+    pub source_prefix: String,
+    /// A token forced as the first generated token, after the decoder start token.
+    pub forced_bos_token: Option<String>,
+    /// A token added before each tokenized source sentence.
+    pub source_language_token: Option<String>,
+}
+
+impl LanguageConfig {
+    /// Single pair models such as most opus-mt models, the languages are fixed by the model.
+    pub fn fixed() -> Self {
+        Self::default()
+    }
+
+    /// Multilingual opus-mt models, e.g. `opus-mt-en-roa`, `target` is a language code such as
+    /// `fra`.
+    pub fn marian_multilingual(target: &str) -> Self {
+        Self {
+            source_prefix: format!(">>{target}<< "),
+            ..Self::default()
+        }
+    }
+
+    /// T5 models, the languages are given in plain english, e.g. `English` and `German`.
+    pub fn t5(source: &str, target: &str) -> Self {
+        Self {
+            source_prefix: format!("translate {source} to {target}: "),
+            ..Self::default()
+        }
+    }
+
+    /// Madlad400 models, `target` is a language code such as `fr`.
+    pub fn madlad(target: &str) -> Self {
+        Self {
+            source_prefix: format!("<2{target}> "),
+            ..Self::default()
+        }
+    }
+
+    /// M2M100 models, `source` and `target` are language codes such as `en` and `fr`.
+    pub fn m2m100(source: &str, target: &str) -> Self {
+        Self {
+            source_prefix: String::new(),
+            forced_bos_token: Some(format!("__{target}__")),
+            source_language_token: Some(format!("__{source}__")),
+        }
+    }
+
+    /// NLLB models, `source` and `target` are flores-200 codes such as `eng_Latn`.
+    pub fn nllb(source: &str, target: &str) -> Self {
+        Self {
+            source_prefix: String::new(),
+            forced_bos_token: Some(target.to_string()),
+            source_language_token: Some(source.to_string()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TranslationConfig {
+    pub language: LanguageConfig,
+    pub decoder_start_token_id: u32,
+    pub eos_token_id: u32,
+    /// The number of sentences translated together.
+    pub batch_size: usize,
+    /// The maximum number of tokens generated per sentence.
+    pub max_new_tokens: usize,
+}
+
+impl TranslationConfig {
+    pub fn marian(cfg: &crate::models::marian::Config, language: LanguageConfig) -> Self {
+        Self {
+            language,
+            decoder_start_token_id: cfg.decoder_start_token_id,
+            eos_token_id: cfg.eos_token_id,
+            batch_size: 16,
+            max_new_tokens: cfg.max_position_embeddings,
+        }
+    }
+
+    pub fn t5(cfg: &crate::models::t5::Config, language: LanguageConfig) -> Self {
+        Self {
+            language,
+            decoder_start_token_id: cfg.decoder_start_token_id.unwrap_or(cfg.pad_token_id) as u32,
+            eos_token_id: cfg.eos_token_id as u32,
+            batch_size: 16,
+            max_new_tokens: 512,
+        }
+    }
+}
+
+/// A sentence and the whitespace that follows it in the original document.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Sentence<'a> {
+    pub text: &'a str,
+    pub separator: &'a str,
+}
+
+/// Splits a document in sentences.
+///
+/// A sentence ends on `.`, `!`, `?` or their full-width variants when followed by whitespace,
+/// and on line breaks. A `.` following a single letter, as in initials, does not end a
+/// sentence. Concatenating the sentences and their separators gives back the original text,
+/// except for the leading whitespace that is omitted.
+pub fn split_sentences(text: &str) -> Vec<Sentence<'_>> {
+    let text = text.trim_start();
+    let mut sentences = vec![];
+    let chars: Vec<(usize, char)> = text.char_indices().collect();
+    let mut start = 0;
+    let mut i = 0;
+    while i < chars.len() {
+        let (pos, c) = chars[i];
+        let next_is_space = chars.get(i + 1).is_none_or(|(_, c)| c.is_whitespace());
+        let is_end = match c {
+            '\n' => true,
+            '!' | '?' | '。' | '！' | '？' => next_is_space,
+            '.' => {
+                let single_letter = i >= 1
+                    && chars[i - 1].1.is_alphabetic()
+                    && (i < 2 || !chars[i - 2].1.is_alphanumeric());
+                next_is_space && !single_letter
+            }
+            _ => false,
+        };
+        if !is_end {
+            i += 1;
+            continue;
+        }
+        let end = if c == '\n' { pos } else { pos + c.len_utf8() };
+        // Extend the separator over the following whitespace.
+        let mut j = if c == '\n' { i } else { i + 1 };
+        while j < chars.len() && chars[j].1.is_whitespace() {
+            j += 1
+        }
+        let sep_end = chars.get(j).map_or(text.len(), |(p, _)| *p);
+        sentences.push(Sentence {
+            text: &text[start..end],
+            separator: &text[end..sep_end],
+        });
+        start = sep_end;
+        i = j;
+    }
+    if start < text.len() {
+        let rest = &text[start..];
+        let trimmed = rest.trim_end();
+        sentences.push(Sentence {
+            text: trimmed,
+            separator: &rest[trimmed.len()..],
+        })
+    }
+    sentences
+}
+
+/// Greedy batched decoding, the source sequences must all have the same length.
+///
+/// Returns the generated tokens for each sequence, the forced bos and eos tokens are not
+/// included.
+pub fn generate_batch<M: Seq2SeqModel>(
+    model: &mut M,
+    sources: &[Vec<u32>],
+    forced_bos_token_id: Option<u32>,
+    cfg: &TranslationConfig,
+    device: &Device,
+) -> Result<Vec<Vec<u32>>> {
+    let b_size = sources.len();
+    if b_size == 0 {
+        return Ok(vec![]);
+    }
+    let src_len = sources[0].len();
+    if sources.iter().any(|s| s.len() != src_len) {
+        candle::bail!("all the sources in a batch must have the same length")
+    }
+    model.reset_kv_cache();
+    let input_ids = Tensor::new(sources.concat(), device)?.reshape((b_size, src_len))?;
+    let encoder_output = model.encode(&input_ids)?;
+    let mut prompt = vec![cfg.decoder_start_token_id];
+    prompt.extend(forced_bos_token_id);
+    let mut tokens: Vec<Vec<u32>> = vec![prompt.clone(); b_size];
+    let mut finished = vec![false; b_size];
+    let mut seqlen_offset = 0;
+    for _ in 0..cfg.max_new_tokens {
+        let seq_len = tokens[0].len();
+        let ids = Tensor::new(tokens.concat(), device)?.reshape((b_size, seq_len))?;
+        let logits = model.decode(&ids, &encoder_output, seqlen_offset)?;
+        seqlen_offset = seq_len;
+        let next_tokens = logits.argmax(D::Minus1)?.to_vec1::<u32>()?;
+        for (b, next_token) in next_tokens.into_iter().enumerate() {
+            // Finished sequences keep getting eos tokens so that all the sequences have the
+            // same length.
+            let next_token = if finished[b] {
+                cfg.eos_token_id
+            } else {
+                next_token
+            };
+            finished[b] |= next_token == cfg.eos_token_id;
+            tokens[b].push(next_token)
+        }
+        if finished.iter().all(|&f| f) {
+            break;
+        }
+    }
+    let generated = tokens
+        .into_iter()
+        .map(|t| {
+            t.into_iter()
+                .skip(prompt.len())
+                .take_while(|&t| t != cfg.eos_token_id)
+                .collect()
+        })
+        .collect();
+    Ok(generated)
+}
+
+fn token_id<T: TextTokenizer>(tokenizer: &T, token: &str) -> Result<u32> {
+    match tokenizer.token_to_id(token) {
+        None => candle::bail!("no token-id for {token}"),
+        Some(id) => Ok(id),
+    }
+}
+
+pub struct TranslationPipeline<M, S, T> {
+    model: M,
+    source_tokenizer: S,
+    target_tokenizer: T,
+    source_language_token_id: Option<u32>,
+    forced_bos_token_id: Option<u32>,
+    config: TranslationConfig,
+    device: Device,
+}
+
+impl<M: Seq2SeqModel, S: TextTokenizer, T: TextTokenizer> TranslationPipeline<M, S, T> {
+    /// Creates a pipeline, the source and target tokenizers are the same for most models but
+    /// opus-mt models use separate ones.
+    pub fn new(
+        model: M,
+        source_tokenizer: S,
+        target_tokenizer: T,
+        config: TranslationConfig,
+        device: &Device,
+    ) -> Result<Self> {
+        let source_language_token_id = match &config.language.source_language_token {
+            None => None,
+            Some(token) => Some(token_id(&source_tokenizer, token)?),
+        };
+        let forced_bos_token_id = match &config.language.forced_bos_token {
+            None => None,
+            Some(token) => Some(token_id(&target_tokenizer, token)?),
+        };
+        Ok(Self {
+            model,
+            source_tokenizer,
+            target_tokenizer,
+            source_language_token_id,
+            forced_bos_token_id,
+            config,
+            device: device.clone(),
+        })
+    }
+
+    pub fn config(&self) -> &TranslationConfig {
+        &self.config
+    }
+
+    pub fn model(&mut self) -> &mut M {
+        &mut self.model
+    }
+
+    fn source_tokens(&self, sentence: &str) -> Result<Vec<u32>> {
+        let text = format!("{}{sentence}", self.config.language.source_prefix);
+        let mut tokens: Vec<u32> = self.source_language_token_id.into_iter().collect();
+        tokens.extend(self.source_tokenizer.encode(&text)?);
+        tokens.push(self.config.eos_token_id);
+        Ok(tokens)
+    }
+
+    /// Translates some sentences, each sentence is translated independently.
+    pub fn translate_sentences(&mut self, sentences: &[&str]) -> Result<Vec<String>> {
+        let sources = sentences
+            .iter()
+            .map(|s| self.source_tokens(s))
+            .collect::<Result<Vec<_>>>()?;
+        // Group the sentences by length, the groups are ordered by length to get deterministic
+        // batches.
+        let mut by_len: BTreeMap<usize, Vec<usize>> = BTreeMap::new();
+        for (i, s) in sources.iter().enumerate() {
+            by_len.entry(s.len()).or_default().push(i)
+        }
+        let mut translations = vec![String::new(); sentences.len()];
+        for indexes in by_len.values() {
+            for batch in indexes.chunks(self.config.batch_size.max(1)) {
+                let batch_sources: Vec<Vec<u32>> =
+                    batch.iter().map(|&i| sources[i].clone()).collect();
+                let generated = generate_batch(
+                    &mut self.model,
+                    &batch_sources,
+                    self.forced_bos_token_id,
+                    &self.config,
+                    &self.device,
+                )?;
+                for (&i, tokens) in batch.iter().zip(generated) {
+                    translations[i] = self.target_tokenizer.decode(&tokens)?
+                }
+            }
+        }
+        Ok(translations)
+    }
+
+    /// Translates a document, the document is split in sentences that are translated in
+    /// batches and then joined back using the original separators.
+    pub fn translate(&mut self, text: &str) -> Result<String> {
+        let sentences = split_sentences(text);
+        let to_translate: Vec<&str> = sentences
+            .iter()
+            .map(|s| s.text)
+            .filter(|s| !s.is_empty())
+            .collect();
+        let mut translations = self.translate_sentences(&to_translate)?.into_iter();
+        let mut output = String::new();
+        for sentence in sentences.iter() {
+            if !sentence.text.is_empty() {
+                if let Some(translation) = translations.next() {
+                    output.push_str(translation.trim())
+                }
+            }
+            output.push_str(sentence.separator)
+        }
+        Ok(output)
+    }
+}
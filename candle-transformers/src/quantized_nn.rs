@@ -124,3 +124,58 @@ impl Module for RmsNorm {
         candle_nn::ops::rms_norm(x, &self.weight, self.eps as f32)
     }
 }
+
+/// A linear layer using either unquantized weights or the AWQ/GPTQ weights selected by the
+/// `quant_method` of the checkpoint quantization config.
+#[derive(Debug, Clone)]
+pub enum QuantizedLinear {
+    Linear(candle_nn::Linear),
+    Awq(crate::awq::AwqLinear),
+    Gptq(crate::gptq::GptqLinear),
+}
+
+impl QuantizedLinear {
+    pub fn new(
+        in_dim: usize,
+        out_dim: usize,
+        bias: bool,
+        cfg: Option<&crate::config::QuantizationConfig>,
+        vb: candle_nn::VarBuilder,
+    ) -> Result<Self> {
+        let cfg = match cfg {
+            None => {
+                return Ok(Self::Linear(candle_nn::linear_b(
+                    in_dim, out_dim, bias, vb,
+                )?))
+            }
+            Some(cfg) => cfg,
+        };
+        let linear = match cfg.quant_method.as_str() {
+            "awq" => {
+                let cfg = cfg.awq()?;
+                cfg.check()?;
+                let linear = crate::awq::AwqLinear::new(in_dim, out_dim, cfg.group_size, bias, vb)?;
+                Self::Awq(linear)
+            }
+            "gptq" => Self::Gptq(crate::gptq::GptqLinear::new(
+                in_dim,
+                out_dim,
+                bias,
+                &cfg.gptq()?,
+                vb,
+            )?),
+            method => candle::bail!("unsupported quantization method {method}"),
+        };
+        Ok(linear)
+    }
+}
+
+impl Module for QuantizedLinear {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        match self {
+            Self::Linear(l) => l.forward(xs),
+            Self::Awq(l) => l.forward(xs),
+            Self::Gptq(l) => l.forward(xs),
+        }
+    }
+}
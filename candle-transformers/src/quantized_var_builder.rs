@@ -2,7 +2,9 @@
 //!
 //! VarBuilder is a utility to store quantized tensors from a [GGUF model file](https://huggingface.co/docs/hub/gguf).
 //! These tensors can be loaded from disk using `from_gguf` or from an in-memory
-//! buffer using `from_gguf_buffer`.
+//! buffer using `from_gguf_buffer`. When multiple processes serve the same model,
+//! `from_gguf_shared` maps the weights from a shared memory object so that a single copy is kept
+//! in memory.
 
 use candle::quantized::QTensor;
 use candle::{Device, Result, Shape};
@@ -47,6 +49,42 @@ impl VarBuilder {
         })
     }
 
+    /// Loads the gguf file through a shared memory object named `name`, creating this object if
+    /// needed. Processes on the same host using the same name share a single copy of the weights.
+    ///
+    /// # Safety
+    ///
+    /// The unsafe is inherited from
+    /// [`SharedMapping::open_or_create`](candle::quantized::shared::SharedMapping::open_or_create).
+    pub unsafe fn from_gguf_shared<P: AsRef<std::path::Path>>(
+        p: P,
+        name: &str,
+        device: &Device,
+    ) -> Result<Self> {
+        let mapping = candle::quantized::shared::SharedMapping::open_or_create(name, p)?;
+        Self::from_shared_mapping(&mapping, device)
+    }
+
+    /// Loads the tensors from a shared mapping of a gguf file, on cpu the tensors reference the
+    /// mapping directly.
+    pub fn from_shared_mapping(
+        mapping: &candle::quantized::shared::SharedMapping,
+        device: &Device,
+    ) -> Result<Self> {
+        let mut cursor = std::io::Cursor::new(mapping.as_bytes());
+        let content = candle::quantized::gguf_file::Content::read(&mut cursor)?;
+        let mut data = std::collections::HashMap::new();
+        for tensor_name in content.tensor_infos.keys() {
+            let tensor = content.tensor_from_mapping(mapping, tensor_name, device)?;
+            data.insert(tensor_name.to_string(), Arc::new(tensor));
+        }
+        Ok(Self {
+            data: Arc::new(data),
+            path: Vec::new(),
+            device: device.clone(),
+        })
+    }
+
     pub fn pp<S: ToString>(&self, s: S) -> Self {
         let mut path = self.path.clone();
         path.push(s.to_string());
@@ -101,4 +139,10 @@ impl VarBuilder {
     pub fn contains_key(&self, key: &str) -> bool {
         self.data.contains_key(key)
     }
+
+    /// Whether a tensor exists for `name` relative to the current path, whereas `contains_key`
+    /// takes the full name of the tensor.
+    pub fn contains_tensor(&self, name: &str) -> bool {
+        self.data.contains_key(&self.path(name))
+    }
 }
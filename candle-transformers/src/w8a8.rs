@@ -0,0 +1,335 @@
+//! Linear layers with int8 weights and dynamically quantized int8 activations, a.k.a. W8A8.
+//!
+//! The weights are quantized symmetrically with one scale per output feature when the layer is
+//! created. On each forward pass the activations are quantized with one scale per row, i.e. per
+//! token, the matmul runs on int8 values with i32 accumulation and the result is dequantized
+//! with the product of the two scales in the same step.
+//!
+//! On cpu the int8 dot products use avx2 or avx-vnni on x86 and neon on aarch64 when enabled at
+//! compile time, on cuda they use the `dp4a` instruction. As the weights are read as int8, this
+//! roughly halves the memory traffic compared to f16 weights for memory-bound layers.
+use candle::{CpuStorage, DType, Layout, Module, Result, Shape, Tensor, WithDType};
+use candle_nn::VarBuilder;
+use rayon::prelude::*;
+
+// The int8 values, both for weights and activations, are in [-127, 127] so that the sign trick
+// used by the simd dot products cannot overflow.
+const Q_MAX: f32 = 127.;
+
+fn dot_i8_scalar(xs: &[i8], ys: &[i8]) -> i32 {
+    xs.iter()
+        .zip(ys.iter())
+        .map(|(&x, &y)| x as i32 * y as i32)
+        .sum()
+}
+
+#[cfg(all(target_arch = "x86_64", target_feature = "avx2"))]
+unsafe fn dot_i8_avx2(xs: &[i8], ys: &[i8]) -> i32 {
+    use std::arch::x86_64::*;
+    let n = xs.len() / 32 * 32;
+    let mut acc = _mm256_setzero_si256();
+    for i in (0..n).step_by(32) {
+        let x = _mm256_loadu_si256(xs.as_ptr().add(i) as *const __m256i);
+        let y = _mm256_loadu_si256(ys.as_ptr().add(i) as *const __m256i);
+        // The unsigned times signed products use |x| and y with the sign of x.
+        let ax = _mm256_sign_epi8(x, x);
+        let sy = _mm256_sign_epi8(y, x);
+        #[cfg(target_feature = "avxvnni")]
+        {
+            acc = _mm256_dpbusd_avx_epi32(acc, ax, sy);
+        }
+        #[cfg(not(target_feature = "avxvnni"))]
+        {
+            let dot = _mm256_maddubs_epi16(ax, sy);
+            acc = _mm256_add_epi32(acc, _mm256_madd_epi16(dot, _mm256_set1_epi16(1)));
+        }
+    }
+    let sum = _mm_add_epi32(
+        _mm256_castsi256_si128(acc),
+        _mm256_extracti128_si256::<1>(acc),
+    );
+    let sum = _mm_hadd_epi32(sum, sum);
+    let sum = _mm_hadd_epi32(sum, sum);
+    _mm_cvtsi128_si32(sum) + dot_i8_scalar(&xs[n..], &ys[n..])
+}
+
+#[cfg(all(target_arch = "aarch64", target_feature = "neon"))]
+unsafe fn dot_i8_neon(xs: &[i8], ys: &[i8]) -> i32 {
+    use std::arch::aarch64::*;
+    let n = xs.len() / 16 * 16;
+    let mut acc = vdupq_n_s32(0);
+    for i in (0..n).step_by(16) {
+        let x = vld1q_s8(xs.as_ptr().add(i));
+        let y = vld1q_s8(ys.as_ptr().add(i));
+        acc = vpadalq_s16(acc, vmull_s8(vget_low_s8(x), vget_low_s8(y)));
+        acc = vpadalq_s16(acc, vmull_high_s8(x, y));
+    }
+    vaddvq_s32(acc) + dot_i8_scalar(&xs[n..], &ys[n..])
+}
+
+#[allow(unreachable_code)]
+fn dot_i8(xs: &[i8], ys: &[i8]) -> i32 {
+    #[cfg(all(target_arch = "x86_64", target_feature = "avx2"))]
+    return unsafe { dot_i8_avx2(xs, ys) };
+
+    #[cfg(all(target_arch = "aarch64", target_feature = "neon"))]
+    return unsafe { dot_i8_neon(xs, ys) };
+
+    dot_i8_scalar(xs, ys)
+}
+
+// Quantizes a row of activations, returns the int8 values and their scale.
+fn quantize_row<T: WithDType>(xs: &[T]) -> (Vec<i8>, f32) {
+    let amax = xs
+        .iter()
+        .fold(0f32, |acc, x| acc.max((x.to_f64() as f32).abs()));
+    let scale = amax / Q_MAX;
+    let inv_scale = if scale == 0. { 0. } else { 1. / scale };
+    let xq = xs
+        .iter()
+        .map(|x| (x.to_f64() as f32 * inv_scale).round().clamp(-Q_MAX, Q_MAX) as i8)
+        .collect();
+    (xq, scale)
+}
+
+fn matmul_slice<T: WithDType>(
+    xs: &[T],
+    weight: &[i8],
+    weight_scales: &[f32],
+    (n_rows, in_dim, out_dim): (usize, usize, usize),
+) -> Vec<T> {
+    let rows: Vec<_> = xs.par_chunks(in_dim).map(quantize_row).collect();
+    let mut dst = vec![T::zero(); n_rows * out_dim];
+    dst.par_iter_mut().enumerate().for_each(|(idx, dst)| {
+        let (row, o) = (idx / out_dim, idx % out_dim);
+        let (xq, x_scale) = &rows[row];
+        let acc = dot_i8(xq, &weight[o * in_dim..(o + 1) * in_dim]);
+        *dst = T::from_f64((acc as f32 * x_scale * weight_scales[o]) as f64)
+    });
+    dst
+}
+
+// The inputs are the `(n_rows, in_dim)` activations and the `(out_dim, in_dim)` int8 weights
+// stored as u8.
+struct W8A8Matmul {
+    weight_scales: Tensor,
+}
+
+fn w8a8_dims(xs_l: &Layout, w_l: &Layout, scales: &Tensor) -> Result<(usize, usize, usize)> {
+    let (n_rows, in_dim) = xs_l.shape().dims2()?;
+    let (out_dim, w_in_dim) = w_l.shape().dims2()?;
+    if in_dim != w_in_dim || scales.dims1()? != out_dim {
+        candle::bail!(
+            "w8a8-matmul, shape mismatch {:?} {:?} {:?}",
+            xs_l.shape(),
+            w_l.shape(),
+            scales.shape()
+        )
+    }
+    Ok((n_rows, in_dim, out_dim))
+}
+
+impl candle::CustomOp2 for W8A8Matmul {
+    fn name(&self) -> &'static str {
+        "w8a8-matmul"
+    }
+
+    fn cpu_fwd(
+        &self,
+        s1: &CpuStorage,
+        l1: &Layout,
+        s2: &CpuStorage,
+        l2: &Layout,
+    ) -> Result<(CpuStorage, Shape)> {
+        use candle::backend::BackendStorage;
+
+        fn contiguous<'a, T: WithDType>(s: &'a CpuStorage, l: &Layout) -> Result<&'a [T]> {
+            match l.contiguous_offsets() {
+                None => candle::bail!("w8a8-matmul, inputs have to be contiguous"),
+                Some((o1, o2)) => Ok(&s.as_slice::<T>()?[o1..o2]),
+            }
+        }
+        let dims = w8a8_dims(l1, l2, &self.weight_scales)?;
+        let (scales, l3) = self.weight_scales.storage_and_layout();
+        let scales = match &*scales {
+            candle::Storage::Cpu(s3) => contiguous::<f32>(s3, l3)?,
+            _ => candle::bail!("w8a8-matmul, the weight scales have to be on the cpu"),
+        };
+        let weight = contiguous::<u8>(s2, l2)?;
+        // SAFETY: u8 and i8 have the same size and alignment.
+        let weight =
+            unsafe { std::slice::from_raw_parts(weight.as_ptr() as *const i8, weight.len()) };
+        let dst = match s1 {
+            CpuStorage::F16(_) => {
+                CpuStorage::F16(matmul_slice(contiguous(s1, l1)?, weight, scales, dims))
+            }
+            CpuStorage::BF16(_) => {
+                CpuStorage::BF16(matmul_slice(contiguous(s1, l1)?, weight, scales, dims))
+            }
+            CpuStorage::F32(_) => {
+                CpuStorage::F32(matmul_slice(contiguous(s1, l1)?, weight, scales, dims))
+            }
+            s => candle::bail!("w8a8-matmul, unsupported dtype {:?}", s.dtype()),
+        };
+        Ok((dst, Shape::from((dims.0, dims.2))))
+    }
+
+    #[cfg(feature = "cuda")]
+    fn cuda_fwd(
+        &self,
+        s1: &candle::CudaStorage,
+        l1: &Layout,
+        s2: &candle::CudaStorage,
+        l2: &Layout,
+    ) -> Result<(candle::CudaStorage, Shape)> {
+        use crate::awq::cuda_contiguous;
+        use candle::backend::BackendStorage;
+        use candle::cuda_backend::cudarc::driver::{
+            CudaSlice, CudaView, DeviceRepr, LaunchAsync, LaunchConfig,
+        };
+        use candle::cuda_backend::{kernel_name, kernels, CudaDType, CudaStorageSlice, WrapErr};
+        use candle::{CudaDevice, Storage};
+
+        // Has to match W8A8_WARPS in the kernels.
+        const WARPS: usize = 4;
+
+        fn launch<T: CudaDType + DeviceRepr + WithDType>(
+            dev: &CudaDevice,
+            (xs, xs_l): (&candle::CudaStorage, &Layout),
+            weight: &CudaView<u8>,
+            weight_scales: &CudaView<f32>,
+            (n_rows, in_dim, out_dim): (usize, usize, usize),
+        ) -> Result<CudaSlice<T>> {
+            let xs = cuda_contiguous(xs.as_cuda_slice::<T>()?, xs_l)?;
+            // SAFETY: Set later by running the kernels.
+            let xq = unsafe { dev.alloc::<u8>(n_rows * in_dim) }.w()?;
+            let x_scales = unsafe { dev.alloc::<f32>(n_rows) }.w()?;
+            let dst = unsafe { dev.alloc::<T>(n_rows * out_dim) }.w()?;
+            let cfg = LaunchConfig {
+                grid_dim: (n_rows as u32, 1, 1),
+                block_dim: (32 * WARPS as u32, 1, 1),
+                shared_mem_bytes: 0,
+            };
+            let name = kernel_name::<T>("w8a8_quantize_rows");
+            let func = dev.get_or_load_func(&name, kernels::W8A8)?;
+            // SAFETY: ffi.
+            unsafe { func.launch(cfg, (&xs, &xq, &x_scales, in_dim)) }.w()?;
+            let cfg = LaunchConfig {
+                grid_dim: (out_dim.div_ceil(WARPS) as u32, n_rows as u32, 1),
+                block_dim: (32, WARPS as u32, 1),
+                shared_mem_bytes: 0,
+            };
+            let func = dev.get_or_load_func(&kernel_name::<T>("w8a8_matmul"), kernels::W8A8)?;
+            let params = (&xq, &x_scales, weight, weight_scales, &dst, in_dim, out_dim);
+            // SAFETY: ffi.
+            unsafe { func.launch(cfg, params) }.w()?;
+            Ok(dst)
+        }
+
+        let dims = w8a8_dims(l1, l2, &self.weight_scales)?;
+        if dims.1 % 4 != 0 {
+            candle::bail!("w8a8-matmul, in_dim {} is not a multiple of 4", dims.1)
+        }
+        let (scales, l3) = self.weight_scales.storage_and_layout();
+        let scales = match &*scales {
+            Storage::Cuda(s3) => cuda_contiguous(s3.as_cuda_slice::<f32>()?, l3)?,
+            _ => candle::bail!("w8a8-matmul, the weight scales have to be on a cuda device"),
+        };
+        let weight = cuda_contiguous(s2.as_cuda_slice::<u8>()?, l2)?;
+        let dev = s1.device().clone();
+        let a1 = (s1, l1);
+        let slice = match s1.dtype() {
+            DType::F16 => CudaStorageSlice::F16(launch(&dev, a1, &weight, &scales, dims)?),
+            DType::BF16 => CudaStorageSlice::BF16(launch(&dev, a1, &weight, &scales, dims)?),
+            DType::F32 => CudaStorageSlice::F32(launch(&dev, a1, &weight, &scales, dims)?),
+            dtype => candle::bail!("w8a8-matmul, unsupported dtype {dtype:?}"),
+        };
+        let dst = candle::cuda_backend::CudaStorage { slice, device: dev };
+        Ok((dst, Shape::from((dims.0, dims.2))))
+    }
+}
+
+/// A linear layer with int8 weights, the activations are quantized to int8 on the fly.
+#[derive(Debug, Clone)]
+pub struct W8A8Linear {
+    // The int8 values stored as u8, with shape (out_dim, in_dim).
+    weight: Tensor,
+    weight_scales: Tensor,
+    bias: Option<Tensor>,
+}
+
+impl W8A8Linear {
+    /// Quantizes the `(out_dim, in_dim)` weights of a linear layer with one scale per output
+    /// feature.
+    pub fn quantize(weight: &Tensor, bias: Option<Tensor>) -> Result<Self> {
+        let (_out_dim, _in_dim) = weight.dims2()?;
+        let weight = weight.to_dtype(DType::F32)?;
+        let weight_scales = (weight.abs()?.max_keepdim(1)? / Q_MAX as f64)?;
+        let q = weight
+            .broadcast_div(&weight_scales.maximum(f32::MIN_POSITIVE)?)?
+            .round()?
+            .clamp(-Q_MAX, Q_MAX)?;
+        // Store the two's complement bit patterns of the int8 values.
+        let weight = q
+            .lt(0f32)?
+            .where_cond(&(&q + 256.)?, &q)?
+            .to_dtype(DType::U8)?;
+        Ok(Self {
+            weight,
+            weight_scales: weight_scales.squeeze(1)?,
+            bias,
+        })
+    }
+
+    pub fn from_linear(linear: &candle_nn::Linear) -> Result<Self> {
+        Self::quantize(linear.weight(), linear.bias().cloned())
+    }
+
+    /// Loads the floating point `weight` and optional `bias` of a linear layer and quantizes the
+    /// weights.
+    pub fn new(in_dim: usize, out_dim: usize, bias: bool, vb: VarBuilder) -> Result<Self> {
+        let weight = vb.get((out_dim, in_dim), "weight")?;
+        let bias = if bias {
+            Some(vb.get(out_dim, "bias")?)
+        } else {
+            None
+        };
+        Self::quantize(&weight, bias)
+    }
+
+    /// The dequantized f32 weights, with shape `(out_dim, in_dim)`.
+    pub fn dequantize(&self) -> Result<Tensor> {
+        let q = self.weight.to_dtype(DType::F32)?;
+        let q = q.ge(128f32)?.where_cond(&(&q - 256.)?, &q)?;
+        q.broadcast_mul(&self.weight_scales.unsqueeze(1)?)
+    }
+
+    pub fn bias(&self) -> Option<&Tensor> {
+        self.bias.as_ref()
+    }
+}
+
+impl Module for W8A8Linear {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        let (out_dim, in_dim) = self.weight.dims2()?;
+        let mut dims = xs.dims().to_vec();
+        let n_rows = xs.elem_count() / in_dim;
+        let op = W8A8Matmul {
+            weight_scales: self.weight_scales.clone(),
+        };
+        let ys = xs
+            .reshape((n_rows, in_dim))?
+            .contiguous()?
+            .apply_op2_no_bwd(&self.weight, &op)?;
+        match dims.last_mut() {
+            None => candle::bail!("w8a8, unexpected scalar input"),
+            Some(d) => *d = out_dim,
+        }
+        let ys = ys.reshape(dims)?;
+        match &self.bias {
+            None => Ok(ys),
+            // The output uses the dtype of the activations which can differ from the bias.
+            Some(bias) => ys.broadcast_add(&bias.to_dtype(ys.dtype())?),
+        }
+    }
+}
@@ -0,0 +1,103 @@
+use candle::{DType, Device, Module, Result, Tensor};
+use candle_transformers::awq::{AwqConfig, AwqLinear};
+use candle_transformers::config::QuantizationConfig;
+use std::collections::HashMap;
+
+// The order in which the output features are packed in each u32.
+const AWQ_ORDER: [usize; 8] = [0, 2, 4, 6, 1, 3, 5, 7];
+
+fn pack(values: &[Vec<u32>]) -> Vec<Vec<i64>> {
+    values
+        .iter()
+        .map(|row| {
+            row.chunks(8)
+                .map(|chunk| {
+                    let packed = AWQ_ORDER
+                        .iter()
+                        .enumerate()
+                        .fold(0u32, |acc, (i, &o)| acc | (chunk[o] << (4 * i)));
+                    // The checkpoints store the packed values as i32.
+                    packed as i32 as i64
+                })
+                .collect()
+        })
+        .collect()
+}
+
+#[test]
+fn awq_linear() -> Result<()> {
+    let dev = &Device::Cpu;
+    let (in_dim, out_dim, group_size) = (8, 16, 4);
+    let n_groups = in_dim / group_size;
+    let q: Vec<Vec<u32>> = (0..in_dim)
+        .map(|i| {
+            (0..out_dim)
+                .map(|o| ((i * 7 + o * 3) % 16) as u32)
+                .collect()
+        })
+        .collect();
+    let z: Vec<Vec<u32>> = (0..n_groups)
+        .map(|g| (0..out_dim).map(|o| ((g * 5 + o) % 16) as u32).collect())
+        .collect();
+    let scales = Tensor::rand(0.01f32, 0.1, (n_groups, out_dim), dev)?;
+    let s = scales.to_vec2::<f32>()?;
+    let expected: Vec<Vec<f32>> = (0..in_dim)
+        .map(|i| {
+            (0..out_dim)
+                .map(|o| {
+                    let g = i / group_size;
+                    (q[i][o] as f32 - z[g][o] as f32) * s[g][o]
+                })
+                .collect()
+        })
+        .collect();
+    let expected = Tensor::new(expected, dev)?;
+
+    let bias = Tensor::randn(0f32, 1., out_dim, dev)?;
+    let tensors = HashMap::from([
+        ("qweight".to_string(), Tensor::new(pack(&q), dev)?),
+        ("qzeros".to_string(), Tensor::new(pack(&z), dev)?),
+        ("scales".to_string(), scales),
+        ("bias".to_string(), bias.clone()),
+    ]);
+    let vb = candle_nn::VarBuilder::from_tensors(tensors, DType::F32, dev);
+    let linear = AwqLinear::new(in_dim, out_dim, group_size, true, vb)?;
+    let w = linear.dequantize()?;
+    let diff = (&w - &expected)?.abs()?.max_all()?.to_scalar::<f32>()?;
+    assert!(diff < 1e-6, "{diff}");
+
+    let xs = Tensor::randn(0f32, 1., (2, 3, in_dim), dev)?;
+    let ys = linear.forward(&xs)?;
+    let expected = xs.broadcast_matmul(&expected)?.broadcast_add(&bias)?;
+    assert_eq!(ys.dims(), &[2, 3, out_dim]);
+    let diff = (ys - expected)?.abs()?.max_all()?.to_scalar::<f32>()?;
+    assert!(diff < 1e-5, "{diff}");
+    Ok(())
+}
+
+#[test]
+fn awq_config() -> Result<()> {
+    let cfg: QuantizationConfig = serde_json::from_str(
+        r#"{"bits": 4, "group_size": 128, "quant_method": "awq", "version": "GEMM", "zero_point": true}"#,
+    )
+    .map_err(candle::Error::wrap)?;
+    let awq = cfg.awq()?;
+    assert_eq!(awq.group_size, 128);
+    awq.check()?;
+    assert!(cfg.gptq().is_err());
+    let cfg: QuantizationConfig =
+        serde_json::from_str(r#"{"quant_method": "awq", "w_bit": 3, "q_group_size": 64}"#)
+            .map_err(candle::Error::wrap)?;
+    let awq = cfg.awq()?;
+    assert_eq!(
+        awq,
+        AwqConfig {
+            bits: 3,
+            group_size: 64,
+            zero_point: true,
+            version: "gemm".to_string()
+        }
+    );
+    assert!(awq.check().is_err());
+    Ok(())
+}
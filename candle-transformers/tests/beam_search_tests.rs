@@ -0,0 +1,193 @@
+use candle::{Device, IndexOp, Result, Tensor};
+use candle_transformers::generation::{beam_search, BeamSearchConfig, BeamSearchModel};
+
+const VOCAB: usize = 4;
+
+// A trigram model, the logits depend on the last two tokens. The cache stores the processed
+// tokens of each entry of the batch so that the reordering is checked by the results.
+struct Trigram {
+    logits: Tensor,
+    cache: Vec<Vec<u32>>,
+    num_calls: usize,
+}
+
+impl Trigram {
+    fn new(logits: Tensor) -> Self {
+        Self {
+            logits,
+            cache: vec![],
+            num_calls: 0,
+        }
+    }
+
+    fn log_probs(&self, prev: u32, last: u32) -> Result<Vec<f32>> {
+        let logits = self.logits.i(prev as usize * VOCAB + last as usize)?;
+        candle_nn::ops::log_softmax(&logits, 0)?.to_vec1()
+    }
+}
+
+impl BeamSearchModel for Trigram {
+    fn forward(&mut self, tokens: &Tensor, seqlen_offset: usize) -> Result<Tensor> {
+        let tokens = tokens.to_vec2::<u32>()?;
+        self.num_calls += 1;
+        if self.cache.is_empty() {
+            self.cache = vec![vec![]; tokens.len()]
+        }
+        assert_eq!(self.cache.len(), tokens.len());
+        let mut ids = vec![];
+        for (cache, tokens) in self.cache.iter_mut().zip(tokens.iter()) {
+            assert_eq!(cache.len(), seqlen_offset);
+            cache.extend_from_slice(tokens);
+            let n = cache.len();
+            ids.push(cache[n - 2] * VOCAB as u32 + cache[n - 1])
+        }
+        self.logits
+            .index_select(&Tensor::new(ids, &Device::Cpu)?, 0)
+    }
+
+    fn reorder_cache(&mut self, indices: &Tensor) -> Result<()> {
+        let indices = indices.to_vec1::<u32>()?;
+        self.cache = indices
+            .iter()
+            .map(|&i| self.cache[i as usize].clone())
+            .collect();
+        Ok(())
+    }
+}
+
+fn random_logits() -> Result<Tensor> {
+    Tensor::randn(0f32, 2., (VOCAB * VOCAB, VOCAB), &Device::Cpu)
+}
+
+#[test]
+fn beam_search_top_sequences() -> Result<()> {
+    let prompt = [1u32, 2];
+    let model = Trigram::new(random_logits()?);
+    // All the sequences of three tokens with their log probabilities.
+    let mut all = vec![];
+    for a in 0..VOCAB as u32 {
+        for b in 0..VOCAB as u32 {
+            for c in 0..VOCAB as u32 {
+                let lp = model.log_probs(prompt[0], prompt[1])?[a as usize]
+                    + model.log_probs(prompt[1], a)?[b as usize]
+                    + model.log_probs(a, b)?[c as usize];
+                all.push((vec![a, b, c], lp as f64))
+            }
+        }
+    }
+    all.sort_by(|a, b| b.1.total_cmp(&a.1));
+
+    // With as many beams as sequences of two tokens, the search is exhaustive.
+    let mut model = model;
+    let config = BeamSearchConfig::new(VOCAB * VOCAB, 3).with_num_return_sequences(5);
+    let hyps = beam_search(&mut model, &prompt, &config, &Device::Cpu)?;
+    assert_eq!(hyps.len(), 5);
+    assert_eq!(model.num_calls, 3);
+    for (hyp, (tokens, lp)) in hyps.iter().zip(all.iter()) {
+        assert_eq!(&hyp.tokens, tokens);
+        assert!((hyp.log_prob - lp).abs() < 1e-4);
+        assert!((hyp.score - lp / 3.).abs() < 1e-4);
+    }
+
+    // A single beam is greedy decoding.
+    let mut model = Trigram::new(model.logits);
+    let hyps = beam_search(
+        &mut model,
+        &prompt,
+        &BeamSearchConfig::new(1, 6),
+        &Device::Cpu,
+    )?;
+    let mut greedy = prompt.to_vec();
+    for _ in 0..6 {
+        let n = greedy.len();
+        let lp = model.log_probs(greedy[n - 2], greedy[n - 1])?;
+        let next = (0..VOCAB).max_by(|&i, &j| lp[i].total_cmp(&lp[j])).unwrap();
+        greedy.push(next as u32)
+    }
+    assert_eq!(hyps[0].tokens, greedy[2..]);
+    Ok(())
+}
+
+#[test]
+fn beam_search_eos_and_length_penalty() -> Result<()> {
+    let eos = 0u32;
+    let prompt = [1u32, 2];
+    let logits = random_logits()?;
+    for length_penalty in [0., 1., 2.] {
+        let mut model = Trigram::new(logits.clone());
+        let config = BeamSearchConfig::new(3, 8)
+            .with_eos_tokens(vec![eos])
+            .with_length_penalty(length_penalty)
+            .with_num_return_sequences(3);
+        let hyps = beam_search(&mut model, &prompt, &config, &Device::Cpu)?;
+        assert_eq!(hyps.len(), 3);
+        for hyp in hyps.iter() {
+            let eos_pos = hyp.tokens.iter().position(|&t| t == eos);
+            assert!(eos_pos.map_or(hyp.tokens.len() == 8, |p| p == hyp.tokens.len() - 1));
+            let len = hyp.tokens.len() as f64;
+            assert!((hyp.score - hyp.log_prob / len.powf(length_penalty)).abs() < 1e-6);
+        }
+        assert!(hyps.windows(2).all(|w| w[0].score >= w[1].score));
+    }
+
+    // When the end of sequence token is the most likely one after the prompt, early stopping
+    // returns as soon as the beams have finished.
+    let mut logits = logits.to_vec2::<f32>()?;
+    for logits in logits.iter_mut() {
+        logits[eos as usize] = 10.
+    }
+    let logits = Tensor::new(logits, &Device::Cpu)?;
+    let mut model = Trigram::new(logits);
+    let config = BeamSearchConfig::new(2, 8)
+        .with_eos_tokens(vec![eos])
+        .with_early_stopping(true);
+    let hyps = beam_search(&mut model, &prompt, &config, &Device::Cpu)?;
+    assert_eq!(hyps[0].tokens, [eos]);
+    assert_eq!(model.num_calls, 2);
+    Ok(())
+}
+
+#[test]
+fn diverse_beam_search() -> Result<()> {
+    let prompt = [3u32, 1];
+    let logits = random_logits()?;
+    // Without the diversity penalty the groups select the same tokens.
+    let mut model = Trigram::new(logits.clone());
+    let config = BeamSearchConfig::new(4, 1)
+        .with_diversity(2, 0.)
+        .with_num_return_sequences(4);
+    let hyps = beam_search(&mut model, &prompt, &config, &Device::Cpu)?;
+    let mut tokens = hyps.iter().map(|h| h.tokens[0]).collect::<Vec<_>>();
+    tokens.sort();
+    assert_eq!(tokens[0], tokens[1]);
+
+    // With a large penalty, the second group avoids the tokens of the first one.
+    let mut model = Trigram::new(logits.clone());
+    let config = BeamSearchConfig::new(4, 1)
+        .with_diversity(2, 100.)
+        .with_num_return_sequences(4);
+    let hyps = beam_search(&mut model, &prompt, &config, &Device::Cpu)?;
+    let mut tokens = hyps.iter().map(|h| h.tokens[0]).collect::<Vec<_>>();
+    tokens.sort();
+    assert_eq!(tokens, [0, 1, 2, 3]);
+
+    // There are always enough tokens to avoid the penalty.
+    let mut model = Trigram::new(logits);
+    let config = BeamSearchConfig::new(4, 3)
+        .with_diversity(2, 100.)
+        .with_num_return_sequences(4);
+    let hyps = beam_search(&mut model, &prompt, &config, &Device::Cpu)?;
+    assert_eq!(hyps.len(), 4);
+    for hyp in hyps.iter() {
+        assert!(hyp.score > -100.);
+        assert!((hyp.score - hyp.log_prob / 3.).abs() < 1e-4)
+    }
+    assert!(beam_search(
+        &mut model,
+        &prompt,
+        &BeamSearchConfig::new(3, 1).with_diversity(2, 1.),
+        &Device::Cpu
+    )
+    .is_err());
+    Ok(())
+}
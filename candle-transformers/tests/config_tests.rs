@@ -0,0 +1,119 @@
+use candle::Result;
+use candle_transformers::config::{
+    parse_json, ModelConfig, QuantizationConfig, RopeScaling, ValidationResult, VisionConfig,
+};
+use candle_transformers::models::llama::LlamaConfig;
+
+#[derive(Debug, serde::Deserialize)]
+struct MultimodalConfig {
+    hidden_size: usize,
+    rope_scaling: Option<RopeScaling>,
+    quantization_config: Option<QuantizationConfig>,
+    vision_config: VisionConfig,
+}
+
+impl ModelConfig for MultimodalConfig {
+    fn validate(&self) -> ValidationResult {
+        use candle_transformers::config::validate_nested;
+        candle_transformers::config::ensure_positive("hidden_size", self.hidden_size)?;
+        validate_nested("rope_scaling", self.rope_scaling.as_ref())?;
+        validate_nested("quantization_config", self.quantization_config.as_ref())?;
+        validate_nested("vision_config", Some(&self.vision_config))
+    }
+}
+
+const MULTIMODAL: &str = r#"{
+    "architectures": ["MultimodalForCausalLM"],
+    "hidden_size": 64,
+    "rope_scaling": {"type": "yarn", "factor": 4.0, "beta_fast": 32, "finetuned": true},
+    "quantization_config": {"quant_method": "awq", "bits": 4, "group_size": 128},
+    "vision_config": {
+        "hidden_size": 32,
+        "intermediate_size": 128,
+        "num_hidden_layers": 2,
+        "num_attention_heads": 4,
+        "image_size": 224,
+        "patch_size": 14,
+        "hidden_act": "gelu",
+        "projector": {"depth": 2}
+    }
+}"#;
+
+#[test]
+fn nested_configs() -> Result<()> {
+    let parsed = parse_json::<MultimodalConfig>(MULTIMODAL)?;
+    let cfg = parsed.config;
+    assert_eq!(
+        parsed.unknown_fields,
+        [
+            "architectures",
+            "rope_scaling.finetuned",
+            "vision_config.projector"
+        ]
+    );
+    let rope = cfg.rope_scaling.unwrap();
+    assert_eq!(rope.rope_type, "yarn");
+    assert_eq!(rope.beta_fast, Some(32.));
+    assert_eq!(cfg.quantization_config.unwrap().bits, Some(4));
+    assert_eq!(cfg.vision_config.num_channels, 3);
+    assert_eq!(cfg.vision_config.num_patches(), 256);
+    Ok(())
+}
+
+#[test]
+fn errors_name_the_field() -> Result<()> {
+    let json = MULTIMODAL.replace(r#""patch_size": 14"#, r#""patch_size": "14""#);
+    let err = parse_json::<MultimodalConfig>(&json).unwrap_err();
+    assert_eq!(err.field, "vision_config.patch_size");
+
+    let json = MULTIMODAL.replace(r#""bits": 4"#, r#""bits": 12"#);
+    let err = parse_json::<MultimodalConfig>(&json).unwrap_err();
+    assert_eq!(err.field, "quantization_config.bits");
+    assert_eq!(
+        err.to_string(),
+        "invalid config, field `quantization_config.bits`: 12 is not between 2 and 8"
+    );
+
+    let json = MULTIMODAL.replace(r#""image_size": 224"#, r#""image_size": 220"#);
+    let err = parse_json::<MultimodalConfig>(&json).unwrap_err();
+    assert_eq!(err.field, "vision_config.image_size");
+
+    let json = MULTIMODAL.replace(r#""type": "yarn""#, r#""rope_type": "llama3""#);
+    let err = parse_json::<MultimodalConfig>(&json).unwrap_err();
+    assert_eq!(err.field, "rope_scaling.low_freq_factor");
+
+    let err = parse_json::<MultimodalConfig>(r#"{"hidden_size": 64}"#).unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "invalid config: missing field `vision_config`"
+    );
+    Ok(())
+}
+
+#[test]
+fn llama_config() -> Result<()> {
+    let json = r#"{
+        "hidden_size": 256,
+        "intermediate_size": 512,
+        "vocab_size": 1000,
+        "num_hidden_layers": 2,
+        "num_attention_heads": 8,
+        "num_key_value_heads": 2,
+        "rms_norm_eps": 1e-5,
+        "max_position_embeddings": 2048,
+        "eos_token_id": [1, 2]
+    }"#;
+    let cfg = LlamaConfig::from_json(json)?;
+    assert_eq!(cfg.rope_theta, 10_000.);
+    assert_eq!(cfg.num_key_value_heads(), 2);
+
+    let err = LlamaConfig::from_json(
+        &json.replace("\"num_key_value_heads\": 2", "\"num_key_value_heads\": 3"),
+    )
+    .unwrap_err();
+    assert!(
+        err.to_string().contains("field `num_attention_heads`"),
+        "{err}"
+    );
+    Ok(())
+}
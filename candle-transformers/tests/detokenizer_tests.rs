@@ -0,0 +1,100 @@
+use candle::Result;
+use candle_transformers::generation::{Detokenizer, SpecialTokenPolicy};
+use std::collections::HashMap;
+
+const VOCAB: [&str; 10] = [
+    "▁Hello", "▁wor", "ld", "!", "<0xE2>", "<0x82>", "<0xAC>", "<0xFF>", "<s>", "</s>",
+];
+const BOS: u32 = 8;
+const EOS: u32 = 9;
+
+// A SentencePiece decoder with byte fallback: the byte tokens are concatenated before being
+// decoded as utf-8, `▁` is a space and the leading space is removed.
+fn decode(tokens: &[u32]) -> Result<String> {
+    let mut bytes = vec![];
+    for &token in tokens {
+        let piece = VOCAB[token as usize];
+        match piece.strip_prefix("<0x").and_then(|p| p.strip_suffix('>')) {
+            Some(hex) => bytes.push(u8::from_str_radix(hex, 16).unwrap()),
+            None => bytes.extend_from_slice(piece.replace('▁', " ").as_bytes()),
+        }
+    }
+    let text = String::from_utf8_lossy(&bytes).to_string();
+    Ok(text.strip_prefix(' ').unwrap_or(&text).to_string())
+}
+
+fn detokenizer(policy: SpecialTokenPolicy) -> Detokenizer {
+    let special_tokens = HashMap::from([(BOS, "<s>".to_string()), (EOS, "</s>".to_string())]);
+    Detokenizer::new(decode)
+        .with_special_tokens(special_tokens)
+        .with_policy(policy)
+}
+
+fn stream(detokenizer: &mut Detokenizer, tokens: &[u32]) -> Result<Vec<Option<String>>> {
+    tokens.iter().map(|&t| detokenizer.next_token(t)).collect()
+}
+
+#[test]
+fn detokenizer_spaces_and_bytes() -> Result<()> {
+    let mut detokenizer = detokenizer(SpecialTokenPolicy::Skip);
+    let pieces = stream(&mut detokenizer, &[0, 1, 2, 4, 5, 6, 3])?;
+    let pieces = pieces.iter().map(|p| p.as_deref()).collect::<Vec<_>>();
+    // The euro sign is only returned once its three bytes are there.
+    assert_eq!(
+        pieces,
+        [
+            Some("Hello"),
+            Some(" wor"),
+            Some("ld"),
+            None,
+            None,
+            Some("€"),
+            Some("!")
+        ]
+    );
+    assert_eq!(detokenizer.flush()?, "");
+
+    // The text is the same as the decoding of all the tokens.
+    let tokens = [4, 0, 5, 6, 1, 2, 3, 4, 5];
+    detokenizer.reset();
+    let mut text = stream(&mut detokenizer, &tokens)?
+        .into_iter()
+        .flatten()
+        .collect::<String>();
+    assert_eq!(detokenizer.pending_text()?, "\u{FFFD}");
+    text.push_str(&detokenizer.flush()?);
+    assert_eq!(text, decode(&tokens)?);
+    Ok(())
+}
+
+#[test]
+fn detokenizer_invalid_bytes() -> Result<()> {
+    // Invalid bytes are returned as replacement characters after a few held back tokens.
+    let mut detokenizer = detokenizer(SpecialTokenPolicy::Skip);
+    let pieces = stream(&mut detokenizer, &[7, 7, 7, 7])?;
+    assert_eq!(pieces, [None, None, None, Some("\u{FFFD}".repeat(4))]);
+    let pieces = stream(&mut detokenizer, &[4, 5])?;
+    assert_eq!(pieces, [None, None]);
+    assert_eq!(detokenizer.flush()?, "\u{FFFD}");
+    Ok(())
+}
+
+#[test]
+fn detokenizer_special_tokens() -> Result<()> {
+    let tokens = [BOS, 0, EOS, 1, 2, 4, EOS];
+    let text = |policy| -> Result<String> {
+        let mut detokenizer = detokenizer(policy);
+        let text = stream(&mut detokenizer, &tokens)?.into_iter().flatten();
+        Ok(text.collect())
+    };
+    assert_eq!(text(SpecialTokenPolicy::Skip)?, "Hello world\u{FFFD}");
+    assert_eq!(
+        text(SpecialTokenPolicy::Include)?,
+        "<s>Hello</s> world\u{FFFD}</s>"
+    );
+    assert_eq!(
+        text(SpecialTokenPolicy::IncludeOnly(vec![EOS]))?,
+        "Hello</s> world\u{FFFD}</s>"
+    );
+    Ok(())
+}
@@ -0,0 +1,62 @@
+use candle::{DType, Device, IndexOp, Result, Tensor};
+use candle_transformers::models::dit::{joint_attention, Modulation, Qkv};
+
+#[test]
+fn modulation_zero_init() -> Result<()> {
+    let dev = &Device::Cpu;
+    let dim = 4;
+    // With a zero initialized projection, the modulated sub-blocks are the identity.
+    let lin = candle_nn::Linear::new(
+        Tensor::zeros((6 * dim, dim), DType::F32, dev)?,
+        Some(Tensor::zeros(6 * dim, DType::F32, dev)?),
+    );
+    let modulation = Modulation::new(lin, 2);
+    let vec_ = Tensor::randn(0f32, 1., (2, dim), dev)?;
+    let mods = modulation.forward(&vec_)?;
+    assert_eq!(mods.len(), 2);
+    assert_eq!(mods[0].shift.dims(), [2, 1, dim]);
+    let xs = Tensor::randn(0f32, 1., (2, 3, dim), dev)?;
+    let ys = mods[1].scale_shift(&xs)?;
+    assert_eq!(ys.to_vec3::<f32>()?, xs.to_vec3::<f32>()?);
+    let ys = (&xs + mods[1].gate(&xs)?)?;
+    assert_eq!(ys.to_vec3::<f32>()?, xs.to_vec3::<f32>()?);
+
+    // The bias is split in shift, scale, and gate for each sub-block.
+    let bias = Tensor::arange(0f32, (6 * dim) as f32, dev)?;
+    let lin = candle_nn::Linear::new(Tensor::zeros((6 * dim, dim), DType::F32, dev)?, Some(bias));
+    let mods = Modulation::new(lin, 2).forward(&vec_)?;
+    assert_eq!(
+        mods[1].scale.i((0, 0))?.to_vec1::<f32>()?,
+        [16., 17., 18., 19.]
+    );
+    Ok(())
+}
+
+#[test]
+fn joint_attention_split() -> Result<()> {
+    let dev = &Device::Cpu;
+    let qkv = |l: usize| -> Result<Qkv> {
+        let qkv = Tensor::randn(0f32, 1., (1, l, 3 * 4), dev)?;
+        Qkv::split_heads(&qkv, 2)
+    };
+    let (context, x) = (qkv(3)?, qkv(5)?);
+    assert_eq!(context.q.dims(), [1, 2, 3, 2]);
+    // Return the queries so that the outputs can be compared with the inputs.
+    let (context_attn, x_attn) = joint_attention(&context, &x, 2, |qkv| {
+        assert_eq!(qkv.k.dims(), [1, 2, 8, 2]);
+        qkv.q.transpose(1, 2)?.flatten_from(2)
+    })?;
+    assert_eq!(
+        context_attn.to_vec3::<f32>()?,
+        context
+            .q
+            .transpose(1, 2)?
+            .flatten_from(2)?
+            .to_vec3::<f32>()?
+    );
+    assert_eq!(
+        x_attn.to_vec3::<f32>()?,
+        x.q.transpose(1, 2)?.flatten_from(2)?.to_vec3::<f32>()?
+    );
+    Ok(())
+}
@@ -0,0 +1,554 @@
+use candle::{DType, Device, Result, Tensor};
+use candle_nn::multi_lora::{AdapterSegment, AdapterSelection, LoraWeights, MultiLoraLinear};
+use candle_nn::paged_attention::{PagedBatch, PagedKvCache};
+use candle_nn::{Linear, Module};
+use candle_transformers::generation::batching::{
+    BatchingPolicy, LatencySlo, SchedulerState, SloPolicy, StepBudget, StepStats,
+};
+use candle_transformers::generation::{
+    CancellationToken, Engine, EngineConfig, EngineModel, FairShare, FinishReason, GenerationEvent,
+    GenerationRequest, Priority,
+};
+use std::time::Duration;
+
+const VOCAB: usize = 16;
+const DIM: usize = 8;
+
+// A single attention layer with random weights, the positions are added to the embeddings.
+struct ToyModel {
+    emb: Tensor,
+    wq: Tensor,
+    wk: Tensor,
+    wv: Tensor,
+    head: Tensor,
+    num_calls: usize,
+    num_tokens: usize,
+}
+
+impl ToyModel {
+    fn new(dev: &Device) -> Result<Self> {
+        let w = |i, o| Tensor::randn(0f32, 1., (i, o), dev);
+        Ok(Self {
+            emb: w(VOCAB, DIM)?,
+            wq: w(DIM, DIM)?,
+            wk: w(DIM, DIM)?,
+            wv: w(DIM, DIM)?,
+            head: (w(DIM, VOCAB)? * 4.)?,
+            num_calls: 0,
+            num_tokens: 0,
+        })
+    }
+}
+
+impl EngineModel for ToyModel {
+    fn forward(
+        &mut self,
+        tokens: &Tensor,
+        positions: &Tensor,
+        batch: &PagedBatch,
+        cache: &PagedKvCache,
+    ) -> Result<Tensor> {
+        self.num_calls += 1;
+        let n = tokens.dim(0)?;
+        self.num_tokens += n;
+        let pos = positions.to_dtype(DType::F32)?.unsqueeze(1)?.sin()?;
+        let xs = self.emb.index_select(tokens, 0)?.broadcast_add(&pos)?;
+        let q = xs.matmul(&self.wq)?.reshape((n, 1, DIM))?;
+        let k = xs.matmul(&self.wk)?.reshape((n, 1, DIM))?;
+        let v = xs.matmul(&self.wv)?.reshape((n, 1, DIM))?;
+        cache.write(0, batch, &k, &v)?;
+        let attn = cache.attention(0, batch, &q, &Default::default())?;
+        let xs = (xs + attn.reshape((n, DIM))?)?;
+        let mut last = vec![];
+        let mut offset = 0;
+        for &len in batch.num_new_tokens() {
+            offset += len;
+            last.push(offset as u32 - 1)
+        }
+        let last = Tensor::new(last, tokens.device())?;
+        xs.index_select(&last, 0)?.matmul(&self.head)
+    }
+}
+
+fn prompts() -> Vec<Vec<u32>> {
+    vec![
+        vec![1, 2, 3, 4, 5],
+        vec![7],
+        vec![3, 9, 11, 2, 0, 15, 8, 6, 4, 13],
+    ]
+}
+
+fn generate_alone(model: ToyModel, prompt: &[u32], max_new_tokens: usize) -> Result<Vec<u32>> {
+    let cache = PagedKvCache::new(1, 16, 4, 1, DIM, DType::F32, &Device::Cpu)?;
+    let mut engine = Engine::new(model, cache, EngineConfig::default());
+    let stream = engine.submit(GenerationRequest::new(prompt.to_vec(), max_new_tokens))?;
+    while engine.step()? {}
+    Ok(stream.tokens()?.0)
+}
+
+fn clone(model: &ToyModel) -> ToyModel {
+    ToyModel {
+        emb: model.emb.clone(),
+        wq: model.wq.clone(),
+        wk: model.wk.clone(),
+        wv: model.wv.clone(),
+        head: model.head.clone(),
+        num_calls: 0,
+        num_tokens: 0,
+    }
+}
+
+#[test]
+fn engine_batches_requests() -> Result<()> {
+    let dev = &Device::Cpu;
+    let model = ToyModel::new(dev)?;
+    let expected = prompts()
+        .iter()
+        .map(|p| generate_alone(clone(&model), p, 6))
+        .collect::<Result<Vec<_>>>()?;
+
+    let cache = PagedKvCache::new(1, 16, 4, 1, DIM, DType::F32, dev)?;
+    let mut engine = Engine::new(clone(&model), cache, EngineConfig::default());
+    let handle = engine.handle();
+    let prompts = prompts();
+    let s0 = handle.submit(GenerationRequest::new(prompts[0].clone(), 6))?;
+    let s1 = handle.submit(GenerationRequest::new(prompts[1].clone(), 6))?;
+    // The first two requests start before the third one is submitted.
+    assert!(engine.step()?);
+    assert!(engine.step()?);
+    let s2 = handle.submit(GenerationRequest::new(prompts[2].clone(), 6))?;
+    while engine.step()? {}
+    assert!(engine.is_idle());
+    assert_eq!(engine.cache().num_free_blocks(), 16);
+    // The decoding of the first two requests is batched with the prefill of the third one.
+    assert_eq!(engine.model().num_calls, 8);
+    for (stream, expected) in [s0, s1, s2].into_iter().zip(expected) {
+        assert_eq!(stream.tokens()?, (expected, FinishReason::Length));
+    }
+    Ok(())
+}
+
+#[test]
+fn engine_preempts_on_full_cache() -> Result<()> {
+    let dev = &Device::Cpu;
+    let model = ToyModel::new(dev)?;
+    let expected = prompts()
+        .iter()
+        .map(|p| generate_alone(clone(&model), p, 6))
+        .collect::<Result<Vec<_>>>()?;
+
+    // Only 6 blocks of 4 tokens, the three sequences need 11 blocks in total.
+    let cache = PagedKvCache::new(1, 6, 4, 1, DIM, DType::F32, dev)?;
+    let mut engine = Engine::new(clone(&model), cache, EngineConfig::default());
+    let streams = prompts()
+        .into_iter()
+        .map(|p| engine.submit(GenerationRequest::new(p, 6)))
+        .collect::<Result<Vec<_>>>()?;
+    let mut preempted = false;
+    while engine.step()? {
+        preempted |= engine.num_waiting() > 0;
+    }
+    assert!(preempted);
+    for (stream, expected) in streams.into_iter().zip(expected) {
+        assert_eq!(stream.tokens()?, (expected, FinishReason::Length));
+    }
+    Ok(())
+}
+
+#[test]
+fn engine_stop_tokens_and_threads() -> Result<()> {
+    let dev = &Device::Cpu;
+    let model = ToyModel::new(dev)?;
+    let prompt = vec![1, 2, 3];
+    let tokens = generate_alone(clone(&model), &prompt, 4)?;
+
+    let cache = PagedKvCache::new(1, 16, 4, 1, DIM, DType::F32, dev)?;
+    let engine = Engine::new(model, cache, EngineConfig::default());
+    let handle = engine.handle();
+    let thread = std::thread::spawn(move || engine.run());
+    let request = GenerationRequest::new(prompt.clone(), 4).with_stop_tokens(vec![tokens[1]]);
+    let events = handle.submit(request)?.collect::<Vec<_>>();
+    let stop = tokens.iter().position(|&t| t == tokens[1]).unwrap_or(1);
+    let mut expected = tokens[..=stop]
+        .iter()
+        .map(|&t| GenerationEvent::Token(t))
+        .collect::<Vec<_>>();
+    expected.push(GenerationEvent::Finished(FinishReason::Stop));
+    assert_eq!(events, expected);
+
+    let events = handle
+        .submit(GenerationRequest::new(vec![], 4))?
+        .collect::<Vec<_>>();
+    assert!(matches!(events[..], [GenerationEvent::Error(_)]));
+    // The engine stops once all the handles have been dropped.
+    drop(handle);
+    thread.join().unwrap();
+    Ok(())
+}
+
+#[test]
+fn engine_cancellation() -> Result<()> {
+    let dev = &Device::Cpu;
+    let cache = PagedKvCache::new(1, 16, 4, 1, DIM, DType::F32, dev)?;
+    let mut engine = Engine::new(ToyModel::new(dev)?, cache, EngineConfig::default());
+    let prompts = prompts();
+    let (c0, c1) = (CancellationToken::new(), CancellationToken::new());
+    let request = GenerationRequest::new(prompts[0].clone(), 8).with_cancellation(c0.clone());
+    let s0 = engine.submit(request)?;
+    let request = GenerationRequest::new(prompts[1].clone(), 8).with_cancellation(c1.clone());
+    let s1 = engine.submit(request)?;
+    let s2 = engine.submit(GenerationRequest::new(prompts[2].clone(), 8))?;
+    // The second request is cancelled before being scheduled.
+    c1.cancel();
+    assert!(engine.step()?);
+    assert_eq!(engine.num_running(), 2);
+    assert!(engine.step()?);
+    c0.cancel();
+    assert!(engine.step()?);
+    assert_eq!(engine.num_running(), 1);
+    while engine.step()? {}
+    assert_eq!(engine.cache().num_free_blocks(), 16);
+    let (tokens, reason) = s0.tokens()?;
+    assert_eq!((tokens.len(), reason), (2, FinishReason::Cancelled));
+    assert_eq!(s1.tokens()?, (vec![], FinishReason::Cancelled));
+    assert_eq!(s2.tokens()?.1, FinishReason::Length);
+    Ok(())
+}
+
+// The embeddings followed by a projection with LoRA adapters, there is no attention as the
+// tokens only have to be processed with the adapter of their sequence.
+struct LoraModel {
+    emb: Tensor,
+    head: MultiLoraLinear,
+    selection: AdapterSelection,
+}
+
+impl LoraModel {
+    fn new(dev: &Device) -> Result<Self> {
+        let w = |shape: (usize, usize)| Tensor::randn(0f32, 1., shape, dev);
+        let selection = AdapterSelection::new();
+        let mut head = MultiLoraLinear::new(Linear::new(w((VOCAB, DIM))?, None), &selection);
+        for id in 0..2 {
+            head.add_adapter(id, LoraWeights::new(w((2, DIM))?, w((VOCAB, 2))?, 8.)?)?;
+        }
+        Ok(Self {
+            emb: w((VOCAB, DIM))?,
+            head,
+            selection,
+        })
+    }
+}
+
+impl EngineModel for LoraModel {
+    fn forward(
+        &mut self,
+        tokens: &Tensor,
+        _positions: &Tensor,
+        batch: &PagedBatch,
+        _cache: &PagedKvCache,
+    ) -> Result<Tensor> {
+        let logits = self.head.forward(&self.emb.index_select(tokens, 0)?)?;
+        let mut last = vec![];
+        let mut offset = 0;
+        for &len in batch.num_new_tokens() {
+            offset += len;
+            last.push(offset as u32 - 1)
+        }
+        logits.index_select(&Tensor::new(last, tokens.device())?, 0)
+    }
+
+    fn has_adapter(&self, id: usize) -> bool {
+        self.head.has_adapter(id)
+    }
+
+    fn set_adapters(&mut self, segments: &[AdapterSegment]) -> Result<()> {
+        self.selection.set(segments.to_vec())
+    }
+}
+
+#[test]
+fn engine_adapters() -> Result<()> {
+    let dev = &Device::Cpu;
+    let model = LoraModel::new(dev)?;
+    let cache = || PagedKvCache::new(1, 16, 4, 1, DIM, DType::F32, dev);
+    let mut engine = Engine::new(model, cache()?, EngineConfig::default());
+    let prompt = prompts()[0].clone();
+    let request = |adapter: Option<usize>| {
+        let request = GenerationRequest::new(prompt.clone(), 6);
+        match adapter {
+            Some(adapter) => request.with_adapter(adapter),
+            None => request,
+        }
+    };
+    let adapters = [Some(1), None, Some(0), Some(1)];
+    let mut expected = vec![];
+    for &adapter in adapters.iter() {
+        let stream = engine.submit(request(adapter))?;
+        while engine.step()? {}
+        expected.push(stream.tokens()?.0)
+    }
+    assert_ne!(expected[0], expected[1]);
+    assert_ne!(expected[0], expected[2]);
+    assert_eq!(expected[0], expected[3]);
+
+    // The requests with different adapters are batched together.
+    let streams = adapters
+        .iter()
+        .map(|&adapter| engine.submit(request(adapter)))
+        .collect::<Result<Vec<_>>>()?;
+    assert!(engine.step()?);
+    assert_eq!(engine.num_running(), 4);
+    while engine.step()? {}
+    for (stream, expected) in streams.into_iter().zip(expected) {
+        assert_eq!(stream.tokens()?.0, expected)
+    }
+
+    let stream = engine.submit(request(Some(2)))?;
+    assert!(!engine.step()?);
+    let events = stream.collect::<Vec<_>>();
+    assert!(matches!(events[..], [GenerationEvent::Error(_)]));
+    Ok(())
+}
+
+#[test]
+fn engine_prefilled_requests() -> Result<()> {
+    use candle_nn::kv_transfer::KvBlocks;
+    use candle_transformers::generation::engine::prefill;
+
+    let dev = &Device::Cpu;
+    let model = ToyModel::new(dev)?;
+    let prompts = prompts();
+    let expected = generate_alone(clone(&model), &prompts[2], 6)?;
+
+    // The prefill worker uses its own cache, the blocks are serialized to reach the decoder.
+    let mut prefill_model = clone(&model);
+    let mut prefill_cache = PagedKvCache::new(1, 8, 4, 1, DIM, DType::F32, dev)?;
+    let (logits, blocks) = prefill(&mut prefill_model, &mut prefill_cache, &prompts[2], None)?;
+    assert_eq!(prefill_cache.num_free_blocks(), 8);
+    assert_eq!(blocks.seq_len(), prompts[2].len());
+    let first = logits.argmax(0)?.to_scalar::<u32>()?;
+    assert_eq!(first, expected[0]);
+    let blocks = KvBlocks::from_bytes(&blocks.to_bytes()?, dev)?;
+
+    let cache = PagedKvCache::new(1, 16, 4, 1, DIM, DType::F32, dev)?;
+    let mut engine = Engine::new(clone(&model), cache, EngineConfig::default());
+    let s0 = engine.submit(GenerationRequest::new(prompts[0].clone(), 6))?;
+    let mut prompt = prompts[2].clone();
+    prompt.push(first);
+    let s1 = engine.submit_prefilled(GenerationRequest::new(prompt, 5), blocks.clone())?;
+    // The prefilled blocks have to leave at least one token of the prompt to process.
+    let s2 = engine.submit_prefilled(GenerationRequest::new(prompts[2].clone(), 5), blocks)?;
+    while engine.step()? {}
+    assert_eq!(engine.cache().num_free_blocks(), 16);
+    assert_eq!(s1.tokens()?, (expected[1..].to_vec(), FinishReason::Length));
+    assert_eq!(
+        s0.tokens()?,
+        (
+            generate_alone(clone(&model), &prompts[0], 6)?,
+            FinishReason::Length
+        )
+    );
+    assert!(matches!(
+        s2.into_iter().next(),
+        Some(GenerationEvent::Error(_))
+    ));
+    Ok(())
+}
+
+#[test]
+fn engine_chunked_prefill() -> Result<()> {
+    let dev = &Device::Cpu;
+    let model = ToyModel::new(dev)?;
+    let expected = prompts()
+        .iter()
+        .map(|p| generate_alone(clone(&model), p, 6))
+        .collect::<Result<Vec<_>>>()?;
+
+    let cache = PagedKvCache::new(1, 16, 4, 1, DIM, DType::F32, dev)?;
+    let config = EngineConfig {
+        max_batch_tokens: 4,
+        chunked_prefill: true,
+        ..Default::default()
+    };
+    let mut engine = Engine::new(clone(&model), cache, config);
+    let streams = prompts()
+        .into_iter()
+        .map(|p| engine.submit(GenerationRequest::new(p, 6)))
+        .collect::<Result<Vec<_>>>()?;
+    let mut num_steps = 0;
+    while engine.step()? {
+        num_steps += 1
+    }
+    // The 16 prompt tokens take at least 4 steps, the decoding of the first sequences overlaps
+    // with the prefill of the last one.
+    assert!(num_steps > 6);
+    for (stream, expected) in streams.into_iter().zip(expected) {
+        assert_eq!(stream.tokens()?, (expected, FinishReason::Length));
+    }
+    Ok(())
+}
+
+#[test]
+fn engine_priorities() -> Result<()> {
+    let dev = &Device::Cpu;
+    let model = ToyModel::new(dev)?;
+    let prompts = prompts();
+    let expected_low = generate_alone(clone(&model), &prompts[2], 6)?;
+    let expected_high = generate_alone(clone(&model), &prompts[0], 6)?;
+    for offload_preempted in [false, true] {
+        let cache = PagedKvCache::new(1, 16, 4, 1, DIM, DType::F32, dev)?;
+        let config = EngineConfig {
+            max_batch_size: 1,
+            offload_preempted,
+            ..Default::default()
+        };
+        let mut engine = Engine::new(clone(&model), cache, config);
+        let low = GenerationRequest::new(prompts[2].clone(), 6).with_priority(Priority::Low);
+        let low = engine.submit(low)?;
+        assert!(engine.step()?);
+        assert!(engine.step()?);
+        // The high priority request preempts the running one.
+        let high = GenerationRequest::new(prompts[0].clone(), 6).with_priority(Priority::High);
+        let high = engine.submit(high)?;
+        assert!(engine.step()?);
+        assert_eq!((engine.num_running(), engine.num_waiting()), (1, 1));
+        while engine.step()? {}
+        assert_eq!(engine.cache().num_free_blocks(), 16);
+        assert_eq!(
+            high.tokens()?,
+            (expected_high.clone(), FinishReason::Length)
+        );
+        assert_eq!(low.tokens()?, (expected_low.clone(), FinishReason::Length));
+        // The 11 tokens cached for the low priority sequence are only processed again when they
+        // are not offloaded.
+        let num_tokens = if offload_preempted { 25 } else { 36 };
+        assert_eq!(engine.model().num_tokens, num_tokens);
+    }
+    Ok(())
+}
+
+#[test]
+fn engine_fairness() -> Result<()> {
+    let dev = &Device::Cpu;
+    let model = ToyModel::new(dev)?;
+    let prompts = prompts();
+    let cache = PagedKvCache::new(1, 16, 4, 1, DIM, DType::F32, dev)?;
+    let config = EngineConfig {
+        max_batch_size: 1,
+        ..Default::default()
+    };
+    let mut engine =
+        Engine::new(clone(&model), cache, config).with_fairness(FairShare::new(0., 8.));
+    let request =
+        |i: usize, client| GenerationRequest::new(prompts[i].clone(), 4).with_client(client);
+    let a1 = engine.submit(request(0, "a"))?;
+    let mut a2 = engine.submit(request(0, "a"))?;
+    let mut b = engine.submit(request(1, "b"))?;
+    // The first request of a uses its 8 tokens, the request of b goes before its second one.
+    for _step in 0..8 {
+        assert!(engine.step()?);
+    }
+    assert_eq!(a1.tokens()?.0.len(), 4);
+    let mut events = vec![];
+    while let Some(event) = b.try_next() {
+        events.push(event)
+    }
+    assert_eq!(events.len(), 5);
+    assert_eq!(events[4], GenerationEvent::Finished(FinishReason::Length));
+    assert!(a2.try_next().is_none());
+    while engine.step()? {}
+    assert_eq!(a2.tokens()?.0.len(), 4);
+    Ok(())
+}
+
+// Uses a single sequence per step and records the stats of the steps.
+struct Recorder(std::sync::Arc<std::sync::Mutex<Vec<StepStats>>>);
+
+impl BatchingPolicy for Recorder {
+    fn budget(&mut self, _state: &SchedulerState) -> StepBudget {
+        StepBudget {
+            max_batch_size: 1,
+            max_batch_tokens: 3,
+            chunked_prefill: true,
+        }
+    }
+
+    fn record(&mut self, stats: &StepStats) {
+        self.0.lock().unwrap().push(stats.clone())
+    }
+}
+
+#[test]
+fn engine_batching_policy() -> Result<()> {
+    let dev = &Device::Cpu;
+    let model = ToyModel::new(dev)?;
+    let prompts = prompts();
+    let expected = generate_alone(clone(&model), &prompts[0], 2)?;
+    let stats = std::sync::Arc::new(std::sync::Mutex::new(vec![]));
+    let cache = PagedKvCache::new(1, 16, 4, 1, DIM, DType::F32, dev)?;
+    let mut engine = Engine::new(clone(&model), cache, EngineConfig::default())
+        .with_policy(Recorder(stats.clone()));
+    let s0 = engine.submit(GenerationRequest::new(prompts[0].clone(), 2))?;
+    let s1 = engine.submit(GenerationRequest::new(prompts[1].clone(), 2))?;
+    while engine.step()? {}
+    assert_eq!(s0.tokens()?, (expected, FinishReason::Length));
+    assert_eq!(s1.tokens()?.0.len(), 2);
+    let stats = stats.lock().unwrap();
+    let tokens = stats
+        .iter()
+        .map(|s| {
+            (
+                s.num_tokens,
+                s.num_decode_sequences,
+                s.time_to_first_token.len(),
+            )
+        })
+        .collect::<Vec<_>>();
+    // The 5 tokens prompt is split in two chunks, the second request waits for the first one.
+    assert_eq!(
+        tokens,
+        [(3, 0, 0), (2, 0, 1), (1, 1, 0), (1, 0, 1), (1, 1, 0)]
+    );
+    Ok(())
+}
+
+#[test]
+fn slo_policy_adapts() {
+    let slo = LatencySlo::new(Duration::from_millis(200), Duration::from_millis(20));
+    let mut policy = SloPolicy::new(slo, 8, 256).with_min_batch_tokens(32);
+    let state = SchedulerState {
+        num_running: 8,
+        num_waiting: 2,
+        oldest_waiting: Some(Duration::from_millis(10)),
+    };
+    let step = |duration, num_sequences, num_prefill_tokens| StepStats {
+        duration: Duration::from_millis(duration),
+        num_sequences,
+        num_decode_sequences: num_sequences,
+        num_tokens: num_sequences + num_prefill_tokens,
+        num_prefill_tokens,
+        time_to_first_token: vec![],
+    };
+    assert_eq!(policy.budget(&state).max_batch_tokens, 256);
+    assert!(policy.budget(&state).chunked_prefill);
+    // A slow step with some prefill reduces the token budget.
+    policy.record(&step(30, 8, 200));
+    assert_eq!((policy.batch_tokens(), policy.batch_size()), (128, 8));
+    policy.record(&step(30, 8, 100));
+    policy.record(&step(30, 8, 50));
+    assert_eq!(policy.batch_tokens(), 32);
+    // The decoding alone is too slow, fewer sequences are admitted.
+    policy.record(&step(25, 8, 0));
+    assert_eq!((policy.batch_tokens(), policy.batch_size()), (32, 6));
+    assert_eq!(policy.budget(&state).max_batch_size, 6);
+    // Fast steps grow the budget back.
+    policy.record(&step(5, 6, 10));
+    assert_eq!((policy.batch_tokens(), policy.batch_size()), (48, 7));
+    // The full budget is used when a request waited longer than the time to first token.
+    let late = SchedulerState {
+        oldest_waiting: Some(Duration::from_millis(300)),
+        ..state
+    };
+    assert_eq!(policy.budget(&late).max_batch_tokens, 256);
+    assert_eq!(policy.budget(&state).max_batch_tokens, 48);
+}
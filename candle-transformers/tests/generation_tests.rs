@@ -1,5 +1,9 @@
 use candle::{Device, Result, Tensor};
-use candle_transformers::generation::LogitsProcessor;
+use candle_transformers::generation::processors::{
+    Dry, FrequencyPenalty, MinP, Mirostat, Processor, RepetitionPenalty, TailFree, Temperature,
+    TopK, TopP, Typical,
+};
+use candle_transformers::generation::{LogitsProcessor, Sampling};
 
 #[test]
 fn sample_with_zero_temperature() -> Result<()> {
@@ -54,3 +58,117 @@ fn sample_with_top_k() -> Result<()> {
     assert_eq!(token, 2);
     Ok(())
 }
+
+// The tokens that are not filtered out by the processor.
+fn kept(processor: &mut impl Processor, prs: &[f32]) -> Result<Vec<usize>> {
+    let mut logits = prs.iter().map(|p| p.ln()).collect::<Vec<_>>();
+    processor.process(&mut logits, &[])?;
+    Ok((0..logits.len())
+        .filter(|&i| logits[i] > f32::NEG_INFINITY)
+        .collect())
+}
+
+#[test]
+fn filtering_processors() -> Result<()> {
+    let prs = [0.5, 0.3, 0.1, 0.1];
+    assert_eq!(kept(&mut TopK::new(3), &prs)?, [0, 1, 2]);
+    assert_eq!(kept(&mut TopK::new(10), &prs)?, [0, 1, 2, 3]);
+    assert_eq!(kept(&mut TopP::new(0.75), &prs)?, [0, 1]);
+    assert_eq!(kept(&mut TopP::new(0.1), &prs)?, [0]);
+    assert_eq!(kept(&mut MinP::new(0.3), &prs)?, [0, 1]);
+    assert_eq!(kept(&mut MinP::new(0.1), &prs)?, [0, 1, 2, 3]);
+    // The second token has the surprise closest to the entropy.
+    assert_eq!(kept(&mut Typical::new(0.2), &prs)?, [1]);
+    assert_eq!(kept(&mut Typical::new(0.5), &prs)?, [0, 1]);
+    assert_eq!(kept(&mut TailFree::new(0.5), &prs)?, [0, 1]);
+    assert_eq!(kept(&mut TailFree::new(1.), &prs)?, [0, 1, 2, 3]);
+
+    let mut logits = vec![1f32, 2., 4.];
+    Temperature::new(2.).process(&mut logits, &[])?;
+    assert_eq!(logits, [0.5, 1., 2.]);
+    assert!(Temperature::new(0.).process(&mut logits, &[]).is_err());
+    Ok(())
+}
+
+#[test]
+fn penalty_processors() -> Result<()> {
+    let mut logits = vec![1f32, -1., 2., 3.];
+    // Only the last two tokens of the context are penalized.
+    RepetitionPenalty::new(2., 2).process(&mut logits, &[0, 1, 2, 2])?;
+    assert_eq!(logits, [1., -1., 1., 3.]);
+    RepetitionPenalty::new(2., 0).process(&mut logits, &[0, 1, 2, 2])?;
+    assert_eq!(logits, [0.5, -2., 0.5, 3.]);
+
+    let mut logits = vec![1f32, 1., 1., 1.];
+    FrequencyPenalty::new(0.5, 1., 0).process(&mut logits, &[0, 0, 2, 7])?;
+    assert_eq!(logits, [-1., 1., -0.5, 1.]);
+
+    // The context ends with "1 2 3" which was followed by 4.
+    let context = [1, 2, 3, 4, 1, 2, 3];
+    let dry = Dry::new(1., 2., 2, 0);
+    let lengths = dry.match_lengths(&context);
+    assert_eq!(lengths.get(&4), Some(&3));
+    let mut logits = vec![0f32; 5];
+    dry.clone().process(&mut logits, &context)?;
+    assert_eq!(logits, [0., 0., 0., 0., -2.]);
+    // The sequences cannot extend over a breaker.
+    let mut logits = vec![0f32; 5];
+    dry.clone()
+        .with_sequence_breakers(&[2])
+        .process(&mut logits, &context)?;
+    assert_eq!(logits, [0.; 5]);
+    // The earlier occurrence is outside of the window.
+    let mut logits = vec![0f32; 5];
+    Dry::new(1., 2., 2, 5).process(&mut logits, &context)?;
+    assert_eq!(logits, [0.; 5]);
+    Ok(())
+}
+
+#[test]
+fn mirostat() -> Result<()> {
+    let prs = [0.5f32, 0.3, 0.1, 0.1];
+    let mut mirostat = Mirostat::new(1., 0.1);
+    assert_eq!(mirostat.mu(), 2.);
+    // The surprises are 1, 1.74 and 3.32 bits.
+    assert_eq!(kept(&mut mirostat, &prs)?, [0, 1]);
+    // mu decreases by eta times the difference between the surprise and tau.
+    let logits = prs.iter().map(|p| p.ln()).collect::<Vec<_>>();
+    mirostat.accept(0, &logits)?;
+    assert!((mirostat.mu() - 2.).abs() < 1e-6, "{}", mirostat.mu());
+    mirostat.accept(2, &logits)?;
+    let expected = 2. - 0.1 * (-0.1f32.log2() - 1.);
+    assert!((mirostat.mu() - expected).abs() < 1e-6, "{}", mirostat.mu());
+    mirostat.reset();
+    assert_eq!(mirostat.mu(), 2.);
+    Ok(())
+}
+
+#[test]
+fn sample_with_processors() -> Result<()> {
+    let logits = Tensor::new(&[0.1f32, 0.2, 0.3, 0.4], &Device::Cpu)?;
+    let mut logits_process = LogitsProcessor::from_sampling(42, Sampling::ArgMax)
+        .with_processor(RepetitionPenalty::new(10., 0));
+    assert_eq!(logits_process.sample(&logits)?, 3);
+    assert_eq!(logits_process.sample_with_context(&logits, &[3])?, 2);
+    assert_eq!(logits_process.sample_with_context(&logits, &[3, 2])?, 1);
+
+    let logits = Tensor::new(&[0.5f32, 0.3, 0.1, 0.1], &Device::Cpu)?.log()?;
+    let mut logits_process = LogitsProcessor::from_sampling(42, Sampling::All { temperature: 1. })
+        .with_processor(FrequencyPenalty::new(0., 10., 0))
+        .with_processor(MinP::new(0.3));
+    let mut counts = [0; 4];
+    for _ in 0..200 {
+        counts[logits_process.sample_with_context(&logits, &[1])? as usize] += 1
+    }
+    // Token 1 is penalized below the min-p threshold.
+    assert_eq!(counts, [200, 0, 0, 0]);
+
+    let mut logits_process = LogitsProcessor::from_sampling(42, Sampling::All { temperature: 1. })
+        .with_processor(Mirostat::new(1., 0.1));
+    for _ in 0..200 {
+        let token = logits_process.sample(&logits)?;
+        assert!(token < 2, "{token}");
+    }
+    logits_process.reset();
+    Ok(())
+}
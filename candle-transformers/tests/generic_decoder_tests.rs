@@ -0,0 +1,150 @@
+use candle::test_utils::max_diff;
+use candle::{DType, Device, Result, Tensor};
+use candle_nn::{VarBuilder, VarMap};
+use candle_transformers::config::ModelConfig;
+use candle_transformers::models::generic_decoder::{Architecture, Model};
+use candle_transformers::models::qwen2;
+
+const QWEN2: &str = r#"{
+    "vocab_size": 64,
+    "hidden_size": 32,
+    "intermediate_size": 48,
+    "num_layers": 2,
+    "num_attention_heads": 4,
+    "num_key_value_heads": 2,
+    "max_position_embeddings": 64,
+    "attention": { "qkv_bias": true },
+    "positions": { "type": "rope", "theta": 10000.0 },
+    "norm": { "eps": 1e-6 },
+    "tie_word_embeddings": true
+}"#;
+
+#[test]
+fn generic_decoder_matches_qwen2() -> Result<()> {
+    let dev = &Device::Cpu;
+    let varmap = VarMap::new();
+    let vb = VarBuilder::from_varmap(&varmap, DType::F32, dev);
+    let cfg = qwen2::Config {
+        vocab_size: 64,
+        hidden_size: 32,
+        intermediate_size: 48,
+        num_hidden_layers: 2,
+        num_attention_heads: 4,
+        num_key_value_heads: 2,
+        max_position_embeddings: 64,
+        sliding_window: 64,
+        max_window_layers: 2,
+        tie_word_embeddings: true,
+        rope_theta: 10000.,
+        rms_norm_eps: 1e-6,
+        use_sliding_window: false,
+        hidden_act: candle_nn::Activation::Silu,
+    };
+    let mut reference = qwen2::ModelForCausalLM::new(&cfg, vb.clone())?;
+    let arch = Architecture::from_json(QWEN2)?;
+    let mut model = Model::new(&arch, vb)?;
+    let n_vars = varmap.all_vars().len();
+
+    let prompt = Tensor::new(&[[3u32, 14, 15, 9, 26]], dev)?;
+    let expected = reference.forward(&prompt, 0)?.squeeze(1)?;
+    let logits = model.forward(&prompt, 0)?;
+    assert_eq!(logits.dims(), &[1, 64]);
+    assert!(max_diff(&logits, &expected)? < 1e-5);
+    // The generic model uses the same weights, no new variable has been created.
+    assert_eq!(varmap.all_vars().len(), n_vars);
+
+    // A decoding step using the kv cache.
+    let next = Tensor::new(&[[5u32]], dev)?;
+    let expected = reference.forward(&next, 5)?.squeeze(1)?;
+    let logits = model.forward(&next, 5)?;
+    assert!(max_diff(&logits, &expected)? < 1e-5);
+
+    model.clear_kv_cache();
+    let all = Tensor::new(&[[3u32, 14, 15, 9, 26, 5]], dev)?;
+    assert!(max_diff(&model.forward(&all, 0)?, &logits)? < 1e-5);
+    Ok(())
+}
+
+#[test]
+fn generic_decoder_variants() -> Result<()> {
+    let dev = &Device::Cpu;
+    let arch = Architecture::from_json(
+        r#"{
+        "vocab_size": 32,
+        "hidden_size": 16,
+        "intermediate_size": 64,
+        "num_layers": 2,
+        "num_attention_heads": 2,
+        "max_position_embeddings": 16,
+        "attention": { "qkv_bias": true, "out_bias": true, "sliding_window": 3, "softcap": 30.0 },
+        "positions": { "type": "learned" },
+        "mlp": { "kind": "plain", "activation": "gelu", "bias": true },
+        "norm": { "kind": "layer", "eps": 1e-5 },
+        "block": "parallel",
+        "final_logit_softcap": 10.0,
+        "weights": { "model": "transformer", "layers": "h", "attention": "attn", "mlp": "ffn" }
+    }"#,
+    )?;
+    let varmap = VarMap::new();
+    let vb = VarBuilder::from_varmap(&varmap, DType::F32, dev);
+    let mut model = Model::new(&arch, vb)?;
+    let names = varmap.named_vars();
+    let names: Vec<_> = names.iter().map(|(n, _)| n.as_str()).collect();
+    assert!(names.contains(&"transformer.embed_positions.weight"));
+    assert!(names.contains(&"transformer.h.1.attn.o_proj.bias"));
+    assert!(names.contains(&"transformer.h.0.ffn.up_proj.bias"));
+    assert!(!names.contains(&"transformer.h.0.ffn.gate_proj.weight"));
+    assert!(!names.contains(&"transformer.h.0.post_attention_layernorm.weight"));
+    assert!(names.contains(&"transformer.norm.bias"));
+    assert!(names.contains(&"lm_head.weight"));
+
+    let prompt = Tensor::new(&[[1u32, 2, 3, 4, 5, 6], [6, 5, 4, 3, 2, 1]], dev)?;
+    let logits = model.forward(&prompt, 0)?;
+    assert_eq!(logits.dims(), &[2, 32]);
+    let max = logits.abs()?.flatten_all()?.max(0)?.to_scalar::<f32>()?;
+    assert!(max <= 10.);
+
+    // With a window of 3 tokens and two layers, the last token sees the 5 last tokens: changing
+    // the first token has no effect on the logits while changing the second one does.
+    model.clear_kv_cache();
+    let other = Tensor::new(&[[9u32, 9, 9, 4, 5, 6]], dev)?;
+    let first = model.forward(&prompt.narrow(0, 0, 1)?, 0)?;
+    model.clear_kv_cache();
+    let second = model.forward(&other, 0)?;
+    assert!(max_diff(&first, &second)? > 0.);
+    model.clear_kv_cache();
+    let third = model.forward(&Tensor::new(&[[0u32, 2, 3, 4, 5, 6]], dev)?, 0)?;
+    assert!(max_diff(&first, &third)? < 1e-6);
+    Ok(())
+}
+
+#[test]
+fn generic_decoder_validation() {
+    let arch = |json: &str| Architecture::from_json(json).map_err(|e| e.to_string());
+    let base = r#""vocab_size": 32, "hidden_size": 16, "intermediate_size": 64, "num_layers": 2"#;
+    let err = arch(&format!(r#"{{ {base}, "num_attention_heads": 3 }}"#)).unwrap_err();
+    assert!(err.contains("hidden_size"), "{err}");
+    let err = arch(&format!(
+        r#"{{ {base}, "num_attention_heads": 4, "num_key_value_heads": 3 }}"#
+    ))
+    .unwrap_err();
+    assert!(err.contains("num_attention_heads"), "{err}");
+    let err = arch(&format!(
+        r#"{{ {base}, "num_attention_heads": 4, "positions": {{ "type": "rope", "partial_rotary_factor": 1.5 }} }}"#
+    ))
+    .unwrap_err();
+    assert!(err.contains("partial_rotary_factor"), "{err}");
+    let err = arch(&format!(
+        r#"{{ {base}, "num_attention_heads": 4, "mlp": {{ "kind": "moe" }} }}"#
+    ))
+    .unwrap_err();
+    assert!(err.contains("moe"), "{err}");
+
+    let arch = arch(&format!(
+        r#"{{ {base}, "num_attention_heads": 4, "positions": {{ "type": "rope", "partial_rotary_factor": 0.5 }} }}"#
+    ))
+    .unwrap();
+    assert_eq!(arch.head_dim(), 4);
+    assert_eq!(arch.num_key_value_heads(), 4);
+    assert_eq!(arch.weights.q_proj, "q_proj");
+}
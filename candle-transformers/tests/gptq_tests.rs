@@ -0,0 +1,322 @@
+use candle::{DType, Device, Module, Result, Tensor};
+use candle_transformers::config::QuantizationConfig;
+use candle_transformers::gptq::{GptqConfig, GptqLinear};
+use candle_transformers::quantized_nn::QuantizedLinear;
+use std::collections::HashMap;
+
+// Packs the values along the given dimension, the first value uses the lowest bits.
+fn pack(values: &[Vec<u32>], bits: usize, along_rows: bool) -> Vec<Vec<i64>> {
+    let pack = 32 / bits;
+    let (rows, cols) = (values.len(), values[0].len());
+    let (rows, cols) = if along_rows {
+        (rows / pack, cols)
+    } else {
+        (rows, cols / pack)
+    };
+    (0..rows)
+        .map(|r| {
+            (0..cols)
+                .map(|c| {
+                    let packed = (0..pack).fold(0u32, |acc, k| {
+                        let v = if along_rows {
+                            values[r * pack + k][c]
+                        } else {
+                            values[r][c * pack + k]
+                        };
+                        acc | (v << (bits * k))
+                    });
+                    // The checkpoints store the packed values as i32.
+                    packed as i32 as i64
+                })
+                .collect()
+        })
+        .collect()
+}
+
+fn gptq_config(bits: usize, group_size: isize, checkpoint_format: &str) -> GptqConfig {
+    GptqConfig {
+        bits,
+        group_size,
+        desc_act: true,
+        sym: true,
+        checkpoint_format: checkpoint_format.to_string(),
+    }
+}
+
+#[test]
+fn gptq_linear() -> Result<()> {
+    let dev = &Device::Cpu;
+    let (in_dim, out_dim, group_size) = (32, 16, 8);
+    let n_groups = in_dim / group_size;
+    for (bits, format) in [(4, "gptq"), (2, "gptq"), (8, "gptq_v2")] {
+        let max = 1u32 << bits;
+        let zero_offset = if format == "gptq" { 1 } else { 0 };
+        let q: Vec<Vec<u32>> = (0..in_dim)
+            .map(|i| (0..out_dim).map(|o| (i * 7 + o * 3) as u32 % max).collect())
+            .collect();
+        let z: Vec<Vec<u32>> = (0..n_groups)
+            .map(|g| {
+                (0..out_dim)
+                    .map(|o| (g * 5 + o) as u32 % (max - 1))
+                    .collect()
+            })
+            .collect();
+        // Non-contiguous groups, as used with desc_act.
+        let g_idx: Vec<i64> = (0..in_dim).map(|i| ((i * 3) % n_groups) as i64).collect();
+        let scales = Tensor::rand(0.01f32, 0.1, (n_groups, out_dim), dev)?;
+        let s = scales.to_vec2::<f32>()?;
+        let expected: Vec<Vec<f32>> = (0..in_dim)
+            .map(|i| {
+                let g = g_idx[i] as usize;
+                (0..out_dim)
+                    .map(|o| (q[i][o] as f32 - (z[g][o] + zero_offset) as f32) * s[g][o])
+                    .collect()
+            })
+            .collect();
+        let expected = Tensor::new(expected, dev)?;
+
+        let tensors = HashMap::from([
+            (
+                "qweight".to_string(),
+                Tensor::new(pack(&q, bits, true), dev)?,
+            ),
+            (
+                "qzeros".to_string(),
+                Tensor::new(pack(&z, bits, false), dev)?,
+            ),
+            ("scales".to_string(), scales),
+            ("g_idx".to_string(), Tensor::new(g_idx, dev)?),
+        ]);
+        let vb = candle_nn::VarBuilder::from_tensors(tensors, DType::F32, dev);
+        let cfg = gptq_config(bits, group_size as isize, format);
+        let linear = GptqLinear::new(in_dim, out_dim, false, &cfg, vb)?;
+        let w = linear.dequantize()?;
+        let diff = (&w - &expected)?.abs()?.max_all()?.to_scalar::<f32>()?;
+        assert!(diff < 1e-6, "{bits} {diff}");
+
+        let xs = Tensor::randn(0f32, 1., (2, 3, in_dim), dev)?;
+        let ys = linear.forward(&xs)?;
+        let expected = xs.broadcast_matmul(&expected)?;
+        assert_eq!(ys.dims(), &[2, 3, out_dim]);
+        let diff = (ys - expected)?.abs()?.max_all()?.to_scalar::<f32>()?;
+        assert!(diff < 1e-5, "{bits} {diff}");
+    }
+    Ok(())
+}
+
+#[test]
+fn gptq_default_g_idx() -> Result<()> {
+    let dev = &Device::Cpu;
+    let (in_dim, out_dim, group_size) = (16, 8, 8);
+    let q: Vec<Vec<u32>> = (0..in_dim)
+        .map(|i| (0..out_dim).map(|o| ((i + o) % 16) as u32).collect())
+        .collect();
+    let z = vec![vec![7u32; out_dim], vec![3u32; out_dim]];
+    let scales = Tensor::new(&[[0.5f32; 8], [2f32; 8]], dev)?;
+    let tensors = HashMap::from([
+        ("qweight".to_string(), Tensor::new(pack(&q, 4, true), dev)?),
+        ("qzeros".to_string(), Tensor::new(pack(&z, 4, false), dev)?),
+        ("scales".to_string(), scales),
+    ]);
+    let vb = candle_nn::VarBuilder::from_tensors(tensors, DType::F32, dev);
+    let cfg = gptq_config(4, group_size, "gptq");
+    let w = GptqLinear::new(in_dim, out_dim, false, &cfg, vb)?.dequantize()?;
+    let w = w.to_vec2::<f32>()?;
+    assert_eq!(w[0][0], (0. - 8.) * 0.5);
+    assert_eq!(w[9][2], (11. - 4.) * 2.);
+    Ok(())
+}
+
+#[test]
+fn quantization_config() -> Result<()> {
+    let cfg: QuantizationConfig = serde_json::from_str(
+        r#"{"bits": 4, "group_size": 128, "desc_act": false, "quant_method": "gptq", "damp_percent": 0.01}"#,
+    )
+    .unwrap();
+    let gptq = cfg.gptq()?;
+    assert_eq!(gptq.group_size(4096), 128);
+    assert_eq!(gptq.checkpoint_format, "gptq");
+    assert!(gptq.sym);
+    gptq.check()?;
+    let cfg: QuantizationConfig =
+        serde_json::from_str(r#"{"bits": 4, "group_size": -1, "quant_method": "gptq"}"#).unwrap();
+    assert_eq!(cfg.gptq()?.group_size(4096), 4096);
+    assert!(gptq_config(3, 128, "gptq").check().is_err());
+    assert!(gptq_config(4, 128, "marlin").check().is_err());
+
+    let cfg: QuantizationConfig = serde_json::from_str(
+        r#"{"bits": 4, "group_size": 128, "quant_method": "awq", "version": "GEMM", "zero_point": true}"#,
+    )
+    .unwrap();
+    assert_eq!(cfg.quant_method, "awq");
+    assert!(cfg.gptq().is_err());
+    let cfg: QuantizationConfig =
+        serde_json::from_str(r#"{"bits": 8, "quant_method": "bitsandbytes"}"#).unwrap();
+    let vb = candle_nn::VarBuilder::zeros(DType::F32, &Device::Cpu);
+    assert!(QuantizedLinear::new(8, 4, false, Some(&cfg), vb).is_err());
+
+    // Without a quantization config the layer uses regular weights.
+    let dev = &Device::Cpu;
+    let weight = Tensor::randn(0f32, 1., (4, 8), dev)?;
+    let tensors = HashMap::from([("weight".to_string(), weight.clone())]);
+    let vb = candle_nn::VarBuilder::from_tensors(tensors, DType::F32, dev);
+    let linear = QuantizedLinear::new(8, 4, false, None, vb)?;
+    assert!(matches!(linear, QuantizedLinear::Linear(_)));
+    let xs = Tensor::randn(0f32, 1., (3, 8), dev)?;
+    let diff = (linear.forward(&xs)? - xs.matmul(&weight.t()?)?)?
+        .abs()?
+        .max_all()?
+        .to_scalar::<f32>()?;
+    assert!(diff < 1e-5, "{diff}");
+    Ok(())
+}
+
+// Adds the GPTQ tensors of a linear layer to `quantized` and its dequantized weight to `plain`.
+fn add_gptq_linear(
+    name: &str,
+    in_dim: usize,
+    out_dim: usize,
+    quantized: &mut HashMap<String, Tensor>,
+    plain: &mut HashMap<String, Tensor>,
+) -> Result<()> {
+    let dev = &Device::Cpu;
+    let group_size = 8;
+    let n_groups = in_dim / group_size;
+    let salt = name.len();
+    let q: Vec<Vec<u32>> = (0..in_dim)
+        .map(|i| {
+            (0..out_dim)
+                .map(|o| ((i * 7 + o * 3 + salt) % 16) as u32)
+                .collect()
+        })
+        .collect();
+    let z: Vec<Vec<u32>> = (0..n_groups)
+        .map(|g| {
+            (0..out_dim)
+                .map(|o| ((g * 5 + o + salt) % 15) as u32)
+                .collect()
+        })
+        .collect();
+    let scales = Tensor::rand(0.01f32, 0.05, (n_groups, out_dim), dev)?;
+    let s = scales.to_vec2::<f32>()?;
+    let w: Vec<Vec<f32>> = (0..out_dim)
+        .map(|o| {
+            (0..in_dim)
+                .map(|i| {
+                    let g = i / group_size;
+                    (q[i][o] as f32 - (z[g][o] + 1) as f32) * s[g][o]
+                })
+                .collect()
+        })
+        .collect();
+    let qweight = Tensor::new(pack(&q, 4, true), dev)?;
+    quantized.insert(format!("{name}.qweight"), qweight);
+    let qzeros = Tensor::new(pack(&z, 4, false), dev)?;
+    quantized.insert(format!("{name}.qzeros"), qzeros);
+    quantized.insert(format!("{name}.scales"), scales);
+    plain.insert(format!("{name}.weight"), Tensor::new(w, dev)?);
+    Ok(())
+}
+
+// The tensors of a llama/mistral checkpoint with GPTQ projections, and of the same checkpoint
+// with the dequantized weights.
+fn gptq_checkpoint(
+    hidden: usize,
+    intermediate: usize,
+    kv_dim: usize,
+    vocab: usize,
+) -> Result<(HashMap<String, Tensor>, HashMap<String, Tensor>)> {
+    let dev = &Device::Cpu;
+    let mut quantized = HashMap::new();
+    let mut plain = HashMap::new();
+    let p = "model.layers.0";
+    for (name, in_dim, out_dim) in [
+        ("self_attn.q_proj", hidden, hidden),
+        ("self_attn.k_proj", hidden, kv_dim),
+        ("self_attn.v_proj", hidden, kv_dim),
+        ("self_attn.o_proj", hidden, hidden),
+        ("mlp.gate_proj", hidden, intermediate),
+        ("mlp.up_proj", hidden, intermediate),
+        ("mlp.down_proj", intermediate, hidden),
+    ] {
+        add_gptq_linear(
+            &format!("{p}.{name}"),
+            in_dim,
+            out_dim,
+            &mut quantized,
+            &mut plain,
+        )?
+    }
+    for (name, shape) in [
+        ("model.embed_tokens.weight", vec![vocab, hidden]),
+        ("lm_head.weight", vec![vocab, hidden]),
+        ("model.norm.weight", vec![hidden]),
+        (&format!("{p}.input_layernorm.weight"), vec![hidden]),
+        (
+            &format!("{p}.post_attention_layernorm.weight"),
+            vec![hidden],
+        ),
+    ] {
+        let t = Tensor::randn(0f32, 1., shape, dev)?;
+        quantized.insert(name.to_string(), t.clone());
+        plain.insert(name.to_string(), t);
+    }
+    Ok((quantized, plain))
+}
+
+fn max_diff(a: &Tensor, b: &Tensor) -> Result<f32> {
+    (a - b)?.abs()?.max_all()?.to_scalar::<f32>()
+}
+
+#[test]
+fn gptq_llama_mistral_load() -> Result<()> {
+    use candle_transformers::models::{llama, mistral};
+    let dev = &Device::Cpu;
+    let (quantized, plain) = gptq_checkpoint(32, 48, 16, 10)?;
+    let tokens = Tensor::new(&[[1u32, 4, 2, 9, 7]], dev)?;
+    let quantization = r#"{"quant_method": "gptq", "bits": 4, "group_size": 8, "desc_act": false}"#;
+
+    let cfg = format!(
+        r#"{{"hidden_size": 32, "intermediate_size": 48, "vocab_size": 10, "num_hidden_layers": 1,
+            "num_attention_heads": 4, "num_key_value_heads": 2, "rms_norm_eps": 1e-5,
+            "max_position_embeddings": 64, "quantization_config": {quantization}}}"#
+    );
+    let cfg: llama::LlamaConfig = serde_json::from_str(&cfg).unwrap();
+    let cfg = cfg.into_config(false);
+    let plain_cfg = llama::Config {
+        quantization_config: None,
+        ..cfg.clone()
+    };
+    let forward = |tensors: &HashMap<String, Tensor>, cfg: &llama::Config| -> Result<Tensor> {
+        let vb = candle_nn::VarBuilder::from_tensors(tensors.clone(), DType::F32, dev);
+        let model = llama::Llama::load(vb, cfg)?;
+        let mut cache = llama::Cache::new(false, DType::F32, cfg, dev)?;
+        model.forward(&tokens, 0, &mut cache)
+    };
+    let ys = forward(&quantized, &cfg)?;
+    let expected = forward(&plain, &plain_cfg)?;
+    assert_eq!(ys.dims(), [1, 10]);
+    assert!(max_diff(&ys, &expected)? < 1e-4);
+    // The unquantized weights cannot be loaded with the quantization config.
+    assert!(forward(&plain, &cfg).is_err());
+
+    let cfg = format!(
+        r#"{{"vocab_size": 10, "hidden_size": 32, "intermediate_size": 48, "num_hidden_layers": 1,
+            "num_attention_heads": 4, "num_key_value_heads": 2, "max_position_embeddings": 64,
+            "rms_norm_eps": 1e-5, "rope_theta": 10000.0, "sliding_window": null,
+            "quantization_config": {quantization}}}"#
+    );
+    let cfg: mistral::Config = serde_json::from_str(&cfg).unwrap();
+    let plain_cfg = mistral::Config {
+        quantization_config: None,
+        ..cfg.clone()
+    };
+    let forward = |tensors: &HashMap<String, Tensor>, cfg: &mistral::Config| -> Result<Tensor> {
+        let vb = candle_nn::VarBuilder::from_tensors(tensors.clone(), DType::F32, dev);
+        mistral::Model::new(cfg, vb)?.forward(&tokens, 0)
+    };
+    let ys = forward(&quantized, &cfg)?;
+    let expected = forward(&plain, &plain_cfg)?;
+    assert!(max_diff(&ys, &expected)? < 1e-4);
+    Ok(())
+}
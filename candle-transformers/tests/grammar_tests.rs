@@ -0,0 +1,224 @@
+use candle::{Device, Result, Tensor};
+use candle_transformers::generation::{
+    json_schema::json_schema_to_gbnf, Grammar, GrammarConstraint, LogitsProcessor,
+};
+
+const ANSWER: &str = r#"
+# A comment.
+root   ::= answer ws
+answer ::= "yes" | "no"
+         | "maybe (" [0-9]+ "%)"
+ws     ::= [ \t\n]*
+"#;
+
+#[test]
+fn grammar_matches() -> Result<()> {
+    let grammar = Grammar::parse(ANSWER)?;
+    for text in ["yes", "no \n", "maybe (42%)", "maybe (0%)\t"] {
+        assert!(grammar.matches(text), "{text}")
+    }
+    for text in ["", "ye", "yes!", "maybe ()", "maybe (4a%)", " no"] {
+        assert!(!grammar.matches(text), "{text}")
+    }
+
+    let grammar = Grammar::parse(
+        r#"root ::= ( [a-c] | "\x41" ){2,3} [^a-z]? (. "\n")*
+        "#,
+    )?;
+    for text in ["ab", "aAc", "abcZ", "ab?x\n\u{e9}\n"] {
+        assert!(grammar.matches(text), "{text}")
+    }
+    for text in ["a", "abcab", "abz", "ab?x"] {
+        assert!(!grammar.matches(text), "{text}")
+    }
+    let grammar = Grammar::parse("root ::= \"a\"{2} | \"b\"{1,}")?;
+    assert!(grammar.matches("aa") && grammar.matches("bbb"));
+    assert!(!grammar.matches("a") && !grammar.matches("aaa") && !grammar.matches(""));
+    Ok(())
+}
+
+#[test]
+fn grammar_errors() {
+    for (src, msg) in [
+        ("answer ::= \"a\"", "missing root rule"),
+        ("root ::= a", "undefined rule a"),
+        ("root ::= root \"a\" | \"a\"", "left recursive"),
+        ("root ::= x\nx ::= [a]? root", "left recursive"),
+        ("root ::= \"\"?", "nothing to repeat"),
+        ("root ::= (\"a\"", "expected ')'"),
+        ("root ::= \"a\"{3,1}", "invalid repetition bounds"),
+        ("root ::= \"a\"\nroot ::= \"b\"", "defined twice"),
+    ] {
+        let err = Grammar::parse(src).unwrap_err().to_string();
+        assert!(err.contains(msg), "{src}: {err}")
+    }
+}
+
+fn vocab() -> Vec<Vec<u8>> {
+    [
+        "<eos>", "y", "yes", "no", "n", "o", "maybe", " (", "4", "42", "%)", " ", "s", "e", "", "(",
+    ]
+    .iter()
+    .map(|s| s.as_bytes().to_vec())
+    .collect()
+}
+
+#[test]
+fn grammar_token_mask() -> Result<()> {
+    let vocab = vocab();
+    let grammar = Grammar::parse(ANSWER)?;
+    let mut constraint = GrammarConstraint::new(grammar, &vocab, &[0])?;
+    assert_eq!(constraint.allowed_tokens(), [1, 2, 3, 4, 6]);
+    constraint.advance(6)?;
+    // The tokens can stop in the middle of a literal.
+    assert_eq!(constraint.allowed_tokens(), [7, 11]);
+    assert!(constraint.advance(8).is_err());
+    constraint.advance(7)?;
+    constraint.advance(9)?;
+    // More digits or the closing parenthesis.
+    assert_eq!(constraint.allowed_tokens(), [8, 9, 10]);
+    constraint.advance(10)?;
+    assert!(constraint.is_complete() && !constraint.is_finished());
+    assert_eq!(constraint.allowed_tokens(), [0, 11]);
+    // The disallowed tokens get a minus infinity logit.
+    let logits = Tensor::ones(vocab.len(), candle::DType::F32, &Device::Cpu)?;
+    let masked = constraint.mask_logits(&logits)?.to_vec1::<f32>()?;
+    for (token, &l) in masked.iter().enumerate() {
+        assert_eq!(l == 1., token == 0 || token == 11, "{token} {l}")
+    }
+    constraint.advance(0)?;
+    assert!(constraint.is_finished());
+
+    constraint.reset();
+    constraint.advance(4)?;
+    assert_eq!(constraint.allowed_tokens(), [5]);
+    assert!(constraint.advance(0).is_err());
+
+    // None of the tokens can start the grammar.
+    let grammar = Grammar::parse("root ::= \"z\"")?;
+    let constraint = GrammarConstraint::new(grammar, &vocab, &[0])?;
+    assert!(constraint.allowed_tokens().is_empty());
+    assert!(constraint.mask_logits(&logits).is_err());
+    Ok(())
+}
+
+#[test]
+fn grammar_sample_constrained() -> Result<()> {
+    let vocab = vocab();
+    let grammar = Grammar::parse(ANSWER)?;
+    let mut constraint = GrammarConstraint::new(grammar, &vocab, &[0])?;
+    // A model that prefers the disallowed tokens.
+    let logits = Tensor::new(
+        &[
+            0f32, 0.5, 0.1, 0.2, 0.3, 5., 0.4, 0.6, 0.7, 0.8, 0.9, 1., 6., 7., 8., 0.,
+        ],
+        &Device::Cpu,
+    )?;
+    for seed in 0..10 {
+        let mut logits_processor = LogitsProcessor::new(seed, Some(1.), None);
+        constraint.reset();
+        let mut text = String::new();
+        loop {
+            let token = logits_processor.sample_constrained(&logits, &mut constraint)?;
+            if token == 0 {
+                break;
+            }
+            text.push_str(std::str::from_utf8(&vocab[token as usize]).unwrap());
+            if text.len() > 100 {
+                break;
+            }
+        }
+        assert!(constraint.grammar().matches(&text), "{text}");
+    }
+    Ok(())
+}
+
+#[test]
+fn grammar_byte_tokens() -> Result<()> {
+    // Byte level tokens with "α" split over two tokens, and a token ending with the first byte
+    // of "ω".
+    let vocab: Vec<Vec<u8>> = vec![
+        b"<eos>".to_vec(),
+        vec![0xce],
+        vec![0xb1],
+        vec![0xcf],
+        vec![0xd0],
+        "α".as_bytes().to_vec(),
+        vec![0x89],
+        b"a".to_vec(),
+        vec![0xb1, 0xcf],
+    ];
+    let grammar = Grammar::parse("root ::= [α-ω]+")?;
+    let mut constraint = GrammarConstraint::new(grammar, &vocab, &[0])?;
+    assert_eq!(constraint.allowed_tokens(), [1, 3, 5]);
+    assert!(constraint.advance(4).is_err());
+    constraint.advance(1)?;
+    assert_eq!(constraint.allowed_tokens(), [2, 8]);
+    constraint.advance(8)?;
+    // The grammar is not complete in the middle of a character.
+    assert!(!constraint.is_complete());
+    assert_eq!(constraint.allowed_tokens(), [6]);
+    constraint.advance(6)?;
+    assert!(constraint.is_complete());
+    assert_eq!(constraint.allowed_tokens(), [0, 1, 3, 5]);
+
+    // The negated classes allow the prefixes of the characters they do not exclude.
+    let grammar = Grammar::parse("root ::= [^α-ο]")?;
+    let constraint = GrammarConstraint::new(grammar, &vocab, &[0])?;
+    assert_eq!(constraint.allowed_tokens(), [1, 3, 4, 7]);
+    Ok(())
+}
+
+#[test]
+fn json_schema_grammar() -> Result<()> {
+    let schema = serde_json::json!({
+        "type": "object",
+        "properties": {
+            "name": { "type": "string" },
+            "age": { "type": "integer" },
+            "tags": { "type": "array", "items": { "$ref": "#/$defs/tag" } },
+            "kind": { "enum": ["a", "b", 3] },
+            "score": { "type": ["number", "null"] },
+            "parent": { "$ref": "#/$defs/node" }
+        },
+        "required": ["name", "age"],
+        "$defs": {
+            "tag": { "anyOf": [{ "type": "boolean" }, { "const": "x\"y" }] },
+            "node": {
+                "type": "object",
+                "properties": { "child": { "$ref": "#/$defs/node" } }
+            }
+        }
+    });
+    let gbnf = json_schema_to_gbnf(&schema)?;
+    let grammar = Grammar::from_json_schema(&schema)?;
+    for json in [
+        r#"{"name": "bob", "age": 42}"#,
+        r#"{ "name" : "a\"bé" ,"age":-1 }  "#,
+        r#"{"name": "", "age": 0, "kind": 3}"#,
+        r#"{"name": "", "age": 0, "kind": "b", "score": null, "tags": [true, "x\"y"]}"#,
+        r#"{"name": "", "age": 0, "parent": {"child": {}}, "tags": []}"#,
+        r#"{"name": "", "age": 0, "score": -1.5e3}"#,
+    ] {
+        assert!(grammar.matches(json), "{json}\n{gbnf}")
+    }
+    for json in [
+        r#"{"age": 42, "name": "bob"}"#,
+        r#"{"name": "bob"}"#,
+        r#"{"name": "bob", "age": 4.2}"#,
+        r#"{"name": "bob", "age": 01}"#,
+        r#"{"name": "", "age": 0, "kind": "c"}"#,
+        r#"{"name": "", "age": 0, "tags": ["x"]}"#,
+        r#"{"name": "", "age": 0, "kind": "a", "age": 1}"#,
+        r#"{"name": "", "age": 0,}"#,
+    ] {
+        assert!(!grammar.matches(json), "{json}\n{gbnf}")
+    }
+
+    // Without properties any json value of the given type is accepted.
+    let grammar = Grammar::from_json_schema(&serde_json::json!({ "type": "array" }))?;
+    assert!(grammar.matches(r#"[1, {"a": [null, "b"]}, false]"#));
+    assert!(!grammar.matches(r#"{"a": 1}"#));
+    assert!(json_schema_to_gbnf(&serde_json::json!({ "$ref": "#/other" })).is_err());
+    Ok(())
+}
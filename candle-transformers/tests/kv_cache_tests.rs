@@ -0,0 +1,62 @@
+use candle::test_utils::max_diff;
+use candle::{DType, Device, Result, Tensor};
+use candle_nn::{VarBuilder, VarMap};
+use candle_transformers::models::llama::{Cache, Config, Llama};
+
+fn tiny_config() -> Config {
+    Config {
+        hidden_size: 16,
+        intermediate_size: 32,
+        vocab_size: 32,
+        num_hidden_layers: 2,
+        num_attention_heads: 4,
+        num_key_value_heads: 2,
+        use_flash_attn: false,
+        rms_norm_eps: 1e-5,
+        rope_theta: 10_000.,
+        bos_token_id: None,
+        eos_token_id: None,
+        rope_scaling: None,
+        max_position_embeddings: 64,
+        tie_word_embeddings: false,
+        quantization_config: None,
+    }
+}
+
+fn forward(model: &Llama, tokens: &[u32], index_pos: usize, cache: &mut Cache) -> Result<Tensor> {
+    let input = Tensor::new(tokens, &Device::Cpu)?.unsqueeze(0)?;
+    model.forward(&input, index_pos, cache)
+}
+
+#[test]
+fn llama_cache_fork_and_truncate() -> Result<()> {
+    let dev = &Device::Cpu;
+    let cfg = tiny_config();
+    let varmap = VarMap::new();
+    let model = Llama::load(VarBuilder::from_varmap(&varmap, DType::F32, dev), &cfg)?;
+    let tokens = [3u32, 7, 1, 12, 5, 9];
+    let mut full = Cache::new(true, DType::F32, &cfg, dev)?;
+    let expected = forward(&model, &tokens, 0, &mut full)?;
+
+    let mut cache = Cache::new(true, DType::F32, &cfg, dev)?;
+    forward(&model, &tokens[..4], 0, &mut cache)?;
+    let mut fork = cache.fork();
+    // Roll back the prompt and process the end of the sequence token by token.
+    cache.truncate(2)?;
+    assert_eq!(cache.seq_len(), 2);
+    let mut logits = forward(&model, &[0], 2, &mut cache)?;
+    cache.truncate(2)?;
+    for (i, &token) in tokens.iter().enumerate().skip(2) {
+        logits = forward(&model, &[token], i, &mut cache)?;
+    }
+    assert_eq!(cache.seq_len(), 6);
+    assert!(max_diff(&logits, &expected)? < 1e-5);
+
+    // The fork is not affected by the truncation of the original cache.
+    assert_eq!(fork.seq_len(), 4);
+    forward(&model, &tokens[4..5], 4, &mut fork)?;
+    let logits = forward(&model, &tokens[5..], 5, &mut fork)?;
+    assert!(max_diff(&logits, &expected)? < 1e-5);
+    assert!(fork.truncate(7).is_err());
+    Ok(())
+}
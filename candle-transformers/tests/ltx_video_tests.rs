@@ -0,0 +1,161 @@
+use candle::test_utils::max_diff;
+use candle::{DType, Device, IndexOp, Module, Result, Tensor};
+use candle_nn::{VarBuilder, VarMap};
+use candle_transformers::models::ltx_video::{self, transformer, vae};
+
+// Creates the variables with `f` and replaces them with random values.
+fn random_vars<T>(f: impl Fn(VarBuilder) -> Result<T>) -> Result<T> {
+    let varmap = VarMap::new();
+    f(VarBuilder::from_varmap(&varmap, DType::F32, &Device::Cpu))?;
+    for var in varmap.all_vars() {
+        let scale = 1. / (var.elem_count() as f64 / var.dim(0)?.max(1) as f64).sqrt();
+        var.set(&(var.randn_like(0., 1.)? * scale.min(1.))?)?;
+    }
+    f(VarBuilder::from_varmap(&varmap, DType::F32, &Device::Cpu))
+}
+
+fn tiny_vae_config() -> vae::Config {
+    vae::Config {
+        latent_channels: 4,
+        block_out_channels: vec![4, 8],
+        layers_per_block: vec![1, 1, 1],
+        spatio_temporal_scaling: vec![true, true],
+        patch_size: 2,
+        ..vae::Config::ltx_video()
+    }
+}
+
+#[test]
+fn causal_conv3d() -> Result<()> {
+    let dev = &Device::Cpu;
+    let conv = random_vars(|vb| vae::CausalConv3d::new(2, 3, 3, true, vb))?;
+    let xs = Tensor::randn(0f32, 1., (1, 2, 5, 4, 4), dev)?;
+    let ys = conv.forward(&xs)?;
+    assert_eq!(ys.dims(), [1, 3, 5, 4, 4]);
+    // The output frames only depend on the current and previous frames.
+    let xs2 = Tensor::cat(&[xs.narrow(2, 0, 3)?, xs.narrow(2, 3, 2)?.ones_like()?], 2)?;
+    let ys2 = conv.forward(&xs2)?;
+    assert_eq!(max_diff(&ys.narrow(2, 0, 3)?, &ys2.narrow(2, 0, 3)?)?, 0.);
+    assert!(max_diff(&ys.i((.., .., 3))?, &ys2.i((.., .., 3))?)? > 0.);
+
+    // With a single frame, the replicate padding is the same as summing the temporal taps.
+    let mut varmap = VarMap::new();
+    let vb = VarBuilder::from_varmap(&varmap, DType::F32, dev);
+    let w = Tensor::randn(0f32, 1., (3, 2, 3, 3, 3), dev)?;
+    vae::CausalConv3d::new(2, 3, 3, false, vb.clone())?;
+    let b = Tensor::zeros(3, DType::F32, dev)?;
+    varmap.set([("conv.weight", &w), ("conv.bias", &b)].into_iter())?;
+    let conv = vae::CausalConv3d::new(2, 3, 3, false, vb)?;
+    let xs = Tensor::randn(0f32, 1., (1, 2, 1, 4, 4), dev)?;
+    let ys = conv.forward(&xs)?.squeeze(2)?;
+    let expected = xs.squeeze(2)?.conv2d(&w.sum(2)?, 1, 1, 1, 1)?;
+    assert!(max_diff(&ys, &expected)? < 1e-5);
+    Ok(())
+}
+
+#[test]
+fn vae_tiled_decode() -> Result<()> {
+    let dev = &Device::Cpu;
+    let cfg = tiny_vae_config();
+    assert_eq!(cfg.spatial_compression_ratio(), 8);
+    assert_eq!(cfg.temporal_compression_ratio(), 4);
+    let vae = random_vars(|vb| vae::AutoEncoder::new(&cfg, vb))?;
+    let xs = Tensor::randn(0f32, 1., (1, 4, 5, 3, 5), dev)?;
+    let ys = vae.decode(&xs)?;
+    assert_eq!(ys.dims(), [1, 3, 17, 24, 40]);
+
+    // A single tile gives the same result as decoding everything at once.
+    let tiling = vae::TilingConfig {
+        tile_size: 8,
+        tile_overlap: 1,
+        tile_frames: 8,
+        tile_frame_overlap: 1,
+    };
+    assert!(max_diff(&vae.decode_tiled(&xs, &tiling)?, &ys)? < 1e-5);
+
+    // With smaller tiles, the parts of the first tile that do not overlap with other tiles are
+    // decoded without the context of the other tiles.
+    let tiling = vae::TilingConfig {
+        tile_size: 3,
+        tile_overlap: 1,
+        tile_frames: 3,
+        tile_frame_overlap: 1,
+    };
+    let tiled = vae.decode_tiled(&xs, &tiling)?;
+    assert_eq!(tiled.dims(), ys.dims());
+    assert!(
+        max_diff(
+            &tiled.i((.., .., 0, .., ..8))?,
+            &ys.i((.., .., 0, .., ..8))?
+        )? > 0.
+    );
+    let first = tiled.i((.., .., ..8, ..16, ..16))?;
+    let tile = vae.decode(&xs.i((.., .., ..3, ..3, ..3))?)?;
+    assert!(max_diff(&first, &tile.i((.., .., ..8, ..16, ..16))?)? < 1e-5);
+    Ok(())
+}
+
+fn tiny_transformer_config() -> transformer::Config {
+    transformer::Config {
+        in_channels: 4,
+        out_channels: 4,
+        num_attention_heads: 2,
+        attention_head_dim: 6,
+        cross_attention_dim: 12,
+        num_layers: 2,
+        caption_channels: 8,
+        ..transformer::Config::ltx_video()
+    }
+}
+
+#[test]
+fn transformer_forward() -> Result<()> {
+    let dev = &Device::Cpu;
+    let cfg = tiny_transformer_config();
+    let model = random_vars(|vb| transformer::Transformer::new(&cfg, vb))?;
+    let dims = (2, 2, 3);
+    let latents = Tensor::randn(0f32, 1., (1, 4, 2, 2, 3), dev)?;
+    let packed = ltx_video::pack_latents(&latents)?;
+    assert_eq!(packed.dims(), [1, 12, 4]);
+    assert_eq!(
+        max_diff(&ltx_video::unpack_latents(&packed, dims)?, &latents)?,
+        0.
+    );
+
+    let context = Tensor::randn(0f32, 1., (1, 5, 8), dev)?;
+    let mask = Tensor::new(&[[1u8, 1, 1, 0, 0]], dev)?;
+    let t = Tensor::new(&[500f32], dev)?;
+    let rope_scale = ltx_video::rope_scale(&tiny_vae_config(), 25.);
+    let ys = model.forward(&packed, &context, Some(&mask), &t, dims, rope_scale)?;
+    assert_eq!(ys.dims(), [1, 12, 4]);
+    // The masked text tokens are ignored.
+    let context2 = Tensor::cat(
+        &[
+            context.narrow(1, 0, 3)?,
+            context.narrow(1, 3, 2)?.ones_like()?,
+        ],
+        1,
+    )?;
+    let ys2 = model.forward(&packed, &context2, Some(&mask), &t, dims, rope_scale)?;
+    assert!(max_diff(&ys, &ys2)? < 1e-5);
+    let ys3 = model.forward(&packed, &context2, None, &t, dims, rope_scale)?;
+    assert!(max_diff(&ys, &ys3)? > 1e-4);
+
+    let timesteps = ltx_video::get_schedule(2, 12);
+    assert_eq!(timesteps.len(), 3);
+    assert_eq!((timesteps[0], timesteps[2]), (1., 0.));
+    let context = Tensor::cat(&[&context, &context2], 0)?;
+    let mask = Tensor::cat(&[&mask, &mask], 0)?;
+    let out = ltx_video::denoise(
+        &model,
+        &packed,
+        &context,
+        Some(&mask),
+        &timesteps,
+        3.,
+        dims,
+        rope_scale,
+    )?;
+    assert_eq!(out.dims(), [1, 12, 4]);
+    Ok(())
+}
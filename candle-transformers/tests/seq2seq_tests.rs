@@ -0,0 +1,130 @@
+use candle::{Device, Result, Tensor};
+use candle_transformers::pipelines::seq2seq::{
+    banned_ngram_tokens, GenerationConfig, Seq2SeqModel, Seq2SeqPipeline,
+};
+use std::collections::HashMap;
+
+const START: u32 = 0;
+const EOS: u32 = 1;
+const VOCAB: usize = 10;
+
+// A model where the next token distribution only depends on the last token.
+struct Markov {
+    probs: HashMap<u32, Vec<(u32, f32)>>,
+    encoded: usize,
+    // The seqlen offsets of the decoder calls and the sizes of the reordered caches.
+    offsets: Vec<usize>,
+    reorders: Vec<usize>,
+}
+
+impl Markov {
+    fn new(probs: &[(u32, &[(u32, f32)])]) -> Self {
+        let probs = probs.iter().map(|(t, p)| (*t, p.to_vec())).collect();
+        Self {
+            probs,
+            encoded: 0,
+            offsets: vec![],
+            reorders: vec![],
+        }
+    }
+}
+
+impl Seq2SeqModel for Markov {
+    fn encode(&mut self, input_ids: &Tensor) -> Result<Tensor> {
+        self.encoded += 1;
+        input_ids.to_dtype(candle::DType::F32)
+    }
+
+    fn decode(
+        &mut self,
+        decoder_input_ids: &Tensor,
+        encoder_output: &Tensor,
+        seqlen_offset: usize,
+    ) -> Result<Tensor> {
+        self.offsets.push(seqlen_offset);
+        assert_eq!(decoder_input_ids.dim(0)?, encoder_output.dim(0)?);
+        let ids = decoder_input_ids.to_vec2::<u32>()?;
+        let mut logits = vec![f32::NEG_INFINITY; ids.len() * VOCAB];
+        for (b, ids) in ids.iter().enumerate() {
+            let last = *ids.last().unwrap();
+            for &(token, p) in self.probs.get(&last).unwrap() {
+                logits[b * VOCAB + token as usize] = p.ln()
+            }
+        }
+        Tensor::from_vec(logits, (ids.len(), VOCAB), &Device::Cpu)
+    }
+
+    fn reset_kv_cache(&mut self) {}
+
+    fn reorder_kv_cache(&mut self, indices: &Tensor) -> Result<()> {
+        self.reorders.push(indices.dim(0)?);
+        Ok(())
+    }
+}
+
+fn pipeline(model: Markov) -> Seq2SeqPipeline<Markov> {
+    Seq2SeqPipeline::new(model, GenerationConfig::new(START, EOS), &Device::Cpu)
+}
+
+#[test]
+fn ngrams() {
+    assert_eq!(banned_ngram_tokens(&[1, 2, 3, 1, 2], 3), [3]);
+    assert_eq!(banned_ngram_tokens(&[1, 2, 1, 3, 1], 2), [2, 3]);
+    assert!(banned_ngram_tokens(&[1, 2, 3], 0).is_empty());
+    assert!(banned_ngram_tokens(&[1, 2, 3], 4).is_empty());
+}
+
+#[test]
+fn greedy_length_control() -> Result<()> {
+    let model = Markov::new(&[
+        (START, &[(2, 0.6), (EOS, 0.4)]),
+        (2, &[(3, 0.6), (4, 0.4)]),
+        (3, &[(EOS, 0.5), (2, 0.3), (4, 0.2)]),
+        (4, &[(EOS, 0.9), (2, 0.1)]),
+    ]);
+    let mut pipeline = pipeline(model);
+    assert_eq!(pipeline.generate(&[5, 6])?, [2, 3]);
+    pipeline.config_mut().max_new_tokens = 1;
+    assert_eq!(pipeline.generate(&[5, 6])?, [2]);
+    // The eos token cannot be generated before 3 new tokens.
+    pipeline.config_mut().max_new_tokens = 10;
+    pipeline.config_mut().min_new_tokens = 3;
+    assert_eq!(pipeline.generate(&[5, 6])?, [2, 3, 2, 3]);
+    // The bigram 2 3 can only occur once.
+    pipeline.config_mut().no_repeat_ngram_size = 2;
+    assert_eq!(pipeline.generate(&[5, 6])?, [2, 3, 2, 4]);
+    // The encoder output is cached.
+    assert_eq!(pipeline.model().encoded, 1);
+    assert_eq!(pipeline.encoder_cache().len(), 1);
+    pipeline.generate(&[7])?;
+    assert_eq!(pipeline.model().encoded, 2);
+    Ok(())
+}
+
+#[test]
+fn beam_search_length_penalty() -> Result<()> {
+    let model = Markov::new(&[
+        (START, &[(EOS, 0.45), (7, 0.55)]),
+        (7, &[(EOS, 0.7), (8, 0.3)]),
+        (8, &[(EOS, 1.0)]),
+    ]);
+    let mut pipeline = pipeline(model);
+    // Greedy decoding picks the most likely token at each step.
+    assert_eq!(pipeline.generate(&[5])?, [7]);
+    pipeline.config_mut().num_beams = 2;
+    // The scores of the finished hypotheses are the log probabilities divided by the number
+    // of generated tokens to the power of the length penalty.
+    for (length_penalty, expected) in [(0., vec![]), (1., vec![7]), (2., vec![7, 8])] {
+        pipeline.config_mut().length_penalty = length_penalty;
+        assert_eq!(pipeline.generate(&[5])?, expected, "{length_penalty}");
+    }
+    pipeline.config_mut().min_new_tokens = 2;
+    pipeline.config_mut().length_penalty = 0.;
+    pipeline.model().offsets.clear();
+    pipeline.model().reorders.clear();
+    assert_eq!(pipeline.generate(&[5])?, [7, 8]);
+    // The decoder only processes the new token of each beam and the kv cache is reordered.
+    assert_eq!(pipeline.model().offsets, [0, 1, 2]);
+    assert_eq!(pipeline.model().reorders, [1, 1]);
+    Ok(())
+}
@@ -0,0 +1,153 @@
+use candle::{Device, Result, Tensor};
+use candle_transformers::generation::stopping::{EosTokens, MaxTokens, StoppingCriteria};
+use candle_transformers::generation::{
+    LogitsProcessor, Sampling, SpeculativeDecoder, SpeculativeModel, StopReason,
+};
+
+// A bigram model, the logits only depend on the last token. The cache stores the processed
+// tokens so that the offsets and rollbacks can be checked.
+struct Bigram {
+    logits: Tensor,
+    cache: Vec<u32>,
+    num_calls: usize,
+}
+
+impl Bigram {
+    fn new(logits: Tensor) -> Self {
+        Self {
+            logits,
+            cache: vec![],
+            num_calls: 0,
+        }
+    }
+}
+
+impl SpeculativeModel for Bigram {
+    fn forward(&mut self, tokens: &[u32], seqlen_offset: usize) -> Result<Tensor> {
+        assert_eq!(seqlen_offset, self.cache.len());
+        self.num_calls += 1;
+        self.cache.extend_from_slice(tokens);
+        let ids = Tensor::new(tokens, &Device::Cpu)?;
+        self.logits.index_select(&ids, 0)
+    }
+
+    fn truncate_cache(&mut self, len: usize) -> Result<()> {
+        assert!(len <= self.cache.len());
+        self.cache.truncate(len);
+        Ok(())
+    }
+}
+
+const VOCAB: usize = 8;
+
+fn random_logits() -> Result<Tensor> {
+    Tensor::randn(0f32, 2., (VOCAB, VOCAB), &Device::Cpu)
+}
+
+#[test]
+fn speculative_greedy() -> Result<()> {
+    let target_logits = random_logits()?;
+    // The draft model agrees with the target for half of the tokens.
+    let noise = Tensor::randn(0f32, 2., (VOCAB, VOCAB), &Device::Cpu)?;
+    let mask = Tensor::new(
+        &[[1f32], [0.], [1.], [0.], [1.], [0.], [1.], [0.]],
+        &Device::Cpu,
+    )?;
+    let draft_logits = (&target_logits + noise.broadcast_mul(&mask)?)?;
+
+    let prompt = [3u32, 1];
+    let mut expected = vec![];
+    let mut last = prompt[1];
+    let mut processor = LogitsProcessor::from_sampling(0, Sampling::ArgMax);
+    for _ in 0..20 {
+        last = processor.sample(&target_logits.get(last as usize)?)?;
+        expected.push(last)
+    }
+
+    let draft = Bigram::new(draft_logits);
+    let target = Bigram::new(target_logits.clone());
+    let mut decoder = SpeculativeDecoder::new(draft, target, 3, Sampling::ArgMax, 0, &prompt);
+    let tokens = decoder.generate(20, None)?;
+    assert_eq!(tokens, expected);
+    assert_eq!(decoder.generated_tokens(), expected);
+    assert_eq!(
+        decoder.target().cache[..],
+        decoder.tokens()[..decoder.target().cache.len()]
+    );
+
+    // With the same model for the draft, all the tokens are accepted.
+    let draft = Bigram::new(target_logits.clone());
+    let target = Bigram::new(target_logits.clone());
+    let mut decoder = SpeculativeDecoder::new(draft, target, 4, Sampling::ArgMax, 0, &prompt);
+    let tokens = decoder.generate(20, Some(expected[12]))?;
+    let stop = expected.iter().position(|&t| t == expected[12]).unwrap();
+    assert_eq!(tokens, expected[..=stop]);
+    assert_eq!(decoder.acceptance_rate(), 1.);
+    assert_eq!(decoder.target().num_calls, stop / 5 + 1);
+
+    // The criteria are checked for each accepted token.
+    let draft = Bigram::new(target_logits.clone());
+    let target = Bigram::new(target_logits);
+    let mut decoder = SpeculativeDecoder::new(draft, target, 4, Sampling::ArgMax, 0, &prompt);
+    let mut criteria: Vec<Box<dyn StoppingCriteria>> = vec![
+        Box::new(EosTokens(vec![expected[12]])),
+        Box::new(MaxTokens(7)),
+    ];
+    let (tokens, reason) = decoder.generate_until(&mut criteria)?;
+    let (end, expected_reason) = if stop < 7 {
+        (stop + 1, StopReason::Eos(expected[12]))
+    } else {
+        (7, StopReason::MaxTokens)
+    };
+    assert_eq!(tokens, expected[..end]);
+    assert_eq!(reason, expected_reason);
+    assert_eq!(decoder.tokens().len(), prompt.len() + tokens.len());
+    Ok(())
+}
+
+#[test]
+fn speculative_sampling_distribution() -> Result<()> {
+    // Whether the draft token is accepted or not, the first token follows the target
+    // distribution.
+    let target_logits = random_logits()?;
+    let draft_logits = random_logits()?;
+    let sampling = Sampling::All { temperature: 1. };
+    let prompt = [2u32];
+    let num_runs = 4000;
+    let mut counts = vec![0f32; VOCAB];
+    for seed in 0..num_runs {
+        let draft = Bigram::new(draft_logits.clone());
+        let target = Bigram::new(target_logits.clone());
+        let mut decoder =
+            SpeculativeDecoder::new(draft, target, 1, sampling.clone(), seed, &prompt);
+        let tokens = decoder.step()?;
+        counts[tokens[0] as usize] += 1.;
+    }
+    let expected = sampling.probabilities(&target_logits.get(2)?)?;
+    for (c, p) in counts.iter().zip(expected.iter()) {
+        let freq = c / num_runs as f32;
+        assert!((freq - p).abs() < 0.04, "{counts:?} {expected:?}");
+    }
+    Ok(())
+}
+
+#[test]
+fn sampling_probabilities() -> Result<()> {
+    let logits = Tensor::new(&[1f32, 3., 2., 0.], &Device::Cpu)?;
+    let prs = Sampling::ArgMax.probabilities(&logits)?;
+    assert_eq!(prs, [0., 1., 0., 0.]);
+    let prs = Sampling::TopK {
+        k: 2,
+        temperature: 1.,
+    }
+    .probabilities(&logits)?;
+    let e = 1f32.exp();
+    assert!((prs[1] - e / (e + 1.)).abs() < 1e-6 && prs[0] == 0. && prs[3] == 0.);
+    let prs = Sampling::TopP {
+        p: 0.5,
+        temperature: 1.,
+    }
+    .probabilities(&logits)?;
+    assert_eq!(prs, [0., 1., 0., 0.]);
+    Ok(())
+}
@@ -0,0 +1,89 @@
+use candle::quantized::{gguf_file, GgmlDType, QTensor};
+use candle::{DType, Device, Result, Tensor};
+use candle_transformers::models::stable_diffusion::unet_2d::{
+    BlockConfig, UNet2DConditionModel, UNet2DConditionModelConfig,
+};
+use candle_transformers::models::stable_diffusion::var_builder::VarBuilder;
+use candle_transformers::quantized_var_builder;
+
+fn tiny_config() -> UNet2DConditionModelConfig {
+    UNet2DConditionModelConfig {
+        blocks: vec![
+            BlockConfig {
+                out_channels: 32,
+                use_cross_attn: Some(1),
+                attention_head_dim: 2,
+            },
+            BlockConfig {
+                out_channels: 32,
+                use_cross_attn: None,
+                attention_head_dim: 2,
+            },
+        ],
+        layers_per_block: 1,
+        norm_num_groups: 8,
+        cross_attention_dim: 32,
+        ..Default::default()
+    }
+}
+
+fn rel_err(xs: &Tensor, ys: &Tensor) -> Result<f32> {
+    let diff = (xs - ys)?.abs()?.max_all()?.to_scalar::<f32>()?;
+    let scale = ys.abs()?.max_all()?.to_scalar::<f32>()?;
+    Ok(diff / scale)
+}
+
+#[test]
+fn quantized_unet() -> Result<()> {
+    let dev = &Device::Cpu;
+    let varmap = candle_nn::VarMap::new();
+    let vb = candle_nn::VarBuilder::from_varmap(&varmap, DType::F32, dev);
+    let unet = UNet2DConditionModel::new(vb.clone(), 4, 4, false, tiny_config())?;
+
+    let xs = Tensor::randn(0f32, 1., (1, 4, 8, 8), dev)?;
+    let context = Tensor::randn(0f32, 1., (1, 3, 32), dev)?;
+    let expected = unet.forward(&xs, 10., &context)?;
+
+    // Int8 weights and activations for the linear layers.
+    let vb_int8 = VarBuilder::from(vb).with_int8_linear(true);
+    let unet_int8 = UNet2DConditionModel::new(vb_int8, 4, 4, false, tiny_config())?;
+    let ys = unet_int8.forward(&xs, 10., &context)?;
+    assert_eq!(ys.dims(), expected.dims());
+    let err = rel_err(&ys, &expected)?;
+    assert!(err < 0.1, "{err}");
+
+    // A gguf file with q8_0 linear layers and f32 weights for the other layers.
+    let tensors = varmap
+        .data()
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(name, var)| {
+            let dtype = match var.dims() {
+                [_, in_dim] if in_dim % 32 == 0 => GgmlDType::Q8_0,
+                _ => GgmlDType::F32,
+            };
+            Ok((name.clone(), QTensor::quantize(var.as_tensor(), dtype)?))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    assert!(tensors.iter().any(|(_, t)| t.dtype() == GgmlDType::Q8_0));
+    let tensors = tensors
+        .iter()
+        .map(|(name, t)| (name.as_str(), t))
+        .collect::<Vec<_>>();
+    let mut buffer = std::io::Cursor::new(Vec::new());
+    gguf_file::write(&mut buffer, &[], &tensors)?;
+    let vb = quantized_var_builder::VarBuilder::from_gguf_buffer(buffer.get_ref(), dev)?;
+    let unet_q = UNet2DConditionModel::new(
+        VarBuilder::from_gguf(vb, DType::F32),
+        4,
+        4,
+        false,
+        tiny_config(),
+    )?;
+    let ys = unet_q.forward(&xs, 10., &context)?;
+    assert_eq!(ys.dims(), expected.dims());
+    let err = rel_err(&ys, &expected)?;
+    assert!(err < 0.05, "{err}");
+    Ok(())
+}
@@ -0,0 +1,94 @@
+use candle::Result;
+use candle_transformers::generation::stopping::{
+    CancellationToken, EosTokens, MaxTokens, StopReason, StopStrings, StoppingCriteria,
+};
+
+const VOCAB: [&str; 10] = [
+    "Hello", " world", "<", "/", "s>", "b>", "\n", "User", ":", "",
+];
+
+// Concatenates the token texts, the last two tokens are the two bytes of "é" and decode to a
+// replacement character when the second one is missing.
+fn decode(tokens: &[u32]) -> Result<String> {
+    let mut text = String::new();
+    let mut i = 0;
+    while i < tokens.len() {
+        match (tokens[i], tokens.get(i + 1)) {
+            (9, Some(10)) => {
+                text.push('é');
+                i += 1
+            }
+            (9, _) => text.push('\u{FFFD}'),
+            (t, _) => text.push_str(VOCAB[t as usize]),
+        }
+        i += 1
+    }
+    Ok(text)
+}
+
+// Feeds the tokens one at a time, returns the streamed chunks and the reason to stop.
+fn stream(criteria: &mut StopStrings, tokens: &[u32]) -> Result<(Vec<String>, Option<StopReason>)> {
+    let mut chunks = vec![];
+    for end in 1..=tokens.len() {
+        let reason = criteria.check(&tokens[..end])?;
+        chunks.push(criteria.next_text());
+        if reason.is_some() {
+            return Ok((chunks, reason));
+        }
+    }
+    Ok((chunks, None))
+}
+
+#[test]
+fn stop_strings() -> Result<()> {
+    let stop = vec!["</s>".to_string(), "\nUser:".to_string()];
+    let mut criteria = StopStrings::new(stop, decode);
+    // The stop string spans three tokens and its start is held back.
+    let (chunks, reason) = stream(&mut criteria, &[0, 1, 2, 3, 4, 1])?;
+    assert_eq!(reason, Some(StopReason::StopString("</s>".to_string())));
+    assert_eq!(chunks, ["Hello", " world", "", "", ""]);
+    assert_eq!(criteria.text(), "Hello world");
+    assert_eq!(criteria.finish_text(), "");
+
+    // A held back prefix that does not end up as a stop string is streamed later.
+    criteria.reset();
+    let (chunks, reason) = stream(&mut criteria, &[2, 5, 6, 7, 1, 6, 7, 8])?;
+    assert_eq!(reason, Some(StopReason::StopString("\nUser:".to_string())));
+    assert_eq!(chunks, ["", "<b>", "", "", "\nUser world", "", "", ""]);
+    assert_eq!(criteria.text(), "<b>\nUser world");
+
+    // Incomplete characters are not streamed, the remaining text is returned at the end.
+    let mut criteria = StopStrings::new(vec!["</s>".to_string()], decode);
+    let (chunks, reason) = stream(&mut criteria, &[0, 9, 10, 2])?;
+    assert_eq!(reason, None);
+    assert_eq!(chunks, ["Hello", "", "é", ""]);
+    assert_eq!(criteria.finish_text(), "<");
+    assert_eq!(criteria.text(), "Helloé<");
+    // A shorter sequence starts over.
+    assert_eq!(
+        criteria.check(&[2, 3, 4])?,
+        Some(StopReason::StopString("</s>".to_string()))
+    );
+    assert_eq!(criteria.text(), "");
+    Ok(())
+}
+
+#[test]
+fn combined_criteria() -> Result<()> {
+    let cancellation = CancellationToken::new();
+    let mut criteria: Vec<Box<dyn StoppingCriteria>> = vec![
+        Box::new(EosTokens(vec![7, 9])),
+        Box::new(MaxTokens(4)),
+        Box::new(cancellation.clone()),
+    ];
+    assert_eq!(criteria.check(&[1])?, None);
+    assert_eq!(criteria.check(&[1, 9])?, Some(StopReason::Eos(9)));
+    assert_eq!(criteria.check(&[1, 2, 3, 4])?, Some(StopReason::MaxTokens));
+    // The first criteria that triggers wins.
+    assert_eq!(criteria.check(&[1, 2, 3, 7])?, Some(StopReason::Eos(7)));
+    cancellation.clone().cancel();
+    assert!(cancellation.is_cancelled());
+    assert_eq!(criteria.check(&[1])?, Some(StopReason::Cancelled));
+    assert_ne!(cancellation, CancellationToken::new());
+    Ok(())
+}
@@ -0,0 +1,162 @@
+use candle::{DType, Device, Result, Tensor};
+use candle_transformers::pipelines::translation::{
+    split_sentences, LanguageConfig, Sentence, Seq2SeqModel, TextTokenizer, TranslationConfig,
+    TranslationPipeline,
+};
+
+const EOS: u32 = 0;
+const START: u32 = 1;
+const FR: u32 = 2;
+const EN: u32 = 3;
+const OFFSET: u32 = 16;
+const VOCAB: usize = 256;
+
+// A char level tokenizer for ascii text, with a couple special tokens.
+struct CharTokenizer;
+
+impl TextTokenizer for CharTokenizer {
+    fn encode(&self, text: &str) -> Result<Vec<u32>> {
+        Ok(text.bytes().map(|b| b as u32 + OFFSET).collect())
+    }
+
+    fn decode(&self, ids: &[u32]) -> Result<String> {
+        Ok(ids.iter().map(|&i| (i - OFFSET) as u8 as char).collect())
+    }
+
+    fn token_to_id(&self, token: &str) -> Option<u32> {
+        match token {
+            "__fr__" => Some(FR),
+            "__en__" => Some(EN),
+            _ => None,
+        }
+    }
+}
+
+// A model translating to upper case, the encoder output is the source ids and each decoding
+// step copies the next source token.
+#[derive(Default)]
+struct UpperCase {
+    prompt_len: usize,
+    skip_source: usize,
+    encoded_batches: Vec<usize>,
+}
+
+impl Seq2SeqModel for UpperCase {
+    fn encode(&mut self, input_ids: &Tensor) -> Result<Tensor> {
+        self.encoded_batches.push(input_ids.dim(0)?);
+        input_ids.to_dtype(DType::F32)
+    }
+
+    fn decode(
+        &mut self,
+        decoder_input_ids: &Tensor,
+        encoder_output: &Tensor,
+        _seqlen_offset: usize,
+    ) -> Result<Tensor> {
+        let ids = decoder_input_ids.to_vec2::<u32>()?;
+        let sources = encoder_output.to_vec2::<f32>()?;
+        let mut logits = vec![0f32; ids.len() * VOCAB];
+        for (b, (ids, source)) in ids.iter().zip(sources.iter()).enumerate() {
+            assert_eq!(ids[0], START);
+            if self.prompt_len == 2 {
+                assert_eq!(ids[1], FR);
+            }
+            let pos = self.skip_source + ids.len() - self.prompt_len;
+            let token = source[pos] as u32;
+            let token = if token == EOS {
+                EOS
+            } else {
+                ((token - OFFSET) as u8).to_ascii_uppercase() as u32 + OFFSET
+            };
+            logits[b * VOCAB + token as usize] = 1.;
+        }
+        Tensor::from_vec(logits, (ids.len(), VOCAB), &Device::Cpu)
+    }
+
+    fn reset_kv_cache(&mut self) {}
+
+    fn reorder_kv_cache(&mut self, _: &Tensor) -> Result<()> {
+        Ok(())
+    }
+}
+
+fn config(language: LanguageConfig, batch_size: usize) -> TranslationConfig {
+    TranslationConfig {
+        language,
+        decoder_start_token_id: START,
+        eos_token_id: EOS,
+        batch_size,
+        max_new_tokens: 64,
+    }
+}
+
+#[test]
+fn sentences() {
+    let s = |text, separator| Sentence { text, separator };
+    let text = "  Hello there! How are you?\n\nJ. R. R. Tolkien wrote books.   Ok";
+    assert_eq!(
+        split_sentences(text),
+        [
+            s("Hello there!", " "),
+            s("How are you?", "\n\n"),
+            s("J. R. R. Tolkien wrote books.", "   "),
+            s("Ok", ""),
+        ]
+    );
+    assert_eq!(
+        split_sentences("3.5 is a number. "),
+        [s("3.5 is a number.", " ")]
+    );
+    assert!(split_sentences("  ").is_empty());
+}
+
+#[test]
+fn translate_document() -> Result<()> {
+    let model = UpperCase {
+        prompt_len: 1,
+        ..Default::default()
+    };
+    let cfg = config(LanguageConfig::fixed(), 2);
+    let mut pipeline =
+        TranslationPipeline::new(model, CharTokenizer, CharTokenizer, cfg, &Device::Cpu)?;
+    let text = "Abc. Def.\nGh. Ijk. Lmn.";
+    assert_eq!(pipeline.translate(text)?, "ABC. DEF.\nGH. IJK. LMN.");
+    // Sentences are batched by length: one sentence of 3 chars, then four of 4 chars.
+    assert_eq!(pipeline.model().encoded_batches, [1, 2, 2]);
+    Ok(())
+}
+
+#[test]
+fn language_tokens() -> Result<()> {
+    // The source prefix is part of the source and gets copied by the model.
+    let model = UpperCase {
+        prompt_len: 1,
+        ..Default::default()
+    };
+    let cfg = config(LanguageConfig::marian_multilingual("fra"), 8);
+    let mut pipeline =
+        TranslationPipeline::new(model, CharTokenizer, CharTokenizer, cfg, &Device::Cpu)?;
+    assert_eq!(pipeline.translate("ab. c")?, ">>FRA<< AB. >>FRA<< C");
+
+    // The source language token is skipped by the model, the forced bos token is not output.
+    let model = UpperCase {
+        prompt_len: 2,
+        skip_source: 1,
+        ..Default::default()
+    };
+    let cfg = config(LanguageConfig::m2m100("en", "fr"), 8);
+    let mut pipeline =
+        TranslationPipeline::new(model, CharTokenizer, CharTokenizer, cfg, &Device::Cpu)?;
+    assert_eq!(pipeline.translate_sentences(&["ab", "cde"])?, ["AB", "CDE"]);
+
+    let cfg = config(LanguageConfig::nllb("eng_Latn", "fra_Latn"), 8);
+    let err = TranslationPipeline::new(
+        UpperCase::default(),
+        CharTokenizer,
+        CharTokenizer,
+        cfg,
+        &Device::Cpu,
+    );
+    assert!(err.is_err());
+    Ok(())
+}
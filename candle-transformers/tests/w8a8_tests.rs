@@ -0,0 +1,61 @@
+use candle::{DType, Device, Module, Result, Tensor};
+use candle_transformers::w8a8::W8A8Linear;
+
+// Deterministic values in [-2, 2] so that the error bounds below do not depend on the seed.
+fn values<S: Into<candle::Shape>>(shape: S, offset: f64, dev: &Device) -> Result<Tensor> {
+    let shape = shape.into();
+    let n = shape.elem_count() as u32;
+    let xs = Tensor::arange(0u32, n, dev)?.to_dtype(DType::F32)?;
+    (xs.affine(12.9898, offset)?.sin()? * 2.)?.reshape(shape)
+}
+
+#[test]
+fn w8a8_linear() -> Result<()> {
+    let dev = &Device::Cpu;
+    // An input dimension that is not a multiple of the simd width to also cover the tail.
+    let (in_dim, out_dim) = (100, 7);
+    let weight = values((out_dim, in_dim), 0., dev)?;
+    let bias = values(out_dim, 1., dev)?;
+    let linear = candle_nn::Linear::new(weight.clone(), Some(bias));
+    let w8a8 = W8A8Linear::from_linear(&linear)?;
+
+    // The weights are quantized per output feature.
+    let diff = (w8a8.dequantize()? - &weight)?.abs()?;
+    let step = (weight.abs()?.max_keepdim(1)? / 127.)?;
+    let ok = diff.broadcast_le(&((step * 0.5)? + 1e-6)?)?;
+    assert_eq!(ok.min_all()?.to_scalar::<u8>()?, 1);
+
+    let xs = values((2, 3, in_dim), 2., dev)?;
+    let ys = w8a8.forward(&xs)?;
+    assert_eq!(ys.dims(), [2, 3, out_dim]);
+    let expected = linear.forward(&xs)?;
+    let err = (&ys - &expected)?.abs()?.max_all()?.to_scalar::<f32>()?;
+    assert!(err < 0.3, "{err}");
+
+    // With the dequantized weights, the only remaining error comes from the activations.
+    let deq = candle_nn::Linear::new(w8a8.dequantize()?, w8a8.bias().cloned());
+    let err = (&ys - deq.forward(&xs)?)?
+        .abs()?
+        .max_all()?
+        .to_scalar::<f32>()?;
+    assert!(err < 0.2, "{err}");
+
+    // Rows that are exactly representable with a single scale give exact results.
+    let xs = Tensor::stack(
+        &[
+            Tensor::arange(-127f32, -27., dev)?,
+            Tensor::arange(28f32, 128., dev)?,
+        ],
+        0,
+    )?;
+    let ys = w8a8.forward(&xs)?;
+    let err = (&ys - deq.forward(&xs)?)?
+        .abs()?
+        .max_all()?
+        .to_scalar::<f32>()?;
+    assert!(err < 1e-3, "{err}");
+
+    let ys16 = w8a8.forward(&xs.to_dtype(DType::F16)?)?;
+    assert_eq!(ys16.dtype(), DType::F16);
+    Ok(())
+}
@@ -0,0 +1,98 @@
+use candle::Result;
+use candle_transformers::models::whisper::long_form::{
+    chunks, merge_chunks, segments_from_tokens, Chunk, ChunkConfig, TimedSegment,
+};
+
+const EOT: u32 = 50256;
+const TS: u32 = 50363;
+
+// Timestamp token for a time in seconds relative to the chunk start.
+fn ts(s: f64) -> u32 {
+    TS + (s / 0.02).round() as u32
+}
+
+fn segment(start: f64, end: f64, tokens: &[u32], complete: bool) -> TimedSegment {
+    TimedSegment {
+        start,
+        end,
+        tokens: tokens.to_vec(),
+        complete,
+    }
+}
+
+#[test]
+fn chunking() -> Result<()> {
+    let cfg = ChunkConfig {
+        chunk_frames: 3000,
+        overlap_frames: 500,
+        batch_size: 4,
+    };
+    let cs = chunks(7000, &cfg)?;
+    let starts: Vec<_> = cs.iter().map(|c| (c.start_frame, c.num_frames)).collect();
+    assert_eq!(starts, [(0, 3000), (2500, 3000), (5000, 2000)]);
+    assert_eq!(cs[1].start_s(), 25.);
+    assert_eq!(cs[2].end_s(), 70.);
+    assert_eq!(chunks(3000, &cfg)?.len(), 1);
+    let cfg = ChunkConfig {
+        overlap_frames: 3000,
+        ..cfg
+    };
+    assert!(chunks(100, &cfg).is_err());
+    Ok(())
+}
+
+#[test]
+fn segments() {
+    let chunk = Chunk {
+        start_frame: 1000,
+        num_frames: 3000,
+    };
+    // Special tokens are skipped, the last segment is cut by the end of the chunk.
+    let tokens = [ts(0.), 1, 2, ts(2.4), ts(2.4), 3, ts(5.), ts(6.), 4, 5, EOT];
+    let segments = segments_from_tokens(&tokens, &chunk, EOT, TS);
+    assert_eq!(
+        segments,
+        [
+            segment(10., 12.4, &[1, 2], true),
+            segment(12.4, 15., &[3], true),
+            segment(16., 40., &[4, 5], false),
+        ]
+    );
+    // Without timestamps, the segment covers the whole chunk.
+    let segments = segments_from_tokens(&[EOT + 1, 7, 8], &chunk, EOT, TS);
+    assert_eq!(segments, [segment(10., 40., &[7, 8], true)]);
+}
+
+#[test]
+fn merging() {
+    let c0 = Chunk {
+        start_frame: 0,
+        num_frames: 3000,
+    };
+    let c1 = Chunk {
+        start_frame: 2500,
+        num_frames: 3000,
+    };
+    let c2 = Chunk {
+        start_frame: 5000,
+        num_frames: 1000,
+    };
+    // The overlap boundaries are at 27.5s and 52.5s.
+    let s0 = vec![
+        segment(0., 10., &[1], true),
+        segment(10., 28., &[2], true),
+        segment(28., 30., &[3], false),
+    ];
+    let s1 = vec![
+        segment(25., 28., &[2], true),
+        segment(28., 31., &[3], true),
+        segment(31., 51., &[4], true),
+        segment(51., 55., &[5], false),
+    ];
+    let s2 = vec![segment(50., 51., &[4], true), segment(51., 56., &[5], true)];
+    let merged = merge_chunks(&[(c0, s0), (c1, s1), (c2, s2)]);
+    let tokens: Vec<_> = merged.iter().map(|s| s.tokens[0]).collect();
+    assert_eq!(tokens, [1, 2, 3, 4, 5]);
+    assert_eq!(merged[2], segment(28., 31., &[3], true));
+    assert_eq!(merged[4], segment(51., 56., &[5], true));
+}
@@ -1,4 +1,4 @@
-use candle::quantized::{gguf_file, GgmlDType, QTensor};
+use candle::quantized::{f16_cast, gguf_file, GgmlDType, QTensor};
 use candle::{Device, Result};
 use clap::{Parser, Subcommand, ValueEnum};
 use rayon::prelude::*;
@@ -51,6 +51,8 @@ enum Quantization {
     Q5k,
     Q6k,
     Q8k,
+    Iq2xxs,
+    Iq4nl,
     F16,
     F32,
 }
@@ -70,6 +72,8 @@ impl Quantization {
             Quantization::Q5k => GgmlDType::Q5K,
             Quantization::Q6k => GgmlDType::Q6K,
             Quantization::Q8k => GgmlDType::Q8K,
+            Quantization::Iq2xxs => GgmlDType::Iq2Xxs,
+            Quantization::Iq4nl => GgmlDType::Iq4Nl,
             Quantization::F16 => GgmlDType::F16,
             Quantization::F32 => GgmlDType::F32,
         }
@@ -150,6 +154,12 @@ enum Command {
         /// Which tensor to quantize.
         #[arg(long, value_enum, default_value_t = QuantizationMode::Llama)]
         mode: QuantizationMode,
+
+        /// When converting safetensors files to f16, rescale the tensors that overflow instead of
+        /// saturating their values. The scales are stored in the gguf metadata and applied when
+        /// loading the tensors, which are then kept in f32.
+        #[arg(long)]
+        rescale: bool,
     },
 
     Dequantize {
@@ -160,6 +170,16 @@ enum Command {
         #[arg(long)]
         out_file: std::path::PathBuf,
     },
+
+    /// Average the weights of several checkpoints.
+    Average {
+        /// The checkpoints, each being a safetensors file or a directory of safetensors shards.
+        checkpoints: Vec<std::path::PathBuf>,
+
+        /// The output file, or directory when the first checkpoint is a directory.
+        #[arg(long)]
+        out: std::path::PathBuf,
+    },
 }
 
 #[derive(Parser, Debug, Clone)]
@@ -395,18 +415,60 @@ fn run_ls(
     Ok(())
 }
 
+// Casts the float tensors to f16 with saturation and prints the tensors that lost some values.
+fn run_convert_f16(
+    tensors: &[(String, candle::Tensor)],
+    out_file: &mut std::fs::File,
+    rescale: bool,
+) -> Result<()> {
+    let tensors = tensors
+        .iter()
+        .map(|(k, v)| (k.as_str(), v))
+        .collect::<Vec<_>>();
+    let options = f16_cast::CastOptions { rescale };
+    let report = f16_cast::write_gguf_f16(out_file, &[], &tensors, &options)?;
+    let mut issues = 0;
+    for (name, stats) in report.iter() {
+        if stats.overflow == 0 && stats.underflow == 0 && stats.non_finite == 0 {
+            continue;
+        }
+        issues += 1;
+        println!(
+            "  {name}: max_abs {:.4e}, overflow {}, saturated {}, underflow {}, subnormal {}, non-finite {}, scale {}",
+            stats.max_abs,
+            stats.overflow,
+            stats.saturated,
+            stats.underflow,
+            stats.subnormal,
+            stats.non_finite,
+            stats.scale,
+        );
+    }
+    println!(
+        "converted {} tensors, {issues} with out of range values",
+        report.len()
+    );
+    Ok(())
+}
+
 fn run_quantize_safetensors(
     in_files: &[std::path::PathBuf],
     out_file: std::path::PathBuf,
     q: Quantization,
+    rescale: bool,
 ) -> Result<()> {
     let mut out_file = std::fs::File::create(out_file)?;
-    let mut tensors = std::collections::HashMap::new();
+    // Sorting the tensors by name makes the generated file reproducible.
+    let mut tensors = std::collections::BTreeMap::new();
     for in_file in in_files.iter() {
         let in_tensors = candle::safetensors::load(in_file, &Device::Cpu)?;
         tensors.extend(in_tensors)
     }
     println!("tensors: {}", tensors.len());
+    let tensors = tensors.into_iter().collect::<Vec<_>>();
+    if let Quantization::F16 = q {
+        return run_convert_f16(&tensors, &mut out_file, rescale);
+    }
 
     let dtype = q.dtype();
     let block_size = dtype.block_size();
@@ -454,6 +516,7 @@ fn run_quantize(
     out_file: std::path::PathBuf,
     q: Quantization,
     qmode: QuantizationMode,
+    rescale: bool,
     device: &Device,
 ) -> Result<()> {
     if in_files.is_empty() {
@@ -466,7 +529,7 @@ fn run_quantize(
     }
     if let Some(extension) = in_files[0].extension() {
         if extension == "safetensors" {
-            return run_quantize_safetensors(in_files, out_file, q);
+            return run_quantize_safetensors(in_files, out_file, q, rescale);
         }
     }
 
@@ -535,8 +598,12 @@ fn main() -> anyhow::Result<()> {
             out_file,
             quantization,
             mode,
-        } => run_quantize(&in_file, out_file, quantization, mode, &device)?,
+            rescale,
+        } => run_quantize(&in_file, out_file, quantization, mode, rescale, &device)?,
         Command::Dequantize { in_file, out_file } => run_dequantize(in_file, out_file, &device)?,
+        Command::Average { checkpoints, out } => {
+            candle_nn::swa::average_checkpoints(&checkpoints, out)?
+        }
     }
     Ok(())
 }